@@ -0,0 +1,34 @@
+//! Walk the input as a MADT, a DMAR and an IVRS. Dumped firmware tables
+//! make a good seed corpus: the walkers only look at the header's length
+//! field, not the signature.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zerovisor_fuzz::walk::{self, Layout};
+
+fuzz_target!(|data: &[u8]| {
+    let table = walk::table(data);
+    for layout in [Layout::Madt, Layout::Dmar, Layout::Ivrs] {
+        let mut end = 0usize;
+        walk::for_each_entry(data, layout, |typ, e| {
+            // Structures are disjoint, in order, and inside the table
+            let off = e.as_ptr() as usize - table.as_ptr() as usize;
+            assert!(off >= end && off + e.len() <= table.len());
+            end = off + e.len();
+            match layout {
+                Layout::Madt => { let _ = walk::madt_processor_id(typ, e); }
+                Layout::Dmar => {
+                    let scopes = walk::dmar_struct(typ, e).map_or(0, |d| d.scopes);
+                    walk::dmar_for_each_scope(e, scopes, |_, len, _, path| assert_eq!(path.len() + 6, len));
+                }
+                Layout::Ivrs => { let _ = walk::ivrs_ivhd(typ, e); }
+            }
+        });
+    }
+    let _ = walk::madt_lapic_base(data);
+    walk::madt_for_each_processor_id(data, |_| {});
+    walk::dmar_for_each_drhd(data, |_, _| {});
+    walk::dmar_for_each_device_scope(data, |_, _, _, _, _| {});
+    walk::ivrs_for_each_ivhd(data, |_, _| {});
+});
@@ -0,0 +1,49 @@
+//! Split the input into ZMIG frames the way `ingest_packet` splits a
+//! received packet, then decode each frame the way the receive paths do.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zerovisor_fuzz::crc32::{crc32, crc32_update};
+use zerovisor_fuzz::wire::{self, Hello, Scan};
+
+fuzz_target!(|data: &[u8]| {
+    let mut pos = 0usize;
+    loop {
+        match wire::scan(data, pos) {
+            Scan::Frame { at, hdr, frame, ext, payload } => {
+                let hlen = hdr.wire_len();
+                assert!(at >= pos);
+                assert!(hdr.ver == wire::VER1 || hdr.ver == wire::VER2);
+                assert_eq!(ext.len(), hdr.ext_len as usize);
+                assert_eq!(payload.len(), hdr.payload_len as usize);
+                assert_eq!(frame.len(), hlen + ext.len() + payload.len());
+                assert!(at + frame.len() <= data.len());
+                // A parsed header re-encodes to the bytes it came from
+                assert_eq!(hdr.to_bytes()[..hlen], data[at..at + hlen]);
+                let _ = crc32_update(crc32(ext), payload) == { hdr.crc32 };
+                if wire::tlv_acceptable(ext, |t| t == wire::TLV_NUMA_NODE) {
+                    let _ = wire::tlv_find(ext, wire::TLV_NUMA_NODE);
+                }
+                if hdr.typ == wire::TYP_PAGE && hdr.flags & wire::FLAG_COMP != 0 {
+                    let mut page = [0u8; 4096];
+                    let mut pairs = payload.chunks_exact(2);
+                    let _ = wire::rle_decode(|| pairs.next().map(|p| [p[0], p[1]]), &mut page);
+                }
+                if hdr.typ == wire::TYP_CTRL {
+                    if let Some(h) = Hello::parse(payload) {
+                        let v = h.negotiate();
+                        assert!((wire::VER1..=wire::MAX_VER).contains(&v));
+                    }
+                    let _ = wire::ctrl_parse(payload);
+                }
+                pos = at + frame.len();
+            }
+            Scan::Truncated { at, hdr } => {
+                assert!(at + hdr.wire_len() + hdr.body_len() > data.len());
+                break;
+            }
+            Scan::End => break,
+        }
+    }
+});
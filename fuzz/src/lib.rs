@@ -0,0 +1,20 @@
+//! Host builds of the parsers that take untrusted bytes, and of the
+//! migration channel ring for its unit tests (`cargo test --lib`).
+//!
+//! These are the hypervisor's own source files, included by path rather
+//! than copied, so a fuzz target or test exercises exactly the code that
+//! runs on the target. Each of them depends only on `core`.
+
+#![no_std]
+
+#[path = "../../src/migrate/wire.rs"]
+pub mod wire;
+
+#[path = "../../src/firmware/acpi/walk.rs"]
+pub mod walk;
+
+#[path = "../../src/util/crc32.rs"]
+pub mod crc32;
+
+#[path = "../../src/migrate/ring.rs"]
+pub mod ring;
@@ -0,0 +1,229 @@
+#![allow(dead_code)]
+
+//! Application processor runtime.
+//!
+//! Once the trampoline has an AP in long mode on its own stack, it calls
+//! `ap_entry` through the pointer `install` leaves in the mailbox. The AP
+//! switches to the BSP's CR3, GDT and code/data selectors, loads the shared
+//! halt IDT and then idles, polling its work queue. The BSP dispatches plain
+//! `fn(u64) -> u64` jobs to a CPU and collects the result by ticket.
+//!
+//! Jobs run with interrupts disabled and must not call UEFI services, which
+//! are single-threaded and BSP-only.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::util::spinlock::SpinLock;
+
+/// AP slots; matches the trampoline's stack array.
+pub const MAX_APS: usize = 64;
+const QUEUE_DEPTH: usize = 8;
+
+/// Mailbox offset of the 64-bit entry pointer the trampoline calls.
+pub const MAILBOX_ENTRY: usize = 0x10;
+
+pub type Job = fn(u64) -> u64;
+
+#[derive(Clone, Copy)]
+struct Pending { ticket: u64, func: Job, arg: u64 }
+
+struct Queue {
+    jobs: [Option<Pending>; QUEUE_DEPTH],
+    head: usize,
+    len: usize,
+    next_ticket: u64,
+    /// (ticket, result) ring of completed jobs
+    done: [(u64, u64); QUEUE_DEPTH],
+}
+
+impl Queue {
+    const EMPTY: Queue = Queue { jobs: [None; QUEUE_DEPTH], head: 0, len: 0, next_ticket: 1, done: [(0, 0); QUEUE_DEPTH] };
+
+    fn push(&mut self, func: Job, arg: u64) -> Option<u64> {
+        if self.len == QUEUE_DEPTH { return None; }
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.jobs[(self.head + self.len) % QUEUE_DEPTH] = Some(Pending { ticket, func, arg });
+        self.len += 1;
+        Some(ticket)
+    }
+
+    fn pop(&mut self) -> Option<Pending> {
+        if self.len == 0 { return None; }
+        let j = self.jobs[self.head].take();
+        self.head = (self.head + 1) % QUEUE_DEPTH;
+        self.len -= 1;
+        j
+    }
+}
+
+const Q: SpinLock<Queue> = SpinLock::new(Queue::EMPTY);
+static QUEUES: [SpinLock<Queue>; MAX_APS] = [Q; MAX_APS];
+
+const OFFLINE: AtomicBool = AtomicBool::new(false);
+static ONLINE: [AtomicBool; MAX_APS] = [OFFLINE; MAX_APS];
+const NO_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+static APIC_IDS: [AtomicU32; MAX_APS] = [NO_ID; MAX_APS];
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static JOBS_RUN: [AtomicU64; MAX_APS] = [ZERO; MAX_APS];
+
+/// BSP state the APs adopt, captured by `install`.
+#[derive(Clone, Copy)]
+struct BspState { cr3: u64, gdt_base: u64, gdt_limit: u16, cs: u16, ds: u16 }
+
+static BSP: SpinLock<BspState> = SpinLock::new(BspState { cr3: 0, gdt_base: 0, gdt_limit: 0, cs: 0, ds: 0 });
+
+#[repr(C, packed)]
+struct DescPtr { limit: u16, base: u64 }
+
+/// Record the BSP's paging and segmentation for the APs and point the
+/// trampoline mailbox at `ap_entry`. Call before releasing the APs (GO).
+pub fn install(mailbox: u64) {
+    let (cr3, cs, ds): (u64, u16, u16);
+    let mut gdtr = DescPtr { limit: 0, base: 0 };
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {0:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {0:x}, ss", out(reg) ds, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("sgdt [{}]", in(reg) &mut gdtr, options(nostack, preserves_flags));
+    }
+    BSP.lock(|b| *b = BspState { cr3, gdt_base: gdtr.base, gdt_limit: gdtr.limit, cs, ds });
+    crate::arch::x86::idt::prepare(cs);
+    unsafe { core::ptr::write_volatile((mailbox as usize + MAILBOX_ENTRY) as *mut u64, ap_entry as *const () as usize as u64); }
+}
+
+/// First Rust code on an AP. `index` is the trampoline arrival order (and
+/// stack slot), `apic_id` the initial APIC ID from CPUID.
+extern "sysv64" fn ap_entry(index: u32, apic_id: u32) -> ! {
+    let b = BSP.lock(|b| *b);
+    let gdtr = DescPtr { limit: b.gdt_limit, base: b.gdt_base };
+    unsafe {
+        core::arch::asm!("mov cr3, {}", in(reg) b.cr3, options(nostack, preserves_flags));
+        core::arch::asm!("lgdt [{}]", in(reg) &gdtr, options(readonly, nostack, preserves_flags));
+        // Far return into the BSP's code selector, then reload data selectors.
+        core::arch::asm!(
+            "push {cs}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            ".byte 0x48, 0xCB", // retfq
+            "2:",
+            "mov ds, {ds:x}",
+            "mov es, {ds:x}",
+            "mov ss, {ds:x}",
+            cs = in(reg) b.cs as u64,
+            ds = in(reg) b.ds,
+            tmp = out(reg) _,
+        );
+    }
+    crate::arch::x86::idt::load();
+    let i = index as usize;
+    if i >= MAX_APS { loop { unsafe { core::arch::asm!("cli; hlt", options(nomem, nostack)); } } }
+    APIC_IDS[i].store(apic_id, Ordering::Relaxed);
+    ONLINE[i].store(true, Ordering::Release);
+    loop {
+        match QUEUES[i].lock(|q| q.pop()) {
+            Some(job) => {
+                let r = (job.func)(job.arg);
+                QUEUES[i].lock(|q| q.done[(job.ticket as usize) % QUEUE_DEPTH] = (job.ticket, r));
+                JOBS_RUN[i].fetch_add(1, Ordering::Relaxed);
+            }
+            None => core::hint::spin_loop(),
+        }
+    }
+}
+
+pub fn is_online(cpu: usize) -> bool { cpu < MAX_APS && ONLINE[cpu].load(Ordering::Acquire) }
+
+pub fn online_count() -> usize { ONLINE.iter().filter(|o| o.load(Ordering::Acquire)).count() }
+
+/// APIC ID reported by an online AP.
+pub fn apic_id(cpu: usize) -> Option<u32> {
+    if is_online(cpu) { Some(APIC_IDS[cpu].load(Ordering::Relaxed)) } else { None }
+}
+
+/// Call `f(cpu, apic_id, jobs_run)` for each online AP.
+pub fn for_each_online(mut f: impl FnMut(usize, u32, u64)) {
+    for i in 0..MAX_APS {
+        if is_online(i) { f(i, APIC_IDS[i].load(Ordering::Relaxed), JOBS_RUN[i].load(Ordering::Relaxed)); }
+    }
+}
+
+/// Queue `func(arg)` on AP `cpu`; returns a ticket for `poll`.
+pub fn dispatch(cpu: usize, func: Job, arg: u64) -> Result<u64, &'static str> {
+    if !is_online(cpu) { return Err("smp: cpu not online"); }
+    QUEUES[cpu].lock(|q| q.push(func, arg)).ok_or("smp: work queue full")
+}
+
+/// Queue `func(arg)` on AP `cpu` only if its APIC ID is set in `apic_mask`
+/// (one bit per APIC ID 0..63; 0 allows every CPU).
+pub fn dispatch_within(cpu: usize, apic_mask: u64, func: Job, arg: u64) -> Result<u64, &'static str> {
+    let id = apic_id(cpu).ok_or("smp: cpu not online")?;
+    if apic_mask != 0 && (id >= 64 || (apic_mask & (1u64 << id)) == 0) { return Err("smp: cpu outside the affinity mask"); }
+    dispatch(cpu, func, arg)
+}
+
+/// Result of a dispatched job, once the AP has finished it.
+pub fn poll(cpu: usize, ticket: u64) -> Option<u64> {
+    if cpu >= MAX_APS { return None; }
+    QUEUES[cpu].lock(|q| { let (t, r) = q.done[(ticket as usize) % QUEUE_DEPTH]; if t == ticket { Some(r) } else { None } })
+}
+
+/// Dispatch and wait up to `timeout_us` for the result.
+pub fn run_on(system_table: &SystemTable<Boot>, cpu: usize, func: Job, arg: u64, timeout_us: u64) -> Result<u64, &'static str> {
+    let ticket = dispatch(cpu, func, arg)?;
+    let mut waited = 0;
+    loop {
+        if let Some(r) = poll(cpu, ticket) { return Ok(r); }
+        if waited >= timeout_us { return Err("smp: timed out waiting for cpu"); }
+        let _ = system_table.boot_services().stall(10);
+        waited += 10;
+    }
+}
+
+/// Run `func(arg)` on every online AP; returns how many finished in time.
+pub fn broadcast(system_table: &SystemTable<Boot>, func: Job, arg: u64, timeout_us: u64) -> usize {
+    let mut tickets = [0u64; MAX_APS];
+    for (i, t) in tickets.iter_mut().enumerate() { *t = dispatch(i, func, arg).unwrap_or(0); }
+    let mut waited = 0;
+    loop {
+        let done = tickets.iter().enumerate().filter(|&(i, &t)| t != 0 && poll(i, t).is_some()).count();
+        let want = tickets.iter().filter(|&&t| t != 0).count();
+        if done == want || waited >= timeout_us { return done; }
+        let _ = system_table.boot_services().stall(10);
+        waited += 10;
+    }
+}
+
+/// Job: flush this CPU's non-global TLB entries by reloading CR3.
+pub fn job_flush_tlb(_: u64) -> u64 {
+    unsafe { core::arch::asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _, options(nostack, preserves_flags)); }
+    0
+}
+
+/// Job: this CPU's current APIC ID (CPUID leaf 1).
+pub fn job_apic_id(_: u64) -> u64 {
+    (crate::arch::x86::cpuid::cpuid(1, 0).ebx >> 24) as u64
+}
+
+/// Job: echo `arg + 1`, to check dispatch and completion.
+pub fn job_ping(arg: u64) -> u64 { arg.wrapping_add(1) }
+
+/// Job: TSC on this CPU.
+pub fn job_rdtsc(_: u64) -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe { core::arch::asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags)); }
+    (hi as u64) << 32 | lo as u64
+}
+
+/// Named jobs for `smp run`.
+pub fn job_by_name(name: &str) -> Option<Job> {
+    match name {
+        "ping" => Some(job_ping),
+        "apicid" => Some(job_apic_id),
+        "tlb" => Some(job_flush_tlb),
+        "tsc" => Some(job_rdtsc),
+        _ => None,
+    }
+}
@@ -0,0 +1,125 @@
+#![allow(dead_code)]
+
+//! Confidential-computing capability detection (AMD SEV family, Intel TDX).
+//!
+//! Only reads CPUID and the MSRs that CPUID says exist, so it is safe to call
+//! on any host. "Enabled" means firmware switched the feature on; the PSP
+//! (SEV) or the TDX module still has to be initialised before a guest can run.
+
+use crate::arch::x86::cpuid::cpuid;
+use crate::arch::x86::vm::{detect_vendor, Vendor};
+
+/// AMD memory encryption leaf
+pub const LEAF_AMD_MEM_ENC: u32 = 0x8000_001F;
+/// SYSCFG; bit 23 = MemEncryptionModEn (set by firmware for SME/SEV)
+pub const MSR_AMD_SYSCFG: u32 = 0xC001_0010;
+const SYSCFG_MEM_ENC_EN: u64 = 1 << 23;
+
+/// IA32_MTRRCAP; bit 15 = SEAMRR supported
+pub const MSR_IA32_MTRRCAP: u32 = 0xFE;
+/// IA32_SEAMRR_PHYS_MASK; bit 11 = range valid (TDX module area configured)
+pub const MSR_IA32_SEAMRR_PHYS_MASK: u32 = 0x1401;
+/// IA32_TME_ACTIVATE; bit 0 = locked, bit 1 = enabled
+pub const MSR_IA32_TME_ACTIVATE: u32 = 0x982;
+/// IA32_MKTME_KEYID_PARTITIONING; [31:0] MKTME KeyIDs, [63:32] TDX private KeyIDs
+pub const MSR_IA32_MKTME_KEYID_PART: u32 = 0x87;
+
+/// AMD SEV family capabilities from CPUID 0x8000001F and SYSCFG.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SevCaps {
+    pub sme: bool,
+    pub sev: bool,
+    pub sev_es: bool,
+    pub sev_snp: bool,
+    /// Firmware turned on memory encryption (SYSCFG.MemEncryptionModEn)
+    pub enabled: bool,
+    /// Page-table bit that marks a page encrypted
+    pub c_bit: u8,
+    /// Physical address bits lost when encryption is on
+    pub phys_reduction: u8,
+    /// Highest ASID usable by an encrypted guest
+    pub max_asid: u32,
+    /// Lowest ASID for plain SEV; SEV-ES/SNP guests use 1..min_sev_asid
+    pub min_sev_asid: u32,
+}
+
+/// Intel TDX capabilities from MTRRCAP, SEAMRR and the KeyID partitioning.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TdxCaps {
+    /// CPU implements SEAMRR
+    pub seamrr: bool,
+    /// Firmware configured the SEAM range (where the TDX module lives)
+    pub enabled: bool,
+    /// Total-memory encryption is active and locked
+    pub tme: bool,
+    /// First private KeyID (HKID) and how many there are
+    pub keyid_base: u32,
+    pub keyids: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Caps { pub sev: SevCaps, pub tdx: TdxCaps }
+
+fn sev_caps() -> SevCaps {
+    if cpuid(0x8000_0000, 0).eax < LEAF_AMD_MEM_ENC { return SevCaps::default(); }
+    let r = cpuid(LEAF_AMD_MEM_ENC, 0);
+    let mut c = SevCaps {
+        sme: r.eax & (1 << 0) != 0,
+        sev: r.eax & (1 << 1) != 0,
+        sev_es: r.eax & (1 << 3) != 0,
+        sev_snp: r.eax & (1 << 4) != 0,
+        c_bit: (r.ebx & 0x3F) as u8,
+        phys_reduction: ((r.ebx >> 6) & 0x3F) as u8,
+        max_asid: r.ecx,
+        min_sev_asid: r.edx,
+        enabled: false,
+    };
+    if c.sme || c.sev { c.enabled = unsafe { crate::arch::x86::msr::rdmsr(MSR_AMD_SYSCFG) } & SYSCFG_MEM_ENC_EN != 0; }
+    c
+}
+
+fn tdx_caps() -> TdxCaps {
+    let mut c = TdxCaps::default();
+    // TME_EN: CPUID.7.0:ECX[13]
+    if cpuid(0, 0).eax >= 7 && cpuid(7, 0).ecx & (1 << 13) != 0 {
+        let act = unsafe { crate::arch::x86::msr::rdmsr(MSR_IA32_TME_ACTIVATE) };
+        c.tme = act & 0b11 == 0b11;
+        if c.tme {
+            let part = unsafe { crate::arch::x86::msr::rdmsr(MSR_IA32_MKTME_KEYID_PART) };
+            let mktme = part as u32;
+            c.keyids = (part >> 32) as u32;
+            c.keyid_base = mktme + 1;
+        }
+    }
+    c.seamrr = unsafe { crate::arch::x86::msr::rdmsr(MSR_IA32_MTRRCAP) } & (1 << 15) != 0;
+    if c.seamrr { c.enabled = unsafe { crate::arch::x86::msr::rdmsr(MSR_IA32_SEAMRR_PHYS_MASK) } & (1 << 11) != 0; }
+    c
+}
+
+/// Probe the host. Vendor-specific MSRs are only touched on that vendor.
+pub fn detect() -> Caps {
+    match detect_vendor() {
+        Vendor::Amd => Caps { sev: sev_caps(), tdx: TdxCaps::default() },
+        Vendor::Intel => Caps { sev: SevCaps::default(), tdx: tdx_caps() },
+        Vendor::Unknown => Caps::default(),
+    }
+}
+
+/// SEAMCALL leaf numbers used by the host side of TDX.
+pub mod seamcall {
+    pub const TDH_SYS_INFO: u64 = 32;
+    pub const TDH_SYS_INIT: u64 = 33;
+    pub const TDH_SYS_LP_INIT: u64 = 35;
+    pub const TDH_MNG_CREATE: u64 = 9;
+    pub const TDH_MNG_KEY_CONFIG: u64 = 8;
+}
+
+/// Issue a SEAMCALL. No TDX module is loaded by this hypervisor yet, and
+/// SEAMCALL outside VMX root with a module present would #UD, so this only
+/// reports why it cannot proceed.
+pub fn seamcall(_leaf: u64, _args: [u64; 4]) -> Result<[u64; 4], &'static str> {
+    let c = tdx_caps();
+    if !c.seamrr { return Err("tdx: SEAM not supported by this CPU"); }
+    if !c.enabled { return Err("tdx: SEAM range not configured by firmware"); }
+    Err("tdx: no TDX module loaded")
+}
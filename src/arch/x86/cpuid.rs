@@ -79,6 +79,20 @@ pub fn has_invariant_tsc() -> bool {
     (r.edx & (1 << 8)) != 0
 }
 
+/// Indicates the LAPIC timer's TSC-deadline mode via CPUID.1:ECX[24].
+#[inline(always)]
+pub fn has_tsc_deadline() -> bool {
+    let r = cpuid(leaf::BASIC_FEATURES, 0);
+    (r.ecx & (1 << 24)) != 0
+}
+
+/// Indicates 1GiB page support via CPUID.80000001:EDX[26].
+#[inline(always)]
+pub fn has_page1gb() -> bool {
+    let r = cpuid(leaf::AMD_EXT_FEATURES, 0);
+    (r.edx & (1 << 26)) != 0
+}
+
 /// Indicates presence of x2APIC via CPUID.1:ECX[21].
 #[inline(always)]
 pub fn has_x2apic() -> bool {
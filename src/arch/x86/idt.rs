@@ -79,12 +79,21 @@ fn set_gate(idx: usize, handler: u64, selector: u16, ist: u8, type_attr: u8) {
 
 /// Initialize IDT with a default non-returning handler for all vectors and load it.
 pub fn init() {
-    let cs = get_cs_selector();
+    prepare(get_cs_selector());
+    load();
+}
+
+/// Fill every gate with the halt stub for code selector `cs` without loading.
+pub fn prepare(cs: u16) {
     let handler = isr_addr();
     // 0x8E = present | DPL=0 | type=0xE (interrupt gate)
     for i in 0..256usize {
         set_gate(i, handler, cs, 0, 0x8E);
     }
+}
+
+/// Load the prepared IDT on the calling CPU.
+pub fn load() {
     unsafe { load_idt(); }
 }
 
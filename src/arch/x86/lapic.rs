@@ -14,6 +14,15 @@ const LAPIC_EOI: usize = 0x0B0;        // End Of Interrupt
 const LAPIC_SVR: usize = 0x0F0;        // Spurious Interrupt Vector Register
 const LAPIC_ICR_LOW: usize = 0x300;    // Interrupt Command Register low
 const LAPIC_ICR_HIGH: usize = 0x310;   // Interrupt Command Register high
+const LAPIC_LVT_TIMER: usize = 0x320;  // LVT Timer
+
+/// x2APIC MSRs for SVR and LVT Timer
+const X2APIC_SVR: u32 = 0x80F;
+const X2APIC_LVT_TIMER: u32 = 0x832;
+const MSR_TSC_DEADLINE: u32 = 0x6E0;
+
+/// LVT Timer mode bits 18:17 = 10b (TSC-deadline)
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
 
 /// ICR delivery modes
 const ICR_DM_INIT: u32 = 0x5 << 8;
@@ -115,6 +124,34 @@ pub fn is_x2apic_enabled() -> bool {
     (v & (1 << 10)) != 0
 }
 
+/// Software-enable this CPU's LAPIC and put its timer in TSC-deadline mode
+/// with `vector`. The timer stays idle until `set_tsc_deadline`. Returns
+/// false if the mode is unsupported or the LAPIC is globally disabled.
+pub fn setup_tsc_deadline_timer(vector: u8) -> bool {
+    if !crate::arch::x86::cpuid::has_tsc_deadline() { return false; }
+    if is_x2apic_enabled() {
+        unsafe {
+            let svr = crate::arch::x86::msr::rdmsr(X2APIC_SVR);
+            if (svr & 0x100) == 0 { crate::arch::x86::msr::wrmsr(X2APIC_SVR, (svr & !0xFF) | 0x1FF); }
+            crate::arch::x86::msr::wrmsr(X2APIC_LVT_TIMER, (LVT_TIMER_TSC_DEADLINE | vector as u32) as u64);
+        }
+    } else {
+        let Some(base) = apic_base_via_msr() else { return false };
+        unsafe {
+            if (mmio_read32(base, LAPIC_SVR) & 0x100) == 0 { let _ = enable_svr(base, 0xFF); }
+            mmio_write32(base, LAPIC_LVT_TIMER, LVT_TIMER_TSC_DEADLINE | vector as u32);
+        }
+    }
+    // The LVT write must be ordered before the first IA32_TSC_DEADLINE write (SDM 10.5.4.1).
+    unsafe { core::arch::asm!("mfence", options(nostack, preserves_flags)); }
+    true
+}
+
+/// Arm the TSC-deadline timer for absolute TSC value `deadline`; zero disarms.
+pub fn set_tsc_deadline(deadline: u64) {
+    unsafe { crate::arch::x86::msr::wrmsr(MSR_TSC_DEADLINE, deadline); }
+}
+
 /// Send INIT via x2APIC MSR ICR (0x830) to target APIC ID.
 fn send_init_x2apic(apic_id: u32) {
     // x2APIC ICR is 64-bit: [63:32] dest, [31:0] low with delivery mode/shorthand
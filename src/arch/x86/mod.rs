@@ -1,11 +1,18 @@
 //! x86_64 specific code paths.
 
 pub mod cpuid;
+pub mod cc;
 pub mod msr;
+pub mod port;
+pub mod uart;
+pub mod rapl;
+pub mod pstate;
+pub mod thermal;
 pub mod vm;
 pub mod smp;
 pub mod lapic;
 pub mod trampoline;
 pub mod idt;
+pub mod ap;
 
 
@@ -0,0 +1,183 @@
+#![allow(dead_code)]
+
+//! CPU performance-state accessors.
+//!
+//! Three interfaces are handled: Intel HWP (hardware P-states, requested
+//! through `IA32_HWP_REQUEST` once `IA32_PM_ENABLE` is set), Intel EIST
+//! (a core ratio written to `IA32_PERF_CTL`) and AMD hardware P-states
+//! (`P0` fastest, selected through the P-state control MSR). Callers work
+//! in levels 0..=100, from the slowest to the fastest state the part
+//! allows; `Caps::encode` turns a level into the raw control value. The
+//! control MSRs are per logical CPU, so `write` must run on the CPU being
+//! changed; `job_write` is the AP form.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::cpuid::{cpuid, leaf};
+use super::msr::{rdmsr, wrmsr};
+
+pub const MSR_PLATFORM_INFO: u32 = 0xCE;
+pub const IA32_PERF_STATUS: u32 = 0x198;
+pub const IA32_PERF_CTL: u32 = 0x199;
+pub const IA32_MISC_ENABLE: u32 = 0x1A0;
+pub const IA32_PM_ENABLE: u32 = 0x770;
+pub const IA32_HWP_CAPABILITIES: u32 = 0x771;
+pub const IA32_HWP_REQUEST: u32 = 0x774;
+pub const MSR_AMD_PSTATE_LIMIT: u32 = 0xC001_0061;
+pub const MSR_AMD_PSTATE_CTL: u32 = 0xC001_0062;
+pub const MSR_AMD_PSTATE_STATUS: u32 = 0xC001_0063;
+
+/// `IA32_MISC_ENABLE` bit 16: Enhanced SpeedStep enabled
+const MISC_EIST: u64 = 1 << 16;
+const HWP_MIN_SHIFT: u32 = 0;
+const HWP_MAX_SHIFT: u32 = 8;
+const HWP_DESIRED_SHIFT: u32 = 16;
+const HWP_EPP_SHIFT: u32 = 24;
+/// Intel core ratios are multiples of the 100 MHz bus clock
+const BUS_MHZ: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind { None, IntelHwp, IntelEist, Amd }
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self { Kind::None => "none", Kind::IntelHwp => "intel-hwp", Kind::IntelEist => "intel-eist", Kind::Amd => "amd" }
+    }
+    fn code(self) -> u8 { match self { Kind::None => 0, Kind::IntelHwp => 1, Kind::IntelEist => 2, Kind::Amd => 3 } }
+    fn from_code(c: u8) -> Kind { match c { 1 => Kind::IntelHwp, 2 => Kind::IntelEist, 3 => Kind::Amd, _ => Kind::None } }
+    fn ctl_msr(self) -> Option<u32> {
+        match self { Kind::IntelHwp => Some(IA32_HWP_REQUEST), Kind::IntelEist => Some(IA32_PERF_CTL), Kind::Amd => Some(MSR_AMD_PSTATE_CTL), Kind::None => None }
+    }
+}
+
+/// Interface `write` uses, set by `probe`.
+static KIND: AtomicU8 = AtomicU8::new(0);
+
+pub fn detect() -> Kind {
+    let v = cpuid(0, 0);
+    let mut s = [0u8; 12];
+    s[0..4].copy_from_slice(&v.ebx.to_le_bytes());
+    s[4..8].copy_from_slice(&v.edx.to_le_bytes());
+    s[8..12].copy_from_slice(&v.ecx.to_le_bytes());
+    match &s {
+        b"GenuineIntel" => {
+            // CPUID.06H:EAX[7] = HWP; CPUID.01H:ECX[7] = EIST
+            if v.eax >= 6 && (cpuid(6, 0).eax & (1 << 7)) != 0 { return Kind::IntelHwp; }
+            if (cpuid(leaf::BASIC_FEATURES, 0).ecx & (1 << 7)) != 0 && (unsafe { rdmsr(IA32_MISC_ENABLE) } & MISC_EIST) != 0 { Kind::IntelEist } else { Kind::None }
+        }
+        b"AuthenticAMD" | b"HygonGenuine" => {
+            // CPUID.80000007:EDX[7] = hardware P-state control
+            if cpuid(0x8000_0000, 0).eax >= leaf::AMD_APM && (cpuid(leaf::AMD_APM, 0).edx & (1 << 7)) != 0 { Kind::Amd } else { Kind::None }
+        }
+        _ => Kind::None,
+    }
+}
+
+/// Performance range of the part.
+#[derive(Clone, Copy, Debug)]
+pub struct Caps {
+    pub kind: Kind,
+    /// Slowest state: lowest HWP performance or core ratio, or the highest
+    /// AMD P-state number
+    pub lowest: u8,
+    /// Fastest state without turbo: guaranteed HWP performance or maximum
+    /// non-turbo ratio, or AMD's current P-state limit
+    pub highest: u8,
+    /// Highest HWP performance or ratio including turbo (`highest` on AMD)
+    pub turbo: u8,
+    /// HWP energy/performance preference is supported
+    pub epp: bool,
+}
+
+/// Detect the interface and read its range.
+pub fn probe() -> Option<Caps> {
+    let kind = detect();
+    let caps = match kind {
+        Kind::None => return None,
+        Kind::IntelHwp => {
+            let c = unsafe { rdmsr(IA32_HWP_CAPABILITIES) };
+            Caps { kind, lowest: (c >> 24) as u8, highest: (c >> 8) as u8, turbo: c as u8, epp: (cpuid(6, 0).eax & (1 << 10)) != 0 }
+        }
+        Kind::IntelEist => {
+            let p = unsafe { rdmsr(MSR_PLATFORM_INFO) };
+            let max = (p >> 8) as u8;
+            Caps { kind, lowest: ((p >> 40) as u8).min(max), highest: max, turbo: max, epp: false }
+        }
+        Kind::Amd => {
+            let l = unsafe { rdmsr(MSR_AMD_PSTATE_LIMIT) };
+            let cur_limit = (l & 0x7) as u8;
+            let max_val = ((l >> 4) & 0x7) as u8;
+            Caps { kind, lowest: max_val.max(cur_limit), highest: cur_limit, turbo: cur_limit, epp: false }
+        }
+    };
+    if caps.kind != Kind::Amd && (caps.highest == 0 || caps.lowest > caps.highest) { return None; }
+    KIND.store(kind.code(), Ordering::Relaxed);
+    Some(caps)
+}
+
+/// Hand P-state selection to the interface `probe` found. For HWP this sets
+/// `IA32_PM_ENABLE`, which cannot be undone until reset; the others need
+/// nothing.
+pub fn enable() {
+    if Kind::from_code(KIND.load(Ordering::Relaxed)) == Kind::IntelHwp {
+        unsafe { if rdmsr(IA32_PM_ENABLE) & 1 == 0 { wrmsr(IA32_PM_ENABLE, 1); } }
+    }
+}
+
+impl Caps {
+    /// Hardware state for `level` (0 slowest, 100 fastest).
+    pub fn state(&self, level: u8) -> u8 {
+        let level = level.min(100) as u32;
+        match self.kind {
+            // Higher P-state numbers are slower.
+            Kind::Amd => self.lowest - ((self.lowest - self.highest) as u32 * level / 100) as u8,
+            _ => self.lowest + ((self.highest - self.lowest) as u32 * level / 100) as u8,
+        }
+    }
+
+    /// Raw control-MSR value for `level`. `epp` (0 favours performance, 255
+    /// energy) applies to HWP only.
+    pub fn encode(&self, level: u8, epp: u8) -> u64 {
+        let st = self.state(level) as u64;
+        match self.kind {
+            Kind::IntelHwp => {
+                // Let the hardware go from lowest up to the chosen ceiling
+                // (turbo at full level) and aim for the chosen point.
+                let max = if level >= 100 { self.turbo as u64 } else { st };
+                let mut v = (self.lowest as u64) << HWP_MIN_SHIFT | max << HWP_MAX_SHIFT | st << HWP_DESIRED_SHIFT;
+                if self.epp { v |= (epp as u64) << HWP_EPP_SHIFT; }
+                v
+            }
+            Kind::IntelEist => st << 8,
+            Kind::Amd => st,
+            Kind::None => 0,
+        }
+    }
+
+    /// Approximate core clock of `state` in MHz (Intel only).
+    pub fn mhz(&self, state: u8) -> Option<u32> {
+        match self.kind { Kind::IntelEist | Kind::IntelHwp => Some(state as u32 * BUS_MHZ), _ => None }
+    }
+}
+
+/// Write a control value from `Caps::encode` on this CPU.
+pub fn write(raw: u64) {
+    if let Some(msr) = Kind::from_code(KIND.load(Ordering::Relaxed)).ctl_msr() { unsafe { wrmsr(msr, raw) } }
+}
+
+/// State this CPU is running at: the current ratio (EIST), the requested
+/// desired performance (HWP) or the current P-state (AMD).
+pub fn current() -> Option<u8> {
+    match Kind::from_code(KIND.load(Ordering::Relaxed)) {
+        Kind::IntelHwp => Some((unsafe { rdmsr(IA32_HWP_REQUEST) } >> HWP_DESIRED_SHIFT) as u8),
+        Kind::IntelEist => Some((unsafe { rdmsr(IA32_PERF_STATUS) } >> 8) as u8),
+        Kind::Amd => Some((unsafe { rdmsr(MSR_AMD_PSTATE_STATUS) } & 0x7) as u8),
+        Kind::None => None,
+    }
+}
+
+/// AP job: write `raw` and return the resulting state.
+pub fn job_write(raw: u64) -> u64 {
+    write(raw);
+    current().map_or(u64::MAX, |s| s as u64)
+}
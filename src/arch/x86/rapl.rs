@@ -0,0 +1,152 @@
+#![allow(dead_code)]
+
+//! RAPL (Running Average Power Limit) package accessors.
+//!
+//! Intel exposes package energy, TDP and a programmable PL1 limit through
+//! `MSR_PKG_*`. AMD (Zen) exposes package energy only; there is no public MSR
+//! to program a package limit, so capping on AMD is software-only. Intel has
+//! no CPUID bit for RAPL, so support is inferred from the CPU model and all
+//! MSR accesses are gated on detection to avoid #GP on other parts.
+
+use super::cpuid::{cpuid, leaf};
+use super::msr::{rdmsr, wrmsr};
+
+pub const MSR_RAPL_POWER_UNIT: u32 = 0x606;
+pub const MSR_PKG_POWER_LIMIT: u32 = 0x610;
+pub const MSR_PKG_ENERGY_STATUS: u32 = 0x611;
+pub const MSR_PKG_POWER_INFO: u32 = 0x614;
+pub const MSR_AMD_RAPL_POWER_UNIT: u32 = 0xC001_0299;
+pub const MSR_AMD_PKG_ENERGY_STATUS: u32 = 0xC001_029B;
+
+const PL1_POWER_MASK: u64 = 0x7FFF;
+const PL1_ENABLE: u64 = 1 << 15;
+const PL1_CLAMP: u64 = 1 << 16;
+const PL1_WINDOW_SHIFT: u32 = 17;
+const PL1_WINDOW_MASK: u64 = 0x7F << PL1_WINDOW_SHIFT;
+const PKG_LIMIT_LOCK: u64 = 1 << 63;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind { None, Intel, Amd }
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self { Kind::None => "none", Kind::Intel => "intel", Kind::Amd => "amd" }
+    }
+    /// Package limit can be programmed in hardware
+    pub fn can_limit(self) -> bool { self == Kind::Intel }
+}
+
+/// Family 6 models with package RAPL (Sandy Bridge and later).
+const INTEL_MODELS: &[u8] = &[
+    0x2A, 0x2D, 0x3A, 0x3C, 0x3E, 0x3F, 0x45, 0x46, 0x47, 0x4F, 0x56, 0x4E, 0x5E, 0x55,
+    0x8E, 0x9E, 0x66, 0x6A, 0x6C, 0x7D, 0x7E, 0x8C, 0x8D, 0x8F, 0x97, 0x9A, 0xA5, 0xA6,
+    0xAA, 0xAC, 0xB7, 0xBA, 0xBF, 0xCF, 0x37, 0x4C, 0x4D, 0x5A, 0x5C, 0x5F, 0x7A, 0x86, 0x96, 0x9C,
+];
+
+pub fn detect() -> Kind {
+    let v = cpuid(0, 0);
+    let mut s = [0u8; 12];
+    s[0..4].copy_from_slice(&v.ebx.to_le_bytes());
+    s[4..8].copy_from_slice(&v.edx.to_le_bytes());
+    s[8..12].copy_from_slice(&v.ecx.to_le_bytes());
+    match &s {
+        b"GenuineIntel" => {
+            let sig = cpuid(leaf::BASIC_FEATURES, 0).eax;
+            let family = (sig >> 8) & 0xF;
+            let model = (((sig >> 16) & 0xF) << 4 | ((sig >> 4) & 0xF)) as u8;
+            if family == 6 && INTEL_MODELS.contains(&model) { Kind::Intel } else { Kind::None }
+        }
+        b"AuthenticAMD" | b"HygonGenuine" => {
+            // CPUID.80000007:EDX[14] = RAPL
+            if cpuid(0x8000_0000, 0).eax >= leaf::AMD_APM && (cpuid(leaf::AMD_APM, 0).edx & (1 << 14)) != 0 { Kind::Amd } else { Kind::None }
+        }
+        _ => Kind::None,
+    }
+}
+
+/// Unit shifts from the power-unit MSR: one unit is `1 / 2^shift` W, J or s.
+#[derive(Clone, Copy, Debug)]
+pub struct Units { pub power_shift: u8, pub energy_shift: u8, pub time_shift: u8 }
+
+pub fn units(kind: Kind) -> Option<Units> {
+    let msr = match kind { Kind::Intel => MSR_RAPL_POWER_UNIT, Kind::Amd => MSR_AMD_RAPL_POWER_UNIT, Kind::None => return None };
+    let v = unsafe { rdmsr(msr) };
+    Some(Units { power_shift: (v & 0xF) as u8, energy_shift: ((v >> 8) & 0x1F) as u8, time_shift: ((v >> 16) & 0xF) as u8 })
+}
+
+/// Raw 32-bit package energy counter (wraps).
+pub fn pkg_energy_raw(kind: Kind) -> Option<u32> {
+    let msr = match kind { Kind::Intel => MSR_PKG_ENERGY_STATUS, Kind::Amd => MSR_AMD_PKG_ENERGY_STATUS, Kind::None => return None };
+    Some(unsafe { rdmsr(msr) } as u32)
+}
+
+/// Convert a raw energy-counter delta to microjoules.
+pub fn energy_to_uj(u: Units, raw: u64) -> u64 { ((raw as u128 * 1_000_000) >> u.energy_shift) as u64 }
+
+fn power_to_mw(u: Units, raw: u64) -> u64 { ((raw as u128 * 1000) >> u.power_shift) as u64 }
+fn mw_to_power(u: Units, mw: u64) -> u64 { ((((mw as u128) << u.power_shift) / 1000) as u64).min(PL1_POWER_MASK) }
+
+/// Thermal design power of the package in milliwatts (Intel only).
+pub fn tdp_mw(kind: Kind, u: Units) -> Option<u64> {
+    if kind != Kind::Intel { return None; }
+    let v = unsafe { rdmsr(MSR_PKG_POWER_INFO) };
+    Some(power_to_mw(u, v & PL1_POWER_MASK))
+}
+
+/// Decoded PL1 (long-term) package limit.
+#[derive(Clone, Copy, Debug)]
+pub struct Pl1 { pub mw: u64, pub window_us: u64, pub enabled: bool, pub clamp: bool, pub locked: bool }
+
+fn window_to_us(u: Units, field: u64) -> u64 {
+    // 2^Y * (1 + Z/4) time units; Y = bits 4:0, Z = bits 6:5 of the field
+    let y = (field & 0x1F) as u32;
+    let z = (field >> 5) & 0x3;
+    let units = ((1u128 << y) * (4 + z as u128)) / 4;
+    ((units * 1_000_000) >> u.time_shift) as u64
+}
+
+/// Largest encodable window not above `us` (at least the smallest encoding).
+fn us_to_window(u: Units, us: u64) -> u64 {
+    let mut best = 0u64;
+    for y in 0..32u64 {
+        for z in 0..4u64 {
+            let f = (z << 5) | y;
+            if window_to_us(u, f) <= us && window_to_us(u, f) >= window_to_us(u, best) { best = f; }
+        }
+    }
+    best
+}
+
+/// Raw `MSR_PKG_POWER_LIMIT` value, for saving and restoring.
+pub fn pkg_limit_raw(kind: Kind) -> Option<u64> {
+    if kind != Kind::Intel { return None; }
+    Some(unsafe { rdmsr(MSR_PKG_POWER_LIMIT) })
+}
+
+pub fn read_pl1(kind: Kind, u: Units) -> Option<Pl1> {
+    let v = pkg_limit_raw(kind)?;
+    Some(Pl1 {
+        mw: power_to_mw(u, v & PL1_POWER_MASK),
+        window_us: window_to_us(u, (v & PL1_WINDOW_MASK) >> PL1_WINDOW_SHIFT),
+        enabled: v & PL1_ENABLE != 0,
+        clamp: v & PL1_CLAMP != 0,
+        locked: v & PKG_LIMIT_LOCK != 0,
+    })
+}
+
+/// Program and enable PL1, keeping PL2 (upper half) untouched.
+pub fn write_pl1(kind: Kind, u: Units, mw: u64, window_us: u64) -> Result<(), &'static str> {
+    let v = pkg_limit_raw(kind).ok_or("rapl: package limit not supported")?;
+    if v & PKG_LIMIT_LOCK != 0 { return Err("rapl: package limit locked by firmware"); }
+    let low = mw_to_power(u, mw) | PL1_ENABLE | PL1_CLAMP | (us_to_window(u, window_us) << PL1_WINDOW_SHIFT);
+    unsafe { wrmsr(MSR_PKG_POWER_LIMIT, (v & !0xFFFF_FFFF) | low) };
+    Ok(())
+}
+
+/// Restore a value previously read with `pkg_limit_raw`.
+pub fn restore_pkg_limit(kind: Kind, raw: u64) -> Result<(), &'static str> {
+    let v = pkg_limit_raw(kind).ok_or("rapl: package limit not supported")?;
+    if v & PKG_LIMIT_LOCK != 0 { return Err("rapl: package limit locked by firmware"); }
+    unsafe { wrmsr(MSR_PKG_POWER_LIMIT, raw & !PKG_LIMIT_LOCK) };
+    Ok(())
+}
@@ -51,6 +51,9 @@ pub fn start_aps_init_sipi(system_table: &SystemTable<Boot>, lapic_base: usize,
     }
 }
 
+/// Per-AP stack size in pages
+const AP_STACK_PAGES: usize = 4;
+
 /// Prepare paging for APs and write CR3 value into a shared mailbox area.
 /// For now, we colocate CR3 value right after the counter (offset + 2).
 pub fn write_ap_cr3_mailbox(system_table: &SystemTable<Boot>, trampoline_phys_page: u64, limit_bytes: u64) {
@@ -64,10 +67,11 @@ pub fn write_ap_cr3_mailbox(system_table: &SystemTable<Boot>, trampoline_phys_pa
             core::ptr::write_volatile((trampoline_phys_page as usize + 0x819) as *mut u8, 0u8);
             core::ptr::write_volatile((trampoline_phys_page as usize + 0x81A) as *mut u16, 0u16);
         }
-        // Allocate stacks array for up to 64 APs: write RSP entries at mailbox+64+(idx*8)
+        // Allocate stacks array for up to 64 APs: write RSP entries at mailbox+64+(idx*8).
+        // APs run Rust code (the `ap` idle loop and dispatched jobs) on these.
         for i in 0..64u32 {
-            if let Some(stack) = crate::mm::uefi::alloc_pages(system_table, 1, uefi::table::boot::MemoryType::LOADER_DATA) {
-                let rsp = unsafe { stack.add(4096) } as u64;
+            if let Some(stack) = crate::mm::uefi::alloc_pages(system_table, AP_STACK_PAGES, uefi::table::boot::MemoryType::LOADER_DATA) {
+                let rsp = unsafe { stack.add(AP_STACK_PAGES * 4096) } as u64;
                 let rsp_ptr = (trampoline_phys_page as usize + 0x840 + (i as usize) * 8) as *mut u64;
                 unsafe { core::ptr::write_volatile(rsp_ptr, rsp); }
             } else {
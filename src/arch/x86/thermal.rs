@@ -0,0 +1,138 @@
+#![allow(dead_code)]
+
+//! Package temperature sensors.
+//!
+//! Intel reports the package temperature as a distance below TjMax in
+//! `IA32_PACKAGE_THERM_STATUS` (CPUID.06H:EAX[6]); parts without package
+//! thermal management fall back to the core sensor of the CPU reading it.
+//! AMD family 17h and later expose Tctl through the SMN index/data pair of
+//! the root complex at 00:00.0, which covers the first socket only. Intel
+//! readings are of the package the calling CPU belongs to, so other packages
+//! are read by dispatching `job_read` to one of their CPUs.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::cpuid::{cpuid, leaf};
+use super::msr::rdmsr;
+use super::port::{inl, outl};
+
+pub const IA32_THERM_STATUS: u32 = 0x19C;
+pub const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+pub const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+
+/// Digital readout is valid
+const THERM_READING_VALID: u64 = 1 << 31;
+const PCI_CFG_ADDR: u16 = 0xCF8;
+const PCI_CFG_DATA: u16 = 0xCFC;
+/// Root complex SMN index and data registers
+const SMN_INDEX: u32 = 0x60;
+const SMN_DATA: u32 = 0x64;
+const SMN_THM_TCTL: u32 = 0x0005_9800;
+/// Tctl is reported with a 49 C offset when this range bit is set
+const TCTL_RANGE_SEL: u32 = 1 << 19;
+const DEFAULT_TJMAX: u8 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind { None, IntelPackage, IntelCore, Amd }
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self { Kind::None => "none", Kind::IntelPackage => "intel-pkg", Kind::IntelCore => "intel-core", Kind::Amd => "amd-tctl" }
+    }
+    fn code(self) -> u8 { match self { Kind::None => 0, Kind::IntelPackage => 1, Kind::IntelCore => 2, Kind::Amd => 3 } }
+    fn from_code(c: u8) -> Kind { match c { 1 => Kind::IntelPackage, 2 => Kind::IntelCore, 3 => Kind::Amd, _ => Kind::None } }
+}
+
+/// Sensor `read` uses, set by `probe`.
+static KIND: AtomicU8 = AtomicU8::new(0);
+
+pub fn detect() -> Kind {
+    let v = cpuid(0, 0);
+    let mut s = [0u8; 12];
+    s[0..4].copy_from_slice(&v.ebx.to_le_bytes());
+    s[4..8].copy_from_slice(&v.edx.to_le_bytes());
+    s[8..12].copy_from_slice(&v.ecx.to_le_bytes());
+    match &s {
+        b"GenuineIntel" if v.eax >= 6 => {
+            // CPUID.06H:EAX[6] = package thermal management, EAX[0] = core DTS
+            let pm = cpuid(6, 0).eax;
+            if pm & (1 << 6) != 0 { Kind::IntelPackage } else if pm & 1 != 0 { Kind::IntelCore } else { Kind::None }
+        }
+        b"AuthenticAMD" | b"HygonGenuine" => {
+            let sig = cpuid(leaf::BASIC_FEATURES, 0).eax;
+            let family = ((sig >> 8) & 0xF) + ((sig >> 20) & 0xFF);
+            if family >= 0x17 { Kind::Amd } else { Kind::None }
+        }
+        _ => Kind::None,
+    }
+}
+
+/// Detect the sensor and remember it for `read`.
+pub fn probe() -> Kind {
+    let k = detect();
+    KIND.store(k.code(), Ordering::Relaxed);
+    k
+}
+
+/// Temperature at which the CPU throttles itself, in C (Intel).
+pub fn tjmax() -> Option<u8> {
+    match Kind::from_code(KIND.load(Ordering::Relaxed)) {
+        Kind::IntelPackage | Kind::IntelCore => {
+            let t = ((unsafe { rdmsr(MSR_TEMPERATURE_TARGET) } >> 16) & 0xFF) as u8;
+            Some(if t == 0 { DEFAULT_TJMAX } else { t })
+        }
+        _ => None,
+    }
+}
+
+fn smn_read(addr: u32) -> u32 {
+    let cfg = |off: u32| 0x8000_0000u32 | (off & 0xFC);
+    unsafe {
+        outl(PCI_CFG_ADDR, cfg(SMN_INDEX));
+        outl(PCI_CFG_DATA, addr);
+        outl(PCI_CFG_ADDR, cfg(SMN_DATA));
+        inl(PCI_CFG_DATA)
+    }
+}
+
+/// Temperature of this CPU's package in C, if the sensor has a reading.
+pub fn read() -> Option<i32> {
+    match Kind::from_code(KIND.load(Ordering::Relaxed)) {
+        k @ (Kind::IntelPackage | Kind::IntelCore) => {
+            let msr = if k == Kind::IntelPackage { IA32_PACKAGE_THERM_STATUS } else { IA32_THERM_STATUS };
+            let v = unsafe { rdmsr(msr) };
+            if k == Kind::IntelCore && v & THERM_READING_VALID == 0 { return None; }
+            let below = ((v >> 16) & 0x7F) as i32;
+            Some(tjmax()? as i32 - below)
+        }
+        Kind::Amd => {
+            let v = smn_read(SMN_THM_TCTL);
+            // 11-bit reading in 0.125 C steps
+            let c = ((v >> 21) * 125 / 1000) as i32;
+            Some(if v & TCTL_RANGE_SEL != 0 { c - 49 } else { c })
+        }
+        Kind::None => None,
+    }
+}
+
+/// Right shift turning an x2APIC/APIC ID into a package number.
+pub fn package_shift() -> u32 {
+    if cpuid(0, 0).eax >= 0xB {
+        // Leaf 0BH: each subleaf gives the shift to the next level up; the
+        // last one with a non-zero type is the core level.
+        let mut shift = 0;
+        for sub in 0..8 {
+            let r = cpuid(0xB, sub);
+            if (r.ecx >> 8) & 0xFF == 0 { break; }
+            shift = r.eax & 0x1F;
+        }
+        if shift != 0 { return shift; }
+    }
+    let logical = (cpuid(leaf::BASIC_FEATURES, 0).ebx >> 16) & 0xFF;
+    32 - logical.max(1).saturating_sub(1).leading_zeros()
+}
+
+/// AP job: this CPU's package temperature, or `u64::MAX` without a reading.
+pub fn job_read(_: u64) -> u64 {
+    read().map_or(u64::MAX, |t| t as u32 as u64)
+}
@@ -5,8 +5,9 @@
 //! This module constructs a tiny 16-bit code sequence placed at a 4KiB-aligned
 //! physical page (preferably below 1MiB) so that APs started by SIPI begin
 //! execution at that page base. The code increments a mailbox counter located
-//! within the same page and halts, enabling the BSP to observe successful AP
-//! wakeups without requiring protected/long mode transitions yet.
+//! within the same page, walks the AP through protected mode into long mode
+//! on its own stack, handshakes with the BSP and then calls the runtime entry
+//! point left in the mailbox (see `ap::install`).
 
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
@@ -20,25 +21,26 @@ pub struct TrampolineInfo {
 }
 
 /// Build 16-bit real-mode bootstrap that switches to 32-bit protected mode,
-/// updates a mailbox counter and success flag, then halts.
-fn build_pm_trampoline(mailbox_off: u16, gdtr_off: u16, pm_entry_off: u16, lm_entry_off: u16, out: &mut [u8]) -> usize {
+/// updates a mailbox counter and success flag, then enters long mode.
+///
+/// The GDT descriptors have base 0, so everything past the real-mode part
+/// addresses the page by its physical address `page`, not by offset.
+fn build_pm_trampoline(page: u32, mailbox_off: u16, gdtr_off: u16, lm_entry_off: u16, out: &mut [u8]) -> usize {
     // 16-bit code:
     //   cli
     //   push cs; pop ds
     //   lgdt [gdtr]
     //   mov eax, cr0; or eax, 1; mov cr0, eax    ; enable PE
-    //   jmp 0x08:pm_entry
-    // pm_entry:
+    //   jmp dword 0x08:page+pm_entry
+    // pm_entry (32-bit):
     //   mov ax, 0x10; mov ds, ax; mov es, ax; mov ss, ax
-    //   mov ebx, mailbox_off; mov ax, [bx]; inc ax; mov [bx], ax
-    //   mov byte [bx+4], 1
+    //   mov ebx, page+mailbox_off; mov ax, [ebx]; inc ax; mov [ebx], ax
+    //   mov byte [ebx+4], 1
     //   mov eax, [ebx+2]; mov cr3, eax             ; load AP CR3 (low 32 bits)
     //   mov eax, cr4; or eax, 0x20; mov cr4, eax   ; set PAE
     //   mov ecx, 0xC0000080; rdmsr; or eax, 0x100; wrmsr  ; set EFER.LME
     //   mov eax, cr0; or eax, 0x80000000; mov cr0, eax    ; enable PG
-    //   jmp 0x18:lm_entry
-    // lm_entry (64-bit):
-    //   mov byte [rip+disp32_to_mailbox_plus_5], 1; hlt; jmp $
+    //   jmp 0x18:page+lm_entry
     let mut n = 0usize;
     let emit = |buf: &mut [u8], n: &mut usize, bytes: &[u8]| { for &b in bytes { buf[*n] = b; *n += 1; } };
     // cli; push cs; pop ds
@@ -51,19 +53,23 @@ fn build_pm_trampoline(mailbox_off: u16, gdtr_off: u16, pm_entry_off: u16, lm_en
     emit(out, &mut n, &[0x66, 0x83, 0xC8, 0x01]);
     // mov cr0, eax
     emit(out, &mut n, &[0x66, 0x0F, 0x22, 0xC0]);
-    // far jmp 0x08:pm_entry
-    emit(out, &mut n, &[0xEA, (pm_entry_off & 0xFF) as u8, (pm_entry_off >> 8) as u8, 0x08, 0x00]);
+    // far jmp dword 0x08:pm_entry (operand-size prefix for the 32-bit offset)
+    let pm_entry = page + n as u32 + 8;
+    emit(out, &mut n, &[0x66, 0xEA]);
+    emit(out, &mut n, &pm_entry.to_le_bytes());
+    emit(out, &mut n, &[0x08, 0x00]);
     // pm_entry label starts here
     // mov ax, 0x10
-    emit(out, &mut n, &[0xB8, 0x10, 0x00]);
+    emit(out, &mut n, &[0x66, 0xB8, 0x10, 0x00]);
     // mov ds, ax; mov es, ax; mov ss, ax
     emit(out, &mut n, &[0x8E, 0xD8, 0x8E, 0xC0, 0x8E, 0xD0]);
-    // mov ebx, mailbox_off
-    emit(out, &mut n, &[0xBB, (mailbox_off as u32 & 0xFF) as u8, ((mailbox_off as u32 >> 8) & 0xFF) as u8, 0x00, 0x00]);
-    // mov ax, [bx]; inc ax; mov [bx], ax
+    // mov ebx, page + mailbox_off
+    emit(out, &mut n, &[0xBB]);
+    emit(out, &mut n, &(page + mailbox_off as u32).to_le_bytes());
+    // mov ax, [ebx]; inc ax; mov [ebx], ax
     emit(out, &mut n, &[0x66, 0x8B, 0x03, 0x66, 0x40, 0x66, 0x89, 0x03]);
-    // mov byte [bx+4], 1
-    emit(out, &mut n, &[0x80, 0x47, 0x04, 0x01]);
+    // mov byte [ebx+4], 1
+    emit(out, &mut n, &[0xC6, 0x43, 0x04, 0x01]);
     // mov eax, [ebx+2]
     emit(out, &mut n, &[0x8B, 0x43, 0x02]);
     // mov cr3, eax
@@ -88,10 +94,10 @@ fn build_pm_trampoline(mailbox_off: u16, gdtr_off: u16, pm_entry_off: u16, lm_en
     emit(out, &mut n, &[0x0D, 0x00, 0x00, 0x00, 0x80]);
     // mov cr0, eax
     emit(out, &mut n, &[0x0F, 0x22, 0xC0]);
-    // far jmp 0x18:lm_entry_off (32-bit offset)
-    emit(out, &mut n, &[0xEA,
-        (lm_entry_off & 0xFF) as u8, (lm_entry_off >> 8) as u8, 0x00, 0x00,
-        0x18, 0x00]);
+    // far jmp 0x18:lm_entry (32-bit offset)
+    emit(out, &mut n, &[0xEA]);
+    emit(out, &mut n, &(page + lm_entry_off as u32).to_le_bytes());
+    emit(out, &mut n, &[0x18, 0x00]);
     // hlt; jmp $
     emit(out, &mut n, &[0xF4, 0xEB, 0xFE]);
     n
@@ -108,17 +114,16 @@ pub fn prepare_real_mode_trampoline(system_table: &SystemTable<Boot>) -> Option<
     // Choose GDTR and GDT placement inside the page.
     let gdtr_off: u16 = 0x08E0;
     let gdt_off: u16 = 0x0900;
-    // The protected-mode entry point offset inside the page
-    let pm_entry_off: u16 = 0x0020; // after initial prologue and far jump target
     // Zero page and set mailbox to 0
     unsafe {
         core::ptr::write_bytes(page, 0, 4096);
         core::ptr::write_volatile(page.add(mailbox_off as usize) as *mut u16, 0u16);
     }
     // Build and write the 16-bit/pm bootstrap code at page base.
-    let lm_entry_off: u16 = 0x0060;
+    // Long-mode entry follows the real/protected-mode code (~110 bytes).
+    let lm_entry_off: u16 = 0x0080;
     let mut buf = [0u8; 192];
-    let code_len = build_pm_trampoline(mailbox_off, gdtr_off, pm_entry_off, lm_entry_off, &mut buf);
+    let code_len = build_pm_trampoline(page as u32, mailbox_off, gdtr_off, lm_entry_off, &mut buf);
     unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), page as *mut u8, code_len); }
     // Write GDTR structure (limit + base)
     let gdt_base = (page as u64).wrapping_add(gdt_off as u64);
@@ -172,28 +177,36 @@ pub fn prepare_real_mode_trampoline(system_table: &SystemTable<Boot>) -> Option<
     //   mov eax, 1; cpuid; shr ebx, 24; mov byte [rip+disp32_to_mailbox_plus_8], bl
     //   movzx ecx, word [rip+disp32_to_mailbox_plus_6]; sub ecx, 1
     //   lea rdx, [rip+disp32_to_mailbox_plus_32]; mov [rdx+rcx], bl
-    //   mov rsp, qword [rip+disp32_to_mailbox_plus_16]
+    //   lea rsi, [rip+disp32_to_mailbox_plus_64]; mov rsp, [rsi+rcx*8]
+    //   wait for GO, set READY, inc READY_CNT
+    //   mov edi, ecx; movzx esi, bl; mov rax, [rip+disp32_to_mailbox_plus_16]
+    //   test rax, rax; je halt; call rax    ; ap_entry(index, apic_id), sysv64
+    // halt:
     //   hlt
     //   jmp $
     let lm_ptr = unsafe { page.add(lm_entry_off as usize) } as *mut u8;
-    let disp5 = (mailbox_off as i32 + 5) - (lm_entry_off as i32 + 6);
-    let disp6 = (mailbox_off as i32 + 6) - (lm_entry_off as i32 + 7 + 6); // after 66 FF 05 disp32 (7 bytes ahead)
-    // After the CPUID sequence, the RIP-relative displacement base advances accordingly.
-    // MOV byte [RIP+disp32] after CPUID+SHR uses RIP at offset 27+? We'll compute with exact offsets below.
-    // For MOV byte [RIP+disp32], RIP points to the next instruction (offset 27+3=30)
+    // RIP-relative operands are relative to the end of the instruction,
+    // immediates included.
+    // For MOV byte [RIP+disp32], imm8, RIP at next (offset 7)
+    let disp5 = (mailbox_off as i32 + 5) - (lm_entry_off as i32 + 7);
+    // For INC word [RIP+disp32], RIP at next (offset 14)
+    let disp6 = (mailbox_off as i32 + 6) - (lm_entry_off as i32 + 14);
+    // For MOV byte [RIP+disp32], BL, RIP at next (offset 30)
     let disp8 = (mailbox_off as i32 + 8) - (lm_entry_off as i32 + 30);
     // For MOVZX ECX, word [RIP+disp32], RIP points to next (offset 37)
     let disp_count = (mailbox_off as i32 + 6) - (lm_entry_off as i32 + 37);
-    // For LEA RDX, [RIP+disp32] to IDs base (mailbox+32), RIP at next (offset 44)
-    let disp_ids = (mailbox_off as i32 + 32) - (lm_entry_off as i32 + 44);
+    // For LEA RDX, [RIP+disp32] to IDs base (mailbox+32), RIP at next (offset 47)
+    let disp_ids = (mailbox_off as i32 + 32) - (lm_entry_off as i32 + 47);
     // For LEA RSI, [RIP+disp32] to RSP array base (mailbox+64), RIP at next (offset 57)
     let disp_rsp = (mailbox_off as i32 + 64) - (lm_entry_off as i32 + 57);
-    // For MOV AL, [RIP+disp32] to GO flag (mailbox+24), RIP at next (offset 70)
-    let disp_go = (mailbox_off as i32 + 24) - (lm_entry_off as i32 + 70);
-    // For MOV byte [RIP+disp32],1 to READY flag (mailbox+25), RIP at next (offset 82)
-    let disp_ready = (mailbox_off as i32 + 25) - (lm_entry_off as i32 + 82);
-    // For INC word [RIP+disp32] to READY count (mailbox+26), RIP at next (offset 89)
-    let disp_readycnt = (mailbox_off as i32 + 26) - (lm_entry_off as i32 + 89);
+    // For MOV AL, [RIP+disp32] to GO flag (mailbox+24), RIP at next (offset 67)
+    let disp_go = (mailbox_off as i32 + 24) - (lm_entry_off as i32 + 67);
+    // For MOV byte [RIP+disp32],1 to READY flag (mailbox+25), RIP at next (offset 80)
+    let disp_ready = (mailbox_off as i32 + 25) - (lm_entry_off as i32 + 80);
+    // For INC word [RIP+disp32] to READY count (mailbox+26), RIP at next (offset 87)
+    let disp_readycnt = (mailbox_off as i32 + 26) - (lm_entry_off as i32 + 87);
+    // For MOV RAX, [RIP+disp32] to the entry pointer (mailbox+16), RIP at next (offset 99)
+    let disp_entry = (mailbox_off as i32 + 16) - (lm_entry_off as i32 + 99);
     unsafe {
         // C6 05 disp32 imm8
         core::ptr::write_volatile(lm_ptr.add(0), 0xC6u8);
@@ -247,14 +260,14 @@ pub fn prepare_real_mode_trampoline(system_table: &SystemTable<Boot>) -> Option<
         core::ptr::write_volatile(lm_ptr.add(58), 0x8Bu8);
         core::ptr::write_volatile(lm_ptr.add(59), 0x24u8);
         core::ptr::write_volatile(lm_ptr.add(60), 0xCEu8);
-        // wait loop: mov al,[rip+disp_go]; test al,al; jne +5; jmp -12
+        // wait loop: mov al,[rip+disp_go]; test al,al; jne +2; jmp -12
         core::ptr::write_volatile(lm_ptr.add(61), 0x8Au8); // MOV AL, [RIP+disp32]
         core::ptr::write_volatile(lm_ptr.add(62), 0x05u8);
         core::ptr::write_volatile(lm_ptr.add(63) as *mut i32, disp_go);
         core::ptr::write_volatile(lm_ptr.add(67), 0x84u8); // TEST AL, AL
         core::ptr::write_volatile(lm_ptr.add(68), 0xC0u8);
-        core::ptr::write_volatile(lm_ptr.add(69), 0x75u8); // JNE +5
-        core::ptr::write_volatile(lm_ptr.add(70), 0x05u8);
+        core::ptr::write_volatile(lm_ptr.add(69), 0x75u8); // JNE +2
+        core::ptr::write_volatile(lm_ptr.add(70), 0x02u8);
         core::ptr::write_volatile(lm_ptr.add(71), 0xEBu8); // JMP -12
         core::ptr::write_volatile(lm_ptr.add(72), 0xF4u8);
         // mov byte [rip+disp_ready], 1
@@ -267,10 +280,31 @@ pub fn prepare_real_mode_trampoline(system_table: &SystemTable<Boot>) -> Option<
         core::ptr::write_volatile(lm_ptr.add(81), 0xFFu8);
         core::ptr::write_volatile(lm_ptr.add(82), 0x05u8);
         core::ptr::write_volatile(lm_ptr.add(83) as *mut i32, disp_readycnt);
-        // hlt; jmp $
-        core::ptr::write_volatile(lm_ptr.add(87), 0xF4u8);
-        core::ptr::write_volatile(lm_ptr.add(88), 0xEBu8);
-        core::ptr::write_volatile(lm_ptr.add(89), 0xFEu8);
+        // mov edi, ecx (AP index)
+        core::ptr::write_volatile(lm_ptr.add(87), 0x89u8);
+        core::ptr::write_volatile(lm_ptr.add(88), 0xCFu8);
+        // movzx esi, bl (initial APIC ID)
+        core::ptr::write_volatile(lm_ptr.add(89), 0x0Fu8);
+        core::ptr::write_volatile(lm_ptr.add(90), 0xB6u8);
+        core::ptr::write_volatile(lm_ptr.add(91), 0xF3u8);
+        // mov rax, [rip+disp_entry]
+        core::ptr::write_volatile(lm_ptr.add(92), 0x48u8);
+        core::ptr::write_volatile(lm_ptr.add(93), 0x8Bu8);
+        core::ptr::write_volatile(lm_ptr.add(94), 0x05u8);
+        core::ptr::write_volatile(lm_ptr.add(95) as *mut i32, disp_entry);
+        // test rax, rax; je +2
+        core::ptr::write_volatile(lm_ptr.add(99), 0x48u8);
+        core::ptr::write_volatile(lm_ptr.add(100), 0x85u8);
+        core::ptr::write_volatile(lm_ptr.add(101), 0xC0u8);
+        core::ptr::write_volatile(lm_ptr.add(102), 0x74u8);
+        core::ptr::write_volatile(lm_ptr.add(103), 0x02u8);
+        // call rax
+        core::ptr::write_volatile(lm_ptr.add(104), 0xFFu8);
+        core::ptr::write_volatile(lm_ptr.add(105), 0xD0u8);
+        // hlt; jmp $-1
+        core::ptr::write_volatile(lm_ptr.add(106), 0xF4u8);
+        core::ptr::write_volatile(lm_ptr.add(107), 0xEBu8);
+        core::ptr::write_volatile(lm_ptr.add(108), 0xFDu8);
     }
     // Compute SIPI vector (page number >> 12 lower 8 bits)
     let phys = page as u64;
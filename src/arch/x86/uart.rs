@@ -0,0 +1,78 @@
+#![allow(dead_code)]
+
+//! Polled 16550 UART on legacy I/O ports.
+//!
+//! No interrupts and no flow control: `init` programs the line (8N1, FIFOs
+//! on), `putc` waits a bounded time for the transmit holding register and
+//! `getc` returns a byte if one is waiting. Shared by the serial console and
+//! the GDB stub.
+
+use super::port::{inb, outb};
+
+/// Base ports of COM1-COM4
+pub const COM: [u16; 4] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+/// Input clock divided by 16; the divisor latch counts in these units
+pub const BASE_BAUD: u32 = 115_200;
+
+const THR: u16 = 0;
+const IER: u16 = 1;
+const FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const SCR: u16 = 7;
+const LCR_DLAB: u8 = 0x80;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// `com1`..`com4` or a hex I/O port base.
+pub fn parse_port(s: &str) -> Option<u16> {
+    match s {
+        "com1" => Some(COM[0]),
+        "com2" => Some(COM[1]),
+        "com3" => Some(COM[2]),
+        "com4" => Some(COM[3]),
+        _ => u16::from_str_radix(s.trim_start_matches("0x"), 16).ok(),
+    }
+}
+
+/// `com<n>` for the standard bases, else None.
+pub fn port_name(port: u16) -> Option<&'static str> {
+    COM.iter().position(|&p| p == port).map(|i| ["com1", "com2", "com3", "com4"][i])
+}
+
+/// Probe the UART at `port` and program it for `baud` 8N1.
+pub fn init(port: u16, baud: u32) -> Result<(), &'static str> {
+    if baud == 0 || baud > BASE_BAUD || BASE_BAUD % baud != 0 { return Err("uart: unsupported baud rate"); }
+    let div = (BASE_BAUD / baud) as u16;
+    unsafe {
+        // The scratch register reads back on a real 16550.
+        outb(port + SCR, 0x5A);
+        if inb(port + SCR) != 0x5A { return Err("uart: no UART at that port"); }
+        outb(port + IER, 0x00); // no interrupts
+        outb(port + LCR, LCR_DLAB);
+        outb(port + THR, div as u8);
+        outb(port + IER, (div >> 8) as u8);
+        outb(port + LCR, 0x03); // 8N1
+        outb(port + FCR, 0xC7); // FIFOs on and cleared
+        outb(port + MCR, 0x03); // DTR, RTS
+    }
+    Ok(())
+}
+
+pub fn getc(port: u16) -> Option<u8> {
+    unsafe { if (inb(port + LSR) & LSR_DATA_READY) != 0 { Some(inb(port + THR)) } else { None } }
+}
+
+pub fn putc(port: u16, b: u8) {
+    // Give up after a while rather than hang on a dead line.
+    for _ in 0..100_000 {
+        if unsafe { inb(port + LSR) } & LSR_THR_EMPTY != 0 { break; }
+        core::hint::spin_loop();
+    }
+    unsafe { outb(port + THR, b); }
+}
+
+pub fn write(port: u16, data: &[u8]) {
+    for &b in data { putc(port, b); }
+}
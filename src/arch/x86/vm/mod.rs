@@ -6,6 +6,8 @@ pub mod vmx;
 pub mod svm;
 #[cfg(any(target_arch = "x86_64"))]
 pub mod vmcs;
+#[cfg(any(target_arch = "x86_64"))]
+pub mod percpu;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Vendor {
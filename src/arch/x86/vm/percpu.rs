@@ -0,0 +1,182 @@
+#![allow(dead_code)]
+
+//! Per-CPU virtualization enablement on the application processors.
+//!
+//! Each AP gets two pages allocated by the BSP: a host page holding a copy of
+//! the BSP's GDT extended with a TSS descriptor (VM exits need a non-null host
+//! TR, which UEFI never loads) plus the TSS itself, and the VMXON region (or,
+//! on AMD, the host save area). Enabling runs as an `ap` job on the target
+//! CPU: it switches to the extended GDT, loads TR and executes VMXON (or sets
+//! EFER.SVME and VM_HSAVE_PA). The BSP itself is never put in root operation
+//! here; it keeps running the shell and UEFI services.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use uefi::table::boot::MemoryType;
+
+use crate::arch::x86::ap::{self, MAX_APS};
+use crate::arch::x86::vm::Vendor;
+use crate::util::spinlock::SpinLock;
+
+const PAGES: usize = 2;
+/// Host page layout: GDT copy at 0, TSS at `TSS_OFF`, metadata at `META_OFF`.
+const GDT_MAX: usize = 0x800;
+const TSS_OFF: usize = 0x800;
+const TSS_LEN: usize = 104;
+const META_OFF: usize = 0xFF0;
+
+const MSR_EFER: u32 = 0xC000_0080;
+const MSR_VM_CR: u32 = 0xC001_0114;
+const MSR_VM_HSAVE_PA: u32 = 0xC001_0117;
+const EFER_SVME: u64 = 1 << 12;
+const VM_CR_SVMDIS: u64 = 1 << 4;
+
+/// Root-operation state of one AP.
+#[derive(Clone, Copy, Debug)]
+pub struct HostCpu {
+    /// Physical address of the two-page block (host page, then VMXON/HSAVE)
+    pub pages: u64,
+    pub gdt_base: u64,
+    pub tr_sel: u16,
+    pub tr_base: u64,
+    pub vendor: Vendor,
+}
+
+static HOSTS: SpinLock<[Option<HostCpu>; MAX_APS]> = SpinLock::new([None; MAX_APS]);
+const NOT_ROOT: AtomicBool = AtomicBool::new(false);
+static ROOT: [AtomicBool; MAX_APS] = [NOT_ROOT; MAX_APS];
+
+#[repr(C, packed)]
+struct DescPtr { limit: u16, base: u64 }
+
+/// True once `cpu` (an AP index) runs in VMX root operation or has SVM enabled.
+pub fn is_root(cpu: usize) -> bool { cpu < MAX_APS && ROOT[cpu].load(Ordering::Acquire) }
+
+pub fn root_count() -> usize { ROOT.iter().filter(|r| r.load(Ordering::Acquire)).count() }
+
+/// Host-state parameters for VMCS setup on `cpu`.
+pub fn host(cpu: usize) -> Option<HostCpu> {
+    if !is_root(cpu) { return None; }
+    HOSTS.lock(|t| t[cpu])
+}
+
+/// Lay out the host page: GDT copy + 64-bit TSS descriptor, zeroed TSS, and
+/// the (limit, selector) pair the enabling job reads back.
+fn prepare_host_page(page: *mut u8) -> Result<(u16, u64), &'static str> {
+    let mut gdtr = DescPtr { limit: 0, base: 0 };
+    unsafe { core::arch::asm!("sgdt [{}]", in(reg) &mut gdtr, options(nostack, preserves_flags)); }
+    let len = ((gdtr.limit as usize) + 1 + 7) & !7;
+    if len + 16 > GDT_MAX { return Err("vmx: BSP GDT too large to extend"); }
+    let tss = page as u64 + TSS_OFF as u64;
+    unsafe {
+        core::ptr::write_bytes(page, 0, 4096 * PAGES);
+        core::ptr::copy_nonoverlapping(gdtr.base as *const u8, page, (gdtr.limit as usize) + 1);
+        // I/O map base past the TSS limit: no I/O permission bitmap.
+        core::ptr::write_unaligned(page.add(TSS_OFF + 102) as *mut u16, TSS_LEN as u16);
+        let lo: u64 = (TSS_LEN as u64 - 1)
+            | ((tss & 0xFF_FFFF) << 16)
+            | (0x89u64 << 40) // present, available 64-bit TSS
+            | (((tss >> 24) & 0xFF) << 56);
+        core::ptr::write_unaligned(page.add(len) as *mut u64, lo);
+        core::ptr::write_unaligned(page.add(len + 8) as *mut u64, tss >> 32);
+        core::ptr::write_unaligned(page.add(META_OFF) as *mut u16, (len + 15) as u16);
+        core::ptr::write_unaligned(page.add(META_OFF + 2) as *mut u16, len as u16);
+    }
+    Ok((len as u16, tss))
+}
+
+/// AP half of enabling: same selectors, extended GDT, then load TR.
+fn load_host_tables(pages: u64) {
+    let limit = unsafe { core::ptr::read_unaligned((pages as usize + META_OFF) as *const u16) };
+    let sel = unsafe { core::ptr::read_unaligned((pages as usize + META_OFF + 2) as *const u16) };
+    let gdtr = DescPtr { limit, base: pages };
+    unsafe {
+        core::arch::asm!("lgdt [{}]", in(reg) &gdtr, options(readonly, nostack, preserves_flags));
+        core::arch::asm!("ltr {0:x}", in(reg) sel, options(nostack, preserves_flags));
+    }
+}
+
+/// Job: VMXON on this CPU. `arg` is the two-page block; returns 0 on success.
+fn job_vmx_on(pages: u64) -> u64 {
+    load_host_tables(pages);
+    match crate::arch::x86::vm::vmx::vmxon_here(pages + 4096) { Ok(()) => 0, Err(_) => 1 }
+}
+
+/// Job: enable SVM on this CPU with the host save area in the second page.
+fn job_svm_on(pages: u64) -> u64 {
+    use crate::arch::x86::msr::{rdmsr, wrmsr};
+    load_host_tables(pages);
+    unsafe {
+        if (rdmsr(MSR_VM_CR) & VM_CR_SVMDIS) != 0 { return 2; }
+        wrmsr(MSR_EFER, rdmsr(MSR_EFER) | EFER_SVME);
+        wrmsr(MSR_VM_HSAVE_PA, pages + 4096);
+    }
+    0
+}
+
+fn job_vmx_off(_: u64) -> u64 { crate::arch::x86::vm::vmx::vmxoff_here(); 0 }
+
+fn job_svm_off(_: u64) -> u64 {
+    use crate::arch::x86::msr::{rdmsr, wrmsr};
+    unsafe { wrmsr(MSR_EFER, rdmsr(MSR_EFER) & !EFER_SVME); }
+    0
+}
+
+/// Put every online AP that is not yet enabled into root operation.
+/// Returns the number of APs now enabled.
+pub fn enable_all(system_table: &SystemTable<Boot>) -> Result<usize, &'static str> {
+    let vendor = crate::arch::x86::vm::detect_vendor();
+    let job: ap::Job = match vendor {
+        Vendor::Intel if crate::arch::x86::vm::vmx::vmx_preflight_available() => job_vmx_on,
+        Vendor::Amd if crate::arch::x86::vm::svm::svm_preflight_available() => job_svm_on,
+        _ => return Err("vmx: no hardware virtualization on this CPU"),
+    };
+    let mut first_err = None;
+    for cpu in 0..MAX_APS {
+        if !ap::is_online(cpu) || is_root(cpu) { continue; }
+        // Pages from an earlier enable are reused: the TSS there may still be loaded in TR.
+        let mem = match HOSTS.lock(|t| t[cpu].map(|h| h.pages)) {
+            Some(p) => p as *mut u8,
+            None => match crate::mm::uefi::alloc_pages(system_table, PAGES, MemoryType::LOADER_DATA) {
+                Some(p) => p,
+                None => { first_err.get_or_insert("vmx: out of memory for per-CPU regions"); break; }
+            },
+        };
+        let (tr_sel, tr_base) = match prepare_host_page(mem) {
+            Ok(v) => v,
+            Err(e) => { crate::mm::uefi::free_pages(system_table, mem, PAGES); HOSTS.lock(|t| t[cpu] = None); return Err(e); }
+        };
+        if vendor == Vendor::Intel {
+            let rev = crate::arch::x86::vm::vmx::vmcs_revision();
+            unsafe { core::ptr::write_unaligned(mem.add(4096) as *mut u32, rev); }
+        }
+        match ap::run_on(system_table, cpu, job, mem as u64, 100_000) {
+            Ok(r) => {
+                // TR now points into the host page whether or not enabling worked.
+                HOSTS.lock(|t| t[cpu] = Some(HostCpu { pages: mem as u64, gdt_base: mem as u64, tr_sel, tr_base, vendor }));
+                if r == 0 { ROOT[cpu].store(true, Ordering::Release); } else { first_err.get_or_insert("vmx: root operation refused on an AP"); }
+            }
+            // A job that timed out may still touch the pages; leave them allocated.
+            Err(e) => { first_err.get_or_insert(e); }
+        }
+    }
+    let n = root_count();
+    match first_err { Some(e) if n == 0 => Err(e), _ => Ok(n) }
+}
+
+/// Take every enabled AP out of root operation. The caller must make sure
+/// no vCPU is running on them.
+pub fn disable_all(system_table: &SystemTable<Boot>) -> usize {
+    let mut n = 0;
+    for cpu in 0..MAX_APS {
+        let Some(h) = host(cpu) else { continue };
+        let job: ap::Job = if h.vendor == Vendor::Amd { job_svm_off } else { job_vmx_off };
+        if ap::run_on(system_table, cpu, job, 0, 100_000).is_err() { continue; }
+        // TR still references the TSS in the host page, so the pages stay
+        // recorded for the next `enable_all`.
+        ROOT[cpu].store(false, Ordering::Release);
+        n += 1;
+    }
+    n
+}
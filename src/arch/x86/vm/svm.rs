@@ -4,6 +4,33 @@
 
 use crate::arch::x86::cpuid;
 
+/// VMCB TSC offset
+pub const VMCB_TSC_OFFSET: usize = 0x50;
+
+// VMCB control-area offsets used for interrupt delivery
+/// Virtual TPR / V_IRQ / priority / ignore-TPR / V_INTR_MASKING / AVIC enable
+pub const VMCB_INT_CTL: usize = 0x60;
+/// V_INTR_VECTOR (bits 7:0)
+pub const VMCB_INT_VECTOR: usize = 0x64;
+/// Interrupt shadow (bit 0)
+pub const VMCB_INT_STATE: usize = 0x68;
+/// Event injection (EVENTINJ)
+pub const VMCB_EVENTINJ: usize = 0xA8;
+/// AVIC APIC backing page pointer
+pub const VMCB_AVIC_BACKING_PAGE: usize = 0xE0;
+/// AVIC logical / physical APIC ID table pointers
+pub const VMCB_AVIC_LOGICAL_TABLE: usize = 0xF0;
+pub const VMCB_AVIC_PHYSICAL_TABLE: usize = 0xF8;
+
+pub const INT_CTL_V_IRQ: u64 = 1 << 8;
+pub const INT_CTL_V_IGN_TPR: u64 = 1 << 20;
+pub const INT_CTL_V_INTR_MASKING: u64 = 1 << 24;
+pub const INT_CTL_AVIC_ENABLE: u64 = 1 << 31;
+
+/// CPUID 0x8000000A:EDX feature bits
+pub const SVM_FEAT_AVIC: u32 = 1 << 13;
+pub const SVM_FEAT_X2AVIC: u32 = 1 << 18;
+
 /// SVM availability preflight (read-only).
 pub fn svm_preflight_available() -> bool {
     cpuid::has_svm()
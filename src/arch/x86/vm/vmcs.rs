@@ -8,6 +8,13 @@ pub const IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
 pub const IA32_VMX_PROCBASED_CTLS2: u32 = 0x48B;
 pub const IA32_VMX_EXIT_CTLS: u32 = 0x483;
 pub const IA32_VMX_ENTRY_CTLS: u32 = 0x484;
+pub const IA32_VMX_MISC: u32 = 0x485;
+/// "True" variants, valid when IA32_VMX_BASIC bit 55 is set; they allow
+/// clearing default-1 controls such as CR3-load exiting.
+pub const IA32_VMX_TRUE_PINBASED_CTLS: u32 = 0x48D;
+pub const IA32_VMX_TRUE_PROCBASED_CTLS: u32 = 0x48E;
+pub const IA32_VMX_TRUE_EXIT_CTLS: u32 = 0x48F;
+pub const IA32_VMX_TRUE_ENTRY_CTLS: u32 = 0x490;
 
 /// Extract allowed-0 and allowed-1 masks from a VMX control MSR value.
 #[inline(always)]
@@ -24,6 +31,13 @@ pub fn satisfy_controls(desired: u32, allowed_0: u32, allowed_1: u32) -> u32 {
     (desired & allowed_0) | allowed_1
 }
 
+/// Adjust `desired` against a VMX capability MSR: bits clear in the high
+/// dword cannot be set, bits set in the low dword must be.
+pub fn adjust_controls(msr: u32, desired: u32) -> u32 {
+    let cap = unsafe { crate::arch::x86::msr::rdmsr(msr) };
+    (desired & (cap >> 32) as u32) | cap as u32
+}
+
 /// Allocate a 4KiB VMCS region and write the revision ID at the first 31 bits.
 pub fn alloc_vmcs_region(system_table: &uefi::table::SystemTable<uefi::prelude::Boot>) -> Option<*mut u8> {
     let page = crate::mm::uefi::alloc_pages(system_table, 1, uefi::table::boot::MemoryType::LOADER_DATA)?;
@@ -50,6 +64,193 @@ pub const VMCS_PROCBASED_CTLS: u64 = 0x0000_4002;
 pub const VMCS_SECONDARY_CTLS: u64 = 0x0000_401E;
 /// EPT pointer (EPTP), 64-bit field
 pub const VMCS_EPT_POINTER: u64 = 0x0000_201A;
+/// Pin-based VM-execution controls
+pub const VMCS_PINBASED_CTLS: u64 = 0x0000_4000;
+/// TSC offset, 64-bit field
+pub const VMCS_TSC_OFFSET: u64 = 0x0000_2010;
+
+// --- Event injection and APIC virtualization ---
+
+/// VM-entry interruption-information field
+pub const VMCS_ENTRY_INTR_INFO: u64 = 0x0000_4016;
+/// VM-entry exception error code
+pub const VMCS_ENTRY_EXCEPTION_ERRCODE: u64 = 0x0000_4018;
+/// VM-entry instruction length (software interrupts/exceptions)
+pub const VMCS_ENTRY_INSTR_LEN: u64 = 0x0000_401A;
+/// TPR threshold (TPR shadow without virtual-interrupt delivery)
+pub const VMCS_TPR_THRESHOLD: u64 = 0x0000_401C;
+/// Guest interruptibility state
+pub const VMCS_GUEST_INTERRUPTIBILITY: u64 = 0x0000_4824;
+/// Guest interrupt status (RVI in 7:0, SVI in 15:8)
+pub const VMCS_GUEST_INTR_STATUS: u64 = 0x0000_0810;
+/// Posted-interrupt notification vector
+pub const VMCS_POSTED_INTR_NV: u64 = 0x0000_0002;
+/// Virtual-APIC page address
+pub const VMCS_VIRTUAL_APIC_ADDR: u64 = 0x0000_2012;
+/// APIC-access page address
+pub const VMCS_APIC_ACCESS_ADDR: u64 = 0x0000_2014;
+/// Posted-interrupt descriptor address
+pub const VMCS_POSTED_INTR_DESC: u64 = 0x0000_2016;
+/// EOI-exit bitmaps 0..3 (vectors 0-63, 64-127, 128-191, 192-255)
+pub const VMCS_EOI_EXIT_BITMAP0: u64 = 0x0000_201C;
+
+/// Primary processor-based control bits
+pub const PROC_INTR_WINDOW_EXITING: u32 = 1 << 2;
+pub const PROC_USE_TSC_OFFSETTING: u32 = 1 << 3;
+pub const PROC_TPR_SHADOW: u32 = 1 << 21;
+pub const PROC_MONITOR_TRAP: u32 = 1 << 27;
+pub const PROC_ACTIVATE_SECONDARY: u32 = 1 << 31;
+/// Secondary processor-based control bits
+pub const PROC2_VIRT_APIC_ACCESSES: u32 = 1 << 0;
+pub const PROC2_VIRT_X2APIC: u32 = 1 << 4;
+pub const PROC2_APIC_REG_VIRT: u32 = 1 << 8;
+pub const PROC2_VIRT_INTR_DELIVERY: u32 = 1 << 9;
+/// Pin-based control bits
+pub const PIN_POSTED_INTERRUPTS: u32 = 1 << 7;
+
+// --- Guest-state area (subset used for initial vCPU state) ---
+
+pub const VMCS_GUEST_ES_SELECTOR: u64 = 0x0000_0800;
+pub const VMCS_GUEST_CS_SELECTOR: u64 = 0x0000_0802;
+pub const VMCS_GUEST_SS_SELECTOR: u64 = 0x0000_0804;
+pub const VMCS_GUEST_DS_SELECTOR: u64 = 0x0000_0806;
+pub const VMCS_GUEST_IA32_EFER: u64 = 0x0000_2806;
+pub const VMCS_GUEST_CS_LIMIT: u64 = 0x0000_4802;
+pub const VMCS_GUEST_GDTR_LIMIT: u64 = 0x0000_4810;
+pub const VMCS_GUEST_ES_AR: u64 = 0x0000_4814;
+pub const VMCS_GUEST_CS_AR: u64 = 0x0000_4816;
+pub const VMCS_GUEST_SS_AR: u64 = 0x0000_4818;
+pub const VMCS_GUEST_DS_AR: u64 = 0x0000_481A;
+pub const VMCS_GUEST_CR0: u64 = 0x0000_6800;
+pub const VMCS_GUEST_CR3: u64 = 0x0000_6802;
+pub const VMCS_GUEST_CR4: u64 = 0x0000_6804;
+pub const VMCS_GUEST_GDTR_BASE: u64 = 0x0000_6816;
+pub const VMCS_GUEST_RSP: u64 = 0x0000_681C;
+pub const VMCS_GUEST_RIP: u64 = 0x0000_681E;
+pub const VMCS_GUEST_RFLAGS: u64 = 0x0000_6820;
+
+pub const VMCS_GUEST_FS_SELECTOR: u64 = 0x0000_0808;
+pub const VMCS_GUEST_GS_SELECTOR: u64 = 0x0000_080A;
+pub const VMCS_GUEST_LDTR_SELECTOR: u64 = 0x0000_080C;
+pub const VMCS_GUEST_TR_SELECTOR: u64 = 0x0000_080E;
+pub const VMCS_LINK_POINTER: u64 = 0x0000_2800;
+pub const VMCS_GUEST_ES_LIMIT: u64 = 0x0000_4800;
+pub const VMCS_GUEST_SS_LIMIT: u64 = 0x0000_4804;
+pub const VMCS_GUEST_DS_LIMIT: u64 = 0x0000_4806;
+pub const VMCS_GUEST_FS_LIMIT: u64 = 0x0000_4808;
+pub const VMCS_GUEST_GS_LIMIT: u64 = 0x0000_480A;
+pub const VMCS_GUEST_LDTR_LIMIT: u64 = 0x0000_480C;
+pub const VMCS_GUEST_TR_LIMIT: u64 = 0x0000_480E;
+pub const VMCS_GUEST_IDTR_LIMIT: u64 = 0x0000_4812;
+pub const VMCS_GUEST_FS_AR: u64 = 0x0000_481C;
+pub const VMCS_GUEST_GS_AR: u64 = 0x0000_481E;
+pub const VMCS_GUEST_LDTR_AR: u64 = 0x0000_4820;
+pub const VMCS_GUEST_TR_AR: u64 = 0x0000_4822;
+pub const VMCS_GUEST_ACTIVITY_STATE: u64 = 0x0000_4826;
+pub const VMCS_GUEST_SYSENTER_CS: u64 = 0x0000_482A;
+pub const VMCS_PREEMPTION_TIMER: u64 = 0x0000_482E;
+pub const VMCS_GUEST_DEBUGCTL: u64 = 0x0000_2802;
+pub const VMCS_GUEST_ES_BASE: u64 = 0x0000_6806;
+pub const VMCS_GUEST_CS_BASE: u64 = 0x0000_6808;
+pub const VMCS_GUEST_SS_BASE: u64 = 0x0000_680A;
+pub const VMCS_GUEST_DS_BASE: u64 = 0x0000_680C;
+pub const VMCS_GUEST_FS_BASE: u64 = 0x0000_680E;
+pub const VMCS_GUEST_GS_BASE: u64 = 0x0000_6810;
+pub const VMCS_GUEST_LDTR_BASE: u64 = 0x0000_6812;
+pub const VMCS_GUEST_TR_BASE: u64 = 0x0000_6814;
+pub const VMCS_GUEST_IDTR_BASE: u64 = 0x0000_6818;
+pub const VMCS_GUEST_DR7: u64 = 0x0000_681A;
+pub const VMCS_GUEST_PENDING_DBG: u64 = 0x0000_6822;
+pub const VMCS_GUEST_SYSENTER_ESP: u64 = 0x0000_6824;
+pub const VMCS_GUEST_SYSENTER_EIP: u64 = 0x0000_6826;
+
+/// Access rights for a flat 64-bit code segment (type=0xB, S, P, L, G).
+pub const AR_CODE64: u64 = 0xA09B;
+/// Access rights for a flat read/write data segment (type=0x3, S, P, D/B, G).
+pub const AR_DATA: u64 = 0xC093;
+
+/// Real-mode code and data segments, and a busy TSS
+pub const AR_CODE16: u64 = 0x009B;
+pub const AR_DATA16: u64 = 0x0093;
+pub const AR_TSS_BUSY: u64 = 0x008B;
+/// Segment marked unusable (bit 16), used for a null LDTR
+pub const AR_UNUSABLE: u64 = 1 << 16;
+
+// --- Host-state area ---
+
+pub const VMCS_HOST_ES_SELECTOR: u64 = 0x0000_0C00;
+pub const VMCS_HOST_CS_SELECTOR: u64 = 0x0000_0C02;
+pub const VMCS_HOST_SS_SELECTOR: u64 = 0x0000_0C04;
+pub const VMCS_HOST_DS_SELECTOR: u64 = 0x0000_0C06;
+pub const VMCS_HOST_FS_SELECTOR: u64 = 0x0000_0C08;
+pub const VMCS_HOST_GS_SELECTOR: u64 = 0x0000_0C0A;
+pub const VMCS_HOST_TR_SELECTOR: u64 = 0x0000_0C0C;
+pub const VMCS_HOST_IA32_EFER: u64 = 0x0000_2C02;
+pub const VMCS_HOST_SYSENTER_CS: u64 = 0x0000_4C00;
+pub const VMCS_HOST_CR0: u64 = 0x0000_6C00;
+pub const VMCS_HOST_CR3: u64 = 0x0000_6C02;
+pub const VMCS_HOST_CR4: u64 = 0x0000_6C04;
+pub const VMCS_HOST_FS_BASE: u64 = 0x0000_6C06;
+pub const VMCS_HOST_GS_BASE: u64 = 0x0000_6C08;
+pub const VMCS_HOST_TR_BASE: u64 = 0x0000_6C0A;
+pub const VMCS_HOST_GDTR_BASE: u64 = 0x0000_6C0C;
+pub const VMCS_HOST_IDTR_BASE: u64 = 0x0000_6C0E;
+pub const VMCS_HOST_SYSENTER_ESP: u64 = 0x0000_6C10;
+pub const VMCS_HOST_SYSENTER_EIP: u64 = 0x0000_6C12;
+pub const VMCS_HOST_RSP: u64 = 0x0000_6C14;
+pub const VMCS_HOST_RIP: u64 = 0x0000_6C16;
+
+// --- Remaining controls and exit information ---
+
+pub const VMCS_EXCEPTION_BITMAP: u64 = 0x0000_4004;
+pub const VMCS_CR3_TARGET_COUNT: u64 = 0x0000_400A;
+pub const VMCS_EXIT_CTLS: u64 = 0x0000_400C;
+pub const VMCS_EXIT_MSR_STORE_COUNT: u64 = 0x0000_400E;
+pub const VMCS_EXIT_MSR_LOAD_COUNT: u64 = 0x0000_4010;
+pub const VMCS_ENTRY_CTLS: u64 = 0x0000_4012;
+pub const VMCS_ENTRY_MSR_LOAD_COUNT: u64 = 0x0000_4014;
+pub const VMCS_CR0_MASK: u64 = 0x0000_6000;
+pub const VMCS_CR4_MASK: u64 = 0x0000_6002;
+pub const VMCS_CR0_SHADOW: u64 = 0x0000_6004;
+pub const VMCS_CR4_SHADOW: u64 = 0x0000_6006;
+pub const VMCS_GUEST_PHYS_ADDR: u64 = 0x0000_2400;
+pub const VMCS_INSTR_ERROR: u64 = 0x0000_4400;
+pub const VMCS_EXIT_REASON: u64 = 0x0000_4402;
+pub const VMCS_EXIT_INTR_INFO: u64 = 0x0000_4404;
+pub const VMCS_EXIT_INSTR_LEN: u64 = 0x0000_440C;
+pub const VMCS_EXIT_QUALIFICATION: u64 = 0x0000_6400;
+
+/// Pin-based, VM-exit and VM-entry control bits
+pub const PIN_EXT_INTR_EXITING: u32 = 1 << 0;
+pub const PIN_NMI_EXITING: u32 = 1 << 3;
+pub const PIN_PREEMPTION_TIMER: u32 = 1 << 6;
+pub const PROC_HLT_EXITING: u32 = 1 << 7;
+pub const PROC_UNCOND_IO_EXITING: u32 = 1 << 24;
+pub const PROC2_ENABLE_EPT: u32 = 1 << 1;
+pub const PROC2_ENABLE_VPID: u32 = 1 << 5;
+pub const PROC2_UNRESTRICTED_GUEST: u32 = 1 << 7;
+pub const PROC2_ENABLE_PML: u32 = 1 << 17;
+pub const EXIT_HOST_ADDR_SPACE: u32 = 1 << 9;
+pub const EXIT_ACK_INTR: u32 = 1 << 15;
+pub const EXIT_SAVE_EFER: u32 = 1 << 20;
+pub const EXIT_LOAD_EFER: u32 = 1 << 21;
+pub const ENTRY_IA32E_GUEST: u32 = 1 << 9;
+pub const ENTRY_LOAD_EFER: u32 = 1 << 15;
+
+/// Basic exit reasons (VMCS_EXIT_REASON bits 15:0)
+pub const EXIT_EXCEPTION_NMI: u32 = 0;
+pub const EXIT_EXTERNAL_INTR: u32 = 1;
+pub const EXIT_TRIPLE_FAULT: u32 = 2;
+pub const EXIT_INTR_WINDOW: u32 = 7;
+pub const EXIT_CPUID: u32 = 10;
+pub const EXIT_HLT: u32 = 12;
+pub const EXIT_CR_ACCESS: u32 = 28;
+pub const EXIT_IO: u32 = 30;
+pub const EXIT_RDMSR: u32 = 31;
+pub const EXIT_WRMSR: u32 = 32;
+pub const EXIT_MONITOR_TRAP: u32 = 37;
+pub const EXIT_EPT_VIOLATION: u32 = 48;
+pub const EXIT_PREEMPTION_TIMER: u32 = 52;
 
 /// Write a VMCS field; returns Ok if VMwrite succeeds (no CF/ZF).
 #[inline(always)]
@@ -57,7 +258,7 @@ pub fn vmwrite(field: u64, value: u64) -> Result<(), &'static str> {
     let mut rflags: u64;
     unsafe {
         core::arch::asm!(
-            "vmwrite {val}, {fld}"
+            "vmwrite {fld}, {val}"
             , fld = in(reg) field
             , val = in(reg) value
             , options(nostack, preserves_flags)
@@ -71,5 +272,20 @@ pub fn vmwrite(field: u64, value: u64) -> Result<(), &'static str> {
     Ok(())
 }
 
-
-
+/// Read a VMCS field; fails when no current VMCS is loaded or the field is invalid.
+#[inline(always)]
+pub fn vmread(field: u64) -> Result<u64, &'static str> {
+    let value: u64;
+    let rflags: u64;
+    unsafe {
+        core::arch::asm!(
+            "vmread {val}, {fld}"
+            , fld = in(reg) field
+            , val = out(reg) value
+            , options(nostack, preserves_flags)
+        );
+        core::arch::asm!("pushfq; pop {}", out(reg) rflags, options(nostack, preserves_flags));
+    }
+    if (rflags & 0x41) != 0 { return Err("vmread failed"); }
+    Ok(value)
+}
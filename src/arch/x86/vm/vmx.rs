@@ -62,17 +62,6 @@ fn read_rflags() -> u64 {
     r
 }
 
-#[inline(always)]
-fn u64_to_hex_buf(v: u64, out: &mut [u8]) -> usize {
-    const HEX: &[u8; 16] = b"0123456789ABCDEF";
-    let mut started = false; let mut n = 0;
-    for i in (0..16).rev() {
-        let nyb = ((v >> (i * 4)) & 0xF) as usize;
-        if nyb != 0 || started || i == 0 { started = true; if n < out.len() { out[n] = HEX[nyb]; n += 1; } }
-    }
-    n
-}
-
 /// Read VMX control MSRs and print allowed masks.
 pub fn vmx_report_controls(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>) {
     let pin = unsafe { crate::arch::x86::msr::rdmsr(0x481) };
@@ -92,7 +81,7 @@ pub fn vmx_report_controls(system_table: &mut uefi::table::SystemTable<uefi::pre
     ] {
         let mut n = 0;
         for &b in label { buf[n] = b; n += 1; }
-        n += u64_to_hex_buf(val, &mut buf[n..]);
+        n += crate::util::format::u64_hex(val, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     }
@@ -320,3 +309,200 @@ pub fn vmx_ept_smoke_test(system_table: &mut uefi::table::SystemTable<uefi::prel
 }
 
 
+
+// ---- Per-CPU root operation and guest entry ----
+
+const VMX_BASIC_REV_MASK: u64 = 0x7FFF_FFFF;
+
+/// VMCS revision identifier to stamp into VMXON and VMCS regions.
+pub fn vmcs_revision() -> u32 {
+    (unsafe { crate::arch::x86::msr::rdmsr(crate::arch::x86::msr::IA32_VMX_BASIC) } & VMX_BASIC_REV_MASK) as u32
+}
+
+/// Enter VMX root operation on the executing CPU. The region at
+/// `vmxon_phys` must already carry the revision ID. CR0/CR4 stay adjusted
+/// (with CR4.VMXE set) until `vmxoff_here`.
+pub fn vmxon_here(vmxon_phys: u64) -> Result<(), &'static str> {
+    if !vmx_preflight_available() { return Err("VMX not available"); }
+    let fc = unsafe { crate::arch::x86::msr::rdmsr(IA32_FEATURE_CONTROL) };
+    if (fc & 1) == 0 {
+        // Unlocked: allow VMX outside SMX and lock, as firmware normally would.
+        unsafe { crate::arch::x86::msr::wrmsr(IA32_FEATURE_CONTROL, fc | (1 << 2) | 1); }
+    }
+    feature_control_allows_vmx()?;
+    let (mut cr0, mut cr4): (u64, u64);
+    unsafe {
+        core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nostack, preserves_flags));
+        core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nostack, preserves_flags));
+    }
+    if (cr4 & (1 << 13)) != 0 { return Err("VMX already enabled on this CPU"); }
+    (cr0, cr4) = vmx_adjust_cr0_cr4(cr0, cr4 | (1 << 13));
+    let failed: u8;
+    unsafe {
+        core::arch::asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+        core::arch::asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+        core::arch::asm!("vmxon [{}]", "setbe {}", in(reg) &vmxon_phys, out(reg_byte) failed, options(nostack));
+    }
+    if failed != 0 {
+        unsafe { core::arch::asm!("mov cr4, {}", in(reg) cr4 & !(1 << 13), options(nostack, preserves_flags)); }
+        return Err("VMXON failed (CF/ZF)");
+    }
+    Ok(())
+}
+
+/// Leave VMX root operation on the executing CPU and clear CR4.VMXE.
+pub fn vmxoff_here() {
+    unsafe {
+        core::arch::asm!("vmxoff", options(nostack));
+        core::arch::asm!("mov {0}, cr4", "btr {0}, 13", "mov cr4, {0}", out(reg) _, options(nostack));
+    }
+}
+
+/// VMCLEAR the VMCS at `phys`, flushing its state to memory and making it inactive.
+pub fn vmclear(phys: u64) -> Result<(), &'static str> {
+    let failed: u8;
+    unsafe { core::arch::asm!("vmclear [{}]", "setbe {}", in(reg) &phys, out(reg_byte) failed, options(nostack)); }
+    if failed != 0 { Err("VMCLEAR failed") } else { Ok(()) }
+}
+
+/// Make the VMCS at `phys` current on the executing CPU.
+pub fn vmptrld(phys: u64) -> Result<(), &'static str> {
+    let failed: u8;
+    unsafe { core::arch::asm!("vmptrld [{}]", "setbe {}", in(reg) &phys, out(reg_byte) failed, options(nostack)); }
+    if failed != 0 { Err("VMPTRLD failed") } else { Ok(()) }
+}
+
+/// Drop translations cached for `eptp` on the executing CPU: INVEPT
+/// single-context when the CPU supports it, all-context otherwise.
+pub fn invept(eptp: u64) -> Result<(), &'static str> {
+    let cap = unsafe { crate::arch::x86::msr::rdmsr(crate::arch::x86::msr::IA32_VMX_EPT_VPID_CAP) };
+    let kind: u64 = if (cap & (1 << 25)) != 0 { 1 } else { 2 };
+    let desc: [u64; 2] = [eptp, 0];
+    let failed: u8;
+    unsafe { core::arch::asm!("invept {}, [{}]", "setbe {}", in(reg) kind, in(reg) &desc, out(reg_byte) failed, options(nostack)); }
+    if failed != 0 { Err("INVEPT failed") } else { Ok(()) }
+}
+
+#[repr(C, packed)]
+struct DescPtr { limit: u16, base: u64 }
+
+/// Fill the host-state area of the current VMCS from the executing CPU.
+/// UEFI leaves TR unloaded, so the caller supplies a per-CPU TSS and the GDT
+/// holding its descriptor. HOST_RSP/HOST_RIP are written by `enter`.
+pub fn write_host_state(gdt_base: u64, tr_sel: u16, tr_base: u64) -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    use crate::arch::x86::msr::rdmsr;
+    let (cr0, cr3, cr4): (u64, u64, u64);
+    let (cs, ss): (u16, u16);
+    let mut idtr = DescPtr { limit: 0, base: 0 };
+    unsafe {
+        core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {0:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {0:x}, ss", out(reg) ss, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("sidt [{}]", in(reg) &mut idtr, options(nostack, preserves_flags));
+    }
+    vmwrite(VMCS_HOST_CR0, cr0)?;
+    vmwrite(VMCS_HOST_CR3, cr3)?;
+    vmwrite(VMCS_HOST_CR4, cr4)?;
+    vmwrite(VMCS_HOST_CS_SELECTOR, (cs & !7) as u64)?;
+    for f in [VMCS_HOST_SS_SELECTOR, VMCS_HOST_DS_SELECTOR, VMCS_HOST_ES_SELECTOR] { vmwrite(f, (ss & !7) as u64)?; }
+    vmwrite(VMCS_HOST_FS_SELECTOR, 0)?;
+    vmwrite(VMCS_HOST_GS_SELECTOR, 0)?;
+    vmwrite(VMCS_HOST_TR_SELECTOR, tr_sel as u64)?;
+    unsafe {
+        vmwrite(VMCS_HOST_FS_BASE, rdmsr(0xC000_0100))?;
+        vmwrite(VMCS_HOST_GS_BASE, rdmsr(0xC000_0101))?;
+        vmwrite(VMCS_HOST_IA32_EFER, rdmsr(0xC000_0080))?;
+        vmwrite(VMCS_HOST_SYSENTER_CS, rdmsr(0x174))?;
+        vmwrite(VMCS_HOST_SYSENTER_ESP, rdmsr(0x175))?;
+        vmwrite(VMCS_HOST_SYSENTER_EIP, rdmsr(0x176))?;
+    }
+    vmwrite(VMCS_HOST_TR_BASE, tr_base)?;
+    vmwrite(VMCS_HOST_GDTR_BASE, gdt_base)?;
+    vmwrite(VMCS_HOST_IDTR_BASE, idtr.base)?;
+    Ok(())
+}
+
+/// VMLAUNCH (or VMRESUME once `launched`) the current VMCS with the guest
+/// general-purpose registers in `gpr` (x86 encoding order; RSP lives in the
+/// VMCS and its slot is ignored). Returns on the next VM exit with `gpr`
+/// holding the guest's registers, or with an error if the entry itself failed.
+pub fn enter(gpr: &mut [u64; 16], launched: bool) -> Result<(), &'static str> {
+    let failed: u64;
+    unsafe {
+        core::arch::asm!(
+            "push rbx",
+            "push rbp",
+            "push rdi",
+            // VM exits land at 2: with RSP pointing at the saved GPR pointer.
+            "mov rax, rsp",
+            "mov edx, 0x6C14",
+            "vmwrite rdx, rax",
+            "lea rax, [rip + 2f]",
+            "mov edx, 0x6C16",
+            "vmwrite rdx, rax",
+            "test esi, esi",
+            "mov rax, [rdi]",
+            "mov rcx, [rdi + 8]",
+            "mov rdx, [rdi + 16]",
+            "mov rbx, [rdi + 24]",
+            "mov rbp, [rdi + 40]",
+            "mov rsi, [rdi + 48]",
+            "mov r8, [rdi + 64]",
+            "mov r9, [rdi + 72]",
+            "mov r10, [rdi + 80]",
+            "mov r11, [rdi + 88]",
+            "mov r12, [rdi + 96]",
+            "mov r13, [rdi + 104]",
+            "mov r14, [rdi + 112]",
+            "mov r15, [rdi + 120]",
+            "mov rdi, [rdi + 56]",
+            "jnz 3f",
+            "vmlaunch",
+            "jmp 4f",
+            "3:",
+            "vmresume",
+            "4:",
+            // Entry failed; guest values in callee-saved registers are discarded.
+            "pop rdi",
+            "pop rbp",
+            "pop rbx",
+            "mov eax, 1",
+            "jmp 5f",
+            "2:",
+            "push rdi",
+            "mov rdi, [rsp + 8]",
+            "mov [rdi], rax",
+            "mov [rdi + 8], rcx",
+            "mov [rdi + 16], rdx",
+            "mov [rdi + 24], rbx",
+            "mov [rdi + 40], rbp",
+            "mov [rdi + 48], rsi",
+            "mov [rdi + 64], r8",
+            "mov [rdi + 72], r9",
+            "mov [rdi + 80], r10",
+            "mov [rdi + 88], r11",
+            "mov [rdi + 96], r12",
+            "mov [rdi + 104], r13",
+            "mov [rdi + 112], r14",
+            "mov [rdi + 120], r15",
+            "pop rax",
+            "mov [rdi + 56], rax",
+            "pop rdi",
+            "pop rbp",
+            "pop rbx",
+            "xor eax, eax",
+            "5:",
+            inout("rdi") gpr.as_mut_ptr() => _,
+            inout("rsi") launched as u64 => _,
+            lateout("rax") failed,
+            lateout("rcx") _, lateout("rdx") _,
+            lateout("r8") _, lateout("r9") _, lateout("r10") _, lateout("r11") _,
+            lateout("r12") _, lateout("r13") _, lateout("r14") _, lateout("r15") _,
+        );
+    }
+    if failed != 0 { return Err("vmx: VM entry failed"); }
+    Ok(())
+}
@@ -0,0 +1,641 @@
+//! HA failover: restarting the VMs of a dead node on the survivors.
+//!
+//! A VM is opted in with `protect`. On every tick its node broadcasts the
+//! inventory record of one protected VM in turn (`MSG_INVENTORY`): size,
+//! name, anti-affinity group, and the image path and command line it was
+//! loaded from. Every node keeps these records as replicas. There are no
+//! guest checkpoints, so a VM is restarted from its image, which must be
+//! reachable under the same ESP path on every node (shared storage).
+//!
+//! When membership declares a node dead, its replicas are frozen. The
+//! alive node with the lowest id coordinates. Once the fencing step clears
+//! the dead node it evacuates one VM per tick: it scores the nodes as
+//! `placement` does, skipping any node that already runs a VM of the same
+//! group, then restarts the VM itself or sends the record to the target as
+//! `MSG_RESTART`. The target creates, loads and starts the VM, protects the
+//! new VM and answers with `MSG_RESTARTED`. An evacuation that finds no
+//! target is retried every tick; one that fails or gets no answer within
+//! two peer timeouts is given up. Outcomes are audited and the last
+//! `LOG_LEN` are kept for `cluster ha`.
+//!
+//! Fencing is the policy's job. With `Fence::Quorum` a node evacuates only
+//! while it is quorate, and the HA peer's VMs only while it holds the
+//! witness lease; a node that loses quorum pauses its own protected VMs
+//! because the survivors are about to restart them. `Fence::None` trusts
+//! the failure detector alone. Either way evacuation waits `delay_ms` after
+//! the node was declared dead.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use super::membership::{self, Health, MAX_MEMBERS};
+use super::placement::{self, Target};
+use super::{Config, Msg, MSG_INVENTORY, MSG_RESTART, MSG_RESTARTED};
+use crate::diag::audit::{self, AuditKind};
+use crate::hv::{admission, loader, run, vm};
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_PROTECTED: usize = 16;
+pub const MAX_REPLICAS: usize = 32;
+pub const LOG_LEN: usize = 8;
+const INBOX: usize = 2;
+const PAUSE_TIMEOUT_US: u64 = 200_000;
+/// Fixed fields of an inventory record, then name, path and command line
+const NAME_OFF: usize = 72;
+const PATH_OFF: usize = NAME_OFF + vm::NAME_MAX;
+const CMDLINE_OFF: usize = PATH_OFF + loader::ORIGIN_PATH_MAX;
+pub(super) const RECORD_LEN: usize = CMDLINE_OFF + loader::ORIGIN_CMDLINE_MAX;
+pub(super) const REPLY_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fence {
+    /// Evacuate as soon as the failure detector declares a node dead
+    None,
+    /// Evacuate only with quorum; fence this node's VMs when it is lost
+    Quorum,
+}
+
+impl Fence {
+    pub fn name(self) -> &'static str { match self { Fence::None => "none", Fence::Quorum => "quorum" } }
+    pub fn parse(s: &str) -> Option<Fence> {
+        match s { "none" => Some(Fence::None), "quorum" => Some(Fence::Quorum), _ => None }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Policy {
+    pub fence: Fence,
+    /// Grace period between a node's death and its evacuation
+    pub delay_ms: u32,
+}
+
+impl Policy {
+    pub const DEFAULT: Policy = Policy { fence: Fence::Quorum, delay_ms: 0 };
+}
+
+/// Result of the fencing step, as audited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FenceAction {
+    /// The dead node may be evacuated
+    Cleared,
+    /// Evacuation held: this node lacks quorum or the witness lease
+    Refused,
+    /// This node lost quorum and paused its protected VMs
+    SelfFence,
+}
+
+impl FenceAction {
+    pub fn code(self) -> u8 { match self { FenceAction::Cleared => 0, FenceAction::Refused => 1, FenceAction::SelfFence => 2 } }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pending,
+    Restarted(u64),
+    Failed,
+    /// No node fits or all run a VM of the same group; retried
+    NoTarget,
+    Lost,
+}
+
+impl Outcome {
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Pending => "pending",
+            Outcome::Restarted(_) => "restarted",
+            Outcome::Failed => "failed",
+            Outcome::NoTarget => "no-target",
+            Outcome::Lost => "lost",
+        }
+    }
+
+    /// Stable code recorded in the audit log.
+    pub fn code(self) -> u8 {
+        match self { Outcome::Restarted(_) => 0, Outcome::Failed => 1, Outcome::NoTarget => 2, Outcome::Lost => 3, Outcome::Pending => 0xFF }
+    }
+}
+
+/// What a node needs to restart a protected VM.
+#[derive(Clone, Copy, Debug)]
+pub struct Inventory {
+    pub owner: u64,
+    pub vm: u64,
+    /// Anti-affinity group (0 = none)
+    pub group: u8,
+    pub vcpus: u32,
+    pub memory_bytes: u64,
+    pub ram_bytes: u64,
+    pub flat_load_gpa: u64,
+    /// (owner, vm) this VM was restarted from
+    pub replaces: Option<(u64, u64)>,
+    name: [u8; vm::NAME_MAX],
+    name_len: u8,
+    path: [u8; loader::ORIGIN_PATH_MAX],
+    path_len: u8,
+    cmdline: [u8; loader::ORIGIN_CMDLINE_MAX],
+    cmdline_len: u16,
+}
+
+impl Inventory {
+    pub fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("?") }
+    pub fn path(&self) -> &str { core::str::from_utf8(&self.path[..self.path_len as usize]).unwrap_or("") }
+    pub fn cmdline(&self) -> &str { core::str::from_utf8(&self.cmdline[..self.cmdline_len as usize]).unwrap_or("") }
+
+    fn encode(&self, b: &mut [u8; RECORD_LEN]) {
+        *b = [0; RECORD_LEN];
+        b[0..8].copy_from_slice(&self.owner.to_le_bytes());
+        b[8..16].copy_from_slice(&self.vm.to_le_bytes());
+        b[16..24].copy_from_slice(&self.memory_bytes.to_le_bytes());
+        b[24..32].copy_from_slice(&self.ram_bytes.to_le_bytes());
+        b[32..40].copy_from_slice(&self.flat_load_gpa.to_le_bytes());
+        b[40..44].copy_from_slice(&self.vcpus.to_le_bytes());
+        b[44] = self.group;
+        b[45] = self.name_len;
+        b[46..48].copy_from_slice(&self.cmdline_len.to_le_bytes());
+        b[48] = self.path_len;
+        if let Some((owner, id)) = self.replaces {
+            b[49] = 1;
+            b[56..64].copy_from_slice(&owner.to_le_bytes());
+            b[64..72].copy_from_slice(&id.to_le_bytes());
+        }
+        b[NAME_OFF..PATH_OFF].copy_from_slice(&self.name);
+        b[PATH_OFF..CMDLINE_OFF].copy_from_slice(&self.path);
+        b[CMDLINE_OFF..RECORD_LEN].copy_from_slice(&self.cmdline);
+    }
+
+    fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() < RECORD_LEN { return None; }
+        let le64 = |o: usize| { let mut x = [0u8; 8]; x.copy_from_slice(&b[o..o + 8]); u64::from_le_bytes(x) };
+        let name_len = b[45] as usize;
+        let cmdline_len = u16::from_le_bytes([b[46], b[47]]) as usize;
+        let path_len = b[48] as usize;
+        if name_len > vm::NAME_MAX || path_len == 0 || path_len > loader::ORIGIN_PATH_MAX || cmdline_len > loader::ORIGIN_CMDLINE_MAX { return None; }
+        let mut inv = Inventory {
+            owner: le64(0), vm: le64(8), group: b[44], vcpus: u32::from_le_bytes([b[40], b[41], b[42], b[43]]),
+            memory_bytes: le64(16), ram_bytes: le64(24), flat_load_gpa: le64(32),
+            replaces: if b[49] == 1 { Some((le64(56), le64(64))) } else { None },
+            name: [0; vm::NAME_MAX], name_len: name_len as u8,
+            path: [0; loader::ORIGIN_PATH_MAX], path_len: path_len as u8,
+            cmdline: [0; loader::ORIGIN_CMDLINE_MAX], cmdline_len: cmdline_len as u16,
+        };
+        inv.name.copy_from_slice(&b[NAME_OFF..PATH_OFF]);
+        inv.path.copy_from_slice(&b[PATH_OFF..CMDLINE_OFF]);
+        inv.cmdline.copy_from_slice(&b[CMDLINE_OFF..RECORD_LEN]);
+        Some(inv)
+    }
+}
+
+/// A VM of this node that is restarted elsewhere if the node dies.
+#[derive(Clone, Copy, Debug)]
+pub struct Protected {
+    pub vm: u64,
+    pub group: u8,
+    /// Set when this VM is itself the result of an evacuation
+    pub replaces: Option<(u64, u64)>,
+}
+
+/// Inventory of another node's protected VM.
+#[derive(Clone, Copy, Debug)]
+pub struct Replica {
+    pub inv: Inventory,
+    /// Evacuation ticket (0 = not handed out)
+    pub ticket: u64,
+    /// `now_ms` of the last refresh from the owner
+    refreshed: u64,
+    /// A `NoTarget` outcome was already logged
+    blocked: bool,
+}
+
+impl Replica {
+    pub fn age_ms(&self, now: u64) -> u64 { now.wrapping_sub(self.refreshed) }
+}
+
+/// One entry of the evacuation log.
+#[derive(Clone, Copy, Debug)]
+pub struct Evacuation {
+    pub ticket: u64,
+    pub owner: u64,
+    pub vm: u64,
+    pub group: u8,
+    /// Node asked to restart the VM (0 with `NoTarget`)
+    pub target: u64,
+    pub outcome: Outcome,
+    sent: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Dead {
+    node: u64,
+    since: u64,
+    /// Last fencing result audited for the node
+    fence: Option<FenceAction>,
+}
+
+#[derive(Clone, Copy)]
+struct Inbound {
+    src: [u8; 6],
+    coordinator: u64,
+    ticket: u64,
+    inv: Inventory,
+}
+
+struct State {
+    policy: Policy,
+    protected: [Option<Protected>; MAX_PROTECTED],
+    cursor: usize,
+    replicas: [Option<Replica>; MAX_REPLICAS],
+    dead: [Option<Dead>; MAX_MEMBERS],
+    log: [Option<Evacuation>; LOG_LEN],
+    log_next: usize,
+    next_ticket: u64,
+    inbox: [Option<Inbound>; INBOX],
+    /// Answers to this node's tickets, not yet settled
+    replies: [Option<(u64, Outcome)>; LOG_LEN],
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    policy: Policy::DEFAULT, protected: [None; MAX_PROTECTED], cursor: 0, replicas: [None; MAX_REPLICAS],
+    dead: [None; MAX_MEMBERS], log: [None; LOG_LEN], log_next: 0, next_ticket: 1, inbox: [None; INBOX],
+    replies: [None; LOG_LEN],
+});
+
+pub fn policy() -> Policy { STATE.lock(|s| s.policy) }
+pub fn set_policy(p: Policy) { STATE.lock(|s| s.policy = p); }
+
+/// Protect `vm_id`, or change its group. The VM needs an image loaded from
+/// the ESP to be restarted from.
+pub fn protect(vm_id: u64, group: u8) -> Result<(), &'static str> {
+    if vm::find_vm(vm_id).is_none() { return Err("ha: no such vm"); }
+    loader::origin(vm_id, |_| ()).map_err(|_| "ha: vm has no image to restart from")?;
+    insert(Protected { vm: vm_id, group, replaces: None })
+}
+
+fn insert(p: Protected) -> Result<(), &'static str> {
+    STATE.lock(|s| {
+        if let Some(e) = s.protected.iter_mut().flatten().find(|e| e.vm == p.vm) {
+            e.group = p.group;
+            return Ok(());
+        }
+        let slot = s.protected.iter_mut().find(|e| e.is_none()).ok_or("ha: protection table full")?;
+        *slot = Some(p);
+        Ok(())
+    })
+}
+
+pub fn unprotect(vm_id: u64) -> bool {
+    STATE.lock(|s| match s.protected.iter_mut().find(|e| matches!(e, Some(p) if p.vm == vm_id)) {
+        Some(e) => { *e = None; true }
+        None => false,
+    })
+}
+
+pub fn protection(vm_id: u64) -> Option<Protected> {
+    STATE.lock(|s| s.protected.iter().flatten().find(|p| p.vm == vm_id).copied())
+}
+
+pub fn for_each_protected(mut f: impl FnMut(&Protected)) {
+    for i in 0..MAX_PROTECTED {
+        if let Some(p) = STATE.lock(|s| s.protected[i]) { f(&p); }
+    }
+}
+
+/// Call `f` for each replica. Entries are copied out one at a time.
+pub fn for_each_replica(mut f: impl FnMut(&Replica)) {
+    for i in 0..MAX_REPLICAS {
+        if let Some(r) = STATE.lock(|s| s.replicas[i]) { f(&r); }
+    }
+}
+
+/// Call `f` for each logged evacuation, oldest first.
+pub fn for_each_evacuation(mut f: impl FnMut(&Evacuation)) {
+    for i in 0..LOG_LEN {
+        let e = STATE.lock(|s| s.log[(s.log_next + i) % LOG_LEN]);
+        if let Some(e) = e { f(&e); }
+    }
+}
+
+/// Drop replicas and failure state when the cluster is reconfigured.
+/// Protection and the policy are kept.
+pub(super) fn reset() {
+    STATE.lock(|s| {
+        s.replicas = [None; MAX_REPLICAS];
+        s.dead = [None; MAX_MEMBERS];
+        s.inbox = [None; INBOX];
+        s.replies = [None; LOG_LEN];
+    });
+}
+
+fn inventory(own: u64, p: &Protected) -> Option<Inventory> {
+    let info = vm::find_vm(p.vm)?;
+    // Generated names ("vm<id>") are not carried over; they would collide
+    // with the target's own numbering.
+    let generated = info.name().strip_prefix("vm").and_then(|d| d.parse::<u64>().ok()) == Some(p.vm);
+    let name = if generated { "" } else { info.name() };
+    loader::origin(p.vm, |req| {
+        let mut inv = Inventory {
+            owner: own, vm: p.vm, group: p.group, vcpus: info.vcpus, memory_bytes: info.memory_bytes,
+            ram_bytes: req.ram_bytes, flat_load_gpa: req.flat_load_gpa, replaces: p.replaces,
+            name: [0; vm::NAME_MAX], name_len: name.len() as u8,
+            path: [0; loader::ORIGIN_PATH_MAX], path_len: req.path.len() as u8,
+            cmdline: [0; loader::ORIGIN_CMDLINE_MAX], cmdline_len: req.cmdline.len() as u16,
+        };
+        inv.name[..name.len()].copy_from_slice(name.as_bytes());
+        inv.path[..req.path.len()].copy_from_slice(req.path.as_bytes());
+        inv.cmdline[..req.cmdline.len()].copy_from_slice(req.cmdline.as_bytes());
+        inv
+    }).ok()
+}
+
+fn is_dead(s: &State, node: u64) -> bool { s.dead.iter().flatten().any(|d| d.node == node) }
+
+/// `MSG_INVENTORY` from the owner (`msg.node`).
+pub(super) fn on_inventory(msg: &Msg, body: &[u8], now: u64) {
+    let inv = match Inventory::decode(body) { Some(i) if i.owner == msg.node => i, _ => return };
+    STATE.lock(|s| {
+        if let Some((owner, id)) = inv.replaces {
+            for slot in s.replicas.iter_mut() {
+                if matches!(slot, Some(r) if r.inv.owner == owner && r.inv.vm == id) { *slot = None; }
+            }
+        }
+        if let Some(r) = s.replicas.iter_mut().flatten().find(|r| r.inv.owner == inv.owner && r.inv.vm == inv.vm) {
+            if r.ticket == 0 { r.inv = inv; r.refreshed = now; }
+            return;
+        }
+        if let Some(slot) = s.replicas.iter_mut().find(|r| r.is_none()) {
+            *slot = Some(Replica { inv, ticket: 0, refreshed: now, blocked: false });
+        }
+    });
+}
+
+/// `MSG_RESTART` addressed to this node; queued for `tick`.
+pub(super) fn on_restart(src: [u8; 6], msg: &Msg, body: &[u8]) {
+    let Some(inv) = Inventory::decode(body) else { return };
+    let job = Inbound { src, coordinator: msg.node, ticket: msg.arg, inv };
+    STATE.lock(|s| {
+        if s.inbox.iter().flatten().any(|j| j.coordinator == job.coordinator && j.ticket == job.ticket) { return; }
+        if let Some(slot) = s.inbox.iter_mut().find(|j| j.is_none()) { *slot = Some(job); }
+    });
+}
+
+/// `MSG_RESTARTED` answering one of this node's tickets; settled by `tick`.
+pub(super) fn on_restarted(msg: &Msg, body: &[u8]) {
+    if body.len() < REPLY_LEN { return; }
+    let mut x = [0u8; 8]; x.copy_from_slice(&body[8..16]);
+    let outcome = if body[0] == 0 { Outcome::Restarted(u64::from_le_bytes(x)) } else { Outcome::Failed };
+    STATE.lock(|s| {
+        if !s.log.iter().flatten().any(|e| e.ticket == msg.arg && e.target == msg.seq && e.outcome == Outcome::Pending) { return; }
+        if s.replies.iter().flatten().any(|r| r.0 == msg.arg) { return; }
+        if let Some(slot) = s.replies.iter_mut().find(|r| r.is_none()) { *slot = Some((msg.arg, outcome)); }
+    });
+}
+
+/// Membership change reported by `membership::round`.
+pub(super) fn on_health(node: u64, health: Health, now: u64) {
+    STATE.lock(|s| match health {
+        Health::Dead => {
+            if is_dead(s, node) { return; }
+            if let Some(slot) = s.dead.iter_mut().find(|d| d.is_none()) { *slot = Some(Dead { node, since: now, fence: None }); }
+        }
+        Health::Alive => {
+            for slot in s.dead.iter_mut() { if matches!(slot, Some(d) if d.node == node) { *slot = None; } }
+            // Whatever was handed out runs elsewhere now; the owner announces
+            // the rest again.
+            for slot in s.replicas.iter_mut() {
+                if matches!(slot, Some(r) if r.inv.owner == node && r.ticket != 0) { *slot = None; }
+            }
+        }
+        Health::Suspect => {}
+    });
+}
+
+/// Quorum was lost. Under `Fence::Quorum` this node pauses its protected
+/// VMs, which the survivors may restart; they stay paused until resumed.
+pub(super) fn on_quorum_lost(system_table: &mut SystemTable<Boot>, own: u64) {
+    if policy().fence != Fence::Quorum { return; }
+    let mut paused = 0u32;
+    for_each_protected(|p| {
+        if run::active(p.vm) != 0 && run::pause(system_table, p.vm, PAUSE_TIMEOUT_US) == 0 { paused += 1; }
+    });
+    audit::record(AuditKind::HaFence { node: own, action: FenceAction::SelfFence.code() });
+    let mut msg = [0u8; 64]; let mut n = 0;
+    for &b in b"quorum lost, paused " { msg[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(paused, &mut msg[n..]);
+    for &b in b" protected vms" { msg[n] = b; n += 1; }
+    crate::obs::log::error(system_table, "ha", core::str::from_utf8(&msg[..n]).unwrap_or("self-fence"));
+}
+
+/// Create, load and start `inv` on this node, and protect the new VM.
+fn restart_here(system_table: &SystemTable<Boot>, inv: &Inventory) -> Result<u64, &'static str> {
+    let config = vm::VmConfig { memory_bytes: inv.memory_bytes, vcpu_count: inv.vcpus, ..Default::default() };
+    let created = vm::Vm::try_create(system_table, config, 0, 0).map_err(|_| "ha: admission refused")?;
+    let name = Some(inv.name()).filter(|n| vm::valid_name(n) && vm::find_vm_by_name(n).is_none());
+    if let Err(e) = vm::register(&created, name) {
+        created.destroy();
+        return Err(e);
+    }
+    let id = created.id.0;
+    let req = loader::LoadRequest { vm_id: id, path: inv.path(), ram_bytes: inv.ram_bytes, flat_load_gpa: inv.flat_load_gpa, cmdline: inv.cmdline() };
+    if let Err(e) = loader::load(system_table, &req).and_then(|_| run::start(system_table, id)) {
+        let _ = vm::destroy_vm(id);
+        return Err(e);
+    }
+    let _ = insert(Protected { vm: id, group: inv.group, replaces: Some((inv.owner, inv.vm)) });
+    Ok(id)
+}
+
+/// Whether `node` runs a VM of `group`, counting evacuations under way.
+fn hosts_group(s: &State, own: u64, node: u64, group: u8) -> bool {
+    if group == 0 { return false; }
+    if node == own && s.protected.iter().flatten().any(|p| p.group == group) { return true; }
+    s.replicas.iter().flatten().any(|r| r.inv.owner == node && r.inv.group == group && r.ticket == 0 && !is_dead(s, node))
+        || s.log.iter().flatten().any(|e| e.target == node && e.group == group && matches!(e.outcome, Outcome::Pending | Outcome::Restarted(_)))
+}
+
+/// Best node for `inv` by placement score, outside its group.
+fn choose(system_table: &SystemTable<Boot>, own: u64, inv: &Inventory) -> Option<Target> {
+    let spec = placement::Spec::new(inv.vcpus, inv.memory_bytes >> 20, 0, 0, None).ok()?;
+    let w = placement::weights();
+    let mut best: Option<(Target, u16)> = None;
+    let req = admission::Request { vcpus: inv.vcpus, memory_bytes: inv.memory_bytes, hugepage_bytes: 0, devices: 0 };
+    if admission::would_admit(&req).is_ok() && !STATE.lock(|s| hosts_group(s, own, own, inv.group)) {
+        if let Some(sc) = placement::score(&placement::local(system_table), &spec, w) { best = Some((Target::Local, sc)); }
+    }
+    membership::for_each(|m| {
+        let Some(r) = m.res else { return };
+        if m.health != Health::Alive || m.mac == [0; 6] { return; }
+        if STATE.lock(|s| hosts_group(s, own, m.node, inv.group)) { return; }
+        if let Some(sc) = placement::score(&r, &spec, w) {
+            if best.map_or(true, |b| sc > b.1) { best = Some((Target::Remote { node: m.node, mac: m.mac }, sc)); }
+        }
+    });
+    best.map(|b| b.0)
+}
+
+fn push_log(s: &mut State, e: Evacuation) {
+    s.log[s.log_next] = Some(e);
+    s.log_next = (s.log_next + 1) % LOG_LEN;
+}
+
+/// Settle evacuation `ticket`: log, audit and count the outcome and drop
+/// the replica. The restarted VM announces itself under its new owner.
+fn finish(system_table: &mut SystemTable<Boot>, ticket: u64, outcome: Outcome) {
+    let e = STATE.lock(|s| {
+        let e = s.log.iter_mut().flatten().find(|e| e.ticket == ticket)?;
+        e.outcome = outcome;
+        let e = *e;
+        for slot in s.replicas.iter_mut() {
+            if matches!(slot, Some(r) if r.ticket == ticket) { *slot = None; }
+        }
+        Some(e)
+    });
+    if let Some(e) = e { report(system_table, &e); }
+}
+
+fn report(system_table: &mut SystemTable<Boot>, e: &Evacuation) {
+    audit::record(AuditKind::HaEvacuate { vm: e.vm, owner: e.owner as u32, target: e.target as u32, outcome: e.outcome.code() });
+    let counter = match e.outcome {
+        Outcome::Restarted(_) => &crate::obs::metrics::CLUSTER_HA_RESTARTED,
+        _ => &crate::obs::metrics::CLUSTER_HA_FAILED,
+    };
+    if e.outcome != Outcome::NoTarget { crate::obs::metrics::Counter::new(counter).inc(); }
+    let mut msg = [0u8; 128]; let mut n = 0;
+    for &b in b"evacuate vm " { msg[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(e.vm, &mut msg[n..]);
+    for &b in b" of node " { msg[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(e.owner, &mut msg[n..]);
+    if e.target != 0 {
+        for &b in b" to node " { msg[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(e.target, &mut msg[n..]);
+    }
+    for &b in b": " { msg[n] = b; n += 1; }
+    for &b in e.outcome.name().as_bytes() { msg[n] = b; n += 1; }
+    if let Outcome::Restarted(id) = e.outcome {
+        for &b in b" as vm " { msg[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(id, &mut msg[n..]);
+    }
+    let text = core::str::from_utf8(&msg[..n]).unwrap_or("evacuation");
+    match e.outcome {
+        Outcome::Restarted(_) => crate::obs::log::info(system_table, "ha", text),
+        _ => crate::obs::log::error(system_table, "ha", text),
+    }
+}
+
+/// Fencing step for a dead node: whether its VMs may be restarted now.
+fn fence(cfg: &Config, p: Policy, node: u64) -> FenceAction {
+    if p.fence == Fence::None { return FenceAction::Cleared; }
+    let quorate = super::mode().quorate() && (node != cfg.peer || super::may_failover());
+    if quorate { FenceAction::Cleared } else { FenceAction::Refused }
+}
+
+/// Whether this node coordinates evacuations: the alive node with the lowest id.
+fn coordinator(own: u64) -> bool {
+    let mut lowest = own;
+    membership::for_each(|m| if m.health == Health::Alive && m.node < lowest { lowest = m.node; });
+    lowest == own
+}
+
+/// Periodic work from `super::tick`: announce a protected VM, age replicas,
+/// serve restart requests and, on the coordinator, evacuate dead nodes.
+pub(super) fn tick(system_table: &mut SystemTable<Boot>, cfg: &Config, now: u64) {
+    announce(system_table, cfg);
+
+    let t = membership::timeouts(cfg);
+    let stale = t.dead_ms as u64 + cfg.interval_ms as u64 * (2 * MAX_PROTECTED as u64);
+    STATE.lock(|s| {
+        for i in 0..MAX_REPLICAS {
+            let Some(r) = s.replicas[i] else { continue };
+            if !is_dead(s, r.inv.owner) && now.wrapping_sub(r.refreshed) >= stale { s.replicas[i] = None; }
+        }
+    });
+
+    while let Some(job) = STATE.lock(|s| s.inbox.iter_mut().find_map(|j| j.take())) {
+        let res = restart_here(system_table, &job.inv);
+        let mut body = [0u8; REPLY_LEN];
+        match res {
+            Ok(id) => body[8..16].copy_from_slice(&id.to_le_bytes()),
+            Err(_) => body[0] = 1,
+        }
+        let msg = Msg { typ: MSG_RESTARTED, cluster: cfg.cluster, node: job.coordinator, term: super::term(), seq: cfg.node, arg: job.ticket };
+        let _ = super::send_msg_body(system_table, cfg.link, cfg.mac, job.src, &msg, &body);
+    }
+
+    while let Some((ticket, outcome)) = STATE.lock(|s| s.replies.iter_mut().find_map(|r| r.take())) {
+        finish(system_table, ticket, outcome);
+    }
+    let lost_after = cfg.timeout_ms.saturating_mul(2) as u64;
+    while let Some(ticket) = STATE.lock(|s| s.log.iter().flatten()
+        .find(|e| e.outcome == Outcome::Pending && e.sent != 0 && now.wrapping_sub(e.sent) >= lost_after).map(|e| e.ticket)) {
+        finish(system_table, ticket, Outcome::Lost);
+    }
+
+    if coordinator(cfg.node) { evacuate(system_table, cfg, now); }
+}
+
+/// Broadcast the next protected VM's inventory; forget VMs that are gone.
+fn announce(system_table: &mut SystemTable<Boot>, cfg: &Config) {
+    for _ in 0..MAX_PROTECTED {
+        let (i, p) = STATE.lock(|s| { let i = s.cursor; s.cursor = (i + 1) % MAX_PROTECTED; (i, s.protected[i]) });
+        let Some(p) = p else { continue };
+        if vm::find_vm(p.vm).is_none() {
+            STATE.lock(|s| if matches!(s.protected[i], Some(x) if x.vm == p.vm) { s.protected[i] = None; });
+            continue;
+        }
+        let Some(inv) = inventory(cfg.node, &p) else { continue };
+        let mut body = [0u8; RECORD_LEN];
+        inv.encode(&mut body);
+        let msg = Msg { typ: MSG_INVENTORY, cluster: cfg.cluster, node: cfg.node, term: super::term(), seq: p.vm, arg: p.group as u64 };
+        let _ = super::send_msg_body(system_table, cfg.link, cfg.mac, [0xFF; 6], &msg, &body);
+        return;
+    }
+}
+
+/// Hand out one VM of a dead, fenced node.
+fn evacuate(system_table: &mut SystemTable<Boot>, cfg: &Config, now: u64) {
+    let p = policy();
+    for i in 0..MAX_MEMBERS {
+        let Some(d) = STATE.lock(|s| s.dead[i]) else { continue };
+        if now.wrapping_sub(d.since) < p.delay_ms as u64 { continue; }
+        let action = fence(cfg, p, d.node);
+        if d.fence != Some(action) {
+            STATE.lock(|s| if let Some(x) = s.dead[i].as_mut() { x.fence = Some(action); });
+            audit::record(AuditKind::HaFence { node: d.node, action: action.code() });
+        }
+        if action != FenceAction::Cleared { continue; }
+        for j in 0..MAX_REPLICAS {
+            let Some(r) = STATE.lock(|s| s.replicas[j]) else { continue };
+            if r.inv.owner != d.node || r.ticket != 0 { continue; }
+            let target = choose(system_table, cfg.node, &r.inv);
+            let Some(target) = target else {
+                if !r.blocked {
+                    let e = Evacuation { ticket: 0, owner: d.node, vm: r.inv.vm, group: r.inv.group, target: 0, outcome: Outcome::NoTarget, sent: 0 };
+                    STATE.lock(|s| {
+                        if let Some(x) = s.replicas[j].as_mut() { x.blocked = true; }
+                        push_log(s, e);
+                    });
+                    report(system_table, &e);
+                }
+                continue;
+            };
+            let node = match target { Target::Local => cfg.node, Target::Remote { node, .. } => node };
+            let ticket = STATE.lock(|s| {
+                let ticket = s.next_ticket; s.next_ticket += 1;
+                if let Some(x) = s.replicas[j].as_mut() { x.ticket = ticket; }
+                push_log(s, Evacuation { ticket, owner: d.node, vm: r.inv.vm, group: r.inv.group, target: node, outcome: Outcome::Pending, sent: now });
+                ticket
+            });
+            match target {
+                Target::Local => {
+                    let outcome = match restart_here(system_table, &r.inv) { Ok(id) => Outcome::Restarted(id), Err(_) => Outcome::Failed };
+                    finish(system_table, ticket, outcome);
+                }
+                Target::Remote { mac, .. } => {
+                    let mut body = [0u8; RECORD_LEN];
+                    r.inv.encode(&mut body);
+                    let msg = Msg { typ: MSG_RESTART, cluster: cfg.cluster, node: cfg.node, term: super::term(), seq: node, arg: ticket };
+                    if !super::send_msg_body(system_table, cfg.link, cfg.mac, mac, &msg, &body) { finish(system_table, ticket, Outcome::Failed); }
+                }
+            }
+            return;
+        }
+    }
+}
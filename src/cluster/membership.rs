@@ -0,0 +1,338 @@
+//! Membership view of every node in the cluster, kept by heartbeat gossip.
+//!
+//! The HA pair in `super` only tracks its one peer. This view covers up to
+//! `MAX_MEMBERS` nodes on the management network. Each node owns a heartbeat
+//! counter (the cluster tick sequence) and, on every tick, sends its view as
+//! a `MSG_GOSSIP` frame to a few members picked round-robin from the static
+//! peer list and the members it has heard from. Every `DISCOVER_ROUNDS`
+//! rounds, or while it knows nobody, it broadcasts instead so nodes nobody
+//! listed still find each other. A receiver merges entry by entry and keeps
+//! the higher counter.
+//!
+//! Failure detection is local: a member whose counter has not moved for
+//! `suspect_ms` is suspect, for `dead_ms` dead. Dead members learned by
+//! gossip are forgotten after another `dead_ms`; static peers stay listed so
+//! they are probed again when they come back.
+//!
+//! After the entries, a gossip frame carries the sender's own
+//! `placement::Resources`, kept per member for placement decisions.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use super::placement::Resources;
+use super::{Config, Msg, ETHERTYPE, ETH_HDR, MSG_GOSSIP, MSG_LEN};
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_MEMBERS: usize = 16;
+/// Gossip targets per round
+const FANOUT: usize = 2;
+const DISCOVER_ROUNDS: u64 = 10;
+/// node u64, heartbeat u64, mac [6], 2 reserved
+const ENTRY_LEN: usize = 24;
+const FRAME_MAX: usize = ETH_HDR + MSG_LEN + ENTRY_LEN * (MAX_MEMBERS + 1) + Resources::ENCODED_LEN;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+impl Health {
+    pub fn name(self) -> &'static str {
+        match self { Health::Alive => "alive", Health::Suspect => "suspect", Health::Dead => "dead" }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Member {
+    pub node: u64,
+    /// Station address; zero until heard from or learned by gossip
+    pub mac: [u8; 6],
+    pub heartbeat: u64,
+    pub health: Health,
+    /// Listed with `add_peer` rather than learned
+    pub static_peer: bool,
+    /// Last summary the member sent about itself
+    pub res: Option<Resources>,
+    /// `now_ms` when the heartbeat last moved (0 = never)
+    seen: u64,
+    /// `now_ms` when the entry was created or went dead
+    since: u64,
+}
+
+impl Member {
+    /// Milliseconds since the heartbeat last moved (None = never heard).
+    pub fn age_ms(&self, now: u64) -> Option<u64> {
+        if self.seen == 0 { None } else { Some(now.wrapping_sub(self.seen)) }
+    }
+}
+
+/// Failure detection timeouts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    pub suspect_ms: u32,
+    pub dead_ms: u32,
+}
+
+impl Timeouts {
+    /// Suspect after the HA peer timeout, dead after three of them.
+    pub fn from_config(cfg: &Config) -> Self {
+        Timeouts { suspect_ms: cfg.timeout_ms, dead_ms: cfg.timeout_ms.saturating_mul(3) }
+    }
+}
+
+struct View {
+    members: [Option<Member>; MAX_MEMBERS],
+    timeouts: Option<Timeouts>,
+    round: u64,
+    next: usize,
+}
+
+static VIEW: SpinLock<View> = SpinLock::new(View { members: [None; MAX_MEMBERS], timeouts: None, round: 0, next: 0 });
+
+fn find(v: &View, node: u64) -> Option<usize> {
+    v.members.iter().position(|m| matches!(m, Some(m) if m.node == node))
+}
+
+/// Slot for `node`, creating an entry if there is room.
+fn entry(v: &mut View, node: u64, now: u64) -> Option<&mut Member> {
+    let i = match find(v, node) {
+        Some(i) => i,
+        None => {
+            let i = v.members.iter().position(|m| m.is_none())?;
+            v.members[i] = Some(Member { node, mac: [0; 6], heartbeat: 0, health: Health::Suspect, static_peer: false, res: None, seen: 0, since: now });
+            i
+        }
+    };
+    v.members[i].as_mut()
+}
+
+/// Start over for `cfg`: learned members are dropped, static peers kept and
+/// reset, and the HA peer is listed as a static peer.
+pub(super) fn reset(cfg: Option<&Config>) {
+    let now = crate::time::clock::now_ms();
+    VIEW.lock(|v| {
+        for slot in v.members.iter_mut() {
+            match slot {
+                Some(m) if m.static_peer => { m.heartbeat = 0; m.seen = 0; m.since = now; m.health = Health::Suspect; m.res = None; }
+                _ => *slot = None,
+            }
+        }
+        v.round = 0;
+        v.next = 0;
+        if let Some(c) = cfg {
+            if let Some(i) = find(v, c.node) { v.members[i] = None; }
+            if let Some(m) = entry(v, c.peer, now) {
+                m.static_peer = true;
+                if c.peer_mac != [0xFF; 6] { m.mac = c.peer_mac; }
+            }
+        }
+    });
+}
+
+/// Add or update a static peer. `mac` may be zero to learn it from gossip.
+pub fn add_peer(node: u64, mac: [u8; 6]) -> Result<(), &'static str> {
+    if super::config().is_some_and(|c| c.node == node) { return Err("cluster: peer is this node"); }
+    let now = crate::time::clock::now_ms();
+    VIEW.lock(|v| match entry(v, node, now) {
+        Some(m) => {
+            m.static_peer = true;
+            if mac != [0; 6] { m.mac = mac; }
+            Ok(())
+        }
+        None => Err("cluster: member table full"),
+    })
+}
+
+pub fn remove_peer(node: u64) -> Result<(), &'static str> {
+    VIEW.lock(|v| match find(v, node) {
+        Some(i) if v.members[i].is_some_and(|m| m.static_peer) => { v.members[i] = None; Ok(()) }
+        _ => Err("cluster: no such static peer"),
+    })
+}
+
+/// Override the failure detection timeouts (None = derive from the config).
+pub fn set_timeouts(t: Option<Timeouts>) -> Result<(), &'static str> {
+    if let Some(t) = t {
+        if t.suspect_ms == 0 || t.dead_ms <= t.suspect_ms { return Err("cluster: need 0 < suspect < dead"); }
+    }
+    VIEW.lock(|v| v.timeouts = t);
+    Ok(())
+}
+
+pub fn timeouts(cfg: &Config) -> Timeouts { VIEW.lock(|v| v.timeouts).unwrap_or(Timeouts::from_config(cfg)) }
+
+/// Call `f` for each member. Entries are copied out one at a time so `f`
+/// may call back into this module.
+pub fn for_each(mut f: impl FnMut(&Member)) {
+    for i in 0..MAX_MEMBERS {
+        if let Some(m) = VIEW.lock(|v| v.members[i]) { f(&m); }
+    }
+}
+
+/// (alive, suspect, dead) member counts.
+pub fn counts() -> (usize, usize, usize) {
+    VIEW.lock(|v| v.members.iter().flatten().fold((0, 0, 0), |(a, s, d), m| match m.health {
+        Health::Alive => (a + 1, s, d),
+        Health::Suspect => (a, s + 1, d),
+        Health::Dead => (a, s, d + 1),
+    }))
+}
+
+/// Fold a heartbeat for `node` into the view. Health is left to the next
+/// `round`, which can report the transition. `direct` is set when `node`
+/// sent the frame itself; its own counter is taken as is, so a member that
+/// restarted and counts from zero again is not ignored.
+fn observe(v: &mut View, node: u64, mac: [u8; 6], heartbeat: u64, direct: bool, now: u64) {
+    let m = match entry(v, node, now) { Some(m) => m, None => return };
+    if mac != [0; 6] && mac != [0xFF; 6] { m.mac = mac; }
+    if direct || heartbeat > m.heartbeat { m.heartbeat = heartbeat; m.seen = now; }
+}
+
+/// Heartbeat from the HA peer; it counts as gossip about itself.
+pub(super) fn on_heartbeat(src: [u8; 6], msg: &Msg, now: u64) {
+    VIEW.lock(|v| observe(v, msg.node, src, msg.seq, true, now));
+}
+
+/// Gossip frame body after the message header. `own` is this node's id.
+pub(super) fn on_gossip(src: [u8; 6], msg: &Msg, entries: &[u8], own: u64, now: u64) {
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::CLUSTER_GOSSIP_RX).inc();
+    let count = (msg.arg as usize).min(entries.len() / ENTRY_LEN).min(MAX_MEMBERS + 1);
+    VIEW.lock(|v| {
+        observe(v, msg.node, src, msg.seq, true, now);
+        for e in entries[..count * ENTRY_LEN].chunks_exact(ENTRY_LEN) {
+            let le64 = |o: usize| { let mut x = [0u8; 8]; x.copy_from_slice(&e[o..o + 8]); u64::from_le_bytes(x) };
+            let node = le64(0);
+            if node == own || node == msg.node { continue; }
+            let mut mac = [0u8; 6]; mac.copy_from_slice(&e[16..22]);
+            observe(v, node, mac, le64(8), false, now);
+        }
+        if let Some(r) = Resources::decode(&entries[count * ENTRY_LEN..]) {
+            if let Some(i) = find(v, msg.node) { if let Some(m) = v.members[i].as_mut() { m.res = Some(r); } }
+        }
+    });
+}
+
+/// Age members and report transitions; then send this round's gossip.
+/// `seq` is this node's heartbeat counter.
+pub(super) fn round(system_table: &mut SystemTable<Boot>, cfg: &Config, term: u64, seq: u64, now: u64) {
+    let t = timeouts(cfg);
+    let res = super::placement::local(system_table);
+    let mut changed = [(0u64, Health::Alive); MAX_MEMBERS];
+    let mut nchanged = 0;
+    let mut f = [0u8; FRAME_MAX];
+    let mut n = ETH_HDR + MSG_LEN;
+    let mut targets = [[0u8; 6]; FANOUT];
+    let mut ntargets = 0;
+    let broadcast = VIEW.lock(|v| {
+        for slot in v.members.iter_mut() {
+            let m = match slot { Some(m) => m, None => continue };
+            let idle = now.wrapping_sub(if m.seen == 0 { m.since } else { m.seen });
+            let health = if idle >= t.dead_ms as u64 { Health::Dead }
+                else if idle >= t.suspect_ms as u64 { Health::Suspect }
+                else if m.seen != 0 { Health::Alive }
+                else { m.health };
+            if health != m.health {
+                if health == Health::Dead { m.since = now; }
+                // A member that was never heard from starts out suspect;
+                // only report it once it is declared dead.
+                if m.seen != 0 || health == Health::Dead { changed[nchanged] = (m.node, health); nchanged += 1; }
+                m.health = health;
+            }
+            if m.health == Health::Dead && !m.static_peer && now.wrapping_sub(m.since) >= t.dead_ms as u64 {
+                *slot = None;
+                continue;
+            }
+            // Dead members are not passed on, so they fade out everywhere.
+            if m.health != Health::Dead && m.seen != 0 {
+                f[n..n + 8].copy_from_slice(&m.node.to_le_bytes());
+                f[n + 8..n + 16].copy_from_slice(&m.heartbeat.to_le_bytes());
+                f[n + 16..n + 22].copy_from_slice(&m.mac);
+                n += ENTRY_LEN;
+            }
+        }
+        v.round += 1;
+        // Probe static peers even while dead; learned members only while up.
+        let mut looked = 0;
+        while ntargets < FANOUT && looked < MAX_MEMBERS {
+            let i = (v.next + looked) % MAX_MEMBERS;
+            looked += 1;
+            if let Some(m) = v.members[i] {
+                let known = m.mac != [0; 6];
+                if known && (m.static_peer || m.health != Health::Dead) && !targets[..ntargets].contains(&m.mac) {
+                    targets[ntargets] = m.mac;
+                    ntargets += 1;
+                }
+            }
+        }
+        v.next = (v.next + looked) % MAX_MEMBERS;
+        ntargets == 0 || v.round % DISCOVER_ROUNDS == 0
+    });
+    for &(node, health) in &changed[..nchanged] {
+        report(system_table, node, health);
+        super::ha::on_health(node, health, now);
+    }
+
+    let entries = (n - ETH_HDR - MSG_LEN) / ENTRY_LEN;
+    res.encode(&mut f[n..]);
+    n += Resources::ENCODED_LEN;
+    let msg = Msg { typ: MSG_GOSSIP, cluster: cfg.cluster, node: cfg.node, term, seq, arg: entries as u64 };
+    msg.encode(&mut f[ETH_HDR..]);
+    f[6..12].copy_from_slice(&cfg.mac);
+    f[12..14].copy_from_slice(&ETHERTYPE.to_be_bytes());
+    if broadcast { targets[0] = [0xFF; 6]; ntargets = 1; }
+    for dst in &targets[..ntargets] {
+        f[0..6].copy_from_slice(dst);
+        if crate::hv::vdev::net::uplink_send(system_table, cfg.link, &f[..n]) {
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::CLUSTER_GOSSIP_TX).inc();
+        }
+    }
+}
+
+fn report(system_table: &mut SystemTable<Boot>, node: u64, health: Health) {
+    let mut msg = [0u8; 64]; let mut n = 0;
+    for &b in b"member " { msg[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(node, &mut msg[n..]);
+    for &b in b" " { msg[n] = b; n += 1; }
+    for &b in health.name().as_bytes() { msg[n] = b; n += 1; }
+    let text = core::str::from_utf8(&msg[..n]).unwrap_or("member change");
+    match health {
+        Health::Dead => {
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::CLUSTER_MEMBER_FAILED).inc();
+            crate::obs::log::error(system_table, "cluster", text)
+        }
+        Health::Suspect => crate::obs::log::warn(system_table, "cluster", text),
+        Health::Alive => crate::obs::log::info(system_table, "cluster", text),
+    }
+}
+
+// ---- Persistence of the static peer list ----
+
+pub(super) const PEERS_ENCODED_LEN: usize = 4 + MAX_MEMBERS * 16;
+
+/// Encode static peers as count u32 then (node u64, mac [6], 2 reserved).
+pub(super) fn encode_peers(b: &mut [u8; PEERS_ENCODED_LEN]) -> usize {
+    *b = [0; PEERS_ENCODED_LEN];
+    let mut count = 0usize;
+    for_each(|m| {
+        if !m.static_peer { return; }
+        let o = 4 + count * 16;
+        b[o..o + 8].copy_from_slice(&m.node.to_le_bytes());
+        b[o + 8..o + 14].copy_from_slice(&m.mac);
+        count += 1;
+    });
+    b[0..4].copy_from_slice(&(count as u32).to_le_bytes());
+    4 + count * 16
+}
+
+pub(super) fn decode_peers(b: &[u8]) {
+    if b.len() < 4 { return; }
+    let count = (u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize).min(MAX_MEMBERS).min((b.len() - 4) / 16);
+    for e in b[4..4 + count * 16].chunks_exact(16) {
+        let mut x = [0u8; 8]; x.copy_from_slice(&e[0..8]);
+        let mut mac = [0u8; 6]; mac.copy_from_slice(&e[8..14]);
+        let _ = add_peer(u64::from_le_bytes(x), mac);
+    }
+}
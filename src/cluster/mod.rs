@@ -0,0 +1,451 @@
+#![allow(dead_code)]
+
+//! Two-node cluster membership with a witness.
+//!
+//! Two nodes exchange heartbeats over a raw Ethernet link (the host uplinks of
+//! `hv::vdev::net`) with their own EtherType. Two votes cannot form a majority
+//! on their own, so a third vote comes from a witness (see `witness`). The
+//! node evaluates quorum on every `tick` and reports one of:
+//!
+//! - `Full`: peer and witness reachable.
+//! - `Degraded(WitnessLost)` / `Degraded(NoWitness)`: peer reachable, no tie
+//!   breaker. Still quorate, but losing the peer next means fencing.
+//! - `Degraded(PeerLost)`: peer gone, this node holds the witness lease. The
+//!   only mode in which `may_failover` is true.
+//! - `NoQuorum`: peer gone and no lease. The node must not start or take over
+//!   workloads of the peer.
+//!
+//! `tick` runs from the CLI idle loop, so the peer timeout has to cover the
+//! longest time a node spends away from the prompt.
+//!
+//! Beyond the pair, every tick also gossips a membership view of all nodes
+//! on the management network (see `membership`). Quorum is still decided by
+//! the pair and the witness alone. New VMs can be placed on any member
+//! (see `placement`), and the protected VMs of a member that dies are
+//! restarted on the survivors (see `ha`). Control-plane state of every
+//! member is replicated by a PBFT-ordered store (see `store`).
+//!
+//! Timestamps throughout are `time::clock::now_ms` readings, 0 for never.
+
+pub mod ha;
+pub mod membership;
+pub mod placement;
+pub mod store;
+pub mod witness;
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::diag::audit::{record, AuditKind};
+use crate::hv::vdev::net::Uplink;
+use crate::util::spinlock::SpinLock;
+use self::witness::Witness;
+
+/// EtherType of cluster frames (migration uses 0x88B5).
+pub const ETHERTYPE: u16 = 0x88B6;
+const MAGIC: [u8; 4] = *b"ZVCL";
+const VERSION: u8 = 1;
+pub(crate) const MSG_HB: u8 = 1;
+pub(crate) const MSG_VOTE_REQ: u8 = 2;
+pub(crate) const MSG_VOTE_GRANT: u8 = 3;
+pub(crate) const MSG_VOTE_DENY: u8 = 4;
+/// Membership view; `seq` is the sender's heartbeat, `arg` the entry count
+pub(crate) const MSG_GOSSIP: u8 = 5;
+/// VM create request; `node` is the requester, `seq` the target, `arg` the ticket
+pub(crate) const MSG_PLACE: u8 = 6;
+/// Answer to `MSG_PLACE`, same fields
+pub(crate) const MSG_PLACED: u8 = 7;
+/// Inventory of a protected VM; `node` is the owner, `seq` the VM id, `arg` its group
+pub(crate) const MSG_INVENTORY: u8 = 8;
+/// Restart request of an evacuated VM; `node` is the coordinator, `seq` the target, `arg` the ticket
+pub(crate) const MSG_RESTART: u8 = 9;
+/// Answer to `MSG_RESTART`, same fields
+pub(crate) const MSG_RESTARTED: u8 = 10;
+/// Replicated store protocol; `term` is the view, `seq` the sequence number,
+/// `arg` the sender's last executed one, the body says the rest
+pub(crate) const MSG_LOG: u8 = 11;
+const ETH_HDR: usize = 14;
+const MSG_LEN: usize = 48;
+/// Longest body after the message header
+const BODY_MAX: usize = ha::RECORD_LEN;
+
+pub const DEFAULT_INTERVAL_MS: u32 = 500;
+pub const DEFAULT_TIMEOUT_MS: u32 = 3000;
+
+/// Cluster frame body, after the Ethernet header.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Msg {
+    pub typ: u8,
+    pub cluster: u32,
+    /// Sender (HB, VOTE_REQ) or requester being answered (GRANT, DENY)
+    pub node: u64,
+    pub term: u64,
+    pub seq: u64,
+    /// VOTE_REQ: lease timeout in ms; GRANT/DENY: current lease holder
+    pub arg: u64,
+}
+
+impl Msg {
+    fn encode(&self, b: &mut [u8]) {
+        b[..MSG_LEN].fill(0);
+        b[0..4].copy_from_slice(&MAGIC);
+        b[4] = VERSION;
+        b[5] = self.typ;
+        b[8..12].copy_from_slice(&self.cluster.to_le_bytes());
+        b[16..24].copy_from_slice(&self.node.to_le_bytes());
+        b[24..32].copy_from_slice(&self.term.to_le_bytes());
+        b[32..40].copy_from_slice(&self.seq.to_le_bytes());
+        b[40..48].copy_from_slice(&self.arg.to_le_bytes());
+    }
+
+    fn decode(b: &[u8]) -> Option<Msg> {
+        if b.len() < MSG_LEN || b[0..4] != MAGIC || b[4] != VERSION { return None; }
+        let le64 = |o: usize| { let mut x = [0u8; 8]; x.copy_from_slice(&b[o..o + 8]); u64::from_le_bytes(x) };
+        Some(Msg {
+            typ: b[5],
+            cluster: u32::from_le_bytes([b[8], b[9], b[10], b[11]]),
+            node: le64(16), term: le64(24), seq: le64(32), arg: le64(40),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Shared by both nodes and the witness
+    pub cluster: u32,
+    pub node: u64,
+    pub peer: u64,
+    /// Peer station address; broadcast if unknown
+    pub peer_mac: [u8; 6],
+    /// This node's station address, used as frame source (zero if unknown)
+    pub mac: [u8; 6],
+    pub link: Uplink,
+    pub interval_ms: u32,
+    pub timeout_ms: u32,
+    pub witness: Witness,
+}
+
+impl Config {
+    pub const ENCODED_LEN: usize = 64;
+
+    pub fn new(cluster: u32, node: u64, peer: u64) -> Self {
+        Config {
+            cluster, node, peer, peer_mac: [0xFF; 6], mac: [0; 6], link: Uplink::Snp,
+            interval_ms: DEFAULT_INTERVAL_MS, timeout_ms: DEFAULT_TIMEOUT_MS, witness: Witness::None,
+        }
+    }
+
+    pub fn encode(&self, b: &mut [u8; Self::ENCODED_LEN]) {
+        *b = [0; Self::ENCODED_LEN];
+        b[0] = VERSION;
+        b[1] = match self.link { Uplink::None => 0, Uplink::Virtio => 1, Uplink::Snp => 2 };
+        b[4..8].copy_from_slice(&self.cluster.to_le_bytes());
+        b[8..16].copy_from_slice(&self.node.to_le_bytes());
+        b[16..24].copy_from_slice(&self.peer.to_le_bytes());
+        b[24..30].copy_from_slice(&self.peer_mac);
+        b[30..36].copy_from_slice(&self.mac);
+        b[36..40].copy_from_slice(&self.interval_ms.to_le_bytes());
+        b[40..44].copy_from_slice(&self.timeout_ms.to_le_bytes());
+        match self.witness {
+            Witness::None => b[44] = 0,
+            Witness::Disk { disk, lba } => {
+                b[44] = 1;
+                b[46..48].copy_from_slice(&disk.to_le_bytes());
+                b[48..56].copy_from_slice(&lba.to_le_bytes());
+            }
+            Witness::Endpoint { mac } => { b[44] = 2; b[48..54].copy_from_slice(&mac); }
+        }
+    }
+
+    pub fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() < Self::ENCODED_LEN || b[0] != VERSION { return None; }
+        let le32 = |o: usize| u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]]);
+        let le64 = |o: usize| { let mut x = [0u8; 8]; x.copy_from_slice(&b[o..o + 8]); u64::from_le_bytes(x) };
+        let mut peer_mac = [0u8; 6]; peer_mac.copy_from_slice(&b[24..30]);
+        let mut mac = [0u8; 6]; mac.copy_from_slice(&b[30..36]);
+        let link = match b[1] { 0 => Uplink::None, 1 => Uplink::Virtio, 2 => Uplink::Snp, _ => return None };
+        let witness = match b[44] {
+            0 => Witness::None,
+            1 => Witness::Disk { disk: u16::from_le_bytes([b[46], b[47]]), lba: le64(48) },
+            2 => { let mut m = [0u8; 6]; m.copy_from_slice(&b[48..54]); Witness::Endpoint { mac: m } }
+            _ => return None,
+        };
+        Some(Config {
+            cluster: le32(4), node: le64(8), peer: le64(16), peer_mac, mac, link,
+            interval_ms: le32(36), timeout_ms: le32(40), witness,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Degraded {
+    /// Witness configured but unreachable
+    WitnessLost,
+    /// No witness configured
+    NoWitness,
+    /// Peer unreachable; this node holds the witness lease
+    PeerLost,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// No cluster configured
+    Standalone,
+    Full,
+    Degraded(Degraded),
+    /// Fenced: no workload may be started or taken over
+    NoQuorum,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Standalone => "standalone",
+            Mode::Full => "full",
+            Mode::Degraded(Degraded::WitnessLost) => "degraded(witness-lost)",
+            Mode::Degraded(Degraded::NoWitness) => "degraded(no-witness)",
+            Mode::Degraded(Degraded::PeerLost) => "degraded(peer-lost)",
+            Mode::NoQuorum => "no-quorum",
+        }
+    }
+
+    /// Stable code recorded in the audit log.
+    pub fn code(self) -> u8 {
+        match self {
+            Mode::Standalone => 0,
+            Mode::Full => 1,
+            Mode::Degraded(Degraded::WitnessLost) => 2,
+            Mode::Degraded(Degraded::NoWitness) => 3,
+            Mode::Degraded(Degraded::PeerLost) => 4,
+            Mode::NoQuorum => 5,
+        }
+    }
+
+    pub fn quorate(self) -> bool { self != Mode::NoQuorum }
+}
+
+/// Snapshot for status reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub cfg: Option<Config>,
+    pub mode: Mode,
+    pub term: u64,
+    /// Milliseconds since the last heartbeat from the peer (None = never seen)
+    pub peer_age_ms: Option<u64>,
+    pub peer_term: u64,
+    pub vote: witness::Vote,
+    pub since_ms: u64,
+}
+
+struct State {
+    cfg: Option<Config>,
+    mode: Mode,
+    term: u64,
+    seq: u64,
+    peer_last: u64,
+    peer_term: u64,
+    last_tick: u64,
+    mode_since: u64,
+    vote: witness::Vote,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    cfg: None, mode: Mode::Standalone, term: 0, seq: 0, peer_last: 0, peer_term: 0,
+    last_tick: 0, mode_since: 0, vote: witness::Vote { reachable: false, lease: false, peer_alive: false },
+});
+
+/// Install `cfg` (None leaves the cluster). Membership state starts over;
+/// static peers are kept.
+pub fn configure(cfg: Option<Config>) {
+    STATE.lock(|s| {
+        s.cfg = cfg; s.mode = Mode::Standalone; s.peer_last = 0; s.peer_term = 0;
+        s.last_tick = 0; s.mode_since = 0; s.vote = witness::Vote::default();
+    });
+    witness::reset();
+    membership::reset(cfg.as_ref());
+    ha::reset();
+    if cfg.is_none() { store::stop(); }
+}
+
+pub fn config() -> Option<Config> { STATE.lock(|s| s.cfg) }
+
+pub fn set_witness(w: Witness) -> Result<(), &'static str> {
+    let ok = STATE.lock(|s| match s.cfg.as_mut() { Some(c) => { c.witness = w; true } None => false });
+    if !ok { return Err("cluster: not configured"); }
+    witness::reset();
+    Ok(())
+}
+
+pub fn mode() -> Mode { STATE.lock(|s| s.mode) }
+
+/// Whether this node may take over the peer's workloads right now.
+pub fn may_failover() -> bool { mode() == Mode::Degraded(Degraded::PeerLost) }
+
+/// Fencing token for actions taken after a fail-over; bumped on every takeover.
+pub fn term() -> u64 { STATE.lock(|s| s.term) }
+
+pub fn status() -> Status {
+    let now = crate::time::clock::now_ms();
+    STATE.lock(|s| Status {
+        cfg: s.cfg, mode: s.mode, term: s.term,
+        peer_age_ms: if s.peer_last == 0 { None } else { Some(now.wrapping_sub(s.peer_last)) },
+        peer_term: s.peer_term, vote: s.vote,
+        since_ms: if s.mode_since == 0 { 0 } else { now.wrapping_sub(s.mode_since) },
+    })
+}
+
+fn send_msg(system_table: &mut SystemTable<Boot>, link: Uplink, src: [u8; 6], dst: [u8; 6], msg: &Msg) -> bool {
+    let mut f = [0u8; ETH_HDR + MSG_LEN];
+    f[0..6].copy_from_slice(&dst);
+    f[6..12].copy_from_slice(&src);
+    f[12..14].copy_from_slice(&ETHERTYPE.to_be_bytes());
+    msg.encode(&mut f[ETH_HDR..]);
+    crate::hv::vdev::net::uplink_send(system_table, link, &f)
+}
+
+/// `msg` followed by `body` (at most `BODY_MAX` bytes).
+fn send_msg_body(system_table: &mut SystemTable<Boot>, link: Uplink, src: [u8; 6], dst: [u8; 6], msg: &Msg, body: &[u8]) -> bool {
+    let mut f = [0u8; ETH_HDR + MSG_LEN + BODY_MAX];
+    f[0..6].copy_from_slice(&dst);
+    f[6..12].copy_from_slice(&src);
+    f[12..14].copy_from_slice(&ETHERTYPE.to_be_bytes());
+    msg.encode(&mut f[ETH_HDR..]);
+    let n = ETH_HDR + MSG_LEN + body.len().min(BODY_MAX);
+    f[ETH_HDR + MSG_LEN..n].copy_from_slice(&body[..n - ETH_HDR - MSG_LEN]);
+    crate::hv::vdev::net::uplink_send(system_table, link, &f[..n])
+}
+
+/// Cluster frame received on any uplink (demultiplexed by `vdev::net::inbound`).
+pub fn on_frame(frame: &[u8]) {
+    if frame.len() < ETH_HDR + MSG_LEN { return; }
+    let msg = match Msg::decode(&frame[ETH_HDR..]) { Some(m) => m, None => return };
+    let mut src = [0u8; 6]; src.copy_from_slice(&frame[6..12]);
+    let now = crate::time::clock::now_ms();
+    if msg.typ == MSG_VOTE_REQ { witness::on_request(src, &msg, now); return; }
+    let cfg = match config() { Some(c) if c.cluster == msg.cluster => c, _ => return };
+    match msg.typ {
+        MSG_GOSSIP if msg.node != cfg.node => membership::on_gossip(src, &msg, &frame[ETH_HDR + MSG_LEN..], cfg.node, now),
+        MSG_PLACE if msg.seq == cfg.node => placement::on_place(src, &msg, &frame[ETH_HDR + MSG_LEN..]),
+        MSG_PLACED if msg.node == cfg.node => placement::on_placed(&msg, &frame[ETH_HDR + MSG_LEN..]),
+        MSG_INVENTORY if msg.node != cfg.node => ha::on_inventory(&msg, &frame[ETH_HDR + MSG_LEN..], now),
+        MSG_RESTART if msg.seq == cfg.node => ha::on_restart(src, &msg, &frame[ETH_HDR + MSG_LEN..]),
+        MSG_RESTARTED if msg.node == cfg.node => ha::on_restarted(&msg, &frame[ETH_HDR + MSG_LEN..]),
+        MSG_LOG if msg.node != cfg.node => store::on_frame(&msg, &frame[ETH_HDR + MSG_LEN..], now),
+        MSG_HB if msg.node == cfg.peer => {
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::CLUSTER_HB_RX).inc();
+            STATE.lock(|s| {
+                s.peer_last = now;
+                s.peer_term = msg.term;
+                // Terms only move forward; adopt the peer's after its takeover.
+                if msg.term > s.term { s.term = msg.term; }
+            });
+            membership::on_heartbeat(src, &msg, now);
+        }
+        MSG_VOTE_GRANT | MSG_VOTE_DENY if msg.node == cfg.node => witness::on_reply(&msg, cfg.timeout_ms, now),
+        _ => {}
+    }
+}
+
+fn evaluate(cfg: &Config, peer_up: bool, vote: witness::Vote) -> Mode {
+    match (peer_up, cfg.witness) {
+        (true, Witness::None) => Mode::Degraded(Degraded::NoWitness),
+        (true, _) if vote.reachable => Mode::Full,
+        (true, _) => Mode::Degraded(Degraded::WitnessLost),
+        (false, _) if vote.lease => Mode::Degraded(Degraded::PeerLost),
+        (false, _) => Mode::NoQuorum,
+    }
+}
+
+/// Periodic work: heartbeat, receive, witness round, quorum evaluation.
+/// Rate-limited to the configured interval unless `force` is set.
+pub fn tick(system_table: &mut SystemTable<Boot>, force: bool) -> Mode {
+    if let Some(link) = witness::serving() {
+        let _ = crate::hv::vdev::net::uplink_recv(system_table, link, 16);
+        witness::serve_flush(system_table, |st, l, dst, m| send_msg(st, l, [0; 6], dst, m));
+    }
+    let cfg = match config() { Some(c) => c, None => return Mode::Standalone };
+    let _ = crate::time::clock::init(system_table);
+    let now = crate::time::clock::now_ms();
+    let due = STATE.lock(|s| {
+        let due = force || s.last_tick == 0 || now.wrapping_sub(s.last_tick) >= cfg.interval_ms as u64;
+        if due { s.last_tick = now; s.seq += 1; }
+        due
+    });
+    if !due { return mode(); }
+    let (term, seq) = STATE.lock(|s| (s.term, s.seq));
+    let hb = Msg { typ: MSG_HB, cluster: cfg.cluster, node: cfg.node, term, seq, arg: 0 };
+    if send_msg(system_table, cfg.link, cfg.mac, cfg.peer_mac, &hb) {
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::CLUSTER_HB_TX).inc();
+    }
+    let _ = crate::hv::vdev::net::uplink_recv(system_table, cfg.link, 16);
+    membership::round(system_table, &cfg, term, seq, now);
+    placement::serve(system_table, &cfg);
+    ha::tick(system_table, &cfg, now);
+    store::tick(system_table, &cfg, now);
+    let vote = witness::poll(system_table, &cfg, term, seq, now, |st, dst, m| send_msg(st, cfg.link, cfg.mac, dst, m));
+    let now = crate::time::clock::now_ms();
+    let (old, new, term) = STATE.lock(|s| {
+        let peer_up = s.peer_last != 0 && now.wrapping_sub(s.peer_last) < cfg.timeout_ms as u64;
+        let new = evaluate(&cfg, peer_up, vote);
+        let old = s.mode;
+        s.vote = vote;
+        if new != old {
+            if new == Mode::Degraded(Degraded::PeerLost) { s.term += 1; }
+            s.mode = new;
+            s.mode_since = now;
+        }
+        (old, new, s.term)
+    });
+    if new != old { report_change(system_table, &cfg, old, new, term); }
+    new
+}
+
+fn report_change(system_table: &mut SystemTable<Boot>, cfg: &Config, old: Mode, new: Mode, term: u64) {
+    record(AuditKind::ClusterMode { mode: new.code(), term });
+    if old.quorate() && !new.quorate() {
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::CLUSTER_QUORUM_LOST).inc();
+        ha::on_quorum_lost(system_table, cfg.node);
+    }
+    let mut msg = [0u8; 96]; let mut n = 0;
+    for &b in b"mode " { msg[n] = b; n += 1; }
+    for &b in old.name().as_bytes() { msg[n] = b; n += 1; }
+    for &b in b" -> " { msg[n] = b; n += 1; }
+    for &b in new.name().as_bytes() { msg[n] = b; n += 1; }
+    for &b in b" term=" { msg[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(term, &mut msg[n..]);
+    let text = core::str::from_utf8(&msg[..n]).unwrap_or("mode change");
+    match new {
+        Mode::NoQuorum => crate::obs::log::error(system_table, "cluster", text),
+        Mode::Degraded(_) => crate::obs::log::warn(system_table, "cluster", text),
+        _ => crate::obs::log::info(system_table, "cluster", text),
+    }
+}
+
+// ---- Persistence ----
+
+const VAR_NS: uefi::table::runtime::VariableVendor = uefi::table::runtime::VariableVendor::GLOBAL_VARIABLE;
+
+/// Save the configuration and the static peer list.
+pub fn save(system_table: &SystemTable<Boot>) -> Result<(), &'static str> {
+    let cfg = config().ok_or("cluster: not configured")?;
+    let mut buf = [0u8; Config::ENCODED_LEN];
+    cfg.encode(&mut buf);
+    let attrs = uefi::table::runtime::VariableAttributes::BOOTSERVICE_ACCESS | uefi::table::runtime::VariableAttributes::NON_VOLATILE;
+    system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorCluster"), &VAR_NS, attrs, &buf).map_err(|_| "cluster: set_variable failed")?;
+    let mut peers = [0u8; membership::PEERS_ENCODED_LEN];
+    let n = membership::encode_peers(&mut peers);
+    system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorClusterPeers"), &VAR_NS, attrs, &peers[..n]).map_err(|_| "cluster: set_variable failed")
+}
+
+/// Load and install the saved configuration and static peers, if any.
+pub fn load(system_table: &SystemTable<Boot>) -> Option<Config> {
+    let mut buf = [0u8; Config::ENCODED_LEN];
+    let (data, _) = system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorCluster"), &VAR_NS, &mut buf).ok()?;
+    let cfg = Config::decode(data)?;
+    configure(Some(cfg));
+    let mut peers = [0u8; membership::PEERS_ENCODED_LEN];
+    if let Ok((data, _)) = system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorClusterPeers"), &VAR_NS, &mut peers) {
+        membership::decode_peers(data);
+    }
+    Some(cfg)
+}
@@ -0,0 +1,810 @@
+//! Choosing the node a new VM runs on.
+//!
+//! Every node appends a `Resources` summary to its gossip frames. With
+//! placement enabled, a create request is scored against this node and every
+//! alive member whose summary is known, and the best node gets the VM. A
+//! remote node has to beat the local score by `MARGIN` so that near-equal
+//! nodes do not pass requests around.
+//!
+//! A forwarded request is queued and sent as `MSG_PLACE` on the next tick,
+//! so callers need no network access. The target admits and registers the
+//! VM on its own next tick and answers with `MSG_PLACED`. Nothing is
+//! retried: a request or answer that goes missing shows up as `lost` in the
+//! decision log after two peer timeouts.
+//!
+//! The last `LOG_LEN` decisions are kept for `cluster placement`, and every
+//! VM created through placement keeps a `Record` for `vm info`.
+//!
+//! A VM can be made a member of a labelled `Group` whose `Rule` keeps the
+//! members together or apart, per host or per NUMA node. Nodes advertise
+//! the groups they run in their summary, and a node that would break a rule
+//! is not a candidate, neither for new VMs nor as a migration target.
+//! NUMA-scoped members are pinned to the CPUs of one node when created.
+//! Rules are only checked when a member is placed; `check` reports the
+//! ones broken since, e.g. by `vm pin` or by two nodes placing at once.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use super::membership::{self, Health};
+use super::{Config, Msg, MSG_PLACE, MSG_PLACED};
+use crate::firmware::acpi::numa::NumaTopology;
+use crate::hv::admission::{self, Resource};
+use crate::hv::vm;
+use crate::util::spinlock::SpinLock;
+
+/// Points a remote node must score above this one to get the VM
+pub const MARGIN: u16 = 50;
+pub const LOG_LEN: usize = 8;
+const RECORDS: usize = 16;
+const INBOX: usize = 4;
+const OUTBOX: usize = 4;
+pub(super) const SPEC_LEN: usize = 96;
+pub(super) const REPLY_LEN: usize = 16;
+pub const GROUP_MAX: usize = 16;
+/// Groups a node advertises in its summary
+pub const GROUPS: usize = 8;
+/// Grouped VMs on this node
+const MEMBERS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    Host,
+    Numa,
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self { Scope::Host => "host", Scope::Numa => "numa" }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s { "host" => Some(Scope::Host), "numa" => Some(Scope::Numa), _ => None }
+    }
+}
+
+/// What a group asks of its members.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rule {
+    /// All members on one host (and one NUMA node)
+    Affinity(Scope),
+    /// No two members on one host (or NUMA node)
+    AntiAffinity(Scope),
+}
+
+impl Rule {
+    pub fn key(self) -> &'static str {
+        match self { Rule::Affinity(_) => "affinity", Rule::AntiAffinity(_) => "anti-affinity" }
+    }
+
+    pub fn scope(self) -> Scope {
+        match self { Rule::Affinity(s) | Rule::AntiAffinity(s) => s }
+    }
+
+    /// `affinity=<scope>` or `anti-affinity=<scope>`.
+    pub fn parse(w: &str) -> Option<Self> {
+        if let Some(v) = w.strip_prefix("anti-affinity=") { return Scope::parse(v).map(Rule::AntiAffinity); }
+        w.strip_prefix("affinity=").and_then(Scope::parse).map(Rule::Affinity)
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Rule::Affinity(Scope::Host) => 1,
+            Rule::Affinity(Scope::Numa) => 2,
+            Rule::AntiAffinity(Scope::Host) => 3,
+            Rule::AntiAffinity(Scope::Numa) => 4,
+        }
+    }
+
+    fn from_code(c: u8) -> Option<Self> {
+        match c {
+            1 => Some(Rule::Affinity(Scope::Host)),
+            2 => Some(Rule::Affinity(Scope::Numa)),
+            3 => Some(Rule::AntiAffinity(Scope::Host)),
+            4 => Some(Rule::AntiAffinity(Scope::Numa)),
+            _ => None,
+        }
+    }
+}
+
+/// A labelled placement group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Group {
+    label: [u8; GROUP_MAX],
+    len: u8,
+    pub rule: Rule,
+}
+
+impl Group {
+    pub fn new(label: &str, rule: Rule) -> Result<Self, &'static str> {
+        if label.is_empty() || label.len() > GROUP_MAX || !label.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_') {
+            return Err("placement: group label must be 1..16 of A-Z a-z 0-9 - _");
+        }
+        let mut g = Group { label: [0; GROUP_MAX], len: label.len() as u8, rule };
+        g.label[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(g)
+    }
+
+    pub fn label(&self) -> &str { core::str::from_utf8(&self.label[..self.len as usize]).unwrap_or("?") }
+
+    /// FNV-1a of the label; summaries carry only this.
+    pub fn tag(&self) -> u32 {
+        self.label[..self.len as usize].iter().fold(0x811C_9DC5u32, |h, &b| (h ^ b as u32).wrapping_mul(0x0100_0193))
+    }
+}
+
+/// Members of one group on a node, as advertised.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GroupUse {
+    pub tag: u32,
+    pub rule: Rule,
+    pub count: u8,
+    /// NUMA nodes the members may run on, bit per node
+    pub numa: u16,
+}
+
+/// What a node advertises about itself for placement.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Resources {
+    /// Guest memory still admissible
+    pub mem_free_mib: u32,
+    pub mem_total_mib: u32,
+    /// vCPUs still admissible under the overcommit ratio
+    pub vcpus_free: u32,
+    pub vcpus_total: u32,
+    pub huge_free_mib: u32,
+    pub devices_free: u16,
+    /// Committed vCPUs per logical CPU, percent
+    pub load_pct: u16,
+    /// Memory and CPUs of the largest NUMA node
+    pub numa_mib: u32,
+    pub numa_cpus: u16,
+    /// Package draw against the power cap (or TDP), percent
+    pub power_pct: Option<u8>,
+    pub numa_nodes: u8,
+    pub groups: [Option<GroupUse>; GROUPS],
+}
+
+impl Resources {
+    pub const ENCODED_LEN: usize = 40 + 8 * GROUPS;
+
+    pub fn encode(&self, b: &mut [u8]) {
+        b[..Self::ENCODED_LEN].fill(0);
+        b[0..4].copy_from_slice(&self.mem_free_mib.to_le_bytes());
+        b[4..8].copy_from_slice(&self.mem_total_mib.to_le_bytes());
+        b[8..12].copy_from_slice(&self.vcpus_free.to_le_bytes());
+        b[12..16].copy_from_slice(&self.vcpus_total.to_le_bytes());
+        b[16..20].copy_from_slice(&self.huge_free_mib.to_le_bytes());
+        b[20..22].copy_from_slice(&self.devices_free.to_le_bytes());
+        b[22..24].copy_from_slice(&self.load_pct.to_le_bytes());
+        b[24..28].copy_from_slice(&self.numa_mib.to_le_bytes());
+        b[28..30].copy_from_slice(&self.numa_cpus.to_le_bytes());
+        b[30] = self.power_pct.unwrap_or(0xFF);
+        b[31] = self.numa_nodes;
+        for (i, g) in self.groups.iter().enumerate() {
+            let Some(g) = g else { continue };
+            let o = 40 + 8 * i;
+            b[o..o + 4].copy_from_slice(&g.tag.to_le_bytes());
+            b[o + 4] = g.rule.code();
+            b[o + 5] = g.count;
+            b[o + 6..o + 8].copy_from_slice(&g.numa.to_le_bytes());
+        }
+    }
+
+    pub fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() < Self::ENCODED_LEN { return None; }
+        let le32 = |o: usize| u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]]);
+        let le16 = |o: usize| u16::from_le_bytes([b[o], b[o + 1]]);
+        let mut groups = [None; GROUPS];
+        for (i, g) in groups.iter_mut().enumerate() {
+            let o = 40 + 8 * i;
+            *g = Rule::from_code(b[o + 4]).map(|rule| GroupUse { tag: le32(o), rule, count: b[o + 5], numa: le16(o + 6) });
+        }
+        Some(Resources {
+            mem_free_mib: le32(0), mem_total_mib: le32(4), vcpus_free: le32(8), vcpus_total: le32(12),
+            huge_free_mib: le32(16), devices_free: le16(20), load_pct: le16(22), numa_mib: le32(24), numa_cpus: le16(28),
+            power_pct: if b[30] == 0xFF { None } else { Some(b[30]) },
+            numa_nodes: b[31], groups,
+        })
+    }
+
+    fn group(&self, tag: u32) -> Option<&GroupUse> { self.groups.iter().flatten().find(|g| g.tag == tag) }
+}
+
+/// This node's summary from admission, the NUMA topology and the power
+/// sampler.
+pub fn local(system_table: &SystemTable<Boot>) -> Resources {
+    admission::init_capacity(system_table);
+    let p = admission::policy();
+    let c = admission::committed();
+    let mib = |b: u64| (b >> 20).min(u32::MAX as u64) as u32;
+    let topo = crate::firmware::acpi::numa::topology(system_table);
+    let (mut numa_bytes, mut numa_cpus) = (0u64, 0usize);
+    for node in 0..topo.nodes as u8 {
+        let bytes = topo.node_bytes(node).min(p.host_memory);
+        if bytes > numa_bytes { numa_bytes = bytes; numa_cpus = topo.node_cpus(node); }
+    }
+    let power = crate::hv::power::status();
+    let reference = if power.cap_mw != 0 { Some(power.cap_mw) } else { power.tdp_mw };
+    let power_pct = match reference {
+        Some(r) if r != 0 && power.draw_mw != 0 => Some((power.draw_mw * 100 / r).min(254) as u8),
+        _ => None,
+    };
+    Resources {
+        mem_free_mib: mib(p.mem_limit().saturating_sub(c.memory)),
+        mem_total_mib: mib(p.mem_limit()),
+        vcpus_free: p.vcpu_limit().saturating_sub(c.vcpus).min(u32::MAX as u64) as u32,
+        vcpus_total: p.vcpu_limit().min(u32::MAX as u64) as u32,
+        huge_free_mib: mib(p.hugepage_pool.saturating_sub(c.hugepages)),
+        devices_free: (p.max_devices as u64).saturating_sub(c.devices).min(u16::MAX as u64) as u16,
+        load_pct: if p.host_cpus == 0 { 0 } else { (c.vcpus * 100 / p.host_cpus as u64).min(u16::MAX as u64) as u16 },
+        numa_mib: mib(numa_bytes),
+        numa_cpus: numa_cpus.min(u16::MAX as usize) as u16,
+        power_pct,
+        numa_nodes: topo.nodes.clamp(1, 16) as u8,
+        groups: group_uses(&topo),
+    }
+}
+
+fn all_nodes(nodes: usize) -> u16 { if nodes >= 16 { u16::MAX } else { (1u16 << nodes.max(1)) - 1 } }
+
+/// NUMA nodes a VM with CPU affinity `mask` may run on.
+fn numa_of(topo: &NumaTopology, mask: u64) -> u16 {
+    let mut m = 0u16;
+    for c in topo.cpu_affinities() {
+        if crate::hv::sched::affinity::allows(mask, c.apic_id) && c.node < 16 { m |= 1 << c.node; }
+    }
+    if m == 0 || mask == 0 { all_nodes(topo.nodes) } else { m }
+}
+
+/// Summaries of the groups with members here. Members whose VM is gone
+/// are dropped on the way.
+fn group_uses(topo: &NumaTopology) -> [Option<GroupUse>; GROUPS] {
+    let mut uses: [Option<GroupUse>; GROUPS] = [None; GROUPS];
+    for i in 0..MEMBERS {
+        let Some((vm_id, g)) = STATE.lock(|s| s.members[i]) else { continue };
+        let Some(info) = vm::find_vm(vm_id) else { STATE.lock(|s| s.members[i] = None); continue };
+        let numa = numa_of(topo, info.affinity);
+        match uses.iter_mut().flatten().find(|u| u.tag == g.tag()) {
+            Some(u) => { u.count = u.count.saturating_add(1); u.numa |= numa; }
+            None => if let Some(slot) = uses.iter_mut().find(|u| u.is_none()) { *slot = Some(GroupUse { tag: g.tag(), rule: g.rule, count: 1, numa }); },
+        }
+    }
+    uses
+}
+
+/// First NUMA node of `nodes` not in `used`, most CPUs first.
+fn free_node(topo: &NumaTopology, used: u16) -> Option<u8> {
+    (0..topo.nodes.clamp(1, 16) as u8).filter(|&n| used & (1 << n) == 0).max_by_key(|&n| (topo.node_cpus(n), u8::MAX - n))
+}
+
+/// Whether a node advertising `r` may take a member of `g`. `anchored`
+/// says that members of the group already run somewhere.
+fn allows(r: &Resources, g: &Group, anchored: bool) -> bool {
+    let used = r.group(g.tag());
+    match g.rule {
+        Rule::AntiAffinity(Scope::Host) => used.is_none(),
+        Rule::AntiAffinity(Scope::Numa) => used.map_or(true, |u| u.numa & all_nodes(r.numa_nodes as usize) != all_nodes(r.numa_nodes as usize)),
+        Rule::Affinity(_) => !anchored || used.is_some(),
+    }
+}
+
+/// Whether an alive member runs VMs of the group `tag`.
+fn remote_has(tag: u32) -> bool {
+    let mut found = false;
+    membership::for_each(|m| {
+        if m.health == Health::Alive && m.res.is_some_and(|r| r.group(tag).is_some()) { found = true; }
+    });
+    found
+}
+
+/// A VM create request as it is scored and forwarded.
+#[derive(Clone, Copy, Debug)]
+pub struct Spec {
+    pub vcpus: u32,
+    pub memory_mib: u64,
+    pub hugepages_mib: u64,
+    pub devices: u32,
+    pub group: Option<Group>,
+    name: [u8; vm::NAME_MAX],
+    name_len: u8,
+}
+
+impl Spec {
+    pub fn new(vcpus: u32, memory_mib: u64, hugepages_mib: u64, devices: u32, name: Option<&str>) -> Result<Self, &'static str> {
+        let mut s = Spec { vcpus: vcpus.max(1), memory_mib, hugepages_mib, devices, group: None, name: [0; vm::NAME_MAX], name_len: 0 };
+        if let Some(n) = name {
+            if !vm::valid_name(n) { return Err("vm: invalid name"); }
+            s.name[..n.len()].copy_from_slice(n.as_bytes());
+            s.name_len = n.len() as u8;
+        }
+        Ok(s)
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        if self.name_len == 0 { None } else { core::str::from_utf8(&self.name[..self.name_len as usize]).ok() }
+    }
+
+    fn request(&self) -> admission::Request {
+        admission::Request { vcpus: self.vcpus, memory_bytes: self.memory_mib << 20, hugepage_bytes: self.hugepages_mib << 20, devices: self.devices }
+    }
+}
+
+/// Relative weight of each score term.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Weights {
+    pub mem: u8,
+    pub cpu: u8,
+    pub numa: u8,
+    pub energy: u8,
+}
+
+impl Weights {
+    pub const DEFAULT: Weights = Weights { mem: 4, cpu: 3, numa: 2, energy: 1 };
+}
+
+/// Score of placing `spec` on a node with `r`, 0..=1000, or None if it
+/// does not fit. Memory and CPU score the headroom left after placement,
+/// NUMA whether the VM fits on one node, energy the distance to the cap.
+pub fn score(r: &Resources, spec: &Spec, w: Weights) -> Option<u16> {
+    let vcpus = spec.vcpus as u64;
+    if (r.mem_free_mib as u64) < spec.memory_mib || (r.vcpus_free as u64) < vcpus
+        || (r.huge_free_mib as u64) < spec.hugepages_mib || (r.devices_free as u32) < spec.devices {
+        return None;
+    }
+    let frac = |left: u64, total: u64| if total == 0 { 0 } else { (left * 1000 / total).min(1000) };
+    let mem = frac(r.mem_free_mib as u64 - spec.memory_mib, r.mem_total_mib as u64);
+    let cpu = frac(r.vcpus_free as u64 - vcpus, r.vcpus_total as u64);
+    let numa = if spec.memory_mib <= r.numa_mib as u64 && vcpus <= r.numa_cpus as u64 { 1000 } else { 0 };
+    let energy = match r.power_pct { Some(p) => 1000 - 10 * p.min(100) as u64, None => 500 };
+    let total = w.mem as u64 + w.cpu as u64 + w.numa as u64 + w.energy as u64;
+    if total == 0 { return Some(0); }
+    Some(((mem * w.mem as u64 + cpu * w.cpu as u64 + numa * w.numa as u64 + energy * w.energy as u64) / total) as u16)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Local,
+    Remote { node: u64, mac: [u8; 6] },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub target: Target,
+    /// Score of the chosen node (None: nothing fits, admission will refuse)
+    pub score: Option<u16>,
+    pub local_score: Option<u16>,
+    /// Best remote node and its score, whether chosen or not
+    pub best_remote: Option<(u64, u16)>,
+    /// Nodes considered, this one included
+    pub candidates: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pending,
+    Created(u64),
+    Denied(Resource),
+    NameInUse,
+    Failed,
+    Lost,
+}
+
+impl Outcome {
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Pending => "pending",
+            Outcome::Created(_) => "created",
+            Outcome::Denied(_) => "denied",
+            Outcome::NameInUse => "name-in-use",
+            Outcome::Failed => "failed",
+            Outcome::Lost => "lost",
+        }
+    }
+}
+
+/// One entry of the decision log.
+#[derive(Clone, Copy, Debug)]
+pub struct LogEntry {
+    pub ticket: u64,
+    /// Node the VM went to (this node's id for local placements)
+    pub node: u64,
+    pub score: Option<u16>,
+    pub local_score: Option<u16>,
+    pub vcpus: u32,
+    pub memory_mib: u64,
+    pub outcome: Outcome,
+    sent: u64,
+}
+
+/// How a VM on this node was placed.
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub vm: u64,
+    /// Node that made the decision (this node unless forwarded; 0 standalone)
+    pub origin: u64,
+    pub score: Option<u16>,
+    /// The origin's own score for the request
+    pub origin_score: Option<u16>,
+    pub best_remote: Option<(u64, u16)>,
+    pub candidates: u8,
+}
+
+#[derive(Clone, Copy)]
+struct Outbound {
+    ticket: u64,
+    node: u64,
+    mac: [u8; 6],
+    body: [u8; SPEC_LEN],
+}
+
+#[derive(Clone, Copy)]
+struct Inbound {
+    src: [u8; 6],
+    origin: u64,
+    ticket: u64,
+    spec: Spec,
+    score: Option<u16>,
+    origin_score: Option<u16>,
+    candidates: u8,
+}
+
+struct State {
+    enabled: bool,
+    weights: Weights,
+    next_ticket: u64,
+    log: [Option<LogEntry>; LOG_LEN],
+    log_next: usize,
+    records: [Option<Record>; RECORDS],
+    rec_next: usize,
+    inbox: [Option<Inbound>; INBOX],
+    outbox: [Option<Outbound>; OUTBOX],
+    members: [Option<(u64, Group)>; MEMBERS],
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    enabled: false, weights: Weights::DEFAULT, next_ticket: 1,
+    log: [None; LOG_LEN], log_next: 0, records: [None; RECORDS], rec_next: 0, inbox: [None; INBOX], outbox: [None; OUTBOX],
+    members: [None; MEMBERS],
+});
+
+pub fn enabled() -> bool { STATE.lock(|s| s.enabled) }
+pub fn set_enabled(on: bool) { STATE.lock(|s| s.enabled = on); }
+pub fn weights() -> Weights { STATE.lock(|s| s.weights) }
+
+pub fn set_weights(w: Weights) -> Result<(), &'static str> {
+    if w.mem as u32 + w.cpu as u32 + w.numa as u32 + w.energy as u32 == 0 { return Err("placement: weights are all zero"); }
+    STATE.lock(|s| s.weights = w);
+    Ok(())
+}
+
+/// Score `spec` here and on every alive member. Without a cluster or with
+/// placement disabled the decision is always local.
+pub fn decide(system_table: &SystemTable<Boot>, spec: &Spec) -> Decision {
+    let w = weights();
+    let here = local(system_table);
+    let anchored = spec.group.is_some_and(|g| here.group(g.tag()).is_some() || remote_has(g.tag()));
+    let fits = |r: &Resources| spec.group.map_or(true, |g| allows(r, &g, anchored));
+    let local_score = match admission::would_admit(&spec.request()) {
+        Ok(()) if fits(&here) => score(&here, spec, w),
+        _ => None,
+    };
+    let mut best: Option<(u64, [u8; 6], u16)> = None;
+    let mut candidates = 1u8;
+    if enabled() && super::config().is_some() {
+        membership::for_each(|m| {
+            let Some(r) = m.res else { return };
+            if m.health != Health::Alive || m.mac == [0; 6] { return; }
+            candidates = candidates.saturating_add(1);
+            if !fits(&r) { return; }
+            if let Some(s) = score(&r, spec, w) {
+                if best.map_or(true, |b| s > b.2) { best = Some((m.node, m.mac, s)); }
+            }
+        });
+    }
+    let remote = match (best, local_score) {
+        (Some(b), Some(l)) if b.2 > l.saturating_add(MARGIN) => Some(b),
+        (Some(b), None) => Some(b),
+        _ => None,
+    };
+    Decision {
+        target: match remote { Some((node, mac, _)) => Target::Remote { node, mac }, None => Target::Local },
+        score: match remote { Some(b) => Some(b.2), None => local_score },
+        local_score,
+        best_remote: best.map(|b| (b.0, b.2)),
+        candidates,
+    }
+}
+
+fn push_log(s: &mut State, e: LogEntry) {
+    s.log[s.log_next] = Some(e);
+    s.log_next = (s.log_next + 1) % LOG_LEN;
+}
+
+fn push_record(s: &mut State, r: Record) {
+    s.records[s.rec_next] = Some(r);
+    s.rec_next = (s.rec_next + 1) % RECORDS;
+}
+
+/// Note a VM created here after a local decision.
+pub fn placed_locally(vm_id: u64, spec: &Spec, d: &Decision) {
+    let own = super::config().map_or(0, |c| c.node);
+    STATE.lock(|s| {
+        let ticket = s.next_ticket; s.next_ticket += 1;
+        push_log(s, LogEntry { ticket, node: own, score: d.score, local_score: d.local_score, vcpus: spec.vcpus, memory_mib: spec.memory_mib, outcome: Outcome::Created(vm_id), sent: 0 });
+        push_record(s, Record { vm: vm_id, origin: own, score: d.score, origin_score: d.local_score, best_remote: d.best_remote, candidates: d.candidates });
+    });
+}
+
+/// Placement record of `vm_id`, if it was created through placement.
+pub fn record(vm_id: u64) -> Option<Record> {
+    STATE.lock(|s| s.records.iter().flatten().find(|r| r.vm == vm_id).copied())
+}
+
+/// Call `f` for each logged decision, oldest first.
+pub fn for_each_decision(mut f: impl FnMut(&LogEntry)) {
+    for i in 0..LOG_LEN {
+        let e = STATE.lock(|s| s.log[(s.log_next + i) % LOG_LEN]);
+        if let Some(e) = e { f(&e); }
+    }
+}
+
+/// Check that a new member of `g` may run on this node. Returns the NUMA
+/// node to pin it to for NUMA-scoped rules.
+pub fn admit_group(system_table: &SystemTable<Boot>, g: &Group) -> Result<Option<u8>, &'static str> {
+    let topo = crate::firmware::acpi::numa::topology(system_table);
+    let uses = group_uses(&topo);
+    let used = uses.iter().flatten().find(|u| u.tag == g.tag());
+    match (g.rule, used) {
+        (Rule::AntiAffinity(Scope::Host), Some(_)) => Err("placement: a member of the group already runs on this host"),
+        (Rule::AntiAffinity(Scope::Host), None) => Ok(None),
+        (Rule::AntiAffinity(Scope::Numa), u) => free_node(&topo, u.map_or(0, |u| u.numa)).map(Some).ok_or("placement: every NUMA node here has a member of the group"),
+        (Rule::Affinity(_), None) if remote_has(g.tag()) => Err("placement: members of the group run on another node"),
+        (Rule::Affinity(Scope::Host), _) => Ok(None),
+        (Rule::Affinity(Scope::Numa), None) => Ok(free_node(&topo, 0)),
+        // Join the node the members are pinned to; unpinned ones leave no choice.
+        (Rule::Affinity(Scope::Numa), Some(u)) => Ok(if u.numa.count_ones() == 1 { Some(u.numa.trailing_zeros() as u8) } else { None }),
+    }
+}
+
+/// Make `vm_id` a member of `g`, pinned to the CPUs of NUMA node `numa` if
+/// given (see `admit_group`).
+pub fn join(system_table: &SystemTable<Boot>, vm_id: u64, g: Group, numa: Option<u8>) -> Result<(), &'static str> {
+    STATE.lock(|s| {
+        let slot = s.members.iter().position(|m| matches!(m, Some((id, _)) if *id == vm_id)).or_else(|| s.members.iter().position(|m| m.is_none()));
+        let slot = slot.ok_or("placement: too many grouped VMs")?;
+        s.members[slot] = Some((vm_id, g));
+        Ok(())
+    })?;
+    let topo = crate::firmware::acpi::numa::topology(system_table);
+    let Some(node) = numa.filter(|_| topo.nodes > 1) else { return Ok(()) };
+    let mask = topo.cpu_affinities().iter().filter(|c| c.node == node && c.apic_id < 64).fold(0u64, |m, c| m | (1u64 << c.apic_id));
+    crate::hv::sched::affinity::pin(system_table, vm_id, mask).map(|_| ())
+}
+
+pub fn leave(vm_id: u64) -> bool {
+    STATE.lock(|s| match s.members.iter().position(|m| matches!(m, Some((id, _)) if *id == vm_id)) {
+        Some(i) => { s.members[i] = None; true }
+        None => false,
+    })
+}
+
+pub fn group_of(vm_id: u64) -> Option<Group> {
+    STATE.lock(|s| s.members.iter().flatten().find(|m| m.0 == vm_id).map(|m| m.1))
+}
+
+/// The best alive member to migrate `vm_id` to: it must fit the VM and
+/// keep the VM's group rule. Returns the node, its address and score.
+pub fn migration_target(vm_id: u64) -> Result<(u64, [u8; 6], u16), &'static str> {
+    let info = vm::find_vm(vm_id).ok_or("placement: no such vm")?;
+    let group = group_of(vm_id);
+    let mut spec = Spec::new(info.vcpus, info.memory_bytes >> 20, 0, 0, None)?;
+    spec.group = group;
+    let anchored = match group {
+        Some(g) if matches!(g.rule, Rule::Affinity(_)) => {
+            let here = STATE.lock(|s| s.members.iter().flatten().filter(|m| m.0 != vm_id && m.1.tag() == g.tag()).count());
+            if here != 0 && !remote_has(g.tag()) { return Err("placement: the vm's group keeps it on this host"); }
+            here != 0 || remote_has(g.tag())
+        }
+        _ => false,
+    };
+    let w = weights();
+    let mut best: Option<(u64, [u8; 6], u16)> = None;
+    membership::for_each(|m| {
+        let Some(r) = m.res else { return };
+        if m.health != Health::Alive || m.mac == [0; 6] { return; }
+        if group.is_some_and(|g| !allows(&r, &g, anchored)) { return; }
+        if let Some(sc) = score(&r, &spec, w) {
+            if best.map_or(true, |b| sc > b.2) { best = Some((m.node, m.mac, sc)); }
+        }
+    });
+    best.ok_or("placement: no member can take the vm")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conflict {
+    /// Another member on this host breaking the rule with it
+    Vm(u64),
+    /// Members of the group on another node
+    Node(u64),
+    /// A NUMA-affine member not pinned to one node
+    Unpinned,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Violation {
+    pub vm: u64,
+    pub group: Group,
+    pub with: Conflict,
+}
+
+/// Report each broken rule this node's members take part in, given this
+/// node's NUMA topology. Returns the number reported.
+pub fn check(topo: &NumaTopology, mut f: impl FnMut(&Violation)) -> usize {
+    let mut here: [Option<(u64, Group, u16)>; MEMBERS] = [None; MEMBERS];
+    for (i, h) in here.iter_mut().enumerate() {
+        let Some((vm_id, g)) = STATE.lock(|s| s.members[i]) else { continue };
+        if let Some(info) = vm::find_vm(vm_id) { *h = Some((vm_id, g, numa_of(topo, info.affinity))); }
+    }
+    let mut count = 0;
+    let mut report = |v: Violation| { count += 1; f(&v); };
+    for i in 0..MEMBERS {
+        let Some((vm_id, g, numa)) = here[i] else { continue };
+        let mut first = true;
+        for &(other, _, other_numa) in here[..i].iter().flatten().filter(|o| o.1.tag() == g.tag()) {
+            first = false;
+            let broken = match g.rule {
+                Rule::AntiAffinity(Scope::Host) => true,
+                Rule::AntiAffinity(Scope::Numa) => numa & other_numa != 0,
+                Rule::Affinity(Scope::Numa) => numa != other_numa,
+                Rule::Affinity(Scope::Host) => false,
+            };
+            if broken { report(Violation { vm: vm_id, group: g, with: Conflict::Vm(other) }); }
+        }
+        if g.rule == Rule::Affinity(Scope::Numa) && topo.nodes > 1 && numa.count_ones() > 1 {
+            report(Violation { vm: vm_id, group: g, with: Conflict::Unpinned });
+        }
+        // Only one member per group is checked against the other nodes.
+        if first && g.rule != Rule::AntiAffinity(Scope::Numa) {
+            membership::for_each(|m| {
+                if m.health == Health::Alive && m.res.is_some_and(|r| r.group(g.tag()).is_some()) {
+                    report(Violation { vm: vm_id, group: g, with: Conflict::Node(m.node) });
+                }
+            });
+        }
+    }
+    count
+}
+
+/// Queue `spec` for the remote target of `d`. Returns the ticket that the
+/// answer will be logged under.
+pub fn forward(spec: &Spec, d: &Decision) -> Result<u64, &'static str> {
+    let Target::Remote { node, mac } = d.target else { return Err("placement: decision is local") };
+    let mut body = [0u8; SPEC_LEN];
+    body[0..4].copy_from_slice(&spec.vcpus.to_le_bytes());
+    body[4..8].copy_from_slice(&spec.devices.to_le_bytes());
+    body[8..16].copy_from_slice(&spec.memory_mib.to_le_bytes());
+    body[16..24].copy_from_slice(&spec.hugepages_mib.to_le_bytes());
+    body[24..26].copy_from_slice(&d.score.unwrap_or(u16::MAX).to_le_bytes());
+    body[26..28].copy_from_slice(&d.local_score.unwrap_or(u16::MAX).to_le_bytes());
+    body[28] = d.candidates;
+    body[29] = spec.name_len;
+    body[32..32 + spec.name_len as usize].copy_from_slice(&spec.name[..spec.name_len as usize]);
+    if let Some(g) = spec.group {
+        body[30] = g.len;
+        body[31] = g.rule.code();
+        body[64..64 + GROUP_MAX].copy_from_slice(&g.label);
+    }
+    STATE.lock(|s| {
+        let slot = s.outbox.iter().position(|o| o.is_none()).ok_or("placement: too many requests in flight")?;
+        let ticket = s.next_ticket; s.next_ticket += 1;
+        s.outbox[slot] = Some(Outbound { ticket, node, mac, body });
+        push_log(s, LogEntry {
+            ticket, node, score: d.score, local_score: d.local_score, vcpus: spec.vcpus, memory_mib: spec.memory_mib, outcome: Outcome::Pending, sent: 0,
+        });
+        Ok(ticket)
+    })
+}
+
+/// `MSG_PLACE` addressed to this node (`msg.seq`); queued for `serve`.
+pub(super) fn on_place(src: [u8; 6], msg: &Msg, body: &[u8]) {
+    if body.len() < SPEC_LEN { return; }
+    let le32 = |o: usize| u32::from_le_bytes([body[o], body[o + 1], body[o + 2], body[o + 3]]);
+    let le64 = |o: usize| { let mut x = [0u8; 8]; x.copy_from_slice(&body[o..o + 8]); u64::from_le_bytes(x) };
+    let opt = |o: usize| match u16::from_le_bytes([body[o], body[o + 1]]) { u16::MAX => None, v => Some(v) };
+    let name_len = (body[29] as usize).min(vm::NAME_MAX);
+    let name = core::str::from_utf8(&body[32..32 + name_len]).ok().filter(|n| !n.is_empty());
+    let mut spec = match Spec::new(le32(0), le64(8), le64(16), le32(4), name) { Ok(s) => s, Err(_) => return };
+    if let Some(rule) = Rule::from_code(body[31]) {
+        let label = core::str::from_utf8(&body[64..64 + (body[30] as usize).min(GROUP_MAX)]).unwrap_or("");
+        match Group::new(label, rule) { Ok(g) => spec.group = Some(g), Err(_) => return }
+    }
+    let job = Inbound { src, origin: msg.node, ticket: msg.arg, spec, score: opt(24), origin_score: opt(26), candidates: body[28] };
+    STATE.lock(|s| {
+        if s.inbox.iter().flatten().any(|j| j.origin == job.origin && j.ticket == job.ticket) { return; }
+        if let Some(slot) = s.inbox.iter_mut().find(|j| j.is_none()) { *slot = Some(job); }
+    });
+}
+
+fn resource_code(r: Resource) -> u8 {
+    match r { Resource::Vcpus => 0, Resource::Memory => 1, Resource::HugePages => 2, Resource::Devices => 3, Resource::Slots => 4, Resource::GuestKeys => 5 }
+}
+
+fn resource_of(c: u8) -> Resource {
+    match c { 0 => Resource::Vcpus, 1 => Resource::Memory, 2 => Resource::HugePages, 3 => Resource::Devices, 4 => Resource::Slots, _ => Resource::GuestKeys }
+}
+
+/// `MSG_PLACED` answering one of this node's tickets.
+pub(super) fn on_placed(msg: &Msg, body: &[u8]) {
+    if body.len() < REPLY_LEN { return; }
+    let mut x = [0u8; 8]; x.copy_from_slice(&body[8..16]);
+    let outcome = match body[0] {
+        0 => Outcome::Created(u64::from_le_bytes(x)),
+        1 => Outcome::Denied(resource_of(body[1])),
+        2 => Outcome::NameInUse,
+        _ => Outcome::Failed,
+    };
+    STATE.lock(|s| {
+        if let Some(e) = s.log.iter_mut().flatten().find(|e| e.ticket == msg.arg && e.node == msg.seq) { e.outcome = outcome; }
+    });
+}
+
+/// Create `spec` on this node, as `vm new` does.
+fn create_here(system_table: &SystemTable<Boot>, spec: &Spec) -> Outcome {
+    if spec.name().is_some_and(|n| vm::find_vm_by_name(n).is_some()) { return Outcome::NameInUse; }
+    let numa = match spec.group.map(|g| admit_group(system_table, &g)) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => return Outcome::Failed,
+        None => None,
+    };
+    let config = vm::VmConfig { memory_bytes: spec.memory_mib << 20, vcpu_count: spec.vcpus, ..Default::default() };
+    let created = match vm::Vm::try_create(system_table, config, spec.hugepages_mib << 20, spec.devices) {
+        Ok(v) => v,
+        Err(e) => return Outcome::Denied(e.resource),
+    };
+    if vm::register(&created, spec.name()).is_err() {
+        created.destroy();
+        return Outcome::Failed;
+    }
+    if let Some(g) = spec.group { let _ = join(system_table, created.id.0, g, numa); }
+    Outcome::Created(created.id.0)
+}
+
+/// Periodic work from `super::tick`: send queued requests, create VMs
+/// forwarded here and answer, and give up on requests never answered.
+pub(super) fn serve(system_table: &mut SystemTable<Boot>, cfg: &Config) {
+    while let Some(out) = STATE.lock(|s| s.outbox.iter_mut().find_map(|o| o.take())) {
+        let msg = Msg { typ: MSG_PLACE, cluster: cfg.cluster, node: cfg.node, term: super::term(), seq: out.node, arg: out.ticket };
+        let sent = super::send_msg_body(system_table, cfg.link, cfg.mac, out.mac, &msg, &out.body);
+        let now = crate::time::clock::now_ms();
+        STATE.lock(|s| {
+            if let Some(e) = s.log.iter_mut().flatten().find(|e| e.ticket == out.ticket) {
+                if sent { e.sent = now; } else { e.outcome = Outcome::Failed; }
+            }
+        });
+    }
+    while let Some(job) = STATE.lock(|s| s.inbox.iter_mut().find_map(|j| j.take())) {
+        let outcome = create_here(system_table, &job.spec);
+        if let Outcome::Created(id) = outcome {
+            STATE.lock(|s| push_record(s, Record {
+                vm: id, origin: job.origin, score: job.score, origin_score: job.origin_score, best_remote: None, candidates: job.candidates,
+            }));
+        }
+        let mut body = [0u8; REPLY_LEN];
+        match outcome {
+            Outcome::Created(id) => body[8..16].copy_from_slice(&id.to_le_bytes()),
+            Outcome::Denied(r) => { body[0] = 1; body[1] = resource_code(r); }
+            Outcome::NameInUse => body[0] = 2,
+            _ => body[0] = 3,
+        }
+        let msg = Msg { typ: MSG_PLACED, cluster: cfg.cluster, node: job.origin, term: super::term(), seq: cfg.node, arg: job.ticket };
+        let _ = super::send_msg_body(system_table, cfg.link, cfg.mac, job.src, &msg, &body);
+    }
+    let now = crate::time::clock::now_ms();
+    let lost_after = cfg.timeout_ms.saturating_mul(2) as u64;
+    STATE.lock(|s| {
+        for e in s.log.iter_mut().flatten() {
+            if e.outcome == Outcome::Pending && e.sent != 0 && now.wrapping_sub(e.sent) >= lost_after { e.outcome = Outcome::Lost; }
+        }
+    });
+}
@@ -0,0 +1,767 @@
+//! Replicated configuration store, ordered with PBFT.
+//!
+//! A fixed list of `n` replicas, this node among them, keeps the same
+//! key/value map of control-plane state: every node's VM registry, its
+//! IOMMU assignments and saved migration settings, plus whatever the
+//! operator puts. Reads are served from the local copy. A write is an
+//! `Entry` that the leader of the current view gives a sequence number; it
+//! goes through pre-prepare, prepare and commit, each a broadcast
+//! `MSG_LOG` frame, and is executed once `quorum` replicas committed it.
+//! With `n = 3f + 1` replicas, up to `f` of them may fail or lie.
+//!
+//! Frames go out on the cluster tick, so a write takes about three ticks.
+//! Replicas repeat their votes on every tick until a slot executes, which
+//! covers lost frames. Each frame carries an HMAC-SHA256 keyed with the
+//! replicas' shared secret. That keeps other hosts on the link out, but it
+//! does not tell replicas apart: a faulty replica is assumed to send only
+//! in its own name.
+//!
+//! When a queued write or a proposal is not executed within the peer
+//! timeout, the replicas move to the next view, led by the next replica in
+//! the list. View-change votes carry each replica's prepared entries and
+//! the new leader proposes them again. A replica that prepared an entry
+//! never prepares a different one for that slot, so an executed entry
+//! cannot be replaced. A replica that falls behind asks for a snapshot of
+//! the map and adopts it once `f + 1` replicas report the same digest.
+//!
+//! Nothing is saved: a node that restarts joins with `init` again and
+//! catches up by snapshot.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use super::{Config, Msg, MSG_LEN, MSG_LOG};
+use crate::util::sha256::{self, Sha256, DIGEST_LEN};
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_REPLICAS: usize = 7;
+pub const KEY_MAX: usize = 32;
+pub const VALUE_MAX: usize = 64;
+pub const KV_MAX: usize = 128;
+pub const HISTORY: usize = 32;
+pub const SECRET_MIN: usize = 16;
+pub const SECRET_MAX: usize = 64;
+/// Sequence numbers in flight beyond the last executed one
+const WINDOW: usize = 8;
+const QUEUE: usize = 8;
+/// op, key and value lengths, origin, request number, key, value
+const ENTRY_LEN: usize = 24 + KEY_MAX + VALUE_MAX;
+/// phase, aux, digest, entry, then the HMAC
+const BODY_LEN: usize = 16 + DIGEST_LEN + ENTRY_LEN + DIGEST_LEN;
+
+const REQUEST: u8 = 1;
+const PRE_PREPARE: u8 = 2;
+const PREPARE: u8 = 3;
+const COMMIT: u8 = 4;
+/// `term` is the view asked for; `seq`/`aux` a prepared slot and its view (0: vote only)
+const VIEW_CHANGE: u8 = 5;
+const NEW_VIEW: u8 = 6;
+/// `aux` is the replica asked to send the entries
+const SNAP_REQ: u8 = 7;
+/// `seq` is the snapshot's sequence number, `aux` the entry count, digest of the map
+const SNAP: u8 = 8;
+/// `aux` is the index of the entry within the snapshot
+const SNAP_ENTRY: u8 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Fills a slot a new leader found no entry for
+    Nop,
+    Put,
+    Del,
+}
+
+/// One write, as ordered and replicated.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub op: Op,
+    /// Node that submitted the write and its request number there
+    pub origin: u64,
+    pub req: u64,
+    key: [u8; KEY_MAX],
+    key_len: u8,
+    value: [u8; VALUE_MAX],
+    value_len: u8,
+}
+
+impl Entry {
+    const NOP: Entry = Entry { op: Op::Nop, origin: 0, req: 0, key: [0; KEY_MAX], key_len: 0, value: [0; VALUE_MAX], value_len: 0 };
+
+    fn new(op: Op, origin: u64, req: u64, key: &str, value: &str) -> Result<Self, &'static str> {
+        if !valid_key(key) { return Err("store: key must be 1..32 printable characters without spaces"); }
+        if value.len() > VALUE_MAX || !value.bytes().all(|c| (0x20..0x7F).contains(&c)) { return Err("store: value must be up to 64 printable characters"); }
+        let mut e = Entry { op, origin, req, key_len: key.len() as u8, value_len: value.len() as u8, ..Entry::NOP };
+        e.key[..key.len()].copy_from_slice(key.as_bytes());
+        e.value[..value.len()].copy_from_slice(value.as_bytes());
+        Ok(e)
+    }
+
+    pub fn key(&self) -> &str { core::str::from_utf8(&self.key[..self.key_len as usize]).unwrap_or("?") }
+    pub fn value(&self) -> &str { core::str::from_utf8(&self.value[..self.value_len as usize]).unwrap_or("?") }
+
+    fn encode(&self, b: &mut [u8]) {
+        b[..ENTRY_LEN].fill(0);
+        b[0] = match self.op { Op::Nop => 0, Op::Put => 1, Op::Del => 2 };
+        b[1] = self.key_len;
+        b[2] = self.value_len;
+        b[8..16].copy_from_slice(&self.origin.to_le_bytes());
+        b[16..24].copy_from_slice(&self.req.to_le_bytes());
+        b[24..24 + KEY_MAX].copy_from_slice(&self.key);
+        b[24 + KEY_MAX..ENTRY_LEN].copy_from_slice(&self.value);
+    }
+
+    fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() < ENTRY_LEN || b[1] as usize > KEY_MAX || b[2] as usize > VALUE_MAX { return None; }
+        let le64 = |o: usize| { let mut x = [0u8; 8]; x.copy_from_slice(&b[o..o + 8]); u64::from_le_bytes(x) };
+        let op = match b[0] { 0 => Op::Nop, 1 => Op::Put, 2 => Op::Del, _ => return None };
+        let mut e = Entry { op, origin: le64(8), req: le64(16), key_len: b[1], value_len: b[2], ..Entry::NOP };
+        e.key.copy_from_slice(&b[24..24 + KEY_MAX]);
+        e.value.copy_from_slice(&b[24 + KEY_MAX..ENTRY_LEN]);
+        Some(e)
+    }
+
+    fn digest(&self) -> [u8; DIGEST_LEN] {
+        let mut b = [0u8; ENTRY_LEN];
+        self.encode(&mut b);
+        sha256::sha256(&b)
+    }
+}
+
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= KEY_MAX && key.bytes().all(|c| (0x21..0x7F).contains(&c))
+}
+
+/// An executed entry, kept for `cluster log`.
+#[derive(Clone, Copy, Debug)]
+pub struct Logged {
+    pub seq: u64,
+    pub view: u64,
+    pub entry: Entry,
+    /// False for a put that found the map full
+    pub applied: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub running: bool,
+    pub replicas: [u64; MAX_REPLICAS],
+    pub n: usize,
+    pub f: usize,
+    pub quorum: usize,
+    pub view: u64,
+    pub leader: u64,
+    /// Last executed sequence number
+    pub executed: u64,
+    /// View this node asked to move to, while a view change is under way
+    pub view_change: Option<u64>,
+    pub queued: usize,
+    pub keys: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    seq: u64,
+    /// View of the pre-prepare (or of the first vote seen)
+    view: u64,
+    digest: [u8; DIGEST_LEN],
+    entry: Entry,
+    /// Pre-prepare from the view's leader accepted
+    pre: bool,
+    /// Replicas (bit per index) that sent PREPARE / COMMIT in `view`
+    prepares: u8,
+    commits: u8,
+    /// Digest and view this replica prepared; no other digest is prepared
+    locked: Option<([u8; DIGEST_LEN], u64)>,
+    since: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Queued {
+    entry: Entry,
+    digest: [u8; DIGEST_LEN],
+    since: u64,
+    /// Sequence number a pre-prepare gave it (0 = not proposed yet)
+    seq: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Kv {
+    entry: Entry,
+}
+
+#[derive(Clone, Copy)]
+struct ViewChange {
+    target: u64,
+    votes: u8,
+    since: u64,
+    /// This replica voted for `target` itself
+    joined: bool,
+    /// Prepared entries reported for slots after `exec`: (seq, view, entry)
+    carried: [Option<(u64, u64, Entry)>; WINDOW],
+}
+
+struct Snapshot {
+    /// Replica sending the entries, sequence number and entry count
+    source: u64,
+    seq: u64,
+    count: u64,
+    got: u128,
+    kv: [Option<Kv>; KV_MAX],
+    /// Headers by replica index: (seq, digest)
+    headers: [Option<(u64, [u8; DIGEST_LEN])>; MAX_REPLICAS],
+    asked: u64,
+    /// Replica that asked this node for a snapshot, and the source it named
+    reply: Option<u64>,
+}
+
+struct State {
+    running: bool,
+    key: [u8; DIGEST_LEN],
+    replicas: [u64; MAX_REPLICAS],
+    n: usize,
+    view: u64,
+    exec: u64,
+    /// Last sequence number this node proposed as leader
+    proposed: u64,
+    progress: u64,
+    next_req: u64,
+    slots: [Option<Slot>; WINDOW],
+    queue: [Option<Queued>; QUEUE],
+    vc: Option<ViewChange>,
+    peer_exec: [u64; MAX_REPLICAS],
+    kv: [Option<Kv>; KV_MAX],
+    history: [Option<Logged>; HISTORY],
+    hist_next: usize,
+    snap: Snapshot,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    running: false, key: [0; DIGEST_LEN], replicas: [0; MAX_REPLICAS], n: 0, view: 0, exec: 0, proposed: 0, progress: 0, next_req: 1,
+    slots: [None; WINDOW], queue: [None; QUEUE], vc: None, peer_exec: [0; MAX_REPLICAS], kv: [None; KV_MAX],
+    history: [None; HISTORY], hist_next: 0,
+    snap: Snapshot { source: 0, seq: 0, count: 0, got: 0, kv: [None; KV_MAX], headers: [None; MAX_REPLICAS], asked: 0, reply: None },
+});
+
+impl State {
+    fn f(&self) -> usize { self.n.saturating_sub(1) / 3 }
+    /// Smallest set of replicas any two of which share `f + 1`
+    fn quorum(&self) -> usize { (self.n + self.f()) / 2 + 1 }
+    fn index(&self, node: u64) -> Option<usize> { self.replicas[..self.n].iter().position(|&r| r == node) }
+    fn leader(&self, view: u64) -> u64 { if self.n == 0 { 0 } else { self.replicas[(view % self.n as u64) as usize] } }
+    fn slot(&mut self, seq: u64) -> &mut Option<Slot> { &mut self.slots[(seq % WINDOW as u64) as usize] }
+    fn in_window(&self, seq: u64) -> bool { seq > self.exec && seq <= self.exec + WINDOW as u64 }
+}
+
+fn own() -> u64 { super::config().map_or(0, |c| c.node) }
+
+/// Start the store with the replica list (this node included) and shared
+/// secret. The map starts empty and is filled by snapshot if others run.
+pub fn init(replicas: &[u64], secret: &str) -> Result<(), &'static str> {
+    let own = super::config().ok_or("cluster: not configured")?.node;
+    if replicas.is_empty() || replicas.len() > MAX_REPLICAS { return Err("store: need 1..7 replicas"); }
+    if !replicas.contains(&own) { return Err("store: this node must be a replica"); }
+    if replicas.iter().enumerate().any(|(i, r)| replicas[..i].contains(r)) { return Err("store: duplicate replica"); }
+    if secret.len() < SECRET_MIN || secret.len() > SECRET_MAX { return Err("store: secret must be 16..64 characters"); }
+    let now = crate::time::clock::now_ms();
+    STATE.lock(|s| {
+        s.running = true;
+        s.key = sha256::sha256(secret.as_bytes());
+        s.replicas = [0; MAX_REPLICAS];
+        s.replicas[..replicas.len()].copy_from_slice(replicas);
+        s.n = replicas.len();
+        s.view = 0; s.exec = 0; s.proposed = 0; s.progress = now;
+        s.slots = [None; WINDOW]; s.queue = [None; QUEUE]; s.vc = None;
+        s.peer_exec = [0; MAX_REPLICAS]; s.kv = [None; KV_MAX];
+        s.history = [None; HISTORY]; s.hist_next = 0;
+        s.snap.headers = [None; MAX_REPLICAS]; s.snap.got = 0; s.snap.count = 0; s.snap.asked = 0; s.snap.reply = None;
+    });
+    Ok(())
+}
+
+pub fn stop() { STATE.lock(|s| s.running = false); }
+pub fn running() -> bool { STATE.lock(|s| s.running) }
+
+pub fn status() -> Status {
+    STATE.lock(|s| Status {
+        running: s.running, replicas: s.replicas, n: s.n, f: s.f(), quorum: s.quorum(), view: s.view, leader: s.leader(s.view),
+        executed: s.exec, view_change: s.vc.filter(|v| v.joined).map(|v| v.target),
+        queued: s.queue.iter().flatten().count(), keys: s.kv.iter().flatten().count(),
+    })
+}
+
+fn submit(op: Op, key: &str, value: &str) -> Result<u64, &'static str> {
+    let origin = own();
+    let now = crate::time::clock::now_ms();
+    STATE.lock(|s| {
+        if !s.running { return Err("store: not running"); }
+        let entry = Entry::new(op, origin, s.next_req, key, value)?;
+        let slot = s.queue.iter_mut().find(|q| q.is_none()).ok_or("store: too many writes in flight")?;
+        *slot = Some(Queued { entry, digest: entry.digest(), since: now, seq: 0 });
+        s.next_req += 1;
+        Ok(entry.req)
+    })
+}
+
+/// Queue a write of `key`. Returns its request number.
+pub fn put(key: &str, value: &str) -> Result<u64, &'static str> { submit(Op::Put, key, value) }
+
+pub fn delete(key: &str) -> Result<u64, &'static str> { submit(Op::Del, key, "") }
+
+/// Run `f` on the value of `key` in the local copy.
+pub fn get<R>(key: &str, f: impl FnOnce(&str) -> R) -> Option<R> {
+    let e = STATE.lock(|s| s.kv.iter().flatten().find(|k| k.entry.key() == key).map(|k| k.entry))?;
+    Some(f(e.value()))
+}
+
+/// Call `f(key, value)` for each key starting with `prefix`.
+pub fn for_each(prefix: &str, mut f: impl FnMut(&str, &str)) {
+    for i in 0..KV_MAX {
+        if let Some(k) = STATE.lock(|s| s.kv[i]) {
+            if k.entry.key().starts_with(prefix) { f(k.entry.key(), k.entry.value()); }
+        }
+    }
+}
+
+/// Call `f` for each executed entry still kept, oldest first.
+pub fn for_each_logged(mut f: impl FnMut(&Logged)) {
+    for i in 0..HISTORY {
+        let e = STATE.lock(|s| s.history[(s.hist_next + i) % HISTORY]);
+        if let Some(e) = e { f(&e); }
+    }
+}
+
+// ---- Control-plane state published into the store ----
+
+/// `<kind>/<node>/<id>` into `buf`.
+fn key_of<'a>(buf: &'a mut [u8; KEY_MAX + 8], kind: &[u8], node: u64, id: &[u8]) -> &'a str {
+    let mut n = 0;
+    for &b in kind { buf[n] = b; n += 1; }
+    buf[n] = b'/'; n += 1;
+    n += crate::util::format::u64_dec(node, &mut buf[n..]);
+    buf[n] = b'/'; n += 1;
+    for &b in id.iter().take(buf.len() - n) { buf[n] = b; n += 1; }
+    core::str::from_utf8(&buf[..n]).unwrap_or("")
+}
+
+/// Publish the registry entry of `vm_id`, or its removal.
+pub fn note_vm(vm_id: u64) {
+    if !running() { return; }
+    let mut id = [0u8; 20];
+    let len = crate::util::format::u64_dec(vm_id, &mut id);
+    let mut kb = [0u8; KEY_MAX + 8];
+    let key = key_of(&mut kb, b"vm", own(), &id[..len]);
+    let Some(info) = crate::hv::vm::find_vm(vm_id) else { let _ = delete(key); return };
+    let mut v = [0u8; VALUE_MAX + 32]; let mut n = 0;
+    for &b in b"name=" { v[n] = b; n += 1; }
+    for &b in info.name().as_bytes() { v[n] = b; n += 1; }
+    for &b in b" vcpus=" { v[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(info.vcpus as u64, &mut v[n..]);
+    for &b in b" mem=" { v[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(info.memory_bytes >> 20, &mut v[n..]);
+    for &b in b"MiB" { v[n] = b; n += 1; }
+    let _ = put(key, core::str::from_utf8(&v[..n.min(VALUE_MAX)]).unwrap_or(""));
+}
+
+/// Publish the IOMMU domain of a device (None: unassigned).
+pub fn note_iommu(seg: u16, bus: u8, dev: u8, func: u8, dom: Option<u16>) {
+    if !running() { return; }
+    let mut id = [0u8; 20]; let mut n = 0;
+    n += crate::util::format::u32_dec(seg as u32, &mut id[n..]);
+    id[n] = b':'; n += 1;
+    n += crate::util::format::u32_dec(bus as u32, &mut id[n..]);
+    id[n] = b':'; n += 1;
+    n += crate::util::format::u32_dec(dev as u32, &mut id[n..]);
+    id[n] = b'.'; n += 1;
+    n += crate::util::format::u32_dec(func as u32, &mut id[n..]);
+    let mut kb = [0u8; KEY_MAX + 8];
+    let key = key_of(&mut kb, b"iommu", own(), &id[..n]);
+    match dom {
+        Some(d) => {
+            let mut v = [0u8; 16]; let mut m = 0;
+            for &b in b"dom=" { v[m] = b; m += 1; }
+            m += crate::util::format::u32_dec(d as u32, &mut v[m..]);
+            let _ = put(key, core::str::from_utf8(&v[..m]).unwrap_or(""));
+        }
+        None => { let _ = delete(key); }
+    }
+}
+
+/// Publish the saved migration settings of this node.
+pub fn note_migrate() {
+    if !running() { return; }
+    let mut kb = [0u8; KEY_MAX + 8];
+    let key = key_of(&mut kb, b"migrate", own(), b"cfg");
+    let mut v = [0u8; VALUE_MAX]; let mut n = 0;
+    for &b in b"chunk=" { v[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(crate::migrate::get_chunk_size() as u64, &mut v[n..]);
+    for &b in b" mtu=" { v[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(crate::migrate::net_get_mtu() as u64, &mut v[n..]);
+    for &b in b" sink=" { v[n] = b; n += 1; }
+    let sink: &[u8] = match crate::migrate::get_default_sink() {
+        crate::migrate::ExportSink::Console => b"console",
+        crate::migrate::ExportSink::Null => b"null",
+        crate::migrate::ExportSink::Buffer => b"buffer",
+        crate::migrate::ExportSink::Snp => b"snp",
+        crate::migrate::ExportSink::Virtio => b"virtio",
+        crate::migrate::ExportSink::Rdma => b"rdma",
+    };
+    for &b in sink { v[n] = b; n += 1; }
+    let _ = put(key, core::str::from_utf8(&v[..n]).unwrap_or(""));
+}
+
+// ---- Protocol ----
+
+fn mac(key: &[u8; DIGEST_LEN], header: &[u8], body: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut pad = [0x36u8; 64];
+    for (p, k) in pad.iter_mut().zip(key.iter()) { *p ^= k; }
+    let mut h = Sha256::new();
+    h.update(&pad); h.update(header); h.update(body);
+    let inner = h.finish();
+    for p in pad.iter_mut() { *p ^= 0x36 ^ 0x5C; }
+    let mut h = Sha256::new();
+    h.update(&pad); h.update(&inner);
+    h.finish()
+}
+
+/// What `tick` sends: (phase, term, seq, aux, digest, entry).
+type Out = (u8, u64, u64, u64, [u8; DIGEST_LEN], Entry);
+
+fn send(system_table: &mut SystemTable<Boot>, cfg: &Config, key: &[u8; DIGEST_LEN], exec: u64, o: &Out) -> bool {
+    let msg = Msg { typ: MSG_LOG, cluster: cfg.cluster, node: cfg.node, term: o.1, seq: o.2, arg: exec };
+    let mut body = [0u8; BODY_LEN];
+    body[0] = o.0;
+    body[8..16].copy_from_slice(&o.3.to_le_bytes());
+    body[16..16 + DIGEST_LEN].copy_from_slice(&o.4);
+    o.5.encode(&mut body[16 + DIGEST_LEN..]);
+    let mut header = [0u8; MSG_LEN];
+    msg.encode(&mut header);
+    let tag = mac(key, &header, &body[..BODY_LEN - DIGEST_LEN]);
+    body[BODY_LEN - DIGEST_LEN..].copy_from_slice(&tag);
+    super::send_msg_body(system_table, cfg.link, cfg.mac, [0xFF; 6], &msg, &body)
+}
+
+fn bits(m: u8) -> usize { m.count_ones() as usize }
+
+/// `MSG_LOG` frame from another node: check it, then fold it into the state.
+/// Replies go out on the next `tick`.
+pub(super) fn on_frame(msg: &Msg, body: &[u8], now: u64) {
+    if body.len() < BODY_LEN { return; }
+    let mut header = [0u8; MSG_LEN];
+    msg.encode(&mut header);
+    let mut x = [0u8; 8]; x.copy_from_slice(&body[8..16]);
+    let aux = u64::from_le_bytes(x);
+    let mut digest = [0u8; DIGEST_LEN]; digest.copy_from_slice(&body[16..16 + DIGEST_LEN]);
+    let entry = Entry::decode(&body[16 + DIGEST_LEN..]);
+    let own = own();
+    STATE.lock(|s| {
+        if !s.running { return; }
+        let Some(from) = s.index(msg.node) else { return };
+        let tag = mac(&s.key, &header, &body[..BODY_LEN - DIGEST_LEN]);
+        // Compare without an early exit.
+        if tag.iter().zip(&body[BODY_LEN - DIGEST_LEN..BODY_LEN]).fold(0u8, |a, (x, y)| a | (x ^ y)) != 0 { return; }
+        if msg.arg > s.peer_exec[from] { s.peer_exec[from] = msg.arg; }
+        let bit = 1u8 << from;
+        match body[0] {
+            REQUEST => if let Some(e) = entry { enqueue(s, e, now); },
+            PRE_PREPARE => if let Some(e) = entry { pre_prepare(s, msg, from, &e, now, own); },
+            PREPARE | COMMIT => {
+                if !s.in_window(msg.seq) { return; }
+                let seq = msg.seq;
+                let slot = s.slot(seq);
+                let fresh = match slot { Some(x) => x.seq != seq, None => true };
+                if fresh {
+                    *slot = Some(Slot { seq, view: msg.term, digest, entry: Entry::NOP, pre: false, prepares: 0, commits: 0, locked: None, since: now });
+                }
+                let Some(x) = slot.as_mut() else { return };
+                if x.view != msg.term || x.digest != digest { return; }
+                if body[0] == PREPARE { x.prepares |= bit; } else { x.commits |= bit; }
+            }
+            VIEW_CHANGE => {
+                if msg.term <= s.view { return; }
+                let exec = s.exec;
+                let vc = match s.vc.as_mut() {
+                    Some(v) if v.target == msg.term => v,
+                    Some(v) if v.target > msg.term => return,
+                    _ => {
+                        s.vc = Some(ViewChange { target: msg.term, votes: 0, since: now, joined: false, carried: [None; WINDOW] });
+                        s.vc.as_mut().unwrap()
+                    }
+                };
+                vc.votes |= bit;
+                if let Some(e) = entry.filter(|e| msg.seq > exec && msg.seq <= exec + WINDOW as u64 && e.digest() == digest) {
+                    let c = &mut vc.carried[(msg.seq % WINDOW as u64) as usize];
+                    if !matches!(c, Some((seq, view, _)) if *seq == msg.seq && *view >= aux) { *c = Some((msg.seq, aux, e)); }
+                }
+            }
+            NEW_VIEW => {
+                if msg.term <= s.view || s.leader(msg.term) != msg.node { return; }
+                adopt_view(s, msg.term, now);
+            }
+            SNAP_REQ => s.snap.reply = Some(aux),
+            SNAP => s.snap.headers[from] = Some((msg.seq, digest)),
+            SNAP_ENTRY => {
+                let Some(e) = entry else { return };
+                if msg.node != s.snap.source || msg.seq != s.snap.seq || aux >= KV_MAX as u64 { return; }
+                s.snap.kv[aux as usize] = Some(Kv { entry: e });
+                s.snap.got |= 1u128 << aux;
+            }
+            _ => {}
+        }
+        // The source's header names the snapshot its entries belong to.
+        if body[0] == SNAP && msg.node == s.snap.source && s.snap.seq != msg.seq {
+            s.snap.seq = msg.seq;
+            s.snap.count = aux.min(KV_MAX as u64);
+            s.snap.got = 0;
+            s.snap.kv = [None; KV_MAX];
+        }
+    });
+}
+
+/// Whether an entry with `digest` is queued, in flight or recently executed.
+fn known(s: &State, digest: &[u8; DIGEST_LEN]) -> bool {
+    s.queue.iter().flatten().any(|q| q.digest == *digest)
+        || s.slots.iter().flatten().any(|x| x.digest == *digest && x.seq > s.exec)
+        || s.history.iter().flatten().any(|h| h.entry.digest() == *digest)
+}
+
+fn enqueue(s: &mut State, e: Entry, now: u64) {
+    if e.op == Op::Nop { return; }
+    let d = e.digest();
+    if known(s, &d) { return; }
+    if let Some(slot) = s.queue.iter_mut().find(|q| q.is_none()) { *slot = Some(Queued { entry: e, digest: d, since: now, seq: 0 }); }
+}
+
+fn pre_prepare(s: &mut State, msg: &Msg, from: usize, e: &Entry, now: u64, own: u64) {
+    if msg.term != s.view || s.leader(s.view) != msg.node || !s.in_window(msg.seq) { return; }
+    if s.vc.is_some_and(|v| v.joined) { return; }
+    let d = e.digest();
+    let me = match s.index(own) { Some(i) => 1u8 << i, None => return };
+    let seq = msg.seq;
+    let view = s.view;
+    let slot = s.slot(seq);
+    if let Some(x) = slot.as_ref().filter(|x| x.seq == seq) {
+        if x.pre && x.view == view && x.digest != d { return; }
+        if x.locked.is_some_and(|(ld, _)| ld != d) { return; }
+    }
+    let keep = matches!(slot, Some(x) if x.seq == seq && x.view == view && x.digest == d);
+    let (prepares, commits, locked, since) = match slot {
+        Some(x) if keep => (x.prepares, x.commits, x.locked, x.since),
+        Some(x) if x.seq == seq => (0, 0, x.locked, now),
+        _ => (0, 0, None, now),
+    };
+    *slot = Some(Slot { seq, view, digest: d, entry: *e, pre: true, prepares: prepares | (1 << from) | me, commits, locked, since });
+    for q in s.queue.iter_mut().flatten() { if q.digest == d { q.seq = seq; } }
+}
+
+/// Enter `view`: votes of older views no longer count, locks stay.
+fn adopt_view(s: &mut State, view: u64, now: u64) {
+    s.view = view;
+    s.vc = None;
+    s.proposed = s.exec;
+    let exec = s.exec;
+    for x in s.slots.iter_mut().flatten() {
+        if x.seq <= exec { continue; }
+        x.pre = false; x.prepares = 0; x.commits = 0; x.since = now;
+    }
+    for q in s.queue.iter_mut().flatten() { q.seq = 0; q.since = now; }
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::CLUSTER_LOG_VIEW_CHANGES).inc();
+}
+
+fn apply(s: &mut State, seq: u64, view: u64, e: &Entry) {
+    let applied = match e.op {
+        Op::Nop => true,
+        Op::Put => match s.kv.iter().position(|k| matches!(k, Some(k) if k.entry.key() == e.key())) {
+            Some(i) => { s.kv[i] = Some(Kv { entry: *e }); true }
+            None => match s.kv.iter_mut().find(|k| k.is_none()) {
+                Some(k) => { *k = Some(Kv { entry: *e }); true }
+                None => false,
+            },
+        },
+        Op::Del => {
+            for k in s.kv.iter_mut() { if matches!(k, Some(k) if k.entry.key() == e.key()) { *k = None; } }
+            true
+        }
+    };
+    s.history[s.hist_next] = Some(Logged { seq, view, entry: *e, applied });
+    s.hist_next = (s.hist_next + 1) % HISTORY;
+    let d = e.digest();
+    for q in s.queue.iter_mut() { if matches!(q, Some(q) if q.digest == d) { *q = None; } }
+}
+
+/// Digest of the map, entries taken in key order.
+fn map_digest(kv: &[Option<Kv>; KV_MAX]) -> [u8; DIGEST_LEN] {
+    let mut h = Sha256::new();
+    let mut last: Option<&str> = None;
+    loop {
+        let next = kv.iter().flatten().map(|k| k.entry.key()).filter(|k| last.map_or(true, |l| *k > l)).min();
+        let Some(key) = next else { break };
+        let k = kv.iter().flatten().find(|k| k.entry.key() == key).unwrap();
+        let mut b = [0u8; ENTRY_LEN];
+        k.entry.encode(&mut b);
+        h.update(&b);
+        last = Some(key);
+    }
+    h.finish()
+}
+
+/// Periodic work from `super::tick`: view changes, proposals, votes,
+/// execution and catch-up.
+pub(super) fn tick(system_table: &mut SystemTable<Boot>, cfg: &Config, now: u64) {
+    let timeout = cfg.timeout_ms as u64;
+    let mut out: [Option<Out>; 3 * WINDOW + 4] = [None; 3 * WINDOW + 4];
+    let mut nout = 0;
+    let mut push = |o: Out| if nout < out.len() { out[nout] = Some(o); nout += 1; };
+    let mut executed = 0u32;
+    let step = STATE.lock(|s| {
+        if !s.running { return None; }
+        let me_idx = s.index(cfg.node)?;
+        let me = 1u8 << me_idx;
+        let q = s.quorum();
+        let f = s.f();
+
+        // View change: stuck writes or proposals start one, f + 1 votes
+        // for a view pull this replica along, and a quorum installs it.
+        let stuck = s.queue.iter().flatten().any(|x| now.wrapping_sub(x.since) >= timeout)
+            || s.slots.iter().flatten().any(|x| x.seq > s.exec && x.pre && now.wrapping_sub(x.since) >= timeout);
+        let next = match s.vc {
+            Some(v) if v.joined && now.wrapping_sub(v.since) >= 2 * timeout => Some(v.target + 1),
+            Some(v) if !v.joined && bits(v.votes) > f => Some(v.target),
+            None if stuck && s.n > 1 => Some(s.view + 1),
+            _ => None,
+        };
+        if let Some(target) = next {
+            let mut vc = match s.vc { Some(v) if v.target == target => v, _ => ViewChange { target, votes: 0, since: now, joined: false, carried: [None; WINDOW] } };
+            if !vc.joined { vc.since = now; }
+            vc.joined = true;
+            vc.votes |= me;
+            s.vc = Some(vc);
+        }
+        if let Some(mut vc) = s.vc.filter(|v| v.joined) {
+            let exec = s.exec;
+            for x in s.slots.iter().flatten().filter(|x| x.seq > exec) {
+                let Some((d, view)) = x.locked else { continue };
+                if x.entry.digest() != d { continue; }
+                push((VIEW_CHANGE, vc.target, x.seq, view, d, x.entry));
+                let c = &mut vc.carried[(x.seq % WINDOW as u64) as usize];
+                if !matches!(c, Some((seq, v, _)) if *seq == x.seq && *v >= view) { *c = Some((x.seq, view, x.entry)); }
+            }
+            push((VIEW_CHANGE, vc.target, 0, 0, [0; DIGEST_LEN], Entry::NOP));
+            s.vc = Some(vc);
+            if s.leader(vc.target) == cfg.node && bits(vc.votes) >= q {
+                adopt_view(s, vc.target, now);
+                push((NEW_VIEW, vc.target, 0, 0, [0; DIGEST_LEN], Entry::NOP));
+                let last = vc.carried.iter().flatten().map(|c| c.0).filter(|&seq| seq > exec).max().unwrap_or(exec);
+                for seq in exec + 1..=last {
+                    let e = vc.carried.iter().flatten().find(|c| c.0 == seq).map_or(Entry::NOP, |c| c.2);
+                    let locked = s.slot(seq).and_then(|x| x.locked.filter(|_| x.seq == seq));
+                    *s.slot(seq) = Some(Slot { seq, view: vc.target, digest: e.digest(), entry: e, pre: true, prepares: me, commits: 0, locked, since: now });
+                }
+                s.proposed = last;
+            }
+        }
+
+        // Leader: give queued writes the next free sequence numbers.
+        if s.leader(s.view) == cfg.node && s.vc.is_none() {
+            for i in 0..QUEUE {
+                let Some(qd) = s.queue[i] else { continue };
+                if qd.seq != 0 { continue; }
+                let seq = s.proposed.max(s.exec) + 1;
+                if !s.in_window(seq) { break; }
+                let view = s.view;
+                *s.slot(seq) = Some(Slot { seq, view, digest: qd.digest, entry: qd.entry, pre: true, prepares: me, commits: 0, locked: None, since: now });
+                if let Some(x) = s.queue[i].as_mut() { x.seq = seq; }
+                s.proposed = seq;
+            }
+        }
+
+        // Votes, repeated every tick until the slot executes.
+        let leader = s.leader(s.view) == cfg.node;
+        let (view, exec) = (s.view, s.exec);
+        for x in s.slots.iter_mut().flatten() {
+            if x.seq <= exec || !x.pre || x.view != view { continue; }
+            if leader { push((PRE_PREPARE, view, x.seq, 0, x.digest, x.entry)); }
+            x.prepares |= me;
+            push((PREPARE, view, x.seq, 0, x.digest, Entry::NOP));
+            if bits(x.prepares) >= q {
+                x.locked = Some((x.digest, view));
+                x.commits |= me;
+                push((COMMIT, view, x.seq, 0, x.digest, Entry::NOP));
+            }
+        }
+
+        // Execute in order.
+        loop {
+            let seq = s.exec + 1;
+            let Some(x) = *s.slot(seq) else { break };
+            if x.seq != seq || !x.pre || x.locked.is_none() || bits(x.commits) < q { break; }
+            apply(s, seq, x.view, &x.entry);
+            s.exec = seq;
+            s.progress = now;
+            executed += 1;
+        }
+
+        // Followers hand their own writes to the leader.
+        if !leader {
+            for qd in s.queue.iter().flatten() {
+                if qd.seq == 0 && qd.entry.origin == cfg.node { push((REQUEST, s.view, 0, 0, qd.digest, qd.entry)); }
+            }
+        }
+
+        // Snapshots: answer a request, and ask for one when f + 1 replicas
+        // are ahead and nothing executed for a timeout.
+        let mut snap_entries = None;
+        if let Some(source) = s.snap.reply.take() {
+            let count = s.kv.iter().flatten().count() as u64;
+            push((SNAP, s.view, s.exec, count, map_digest(&s.kv), Entry::NOP));
+            if source == cfg.node { snap_entries = Some(s.exec); }
+        }
+        let ahead = s.peer_exec[..s.n].iter().filter(|&&e| e > s.exec).count();
+        if ahead > f && now.wrapping_sub(s.progress) >= timeout && now.wrapping_sub(s.snap.asked) >= timeout {
+            let best = (0..s.n).filter(|&i| i != me_idx).max_by_key(|&i| s.peer_exec[i]).map(|i| s.replicas[i]);
+            if let Some(src) = best {
+                s.snap.source = src; s.snap.seq = 0; s.snap.count = 0; s.snap.got = 0;
+                s.snap.headers = [None; MAX_REPLICAS];
+                s.snap.asked = now;
+                push((SNAP_REQ, s.view, 0, src, [0; DIGEST_LEN], Entry::NOP));
+            }
+        }
+        let complete = s.snap.seq > s.exec && s.snap.got.count_ones() as u64 == s.snap.count;
+        if complete {
+            let d = map_digest(&s.snap.kv);
+            let vouched = s.snap.headers[..s.n].iter().flatten().filter(|h| h.0 == s.snap.seq && h.1 == d).count();
+            if vouched > f {
+                s.kv = s.snap.kv;
+                s.exec = s.snap.seq;
+                s.proposed = s.proposed.max(s.exec);
+                s.progress = now;
+                s.snap.seq = 0;
+                // Writes proposed up to there were executed by the others.
+                let exec = s.exec;
+                for q in s.queue.iter_mut() {
+                    match q { Some(x) if x.seq != 0 && x.seq <= exec => *q = None, Some(x) => { x.seq = 0; x.since = now; } None => {} }
+                }
+                return Some((s.key, s.exec, snap_entries, Some(s.exec)));
+            }
+        }
+        Some((s.key, s.exec, snap_entries, None))
+    });
+    let Some((key, exec, snap_entries, installed)) = step else { return };
+    for o in out[..nout].iter().flatten() { let _ = send(system_table, cfg, &key, exec, o); }
+    if let Some(seq) = snap_entries {
+        let mut index = 0u64;
+        for i in 0..KV_MAX {
+            let Some(k) = STATE.lock(|s| s.kv[i]) else { continue };
+            let _ = send(system_table, cfg, &key, exec, &(SNAP_ENTRY, 0, seq, index, k.entry.digest(), k.entry));
+            index += 1;
+        }
+    }
+    if executed != 0 {
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::CLUSTER_LOG_COMMITS).add(executed as u64);
+    }
+    if let Some(seq) = installed {
+        let mut msg = [0u8; 48]; let mut n = 0;
+        for &b in b"installed snapshot at seq " { msg[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(seq, &mut msg[n..]);
+        crate::obs::log::info(system_table, "store", core::str::from_utf8(&msg[..n]).unwrap_or("snapshot"));
+    }
+}
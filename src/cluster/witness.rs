@@ -0,0 +1,253 @@
+#![allow(dead_code)]
+
+//! Tie-breaker for two-node clusters.
+//!
+//! A witness is a third vote that belongs to neither node. It is held as a
+//! lease: at most one node holds it at any time, and a node that loses its
+//! peer may only take over while it holds the lease.
+//!
+//! Disk witness: a shared block device (host disk index on each node, same
+//! LBA). Each node owns one block (lower node id at `lba`, higher at
+//! `lba + 1`) and rewrites it on every tick with an increasing counter, in
+//! the spirit of SBD. A node holds the lease if its own write succeeded and
+//! either the peer's counter stopped advancing for the peer timeout, or the
+//! peer is still alive on disk and this node has the lower id. The last rule
+//! resolves a pure network partition: both nodes still see each other on the
+//! disk and only the lower id keeps running.
+//!
+//! Endpoint witness: a third host running `cluster witness serve` on the same
+//! L2 segment. Nodes renew a lease with VOTE_REQ; the witness grants it to the
+//! current holder and to anyone once the holder stops renewing for the
+//! timeout carried in the request. A node only trusts a grant for half the
+//! timeout, measured from when it sent the request.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::vdev::net::Uplink;
+use crate::util::spinlock::SpinLock;
+use super::{Msg, MSG_VOTE_DENY, MSG_VOTE_GRANT, MSG_VOTE_REQ};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Witness {
+    None,
+    /// Two consecutive blocks on host disk `disk` (Block I/O handle index)
+    Disk { disk: u16, lba: u64 },
+    /// Host running `cluster witness serve`
+    Endpoint { mac: [u8; 6] },
+}
+
+/// Result of one witness round.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Vote {
+    /// Witness answered (disk I/O succeeded, endpoint replied) within the timeout
+    pub reachable: bool,
+    /// This node holds the witness lease
+    pub lease: bool,
+    /// Disk witness only: the peer's slot is still advancing
+    pub peer_alive: bool,
+}
+
+const SLOT_MAGIC: [u8; 4] = *b"ZVWT";
+const SLOT_VERSION: u8 = 1;
+const SLOT_LEN: usize = 44;
+/// Largest block size the disk witness handles
+const MAX_BLOCK: usize = 4096;
+const PENDING_REQS: usize = 4;
+const MAX_LEASES: usize = 4;
+const MAX_REPLIES: usize = 8;
+
+struct State {
+    counter: u64,
+    last_ok: u64,
+    peer_counter: u64,
+    /// `now_ms` when the peer's counter last changed (or was first seen)
+    peer_change: u64,
+    /// Endpoint: (seq, send time in ms) of recent requests
+    sent: [(u64, u64); PENDING_REQS],
+    /// Endpoint: lease trusted until this `now_ms`
+    lease_until: u64,
+    /// Endpoint: node currently holding the lease, as reported by the witness
+    holder: u64,
+    errors: u64,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    counter: 0, last_ok: 0, peer_counter: 0, peer_change: 0,
+    sent: [(0, 0); PENDING_REQS], lease_until: 0, holder: 0, errors: 0,
+});
+
+/// Reset all observations (configuration changed).
+pub fn reset() {
+    STATE.lock(|s| {
+        s.last_ok = 0; s.peer_counter = 0; s.peer_change = 0;
+        s.sent = [(0, 0); PENDING_REQS]; s.lease_until = 0; s.holder = 0;
+    });
+}
+
+/// Node reported as lease holder by the endpoint witness (0 = unknown).
+pub fn holder() -> u64 { STATE.lock(|s| s.holder) }
+pub fn errors() -> u64 { STATE.lock(|s| s.errors) }
+
+fn encode_slot(buf: &mut [u8], cluster: u32, node: u64, term: u64, counter: u64) {
+    buf[..SLOT_LEN].fill(0);
+    buf[0..4].copy_from_slice(&SLOT_MAGIC);
+    buf[4] = SLOT_VERSION;
+    buf[8..12].copy_from_slice(&cluster.to_le_bytes());
+    buf[16..24].copy_from_slice(&node.to_le_bytes());
+    buf[24..32].copy_from_slice(&term.to_le_bytes());
+    buf[32..40].copy_from_slice(&counter.to_le_bytes());
+    let crc = crate::util::crc32::crc32(&buf[..40]);
+    buf[40..44].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Counter of a valid slot written by `node` of `cluster`.
+fn decode_slot(buf: &[u8], cluster: u32, node: u64) -> Option<u64> {
+    if buf[0..4] != SLOT_MAGIC || buf[4] != SLOT_VERSION { return None; }
+    let le32 = |o: usize| u32::from_le_bytes([buf[o], buf[o + 1], buf[o + 2], buf[o + 3]]);
+    let le64 = |o: usize| { let mut b = [0u8; 8]; b.copy_from_slice(&buf[o..o + 8]); u64::from_le_bytes(b) };
+    if le32(40) != crate::util::crc32::crc32(&buf[..40]) { return None; }
+    if le32(8) != cluster || le64(16) != node { return None; }
+    Some(le64(32))
+}
+
+/// Read both slots, then write ours. Returns the peer's counter if its slot is valid.
+fn disk_round(system_table: &SystemTable<Boot>, disk: u16, lba: u64, cfg: &super::Config, term: u64, counter: u64) -> Result<Option<u64>, &'static str> {
+    let h = crate::hv::vdev::blk::host_disk_handle(system_table, disk as usize).ok_or("no such host disk")?;
+    let mut bio = crate::hv::vdev::blk::open_blockio(system_table, h).ok_or("Block I/O open failed")?;
+    let media = bio.media();
+    let bs = media.block_size() as usize;
+    if !media.is_media_present() || bs < SLOT_LEN || bs > MAX_BLOCK { return Err("unusable media"); }
+    if media.is_read_only() { return Err("media is read-only"); }
+    if lba.saturating_add(1) > media.last_block() { return Err("witness lba beyond end of disk"); }
+    let id = media.media_id();
+    let (mine, theirs) = if cfg.node < cfg.peer { (0usize, 1usize) } else { (1, 0) };
+    let mut buf = [0u8; 2 * MAX_BLOCK];
+    bio.read_blocks(id, lba, &mut buf[..2 * bs]).map_err(|_| "read failed")?;
+    let peer = decode_slot(&buf[theirs * bs..theirs * bs + bs], cfg.cluster, cfg.peer);
+    let slot = &mut buf[mine * bs..mine * bs + bs];
+    slot.fill(0);
+    encode_slot(slot, cfg.cluster, cfg.node, term, counter);
+    bio.write_blocks(id, lba + mine as u64, &buf[mine * bs..mine * bs + bs]).map_err(|_| "write failed")?;
+    let _ = bio.flush_blocks();
+    Ok(peer)
+}
+
+/// Run one witness round for `cfg` at `now` ms. Endpoint requests go out
+/// through `send`; their replies arrive later via `on_reply`.
+pub(crate) fn poll(system_table: &mut SystemTable<Boot>, cfg: &super::Config, term: u64, seq: u64, now: u64, mut send: impl FnMut(&mut SystemTable<Boot>, [u8; 6], &Msg) -> bool) -> Vote {
+    let timeout = cfg.timeout_ms as u64;
+    match cfg.witness {
+        Witness::None => Vote::default(),
+        Witness::Disk { disk, lba } => {
+            let counter = STATE.lock(|s| { s.counter += 1; s.counter });
+            let res = disk_round(system_table, disk, lba, cfg, term, counter);
+            STATE.lock(|s| {
+                let ok = match res {
+                    Ok(peer) => {
+                        s.last_ok = now;
+                        match peer {
+                            Some(c) if c != s.peer_counter || s.peer_change == 0 => { s.peer_counter = c; s.peer_change = now; }
+                            None if s.peer_change == 0 => s.peer_change = now,
+                            _ => {}
+                        }
+                        true
+                    }
+                    Err(_) => { s.errors += 1; false }
+                };
+                let peer_alive = s.peer_change != 0 && now.wrapping_sub(s.peer_change) < timeout;
+                let reachable = s.last_ok != 0 && now.wrapping_sub(s.last_ok) < timeout;
+                // The lease needs a successful write in this very round.
+                let lease = ok && (!peer_alive || cfg.node < cfg.peer);
+                Vote { reachable, lease, peer_alive }
+            })
+        }
+        Witness::Endpoint { mac } => {
+            let msg = Msg { typ: MSG_VOTE_REQ, cluster: cfg.cluster, node: cfg.node, term, seq, arg: cfg.timeout_ms as u64 };
+            if send(system_table, mac, &msg) {
+                STATE.lock(|s| { let i = (seq as usize) % PENDING_REQS; s.sent[i] = (seq, now); });
+            } else {
+                STATE.lock(|s| s.errors += 1);
+            }
+            STATE.lock(|s| Vote {
+                reachable: s.last_ok != 0 && now.wrapping_sub(s.last_ok) < timeout,
+                lease: s.lease_until != 0 && (s.lease_until.wrapping_sub(now) as i64) > 0,
+                peer_alive: false,
+            })
+        }
+    }
+}
+
+/// GRANT or DENY from the endpoint witness for one of our requests.
+pub(crate) fn on_reply(msg: &Msg, timeout_ms: u32, now: u64) {
+    STATE.lock(|s| {
+        let sent = s.sent[(msg.seq as usize) % PENDING_REQS];
+        if sent.0 != msg.seq || sent.1 == 0 { return; }
+        s.last_ok = now;
+        s.holder = msg.arg;
+        if msg.typ == MSG_VOTE_GRANT {
+            s.lease_until = sent.1.wrapping_add(timeout_ms as u64 / 2);
+        } else {
+            s.lease_until = 0;
+        }
+    });
+}
+
+// ---- Serving as the witness for other clusters ----
+
+#[derive(Clone, Copy)]
+struct Lease { cluster: u32, holder: u64, expires: u64 }
+
+struct Server {
+    link: Option<Uplink>,
+    leases: [Option<Lease>; MAX_LEASES],
+    /// Replies waiting for the next `serve_flush`
+    replies: [Option<([u8; 6], Msg)>; MAX_REPLIES],
+    granted: u64,
+    denied: u64,
+}
+
+static SERVER: SpinLock<Server> = SpinLock::new(Server { link: None, leases: [None; MAX_LEASES], replies: [None; MAX_REPLIES], granted: 0, denied: 0 });
+
+/// Start (`Some(link)`) or stop serving witness requests on `link`.
+pub fn serve(link: Option<Uplink>) {
+    SERVER.lock(|s| { s.link = link; s.leases = [None; MAX_LEASES]; s.replies = [None; MAX_REPLIES]; });
+}
+
+pub fn serving() -> Option<Uplink> { SERVER.lock(|s| s.link) }
+/// (granted, denied) requests since serving started.
+pub fn serve_stats() -> (u64, u64) { SERVER.lock(|s| (s.granted, s.denied)) }
+
+/// Decide a VOTE_REQ received from `src` and queue the reply.
+pub(crate) fn on_request(src: [u8; 6], msg: &Msg, now: u64) {
+    SERVER.lock(|s| {
+        if s.link.is_none() { return; }
+        let ttl = (msg.arg as u32).clamp(100, 600_000) as u64;
+        let slot = s.leases.iter().position(|l| matches!(l, Some(l) if l.cluster == msg.cluster))
+            .or_else(|| s.leases.iter().position(|l| l.is_none()))
+            .or_else(|| s.leases.iter().position(|l| matches!(l, Some(l) if (l.expires.wrapping_sub(now) as i64) <= 0)));
+        let grant = match slot {
+            Some(i) => match s.leases[i] {
+                Some(l) if l.cluster == msg.cluster && l.holder != msg.node && (l.expires.wrapping_sub(now) as i64) > 0 => false,
+                _ => { s.leases[i] = Some(Lease { cluster: msg.cluster, holder: msg.node, expires: now.wrapping_add(ttl) }); true }
+            },
+            None => false,
+        };
+        if grant { s.granted += 1; } else { s.denied += 1; }
+        let holder = slot.and_then(|i| s.leases[i]).map(|l| l.holder).unwrap_or(0);
+        let reply = Msg { typ: if grant { MSG_VOTE_GRANT } else { MSG_VOTE_DENY }, cluster: msg.cluster, node: msg.node, term: msg.term, seq: msg.seq, arg: holder };
+        // Unknown source address: answer on broadcast, nodes match the echoed node id.
+        let dst = if src == [0; 6] { [0xFF; 6] } else { src };
+        if let Some(r) = s.replies.iter_mut().find(|r| r.is_none()) { *r = Some((dst, reply)); }
+    });
+}
+
+/// Send queued replies. Returns the number sent.
+pub(crate) fn serve_flush(system_table: &mut SystemTable<Boot>, mut send: impl FnMut(&mut SystemTable<Boot>, Uplink, [u8; 6], &Msg) -> bool) -> usize {
+    let link = match serving() { Some(l) => l, None => return 0 };
+    let mut sent = 0usize;
+    while let Some((dst, msg)) = SERVER.lock(|s| s.replies.iter_mut().find_map(|r| r.take())) {
+        if send(system_table, link, dst, &msg) { sent += 1; }
+    }
+    sent
+}
@@ -0,0 +1,2314 @@
+#![allow(dead_code)]
+
+//! Management API: JSON routes under `/v1` on the `http` responder.
+//!
+//! The routes are listed once, in `api_routes!`, which expands to the
+//! dispatch table and to the OpenAPI 3 document served at
+//! `/v1/openapi.json`; the document is a `concat!` of literals, so it is
+//! built at compile time. `API_VERSION` is its `info.version`.
+//!
+//! Every route but the document needs `Authorization: Bearer <token>`. Each
+//! token, added with `http token add`, has a name and a `Role`; each route
+//! declares the `Capability` it needs (`x-capability` in the document) and
+//! the role must grant it. Refusals, unknown tokens included, are audited
+//! as `api_denied` with the token name and the client address. Only a
+//! SHA-256 of each secret is kept. Until a token exists the API answers
+//! 503, so turning on the metrics listener does not expose VM control.
+//! Errors come back as `{"error":"<module>: <message>"}`.
+
+use core::fmt::Write as _;
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use super::http::Request;
+use crate::cluster::placement;
+use crate::hv::vm::{self, VmInfo};
+use crate::util::format::BufWriter;
+use crate::util::sha256::{sha256, DIGEST_LEN};
+use crate::util::spinlock::SpinLock;
+
+const JSON: &str = "application/json";
+/// Shortest token secret accepted
+pub const TOKEN_MIN: usize = 16;
+const TOKEN_MAX: usize = 128;
+pub const MAX_TOKENS: usize = 8;
+/// Longest token name; names go into audit records whole
+pub const NAME_MAX: usize = 8;
+/// How long `stop` waits for the vCPUs to leave the guest
+const STOP_TIMEOUT_US: u64 = 200_000;
+
+/// What a route needs the caller's role to grant. Codes are stored in
+/// audit records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability { VmRead, VmCreate, VmStart, VmDestroy, MigrateExecute, MetricsRead, AttestRead, IommuModify, AuditRead, AuditWrite, ClusterRead, CarbonWrite, PowerWrite, NetworkWrite, StorageWrite }
+
+impl Capability {
+    pub fn code(self) -> u8 { self as u8 }
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::VmRead => "vm.read",
+            Capability::VmCreate => "vm.create",
+            Capability::VmStart => "vm.start",
+            Capability::VmDestroy => "vm.destroy",
+            Capability::MigrateExecute => "migrate.execute",
+            Capability::MetricsRead => "metrics.read",
+            Capability::AttestRead => "attest.read",
+            Capability::IommuModify => "iommu.modify",
+            Capability::AuditRead => "audit.read",
+            Capability::AuditWrite => "audit.write",
+            Capability::ClusterRead => "cluster.read",
+            Capability::CarbonWrite => "carbon.write",
+            Capability::PowerWrite => "power.write",
+            Capability::NetworkWrite => "network.write",
+            Capability::StorageWrite => "storage.write",
+        }
+    }
+}
+
+/// Capability for its name, for the route table.
+macro_rules! api_cap {
+    ("vm.read") => { Capability::VmRead };
+    ("vm.create") => { Capability::VmCreate };
+    ("vm.start") => { Capability::VmStart };
+    ("vm.destroy") => { Capability::VmDestroy };
+    ("migrate.execute") => { Capability::MigrateExecute };
+    ("metrics.read") => { Capability::MetricsRead };
+    ("attest.read") => { Capability::AttestRead };
+    ("iommu.modify") => { Capability::IommuModify };
+    ("audit.read") => { Capability::AuditRead };
+    ("audit.write") => { Capability::AuditWrite };
+    ("cluster.read") => { Capability::ClusterRead };
+    ("carbon.write") => { Capability::CarbonWrite };
+    ("power.write") => { Capability::PowerWrite };
+    ("network.write") => { Capability::NetworkWrite };
+    ("storage.write") => { Capability::StorageWrite };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Reads state, metrics, attestation and cluster membership
+    Viewer,
+    /// Also creates, starts and stops VMs, drives migration, feeds the
+    /// carbon signal, picks the DVFS governor, configures the switch,
+    /// manages storage volumes and reads the audit log
+    Operator,
+    /// Everything, destructive operations and audit retention included
+    Admin,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str { match self { Role::Viewer => "viewer", Role::Operator => "operator", Role::Admin => "admin" } }
+    pub fn parse(s: &str) -> Option<Role> {
+        match s { "viewer" => Some(Role::Viewer), "operator" => Some(Role::Operator), "admin" => Some(Role::Admin), _ => None }
+    }
+    pub fn allows(self, cap: Capability) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Operator => !matches!(cap, Capability::VmDestroy | Capability::IommuModify | Capability::AuditWrite),
+            Role::Viewer => matches!(cap, Capability::VmRead | Capability::MetricsRead | Capability::AttestRead | Capability::ClusterRead),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Token {
+    name: [u8; NAME_MAX],
+    name_len: u8,
+    role: Role,
+    /// SHA-256 of the secret
+    digest: [u8; DIGEST_LEN],
+}
+
+impl Token {
+    fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("?") }
+}
+
+static TOKENS: SpinLock<[Option<Token>; MAX_TOKENS]> = SpinLock::new([None; MAX_TOKENS]);
+
+/// Add a token, or replace the one with the same name.
+pub fn add_token(name: &str, role: Role, secret: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.len() > NAME_MAX || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-') {
+        return Err("api: token name must be 1..8 of [A-Za-z0-9_-]");
+    }
+    if secret.len() < TOKEN_MIN || secret.len() > TOKEN_MAX { return Err("api: token must be 16..128 characters"); }
+    if !secret.bytes().all(|b| b.is_ascii_graphic()) { return Err("api: token must be printable ASCII without spaces"); }
+    let mut t = Token { name: [0; NAME_MAX], name_len: name.len() as u8, role, digest: sha256(secret.as_bytes()) };
+    t.name[..name.len()].copy_from_slice(name.as_bytes());
+    TOKENS.lock(|ts| {
+        if ts.iter().flatten().any(|o| o.digest == t.digest && o.name() != name) { return Err("api: secret already used by another token"); }
+        let slot = ts.iter().position(|o| matches!(o, Some(o) if o.name() == name))
+            .or_else(|| ts.iter().position(|o| o.is_none()))
+            .ok_or("api: token table full")?;
+        ts[slot] = Some(t);
+        Ok(())
+    })
+}
+
+pub fn remove_token(name: &str) -> Result<(), &'static str> {
+    TOKENS.lock(|ts| {
+        let slot = ts.iter().position(|o| matches!(o, Some(o) if o.name() == name)).ok_or("api: no such token")?;
+        ts[slot] = None;
+        Ok(())
+    })
+}
+
+pub fn clear_tokens() { TOKENS.lock(|ts| *ts = [None; MAX_TOKENS]); }
+
+/// Name and role of each token.
+pub fn for_each_token(mut f: impl FnMut(&str, Role)) {
+    for t in TOKENS.lock(|ts| *ts).iter().flatten() { f(t.name(), t.role); }
+}
+
+pub fn enabled() -> bool { TOKENS.lock(|ts| ts.iter().any(|t| t.is_some())) }
+
+/// Who is calling, once the bearer token matched.
+struct Caller {
+    name: [u8; NAME_MAX],
+    role: Role,
+}
+
+/// Match the bearer token. On failure, the status and message to answer
+/// with and whether the attempt is worth auditing.
+fn authenticate(r: &Request) -> Result<Caller, (&'static str, &'static str, bool)> {
+    let tokens = TOKENS.lock(|ts| *ts);
+    if tokens.iter().all(|t| t.is_none()) { return Err(("503 Service Unavailable", "api: disabled (add a token with http token)", false)); }
+    let got = r.header("authorization").and_then(|v| {
+        let sp = v.iter().position(|&b| b == b' ')?;
+        if !v[..sp].eq_ignore_ascii_case(b"bearer") { return None; }
+        let t = &v[sp + 1..];
+        Some(&t[t.iter().position(|&b| b != b' ').unwrap_or(t.len())..])
+    });
+    let Some(got) = got else { return Err(("401 Unauthorized", "api: bearer token required", true)) };
+    let d = sha256(got);
+    // Compare against every token, without early exits
+    let mut found = None;
+    for t in tokens.iter().flatten() {
+        if d.iter().zip(t.digest.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0 { found = Some(*t); }
+    }
+    let t = found.ok_or(("403 Forbidden", "api: bad token", true))?;
+    Ok(Caller { name: t.name, role: t.role })
+}
+
+fn audit_denied(caller: [u8; NAME_MAX], cap: Capability, r: &Request) {
+    crate::diag::audit::record(crate::diag::audit::AuditKind::ApiDenied { caller, cap: cap.code(), peer: r.peer });
+}
+
+type Reply = (&'static str, &'static str);
+/// A route handler: the system table, the request, the `{vm}` path segment
+/// (empty when the route has none) and the body writer.
+type Handler = fn(&SystemTable<Boot>, &Request, &[u8], &mut BufWriter) -> Reply;
+
+struct Route {
+    /// Lower-case, as in the OpenAPI document
+    method: &'static str,
+    path: &'static str,
+    cap: Capability,
+    handler: Handler,
+}
+
+/// One operation of a path item. Each is written
+/// `(method handler "capability" "summary" [? query, ..] [<- RequestSchema] => "status" "content type" ResponseSchema [| ..])`,
+/// with one `|` alternative per further success status.
+macro_rules! api_op {
+    ($m:ident $h:ident $cap:literal $sum:literal $(? $q0:ident $(, $q:ident)*)? $(<- $req:ident)? => $st:literal $ct:literal $resp:ident $(| $st2:literal $ct2:literal $resp2:ident)*) => {
+        concat!(
+            "\"", stringify!($m), "\":{\"operationId\":\"", stringify!($h), "\",\"summary\":\"", $sum, "\",\"x-capability\":\"", $cap, "\"",
+            $(",\"parameters\":[", api_query!($q0) $(, ",", api_query!($q))*, "]",)?
+            $(",\"requestBody\":{\"required\":true,\"content\":{\"application/json\":{\"schema\":{\"$ref\":\"#/components/schemas/", stringify!($req), "\"}}}}",)?
+            ",\"responses\":{\"", $st, "\":{\"description\":\"Success\",\"content\":{\"", $ct, "\":{\"schema\":{\"$ref\":\"#/components/schemas/", stringify!($resp), "\"}}}},",
+            $("\"", $st2, "\":{\"description\":\"Success\",\"content\":{\"", $ct2, "\":{\"schema\":{\"$ref\":\"#/components/schemas/", stringify!($resp2), "\"}}}},",)*
+            "\"default\":{\"$ref\":\"#/components/responses/Error\"}}}"
+        )
+    };
+}
+
+macro_rules! api_query {
+    ($q:ident) => { concat!("{\"name\":\"", stringify!($q), "\",\"in\":\"query\",\"schema\":{\"type\":\"string\"}}") };
+}
+
+macro_rules! api_path_item {
+    (($($p:ident)?) $o0:tt $($o:tt)*) => {
+        concat!(
+            "{",
+            $("\"parameters\":[{\"name\":\"", stringify!($p), "\",\"in\":\"path\",\"required\":true,\"description\":\"Decimal id or name\",\"schema\":{\"type\":\"string\"}}],",)?
+            api_op! $o0 $(, ",", api_op! $o)*,
+            "}"
+        )
+    };
+}
+
+macro_rules! api_route {
+    ($path:literal, ($m:ident $h:ident $cap:tt $($rest:tt)*)) => { Route { method: stringify!($m), path: $path, cap: api_cap!($cap), handler: $h } };
+}
+
+/// The route table and the OpenAPI document, both from one list so they
+/// cannot drift apart.
+macro_rules! api_routes {
+    ($p0:literal $([$v0:ident])? { $($o0:tt)+ } $($p:literal $([$v:ident])? { $($o:tt)+ })*) => {
+        static ROUTES: &[Route] = &[$(api_route!($p0, $o0),)+ $($(api_route!($p, $o),)+)*];
+
+        /// OpenAPI 3 description of every route, served at `/v1/openapi.json`.
+        pub const OPENAPI: &str = concat!(
+            "{\"openapi\":\"3.0.3\",\"info\":{\"title\":\"Zerovisor management API\",\"version\":\"", api_version!(), "\"},",
+            "\"servers\":[{\"url\":\"/\"}],\"security\":[{\"bearer\":[]}],\"paths\":{",
+            "\"/v1/openapi.json\":{\"get\":{\"operationId\":\"openapi\",\"summary\":\"This document\",\"security\":[],",
+            "\"responses\":{\"200\":{\"description\":\"OpenAPI 3 document\",\"content\":{\"application/json\":{}}}}}},",
+            "\"", $p0, "\":", api_path_item!(($($v0)?) $($o0)+),
+            $(",\"", $p, "\":", api_path_item!(($($v)?) $($o)+),)*
+            "},\"components\":{\"securitySchemes\":{\"bearer\":{\"type\":\"http\",\"scheme\":\"bearer\"}},",
+            "\"responses\":{\"Error\":{\"description\":\"Failure\",\"content\":{\"application/json\":{\"schema\":{\"$ref\":\"#/components/schemas/Error\"}}}}},",
+            "\"schemas\":", api_schemas!(), "}}"
+        );
+    };
+}
+
+/// API semver. Additive changes bump the minor; anything that breaks a
+/// client moves the routes to a new `/v<n>` prefix.
+macro_rules! api_version { () => { "1.18.0" }; }
+pub const API_VERSION: &str = api_version!();
+
+macro_rules! api_schemas {
+    () => {
+        "{\
+\"Error\":{\"type\":\"object\",\"required\":[\"error\"],\"properties\":{\
+\"error\":{\"type\":\"string\"},\
+\"resource\":{\"type\":\"string\",\"description\":\"Admission: the constraining resource\"},\
+\"requested\":{\"type\":\"integer\"},\"available\":{\"type\":\"integer\"},\"limit\":{\"type\":\"integer\"},\
+\"vcpus_live\":{\"type\":\"integer\",\"description\":\"Stop: vCPUs that did not leave the guest\"},\
+\"capability\":{\"type\":\"string\",\"description\":\"403: the capability the caller's role lacks\"},\
+\"code\":{\"type\":\"string\",\"description\":\"CSI calls: the gRPC status code\"}}},\
+\"Vm\":{\"type\":\"object\",\"required\":[\"id\",\"name\",\"state\",\"vcpus\",\"memory_bytes\",\"vendor\",\"starts\"],\"properties\":{\
+\"id\":{\"type\":\"integer\"},\"name\":{\"type\":\"string\"},\
+\"state\":{\"type\":\"string\",\"enum\":[\"created\",\"running\",\"paused\",\"migrating\",\"stopped\"]},\
+\"vcpus\":{\"type\":\"integer\"},\"memory_bytes\":{\"type\":\"integer\"},\
+\"vendor\":{\"type\":\"string\",\"enum\":[\"intel\",\"amd\",\"unknown\"]},\"starts\":{\"type\":\"integer\"}}},\
+\"VmDetail\":{\"allOf\":[{\"$ref\":\"#/components/schemas/Vm\"},{\"type\":\"object\",\"required\":[\"destroyable\"],\"properties\":{\
+\"vcpus_live\":{\"type\":\"integer\"},\"exits\":{\"type\":\"integer\"},\"mem_reserved\":{\"type\":\"integer\"},\
+\"mem_backed\":{\"type\":\"integer\"},\"run_ms\":{\"type\":\"integer\"},\"destroyable\":{\"type\":\"boolean\"},\
+\"placement\":{\"$ref\":\"#/components/schemas/Placement\"},\"group\":{\"$ref\":\"#/components/schemas/Group\"}}}]},\
+\"Group\":{\"type\":\"object\",\"required\":[\"label\",\"rule\"],\"properties\":{\
+\"label\":{\"type\":\"string\"},\"rule\":{\"type\":\"string\",\"enum\":[\"affinity=host\",\"affinity=numa\",\"anti-affinity=host\",\"anti-affinity=numa\"]}}},\
+\"Placement\":{\"type\":\"object\",\"required\":[\"origin\",\"score\",\"origin_score\",\"candidates\"],\"properties\":{\
+\"origin\":{\"type\":\"integer\",\"description\":\"Node that chose this one\"},\
+\"score\":{\"type\":\"integer\",\"nullable\":true},\"origin_score\":{\"type\":\"integer\",\"nullable\":true},\
+\"best_remote\":{\"type\":\"integer\"},\"best_remote_score\":{\"type\":\"integer\"},\"candidates\":{\"type\":\"integer\"}}},\
+\"Forwarded\":{\"type\":\"object\",\"required\":[\"forwarded\",\"node\",\"ticket\"],\"properties\":{\
+\"forwarded\":{\"type\":\"boolean\"},\"node\":{\"type\":\"integer\"},\"ticket\":{\"type\":\"integer\"},\
+\"score\":{\"type\":\"integer\"},\"local_score\":{\"type\":\"integer\",\"nullable\":true}}},\
+\"VmList\":{\"type\":\"object\",\"required\":[\"vms\"],\"properties\":{\"vms\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/Vm\"}}}},\
+\"CreateVm\":{\"type\":\"object\",\"properties\":{\
+\"name\":{\"type\":\"string\",\"pattern\":\"^[A-Za-z][A-Za-z0-9_.-]*$\"},\
+\"vcpus\":{\"type\":\"integer\",\"minimum\":1,\"default\":1},\"memory_mib\":{\"type\":\"integer\",\"minimum\":1,\"default\":256},\
+\"hugepages_mib\":{\"type\":\"integer\",\"default\":0},\"devices\":{\"type\":\"integer\",\"default\":0},\
+\"placement\":{\"type\":\"string\",\"enum\":[\"auto\",\"local\"],\"description\":\"auto follows cluster placement\"},\
+\"group\":{\"type\":\"string\",\"pattern\":\"^[A-Za-z0-9_-]{1,16}$\"},\
+\"rule\":{\"type\":\"string\",\"enum\":[\"affinity=host\",\"affinity=numa\",\"anti-affinity=host\",\"anti-affinity=numa\"],\"default\":\"anti-affinity=host\"}}},\
+\"Destroyed\":{\"type\":\"object\",\"required\":[\"id\",\"destroyed\"],\"properties\":{\"id\":{\"type\":\"integer\"},\"destroyed\":{\"type\":\"boolean\"}}},\
+\"Started\":{\"type\":\"object\",\"required\":[\"id\",\"vcpus_started\"],\"properties\":{\"id\":{\"type\":\"integer\"},\"vcpus_started\":{\"type\":\"integer\"}}},\
+\"StartVm\":{\"type\":\"object\",\"properties\":{\
+\"defer\":{\"type\":\"boolean\",\"default\":false,\"description\":\"Batch start: wait for a low-carbon window\"},\
+\"hours\":{\"type\":\"integer\",\"minimum\":1,\"default\":1,\"description\":\"Expected batch length, for the energy estimate\"}}},\
+\"Deferred\":{\"type\":\"object\",\"required\":[\"vm\",\"deferred\",\"deadline\"],\"properties\":{\
+\"vm\":{\"type\":\"integer\"},\"deferred\":{\"type\":\"string\",\"enum\":[\"start\",\"migrate\"]},\
+\"deadline\":{\"type\":\"integer\",\"description\":\"Unix time by which it runs regardless of intensity\"}}},\
+\"Stopped\":{\"type\":\"object\",\"required\":[\"id\",\"stopped\"],\"properties\":{\"id\":{\"type\":\"integer\"},\"stopped\":{\"type\":\"boolean\"}}},\
+\"Migration\":{\"type\":\"object\",\"required\":[\"active\"],\"properties\":{\
+\"active\":{\"type\":\"boolean\"},\"vm\":{\"type\":\"integer\"},\"vm_state\":{\"type\":\"string\"},\"dirty_pages\":{\"type\":\"integer\"}}},\
+\"MigrationStart\":{\"type\":\"object\",\"required\":[\"vm\"],\"properties\":{\"vm\":{\"type\":\"integer\"},\
+\"defer\":{\"type\":\"boolean\",\"default\":false,\"description\":\"Wait for a low-carbon window\"}}},\
+\"Carbon\":{\"type\":\"object\",\"required\":[\"intensity_g\",\"source\",\"threshold_g\",\"max_defer_min\",\"avoided_mg\",\"queued\"],\"properties\":{\
+\"intensity_g\":{\"type\":\"integer\",\"nullable\":true,\"description\":\"gCO2e/kWh; null without a signal\"},\
+\"source\":{\"type\":\"string\",\"enum\":[\"none\",\"schedule\",\"sample\"]},\
+\"sample_until\":{\"type\":\"integer\",\"description\":\"Unix expiry of the pushed sample\"},\
+\"threshold_g\":{\"type\":\"integer\"},\"max_defer_min\":{\"type\":\"integer\"},\
+\"avoided_mg\":{\"type\":\"integer\",\"description\":\"Estimated emissions avoided so far, mg CO2e\"},\
+\"queued\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/CarbonJob\"}}}},\
+\"CarbonJob\":{\"type\":\"object\",\"required\":[\"vm\",\"kind\",\"energy_wh\",\"submit_g\",\"deadline\"],\"properties\":{\
+\"vm\":{\"type\":\"integer\"},\"kind\":{\"type\":\"string\",\"enum\":[\"start\",\"migrate\"]},\
+\"energy_wh\":{\"type\":\"integer\"},\"submit_g\":{\"type\":\"integer\"},\"deadline\":{\"type\":\"integer\"}}},\
+\"CarbonSample\":{\"type\":\"object\",\"required\":[\"intensity_g\"],\"properties\":{\
+\"intensity_g\":{\"type\":\"integer\",\"minimum\":0,\"maximum\":5000},\
+\"valid_min\":{\"type\":\"integer\",\"minimum\":1,\"default\":60}}},\
+\"Metrics\":{\"type\":\"string\",\"description\":\"Prometheus text exposition format 0.0.4\"},\
+\"AuditEvent\":{\"type\":\"object\",\"required\":[\"seq\",\"t_ms\",\"kind\"],\"additionalProperties\":true,\"properties\":{\
+\"seq\":{\"type\":\"integer\"},\"t_ms\":{\"type\":\"integer\",\"description\":\"Milliseconds, 0 before time calibration\"},\
+\"kind\":{\"type\":\"string\",\"enum\":[\"boot_start\",\"boot_ready\",\"vm_create\",\"vm_start\",\"vm_stop\",\"vm_destroy\",\"iommu_domain_create\",\"iommu_assign_add\",\"iommu_assign_del\",\"migrate_start\",\"migrate_scan\",\"migrate_stop\",\"tpm_pcr_extend\",\"cluster_mode\",\"iommu_fault\",\"iommu_quarantine\",\"pci_cfg_write\",\"guest_image_sig\",\"host_watchdog\",\"vm_heartbeat\",\"vm_state\",\"api_denied\",\"ha_evacuate\",\"ha_fence\",\"fpga_load\",\"plugin_load\",\"slot_rollback\"]}}},\
+\"AuditPage\":{\"type\":\"object\",\"required\":[\"events\",\"next\",\"returned\",\"lost\",\"more\"],\"properties\":{\
+\"events\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/AuditEvent\"}},\
+\"next\":{\"type\":\"integer\",\"description\":\"Cursor for the next call\"},\"returned\":{\"type\":\"integer\"},\
+\"lost\":{\"type\":\"integer\",\"description\":\"Events after the cursor that the ring overwrote\"},\"more\":{\"type\":\"boolean\"}}},\
+\"AuditRetention\":{\"type\":\"object\",\"required\":[\"size\",\"persist\",\"oldest\",\"next\"],\"properties\":{\
+\"size\":{\"type\":\"integer\",\"description\":\"Events kept in RAM\"},\"persist\":{\"type\":\"string\",\"enum\":[\"none\",\"var\"]},\
+\"oldest\":{\"type\":\"integer\",\"description\":\"Oldest sequence number still in RAM\"},\"next\":{\"type\":\"integer\"},\"saved\":{\"type\":\"boolean\"}}},\
+\"AuditRetentionSet\":{\"type\":\"object\",\"properties\":{\
+\"size\":{\"type\":\"integer\",\"minimum\":16,\"maximum\":1024},\"persist\":{\"type\":\"string\",\"enum\":[\"none\",\"var\"]},\
+\"save\":{\"type\":\"boolean\",\"default\":false,\"description\":\"Keep the policy across reboots\"}}},\
+\"Attestation\":{\"type\":\"object\",\"required\":[\"boot\",\"quote\"],\"properties\":{\
+\"boot\":{\"nullable\":true,\"allOf\":[{\"$ref\":\"#/components/schemas/BootMeasurement\"}]},\
+\"quote\":{\"nullable\":true,\"allOf\":[{\"$ref\":\"#/components/schemas/Quote\"}]},\
+\"error\":{\"type\":\"string\",\"description\":\"Why there is no quote\"}}},\
+\"BootMeasurement\":{\"type\":\"object\",\"required\":[\"events\",\"extended\",\"path\",\"file\",\"image\"],\"properties\":{\
+\"events\":{\"type\":\"integer\"},\"extended\":{\"type\":\"boolean\"},\"path\":{\"type\":\"string\"},\
+\"file\":{\"type\":\"string\",\"nullable\":true,\"description\":\"SHA-256 of zerovisor.efi, hex\"},\
+\"image\":{\"type\":\"string\",\"description\":\"Relocation-normalized SHA-256 of the loaded image, hex\"}}},\
+\"Quote\":{\"type\":\"object\",\"required\":[\"pcrs\",\"nonce\",\"attest\",\"sig_r\",\"sig_s\",\"ak_x\",\"ak_y\"],\"properties\":{\
+\"pcrs\":{\"type\":\"string\",\"description\":\"PCR mask, 0x-prefixed hex\"},\"nonce\":{\"type\":\"string\"},\
+\"attest\":{\"type\":\"string\",\"description\":\"TPMS_ATTEST, hex\"},\
+\"sig_r\":{\"type\":\"string\"},\"sig_s\":{\"type\":\"string\"},\"ak_x\":{\"type\":\"string\"},\"ak_y\":{\"type\":\"string\"}}},\
+\"Dvfs\":{\"type\":\"object\",\"required\":[\"governor\",\"pstate\",\"cpus\"],\"properties\":{\
+\"governor\":{\"type\":\"string\",\"enum\":[\"off\",\"performance\",\"balanced\",\"powersave\"]},\
+\"pstate\":{\"type\":\"string\",\"enum\":[\"none\",\"intel-hwp\",\"intel-eist\",\"amd\"]},\
+\"lowest\":{\"type\":\"integer\"},\"highest\":{\"type\":\"integer\"},\"turbo\":{\"type\":\"integer\"},\
+\"cpus\":{\"type\":\"array\",\"items\":{\"type\":\"object\",\"required\":[\"cpu\",\"util_pct\",\"level\",\"state\",\"transitions\"],\"properties\":{\
+\"cpu\":{\"type\":\"integer\"},\"util_pct\":{\"type\":\"integer\"},\"level\":{\"type\":\"integer\",\"description\":\"0 slowest, 100 fastest\"},\
+\"state\":{\"type\":\"integer\",\"description\":\"Core ratio, HWP performance or AMD P-state\"},\"transitions\":{\"type\":\"integer\"}}}}}},\
+\"GpuList\":{\"type\":\"object\",\"required\":[\"gpus\"],\"properties\":{\"gpus\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/Gpu\"}}}},\
+\"Gpu\":{\"type\":\"object\",\"required\":[\"gpu\",\"vendor\",\"device\",\"max_slices\",\"slices\",\"fb_bytes\",\"carved\"],\"properties\":{\
+\"gpu\":{\"type\":\"string\",\"description\":\"PCI address, seg:bus:dev.fn\"},\"vendor\":{\"type\":\"integer\"},\"device\":{\"type\":\"integer\"},\
+\"max_slices\":{\"type\":\"integer\",\"description\":\"TotalVFs\"},\"slices\":{\"type\":\"integer\",\"description\":\"0 while whole; each slice is a 1/slices profile\"},\
+\"fb_bytes\":{\"type\":\"integer\",\"description\":\"Framebuffer aperture of each slice\"},\
+\"carved\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/GpuSlice\"}}}},\
+\"GpuSlice\":{\"type\":\"object\",\"required\":[\"index\",\"vf\",\"vm\",\"mmio_ops\",\"mmio_ops_per_s\",\"faults\",\"quarantined\"],\"properties\":{\
+\"index\":{\"type\":\"integer\"},\"vf\":{\"type\":\"string\"},\"vm\":{\"type\":\"integer\",\"nullable\":true},\
+\"mmio_ops\":{\"type\":\"integer\",\"description\":\"Guest BAR accesses forwarded since attach\"},\"mmio_ops_per_s\":{\"type\":\"integer\"},\
+\"faults\":{\"type\":\"integer\",\"description\":\"IOMMU faults logged for the VF\"},\"quarantined\":{\"type\":\"boolean\"}}},\
+\"GpuCarve\":{\"type\":\"object\",\"required\":[\"gpu\",\"slices\"],\"properties\":{\
+\"gpu\":{\"type\":\"string\"},\"slices\":{\"type\":\"integer\",\"minimum\":0}}},\
+\"GpuAttach\":{\"type\":\"object\",\"required\":[\"gpu\"],\"properties\":{\
+\"gpu\":{\"type\":\"string\"},\"slice\":{\"type\":\"integer\",\"description\":\"Default: the first free slice\"}}},\
+\"GpuDetached\":{\"type\":\"object\",\"required\":[\"id\",\"detached\"],\"properties\":{\"id\":{\"type\":\"integer\"},\"detached\":{\"type\":\"integer\"}}},\
+\"FpgaList\":{\"type\":\"object\",\"required\":[\"fpgas\"],\"properties\":{\"fpgas\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/Fpga\"}}}},\
+\"Fpga\":{\"type\":\"object\",\"required\":[\"fpga\",\"vendor\",\"device\",\"reconfigurable\",\"regions\"],\"properties\":{\
+\"fpga\":{\"type\":\"string\",\"description\":\"PCI address of the PF, seg:bus:dev.fn\"},\"vendor\":{\"type\":\"integer\"},\"device\":{\"type\":\"integer\"},\
+\"reconfigurable\":{\"type\":\"boolean\",\"description\":\"The FME has a partial-reconfiguration engine\"},\
+\"regions\":{\"type\":\"array\",\"items\":{\"type\":\"object\",\"required\":[\"region\",\"access\",\"bitstream\",\"vm\"],\"properties\":{\
+\"region\":{\"type\":\"integer\"},\"access\":{\"type\":\"string\",\"enum\":[\"pf\",\"vf\"]},\
+\"bitstream\":{\"type\":\"string\",\"nullable\":true,\"description\":\"ESP path loaded since boot\"},\
+\"sig\":{\"type\":\"string\",\"enum\":[\"unsigned\",\"trusted\",\"untrusted\",\"malformed\"]},\
+\"vm\":{\"type\":\"integer\",\"nullable\":true},\"vf\":{\"type\":\"string\"}}}}}},\
+\"FpgaLoad\":{\"type\":\"object\",\"required\":[\"fpga\",\"region\",\"path\"],\"properties\":{\
+\"fpga\":{\"type\":\"string\"},\"region\":{\"type\":\"integer\",\"minimum\":0,\"maximum\":3},\"path\":{\"type\":\"string\"}}},\
+\"FpgaAssign\":{\"type\":\"object\",\"required\":[\"fpga\",\"region\"],\"properties\":{\
+\"fpga\":{\"type\":\"string\"},\"region\":{\"type\":\"integer\",\"minimum\":0,\"maximum\":3}}},\
+\"FpgaAssigned\":{\"type\":\"object\",\"required\":[\"id\",\"fpga\",\"region\",\"vf\"],\"properties\":{\
+\"id\":{\"type\":\"integer\"},\"fpga\":{\"type\":\"string\"},\"region\":{\"type\":\"integer\"},\"vf\":{\"type\":\"string\"}}},\
+\"FpgaReleased\":{\"type\":\"object\",\"required\":[\"id\",\"released\"],\"properties\":{\"id\":{\"type\":\"integer\"},\"released\":{\"type\":\"integer\"}}},\
+\"DvfsGovernor\":{\"type\":\"object\",\"required\":[\"governor\"],\"properties\":{\
+\"governor\":{\"type\":\"string\",\"enum\":[\"off\",\"performance\",\"balanced\",\"powersave\"]}}},\
+\"Cluster\":{\"type\":\"object\",\"required\":[\"configured\",\"mode\",\"members\"],\"properties\":{\
+\"configured\":{\"type\":\"boolean\"},\"cluster\":{\"type\":\"integer\"},\"node\":{\"type\":\"integer\"},\
+\"mode\":{\"type\":\"string\"},\"quorate\":{\"type\":\"boolean\"},\"term\":{\"type\":\"integer\"},\
+\"suspect_ms\":{\"type\":\"integer\"},\"dead_ms\":{\"type\":\"integer\"},\
+\"members\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/Member\"}}}},\
+\"Member\":{\"type\":\"object\",\"required\":[\"node\",\"mac\",\"state\",\"heartbeat\",\"age_ms\",\"static\"],\"properties\":{\
+\"node\":{\"type\":\"integer\"},\"mac\":{\"type\":\"string\"},\
+\"state\":{\"type\":\"string\",\"enum\":[\"alive\",\"suspect\",\"dead\"]},\"heartbeat\":{\"type\":\"integer\"},\
+\"age_ms\":{\"type\":\"integer\",\"nullable\":true,\"description\":\"Since the heartbeat last moved; null if never heard\"},\
+\"static\":{\"type\":\"boolean\",\"description\":\"Listed with cluster peers add\"}}},\
+\"Switch\":{\"type\":\"object\",\"required\":[\"aging_s\",\"ports\",\"fdb\"],\"properties\":{\
+\"aging_s\":{\"type\":\"integer\"},\
+\"ports\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/SwitchPort\"}},\
+\"fdb\":{\"type\":\"array\",\"items\":{\"type\":\"object\",\"required\":[\"mac\",\"vlan\",\"port\",\"static\"],\"properties\":{\
+\"mac\":{\"type\":\"string\"},\"vlan\":{\"type\":\"integer\"},\"port\":{\"type\":\"string\"},\
+\"static\":{\"type\":\"boolean\",\"description\":\"A NIC's own MAC\"},\"age_ms\":{\"type\":\"integer\"}}}}}},\
+\"SwitchPort\":{\"type\":\"object\",\"required\":[\"port\",\"vlan\",\"flood\",\"broadcast_per_s\",\"rx\",\"forwarded\",\"flooded\",\"filtered\",\"storm_drops\",\"vlan_drops\"],\"properties\":{\
+\"port\":{\"type\":\"string\",\"description\":\"nic<n> or uplink\"},\"vm\":{\"type\":\"integer\"},\
+\"vlan\":{\"type\":\"integer\",\"description\":\"Access VLAN; 0 for a trunk\"},\"flood\":{\"type\":\"boolean\"},\
+\"broadcast_per_s\":{\"type\":\"integer\",\"description\":\"0 for no limit\"},\
+\"rx\":{\"type\":\"integer\"},\"forwarded\":{\"type\":\"integer\"},\"flooded\":{\"type\":\"integer\"},\
+\"filtered\":{\"type\":\"integer\"},\"storm_drops\":{\"type\":\"integer\"},\"vlan_drops\":{\"type\":\"integer\"}}},\
+\"SwitchConfig\":{\"type\":\"object\",\"properties\":{\
+\"port\":{\"type\":\"string\",\"description\":\"nic<n> or uplink; needed for the port settings\"},\
+\"vlan\":{\"type\":\"integer\",\"minimum\":0,\"maximum\":4094},\"flood\":{\"type\":\"boolean\"},\
+\"broadcast_per_s\":{\"type\":\"integer\",\"minimum\":0},\"aging_s\":{\"type\":\"integer\",\"minimum\":1}}},\
+\"VmVlan\":{\"type\":\"object\",\"required\":[\"vlan\"],\"properties\":{\
+\"vlan\":{\"type\":\"integer\",\"minimum\":0,\"maximum\":4094,\"description\":\"0 makes the VM's NICs trunks again\"}}},\
+\"Storage\":{\"type\":\"object\",\"required\":[\"pool\",\"volumes\"],\"properties\":{\
+\"pool\":{\"nullable\":true,\"type\":\"object\",\"required\":[\"lba\",\"block_size\",\"generation\",\"capacity\",\"used\",\"provisioned\",\"provision_limit\",\"dirty\"],\"properties\":{\
+\"disk\":{\"type\":\"integer\",\"description\":\"Host Block I/O disk; absent for an NVMe pool\"},\
+\"nvme\":{\"type\":\"string\",\"description\":\"NVMe namespace as <controller>n<nsid>; absent for a Block I/O pool\"},\
+\"lba\":{\"type\":\"integer\"},\"block_size\":{\"type\":\"integer\"},\"generation\":{\"type\":\"integer\"},\
+\"capacity\":{\"type\":\"integer\",\"description\":\"Bytes of data chunks\"},\"used\":{\"type\":\"integer\"},\
+\"provisioned\":{\"type\":\"integer\",\"description\":\"Sum of volume sizes\"},\"provision_limit\":{\"type\":\"integer\"},\
+\"dirty\":{\"type\":\"boolean\",\"description\":\"Allocations not yet in the on-disk metadata\"}}},\
+\"volumes\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/Volume\"}}}},\
+\"Volume\":{\"type\":\"object\",\"required\":[\"id\",\"name\",\"snapshot\",\"size\",\"allocated\",\"exclusive\"],\"properties\":{\
+\"id\":{\"type\":\"integer\"},\"name\":{\"type\":\"string\"},\"snapshot\":{\"type\":\"boolean\",\"description\":\"Read-only\"},\
+\"parent\":{\"type\":\"integer\",\"description\":\"Volume it was snapshotted or cloned from\"},\
+\"size\":{\"type\":\"integer\"},\"allocated\":{\"type\":\"integer\",\"description\":\"Bytes with a chunk behind them\"},\
+\"exclusive\":{\"type\":\"integer\",\"description\":\"Bytes no other volume shares; freed by deleting it\"}}},\
+\"VolumeCreate\":{\"type\":\"object\",\"required\":[\"name\"],\"properties\":{\
+\"name\":{\"type\":\"string\",\"maxLength\":128},\"size_mib\":{\"type\":\"integer\",\"minimum\":1,\"description\":\"New empty volume\"},\
+\"from\":{\"type\":\"string\",\"description\":\"Volume id or name to copy instead\"},\
+\"snapshot\":{\"type\":\"boolean\",\"description\":\"With from: read-only snapshot rather than writable clone\"}}},\
+\"VolumeAttach\":{\"type\":\"object\",\"required\":[\"volume\"],\"properties\":{\
+\"volume\":{\"type\":\"string\",\"description\":\"Volume id or name\"},\"read_only\":{\"type\":\"boolean\"}}},\
+\"VolumeAttached\":{\"type\":\"object\",\"required\":[\"id\",\"disk\",\"pci\",\"volume\",\"read_only\"],\"properties\":{\
+\"id\":{\"type\":\"integer\"},\"disk\":{\"type\":\"integer\"},\"pci\":{\"type\":\"string\"},\"volume\":{\"type\":\"integer\"},\"read_only\":{\"type\":\"boolean\"}}},\
+\"CsiPlugin\":{\"type\":\"object\",\"required\":[\"name\",\"vendor_version\",\"controller_capabilities\",\"access_modes\"],\"properties\":{\
+\"name\":{\"type\":\"string\"},\"vendor_version\":{\"type\":\"string\"},\
+\"controller_capabilities\":{\"type\":\"array\",\"items\":{\"type\":\"string\"}},\"access_modes\":{\"type\":\"array\",\"items\":{\"type\":\"string\"}}}},\
+\"CsiCreate\":{\"type\":\"object\",\"required\":[\"name\"],\"properties\":{\
+\"name\":{\"type\":\"string\",\"maxLength\":128,\"description\":\"Idempotency key; becomes the volume name\"},\
+\"capacity_range\":{\"type\":\"object\",\"properties\":{\"required_bytes\":{\"type\":\"integer\"},\"limit_bytes\":{\"type\":\"integer\"}}},\
+\"volume_capabilities\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/CsiCapability\"}},\
+\"volume_content_source\":{\"type\":\"object\",\"properties\":{\
+\"volume\":{\"type\":\"object\",\"properties\":{\"volume_id\":{\"type\":\"string\"}}},\
+\"snapshot\":{\"type\":\"object\",\"properties\":{\"snapshot_id\":{\"type\":\"string\",\"description\":\"Id of a snapshot volume\"}}}}}}},\
+\"CsiCapability\":{\"type\":\"object\",\"properties\":{\"access_mode\":{\"type\":\"object\",\"properties\":{\
+\"mode\":{\"type\":\"string\",\"enum\":[\"SINGLE_NODE_WRITER\",\"SINGLE_NODE_SINGLE_WRITER\",\"SINGLE_NODE_MULTI_WRITER\",\"SINGLE_NODE_READER_ONLY\",\"MULTI_NODE_READER_ONLY\"]}}}}},\
+\"CsiVolume\":{\"type\":\"object\",\"required\":[\"volume\"],\"properties\":{\"volume\":{\"type\":\"object\",\"required\":[\"volume_id\",\"capacity_bytes\",\"volume_context\"],\"properties\":{\
+\"volume_id\":{\"type\":\"string\",\"description\":\"Decimal storage volume id\"},\"capacity_bytes\":{\"type\":\"integer\"},\
+\"volume_context\":{\"type\":\"object\",\"additionalProperties\":{\"type\":\"string\"}},\
+\"content_source\":{\"type\":\"object\"}}}}},\
+\"CsiVolumeList\":{\"type\":\"object\",\"required\":[\"entries\"],\"properties\":{\"entries\":{\"type\":\"array\",\"items\":{\"allOf\":[{\"$ref\":\"#/components/schemas/CsiVolume\"},\
+{\"type\":\"object\",\"properties\":{\"status\":{\"type\":\"object\",\"properties\":{\"published_node_ids\":{\"type\":\"array\",\"items\":{\"type\":\"string\"}}}}}}]}}}},\
+\"CsiPublish\":{\"type\":\"object\",\"required\":[\"node_id\"],\"properties\":{\
+\"node_id\":{\"type\":\"string\",\"description\":\"VM id or name\"},\"readonly\":{\"type\":\"boolean\"},\
+\"volume_capability\":{\"$ref\":\"#/components/schemas/CsiCapability\"}}},\
+\"CsiPublished\":{\"type\":\"object\",\"required\":[\"publish_context\"],\"properties\":{\"publish_context\":{\"type\":\"object\",\"properties\":{\
+\"disk\":{\"type\":\"string\"},\"pci\":{\"type\":\"string\",\"description\":\"Bus 0 address in the VM\"},\"read_only\":{\"type\":\"string\"}}}}},\
+\"CsiUnpublish\":{\"type\":\"object\",\"properties\":{\"node_id\":{\"type\":\"string\",\"description\":\"VM id or name; absent for every VM\"}}},\
+\"CsiEmpty\":{\"type\":\"object\"},\
+\"CsiCapacity\":{\"type\":\"object\",\"required\":[\"available_capacity\",\"maximum_volume_size\",\"minimum_volume_size\"],\"properties\":{\
+\"available_capacity\":{\"type\":\"integer\",\"description\":\"Bytes CreateVolume can still provision\"},\
+\"maximum_volume_size\":{\"type\":\"integer\"},\"minimum_volume_size\":{\"type\":\"integer\"}}},\
+\"CniNetwork\":{\"type\":\"object\",\"required\":[\"name\",\"bridge\",\"ipam\"],\"properties\":{\
+\"cniVersion\":{\"type\":\"string\"},\"name\":{\"type\":\"string\",\"maxLength\":32},\"type\":{\"type\":\"string\",\"enum\":[\"bridge\"]},\
+\"bridge\":{\"type\":\"string\",\"maxLength\":15},\"vlan\":{\"type\":\"integer\",\"minimum\":0,\"maximum\":4094,\"description\":\"Switch VLAN of the bridge; 0 for trunk NICs\"},\
+\"ipam\":{\"type\":\"object\",\"required\":[\"subnet\"],\"properties\":{\"type\":{\"type\":\"string\",\"enum\":[\"host-local\"]},\
+\"subnet\":{\"type\":\"string\",\"description\":\"IPv4 CIDR, prefix 8..30\"},\"rangeStart\":{\"type\":\"string\"},\"rangeEnd\":{\"type\":\"string\"},\"gateway\":{\"type\":\"string\"},\
+\"routes\":{\"type\":\"array\",\"maxItems\":4,\"items\":{\"type\":\"object\",\"required\":[\"dst\"],\"properties\":{\"dst\":{\"type\":\"string\"},\"gw\":{\"type\":\"string\"}}}}}}}},\
+\"CniNetworkList\":{\"type\":\"object\",\"required\":[\"networks\"],\"properties\":{\"networks\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/CniNetwork\"}}}},\
+\"CniCommand\":{\"type\":\"object\",\"required\":[\"command\",\"network\"],\"properties\":{\
+\"command\":{\"type\":\"string\",\"enum\":[\"ADD\",\"CHECK\",\"DEL\"]},\"network\":{\"type\":\"string\"},\
+\"if_name\":{\"type\":\"string\",\"maxLength\":15,\"description\":\"Default eth0\"},\"mac\":{\"type\":\"string\",\"description\":\"ADD: default 52:54 and the address\"}}},\
+\"CniResult\":{\"type\":\"object\",\"description\":\"CNI result document; empty for DEL\",\"properties\":{\
+\"cniVersion\":{\"type\":\"string\"},\
+\"interfaces\":{\"type\":\"array\",\"items\":{\"type\":\"object\",\"properties\":{\"name\":{\"type\":\"string\"},\"mac\":{\"type\":\"string\"},\"sandbox\":{\"type\":\"string\",\"description\":\"VM id\"}}}},\
+\"ips\":{\"type\":\"array\",\"items\":{\"type\":\"object\",\"properties\":{\"address\":{\"type\":\"string\"},\"gateway\":{\"type\":\"string\"},\"interface\":{\"type\":\"integer\"}}}},\
+\"routes\":{\"type\":\"array\",\"items\":{\"type\":\"object\",\"properties\":{\"dst\":{\"type\":\"string\"},\"gw\":{\"type\":\"string\"}}}},\
+\"dns\":{\"type\":\"object\"}}},\
+\"CniAttachments\":{\"type\":\"object\",\"required\":[\"attachments\"],\"properties\":{\"attachments\":{\"type\":\"array\",\"items\":{\"type\":\"object\",\
+\"required\":[\"network\",\"if_name\",\"nic\",\"result\"],\"properties\":{\"network\":{\"type\":\"string\"},\"if_name\":{\"type\":\"string\"},\
+\"nic\":{\"type\":\"integer\",\"nullable\":true,\"description\":\"NIC index; null until the VM starts\"},\"result\":{\"$ref\":\"#/components/schemas/CniResult\"}}}}}},\
+\"CriVersion\":{\"type\":\"object\",\"required\":[\"version\",\"runtime_name\",\"runtime_version\",\"runtime_api_version\"],\"properties\":{\
+\"version\":{\"type\":\"string\"},\"runtime_name\":{\"type\":\"string\"},\"runtime_version\":{\"type\":\"string\"},\"runtime_api_version\":{\"type\":\"string\"}}},\
+\"CriMetadata\":{\"type\":\"object\",\"required\":[\"name\"],\"properties\":{\"name\":{\"type\":\"string\",\"maxLength\":63},\
+\"namespace\":{\"type\":\"string\",\"maxLength\":63,\"description\":\"Pods only\"},\"uid\":{\"type\":\"string\",\"maxLength\":63,\"description\":\"Pods only\"},\"attempt\":{\"type\":\"integer\"}}},\
+\"CriRunSandbox\":{\"type\":\"object\",\"required\":[\"metadata\"],\"properties\":{\"metadata\":{\"$ref\":\"#/components/schemas/CriMetadata\"},\
+\"kernel\":{\"type\":\"string\",\"description\":\"ESP path of the pod kernel; default pod.bzimage\"},\"cmdline\":{\"type\":\"string\",\"description\":\"Default console=ttyS0\"},\
+\"vcpus\":{\"type\":\"integer\",\"minimum\":1},\"memory_mib\":{\"type\":\"integer\",\"minimum\":1},\"network\":{\"type\":\"string\",\"description\":\"CNI network for eth0\"}}},\
+\"CriSandbox\":{\"type\":\"object\",\"required\":[\"id\",\"metadata\",\"state\",\"created_at\"],\"properties\":{\
+\"id\":{\"type\":\"string\",\"description\":\"VM id\"},\"metadata\":{\"$ref\":\"#/components/schemas/CriMetadata\"},\
+\"state\":{\"type\":\"string\",\"enum\":[\"SANDBOX_READY\",\"SANDBOX_NOTREADY\"]},\"created_at\":{\"type\":\"integer\",\"description\":\"Unix nanoseconds\"},\
+\"network\":{\"type\":\"object\",\"properties\":{\"ip\":{\"type\":\"string\"}}}}},\
+\"CriSandboxList\":{\"type\":\"object\",\"required\":[\"items\"],\"properties\":{\"items\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/CriSandbox\"}}}},\
+\"CriCreateContainer\":{\"type\":\"object\",\"required\":[\"pod_sandbox_id\",\"metadata\",\"image\",\"command\"],\"properties\":{\
+\"pod_sandbox_id\":{\"type\":\"string\"},\"metadata\":{\"$ref\":\"#/components/schemas/CriMetadata\"},\
+\"image\":{\"type\":\"object\",\"required\":[\"image\"],\"properties\":{\"image\":{\"type\":\"string\",\"maxLength\":128}}},\
+\"command\":{\"type\":\"array\",\"items\":{\"type\":\"string\"}},\"args\":{\"type\":\"array\",\"items\":{\"type\":\"string\"}},\"working_dir\":{\"type\":\"string\"},\
+\"envs\":{\"type\":\"array\",\"items\":{\"type\":\"object\",\"required\":[\"key\"],\"properties\":{\"key\":{\"type\":\"string\"},\"value\":{\"type\":\"string\"}}}}}},\
+\"CriContainer\":{\"type\":\"object\",\"required\":[\"id\",\"pod_sandbox_id\",\"metadata\",\"image\",\"state\"],\"properties\":{\
+\"id\":{\"type\":\"string\"},\"pod_sandbox_id\":{\"type\":\"string\"},\"metadata\":{\"$ref\":\"#/components/schemas/CriMetadata\"},\
+\"image\":{\"type\":\"object\",\"properties\":{\"image\":{\"type\":\"string\"}}},\
+\"state\":{\"type\":\"string\",\"enum\":[\"CONTAINER_CREATED\",\"CONTAINER_RUNNING\",\"CONTAINER_EXITED\"]},\
+\"created_at\":{\"type\":\"integer\"},\"started_at\":{\"type\":\"integer\"},\"finished_at\":{\"type\":\"integer\"},\"exit_code\":{\"type\":\"integer\"},\"reason\":{\"type\":\"string\"},\
+\"command\":{\"type\":\"string\",\"description\":\"Shell line the guest agent runs\"},\"log\":{\"type\":\"string\",\"description\":\"Newest output; ContainerStatus only\"}}},\
+\"CriContainerList\":{\"type\":\"object\",\"required\":[\"containers\"],\"properties\":{\"containers\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/CriContainer\"}}}},\
+\"CriStopContainer\":{\"type\":\"object\",\"properties\":{\"timeout\":{\"type\":\"integer\",\"description\":\"Seconds before SIGKILL; 0 kills at once\"}}},\
+\"CriEmpty\":{\"type\":\"object\"},\
+\"MicrovmTemplate\":{\"type\":\"object\",\"required\":[\"id\",\"name\",\"volume\",\"memory_mib\",\"vcpus\",\"pool\",\"clones\",\"latency\"],\"properties\":{\
+\"id\":{\"type\":\"integer\"},\"name\":{\"type\":\"string\"},\"volume\":{\"type\":\"integer\",\"description\":\"Storage volume holding it\"},\
+\"memory_mib\":{\"type\":\"integer\"},\"vcpus\":{\"type\":\"integer\"},\"vsock\":{\"type\":\"boolean\"},\"network\":{\"type\":\"string\"},\
+\"kind\":{\"type\":\"string\",\"enum\":[\"flat\",\"linux\"]},\"signature\":{\"type\":\"string\",\"enum\":[\"unsigned\",\"trusted\",\"untrusted\",\"malformed\"]},\
+\"pool\":{\"type\":\"object\",\"required\":[\"target\",\"warm\"],\"properties\":{\"target\":{\"type\":\"integer\"},\"warm\":{\"type\":\"integer\"},\
+\"error\":{\"type\":\"string\",\"description\":\"Why refilling stopped; the target was reset to 0\"}}},\
+\"clones\":{\"type\":\"integer\",\"description\":\"Pooled and started clones on the template\"},\
+\"latency\":{\"type\":\"object\",\"properties\":{\"starts\":{\"type\":\"integer\"},\"pool_hits\":{\"type\":\"integer\"},\
+\"on_target\":{\"type\":\"integer\",\"description\":\"Starts within target_us\"},\"target_us\":{\"type\":\"integer\"},\
+\"last_us\":{\"type\":\"integer\"},\"best_us\":{\"type\":\"integer\"},\"worst_us\":{\"type\":\"integer\"}}}}},\
+\"MicrovmTemplateList\":{\"type\":\"object\",\"required\":[\"templates\"],\"properties\":{\"templates\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/components/schemas/MicrovmTemplate\"}}}},\
+\"MicrovmTemplateCreate\":{\"type\":\"object\",\"properties\":{\
+\"name\":{\"type\":\"string\",\"pattern\":\"^[A-Za-z][A-Za-z0-9_.-]{0,31}$\"},\"kernel\":{\"type\":\"string\",\"description\":\"ESP path; with name, builds a template\"},\
+\"cmdline\":{\"type\":\"string\"},\"memory_mib\":{\"type\":\"integer\",\"minimum\":1,\"default\":256},\"vcpus\":{\"type\":\"integer\",\"minimum\":1,\"default\":1},\
+\"vsock\":{\"type\":\"boolean\",\"default\":true},\"network\":{\"type\":\"string\",\"description\":\"CNI network for eth0\"},\
+\"volume\":{\"type\":\"string\",\"description\":\"Id or name of a microvm-<name> volume to open instead\"}}},\
+\"MicrovmPool\":{\"type\":\"object\",\"required\":[\"size\"],\"properties\":{\"size\":{\"type\":\"integer\",\"minimum\":0,\"maximum\":8}}},\
+\"MicrovmStart\":{\"type\":\"object\",\"properties\":{\"name\":{\"type\":\"string\",\"pattern\":\"^[A-Za-z][A-Za-z0-9_.-]*$\"}}},\
+\"MicrovmStarted\":{\"type\":\"object\",\"required\":[\"vm\",\"vcpus\",\"start_us\",\"pooled\"],\"properties\":{\
+\"vm\":{\"type\":\"integer\"},\"vcpus\":{\"type\":\"integer\",\"description\":\"vCPUs started\"},\
+\"start_us\":{\"type\":\"integer\",\"description\":\"From the request to the vCPUs running\"},\"pooled\":{\"type\":\"boolean\"}}},\
+\"MicrovmEmpty\":{\"type\":\"object\"},\
+\"WasmModule\":{\"type\":\"object\",\"required\":[\"vm\",\"state\",\"signature\",\"bytes\",\"functions\",\"memory\",\"instructions\",\"host_calls\",\"run_us\",\"parks\"],\"properties\":{\
+\"vm\":{\"type\":\"integer\"},\"state\":{\"type\":\"string\",\"enum\":[\"loaded\",\"running\",\"paused\",\"stopped\",\"exited\",\"trapped\"]},\
+\"exit_code\":{\"type\":\"integer\",\"description\":\"When exited\"},\"trap\":{\"type\":\"string\",\"description\":\"When trapped\"},\
+\"signature\":{\"type\":\"string\",\"enum\":[\"unsigned\",\"trusted\",\"untrusted\",\"malformed\"]},\"bytes\":{\"type\":\"integer\",\"description\":\"Module size\"},\"functions\":{\"type\":\"integer\"},\
+\"memory\":{\"type\":\"object\",\"required\":[\"pages\",\"limit\"],\"properties\":{\"pages\":{\"type\":\"integer\",\"description\":\"64 KiB pages in use\"},\"limit\":{\"type\":\"integer\",\"description\":\"Pages backed\"}}},\
+\"instructions\":{\"type\":\"integer\"},\"host_calls\":{\"type\":\"integer\"},\"run_us\":{\"type\":\"integer\"},\"parks\":{\"type\":\"integer\",\"description\":\"Slices skipped for the VM's cap\"},\
+\"output\":{\"type\":\"string\",\"description\":\"Newest output of the module\"}}},\
+\"WasmLoad\":{\"type\":\"object\",\"required\":[\"path\"],\"properties\":{\"path\":{\"type\":\"string\",\"description\":\"ESP path of the .wasm module\"},\
+\"memory_mib\":{\"type\":\"integer\",\"minimum\":1,\"default\":16,\"description\":\"Linear memory cap\"}}},\
+\"Capabilities\":{\"type\":\"object\",\"required\":[\"version\",\"api_version\",\"features\"],\"properties\":{\
+\"version\":{\"type\":\"string\"},\"api_version\":{\"type\":\"string\"},\
+\"features\":{\"type\":\"array\",\"items\":{\"type\":\"object\",\"required\":[\"name\",\"subsystem\",\"status\"],\"properties\":{\
+\"name\":{\"type\":\"string\"},\"subsystem\":{\"type\":\"string\",\"description\":\"Subsystem that registered it\"},\
+\"status\":{\"type\":\"string\",\"enum\":[\"available\",\"disabled\",\"absent\"]},\"note\":{\"type\":\"string\",\"description\":\"Why it is not available, or a qualifier\"}}}}}}\
+}"
+    };
+}
+
+api_routes! {
+    "/v1/vms" {
+        (get list_vms "vm.read" "List registered VMs" => "200" "application/json" VmList)
+        (post create_vm "vm.create" "Create, admit and register a VM, here or on the node placement picks" <- CreateVm => "201" "application/json" Vm | "202" "application/json" Forwarded)
+    }
+    "/v1/vms/{vm}" [vm] {
+        (get get_vm "vm.read" "One VM with its current usage" => "200" "application/json" VmDetail)
+        (delete destroy_vm "vm.destroy" "Destroy a created or stopped VM" => "200" "application/json" Destroyed)
+    }
+    "/v1/vms/{vm}/start" [vm] {
+        (post start_vm "vm.start" "Run the loaded image on every vCPU, or queue it for a low-carbon window" <- StartVm => "200" "application/json" Started | "202" "application/json" Deferred)
+    }
+    "/v1/vms/{vm}/stop" [vm] {
+        (post stop_vm "vm.start" "Take every vCPU out of the guest" => "200" "application/json" Stopped)
+    }
+    "/v1/migration" {
+        (get migration_status "vm.read" "Dirty tracking in progress, if any" => "200" "application/json" Migration)
+        (post migration_start "migrate.execute" "Start dirty tracking for a VM, or queue it for a low-carbon window" <- MigrationStart => "200" "application/json" Migration | "202" "application/json" Deferred)
+        (delete migration_stop "migrate.execute" "Stop dirty tracking" => "200" "application/json" Migration)
+    }
+    "/v1/metrics" {
+        (get metrics "metrics.read" "Prometheus text of /metrics" => "200" "text/plain; version=0.0.4" Metrics)
+    }
+    "/v1/attestation" {
+        (get attestation "attest.read" "Boot measurement and a TPM quote" ? nonce, pcrs => "200" "application/json" Attestation)
+    }
+    "/v1/audit" {
+        (get audit_events "audit.read" "Audit events from a cursor, filtered by kind and time" ? cursor, limit, kind, since, until => "200" "application/json" AuditPage)
+    }
+    "/v1/audit/retention" {
+        (get audit_retention "audit.read" "Audit ring size and persistence target" => "200" "application/json" AuditRetention)
+        (post audit_set_retention "audit.write" "Resize the audit ring or change where events are persisted" <- AuditRetentionSet => "200" "application/json" AuditRetention)
+    }
+    "/v1/cluster" {
+        (get cluster "cluster.read" "Quorum mode and the gossiped membership view" => "200" "application/json" Cluster)
+    }
+    "/v1/dvfs" {
+        (get dvfs_status "metrics.read" "DVFS governor and per-CPU utilization and performance level" => "200" "application/json" Dvfs)
+        (post dvfs_governor "power.write" "Switch the DVFS governor" <- DvfsGovernor => "200" "application/json" Dvfs)
+    }
+    "/v1/gpus" {
+        (get list_gpus "vm.read" "SR-IOV capable GPUs with their slices, holders and utilization" => "200" "application/json" GpuList)
+        (post carve_gpu "iommu.modify" "Carve a GPU into VF slices, or put it back whole with slices 0" <- GpuCarve => "200" "application/json" GpuList)
+    }
+    "/v1/vms/{vm}/gpu" [vm] {
+        (post attach_gpu "iommu.modify" "Attach a GPU slice to the VM in its IOMMU domain" <- GpuAttach => "200" "application/json" GpuSlice)
+        (delete detach_gpu "iommu.modify" "Detach every GPU slice the VM holds" => "200" "application/json" GpuDetached)
+    }
+    "/v1/fpgas" {
+        (get list_fpgas "vm.read" "DFL FPGA cards with their regions, loaded bitstreams and holders" => "200" "application/json" FpgaList)
+        (post load_fpga "iommu.modify" "Reconfigure a region from a signed partial bitstream on the ESP" <- FpgaLoad => "200" "application/json" FpgaList)
+    }
+    "/v1/vms/{vm}/fpga" [vm] {
+        (post assign_fpga "iommu.modify" "Assign an FPGA region to the VM as a VF in its IOMMU domain" <- FpgaAssign => "200" "application/json" FpgaAssigned)
+        (delete release_fpga "iommu.modify" "Release and reset every FPGA region the VM holds" => "200" "application/json" FpgaReleased)
+    }
+    "/v1/switch" {
+        (get switch_status "vm.read" "Soft switch ports, VLANs, counters and MAC table" => "200" "application/json" Switch)
+        (post switch_config "network.write" "Configure a switch port or the MAC aging time" <- SwitchConfig => "200" "application/json" Switch)
+    }
+    "/v1/switch/fdb" {
+        (delete switch_flush "network.write" "Forget every learned MAC address" => "200" "application/json" Switch)
+    }
+    "/v1/vms/{vm}/vlan" [vm] {
+        (post vm_vlan "network.write" "Put every NIC of the VM on an access VLAN" <- VmVlan => "200" "application/json" Switch)
+    }
+    "/v1/storage" {
+        (get storage_status "vm.read" "Pool capacity and usage, and every volume" => "200" "application/json" Storage)
+    }
+    "/v1/storage/volumes" {
+        (post create_volume "storage.write" "Create a thin volume, or snapshot or clone one" <- VolumeCreate => "201" "application/json" Volume)
+    }
+    "/v1/storage/volumes/{vol}" [vol] {
+        (delete delete_volume "storage.write" "Delete a volume that is not attached" => "200" "application/json" Storage)
+    }
+    "/v1/vms/{vm}/volumes" [vm] {
+        (post attach_volume "storage.write" "Attach a volume to the VM as a virtio-blk disk" <- VolumeAttach => "200" "application/json" VolumeAttached)
+    }
+    "/v1/csi" {
+        (get csi_plugin "vm.read" "CSI plugin name and controller capabilities" => "200" "application/json" CsiPlugin)
+    }
+    "/v1/csi/volumes" {
+        (get csi_list "vm.read" "CSI ListVolumes, with the nodes each volume is published to" => "200" "application/json" CsiVolumeList)
+        (post csi_create "storage.write" "CSI CreateVolume, idempotent by name" <- CsiCreate => "201" "application/json" CsiVolume | "200" "application/json" CsiVolume)
+    }
+    "/v1/csi/volumes/{vol}" [vol] {
+        (delete csi_delete "storage.write" "CSI DeleteVolume; an unknown volume counts as deleted" => "200" "application/json" CsiEmpty)
+    }
+    "/v1/csi/volumes/{vol}/publish" [vol] {
+        (post csi_publish "storage.write" "CSI ControllerPublishVolume: plug the volume into a VM" <- CsiPublish => "200" "application/json" CsiPublished)
+    }
+    "/v1/csi/volumes/{vol}/unpublish" [vol] {
+        (post csi_unpublish "storage.write" "CSI ControllerUnpublishVolume: unplug the volume" <- CsiUnpublish => "200" "application/json" CsiEmpty)
+    }
+    "/v1/csi/capacity" {
+        (get csi_capacity "vm.read" "CSI GetCapacity of the storage pool" => "200" "application/json" CsiCapacity)
+    }
+    "/v1/cni/networks" {
+        (get cni_networks "vm.read" "CNI attachment definitions" => "200" "application/json" CniNetworkList)
+        (post cni_define "network.write" "Define or replace a bridge network with host-local IPAM" <- CniNetwork => "200" "application/json" CniNetwork)
+    }
+    "/v1/cni/networks/{net}" [net] {
+        (delete cni_undefine "network.write" "Remove a network nothing is attached to" => "200" "application/json" CniNetworkList)
+    }
+    "/v1/vms/{vm}/cni" [vm] {
+        (get cni_attachments "vm.read" "The VM's network attachments and their results" => "200" "application/json" CniAttachments)
+        (post cni_command "network.write" "CNI ADD, CHECK or DEL of one VM interface" <- CniCommand => "200" "application/json" CniResult)
+    }
+    "/v1/cri" {
+        (get cri_version "vm.read" "CRI Version of the pod runtime" => "200" "application/json" CriVersion)
+    }
+    "/v1/cri/sandboxes" {
+        (get cri_sandboxes "vm.read" "CRI ListPodSandbox" => "200" "application/json" CriSandboxList)
+        (post cri_run "vm.create" "CRI RunPodSandbox: create, boot and start a pod microVM" <- CriRunSandbox => "201" "application/json" CriSandbox)
+    }
+    "/v1/cri/sandboxes/{pod}" [pod] {
+        (get cri_sandbox "vm.read" "CRI PodSandboxStatus" => "200" "application/json" CriSandbox)
+        (delete cri_remove "vm.destroy" "CRI RemovePodSandbox: destroy the microVM; an unknown sandbox counts as removed" => "200" "application/json" CriEmpty)
+    }
+    "/v1/cri/sandboxes/{pod}/stop" [pod] {
+        (post cri_stop_sandbox "vm.start" "CRI StopPodSandbox: kill its containers, stop the microVM and free its address" => "200" "application/json" CriEmpty)
+    }
+    "/v1/cri/containers" {
+        (get cri_containers "vm.read" "CRI ListContainers" ? pod_sandbox_id => "200" "application/json" CriContainerList)
+        (post cri_create "vm.start" "CRI CreateContainer: record a process for the pod's guest agent" <- CriCreateContainer => "201" "application/json" CriContainer)
+    }
+    "/v1/cri/containers/{ctr}" [ctr] {
+        (get cri_container "vm.read" "CRI ContainerStatus, with the newest output" => "200" "application/json" CriContainer)
+        (delete cri_remove_container "vm.start" "CRI RemoveContainer, killing it first; an unknown container counts as removed" => "200" "application/json" CriEmpty)
+    }
+    "/v1/cri/containers/{ctr}/start" [ctr] {
+        (post cri_start "vm.start" "CRI StartContainer: have the guest agent run the command" => "200" "application/json" CriContainer)
+    }
+    "/v1/cri/containers/{ctr}/stop" [ctr] {
+        (post cri_stop "vm.start" "CRI StopContainer: SIGTERM, then SIGKILL after timeout seconds" <- CriStopContainer => "200" "application/json" CriContainer)
+    }
+    "/v1/microvm/templates" {
+        (get microvm_templates "vm.read" "MicroVM templates with their pools and start latencies" => "200" "application/json" MicrovmTemplateList)
+        (post microvm_create "vm.create" "Build a template from a kernel, or open one stored in a volume" <- MicrovmTemplateCreate => "201" "application/json" MicrovmTemplate)
+    }
+    "/v1/microvm/templates/{tmpl}" [tmpl] {
+        (get microvm_template "vm.read" "One microVM template" => "200" "application/json" MicrovmTemplate)
+        (delete microvm_remove "vm.destroy" "Drop a template and its warm clones; purge=true deletes its volume" ? purge => "200" "application/json" MicrovmEmpty)
+    }
+    "/v1/microvm/templates/{tmpl}/pool" [tmpl] {
+        (post microvm_pool "vm.create" "Set how many warm clones of the template to keep" <- MicrovmPool => "200" "application/json" MicrovmTemplate)
+    }
+    "/v1/microvm/templates/{tmpl}/start" [tmpl] {
+        (post microvm_start "vm.start" "Start a clone of the template, from the pool when one is warm" <- MicrovmStart => "201" "application/json" MicrovmStarted)
+    }
+    "/v1/vms/{vm}/wasm" [vm] {
+        (get wasm_status "vm.read" "The VM's WebAssembly module, its progress and newest output" => "200" "application/json" WasmModule)
+        (post wasm_load "vm.create" "Give the VM a WebAssembly module from the ESP instead of a guest image" <- WasmLoad => "200" "application/json" WasmModule)
+    }
+    "/v1/capabilities" {
+        (get capabilities "vm.read" "Host capabilities as the subsystems registered them at boot" => "200" "application/json" Capabilities)
+    }
+    "/v1/carbon" {
+        (get carbon_status "metrics.read" "Carbon intensity, deferred work and avoided emissions" => "200" "application/json" Carbon)
+        (post carbon_sample "carbon.write" "Push a measured carbon intensity" <- CarbonSample => "200" "application/json" Carbon)
+    }
+}
+
+/// `{vm}` of `path` against `pattern`: `Some(segment)` (empty if the
+/// pattern has none) when the path matches.
+fn matches<'a>(pattern: &str, path: &'a [u8]) -> Option<&'a [u8]> {
+    let mut param: &[u8] = &[];
+    let mut want = pattern.as_bytes().split(|&b| b == b'/');
+    let mut got = path.split(|&b| b == b'/');
+    loop {
+        match (want.next(), got.next()) {
+            (None, None) => return Some(param),
+            (Some(w), Some(g)) if w.first() == Some(&b'{') && !g.is_empty() => param = g,
+            (Some(w), Some(g)) if w == g => {}
+            _ => return None,
+        }
+    }
+}
+
+/// Answer the request at `/v1/...`, writing the body into `w`. Returns the
+/// status line and content type.
+pub(super) fn route(system_table: &SystemTable<Boot>, r: &Request, w: &mut BufWriter) -> Reply {
+    if r.path == b"/v1/openapi.json" {
+        if r.method != b"GET" { return not_allowed(w); }
+        let _ = w.write_str(OPENAPI);
+        return ("200 OK", JSON);
+    }
+    let mut path_known = false;
+    let mut found = None;
+    for route in ROUTES {
+        let Some(param) = matches(route.path, r.path) else { continue };
+        path_known = true;
+        if r.method.eq_ignore_ascii_case(route.method.as_bytes()) { found = Some((route, param)); break; }
+    }
+    let Some((route, param)) = found else {
+        return if path_known { not_allowed(w) } else { fail(w, "404 Not Found", "api: no such route") };
+    };
+    let caller = match authenticate(r) {
+        Ok(c) => c,
+        Err((status, msg, audit)) => {
+            if audit { audit_denied([0; NAME_MAX], route.cap, r); }
+            return fail(w, status, msg);
+        }
+    };
+    if !caller.role.allows(route.cap) {
+        audit_denied(caller.name, route.cap, r);
+        let _ = write!(w, "{{\"error\":\"api: role {} lacks {}\",\"capability\":\"{}\"}}", caller.role.name(), route.cap.name(), route.cap.name());
+        return ("403 Forbidden", JSON);
+    }
+    if r.truncated { return fail(w, "413 Payload Too Large", "api: request too large"); }
+    (route.handler)(system_table, r, param, w)
+}
+
+fn fail(w: &mut BufWriter, status: &'static str, msg: &str) -> Reply {
+    let _ = w.write_str("{\"error\":");
+    json_str(w, msg);
+    let _ = w.write_str("}");
+    (status, JSON)
+}
+
+fn not_allowed(w: &mut BufWriter) -> Reply { fail(w, "405 Method Not Allowed", "api: method not allowed") }
+
+/// A VM by decimal id, else by name.
+fn select(sel: &[u8]) -> Option<VmInfo> {
+    let s = core::str::from_utf8(sel).ok()?;
+    match s.parse::<u64>() {
+        Ok(id) => vm::find_vm(id),
+        Err(_) => vm::find_vm_by_name(s),
+    }
+}
+
+fn list_vms(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let _ = w.write_str("{\"vms\":[");
+    let mut first = true;
+    vm::list_vms(|v| {
+        if !first { let _ = w.write_str(","); }
+        first = false;
+        vm_json(w, &v, false);
+    });
+    let _ = w.write_str("]}");
+    ("200 OK", JSON)
+}
+
+fn get_vm(_: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    vm_json(w, &info, true);
+    ("200 OK", JSON)
+}
+
+fn destroy_vm(_: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    match vm::destroy_vm(info.id) {
+        Ok(()) => { let _ = write!(w, "{{\"id\":{},\"destroyed\":true}}", info.id); ("200 OK", JSON) }
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn start_vm(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    if field(r.body, "defer") == Some(b"true") {
+        let hours = match field(r.body, "hours") {
+            None => crate::hv::carbon::DEFAULT_BATCH_HOURS as u64,
+            Some(_) => match field_u64(r.body, "hours") { Some(h) if h != 0 && h <= u32::MAX as u64 => h, _ => return fail(w, "400 Bad Request", "api: hours must be a positive number") },
+        };
+        match defer(system_table, info.id, crate::hv::carbon::Kind::Start, hours as u32, w) {
+            Ok(Some(reply)) => return reply,
+            Ok(None) => {}
+            Err(reply) => return reply,
+        }
+    }
+    match crate::hv::run::start(system_table, info.id) {
+        Ok(started) => { let _ = write!(w, "{{\"id\":{},\"vcpus_started\":{}}}", info.id, started); ("200 OK", JSON) }
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn stop_vm(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    let left = crate::hv::run::stop(system_table, info.id, STOP_TIMEOUT_US);
+    if left != 0 {
+        let _ = write!(w, "{{\"error\":\"run: vcpus still running\",\"vcpus_live\":{}}}", left);
+        return ("504 Gateway Timeout", JSON);
+    }
+    let _ = write!(w, "{{\"id\":{},\"stopped\":true}}", info.id);
+    ("200 OK", JSON)
+}
+
+fn migration_status(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    migration(w);
+    ("200 OK", JSON)
+}
+
+fn migration_start(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(id) = field_u64(r.body, "vm") else { return fail(w, "400 Bad Request", "api: body needs {\"vm\": <id>}") };
+    if vm::find_vm(id).is_none() { return fail(w, "404 Not Found", "vm: no such vm"); }
+    if field(r.body, "defer") == Some(b"true") {
+        match defer(system_table, id, crate::hv::carbon::Kind::Migrate, 0, w) {
+            Ok(Some(reply)) => return reply,
+            Ok(None) => {}
+            Err(reply) => return reply,
+        }
+    }
+    if !crate::migrate::start_tracking_by_id(system_table, id) { return fail(w, "409 Conflict", "migrate: could not start tracking"); }
+    migration(w);
+    ("200 OK", JSON)
+}
+
+fn migration_stop(system_table: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    if !crate::migrate::stop_tracking(system_table) { return fail(w, "409 Conflict", "migrate: not tracking"); }
+    migration(w);
+    ("200 OK", JSON)
+}
+
+fn metrics(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    super::http::metrics(&mut |s: &str| { let _ = w.write_str(s); });
+    ("200 OK", "text/plain; version=0.0.4")
+}
+
+/// Queue `kind` with the carbon policy. `Ok(None)`: nothing to wait for,
+/// run it now; `Ok(Some)`: queued, 202 written.
+fn defer(system_table: &SystemTable<Boot>, id: u64, kind: crate::hv::carbon::Kind, hours: u32, w: &mut BufWriter) -> Result<Option<Reply>, Reply> {
+    match crate::hv::carbon::submit(system_table, id, kind, hours) {
+        Ok(Some(deadline)) => {
+            let _ = write!(w, "{{\"vm\":{},\"deferred\":\"{}\",\"deadline\":{}}}", id, kind.name(), deadline);
+            Ok(Some(("202 Accepted", JSON)))
+        }
+        Ok(None) => Ok(None),
+        Err(e) => Err(fail(w, "409 Conflict", e)),
+    }
+}
+
+fn dvfs_status(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    dvfs(w);
+    ("200 OK", JSON)
+}
+
+fn dvfs_governor(_: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let g = field(r.body, "governor").and_then(|v| core::str::from_utf8(v).ok()).and_then(crate::hv::dvfs::Governor::parse);
+    let Some(g) = g else { return fail(w, "400 Bad Request", "api: governor must be off, performance, balanced or powersave") };
+    if let Err(e) = crate::hv::dvfs::set_governor(g) { return fail(w, "409 Conflict", e); }
+    dvfs(w);
+    ("200 OK", JSON)
+}
+
+fn dvfs(w: &mut BufWriter) {
+    let s = crate::hv::dvfs::status();
+    let _ = write!(w, "{{\"governor\":\"{}\",\"pstate\":\"{}\"", s.governor.name(), s.caps.map_or("none", |c| c.kind.name()));
+    if let Some(c) = s.caps { let _ = write!(w, ",\"lowest\":{},\"highest\":{},\"turbo\":{}", c.lowest, c.highest, c.turbo); }
+    let _ = w.write_str(",\"cpus\":[");
+    let mut first = true;
+    crate::hv::dvfs::for_each(|cpu, c| {
+        let _ = write!(w, "{}{{\"cpu\":{},\"util_pct\":{},\"level\":{},\"state\":{},\"transitions\":{}}}",
+            if first { "" } else { "," }, cpu, c.util_pct, c.level, c.state, c.transitions);
+        first = false;
+    });
+    let _ = w.write_str("]}");
+}
+
+fn list_gpus(system_table: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let _ = crate::hv::gpu::tick(false);
+    gpus(system_table, w);
+    ("200 OK", JSON)
+}
+
+fn carve_gpu(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let bdf = field(r.body, "gpu").and_then(|v| core::str::from_utf8(v).ok()).and_then(crate::hv::sriov::Bdf::parse);
+    let Some(bdf) = bdf else { return fail(w, "400 Bad Request", "api: body needs {\"gpu\": \"<[seg:]bus:dev.fn>\", \"slices\": <n>}") };
+    let slices = match field_u64(r.body, "slices") {
+        Some(n) if n <= u16::MAX as u64 => n as u16,
+        _ => return fail(w, "400 Bad Request", "api: slices must be a number (0 puts the GPU back whole)"),
+    };
+    // SAFETY: handlers run one at a time on the BSP; the copy does not outlive the call.
+    let mut st = unsafe { system_table.unsafe_clone() };
+    if let Err(e) = crate::hv::gpu::carve(&mut st, bdf, slices) { return fail(w, "409 Conflict", e); }
+    gpus(system_table, w);
+    ("200 OK", JSON)
+}
+
+fn attach_gpu(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    let gpu = field(r.body, "gpu").and_then(|v| core::str::from_utf8(v).ok()).and_then(crate::hv::sriov::Bdf::parse);
+    let Some(gpu) = gpu else { return fail(w, "400 Bad Request", "api: body needs {\"gpu\": \"<[seg:]bus:dev.fn>\"}") };
+    let slice = match field(r.body, "slice") {
+        None => None,
+        Some(_) => match field_u64(r.body, "slice") { Some(k) if k <= u16::MAX as u64 => Some(k as u16), _ => return fail(w, "400 Bad Request", "api: slice must be a number") },
+    };
+    // SAFETY: as in carve_gpu.
+    let mut st = unsafe { system_table.unsafe_clone() };
+    match crate::hv::gpu::attach(&mut st, info.id, gpu, slice) {
+        Ok(sl) => { slice_json(w, &sl); ("200 OK", JSON) }
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn detach_gpu(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    // SAFETY: as in carve_gpu.
+    let mut st = unsafe { system_table.unsafe_clone() };
+    let n = crate::hv::gpu::detach_all(&mut st, info.id);
+    let _ = write!(w, "{{\"id\":{},\"detached\":{}}}", info.id, n);
+    ("200 OK", JSON)
+}
+
+fn bdf_json(w: &mut BufWriter, b: crate::hv::sriov::Bdf) {
+    let mut s = [0u8; 16];
+    let n = b.fmt(&mut s);
+    json_str(w, core::str::from_utf8(&s[..n]).unwrap_or(""));
+}
+
+fn slice_json(w: &mut BufWriter, sl: &crate::hv::gpu::Slice) {
+    let _ = write!(w, "{{\"index\":{},\"vf\":", sl.index);
+    bdf_json(w, sl.vf);
+    match sl.vm_id { Some(v) => { let _ = write!(w, ",\"vm\":{}", v); } None => { let _ = w.write_str(",\"vm\":null"); } }
+    let _ = write!(w, ",\"mmio_ops\":{},\"mmio_ops_per_s\":{},\"faults\":{},\"quarantined\":{}}}", sl.mmio_ops, sl.mmio_ops_per_s, sl.faults, sl.quarantined);
+}
+
+fn gpus(system_table: &SystemTable<Boot>, w: &mut BufWriter) {
+    let _ = w.write_str("{\"gpus\":[");
+    let mut first = true;
+    crate::hv::gpu::for_each_gpu(system_table, |g| {
+        let _ = w.write_str(if first { "{\"gpu\":" } else { ",{\"gpu\":" });
+        first = false;
+        bdf_json(w, g.bdf);
+        let _ = write!(w, ",\"vendor\":{},\"device\":{},\"max_slices\":{},\"slices\":{},\"fb_bytes\":{},\"carved\":[",
+            g.vendor, g.device, g.total_vfs, g.slices, g.fb_bytes);
+        let mut first_slice = true;
+        crate::hv::gpu::for_each_slice(|sl| {
+            if sl.gpu != g.bdf { return; }
+            if !first_slice { let _ = w.write_str(","); }
+            first_slice = false;
+            slice_json(w, sl);
+        });
+        let _ = w.write_str("]}");
+    });
+    let _ = w.write_str("]}");
+}
+
+fn list_fpgas(system_table: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    fpgas(system_table, w);
+    ("200 OK", JSON)
+}
+
+fn load_fpga(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let card = field(r.body, "fpga").and_then(|v| core::str::from_utf8(v).ok()).and_then(crate::hv::sriov::Bdf::parse);
+    let path = field(r.body, "path").and_then(|v| core::str::from_utf8(v).ok());
+    let (Some(card), Some(region), Some(path)) = (card, field_u64(r.body, "region"), path) else {
+        return fail(w, "400 Bad Request", "api: body needs {\"fpga\": \"<[seg:]bus:dev.fn>\", \"region\": <n>, \"path\": \"<esp path>\"}");
+    };
+    if region >= crate::hv::fpga::MAX_REGIONS as u64 { return fail(w, "400 Bad Request", "fpga: no such region"); }
+    // SAFETY: as in carve_gpu.
+    let mut st = unsafe { system_table.unsafe_clone() };
+    if let Err(e) = crate::hv::fpga::load(&mut st, card, region as u8, path) { return fail(w, "409 Conflict", e); }
+    fpgas(system_table, w);
+    ("200 OK", JSON)
+}
+
+fn assign_fpga(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    let card = field(r.body, "fpga").and_then(|v| core::str::from_utf8(v).ok()).and_then(crate::hv::sriov::Bdf::parse);
+    let (Some(card), Some(region)) = (card, field_u64(r.body, "region")) else {
+        return fail(w, "400 Bad Request", "api: body needs {\"fpga\": \"<[seg:]bus:dev.fn>\", \"region\": <n>}");
+    };
+    if region >= crate::hv::fpga::MAX_REGIONS as u64 { return fail(w, "400 Bad Request", "fpga: no such region"); }
+    // SAFETY: as in carve_gpu.
+    let mut st = unsafe { system_table.unsafe_clone() };
+    match crate::hv::fpga::assign(&mut st, info.id, card, region as u8) {
+        Ok(vf) => {
+            let _ = write!(w, "{{\"id\":{},\"fpga\":", info.id);
+            bdf_json(w, card);
+            let _ = write!(w, ",\"region\":{},\"vf\":", region);
+            bdf_json(w, vf);
+            let _ = w.write_str("}");
+            ("200 OK", JSON)
+        }
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn release_fpga(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    // SAFETY: as in carve_gpu.
+    let mut st = unsafe { system_table.unsafe_clone() };
+    let n = crate::hv::fpga::release_all(&mut st, info.id);
+    let _ = write!(w, "{{\"id\":{},\"released\":{}}}", info.id, n);
+    ("200 OK", JSON)
+}
+
+fn fpgas(system_table: &SystemTable<Boot>, w: &mut BufWriter) {
+    let _ = w.write_str("{\"fpgas\":[");
+    let mut first = true;
+    crate::hv::fpga::for_each_card(system_table, |c| {
+        let _ = w.write_str(if first { "{\"fpga\":" } else { ",{\"fpga\":" });
+        first = false;
+        bdf_json(w, c.bdf);
+        let _ = write!(w, ",\"vendor\":{},\"device\":{},\"reconfigurable\":{},\"regions\":[", c.vendor, c.device, c.reconfigurable());
+        let mut first_region = true;
+        for k in (0..c.regions).filter(|&k| c.implemented(k)) {
+            let r = crate::hv::fpga::region(c.bdf, k);
+            let _ = write!(w, "{}{{\"region\":{},\"access\":\"{}\",\"bitstream\":", if first_region { "" } else { "," }, k, if c.vf_access(k) { "vf" } else { "pf" });
+            first_region = false;
+            match r.filter(|r| r.path_len != 0) {
+                Some(r) => { json_str(w, r.path()); let _ = write!(w, ",\"sig\":\"{}\"", r.verdict.map_or("unsigned", |v| v.name())); }
+                None => { let _ = w.write_str("null"); }
+            }
+            match r.and_then(|r| r.vm_id) { Some(v) => { let _ = write!(w, ",\"vm\":{}", v); } None => { let _ = w.write_str(",\"vm\":null"); } }
+            if let Some(vf) = r.and_then(|r| r.vf) { let _ = w.write_str(",\"vf\":"); bdf_json(w, vf); }
+            let _ = w.write_str("}");
+        }
+        let _ = w.write_str("]}");
+    });
+    let _ = w.write_str("]}");
+}
+
+fn switch_status(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    switch(w);
+    ("200 OK", JSON)
+}
+
+fn switch_config(_: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::vdev::switch::{self, Port};
+    if let Some(v) = field(r.body, "aging_s") {
+        let secs = core::str::from_utf8(v).ok().and_then(|v| v.parse::<u32>().ok());
+        let Some(secs) = secs else { return fail(w, "400 Bad Request", "api: aging_s must be a number") };
+        if let Err(e) = switch::set_aging(secs) { return fail(w, "400 Bad Request", e); }
+    }
+    if let Some(name) = field(r.body, "port") {
+        let port = match name {
+            b"uplink" => Some(Port::Uplink),
+            n => n.strip_prefix(b"nic").and_then(|i| core::str::from_utf8(i).ok()).and_then(|i| i.parse::<usize>().ok()).map(Port::Nic),
+        };
+        let Some(cur) = port.and_then(switch::port) else { return fail(w, "404 Not Found", "vswitch: no such port (bridge-mode NICs only)") };
+        let mut cfg = cur.cfg;
+        if field(r.body, "vlan").is_some() {
+            match field_u64(r.body, "vlan") { Some(v) if v <= switch::VLAN_MAX as u64 => cfg.vlan = v as u16, _ => return fail(w, "400 Bad Request", "api: vlan must be 0..4094") }
+        }
+        match field(r.body, "flood") {
+            None => {}
+            Some(b"true") => cfg.flood = true,
+            Some(b"false") => cfg.flood = false,
+            Some(_) => return fail(w, "400 Bad Request", "api: flood must be true or false"),
+        }
+        if field(r.body, "broadcast_per_s").is_some() {
+            match field_u64(r.body, "broadcast_per_s") { Some(v) if v <= u32::MAX as u64 => cfg.bcast_per_s = v as u32, _ => return fail(w, "400 Bad Request", "api: broadcast_per_s must be a number") }
+        }
+        if let Err(e) = switch::set_port(cur.port, cfg) { return fail(w, "400 Bad Request", e); }
+    } else if field(r.body, "aging_s").is_none() {
+        return fail(w, "400 Bad Request", "api: body needs \"port\" or \"aging_s\"");
+    }
+    switch(w);
+    ("200 OK", JSON)
+}
+
+fn switch_flush(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let _ = crate::hv::vdev::switch::flush();
+    switch(w);
+    ("200 OK", JSON)
+}
+
+fn vm_vlan(_: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    let vlan = match field_u64(r.body, "vlan") {
+        Some(v) if v <= crate::hv::vdev::switch::VLAN_MAX as u64 => v as u16,
+        _ => return fail(w, "400 Bad Request", "api: body needs {\"vlan\": <0..4094>}"),
+    };
+    if let Err(e) = crate::hv::vdev::switch::set_vm_vlan(info.id, vlan) { return fail(w, "409 Conflict", e); }
+    switch(w);
+    ("200 OK", JSON)
+}
+
+fn switch_port_name(w: &mut BufWriter, p: crate::hv::vdev::switch::Port) {
+    let _ = match p {
+        crate::hv::vdev::switch::Port::Uplink => w.write_str("\"uplink\""),
+        crate::hv::vdev::switch::Port::Nic(i) => write!(w, "\"nic{}\"", i),
+    };
+}
+
+fn switch(w: &mut BufWriter) {
+    let _ = write!(w, "{{\"aging_s\":{},\"ports\":[", crate::hv::vdev::switch::aging());
+    let mut first = true;
+    crate::hv::vdev::switch::for_each_port(|p| {
+        let _ = w.write_str(if first { "{\"port\":" } else { ",{\"port\":" });
+        first = false;
+        switch_port_name(w, p.port);
+        if let Some(vm) = p.vm_id { let _ = write!(w, ",\"vm\":{}", vm); }
+        let s = &p.stats;
+        let _ = write!(w, ",\"vlan\":{},\"flood\":{},\"broadcast_per_s\":{},\"rx\":{},\"forwarded\":{},\"flooded\":{},\"filtered\":{},\"storm_drops\":{},\"vlan_drops\":{}}}",
+            p.cfg.vlan, p.cfg.flood, p.cfg.bcast_per_s, s.rx, s.forwarded, s.flooded, s.filtered, s.storm_drops, s.vlan_drops);
+    });
+    let _ = w.write_str("],\"fdb\":[");
+    let mut first = true;
+    crate::hv::vdev::switch::for_each_fdb(|e| {
+        let m = e.mac;
+        let _ = write!(w, "{}{{\"mac\":\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\",\"vlan\":{},\"port\":", if first { "" } else { "," }, m[0], m[1], m[2], m[3], m[4], m[5], e.vlan);
+        first = false;
+        switch_port_name(w, e.port);
+        let _ = write!(w, ",\"static\":{}", e.is_static);
+        if !e.is_static { let _ = write!(w, ",\"age_ms\":{}", e.age_ms); }
+        let _ = w.write_str("}");
+    });
+    let _ = w.write_str("]}");
+}
+
+fn storage_status(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    storage(w);
+    ("200 OK", JSON)
+}
+
+fn create_volume(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::storage;
+    let Some(name) = field(r.body, "name").and_then(|v| core::str::from_utf8(v).ok()) else {
+        return fail(w, "400 Bad Request", "api: body needs \"name\"");
+    };
+    let res = match (field(r.body, "from"), field(r.body, "size_mib")) {
+        (Some(from), None) => {
+            let snapshot = match field(r.body, "snapshot") {
+                None | Some(b"false") => false,
+                Some(b"true") => true,
+                Some(_) => return fail(w, "400 Bad Request", "api: snapshot must be true or false"),
+            };
+            let Some(from) = core::str::from_utf8(from).ok().and_then(storage::find) else { return fail(w, "404 Not Found", "storage: no such volume") };
+            storage::copy(system_table, from, name, snapshot)
+        }
+        (None, Some(_)) => match field_u64(r.body, "size_mib") {
+            Some(mib) => storage::create(system_table, name, mib),
+            None => return fail(w, "400 Bad Request", "api: size_mib must be a number"),
+        },
+        _ => return fail(w, "400 Bad Request", "api: body needs one of \"size_mib\" or \"from\""),
+    };
+    match res.and_then(|id| storage::volume(id).ok_or("storage: no such volume")) {
+        Ok(v) => { volume_json(w, &v); ("201 Created", JSON) }
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn delete_volume(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(id) = core::str::from_utf8(sel).ok().and_then(crate::hv::storage::find) else { return fail(w, "404 Not Found", "storage: no such volume") };
+    if let Err(e) = crate::hv::storage::delete(system_table, id) { return fail(w, "409 Conflict", e); }
+    storage(w);
+    ("200 OK", JSON)
+}
+
+fn attach_volume(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    let Some(vol) = field(r.body, "volume") else { return fail(w, "400 Bad Request", "api: body needs {\"volume\": \"<id or name>\"}") };
+    let Some(vol) = core::str::from_utf8(vol).ok().and_then(crate::hv::storage::find) else { return fail(w, "404 Not Found", "storage: no such volume") };
+    let ro = match field(r.body, "read_only") {
+        None | Some(b"false") => false,
+        Some(b"true") => true,
+        Some(_) => return fail(w, "400 Bad Request", "api: read_only must be true or false"),
+    };
+    let (disk, dev) = match crate::hv::vdev::blk::add(system_table, info.id, crate::hv::vdev::blk::Backing::Volume { id: vol }, ro) {
+        Ok(d) => d,
+        Err(e) => return fail(w, "409 Conflict", e),
+    };
+    let mut read_only = ro;
+    crate::hv::vdev::blk::for_each(|i, d| if i == disk { read_only = d.read_only; });
+    let _ = write!(w, "{{\"id\":{},\"disk\":{},\"pci\":\"00:{:02x}.0\",\"volume\":{},\"read_only\":{}}}", info.id, disk, dev, vol, read_only);
+    ("200 OK", JSON)
+}
+
+fn csi_fail(w: &mut BufWriter, (code, msg): crate::hv::csi::Error) -> Reply {
+    use crate::hv::csi::Code;
+    let status = match code {
+        Code::InvalidArgument | Code::OutOfRange => "400 Bad Request",
+        Code::NotFound => "404 Not Found",
+        Code::AlreadyExists | Code::FailedPrecondition => "409 Conflict",
+        Code::ResourceExhausted => "507 Insufficient Storage",
+        Code::Unavailable => "503 Service Unavailable",
+    };
+    let _ = w.write_str("{\"error\":");
+    json_str(w, msg);
+    let _ = write!(w, ",\"code\":\"{}\"}}", code.name());
+    (status, JSON)
+}
+
+/// `"volume":{..}` of a CSI volume message.
+fn csi_volume(w: &mut BufWriter, v: &crate::hv::storage::VolInfo) {
+    let _ = write!(w, "\"volume\":{{\"volume_id\":\"{}\",\"capacity_bytes\":{},\"volume_context\":{{\"name\":", v.id, v.size);
+    json_str(w, v.name());
+    let _ = w.write_str("}");
+    match crate::hv::storage::volume(v.parent) {
+        Some(p) if p.snapshot => { let _ = write!(w, ",\"content_source\":{{\"snapshot\":{{\"snapshot_id\":\"{}\"}}}}", p.id); }
+        Some(p) => { let _ = write!(w, ",\"content_source\":{{\"volume\":{{\"volume_id\":\"{}\"}}}}", p.id); }
+        None => {}
+    }
+    let _ = w.write_str("}");
+}
+
+/// CSI volume id of `sel`: a decimal storage volume id.
+fn csi_id(sel: &[u8]) -> Option<u32> { core::str::from_utf8(sel).ok()?.parse::<u32>().ok() }
+
+/// The access mode of a CSI request, if it names one.
+fn csi_access(body: &[u8]) -> Result<Option<crate::hv::csi::Access>, crate::hv::csi::Error> {
+    match field(body, "mode") {
+        None => Ok(None),
+        Some(m) => crate::hv::csi::Access::parse(core::str::from_utf8(m).unwrap_or("")).map(Some),
+    }
+}
+
+fn csi_plugin(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::csi::{Access, CONTROLLER_CAPS, PLUGIN};
+    let _ = write!(w, "{{\"name\":\"{}\",\"vendor_version\":\"{}\",\"controller_capabilities\":[", PLUGIN, API_VERSION);
+    for (i, c) in CONTROLLER_CAPS.iter().enumerate() { let _ = write!(w, "{}\"{}\"", if i == 0 { "" } else { "," }, c); }
+    let _ = w.write_str("],\"access_modes\":[");
+    for (i, a) in [Access::SingleNodeWriter, Access::SingleNodeReaderOnly, Access::MultiNodeReaderOnly].iter().enumerate() {
+        let _ = write!(w, "{}\"{}\"", if i == 0 { "" } else { "," }, a.name());
+    }
+    let _ = w.write_str("]}");
+    ("200 OK", JSON)
+}
+
+fn csi_list(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let _ = w.write_str("{\"entries\":[");
+    let mut first = true;
+    crate::hv::storage::for_each(|v| {
+        if v.snapshot { return; }
+        if !first { let _ = w.write_str(","); }
+        first = false;
+        let _ = w.write_str("{");
+        csi_volume(w, v);
+        let mut nodes = [(0u64, false); crate::hv::vdev::blk::MAX_DISKS];
+        let n = crate::hv::csi::published(v.id, &mut nodes);
+        let _ = w.write_str(",\"status\":{\"published_node_ids\":[");
+        for (i, (vm, _)) in nodes[..n].iter().enumerate() { let _ = write!(w, "{}\"{}\"", if i == 0 { "" } else { "," }, vm); }
+        let _ = w.write_str("]}}");
+    });
+    let _ = w.write_str("]}");
+    ("200 OK", JSON)
+}
+
+fn csi_create(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::csi::{self, Code, Source};
+    let Some(name) = field(r.body, "name").and_then(|v| core::str::from_utf8(v).ok()) else {
+        return csi_fail(w, (Code::InvalidArgument, "api: body needs \"name\""));
+    };
+    if let Err(e) = csi_access(r.body) { return csi_fail(w, e); }
+    let mut range = [0u64; 2];
+    for (slot, key) in range.iter_mut().zip(["required_bytes", "limit_bytes"]) {
+        if field(r.body, key).is_some() {
+            let Some(v) = field_u64(r.body, key) else { return csi_fail(w, (Code::InvalidArgument, "api: capacity_range bytes must be numbers")) };
+            *slot = v;
+        }
+    }
+    let source = match (field(r.body, "volume_id"), field(r.body, "snapshot_id")) {
+        (None, None) => Source::Empty,
+        (Some(v), None) => match csi_id(v) { Some(id) => Source::Volume(id), None => return csi_fail(w, (Code::NotFound, "csi: no such source volume")) },
+        (None, Some(s)) => match csi_id(s) { Some(id) => Source::Snapshot(id), None => return csi_fail(w, (Code::NotFound, "csi: no such source snapshot")) },
+        (Some(_), Some(_)) => return csi_fail(w, (Code::InvalidArgument, "api: content source is a volume or a snapshot, not both")),
+    };
+    match csi::create_volume(system_table, name, range[0], range[1], source) {
+        Ok((v, created)) => { let _ = w.write_str("{"); csi_volume(w, &v); let _ = w.write_str("}"); (if created { "201 Created" } else { "200 OK" }, JSON) }
+        Err(e) => csi_fail(w, e),
+    }
+}
+
+fn csi_delete(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    if let Some(id) = csi_id(sel) {
+        if let Err(e) = crate::hv::csi::delete_volume(system_table, id) { return csi_fail(w, e); }
+    }
+    let _ = w.write_str("{}");
+    ("200 OK", JSON)
+}
+
+fn csi_publish(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::csi::{self, Access, Code};
+    let Some(id) = csi_id(sel) else { return csi_fail(w, (Code::NotFound, "csi: no such volume")) };
+    let Some(node) = field(r.body, "node_id") else { return csi_fail(w, (Code::InvalidArgument, "api: body needs \"node_id\"")) };
+    let Some(vm) = select(node) else { return csi_fail(w, (Code::NotFound, "csi: no such node")) };
+    let readonly = match field(r.body, "readonly") {
+        None | Some(b"false") => false,
+        Some(b"true") => true,
+        Some(_) => return csi_fail(w, (Code::InvalidArgument, "api: readonly must be true or false")),
+    };
+    let access = match csi_access(r.body) { Ok(a) => a.unwrap_or(Access::SingleNodeWriter), Err(e) => return csi_fail(w, e) };
+    match csi::publish(system_table, id, vm.id, access, readonly) {
+        Ok(p) => {
+            let _ = write!(w, "{{\"publish_context\":{{\"disk\":\"{}\",\"pci\":\"00:{:02x}.0\",\"read_only\":\"{}\"}}}}", p.disk, p.dev, p.read_only);
+            ("200 OK", JSON)
+        }
+        Err(e) => csi_fail(w, e),
+    }
+}
+
+fn csi_unpublish(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let vm = match field(r.body, "node_id") {
+        None | Some(b"") => None,
+        Some(node) => match select(node) {
+            Some(vm) => Some(vm.id),
+            None => { let _ = w.write_str("{}"); return ("200 OK", JSON); }
+        },
+    };
+    if let Some(id) = csi_id(sel) {
+        if let Err(e) = crate::hv::csi::unpublish(system_table, id, vm) { return csi_fail(w, e); }
+    }
+    let _ = w.write_str("{}");
+    ("200 OK", JSON)
+}
+
+fn csi_capacity(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    match crate::hv::csi::available() {
+        Ok(b) => {
+            let _ = write!(w, "{{\"available_capacity\":{},\"maximum_volume_size\":{},\"minimum_volume_size\":{}}}", b, b, crate::hv::storage::CHUNK);
+            ("200 OK", JSON)
+        }
+        Err(e) => csi_fail(w, e),
+    }
+}
+
+/// Quoted dotted-quad `ip`, with `/prefix` if given.
+fn ip_json(w: &mut BufWriter, ip: [u8; 4], prefix: Option<u8>) {
+    let _ = write!(w, "\"{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]);
+    if let Some(p) = prefix { let _ = write!(w, "/{}", p); }
+    let _ = w.write_str("\"");
+}
+
+fn cni_network_json(w: &mut BufWriter, n: &crate::hv::cni::Network) {
+    let _ = write!(w, "{{\"cniVersion\":\"{}\",\"name\":", crate::hv::cni::CNI_VERSION);
+    json_str(w, n.name());
+    let _ = w.write_str(",\"type\":\"bridge\",\"bridge\":");
+    json_str(w, n.bridge());
+    let _ = write!(w, ",\"vlan\":{},\"ipam\":{{\"type\":\"host-local\",\"subnet\":", n.vlan);
+    ip_json(w, n.subnet, Some(n.prefix));
+    let _ = w.write_str(",\"rangeStart\":");
+    ip_json(w, n.range_start, None);
+    let _ = w.write_str(",\"rangeEnd\":");
+    ip_json(w, n.range_end, None);
+    let _ = w.write_str(",\"gateway\":");
+    ip_json(w, n.gateway, None);
+    let _ = w.write_str(",\"routes\":[");
+    for (i, r) in n.routes().iter().enumerate() {
+        let _ = w.write_str(if i == 0 { "{\"dst\":" } else { ",{\"dst\":" });
+        ip_json(w, r.dst, Some(r.prefix));
+        if let Some(gw) = r.gw { let _ = w.write_str(",\"gw\":"); ip_json(w, gw, None); }
+        let _ = w.write_str("}");
+    }
+    let _ = w.write_str("]}}");
+}
+
+/// The CNI result document of an attachment.
+fn cni_result_json(w: &mut BufWriter, a: &crate::hv::cni::Attachment, n: &crate::hv::cni::Network) {
+    let m = a.mac;
+    let _ = write!(w, "{{\"cniVersion\":\"{}\",\"interfaces\":[{{\"name\":", crate::hv::cni::CNI_VERSION);
+    json_str(w, a.if_name());
+    let _ = write!(w, ",\"mac\":\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\",\"sandbox\":\"{}\"}}],\"ips\":[{{\"address\":", m[0], m[1], m[2], m[3], m[4], m[5], a.vm_id);
+    ip_json(w, a.ip, Some(n.prefix));
+    let _ = w.write_str(",\"gateway\":");
+    ip_json(w, n.gateway, None);
+    let _ = w.write_str(",\"interface\":0}],\"routes\":[");
+    for (i, r) in n.routes().iter().enumerate() {
+        let _ = w.write_str(if i == 0 { "{\"dst\":" } else { ",{\"dst\":" });
+        ip_json(w, r.dst, Some(r.prefix));
+        let _ = w.write_str(",\"gw\":");
+        ip_json(w, r.gw.unwrap_or(n.gateway), None);
+        let _ = w.write_str("}");
+    }
+    let _ = w.write_str("],\"dns\":{}}");
+}
+
+fn cni_list(w: &mut BufWriter) {
+    let _ = w.write_str("{\"networks\":[");
+    let mut first = true;
+    crate::hv::cni::for_each_network(|n| {
+        if !first { let _ = w.write_str(","); }
+        first = false;
+        cni_network_json(w, n);
+    });
+    let _ = w.write_str("]}");
+}
+
+fn cni_networks(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    cni_list(w);
+    ("200 OK", JSON)
+}
+
+fn cni_define(_: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::cni::{self, Network, Route};
+    let text = |k: &str| field(r.body, k).and_then(|v| core::str::from_utf8(v).ok());
+    let (Some(name), Some(bridge)) = (text("name"), text("bridge")) else { return fail(w, "400 Bad Request", "api: body needs \"name\" and \"bridge\""); };
+    if let Some(t) = text("type") { if t != "bridge" { return fail(w, "400 Bad Request", "api: only bridge networks are supported"); } }
+    let Some((subnet, prefix)) = text("subnet").and_then(cni::parse_cidr) else { return fail(w, "400 Bad Request", "api: ipam needs an IPv4 \"subnet\" CIDR"); };
+    let vlan = match field(r.body, "vlan") {
+        None => 0,
+        Some(_) => match field_u64(r.body, "vlan") { Some(v) if v <= u16::MAX as u64 => v as u16, _ => return fail(w, "400 Bad Request", "api: vlan must be a number") },
+    };
+    let mut n = match Network::new(name, bridge, vlan, subnet, prefix) { Ok(n) => n, Err(e) => return fail(w, "400 Bad Request", e) };
+    for (slot, key) in [(&mut n.range_start, "rangeStart"), (&mut n.range_end, "rangeEnd"), (&mut n.gateway, "gateway")] {
+        if let Some(v) = text(key) {
+            let Some(ip) = crate::hv::vdev::net::parse_ipv4(v) else { return fail(w, "400 Bad Request", "api: rangeStart, rangeEnd and gateway must be IPv4 addresses") };
+            *slot = ip;
+        }
+    }
+    for o in objects(r.body, "routes") {
+        let dst = field(o, "dst").and_then(|v| core::str::from_utf8(v).ok()).and_then(cni::parse_cidr);
+        let gw = field(o, "gw").map(|v| core::str::from_utf8(v).ok().and_then(crate::hv::vdev::net::parse_ipv4));
+        let (Some((dst, prefix)), None | Some(Some(_))) = (dst, gw) else { return fail(w, "400 Bad Request", "api: a route needs a \"dst\" CIDR and an optional \"gw\" address") };
+        if let Err(e) = n.add_route(Route { dst, prefix, gw: gw.flatten() }) { return fail(w, "400 Bad Request", e); }
+    }
+    if let Err(e) = cni::define(n) { return fail(w, "409 Conflict", e); }
+    match cni::network(name) {
+        Some(n) => { cni_network_json(w, &n); ("200 OK", JSON) }
+        None => fail(w, "409 Conflict", "cni: no such network"),
+    }
+}
+
+fn cni_undefine(_: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let name = core::str::from_utf8(sel).unwrap_or("");
+    if crate::hv::cni::network(name).is_none() { return fail(w, "404 Not Found", "cni: no such network"); }
+    if let Err(e) = crate::hv::cni::undefine(name) { return fail(w, "409 Conflict", e); }
+    cni_list(w);
+    ("200 OK", JSON)
+}
+
+fn cni_attachments(_: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    let _ = w.write_str("{\"attachments\":[");
+    let mut first = true;
+    crate::hv::cni::for_each(Some(info.id), |a, n| {
+        if !first { let _ = w.write_str(","); }
+        first = false;
+        let _ = w.write_str("{\"network\":");
+        json_str(w, n.name());
+        let _ = w.write_str(",\"if_name\":");
+        json_str(w, a.if_name());
+        match a.nic { Some(i) => { let _ = write!(w, ",\"nic\":{}", i); } None => { let _ = w.write_str(",\"nic\":null"); } }
+        let _ = w.write_str(",\"result\":");
+        cni_result_json(w, a, n);
+        let _ = w.write_str("}");
+    });
+    let _ = w.write_str("]}");
+    ("200 OK", JSON)
+}
+
+fn cni_command(_: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::cni;
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    let text = |k: &str| field(r.body, k).and_then(|v| core::str::from_utf8(v).ok());
+    let Some(network) = text("network") else { return fail(w, "400 Bad Request", "api: body needs \"network\"") };
+    let if_name = text("if_name").unwrap_or("eth0");
+    let res = match text("command") {
+        Some("ADD") => {
+            let mac = match text("mac") {
+                None => None,
+                Some(m) => match crate::hv::vdev::net::parse_mac(m) { Some(m) => Some(m), None => return fail(w, "400 Bad Request", "api: mac must be aa:bb:cc:dd:ee:ff") },
+            };
+            cni::add(info.id, network, if_name, mac)
+        }
+        Some("CHECK") => cni::check(info.id, network, if_name),
+        Some("DEL") => match cni::del(info.id, network, if_name) {
+            Ok(()) => { let _ = w.write_str("{}"); return ("200 OK", JSON); }
+            Err(e) => Err(e),
+        },
+        _ => return fail(w, "400 Bad Request", "api: command must be ADD, CHECK or DEL"),
+    };
+    match res {
+        Ok((a, n)) => { cni_result_json(w, &a, &n); ("200 OK", JSON) }
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn cri_id(sel: &[u8]) -> Option<u64> { core::str::from_utf8(sel).ok()?.parse::<u64>().ok() }
+
+fn cri_container_id(sel: &[u8]) -> Option<u32> { cri_id(sel).and_then(|id| u32::try_from(id).ok()) }
+
+fn cri_version(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::cri::{RUNTIME_API_VERSION, RUNTIME_NAME};
+    let _ = write!(w, "{{\"version\":\"0.1.0\",\"runtime_name\":\"{}\",\"runtime_version\":\"{}\",\"runtime_api_version\":\"{}\"}}", RUNTIME_NAME, env!("CARGO_PKG_VERSION"), RUNTIME_API_VERSION);
+    ("200 OK", JSON)
+}
+
+fn cri_sandbox_json(w: &mut BufWriter, p: &crate::hv::cri::Sandbox) {
+    let _ = write!(w, "{{\"id\":\"{}\",\"metadata\":{{\"name\":", p.id);
+    json_str(w, p.name());
+    let _ = w.write_str(",\"namespace\":");
+    json_str(w, p.namespace());
+    let _ = w.write_str(",\"uid\":");
+    json_str(w, p.uid());
+    let state = if p.ready { "SANDBOX_READY" } else { "SANDBOX_NOTREADY" };
+    let _ = write!(w, ",\"attempt\":{}}},\"state\":\"{}\",\"created_at\":{}", p.attempt, state, p.created_at.saturating_mul(1_000_000_000));
+    if let Some(ip) = crate::hv::cri::ip(p.id) {
+        let _ = w.write_str(",\"network\":{\"ip\":");
+        ip_json(w, ip, None);
+        let _ = w.write_str("}");
+    }
+    let _ = w.write_str("}");
+}
+
+fn cri_container_json(w: &mut BufWriter, c: &crate::hv::cri::Container, detail: bool) {
+    let _ = write!(w, "{{\"id\":\"{}\",\"pod_sandbox_id\":\"{}\",\"metadata\":{{\"name\":", c.id, c.sandbox);
+    json_str(w, c.name());
+    let _ = write!(w, ",\"attempt\":{}}},\"image\":{{\"image\":", c.attempt);
+    json_str(w, c.image());
+    let ns = |t: i64| t.saturating_mul(1_000_000_000);
+    let _ = write!(w, "}},\"state\":\"{}\",\"created_at\":{},\"started_at\":{},\"finished_at\":{},\"exit_code\":{},\"reason\":\"{}\",\"command\":",
+        c.state.name(), ns(c.created_at), ns(c.started_at), ns(c.finished_at), c.exit_code, c.reason);
+    json_str(w, c.command());
+    if detail {
+        // The tail may start inside a character; drop the partial one.
+        let log = c.log();
+        let log = &log[log.iter().position(|&b| b & 0xC0 != 0x80).unwrap_or(log.len())..];
+        let text = match core::str::from_utf8(log) { Ok(s) => s, Err(e) => core::str::from_utf8(&log[..e.valid_up_to()]).unwrap_or("") };
+        let _ = w.write_str(",\"log\":");
+        json_str(w, text);
+    }
+    let _ = w.write_str("}");
+}
+
+fn cri_sandboxes(system_table: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    crate::hv::cri::pump(system_table);
+    let _ = w.write_str("{\"items\":[");
+    let mut first = true;
+    crate::hv::cri::for_each_sandbox(|p| {
+        if !first { let _ = w.write_str(","); }
+        first = false;
+        cri_sandbox_json(w, p);
+    });
+    let _ = w.write_str("]}");
+    ("200 OK", JSON)
+}
+
+fn cri_run(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::cri::{self, PodConfig};
+    let num = |b: &[u8], k: &str, default: u64| match field(b, k) { None => Some(default), Some(_) => field_u64(b, k) };
+    let meta = object(r.body, "metadata").unwrap_or(&[]);
+    let (Some(name), Some(namespace), Some(uid)) = (field_str(meta, "name"), field_str(meta, "namespace"), field_str(meta, "uid")) else {
+        return fail(w, "400 Bad Request", "api: metadata needs \"name\", \"namespace\" and \"uid\"");
+    };
+    let (Some(attempt), Some(vcpus), Some(mem)) = (num(meta, "attempt", 0), num(r.body, "vcpus", 1), num(r.body, "memory_mib", cri::DEFAULT_MEMORY_MIB)) else {
+        return fail(w, "400 Bad Request", "api: attempt, vcpus and memory_mib must be numbers");
+    };
+    if attempt > u32::MAX as u64 || vcpus > u32::MAX as u64 { return fail(w, "400 Bad Request", "api: value out of range"); }
+    let mut cfg = PodConfig::new(name, namespace, uid);
+    cfg.attempt = attempt as u32;
+    cfg.vcpus = vcpus as u32;
+    cfg.memory_mib = mem;
+    if let Some(k) = field_str(r.body, "kernel") { cfg.kernel = k; }
+    if let Some(c) = field_str(r.body, "cmdline") { cfg.cmdline = c; }
+    cfg.network = field_str(r.body, "network");
+    match cri::run_sandbox(system_table, &cfg).map(cri::sandbox) {
+        Ok(Some(p)) => { cri_sandbox_json(w, &p); ("201 Created", JSON) }
+        Ok(None) => fail(w, "500 Internal Server Error", "cri: sandbox table lost the new pod"),
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn cri_sandbox(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    crate::hv::cri::pump(system_table);
+    let Some(p) = cri_id(sel).and_then(crate::hv::cri::sandbox) else { return fail(w, "404 Not Found", "cri: no such pod sandbox") };
+    cri_sandbox_json(w, &p);
+    ("200 OK", JSON)
+}
+
+fn cri_stop_sandbox(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    if let Some(id) = cri_id(sel) {
+        if let Err(e) = crate::hv::cri::stop_sandbox(system_table, id) { return fail(w, "409 Conflict", e); }
+    }
+    let _ = w.write_str("{}");
+    ("200 OK", JSON)
+}
+
+fn cri_remove(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    if let Some(id) = cri_id(sel) {
+        if let Err(e) = crate::hv::cri::remove_sandbox(system_table, id) { return fail(w, "409 Conflict", e); }
+    }
+    let _ = w.write_str("{}");
+    ("200 OK", JSON)
+}
+
+fn cri_containers(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let pod = match r.query("pod_sandbox_id") {
+        None => None,
+        Some(v) => match cri_id(v) { Some(id) => Some(id), None => return fail(w, "400 Bad Request", "api: pod_sandbox_id must be a sandbox id") },
+    };
+    crate::hv::cri::pump(system_table);
+    let _ = w.write_str("{\"containers\":[");
+    let mut first = true;
+    crate::hv::cri::for_each_container(pod, |c| {
+        if !first { let _ = w.write_str(","); }
+        first = false;
+        cri_container_json(w, c, false);
+    });
+    let _ = w.write_str("]}");
+    ("200 OK", JSON)
+}
+
+fn cri_create(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::cri::{self, Command};
+    let Some(pod) = field(r.body, "pod_sandbox_id").and_then(cri_id) else { return fail(w, "400 Bad Request", "api: body needs \"pod_sandbox_id\"") };
+    if cri::sandbox(pod).is_none() { return fail(w, "404 Not Found", "cri: no such pod sandbox"); }
+    let meta = object(r.body, "metadata").unwrap_or(&[]);
+    let Some(name) = field_str(meta, "name") else { return fail(w, "400 Bad Request", "api: metadata needs \"name\"") };
+    let attempt = match field(meta, "attempt") {
+        None => 0,
+        Some(_) => match field_u64(meta, "attempt") { Some(a) if a <= u32::MAX as u64 => a as u32, _ => return fail(w, "400 Bad Request", "api: attempt must be a number") },
+    };
+    let Some(image) = object(r.body, "image").and_then(|o| field_str(o, "image")) else { return fail(w, "400 Bad Request", "api: body needs \"image\": {\"image\": ..}") };
+    let mut cmd = match Command::new(field_str(r.body, "working_dir").unwrap_or("")) { Ok(c) => c, Err(e) => return fail(w, "400 Bad Request", e) };
+    for o in objects(r.body, "envs") {
+        let Some(key) = field_str(o, "key") else { return fail(w, "400 Bad Request", "api: an env needs a \"key\"") };
+        if let Err(e) = cmd.env(key, field_str(o, "value").unwrap_or("")) { return fail(w, "400 Bad Request", e); }
+    }
+    for word in strings(r.body, "command").chain(strings(r.body, "args")) {
+        let Ok(word) = core::str::from_utf8(word) else { return fail(w, "400 Bad Request", "api: command and args must be text") };
+        if let Err(e) = cmd.arg(word) { return fail(w, "400 Bad Request", e); }
+    }
+    match cri::create_container(system_table, pod, name, attempt, image, &cmd).map(cri::container) {
+        Ok(Some(c)) => { cri_container_json(w, &c, false); ("201 Created", JSON) }
+        Ok(None) => fail(w, "500 Internal Server Error", "cri: container table lost the new container"),
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn cri_container(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    crate::hv::cri::pump(system_table);
+    let Some(c) = cri_container_id(sel).and_then(crate::hv::cri::container) else { return fail(w, "404 Not Found", "cri: no such container") };
+    cri_container_json(w, &c, true);
+    ("200 OK", JSON)
+}
+
+fn cri_start(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(id) = cri_container_id(sel).filter(|&id| crate::hv::cri::container(id).is_some()) else { return fail(w, "404 Not Found", "cri: no such container") };
+    // SAFETY: as in carve_gpu.
+    let mut st = unsafe { system_table.unsafe_clone() };
+    if let Err(e) = crate::hv::cri::start_container(&mut st, id) { return fail(w, "409 Conflict", e); }
+    match crate::hv::cri::container(id) {
+        Some(c) => { cri_container_json(w, &c, false); ("200 OK", JSON) }
+        None => fail(w, "404 Not Found", "cri: no such container"),
+    }
+}
+
+fn cri_stop(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(id) = cri_container_id(sel).filter(|&id| crate::hv::cri::container(id).is_some()) else { return fail(w, "404 Not Found", "cri: no such container") };
+    let timeout = match field(r.body, "timeout") {
+        None => 0,
+        Some(_) => match field_u64(r.body, "timeout") { Some(t) if t <= u32::MAX as u64 => t as u32, _ => return fail(w, "400 Bad Request", "api: timeout must be a number of seconds") },
+    };
+    // SAFETY: as in carve_gpu.
+    let mut st = unsafe { system_table.unsafe_clone() };
+    if let Err(e) = crate::hv::cri::stop_container(&mut st, id, timeout) { return fail(w, "409 Conflict", e); }
+    match crate::hv::cri::container(id) {
+        Some(c) => { cri_container_json(w, &c, false); ("200 OK", JSON) }
+        None => fail(w, "404 Not Found", "cri: no such container"),
+    }
+}
+
+fn cri_remove_container(system_table: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    if let Some(id) = cri_container_id(sel) {
+        // SAFETY: as in carve_gpu.
+        let mut st = unsafe { system_table.unsafe_clone() };
+        if let Err(e) = crate::hv::cri::remove_container(&mut st, id) { return fail(w, "409 Conflict", e); }
+    }
+    let _ = w.write_str("{}");
+    ("200 OK", JSON)
+}
+
+fn microvm_json(w: &mut BufWriter, t: &crate::hv::microvm::Template) {
+    let _ = write!(w, "{{\"id\":{},\"name\":", t.id);
+    json_str(w, t.name());
+    let kind = match t.image.kind { crate::hv::loader::ImageKind::Flat => "flat", crate::hv::loader::ImageKind::Linux => "linux" };
+    let _ = write!(w, ",\"volume\":{},\"memory_mib\":{},\"vcpus\":{},\"vsock\":{},\"kind\":\"{}\",\"signature\":\"{}\"",
+        t.volume, t.memory_mib(), t.vcpus, t.vsock, kind, t.image.signature.name());
+    if let Some(n) = t.network() { let _ = w.write_str(",\"network\":"); json_str(w, n); }
+    let _ = write!(w, ",\"pool\":{{\"target\":{},\"warm\":{}", t.pool_target, crate::hv::microvm::pooled(t.id));
+    if let Some(e) = t.pool_error { let _ = w.write_str(",\"error\":"); json_str(w, e); }
+    let l = &t.latency;
+    let _ = write!(w, "}},\"clones\":{},\"latency\":{{\"starts\":{},\"pool_hits\":{},\"on_target\":{},\"target_us\":{},\"last_us\":{},\"best_us\":{},\"worst_us\":{}}}}}",
+        t.clones, l.starts, l.pool_hits, l.on_target, crate::hv::microvm::TARGET_US, l.last_us, l.best_us, l.worst_us);
+}
+
+fn microvm_id(sel: &[u8]) -> Option<u32> { core::str::from_utf8(sel).ok().and_then(crate::hv::microvm::find) }
+
+fn microvm_templates(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let _ = w.write_str("{\"templates\":[");
+    let mut first = true;
+    crate::hv::microvm::for_each_template(|t| {
+        if !first { let _ = w.write_str(","); }
+        first = false;
+        microvm_json(w, t);
+    });
+    let _ = w.write_str("]}");
+    ("200 OK", JSON)
+}
+
+fn microvm_create(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::hv::microvm::{self, TemplateConfig};
+    let made = match (field_str(r.body, "volume"), field_str(r.body, "name"), field_str(r.body, "kernel")) {
+        (Some(vol), None, None) => microvm::open(system_table, vol),
+        (None, Some(name), Some(kernel)) => {
+            let num = |k: &str, default: u64| match field(r.body, k) { None => Some(default), Some(_) => field_u64(r.body, k) };
+            let (Some(vcpus), Some(mem)) = (num("vcpus", 1), num("memory_mib", microvm::DEFAULT_MEMORY_MIB)) else {
+                return fail(w, "400 Bad Request", "api: vcpus and memory_mib must be numbers");
+            };
+            if vcpus > u32::MAX as u64 { return fail(w, "400 Bad Request", "api: value out of range"); }
+            let mut cfg = TemplateConfig::new(name, kernel);
+            cfg.vcpus = vcpus as u32;
+            cfg.memory_mib = mem;
+            if let Some(c) = field_str(r.body, "cmdline") { cfg.cmdline = c; }
+            cfg.vsock = field(r.body, "vsock") != Some(b"false");
+            cfg.network = field_str(r.body, "network");
+            microvm::create(system_table, &cfg)
+        }
+        _ => return fail(w, "400 Bad Request", "api: give \"name\" and \"kernel\", or \"volume\""),
+    };
+    match made.map(microvm::template) {
+        Ok(Some(t)) => { microvm_json(w, &t); ("201 Created", JSON) }
+        Ok(None) => fail(w, "500 Internal Server Error", "microvm: template table lost the new template"),
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn microvm_template(_: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(t) = microvm_id(sel).and_then(crate::hv::microvm::template) else { return fail(w, "404 Not Found", "microvm: no such template") };
+    microvm_json(w, &t);
+    ("200 OK", JSON)
+}
+
+fn microvm_remove(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(id) = microvm_id(sel) else { return fail(w, "404 Not Found", "microvm: no such template") };
+    let purge = r.query("purge") == Some(b"true".as_slice());
+    if let Err(e) = crate::hv::microvm::remove(system_table, id, purge) { return fail(w, "409 Conflict", e); }
+    let _ = w.write_str("{}");
+    ("200 OK", JSON)
+}
+
+fn microvm_pool(_: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(id) = microvm_id(sel) else { return fail(w, "404 Not Found", "microvm: no such template") };
+    let Some(size) = field_u64(r.body, "size").filter(|&s| s <= u32::MAX as u64) else { return fail(w, "400 Bad Request", "api: size must be a number") };
+    if let Err(e) = crate::hv::microvm::set_pool(id, size as u32) { return fail(w, "400 Bad Request", e); }
+    match crate::hv::microvm::template(id) {
+        Some(t) => { microvm_json(w, &t); ("200 OK", JSON) }
+        None => fail(w, "404 Not Found", "microvm: no such template"),
+    }
+}
+
+fn microvm_start(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(id) = microvm_id(sel) else { return fail(w, "404 Not Found", "microvm: no such template") };
+    match crate::hv::microvm::start(system_table, id, field_str(r.body, "name")) {
+        Ok(s) => {
+            let _ = write!(w, "{{\"vm\":{},\"vcpus\":{},\"start_us\":{},\"pooled\":{}}}", s.vm_id, s.vcpus, s.us, s.pooled);
+            ("201 Created", JSON)
+        }
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn wasm_json(w: &mut BufWriter, s: &crate::hv::wasm::Status) {
+    use crate::hv::wasm::State;
+    let _ = write!(w, "{{\"vm\":{},\"state\":\"{}\"", s.vm_id, s.state.name());
+    match s.state {
+        State::Exited(code) => { let _ = write!(w, ",\"exit_code\":{}", code); }
+        State::Trapped(why) => { let _ = w.write_str(",\"trap\":"); json_str(w, why); }
+        _ => {}
+    }
+    let _ = write!(w, ",\"signature\":\"{}\",\"bytes\":{},\"functions\":{},\"memory\":{{\"pages\":{},\"limit\":{}}},\"instructions\":{},\"host_calls\":{},\"run_us\":{},\"parks\":{},\"output\":",
+        s.signature.name(), s.bytes, s.functions, s.pages, s.limit, s.instructions, s.host_calls, s.run_us, s.parks);
+    let mut buf = [0u8; crate::hv::wasm::OUT_MAX];
+    let n = crate::hv::wasm::output(s.vm_id, &mut buf);
+    // Output is bytes; keep the valid UTF-8 after any character cut off at the front.
+    let start = buf[..n].iter().position(|&b| b & 0xC0 != 0x80).unwrap_or(n);
+    let text = match core::str::from_utf8(&buf[start..n]) {
+        Ok(t) => t,
+        Err(e) => core::str::from_utf8(&buf[start..start + e.valid_up_to()]).unwrap_or(""),
+    };
+    json_str(w, text);
+    let _ = w.write_str("}");
+}
+
+fn wasm_status(_: &SystemTable<Boot>, _: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    let Some(s) = crate::hv::wasm::status(info.id) else { return fail(w, "404 Not Found", "wasm: no module loaded") };
+    wasm_json(w, &s);
+    ("200 OK", JSON)
+}
+
+fn wasm_load(system_table: &SystemTable<Boot>, r: &Request, sel: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(info) = select(sel) else { return fail(w, "404 Not Found", "vm: no such vm") };
+    let Some(path) = field_str(r.body, "path") else { return fail(w, "400 Bad Request", "api: path is required") };
+    let mem = match field(r.body, "memory_mib") {
+        None => crate::hv::wasm::DEFAULT_MEMORY_MIB,
+        Some(_) => match field_u64(r.body, "memory_mib") { Some(v) => v, None => return fail(w, "400 Bad Request", "api: memory_mib must be a number") },
+    };
+    match crate::hv::wasm::load(system_table, info.id, path, mem) {
+        Ok(s) => { wasm_json(w, &s); ("200 OK", JSON) }
+        Err(e) => fail(w, "409 Conflict", e),
+    }
+}
+
+fn capabilities(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let _ = write!(w, "{{\"version\":\"{}\",\"api_version\":\"{}\",\"features\":[", env!("CARGO_PKG_VERSION"), API_VERSION);
+    let mut first = true;
+    crate::hv::features::for_each(|f| {
+        let _ = write!(w, "{}{{\"name\":\"{}\",\"subsystem\":\"{}\",\"status\":\"{}\"", if first { "" } else { "," }, f.name, f.subsystem, f.status.name());
+        if !f.note.is_empty() { let _ = w.write_str(",\"note\":"); json_str(w, f.note); }
+        let _ = w.write_str("}");
+        first = false;
+    });
+    let _ = w.write_str("]}");
+    ("200 OK", JSON)
+}
+
+fn volume_json(w: &mut BufWriter, v: &crate::hv::storage::VolInfo) {
+    let _ = write!(w, "{{\"id\":{},\"name\":", v.id);
+    json_str(w, v.name());
+    let _ = write!(w, ",\"snapshot\":{}", v.snapshot);
+    if v.parent != 0 { let _ = write!(w, ",\"parent\":{}", v.parent); }
+    let _ = write!(w, ",\"size\":{},\"allocated\":{},\"exclusive\":{}}}", v.size, v.allocated, v.exclusive);
+}
+
+fn storage(w: &mut BufWriter) {
+    match crate::hv::storage::info() {
+        Some(p) => {
+            match p.device {
+                crate::hv::storage::Device::Disk(d) => { let _ = write!(w, "{{\"pool\":{{\"disk\":{}", d); }
+                crate::hv::storage::Device::Nvme { ctrl, nsid } => { let _ = write!(w, "{{\"pool\":{{\"nvme\":\"{}n{}\"", ctrl, nsid); }
+            }
+            let _ = write!(w, ",\"lba\":{},\"block_size\":{},\"generation\":{},\"capacity\":{},\"used\":{},\"provisioned\":{},\"provision_limit\":{},\"dirty\":{}}},\"volumes\":[",
+                p.lba, p.block_size, p.generation, p.capacity, p.used, p.provisioned, p.provision_limit, p.dirty);
+        }
+        None => { let _ = w.write_str("{\"pool\":null,\"volumes\":["); }
+    }
+    let mut first = true;
+    crate::hv::storage::for_each(|v| {
+        if !first { let _ = w.write_str(","); }
+        first = false;
+        volume_json(w, v);
+    });
+    let _ = w.write_str("]}");
+}
+
+fn carbon_status(system_table: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    carbon(system_table, w);
+    ("200 OK", JSON)
+}
+
+fn carbon_sample(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let Some(g) = field_u64(r.body, "intensity_g") else { return fail(w, "400 Bad Request", "api: body needs {\"intensity_g\": <gCO2e/kWh>}") };
+    let valid = match field(r.body, "valid_min") {
+        None => crate::hv::carbon::DEFAULT_SAMPLE_MIN as u64,
+        Some(_) => match field_u64(r.body, "valid_min") { Some(v) => v, None => return fail(w, "400 Bad Request", "api: valid_min must be a number") },
+    };
+    if g > u32::MAX as u64 || valid > u32::MAX as u64 { return fail(w, "400 Bad Request", "api: value out of range"); }
+    if let Err(e) = crate::hv::carbon::push_sample(system_table, g as u32, valid as u32) { return fail(w, "400 Bad Request", e); }
+    carbon(system_table, w);
+    ("200 OK", JSON)
+}
+
+fn carbon(system_table: &SystemTable<Boot>, w: &mut BufWriter) {
+    let s = crate::hv::carbon::status(system_table);
+    let _ = w.write_str("{\"intensity_g\":");
+    let _ = match s.intensity_g { Some(g) => write!(w, "{}", g), None => w.write_str("null") };
+    let _ = write!(w, ",\"source\":\"{}\"", s.source.name());
+    if let Some(until) = s.sample_until { let _ = write!(w, ",\"sample_until\":{}", until); }
+    let _ = write!(w, ",\"threshold_g\":{},\"max_defer_min\":{},\"avoided_mg\":{},\"queued\":[",
+        s.policy.threshold_g, s.policy.max_defer_min, crate::hv::carbon::avoided_mg());
+    let mut first = true;
+    crate::hv::carbon::for_each(|j| {
+        let _ = write!(w, "{}{{\"vm\":{},\"kind\":\"{}\",\"energy_wh\":{},\"submit_g\":{},\"deadline\":{}}}",
+            if first { "" } else { "," }, j.vm_id, j.kind.name(), j.energy_wh, j.submit_g, j.deadline_unix);
+        first = false;
+    });
+    let _ = w.write_str("]}");
+}
+
+fn cluster(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let st = crate::cluster::status();
+    let Some(cfg) = st.cfg else {
+        let _ = w.write_str("{\"configured\":false,\"mode\":\"standalone\",\"members\":[]}");
+        return ("200 OK", JSON);
+    };
+    let t = crate::cluster::membership::timeouts(&cfg);
+    let _ = write!(w, "{{\"configured\":true,\"cluster\":{},\"node\":{},\"mode\":\"{}\",\"quorate\":{},\"term\":{},\"suspect_ms\":{},\"dead_ms\":{},\"members\":[",
+        cfg.cluster, cfg.node, st.mode.name(), st.mode.quorate(), st.term, t.suspect_ms, t.dead_ms);
+    let now = crate::time::clock::now_ms();
+    let mut first = true;
+    crate::cluster::membership::for_each(|m| {
+        let c = m.mac;
+        let _ = write!(w, "{}{{\"node\":{},\"mac\":\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\",\"state\":\"{}\",\"heartbeat\":{},\"age_ms\":",
+            if first { "" } else { "," }, m.node, c[0], c[1], c[2], c[3], c[4], c[5], m.health.name(), m.heartbeat);
+        let _ = match m.age_ms(now) { Some(ms) => write!(w, "{}", ms), None => w.write_str("null") };
+        let _ = write!(w, ",\"static\":{}}}", m.static_peer);
+        first = false;
+    });
+    let _ = w.write_str("]}");
+    ("200 OK", JSON)
+}
+
+fn create_vm(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let body = r.body;
+    let num = |key: &str, default: u64| match field(body, key) {
+        None => Some(default),
+        Some(_) => field_u64(body, key),
+    };
+    let (Some(vcpus), Some(mem_mib), Some(huge_mib), Some(devs)) = (num("vcpus", 1), num("memory_mib", 256), num("hugepages_mib", 0), num("devices", 0)) else {
+        return fail(w, "400 Bad Request", "api: vcpus, memory_mib, hugepages_mib and devices must be numbers");
+    };
+    if vcpus == 0 || vcpus > u32::MAX as u64 || devs > u32::MAX as u64 || mem_mib == 0 || mem_mib >> 44 != 0 || huge_mib >> 44 != 0 {
+        return fail(w, "400 Bad Request", "api: value out of range");
+    }
+    let name = match field(body, "name") {
+        None => None,
+        Some(n) => match core::str::from_utf8(n) { Ok(s) => Some(s), Err(_) => return fail(w, "400 Bad Request", "vm: invalid name") },
+    };
+    if let Some(n) = name {
+        if !vm::valid_name(n) { return fail(w, "400 Bad Request", "vm: invalid name"); }
+        if vm::find_vm_by_name(n).is_some() { return fail(w, "409 Conflict", "vm: name in use"); }
+    }
+    let place = match field(body, "placement") {
+        None | Some(b"auto") => crate::cluster::placement::enabled(),
+        Some(b"local") => false,
+        Some(_) => return fail(w, "400 Bad Request", "api: placement must be auto or local"),
+    };
+    let rule = match field(body, "rule").map(core::str::from_utf8) {
+        None => placement::Rule::AntiAffinity(placement::Scope::Host),
+        Some(Ok(v)) => match placement::Rule::parse(v) { Some(r) => r, None => return fail(w, "400 Bad Request", "api: unknown group rule") },
+        Some(Err(_)) => return fail(w, "400 Bad Request", "api: unknown group rule"),
+    };
+    let group = match field(body, "group").map(core::str::from_utf8) {
+        None => None,
+        Some(Ok(label)) => match placement::Group::new(label, rule) { Ok(g) => Some(g), Err(e) => return fail(w, "400 Bad Request", e) },
+        Some(Err(_)) => return fail(w, "400 Bad Request", "api: group label must be text"),
+    };
+    let mut placed = None;
+    if place {
+        let Ok(mut spec) = placement::Spec::new(vcpus as u32, mem_mib, huge_mib, devs as u32, name) else { return fail(w, "400 Bad Request", "vm: invalid name") };
+        spec.group = group;
+        let d = placement::decide(system_table, &spec);
+        if let placement::Target::Remote { node, .. } = d.target {
+            return match placement::forward(&spec, &d) {
+                Ok(ticket) => {
+                    let _ = write!(w, "{{\"forwarded\":true,\"node\":{},\"ticket\":{},\"score\":{},\"local_score\":", node, ticket, d.score.unwrap_or(0));
+                    let _ = match d.local_score { Some(l) => write!(w, "{}}}", l), None => w.write_str("null}") };
+                    ("202 Accepted", JSON)
+                }
+                Err(e) => fail(w, "503 Service Unavailable", e),
+            };
+        }
+        placed = Some((spec, d));
+    }
+    let numa = match group.map(|g| placement::admit_group(system_table, &g)) {
+        Some(Ok(n)) => n,
+        Some(Err(e)) => return fail(w, "409 Conflict", e),
+        None => None,
+    };
+    let config = vm::VmConfig { memory_bytes: mem_mib << 20, vcpu_count: vcpus as u32, ..Default::default() };
+    let created = match vm::Vm::try_create(system_table, config, huge_mib << 20, devs as u32) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = write!(w, "{{\"error\":\"admission: denied\",\"resource\":\"{}\",\"requested\":{},\"available\":{},\"limit\":{}}}", e.resource.name(), e.requested, e.available, e.limit);
+            return ("409 Conflict", JSON);
+        }
+    };
+    if let Err(e) = vm::register(&created, name) {
+        created.destroy();
+        return fail(w, "409 Conflict", e);
+    }
+    if let Some((spec, d)) = placed { placement::placed_locally(created.id.0, &spec, &d); }
+    // The VM exists either way; a failed pin shows up in `cluster placement check`.
+    if let Some(g) = group { let _ = placement::join(system_table, created.id.0, g, numa); }
+    match vm::find_vm(created.id.0) {
+        Some(info) => { vm_json(w, &info, false); ("201 Created", JSON) }
+        None => fail(w, "500 Internal Server Error", "vm: registry lost the new vm"),
+    }
+}
+
+fn vm_json(w: &mut BufWriter, v: &VmInfo, detail: bool) {
+    let vendor = match v.vendor { vm::HvVendor::Intel => "intel", vm::HvVendor::Amd => "amd", vm::HvVendor::Unknown => "unknown" };
+    let _ = write!(w, "{{\"id\":{},\"name\":", v.id);
+    json_str(w, v.name());
+    let _ = write!(w, ",\"state\":\"{}\",\"vcpus\":{},\"memory_bytes\":{},\"vendor\":\"{}\",\"starts\":{}", v.state.name(), v.vcpus, v.memory_bytes, vendor, v.starts);
+    if detail {
+        if let Some(u) = vm::usage(v.id) {
+            let _ = write!(w, ",\"vcpus_live\":{},\"exits\":{},\"mem_reserved\":{},\"mem_backed\":{},\"run_ms\":{}", u.vcpus_live, u.exits, u.mem_reserved, u.mem_backed, u.run_ms);
+        }
+        let _ = write!(w, ",\"destroyable\":{}", v.state.destroyable());
+        if let Some(p) = placement::record(v.id) {
+            let opt = |w: &mut BufWriter, s: Option<u16>| { let _ = match s { Some(v) => write!(w, "{}", v), None => w.write_str("null") }; };
+            let _ = write!(w, ",\"placement\":{{\"origin\":{},\"score\":", p.origin);
+            opt(w, p.score);
+            let _ = w.write_str(",\"origin_score\":");
+            opt(w, p.origin_score);
+            if let Some((node, score)) = p.best_remote { let _ = write!(w, ",\"best_remote\":{},\"best_remote_score\":{}", node, score); }
+            let _ = write!(w, ",\"candidates\":{}}}", p.candidates);
+        }
+        if let Some(g) = placement::group_of(v.id) {
+            let _ = write!(w, ",\"group\":{{\"label\":\"{}\",\"rule\":\"{}={}\"}}", g.label(), g.rule.key(), g.rule.scope().name());
+        }
+    }
+    let _ = w.write_str("}");
+}
+
+fn migration(w: &mut BufWriter) {
+    match crate::migrate::tracking() {
+        Some((id, dirty)) => {
+            let state = vm::find_vm(id).map_or("gone", |v| v.state.name());
+            let _ = write!(w, "{{\"active\":true,\"vm\":{},\"vm_state\":\"{}\",\"dirty_pages\":{}}}", id, state, dirty);
+        }
+        None => { let _ = w.write_str("{\"active\":false}"); }
+    }
+}
+
+fn attestation(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    let mut nonce = [0u8; crate::tpm::cmd::NONCE_MAX];
+    let nlen = match r.query("nonce").map(|h| unhex(h, &mut nonce)) {
+        None => 0,
+        Some(Some(n)) => n,
+        Some(None) => return fail(w, "400 Bad Request", "api: nonce must be hex, up to 32 bytes"),
+    };
+    let mask = match r.query("pcrs") {
+        None => 0,
+        Some(h) => match core::str::from_utf8(h).ok().and_then(|s| u32::from_str_radix(s.trim_start_matches("0x"), 16).ok()) {
+            Some(m) => m,
+            None => return fail(w, "400 Bad Request", "api: pcrs must be a hex mask"),
+        },
+    };
+    let mut hexbuf = [0u8; 2 * crate::tpm::cmd::ATTEST_MAX];
+    let mut hex = |w: &mut BufWriter, d: &[u8]| {
+        let n = crate::tpm::attest::hex(d, &mut hexbuf);
+        let _ = w.write_str("\"");
+        let _ = w.write_str(core::str::from_utf8(&hexbuf[..n]).unwrap_or(""));
+        let _ = w.write_str("\"");
+    };
+    let _ = w.write_str("{\"boot\":");
+    match crate::tpm::attest::boot_measurement() {
+        None => { let _ = w.write_str("null"); }
+        Some(m) => {
+            let _ = write!(w, "{{\"events\":{},\"extended\":{},\"path\":", m.events, m.extended);
+            json_str(w, m.path());
+            let _ = w.write_str(",\"file\":");
+            match m.file { Some(d) => hex(w, &d), None => { let _ = w.write_str("null"); } }
+            let _ = w.write_str(",\"image\":");
+            hex(w, &m.image);
+            let _ = w.write_str("}");
+        }
+    }
+    let q = match crate::tpm::attest::quote(system_table, &nonce[..nlen], mask) {
+        Ok(q) => q,
+        Err(e) => {
+            let _ = w.write_str(",\"quote\":null,\"error\":");
+            json_str(w, e);
+            let _ = w.write_str("}");
+            return ("200 OK", JSON);
+        }
+    };
+    let mask = if mask == 0 { crate::tpm::attest::DEFAULT_QUOTE_MASK } else { mask };
+    let _ = write!(w, ",\"quote\":{{\"pcrs\":\"{:#x}\",\"nonce\":", mask);
+    hex(w, &nonce[..nlen]);
+    let _ = w.write_str(",\"attest\":");
+    hex(w, &q.attest[..q.attest_len]);
+    let _ = w.write_str(",\"sig_r\":");
+    hex(w, &q.sig_r);
+    let _ = w.write_str(",\"sig_s\":");
+    hex(w, &q.sig_s);
+    let _ = w.write_str(",\"ak_x\":");
+    hex(w, &q.ak_x);
+    let _ = w.write_str(",\"ak_y\":");
+    hex(w, &q.ak_y);
+    let _ = w.write_str("}}");
+    ("200 OK", JSON)
+}
+
+/// Most events one `GET /v1/audit` returns, so a page of rendered events
+/// fits the response buffer.
+const AUDIT_PAGE_MAX: usize = 100;
+
+fn audit_events(_: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::diag::audit;
+    let num = |key: &str| r.query(key).map(|v| core::str::from_utf8(v).ok().and_then(|s| s.parse::<u64>().ok()));
+    let cursor = match num("cursor") {
+        None => 0,
+        Some(Some(c)) => c,
+        Some(None) => return fail(w, "400 Bad Request", "api: cursor must be a number"),
+    };
+    let limit = match num("limit") {
+        None => 64,
+        Some(Some(l)) if (1..=AUDIT_PAGE_MAX as u64).contains(&l) => l as usize,
+        Some(_) => return fail(w, "400 Bad Request", "api: limit must be 1..100"),
+    };
+    let mut filter = audit::Filter::ALL;
+    match num("since") {
+        None => {}
+        Some(Some(ms)) => filter.since_ms = ms,
+        Some(None) => return fail(w, "400 Bad Request", "api: since must be a number"),
+    }
+    match num("until") {
+        None => {}
+        Some(Some(ms)) => filter.until_ms = ms,
+        Some(None) => return fail(w, "400 Bad Request", "api: until must be a number"),
+    }
+    for k in r.query("kind").unwrap_or(&[]).split(|&b| b == b',').filter(|k| !k.is_empty()) {
+        match core::str::from_utf8(k).ok().and_then(audit::Filter::kind_bit) {
+            Some(bit) => filter.kinds |= bit,
+            None => return fail(w, "400 Bad Request", "api: unknown audit kind"),
+        }
+    }
+    let _ = w.write_str("{\"events\":[");
+    let mut buf = [0u8; 192];
+    let mut first = true;
+    let page = audit::query(cursor, &filter, limit, |ev| {
+        let n = audit::render_json(ev, &mut buf);
+        if !first { let _ = w.write_str(","); }
+        let _ = w.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("{}"));
+        first = false;
+    });
+    let _ = write!(w, "],\"next\":{},\"returned\":{},\"lost\":{},\"more\":{}}}", page.next, page.returned, page.lost, page.more);
+    ("200 OK", JSON)
+}
+
+fn audit_retention(_: &SystemTable<Boot>, _: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    retention_json(w, false);
+    ("200 OK", JSON)
+}
+
+fn audit_set_retention(system_table: &SystemTable<Boot>, r: &Request, _: &[u8], w: &mut BufWriter) -> Reply {
+    use crate::diag::audit::{self, Persist};
+    let mut p = audit::retention();
+    if field(r.body, "size").is_some() {
+        let Some(size) = field_u64(r.body, "size") else { return fail(w, "400 Bad Request", "api: size must be a number") };
+        p.capacity = size.min(usize::MAX as u64) as usize;
+    }
+    match field(r.body, "persist") {
+        None => {}
+        Some(b"none") => p.persist = Persist::None,
+        Some(b"var") => p.persist = Persist::Variable,
+        Some(_) => return fail(w, "400 Bad Request", "api: persist must be none or var"),
+    }
+    let save = field(r.body, "save") == Some(b"true".as_slice());
+    if let Err(e) = audit::set_retention(p) { return fail(w, "400 Bad Request", e); }
+    if save {
+        if let Err(e) = audit::save_retention(system_table) { return fail(w, "500 Internal Server Error", e); }
+    }
+    retention_json(w, save);
+    ("200 OK", JSON)
+}
+
+fn retention_json(w: &mut BufWriter, saved: bool) {
+    use crate::diag::audit;
+    let p = audit::retention();
+    let _ = write!(w, "{{\"size\":{},\"persist\":\"{}\",\"oldest\":{},\"next\":{}", p.capacity, p.persist.name(), audit::oldest(), audit::head());
+    if saved { let _ = w.write_str(",\"saved\":true"); }
+    let _ = w.write_str("}");
+}
+
+/// `s` as a JSON string literal.
+fn json_str(w: &mut BufWriter, s: &str) {
+    let _ = w.write_str("\"");
+    for c in s.chars() {
+        let _ = match c {
+            '"' => w.write_str("\\\""),
+            '\\' => w.write_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32),
+            c => w.write_char(c),
+        };
+    }
+    let _ = w.write_str("\"");
+}
+
+/// Raw value of `"key"` in a flat JSON object: a string's contents (no
+/// escapes) or a bare number or literal.
+fn field<'a>(body: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let skip_ws = |mut j: usize| { while j < body.len() && body[j].is_ascii_whitespace() { j += 1; } j };
+    let mut i = 0;
+    while i < body.len() {
+        if body[i] != b'"' { i += 1; continue; }
+        let end = i + 1 + body[i + 1..].iter().position(|&b| b == b'"')?;
+        let name = &body[i + 1..end];
+        let j = skip_ws(end + 1);
+        if body.get(j) != Some(&b':') { i = end + 1; continue; }
+        let j = skip_ws(j + 1);
+        let (value, next) = if body.get(j) == Some(&b'"') {
+            let e = j + 1 + body[j + 1..].iter().position(|&b| b == b'"')?;
+            (&body[j + 1..e], e + 1)
+        } else {
+            let e = body[j..].iter().position(|&b| b == b',' || b == b'}' || b.is_ascii_whitespace()).map_or(body.len(), |p| j + p);
+            (&body[j..e], e)
+        };
+        if name == key.as_bytes() { return Some(value); }
+        i = next;
+    }
+    None
+}
+
+/// Offset of the first byte of the value of `"key"`.
+fn value_at(body: &[u8], key: &str) -> Option<usize> {
+    let k = key.as_bytes();
+    let i = (0..body.len()).find(|&i| body[i] == b'"' && body[i + 1..].starts_with(k) && body.get(i + 1 + k.len()) == Some(&b'"'))?;
+    let j = i + 2 + k.len() + body[i + 2 + k.len()..].iter().position(|b| !b.is_ascii_whitespace())?;
+    if body[j] != b':' { return None; }
+    Some(j + 1 + body[j + 1..].iter().position(|b| !b.is_ascii_whitespace())?)
+}
+
+/// The flat object that is the value of `"key"`, without its braces.
+fn object<'a>(body: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let j = value_at(body, key).filter(|&j| body[j] == b'{')?;
+    let e = j + 1 + body[j + 1..].iter().position(|&c| c == b'}')?;
+    Some(&body[j + 1..e])
+}
+
+/// The strings (no escapes) in the array that is the value of `"key"`.
+fn strings<'a>(body: &'a [u8], key: &str) -> impl Iterator<Item = &'a [u8]> {
+    let mut at = value_at(body, key).filter(|&j| body[j] == b'[').map_or(body.len(), |j| j + 1);
+    core::iter::from_fn(move || {
+        while let Some(&b) = body.get(at) {
+            at += 1;
+            match b {
+                b'"' => {
+                    let e = at + body[at..].iter().position(|&c| c == b'"')?;
+                    let s = &body[at..e];
+                    at = e + 1;
+                    return Some(s);
+                }
+                b',' => {}
+                b if b.is_ascii_whitespace() => {}
+                _ => { at = body.len(); return None; }
+            }
+        }
+        None
+    })
+}
+
+/// The flat objects in the array that is the value of `"key"`.
+fn objects<'a>(body: &'a [u8], key: &str) -> impl Iterator<Item = &'a [u8]> {
+    let mut at = value_at(body, key).filter(|&j| body[j] == b'[').map_or(body.len(), |j| j + 1);
+    core::iter::from_fn(move || {
+        while let Some(&b) = body.get(at) {
+            at += 1;
+            match b {
+                b'{' => {
+                    let e = at + body[at..].iter().position(|&c| c == b'}')?;
+                    let o = &body[at..e];
+                    at = e + 1;
+                    return Some(o);
+                }
+                b']' => { at = body.len(); return None; }
+                _ => {}
+            }
+        }
+        None
+    })
+}
+
+fn field_str<'a>(body: &'a [u8], key: &str) -> Option<&'a str> { core::str::from_utf8(field(body, key)?).ok() }
+
+fn field_u64(body: &[u8], key: &str) -> Option<u64> {
+    core::str::from_utf8(field(body, key)?).ok()?.parse::<u64>().ok()
+}
+
+/// Decode hex `h` into `out`; returns the byte count.
+fn unhex(h: &[u8], out: &mut [u8]) -> Option<usize> {
+    if h.len() % 2 != 0 || h.len() / 2 > out.len() { return None; }
+    for (k, pair) in h.chunks(2).enumerate() {
+        out[k] = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(h.len() / 2)
+}
@@ -18,8 +18,12 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
         let stdout = system_table.stdout();
         let _ = stdout.write_str("CLI: type 'help' for commands\r\n");
     }
-    // Buffer for input line (ASCII only)
-    let mut buf = [0u8; 80];
+    // Apply the saved audit retention policy, if any.
+    let _ = crate::diag::audit::load_retention(system_table);
+    // And the guest image signature policy.
+    crate::hv::imgsig::load_policy(system_table);
+    // Buffer for input line (ASCII only); sized for ESP paths and kernel command lines
+    let mut buf = [0u8; 160];
     loop {
         // Prompt
         {
@@ -61,7 +65,49 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                         }
                     }
                 }
-                Ok(None) => { let _ = system_table.boot_services().stall(1000); }
+                Ok(None) => {
+                    if let Some(b) = crate::obs::console::read_byte() {
+                        crate::obs::console::echo(b);
+                        match b {
+                            b'\r' | b'\n' => {
+                                let _ = system_table.stdout().write_str("\r\n");
+                                break 'readline;
+                            }
+                            0x08 | 0x7F => { if len > 0 { len -= 1; } }
+                            0x20..=0x7E if len < buf.len() => { buf[len] = b; len += 1; }
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    // Idle at the prompt: give housekeeping its budgeted share.
+                    crate::hv::ksm::refill(system_table);
+                    let _ = crate::hv::microvm::refill(system_table);
+                    let _ = crate::hv::sched::background::run();
+                    let _ = crate::hv::vdev::net::pump(system_table, 16);
+                    let _ = crate::ctl::http::poll(system_table);
+                    let _ = crate::hv::vdev::blk::pump(system_table, 8);
+                    let _ = crate::hv::vdev::console::pump(system_table);
+                    let _ = crate::hv::vdev::vsock::pump(system_table);
+                    let _ = crate::hv::cri::pump(system_table);
+                    let _ = crate::hv::vdev::balloon::pump(system_table, 16);
+                    let _ = crate::hv::vdev::accel::pump(16);
+                    let _ = crate::hv::zero_copy::pump(system_table);
+                    crate::hv::gdb::poll();
+                    let _ = crate::cluster::tick(system_table, false);
+                    let _ = crate::hv::power::tick(system_table, false);
+                    let _ = crate::hv::carbon::tick(system_table, false);
+                    let _ = crate::hv::dvfs::tick(system_table, false);
+                    let _ = crate::hv::thermal::tick(system_table, false);
+                    let _ = crate::hv::gpu::tick(false);
+                    let _ = crate::iommu::fault::poll(system_table);
+                    crate::diag::audit::tick(system_table);
+                    crate::obs::page::tick();
+                    let _ = crate::hv::heartbeat::tick(system_table);
+                    crate::diag::watchdog::pet(system_table);
+                    crate::time::timer::poll();
+                    crate::migrate::retransmit_pump(system_table);
+                    let _ = system_table.boot_services().stall(1000);
+                }
                 Err(_) => { let _ = system_table.boot_services().stall(1000); }
             }
         }
@@ -69,7 +115,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
         let cmd = core::str::from_utf8(&buf[..len]).unwrap_or("").trim();
         if cmd.eq_ignore_ascii_case("help") {
             let stdout = system_table.stdout();
-            let _ = stdout.write_str("Commands: help | version | info | virtio | virtio net init | virtio net tx <hex> | virtio net tx-eth <hex> | iommu | pci | pci find [vid=<hex>] [did=<hex>] | pci class <cc> <sc> | vm | vm pause|vm resume | vm list | migrate | migrate start|migrate start id=<id>|migrate scan [clear] | migrate plan | migrate export start=<hex> len=<hex> [sink=console|null|buffer|snp|virtio] | migrate precopy [rounds=<n>] [clear] [sink=console|null|buffer|snp|virtio] | migrate precopy-throttle [rounds=<n>] [clear] [sink=console|null|buffer|snp|virtio] rate=<kbps> | migrate send-dirty [compress] [sink=console|null|buffer|snp|virtio] | migrate resend from=<seq> [count=<n>] [compress] [sink=console|null|buffer|snp|virtio] | migrate ctrl ack <seq> [sink=console|null|buffer|snp|virtio] | migrate ctrl nak <seq> [sink=console|null|buffer|snp|virtio] | migrate chan new [pages=<n>] | migrate chan clear | migrate chan dump [len=<n>] [hex] | migrate chan chunk [get|set <bytes>] | migrate chan consume <bytes> | migrate net mac [get|set xx:xx:xx:xx:xx:xx] | migrate net mtu [get|set <n>] | migrate net ether [get|set <hex>] | snp [discover|use <idx>|info|pump [limit=<n>] | poll [cycles=<n>] [sleep=<us>] [ctrl] [verify] [empty=<n>]] | virtio net pump [limit=<n>] | virtio net poll [cycles=<n>] [sleep=<us>] [ctrl] [verify] [empty=<n>] | migrate ctrl resend-sink [console|null|buffer|snp|virtio] | migrate ctrl auto-ack [on|off] | migrate ctrl auto-nak [on|off] | migrate default-sink [console|null|buffer|snp|virtio] | migrate txlog [count=<n>] | migrate reset | migrate cfg save|load | migrate session start|elapsed|bw|bw_net | migrate summary | migrate handle-ctrl [limit=<n>] | migrate verify [limit=<n>] [quiet] | migrate replay [pages=<n>] | migrate export-dirty | migrate stop | trace | trace clear | metrics | metrics clear | audit | logs | logs filter [level=<info|warn|error>] [cat=<prefix>] | loglevel [info|warn|error] | time [show|wait <usec> [busy|stall]] | wdog [off|<secs>] | sec | lang [en|ja|zh|auto] | dump [regs|idt|gdt] | quit\r\n");
+            let _ = stdout.write_str("Commands: help | version | info | virtio | virtio net init | virtio net tx <hex> | virtio net tx-eth <hex> | virtio nic [probe|info|poll [limit=<n>]|msix vector=<n> [dest=<apic>]|msix off|shutdown] | iommu | numa | mem [stats] | mem heap | mem overcommit pct=<n> | mem ksm [on|off] | smp | smp vmx on|off | smp run <cpu>|all ping|apicid|tlb|tsc [arg=<n>] | pci | pci find [vid=<hex>] [did=<hex>] | pci class <cc> <sc> | pci caps <bdf> | pci msix <bdf> [all] | pci allow|disallow [<bdf>] | pci set-bme <bdf> on|off | pci bars <bdf> | pci sriov [enable <bdf> numvfs=<n>|disable <bdf>] | gpu [list] | gpu carve <bdf> slices=<n>|off | gpu attach id=<n> gpu=<bdf> [slice=<k>] | gpu detach id=<n> gpu=<bdf> slice=<k> | fpga | fpga load <bdf> region=<n> path=<esp path> | fpga assign|release id=<n> fpga=<bdf> region=<n> | vm | vm pause|vm resume | vm list | vm load id=<n> path=<esp path> [mem=<MiB>] [at=<hex>] [cmdline=<text>] | vm new [vcpus=<n>] [mem=<MiB>] [huge=<MiB>] [dev=<n>] [cvm=sev|sev-es|sev-snp|tdx] [name=<s>] [place=auto|local] [group=<label> [affinity=host|numa|anti-affinity=host|numa]] | vm info|destroy id=<n>|name=<s> | vm rename id=<n> name=<s> | vm pause|resume id=<n>|name=<s> | vm admission [show|ratio vcpu=<pct> mem=<pct>|hugepool=<MiB>|devices=<n>|release id=<n>] | vm apicv | vm apic id=<n> [vcpu=<n>] | vm irq id=<n> [vcpu=<n>] vec=<n> [level|nmi] | vm timeinfo id=<n> | vm devices id=<n> | vm acpi id=<n> | vm numa id=<n> [vnuma=on|off] | vm run|stop id=<n> | vm vcpus id=<n> | vm rt [set id=<n> period=<us> budget=<us>|clear id=<n>] | vm pin id=<n> [cpus=<hex>|any] | vm mmio-trace [id=<n> base=<hex> len=<hex> [rate=<n>]|id=<n> off] | vm rtc id=<n> [mode=emul|host] [local|utc] [tz=<min>] [date=<unix>] [save] | vm net [add id=<n> [mac=..] [mode=bridge|nat] [ip=..]|uplink none|virtio|snp|nat hostip= hostmac= gwmac=|pump] | vm net qos [id=<n> [rate=<mbps>|off] [prio=high|normal|low]] | net switch [fdb [flush]|aging secs=<n>] | net switch port nic=<n>|uplink [vlan=<n>|trunk] [flood=on|off] [bcast=<n>|off] | net switch vm id=<n> vlan=<n>|none | net cni | cri | microvm | wasm [load id=<n> path=<p> [mem=<MiB>]|log id=<n>] | plugin [list|load path=<esp path>|enable|disable <name>] | slot [try <a|b> [boots=<n>]|ok] | setup [iommu on|off] [timeout=<s>] | vm blk [add id=<n> (file=<path>|disk=<idx> [lba=<n>] [count=<n>]|vol=<id|name>|nvme=<c>n<ns>) [ro]|hostdisks|pump] | nvme [list] | nvme probe <bdf> | nvme ns | nvme release <ctrl> | nvme assign|unassign id=<n> ctrl=<bdf> | storage | storage pool format disk=<idx>|nvme=<c>n<ns> [lba=<n>] [count=<n>] [force] | storage pool open disk=<idx>|nvme=<c>n<ns> [lba=<n>] | storage pool close | storage sync | storage vol create name=<s> size=<MiB> | storage vol snapshot|clone from=<id|name> name=<s> | storage vol delete vol=<id|name> | vm console [add id=<n>|id=<n> [log [bytes=<n>]|echo on|off|send <text>]] | vm vsock [add id=<n> [cid=<n>]|id=<n> connect|close] | vm balloon [add id=<n>|id=<n> target=<MiB>|reclaim mb=<n>|relax mb=<n>|pump] | vm accel [add id=<n>|id=<n> [inflight=<n>] [rate=<KiB/s>|off] [max=<KiB>]|engines|pump] | vm shm [create name=<s> size=<KiB>|destroy name=<s>|attach name=<s> id=<n> [ro]|detach name=<s> id=<n>|perm name=<s> id=<n> ro|rw] | vm ping id=<n> [timeout=<ms>] | vm exec id=<n> [timeout=<ms>] <command> | vm attach|detach id=<n> vf=<bdf> | vm gdb [attach id=<n> port=com1..com4|<hex>|detach id=<n>] | vm coredump id=<n> path=<esp path> | vm heartbeat [id=<n> interval=<ms> [misses=<n>] [action=log|pause|restart]|id=<n> off|resume] | migrate | migrate start|migrate start id=<id>|migrate scan [clear] | migrate plan | migrate target id=<n> | migrate export start=<hex> len=<hex> [sink=console|null|buffer|snp|virtio|rdma] | migrate precopy [rounds=<n>] [clear] [sink=console|null|buffer|snp|virtio|rdma] | migrate precopy-throttle [rounds=<n>] [clear] [sink=console|null|buffer|snp|virtio|rdma] rate=<kbps> | migrate send-dirty [compress] [sink=console|null|buffer|snp|virtio|rdma] | migrate resend from=<seq> [count=<n>] [compress] [sink=console|null|buffer|snp|virtio|rdma] | migrate ctrl ack <seq> [sink=console|null|buffer|snp|virtio] | migrate ctrl nak <seq> [sink=console|null|buffer|snp|virtio] | migrate hello [sink=console|null|buffer|snp|virtio] | migrate wire [reset] | migrate chan new [pages=<n>] | migrate chan clear | migrate chan dump [len=<n>] [hex] | migrate chan chunk [get|set <bytes>] | migrate chan consume <bytes> | migrate chan watermark high=<pct> low=<pct> | migrate chan overflow drop-oldest|reject | migrate net mac [get|set xx:xx:xx:xx:xx:xx] | migrate net mtu [get|set <n>] | migrate net ether [get|set <hex>] | migrate rdma [peer link=snp|virtio mac=<mac> peermac=<mac> ip=<a.b.c.d> peerip=<a.b.c.d> qpn=<n> peerqpn=<n> rkey=<hex> va=<hex> len=<hex> [psn=<n>] [pmtu=<n>]|register|regions|off] | snp [discover|use <idx>|info|pump [limit=<n>] | poll [cycles=<n>] [sleep=<us>] [ctrl] [verify] [empty=<n>]] | virtio net pump [limit=<n>] | virtio net poll [cycles=<n>] [sleep=<us>] [ctrl] [verify] [empty=<n>] | migrate ctrl resend-sink [console|null|buffer|snp|virtio] | migrate ctrl auto-ack [on|off] | migrate ctrl auto-nak [on|off] | migrate ctrl retransmit <ms>|off | migrate default-sink [console|null|buffer|snp|virtio|rdma] | migrate txlog [count=<n>] | migrate reset | migrate cfg save|load | migrate session start|elapsed|bw|bw_net | migrate summary | migrate handle-ctrl [limit=<n>] | migrate verify [limit=<n>] [quiet] | migrate replay [pages=<n>] | migrate selftest [pages=<n>] [sink=buffer|snp|virtio] [raw] | migrate formal [iters=<n>] [seed=<n>] | migrate export-dirty | migrate stop | trace [last=<n>] | trace clear | trace mask [all|none|<cat>,..] | trace export path=<esp path>|chan | metrics | metrics hist | metrics page | metrics clear | metrics prom [check] | http [on ip=<a.b.c.d> [port=<n>] [mac=<mac>]|off] | http token [add name=<s> role=viewer|operator|admin secret=<s>|del name=<s>|off] | audit | audit query [from=<seq>] [limit=<n>] [kind=<k>,..] [since=<ms>] [until=<ms>] [json] | audit retention [size=<n>] [persist=none|var] [save] | audit flush | audit persisted | audit verify | logs | logs filter [level=<info|warn|error>] [cat=<prefix>] | loglevel [info|warn|error|cat=<cat>,..|cat=all] | time [show|wait <usec> [busy|stall]] | timer [stats] | wdog [off|<secs>] | sec [cvm] | sec policy [strict|permissive] [save] | sec keys [add <hex>|del <n>|clear] | flow | flow label vm=<n>|shm=<name>|net|dest=<mac> [conf=<0-3>] [cats=<hex>] [integ=<0-3>]|none | lang [en|ja|zh|<catalog tag>|auto] | lang load [path=<esp path>] | dump [regs|idt|gdt] | crash [clear] | console [serial on [port=com1|com2|<hex>] [baud=<n>] [save]|serial off [save]] | runtime enter | tpm [info|init|rc] | tpm pcr read <n> | tpm pcr extend <n> <hex> | tpm nv define|undefine|write|read idx=<hex> [size=<n>] [off=<n>] [data=<text>] | tpm seal [pcrs=<hex>] data=<text> | tpm unseal | tpm log [raw|verify [pcrs=<hex>]] | tpm quote [nonce=<hex>] [pcrs=<hex>] | cluster | cluster set cluster=<n> node=<n> peer=<n> [peermac=<mac>] [mac=<mac>] [link=snp|virtio] [interval=<ms>] [timeout=<ms>] | cluster leave | cluster witness none|disk=<idx> [lba=<n>]|endpoint=<mac>|serve [link=snp|virtio]|unserve | cluster status | cluster peers add node=<n> [mac=<mac>]|del node=<n> | cluster detect suspect=<ms> dead=<ms>|auto | cluster placement [on|off|check|weights [mem=<n>] [cpu=<n>] [numa=<n>] [energy=<n>]] | cluster ha [protect id=<n> [group=<g>]|unprotect id=<n>|fence none|quorum [delay=<ms>]] | cluster log [init replicas=<n,n,..> secret=<s>|off|put <key> <value>|del <key>|get <key>|kv [prefix]] | cluster save|load|tick | power | power cap watts=<n>|mw=<n>|off | power vm id=<n> prio=high|normal|low [floor=<pct>] | power vm id=<n> off | power tick | dvfs | dvfs governor performance|balanced|powersave|off | dvfs tick | thermal | thermal on [trip=<C>] [hyst=<C>] [floor=<pct>] | thermal off | thermal tick | carbon | carbon schedule [<g0>,..,<g23>|off] | carbon sample g=<n> [valid=<min>]|off | carbon policy [threshold=<g>] [max-defer=<min>] [vcpu-w=<n>] [mig-wh=<n>] | carbon defer start id=<n> [hours=<n>]|migrate id=<n> | carbon cancel id=<n> | carbon history | carbon tick | sched | sched vm id=<n> [weight=<n>] [cap=<pct>] | sched bg budget pct=<n> [window=<us>] | sched bg enable|disable <name> | sched bg run | sched rt mask=<hex> | quit\r\n");
         if cmd.starts_with("virtio net pump") {
             // virtio net pump [limit=<n>]
             let rest = cmd.strip_prefix("virtio net pump").unwrap_or("").trim();
@@ -99,6 +145,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                        else if v.eq_ignore_ascii_case("buffer") { crate::migrate::ExportSink::Buffer }
                        else if v.eq_ignore_ascii_case("snp") { crate::migrate::ExportSink::Snp }
                        else if v.eq_ignore_ascii_case("virtio") { crate::migrate::ExportSink::Virtio }
+                       else if v.eq_ignore_ascii_case("rdma") { crate::migrate::ExportSink::Rdma }
                        else { crate::migrate::ExportSink::Buffer };
             crate::migrate::set_default_sink(sink);
             let _ = system_table.stdout().write_str("migrate: default sink updated\r\n");
@@ -116,6 +163,18 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let _ = system_table.stdout().write_str("migrate: ctrl auto-nak updated\r\n");
             continue;
         }
+        if cmd.starts_with("migrate ctrl retransmit ") {
+            let v = cmd[24..].trim();
+            let ms = if v.eq_ignore_ascii_case("off") { Some(0) } else { v.parse::<u64>().ok().filter(|&ms| ms != 0) };
+            match ms {
+                Some(ms) => {
+                    crate::migrate::ctrl_set_retransmit_ms(ms);
+                    let _ = system_table.stdout().write_str("migrate: ctrl retransmit updated\r\n");
+                }
+                None => { let _ = system_table.stdout().write_str("usage: migrate ctrl retransmit <ms>|off\r\n"); }
+            }
+            continue;
+        }
         if cmd.starts_with("migrate ctrl resend-sink ") {
             let v = &cmd[25..].trim();
             let sink = if v.eq_ignore_ascii_case("console") { crate::migrate::ExportSink::Console }
@@ -196,8 +255,8 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let _ = system_table.stdout().write_str("usage: migrate cfg [save|load]\r\n");
             continue;
         }
-            let _ = stdout.write_str("  iommu: info | units | root <bus> | lsctx <bus> | dump <bus:dev.func> | plan | validate | verify | verify-map | xlate bdf=<seg:bus:dev.func> iova=<hex> | walk bdf=<seg:bus:dev.func> iova=<hex> | apply | apply-refresh | apply-safe | quick | sync | invalidate | invalidate dom=<id> | invalidate bdf=<seg:bus:dev.func> | hard-invalidate | fsts | fclear | stats | summary | cfg save|cfg load | selftest [quick] [no-apply] [no-inv] [dom=<id>] [walk=<n>] [xlate=<n>] | sample dom=<id> iova=<hex> [count=<n>] [walk] [xlate] | amdv enable|amdv disable | amdv quick\r\n");
-            let _ = stdout.write_str("  dom: new | destroy <id> | purge <id> | seg:bus:dev.func assign <id> | seg:bus:dev.func unassign | list | map dom=<id> iova=<hex> pa=<hex> len=<hex> perm=[rwx] | unmap dom=<id> iova=<hex> len=<hex> | mappings | dump\r\n");
+            let _ = stdout.write_str("  iommu: info | units | root <bus> | lsctx <bus> | dump <bus:dev.func> | plan | validate | verify | verify-map | xlate bdf=<seg:bus:dev.func> iova=<hex> | walk bdf=<seg:bus:dev.func> iova=<hex> | apply | apply-refresh | apply-safe | quick | sync | invalidate | invalidate dom=<id> | invalidate bdf=<seg:bus:dev.func> | hard-invalidate | fsts | fclear | faults [clear|threshold=<n>|off|quarantine bdf=<seg:bus:dev.func>|release bdf=<seg:bus:dev.func>] | stats | summary | cfg save|cfg load | selftest [quick] [no-apply] [no-inv] [dom=<id>] [walk=<n>] [xlate=<n>] | sample dom=<id> iova=<hex> [count=<n>] [walk] [xlate] | amdv enable|amdv disable | amdv quick | amdv apply | amdv xlate dom=<id> iova=<hex> | ir status|dump|enable [strict|compat]|disable|route bdf=<seg:bus:dev.func> | pasid [status|enable|disable|bind bdf=<seg:bus:dev.func> pasid=<n> dom=<id>|bind-fl bdf=<seg:bus:dev.func> pasid=<n> dom=<id> [root=<hex>]|unbind bdf=<seg:bus:dev.func> pasid=<n>|map bdf=<seg:bus:dev.func> pasid=<n> iova=<hex> pa=<hex> len=<hex> [ro]|unmap bdf=<seg:bus:dev.func> pasid=<n> iova=<hex> len=<hex>|xlate bdf=<seg:bus:dev.func> pasid=<n> iova=<hex>]\r\n");
+            let _ = stdout.write_str("  dom: new | destroy <id> | purge <id> | seg:bus:dev.func assign <id> | seg:bus:dev.func unassign | list | map dom=<id> iova=<hex> pa=<hex> len=<hex> perm=[rwx] | unmap dom=<id> iova=<hex> len=<hex> | mappings | dump | export [file=<path>] | import [file=<path>]\r\n");
             continue;
         }
         if cmd.eq_ignore_ascii_case("version") {
@@ -205,17 +264,23 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let mut buf = [0u8; 192]; let mut n = 0;
             for &b in b"zerovisor " { buf[n] = b; n += 1; }
             for &b in env!("CARGO_PKG_VERSION").as_bytes() { buf[n] = b; n += 1; }
-            for &b in b" (x86_64-uefi)" { buf[n] = b; n += 1; }
-            // Print compiled feature flags for quick introspection
-            for &b in b" features=[" { buf[n] = b; n += 1; }
-            let mut first = true;
-            #[cfg(feature = "snp")]
-            { if !first { buf[n] = b' '; n += 1; } for &c in b"snp" { buf[n] = c; n += 1; } first = false; }
-            #[cfg(feature = "virtio-net")]
-            { if !first { buf[n] = b' '; n += 1; } for &c in b"virtio-net" { buf[n] = c; n += 1; } first = false; }
-            if first { for &c in b"none" { buf[n] = c; n += 1; } }
-            for &b in b"]\r\n" { buf[n] = b; n += 1; }
+            for &b in b" (x86_64-uefi) api=" { buf[n] = b; n += 1; }
+            for &b in crate::ctl::api::API_VERSION.as_bytes() { buf[n] = b; n += 1; }
+            for &b in b"\r\n" { buf[n] = b; n += 1; }
             let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+            // Capabilities as the subsystems registered them
+            crate::hv::features::for_each(|f| {
+                let mut out = [0u8; 128]; let mut n = 0;
+                for &b in b"  " { out[n] = b; n += 1; }
+                for &b in f.name.as_bytes() { out[n] = b; n += 1; }
+                out[n] = b'='; n += 1;
+                for &b in f.status.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" (" { out[n] = b; n += 1; }
+                for &b in f.subsystem.as_bytes() { out[n] = b; n += 1; }
+                if !f.note.is_empty() { for &b in b"; " { out[n] = b; n += 1; } for &b in f.note.as_bytes() { out[n] = b; n += 1; } }
+                for &b in b")\r\n" { out[n] = b; n += 1; }
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
             continue;
         }
         if cmd.starts_with("dom ") {
@@ -234,18 +299,47 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                     let stdout = system_table.stdout();
                     let mut buf = [0u8; 64]; let mut nbytes = 0;
                     for &b in b"purged maps=" { buf[nbytes] = b; nbytes += 1; }
-                    nbytes += crate::firmware::acpi::u32_to_dec(n, &mut buf[nbytes..]);
+                    nbytes += crate::util::format::u32_dec(n, &mut buf[nbytes..]);
                     buf[nbytes] = b'\r'; nbytes += 1; buf[nbytes] = b'\n'; nbytes += 1;
                     let _ = stdout.write_str(core::str::from_utf8(&buf[..nbytes]).unwrap_or("\r\n"));
                     continue;
                 }
             }
+            if rest == "export" || rest.starts_with("export ") || rest == "import" || rest.starts_with("import ") {
+                // dom export|import [file=<esp path>]  (default: UEFI variable)
+                let (verb, args) = rest.split_once(' ').unwrap_or((rest, ""));
+                let file = args.split_whitespace().find_map(|t| t.strip_prefix("file="));
+                let r = match (verb, file) {
+                    ("export", Some(p)) => crate::iommu::blob::save_file(system_table, p),
+                    ("export", None) => crate::iommu::blob::save_var(system_table),
+                    (_, Some(p)) => crate::iommu::blob::load_file(system_table, p),
+                    (_, None) => crate::iommu::blob::load_var(system_table),
+                };
+                let stdout = system_table.stdout();
+                match r {
+                    Ok(sm) => {
+                        let mut buf = [0u8; 128]; let mut n = 0;
+                        for &b in if verb == "export" { &b"exported domains="[..] } else { &b"imported domains="[..] } { buf[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(sm.domains as u64, &mut buf[n..]);
+                        for &b in b" assignments=" { buf[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(sm.assignments as u64, &mut buf[n..]);
+                        for &b in b" mappings=" { buf[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(sm.mappings as u64, &mut buf[n..]);
+                        for &b in b" bytes=" { buf[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(sm.bytes as u64, &mut buf[n..]);
+                        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
             if rest.eq_ignore_ascii_case("new") {
                 if let Some(id) = crate::iommu::state::create_domain() {
                     let stdout = system_table.stdout();
                     let mut buf = [0u8; 64]; let mut n = 0;
                     for &b in b"domain id=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(id as u32, &mut buf[n..]);
                     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
                     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                 }
@@ -304,7 +398,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 crate::iommu::state::list_domains(|id| {
                     let mut buf = [0u8; 32]; let mut n = 0;
                     for &b in b"  id=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(id as u32, &mut buf[n..]);
                     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
                     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                 });
@@ -314,15 +408,15 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                     let mut buf = [0u8; 96]; let mut n = 0;
                     for &b in b"  " { buf[n] = b; n += 1; }
                     for &b in b"seg=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
                     for &b in b" bus=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
                     for &b in b" dev=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
                     for &b in b" fn=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
                     for &b in b" dom=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(dom as u32, &mut buf[n..]);
                     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
                     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                 });
@@ -341,7 +435,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 if let (Some(domid), Some(iova), Some(pa), Some(len)) = (domid, iova, pa, len) {
                     let ok = crate::iommu::state::add_mapping(domid, iova, pa, len, r, w, x);
                     let stdout = system_table.stdout();
-                    if ok { let _ = stdout.write_str("mapped\r\n"); crate::iommu::vtd::apply_mappings(system_table); } else { let _ = stdout.write_str("map failed\r\n"); }
+                    if ok { let _ = stdout.write_str("mapped\r\n"); crate::iommu::vtd::apply_mappings(system_table); crate::iommu::amdv::apply_mappings(system_table); } else { let _ = stdout.write_str("map failed\r\n"); }
                 }
                 continue;
             }
@@ -357,6 +451,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                     let ok = crate::iommu::state::remove_mapping(domid, iova, len);
                     if ok {
                         crate::iommu::vtd::unmap_range(system_table, domid, iova, len);
+                        crate::iommu::amdv::unmap_range(system_table, domid, iova, len);
                         let stdout = system_table.stdout();
                         let _ = stdout.write_str("unmapped\r\n");
                     } else {
@@ -372,7 +467,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 crate::iommu::state::list_domains(|id| {
                     let mut buf = [0u8; 32]; let mut n = 0;
                     for &b in b"  id=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(id as u32, &mut buf[n..]);
                     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
                     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                 });
@@ -380,15 +475,15 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 crate::iommu::state::list_assignments(|seg,bus,dev,func,dom| {
                     let mut buf = [0u8; 96]; let mut n = 0;
                     for &b in b"  seg=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
                     for &b in b" bus=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
                     for &b in b" dev=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
                     for &b in b" fn=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
                     for &b in b" dom=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(dom as u32, &mut buf[n..]);
                     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
                     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                 });
@@ -396,7 +491,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 crate::iommu::state::list_mappings(|dom,iova,pa,len,r,w,x| {
                     let mut buf = [0u8; 128]; let mut n = 0;
                     for &b in b"  dom=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(dom as u32, &mut buf[n..]);
                     for &b in b" iova=0x" { buf[n] = b; n += 1; }
                     n += crate::util::format::u64_hex(iova, &mut buf[n..]);
                     for &b in b" pa=0x" { buf[n] = b; n += 1; }
@@ -417,7 +512,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 crate::iommu::state::list_mappings(|dom,iova,pa,len,r,w,x| {
                     let mut buf = [0u8; 128]; let mut n = 0;
                     for &b in b"  dom=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(dom as u32, &mut buf[n..]);
                     for &b in b" iova=0x" { buf[n] = b; n += 1; }
                     n += crate::util::format::u64_hex(iova, &mut buf[n..]);
                     for &b in b" pa=0x" { buf[n] = b; n += 1; }
@@ -463,7 +558,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let stdout = system_table.stdout();
             let mut buf = [0u8; 64]; let mut n = 0;
             for &b in b"virtio-net: tx bytes=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(sent as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(sent as u32, &mut buf[n..]);
             buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
             let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
             continue;
@@ -474,11 +569,121 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let stdout = system_table.stdout();
             let mut buf = [0u8; 64]; let mut n = 0;
             for &b in b"virtio-net: tx-eth bytes=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(sent as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(sent as u32, &mut buf[n..]);
             buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
             let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
             continue;
         }
+        if cmd == "virtio nic" || cmd.starts_with("virtio nic ") {
+            // virtio nic [probe|info|poll [limit=<n>]|msix vector=<n> [dest=<apic>]|msix off|shutdown]
+            let mut it = cmd[10..].split_whitespace();
+            match it.next() {
+                Some("probe") => {
+                    let r = crate::virtio::nic::probe(system_table);
+                    let stdout = system_table.stdout();
+                    match r {
+                        Ok(_) => { let _ = stdout.write_str("virtio-nic: up\r\n"); }
+                        Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                    }
+                }
+                None | Some("info") => {
+                    let stdout = system_table.stdout();
+                    let info = match crate::virtio::nic::info() {
+                        Some(i) => i,
+                        None => { let _ = stdout.write_str("virtio-nic: down\r\n"); continue; }
+                    };
+                    let hex = b"0123456789abcdef";
+                    let mut out = [0u8; 192]; let mut n = 0;
+                    for &b in b"virtio-nic: cfg=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(info.cfg as u64, &mut out[n..]);
+                    for &b in b" mac=" { out[n] = b; n += 1; }
+                    for (i, &m) in info.mac.iter().enumerate() {
+                        if i != 0 { out[n] = b':'; n += 1; }
+                        out[n] = hex[(m >> 4) as usize]; out[n + 1] = hex[(m & 0xF) as usize]; n += 2;
+                    }
+                    for &b in b" features=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(info.features, &mut out[n..]);
+                    for &b in b" rxq=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(info.rx_size as u64, &mut out[n..]);
+                    for &b in b" txq=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(info.tx_size as u64, &mut out[n..]);
+                    for &b in if crate::virtio::nic::link_up() { &b" link=up"[..] } else { &b" link=down"[..] } { out[n] = b; n += 1; }
+                    match info.msix {
+                        Some((v, d)) => {
+                            for &b in b" irq=msix vector=0x" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_hex(v as u64, &mut out[n..]);
+                            for &b in b" dest=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(d as u64, &mut out[n..]);
+                            for &b in b" irqs=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(crate::virtio::nic::irq_count(), &mut out[n..]);
+                        }
+                        None => { for &b in b" irq=polled" { out[n] = b; n += 1; } }
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    let s = crate::virtio::nic::stats();
+                    let (total, free) = crate::mm::dma::usage();
+                    let mut n = 0;
+                    for &b in b"virtio-nic: rx_frames=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(s.rx_frames, &mut out[n..]);
+                    for &b in b" rx_bytes=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(s.rx_bytes, &mut out[n..]);
+                    for &b in b" tx_frames=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(s.tx_frames, &mut out[n..]);
+                    for &b in b" tx_bytes=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(s.tx_bytes, &mut out[n..]);
+                    for &b in b" tx_full=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(s.tx_full, &mut out[n..]);
+                    for &b in b" dma_free=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(free as u64, &mut out[n..]);
+                    out[n] = b'/'; n += 1;
+                    n += crate::util::format::u64_dec(total as u64, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Some("poll") => {
+                    let limit = it.next().and_then(|w| w.strip_prefix("limit=")).and_then(|v| v.parse::<usize>().ok()).unwrap_or(0);
+                    let mut bytes = 0u64;
+                    let got = crate::virtio::nic::recv(limit, |f| bytes += f.len() as u64);
+                    let stdout = system_table.stdout();
+                    let mut out = [0u8; 80]; let mut n = 0;
+                    for &b in b"virtio-nic: frames=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(got as u64, &mut out[n..]);
+                    for &b in b" bytes=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(bytes, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Some("msix") => {
+                    let mut vector: Option<u8> = None; let mut dest = 0u32; let mut off = false; let mut bad = false;
+                    for w in it {
+                        if w == "off" { off = true; }
+                        else if let Some(v) = w.strip_prefix("vector=") {
+                            let p = match v.strip_prefix("0x") { Some(h) => u8::from_str_radix(h, 16), None => v.parse::<u8>() };
+                            match p { Ok(x) => vector = Some(x), Err(_) => bad = true }
+                        }
+                        else if let Some(v) = w.strip_prefix("dest=") { match v.parse::<u32>() { Ok(x) => dest = x, Err(_) => bad = true } }
+                        else { bad = true; }
+                    }
+                    let r = match (off, vector) {
+                        (true, None) if !bad => { crate::virtio::nic::disable_msix(); Ok("virtio-nic: polled\r\n") }
+                        (false, Some(v)) if !bad => crate::virtio::nic::enable_msix(v, dest).map(|_| "virtio-nic: msix on\r\n"),
+                        _ => Ok("usage: virtio nic msix vector=<n> [dest=<apic>] | virtio nic msix off\r\n"),
+                    };
+                    let stdout = system_table.stdout();
+                    match r {
+                        Ok(m) => { let _ = stdout.write_str(m); }
+                        Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                    }
+                }
+                Some("shutdown") => {
+                    crate::virtio::nic::shutdown();
+                    let _ = system_table.stdout().write_str("virtio-nic: down\r\n");
+                }
+                _ => { let _ = system_table.stdout().write_str("usage: virtio nic [probe|info|poll [limit=<n>]|msix vector=<n> [dest=<apic>]|msix off|shutdown]\r\n"); }
+            }
+            continue;
+        }
         if cmd.eq_ignore_ascii_case("iommu") || cmd.eq_ignore_ascii_case("iommu info") {
             vtd::probe_and_report(system_table);
             vtd::report_details(system_table);
@@ -499,10 +704,195 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             crate::iommu::amdv::report_units(system_table);
             continue;
         }
+        if cmd.eq_ignore_ascii_case("iommu amdv apply") {
+            amdv::apply_assignments(system_table);
+            amdv::apply_mappings(system_table);
+            continue;
+        }
+        if cmd.starts_with("iommu amdv xlate ") {
+            // iommu amdv xlate dom=<id> iova=<hex>
+            let mut dom: Option<u16> = None; let mut iova: Option<u64> = None;
+            for tok in cmd[17..].split_whitespace() {
+                if let Some(v) = tok.strip_prefix("dom=") { dom = v.parse::<u16>().ok(); continue; }
+                if let Some(v) = tok.strip_prefix("iova=") { iova = u64::from_str_radix(v.trim_start_matches("0x"), 16).ok(); continue; }
+            }
+            let stdout = system_table.stdout();
+            let (dom, iova) = match (dom, iova) {
+                (Some(d), Some(i)) => (d, i),
+                _ => { let _ = stdout.write_str("usage: iommu amdv xlate dom=<id> iova=<hex>\r\n"); continue; }
+            };
+            let mut buf = [0u8; 96]; let mut n = 0;
+            for &b in b"AMD-Vi: iova=0x" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(iova, &mut buf[n..]);
+            match amdv::translate(dom, iova) {
+                Some((pa, r, w)) => {
+                    for &b in b" -> pa=0x" { buf[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(pa, &mut buf[n..]);
+                    buf[n] = b' '; n += 1;
+                    buf[n] = if r { b'r' } else { b'-' }; n += 1;
+                    buf[n] = if w { b'w' } else { b'-' }; n += 1;
+                }
+                None => { for &b in b" not mapped" { buf[n] = b; n += 1; } }
+            }
+            buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+            continue;
+        }
         if cmd.eq_ignore_ascii_case("iommu amdv disable") {
             crate::iommu::amdv::disable_translation_all(system_table);
             continue;
         }
+        if cmd == "iommu faults" || cmd.starts_with("iommu faults ") {
+            // iommu faults [clear|threshold=<n>|off|quarantine bdf=<seg:bus:dev.func>|release bdf=<seg:bus:dev.func>]
+            let rest = cmd[12..].trim();
+            if rest.is_empty() {
+                let _ = crate::iommu::fault::poll(system_table);
+                crate::iommu::fault::report(system_table);
+                continue;
+            }
+            if rest == "clear" {
+                crate::iommu::fault::clear();
+                let _ = system_table.stdout().write_str("faults: cleared\r\n");
+                continue;
+            }
+            if rest == "off" {
+                crate::iommu::fault::set_threshold(0);
+                let _ = system_table.stdout().write_str("faults: auto-quarantine off\r\n");
+                continue;
+            }
+            if let Some(n) = rest.strip_prefix("threshold=").and_then(|v| v.parse::<u32>().ok()) {
+                crate::iommu::fault::set_threshold(n);
+                let _ = system_table.stdout().write_str("faults: threshold set\r\n");
+                continue;
+            }
+            if let Some(b) = rest.strip_prefix("quarantine bdf=").and_then(crate::hv::sriov::Bdf::parse) {
+                let r = crate::iommu::fault::quarantine(system_table, b.seg, b.bus, b.dev, b.func);
+                let stdout = system_table.stdout();
+                match r {
+                    Ok(_) => { let _ = stdout.write_str("faults: device quarantined\r\n"); }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(b) = rest.strip_prefix("release bdf=").and_then(crate::hv::sriov::Bdf::parse) {
+                let stdout = system_table.stdout();
+                match crate::iommu::fault::release(b.seg, b.bus, b.dev, b.func) {
+                    Ok(()) => { let _ = stdout.write_str("faults: quarantine lifted (device left unassigned)\r\n"); }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            let _ = system_table.stdout().write_str("usage: iommu faults [clear|threshold=<n>|off|quarantine bdf=<seg:bus:dev.func>|release bdf=<seg:bus:dev.func>]\r\n");
+            continue;
+        }
+        if cmd == "iommu ir" || cmd.starts_with("iommu ir ") {
+            // iommu ir status | dump | enable [strict|compat] | disable | route bdf=<seg:bus:dev.func>
+            let rest = cmd[8..].trim();
+            if rest.is_empty() || rest == "status" { crate::iommu::vtd_ir::report_status(system_table); continue; }
+            if rest == "dump" { crate::iommu::vtd_ir::dump(system_table); continue; }
+            if rest == "enable" || rest == "enable strict" || rest == "enable compat" {
+                let strict = match rest { "enable strict" => true, "enable compat" => false, _ => crate::iommu::vtd_ir::default_strict() };
+                match crate::iommu::vtd_ir::enable(system_table, strict) {
+                    Ok(_) => crate::iommu::vtd_ir::report_status(system_table),
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest == "disable" {
+                crate::iommu::vtd_ir::disable(system_table);
+                let _ = system_table.stdout().write_str("IR: disabled, original MSI messages restored\r\n");
+                continue;
+            }
+            if let Some(v) = rest.strip_prefix("route bdf=") {
+                if let Some(b) = crate::hv::sriov::Bdf::parse(v) {
+                    match crate::iommu::vtd_ir::route_device(system_table, b.seg, b.bus, b.dev, b.func) {
+                        Ok(n) => {
+                            let mut out = [0u8; 64]; let mut k = 0;
+                            for &c in b"IR: routed " { out[k] = c; k += 1; }
+                            k += crate::util::format::u64_dec(n as u64, &mut out[k..]);
+                            for &c in b" messages\r\n" { out[k] = c; k += 1; }
+                            let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..k]).unwrap_or("\r\n"));
+                        }
+                        Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                    }
+                    continue;
+                }
+            }
+            let _ = system_table.stdout().write_str("usage: iommu ir [status|dump|enable [strict|compat]|disable|route bdf=<seg:bus:dev.func>]\r\n");
+            continue;
+        }
+        if cmd == "iommu pasid" || cmd.starts_with("iommu pasid ") {
+            // iommu pasid [status|enable|disable|bind ..|bind-fl ..|unbind ..|map ..|unmap ..|xlate ..]
+            const USAGE: &str = "usage: iommu pasid [status|enable|disable|bind bdf=<seg:bus:dev.func> pasid=<n> dom=<id>|bind-fl bdf=<seg:bus:dev.func> pasid=<n> dom=<id> [root=<hex>]|unbind bdf=<seg:bus:dev.func> pasid=<n>|map bdf=<seg:bus:dev.func> pasid=<n> iova=<hex> pa=<hex> len=<hex> [ro]|unmap bdf=<seg:bus:dev.func> pasid=<n> iova=<hex> len=<hex>|xlate bdf=<seg:bus:dev.func> pasid=<n> iova=<hex>]\r\n";
+            let rest = cmd[11..].trim();
+            let (verb, args) = rest.split_once(' ').unwrap_or((rest, ""));
+            if verb.is_empty() || verb == "status" { crate::iommu::vtd_sm::report(system_table); continue; }
+            if verb == "enable" {
+                match crate::iommu::vtd_sm::enable(system_table) {
+                    Ok(_) => crate::iommu::vtd_sm::report(system_table),
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if verb == "disable" {
+                let r = crate::iommu::vtd_sm::disable(system_table);
+                let stdout = system_table.stdout();
+                match r {
+                    Ok(()) => { let _ = stdout.write_str("SM: legacy root tables restored\r\n"); }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            let mut bdf = None; let mut pasid: Option<u32> = None; let mut dom: Option<u16> = None;
+            let mut root: Option<u64> = None; let mut iova: Option<u64> = None; let mut pa: Option<u64> = None; let mut len: Option<u64> = None;
+            let mut ro = false;
+            let hex = |v: &str| u64::from_str_radix(v.trim_start_matches("0x"), 16).ok();
+            for tok in args.split_whitespace() {
+                if let Some(v) = tok.strip_prefix("bdf=") { bdf = crate::hv::sriov::Bdf::parse(v); continue; }
+                if let Some(v) = tok.strip_prefix("pasid=") { pasid = v.parse::<u32>().ok(); continue; }
+                if let Some(v) = tok.strip_prefix("dom=") { dom = v.parse::<u16>().ok(); continue; }
+                if let Some(v) = tok.strip_prefix("root=") { root = hex(v); continue; }
+                if let Some(v) = tok.strip_prefix("iova=") { iova = hex(v); continue; }
+                if let Some(v) = tok.strip_prefix("pa=") { pa = hex(v); continue; }
+                if let Some(v) = tok.strip_prefix("len=") { len = hex(v); continue; }
+                if tok == "ro" { ro = true; }
+            }
+            let (b, pasid) = match (bdf, pasid) {
+                (Some(b), Some(p)) => (b, p),
+                _ => { let _ = system_table.stdout().write_str(USAGE); continue; }
+            };
+            if verb == "xlate" {
+                let iova = match iova { Some(v) => v, None => { let _ = system_table.stdout().write_str(USAGE); continue; } };
+                let mut buf = [0u8; 96]; let mut n = 0;
+                for &c in b"SM: iova=0x" { buf[n] = c; n += 1; }
+                n += crate::util::format::u64_hex(iova, &mut buf[n..]);
+                match crate::iommu::vtd_sm::translate(b.seg, b.bus, b.dev, b.func, pasid, iova) {
+                    Some((pa, w)) => {
+                        for &c in b" -> pa=0x" { buf[n] = c; n += 1; }
+                        n += crate::util::format::u64_hex(pa, &mut buf[n..]);
+                        for &c in if w { &b" rw"[..] } else { &b" r-"[..] } { buf[n] = c; n += 1; }
+                    }
+                    None => { for &c in b" not mapped" { buf[n] = c; n += 1; } }
+                }
+                buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            let r = match (verb, dom, iova, pa, len) {
+                ("bind", Some(d), ..) => crate::iommu::vtd_sm::bind(system_table, b.seg, b.bus, b.dev, b.func, pasid, d),
+                ("bind-fl", Some(d), ..) => crate::iommu::vtd_sm::bind_first_level(system_table, b.seg, b.bus, b.dev, b.func, pasid, d, root),
+                ("unbind", ..) => crate::iommu::vtd_sm::unbind(system_table, b.seg, b.bus, b.dev, b.func, pasid),
+                ("map", _, Some(i), Some(p), Some(l)) => crate::iommu::vtd_sm::map(system_table, b.seg, b.bus, b.dev, b.func, pasid, i, p, l, !ro),
+                ("unmap", _, Some(i), _, Some(l)) => crate::iommu::vtd_sm::unmap(system_table, b.seg, b.bus, b.dev, b.func, pasid, i, l),
+                _ => { let _ = system_table.stdout().write_str(USAGE); continue; }
+            };
+            let stdout = system_table.stdout();
+            match r {
+                Ok(()) => { let _ = stdout.write_str("SM: ok\r\n"); }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
+            continue;
+        }
         if cmd.eq_ignore_ascii_case("iommu summary") {
             vtd::report_summary(system_table);
             continue;
@@ -592,16 +982,16 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             vtd::validate_assignments(system_table);
             continue;
         }
-        if cmd.eq_ignore_ascii_case("iommu cfg save") {
-            crate::iommu::cfg_save(system_table);
+        if cmd.eq_ignore_ascii_case("iommu cfg save") || cmd.eq_ignore_ascii_case("iommu cfg load") {
+            // Shorthand for `dom export` / `dom import` through the UEFI variable
+            let save = cmd.eq_ignore_ascii_case("iommu cfg save");
+            let r = if save { crate::iommu::blob::save_var(system_table) } else { crate::iommu::blob::load_var(system_table) };
             let lang2 = crate::i18n::detect_lang(system_table);
-            let _ = system_table.stdout().write_str(crate::i18n::t(lang2, crate::i18n::key::IOMMU_CFG_SAVED));
-            continue;
-        }
-        if cmd.eq_ignore_ascii_case("iommu cfg load") {
-            crate::iommu::cfg_load(system_table);
-            let lang2 = crate::i18n::detect_lang(system_table);
-            let _ = system_table.stdout().write_str(crate::i18n::t(lang2, crate::i18n::key::IOMMU_CFG_LOADED));
+            let stdout = system_table.stdout();
+            match r {
+                Ok(_) => { let _ = stdout.write_str(crate::i18n::t(lang2, if save { crate::i18n::key::IOMMU_CFG_SAVED } else { crate::i18n::key::IOMMU_CFG_LOADED })); }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
             continue;
         }
         if cmd.eq_ignore_ascii_case("iommu verify") {
@@ -668,10 +1058,12 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
         }
         if cmd.eq_ignore_ascii_case("iommu apply") {
             vtd::apply_assignments(system_table);
+            amdv::apply_assignments(system_table);
             continue;
         }
         if cmd.eq_ignore_ascii_case("iommu apply-refresh") {
             vtd::apply_and_refresh(system_table);
+            amdv::apply_assignments(system_table);
             continue;
         }
         if cmd.eq_ignore_ascii_case("iommu apply-safe") {
@@ -696,7 +1088,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
         }
         if cmd.starts_with("iommu invalidate dom=") {
             let v = &cmd[21..].trim();
-            if let Ok(domid) = v.parse::<u16>() { vtd::invalidate_domain(system_table, domid); continue; }
+            if let Ok(domid) = v.parse::<u16>() { vtd::invalidate_domain(system_table, domid); let _ = amdv::invalidate_domain(domid); continue; }
             let stdout = system_table.stdout();
             let _ = stdout.write_str("usage: iommu invalidate dom=<id>\r\n");
             continue;
@@ -762,8 +1154,86 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let _ = stdout.write_str("usage: iommu dump <bus:dev.func> (hex)\r\n");
             continue;
         }
-        if cmd.eq_ignore_ascii_case("trace") {
-            crate::obs::trace::dump(system_table);
+        if cmd.eq_ignore_ascii_case("trace") || cmd.starts_with("trace ") {
+            // trace [last=<n>] | trace clear | trace mask [all|none|<cat>,..] | trace export path=<esp path>|chan
+            let mut it = cmd.split_whitespace().skip(1);
+            let sub = it.next().unwrap_or("");
+            if sub.eq_ignore_ascii_case("clear") {
+                crate::obs::trace::clear();
+                let _ = system_table.stdout().write_str("trace: cleared\r\n");
+                continue;
+            }
+            if sub.eq_ignore_ascii_case("mask") {
+                if let Some(v) = it.next() {
+                    match crate::obs::trace::parse_mask(v) {
+                        Some(m) => crate::obs::trace::set_mask(m),
+                        None => { let _ = system_table.stdout().write_str("usage: trace mask [all|none|<cat>,..] (vm,exit,iommu,migrate,sched,mmio)\r\n"); continue; }
+                    }
+                }
+                let m = crate::obs::trace::mask();
+                let mut out = [0u8; 96];
+                let mut n = 0;
+                for &b in b"trace: mask=" { out[n] = b; n += 1; }
+                let mut any = false;
+                for (name, bit) in crate::obs::trace::CATEGORIES.iter() {
+                    if m & bit == 0 { continue; }
+                    if any { out[n] = b','; n += 1; }
+                    for &b in name.as_bytes() { out[n] = b; n += 1; }
+                    any = true;
+                }
+                if !any { for &b in b"none" { out[n] = b; n += 1; } }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if sub.eq_ignore_ascii_case("export") {
+                let mut path: Option<&str> = None;
+                let mut chan = false;
+                for tok in it {
+                    if let Some(v) = tok.strip_prefix("path=") { path = Some(v); continue; }
+                    if tok.eq_ignore_ascii_case("chan") { chan = true; }
+                }
+                let res = match (path, chan) {
+                    (Some(p), false) => crate::obs::trace::export_file(system_table, p),
+                    (None, true) => {
+                        let (_, cap) = crate::migrate::chan_stats();
+                        let need = crate::obs::trace::HEADER_LEN + crate::obs::trace::TRACE_CAP * crate::obs::trace::RECORD_LEN;
+                        if cap == 0 { Err("trace: no migration channel (migrate chan new)") }
+                        else if cap < need { Err("trace: migration channel too small for the ring") }
+                        else {
+                            let mut bytes = 0u64;
+                            crate::obs::trace::export_with_writer(|b| {
+                                if crate::migrate::chan_write_bytes(b) != b.len() { return Err("trace: migration channel write failed"); }
+                                bytes += b.len() as u64;
+                                Ok(())
+                            }).map(|r| (r, bytes))
+                        }
+                    }
+                    _ => { let _ = system_table.stdout().write_str("usage: trace export path=<esp path>|chan\r\n"); continue; }
+                };
+                match res {
+                    Ok((records, bytes)) => {
+                        let mut out = [0u8; 96];
+                        let mut n = 0;
+                        for &b in b"trace: exported " { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(records, &mut out[n..]);
+                        for &b in b" records, " { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(bytes, &mut out[n..]);
+                        for &b in b" bytes, dropped=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(crate::obs::trace::dropped(), &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            let last = match sub.strip_prefix("last=").map(|v| v.parse::<usize>()) {
+                None if sub.is_empty() => crate::obs::trace::DUMP_DEFAULT,
+                Some(Ok(n)) => n,
+                _ => { let _ = system_table.stdout().write_str("usage: trace [last=<n>] | trace clear | trace mask [all|none|<cat>,..] | trace export path=<esp path>|chan\r\n"); continue; }
+            };
+            crate::obs::trace::dump(system_table, last);
             continue;
         }
         if cmd.eq_ignore_ascii_case("migrate") {
@@ -771,7 +1241,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             continue;
         }
         if cmd.eq_ignore_ascii_case("migrate start") {
-            let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 256 << 20, vcpu_count: 1 });
+            let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 256 << 20, vcpu_count: 1, ..Default::default() });
             let _ = crate::hv::vm::register_vm(&vm);
             if crate::migrate::start_tracking(system_table, &vm) {
                 let lang = crate::i18n::detect_lang(system_table);
@@ -805,7 +1275,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let (_runs, _pages, bytes) = crate::migrate::export_dirty_runs(system_table, crate::migrate::ExportSink::Console);
             let mut buf = [0u8; 64]; let mut i = 0;
             for &b in b"migrate: export_bytes=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(bytes as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(bytes as u32, &mut buf[i..]);
             buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
             let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
             continue;
@@ -816,7 +1286,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let stdout = system_table.stdout();
             let mut buf = [0u8; 64]; let mut i = 0;
             for &b in b"migrate: dirty_pages=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(n as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(n as u32, &mut buf[i..]);
             buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
             let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
             continue;
@@ -832,6 +1302,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                     sink = if v.eq_ignore_ascii_case("null") { crate::migrate::ExportSink::Null }
                     else if v.eq_ignore_ascii_case("buffer") { crate::migrate::ExportSink::Buffer }
                     else if v.eq_ignore_ascii_case("snp") { crate::migrate::ExportSink::Snp }
+                    else if v.eq_ignore_ascii_case("rdma") { crate::migrate::ExportSink::Rdma }
                     else { crate::migrate::ExportSink::Console };
                     continue;
                 }
@@ -841,7 +1312,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 let stdout = system_table.stdout();
                 let mut buf = [0u8; 64]; let mut i = 0;
                 for &b in b"migrate: export_bytes=" { buf[i] = b; i += 1; }
-                i += crate::firmware::acpi::u32_to_dec(bytes as u32, &mut buf[i..]);
+                i += crate::util::format::u32_dec(bytes as u32, &mut buf[i..]);
                 buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
                 let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
                 continue;
@@ -862,6 +1333,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                     else if v.eq_ignore_ascii_case("buffer") { crate::migrate::ExportSink::Buffer }
                     else if v.eq_ignore_ascii_case("snp") { crate::migrate::ExportSink::Snp }
                     else if v.eq_ignore_ascii_case("virtio") { crate::migrate::ExportSink::Virtio }
+                    else if v.eq_ignore_ascii_case("rdma") { crate::migrate::ExportSink::Rdma }
                     else { crate::migrate::ExportSink::Null };
                     continue;
                 }
@@ -870,11 +1342,11 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let stdout = system_table.stdout();
             let mut buf = [0u8; 96]; let mut i = 0;
             for &b in b"migrate: precopy rounds=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(done as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(done as u32, &mut buf[i..]);
             for &b in b" pages=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(pages as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(pages as u32, &mut buf[i..]);
             for &b in b" bytes=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(bytes as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(bytes as u32, &mut buf[i..]);
             buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
             let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
             continue;
@@ -891,6 +1363,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                     else if v.eq_ignore_ascii_case("buffer") { crate::migrate::ExportSink::Buffer }
                     else if v.eq_ignore_ascii_case("snp") { crate::migrate::ExportSink::Snp }
                     else if v.eq_ignore_ascii_case("virtio") { crate::migrate::ExportSink::Virtio }
+                    else if v.eq_ignore_ascii_case("rdma") { crate::migrate::ExportSink::Rdma }
                     else { crate::migrate::ExportSink::Null };
                     continue;
                 }
@@ -900,11 +1373,11 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let stdout = system_table.stdout();
             let mut buf = [0u8; 96]; let mut i = 0;
             for &b in b"migrate: precopy rounds=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(done as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(done as u32, &mut buf[i..]);
             for &b in b" pages=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(pages as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(pages as u32, &mut buf[i..]);
             for &b in b" bytes=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(bytes as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(bytes as u32, &mut buf[i..]);
             buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
             let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
             continue;
@@ -930,6 +1403,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                     else if v.eq_ignore_ascii_case("buffer") { crate::migrate::ExportSink::Buffer }
                     else if v.eq_ignore_ascii_case("snp") { crate::migrate::ExportSink::Snp }
                     else if v.eq_ignore_ascii_case("virtio") { crate::migrate::ExportSink::Virtio }
+                    else if v.eq_ignore_ascii_case("rdma") { crate::migrate::ExportSink::Rdma }
                     else { crate::migrate::ExportSink::Null };
                     continue;
                 }
@@ -938,11 +1412,11 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let stdout = system_table.stdout();
             let mut buf = [0u8; 96]; let mut i = 0;
             for &b in b"migrate: sent frames=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(frames as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(frames as u32, &mut buf[i..]);
             for &b in b" pages=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(pages as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(pages as u32, &mut buf[i..]);
             for &b in b" bytes=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(bytes as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(bytes as u32, &mut buf[i..]);
             buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
             let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
             continue;
@@ -981,7 +1455,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                     let sz = crate::migrate::get_chunk_size();
                     let mut buf = [0u8; 48]; let mut i = 0;
                     for &b in b"migrate: chunk=" { buf[i] = b; i += 1; }
-                    i += crate::firmware::acpi::u32_to_dec(sz as u32, &mut buf[i..]);
+                    i += crate::util::format::u32_dec(sz as u32, &mut buf[i..]);
                     buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
                     let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
                     continue;
@@ -994,16 +1468,147 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 let _ = system_table.stdout().write_str("usage: migrate chan chunk [get|set <bytes>]\r\n");
                 continue;
             }
-            let (len, cap) = crate::migrate::chan_stats();
-            let mut buf = [0u8; 64]; let mut i = 0;
+            if let Some(rest2) = rest.strip_prefix("watermark") {
+                // migrate chan watermark high=<pct> low=<pct>
+                let (mut hi, mut lo) = (75u8, 25u8);
+                for tok in rest2.split_whitespace() {
+                    if let Some(v) = tok.strip_prefix("high=") { let _ = v.parse::<u8>().map(|n| hi = n); continue; }
+                    if let Some(v) = tok.strip_prefix("low=") { let _ = v.parse::<u8>().map(|n| lo = n); continue; }
+                }
+                match crate::migrate::chan_set_watermarks(hi, lo) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("migrate: chan watermarks updated\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(rest2) = rest.strip_prefix("overflow") {
+                // migrate chan overflow drop-oldest|reject
+                let o = match rest2.trim() {
+                    "drop-oldest" => crate::migrate::ring::Overflow::DropOldest,
+                    "reject" => crate::migrate::ring::Overflow::Reject,
+                    _ => { let _ = system_table.stdout().write_str("usage: migrate chan overflow drop-oldest|reject\r\n"); continue; }
+                };
+                crate::migrate::chan_set_overflow(o);
+                let _ = system_table.stdout().write_str("migrate: chan overflow updated\r\n");
+                continue;
+            }
+            let Some(st) = crate::migrate::chan_ring_stats() else {
+                let _ = system_table.stdout().write_str("migrate: chan len=0 cap=0\r\n");
+                continue;
+            };
+            let mut buf = [0u8; 192]; let mut i = 0;
             for &b in b"migrate: chan len=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(len as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(st.len as u32, &mut buf[i..]);
             for &b in b" cap=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(cap as u32, &mut buf[i..]);
+            i += crate::util::format::u32_dec(st.cap as u32, &mut buf[i..]);
+            for &b in b" stored=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(st.stored, &mut buf[i..]);
+            for &b in b" consumed=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(st.consumed, &mut buf[i..]);
+            for &b in b" lost=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(st.lost, &mut buf[i..]);
+            for &b in b" high=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u32_dec(st.high as u32, &mut buf[i..]);
+            for &b in b" low=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u32_dec(st.low as u32, &mut buf[i..]);
+            let o: &[u8] = match st.overflow { crate::migrate::ring::Overflow::DropOldest => b" overflow=drop-oldest", crate::migrate::ring::Overflow::Reject => b" overflow=reject" };
+            for &b in o { buf[i] = b; i += 1; }
+            if st.above { for &b in b" above-high" { buf[i] = b; i += 1; } }
             buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
             let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
             continue;
         }
+        #[cfg(feature = "formal_verification")]
+        if cmd.starts_with("migrate formal") {
+            // migrate formal [iters=<n>] [seed=<n>]
+            let rest = cmd.strip_prefix("migrate formal").unwrap_or("").trim();
+            let mut iters = 1000u64; let mut seed = crate::time::rdtsc();
+            for tok in rest.split_whitespace() {
+                if let Some(v) = tok.strip_prefix("iters=") { let _ = v.parse::<u64>().map(|n| iters = n); continue; }
+                if let Some(v) = tok.strip_prefix("seed=") { let _ = v.parse::<u64>().map(|n| seed = n); continue; }
+            }
+            let r = crate::migrate::formal::run(iters, seed);
+            let stdout = system_table.stdout();
+            let mut buf = [0u8; 192]; let mut i = 0;
+            for &b in b"formal: seed=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(seed, &mut buf[i..]);
+            for &b in b" bitmap=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.bitmap_cases, &mut buf[i..]);
+            for &b in b" header=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.header_cases, &mut buf[i..]);
+            for &b in b" tlv=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.tlv_cases, &mut buf[i..]);
+            for &b in b" rle=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.rle_cases, &mut buf[i..]);
+            match r.failed {
+                None => { for &b in b" ok" { buf[i] = b; i += 1; } }
+                Some(e) => {
+                    for &b in b" FAILED at " { buf[i] = b; i += 1; }
+                    i += crate::util::format::u64_dec(r.failed_at, &mut buf[i..]);
+                    for &b in b": " { buf[i] = b; i += 1; }
+                    for &b in e.as_bytes().iter().take(buf.len() - i - 2) { buf[i] = b; i += 1; }
+                }
+            }
+            buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd.starts_with("migrate selftest") {
+            // migrate selftest [pages=<n>] [sink=buffer|snp|virtio] [raw]
+            let rest = cmd.strip_prefix("migrate selftest").unwrap_or("").trim();
+            let mut pages = crate::migrate::selftest::DEFAULT_PAGES; let mut compress = true;
+            let mut sink = crate::migrate::ExportSink::Buffer;
+            for tok in rest.split_whitespace() {
+                if let Some(v) = tok.strip_prefix("pages=") { let _ = v.parse::<usize>().map(|n| pages = n); continue; }
+                if tok.eq_ignore_ascii_case("raw") { compress = false; continue; }
+                if let Some(v) = tok.strip_prefix("sink=") {
+                    sink = if v.eq_ignore_ascii_case("snp") { crate::migrate::ExportSink::Snp }
+                    else if v.eq_ignore_ascii_case("virtio") { crate::migrate::ExportSink::Virtio }
+                    else { crate::migrate::ExportSink::Buffer };
+                    continue;
+                }
+            }
+            let r = crate::migrate::selftest::run(system_table, pages, sink, compress);
+            let stdout = system_table.stdout();
+            let mut buf = [0u8; 192]; let mut i = 0;
+            for &b in b"selftest: pages=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.pages as u64, &mut buf[i..]);
+            for &b in b" dirtied=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.dirtied, &mut buf[i..]);
+            for &b in b" sent=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.frames_sent, &mut buf[i..]);
+            for &b in b" skipped=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.skipped, &mut buf[i..]);
+            for &b in b" ok=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.frames_ok, &mut buf[i..]);
+            for &b in b" bad=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.frames_bad, &mut buf[i..]);
+            for &b in b" seq_err=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.seq_errors, &mut buf[i..]);
+            for &b in b" replayed=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.replayed, &mut buf[i..]);
+            for &b in b" mismatch=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.mismatches, &mut buf[i..]);
+            buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
+            i = 0;
+            for &b in b"selftest: bytes=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.bytes, &mut buf[i..]);
+            for &b in b" elapsed_us=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.elapsed_us, &mut buf[i..]);
+            for &b in b" kib_s=" { buf[i] = b; i += 1; }
+            i += crate::util::format::u64_dec(r.kib_per_sec(), &mut buf[i..]);
+            match r.failed {
+                None => { for &b in b" PASS" { buf[i] = b; i += 1; } }
+                Some(stage) => {
+                    for &b in b" FAIL stage=" { buf[i] = b; i += 1; }
+                    for &b in stage.as_bytes() { if i + 2 < buf.len() { buf[i] = b; i += 1; } }
+                }
+            }
+            buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
+            continue;
+        }
         if cmd.starts_with("migrate verify") {
             // migrate verify [limit=<n>] [quiet]
             let rest = cmd.strip_prefix("migrate verify").unwrap_or("").trim();
@@ -1037,6 +1642,7 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                     sink = if v.eq_ignore_ascii_case("console") { crate::migrate::ExportSink::Console }
                     else if v.eq_ignore_ascii_case("buffer") { crate::migrate::ExportSink::Buffer }
                     else if v.eq_ignore_ascii_case("snp") { crate::migrate::ExportSink::Snp }
+                    else if v.eq_ignore_ascii_case("rdma") { crate::migrate::ExportSink::Rdma }
                     else { crate::migrate::ExportSink::Null };
                     continue;
                 }
@@ -1046,9 +1652,9 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 let stdout = system_table.stdout();
                 let mut buf = [0u8; 96]; let mut i = 0;
                 for &b in b"migrate: resent frames=" { buf[i] = b; i += 1; }
-                i += crate::firmware::acpi::u32_to_dec(frames as u32, &mut buf[i..]);
+                i += crate::util::format::u32_dec(frames as u32, &mut buf[i..]);
                 for &b in b" bytes=" { buf[i] = b; i += 1; }
-                i += crate::firmware::acpi::u32_to_dec(bytes as u32, &mut buf[i..]);
+                i += crate::util::format::u32_dec(bytes as u32, &mut buf[i..]);
                 buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
                 let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
                 continue;
@@ -1056,6 +1662,42 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let _ = system_table.stdout().write_str("usage: migrate resend from=<seq> [count=<n>] [compress] [sink=console|null|buffer]\r\n");
             continue;
         }
+        if cmd.starts_with("migrate hello") {
+            // migrate hello [sink=console|null|buffer|snp|virtio]
+            let mut sink = crate::migrate::ctrl_get_resend_sink();
+            for tok in cmd["migrate hello".len()..].split_whitespace() {
+                if let Some(v) = tok.strip_prefix("sink=") {
+                    sink = if v.eq_ignore_ascii_case("console") { crate::migrate::ExportSink::Console }
+                           else if v.eq_ignore_ascii_case("null") { crate::migrate::ExportSink::Null }
+                           else if v.eq_ignore_ascii_case("snp") { crate::migrate::ExportSink::Snp }
+                           else if v.eq_ignore_ascii_case("virtio") { crate::migrate::ExportSink::Virtio }
+                           else { crate::migrate::ExportSink::Buffer };
+                }
+            }
+            crate::migrate::send_hello(system_table, sink, false);
+            let _ = system_table.stdout().write_str("migrate: hello sent\r\n");
+            continue;
+        }
+        if cmd.starts_with("migrate wire") {
+            // migrate wire [reset]
+            if cmd["migrate wire".len()..].trim() == "reset" { crate::migrate::wire_reset(); }
+            let (ver, peer) = crate::migrate::wire_status();
+            let mut buf = [0u8; 96]; let mut i = 0;
+            for &b in b"wire: tx=v" { buf[i] = b; i += 1; }
+            i += crate::util::format::u32_dec(ver as u32, &mut buf[i..]);
+            match peer {
+                Some(p) => {
+                    for &b in b" peer max=v" { buf[i] = b; i += 1; }
+                    i += crate::util::format::u32_dec(p.max_ver as u32, &mut buf[i..]);
+                    for &b in b" caps=0x" { buf[i] = b; i += 1; }
+                    i += crate::util::format::u64_hex(p.caps as u64, &mut buf[i..]);
+                }
+                None => { for &b in b" peer=none" { buf[i] = b; i += 1; } }
+            }
+            buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
+            let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
+            continue;
+        }
         if cmd.starts_with("migrate ctrl ") {
             // migrate ctrl ack <seq> [sink=...] | migrate ctrl nak <seq> [sink=...]
             let rest = &cmd[13..].trim();
@@ -1078,6 +1720,144 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             let _ = system_table.stdout().write_str("usage: migrate ctrl [ack|nak] <seq> [sink=console|null|buffer]\r\n");
             continue;
         }
+        if let Some(arg) = cmd.strip_prefix("migrate target ") {
+            // migrate target id=<n>: pick the member placement would migrate to and make it the destination
+            let Some(id) = arg.trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok()) else {
+                let _ = system_table.stdout().write_str("usage: migrate target id=<n>\r\n"); continue;
+            };
+            match crate::cluster::placement::migration_target(id) {
+                Ok((node, mac, score)) => {
+                    crate::migrate::net_set_dest_mac(mac);
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    for &b in b"migrate: target node=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(node, &mut out[n..]);
+                    for &b in b" score=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(score as u64, &mut out[n..]);
+                    for &b in b" mac=" { out[n] = b; n += 1; }
+                    for (i, &x) in mac.iter().enumerate() {
+                        if i != 0 { out[n] = b':'; n += 1; }
+                        n += crate::util::format::hex_pad(x as u64, 2, &mut out[n..]);
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "migrate rdma" || cmd.starts_with("migrate rdma ") {
+            // migrate rdma | migrate rdma peer link=.. mac=.. peermac=.. ip=.. peerip=.. qpn=.. peerqpn=.. rkey=.. va=.. len=.. [psn=..] [pmtu=..]
+            // migrate rdma register | migrate rdma regions | migrate rdma off
+            let rest = cmd[12..].trim();
+            let hex2 = |v: u8, o: &mut [u8]| { const H: &[u8; 16] = b"0123456789abcdef"; o[0] = H[(v >> 4) as usize]; o[1] = H[(v & 0xF) as usize]; };
+            let hex = |v: &str| u64::from_str_radix(v.trim_start_matches("0x"), 16).ok();
+            if let Some(args) = rest.strip_prefix("peer") {
+                let mut c = crate::migrate::rdma::Config {
+                    link: crate::hv::vdev::net::Uplink::None, mac: [0; 6], peer_mac: [0; 6], ip: [0; 4], peer_ip: [0; 4],
+                    qpn: 0, peer_qpn: 0, rkey: 0, va: 0, len: 0, psn: 0, pmtu: crate::migrate::rdma::DEFAULT_PMTU,
+                };
+                let mut bad = false; let mut seen = 0u32;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("link=") { match v { "snp" => c.link = crate::hv::vdev::net::Uplink::Snp, "virtio" => c.link = crate::hv::vdev::net::Uplink::Virtio, _ => bad = true } }
+                    else if let Some(v) = w.strip_prefix("mac=") { match crate::hv::vdev::net::parse_mac(v) { Some(a) => { c.mac = a; seen |= 1; } None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("peermac=") { match crate::hv::vdev::net::parse_mac(v) { Some(a) => { c.peer_mac = a; seen |= 2; } None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("ip=") { match crate::hv::vdev::net::parse_ipv4(v) { Some(a) => { c.ip = a; seen |= 4; } None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("peerip=") { match crate::hv::vdev::net::parse_ipv4(v) { Some(a) => { c.peer_ip = a; seen |= 8; } None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("qpn=") { match v.parse::<u32>() { Ok(n) => { c.qpn = n; seen |= 16; } Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("peerqpn=") { match v.parse::<u32>() { Ok(n) => { c.peer_qpn = n; seen |= 32; } Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("rkey=") { match hex(v) { Some(n) if n <= u32::MAX as u64 => { c.rkey = n as u32; seen |= 64; } _ => bad = true } }
+                    else if let Some(v) = w.strip_prefix("va=") { match hex(v) { Some(n) => { c.va = n; seen |= 128; } None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("len=") { match hex(v) { Some(n) => { c.len = n; seen |= 256; } None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("psn=") { match v.parse::<u32>() { Ok(n) => c.psn = n, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("pmtu=") { match v.parse::<u16>() { Ok(n) => c.pmtu = n, Err(_) => bad = true } }
+                    else { bad = true; }
+                }
+                if bad || seen != 511 {
+                    let _ = system_table.stdout().write_str("usage: migrate rdma peer link=snp|virtio mac=<mac> peermac=<mac> ip=<a.b.c.d> peerip=<a.b.c.d> qpn=<n> peerqpn=<n> rkey=<hex> va=<hex> len=<hex> [psn=<n>] [pmtu=<n>]\r\n");
+                    continue;
+                }
+                match crate::migrate::rdma::configure(c) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("migrate rdma: queue pair ready\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest == "register" {
+                match crate::migrate::rdma::register_tracked() {
+                    Ok(lkey) => {
+                        let mut out = [0u8; 48]; let mut n = 0;
+                        for &b in b"migrate rdma: lkey=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(lkey as u64, &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest == "regions" {
+                let stdout = system_table.stdout();
+                let mut any = false;
+                crate::migrate::rdma::for_each_region(|r| {
+                    any = true;
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    for &b in b"  lkey=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.lkey as u64, &mut out[n..]);
+                    for &b in b" base=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(r.base, &mut out[n..]);
+                    for &b in b" len=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(r.len, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                if !any { let _ = stdout.write_str("migrate rdma: no regions\r\n"); }
+                continue;
+            }
+            if rest == "off" {
+                crate::migrate::rdma::disconnect();
+                let _ = system_table.stdout().write_str("migrate rdma: disconnected\r\n");
+                continue;
+            }
+            if !rest.is_empty() {
+                let _ = system_table.stdout().write_str("usage: migrate rdma [peer ..|register|regions|off]\r\n");
+                continue;
+            }
+            let stdout = system_table.stdout();
+            let Some(c) = crate::migrate::rdma::config() else { let _ = stdout.write_str("migrate rdma: not connected\r\n"); continue; };
+            let (inflight, error) = crate::migrate::rdma::status();
+            let s = crate::migrate::rdma::stats();
+            let mut out = [0u8; 256]; let mut n = 0;
+            for &b in b"migrate rdma: peer=" { out[n] = b; n += 1; }
+            for (i, v) in c.peer_mac.iter().enumerate() { hex2(*v, &mut out[n..]); n += 2; if i != 5 { out[n] = b':'; n += 1; } }
+            for &b in b" qpn=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(c.qpn as u64, &mut out[n..]);
+            for &b in b" peerqpn=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(c.peer_qpn as u64, &mut out[n..]);
+            for &b in b" pmtu=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(c.pmtu as u64, &mut out[n..]);
+            for &b in b" inflight=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(inflight as u64, &mut out[n..]);
+            if error { for &b in b" ERROR" { out[n] = b; n += 1; } }
+            for &b in b"\r\n  posted=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.posted, &mut out[n..]);
+            for &b in b" completed=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.completed, &mut out[n..]);
+            for &b in b" failed=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.failed, &mut out[n..]);
+            for &b in b" packets=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.packets, &mut out[n..]);
+            for &b in b" bytes=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.bytes, &mut out[n..]);
+            for &b in b" retransmits=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.retransmits, &mut out[n..]);
+            for &b in b" naks=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.naks, &mut out[n..]);
+            for &b in b" timeouts=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.timeouts, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
         if cmd.starts_with("migrate net ") {
             // migrate net mac [get|set xx:xx:xx:xx:xx:xx]
             // migrate net mtu [get|set <n>]
@@ -1087,15 +1867,14 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 let sub = &rest[4..].trim();
                 if sub.eq_ignore_ascii_case("get") {
                     let mac = crate::migrate::net_get_dest_mac();
-                    let mut out = [0u8; 64]; let mut n = 0;
+                    let mut out = [0u8; 32]; let mut n = 0;
                     let lang2 = crate::i18n::detect_lang(system_table);
-                    for &b in crate::i18n::t(lang2, crate::i18n::key::MIG_NET_MAC_PREFIX).as_bytes() { out[n] = b; n += 1; }
                     for i in 0..6 {
-                        n += crate::util::format::u64_hex(mac[i] as u64, &mut out[n..]);
+                        n += crate::util::format::hex_pad(mac[i] as u64, 2, &mut out[n..]);
                         if i != 5 { out[n] = b':'; n += 1; }
                     }
-                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
-                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    let msg = crate::i18n::t_fmt(lang2, crate::i18n::key::MIG_NET_MAC, &[crate::i18n::Arg::Str(core::str::from_utf8(&out[..n]).unwrap_or(""))]);
+                    let _ = system_table.stdout().write_str(msg.as_str());
                     continue;
                 }
                 if let Some(v) = sub.strip_prefix("set ") {
@@ -1116,12 +1895,9 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 let sub = &rest[4..].trim();
                 if sub.eq_ignore_ascii_case("get") {
                     let mtu = crate::migrate::net_get_mtu();
-                    let mut out = [0u8; 48]; let mut n = 0;
                     let lang2 = crate::i18n::detect_lang(system_table);
-                    for &b in crate::i18n::t(lang2, crate::i18n::key::MIG_NET_MTU_PREFIX).as_bytes() { out[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(mtu as u32, &mut out[n..]);
-                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
-                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    let msg = crate::i18n::t_fmt(lang2, crate::i18n::key::MIG_NET_MTU, &[crate::i18n::Arg::Dec(mtu as u64)]);
+                    let _ = system_table.stdout().write_str(msg.as_str());
                     continue;
                 }
                 if let Some(v) = sub.strip_prefix("set ") {
@@ -1134,11 +1910,9 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
                 let sub = &rest[6..].trim();
                 if sub.eq_ignore_ascii_case("get") {
                     let et = crate::migrate::net_get_ethertype();
-                    let mut out = [0u8; 48]; let mut n = 0; let lang2 = crate::i18n::detect_lang(system_table);
-                    for &b in crate::i18n::t(lang2, crate::i18n::key::MIG_NET_ETHER_PREFIX).as_bytes() { out[n] = b; n += 1; }
-                    n += crate::util::format::u64_hex(et as u64, &mut out[n..]);
-                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
-                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    let lang2 = crate::i18n::detect_lang(system_table);
+                    let msg = crate::i18n::t_fmt(lang2, crate::i18n::key::MIG_NET_ETHER, &[crate::i18n::Arg::Hex(et as u64)]);
+                    let _ = system_table.stdout().write_str(msg.as_str());
                     continue;
                 }
                 if let Some(v) = sub.strip_prefix("set ") {
@@ -1190,18 +1964,80 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             crate::migrate::snp_poll_ex(system_table, cycles, sleep_us, do_ctrl, do_verify, empty_limit);
             continue;
         }
-        if cmd.eq_ignore_ascii_case("trace clear") {
-            crate::obs::trace::clear();
+        if cmd.eq_ignore_ascii_case("metrics") {
+            crate::obs::metrics::dump(system_table);
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("metrics hist") {
+            crate::obs::metrics::dump_hist(system_table);
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("metrics prom") {
             let stdout = system_table.stdout();
-            let _ = stdout.write_str("trace: cleared\r\n");
+            crate::obs::prom::render(|line| {
+                let _ = stdout.write_str(line.trim_end_matches('\n'));
+                let _ = stdout.write_str("\r\n");
+            });
             continue;
         }
-        if cmd.eq_ignore_ascii_case("metrics") {
-            crate::obs::metrics::dump(system_table);
+        if cmd.eq_ignore_ascii_case("metrics prom check") {
+            // Render into a scratch buffer and parse it back into typed families.
+            const PAGES: usize = 8;
+            let p = match crate::mm::uefi::alloc_pages(system_table, PAGES, uefi::table::boot::MemoryType::LOADER_DATA) {
+                Some(p) => p,
+                None => { let _ = system_table.stdout().write_str("metrics: alloc failed\r\n"); continue; }
+            };
+            let scratch = unsafe { core::slice::from_raw_parts_mut(p, PAGES * 4096) };
+            let mut len = 0usize; let mut truncated = false;
+            crate::obs::prom::render(|line| {
+                let b = line.as_bytes();
+                if len + b.len() > scratch.len() { truncated = true; return; }
+                scratch[len..len + b.len()].copy_from_slice(b);
+                len += b.len();
+            });
+            let text = core::str::from_utf8(&scratch[..len]).unwrap_or("");
+            let mut samples = 0usize;
+            let r = crate::obs::prom::parse(text, |fam| { samples += fam.len; });
+            let mut out = [0u8; 96]; let mut n = 0;
+            match r {
+                Ok(fams) => {
+                    for &b in b"metrics prom: families=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(fams as u32, &mut out[n..]);
+                    for &b in b" samples=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(samples as u32, &mut out[n..]);
+                    for &b in b" bytes=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(len as u32, &mut out[n..]);
+                    if truncated { for &b in b" truncated" { out[n] = b; n += 1; } }
+                }
+                Err(line) => {
+                    for &b in b"metrics prom: parse error at line " { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(line as u32, &mut out[n..]);
+                }
+            }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            crate::mm::uefi::free_pages(system_table, p, PAGES);
+            let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("metrics page") {
+            let stdout = system_table.stdout();
+            let Some(info) = crate::obs::page::info() else { let _ = stdout.write_str("metrics page: not published\r\n"); continue; };
+            let mut out = [0u8; 160]; let mut n = 0;
+            for &b in b"metrics page: addr=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(info.addr, &mut out[n..]);
+            for &b in b" guid=" { out[n] = b; n += 1; }
+            for &b in crate::obs::page::METRICS_PAGE_GUID_STR.as_bytes() { out[n] = b; n += 1; }
+            for &b in b" entries=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(info.entries as u64, &mut out[n..]);
+            for &b in b" updates=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(info.updates, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
             continue;
         }
         if cmd.eq_ignore_ascii_case("metrics clear") {
             crate::obs::metrics::reset();
+            crate::hv::sched::credit::reset_stats();
             let stdout = system_table.stdout();
             let _ = stdout.write_str("metrics: cleared\r\n");
             continue;
@@ -1225,31 +2061,232 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             crate::obs::log::dump_filtered(system_table, lvl, cat);
             continue;
         }
+        if cmd == "loglevel" {
+            let mut out = [0u8; 160]; let mut n = 0;
+            for &b in b"loglevel: " { out[n] = b; n += 1; }
+            let lv: &[u8] = match crate::obs::log::get_min_level() { 0 => b"info", 1 => b"warn", _ => b"error" };
+            for &b in lv { out[n] = b; n += 1; }
+            for &b in b" cat=" { out[n] = b; n += 1; }
+            crate::obs::log::category_filter(|c| {
+                let c = if c.is_empty() { "all" } else { c };
+                for &b in c.as_bytes() { out[n] = b; n += 1; }
+            });
+            for &b in b"\r\n" { out[n] = b; n += 1; }
+            let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if let Some(list) = cmd.strip_prefix("loglevel cat=") {
+            match crate::obs::log::set_category_filter(list) {
+                Ok(()) => { let _ = system_table.stdout().write_str("loglevel: updated\r\n"); }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
         if cmd.starts_with("loglevel ") {
             let rest = &cmd[9..].trim();
             if rest.eq_ignore_ascii_case("info") { crate::obs::log::set_min_level_info(); }
             else if rest.eq_ignore_ascii_case("warn") { crate::obs::log::set_min_level_warn(); }
             else if rest.eq_ignore_ascii_case("error") { crate::obs::log::set_min_level_error(); }
-            else { let stdout = system_table.stdout(); let _ = stdout.write_str("usage: loglevel [info|warn|error]\r\n"); continue; }
+            else { let stdout = system_table.stdout(); let _ = stdout.write_str("usage: loglevel [info|warn|error|cat=<cat>,..|cat=all]\r\n"); continue; }
             let stdout = system_table.stdout();
             let _ = stdout.write_str("loglevel: updated\r\n");
             continue;
         }
-        if cmd.starts_with("dump ") {
-            let rest = &cmd[5..].trim();
-            if rest.eq_ignore_ascii_case("regs") { crate::diag::dump::dump_regs(system_table); continue; }
-            if rest.eq_ignore_ascii_case("idt") { crate::diag::dump::dump_idt(system_table); continue; }
-            if rest.eq_ignore_ascii_case("gdt") { crate::diag::dump::dump_gdt(system_table); continue; }
-            let stdout = system_table.stdout();
-            let _ = stdout.write_str("usage: dump [regs|idt|gdt]\r\n");
+        if cmd == "runtime enter" {
+            match crate::hv::runtime::enter(system_table) {
+                Ok(never) => match never {},
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
             continue;
         }
-		if cmd.starts_with("lang ") {
-			let rest = &cmd[5..].trim();
+        if cmd == "slot" || cmd.starts_with("slot ") {
+            // slot | slot try <a|b> [boots=<n>] | slot ok
+            use crate::hv::bootslot;
+            let mut it = cmd[4..].split_whitespace();
+            let res = match (it.next(), it.next(), it.next(), it.next()) {
+                (None, ..) => Ok(bootslot::load(system_table)),
+                (Some("ok"), None, ..) => bootslot::mark_ok(system_table),
+                (Some("try"), Some(s), boots, None) => match (bootslot::parse_slot(s), boots.map(|b| b.strip_prefix("boots=").and_then(|v| v.parse::<u8>().ok()))) {
+                    (Some(slot), None) => bootslot::try_slot(system_table, slot, bootslot::DEFAULT_BOOTS),
+                    (Some(slot), Some(Some(n))) => bootslot::try_slot(system_table, slot, n),
+                    _ => Err("usage: slot [try <a|b> [boots=<n>] | ok]"),
+                },
+                _ => Err("usage: slot [try <a|b> [boots=<n>] | ok]"),
+            };
+            match res {
+                Ok(s) => {
+                    let mut out = [0u8; 160]; let mut n = 0;
+                    for &b in b"slot: active=" { out[n] = b; n += 1; }
+                    for &b in bootslot::slot_name(s.active).as_bytes() { out[n] = b; n += 1; }
+                    for &b in b" previous=" { out[n] = b; n += 1; }
+                    for &b in bootslot::slot_name(s.previous).as_bytes() { out[n] = b; n += 1; }
+                    for &b in b" running=" { out[n] = b; n += 1; }
+                    for &b in bootslot::running().map(bootslot::slot_name).unwrap_or("none").as_bytes() { out[n] = b; n += 1; }
+                    if s.trial {
+                        for &b in b" trial boots=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(s.boots as u32, &mut out[n..]);
+                        out[n] = b'/'; n += 1;
+                        n += crate::util::format::u32_dec(s.max_boots as u32, &mut out[n..]);
+                    }
+                    for &b in b" rollbacks=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(s.rollbacks, &mut out[n..]);
+                    for slot in [bootslot::SLOT_A, bootslot::SLOT_B] {
+                        out[n] = b' '; n += 1;
+                        for &b in bootslot::slot_name(slot).as_bytes() { out[n] = b; n += 1; }
+                        for &b in if bootslot::present(system_table, slot) { &b"=present"[..] } else { &b"=missing"[..] } { out[n] = b; n += 1; }
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "setup" || cmd.starts_with("setup ") {
+            // setup [iommu on|off] [timeout=<s>]
+            use crate::hv::setup;
+            let mut s = setup::load(system_table);
+            let mut it = cmd[5..].split_whitespace();
+            let mut res = Ok(());
+            while let Some(tok) = it.next() {
+                match (tok, tok.strip_prefix("timeout=")) {
+                    ("iommu", _) => match it.next() {
+                        Some("on") => s.iommu_enforce = true,
+                        Some("off") => s.iommu_enforce = false,
+                        _ => { res = Err("usage: setup [iommu on|off] [timeout=<s>]"); break; }
+                    },
+                    (_, Some(v)) => match v.parse::<u8>() { Ok(t) => s.timeout = t, Err(_) => { res = Err("setup: bad timeout"); break; } },
+                    _ => { res = Err("usage: setup [iommu on|off] [timeout=<s>]"); break; }
+                }
+            }
+            let changed = cmd.len() > 5;
+            if res.is_ok() && changed { res = setup::save(system_table, &s); }
+            match res {
+                Ok(()) => {
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    for &b in b"setup: iommu=" { out[n] = b; n += 1; }
+                    for &b in if s.iommu_enforce { &b"on"[..] } else { &b"off"[..] } { out[n] = b; n += 1; }
+                    for &b in b" timeout=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(s.timeout as u32, &mut out[n..]);
+                    out[n] = b's'; n += 1;
+                    if changed { for &b in b" (from next boot)" { out[n] = b; n += 1; } }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "console" || cmd.starts_with("console ") {
+            let rest = cmd[7..].trim();
+            let mut save = false;
+            let res: Result<(), &'static str> = if rest.is_empty() {
+                Ok(())
+            } else if let Some(args) = rest.strip_prefix("serial on") {
+                let mut port = crate::arch::x86::uart::COM[0];
+                let mut baud = crate::obs::console::DEFAULT_BAUD;
+                let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("port=") { match crate::arch::x86::uart::parse_port(v) { Some(x) => port = x, None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("baud=") { match v.parse::<u32>() { Ok(x) => baud = x, Err(_) => bad = true } }
+                    else if w == "save" { save = true; }
+                    else { bad = true; }
+                }
+                let mut busy = false;
+                crate::hv::gdb::for_each(|g| if g.port == port { busy = true; });
+                if bad { Err("usage: console serial on [port=com1|com2|<hex>] [baud=<n>] [save]") }
+                else if busy { Err("console: port in use by vm gdb") }
+                else { crate::obs::console::enable(port, baud) }
+            } else if let Some(args) = rest.strip_prefix("serial off") {
+                match args.trim() {
+                    "" => { crate::obs::console::disable(); Ok(()) }
+                    "save" => { crate::obs::console::disable(); save = true; Ok(()) }
+                    _ => Err("usage: console serial off [save]"),
+                }
+            } else {
+                Err("usage: console [serial on [port=com1|com2|<hex>] [baud=<n>] [save]|serial off [save]]")
+            };
+            let res = res.and_then(|_| if save { crate::obs::console::save(system_table) } else { Ok(()) });
+            if let Err(e) = res { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); continue; }
+            let mut out = [0u8; 96]; let mut n = 0;
+            match crate::obs::console::serial() {
+                Some((port, baud)) => {
+                    for &b in b"console: uefi + serial " { out[n] = b; n += 1; }
+                    match crate::arch::x86::uart::port_name(port) {
+                        Some(name) => for &b in name.as_bytes() { out[n] = b; n += 1; },
+                        None => { for &b in b"0x" { out[n] = b; n += 1; } n += crate::util::format::u64_hex(port as u64, &mut out[n..]); }
+                    }
+                    out[n] = b' '; n += 1;
+                    n += crate::util::format::u64_dec(baud as u64, &mut out[n..]);
+                    for &b in b" 8N1" { out[n] = b; n += 1; }
+                }
+                None => for &b in b"console: uefi only" { out[n] = b; n += 1; },
+            }
+            if save { for &b in b" (saved)" { out[n] = b; n += 1; } }
+            for &b in b"\r\n" { out[n] = b; n += 1; }
+            let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd == "crash" || cmd.starts_with("crash ") {
+            let rest = cmd[5..].trim();
+            if rest.is_empty() {
+                if !crate::diag::panic::show_crash(system_table) { let _ = system_table.stdout().write_str("crash: no crash record\r\n"); }
+            } else if rest == "clear" {
+                let msg = if crate::diag::panic::clear_crash(system_table) { "crash: record cleared\r\n" } else { "crash: no crash record\r\n" };
+                let _ = system_table.stdout().write_str(msg);
+            } else {
+                let _ = system_table.stdout().write_str("usage: crash [clear]\r\n");
+            }
+            continue;
+        }
+        if cmd.starts_with("dump ") {
+            let rest = &cmd[5..].trim();
+            if rest.eq_ignore_ascii_case("regs") { crate::diag::dump::dump_regs(system_table); continue; }
+            if rest.eq_ignore_ascii_case("idt") { crate::diag::dump::dump_idt(system_table); continue; }
+            if rest.eq_ignore_ascii_case("gdt") { crate::diag::dump::dump_gdt(system_table); continue; }
+            let stdout = system_table.stdout();
+            let _ = stdout.write_str("usage: dump [regs|idt|gdt]\r\n");
+            continue;
+        }
+        if cmd == "lang" {
+            let lang = i18n::detect_lang(system_table);
+            let mut out = [0u8; 96]; let mut n = 0;
+            for &b in b"lang: " { out[n] = b; n += 1; }
+            for &b in i18n::lang_tag(lang).as_bytes() { out[n] = b; n += 1; }
+            for &b in b" catalog=" { out[n] = b; n += 1; }
+            let tag = i18n::ext_tag();
+            for &b in (if tag.is_empty() { "none" } else { tag }).as_bytes() { out[n] = b; n += 1; }
+            for &b in b"\r\n" { out[n] = b; n += 1; }
+            let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if let Some(rest) = cmd.strip_prefix("lang load") {
+            let path = rest.trim().strip_prefix("path=").unwrap_or(i18n::CATALOG_PATH);
+            match i18n::load_catalog(system_table, path) {
+                Ok(s) => {
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    for &b in b"lang: loaded " { out[n] = b; n += 1; }
+                    for &b in i18n::ext_tag().as_bytes() { out[n] = b; n += 1; }
+                    for &b in b", messages=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(s.messages as u64, &mut out[n..]);
+                    for &b in b" unknown=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(s.unknown as u64, &mut out[n..]);
+                    for &b in b"\r\n" { out[n] = b; n += 1; }
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+		if cmd.starts_with("lang ") {
+			let rest = &cmd[5..].trim();
+			let ext = i18n::ext_tag();
 			if rest.eq_ignore_ascii_case("en") { i18n::set_lang_override(Some(Lang::En)); }
 			else if rest.eq_ignore_ascii_case("ja") { i18n::set_lang_override(Some(Lang::Ja)); }
 			else if rest.eq_ignore_ascii_case("zh") { i18n::set_lang_override(Some(Lang::Zh)); }
-			else { i18n::set_lang_override(None); }
+			else if !ext.is_empty() && rest.eq_ignore_ascii_case(ext) { i18n::set_lang_override(Some(Lang::Ext)); }
+			else if rest.eq_ignore_ascii_case("auto") { i18n::set_lang_override(None); }
+			else { let _ = system_table.stdout().write_str("usage: lang [en|ja|zh|<catalog tag>|auto] | lang load [path=<esp path>]\r\n"); continue; }
             // Persist override to UEFI variable for next boot
             i18n::save_lang_override(system_table);
             let stdout = system_table.stdout();
@@ -1260,10 +2297,302 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             crate::diag::security::report_security(system_table);
             continue;
         }
+        if cmd.starts_with("sec policy") {
+            // sec policy [strict|permissive] [save]
+            let mut save = false; let mut ok = true;
+            for w in cmd[10..].split_whitespace() {
+                match w {
+                    "strict" => crate::hv::imgsig::set_strict(true),
+                    "permissive" => crate::hv::imgsig::set_strict(false),
+                    "save" => save = true,
+                    _ => ok = false,
+                }
+            }
+            if !ok { let _ = system_table.stdout().write_str("usage: sec policy [strict|permissive] [save]\r\n"); continue; }
+            let saved = if save { Some(crate::hv::imgsig::save_policy(system_table)) } else { None };
+            let (_, keys) = crate::hv::imgsig::keys(system_table);
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 96]; let mut n = 0;
+            for &b in b"sec: guest image policy=" { out[n] = b; n += 1; }
+            for &b in if crate::hv::imgsig::strict() { b"strict".as_slice() } else { b"permissive".as_slice() } { out[n] = b; n += 1; }
+            for &b in b" keys=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(keys as u64, &mut out[n..]);
+            match saved {
+                Some(Ok(())) => for &b in b" (saved)" { out[n] = b; n += 1; },
+                Some(Err(_)) => for &b in b" (save failed)" { out[n] = b; n += 1; },
+                None => {}
+            }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd.starts_with("sec keys") {
+            // sec keys [add <hex>|del <n>|clear]
+            let mut it = cmd[8..].split_whitespace();
+            let r = match (it.next(), it.next()) {
+                (None, _) => Ok(()),
+                (Some("add"), Some(h)) if h.len() == 2 * crate::util::ed25519::PUBLIC_KEY_LEN => {
+                    let mut k = [0u8; crate::util::ed25519::PUBLIC_KEY_LEN];
+                    let parsed = k.iter_mut().enumerate().all(|(i, b)| match u8::from_str_radix(&h[2 * i..2 * i + 2], 16) { Ok(v) => { *b = v; true } Err(_) => false });
+                    if parsed { crate::hv::imgsig::add_key(system_table, &k).map(|_| ()) } else { Err("usage: sec keys add <64 hex digits>") }
+                }
+                (Some("add"), _) => Err("usage: sec keys add <64 hex digits>"),
+                (Some("del"), Some(i)) => match i.parse::<usize>() { Ok(i) => crate::hv::imgsig::remove_key(system_table, i), Err(_) => Err("usage: sec keys del <n>") },
+                (Some("clear"), None) => crate::hv::imgsig::clear_keys(system_table),
+                _ => Err("usage: sec keys [add <hex>|del <n>|clear]"),
+            };
+            let (keys, count) = crate::hv::imgsig::keys(system_table);
+            let stdout = system_table.stdout();
+            if let Err(e) = r { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); continue; }
+            for (i, k) in keys[..count].iter().enumerate() {
+                let mut out = [0u8; 96]; let mut n = 0;
+                for &b in b"sec: key " { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(i as u64, &mut out[n..]);
+                for &b in b" ed25519 " { out[n] = b; n += 1; }
+                n += crate::tpm::attest::hex(k, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            if count == 0 { let _ = stdout.write_str("sec: no trusted guest keys\r\n"); }
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("flow") {
+            // Labelled objects; everything else is at the bottom of the lattice
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::info_flow::for_each(|o, l| {
+                let mut out = [0u8; 96]; let mut n = 0;
+                for &b in b"flow: " { out[n] = b; n += 1; }
+                n += o.write(&mut out[n..]);
+                out[n] = b' '; n += 1;
+                n += l.write(&mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                any = true;
+            });
+            if !any { let _ = stdout.write_str("flow: no labels (all objects conf=0 cats=0x0 integ=0)\r\n"); }
+            continue;
+        }
+        if cmd.starts_with("flow label") {
+            // flow label <object> [conf=<n>] [cats=<hex>] [integ=<n>] | none
+            let mut words = cmd[10..].split_whitespace();
+            let obj = words.next().and_then(crate::hv::info_flow::Object::parse);
+            let rest = words.clone();
+            let label = match obj {
+                Some(_) if rest.clone().eq(["none"]) => Some(None),
+                Some(o) if rest.clone().next().is_some() => crate::hv::info_flow::Label::parse_words(crate::hv::info_flow::label_of(o), rest).map(Some),
+                _ => None,
+            };
+            let stdout = system_table.stdout();
+            let (Some(o), Some(l)) = (obj, label) else {
+                let _ = stdout.write_str("usage: flow label vm=<n>|shm=<name>|net|dest=<mac> [conf=<0-3>] [cats=<hex>] [integ=<0-3>] | flow label <object> none\r\n");
+                continue;
+            };
+            match crate::hv::info_flow::set_label(o, l) {
+                Ok(()) => {
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    for &b in b"flow: " { out[n] = b; n += 1; }
+                    n += o.write(&mut out[n..]);
+                    out[n] = b' '; n += 1;
+                    n += crate::hv::info_flow::label_of(o).write(&mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("sec cvm") {
+            // Host memory-encryption capabilities, then each confidential VM
+            let c = crate::hv::confidential::caps();
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 160]; let mut n = 0;
+            for &b in b"cvm: sev c_bit=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(c.sev.c_bit as u64, &mut out[n..]);
+            for &b in b" phys_reduction=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(c.sev.phys_reduction as u64, &mut out[n..]);
+            for &b in b" asids=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(c.sev.max_asid as u64, &mut out[n..]);
+            for &b in b" min_sev_asid=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(c.sev.min_sev_asid as u64, &mut out[n..]);
+            for &b in b" | tdx seamrr=" { out[n] = b; n += 1; }
+            for &b in if c.tdx.seamrr { b"yes".as_slice() } else { b"no".as_slice() } { out[n] = b; n += 1; }
+            for &b in b" tme=" { out[n] = b; n += 1; }
+            for &b in if c.tdx.tme { b"yes".as_slice() } else { b"no".as_slice() } { out[n] = b; n += 1; }
+            for &b in b" keyids=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(c.tdx.keyids as u64, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            crate::hv::confidential::for_each_kind(|h| {
+                let mut out = [0u8; 96]; let mut n = 0;
+                for &b in b"cvm: " { out[n] = b; n += 1; }
+                for &b in h.kind.name().as_bytes() { out[n] = b; n += 1; }
+                out[n] = b' '; n += 1;
+                for &b in h.readiness.name().as_bytes() { out[n] = b; n += 1; }
+                if let Some((lo, hi)) = h.keys {
+                    for &b in b" keys=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(lo as u64, &mut out[n..]);
+                    out[n] = b'-'; n += 1;
+                    n += crate::util::format::u64_dec(hi as u64, &mut out[n..]);
+                    for &b in b" free=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(h.free as u64, &mut out[n..]);
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            crate::hv::confidential::for_each_guest(|vm, kind, key| {
+                let mut out = [0u8; 64]; let mut n = 0;
+                for &b in b"cvm: vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(vm, &mut out[n..]);
+                out[n] = b' '; n += 1;
+                for &b in kind.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" key=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(key as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            continue;
+        }
         if cmd.eq_ignore_ascii_case("audit") {
             crate::diag::audit::dump(system_table);
             continue;
         }
+        if cmd.starts_with("audit query") {
+            // audit query [from=<seq>] [limit=<n>] [kind=<k>[,<k>..]] [since=<ms>] [until=<ms>] [json]
+            use crate::diag::audit;
+            let mut from = 0u64; let mut limit = 64usize; let mut filter = audit::Filter::ALL; let mut json = false; let mut bad = false;
+            for w in cmd[11..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("from=") { match v.parse::<u64>() { Ok(x) => from = x, Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("limit=") { match v.parse::<usize>() { Ok(x) if x != 0 => limit = x, _ => bad = true } }
+                else if let Some(v) = w.strip_prefix("since=") { match v.parse::<u64>() { Ok(x) => filter.since_ms = x, Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("until=") { match v.parse::<u64>() { Ok(x) => filter.until_ms = x, Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("kind=") {
+                    for k in v.split(',') { match audit::Filter::kind_bit(k) { Some(b) => filter.kinds |= b, None => bad = true } }
+                }
+                else if w == "json" { json = true; }
+                else { bad = true; }
+            }
+            if bad {
+                let _ = system_table.stdout().write_str("usage: audit query [from=<seq>] [limit=<n>] [kind=<k>[,<k>..]] [since=<ms>] [until=<ms>] [json]\r\n");
+                continue;
+            }
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 192];
+            let page = audit::query(from, &filter, limit, |r| {
+                let mut n = if json { audit::render_json(r, &mut out) } else {
+                    let mut n = 0;
+                    out[n] = b'#'; n += 1;
+                    n += crate::util::format::u64_dec(r.seq, &mut out[n..]);
+                    for &b in b" t=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.t_ms, &mut out[n..]);
+                    for &b in b"ms " { out[n] = b; n += 1; }
+                    n + audit::render_text(&r.kind, &mut out[n..])
+                };
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            // Trailer carries the cursor for the next call.
+            let mut n = 0;
+            for &b in if json { &b"{\"next\":"[..] } else { &b"audit: next="[..] } { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(page.next, &mut out[n..]);
+            for &b in if json { &b",\"returned\":"[..] } else { &b" returned="[..] } { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(page.returned as u64, &mut out[n..]);
+            for &b in if json { &b",\"lost\":"[..] } else { &b" lost="[..] } { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(page.lost, &mut out[n..]);
+            for &b in if json { &b",\"more\":"[..] } else { &b" more="[..] } { out[n] = b; n += 1; }
+            for &b in if page.more { &b"true"[..] } else { &b"false"[..] } { out[n] = b; n += 1; }
+            if json { out[n] = b'}'; n += 1; }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd.starts_with("audit retention") {
+            // audit retention [size=<n>] [persist=none|var] [save]
+            use crate::diag::audit;
+            let mut r = audit::retention(); let mut save = false; let mut bad = false;
+            for w in cmd[15..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("size=") { match v.parse::<usize>() { Ok(x) => r.capacity = x, Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("persist=") {
+                    match v { "none" => r.persist = audit::Persist::None, "var" => r.persist = audit::Persist::Variable, _ => bad = true }
+                }
+                else if w == "save" { save = true; }
+                else { bad = true; }
+            }
+            if bad {
+                let _ = system_table.stdout().write_str("usage: audit retention [size=<16..1024>] [persist=none|var] [save]\r\n");
+                continue;
+            }
+            if let Err(e) = audit::set_retention(r) { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); continue; }
+            if save {
+                if let Err(e) = audit::save_retention(system_table) { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); continue; }
+            }
+            let r = audit::retention();
+            let mut out = [0u8; 128]; let mut n = 0;
+            for &b in b"audit: size=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(r.capacity as u64, &mut out[n..]);
+            for &b in b" persist=" { out[n] = b; n += 1; }
+            for &b in r.persist.name().as_bytes() { out[n] = b; n += 1; }
+            for &b in b" oldest=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(audit::oldest(), &mut out[n..]);
+            for &b in b" next=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(audit::head(), &mut out[n..]);
+            if save { for &b in b" (saved)" { out[n] = b; n += 1; } }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd == "audit flush" {
+            match crate::diag::audit::flush(system_table) {
+                Ok(count) => {
+                    let mut out = [0u8; 64]; let mut n = 0;
+                    for &b in b"audit: persisted " { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(count as u64, &mut out[n..]);
+                    for &b in b" events\r\n" { out[n] = b; n += 1; }
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "audit persisted" {
+            let mut out = [0u8; 192];
+            let got = crate::diag::audit::read_persisted(system_table, |st, r| {
+                let mut n = crate::diag::audit::render_json(r, &mut out);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = st.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if got.is_none() { let _ = system_table.stdout().write_str("audit: nothing persisted\r\n"); }
+            continue;
+        }
+        if cmd == "audit verify" {
+            match crate::diag::audit::verify(system_table) {
+                Ok(v) => {
+                    let mut out = [0u8; 256]; let mut n = 0;
+                    for &b in b"audit chain: records " { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v.first, &mut out[n..]);
+                    for &b in b".." { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v.next, &mut out[n..]);
+                    for &b in b" gaps=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v.gaps, &mut out[n..]);
+                    for &b in b" head=" { out[n] = b; n += 1; }
+                    let mut h = [0u8; 8]; h.copy_from_slice(&v.head[..8]);
+                    n += crate::util::format::u64_hex(u64::from_be_bytes(h), &mut out[n..]);
+                    match v.broken {
+                        None => for &b in b" ok\r\n" { out[n] = b; n += 1; },
+                        Some((pos, why)) => {
+                            for &b in b" BROKEN at " { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(pos, &mut out[n..]);
+                            for &b in b": " { out[n] = b; n += 1; }
+                            for &b in why.as_bytes() { out[n] = b; n += 1; }
+                            for &b in b"\r\n" { out[n] = b; n += 1; }
+                        }
+                    }
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
         if cmd.starts_with("wdog") {
             let rest = cmd.strip_prefix("wdog").unwrap_or("").trim();
             if rest.is_empty() {
@@ -1292,181 +2621,4851 @@ pub fn run_cli(system_table: &mut SystemTable<Boot>) {
             }
             continue;
         }
-        if cmd.eq_ignore_ascii_case("pci") {
-            crate::iommu::report_pci_endpoints(system_table);
+        if cmd == "smp" {
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::arch::x86::ap::for_each_online(|cpu, apic, jobs| {
+                any = true;
+                let mut buf = [0u8; 64]; let mut n = 0;
+                for &b in b"smp: cpu=" { buf[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(cpu as u32, &mut buf[n..]);
+                for &b in b" apic=" { buf[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(apic, &mut buf[n..]);
+                for &b in b" jobs=" { buf[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(jobs, &mut buf[n..]);
+                if crate::arch::x86::vm::percpu::is_root(cpu) { for &b in b" vmx" { buf[n] = b; n += 1; } }
+                buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("smp: no APs online\r\n"); }
             continue;
         }
-        if cmd.starts_with("pci class ") {
-            let rest = &cmd[10..].trim();
-            let mut parts = rest.split_whitespace();
-            let parse_num = |s: &str| -> Option<u32> { if let Some(h) = s.strip_prefix("0x") { u32::from_str_radix(h, 16).ok() } else { s.parse::<u32>().ok() } };
-            if let (Some(ccs), Some(scs)) = (parts.next(), parts.next()) {
-                if let (Some(cc), Some(sc)) = (parse_num(ccs), parse_num(scs)) {
-                    // Simple acknowledgment line
-                    let stdout = system_table.stdout();
-                    let mut buf = [0u8; 64]; let mut n = 0;
-                    for &b in b"filter: class=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(cc, &mut buf[n..]); buf[n] = b'/'; n += 1;
-                    n += crate::firmware::acpi::u32_to_dec(sc, &mut buf[n..]); buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
-                    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
-                    // Full filtered enumeration
-                    crate::iommu::report_pci_by_class(system_table, cc as u8, sc as u8);
-                    continue;
+        if cmd == "smp vmx on" || cmd == "smp vmx off" {
+            if cmd.ends_with("off") {
+                if crate::hv::run::active_total() != 0 { let _ = system_table.stdout().write_str("smp: vCPUs still running (vm stop)\r\n"); continue; }
+                let n = crate::arch::x86::vm::percpu::disable_all(system_table);
+                let mut buf = [0u8; 48]; let mut k = 0;
+                for &b in b"smp: root operation left on " { buf[k] = b; k += 1; }
+                k += crate::util::format::u32_dec(n as u32, &mut buf[k..]);
+                buf[k] = b'\r'; k += 1; buf[k] = b'\n'; k += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..k]).unwrap_or("\r\n"));
+                continue;
+            }
+            match crate::arch::x86::vm::percpu::enable_all(system_table) {
+                Ok(n) => {
+                    let mut buf = [0u8; 48]; let mut k = 0;
+                    for &b in b"smp: root operation on " { buf[k] = b; k += 1; }
+                    k += crate::util::format::u32_dec(n as u32, &mut buf[k..]);
+                    buf[k] = b'/'; k += 1;
+                    k += crate::util::format::u32_dec(crate::arch::x86::ap::online_count() as u32, &mut buf[k..]);
+                    buf[k] = b'\r'; k += 1; buf[k] = b'\n'; k += 1;
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..k]).unwrap_or("\r\n"));
                 }
+                Err(e) => { let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
             }
-            let stdout = system_table.stdout();
-            let _ = stdout.write_str("usage: pci class <class> <subclass>\r\n");
             continue;
         }
-        if cmd.starts_with("pci find ") {
-            let rest = &cmd[9..].trim();
-            let mut vid: Option<u16> = None; let mut did: Option<u16> = None;
-            for tok in rest.split_whitespace() {
-                if let Some(v) = tok.strip_prefix("vid=") { vid = u16::from_str_radix(v.trim_start_matches("0x"), 16).ok(); continue; }
-                if let Some(v) = tok.strip_prefix("did=") { did = u16::from_str_radix(v.trim_start_matches("0x"), 16).ok(); continue; }
+        if cmd.starts_with("smp run ") {
+            // smp run <cpu>|all <ping|apicid|tlb|tsc> [arg=<n>]
+            let mut it = cmd[8..].split_whitespace();
+            let target = it.next();
+            let job = it.next().and_then(crate::arch::x86::ap::job_by_name);
+            let arg = it.next().and_then(|w| w.strip_prefix("arg=")).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+            let (Some(target), Some(job)) = (target, job) else {
+                let _ = system_table.stdout().write_str("usage: smp run <cpu>|all ping|apicid|tlb|tsc [arg=<n>]\r\n");
+                continue;
+            };
+            if target == "all" {
+                let done = crate::arch::x86::ap::broadcast(system_table, job, arg, 100_000);
+                let mut buf = [0u8; 64]; let mut n = 0;
+                for &b in b"smp: completed on " { buf[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(done as u32, &mut buf[n..]);
+                buf[n] = b'/'; n += 1;
+                n += crate::util::format::u32_dec(crate::arch::x86::ap::online_count() as u32, &mut buf[n..]);
+                buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+                continue;
             }
-            if let Some(mcfg_hdr) = crate::firmware::acpi::find_mcfg(system_table) {
-                crate::firmware::acpi::mcfg_for_each_allocation_from(|a| {
-                    let mut bus = a.start_bus;
-                    while bus <= a.end_bus {
-                        for dev in 0u8..32u8 { for func in 0u8..8u8 {
-                            let cfg = crate::iommu::ecam_fn_base(a.base_address, a.start_bus, bus, dev, func);
-                            let v = crate::iommu::mmio_read16(cfg + 0x00);
-                            if v == 0xFFFF { continue; }
-                            let d = crate::iommu::mmio_read16(cfg + 0x02);
-                            if let Some(w) = vid { if v != w { continue; } }
-                            if let Some(w) = did { if d != w { continue; } }
-                            let stdout = system_table.stdout();
-                            let mut buf = [0u8; 96]; let mut n = 0;
-                            for &b in b"PCI: seg=" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(a.pci_segment as u32, &mut buf[n..]);
-                            for &b in b" b=" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
-                            for &b in b" d=" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
-                            for &b in b" f=" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
-                            for &b in b" vid=0x" { buf[n] = b; n += 1; }
-                            n += crate::util::format::u64_hex(v as u64, &mut buf[n..]);
-                            for &b in b" did=0x" { buf[n] = b; n += 1; }
-                            n += crate::util::format::u64_hex(d as u64, &mut buf[n..]);
-                            buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
-                            let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
-                        } }
-                        if bus == 0xFF { break; }
-                        bus = bus.saturating_add(1);
-                    }
-                }, mcfg_hdr);
+            let r = match target.parse::<usize>() {
+                Ok(cpu) => crate::arch::x86::ap::run_on(system_table, cpu, job, arg, 100_000),
+                Err(_) => Err("smp: bad cpu index"),
+            };
+            let stdout = system_table.stdout();
+            match r {
+                Ok(v) => {
+                    let mut buf = [0u8; 48]; let mut n = 0;
+                    for &b in b"smp: result=0x" { buf[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(v, &mut buf[n..]);
+                    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
             }
             continue;
         }
-        if cmd.eq_ignore_ascii_case("time") || cmd.eq_ignore_ascii_case("time show") {
-            let hz = crate::time::tsc_hz();
+        if cmd.eq_ignore_ascii_case("numa") {
+            let topo = crate::firmware::acpi::numa::topology(system_table);
             let stdout = system_table.stdout();
-            let mut buf = [0u8; 64]; let mut n = 0;
-            for &b in b"time: tsc_hz=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec((hz / 1_000_000) as u32, &mut buf[n..]);
-            for &b in b" MHz\r\n" { buf[n] = b; n += 1; }
-            let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+            crate::firmware::acpi::numa::report(&topo, |s| { let _ = stdout.write_str(s); });
             continue;
         }
-        if cmd.starts_with("time wait ") {
-            // time wait <usec> [busy|stall]
-            let rest = &cmd[10..].trim();
-            let mut parts = rest.split_whitespace();
-            if let Some(us_s) = parts.next() {
-                if let Ok(usec) = us_s.parse::<u64>() {
-                    let mode = parts.next().unwrap_or("busy");
-                    if mode.eq_ignore_ascii_case("stall") {
-                        let _ = system_table.boot_services().stall(usec as usize);
-                    } else {
-                        let hz = crate::time::tsc_hz();
-                        crate::time::busy_wait_tsc(system_table, usec, hz);
+        if cmd == "mem" || cmd.starts_with("mem ") {
+            // mem stats | mem heap | mem overcommit pct=<n> | mem ksm [on|off]
+            let rest = cmd[3..].trim();
+            if rest == "heap" {
+                let h = crate::mm::heap::stats();
+                let mut out = [0u8; 256]; let mut n = 0;
+                for &b in b"mem: heap backend=" { out[n] = b; n += 1; }
+                for &b in if h.pool { b"pool".as_slice() } else { b"arena".as_slice() } { out[n] = b; n += 1; }
+                for (name, v) in [(&b" pool_live="[..], h.pool_live), (b" arena=", h.arena_len as u64), (b" used=", h.arena_used as u64),
+                                  (b" peak=", h.arena_peak as u64), (b" leaked=", h.leaked)] {
+                    for &b in name { out[n] = b; n += 1; }
+                    n += crate::util::format::size(v, &mut out[n..]);
+                }
+                for (name, v) in [(&b" allocs="[..], h.allocs), (b" frees=", h.frees), (b" failed=", h.failed)] {
+                    for &b in name { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if rest == "ksm" || rest.starts_with("ksm ") {
+                match rest[3..].trim() {
+                    "" => {}
+                    "on" | "off" => {
+                        let on = rest.ends_with("on");
+                        if !crate::hv::ksm::enable(on) { let _ = system_table.stdout().write_str("mem: ksm: no free background task slot\r\n"); continue; }
+                        if on { crate::hv::ksm::refill(system_table); }
                     }
-                    let stdout = system_table.stdout();
-                    let _ = stdout.write_str("time: wait done\r\n");
-                    continue;
+                    _ => { let _ = system_table.stdout().write_str("usage: mem ksm [on|off]\r\n"); continue; }
+                }
+                let k = crate::hv::ksm::stats();
+                let mut out = [0u8; 256]; let mut n = 0;
+                for &b in b"mem: ksm " { out[n] = b; n += 1; }
+                for &b in if k.enabled { b"on".as_slice() } else { b"off".as_slice() } { out[n] = b; n += 1; }
+                for (name, v) in [(&b" scanned="[..], k.scanned), (b" rounds=", k.rounds), (b" merges=", k.merges), (b" cow_breaks=", k.cow_breaks),
+                                  (b" mismatches=", k.mismatches), (b" stable=", k.stable as u64), (b" shared=", k.shared as u64), (b" reserve=", k.reserve as u64)] {
+                    for &b in name { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                for &b in b" saved=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(k.saved(), &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if let Some(v) = rest.strip_prefix("overcommit ") {
+                match v.trim().strip_prefix("pct=").and_then(|x| x.parse::<u32>().ok()) {
+                    Some(p) if p != 0 => { crate::mm::guest::set_overcommit_pct(p); let _ = system_table.stdout().write_str("mem: overcommit set\r\n"); }
+                    _ => { let _ = system_table.stdout().write_str("usage: mem overcommit pct=<n>\r\n"); }
                 }
+                continue;
             }
+            if !rest.is_empty() && rest != "stats" { let _ = system_table.stdout().write_str("usage: mem stats | mem heap | mem overcommit pct=<n> | mem ksm [on|off]\r\n"); continue; }
+            let _ = crate::mm::guest::scan(system_table);
+            let st = crate::mm::guest::stats();
             let stdout = system_table.stdout();
-            let _ = stdout.write_str("usage: time wait <usec> [busy|stall]\r\n");
+            let mut out = [0u8; 256]; let mut n = 0;
+            for (name, v) in [(&b"mem: usable=0x"[..], st.map.usable), (b" loader=0x", st.map.loader), (b" boot=0x", st.map.boot_services),
+                              (b" runtime=0x", st.map.runtime), (b" acpi=0x", st.map.acpi), (b" mmio=0x", st.map.mmio), (b" reserved=0x", st.map.reserved)] {
+                for &b in name { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(v, &mut out[n..]);
+            }
+            for &b in b" entries=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(st.map.entries, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            n = 0;
+            for (name, v) in [(&b"mem: pool=0x"[..], st.pool), (b" free=0x", st.free), (b" capacity=0x", st.capacity),
+                              (b" reserved=0x", st.reserved), (b" lent=0x", st.lent), (b" backed=0x", st.backed), (b" limit=0x", st.limit)] {
+                for &b in name { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(v, &mut out[n..]);
+            }
+            for &b in b" overcommit=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(st.overcommit_pct, &mut out[n..]);
+            for &b in b"% extents=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(st.extents, &mut out[n..]);
+            for &b in b" free_ranges=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(st.free_ranges, &mut out[n..]);
+            if st.adopted { for &b in b" adopted" { out[n] = b; n += 1; } }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            let mut total = crate::mm::stage2::LeafStats::default();
+            for v in crate::mm::guest::vms().iter().flatten() {
+                let leaves = crate::hv::loader::find_image(v.vm_id).filter(|g| g.root_phys != 0).map(|g| crate::mm::stage2::leaf_stats(g.root_phys, g.ram_bytes));
+                n = 0;
+                for &b in b"  vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.vm_id, &mut out[n..]);
+                for &b in b" reserved=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(v.reserved, &mut out[n..]);
+                if v.lent != 0 {
+                    for &b in b" lent=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(v.lent, &mut out[n..]);
+                }
+                for &b in b" backed=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(v.backed, &mut out[n..]);
+                for &b in b" extents=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(v.extents, &mut out[n..]);
+                let shared = crate::hv::ksm::shared_pages(v.vm_id);
+                if shared != 0 {
+                    for &b in b" ksm_shared=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(shared, &mut out[n..]);
+                }
+                if let Some(l) = leaves {
+                    for (name, c) in [(&b" 1g="[..], l.g1), (b" 2m=", l.m2), (b" 4k=", l.k4)] {
+                        for &b in name { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(c, &mut out[n..]);
+                    }
+                    for &b in b" large=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(l.large_pct(), &mut out[n..]);
+                    out[n] = b'%'; n += 1;
+                    total.add(&l);
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            n = 0;
+            for &b in b"mem: stage2 large_page_ratio=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(total.large_pct(), &mut out[n..]);
+            for &b in b"% mapped=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(total.bytes(), &mut out[n..]);
+            for &b in b" ksm_saved=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(crate::hv::ksm::stats().saved(), &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
             continue;
         }
-        if cmd.eq_ignore_ascii_case("vm") {
-            // Create a tiny VM object and print its id, try start (VMX smoke paths)
-            let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 64 << 20, vcpu_count: 1 });
-            let _ = crate::hv::vm::register_vm(&vm);
-            let mut vcpu = crate::hv::vcpu::Vcpu::new(0);
-            vcpu.start();
-            vm.start(system_table);
+        if cmd.eq_ignore_ascii_case("pci") {
+            crate::iommu::report_pci_endpoints(system_table);
+            continue;
+        }
+        if cmd.starts_with("pci caps ") || cmd.starts_with("pci msix ") {
+            // pci caps <bdf> | pci msix <bdf> [all]
+            let msix = cmd.starts_with("pci msix ");
+            let mut it = cmd[9..].split_whitespace();
+            let bdf = it.next().and_then(crate::hv::sriov::Bdf::parse);
+            let all = it.next() == Some("all");
+            let r = match bdf {
+                Some(b) if msix => crate::iommu::pcicap::report_msix(system_table, b.seg, b.bus, b.dev, b.func, all),
+                Some(b) => crate::iommu::pcicap::report_caps(system_table, b.seg, b.bus, b.dev, b.func),
+                None => Err(if msix { "usage: pci msix <[seg:]bus:dev.fn> [all]" } else { "usage: pci caps <[seg:]bus:dev.fn>" }),
+            };
+            if let Err(e) = r { let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            continue;
+        }
+        if cmd == "pci allow" {
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::iommu::pcicfg::for_each_allowed(|seg, bus, dev, func| {
+                any = true;
+                let mut buf = [0u8; 32]; let mut n = 0;
+                for &b in b"allow " { buf[n] = b; n += 1; }
+                n += crate::hv::sriov::Bdf { seg, bus, dev, func }.fmt(&mut buf[n..]);
+                buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("pci: allowlist empty\r\n"); }
+            continue;
+        }
+        if cmd.starts_with("pci allow ") || cmd.starts_with("pci disallow ") {
+            // pci allow <bdf> | pci disallow <bdf>
+            let allow = cmd.starts_with("pci allow ");
+            let arg = if allow { &cmd[10..] } else { &cmd[13..] };
+            let stdout = system_table.stdout();
+            match crate::hv::sriov::Bdf::parse(arg.trim()) {
+                Some(b) if allow => match crate::iommu::pcicfg::allow(b.seg, b.bus, b.dev, b.func) {
+                    Ok(()) => { let _ = stdout.write_str("pci: allowed\r\n"); }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                },
+                Some(b) => {
+                    let _ = stdout.write_str(if crate::iommu::pcicfg::disallow(b.seg, b.bus, b.dev, b.func) { "pci: disallowed\r\n" } else { "pci: not on allowlist\r\n" });
+                }
+                None => { let _ = stdout.write_str("usage: pci allow|disallow <[seg:]bus:dev.fn>\r\n"); }
+            }
+            continue;
+        }
+        if cmd.starts_with("pci set-bme ") {
+            // pci set-bme <bdf> on|off
+            let mut it = cmd[12..].split_whitespace();
+            let bdf = it.next().and_then(crate::hv::sriov::Bdf::parse);
+            let on = match it.next() { Some("on") => Some(true), Some("off") => Some(false), _ => None };
+            let (Some(b), Some(on)) = (bdf, on) else {
+                let _ = system_table.stdout().write_str("usage: pci set-bme <[seg:]bus:dev.fn> on|off\r\n");
+                continue;
+            };
+            let r = crate::iommu::pcicfg::set_bus_master(system_table, b.seg, b.bus, b.dev, b.func, on);
+            let stdout = system_table.stdout();
+            match r {
+                Ok(cmdreg) => {
+                    let mut buf = [0u8; 48]; let mut n = 0;
+                    for &c in if on { b"pci: bme on cmd=0x" as &[u8] } else { b"pci: bme off cmd=0x" } { buf[n] = c; n += 1; }
+                    n += crate::util::format::u64_hex(cmdreg as u64, &mut buf[n..]);
+                    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd.starts_with("pci bars ") {
+            let r = match crate::hv::sriov::Bdf::parse(cmd[9..].trim()) {
+                Some(b) => crate::iommu::pcicfg::report_bars(system_table, b.seg, b.bus, b.dev, b.func),
+                None => Err("usage: pci bars <[seg:]bus:dev.fn>"),
+            };
+            if let Err(e) = r { let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            continue;
+        }
+        if cmd == "pci sriov" || cmd.starts_with("pci sriov ") {
+            // pci sriov | pci sriov enable <bdf> numvfs=<n> | pci sriov disable <bdf>
+            let mut it = cmd[9..].split_whitespace();
+            let sub = it.next();
+            if sub.is_none() {
+                let stdout = system_table.stdout();
+                let mut any = false;
+                crate::hv::sriov::for_each_pf(|p| {
+                    any = true;
+                    let mut out = [0u8; 160]; let mut n = 0;
+                    for &b in b"sriov: pf=" { out[n] = b; n += 1; }
+                    n += p.bdf.fmt(&mut out[n..]);
+                    for &b in b" vf_did=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(p.vf_device as u64, &mut out[n..]);
+                    for &b in b" vfs=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(p.num_vfs as u64, &mut out[n..]);
+                    out[n] = b'/'; n += 1;
+                    n += crate::util::format::u64_dec(p.total_vfs as u64, &mut out[n..]);
+                    for &b in b" first=" { out[n] = b; n += 1; }
+                    n += p.vf_bdf(0).fmt(&mut out[n..]);
+                    for &b in b" stride=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(p.stride as u64, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                crate::hv::sriov::for_each_attached(|a| {
+                    any = true;
+                    let mut out = [0u8; 128]; let mut n = 0;
+                    for &b in b"sriov: vf=" { out[n] = b; n += 1; }
+                    n += a.vf.fmt(&mut out[n..]);
+                    for &b in b" vm=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(a.vm_id, &mut out[n..]);
+                    for &b in b" guest_dev=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(a.guest_dev as u64, &mut out[n..]);
+                    for &b in b" domain=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(a.domid as u64, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                if !any { let _ = stdout.write_str("sriov: no VFs enabled\r\n"); }
+                continue;
+            }
+            let bdf = it.next().and_then(crate::hv::sriov::Bdf::parse);
+            let mut numvfs: Option<u16> = None; let mut bad = false;
+            for w in it {
+                if let Some(v) = w.strip_prefix("numvfs=") { match v.parse::<u16>() { Ok(x) => numvfs = Some(x), Err(_) => bad = true } } else { bad = true; }
+            }
+            let r = match (sub, bdf, numvfs) {
+                (Some("enable"), Some(b), Some(nv)) if !bad => crate::hv::sriov::enable(system_table, b, nv).map(|_| "sriov: VFs enabled\r\n"),
+                (Some("disable"), Some(b), None) if !bad => crate::hv::sriov::disable(b).map(|_| "sriov: VFs disabled\r\n"),
+                _ => Ok("usage: pci sriov [enable <[seg:]bus:dev.fn> numvfs=<n>|disable <[seg:]bus:dev.fn>]\r\n"),
+            };
+            let stdout = system_table.stdout();
+            match r {
+                Ok(m) => { let _ = stdout.write_str(m); }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "fpga" || cmd.starts_with("fpga ") {
+            // fpga | fpga load <bdf> region=<n> path=<esp path> | fpga assign|release id=<n> fpga=<bdf> region=<n>
+            let mut it = cmd[4..].split_whitespace();
+            let sub = it.next();
+            if sub.is_none() {
+                let mut cards: [Option<crate::hv::fpga::Card>; crate::hv::fpga::MAX_CARDS] = [None; crate::hv::fpga::MAX_CARDS];
+                let mut count = 0;
+                crate::hv::fpga::for_each_card(system_table, |c| if count < cards.len() { cards[count] = Some(*c); count += 1; });
+                let stdout = system_table.stdout();
+                for c in cards.iter().flatten() {
+                    let mut out = [0u8; 128]; let mut n = 0;
+                    for &b in b"fpga: " { out[n] = b; n += 1; }
+                    n += c.bdf.fmt(&mut out[n..]);
+                    for &b in b" vid=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(c.vendor as u64, &mut out[n..]);
+                    for &b in b" did=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(c.device as u64, &mut out[n..]);
+                    for &b in b" regions=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(c.regions as u64, &mut out[n..]);
+                    if !c.reconfigurable() { for &b in b" (no PR engine)" { out[n] = b; n += 1; } }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    for k in 0..c.regions {
+                        if !c.implemented(k) { continue; }
+                        let r = crate::hv::fpga::region(c.bdf, k);
+                        let mut out = [0u8; 160]; let mut n = 0;
+                        for &b in b"fpga:   region " { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(k as u64, &mut out[n..]);
+                        for &b in if c.vf_access(k) { &b" access=vf"[..] } else { &b" access=pf"[..] } { out[n] = b; n += 1; }
+                        match r.filter(|r| r.path_len != 0) {
+                            Some(r) => {
+                                for &b in b" bitstream=" { out[n] = b; n += 1; }
+                                for &b in r.path().as_bytes() { out[n] = b; n += 1; }
+                                for &b in b" sig=" { out[n] = b; n += 1; }
+                                for &b in r.verdict.map_or("-", |v| v.name()).as_bytes() { out[n] = b; n += 1; }
+                            }
+                            None => for &b in b" bitstream=(from boot)" { out[n] = b; n += 1; },
+                        }
+                        if let Some(vm) = r.and_then(|r| r.vm_id) {
+                            for &b in b" vm=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(vm, &mut out[n..]);
+                        }
+                        if let Some(vf) = r.and_then(|r| r.vf) {
+                            for &b in b" vf=" { out[n] = b; n += 1; }
+                            n += vf.fmt(&mut out[n..]);
+                        }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                }
+                if count == 0 { let _ = stdout.write_str("fpga: no DFL FPGA cards\r\n"); }
+                continue;
+            }
+            const USAGE: &str = "usage: fpga | fpga load <[seg:]bus:dev.fn> region=<n> path=<esp path> | fpga assign|release id=<n> fpga=<bdf> region=<n>\r\n";
+            let mut bdf: Option<crate::hv::sriov::Bdf> = None; let mut id: Option<u64> = None; let mut region: Option<u8> = None; let mut path: Option<&str> = None; let mut bad = false;
+            for w in it {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("fpga=") { match crate::hv::sriov::Bdf::parse(v) { Some(b) => bdf = Some(b), None => bad = true } }
+                else if let Some(v) = w.strip_prefix("region=") { match v.parse::<u8>() { Ok(x) => region = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("path=") { path = Some(v); }
+                else if bdf.is_none() && sub == Some("load") { match crate::hv::sriov::Bdf::parse(w) { Some(b) => bdf = Some(b), None => bad = true } }
+                else { bad = true; }
+            }
+            let r = match (sub, bdf, region, id, path) {
+                (Some("load"), Some(b), Some(k), None, Some(p)) if !bad => crate::hv::fpga::load(system_table, b, k, p).map(|v| match v {
+                    crate::hv::imgsig::Verdict::Trusted { .. } => "fpga: region reconfigured (signature trusted)\r\n",
+                    _ => "fpga: region reconfigured (signature not trusted; permissive policy)\r\n",
+                }),
+                (Some("assign"), Some(b), Some(k), Some(vm), None) if !bad => crate::hv::fpga::assign(system_table, vm, b, k).map(|_| "fpga: region assigned\r\n"),
+                (Some("release"), Some(b), Some(k), Some(vm), None) if !bad => crate::hv::fpga::release(system_table, vm, b, k).map(|_| "fpga: region released and reset\r\n"),
+                _ => Ok(USAGE),
+            };
+            let stdout = system_table.stdout();
+            match r {
+                Ok(m) => { let _ = stdout.write_str(m); }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "gpu" || cmd.starts_with("gpu ") {
+            // gpu [list] | gpu carve <bdf> slices=<n>|off | gpu attach id=<n> gpu=<bdf> [slice=<k>] | gpu detach id=<n> gpu=<bdf> slice=<k>
+            let mut it = cmd[3..].split_whitespace();
+            let sub = it.next();
+            if sub.is_none() || sub == Some("list") {
+                let _ = crate::hv::gpu::tick(false);
+                let mut gpus: [Option<crate::hv::gpu::Gpu>; 16] = [None; 16];
+                let mut count = 0;
+                crate::hv::gpu::for_each_gpu(system_table, |g| if count < gpus.len() { gpus[count] = Some(*g); count += 1; });
+                let stdout = system_table.stdout();
+                for g in gpus.iter().flatten() {
+                    let mut out = [0u8; 160]; let mut n = 0;
+                    for &b in b"gpu: " { out[n] = b; n += 1; }
+                    n += g.bdf.fmt(&mut out[n..]);
+                    for &b in b" vid=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(g.vendor as u64, &mut out[n..]);
+                    for &b in b" did=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(g.device as u64, &mut out[n..]);
+                    for &b in b" max_slices=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(g.total_vfs as u64, &mut out[n..]);
+                    if g.slices == 0 {
+                        for &b in b" whole" { out[n] = b; n += 1; }
+                    } else {
+                        for &b in b" profile=1/" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(g.slices as u64, &mut out[n..]);
+                        for &b in b" fb=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(g.fb_bytes >> 20, &mut out[n..]);
+                        for &b in b"MiB" { out[n] = b; n += 1; }
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                crate::hv::gpu::for_each_slice(|sl| {
+                    let mut out = [0u8; 160]; let mut n = 0;
+                    for &b in b"gpu:   " { out[n] = b; n += 1; }
+                    n += sl.gpu.fmt(&mut out[n..]);
+                    out[n] = b'#'; n += 1;
+                    n += crate::util::format::u64_dec(sl.index as u64, &mut out[n..]);
+                    for &b in b" vf=" { out[n] = b; n += 1; }
+                    n += sl.vf.fmt(&mut out[n..]);
+                    match sl.vm_id {
+                        Some(vm) => {
+                            for &b in b" vm=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(vm, &mut out[n..]);
+                            for &b in b" mmio_ops=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(sl.mmio_ops, &mut out[n..]);
+                            for &b in b" mmio_ops/s=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(sl.mmio_ops_per_s, &mut out[n..]);
+                        }
+                        None => for &b in b" free" { out[n] = b; n += 1; },
+                    }
+                    if sl.faults != 0 {
+                        for &b in b" faults=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(sl.faults, &mut out[n..]);
+                    }
+                    if sl.quarantined { for &b in b" quarantined" { out[n] = b; n += 1; } }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                if count == 0 { let _ = stdout.write_str("gpu: no SR-IOV capable GPUs\r\n"); }
+                continue;
+            }
+            const USAGE: &str = "usage: gpu [list] | gpu carve <[seg:]bus:dev.fn> slices=<n>|off | gpu attach id=<n> gpu=<bdf> [slice=<k>] | gpu detach id=<n> gpu=<bdf> slice=<k>\r\n";
+            if sub == Some("carve") {
+                let bdf = it.next().and_then(crate::hv::sriov::Bdf::parse);
+                let slices = match it.next() {
+                    Some("off") => Some(0),
+                    Some(w) => w.strip_prefix("slices=").and_then(|v| if v == "off" { Some(0) } else { v.parse::<u16>().ok() }),
+                    None => None,
+                };
+                let (Some(bdf), Some(slices), None) = (bdf, slices, it.next()) else { let _ = system_table.stdout().write_str(USAGE); continue; };
+                match crate::hv::gpu::carve(system_table, bdf, slices) {
+                    Ok(g) => {
+                        let mut out = [0u8; 96]; let mut n = 0;
+                        for &b in b"gpu: " { out[n] = b; n += 1; }
+                        n += g.bdf.fmt(&mut out[n..]);
+                        if g.slices == 0 {
+                            for &b in b" whole" { out[n] = b; n += 1; }
+                        } else {
+                            for &b in b" carved into " { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(g.slices as u64, &mut out[n..]);
+                            for &b in b" slices, fb=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(g.fb_bytes >> 20, &mut out[n..]);
+                            for &b in b"MiB each" { out[n] = b; n += 1; }
+                        }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            let is_attach = sub == Some("attach");
+            if !is_attach && sub != Some("detach") { let _ = system_table.stdout().write_str(USAGE); continue; }
+            let mut id: Option<u64> = None; let mut gpu: Option<crate::hv::sriov::Bdf> = None; let mut slice: Option<u16> = None; let mut bad = false;
+            for w in it {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("gpu=") { match crate::hv::sriov::Bdf::parse(v) { Some(b) => gpu = Some(b), None => bad = true } }
+                else if let Some(v) = w.strip_prefix("slice=") { match v.parse::<u16>() { Ok(x) => slice = Some(x), Err(_) => bad = true } }
+                else { bad = true; }
+            }
+            let (id, gpu) = match (id, gpu) {
+                (Some(i), Some(g)) if !bad && (is_attach || slice.is_some()) => (i, g),
+                _ => { let _ = system_table.stdout().write_str(USAGE); continue; }
+            };
+            if !is_attach {
+                match crate::hv::gpu::detach(system_table, id, gpu, slice.unwrap_or(0)) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("gpu: slice detached\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            match crate::hv::gpu::attach(system_table, id, gpu, slice) {
+                Ok(sl) => {
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    for &b in b"gpu: slice " { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(sl.index as u64, &mut out[n..]);
+                    for &b in b" (vf " { out[n] = b; n += 1; }
+                    n += sl.vf.fmt(&mut out[n..]);
+                    for &b in b") attached to vm " { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(id, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd.starts_with("pci class ") {
+            let rest = &cmd[10..].trim();
+            let mut parts = rest.split_whitespace();
+            let parse_num = |s: &str| -> Option<u32> { if let Some(h) = s.strip_prefix("0x") { u32::from_str_radix(h, 16).ok() } else { s.parse::<u32>().ok() } };
+            if let (Some(ccs), Some(scs)) = (parts.next(), parts.next()) {
+                if let (Some(cc), Some(sc)) = (parse_num(ccs), parse_num(scs)) {
+                    // Simple acknowledgment line
+                    let stdout = system_table.stdout();
+                    let mut buf = [0u8; 64]; let mut n = 0;
+                    for &b in b"filter: class=" { buf[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(cc, &mut buf[n..]); buf[n] = b'/'; n += 1;
+                    n += crate::util::format::u32_dec(sc, &mut buf[n..]); buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+                    // Full filtered enumeration
+                    crate::iommu::report_pci_by_class(system_table, cc as u8, sc as u8);
+                    continue;
+                }
+            }
+            let stdout = system_table.stdout();
+            let _ = stdout.write_str("usage: pci class <class> <subclass>\r\n");
+            continue;
+        }
+        if cmd.starts_with("pci find ") {
+            let rest = &cmd[9..].trim();
+            let mut vid: Option<u16> = None; let mut did: Option<u16> = None;
+            for tok in rest.split_whitespace() {
+                if let Some(v) = tok.strip_prefix("vid=") { vid = u16::from_str_radix(v.trim_start_matches("0x"), 16).ok(); continue; }
+                if let Some(v) = tok.strip_prefix("did=") { did = u16::from_str_radix(v.trim_start_matches("0x"), 16).ok(); continue; }
+            }
+            if let Some(mcfg_hdr) = crate::firmware::acpi::find_mcfg(system_table) {
+                crate::firmware::acpi::mcfg_for_each_allocation_from(|a| {
+                    let mut bus = a.start_bus;
+                    while bus <= a.end_bus {
+                        for dev in 0u8..32u8 { for func in 0u8..8u8 {
+                            let cfg = crate::iommu::ecam_fn_base(a.base_address, a.start_bus, bus, dev, func);
+                            let v = crate::iommu::mmio_read16(cfg + 0x00);
+                            if v == 0xFFFF { continue; }
+                            let d = crate::iommu::mmio_read16(cfg + 0x02);
+                            if let Some(w) = vid { if v != w { continue; } }
+                            if let Some(w) = did { if d != w { continue; } }
+                            let stdout = system_table.stdout();
+                            let mut buf = [0u8; 96]; let mut n = 0;
+                            for &b in b"PCI: seg=" { buf[n] = b; n += 1; }
+                            n += crate::util::format::u32_dec(a.pci_segment as u32, &mut buf[n..]);
+                            for &b in b" b=" { buf[n] = b; n += 1; }
+                            n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
+                            for &b in b" d=" { buf[n] = b; n += 1; }
+                            n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
+                            for &b in b" f=" { buf[n] = b; n += 1; }
+                            n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
+                            for &b in b" vid=0x" { buf[n] = b; n += 1; }
+                            n += crate::util::format::u64_hex(v as u64, &mut buf[n..]);
+                            for &b in b" did=0x" { buf[n] = b; n += 1; }
+                            n += crate::util::format::u64_hex(d as u64, &mut buf[n..]);
+                            buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+                            let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+                        } }
+                        if bus == 0xFF { break; }
+                        bus = bus.saturating_add(1);
+                    }
+                }, mcfg_hdr);
+            }
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("time") || cmd.eq_ignore_ascii_case("time show") {
+            let hz = crate::time::tsc_hz();
+            let stdout = system_table.stdout();
+            let mut buf = [0u8; 64]; let mut n = 0;
+            for &b in b"time: tsc_hz=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u32_dec((hz / 1_000_000) as u32, &mut buf[n..]);
+            for &b in b" MHz\r\n" { buf[n] = b; n += 1; }
+            let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+            let up = crate::time::clock::uptime();
+            let _ = write!(stdout, "time: clock={} hz={} uptime={}.{:03}s\r\n", crate::time::clock::source().name(), crate::time::clock::hz(), up.as_secs(), up.subsec_millis());
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("timer") || cmd.eq_ignore_ascii_case("timer stats") {
+            let stdout = system_table.stdout();
+            crate::time::timer::render(|s| { let _ = stdout.write_str(s); });
+            continue;
+        }
+        if cmd.starts_with("time wait ") {
+            // time wait <usec> [busy|stall]
+            let rest = &cmd[10..].trim();
+            let mut parts = rest.split_whitespace();
+            if let Some(us_s) = parts.next() {
+                if let Ok(usec) = us_s.parse::<u64>() {
+                    let mode = parts.next().unwrap_or("busy");
+                    if mode.eq_ignore_ascii_case("stall") {
+                        let _ = system_table.boot_services().stall(usec as usize);
+                    } else if crate::time::clock::hz() == 0 {
+                        let _ = system_table.boot_services().stall(usec as usize);
+                    } else {
+                        crate::time::clock::delay_us(usec);
+                    }
+                    let stdout = system_table.stdout();
+                    let _ = stdout.write_str("time: wait done\r\n");
+                    continue;
+                }
+            }
+            let stdout = system_table.stdout();
+            let _ = stdout.write_str("usage: time wait <usec> [busy|stall]\r\n");
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("sched") || cmd.eq_ignore_ascii_case("sched bg") {
+            let b = crate::hv::sched::background::budget();
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 192]; let mut n = 0;
+            for &b2 in b"sched: rt_mask=0x" { out[n] = b2; n += 1; }
+            n += crate::util::format::u64_hex(crate::hv::sched::rt_reserved(), &mut out[n..]);
+            for &b2 in b" bg_budget=" { out[n] = b2; n += 1; }
+            n += crate::util::format::u32_dec(b.pct, &mut out[n..]);
+            for &b2 in b"% window_us=" { out[n] = b2; n += 1; }
+            n += crate::util::format::u32_dec(b.window_us as u32, &mut out[n..]);
+            for &b2 in b" used=" { out[n] = b2; n += 1; }
+            n += crate::util::format::u32_dec(crate::hv::sched::background::window_usage_pct(), &mut out[n..]);
+            out[n] = b'%'; n += 1;
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            for t in crate::hv::sched::background::tasks().iter().flatten() {
+                n = 0;
+                for &b2 in b"  task " { out[n] = b2; n += 1; }
+                for &b2 in t.name.as_bytes().iter().take(32) { out[n] = b2; n += 1; }
+                for &b2 in b" kind=" { out[n] = b2; n += 1; }
+                for &b2 in t.kind.name().as_bytes() { out[n] = b2; n += 1; }
+                let st: &[u8] = if !t.enabled { b" disabled" } else if t.busy { b" busy" } else { b" idle" };
+                for &b2 in st { out[n] = b2; n += 1; }
+                for &b2 in b" runs=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u32_dec(t.runs as u32, &mut out[n..]);
+                for &b2 in b" cpu_us=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u64_dec(t.run_ns / 1_000, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            // Credit scheduler: per-AP runqueues, then per-VM parameters and usage
+            n = 0;
+            for &b2 in b"sched: credit tick_us=" { out[n] = b2; n += 1; }
+            n += crate::util::format::u64_dec(crate::hv::sched::credit::TICK_US, &mut out[n..]);
+            for &b2 in b" period_us=" { out[n] = b2; n += 1; }
+            n += crate::util::format::u64_dec(crate::hv::sched::credit::PERIOD_US, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            for cpu in 0..crate::arch::x86::ap::MAX_APS {
+                let c = crate::hv::sched::credit::cpu_stats(cpu);
+                if c.vcpus == 0 && c.slices == 0 { continue; }
+                n = 0;
+                for &b2 in b"  cpu " { out[n] = b2; n += 1; }
+                n += crate::util::format::u32_dec(cpu as u32, &mut out[n..]);
+                for &b2 in b" vcpus=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u32_dec(c.vcpus as u32, &mut out[n..]);
+                for &b2 in b" queued=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u32_dec(c.queued as u32, &mut out[n..]);
+                for &b2 in b" slices=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u64_dec(c.slices, &mut out[n..]);
+                for &b2 in b" switches=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u64_dec(c.switches, &mut out[n..]);
+                for &b2 in b" preempts=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u64_dec(c.preempts, &mut out[n..]);
+                for &b2 in b" rt_util_ppm=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u64_dec(crate::hv::sched::rt::cpu_util_ppm(cpu), &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            for d in crate::hv::sched::credit::domains().iter().flatten() {
+                n = 0;
+                for &b2 in b"  vm " { out[n] = b2; n += 1; }
+                n += crate::util::format::u64_dec(d.vm_id, &mut out[n..]);
+                for &b2 in b" weight=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u32_dec(d.params.weight, &mut out[n..]);
+                for &b2 in b" cap=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u32_dec(d.params.cap, &mut out[n..]);
+                for &b2 in b"% vcpus=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u32_dec(d.vcpus as u32, &mut out[n..]);
+                for &b2 in b" credit=" { out[n] = b2; n += 1; }
+                n += crate::util::format::i64_dec(d.credit, &mut out[n..]);
+                for &b2 in b" run_us=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u64_dec(d.run_us, &mut out[n..]);
+                for &b2 in b" parks=" { out[n] = b2; n += 1; }
+                n += crate::util::format::u64_dec(d.parks, &mut out[n..]);
+                if d.parked { for &b2 in b" parked" { out[n] = b2; n += 1; } }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            continue;
+        }
+        if cmd == "http" || cmd.starts_with("http ") {
+            // http | http on ip=<a.b.c.d> [port=<n>] [mac=<mac>] | http off
+            // http token [add name=<s> role=viewer|operator|admin secret=<s>|del name=<s>|off]
+            let rest = cmd[4..].trim();
+            if let Some(t) = rest.strip_prefix("token") {
+                const USAGE: &str = "usage: http token [add name=<s> role=viewer|operator|admin secret=<16..128 chars>|del name=<s>|off]\r\n";
+                let t = t.trim();
+                let arg = |k: &str| t.split_whitespace().find_map(|w| w.strip_prefix(k));
+                let res = if t.is_empty() {
+                    let stdout = system_table.stdout();
+                    let mut any = false;
+                    crate::ctl::api::for_each_token(|name, role| {
+                        any = true;
+                        let _ = stdout.write_str("token ");
+                        let _ = stdout.write_str(name);
+                        let _ = stdout.write_str(" role=");
+                        let _ = stdout.write_str(role.name());
+                        let _ = stdout.write_str("\r\n");
+                    });
+                    if !any { let _ = stdout.write_str("http: no API tokens (/v1 disabled)\r\n"); }
+                    continue;
+                } else if t.eq_ignore_ascii_case("off") {
+                    crate::ctl::api::clear_tokens();
+                    Ok("http: all API tokens removed, /v1 disabled\r\n")
+                } else if t.starts_with("add ") {
+                    match (arg("name="), arg("role=").and_then(crate::ctl::api::Role::parse), arg("secret=")) {
+                        (Some(name), Some(role), Some(secret)) => crate::ctl::api::add_token(name, role, secret).map(|_| "http: token added\r\n"),
+                        _ => Ok(USAGE),
+                    }
+                } else if t.starts_with("del ") {
+                    match arg("name=") {
+                        Some(name) => crate::ctl::api::remove_token(name).map(|_| "http: token removed\r\n"),
+                        None => Ok(USAGE),
+                    }
+                } else {
+                    Ok(USAGE)
+                };
+                let stdout = system_table.stdout();
+                match res {
+                    Ok(msg) => { let _ = stdout.write_str(msg); }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest.eq_ignore_ascii_case("off") {
+                crate::ctl::http::configure(None);
+                let _ = system_table.stdout().write_str("http: off\r\n");
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("on") {
+                let (mut ip, mut port, mut mac, mut bad) = (None, crate::ctl::http::DEFAULT_PORT, crate::hv::vdev::nat::host().mac, false);
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("ip=") { match crate::hv::vdev::net::parse_ipv4(v) { Some(a) => ip = Some(a), None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("port=") { match v.parse::<u16>() { Ok(x) if x != 0 => port = x, _ => bad = true } }
+                    else if let Some(v) = w.strip_prefix("mac=") { match crate::hv::vdev::net::parse_mac(v) { Some(a) => mac = a, None => bad = true } }
+                    else { bad = true; }
+                }
+                let ip = match ip { Some(a) if !bad => a, _ => { let _ = system_table.stdout().write_str("usage: http on ip=<a.b.c.d> [port=<n>] [mac=<mac>] | http off | http token [add|del|off]\r\n"); continue; } };
+                if mac == [0; 6] { let _ = system_table.stdout().write_str("http: mac= required (no NAT host MAC set)\r\n"); continue; }
+                crate::ctl::http::configure(Some(crate::ctl::http::Config { ip, mac, port }));
+                if crate::hv::vdev::net::uplink() == crate::hv::vdev::net::Uplink::None {
+                    let _ = system_table.stdout().write_str("http: on, but no uplink yet (vm net uplink virtio|snp)\r\n");
+                } else {
+                    let _ = system_table.stdout().write_str(if crate::ctl::api::enabled() { "http: on, serving /metrics and /v1\r\n" } else { "http: on, serving /metrics (http token add enables /v1)\r\n" });
+                }
+                continue;
+            }
+            if !rest.is_empty() { let _ = system_table.stdout().write_str("usage: http on ip=<a.b.c.d> [port=<n>] [mac=<mac>] | http off | http token [add|del|off]\r\n"); continue; }
+            let mut out = [0u8; 192];
+            let mut n = 0;
+            match crate::ctl::http::config() {
+                None => { for &b in b"http: off" { out[n] = b; n += 1; } }
+                Some(c) => {
+                    for &b in b"http: listening on " { out[n] = b; n += 1; }
+                    for (k, v) in c.ip.iter().enumerate() {
+                        if k != 0 { out[n] = b'.'; n += 1; }
+                        n += crate::util::format::u32_dec(*v as u32, &mut out[n..]);
+                    }
+                    out[n] = b':'; n += 1;
+                    n += crate::util::format::u32_dec(c.port as u32, &mut out[n..]);
+                    let st = crate::ctl::http::stats();
+                    for &b in b" conns=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(crate::ctl::http::connections() as u64, &mut out[n..]);
+                    for &b in b" accepted=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(st.connections, &mut out[n..]);
+                    for &b in b" requests=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(st.requests, &mut out[n..]);
+                    for &b in b" retransmits=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(st.retransmits, &mut out[n..]);
+                    for &b in b" refused=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(st.drops, &mut out[n..]);
+                    for &b in if crate::ctl::api::enabled() { &b" api=on"[..] } else { &b" api=off"[..] } { out[n] = b; n += 1; }
+                }
+            }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd == "cluster" || cmd.starts_with("cluster ") {
+            // cluster | cluster set cluster=<n> node=<n> peer=<n> [peermac=<mac>] [mac=<mac>] [link=snp|virtio] [interval=<ms>] [timeout=<ms>]
+            // cluster leave | cluster witness none|disk=<idx> [lba=<n>]|endpoint=<mac>|serve [link=snp|virtio]|unserve | cluster save|load|tick
+            // cluster status | cluster peers add node=<n> [mac=<mac>]|del node=<n> | cluster detect suspect=<ms> dead=<ms>|auto
+            // cluster placement [on|off|check|weights [mem=<n>] [cpu=<n>] [numa=<n>] [energy=<n>]]
+            // cluster ha [protect id=<n> [group=<g>]|unprotect id=<n>|fence none|quorum [delay=<ms>]]
+            // cluster log [init replicas=<n,n,..> secret=<s>|off|put <key> <value>|del <key>|get <key>|kv [prefix]]
+            let rest = cmd[7..].trim();
+            let hex2 = |v: u8, o: &mut [u8]| { const H: &[u8; 16] = b"0123456789abcdef"; o[0] = H[(v >> 4) as usize]; o[1] = H[(v & 0xF) as usize]; };
+            let parse_link = |v: &str| match v { "snp" => Some(crate::hv::vdev::net::Uplink::Snp), "virtio" => Some(crate::hv::vdev::net::Uplink::Virtio), _ => None };
+            if let Some(args) = rest.strip_prefix("set") {
+                let mut cfg = crate::cluster::config().unwrap_or(crate::cluster::Config::new(0, 0, 0));
+                let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("cluster=") { match v.parse::<u32>() { Ok(x) => cfg.cluster = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("node=") { match v.parse::<u64>() { Ok(x) => cfg.node = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("peer=") { match v.parse::<u64>() { Ok(x) => cfg.peer = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("peermac=") { match crate::hv::vdev::net::parse_mac(v) { Some(a) => cfg.peer_mac = a, None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("mac=") { match crate::hv::vdev::net::parse_mac(v) { Some(a) => cfg.mac = a, None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("link=") { match parse_link(v) { Some(l) => cfg.link = l, None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("interval=") { match v.parse::<u32>() { Ok(x) if x >= 10 => cfg.interval_ms = x, _ => bad = true } }
+                    else if let Some(v) = w.strip_prefix("timeout=") { match v.parse::<u32>() { Ok(x) if x >= 100 => cfg.timeout_ms = x, _ => bad = true } }
+                    else { bad = true; }
+                }
+                if bad || cfg.node == 0 || cfg.peer == 0 {
+                    let _ = system_table.stdout().write_str("usage: cluster set cluster=<n> node=<n> peer=<n> [peermac=<mac>] [mac=<mac>] [link=snp|virtio] [interval=<ms>] [timeout=<ms>]\r\n");
+                    continue;
+                }
+                if cfg.node == cfg.peer { let _ = system_table.stdout().write_str("cluster: node and peer ids must differ\r\n"); continue; }
+                if cfg.timeout_ms <= cfg.interval_ms * 2 { let _ = system_table.stdout().write_str("cluster: timeout must exceed two heartbeat intervals\r\n"); continue; }
+                crate::cluster::configure(Some(cfg));
+                let _ = system_table.stdout().write_str("cluster: configured\r\n");
+                continue;
+            }
+            if rest == "leave" {
+                crate::cluster::configure(None);
+                let _ = system_table.stdout().write_str("cluster: left, standalone\r\n");
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("witness") {
+                let args = args.trim();
+                if let Some(v) = args.strip_prefix("serve") {
+                    let link = match v.trim().strip_prefix("link=") { Some(l) => parse_link(l), None if v.trim().is_empty() => Some(crate::hv::vdev::net::Uplink::Snp), None => None };
+                    match link {
+                        Some(l) => { crate::cluster::witness::serve(Some(l)); let _ = system_table.stdout().write_str("cluster: serving as witness\r\n"); }
+                        None => { let _ = system_table.stdout().write_str("usage: cluster witness serve [link=snp|virtio]\r\n"); }
+                    }
+                    continue;
+                }
+                if args == "unserve" {
+                    crate::cluster::witness::serve(None);
+                    let _ = system_table.stdout().write_str("cluster: witness service stopped\r\n");
+                    continue;
+                }
+                let mut w: Option<crate::cluster::witness::Witness> = None; let mut lba = 0u64; let mut bad = false;
+                for t in args.split_whitespace() {
+                    if t == "none" { w = Some(crate::cluster::witness::Witness::None); }
+                    else if let Some(v) = t.strip_prefix("disk=") { match v.parse::<u16>() { Ok(d) => w = Some(crate::cluster::witness::Witness::Disk { disk: d, lba: 0 }), Err(_) => bad = true } }
+                    else if let Some(v) = t.strip_prefix("lba=") { match v.parse::<u64>() { Ok(x) => lba = x, Err(_) => bad = true } }
+                    else if let Some(v) = t.strip_prefix("endpoint=") { match crate::hv::vdev::net::parse_mac(v) { Some(m) => w = Some(crate::cluster::witness::Witness::Endpoint { mac: m }), None => bad = true } }
+                    else { bad = true; }
+                }
+                if let Some(crate::cluster::witness::Witness::Disk { disk, .. }) = w { w = Some(crate::cluster::witness::Witness::Disk { disk, lba }); }
+                let w = match w { Some(w) if !bad => w, _ => { let _ = system_table.stdout().write_str("usage: cluster witness none|disk=<idx> [lba=<n>]|endpoint=<mac>|serve [link=snp|virtio]|unserve\r\n"); continue; } };
+                match crate::cluster::set_witness(w) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("cluster: witness set\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest == "save" {
+                match crate::cluster::save(system_table) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("cluster: saved\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest == "load" {
+                let msg = if crate::cluster::load(system_table).is_some() { "cluster: loaded\r\n" } else { "cluster: no saved configuration\r\n" };
+                let _ = system_table.stdout().write_str(msg);
+                continue;
+            }
+            if rest == "tick" {
+                let m = crate::cluster::tick(system_table, true);
+                let mut out = [0u8; 64]; let mut n = 0;
+                for &b in b"cluster: mode=" { out[n] = b; n += 1; }
+                for &b in m.name().as_bytes() { out[n] = b; n += 1; }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("peers") {
+                let mut words = args.split_whitespace();
+                let op = words.next();
+                let mut node = None; let mut mac = [0u8; 6]; let mut bad = false;
+                for w in words {
+                    if let Some(v) = w.strip_prefix("node=") { match v.parse::<u64>() { Ok(x) if x != 0 => node = Some(x), _ => bad = true } }
+                    else if let Some(v) = w.strip_prefix("mac=") { match crate::hv::vdev::net::parse_mac(v) { Some(a) => mac = a, None => bad = true } }
+                    else { bad = true; }
+                }
+                let res = match (op, node) {
+                    (Some("add"), Some(n)) if !bad => crate::cluster::membership::add_peer(n, mac),
+                    (Some("del"), Some(n)) if !bad && mac == [0; 6] => crate::cluster::membership::remove_peer(n),
+                    _ => { let _ = system_table.stdout().write_str("usage: cluster peers add node=<n> [mac=<mac>] | cluster peers del node=<n>\r\n"); continue; }
+                };
+                match res {
+                    Ok(()) => { let _ = system_table.stdout().write_str("cluster: peers updated\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("detect") {
+                let args = args.trim();
+                let t = if args == "auto" { Ok(None) } else {
+                    let mut t = crate::cluster::membership::Timeouts { suspect_ms: 0, dead_ms: 0 }; let mut bad = false;
+                    for w in args.split_whitespace() {
+                        if let Some(v) = w.strip_prefix("suspect=") { match v.parse::<u32>() { Ok(x) => t.suspect_ms = x, Err(_) => bad = true } }
+                        else if let Some(v) = w.strip_prefix("dead=") { match v.parse::<u32>() { Ok(x) => t.dead_ms = x, Err(_) => bad = true } }
+                        else { bad = true; }
+                    }
+                    if bad || args.is_empty() { Err(()) } else { Ok(Some(t)) }
+                };
+                let t = match t { Ok(t) => t, Err(()) => { let _ = system_table.stdout().write_str("usage: cluster detect suspect=<ms> dead=<ms> | cluster detect auto\r\n"); continue; } };
+                match crate::cluster::membership::set_timeouts(t) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("cluster: failure detection updated\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("placement") {
+                let args = args.trim();
+                if args == "on" || args == "off" {
+                    crate::cluster::placement::set_enabled(args == "on");
+                    let _ = system_table.stdout().write_str(if args == "on" { "cluster: placement on\r\n" } else { "cluster: placement off\r\n" });
+                    continue;
+                }
+                if let Some(ws) = args.strip_prefix("weights") {
+                    let mut w = crate::cluster::placement::weights(); let mut bad = false;
+                    for t in ws.split_whitespace() {
+                        let (k, v) = match t.split_once('=') { Some(kv) => kv, None => { bad = true; break; } };
+                        let Ok(v) = v.parse::<u8>() else { bad = true; break; };
+                        match k { "mem" => w.mem = v, "cpu" => w.cpu = v, "numa" => w.numa = v, "energy" => w.energy = v, _ => bad = true }
+                    }
+                    if bad { let _ = system_table.stdout().write_str("usage: cluster placement weights [mem=<n>] [cpu=<n>] [numa=<n>] [energy=<n>]\r\n"); continue; }
+                    match crate::cluster::placement::set_weights(w) {
+                        Ok(()) => { let _ = system_table.stdout().write_str("cluster: placement weights set\r\n"); }
+                        Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                    }
+                    continue;
+                }
+                if args == "check" {
+                    let topo = crate::firmware::acpi::numa::topology(system_table);
+                    let stdout = system_table.stdout();
+                    let found = crate::cluster::placement::check(&topo, |v| {
+                        let mut out = [0u8; 128]; let mut n = 0;
+                        for &b in b"  vm=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(v.vm, &mut out[n..]);
+                        for &b in b" group=" { out[n] = b; n += 1; }
+                        for &b in v.group.label().as_bytes() { out[n] = b; n += 1; }
+                        out[n] = b' '; n += 1;
+                        for &b in v.group.rule.key().as_bytes() { out[n] = b; n += 1; }
+                        out[n] = b'='; n += 1;
+                        for &b in v.group.rule.scope().name().as_bytes() { out[n] = b; n += 1; }
+                        match v.with {
+                            crate::cluster::placement::Conflict::Vm(id) => { for &b in b" broken with vm=" { out[n] = b; n += 1; } n += crate::util::format::u64_dec(id, &mut out[n..]); }
+                            crate::cluster::placement::Conflict::Node(node) => { for &b in b" broken with node=" { out[n] = b; n += 1; } n += crate::util::format::u64_dec(node, &mut out[n..]); }
+                            crate::cluster::placement::Conflict::Unpinned => for &b in b" not pinned to one NUMA node" { out[n] = b; n += 1; },
+                        }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    });
+                    let _ = system_table.stdout().write_str(if found == 0 { "placement: no group violations\r\n" } else { "placement: group rules broken\r\n" });
+                    continue;
+                }
+                if !args.is_empty() { let _ = system_table.stdout().write_str("usage: cluster placement [on|off|check|weights ...]\r\n"); continue; }
+                let local = crate::cluster::placement::local(system_table);
+                let w = crate::cluster::placement::weights();
+                let stdout = system_table.stdout();
+                let mut out = [0u8; 160]; let mut n = 0;
+                for &b in b"placement: " { out[n] = b; n += 1; }
+                for &b in if crate::cluster::placement::enabled() { b"on".as_slice() } else { b"off".as_slice() } { out[n] = b; n += 1; }
+                for (k, v) in [(&b" weights mem="[..], w.mem), (b" cpu=", w.cpu), (b" numa=", w.numa), (b" energy=", w.energy)] {
+                    for &b in k { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v as u64, &mut out[n..]);
+                }
+                for &b in b" margin=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(crate::cluster::placement::MARGIN as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                let mut res_line = |label: &[u8], node: u64, r: &crate::cluster::placement::Resources| {
+                    let mut out = [0u8; 192]; let mut n = 0;
+                    for &b in label { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(node, &mut out[n..]);
+                    for (k, v) in [(&b" mem_free="[..], r.mem_free_mib as u64), (b"/", r.mem_total_mib as u64), (b"MiB vcpus_free=", r.vcpus_free as u64), (b"/", r.vcpus_total as u64),
+                                   (b" load=", r.load_pct as u64), (b"% huge_free=", r.huge_free_mib as u64), (b"MiB devices_free=", r.devices_free as u64),
+                                   (b" numa=", r.numa_mib as u64), (b"MiB/", r.numa_cpus as u64)] {
+                        for &b in k { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(v, &mut out[n..]);
+                    }
+                    for &b in b"cpu power=" { out[n] = b; n += 1; }
+                    match r.power_pct { Some(p) => { n += crate::util::format::u64_dec(p as u64, &mut out[n..]); out[n] = b'%'; n += 1; } None => for &b in b"unknown" { out[n] = b; n += 1; } }
+                    for &b in b" groups=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.groups.iter().flatten().count() as u64, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                };
+                res_line(b"  local node=", crate::cluster::config().map_or(0, |c| c.node), &local);
+                crate::cluster::membership::for_each(|m| {
+                    if let (Some(r), crate::cluster::membership::Health::Alive) = (m.res, m.health) { res_line(b"  member node=", m.node, &r); }
+                });
+                crate::cluster::placement::for_each_decision(|e| {
+                    let mut out = [0u8; 160]; let mut n = 0;
+                    for &b in b"  ticket=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(e.ticket, &mut out[n..]);
+                    for &b in b" node=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(e.node, &mut out[n..]);
+                    for &b in b" vcpus=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(e.vcpus as u64, &mut out[n..]);
+                    for &b in b" mem=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(e.memory_mib, &mut out[n..]);
+                    for &b in b"MiB score=" { out[n] = b; n += 1; }
+                    match e.score { Some(v) => n += crate::util::format::u64_dec(v as u64, &mut out[n..]), None => for &b in b"unfit" { out[n] = b; n += 1; } }
+                    for &b in b" local=" { out[n] = b; n += 1; }
+                    match e.local_score { Some(v) => n += crate::util::format::u64_dec(v as u64, &mut out[n..]), None => for &b in b"unfit" { out[n] = b; n += 1; } }
+                    out[n] = b' '; n += 1;
+                    for &b in e.outcome.name().as_bytes() { out[n] = b; n += 1; }
+                    match e.outcome {
+                        crate::cluster::placement::Outcome::Created(id) => { for &b in b" vm=" { out[n] = b; n += 1; } n += crate::util::format::u64_dec(id, &mut out[n..]); }
+                        crate::cluster::placement::Outcome::Denied(r) => { for &b in b" resource=" { out[n] = b; n += 1; } for &b in r.name().as_bytes() { out[n] = b; n += 1; } }
+                        _ => {}
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("ha") {
+                let args = args.trim();
+                if let Some(a) = args.strip_prefix("protect ") {
+                    let (mut id, mut group, mut bad) = (None, 0u8, false);
+                    for w in a.split_whitespace() {
+                        if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); }
+                        else if let Some(v) = w.strip_prefix("group=") { match v.parse::<u8>() { Ok(g) => group = g, Err(_) => bad = true } }
+                        else { bad = true; }
+                    }
+                    let id = match id { Some(v) if !bad => v, _ => { let _ = system_table.stdout().write_str("usage: cluster ha protect id=<n> [group=<0-255>]\r\n"); continue; } };
+                    match crate::cluster::ha::protect(id, group) {
+                        Ok(()) => { let _ = system_table.stdout().write_str("cluster: vm protected\r\n"); }
+                        Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                    }
+                    continue;
+                }
+                if let Some(a) = args.strip_prefix("unprotect ") {
+                    let ok = a.trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok()).map(crate::cluster::ha::unprotect);
+                    let msg = match ok { Some(true) => "cluster: vm unprotected\r\n", Some(false) => "ha: vm not protected\r\n", None => "usage: cluster ha unprotect id=<n>\r\n" };
+                    let _ = system_table.stdout().write_str(msg);
+                    continue;
+                }
+                if let Some(a) = args.strip_prefix("fence ") {
+                    let mut p = crate::cluster::ha::policy(); let mut bad = false;
+                    for w in a.split_whitespace() {
+                        if let Some(v) = w.strip_prefix("delay=") { match v.parse::<u32>() { Ok(d) => p.delay_ms = d, Err(_) => bad = true } }
+                        else { match crate::cluster::ha::Fence::parse(w) { Some(f) => p.fence = f, None => bad = true } }
+                    }
+                    if bad { let _ = system_table.stdout().write_str("usage: cluster ha fence none|quorum [delay=<ms>]\r\n"); continue; }
+                    crate::cluster::ha::set_policy(p);
+                    let _ = system_table.stdout().write_str("cluster: ha fencing updated\r\n");
+                    continue;
+                }
+                if !args.is_empty() { let _ = system_table.stdout().write_str("usage: cluster ha [protect id=<n> [group=<g>]|unprotect id=<n>|fence none|quorum [delay=<ms>]]\r\n"); continue; }
+                let p = crate::cluster::ha::policy();
+                let now = crate::time::clock::now_ms();
+                let stdout = system_table.stdout();
+                let mut out = [0u8; 96]; let mut n = 0;
+                for &b in b"ha: fence=" { out[n] = b; n += 1; }
+                for &b in p.fence.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" delay=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(p.delay_ms as u64, &mut out[n..]);
+                for &b in b"ms" { out[n] = b; n += 1; }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                crate::cluster::ha::for_each_protected(|pr| {
+                    let mut out = [0u8; 128]; let mut n = 0;
+                    for &b in b"  protected vm=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(pr.vm, &mut out[n..]);
+                    for &b in b" group=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(pr.group as u64, &mut out[n..]);
+                    if let Some((owner, vm)) = pr.replaces {
+                        for &b in b" from=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(owner, &mut out[n..]);
+                        out[n] = b':'; n += 1;
+                        n += crate::util::format::u64_dec(vm, &mut out[n..]);
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                crate::cluster::ha::for_each_replica(|r| {
+                    let mut out = [0u8; 256]; let mut n = 0;
+                    for &b in b"  replica node=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.inv.owner, &mut out[n..]);
+                    for &b in b" vm=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.inv.vm, &mut out[n..]);
+                    if !r.inv.name().is_empty() {
+                        for &b in b" name=" { out[n] = b; n += 1; }
+                        for &b in r.inv.name().as_bytes() { out[n] = b; n += 1; }
+                    }
+                    for &b in b" group=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.inv.group as u64, &mut out[n..]);
+                    for &b in b" vcpus=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.inv.vcpus as u64, &mut out[n..]);
+                    for &b in b" mem=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.inv.memory_bytes >> 20, &mut out[n..]);
+                    for &b in b"MiB age=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.age_ms(now), &mut out[n..]);
+                    for &b in b"ms path=" { out[n] = b; n += 1; }
+                    for &b in r.inv.path().as_bytes().iter().take(out.len() - n - 24) { out[n] = b; n += 1; }
+                    if r.ticket != 0 {
+                        for &b in b" ticket=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(r.ticket, &mut out[n..]);
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                crate::cluster::ha::for_each_evacuation(|e| {
+                    let mut out = [0u8; 128]; let mut n = 0;
+                    for &b in b"  evacuation ticket=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(e.ticket, &mut out[n..]);
+                    for &b in b" node=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(e.owner, &mut out[n..]);
+                    for &b in b" vm=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(e.vm, &mut out[n..]);
+                    if e.target != 0 {
+                        for &b in b" target=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(e.target, &mut out[n..]);
+                    }
+                    out[n] = b' '; n += 1;
+                    for &b in e.outcome.name().as_bytes() { out[n] = b; n += 1; }
+                    if let crate::cluster::ha::Outcome::Restarted(id) = e.outcome {
+                        for &b in b" as=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(id, &mut out[n..]);
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("log") {
+                let args = args.trim();
+                if let Some(a) = args.strip_prefix("init ") {
+                    let mut ids = [0u64; crate::cluster::store::MAX_REPLICAS]; let mut count = 0;
+                    let (mut secret, mut bad) = ("", false);
+                    for w in a.split_whitespace() {
+                        if let Some(v) = w.strip_prefix("replicas=") {
+                            for id in v.split(',') {
+                                match id.parse::<u64>() { Ok(x) if x != 0 && count < ids.len() => { ids[count] = x; count += 1; } _ => bad = true }
+                            }
+                        }
+                        else if let Some(v) = w.strip_prefix("secret=") { secret = v; }
+                        else { bad = true; }
+                    }
+                    if bad || count == 0 || secret.is_empty() { let _ = system_table.stdout().write_str("usage: cluster log init replicas=<n,n,..> secret=<16-64 chars>\r\n"); continue; }
+                    match crate::cluster::store::init(&ids[..count], secret) {
+                        Ok(()) => { let _ = system_table.stdout().write_str("cluster: replicated log started\r\n"); }
+                        Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                    }
+                    continue;
+                }
+                if args == "off" {
+                    crate::cluster::store::stop();
+                    let _ = system_table.stdout().write_str("cluster: replicated log stopped\r\n");
+                    continue;
+                }
+                let queued = |system_table: &mut SystemTable<Boot>, r: Result<u64, &'static str>| match r {
+                    Ok(req) => {
+                        let mut out = [0u8; 48]; let mut n = 0;
+                        for &b in b"cluster: queued as request " { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(req, &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                };
+                if let Some(a) = args.strip_prefix("put ") {
+                    let a = a.trim();
+                    let (key, value) = a.split_once(' ').map_or((a, ""), |(k, v)| (k, v.trim()));
+                    if value.is_empty() { let _ = system_table.stdout().write_str("usage: cluster log put <key> <value>\r\n"); continue; }
+                    queued(system_table, crate::cluster::store::put(key, value));
+                    continue;
+                }
+                if let Some(key) = args.strip_prefix("del ") {
+                    queued(system_table, crate::cluster::store::delete(key.trim()));
+                    continue;
+                }
+                if let Some(key) = args.strip_prefix("get ") {
+                    let stdout = system_table.stdout();
+                    match crate::cluster::store::get(key.trim(), |v| { let _ = stdout.write_str(v); }) {
+                        Some(()) => { let _ = stdout.write_str("\r\n"); }
+                        None => { let _ = stdout.write_str("store: no such key\r\n"); }
+                    }
+                    continue;
+                }
+                if args == "kv" || args.starts_with("kv ") {
+                    let stdout = system_table.stdout();
+                    crate::cluster::store::for_each(args[2..].trim(), |k, v| {
+                        let _ = stdout.write_str("  "); let _ = stdout.write_str(k);
+                        let _ = stdout.write_str(" = "); let _ = stdout.write_str(v);
+                        let _ = stdout.write_str("\r\n");
+                    });
+                    continue;
+                }
+                if !args.is_empty() { let _ = system_table.stdout().write_str("usage: cluster log [init replicas=<n,n,..> secret=<s>|off|put <key> <value>|del <key>|get <key>|kv [prefix]]\r\n"); continue; }
+                let st = crate::cluster::store::status();
+                let stdout = system_table.stdout();
+                if !st.running { let _ = stdout.write_str("log: stopped\r\n"); continue; }
+                let mut out = [0u8; 256]; let mut n = 0;
+                for &b in b"log: view=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(st.view, &mut out[n..]);
+                for &b in b" leader=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(st.leader, &mut out[n..]);
+                for &b in b" executed=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(st.executed, &mut out[n..]);
+                for &b in b" replicas=" { out[n] = b; n += 1; }
+                for (i, id) in st.replicas[..st.n].iter().enumerate() {
+                    if i != 0 { out[n] = b','; n += 1; }
+                    n += crate::util::format::u64_dec(*id, &mut out[n..]);
+                }
+                for &b in b" f=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(st.f as u64, &mut out[n..]);
+                for &b in b" quorum=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(st.quorum as u64, &mut out[n..]);
+                for &b in b" keys=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(st.keys as u64, &mut out[n..]);
+                for &b in b" queued=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(st.queued as u64, &mut out[n..]);
+                if let Some(v) = st.view_change {
+                    for &b in b" view-change=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                crate::cluster::store::for_each_logged(|l| {
+                    let mut out = [0u8; 192]; let mut n = 0;
+                    for &b in b"  seq=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(l.seq, &mut out[n..]);
+                    for &b in b" view=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(l.view, &mut out[n..]);
+                    let op: &[u8] = match l.entry.op { crate::cluster::store::Op::Nop => b" nop", crate::cluster::store::Op::Put => b" put ", crate::cluster::store::Op::Del => b" del " };
+                    for &b in op { out[n] = b; n += 1; }
+                    for &b in l.entry.key().as_bytes() { out[n] = b; n += 1; }
+                    if l.entry.op == crate::cluster::store::Op::Put {
+                        out[n] = b'='; n += 1;
+                        for &b in l.entry.value().as_bytes() { out[n] = b; n += 1; }
+                    }
+                    if l.entry.op != crate::cluster::store::Op::Nop {
+                        for &b in b" from=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(l.entry.origin, &mut out[n..]);
+                        out[n] = b'#'; n += 1;
+                        n += crate::util::format::u64_dec(l.entry.req, &mut out[n..]);
+                    }
+                    if !l.applied { for &b in b" (map full)" { out[n] = b; n += 1; } }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                continue;
+            }
+            if !rest.is_empty() && rest != "status" { let _ = system_table.stdout().write_str("usage: cluster [status|set ...|leave|witness ...|peers ...|detect ...|placement ...|ha ...|log ...|save|load|tick]\r\n"); continue; }
+            let st = crate::cluster::status();
+            let stdout = system_table.stdout();
+            if let Some((link, granted, denied)) = crate::cluster::witness::serving().map(|l| { let (g, d) = crate::cluster::witness::serve_stats(); (l, g, d) }) {
+                let mut out = [0u8; 96]; let mut n = 0;
+                for &b in b"cluster: serving witness on " { out[n] = b; n += 1; }
+                for &b in if link == crate::hv::vdev::net::Uplink::Virtio { b"virtio".as_slice() } else { b"snp".as_slice() } { out[n] = b; n += 1; }
+                for &b in b" granted=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(granted, &mut out[n..]);
+                for &b in b" denied=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(denied, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            let cfg = match st.cfg { Some(c) => c, None => { let _ = stdout.write_str("cluster: standalone (not configured)\r\n"); continue; } };
+            let mut out = [0u8; 160]; let mut n = 0;
+            for &b in b"cluster: id=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(cfg.cluster as u64, &mut out[n..]);
+            for &b in b" node=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(cfg.node, &mut out[n..]);
+            for &b in b" peer=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(cfg.peer, &mut out[n..]);
+            for &b in b" mode=" { out[n] = b; n += 1; }
+            for &b in st.mode.name().as_bytes() { out[n] = b; n += 1; }
+            for &b in b" quorum=" { out[n] = b; n += 1; }
+            for &b in if st.mode.quorate() { b"yes".as_slice() } else { b"no".as_slice() } { out[n] = b; n += 1; }
+            for &b in b" failover=" { out[n] = b; n += 1; }
+            for &b in if crate::cluster::may_failover() { b"yes".as_slice() } else { b"no".as_slice() } { out[n] = b; n += 1; }
+            for &b in b" term=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(st.term, &mut out[n..]);
+            for &b in b" since_ms=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(st.since_ms, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            n = 0;
+            for &b in b"cluster: peermac=" { out[n] = b; n += 1; }
+            for (i, v) in cfg.peer_mac.iter().enumerate() { hex2(*v, &mut out[n..]); n += 2; if i != 5 { out[n] = b':'; n += 1; } }
+            for &b in b" last_hb_ms=" { out[n] = b; n += 1; }
+            match st.peer_age_ms { Some(ms) => n += crate::util::format::u64_dec(ms, &mut out[n..]), None => for &b in b"never" { out[n] = b; n += 1; } }
+            for &b in b" peer_term=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(st.peer_term, &mut out[n..]);
+            for &b in b" interval_ms=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(cfg.interval_ms as u64, &mut out[n..]);
+            for &b in b" timeout_ms=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(cfg.timeout_ms as u64, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            n = 0;
+            for &b in b"cluster: witness=" { out[n] = b; n += 1; }
+            match cfg.witness {
+                crate::cluster::witness::Witness::None => for &b in b"none" { out[n] = b; n += 1; },
+                crate::cluster::witness::Witness::Disk { disk, lba } => {
+                    for &b in b"disk:" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(disk as u64, &mut out[n..]);
+                    out[n] = b'@'; n += 1;
+                    n += crate::util::format::u64_dec(lba, &mut out[n..]);
+                    for &b in b" peer_on_disk=" { out[n] = b; n += 1; }
+                    for &b in if st.vote.peer_alive { b"alive".as_slice() } else { b"stale".as_slice() } { out[n] = b; n += 1; }
+                }
+                crate::cluster::witness::Witness::Endpoint { mac } => {
+                    for &b in b"endpoint:" { out[n] = b; n += 1; }
+                    for (i, v) in mac.iter().enumerate() { hex2(*v, &mut out[n..]); n += 2; if i != 5 { out[n] = b':'; n += 1; } }
+                    for &b in b" holder=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(crate::cluster::witness::holder(), &mut out[n..]);
+                }
+            }
+            if cfg.witness != crate::cluster::witness::Witness::None {
+                for &b in b" reachable=" { out[n] = b; n += 1; }
+                for &b in if st.vote.reachable { b"yes".as_slice() } else { b"no".as_slice() } { out[n] = b; n += 1; }
+                for &b in b" lease=" { out[n] = b; n += 1; }
+                for &b in if st.vote.lease { b"held".as_slice() } else { b"not-held".as_slice() } { out[n] = b; n += 1; }
+                for &b in b" errors=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(crate::cluster::witness::errors(), &mut out[n..]);
+            }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            let (alive, suspect, dead) = crate::cluster::membership::counts();
+            let t = crate::cluster::membership::timeouts(&cfg);
+            n = 0;
+            for &b in b"cluster: members alive=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(alive as u64, &mut out[n..]);
+            for &b in b" suspect=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(suspect as u64, &mut out[n..]);
+            for &b in b" dead=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(dead as u64, &mut out[n..]);
+            for &b in b" suspect_ms=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(t.suspect_ms as u64, &mut out[n..]);
+            for &b in b" dead_ms=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(t.dead_ms as u64, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            let now = crate::time::clock::now_ms();
+            crate::cluster::membership::for_each(|m| {
+                let mut out = [0u8; 128]; let mut n = 0;
+                for &b in b"  node=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(m.node, &mut out[n..]);
+                for &b in b" mac=" { out[n] = b; n += 1; }
+                for (i, v) in m.mac.iter().enumerate() { hex2(*v, &mut out[n..]); n += 2; if i != 5 { out[n] = b':'; n += 1; } }
+                for &b in b" state=" { out[n] = b; n += 1; }
+                for &b in m.health.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" hb=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(m.heartbeat, &mut out[n..]);
+                for &b in b" age_ms=" { out[n] = b; n += 1; }
+                match m.age_ms(now) { Some(ms) => n += crate::util::format::u64_dec(ms, &mut out[n..]), None => for &b in b"never" { out[n] = b; n += 1; } }
+                if m.static_peer { for &b in b" static" { out[n] = b; n += 1; } }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            continue;
+        }
+        if cmd == "power" || cmd.starts_with("power ") {
+            // power | power cap watts=<n>|mw=<n>|off | power vm id=<n> prio=high|normal|low [floor=<pct>] | power vm id=<n> off | power tick
+            let rest = cmd[5..].trim();
+            if let Some(args) = rest.strip_prefix("cap") {
+                let args = args.trim();
+                let cap = if args == "off" { Some(None) }
+                    else if let Some(v) = args.strip_prefix("watts=") { v.parse::<u64>().ok().filter(|&w| w != 0).map(|w| Some(w * 1000)) }
+                    else if let Some(v) = args.strip_prefix("mw=") { v.parse::<u64>().ok().filter(|&w| w != 0).map(Some) }
+                    else { None };
+                let cap = match cap { Some(c) => c, None => { let _ = system_table.stdout().write_str("usage: power cap watts=<n>|mw=<n>|off\r\n"); continue; } };
+                let msg = match crate::hv::power::set_cap(cap) {
+                    Ok(_) if cap.is_none() => "power: cap removed\r\n",
+                    Ok(true) => "power: cap set (RAPL PL1 programmed)\r\n",
+                    Ok(false) => "power: cap set (software enforcement only)\r\n",
+                    Err(e) => e,
+                };
+                let _ = system_table.stdout().write_str(msg);
+                let _ = crate::time::clock::init(system_table);
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("vm") {
+                let mut id: Option<u64> = None; let mut prio = None; let mut floor = crate::hv::power::DEFAULT_FLOOR_PCT; let mut off = false; let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("prio=") { match crate::hv::power::Prio::parse(v) { Some(p) => prio = Some(p), None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("floor=") { match v.parse::<u8>() { Ok(x) => floor = x, Err(_) => bad = true } }
+                    else if w == "off" { off = true; }
+                    else { bad = true; }
+                }
+                let id = match id { Some(i) if !bad && (off || prio.is_some()) => i, _ => {
+                    let _ = system_table.stdout().write_str("usage: power vm id=<n> prio=high|normal|low [floor=<pct>] | power vm id=<n> off\r\n");
+                    continue;
+                } };
+                let msg = if off {
+                    if crate::hv::power::clear_vm(id) { "power: budget removed\r\n" } else { "power: no budget for vm\r\n" }
+                } else {
+                    match crate::hv::power::set_vm(id, prio.unwrap_or(crate::hv::power::Prio::Normal), floor) { Ok(()) => "power: budget set\r\n", Err(e) => e }
+                };
+                let _ = system_table.stdout().write_str(msg);
+                continue;
+            }
+            if rest == "tick" {
+                let _ = crate::time::clock::init(system_table);
+                let msg = if crate::hv::power::tick(system_table, true).is_some() { "power: sampled\r\n" } else { "power: no sample (RAPL unavailable or first reading)\r\n" };
+                let _ = system_table.stdout().write_str(msg);
+                continue;
+            }
+            if !rest.is_empty() {
+                let _ = system_table.stdout().write_str("usage: power | power cap watts=<n>|mw=<n>|off | power vm id=<n> prio=high|normal|low [floor=<pct>] | power vm id=<n> off | power tick\r\n");
+                continue;
+            }
+            let s = crate::hv::power::status();
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 160]; let mut n = 0;
+            for &b in b"power: rapl=" { out[n] = b; n += 1; }
+            for &b in s.kind.name().as_bytes() { out[n] = b; n += 1; }
+            for &b in b" cap_mw=" { out[n] = b; n += 1; }
+            if s.cap_mw == 0 { for &b in b"off" { out[n] = b; n += 1; } } else { n += crate::util::format::u64_dec(s.cap_mw, &mut out[n..]); }
+            for &b in b" draw_mw=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.draw_mw, &mut out[n..]);
+            if let Some(tdp) = s.tdp_mw {
+                for &b in b" tdp_mw=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(tdp, &mut out[n..]);
+            }
+            if let Some(pl1) = s.pl1 {
+                for &b in b" pl1_mw=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(pl1.mw, &mut out[n..]);
+                let flags: &[u8] = if !pl1.enabled { b"(disabled)" } else if pl1.locked { b"(locked)" } else { b"" };
+                for &b in flags { out[n] = b; n += 1; }
+            }
+            let mode: &[u8] = if s.cap_mw == 0 { b"" } else if s.hw_limit { b" enforce=hw+sw" } else { b" enforce=sw" };
+            for &b in mode { out[n] = b; n += 1; }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            crate::hv::power::for_each(|b| {
+                let mut n = 0;
+                for &c in b"  vm " { out[n] = c; n += 1; }
+                n += crate::util::format::u64_dec(b.vm_id, &mut out[n..]);
+                for &c in b" prio=" { out[n] = c; n += 1; }
+                for &c in b.prio.name().as_bytes() { out[n] = c; n += 1; }
+                for &c in b" quota=" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_dec(b.quota_pct as u64, &mut out[n..]);
+                for &c in b"% floor=" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_dec(b.floor_pct as u64, &mut out[n..]);
+                for &c in b"% throttled_us=" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_dec(b.throttle_us, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            continue;
+        }
+        if cmd == "thermal" || cmd.starts_with("thermal ") {
+            // thermal | thermal on [trip=<C>] [hyst=<C>] [floor=<pct>] | thermal off | thermal tick
+            let rest = cmd[7..].trim();
+            if rest == "off" || rest == "on" || rest.starts_with("on ") {
+                let mut cfg = crate::hv::thermal::status().cfg; let mut bad = false;
+                cfg.enabled = rest != "off";
+                for w in rest.split_whitespace().skip(1) {
+                    if let Some(v) = w.strip_prefix("trip=") { match v.parse::<i32>() { Ok(x) => cfg.trip_c = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("hyst=") { match v.parse::<i32>() { Ok(x) => cfg.hysteresis_c = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("floor=") { match v.trim_end_matches('%').parse::<u8>() { Ok(x) => cfg.floor_pct = x, Err(_) => bad = true } }
+                    else { bad = true; }
+                }
+                if bad || (!cfg.enabled && rest != "off") {
+                    let _ = system_table.stdout().write_str("usage: thermal on [trip=<C>] [hyst=<C>] [floor=<pct>] | thermal off\r\n");
+                    continue;
+                }
+                let _ = crate::time::clock::init(system_table);
+                let msg = match crate::hv::thermal::configure(cfg) {
+                    Ok(()) if cfg.enabled => "thermal: protection on\r\n",
+                    Ok(()) => "thermal: protection off, quotas restored\r\n",
+                    Err(e) => e,
+                };
+                let _ = system_table.stdout().write_str(msg);
+                continue;
+            }
+            if rest == "tick" {
+                let _ = crate::time::clock::init(system_table);
+                let msg = if crate::hv::thermal::tick(system_table, true).is_some() { "thermal: sampled\r\n" } else { "thermal: no reading\r\n" };
+                let _ = system_table.stdout().write_str(msg);
+                continue;
+            }
+            if !rest.is_empty() {
+                let _ = system_table.stdout().write_str("usage: thermal | thermal on [trip=<C>] [hyst=<C>] [floor=<pct>] | thermal off | thermal tick\r\n");
+                continue;
+            }
+            let s = crate::hv::thermal::status();
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 128]; let mut n = 0;
+            for &b in b"thermal: sensor=" { out[n] = b; n += 1; }
+            for &b in s.kind.name().as_bytes() { out[n] = b; n += 1; }
+            if let Some(tj) = s.tjmax {
+                for &b in b" tjmax_c=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(tj as u32, &mut out[n..]);
+            }
+            for &b in if s.cfg.enabled { &b" protect=on trip_c="[..] } else { &b" protect=off trip_c="[..] } { out[n] = b; n += 1; }
+            n += crate::util::format::i64_dec(s.cfg.trip_c as i64, &mut out[n..]);
+            for &b in b" hyst_c=" { out[n] = b; n += 1; }
+            n += crate::util::format::i64_dec(s.cfg.hysteresis_c as i64, &mut out[n..]);
+            for &b in b" floor=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(s.cfg.floor_pct as u32, &mut out[n..]);
+            out[n] = b'%'; n += 1;
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            crate::hv::thermal::for_each(|p| {
+                let mut n = 0;
+                for &b in b"  package " { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(p.id, &mut out[n..]);
+                for &b in b" temp_c=" { out[n] = b; n += 1; }
+                match p.temp_c { Some(t) => n += crate::util::format::i64_dec(t as i64, &mut out[n..]), None => for &b in b"?" { out[n] = b; n += 1; } }
+                if let Some(m) = p.max_c {
+                    for &b in b" max_c=" { out[n] = b; n += 1; }
+                    n += crate::util::format::i64_dec(m as i64, &mut out[n..]);
+                }
+                for &b in b" quota=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(p.quota_pct as u32, &mut out[n..]);
+                for &b in b"% throttles=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(p.throttles, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            continue;
+        }
+        if cmd == "dvfs" || cmd.starts_with("dvfs ") {
+            // dvfs | dvfs governor performance|balanced|powersave|off | dvfs tick
+            let rest = cmd[4..].trim();
+            if let Some(arg) = rest.strip_prefix("governor") {
+                let Some(g) = crate::hv::dvfs::Governor::parse(arg.trim()) else {
+                    let _ = system_table.stdout().write_str("usage: dvfs governor performance|balanced|powersave|off\r\n");
+                    continue;
+                };
+                let _ = crate::time::clock::init(system_table);
+                let msg = match crate::hv::dvfs::set_governor(g) { Ok(()) => "dvfs: governor set\r\n", Err(e) => e };
+                let _ = system_table.stdout().write_str(msg);
+                continue;
+            }
+            if rest == "tick" {
+                let _ = crate::time::clock::init(system_table);
+                let k = crate::hv::dvfs::tick(system_table, true);
+                let mut out = [0u8; 48]; let mut n = 0;
+                for &b in b"dvfs: changed cpus=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(k as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if !rest.is_empty() {
+                let _ = system_table.stdout().write_str("usage: dvfs | dvfs governor performance|balanced|powersave|off | dvfs tick\r\n");
+                continue;
+            }
+            let s = crate::hv::dvfs::status();
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 160]; let mut n = 0;
+            for &b in b"dvfs: governor=" { out[n] = b; n += 1; }
+            for &b in s.governor.name().as_bytes() { out[n] = b; n += 1; }
+            for &b in b" pstate=" { out[n] = b; n += 1; }
+            match s.caps {
+                None => for &b in b"none" { out[n] = b; n += 1; },
+                Some(c) => {
+                    for &b in c.kind.name().as_bytes() { out[n] = b; n += 1; }
+                    for &b in b" lowest=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(c.lowest as u32, &mut out[n..]);
+                    for &b in b" highest=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(c.highest as u32, &mut out[n..]);
+                    if c.turbo != c.highest {
+                        for &b in b" turbo=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(c.turbo as u32, &mut out[n..]);
+                    }
+                    if c.epp { for &b in b" epp" { out[n] = b; n += 1; } }
+                }
+            }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            crate::hv::dvfs::for_each(|cpu, c| {
+                let mut n = 0;
+                for &b in b"  cpu " { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(cpu as u64, &mut out[n..]);
+                for &b in b" util=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(c.util_pct as u32, &mut out[n..]);
+                for &b in b"% level=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(c.level as u32, &mut out[n..]);
+                for &b in b" state=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(c.state as u32, &mut out[n..]);
+                if let Some(mhz) = s.caps.and_then(|k| k.mhz(c.state)) {
+                    for &b in b" mhz=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(mhz, &mut out[n..]);
+                }
+                if let Some(r) = c.readback {
+                    for &b in b" readback=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(r as u32, &mut out[n..]);
+                }
+                for &b in b" transitions=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(c.transitions, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            continue;
+        }
+        if cmd == "carbon" || cmd.starts_with("carbon ") {
+            // carbon | carbon schedule [<g0>,..,<g23>|off] | carbon sample g=<n> [valid=<min>]|off
+            // carbon policy [threshold=<g>] [max-defer=<min>] [vcpu-w=<n>] [mig-wh=<n>]
+            // carbon defer start|migrate id=<n> [hours=<n>] | carbon cancel id=<n> | carbon history | carbon tick
+            let rest = cmd[6..].trim();
+            if let Some(args) = rest.strip_prefix("schedule") {
+                let args = args.trim();
+                if args == "off" {
+                    let _ = crate::hv::carbon::set_schedule(None);
+                    let _ = system_table.stdout().write_str("carbon: schedule removed\r\n");
+                    continue;
+                }
+                if !args.is_empty() {
+                    let mut hours = [0u16; 24]; let mut k = 0; let mut bad = false;
+                    for v in args.split(',') {
+                        match v.trim().parse::<u16>() { Ok(g) if k < 24 => { hours[k] = g; k += 1; } _ => { bad = true; break; } }
+                    }
+                    if bad || k != 24 { let _ = system_table.stdout().write_str("usage: carbon schedule <g0>,<g1>,..,<g23> (gCO2e/kWh per UTC hour)|off\r\n"); continue; }
+                    let msg = match crate::hv::carbon::set_schedule(Some(hours)) { Ok(()) => "carbon: schedule set\r\n", Err(e) => e };
+                    let _ = system_table.stdout().write_str(msg);
+                    continue;
+                }
+                let schedule = crate::hv::carbon::status(system_table).schedule;
+                let stdout = system_table.stdout();
+                match schedule {
+                    None => { let _ = stdout.write_str("carbon: no schedule\r\n"); }
+                    Some(h) => {
+                        let mut out = [0u8; 160]; let mut n = 0;
+                        for &b in b"carbon: schedule utc=" { out[n] = b; n += 1; }
+                        for (i, &g) in h.iter().enumerate() {
+                            if i != 0 { out[n] = b','; n += 1; }
+                            n += crate::util::format::u64_dec(g as u64, &mut out[n..]);
+                        }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("sample") {
+                let args = args.trim();
+                if args == "off" { crate::hv::carbon::clear_sample(); let _ = system_table.stdout().write_str("carbon: sample cleared\r\n"); continue; }
+                let mut g = None; let mut valid = crate::hv::carbon::DEFAULT_SAMPLE_MIN; let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("g=") { match v.parse::<u32>() { Ok(x) => g = Some(x), Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("valid=") { match v.parse::<u32>() { Ok(x) => valid = x, Err(_) => bad = true } }
+                    else { bad = true; }
+                }
+                let Some(g) = g.filter(|_| !bad) else { let _ = system_table.stdout().write_str("usage: carbon sample g=<gCO2e/kWh> [valid=<min>]|off\r\n"); continue; };
+                let msg = match crate::hv::carbon::push_sample(system_table, g, valid) { Ok(()) => "carbon: sample recorded\r\n", Err(e) => e };
+                let _ = system_table.stdout().write_str(msg);
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("policy") {
+                let mut p = crate::hv::carbon::policy(); let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("threshold=") { match v.parse::<u32>() { Ok(x) => p.threshold_g = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("max-defer=") { match v.parse::<u32>() { Ok(x) => p.max_defer_min = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("vcpu-w=") { match v.parse::<u32>() { Ok(x) => p.vcpu_w = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("mig-wh=") { match v.parse::<u32>() { Ok(x) => p.mig_wh_per_gib = x, Err(_) => bad = true } }
+                    else { bad = true; }
+                }
+                if bad { let _ = system_table.stdout().write_str("usage: carbon policy [threshold=<g>] [max-defer=<min>] [vcpu-w=<n>] [mig-wh=<n>]\r\n"); continue; }
+                if let Err(e) = crate::hv::carbon::set_policy(p) { let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); continue; }
+                let mut out = [0u8; 128]; let mut n = 0;
+                for &b in b"carbon: threshold_g=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(p.threshold_g, &mut out[n..]);
+                for &b in b" max_defer_min=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(p.max_defer_min, &mut out[n..]);
+                for &b in b" vcpu_w=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(p.vcpu_w, &mut out[n..]);
+                for &b in b" mig_wh_per_gib=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(p.mig_wh_per_gib, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("defer ") {
+                let args = args.trim();
+                let (kind, args) = if let Some(a) = args.strip_prefix("start") { (crate::hv::carbon::Kind::Start, a) }
+                    else if let Some(a) = args.strip_prefix("migrate") { (crate::hv::carbon::Kind::Migrate, a) }
+                    else { let _ = system_table.stdout().write_str("usage: carbon defer start id=<n> [hours=<n>] | carbon defer migrate id=<n>\r\n"); continue; };
+                let mut id = None; let mut hours = crate::hv::carbon::DEFAULT_BATCH_HOURS; let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("hours=") { match v.parse::<u32>() { Ok(x) if kind == crate::hv::carbon::Kind::Start => hours = x, _ => bad = true } }
+                    else { bad = true; }
+                }
+                let Some(id) = id.filter(|_| !bad) else { let _ = system_table.stdout().write_str("usage: carbon defer start id=<n> [hours=<n>] | carbon defer migrate id=<n>\r\n"); continue; };
+                let _ = crate::time::clock::init(system_table);
+                match crate::hv::carbon::submit(system_table, id, kind, hours) {
+                    Ok(Some(deadline)) => {
+                        let mut out = [0u8; 96]; let mut n = 0;
+                        for &b in b"carbon: queued until low-carbon window, deadline=" { out[n] = b; n += 1; }
+                        n += crate::util::format::i64_dec(deadline, &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Ok(None) => {
+                        // Already low-carbon (or no signal): run it now.
+                        let ok = match kind {
+                            crate::hv::carbon::Kind::Start => match crate::hv::run::start(system_table, id) {
+                                Ok(_) => true,
+                                Err(e) => { let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); continue; }
+                            },
+                            crate::hv::carbon::Kind::Migrate => crate::migrate::start_tracking_by_id(system_table, id),
+                        };
+                        let _ = system_table.stdout().write_str(if ok { "carbon: low-carbon now, started\r\n" } else { "carbon: low-carbon now, start failed\r\n" });
+                    }
+                    Err(e) => { let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("cancel") {
+                let Some(id) = args.trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok()) else {
+                    let _ = system_table.stdout().write_str("usage: carbon cancel id=<n>\r\n"); continue;
+                };
+                let msg = if crate::hv::carbon::cancel(id, None) != 0 { "carbon: cancelled\r\n" } else { "carbon: nothing queued for vm\r\n" };
+                let _ = system_table.stdout().write_str(msg);
+                continue;
+            }
+            if rest == "tick" {
+                let _ = crate::time::clock::init(system_table);
+                let msg = if crate::hv::carbon::tick(system_table, true).is_some() { "carbon: released one job\r\n" } else { "carbon: nothing released\r\n" };
+                let _ = system_table.stdout().write_str(msg);
+                continue;
+            }
+            if rest == "history" {
+                let stdout = system_table.stdout();
+                let mut out = [0u8; 160]; let mut any = false;
+                crate::hv::carbon::history(|d| {
+                    any = true;
+                    let mut n = 0;
+                    for &b in b"  vm " { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(d.job.vm_id, &mut out[n..]);
+                    out[n] = b' '; n += 1;
+                    for &b in d.job.kind.name().as_bytes() { out[n] = b; n += 1; }
+                    for &b in if d.forced { &b" deadline" [..] } else { &b" window" [..] } { out[n] = b; n += 1; }
+                    for &b in b" at=" { out[n] = b; n += 1; }
+                    n += crate::util::format::i64_dec(d.run_unix, &mut out[n..]);
+                    for &b in b" g=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(d.run_g, &mut out[n..]);
+                    for &b in b" submit_g=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(d.job.submit_g, &mut out[n..]);
+                    for &b in b" energy_wh=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(d.job.energy_wh, &mut out[n..]);
+                    for &b in b" avoided_mg=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(d.avoided_mg, &mut out[n..]);
+                    if !d.ok { for &b in b" failed" { out[n] = b; n += 1; } }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                if !any { let _ = stdout.write_str("carbon: nothing released yet\r\n"); }
+                continue;
+            }
+            if !rest.is_empty() {
+                let _ = system_table.stdout().write_str("usage: carbon | carbon schedule [<g0>,..,<g23>|off] | carbon sample g=<n> [valid=<min>]|off | carbon policy [threshold=<g>] [max-defer=<min>] [vcpu-w=<n>] [mig-wh=<n>] | carbon defer start id=<n> [hours=<n>]|migrate id=<n> | carbon cancel id=<n> | carbon history | carbon tick\r\n");
+                continue;
+            }
+            let s = crate::hv::carbon::status(system_table);
+            let now = crate::hv::vtime::rtc::host_unix(system_table);
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 160]; let mut n = 0;
+            for &b in b"carbon: intensity_g=" { out[n] = b; n += 1; }
+            match s.intensity_g { Some(g) => n += crate::util::format::u32_dec(g, &mut out[n..]), None => for &b in b"none" { out[n] = b; n += 1; } }
+            for &b in b" source=" { out[n] = b; n += 1; }
+            for &b in s.source.name().as_bytes() { out[n] = b; n += 1; }
+            for &b in b" threshold_g=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(s.policy.threshold_g, &mut out[n..]);
+            for &b in b" queued=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(s.queued as u64, &mut out[n..]);
+            // Avoided emissions in grams with three decimals
+            let mg = crate::hv::carbon::avoided_mg();
+            for &b in b" avoided_g=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(mg / 1000, &mut out[n..]);
+            let frac = mg % 1000;
+            out[n] = b'.'; out[n + 1] = b'0' + (frac / 100) as u8; out[n + 2] = b'0' + (frac / 10 % 10) as u8; out[n + 3] = b'0' + (frac % 10) as u8; n += 4;
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            crate::hv::carbon::for_each(|j| {
+                let mut n = 0;
+                for &b in b"  vm " { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(j.vm_id, &mut out[n..]);
+                out[n] = b' '; n += 1;
+                for &b in j.kind.name().as_bytes() { out[n] = b; n += 1; }
+                if j.kind == crate::hv::carbon::Kind::Start {
+                    for &b in b" hours=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(j.hours, &mut out[n..]);
+                }
+                for &b in b" energy_wh=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(j.energy_wh, &mut out[n..]);
+                for &b in b" submit_g=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(j.submit_g, &mut out[n..]);
+                for &b in b" deadline_in_min=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec((j.deadline_unix - now).max(0) as u64 / 60, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            continue;
+        }
+        if cmd.starts_with("sched ") {
+            // sched bg budget pct=<n> [window=<us>] | sched bg enable|disable <name> | sched bg run | sched rt mask=<hex>
+            // sched vm id=<n> [weight=<n>] [cap=<pct>]
+            let rest = cmd[6..].trim();
+            if let Some(args) = rest.strip_prefix("vm ") {
+                let mut id = None; let mut weight = None; let mut cap = None; let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("weight=") { match v.parse::<u32>() { Ok(x) => weight = Some(x), Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("cap=") { match v.trim_end_matches('%').parse::<u32>() { Ok(x) => cap = Some(x), Err(_) => bad = true } }
+                    else { bad = true; }
+                }
+                let Some(id) = id.filter(|_| !bad) else { let _ = system_table.stdout().write_str("usage: sched vm id=<n> [weight=<n>] [cap=<pct>]\r\n"); continue; };
+                if crate::hv::vm::find_vm(id).is_none() { let _ = system_table.stdout().write_str("sched: no such vm\r\n"); continue; }
+                let cur = crate::hv::sched::credit::params(id);
+                let p = crate::hv::sched::credit::Params { weight: weight.unwrap_or(cur.weight), cap: cap.unwrap_or(cur.cap) };
+                if weight.is_some() || cap.is_some() {
+                    if let Err(e) = crate::hv::sched::credit::set_params(id, p) {
+                        let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n");
+                        continue;
+                    }
+                }
+                let mut out = [0u8; 96]; let mut n = 0;
+                for &b in b"sched: vm " { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(id, &mut out[n..]);
+                for &b in b" weight=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(p.weight, &mut out[n..]);
+                for &b in b" cap=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(p.cap, &mut out[n..]);
+                out[n] = b'%'; n += 1;
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("bg budget") {
+                let cur = crate::hv::sched::background::budget();
+                let mut pct = cur.pct; let mut win = cur.window_us;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("pct=") { pct = v.parse::<u32>().unwrap_or(pct); continue; }
+                    if let Some(v) = w.strip_prefix("window=") { win = v.parse::<u64>().unwrap_or(win); continue; }
+                }
+                crate::hv::sched::background::set_budget(pct, win);
+                let _ = system_table.stdout().write_str("sched: budget updated\r\n");
+                continue;
+            }
+            if let Some(name) = rest.strip_prefix("bg enable ") {
+                let ok = crate::hv::sched::background::set_enabled(name.trim(), true);
+                let _ = system_table.stdout().write_str(if ok { "sched: enabled\r\n" } else { "sched: no such task\r\n" });
+                continue;
+            }
+            if let Some(name) = rest.strip_prefix("bg disable ") {
+                let ok = crate::hv::sched::background::set_enabled(name.trim(), false);
+                let _ = system_table.stdout().write_str(if ok { "sched: disabled\r\n" } else { "sched: no such task\r\n" });
+                continue;
+            }
+            if rest.eq_ignore_ascii_case("bg run") {
+                let steps = crate::hv::sched::background::run();
+                let mut out = [0u8; 48]; let mut n = 0;
+                for &b in b"sched: bg steps=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(steps, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if let Some(v) = rest.strip_prefix("rt mask=") {
+                match u64::from_str_radix(v.trim().trim_start_matches("0x"), 16) {
+                    Ok(m) => { crate::hv::sched::set_rt_reserved(m); let _ = system_table.stdout().write_str("sched: rt mask updated\r\n"); }
+                    Err(_) => { let _ = system_table.stdout().write_str("usage: sched rt mask=<hex>\r\n"); }
+                }
+                continue;
+            }
+            let _ = system_table.stdout().write_str("usage: sched | sched vm id=<n> [weight=<n>] [cap=<pct>] | sched bg budget pct=<n> [window=<us>] | sched bg enable|disable <name> | sched bg run | sched rt mask=<hex>\r\n");
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("tpm") || cmd.eq_ignore_ascii_case("tpm info") {
+            crate::tpm::report(system_table);
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("tpm init") {
+            let r = crate::tpm::init(system_table);
+            match r {
+                Ok(_) => crate::tpm::report(system_table),
+                Err(e) => { let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("tpm log") || cmd.eq_ignore_ascii_case("tpm log raw") {
+            // The event log is kept with or without a TPM
+            let stdout = system_table.stdout();
+            let st = crate::tpm::eventlog::stats();
+            let mut out = [0u8; 96]; let mut n = 0;
+            for &b in b"tpm: log events=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(st.events as u64, &mut out[n..]);
+            for &b in b" bytes=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(crate::tpm::eventlog::len() as u64, &mut out[n..]);
+            for &b in b" unextended=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(st.unextended as u64, &mut out[n..]);
+            for &b in b" dropped=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(st.dropped as u64, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            if cmd.len() > 7 {
+                crate::tpm::eventlog::raw(32, |off, chunk| {
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    n += crate::util::format::u64_hex(off as u64, &mut out[n..]);
+                    out[n] = b' '; n += 1;
+                    n += crate::tpm::attest::hex(chunk, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                continue;
+            }
+            let mut i = 0u64;
+            crate::tpm::eventlog::for_each(|e| {
+                let mut out = [0u8; 192]; let mut n = 0;
+                for &b in b"  #" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(i, &mut out[n..]);
+                for &b in b" pcr=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(e.pcr as u64, &mut out[n..]);
+                for &b in b" type=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(e.event_type as u64, &mut out[n..]);
+                for &b in b" sha256=" { out[n] = b; n += 1; }
+                n += crate::tpm::attest::hex(&e.digest, &mut out[n..]);
+                for &b in b" " { out[n] = b; n += 1; }
+                for &x in e.data.iter().take(48) { out[n] = if (0x20..=0x7E).contains(&x) { x } else { b'.' }; n += 1; }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                i += 1;
+            });
+            continue;
+        }
+        if cmd.starts_with("tpm ") {
+            // All other tpm subcommands require an initialized device
+            if let Err(e) = crate::tpm::init(system_table) {
+                let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n");
+                continue;
+            }
+            let rest = cmd[4..].trim();
+            let mut idx: Option<u32> = None; let mut size: Option<u16> = None; let mut off: u16 = 0;
+            let mut pcrs: u32 = 0; let mut text: &str = "";
+            let mut words = rest.split_whitespace();
+            let op = words.next().unwrap_or("");
+            let sub = if op.eq_ignore_ascii_case("pcr") || op.eq_ignore_ascii_case("nv") { words.next().unwrap_or("") } else { "" };
+            let mut pos: [&str; 2] = [""; 2]; let mut npos = 0usize;
+            for w in words {
+                if let Some(v) = w.strip_prefix("idx=") { idx = u32::from_str_radix(v.trim_start_matches("0x"), 16).ok(); continue; }
+                if let Some(v) = w.strip_prefix("size=") { size = v.parse::<u16>().ok(); continue; }
+                if let Some(v) = w.strip_prefix("len=") { size = v.parse::<u16>().ok(); continue; }
+                if let Some(v) = w.strip_prefix("off=") { off = v.parse::<u16>().unwrap_or(0); continue; }
+                if let Some(v) = w.strip_prefix("pcrs=") { pcrs = u32::from_str_radix(v.trim_start_matches("0x"), 16).unwrap_or(0); continue; }
+                if npos < pos.len() { pos[npos] = w; npos += 1; }
+            }
+            // Free text payload is everything after the last key=value/positional prefix
+            if let Some(p) = rest.find("data=") { text = &rest[p + 5..]; }
+            let stdout = system_table.stdout();
+            if op.eq_ignore_ascii_case("pcr") && sub.eq_ignore_ascii_case("read") {
+                let pcr = pos[0].parse::<u32>().unwrap_or(0);
+                match crate::tpm::cmd::pcr_read(pcr) {
+                    Ok(d) => {
+                        let mut out = [0u8; 96]; let mut n = 0;
+                        for &b in b"tpm: pcr" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(pcr, &mut out[n..]);
+                        for &b in b" sha256=" { out[n] = b; n += 1; }
+                        for &x in d.iter() { const HX: &[u8; 16] = b"0123456789abcdef"; out[n] = HX[(x >> 4) as usize]; out[n + 1] = HX[(x & 0xF) as usize]; n += 2; }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if op.eq_ignore_ascii_case("pcr") && sub.eq_ignore_ascii_case("extend") {
+                // digest given as up to 64 hex digits, right-aligned and zero-padded
+                let pcr = pos[0].parse::<u32>().unwrap_or(0);
+                let hex = pos[1].as_bytes();
+                if hex.is_empty() || hex.len() > 64 { let _ = stdout.write_str("usage: tpm pcr extend <pcr> <hex digest>\r\n"); continue; }
+                let mut d = [0u8; crate::tpm::cmd::DIGEST_LEN];
+                let mut ok = true;
+                for (i, &c) in hex.iter().rev().enumerate() {
+                    let v = match c { b'0'..=b'9' => c - b'0', b'a'..=b'f' => 10 + c - b'a', b'A'..=b'F' => 10 + c - b'A', _ => { ok = false; 0 } };
+                    let bi = d.len() - 1 - i / 2;
+                    d[bi] |= if i % 2 == 0 { v } else { v << 4 };
+                }
+                if !ok { let _ = stdout.write_str("usage: tpm pcr extend <pcr> <hex digest>\r\n"); continue; }
+                match crate::tpm::cmd::pcr_extend(pcr, &d) {
+                    Ok(()) => { crate::diag::audit::record(crate::diag::audit::AuditKind::TpmPcrExtend(pcr)); let _ = stdout.write_str("tpm: pcr extended\r\n"); }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if op.eq_ignore_ascii_case("nv") {
+                let index = match idx { Some(v) => v, None => { let _ = stdout.write_str("usage: tpm nv define|undefine|write|read idx=<hex> ...\r\n"); continue; } };
+                let mut out = [0u8; 320]; let mut n = 0;
+                let r: Result<(), &'static str> = if sub.eq_ignore_ascii_case("define") {
+                    crate::tpm::cmd::nv_define(index, size.unwrap_or(64), crate::tpm::cmd::NV_ATTR_DEFAULT)
+                } else if sub.eq_ignore_ascii_case("undefine") {
+                    crate::tpm::cmd::nv_undefine(index)
+                } else if sub.eq_ignore_ascii_case("write") {
+                    crate::tpm::cmd::nv_write(index, off, text.as_bytes())
+                } else if sub.eq_ignore_ascii_case("read") {
+                    let want = core::cmp::min(size.unwrap_or(32) as usize, 256);
+                    let mut data = [0u8; 256];
+                    match crate::tpm::cmd::nv_read(index, off, &mut data[..want]) {
+                        Ok(got) => {
+                            for &b in b"tpm: nv data=" { out[n] = b; n += 1; }
+                            for &x in &data[..got] { out[n] = if x >= 0x20 && x <= 0x7E { x } else { b'.' }; n += 1; }
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    }
+                } else { Err("usage: tpm nv define idx=<hex> [size=<n>] | undefine idx=<hex> | write idx=<hex> [off=<n>] data=<text> | read idx=<hex> [len=<n>] [off=<n>]") };
+                match r {
+                    Ok(()) => {
+                        if n == 0 { for &b in b"tpm: nv ok" { out[n] = b; n += 1; } }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if op.eq_ignore_ascii_case("seal") {
+                let r = crate::tpm::seal_store(system_table, text.as_bytes(), pcrs);
+                let stdout = system_table.stdout();
+                match r {
+                    Ok(()) => { let _ = stdout.write_str("tpm: sealed (blob saved)\r\n"); }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if op.eq_ignore_ascii_case("unseal") {
+                let mut secret = [0u8; crate::tpm::cmd::SEAL_MAX];
+                let r = crate::tpm::unseal_load(system_table, &mut secret);
+                let stdout = system_table.stdout();
+                match r {
+                    Ok(len) => {
+                        let mut out = [0u8; 160]; let mut n = 0;
+                        for &b in b"tpm: unsealed=" { out[n] = b; n += 1; }
+                        for &x in &secret[..len] { out[n] = if x >= 0x20 && x <= 0x7E { x } else { b'.' }; n += 1; }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if op.eq_ignore_ascii_case("log") && pos[0].eq_ignore_ascii_case("verify") {
+                let mask = if pcrs != 0 { pcrs } else { crate::tpm::attest::DEFAULT_QUOTE_MASK };
+                let bad = crate::tpm::attest::verify_log(mask);
+                let mut out = [0u8; 64]; let mut n = 0;
+                for &b in if bad == 0 { b"tpm: log matches pcrs=0x".as_slice() } else { b"tpm: log MISMATCH pcrs=0x".as_slice() } { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(if bad == 0 { mask } else { bad } as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if op.eq_ignore_ascii_case("quote") {
+                // nonce=<hex> is bound into the signed structure
+                let mut nonce = [0u8; crate::tpm::cmd::NONCE_MAX]; let mut nlen = 0usize; let mut ok = true;
+                if let Some(h) = rest.split_whitespace().find_map(|w| w.strip_prefix("nonce=")) {
+                    let h = h.as_bytes();
+                    if h.len() % 2 != 0 || h.len() / 2 > nonce.len() { ok = false; }
+                    for pair in h.chunks(2) {
+                        let v = core::str::from_utf8(pair).ok().and_then(|p| u8::from_str_radix(p, 16).ok());
+                        match v { Some(v) if ok => { nonce[nlen] = v; nlen += 1; } _ => ok = false }
+                    }
+                }
+                if !ok { let _ = stdout.write_str("usage: tpm quote [nonce=<hex, up to 32 bytes>] [pcrs=<hex>]\r\n"); continue; }
+                let r = crate::tpm::attest::quote(system_table, &nonce[..nlen], pcrs);
+                let stdout = system_table.stdout();
+                match r {
+                    Ok(q) => {
+                        let mut out = [0u8; 2 * crate::tpm::cmd::ATTEST_MAX + 32]; let mut n = 0;
+                        for &b in b"tpm: quote attest=" { out[n] = b; n += 1; }
+                        n += crate::tpm::attest::hex(&q.attest[..q.attest_len], &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                        for (label, a, b) in [(&b"tpm: quote sig_r="[..], &q.sig_r, &q.sig_s), (b"tpm: quote ak_x=", &q.ak_x, &q.ak_y)] {
+                            let mut out = [0u8; 192]; let mut n = 0;
+                            for &c in label { out[n] = c; n += 1; }
+                            n += crate::tpm::attest::hex(a, &mut out[n..]);
+                            for &c in if label.ends_with(b"sig_r=") { b" sig_s=".as_slice() } else { b" ak_y=".as_slice() } { out[n] = c; n += 1; }
+                            n += crate::tpm::attest::hex(b, &mut out[n..]);
+                            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                        }
+                        // What the PCRs should replay to: the boot-time file and image digests
+                        if let Some(m) = crate::tpm::attest::boot_measurement() {
+                            let mut out = [0u8; 192]; let mut n = 0;
+                            for &c in b"tpm: quote boot image=" { out[n] = c; n += 1; }
+                            n += crate::tpm::attest::hex(&m.image, &mut out[n..]);
+                            if let Some(f) = m.file {
+                                for &c in b" file=" { out[n] = c; n += 1; }
+                                n += crate::tpm::attest::hex(&f, &mut out[n..]);
+                            }
+                            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                        }
+                    }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if op.eq_ignore_ascii_case("rc") {
+                let mut out = [0u8; 48]; let mut n = 0;
+                for &b in b"tpm: last_rc=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(crate::tpm::cmd::last_rc() as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            let _ = stdout.write_str("usage: tpm [info|init|rc] | tpm pcr read <n> | tpm pcr extend <n> <hex> | tpm nv ... | tpm seal [pcrs=<hex>] data=<text> | tpm unseal | tpm log [raw|verify [pcrs=<hex>]] | tpm quote [nonce=<hex>] [pcrs=<hex>]\r\n");
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("vm") {
+            // Create a tiny VM object and print its id, try start (VMX smoke paths)
+            let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 64 << 20, vcpu_count: 1, ..Default::default() });
+            let _ = crate::hv::vm::register_vm(&vm);
+            let mut vcpu = crate::hv::vcpu::Vcpu::new(0);
+            vcpu.start();
+            if let Err(e) = vm.start(system_table) {
+                let stdout = system_table.stdout();
+                let _ = stdout.write_str(e);
+                let _ = stdout.write_str("\r\n");
+            }
             let stdout = system_table.stdout();
             let mut out = [0u8; 96]; let mut n = 0;
             for &b in b"VM created id=" { out[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(vm.id.0 as u32, &mut out[n..]);
+            n += crate::util::format::u32_dec(vm.id.0 as u32, &mut out[n..]);
             for &b in b" vcpu0=" { out[n] = b; n += 1; }
             let s = match vcpu.state { crate::hv::vcpu::VcpuState::Created => b"created", crate::hv::vcpu::VcpuState::Running => b"running", crate::hv::vcpu::VcpuState::Stopped => b"stopped" };
             for &b in s { out[n] = b; n += 1; }
             out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
             let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
-            vm.stop();
-            vm.destroy();
+            vm.stop();
+            vm.destroy();
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("vm list") {
+            let stdout = system_table.stdout();
+            if crate::hv::vm::vm_count() == 0 { let _ = stdout.write_str("vm: none registered\r\n"); continue; }
+            crate::hv::vm::list_vms(|info| {
+                let mut out = [0u8; 192]; let mut n = 0;
+                for &b in b"vm: id=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(info.id, &mut out[n..]);
+                for &b in b" name=" { out[n] = b; n += 1; }
+                for &b in info.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" state=" { out[n] = b; n += 1; }
+                for &b in info.state.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" vcpus=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(info.vcpus, &mut out[n..]);
+                for &b in b" mem=" { out[n] = b; n += 1; }
+                n += crate::util::format::size(info.memory_bytes, &mut out[n..]);
+                for &b in b" vendor=" { out[n] = b; n += 1; }
+                let v: &[u8] = match info.vendor { crate::hv::vm::HvVendor::Intel => b"intel", crate::hv::vm::HvVendor::Amd => b"amd", crate::hv::vm::HvVendor::Unknown => b"unknown" };
+                for &b in v { out[n] = b; n += 1; }
+                for &b in b" pml4=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(info.pml4_phys, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            continue;
+        }
+        if cmd.starts_with("vm info ") {
+            // vm info id=<n>|name=<s>: registry entry and current resource use
+            let stdout = system_table.stdout();
+            let Some(info) = crate::hv::vm::select(cmd[8..].trim()) else { let _ = stdout.write_str("vm info: no such vm (id=<n>|name=<s>)\r\n"); continue; };
+            let u = crate::hv::vm::usage(info.id).unwrap_or_default();
+            let now = crate::time::clock::now_ms();
+            let mut out = [0u8; 160]; let mut n = 0;
+            for &b in b"vm id=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(info.id, &mut out[n..]);
+            for &b in b" name=" { out[n] = b; n += 1; }
+            for &b in info.name().as_bytes() { out[n] = b; n += 1; }
+            for &b in b" state=" { out[n] = b; n += 1; }
+            for &b in info.state.name().as_bytes() { out[n] = b; n += 1; }
+            for &b in b" for=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(now.saturating_sub(info.since_ms), &mut out[n..]);
+            for &b in b"ms age=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(now.saturating_sub(info.created_ms), &mut out[n..]);
+            for &b in b"ms starts=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(info.starts, &mut out[n..]);
+            for &b in b" run=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(u.run_ms, &mut out[n..]);
+            for &b in b"ms\r\n" { out[n] = b; n += 1; }
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            n = 0;
+            for &b in b"  cpu: vcpus=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(info.vcpus, &mut out[n..]);
+            for &b in b" live=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(u.vcpus_live, &mut out[n..]);
+            for &b in b" exits=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(u.exits, &mut out[n..]);
+            if info.affinity == 0 { for &b in b" cpus=any" { out[n] = b; n += 1; } }
+            else { for &b in b" cpus=0x" { out[n] = b; n += 1; } n += crate::util::format::u64_hex(info.affinity, &mut out[n..]); }
+            for &b in b"\r\n" { out[n] = b; n += 1; }
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            n = 0;
+            for &b in b"  mem: size=" { out[n] = b; n += 1; }
+            n += crate::util::format::size(info.memory_bytes, &mut out[n..]);
+            for &b in b" reserved=" { out[n] = b; n += 1; }
+            n += crate::util::format::size(u.mem_reserved, &mut out[n..]);
+            for &b in b" backed=" { out[n] = b; n += 1; }
+            n += crate::util::format::size(u.mem_backed, &mut out[n..]);
+            for &b in b" pml4=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(info.pml4_phys, &mut out[n..]);
+            for &b in b"\r\n" { out[n] = b; n += 1; }
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            n = 0;
+            for &b in b"  admission:" { out[n] = b; n += 1; }
+            match u.admitted {
+                Some(r) => {
+                    for &b in b" vcpus=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(r.vcpus, &mut out[n..]);
+                    for &b in b" mem=" { out[n] = b; n += 1; }
+                    n += crate::util::format::size(r.memory_bytes, &mut out[n..]);
+                    for &b in b" huge=" { out[n] = b; n += 1; }
+                    n += crate::util::format::size(r.hugepage_bytes, &mut out[n..]);
+                    for &b in b" devices=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(r.devices, &mut out[n..]);
+                }
+                None => for &b in b" none" { out[n] = b; n += 1; },
+            }
+            for &b in b"\r\n" { out[n] = b; n += 1; }
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            if let Some(p) = crate::cluster::placement::record(info.id) {
+                n = 0;
+                for &b in b"  placement: by node=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(p.origin, &mut out[n..]);
+                for &b in b" score=" { out[n] = b; n += 1; }
+                match p.score { Some(v) => n += crate::util::format::u64_dec(v as u64, &mut out[n..]), None => for &b in b"unfit" { out[n] = b; n += 1; } }
+                for &b in b" origin_score=" { out[n] = b; n += 1; }
+                match p.origin_score { Some(v) => n += crate::util::format::u64_dec(v as u64, &mut out[n..]), None => for &b in b"unfit" { out[n] = b; n += 1; } }
+                if let Some((node, v)) = p.best_remote {
+                    for &b in b" best_remote=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(node, &mut out[n..]);
+                    out[n] = b':'; n += 1;
+                    n += crate::util::format::u64_dec(v as u64, &mut out[n..]);
+                }
+                for &b in b" candidates=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(p.candidates as u64, &mut out[n..]);
+                for &b in b"\r\n" { out[n] = b; n += 1; }
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            if let Some(g) = crate::cluster::placement::group_of(info.id) {
+                n = 0;
+                for &b in b"  group: " { out[n] = b; n += 1; }
+                for &b in g.label().as_bytes() { out[n] = b; n += 1; }
+                out[n] = b' '; n += 1;
+                for &b in g.rule.key().as_bytes() { out[n] = b; n += 1; }
+                out[n] = b'='; n += 1;
+                for &b in g.rule.scope().name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b"\r\n" { out[n] = b; n += 1; }
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            continue;
+        }
+        if cmd.starts_with("vm destroy ") {
+            // vm destroy id=<n>|name=<s>: only a created or stopped VM
+            let stdout = system_table.stdout();
+            let Some(info) = crate::hv::vm::select(cmd[11..].trim()) else { let _ = stdout.write_str("vm destroy: no such vm (id=<n>|name=<s>)\r\n"); continue; };
+            match crate::hv::vm::destroy_vm(info.id) {
+                Ok(()) => { let _ = stdout.write_str("vm destroyed\r\n"); }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd.starts_with("vm rename ") {
+            // vm rename id=<n> name=<s>
+            let stdout = system_table.stdout();
+            let mut id = None; let mut name = None;
+            for w in cmd[10..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); }
+                else if let Some(v) = w.strip_prefix("name=") { name = Some(v); }
+            }
+            let (Some(id), Some(name)) = (id, name) else { let _ = stdout.write_str("usage: vm rename id=<n> name=<s>\r\n"); continue; };
+            match crate::hv::vm::rename(id, name) {
+                Ok(()) => { let _ = stdout.write_str("vm renamed\r\n"); }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd.starts_with("vm pause ") || cmd.starts_with("vm resume ") {
+            // vm pause|resume id=<n>|name=<s>: park or release every vCPU
+            let pause = cmd.starts_with("vm pause ");
+            let Some(info) = crate::hv::vm::select(cmd[if pause { 9 } else { 10 }..].trim()) else {
+                let _ = system_table.stdout().write_str("usage: vm pause|resume id=<n>|name=<s>\r\n");
+                continue;
+            };
+            let ok = match info.state {
+                crate::hv::vm::VmState::Migrating => true,
+                crate::hv::vm::VmState::Running => pause,
+                crate::hv::vm::VmState::Paused => !pause,
+                _ => false,
+            };
+            if !ok {
+                let stdout = system_table.stdout();
+                let _ = stdout.write_str(if pause { "vm pause: vm is " } else { "vm resume: vm is " });
+                let _ = stdout.write_str(info.state.name());
+                let _ = stdout.write_str("\r\n");
+                continue;
+            }
+            if pause {
+                let left = crate::hv::run::pause(system_table, info.id, 200_000);
+                if left != 0 {
+                    crate::hv::run::unpause(info.id);
+                    let _ = system_table.stdout().write_str("vm pause: vcpus did not park\r\n");
+                    continue;
+                }
+            } else {
+                crate::hv::run::unpause(info.id);
+            }
+            let _ = system_table.stdout().write_str(if pause { "vm paused\r\n" } else { "vm resumed\r\n" });
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("vm pause") {
+            let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 64 << 20, vcpu_count: 1, ..Default::default() });
+            vm.pause();
+            let _ = system_table.stdout().write_str("vm paused (trace event)\r\n");
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("vm resume") {
+            let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 64 << 20, vcpu_count: 1, ..Default::default() });
+            vm.resume();
+            let _ = system_table.stdout().write_str("vm resumed (trace event)\r\n");
+            continue;
+        }
+        if cmd.eq_ignore_ascii_case("vm apicv") {
+            let c = crate::hv::event::detect_caps();
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 192]; let mut n = 0;
+            for &b in b"apicv:" { out[n] = b; n += 1; }
+            for (label, on) in [
+                (b" tpr_shadow=".as_ref(), c.tpr_shadow),
+                (b" apic_access=".as_ref(), c.virt_apic_access),
+                (b" x2apic_virt=".as_ref(), c.virt_x2apic),
+                (b" reg_virt=".as_ref(), c.apic_reg_virt),
+                (b" vid=".as_ref(), c.virt_intr_delivery),
+                (b" posted=".as_ref(), c.posted_interrupts),
+                (b" avic=".as_ref(), c.avic),
+                (b" x2avic=".as_ref(), c.x2avic),
+            ] {
+                for &b in label { out[n] = b; n += 1; }
+                out[n] = if on { b'1' } else { b'0' }; n += 1;
+            }
+            let mode: &[u8] = if c.accelerated() { b" mode=accelerated" } else { b" mode=inject" };
+            for &b in mode { out[n] = b; n += 1; }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd.starts_with("vm apic ") || cmd.starts_with("vm irq ") {
+            // vm apic id=<n> [vcpu=<n>] | vm irq id=<n> [vcpu=<n>] vec=<n> [level|nmi]
+            let is_irq = cmd.starts_with("vm irq ");
+            let mut id: Option<u64> = None; let mut vcpu: u32 = 0; let mut vec: Option<u8> = None;
+            let mut level = false; let mut nmi = false;
+            for w in cmd[7..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); continue; }
+                if let Some(v) = w.strip_prefix("vcpu=") { vcpu = v.parse::<u32>().unwrap_or(0); continue; }
+                if let Some(v) = w.strip_prefix("vec=") {
+                    vec = if let Some(h) = v.strip_prefix("0x") { u8::from_str_radix(h, 16).ok() } else { v.parse::<u8>().ok() };
+                    continue;
+                }
+                if w.eq_ignore_ascii_case("level") { level = true; continue; }
+                if w.eq_ignore_ascii_case("nmi") { nmi = true; continue; }
+            }
+            let id = match id {
+                Some(v) => v,
+                None => { let _ = system_table.stdout().write_str("usage: vm apic id=<n> [vcpu=<n>] | vm irq id=<n> [vcpu=<n>] vec=<n> [level|nmi]\r\n"); continue; }
+            };
+            if is_irq {
+                let ok = if nmi {
+                    crate::hv::vlapic::with(id, vcpu, |l| l.nmi_pending = true).is_some()
+                } else {
+                    match vec { Some(v) => crate::hv::vlapic::raise(id, vcpu, v, level), None => false }
+                };
+                if !ok { let _ = system_table.stdout().write_str("vm irq: rejected (no such vcpu, apic disabled or vector < 16)\r\n"); continue; }
+            }
+            let l = match crate::hv::vlapic::get(id, vcpu) {
+                Some(l) => l,
+                None => { let _ = system_table.stdout().write_str("vm apic: no such vcpu\r\n"); continue; }
+            };
+            let now = crate::time::rdtsc();
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 192]; let mut n = 0;
+            for &b in b"vlapic: id=" { out[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(l.id, &mut out[n..]);
+            let m: &[u8] = if l.x2apic { b" mode=x2apic" } else { b" mode=xapic" };
+            for &b in m { out[n] = b; n += 1; }
+            for &b in b" svr=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.svr as u64, &mut out[n..]);
+            for &b in b" tpr=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.tpr as u64, &mut out[n..]);
+            for &b in b" ppr=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.ppr() as u64, &mut out[n..]);
+            for &b in b" irr=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.highest_irr().unwrap_or(0) as u64, &mut out[n..]);
+            for &b in b" isr=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.highest_isr().unwrap_or(0) as u64, &mut out[n..]);
+            for &b in b" pending=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.deliverable().unwrap_or(0) as u64, &mut out[n..]);
+            if l.nmi_pending { for &b in b" nmi" { out[n] = b; n += 1; } }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            n = 0;
+            for &b in b"vlapic: lvt_timer=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.lvt[0] as u64, &mut out[n..]);
+            let tm: &[u8] = match l.timer_mode() { crate::hv::vlapic::TimerMode::OneShot => b" oneshot", crate::hv::vlapic::TimerMode::Periodic => b" periodic", crate::hv::vlapic::TimerMode::TscDeadline => b" tsc-deadline" };
+            for &b in tm { out[n] = b; n += 1; }
+            for &b in b" icr=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.timer_icr as u64, &mut out[n..]);
+            for &b in b" ccr=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.read(crate::hv::vlapic::REG_TIMER_CCR, now) as u64, &mut out[n..]);
+            for &b in b" dcr=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.timer_dcr as u64, &mut out[n..]);
+            for &b in b" lint0=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.lvt[3] as u64, &mut out[n..]);
+            for &b in b" lint1=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(l.lvt[4] as u64, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd == "vm console" || cmd.starts_with("vm console ") {
+            // vm console | vm console add id=<n> | vm console id=<n> [log [bytes=<n>]|echo on|off|send <text>]
+            let rest = cmd[10..].trim();
+            if let Some(args) = rest.strip_prefix("add") {
+                let id = args.trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok());
+                let id = match id { Some(v) => v, None => { let _ = system_table.stdout().write_str("usage: vm console add id=<n>\r\n"); continue; } };
+                if crate::hv::vm::find_vm(id).is_none() { let _ = system_table.stdout().write_str("vm console: no such vm\r\n"); continue; }
+                match crate::hv::vdev::console::add(id) {
+                    Ok((idx, dev)) => {
+                        let mut out = [0u8; 64]; let mut n = 0;
+                        for &b in b"vm console: console=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(idx as u64, &mut out[n..]);
+                        for &b in b" pci=00:" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_hex(dev as u64, &mut out[n..]);
+                        for &b in b".0" { out[n] = b; n += 1; }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("id=") {
+                let (idv, tail) = match args.find(' ') { Some(p) => (&args[..p], args[p + 1..].trim()), None => (args, "") };
+                let idx = match idv.parse::<u64>().ok().and_then(crate::hv::vdev::console::find) {
+                    Some(i) => i,
+                    None => { let _ = system_table.stdout().write_str("vm console: vm has no console (vm console add id=<n>)\r\n"); continue; }
+                };
+                if tail.is_empty() {
+                    let _ = system_table.stdout().write_str("vm console: attached, Ctrl-] to detach\r\n");
+                    match crate::hv::vdev::console::attach(system_table, idx, 2048) {
+                        Ok(()) => { let _ = system_table.stdout().write_str("\r\nvm console: detached\r\n"); }
+                        Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                    }
+                    continue;
+                }
+                if let Some(a) = tail.strip_prefix("log") {
+                    let max = a.trim().strip_prefix("bytes=").and_then(|v| v.parse::<usize>().ok()).unwrap_or(crate::hv::vdev::console::LOG_SIZE);
+                    let mut log = [0u8; crate::hv::vdev::console::LOG_SIZE];
+                    let len = crate::hv::vdev::console::log_tail(idx, max, &mut log);
+                    // The log keeps raw bytes; only pass what the text console renders.
+                    let mut kept = 0usize;
+                    for i in 0..len {
+                        let b = log[i];
+                        if b == b'\r' || b == b'\n' || b == b'\t' || (0x20..0x7F).contains(&b) { log[kept] = b; kept += 1; }
+                    }
+                    let stdout = system_table.stdout();
+                    for chunk in log[..kept].chunks(256) { let _ = stdout.write_str(core::str::from_utf8(chunk).unwrap_or("")); }
+                    let _ = stdout.write_str("\r\n");
+                    continue;
+                }
+                if let Some(a) = tail.strip_prefix("echo") {
+                    let on = match a.trim() { "on" => true, "off" => false, _ => { let _ = system_table.stdout().write_str("usage: vm console id=<n> echo on|off\r\n"); continue; } };
+                    let _ = crate::hv::vdev::console::set_echo(idx, on);
+                    let _ = system_table.stdout().write_str(if on { "vm console: echo on\r\n" } else { "vm console: echo off\r\n" });
+                    continue;
+                }
+                if let Some(a) = tail.strip_prefix("send ") {
+                    let n = crate::hv::vdev::console::send_input(idx, a.as_bytes()) + crate::hv::vdev::console::send_input(idx, b"\r");
+                    let mut out = [0u8; 48]; let mut k = 0;
+                    for &b in b"vm console: queued=" { out[k] = b; k += 1; }
+                    k += crate::util::format::u64_dec(n as u64, &mut out[k..]);
+                    out[k] = b'\r'; k += 1; out[k] = b'\n'; k += 1;
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..k]).unwrap_or("\r\n"));
+                    continue;
+                }
+                let _ = system_table.stdout().write_str("usage: vm console id=<n> [log [bytes=<n>]|echo on|off|send <text>]\r\n");
+                continue;
+            }
+            if !rest.is_empty() { let _ = system_table.stdout().write_str("usage: vm console [add id=<n>|id=<n> [log|echo on|off|send <text>]]\r\n"); continue; }
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::vdev::console::for_each(|i, c| {
+                any = true;
+                let mut out = [0u8; 160]; let mut n = 0;
+                for &b in b"console " { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(i as u64, &mut out[n..]);
+                for &b in b": vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(c.vm_id, &mut out[n..]);
+                for &b in b" pci=00:" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(c.dev as u64, &mut out[n..]);
+                for &b in b".0 driver=" { out[n] = b; n += 1; }
+                for &b in if c.driver_ok { b"ok".as_slice() } else { b"no".as_slice() } { out[n] = b; n += 1; }
+                for &b in b" echo=" { out[n] = b; n += 1; }
+                for &b in if c.echo { b"on".as_slice() } else { b"off".as_slice() } { out[n] = b; n += 1; }
+                for &b in b" out=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(c.stats.tx_bytes, &mut out[n..]);
+                for &b in b" in=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(c.stats.rx_bytes, &mut out[n..]);
+                for &b in b" pending_in=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(c.pending_input as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("vm console: none\r\n"); }
+            continue;
+        }
+        if cmd == "vm vsock" || cmd.starts_with("vm vsock ") {
+            // vm vsock | vm vsock add id=<n> [cid=<n>] | vm vsock id=<n> connect|close
+            let rest = cmd[8..].trim();
+            if let Some(args) = rest.strip_prefix("add") {
+                let mut id: Option<u64> = None; let mut cid: Option<u64> = None; let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("cid=") { match v.parse::<u64>() { Ok(x) => cid = Some(x), Err(_) => bad = true } }
+                    else { bad = true; }
+                }
+                let id = match id { Some(v) if !bad => v, _ => { let _ = system_table.stdout().write_str("usage: vm vsock add id=<n> [cid=<n>]\r\n"); continue; } };
+                if crate::hv::vm::find_vm(id).is_none() { let _ = system_table.stdout().write_str("vm vsock: no such vm\r\n"); continue; }
+                match crate::hv::vdev::vsock::add(id, cid) {
+                    Ok((_, dev, cid)) => {
+                        let mut out = [0u8; 64]; let mut n = 0;
+                        for &b in b"vm vsock: cid=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(cid, &mut out[n..]);
+                        for &b in b" pci=00:" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_hex(dev as u64, &mut out[n..]);
+                        for &b in b".0" { out[n] = b; n += 1; }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("id=") {
+                let (idv, tail) = match args.find(' ') { Some(p) => (&args[..p], args[p + 1..].trim()), None => (args, "") };
+                let idx = match idv.parse::<u64>().ok().and_then(crate::hv::vdev::vsock::find) {
+                    Some(i) => i,
+                    None => { let _ = system_table.stdout().write_str("vm vsock: vm has no vsock device (vm vsock add id=<n>)\r\n"); continue; }
+                };
+                match tail {
+                    "connect" => match crate::hv::vdev::vsock::connect(idx) {
+                        Ok(s) => {
+                            let _ = system_table.stdout().write_str("vm vsock: ");
+                            let _ = system_table.stdout().write_str(s.name());
+                            let _ = system_table.stdout().write_str("\r\n");
+                        }
+                        Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                    },
+                    "close" => { crate::hv::vdev::vsock::disconnect(idx); let _ = system_table.stdout().write_str("vm vsock: closed\r\n"); }
+                    _ => { let _ = system_table.stdout().write_str("usage: vm vsock id=<n> connect|close\r\n"); }
+                }
+                continue;
+            }
+            if !rest.is_empty() { let _ = system_table.stdout().write_str("usage: vm vsock [add id=<n> [cid=<n>]|id=<n> connect|close]\r\n"); continue; }
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::vdev::vsock::for_each(|_, v| {
+                any = true;
+                let mut out = [0u8; 192]; let mut n = 0;
+                for &b in b"vsock vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.vm_id, &mut out[n..]);
+                for &b in b" cid=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.cid, &mut out[n..]);
+                for &b in b" pci=00:" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(v.dev as u64, &mut out[n..]);
+                for &b in b".0 driver=" { out[n] = b; n += 1; }
+                for &b in if v.driver_ok { b"ok".as_slice() } else { b"no".as_slice() } { out[n] = b; n += 1; }
+                for &b in b" agent=" { out[n] = b; n += 1; }
+                for &b in v.state.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" pkts_in=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.stats.tx_pkts, &mut out[n..]);
+                for &b in b" pkts_out=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.stats.rx_pkts, &mut out[n..]);
+                for &b in b" msgs_in=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.stats.msgs_in, &mut out[n..]);
+                for &b in b" msgs_out=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.stats.msgs_out, &mut out[n..]);
+                for &b in b" resets=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.stats.resets, &mut out[n..]);
+                for &b in b" drops=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.stats.drops, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("vm vsock: none\r\n"); }
+            continue;
+        }
+        if cmd == "vm balloon" || cmd.starts_with("vm balloon ") {
+            // vm balloon | vm balloon add id=<n> | vm balloon id=<n> target=<MiB> | vm balloon reclaim|relax mb=<n> | vm balloon pump
+            let rest = cmd[10..].trim();
+            if let Some(args) = rest.strip_prefix("add") {
+                let id = args.trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok());
+                let id = match id { Some(v) => v, None => { let _ = system_table.stdout().write_str("usage: vm balloon add id=<n>\r\n"); continue; } };
+                if crate::hv::vm::find_vm(id).is_none() { let _ = system_table.stdout().write_str("vm balloon: no such vm\r\n"); continue; }
+                match crate::hv::vdev::balloon::add(id) {
+                    Ok((idx, dev)) => {
+                        let mut out = [0u8; 64]; let mut n = 0;
+                        for &b in b"vm balloon: balloon=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(idx as u64, &mut out[n..]);
+                        for &b in b" pci=00:" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_hex(dev as u64, &mut out[n..]);
+                        for &b in b".0" { out[n] = b; n += 1; }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("id=") {
+                let (idv, tail) = match args.find(' ') { Some(p) => (&args[..p], args[p + 1..].trim()), None => (args, "") };
+                let id = idv.parse::<u64>().ok();
+                let mib = tail.strip_prefix("target=").and_then(|v| v.parse::<u64>().ok());
+                let (id, mib) = match (id, mib) { (Some(i), Some(m)) => (i, m), _ => { let _ = system_table.stdout().write_str("usage: vm balloon id=<n> target=<MiB>\r\n"); continue; } };
+                match crate::hv::vdev::balloon::set_target(id, mib << 20) {
+                    Ok(pages) => {
+                        let mut out = [0u8; 64]; let mut n = 0;
+                        for &b in b"vm balloon: target_pages=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(pages as u64, &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest.starts_with("reclaim") || rest.starts_with("relax") {
+                let reclaim = rest.starts_with("reclaim");
+                let mib = rest.split_whitespace().nth(1).and_then(|w| w.strip_prefix("mb=")).and_then(|v| v.parse::<u64>().ok());
+                let mib = match mib { Some(m) => m, None => { let _ = system_table.stdout().write_str("usage: vm balloon reclaim|relax mb=<n>\r\n"); continue; } };
+                let bytes = if reclaim { crate::hv::vdev::balloon::reclaim(mib << 20) } else { crate::hv::vdev::balloon::relax(mib << 20) };
+                let mut out = [0u8; 64]; let mut n = 0;
+                for &b in if reclaim { b"vm balloon: asked=0x".as_slice() } else { b"vm balloon: released=0x".as_slice() } { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(bytes, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if rest == "pump" {
+                let done = crate::hv::vdev::balloon::pump(system_table, 0);
+                let mut out = [0u8; 48]; let mut n = 0;
+                for &b in b"vm balloon: completed=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(done as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if !rest.is_empty() { let _ = system_table.stdout().write_str("usage: vm balloon [add id=<n>|id=<n> target=<MiB>|reclaim mb=<n>|relax mb=<n>|pump]\r\n"); continue; }
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::vdev::balloon::for_each(|_, b| {
+                any = true;
+                // Have the guest refresh its figures for the next listing.
+                crate::hv::vdev::balloon::request_stats(b.vm_id);
+                let mut out = [0u8; 256]; let mut n = 0;
+                for &c in b"balloon vm=" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_dec(b.vm_id, &mut out[n..]);
+                for &c in b" pci=00:" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_hex(b.dev as u64, &mut out[n..]);
+                for &c in b".0 driver=" { out[n] = c; n += 1; }
+                for &c in if b.driver_ok { b"ok".as_slice() } else { b"no".as_slice() } { out[n] = c; n += 1; }
+                for (name, v) in [(&b" target="[..], b.target_pages as u64), (b" actual=", b.actual_pages as u64), (b" held=", b.held_pages),
+                                  (b" inflated=", b.stats.inflated), (b" deflated=", b.stats.deflated), (b" ignored=", b.stats.ignored), (b" errors=", b.stats.errors)] {
+                    for &c in name { out[n] = c; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                if b.stats.stat_updates != 0 {
+                    for (name, v) in [(&b" guest_free=0x"[..], b.mem.free), (b" guest_avail=0x", b.mem.available), (b" guest_total=0x", b.mem.total)] {
+                        for &c in name { out[n] = c; n += 1; }
+                        n += crate::util::format::u64_hex(v, &mut out[n..]);
+                    }
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("vm balloon: none\r\n"); }
+            continue;
+        }
+        if cmd == "vm accel" || cmd.starts_with("vm accel ") {
+            // vm accel | vm accel add id=<n> | vm accel id=<n> [inflight=<n>] [rate=<KiB/s>|rate=off] [max=<KiB>] | vm accel engines | vm accel pump
+            let rest = cmd[8..].trim();
+            if let Some(args) = rest.strip_prefix("add") {
+                let id = args.trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok());
+                let id = match id { Some(v) => v, None => { let _ = system_table.stdout().write_str("usage: vm accel add id=<n>\r\n"); continue; } };
+                if crate::hv::vm::find_vm(id).is_none() { let _ = system_table.stdout().write_str("vm accel: no such vm\r\n"); continue; }
+                match crate::hv::vdev::accel::add(id) {
+                    Ok((idx, dev)) => {
+                        let mut out = [0u8; 64]; let mut n = 0;
+                        for &b in b"vm accel: queue=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(idx as u64, &mut out[n..]);
+                        for &b in b" pci=00:" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_hex(dev as u64, &mut out[n..]);
+                        for &b in b".0" { out[n] = b; n += 1; }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("id=") {
+                let (idv, tail) = match args.find(' ') { Some(p) => (&args[..p], args[p + 1..].trim()), None => (args, "") };
+                let id = match idv.parse::<u64>().ok() { Some(v) => v, None => { let _ = system_table.stdout().write_str("usage: vm accel id=<n> [inflight=<n>] [rate=<KiB/s>|off] [max=<KiB>]\r\n"); continue; } };
+                let mut q = None;
+                crate::hv::vdev::accel::for_each(|_, a| if a.vm_id == id { q = Some(a.quota); });
+                let mut q = match q { Some(q) => q, None => { let _ = system_table.stdout().write_str("vm accel: vm has no job queue\r\n"); continue; } };
+                let mut bad = false;
+                for w in tail.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("inflight=") { match v.parse::<u32>() { Ok(x) => q.inflight = x, Err(_) => bad = true } }
+                    else if w == "rate=off" { q.bytes_per_s = 0; }
+                    else if let Some(v) = w.strip_prefix("rate=") { match v.parse::<u64>() { Ok(x) if x != 0 => q.bytes_per_s = x << 10, _ => bad = true } }
+                    else if let Some(v) = w.strip_prefix("max=") { match v.parse::<u32>() { Ok(x) if x <= (u32::MAX >> 10) => q.max_job = x << 10, _ => bad = true } }
+                    else { bad = true; }
+                }
+                if bad { let _ = system_table.stdout().write_str("usage: vm accel id=<n> [inflight=<n>] [rate=<KiB/s>|off] [max=<KiB>]\r\n"); continue; }
+                match crate::hv::vdev::accel::set_quota(id, q) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("vm accel: quota set\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest == "engines" {
+                let stdout = system_table.stdout();
+                crate::hv::vdev::accel::for_each_engine(|i, name, ops, s| {
+                    let mut out = [0u8; 160]; let mut n = 0;
+                    for &b in b"engine " { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(i as u64, &mut out[n..]);
+                    out[n] = b' '; n += 1;
+                    for &b in name.as_bytes().iter().take(32) { out[n] = b; n += 1; }
+                    for &b in b" opcodes=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(ops as u64, &mut out[n..]);
+                    for (label, v) in [(&b" submitted="[..], s.submitted), (b" completed=", s.completed), (b" failed=", s.failed)] {
+                        for &b in label { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(v, &mut out[n..]);
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                continue;
+            }
+            if rest == "pump" {
+                let done = crate::hv::vdev::accel::pump(0);
+                let mut out = [0u8; 48]; let mut n = 0;
+                for &b in b"vm accel: completed=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(done as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if !rest.is_empty() { let _ = system_table.stdout().write_str("usage: vm accel [add id=<n>|id=<n> [inflight=<n>] [rate=<KiB/s>|off] [max=<KiB>]|engines|pump]\r\n"); continue; }
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::vdev::accel::for_each(|_, a| {
+                any = true;
+                let mut out = [0u8; 256]; let mut n = 0;
+                for &c in b"accel vm=" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_dec(a.vm_id, &mut out[n..]);
+                for &c in b" pci=00:" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_hex(a.dev as u64, &mut out[n..]);
+                for &c in b".0 driver=" { out[n] = c; n += 1; }
+                for &c in if a.driver_ok { b"ok".as_slice() } else { b"no".as_slice() } { out[n] = c; n += 1; }
+                for (name, v) in [(&b" inflight="[..], a.inflight as u64), (b"/", a.quota.inflight as u64), (b" rate_kib=", a.quota.bytes_per_s >> 10),
+                                  (b" max_kib=", (a.quota.max_job >> 10) as u64), (b" submitted=", a.stats.submitted), (b" completed=", a.stats.completed),
+                                  (b" rejected=", a.stats.rejected), (b" throttled=", a.stats.throttled), (b" bytes=", a.stats.bytes)] {
+                    for &c in name { out[n] = c; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                if a.held { for &c in b" held" { out[n] = c; n += 1; } }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("vm accel: none\r\n"); }
+            continue;
+        }
+        if cmd == "vm shm" || cmd.starts_with("vm shm ") {
+            // vm shm | vm shm create name=<s> size=<KiB> | vm shm destroy name=<s>
+            // vm shm attach name=<s> id=<n> [ro] | vm shm detach name=<s> id=<n> | vm shm perm name=<s> id=<n> ro|rw
+            let rest = cmd[6..].trim();
+            let mut words = rest.split_whitespace();
+            let op = words.next().unwrap_or("");
+            let mut name: Option<&str> = None; let mut id: Option<u64> = None; let mut kib: Option<u64> = None;
+            let mut perm: Option<crate::hv::zero_copy::Perm> = None; let mut bad = false;
+            for w in words {
+                if let Some(v) = w.strip_prefix("name=") { name = Some(v); }
+                else if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("size=") { match v.parse::<u64>() { Ok(x) => kib = Some(x), Err(_) => bad = true } }
+                else if w == "ro" { perm = Some(crate::hv::zero_copy::Perm::Ro); }
+                else if w == "rw" { perm = Some(crate::hv::zero_copy::Perm::Rw); }
+                else { bad = true; }
+            }
+            let res = match (op, name, id, kib, perm) {
+                _ if bad => None,
+                ("create", Some(nm), None, Some(k), None) => Some(crate::hv::zero_copy::create(system_table, nm, k << 10).map(|_| None)),
+                ("destroy", Some(nm), None, None, None) => Some(crate::hv::zero_copy::destroy(system_table, nm).map(|_| None)),
+                ("attach", Some(nm), Some(i), None, p) if p != Some(crate::hv::zero_copy::Perm::Rw) => {
+                    if crate::hv::vm::find_vm(i).is_none() { let _ = system_table.stdout().write_str("vm shm: no such vm\r\n"); continue; }
+                    Some(crate::hv::zero_copy::attach(system_table, nm, i, p.unwrap_or(crate::hv::zero_copy::Perm::Rw)).map(Some))
+                }
+                ("detach", Some(nm), Some(i), None, None) => Some(crate::hv::zero_copy::detach(nm, i).map(|_| None)),
+                ("perm", Some(nm), Some(i), None, Some(p)) => Some(crate::hv::zero_copy::set_perm(nm, i, p).map(|_| None)),
+                ("", None, None, None, None) => None,
+                _ => { let _ = system_table.stdout().write_str("usage: vm shm [create name=<s> size=<KiB>|destroy name=<s>|attach name=<s> id=<n> [ro]|detach name=<s> id=<n>|perm name=<s> id=<n> ro|rw]\r\n"); continue; }
+            };
+            if let Some(r) = res {
+                match r {
+                    Ok(Some((peer, dev))) => {
+                        let mut out = [0u8; 64]; let mut n = 0;
+                        for &b in b"vm shm: peer=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(peer as u64, &mut out[n..]);
+                        for &b in b" pci=00:" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_hex(dev as u64, &mut out[n..]);
+                        for &b in b".0" { out[n] = b; n += 1; }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Ok(None) => { let _ = system_table.stdout().write_str("vm shm: ok\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if bad { let _ = system_table.stdout().write_str("usage: vm shm [create name=<s> size=<KiB>|destroy name=<s>|attach name=<s> id=<n> [ro]|detach name=<s> id=<n>|perm name=<s> id=<n> ro|rw]\r\n"); continue; }
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::zero_copy::for_each(|i, g| {
+                any = true;
+                let mut out = [0u8; 96]; let mut n = 0;
+                for &c in b"shm " { out[n] = c; n += 1; }
+                for &c in g.name().as_bytes() { out[n] = c; n += 1; }
+                for &c in b" size=0x" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_hex(g.size, &mut out[n..]);
+                for &c in b" host=0x" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_hex(g.base, &mut out[n..]);
+                for &c in b" attached=" { out[n] = c; n += 1; }
+                n += crate::util::format::u64_dec(g.attached as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                crate::hv::zero_copy::for_each_attach(i, |a| {
+                    let mut out = [0u8; 160]; let mut n = 0;
+                    for &c in b"  vm=" { out[n] = c; n += 1; }
+                    n += crate::util::format::u64_dec(a.vm_id, &mut out[n..]);
+                    for &c in b" peer=" { out[n] = c; n += 1; }
+                    n += crate::util::format::u64_dec(a.peer as u64, &mut out[n..]);
+                    for &c in b" pci=00:" { out[n] = c; n += 1; }
+                    n += crate::util::format::u64_hex(a.dev as u64, &mut out[n..]);
+                    for &c in b".0 " { out[n] = c; n += 1; }
+                    for &c in a.perm.name().as_bytes() { out[n] = c; n += 1; }
+                    for &c in b" bar2=0x" { out[n] = c; n += 1; }
+                    n += crate::util::format::u64_hex(a.window, &mut out[n..]);
+                    for &c in if a.mapped { b" mapped".as_slice() } else { b" trapped".as_slice() } { out[n] = c; n += 1; }
+                    for (name, v) in [(&b" rx="[..], a.doorbells_in), (b" tx=", a.doorbells_out)] {
+                        for &c in name { out[n] = c; n += 1; }
+                        n += crate::util::format::u64_dec(v, &mut out[n..]);
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+            });
+            if !any { let _ = stdout.write_str("vm shm: none\r\n"); }
+            continue;
+        }
+        if cmd.starts_with("vm ping ") || cmd.starts_with("vm exec ") {
+            // vm ping id=<n> [timeout=<ms>] | vm exec id=<n> [timeout=<ms>] <command>
+            let is_exec = cmd.starts_with("vm exec ");
+            let mut rest = cmd[8..].trim();
+            let mut id: Option<u64> = None; let mut timeout = if is_exec { 5000u32 } else { 1000u32 }; let mut bad = false;
+            while let Some(w) = rest.split_whitespace().next() {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("timeout=") { match v.parse::<u32>() { Ok(x) if x != 0 => timeout = x, _ => bad = true } }
+                else { break; }
+                rest = rest[w.len()..].trim_start();
+            }
+            let usage = if is_exec { "usage: vm exec id=<n> [timeout=<ms>] <command>\r\n" } else { "usage: vm ping id=<n> [timeout=<ms>]\r\n" };
+            let id = match id { Some(v) if !bad && (is_exec != rest.is_empty()) => v, _ => { let _ = system_table.stdout().write_str(usage); continue; } };
+            let idx = match crate::hv::vdev::vsock::find(id) {
+                Some(i) => i,
+                None => { let _ = system_table.stdout().write_str("vm: vm has no vsock device (vm vsock add id=<n>)\r\n"); continue; }
+            };
+            let _ = crate::time::clock::init(system_table);
+            let mut out = [0u8; 64]; let mut n = 0;
+            if !is_exec {
+                match crate::hv::vdev::vsock::ping(system_table, idx, timeout) {
+                    Ok(us) => {
+                        for &b in b"vm ping: reply from vm " { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(id, &mut out[n..]);
+                        for &b in b" rtt_us=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(us, &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            let r = crate::hv::vdev::vsock::exec(system_table, idx, rest, timeout, |st, data| {
+                // Agent output is raw bytes; only pass what the text console renders.
+                let mut line = [0u8; crate::hv::vdev::vsock::MAX_MSG * 2]; let mut k = 0;
+                for &b in data {
+                    if b == b'\n' { line[k] = b'\r'; k += 1; }
+                    if b == b'\n' || b == b'\t' || (0x20..0x7F).contains(&b) { line[k] = b; k += 1; }
+                }
+                let _ = st.stdout().write_str(core::str::from_utf8(&line[..k]).unwrap_or(""));
+            });
+            match r {
+                Ok(status) => {
+                    for &b in b"\r\nvm exec: exit=" { out[n] = b; n += 1; }
+                    n += crate::util::format::i64_dec(status.into(), &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str("\r\n"); let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd.starts_with("vm attach ") || cmd.starts_with("vm detach ") {
+            // vm attach id=<n> vf=<bdf> | vm detach id=<n> vf=<bdf>
+            let is_attach = cmd.starts_with("vm attach ");
+            let mut id: Option<u64> = None; let mut vf: Option<crate::hv::sriov::Bdf> = None; let mut bad = false;
+            for w in cmd[10..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("vf=") { match crate::hv::sriov::Bdf::parse(v) { Some(b) => vf = Some(b), None => bad = true } }
+                else { bad = true; }
+            }
+            let (id, vf) = match (id, vf) {
+                (Some(i), Some(v)) if !bad => (i, v),
+                _ => { let _ = system_table.stdout().write_str("usage: vm attach|detach id=<n> vf=<[seg:]bus:dev.fn>\r\n"); continue; }
+            };
+            if !is_attach {
+                match crate::hv::sriov::detach(system_table, id, vf) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("vm: VF detached\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            match crate::hv::sriov::attach(system_table, id, vf) {
+                Ok(a) => {
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    for &b in b"vm: VF " { out[n] = b; n += 1; }
+                    n += a.vf.fmt(&mut out[n..]);
+                    for &b in b" attached as guest dev " { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(a.guest_dev as u64, &mut out[n..]);
+                    for &b in b" domain=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(a.domid as u64, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "nvme" || cmd.starts_with("nvme ") {
+            // nvme [list] | nvme probe <bdf> | nvme ns | nvme release <ctrl> | nvme assign|unassign id=<n> ctrl=<bdf>
+            const USAGE: &str = "usage: nvme [list] | nvme probe <[seg:]bus:dev.fn> | nvme ns | nvme release <ctrl> | nvme assign|unassign id=<n> ctrl=<bdf>\r\n";
+            let mut it = cmd[4..].split_whitespace();
+            let sub = it.next();
+            if sub.is_none() || sub == Some("list") {
+                let mut found: [Option<crate::nvme::Found>; 16] = [None; 16];
+                let mut count = 0;
+                crate::nvme::for_each_controller(system_table, |f| if count < found.len() { found[count] = Some(*f); count += 1; });
+                let stdout = system_table.stdout();
+                for f in found.iter().flatten() {
+                    let mut out = [0u8; 192]; let mut n = 0;
+                    for &b in b"nvme: " { out[n] = b; n += 1; }
+                    n += f.bdf.fmt(&mut out[n..]);
+                    for &b in b" vid=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(f.vendor as u64, &mut out[n..]);
+                    for &b in b" did=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(f.device as u64, &mut out[n..]);
+                    match (f.ctrl.and_then(|c| crate::nvme::info(c).map(|i| (c, i))), f.vm_id) {
+                        (Some((c, i)), _) => {
+                            for &b in b" ctrl=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(c as u64, &mut out[n..]);
+                            for &b in b" model=" { out[n] = b; n += 1; }
+                            for &b in i.model().as_bytes() { out[n] = b; n += 1; }
+                            for &b in b" fw=" { out[n] = b; n += 1; }
+                            for &b in i.firmware().as_bytes() { out[n] = b; n += 1; }
+                        }
+                        (None, Some(vm)) => {
+                            for &b in b" vm=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(vm, &mut out[n..]);
+                        }
+                        (None, None) => for &b in b" free" { out[n] = b; n += 1; },
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                crate::nvme::for_each(|c, _, s| {
+                    let mut out = [0u8; 192]; let mut n = 0;
+                    for &b in b"nvme:   ctrl=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(c as u64, &mut out[n..]);
+                    for (k, v) in [(&b" rd="[..], s.reads), (b"/", s.bytes_read), (b"B wr=", s.writes), (b"/", s.bytes_written), (b"B flush=", s.flushes), (b" err=", s.errors)] {
+                        for &b in k { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(v, &mut out[n..]);
+                    }
+                    if s.errors != 0 {
+                        for &b in b" status=0x" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_hex(s.last_status as u64, &mut out[n..]);
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                if count == 0 { let _ = stdout.write_str("nvme: no NVMe controllers\r\n"); }
+                continue;
+            }
+            if sub == Some("probe") {
+                let (Some(bdf), None) = (it.next().and_then(crate::hv::sriov::Bdf::parse), it.next()) else { let _ = system_table.stdout().write_str(USAGE); continue; };
+                match crate::nvme::probe(system_table, bdf) {
+                    Ok(c) => {
+                        let mut nsc = 0u64;
+                        crate::nvme::for_each_namespace(c, |_| nsc += 1);
+                        let i = crate::nvme::info(c);
+                        let mut out = [0u8; 192]; let mut n = 0;
+                        for &b in b"nvme: ctrl=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(c as u64, &mut out[n..]);
+                        if let Some(i) = i {
+                            for &b in b" model=" { out[n] = b; n += 1; }
+                            for &b in i.model().as_bytes() { out[n] = b; n += 1; }
+                            for &b in b" serial=" { out[n] = b; n += 1; }
+                            for &b in i.serial().as_bytes() { out[n] = b; n += 1; }
+                            for &b in b" version=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec((i.version >> 16) as u64, &mut out[n..]);
+                            out[n] = b'.'; n += 1;
+                            n += crate::util::format::u64_dec(((i.version >> 8) & 0xFF) as u64, &mut out[n..]);
+                            for &b in b" max_xfer=" { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec((i.max_xfer >> 10) as u64, &mut out[n..]);
+                            for &b in b"KiB" { out[n] = b; n += 1; }
+                        }
+                        for &b in b" namespaces=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(nsc, &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if sub == Some("ns") {
+                if it.next().is_some() { let _ = system_table.stdout().write_str(USAGE); continue; }
+                let stdout = system_table.stdout();
+                let mut any = false;
+                for c in 0..crate::nvme::MAX_CTRLS {
+                    crate::nvme::for_each_namespace(c, |ns| {
+                        any = true;
+                        let mut out = [0u8; 96]; let mut n = 0;
+                        for &b in b"nvme: " { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(c as u64, &mut out[n..]);
+                        out[n] = b'n'; n += 1;
+                        n += crate::util::format::u64_dec(ns.nsid as u64, &mut out[n..]);
+                        for (k, v) in [(&b" blocks="[..], ns.blocks), (b" bs=", ns.block_size as u64), (b" size=", (ns.blocks * ns.block_size as u64) >> 20)] {
+                            for &b in k { out[n] = b; n += 1; }
+                            n += crate::util::format::u64_dec(v, &mut out[n..]);
+                        }
+                        for &b in b"MiB\r\n" { out[n] = b; n += 1; }
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    });
+                }
+                if !any { let _ = stdout.write_str("nvme: no namespaces (nvme probe <bdf> first)\r\n"); }
+                continue;
+            }
+            if sub == Some("release") {
+                let (Some(c), None) = (it.next().and_then(|v| v.parse::<usize>().ok()), it.next()) else { let _ = system_table.stdout().write_str(USAGE); continue; };
+                match crate::nvme::release(c) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("nvme: controller released\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            let is_assign = sub == Some("assign");
+            if !is_assign && sub != Some("unassign") { let _ = system_table.stdout().write_str(USAGE); continue; }
+            let mut id: Option<u64> = None; let mut ctrl: Option<crate::hv::sriov::Bdf> = None; let mut bad = false;
+            for w in it {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("ctrl=") { match crate::hv::sriov::Bdf::parse(v) { Some(b) => ctrl = Some(b), None => bad = true } }
+                else { bad = true; }
+            }
+            let (Some(id), Some(ctrl), false) = (id, ctrl, bad) else { let _ = system_table.stdout().write_str(USAGE); continue; };
+            if !is_assign {
+                match crate::hv::sriov::detach(system_table, id, ctrl) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("nvme: controller unassigned\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            match crate::nvme::assign(system_table, id, ctrl) {
+                Ok(a) => {
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    for &b in b"nvme: assigned pci=00:" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(a.guest_dev as u64, &mut out[n..]);
+                    for &b in b".0 domain=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(a.domid as u64, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "storage" || cmd.starts_with("storage ") {
+            // storage | storage pool format disk=<idx>|nvme=<c>n<ns> [lba=<n>] [count=<n>] [force] | storage pool open disk=<idx>|nvme=<c>n<ns> [lba=<n>]
+            // storage pool close | storage sync | storage vol create name=<s> size=<MiB>
+            // storage vol snapshot|clone from=<id|name> name=<s> | storage vol delete vol=<id|name>
+            use crate::hv::storage;
+            let rest = cmd[7..].trim();
+            let mib = |b: u64| b >> 20;
+            let pool_line = |p: &storage::PoolInfo| {
+                let mut out = [0u8; 192]; let mut n = 0;
+                match p.device {
+                    storage::Device::Disk(d) => {
+                        for &b in b"storage: pool disk=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(d as u64, &mut out[n..]);
+                    }
+                    storage::Device::Nvme { ctrl, nsid } => {
+                        for &b in b"storage: pool nvme=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(ctrl as u64, &mut out[n..]);
+                        out[n] = b'n'; n += 1;
+                        n += crate::util::format::u64_dec(nsid as u64, &mut out[n..]);
+                    }
+                }
+                for (k, v) in [(&b" lba="[..], p.lba), (b" bs=", p.block_size as u64), (b" gen=", p.generation), (b" used=", mib(p.used)), (b"/", mib(p.capacity)),
+                               (b"MiB provisioned=", mib(p.provisioned)), (b"/", mib(p.provision_limit)), (b"MiB volumes=", p.volumes as u64)] {
+                    for &b in k { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                if p.dirty { for &b in b" dirty" { out[n] = b; n += 1; } }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                (out, n)
+            };
+            let vol_line = |v: &storage::VolInfo| {
+                let mut out = [0u8; 320]; let mut n = 0;
+                for &b in b"storage: vol=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(v.id as u64, &mut out[n..]);
+                for &b in b" name=" { out[n] = b; n += 1; }
+                for &b in v.name().as_bytes() { out[n] = b; n += 1; }
+                for (k, val) in [(&b" size="[..], mib(v.size)), (b"MiB allocated=", mib(v.allocated)), (b"MiB exclusive=", mib(v.exclusive))] {
+                    for &b in k { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(val, &mut out[n..]);
+                }
+                for &b in b"MiB" { out[n] = b; n += 1; }
+                if v.snapshot { for &b in b" snapshot" { out[n] = b; n += 1; } }
+                if v.parent != 0 {
+                    for &b in b" from=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v.parent as u64, &mut out[n..]);
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                (out, n)
+            };
+            if let Some(args) = rest.strip_prefix("pool") {
+                let args = args.trim();
+                let mut disk: Option<storage::Device> = None; let mut lba = 0u64; let mut count = 0u64; let mut force = false; let mut bad = false;
+                let (op, tail) = args.split_once(' ').unwrap_or((args, ""));
+                for w in tail.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("disk=") { disk = v.parse::<usize>().ok().map(storage::Device::Disk); bad |= disk.is_none(); }
+                    else if let Some(v) = w.strip_prefix("nvme=") { disk = crate::nvme::parse_ns(v).map(|(ctrl, nsid)| storage::Device::Nvme { ctrl, nsid }); bad |= disk.is_none(); }
+                    else if let Some(v) = w.strip_prefix("lba=") { match v.parse::<u64>() { Ok(x) => lba = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("count=") { match v.parse::<u64>() { Ok(x) => count = x, Err(_) => bad = true } }
+                    else if w == "force" { force = true; }
+                    else { bad = true; }
+                }
+                let res = match (op, disk) {
+                    ("format", Some(d)) if !bad => storage::format(system_table, d, lba, count, force),
+                    ("open", Some(d)) if !bad && count == 0 && !force => storage::open(system_table, d, lba),
+                    ("close", None) if !bad && tail.is_empty() => match storage::close(system_table) {
+                        Ok(()) => { let _ = system_table.stdout().write_str("storage: pool closed\r\n"); continue; }
+                        Err(e) => Err(e),
+                    },
+                    _ => { let _ = system_table.stdout().write_str("usage: storage pool format disk=<idx>|nvme=<c>n<ns> [lba=<n>] [count=<n>] [force] | open disk=<idx>|nvme=<c>n<ns> [lba=<n>] | close\r\n"); continue; }
+                };
+                match res {
+                    Ok(p) => { let (out, n) = pool_line(&p); let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest == "sync" {
+                match storage::sync(system_table) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("storage: metadata written\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("vol") {
+                let args = args.trim();
+                let (op, tail) = args.split_once(' ').unwrap_or((args, ""));
+                let mut name: Option<&str> = None; let mut size: Option<u64> = None; let mut sel: Option<&str> = None; let mut bad = false;
+                for w in tail.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("name=") { name = Some(v); }
+                    else if let Some(v) = w.strip_prefix("size=") { size = v.parse::<u64>().ok(); bad |= size.is_none(); }
+                    else if let Some(v) = w.strip_prefix("from=").or_else(|| w.strip_prefix("vol=")) { sel = Some(v); }
+                    else { bad = true; }
+                }
+                let src = sel.map(|s| storage::find(s).ok_or("storage: no such volume"));
+                let res = match (op, name, size, src) {
+                    ("create", Some(nm), Some(sz), None) if !bad => storage::create(system_table, nm, sz),
+                    ("snapshot", Some(nm), None, Some(s)) if !bad => s.and_then(|s| storage::copy(system_table, s, nm, true)),
+                    ("clone", Some(nm), None, Some(s)) if !bad => s.and_then(|s| storage::copy(system_table, s, nm, false)),
+                    ("delete", None, None, Some(s)) if !bad => match s.and_then(|s| storage::delete(system_table, s)) {
+                        Ok(()) => { let _ = system_table.stdout().write_str("storage: volume deleted\r\n"); continue; }
+                        Err(e) => Err(e),
+                    },
+                    _ => { let _ = system_table.stdout().write_str("usage: storage vol create name=<s> size=<MiB> | snapshot|clone from=<id|name> name=<s> | delete vol=<id|name>\r\n"); continue; }
+                };
+                match res.and_then(|id| storage::volume(id).ok_or("storage: no such volume")) {
+                    Ok(v) => { let (out, n) = vol_line(&v); let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if !rest.is_empty() { let _ = system_table.stdout().write_str("usage: storage [pool ...|vol ...|sync]\r\n"); continue; }
+            let stdout = system_table.stdout();
+            let Some(p) = storage::info() else { let _ = stdout.write_str("storage: no pool open\r\n"); continue };
+            let (out, n) = pool_line(&p);
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            storage::for_each(|v| { let (out, n) = vol_line(v); let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); });
+            continue;
+        }
+        if cmd.starts_with("vm blk") {
+            // vm blk | vm blk add id=<n> (file=<path> | disk=<idx> [lba=<n>] [count=<n>] | vol=<id|name> | nvme=<c>n<ns>) [ro] | vm blk hostdisks | vm blk pump [limit=<n>]
+            let rest = cmd[6..].trim();
+            if let Some(args) = rest.strip_prefix("add") {
+                let mut id: Option<u64> = None; let mut file: Option<&str> = None; let mut disk: Option<usize> = None;
+                let mut lba = 0u64; let mut count = 0u64; let mut ro = false; let mut bad = false; let mut vol: Option<&str> = None;
+                let mut nvme: Option<(usize, u32)> = None;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); bad |= id.is_none(); }
+                    else if let Some(v) = w.strip_prefix("file=") { file = Some(v); }
+                    else if let Some(v) = w.strip_prefix("vol=") { vol = Some(v); }
+                    else if let Some(v) = w.strip_prefix("nvme=") { nvme = crate::nvme::parse_ns(v); bad |= nvme.is_none(); }
+                    else if let Some(v) = w.strip_prefix("disk=") { disk = v.parse::<usize>().ok(); bad |= disk.is_none(); }
+                    else if let Some(v) = w.strip_prefix("lba=") { match v.parse::<u64>() { Ok(x) => lba = x, Err(_) => bad = true } }
+                    else if let Some(v) = w.strip_prefix("count=") { match v.parse::<u64>() { Ok(x) => count = x, Err(_) => bad = true } }
+                    else if w == "ro" { ro = true; }
+                    else { bad = true; }
+                }
+                if let Some(v) = vol {
+                    if file.is_some() || disk.is_some() { bad = true; }
+                    else if crate::hv::storage::find(v).is_none() { let _ = system_table.stdout().write_str("vm blk: no such volume\r\n"); continue; }
+                }
+                let backing = match (file, disk, vol.and_then(crate::hv::storage::find), nvme) {
+                    (Some(p), None, None, None) => crate::hv::vdev::blk::file_backing(p),
+                    (None, Some(d), None, None) => Some(crate::hv::vdev::blk::Backing::Extent { disk: d, lba, count }),
+                    (None, None, Some(v), None) => Some(crate::hv::vdev::blk::Backing::Volume { id: v }),
+                    (None, None, None, Some((ctrl, nsid))) => Some(crate::hv::vdev::blk::Backing::Nvme { ctrl, nsid }),
+                    _ => None,
+                };
+                let (id, backing) = match (id, backing) {
+                    (Some(i), Some(b)) if !bad => (i, b),
+                    _ => { let _ = system_table.stdout().write_str("usage: vm blk add id=<n> (file=<path> | disk=<idx> [lba=<n>] [count=<n>] | vol=<id|name> | nvme=<c>n<ns>) [ro]\r\n"); continue; }
+                };
+                if crate::hv::vm::find_vm(id).is_none() { let _ = system_table.stdout().write_str("vm blk: no such vm\r\n"); continue; }
+                match crate::hv::vdev::blk::add(system_table, id, backing, ro) {
+                    Ok((idx, dev)) => {
+                        let mut out = [0u8; 64]; let mut n = 0;
+                        for &b in b"vm blk: disk=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(idx as u64, &mut out[n..]);
+                        for &b in b" pci=00:" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_hex(dev as u64, &mut out[n..]);
+                        for &b in b".0" { out[n] = b; n += 1; }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if rest == "hostdisks" {
+                let mut any = false;
+                let mut lines = [[0u8; 96]; 16]; let mut lens = [0usize; 16]; let mut nl = 0usize;
+                crate::hv::vdev::blk::host_disks(system_table, |idx, d| {
+                    any = true;
+                    if nl == lines.len() { return; }
+                    let out = &mut lines[nl]; let mut n = 0;
+                    for &b in b"vm blk: host disk=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(idx as u64, &mut out[n..]);
+                    for &b in b" bs=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(d.block_size as u64, &mut out[n..]);
+                    for &b in b" blocks=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(d.last_block.saturating_add(1), &mut out[n..]);
+                    if d.logical_partition { for &b in b" partition" { out[n] = b; n += 1; } }
+                    if d.read_only { for &b in b" ro" { out[n] = b; n += 1; } }
+                    if !d.present { for &b in b" no-media" { out[n] = b; n += 1; } }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    lens[nl] = n; nl += 1;
+                });
+                let stdout = system_table.stdout();
+                for i in 0..nl { let _ = stdout.write_str(core::str::from_utf8(&lines[i][..lens[i]]).unwrap_or("\r\n")); }
+                if !any { let _ = stdout.write_str("vm blk: no Block I/O handles\r\n"); }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("pump") {
+                let limit = args.trim().strip_prefix("limit=").and_then(|v| v.parse::<usize>().ok()).unwrap_or(0);
+                let done = crate::hv::vdev::blk::pump(system_table, limit);
+                let mut out = [0u8; 48]; let mut n = 0;
+                for &b in b"vm blk: completed=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(done as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if !rest.is_empty() { let _ = system_table.stdout().write_str("usage: vm blk [add id=<n> ...|hostdisks|pump [limit=<n>]]\r\n"); continue; }
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::vdev::blk::for_each(|idx, d| {
+                any = true;
+                let mut out = [0u8; 256]; let mut n = 0;
+                for &b in b"vm blk: disk=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(idx as u64, &mut out[n..]);
+                for &b in b" vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(d.vm_id, &mut out[n..]);
+                for &b in b" pci=00:" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(d.dev as u64, &mut out[n..]);
+                match d.backing {
+                    crate::hv::vdev::blk::Backing::File { path, len } => {
+                        for &b in b".0 file=" { out[n] = b; n += 1; }
+                        for &b in &path[..len] { out[n] = b; n += 1; }
+                    }
+                    crate::hv::vdev::blk::Backing::Extent { disk, lba, .. } => {
+                        for &b in b".0 disk=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(disk as u64, &mut out[n..]);
+                        for &b in b" lba=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(lba, &mut out[n..]);
+                    }
+                    crate::hv::vdev::blk::Backing::Volume { id } => {
+                        for &b in b".0 vol=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(id as u64, &mut out[n..]);
+                    }
+                    crate::hv::vdev::blk::Backing::Nvme { ctrl, nsid } => {
+                        for &b in b".0 nvme=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(ctrl as u64, &mut out[n..]);
+                        out[n] = b'n'; n += 1;
+                        n += crate::util::format::u64_dec(nsid as u64, &mut out[n..]);
+                    }
+                }
+                for &b in b" sectors=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(d.capacity, &mut out[n..]);
+                if d.read_only { for &b in b" ro" { out[n] = b; n += 1; } }
+                let st: &[u8] = if d.driver_ok { b" driver=ok" } else { b" driver=none" };
+                for &b in st { out[n] = b; n += 1; }
+                for &b in b" rd=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(d.stats.reads, &mut out[n..]);
+                for &b in b"/" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(d.stats.bytes_read, &mut out[n..]);
+                for &b in b"B wr=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(d.stats.writes, &mut out[n..]);
+                for &b in b"/" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(d.stats.bytes_written, &mut out[n..]);
+                for &b in b"B flush=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(d.stats.flushes, &mut out[n..]);
+                for &b in b" err=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(d.stats.errors, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("vm blk: none\r\n"); }
+            continue;
+        }
+        if cmd == "net cni" {
+            // net cni: networks and attachments (defined through /v1/cni)
+            let stdout = system_table.stdout();
+            let ip4 = |ip: [u8; 4], o: &mut [u8]| -> usize {
+                let mut n = 0;
+                for (i, v) in ip.iter().enumerate() { n += crate::util::format::u64_dec(*v as u64, &mut o[n..]); if i != 3 { o[n] = b'.'; n += 1; } }
+                n
+            };
+            let mut any = false;
+            crate::hv::cni::for_each_network(|net| {
+                any = true;
+                let mut out = [0u8; 192]; let mut n = 0;
+                for &b in b"net cni: " { out[n] = b; n += 1; }
+                for &b in net.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" bridge=" { out[n] = b; n += 1; }
+                for &b in net.bridge().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" vlan=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(net.vlan as u64, &mut out[n..]);
+                for &b in b" subnet=" { out[n] = b; n += 1; }
+                n += ip4(net.subnet, &mut out[n..]);
+                out[n] = b'/'; n += 1;
+                n += crate::util::format::u64_dec(net.prefix as u64, &mut out[n..]);
+                for &b in b" range=" { out[n] = b; n += 1; }
+                n += ip4(net.range_start, &mut out[n..]);
+                out[n] = b'-'; n += 1;
+                n += ip4(net.range_end, &mut out[n..]);
+                for &b in b" gw=" { out[n] = b; n += 1; }
+                n += ip4(net.gateway, &mut out[n..]);
+                for &b in b" routes=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(net.routes().len() as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            crate::hv::cni::for_each(None, |a, net| {
+                let mut out = [0u8; 160]; let mut n = 0;
+                for &b in b"  vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(a.vm_id, &mut out[n..]);
+                out[n] = b' '; n += 1;
+                for &b in a.if_name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" net=" { out[n] = b; n += 1; }
+                for &b in net.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" ip=" { out[n] = b; n += 1; }
+                n += ip4(a.ip, &mut out[n..]);
+                match a.nic {
+                    Some(i) => { for &b in b" nic=" { out[n] = b; n += 1; } n += crate::util::format::u64_dec(i as u64, &mut out[n..]); }
+                    None => { for &b in b" pending" { out[n] = b; n += 1; } }
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("net cni: no networks\r\n"); }
+            continue;
+        }
+        if cmd == "cri" {
+            // cri: pod sandboxes and their containers (run through /v1/cri)
+            let _ = crate::hv::cri::pump(system_table);
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::cri::for_each_sandbox(|p| {
+                any = true;
+                let mut out = [0u8; 224]; let mut n = 0;
+                for &b in b"cri: pod " { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(p.id, &mut out[n..]);
+                out[n] = b' '; n += 1;
+                for &b in p.namespace().as_bytes() { out[n] = b; n += 1; }
+                out[n] = b'/'; n += 1;
+                for &b in p.name().as_bytes() { out[n] = b; n += 1; }
+                let state: &[u8] = if p.ready { b" ready" } else { b" notready" };
+                for &b in state { out[n] = b; n += 1; }
+                if let Some(net) = p.network() { for &b in b" net=" { out[n] = b; n += 1; } for &b in net.as_bytes() { out[n] = b; n += 1; } }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                crate::hv::cri::for_each_container(Some(p.id), |c| {
+                    let mut out = [0u8; 288]; let mut n = 0;
+                    for &b in b"  ctr " { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(c.id as u64, &mut out[n..]);
+                    out[n] = b' '; n += 1;
+                    for &b in c.name().as_bytes() { out[n] = b; n += 1; }
+                    out[n] = b' '; n += 1;
+                    for &b in c.image().as_bytes() { out[n] = b; n += 1; }
+                    out[n] = b' '; n += 1;
+                    for &b in c.state.name().as_bytes() { out[n] = b; n += 1; }
+                    if c.state == crate::hv::cri::ContainerState::Exited {
+                        for &b in b" exit=" { out[n] = b; n += 1; }
+                        n += crate::util::format::i64_dec(c.exit_code.into(), &mut out[n..]);
+                        out[n] = b' '; n += 1;
+                        for &b in c.reason.as_bytes() { out[n] = b; n += 1; }
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+            });
+            if !any { let _ = stdout.write_str("cri: no pods\r\n"); }
+            continue;
+        }
+        if cmd == "microvm" || cmd.starts_with("microvm ") {
+            // microvm | microvm create name=<s> path=<p> [mem=<MiB>] [vcpus=<n>] [net=<s>] [novsock] | microvm open vol=<id|name>
+            // microvm pool tmpl=<id|name> size=<n> | microvm start tmpl=<id|name> [name=<s>] | microvm remove tmpl=<id|name> [purge]
+            use crate::hv::microvm;
+            let rest = cmd[7..].trim();
+            let tmpl_line = |t: &microvm::Template| {
+                let mut out = [0u8; 448]; let mut n = 0;
+                for &b in b"microvm: tmpl=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(t.id as u64, &mut out[n..]);
+                for &b in b" name=" { out[n] = b; n += 1; }
+                for &b in t.name().as_bytes() { out[n] = b; n += 1; }
+                let l = &t.latency;
+                for (k, v) in [(&b" vol="[..], t.volume as u64), (b" mem=", t.memory_mib()), (b"MiB vcpus=", t.vcpus as u64), (b" pool=", microvm::pooled(t.id) as u64),
+                               (b"/", t.pool_target as u64), (b" clones=", t.clones as u64), (b" starts=", l.starts), (b" hits=", l.pool_hits),
+                               (b" fast=", l.on_target), (b" last=", l.last_us), (b"us best=", l.best_us), (b"us worst=", l.worst_us)] {
+                    for &b in k { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                for &b in b"us" { out[n] = b; n += 1; }
+                if let Some(net) = t.network() { for &b in b" net=" { out[n] = b; n += 1; } for &b in net.as_bytes() { out[n] = b; n += 1; } }
+                if let Some(e) = t.pool_error { for &b in b" (" { out[n] = b; n += 1; } for &b in e.as_bytes() { out[n] = b; n += 1; } out[n] = b')'; n += 1; }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                (out, n)
+            };
+            let (op, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            let mut name: Option<&str> = None; let mut path: Option<&str> = None; let mut mem: Option<u64> = None; let mut vcpus: Option<u32> = None;
+            let mut net: Option<&str> = None; let mut vsock = true; let mut vol: Option<&str> = None; let mut tmpl: Option<&str> = None;
+            let mut size: Option<u32> = None; let mut purge = false; let mut bad = false;
+            for w in tail.split_whitespace() {
+                if let Some(v) = w.strip_prefix("name=") { name = Some(v); }
+                else if let Some(v) = w.strip_prefix("path=") { path = Some(v); }
+                else if let Some(v) = w.strip_prefix("mem=") { mem = v.parse::<u64>().ok(); bad |= mem.is_none(); }
+                else if let Some(v) = w.strip_prefix("vcpus=") { vcpus = v.parse::<u32>().ok(); bad |= vcpus.is_none(); }
+                else if let Some(v) = w.strip_prefix("net=") { net = Some(v); }
+                else if let Some(v) = w.strip_prefix("vol=") { vol = Some(v); }
+                else if let Some(v) = w.strip_prefix("tmpl=") { tmpl = Some(v); }
+                else if let Some(v) = w.strip_prefix("size=") { size = v.parse::<u32>().ok(); bad |= size.is_none(); }
+                else if w == "novsock" { vsock = false; }
+                else if w == "purge" { purge = true; }
+                else { bad = true; }
+            }
+            let t = tmpl.map(|s| microvm::find(s).ok_or("microvm: no such template"));
+            let res = match (op, t) {
+                ("create", None) if !bad && name.is_some() && path.is_some() => {
+                    let mut cfg = microvm::TemplateConfig::new(name.unwrap_or(""), path.unwrap_or(""));
+                    cfg.memory_mib = mem.unwrap_or(cfg.memory_mib);
+                    cfg.vcpus = vcpus.unwrap_or(cfg.vcpus);
+                    cfg.vsock = vsock;
+                    cfg.network = net;
+                    microvm::create(system_table, &cfg)
+                }
+                ("open", None) if !bad && vol.is_some() => microvm::open(system_table, vol.unwrap_or("")),
+                ("pool", Some(t)) if !bad && size.is_some() => t.and_then(|t| microvm::set_pool(t, size.unwrap_or(0)).map(|_| t)),
+                ("start", Some(t)) if !bad => match t.and_then(|t| microvm::start(system_table, t, name)) {
+                    Ok(s) => {
+                        let mut out = [0u8; 96]; let mut n = 0;
+                        for &b in b"microvm: started vm " { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(s.vm_id, &mut out[n..]);
+                        for &b in b" in " { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(s.us, &mut out[n..]);
+                        let how: &[u8] = if s.pooled { b"us (pooled)\r\n" } else { b"us (cloned)\r\n" };
+                        for &b in how { out[n] = b; n += 1; }
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                },
+                ("remove", Some(t)) if !bad => match t.and_then(|t| microvm::remove(system_table, t, purge)) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("microvm: template removed\r\n"); continue; }
+                    Err(e) => Err(e),
+                },
+                ("", None) if !bad => {
+                    let stdout = system_table.stdout();
+                    let mut any = false;
+                    microvm::for_each_template(|t| { any = true; let (out, n) = tmpl_line(t); let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); });
+                    if !any { let _ = stdout.write_str("microvm: no templates\r\n"); }
+                    continue;
+                }
+                _ => { let _ = system_table.stdout().write_str("usage: microvm [create name=<s> path=<p> [mem=<MiB>] [vcpus=<n>] [net=<s>] [novsock] | open vol=<v> | pool tmpl=<t> size=<n> | start tmpl=<t> [name=<s>] | remove tmpl=<t> [purge]]\r\n"); continue; }
+            };
+            match res.and_then(|id| microvm::template(id).ok_or("microvm: no such template")) {
+                Ok(t) => { let (out, n) = tmpl_line(&t); let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); }
+                Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "wasm" || cmd.starts_with("wasm ") {
+            // wasm | wasm load id=<n> path=<p> [mem=<MiB>] | wasm log id=<n>; start and stop with vm run|stop
+            use crate::hv::wasm;
+            let rest = cmd[4..].trim();
+            let line = |s: &wasm::Status| {
+                let mut out = [0u8; 256]; let mut n = 0;
+                for &b in b"wasm: vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(s.vm_id, &mut out[n..]);
+                for &b in b" state=" { out[n] = b; n += 1; }
+                for &b in s.state.name().as_bytes() { out[n] = b; n += 1; }
+                if let wasm::State::Exited(code) = s.state { for &b in b" code=" { out[n] = b; n += 1; } n += crate::util::format::i64_dec(code as i64, &mut out[n..]); }
+                for (k, v) in [(&b" mem="[..], s.pages as u64), (b"/", s.limit as u64), (b"p insns=", s.instructions), (b" calls=", s.host_calls), (b" run=", s.run_us), (b"us parks=", s.parks)] {
+                    for &b in k { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                for &b in b" sig=" { out[n] = b; n += 1; }
+                for &b in s.signature.name().as_bytes() { out[n] = b; n += 1; }
+                if let wasm::State::Trapped(why) = s.state { for &b in b" (" { out[n] = b; n += 1; } for &b in why.as_bytes() { out[n] = b; n += 1; } out[n] = b')'; n += 1; }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                (out, n)
+            };
+            let (op, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            let mut id: Option<u64> = None; let mut path: Option<&str> = None; let mut mem = wasm::DEFAULT_MEMORY_MIB; let mut bad = false;
+            for w in tail.split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); bad |= id.is_none(); }
+                else if let Some(v) = w.strip_prefix("path=") { path = Some(v); }
+                else if let Some(v) = w.strip_prefix("mem=") { match v.parse::<u64>() { Ok(x) => mem = x, Err(_) => bad = true } }
+                else { bad = true; }
+            }
+            match (op, id, path) {
+                ("load", Some(id), Some(p)) if !bad => match wasm::load(system_table, id, p, mem) {
+                    Ok(s) => { let (out, n) = line(&s); let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                },
+                ("log", Some(id), None) if !bad => {
+                    let mut buf = [0u8; wasm::OUT_MAX];
+                    let n = wasm::output(id, &mut buf);
+                    let stdout = system_table.stdout();
+                    if n == 0 { let _ = stdout.write_str("wasm: no output\r\n"); continue; }
+                    for chunk in buf[..n].strip_suffix(b"\n").unwrap_or(&buf[..n]).split(|&b| b == b'\n') {
+                        let _ = stdout.write_str(core::str::from_utf8(chunk).unwrap_or("?"));
+                        let _ = stdout.write_str("\r\n");
+                    }
+                }
+                ("", None, None) if !bad => {
+                    let stdout = system_table.stdout();
+                    let mut any = false;
+                    wasm::for_each(|s| { any = true; let (out, n) = line(s); let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); });
+                    if !any { let _ = stdout.write_str("wasm: no modules loaded\r\n"); }
+                }
+                _ => { let _ = system_table.stdout().write_str("usage: wasm [load id=<n> path=<p> [mem=<MiB>] | log id=<n>]\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "plugin" || cmd.starts_with("plugin ") {
+            // plugin [list] | plugin load path=<esp path> | plugin enable|disable <name>
+            use crate::hv::plugin;
+            let line = |p: &plugin::Plugin| {
+                let mut out = [0u8; 192]; let mut n = 0;
+                for &b in b"plugin: " { out[n] = b; n += 1; }
+                for &b in p.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" v" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(p.version, &mut out[n..]);
+                for &b in b" abi=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(plugin::ABI_MAJOR as u32, &mut out[n..]);
+                out[n] = b'.'; n += 1;
+                n += crate::util::format::u32_dec(p.abi_minor as u32, &mut out[n..]);
+                for &b in if p.enabled { &b" enabled"[..] } else { &b" disabled"[..] } { out[n] = b; n += 1; }
+                for &b in b" hooks=" { out[n] = b; n += 1; }
+                let mut first = true;
+                for (on, name) in p.hooks().iter().zip(["vm_event", "sched_hint", "mmio"]) {
+                    if !on { continue; }
+                    if !first { out[n] = b','; n += 1; }
+                    first = false;
+                    for &b in name.as_bytes() { out[n] = b; n += 1; }
+                }
+                if first { out[n] = b'-'; n += 1; }
+                for (k, v) in [(&b" windows="[..], plugin::windows(p.handle) as u64), (b" calls=", p.calls), (b" pages=", p.pages as u64)] {
+                    for &b in k { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                for &b in b" sig=" { out[n] = b; n += 1; }
+                for &b in p.signature.name().as_bytes() { out[n] = b; n += 1; }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                (out, n)
+            };
+            let mut it = cmd[6..].split_whitespace();
+            match (it.next(), it.next(), it.next()) {
+                (None, ..) | (Some("list"), None, _) => {
+                    let stdout = system_table.stdout();
+                    let mut any = false;
+                    plugin::for_each(|p| { any = true; let (out, n) = line(p); let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); });
+                    if !any { let _ = stdout.write_str("plugin: none loaded\r\n"); }
+                }
+                (Some("load"), Some(arg), None) if arg.starts_with("path=") => match plugin::load(system_table, &arg[5..]) {
+                    Ok(p) => { let (out, n) = line(&p); let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                },
+                (Some(op @ ("enable" | "disable")), Some(name), None) => match plugin::set_enabled(name, op == "enable") {
+                    Ok(()) => { let _ = system_table.stdout().write_str(if op == "enable" { "plugin: enabled\r\n" } else { "plugin: disabled\r\n" }); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                },
+                _ => { let _ = system_table.stdout().write_str("usage: plugin [list | load path=<esp path> | enable|disable <name>]\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "net switch" || cmd.starts_with("net switch ") {
+            // net switch | net switch fdb [flush] | net switch aging secs=<n>
+            // net switch port nic=<n>|uplink [vlan=<n>|trunk] [flood=on|off] [bcast=<n>|off] | net switch vm id=<n> vlan=<n>|none
+            let rest = cmd[10..].trim();
+            let hex2 = |v: u8, o: &mut [u8]| { const H: &[u8; 16] = b"0123456789abcdef"; o[0] = H[(v >> 4) as usize]; o[1] = H[(v & 0xF) as usize]; };
+            let port_name = |p: crate::hv::vdev::switch::Port, o: &mut [u8]| -> usize {
+                match p {
+                    crate::hv::vdev::switch::Port::Uplink => { o[..6].copy_from_slice(b"uplink"); 6 }
+                    crate::hv::vdev::switch::Port::Nic(i) => { o[..3].copy_from_slice(b"nic"); 3 + crate::util::format::u64_dec(i as u64, &mut o[3..]) }
+                }
+            };
+            if rest == "fdb flush" {
+                let n = crate::hv::vdev::switch::flush();
+                let mut out = [0u8; 48]; let mut k = 0;
+                for &b in b"net switch: forgot " { out[k] = b; k += 1; }
+                k += crate::util::format::u64_dec(n as u64, &mut out[k..]);
+                for &b in b" addresses\r\n" { out[k] = b; k += 1; }
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..k]).unwrap_or("\r\n"));
+                continue;
+            }
+            if rest == "fdb" {
+                let stdout = system_table.stdout();
+                let mut any = false;
+                crate::hv::vdev::switch::for_each_fdb(|e| {
+                    any = true;
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    for &b in b"  " { out[n] = b; n += 1; }
+                    for (i, v) in e.mac.iter().enumerate() { hex2(*v, &mut out[n..]); n += 2; if i != 5 { out[n] = b':'; n += 1; } }
+                    for &b in b" vlan=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(e.vlan as u64, &mut out[n..]);
+                    for &b in b" port=" { out[n] = b; n += 1; }
+                    n += port_name(e.port, &mut out[n..]);
+                    if e.is_static { for &b in b" static" { out[n] = b; n += 1; } }
+                    else {
+                        for &b in b" age=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(e.age_ms / 1000, &mut out[n..]);
+                        out[n] = b's'; n += 1;
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                });
+                if !any { let _ = stdout.write_str("net switch: table empty\r\n"); }
+                continue;
+            }
+            if let Some(v) = rest.strip_prefix("aging secs=") {
+                let r = v.trim().parse::<u32>().map_err(|_| "usage: net switch aging secs=<n>").and_then(crate::hv::vdev::switch::set_aging);
+                match r {
+                    Ok(()) => { let _ = system_table.stdout().write_str("net switch: aging set\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("port ") {
+                let mut port = None; let mut bad = false;
+                let mut vlan = None; let mut flood = None; let mut bcast = None;
+                for w in args.split_whitespace() {
+                    if w == "uplink" { port = Some(crate::hv::vdev::switch::Port::Uplink); }
+                    else if let Some(v) = w.strip_prefix("nic=") { match v.parse::<usize>() { Ok(i) if i < crate::hv::vdev::net::MAX_NICS => port = Some(crate::hv::vdev::switch::Port::Nic(i)), _ => bad = true } }
+                    else if w == "vlan=trunk" { vlan = Some(0); }
+                    else if let Some(v) = w.strip_prefix("vlan=") { match v.parse::<u16>() { Ok(n) if n != 0 => vlan = Some(n), _ => bad = true } }
+                    else if w == "flood=on" { flood = Some(true); }
+                    else if w == "flood=off" { flood = Some(false); }
+                    else if w == "bcast=off" { bcast = Some(0); }
+                    else if let Some(v) = w.strip_prefix("bcast=") { match v.parse::<u32>() { Ok(n) => bcast = Some(n), Err(_) => bad = true } }
+                    else { bad = true; }
+                }
+                let Some(p) = port.filter(|_| !bad) else {
+                    let _ = system_table.stdout().write_str("usage: net switch port nic=<n>|uplink [vlan=<n>|trunk] [flood=on|off] [bcast=<n>|off]\r\n");
+                    continue;
+                };
+                let Some(cur) = crate::hv::vdev::switch::port(p) else { let _ = system_table.stdout().write_str("net switch: no such port (bridge-mode NICs only)\r\n"); continue; };
+                let cfg = crate::hv::vdev::switch::PortCfg { vlan: vlan.unwrap_or(cur.cfg.vlan), flood: flood.unwrap_or(cur.cfg.flood), bcast_per_s: bcast.unwrap_or(cur.cfg.bcast_per_s) };
+                match crate::hv::vdev::switch::set_port(p, cfg) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("net switch: port updated\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("vm ") {
+                let mut id = None; let mut vlan = None; let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); bad |= id.is_none(); }
+                    else if w == "vlan=none" { vlan = Some(0); }
+                    else if let Some(v) = w.strip_prefix("vlan=") { match v.parse::<u16>() { Ok(n) if n != 0 => vlan = Some(n), _ => bad = true } }
+                    else { bad = true; }
+                }
+                let (Some(id), Some(vlan), false) = (id, vlan, bad) else {
+                    let _ = system_table.stdout().write_str("usage: net switch vm id=<n> vlan=<n>|none\r\n");
+                    continue;
+                };
+                match crate::hv::vdev::switch::set_vm_vlan(id, vlan) {
+                    Ok(n) => {
+                        let mut out = [0u8; 64]; let mut k = 0;
+                        for &b in b"net switch: vlan set on " { out[k] = b; k += 1; }
+                        k += crate::util::format::u64_dec(n as u64, &mut out[k..]);
+                        for &b in b" port(s)\r\n" { out[k] = b; k += 1; }
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..k]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if !rest.is_empty() {
+                let _ = system_table.stdout().write_str("usage: net switch [fdb [flush]|aging secs=<n>|port ..|vm id=<n> vlan=<n>|none]\r\n");
+                continue;
+            }
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 48]; let mut n = 0;
+            for &b in b"net switch: aging=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(crate::hv::vdev::switch::aging() as u64, &mut out[n..]);
+            for &b in b"s\r\n" { out[n] = b; n += 1; }
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            crate::hv::vdev::switch::for_each_port(|p| {
+                let mut out = [0u8; 224]; let mut n = 0;
+                for &b in b"  " { out[n] = b; n += 1; }
+                n += port_name(p.port, &mut out[n..]);
+                if let Some(vm) = p.vm_id { for &b in b" vm=" { out[n] = b; n += 1; } n += crate::util::format::u64_dec(vm, &mut out[n..]); }
+                for &b in b" vlan=" { out[n] = b; n += 1; }
+                if p.cfg.vlan == 0 { for &b in b"trunk" { out[n] = b; n += 1; } } else { n += crate::util::format::u64_dec(p.cfg.vlan as u64, &mut out[n..]); }
+                for &b in if p.cfg.flood { b" flood=on".as_slice() } else { b" flood=off".as_slice() } { out[n] = b; n += 1; }
+                for &b in b" bcast=" { out[n] = b; n += 1; }
+                if p.cfg.bcast_per_s == 0 { for &b in b"off" { out[n] = b; n += 1; } } else { n += crate::util::format::u64_dec(p.cfg.bcast_per_s as u64, &mut out[n..]); }
+                for (label, v) in [(&b" rx="[..], p.stats.rx), (b" fwd=", p.stats.forwarded), (b" flooded=", p.stats.flooded), (b" filtered=", p.stats.filtered), (b" storm=", p.stats.storm_drops), (b" vlan_drops=", p.stats.vlan_drops)] {
+                    for &b in label { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(v, &mut out[n..]);
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            continue;
+        }
+        if cmd.starts_with("vm net") {
+            // vm net | vm net add id=<n> [mac=..] [mode=bridge|nat] [ip=a.b.c.d] | vm net uplink none|virtio|snp
+            // vm net nat hostip=<ip> hostmac=<mac> gwmac=<mac> | vm net pump [limit=<n>]
+            // vm net qos [id=<n> [rate=<mbps>|off] [prio=high|normal|low]]
+            let rest = cmd[6..].trim();
+            let hex2 = |v: u8, o: &mut [u8]| { const H: &[u8; 16] = b"0123456789abcdef"; o[0] = H[(v >> 4) as usize]; o[1] = H[(v & 0xF) as usize]; };
+            if let Some(args) = rest.strip_prefix("add") {
+                let mut id: Option<u64> = None; let mut mac: Option<[u8; 6]> = None; let mut ip = [0u8; 4];
+                let mut mode = crate::hv::vdev::net::Mode::Bridge; let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); bad |= id.is_none(); }
+                    else if let Some(v) = w.strip_prefix("mac=") { mac = crate::hv::vdev::net::parse_mac(v); bad |= mac.is_none(); }
+                    else if let Some(v) = w.strip_prefix("ip=") { match crate::hv::vdev::net::parse_ipv4(v) { Some(a) => ip = a, None => bad = true } }
+                    else if w == "mode=bridge" { mode = crate::hv::vdev::net::Mode::Bridge; }
+                    else if w == "mode=nat" { mode = crate::hv::vdev::net::Mode::Nat; }
+                    else { bad = true; }
+                }
+                let id = match id { Some(v) if !bad => v, _ => { let _ = system_table.stdout().write_str("usage: vm net add id=<n> [mac=xx:xx:xx:xx:xx:xx] [mode=bridge|nat] [ip=a.b.c.d]\r\n"); continue; } };
+                if crate::hv::vm::find_vm(id).is_none() { let _ = system_table.stdout().write_str("vm net: no such vm\r\n"); continue; }
+                if mode == crate::hv::vdev::net::Mode::Nat && ip == [0; 4] { let _ = system_table.stdout().write_str("vm net: nat mode needs ip=\r\n"); continue; }
+                // Locally administered default: 52:54:00:5a:<vm>:<k>, first one not in use.
+                let mut res = Err("vnet: no free MAC");
+                for k in 0..=255u8 {
+                    let m = mac.unwrap_or([0x52, 0x54, 0x00, 0x5a, id as u8, k]);
+                    res = crate::hv::vdev::net::add(id, m, mode, ip);
+                    if mac.is_some() || res != Err("vnet: MAC already in use") { break; }
+                }
+                match res {
+                    Ok((idx, dev)) => {
+                        let info = crate::hv::vdev::net::get(idx);
+                        let mut out = [0u8; 96]; let mut n = 0;
+                        for &b in b"vm net: nic=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(idx as u64, &mut out[n..]);
+                        for &b in b" pci=00:" { out[n] = b; n += 1; }
+                        hex2(dev, &mut out[n..]); n += 2;
+                        for &b in b".0 mac=" { out[n] = b; n += 1; }
+                        let m = info.map(|i| i.mac).unwrap_or([0; 6]);
+                        for (i, v) in m.iter().enumerate() { hex2(*v, &mut out[n..]); n += 2; if i != 5 { out[n] = b':'; n += 1; } }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(v) = rest.strip_prefix("uplink") {
+                let u = match v.trim() {
+                    "none" => crate::hv::vdev::net::Uplink::None,
+                    "virtio" => crate::hv::vdev::net::Uplink::Virtio,
+                    "snp" => crate::hv::vdev::net::Uplink::Snp,
+                    _ => { let _ = system_table.stdout().write_str("usage: vm net uplink none|virtio|snp\r\n"); continue; }
+                };
+                crate::hv::vdev::net::set_uplink(u);
+                let _ = system_table.stdout().write_str("vm net: uplink set\r\n");
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("nat") {
+                let mut h = crate::hv::vdev::nat::host(); let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("hostip=") { match crate::hv::vdev::net::parse_ipv4(v) { Some(a) => h.ip = a, None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("hostmac=") { match crate::hv::vdev::net::parse_mac(v) { Some(a) => h.mac = a, None => bad = true } }
+                    else if let Some(v) = w.strip_prefix("gwmac=") { match crate::hv::vdev::net::parse_mac(v) { Some(a) => h.gw_mac = a, None => bad = true } }
+                    else { bad = true; }
+                }
+                if bad { let _ = system_table.stdout().write_str("usage: vm net nat [hostip=a.b.c.d] [hostmac=<mac>] [gwmac=<mac>]\r\n"); continue; }
+                crate::hv::vdev::nat::set_host(h);
+                let mut out = [0u8; 128]; let mut n = 0;
+                for &b in b"vm net nat: hostip=" { out[n] = b; n += 1; }
+                for (i, v) in h.ip.iter().enumerate() { n += crate::util::format::u64_dec(*v as u64, &mut out[n..]); if i != 3 { out[n] = b'.'; n += 1; } }
+                for &b in b" hostmac=" { out[n] = b; n += 1; }
+                for (i, v) in h.mac.iter().enumerate() { hex2(*v, &mut out[n..]); n += 2; if i != 5 { out[n] = b':'; n += 1; } }
+                for &b in b" gwmac=" { out[n] = b; n += 1; }
+                for (i, v) in h.gw_mac.iter().enumerate() { hex2(*v, &mut out[n..]); n += 2; if i != 5 { out[n] = b':'; n += 1; } }
+                for &b in b" mappings=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(crate::hv::vdev::nat::mappings() as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("qos") {
+                use crate::hv::vdev::qos;
+                let stdout = system_table.stdout();
+                let line = |q: &qos::Qos| {
+                    let mut out = [0u8; 128]; let mut n = 0;
+                    for &b in b"vm net qos: vm=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(q.vm_id, &mut out[n..]);
+                    for &b in b" rate=" { out[n] = b; n += 1; }
+                    if q.rate_mbps == 0 { for &b in b"off" { out[n] = b; n += 1; } }
+                    else { n += crate::util::format::u64_dec(q.rate_mbps as u64, &mut out[n..]); for &b in b"Mbps" { out[n] = b; n += 1; } }
+                    for &b in b" prio=" { out[n] = b; n += 1; }
+                    for &b in q.prio.name().as_bytes() { out[n] = b; n += 1; }
+                    for &b in b" sent=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(q.sent, &mut out[n..]);
+                    for &b in b"B throttled=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(q.throttled, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    (out, n)
+                };
+                let args = args.trim();
+                if args.is_empty() {
+                    let mut any = false;
+                    qos::for_each(|q| { any = true; let (out, n) = line(q); let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); });
+                    if !any { let _ = stdout.write_str("vm net qos: no limits set\r\n"); }
+                    continue;
+                }
+                let mut id: Option<u64> = None; let mut rate: Option<u32> = None; let mut prio: Option<qos::Prio> = None; let mut bad = false;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); bad |= id.is_none(); }
+                    else if w == "rate=off" { rate = Some(0); }
+                    else if let Some(v) = w.strip_prefix("rate=") { rate = v.parse::<u32>().ok().filter(|&r| r != 0); bad |= rate.is_none(); }
+                    else if let Some(v) = w.strip_prefix("prio=") { prio = qos::Prio::parse(v); bad |= prio.is_none(); }
+                    else { bad = true; }
+                }
+                let id = match id { Some(v) if !bad => v, _ => { let _ = stdout.write_str("usage: vm net qos [id=<n> [rate=<mbps>|off] [prio=high|normal|low]]\r\n"); continue; } };
+                let cur = qos::get(id);
+                let rate = rate.unwrap_or(cur.map_or(0, |q| q.rate_mbps));
+                let prio = prio.unwrap_or(cur.map_or(qos::Prio::Normal, |q| q.prio));
+                match qos::set(id, rate, prio) {
+                    Ok(()) => match qos::get(id) {
+                        Some(q) => { let (out, n) = line(&q); let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n")); }
+                        None => { let _ = stdout.write_str("vm net qos: limits cleared\r\n"); }
+                    },
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("pump") {
+                let limit = args.trim().strip_prefix("limit=").and_then(|v| v.parse::<usize>().ok()).unwrap_or(0);
+                let (sent, recv) = crate::hv::vdev::net::pump(system_table, limit);
+                let mut out = [0u8; 64]; let mut n = 0;
+                for &b in b"vm net: sent=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(sent as u64, &mut out[n..]);
+                for &b in b" received=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(recv as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                continue;
+            }
+            if !rest.is_empty() { let _ = system_table.stdout().write_str("usage: vm net [add id=<n> ...|uplink none|virtio|snp|nat ...|qos ...|pump [limit=<n>]]\r\n"); continue; }
+            let stdout = system_table.stdout();
+            let up: &str = match crate::hv::vdev::net::uplink() { crate::hv::vdev::net::Uplink::None => "vm net: uplink=none\r\n", crate::hv::vdev::net::Uplink::Virtio => "vm net: uplink=virtio\r\n", crate::hv::vdev::net::Uplink::Snp => "vm net: uplink=snp\r\n" };
+            let _ = stdout.write_str(up);
+            crate::hv::vdev::net::for_each(|idx, nic| {
+                let mut out = [0u8; 256]; let mut n = 0;
+                for &b in b"vm net: nic=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(idx as u64, &mut out[n..]);
+                for &b in b" vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(nic.vm_id, &mut out[n..]);
+                for &b in b" pci=00:" { out[n] = b; n += 1; }
+                hex2(nic.dev, &mut out[n..]); n += 2;
+                for &b in b".0 bar=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(nic.bar, &mut out[n..]);
+                for &b in b" mac=" { out[n] = b; n += 1; }
+                for (i, v) in nic.mac.iter().enumerate() { hex2(*v, &mut out[n..]); n += 2; if i != 5 { out[n] = b':'; n += 1; } }
+                let mode: &[u8] = match nic.mode { crate::hv::vdev::net::Mode::Bridge => b" mode=bridge", crate::hv::vdev::net::Mode::Nat => b" mode=nat ip=" };
+                for &b in mode { out[n] = b; n += 1; }
+                if nic.mode == crate::hv::vdev::net::Mode::Nat {
+                    for (i, v) in nic.guest_ip.iter().enumerate() { n += crate::util::format::u64_dec(*v as u64, &mut out[n..]); if i != 3 { out[n] = b'.'; n += 1; } }
+                }
+                let st: &[u8] = if nic.driver_ok { b" driver=ok" } else { b" driver=none" };
+                for &b in st { out[n] = b; n += 1; }
+                for &b in b" tx=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(nic.stats.tx_frames, &mut out[n..]);
+                for &b in b"/" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(nic.stats.tx_bytes, &mut out[n..]);
+                for &b in b"B rx=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(nic.stats.rx_frames, &mut out[n..]);
+                for &b in b"/" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(nic.stats.rx_bytes, &mut out[n..]);
+                for &b in b"B drops=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(nic.stats.tx_drops + nic.stats.rx_drops, &mut out[n..]);
+                for &b in b" backlog=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(nic.backlog as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            continue;
+        }
+        if cmd.starts_with("vm rtc") {
+            // vm rtc id=<n> [mode=emul|host] [local|utc] [tz=<min>] [offset=<s>] [base=<unix>] [date=<unix>] [save]
+            let mut id: Option<u64> = None; let mut date: Option<i64> = None; let mut save = false; let mut bad = false;
+            let mut edits: [Option<&str>; 8] = [None; 8]; let mut ne = 0usize;
+            for w in cmd[6..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); continue; }
+                if let Some(v) = w.strip_prefix("date=") { date = v.parse::<i64>().ok(); bad |= date.is_none(); continue; }
+                if w == "save" { save = true; continue; }
+                if ne < edits.len() { edits[ne] = Some(w); ne += 1; }
+            }
+            let id = match id {
+                Some(v) => v,
+                None => { let _ = system_table.stdout().write_str("usage: vm rtc id=<n> [mode=emul|host] [local|utc] [tz=<min>] [offset=<s>] [base=<unix>] [date=<unix>] [save]\r\n"); continue; }
+            };
+            let mut cfg = match crate::hv::vtime::rtc_config(id) {
+                Some(c) => c,
+                None => { let _ = system_table.stdout().write_str("vm rtc: no such vm\r\n"); continue; }
+            };
+            for w in edits.iter().flatten() {
+                if let Some(v) = w.strip_prefix("mode=") {
+                    match v { "emul" => cfg.mode = crate::hv::vtime::rtc::RtcMode::Emulated, "host" => cfg.mode = crate::hv::vtime::rtc::RtcMode::Host, _ => bad = true }
+                } else if let Some(v) = w.strip_prefix("tz=") {
+                    match v.parse::<i16>() { Ok(m) if (-1440..=1440).contains(&m) => cfg.tz_minutes = m, _ => bad = true }
+                } else if let Some(v) = w.strip_prefix("offset=") {
+                    match v.parse::<i64>() { Ok(o) => cfg.offset_secs = o, Err(_) => bad = true }
+                } else if let Some(v) = w.strip_prefix("base=") {
+                    match v.parse::<i64>() { Ok(b) => cfg.base_unix = b, Err(_) => bad = true }
+                } else if *w == "local" { cfg.localtime = true; } else if *w == "utc" { cfg.localtime = false; } else { bad = true; }
+            }
+            if bad { let _ = system_table.stdout().write_str("vm rtc: invalid argument\r\n"); continue; }
+            let _ = crate::hv::vtime::set_rtc_config(id, cfg);
+            if let Some(t) = date {
+                if !crate::hv::vtime::set_rtc_time(id, t) { let _ = system_table.stdout().write_str("vm rtc: date ignored in host mode\r\n"); }
+            }
+            if save {
+                let cur = crate::hv::vtime::rtc_config(id).unwrap_or(cfg);
+                match crate::hv::vtime::rtc::save(system_table, id, &cur) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("vm rtc: saved\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+            }
+            let cfg = crate::hv::vtime::rtc_config(id).unwrap_or(cfg);
+            let dt = crate::hv::vtime::rtc_now(id).unwrap_or_default();
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 160]; let mut n = 0;
+            let two = |v: u8, o: &mut [u8]| { o[0] = b'0' + v / 10; o[1] = b'0' + v % 10; };
+            for &b in b"vm rtc: " { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(dt.year as u64, &mut out[n..]);
+            out[n] = b'-'; n += 1; two(dt.month, &mut out[n..]); n += 2;
+            out[n] = b'-'; n += 1; two(dt.day, &mut out[n..]); n += 2;
+            out[n] = b' '; n += 1; two(dt.hour, &mut out[n..]); n += 2;
+            out[n] = b':'; n += 1; two(dt.minute, &mut out[n..]); n += 2;
+            out[n] = b':'; n += 1; two(dt.second, &mut out[n..]); n += 2;
+            let mode: &[u8] = match cfg.mode { crate::hv::vtime::rtc::RtcMode::Emulated => b" mode=emul", crate::hv::vtime::rtc::RtcMode::Host => b" mode=host" };
+            for &b in mode { out[n] = b; n += 1; }
+            let zone: &[u8] = if cfg.localtime { b" local tz=" } else { b" utc tz=" };
+            for &b in zone { out[n] = b; n += 1; }
+            n += crate::util::format::i64_dec(cfg.tz_minutes.into(), &mut out[n..]);
+            for &b in b" offset=" { out[n] = b; n += 1; }
+            n += crate::util::format::i64_dec(cfg.offset_secs, &mut out[n..]);
+            for &b in b" base=" { out[n] = b; n += 1; }
+            n += crate::util::format::i64_dec(cfg.base_unix, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
             continue;
         }
-        if cmd.eq_ignore_ascii_case("vm list") {
+        if cmd.starts_with("vm mmio-trace") {
+            // vm mmio-trace | vm mmio-trace id=<n> base=<hex> len=<hex> [rate=<n>] | vm mmio-trace id=<n> off
+            let rest = cmd[13..].trim();
+            if !rest.is_empty() {
+                let mut id: Option<u64> = None; let mut base: Option<u64> = None; let mut len: Option<u64> = None;
+                let mut rate = 0u32; let mut off = false; let mut bad = false;
+                let hex = |v: &str| u64::from_str_radix(v.trim_start_matches("0x"), 16).ok();
+                for w in rest.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); bad |= id.is_none(); }
+                    else if let Some(v) = w.strip_prefix("base=") { base = hex(v); bad |= base.is_none(); }
+                    else if let Some(v) = w.strip_prefix("len=") { len = hex(v); bad |= len.is_none(); }
+                    else if let Some(v) = w.strip_prefix("rate=") { match v.parse::<u32>() { Ok(r) => rate = r, Err(_) => bad = true } }
+                    else if w == "off" { off = true; }
+                    else { bad = true; }
+                }
+                let id = match id { Some(v) if !bad => v, _ => { let _ = system_table.stdout().write_str("usage: vm mmio-trace id=<n> base=<hex> len=<hex> [rate=<n>] | vm mmio-trace id=<n> off\r\n"); continue; } };
+                if off {
+                    let n = crate::hv::mmiotrace::clear_vm(id);
+                    let _ = system_table.stdout().write_str(if n != 0 { "vm mmio-trace: off\r\n" } else { "vm mmio-trace: no filter\r\n" });
+                    continue;
+                }
+                if crate::hv::vm::find_vm(id).is_none() { let _ = system_table.stdout().write_str("vm mmio-trace: no such vm\r\n"); continue; }
+                let (base, len) = match (base, len) { (Some(b), Some(l)) => (b, l), _ => { let _ = system_table.stdout().write_str("vm mmio-trace: base= and len= required\r\n"); continue; } };
+                // The rate limiter runs on the monotonic clock.
+                let _ = crate::time::clock::init(system_table);
+                match crate::hv::mmiotrace::set(id, base, len, rate) {
+                    Ok(()) => { let _ = system_table.stdout().write_str("vm mmio-trace: on (see 'trace')\r\n"); }
+                    Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); }
+                }
+                continue;
+            }
             let stdout = system_table.stdout();
-            crate::hv::vm::list_vms(|info| {
+            let mut any = false;
+            crate::hv::mmiotrace::for_each(|f| {
+                any = true;
                 let mut out = [0u8; 128]; let mut n = 0;
-                for &b in b"vm: id=" { out[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(info.id as u32, &mut out[n..]);
-                for &b in b" vendor=" { out[n] = b; n += 1; }
-                let v: &[u8] = match info.vendor { crate::hv::vm::HvVendor::Intel => b"intel", crate::hv::vm::HvVendor::Amd => b"amd", crate::hv::vm::HvVendor::Unknown => b"unknown" };
-                for &b in v { out[n] = b; n += 1; }
-                for &b in b" pml4=0x" { out[n] = b; n += 1; }
-                n += crate::util::format::u64_hex(info.pml4_phys, &mut out[n..]);
-                for &b in b" mem=0x" { out[n] = b; n += 1; }
-                n += crate::util::format::u64_hex(info.memory_bytes, &mut out[n..]);
+                for &b in b"vm mmio-trace: vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(f.vm_id, &mut out[n..]);
+                for &b in b" base=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(f.base, &mut out[n..]);
+                for &b in b" len=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(f.len, &mut out[n..]);
+                for &b in b" rate=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(f.rate as u64, &mut out[n..]);
+                for &b in b"/s logged=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(f.logged, &mut out[n..]);
+                for &b in b" suppressed=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(f.suppressed, &mut out[n..]);
                 out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
                 let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
             });
+            if !any { let _ = stdout.write_str("vm mmio-trace: no filters\r\n"); }
             continue;
         }
-        if cmd.eq_ignore_ascii_case("vm pause") {
-            let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 64 << 20, vcpu_count: 1 });
-            vm.pause();
-            let _ = system_table.stdout().write_str("vm paused (trace event)\r\n");
+        if cmd.starts_with("vm acpi") {
+            // vm acpi id=<n>: tables the loader built for the guest
+            let id = cmd[7..].trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok());
+            let id = match id { Some(v) => v, None => { let _ = system_table.stdout().write_str("usage: vm acpi id=<n>\r\n"); continue; } };
+            let img = match crate::hv::loader::find_image(id) {
+                Some(i) => i,
+                None => { let _ = system_table.stdout().write_str("vm acpi: no image loaded\r\n"); continue; }
+            };
+            let area = match crate::hv::loader::gpa_to_host(id, crate::hv::acpi::ACPI_GPA) {
+                Some(h) => unsafe { core::slice::from_raw_parts(h as *const u8, crate::hv::acpi::ACPI_LEN) },
+                None => { let _ = system_table.stdout().write_str("vm acpi: area not mapped\r\n"); continue; }
+            };
+            let stdout = system_table.stdout();
+            crate::hv::acpi::for_each_table(area, crate::hv::acpi::ACPI_GPA, img.acpi_rsdp, |sig, gpa, len| {
+                let mut out = [0u8; 64]; let mut n = 0;
+                for &b in b"vm acpi: " { out[n] = b; n += 1; }
+                for &b in &sig { out[n] = b; n += 1; }
+                for &b in b" gpa=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(gpa, &mut out[n..]);
+                for &b in b" len=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(len, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
             continue;
         }
-        if cmd.eq_ignore_ascii_case("vm resume") {
-            let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 64 << 20, vcpu_count: 1 });
-            vm.resume();
-            let _ = system_table.stdout().write_str("vm resumed (trace event)\r\n");
+        if cmd.starts_with("vm numa") {
+            // vm numa id=<n> [vnuma=on|off]: where guest RAM sits per host node
+            let mut id = None; let mut set = None; let mut bad = false;
+            for w in cmd[7..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if w == "vnuma=on" { set = Some(true); }
+                else if w == "vnuma=off" { set = Some(false); }
+                else { bad = true; }
+            }
+            let id = match (id, bad) { (Some(v), false) => v, _ => { let _ = system_table.stdout().write_str("usage: vm numa id=<n> [vnuma=on|off]\r\n"); continue; } };
+            let info = match crate::hv::vm::find_vm(id) {
+                Some(i) => i,
+                None => { let _ = system_table.stdout().write_str("vm numa: no such vm\r\n"); continue; }
+            };
+            if let Some(on) = set {
+                if let Err(e) = crate::hv::numa::set_vnuma(id, on) { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); continue; }
+            }
+            let topo = crate::firmware::acpi::numa::topology(system_table);
+            let spread = crate::hv::numa::spread(system_table, id);
+            let want = crate::hv::numa::plan(system_table, info.affinity, crate::hv::loader::find_image(id).map_or(0, |i| i.ram_bytes));
+            let layout = crate::hv::numa::layout_of(id);
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 128]; let mut n = 0;
+            for &b in b"vm numa: id=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(id, &mut out[n..]);
+            for &b in b" affinity=" { out[n] = b; n += 1; }
+            if info.affinity == 0 { for &b in b"any" { out[n] = b; n += 1; } } else { for &b in b"0x" { out[n] = b; n += 1; } n += crate::util::format::u64_hex(info.affinity, &mut out[n..]); }
+            for &b in if crate::hv::numa::vnuma(id) { &b" vnuma=on"[..] } else { &b" vnuma=off"[..] } { out[n] = b; n += 1; }
+            match layout {
+                Some(l) => { for &b in b" exposed_nodes=" { out[n] = b; n += 1; } n += crate::util::format::u32_dec(l.nodes as u32, &mut out[n..]); }
+                None => for &b in b" exposed_nodes=none" { out[n] = b; n += 1; },
+            }
+            if !topo.from_srat { for &b in b" (no SRAT)" { out[n] = b; n += 1; } }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            for node in 0..topo.nodes.min(crate::firmware::acpi::numa::MAX_NODES) {
+                let planned = want.map_or(0, |w| w[node]);
+                if spread.bytes[node] == 0 && planned == 0 { continue; }
+                let mut n = 0;
+                for &b in b"  node " { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(node as u32, &mut out[n..]);
+                for &b in b" mem_mib=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(spread.bytes[node] >> 20, &mut out[n..]);
+                if want.is_some() {
+                    for &b in b" planned_mib=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(planned >> 20, &mut out[n..]);
+                }
+                if let Some(l) = layout {
+                    if let Some(v) = l.host[..l.nodes].iter().position(|&h| h as usize == node) {
+                        for &b in b" vnode=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(v as u32, &mut out[n..]);
+                        for &b in b" vcpus=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(l.vcpu[..l.vcpus].iter().filter(|&&x| x as usize == v).count() as u32, &mut out[n..]);
+                    }
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            if spread.other != 0 {
+                let mut n = 0;
+                for &b in b"  outside SRAT mem_mib=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(spread.other >> 20, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            }
+            if set.is_some() { let _ = stdout.write_str("vm numa: vnuma change applies at the next vm load\r\n"); }
+            continue;
+        }
+        if cmd.starts_with("vm run ") || cmd.starts_with("vm stop ") {
+            // vm run|stop id=<n>: vCPUs on their pinned APs
+            let is_run = cmd.starts_with("vm run ");
+            let id = cmd[if is_run { 7 } else { 8 }..].trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok());
+            let id = match id { Some(v) => v, None => { let _ = system_table.stdout().write_str("usage: vm run|stop id=<n>\r\n"); continue; } };
+            let mut out = [0u8; 64]; let mut n = 0;
+            if is_run {
+                match crate::hv::run::start(system_table, id) {
+                    Ok(k) => {
+                        for &b in b"vm run: started vcpus=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(k, &mut out[n..]);
+                    }
+                    Err(e) => { let stdout = system_table.stdout(); let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); continue; }
+                }
+            } else {
+                let left = crate::hv::run::stop(system_table, id, 200_000);
+                if left == 0 {
+                    for &b in b"vm stop: all vcpus stopped" { out[n] = b; n += 1; }
+                } else {
+                    for &b in b"vm stop: still running vcpus=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(left, &mut out[n..]);
+                }
+            }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd.starts_with("vm pin") {
+            // vm pin id=<n> [cpus=<hex>|any]: show or set the VM's CPU affinity
+            let mut id = None; let mut cpus = None; let mut bad = false;
+            for w in cmd[6..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if w == "cpus=any" { cpus = Some(0); }
+                else if let Some(v) = w.strip_prefix("cpus=") { match u64::from_str_radix(v.trim_start_matches("0x"), 16) { Ok(x) => cpus = Some(x), Err(_) => bad = true } }
+                else { bad = true; }
+            }
+            let id = match (id, bad) { (Some(v), false) => v, _ => { let _ = system_table.stdout().write_str("usage: vm pin id=<n> [cpus=<hex>|any]\r\n"); continue; } };
+            if let Some(mask) = cpus {
+                let res = crate::hv::sched::affinity::pin(system_table, id, mask);
+                let stdout = system_table.stdout();
+                match res {
+                    Ok(moved) => {
+                        let mut out = [0u8; 64]; let mut n = 0;
+                        for &b in b"vm pin: affinity set, moving=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(moved, &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            let madt = crate::hv::sched::affinity::madt_mask(system_table);
+            let stdout = system_table.stdout();
+            let Some(info) = crate::hv::vm::find_vm(id) else { let _ = stdout.write_str("vm pin: no such vm\r\n"); continue; };
+            let mut out = [0u8; 96]; let mut n = 0;
+            for &b in b"vm pin id=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(id, &mut out[n..]);
+            if info.affinity == 0 { for &b in b" cpus=any" { out[n] = b; n += 1; } }
+            else { for &b in b" cpus=0x" { out[n] = b; n += 1; } n += crate::util::format::u64_hex(info.affinity, &mut out[n..]); }
+            if let Some(m) = madt {
+                for &b in b" madt=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(m, &mut out[n..]);
+            }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            crate::hv::run::for_each(id, |_, r| {
+                if !matches!(r.state, crate::hv::run::RunState::Stopped | crate::hv::run::RunState::Failed) {
+                    let mut out = [0u8; 96]; let mut n = 0;
+                    let apic = crate::arch::x86::ap::apic_id(r.cpu);
+                    for &b in b"  vcpu=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(r.vcpu, &mut out[n..]);
+                    for &b in b" cpu=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(r.cpu as u32, &mut out[n..]);
+                    if let Some(a) = apic {
+                        for &b in b" apic=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(a, &mut out[n..]);
+                    }
+                    let inside = apic.is_some_and(|a| crate::hv::sched::affinity::allows(info.affinity, a));
+                    for &b in if inside { &b" in-mask=yes"[..] } else { &b" in-mask=no"[..] } { out[n] = b; n += 1; }
+                    if let Some(to) = r.pending_move() {
+                        for &b in b" moving-to=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(to as u32, &mut out[n..]);
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+            });
+            continue;
+        }
+        if cmd == "vm rt" || cmd.starts_with("vm rt ") {
+            // vm rt | vm rt set id=<n> period=<us> budget=<us> | vm rt clear id=<n>
+            let rest = cmd[5..].trim();
+            let stdout = system_table.stdout();
+            if rest.is_empty() {
+                let mut any = false;
+                for r in crate::hv::sched::rt::stats().iter().flatten() {
+                    any = true;
+                    let mut out = [0u8; 160]; let mut n = 0;
+                    for &b in b"vm rt id=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.vm_id, &mut out[n..]);
+                    for &b in b" period_us=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.resv.period_us, &mut out[n..]);
+                    for &b in b" budget_us=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.resv.budget_us, &mut out[n..]);
+                    for &b in b" util_ppm=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.resv.util_ppm(), &mut out[n..]);
+                    for &b in b" vcpus=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(r.vcpus as u32, &mut out[n..]);
+                    for &b in b" runs=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.runs, &mut out[n..]);
+                    for &b in b" exhausted=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.exhausted, &mut out[n..]);
+                    for &b in b" misses=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(r.misses, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                if !any { let _ = stdout.write_str("vm rt: no reservations\r\n"); }
+                continue;
+            }
+            let (op, args) = rest.split_once(' ').unwrap_or((rest, ""));
+            let mut id = None; let mut period = None; let mut budget = None; let mut bad = false;
+            for w in args.split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("period=") { match v.parse::<u64>() { Ok(x) => period = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("budget=") { match v.parse::<u64>() { Ok(x) => budget = Some(x), Err(_) => bad = true } }
+                else { bad = true; }
+            }
+            match (op, id, period, budget, bad) {
+                ("set", Some(id), Some(period_us), Some(budget_us), false) => {
+                    let Some(info) = crate::hv::vm::find_vm(id) else { let _ = stdout.write_str("vm rt: no such vm\r\n"); continue; };
+                    let r = crate::hv::sched::rt::Reservation { period_us, budget_us };
+                    match crate::hv::sched::rt::set(id, r, info.vcpus.max(1) as usize, info.affinity) {
+                        Ok(()) => { let _ = stdout.write_str("vm rt: reservation admitted\r\n"); }
+                        Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                    }
+                }
+                ("clear", Some(id), None, None, false) => {
+                    let ok = crate::hv::sched::rt::clear(id);
+                    let _ = stdout.write_str(if ok { "vm rt: reservation cleared\r\n" } else { "vm rt: no reservation\r\n" });
+                }
+                _ => { let _ = stdout.write_str("usage: vm rt | vm rt set id=<n> period=<us> budget=<us> | vm rt clear id=<n>\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "vm heartbeat" || cmd.starts_with("vm heartbeat ") {
+            // vm heartbeat [id=<n> interval=<ms> [misses=<n>] [action=log|pause|restart]|id=<n> off|resume]
+            let mut id = None; let mut interval = None; let mut misses = crate::hv::heartbeat::DEFAULT_MISSES;
+            let mut action = crate::hv::heartbeat::Action::Restart; let mut off = false; let mut resume = false; let mut bad = false;
+            for w in cmd[12..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("interval=") { match v.parse::<u32>() { Ok(x) => interval = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("misses=") { match v.parse::<u32>() { Ok(x) => misses = x, Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("action=") { match crate::hv::heartbeat::Action::parse(v) { Some(a) => action = a, None => bad = true } }
+                else if w == "off" { off = true; }
+                else if w == "resume" { resume = true; }
+                else { bad = true; }
+            }
+            let usage = "usage: vm heartbeat [id=<n> interval=<ms> [misses=<n>] [action=log|pause|restart]|id=<n> off|resume]\r\n";
+            if let Some(id) = id {
+                let res = match (interval, off, resume, bad) {
+                    (Some(ms), false, false, false) => crate::hv::heartbeat::set(crate::hv::heartbeat::Policy { vm_id: id, interval_ms: ms, misses, action }),
+                    (None, true, false, false) => if crate::hv::heartbeat::clear(id) { Ok(()) } else { Err("heartbeat: no policy for vm") },
+                    (None, false, true, false) => crate::hv::heartbeat::resume(id),
+                    _ => { let _ = system_table.stdout().write_str(usage); continue; }
+                };
+                let stdout = system_table.stdout();
+                match res {
+                    Ok(()) => { let _ = stdout.write_str("vm heartbeat: ok\r\n"); }
+                    Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+                }
+                continue;
+            }
+            if bad || interval.is_some() || off || resume { let _ = system_table.stdout().write_str(usage); continue; }
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::heartbeat::for_each(|e| {
+                any = true;
+                let mut out = [0u8; 160]; let mut n = 0;
+                for &b in b"heartbeat vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(e.policy.vm_id, &mut out[n..]);
+                for &b in b" interval=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(e.policy.interval_ms as u64, &mut out[n..]);
+                for &b in b"ms misses=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(e.missed as u64, &mut out[n..]);
+                out[n] = b'/'; n += 1;
+                n += crate::util::format::u64_dec(e.policy.misses as u64, &mut out[n..]);
+                for &b in b" action=" { out[n] = b; n += 1; }
+                for &b in e.policy.action.name().as_bytes() { out[n] = b; n += 1; }
+                for &b in b" beats=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(e.beats, &mut out[n..]);
+                for &b in b" actions=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(e.actions, &mut out[n..]);
+                if e.held { for &b in b" held" { out[n] = b; n += 1; } }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("vm heartbeat: no policies\r\n"); }
+            continue;
+        }
+        if cmd.starts_with("vm coredump") {
+            // vm coredump id=<n> path=<esp path>: pause the VM and write an ELF core
+            let mut id = None; let mut path = None; let mut bad = false;
+            for w in cmd[11..].split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("path=") { path = Some(v); }
+                else { bad = true; }
+            }
+            let (id, path) = match (id, path, bad) { (Some(i), Some(p), false) => (i, p), _ => { let _ = system_table.stdout().write_str("usage: vm coredump id=<n> path=<esp path>\r\n"); continue; } };
+            let res = crate::hv::coredump::write(system_table, id, path);
+            let stdout = system_table.stdout();
+            match res {
+                Ok(s) => {
+                    let mut out = [0u8; 128]; let mut n = 0;
+                    for &b in b"vm coredump: vcpus=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(s.vcpus, &mut out[n..]);
+                    for &b in b" regions=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(s.regions, &mut out[n..]);
+                    for &b in b" mem=" { out[n] = b; n += 1; }
+                    n += crate::util::format::size(s.bytes, &mut out[n..]);
+                    for &b in b" file=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_dec(s.file_bytes, &mut out[n..]);
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
+            continue;
+        }
+        if cmd == "vm gdb" || cmd.starts_with("vm gdb ") {
+            // vm gdb [attach id=<n> port=com1..com4|<hex>|detach id=<n>]
+            let mut words = cmd[6..].split_whitespace();
+            let op = words.next();
+            let mut id = None; let mut port = None; let mut bad = false;
+            for w in words {
+                if let Some(v) = w.strip_prefix("id=") { match v.parse::<u64>() { Ok(x) => id = Some(x), Err(_) => bad = true } }
+                else if let Some(v) = w.strip_prefix("port=") { match crate::hv::gdb::parse_port(v) { Some(x) => port = Some(x), None => bad = true } }
+                else { bad = true; }
+            }
+            let res = match (op, id, port, bad) {
+                (None, None, None, false) => {
+                    let stdout = system_table.stdout();
+                    let mut any = false;
+                    crate::hv::gdb::for_each(|s| {
+                        any = true;
+                        let mut out = [0u8; 96]; let mut n = 0;
+                        for &b in b"gdb: vm=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(s.vm_id, &mut out[n..]);
+                        for &b in b" port=0x" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_hex(s.port as u64, &mut out[n..]);
+                        for &b in if s.waiting { &b" running"[..] } else { &b" stopped"[..] } { out[n] = b; n += 1; }
+                        for &b in b" breaks=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u32_dec(s.breaks, &mut out[n..]);
+                        for &b in b" packets=" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(s.packets, &mut out[n..]);
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                    });
+                    if !any { let _ = stdout.write_str("gdb: no sessions\r\n"); }
+                    continue;
+                }
+                (Some("attach"), Some(id), Some(port), false) => crate::hv::gdb::attach(id, port).map(|_| "gdb: attached, vm halting\r\n"),
+                (Some("detach"), Some(id), None, false) => crate::hv::gdb::detach(id).map(|_| "gdb: detached\r\n"),
+                _ => { let _ = system_table.stdout().write_str("usage: vm gdb [attach id=<n> port=com1..com4|<hex>|detach id=<n>]\r\n"); continue; }
+            };
+            let stdout = system_table.stdout();
+            match res { Ok(m) => { let _ = stdout.write_str(m); } Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); } }
+            continue;
+        }
+        if cmd.starts_with("vm vcpus") {
+            // vm vcpus id=<n>: placement and state of each vCPU
+            let id = cmd[8..].trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok());
+            let id = match id { Some(v) => v, None => { let _ = system_table.stdout().write_str("usage: vm vcpus id=<n>\r\n"); continue; } };
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::run::for_each(id, |slot, r| {
+                any = true;
+                let mut out = [0u8; 160]; let mut n = 0;
+                for &b in b"vm vcpu=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(r.vcpu, &mut out[n..]);
+                for &b in b" cpu=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(r.cpu as u32, &mut out[n..]);
+                for &b in b" state=" { out[n] = b; n += 1; }
+                for &b in r.state.name().as_bytes() { out[n] = b; n += 1; }
+                if let Some(c) = crate::hv::sched::credit::credit(slot) {
+                    for &b in b" credit=" { out[n] = b; n += 1; }
+                    n += crate::util::format::i64_dec(c, &mut out[n..]);
+                }
+                for &b in b" exits=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(r.exits, &mut out[n..]);
+                for &b in b" last=" { out[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(r.last_exit, &mut out[n..]);
+                for &b in b" rip=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(r.rip, &mut out[n..]);
+                if let Some(why) = crate::hv::run::debug_state(slot).and_then(|d| d.parked) {
+                    for &b in b" parked=" { out[n] = b; n += 1; }
+                    for &b in why.name().as_bytes() { out[n] = b; n += 1; }
+                }
+                if let Some(e) = r.error {
+                    for &b in b" (" { out[n] = b; n += 1; }
+                    for &b in e.as_bytes() { if n + 3 < out.len() { out[n] = b; n += 1; } }
+                    out[n] = b')'; n += 1;
+                }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("vm vcpus: not started (vm run id=<n>)\r\n"); }
+            continue;
+        }
+        if cmd.starts_with("vm devices") {
+            // vm devices id=<n>: emulated MMIO regions and port ranges
+            let id = cmd[10..].trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok());
+            let id = match id { Some(v) => v, None => { let _ = system_table.stdout().write_str("usage: vm devices id=<n>\r\n"); continue; } };
+            let stdout = system_table.stdout();
+            let mut any = false;
+            crate::hv::bus::for_each(id, |is_mmio, base, len, name| {
+                any = true;
+                let mut out = [0u8; 96]; let mut n = 0;
+                let kind: &[u8] = if is_mmio { b"vm dev: mmio base=0x" } else { b"vm dev: pio  base=0x" };
+                for &b in kind { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(base, &mut out[n..]);
+                for &b in b" len=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(len, &mut out[n..]);
+                for &b in b" name=" { out[n] = b; n += 1; }
+                for &b in name.as_bytes() { if n + 2 < out.len() { out[n] = b; n += 1; } }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            crate::hv::vpci::for_each(id, |dev, vendor, device, class, subclass| {
+                any = true;
+                let mut out = [0u8; 96]; let mut n = 0;
+                for &b in b"vm dev: pci  00:" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(dev as u64, &mut out[n..]);
+                for &b in b".0 id=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(vendor as u64, &mut out[n..]);
+                out[n] = b':'; n += 1;
+                n += crate::util::format::u64_hex(device as u64, &mut out[n..]);
+                for &b in b" class=0x" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(((class as u64) << 8) | subclass as u64, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            });
+            if !any { let _ = stdout.write_str("vm devices: none\r\n"); }
+            continue;
+        }
+        if cmd.starts_with("vm timeinfo") {
+            // vm timeinfo id=<n>
+            let id = cmd[11..].trim().strip_prefix("id=").and_then(|v| v.parse::<u64>().ok());
+            let id = match id { Some(v) => v, None => { let _ = system_table.stdout().write_str("usage: vm timeinfo id=<n>\r\n"); continue; } };
+            let _ = crate::hv::vtime::poll(id);
+            let ti = match crate::hv::vtime::timeinfo(id) {
+                Some(t) => t,
+                None => { let _ = system_table.stdout().write_str("vm timeinfo: no such vm\r\n"); continue; }
+            };
+            let stdout = system_table.stdout();
+            let mut out = [0u8; 192]; let mut n = 0;
+            for &b in b"vm time: tsc_hz=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(ti.tsc_hz, &mut out[n..]);
+            for &b in b" host_tsc=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(ti.host_tsc, &mut out[n..]);
+            for &b in b" guest_tsc=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(ti.guest_tsc, &mut out[n..]);
+            for &b in b" offset=0x" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(ti.tsc_offset, &mut out[n..]);
+            if ti.paused { for &b in b" paused" { out[n] = b; n += 1; } }
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            n = 0;
+            for &b in b"vm time: host_ns=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(ti.host_elapsed_ns, &mut out[n..]);
+            for &b in b" guest_ns=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(ti.guest_tsc_ns, &mut out[n..]);
+            for &b in b" skew_ns=" { out[n] = b; n += 1; }
+            let skew = ti.tsc_skew_ns();
+            n += crate::util::format::i64_dec(skew, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            n = 0;
+            for &b in b"vm time: hpet=" { out[n] = b; n += 1; }
+            let he: &[u8] = if ti.hpet_enabled { b"on" } else { b"off" };
+            for &b in he { out[n] = b; n += 1; }
+            for &b in b" hpet_ns=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(ti.hpet_ns, &mut out[n..]);
+            for &b in b" pit_count=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(ti.pit_count as u64, &mut out[n..]);
+            for &b in b" pit_rate_mhz=" { out[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(ti.pit_rate_mhz, &mut out[n..]);
+            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            continue;
+        }
+        if cmd.starts_with("vm admission") {
+            // vm admission [show] | vm admission ratio [vcpu=<pct>] [mem=<pct>] | vm admission hugepool=<MiB> | vm admission devices=<n> | vm admission release id=<n>
+            crate::hv::admission::init_capacity(system_table);
+            let rest = cmd[12..].trim();
+            if rest.is_empty() || rest.eq_ignore_ascii_case("show") {
+                crate::hv::admission::report(system_table);
+                continue;
+            }
+            if let Some(args) = rest.strip_prefix("ratio") {
+                let mut vcpu: u32 = 0; let mut mem: u32 = 0;
+                for w in args.split_whitespace() {
+                    if let Some(v) = w.strip_prefix("vcpu=") { vcpu = v.parse::<u32>().unwrap_or(0); continue; }
+                    if let Some(v) = w.strip_prefix("mem=") { mem = v.parse::<u32>().unwrap_or(0); continue; }
+                }
+                if vcpu == 0 && mem == 0 { let _ = system_table.stdout().write_str("usage: vm admission ratio [vcpu=<pct>] [mem=<pct>]\r\n"); continue; }
+                crate::hv::admission::set_ratios(vcpu, mem);
+                crate::hv::admission::report(system_table);
+                continue;
+            }
+            if let Some(v) = rest.strip_prefix("hugepool=") {
+                match v.trim().parse::<u64>() {
+                    Ok(mib) => { crate::hv::admission::set_hugepage_pool(mib << 20); crate::hv::admission::report(system_table); }
+                    Err(_) => { let _ = system_table.stdout().write_str("usage: vm admission hugepool=<MiB>\r\n"); }
+                }
+                continue;
+            }
+            if let Some(v) = rest.strip_prefix("devices=") {
+                match v.trim().parse::<u32>() {
+                    Ok(d) => { crate::hv::admission::set_max_devices(d); crate::hv::admission::report(system_table); }
+                    Err(_) => { let _ = system_table.stdout().write_str("usage: vm admission devices=<n>\r\n"); }
+                }
+                continue;
+            }
+            if let Some(v) = rest.strip_prefix("release id=") {
+                let ok = v.trim().parse::<u64>().map(crate::hv::admission::release).unwrap_or(false);
+                let _ = system_table.stdout().write_str(if ok { "admission: released\r\n" } else { "admission: no reservation\r\n" });
+                continue;
+            }
+            let _ = system_table.stdout().write_str("usage: vm admission [show] | vm admission ratio [vcpu=<pct>] [mem=<pct>] | vm admission hugepool=<MiB> | vm admission devices=<n> | vm admission release id=<n>\r\n");
+            continue;
+        }
+        if cmd.starts_with("vm load") {
+            // vm load id=<n> path=<esp path> [mem=<MiB>] [at=<hex>] [cmdline=<text...>]
+            let rest = cmd[7..].trim();
+            // cmdline= consumes the remainder of the line so it may contain spaces
+            let (opts, cmdline) = match rest.find("cmdline=") { Some(p) => (&rest[..p], rest[p + 8..].trim()), None => (rest, "") };
+            let mut id: Option<u64> = None; let mut path: &str = ""; let mut mem_mib: u64 = 256;
+            let mut at: u64 = crate::hv::loader::DEFAULT_LOAD_GPA;
+            for w in opts.split_whitespace() {
+                if let Some(v) = w.strip_prefix("id=") { id = v.parse::<u64>().ok(); continue; }
+                if let Some(v) = w.strip_prefix("path=") { path = v; continue; }
+                if let Some(v) = w.strip_prefix("mem=") { mem_mib = v.parse::<u64>().unwrap_or(mem_mib); continue; }
+                if let Some(v) = w.strip_prefix("at=") { at = u64::from_str_radix(v.trim_start_matches("0x"), 16).unwrap_or(at); continue; }
+            }
+            let id = match id { Some(v) if !path.is_empty() => v, _ => { let _ = system_table.stdout().write_str("usage: vm load id=<n> path=<esp path> [mem=<MiB>] [at=<hex>] [cmdline=<text>]\r\n"); continue; } };
+            let req = crate::hv::loader::LoadRequest { vm_id: id, path, ram_bytes: mem_mib << 20, flat_load_gpa: at, cmdline };
+            let r = crate::hv::loader::load(system_table, &req);
+            let stdout = system_table.stdout();
+            match r {
+                Ok(img) => {
+                    let mut out = [0u8; 224]; let mut n = 0;
+                    for &b in b"vm load: id=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(id as u32, &mut out[n..]);
+                    let k: &[u8] = match img.kind { crate::hv::loader::ImageKind::Linux => b" kind=linux", crate::hv::loader::ImageKind::Flat => b" kind=flat" };
+                    for &b in k { out[n] = b; n += 1; }
+                    if img.boot_version != 0 {
+                        for &b in b" proto=0x" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_hex(img.boot_version as u64, &mut out[n..]);
+                    }
+                    for &b in b" load=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(img.load_gpa, &mut out[n..]);
+                    for &b in b" size=" { out[n] = b; n += 1; }
+                    n += crate::util::format::u32_dec(img.image_bytes as u32, &mut out[n..]);
+                    for &b in b" rip=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(img.regs.rip, &mut out[n..]);
+                    for &b in b" ram=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(img.ram_host, &mut out[n..]);
+                    for &b in b" root=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(img.root_phys, &mut out[n..]);
+                    for &b in b" rsdp=0x" { out[n] = b; n += 1; }
+                    n += crate::util::format::u64_hex(img.acpi_rsdp, &mut out[n..]);
+                    for &b in b" sig=" { out[n] = b; n += 1; }
+                    for &b in img.signature.name().as_bytes() { out[n] = b; n += 1; }
+                    if let crate::hv::imgsig::Verdict::Trusted { key } = img.signature {
+                        for &b in b"/key" { out[n] = b; n += 1; }
+                        n += crate::util::format::u64_dec(key as u64, &mut out[n..]);
+                    }
+                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                }
+                Err(e) => { let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n"); }
+            }
             continue;
         }
         if cmd.starts_with("vm ") {
             let rest = &cmd[3..];
-            if rest.eq_ignore_ascii_case("new") {
-                let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 256 << 20, vcpu_count: 1 });
-            let _ = crate::hv::vm::register_vm(&vm);
+            if rest.eq_ignore_ascii_case("new") || rest.starts_with("new ") {
+                // vm new [vcpus=<n>] [mem=<MiB>] [huge=<MiB>] [dev=<n>] [cvm=sev|sev-es|sev-snp|tdx] [place=auto|local]
+                //        [group=<label> [affinity=host|numa|anti-affinity=host|numa]]
+                let mut vcpus: u32 = 1; let mut mem_mib: u64 = 256; let mut huge_mib: u64 = 0; let mut devs: u32 = 0;
+                let mut cvm = crate::hv::confidential::Kind::None;
+                let mut name = None;
+                let mut place = crate::cluster::placement::enabled();
+                let (mut label, mut rule) = (None, None);
+                for w in rest[3..].split_whitespace() {
+                    if let Some(v) = w.strip_prefix("place=") { place = v != "local"; continue; }
+                    if let Some(v) = w.strip_prefix("group=") { label = Some(v); continue; }
+                    if let Some(r) = crate::cluster::placement::Rule::parse(w) { rule = Some(r); continue; }
+                    if let Some(v) = w.strip_prefix("vcpus=") { vcpus = v.parse::<u32>().unwrap_or(vcpus); continue; }
+                    if let Some(v) = w.strip_prefix("mem=") { mem_mib = v.parse::<u64>().unwrap_or(mem_mib); continue; }
+                    if let Some(v) = w.strip_prefix("huge=") { huge_mib = v.parse::<u64>().unwrap_or(huge_mib); continue; }
+                    if let Some(v) = w.strip_prefix("dev=") { devs = v.parse::<u32>().unwrap_or(devs); continue; }
+                    if let Some(v) = w.strip_prefix("cvm=") { cvm = crate::hv::confidential::Kind::parse(v).unwrap_or(cvm); continue; }
+                    if let Some(v) = w.strip_prefix("name=") { name = Some(v); continue; }
+                }
+                if let Some(v) = name {
+                    if crate::hv::vm::find_vm_by_name(v).is_some() { let _ = system_table.stdout().write_str("vm: name in use\r\n"); continue; }
+                }
+                let group = match label {
+                    Some(l) => match crate::cluster::placement::Group::new(l, rule.unwrap_or(crate::cluster::placement::Rule::AntiAffinity(crate::cluster::placement::Scope::Host))) {
+                        Ok(g) => Some(g),
+                        Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); continue; }
+                    },
+                    None if rule.is_some() => { let _ = system_table.stdout().write_str("vm: affinity rules need group=<label>\r\n"); continue; }
+                    None => None,
+                };
+                // Confidential VMs need this host's keys and are never forwarded.
+                let placed = if place && cvm == crate::hv::confidential::Kind::None {
+                    let mut spec = match crate::cluster::placement::Spec::new(vcpus, mem_mib, huge_mib, devs, name) {
+                        Ok(s) => s,
+                        Err(e) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); continue; }
+                    };
+                    spec.group = group;
+                    let d = crate::cluster::placement::decide(system_table, &spec);
+                    if let crate::cluster::placement::Target::Remote { node, .. } = d.target {
+                        let mut out = [0u8; 128]; let mut n = 0;
+                        match crate::cluster::placement::forward(&spec, &d) {
+                            Ok(ticket) => {
+                                for &b in b"vm: forwarded to node=" { out[n] = b; n += 1; }
+                                n += crate::util::format::u64_dec(node, &mut out[n..]);
+                                for &b in b" ticket=" { out[n] = b; n += 1; }
+                                n += crate::util::format::u64_dec(ticket, &mut out[n..]);
+                                for &b in b" score=" { out[n] = b; n += 1; }
+                                n += crate::util::format::u64_dec(d.score.unwrap_or(0) as u64, &mut out[n..]);
+                                for &b in b" local=" { out[n] = b; n += 1; }
+                                match d.local_score { Some(l) => n += crate::util::format::u64_dec(l as u64, &mut out[n..]), None => for &b in b"unfit" { out[n] = b; n += 1; } }
+                            }
+                            Err(e) => for &b in e.as_bytes() { out[n] = b; n += 1; },
+                        }
+                        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                        continue;
+                    }
+                    Some((spec, d))
+                } else { None };
+                let numa = match group.map(|g| crate::cluster::placement::admit_group(system_table, &g)) {
+                    Some(Ok(n)) => n,
+                    Some(Err(e)) => { let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n"); continue; }
+                    None => None,
+                };
+                let vm = match crate::hv::vm::Vm::try_create(system_table, crate::hv::vm::VmConfig { memory_bytes: mem_mib << 20, vcpu_count: vcpus, confidential: cvm, ..Default::default() }, huge_mib << 20, devs) {
+                    Ok(vm) => vm,
+                    Err(e) => {
+                        let mut out = [0u8; 160];
+                        let n = e.write_line(&mut out);
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                        continue;
+                    }
+                };
+                if let Err(e) = crate::hv::vm::register(&vm, name) {
+                    vm.destroy();
+                    let stdout = system_table.stdout();
+                    let _ = stdout.write_str(e); let _ = stdout.write_str("\r\n");
+                    continue;
+                }
+                if let Some((spec, d)) = placed { crate::cluster::placement::placed_locally(vm.id.0, &spec, &d); }
+                if let Some(g) = group {
+                    if let Err(e) = crate::cluster::placement::join(system_table, vm.id.0, g, numa) {
+                        let _ = system_table.stdout().write_str(e); let _ = system_table.stdout().write_str("\r\n");
+                    }
+                }
                 let stdout = system_table.stdout();
-                let mut out = [0u8; 64]; let mut n = 0;
+                let mut out = [0u8; 96]; let mut n = 0;
                 for &b in b"vm id=" { out[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(vm.id.0 as u32, &mut out[n..]);
+                n += crate::util::format::u32_dec(vm.id.0 as u32, &mut out[n..]);
+                if let Some(info) = crate::hv::vm::find_vm(vm.id.0) {
+                    for &b in b" name=" { out[n] = b; n += 1; }
+                    for &b in info.name().as_bytes() { out[n] = b; n += 1; }
+                }
+                if cvm != crate::hv::confidential::Kind::None {
+                    for &b in b" cvm=" { out[n] = b; n += 1; }
+                    for &b in cvm.name().as_bytes() { out[n] = b; n += 1; }
+                }
                 out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
                 let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
                 continue;
             }
             if rest.eq_ignore_ascii_case("start") {
-                let vm = crate::hv::vm::Vm::create(system_table, crate::hv::vm::VmConfig { memory_bytes: 256 << 20, vcpu_count: 1 });
+                let vm = match crate::hv::vm::Vm::try_create(system_table, crate::hv::vm::VmConfig { memory_bytes: 256 << 20, vcpu_count: 1, ..Default::default() }, 0, 0) {
+                    Ok(vm) => vm,
+                    Err(e) => {
+                        let mut out = [0u8; 160];
+                        let n = e.write_line(&mut out);
+                        let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                        continue;
+                    }
+                };
             let _ = crate::hv::vm::register_vm(&vm);
                 let mut vcpu = crate::hv::vcpu::Vcpu::new(0);
                 vcpu.start();
-                vm.start(system_table);
+                let msg = match vm.start(system_table) { Ok(()) => "vm started", Err(e) => e };
                 let stdout = system_table.stdout();
-                let _ = stdout.write_str("vm started\r\n");
+                let _ = stdout.write_str(msg);
+                let _ = stdout.write_str("\r\n");
                 continue;
             }
             let stdout = system_table.stdout();
-            let _ = stdout.write_str("usage: vm | vm new | vm start\r\n");
+            let _ = stdout.write_str("usage: vm | vm new [vcpus=<n>] [mem=<MiB>] [huge=<MiB>] [dev=<n>] [cvm=sev|sev-es|sev-snp|tdx] [name=<s>] [place=auto|local] [group=<label> [affinity=host|numa|anti-affinity=host|numa]] | vm info|destroy id=<n>|name=<s> | vm rename id=<n> name=<s> | vm pause|resume id=<n>|name=<s> | vm start | vm load id=<n> path=<esp path> | vm admission | vm apicv | vm apic id=<n> | vm irq id=<n> vec=<n> | vm timeinfo id=<n> | vm devices id=<n> | vm acpi id=<n> | vm numa id=<n> [vnuma=on|off] | vm run|stop id=<n> | vm vcpus id=<n> | vm rt [set id=<n> period=<us> budget=<us>|clear id=<n>] | vm pin id=<n> [cpus=<hex>|any] | vm mmio-trace [id=<n> base=<hex> len=<hex>|id=<n> off] | vm rtc id=<n> [mode=emul|host] [local|utc] [tz=<min>] [date=<unix>] [save] | vm rtc id=<n> [mode=emul|host] [local|utc] [tz=<min>] [offset=<s>] [date=<unix>] [save] | vm net [add|uplink|nat|pump] | vm blk [add|hostdisks|pump] | vm console [add|id=<n>] | vm vsock [add|id=<n>] | vm balloon [add|id=<n>|reclaim|relax|pump] | vm shm [create|destroy|attach|detach|perm] | vm ping|exec id=<n> | vm attach|detach id=<n> vf=<bdf> | vm gdb [attach|detach id=<n>] | vm coredump id=<n> path=<esp path> | vm heartbeat [id=<n> interval=<ms>|id=<n> off|resume]\r\n");
             continue;
         }
         // Unknown
@@ -0,0 +1,540 @@
+#![allow(dead_code)]
+
+//! Management-network HTTP responder.
+//!
+//! Serves `GET /metrics` in the Prometheus text format (everything
+//! `obs::prom::render` emits plus per-VM series), and the token-protected
+//! JSON management API under `/v1` (see `ctl::api`), on an IPv4 address of its
+//! own, reached through the `vm net` uplink (host virtio-net or SNP), so a
+//! stock scraper can collect hypervisor telemetry. `on_frame` claims frames
+//! for that address out of `vdev::net::inbound` and answers ARP for it;
+//! everything else still goes to the guests. `poll`, run from the CLI idle
+//! loop right after the NIC pump, sends whatever is due.
+//!
+//! The TCP side is only what a scraper or API client needs: a few
+//! connections, one request each (`Connection: close`), the response rendered
+//! into a per-connection buffer once the request headers and any
+//! `Content-Length` body are in, sent within the peer's window and
+//! resent from the last acknowledged byte when no ACK arrives in time.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::vdev::net::{self, Uplink};
+use crate::obs::prom::{Line, PREFIX};
+use crate::time::clock::now_ms;
+use crate::util::spinlock::SpinLock;
+
+pub const DEFAULT_PORT: u16 = 9100;
+pub const MAX_CONNS: usize = 4;
+/// Request line, headers and body together
+const REQ_MAX: usize = 1024;
+const RESP_MAX: usize = 32 * 1024;
+/// Room kept in front of the body for the status line and headers
+const HDR_SPACE: usize = 192;
+const MSS: u16 = 1460;
+/// Receive window we advertise; requests are small
+const WINDOW: u16 = 4096;
+/// Segments in flight at most, whatever the peer's window
+const BURST: u32 = 8;
+const RTO_MS: u64 = 300;
+const MAX_RETRIES: u8 = 6;
+/// Connections with no traffic for this long are dropped
+const IDLE_MS: u64 = 10_000;
+
+const ETH_HDR: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const PROTO_TCP: u8 = 6;
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub ip: [u8; 4],
+    pub mac: [u8; 6],
+    pub port: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Tcp {
+    /// SYN seen, SYN-ACK not yet acknowledged
+    SynRcvd,
+    /// Reading the request
+    Established,
+    /// Response (then FIN) going out
+    Sending,
+    /// Our FIN acknowledged, waiting for the peer's
+    FinWait,
+}
+
+#[derive(Clone, Copy)]
+struct Conn {
+    peer_mac: [u8; 6],
+    peer_ip: [u8; 4],
+    peer_port: u16,
+    state: Tcp,
+    /// Our initial sequence number; response byte k is `iss + 1 + k`
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    wnd: u32,
+    mss: u16,
+    peer_fin: bool,
+    ack_due: bool,
+    /// Request headers and body complete (or the peer closed); respond at next poll
+    req_done: bool,
+    req_len: usize,
+    /// Response bytes at `resp[resp_off..resp_off + resp_len]`
+    resp_off: usize,
+    resp_len: usize,
+    last_tx: u64,
+    last_rx: u64,
+    retries: u8,
+}
+
+impl Conn {
+    fn fin_seq(&self) -> u32 { self.iss.wrapping_add(1).wrapping_add(self.resp_len as u32) }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub requests: u64,
+    pub connections: u64,
+    pub retransmits: u64,
+    pub drops: u64,
+}
+
+struct State {
+    cfg: Option<Config>,
+    conns: [Option<Conn>; MAX_CONNS],
+    req: [[u8; REQ_MAX]; MAX_CONNS],
+    resp: [[u8; RESP_MAX]; MAX_CONNS],
+    /// Pending ARP reply: (requester MAC, requester IP)
+    arp: Option<([u8; 6], [u8; 4])>,
+    stats: Stats,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    cfg: None,
+    conns: [None; MAX_CONNS],
+    req: [[0; REQ_MAX]; MAX_CONNS],
+    resp: [[0; RESP_MAX]; MAX_CONNS],
+    arp: None,
+    stats: Stats { requests: 0, connections: 0, retransmits: 0, drops: 0 },
+});
+static IP_ID: AtomicU16 = AtomicU16::new(1);
+
+pub fn configure(cfg: Option<Config>) {
+    STATE.lock(|s| { s.cfg = cfg; s.conns = [None; MAX_CONNS]; s.arp = None; });
+}
+
+pub fn config() -> Option<Config> { STATE.lock(|s| s.cfg) }
+pub fn stats() -> Stats { STATE.lock(|s| s.stats) }
+pub fn connections() -> usize { STATE.lock(|s| s.conns.iter().flatten().count()) }
+
+fn be16(b: &[u8], off: usize) -> u16 { u16::from_be_bytes([b[off], b[off + 1]]) }
+fn be32(b: &[u8], off: usize) -> u32 { u32::from_be_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]]) }
+fn put16(b: &mut [u8], off: usize, v: u16) { b[off..off + 2].copy_from_slice(&v.to_be_bytes()); }
+fn put32(b: &mut [u8], off: usize, v: u32) { b[off..off + 4].copy_from_slice(&v.to_be_bytes()); }
+
+/// One's-complement sum of `b` as big-endian words, added to `sum`.
+fn csum_add(mut sum: u32, b: &[u8]) -> u32 {
+    let mut i = 0;
+    while i + 1 < b.len() { sum += be16(b, i) as u32; i += 2; }
+    if i < b.len() { sum += (b[i] as u32) << 8; }
+    sum
+}
+
+fn csum_fold(mut sum: u32) -> u16 {
+    while (sum >> 16) != 0 { sum = (sum & 0xFFFF) + (sum >> 16); }
+    !(sum as u16)
+}
+
+/// `a` after `b` in sequence space.
+fn seq_gt(a: u32, b: u32) -> bool { (a.wrapping_sub(b) as i32) > 0 }
+
+/// Take a frame off the uplink if it is for the responder. Called from
+/// `vdev::net::inbound` before NAT and bridging.
+pub fn on_frame(frame: &[u8]) -> bool {
+    if frame.len() < ETH_HDR + 20 { return false; }
+    let cfg = match config() { Some(c) => c, None => return false };
+    match be16(frame, 12) {
+        ETHERTYPE_ARP => {
+            let a = &frame[ETH_HDR..];
+            // Ethernet/IPv4 request for our address
+            if a.len() < 28 || be16(a, 0) != 1 || be16(a, 2) != ETHERTYPE_IPV4 || be16(a, 6) != 1 || a[24..28] != cfg.ip { return false; }
+            let mut mac = [0u8; 6]; mac.copy_from_slice(&a[8..14]);
+            let mut ip = [0u8; 4]; ip.copy_from_slice(&a[14..18]);
+            STATE.lock(|s| s.arp = Some((mac, ip)));
+            true
+        }
+        ETHERTYPE_IPV4 => {
+            let f = &frame[ETH_HDR..];
+            if (f[0] >> 4) != 4 || f[16..20] != cfg.ip { return false; }
+            let ihl = ((f[0] & 0x0F) as usize) * 4;
+            let total = (be16(f, 2) as usize).min(f.len());
+            // Our address: nothing else for it reaches the guests.
+            if f[9] != PROTO_TCP || ihl < 20 || total < ihl + 20 { return true; }
+            let t = &f[ihl..total];
+            let doff = ((t[12] >> 4) as usize) * 4;
+            if doff < 20 || doff > t.len() || be16(t, 2) != cfg.port { return true; }
+            let mut peer_mac = [0u8; 6]; peer_mac.copy_from_slice(&frame[6..12]);
+            let mut peer_ip = [0u8; 4]; peer_ip.copy_from_slice(&f[12..16]);
+            segment_in(peer_mac, peer_ip, t, doff);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// MSS option of a SYN, if any.
+fn syn_mss(t: &[u8], doff: usize) -> Option<u16> {
+    let mut i = 20;
+    while i < doff {
+        match t[i] {
+            0 => break,
+            1 => i += 1,
+            2 if i + 4 <= doff && t[i + 1] == 4 => return Some(be16(t, i + 2)),
+            _ => { if i + 1 >= doff || t[i + 1] < 2 { break; } i += t[i + 1] as usize; }
+        }
+    }
+    None
+}
+
+fn segment_in(peer_mac: [u8; 6], peer_ip: [u8; 4], t: &[u8], doff: usize) {
+    let (sport, seq, ack, flags, wnd) = (be16(t, 0), be32(t, 4), be32(t, 8), t[13], be16(t, 14) as u32);
+    let payload = &t[doff..];
+    let now = now_ms();
+    STATE.lock(|s| {
+        let slot = s.conns.iter().position(|c| matches!(c, Some(c) if c.peer_ip == peer_ip && c.peer_port == sport));
+        let i = match slot {
+            Some(i) => i,
+            None => {
+                if flags & SYN == 0 || flags & (ACK | RST) != 0 { return; }
+                let Some(i) = s.conns.iter().position(|c| c.is_none()) else { s.stats.drops += 1; return };
+                let iss = crate::time::rdtsc() as u32;
+                s.conns[i] = Some(Conn {
+                    peer_mac, peer_ip, peer_port: sport, state: Tcp::SynRcvd,
+                    iss, snd_una: iss, snd_nxt: iss, rcv_nxt: seq.wrapping_add(1), wnd,
+                    mss: syn_mss(t, doff).unwrap_or(536).min(MSS),
+                    peer_fin: false, ack_due: false, req_done: false, req_len: 0, resp_off: 0, resp_len: 0,
+                    last_tx: 0, last_rx: now, retries: 0,
+                });
+                s.stats.connections += 1;
+                return;
+            }
+        };
+        let State { conns, req, .. } = s;
+        let Some(c) = conns[i].as_mut() else { return };
+        if flags & RST != 0 { conns[i] = None; return; }
+        c.last_rx = now;
+        // A repeated SYN lost our SYN-ACK: send it again.
+        if flags & SYN != 0 { if c.state == Tcp::SynRcvd { c.snd_nxt = c.iss; } return; }
+        if flags & ACK != 0 && seq_gt(ack, c.snd_una) && !seq_gt(ack, c.snd_nxt) {
+            c.snd_una = ack;
+            c.retries = 0;
+            if c.state == Tcp::SynRcvd { c.state = Tcp::Established; }
+        }
+        c.wnd = wnd;
+        if !payload.is_empty() {
+            if seq == c.rcv_nxt && c.state == Tcp::Established && !c.req_done {
+                let take = payload.len().min(REQ_MAX - c.req_len);
+                req[i][c.req_len..c.req_len + take].copy_from_slice(&payload[..take]);
+                c.req_len += take;
+                c.rcv_nxt = c.rcv_nxt.wrapping_add(payload.len() as u32);
+                if c.req_len == REQ_MAX || request_complete(&req[i][..c.req_len]) { c.req_done = true; }
+            }
+            c.ack_due = true;
+        }
+        if flags & FIN != 0 && seq.wrapping_add(payload.len() as u32) == c.rcv_nxt && !c.peer_fin {
+            c.rcv_nxt = c.rcv_nxt.wrapping_add(1);
+            c.peer_fin = true;
+            c.ack_due = true;
+            if c.state == Tcp::Established { c.req_done = true; }
+        }
+    });
+}
+
+/// A parsed request. Slices point into the connection's request buffer.
+pub(super) struct Request<'a> {
+    pub method: &'a [u8],
+    /// Target up to any `?`
+    pub path: &'a [u8],
+    query: &'a [u8],
+    headers: &'a [u8],
+    pub body: &'a [u8],
+    /// The body announced by `Content-Length` did not fit in `REQ_MAX`
+    pub truncated: bool,
+    /// Client IPv4 address
+    pub peer: [u8; 4],
+}
+
+impl<'a> Request<'a> {
+    fn parse(req: &'a [u8], peer: [u8; 4]) -> Self {
+        let end = req.windows(4).position(|w| w == b"\r\n\r\n");
+        let head = &req[..end.unwrap_or(req.len())];
+        let line_end = head.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(head.len());
+        let mut parts = head[..line_end].split(|&b| b == b' ');
+        let method = parts.next().unwrap_or(&[]);
+        let target = parts.next().unwrap_or(&[]);
+        let (path, query) = match target.iter().position(|&b| b == b'?') {
+            Some(q) => (&target[..q], &target[q + 1..]),
+            None => (target, &[][..]),
+        };
+        let rest = end.map_or(&[][..], |e| &req[e + 4..]);
+        let want = content_length(head);
+        Request { method, path, query, headers: &head[line_end..], body: &rest[..want.min(rest.len())], truncated: want > rest.len(), peer }
+    }
+
+    /// Value of header `name` (any case), leading blanks removed.
+    pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+        self.headers.split(|&b| b == b'\n').find_map(|l| {
+            let l = l.strip_suffix(b"\r").unwrap_or(l);
+            let colon = l.iter().position(|&b| b == b':')?;
+            if !l[..colon].eq_ignore_ascii_case(name.as_bytes()) { return None; }
+            let v = &l[colon + 1..];
+            Some(&v[v.iter().position(|&b| b != b' ' && b != b'\t').unwrap_or(v.len())..])
+        })
+    }
+
+    /// Value of `key` in the query string, undecoded.
+    pub fn query(&self, key: &str) -> Option<&'a [u8]> {
+        self.query.split(|&b| b == b'&').find_map(|kv| {
+            let eq = kv.iter().position(|&b| b == b'=').unwrap_or(kv.len());
+            if &kv[..eq] != key.as_bytes() { return None; }
+            Some(kv.get(eq + 1..).unwrap_or(&[]))
+        })
+    }
+}
+
+/// `Content-Length` among the headers `head`, 0 if absent or malformed.
+fn content_length(head: &[u8]) -> usize {
+    let r = Request { method: &[], path: &[], query: &[], headers: head, body: &[], truncated: false, peer: [0; 4] };
+    r.header("content-length")
+        .and_then(|v| core::str::from_utf8(v).ok())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(0)
+}
+
+/// Headers in and, if they announce one, the whole body.
+fn request_complete(req: &[u8]) -> bool {
+    match req.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => req.len() >= end + 4 + content_length(&req[..end]),
+        None => false,
+    }
+}
+
+/// Build a reply to `req` in `out`; returns (offset, length) of the bytes to send.
+fn respond(system_table: &SystemTable<Boot>, peer: [u8; 4], req: &[u8], out: &mut [u8]) -> (usize, usize) {
+    let r = Request::parse(req, peer);
+    let head = r.method == b"HEAD";
+    let api = r.path.starts_with(b"/v1/");
+    let mut n = HDR_SPACE;
+    let mut full = false;
+    // API replies carry their own JSON body, errors included
+    let mut own_body = false;
+    let (status, ctype): (&str, &str) = if api {
+        let mut w = crate::util::format::BufWriter::new(&mut out[HDR_SPACE..]);
+        let sc = super::api::route(system_table, &r, &mut w);
+        n += w.len();
+        own_body = !w.truncated();
+        if own_body { sc } else { ("500 Internal Server Error", "text/plain") }
+    } else if r.method != b"GET" && !head {
+        ("405 Method Not Allowed", "text/plain")
+    } else if r.path == b"/metrics" {
+        let mut put = |s: &str| {
+            let b = s.as_bytes();
+            if n + b.len() > out.len() { full = true; return; }
+            out[n..n + b.len()].copy_from_slice(b);
+            n += b.len();
+        };
+        metrics(&mut put);
+        if full { ("500 Internal Server Error", "text/plain") } else { ("200 OK", "text/plain; version=0.0.4") }
+    } else {
+        ("404 Not Found", "text/plain")
+    };
+    if !status.starts_with("200") && !own_body {
+        n = HDR_SPACE;
+        for &b in status.as_bytes().iter().chain(b"\n") { out[n] = b; n += 1; }
+    }
+    let body = n - HDR_SPACE;
+    let mut h = [0u8; HDR_SPACE];
+    let mut m = 0;
+    for part in ["HTTP/1.1 ", status, "\r\nContent-Type: ", ctype, "\r\nContent-Length: "] {
+        for &b in part.as_bytes() { h[m] = b; m += 1; }
+    }
+    m += crate::util::format::u64_dec(body as u64, &mut h[m..]);
+    if status.starts_with("401") { for &b in b"\r\nWWW-Authenticate: Bearer" { h[m] = b; m += 1; } }
+    for &b in b"\r\nConnection: close\r\n\r\n" { h[m] = b; m += 1; }
+    out[HDR_SPACE - m..HDR_SPACE].copy_from_slice(&h[..m]);
+    (HDR_SPACE - m, if head { m } else { m + body })
+}
+
+/// The `/metrics` page: `obs::prom` plus the per-VM series.
+pub(super) fn metrics(w: &mut impl FnMut(&str)) {
+    crate::obs::prom::render(&mut *w);
+    vm_series(w);
+}
+
+/// Per-VM series kept by the hypervisor core rather than `obs::metrics`.
+fn vm_series(w: &mut impl FnMut(&str)) {
+    let mut l = Line::new();
+    l.s("# TYPE ").s(PREFIX).s("vm_state gauge").emit(w);
+    crate::hv::vm::list_vms(|v| {
+        l.s(PREFIX).s("vm_state{vm=\"").u(v.id).s("\",name=\"").s(v.name()).s("\",state=\"").s(v.state.name()).s("\"} ").u(v.state.code() as u64).emit(w);
+    });
+    l.s("# TYPE ").s(PREFIX).s("vm_vcpus gauge").emit(w);
+    crate::hv::vm::list_vms(|v| { l.s(PREFIX).s("vm_vcpus{vm=\"").u(v.id).s("\"} ").u(v.vcpus as u64).emit(w); });
+    l.s("# TYPE ").s(PREFIX).s("vm_memory_bytes gauge").emit(w);
+    crate::hv::vm::list_vms(|v| { l.s(PREFIX).s("vm_memory_bytes{vm=\"").u(v.id).s("\"} ").u(v.memory_bytes).emit(w); });
+    l.s("# TYPE ").s(PREFIX).s("vm_exits counter").emit(w);
+    crate::hv::vm::list_vms(|v| {
+        crate::hv::run::for_each(v.id, |_, r| {
+            l.s(PREFIX).s("vm_exits{vm=\"").u(v.id).s("\",vcpu=\"").u(r.vcpu as u64).s("\"} ").u(r.exits).emit(w);
+        });
+    });
+    l.s("# TYPE ").s(PREFIX).s("vm_net_rx_bytes counter").emit(w);
+    net::for_each(|i, n| { l.s(PREFIX).s("vm_net_rx_bytes{vm=\"").u(n.vm_id).s("\",nic=\"").u(i as u64).s("\"} ").u(n.stats.rx_bytes).emit(w); });
+    l.s("# TYPE ").s(PREFIX).s("vm_net_tx_bytes counter").emit(w);
+    net::for_each(|i, n| { l.s(PREFIX).s("vm_net_tx_bytes{vm=\"").u(n.vm_id).s("\",nic=\"").u(i as u64).s("\"} ").u(n.stats.tx_bytes).emit(w); });
+}
+
+/// Ethernet + IPv4 + TCP segment from us to `c`. Returns the frame length.
+fn build_segment(cfg: &Config, c: &Conn, flags: u8, seq: u32, payload: &[u8], out: &mut [u8]) -> usize {
+    let opt = if flags & SYN != 0 { 4 } else { 0 };
+    let tcp_len = 20 + opt + payload.len();
+    out[0..6].copy_from_slice(&c.peer_mac);
+    out[6..12].copy_from_slice(&cfg.mac);
+    put16(out, 12, ETHERTYPE_IPV4);
+    let ip = &mut out[ETH_HDR..ETH_HDR + 20];
+    ip[0] = 0x45;
+    ip[1] = 0;
+    put16(ip, 2, (20 + tcp_len) as u16);
+    put16(ip, 4, IP_ID.fetch_add(1, Ordering::Relaxed));
+    put16(ip, 6, 0x4000); // DF
+    ip[8] = 64;
+    ip[9] = PROTO_TCP;
+    put16(ip, 10, 0);
+    ip[12..16].copy_from_slice(&cfg.ip);
+    ip[16..20].copy_from_slice(&c.peer_ip);
+    let ck = csum_fold(csum_add(0, ip));
+    put16(ip, 10, ck);
+    let t = &mut out[ETH_HDR + 20..ETH_HDR + 20 + tcp_len];
+    put16(t, 0, cfg.port);
+    put16(t, 2, c.peer_port);
+    put32(t, 4, seq);
+    put32(t, 8, c.rcv_nxt);
+    t[12] = (((20 + opt) / 4) as u8) << 4;
+    t[13] = flags;
+    put16(t, 14, WINDOW);
+    put16(t, 16, 0);
+    put16(t, 18, 0);
+    if opt != 0 { t[20] = 2; t[21] = 4; put16(t, 22, MSS); }
+    t[20 + opt..].copy_from_slice(payload);
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&cfg.ip);
+    pseudo[4..8].copy_from_slice(&c.peer_ip);
+    pseudo[9] = PROTO_TCP;
+    put16(&mut pseudo, 10, tcp_len as u16);
+    let ck = csum_fold(csum_add(csum_add(0, &pseudo), t));
+    put16(t, 16, ck);
+    ETH_HDR + 20 + tcp_len
+}
+
+fn arp_reply(cfg: &Config, mac: [u8; 6], ip: [u8; 4], out: &mut [u8]) -> usize {
+    out[0..6].copy_from_slice(&mac);
+    out[6..12].copy_from_slice(&cfg.mac);
+    put16(out, 12, ETHERTYPE_ARP);
+    let a = &mut out[ETH_HDR..ETH_HDR + 28];
+    put16(a, 0, 1);
+    put16(a, 2, ETHERTYPE_IPV4);
+    a[4] = 6;
+    a[5] = 4;
+    put16(a, 6, 2);
+    a[8..14].copy_from_slice(&cfg.mac);
+    a[14..18].copy_from_slice(&cfg.ip);
+    a[18..24].copy_from_slice(&mac);
+    a[24..28].copy_from_slice(&ip);
+    // Pad to the Ethernet minimum
+    out[ETH_HDR + 28..60].fill(0);
+    60
+}
+
+/// Send ARP replies, SYN-ACKs, response data, FINs, ACKs and retransmits
+/// that are due. Returns the number of frames sent.
+pub fn poll(system_table: &mut SystemTable<Boot>) -> usize {
+    let link = net::uplink();
+    if link == Uplink::None { return 0; }
+    let now = now_ms();
+    let mut sent = 0usize;
+    let mut frame = [0u8; net::FRAME_MAX];
+    STATE.lock(|s| {
+        let Some(cfg) = s.cfg else { return };
+        if let Some((mac, ip)) = s.arp.take() {
+            let n = arp_reply(&cfg, mac, ip, &mut frame);
+            if net::uplink_send(system_table, link, &frame[..n]) { sent += 1; }
+        }
+        let State { conns, req, resp, stats, .. } = s;
+        for i in 0..MAX_CONNS {
+            let Some(c) = conns[i].as_mut() else { continue };
+            if now.saturating_sub(c.last_rx) > IDLE_MS { conns[i] = None; continue; }
+            if c.req_done && c.state == Tcp::Established {
+                let (off, len) = respond(system_table, c.peer_ip, &req[i][..c.req_len], &mut resp[i]);
+                c.resp_off = off;
+                c.resp_len = len;
+                c.state = Tcp::Sending;
+                stats.requests += 1;
+                crate::obs::metrics::Counter::new(&crate::obs::metrics::HTTP_REQUESTS).inc();
+            }
+            if c.snd_nxt != c.snd_una && now.saturating_sub(c.last_tx) > RTO_MS {
+                if c.retries >= MAX_RETRIES { conns[i] = None; continue; }
+                c.retries += 1;
+                c.snd_nxt = c.snd_una;
+                stats.retransmits += 1;
+                crate::obs::metrics::Counter::new(&crate::obs::metrics::HTTP_RETRANSMITS).inc();
+            }
+            let mut out = false;
+            if c.state == Tcp::SynRcvd && c.snd_nxt == c.iss {
+                let n = build_segment(&cfg, c, SYN | ACK, c.iss, &[], &mut frame);
+                if net::uplink_send(system_table, link, &frame[..n]) { sent += 1; }
+                c.snd_nxt = c.iss.wrapping_add(1);
+                out = true;
+            }
+            if c.state == Tcp::Sending {
+                let base = c.iss.wrapping_add(1);
+                let limit = c.wnd.min(BURST * c.mss as u32);
+                while c.snd_nxt.wrapping_sub(c.snd_una) < limit && !seq_gt(c.snd_nxt, c.fin_seq()) {
+                    let off = c.snd_nxt.wrapping_sub(base) as usize;
+                    let (flags, len) = if off < c.resp_len {
+                        let room = (limit - c.snd_nxt.wrapping_sub(c.snd_una)) as usize;
+                        (ACK | PSH, (c.resp_len - off).min(c.mss as usize).min(room))
+                    } else {
+                        (FIN | ACK, 0)
+                    };
+                    let data = &resp[i][c.resp_off + off..c.resp_off + off + len];
+                    let n = build_segment(&cfg, c, flags, c.snd_nxt, data, &mut frame);
+                    if !net::uplink_send(system_table, link, &frame[..n]) { break; }
+                    sent += 1;
+                    out = true;
+                    c.snd_nxt = c.snd_nxt.wrapping_add(if flags & FIN != 0 { 1 } else { len as u32 });
+                }
+                if c.snd_una == c.fin_seq().wrapping_add(1) { c.state = Tcp::FinWait; }
+            }
+            if c.ack_due && !out {
+                let n = build_segment(&cfg, c, ACK, c.snd_nxt, &[], &mut frame);
+                if net::uplink_send(system_table, link, &frame[..n]) { sent += 1; }
+                out = true;
+            }
+            if out { c.last_tx = now; c.ack_due = false; }
+            if c.state == Tcp::FinWait && c.peer_fin { conns[i] = None; }
+        }
+    });
+    sent
+}
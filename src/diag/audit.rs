@@ -1,10 +1,23 @@
 #![allow(dead_code)]
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+//! Host audit trail.
+//!
+//! Events go into a RAM ring, each tagged with a monotonically increasing
+//! sequence number and a timestamp on the monotonic clock. Readers page through the ring with a
+//! cursor (the next sequence number they want) and an optional kind/time
+//! filter; a cursor that fell behind the retained window reports how many
+//! events were lost. Retention is configurable: the ring size, and whether
+//! events are periodically appended to a persisted log in UEFI variables.
+//! Persisted records are hash-chained so `verify` can detect edits, gaps and
+//! truncation.
+
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use core::fmt::Write as _;
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
 
+use crate::util::spinlock::SpinLock;
+
 /// Audit event kinds recorded for security and operational visibility.
 #[derive(Clone, Copy, Debug)]
 pub enum AuditKind {
@@ -20,92 +33,965 @@ pub enum AuditKind {
         MigrateStart(u64),
         MigrateScan(u64, u64),
         MigrateStop(u64),
+    TpmPcrExtend(u32),
+    /// Cluster quorum mode change (`cluster::Mode::code`) and current term
+    ClusterMode { mode: u8, term: u64 },
+    /// DMA remapping fault; `amd` selects how `reason` decodes (VT-d fault reason vs AMD-Vi event code)
+    IommuFault { seg: u16, bus: u8, dev: u8, func: u8, amd: bool, reason: u8, write: bool, addr: u64 },
+    /// Device pulled out of its domain and blocked after repeated faults
+    IommuQuarantine { seg: u16, bus: u8, dev: u8, func: u8, dom: u16 },
+    /// PCI config-space write at `off`; `denied` when the interlocks refused it
+    PciConfigWrite { seg: u16, bus: u8, dev: u8, func: u8, off: u16, old: u32, new: u32, denied: bool },
+    /// Signature check of a guest image at load (`hv::imgsig::Verdict::code`); `refused` under strict policy
+    GuestImageSig { vm: u64, verdict: u8, refused: bool },
+    /// Host watchdog armed (`timeout_s` > 0) or disarmed; `backend` is `watchdog::Backend::code`
+    HostWatchdog { backend: u8, timeout_s: u32 },
+    /// Heartbeat policy acted on a VM after `missed` beats (`heartbeat::Action::code`); `ok` false if the action failed
+    VmHeartbeat { vm: u64, action: u8, missed: u32, ok: bool },
+    /// Registry lifecycle change (`hv::vm::VmState::code`)
+    VmState { vm: u64, from: u8, to: u8 },
+    /// Management API call refused: `caller` is the token name (empty for
+    /// an unknown token), `cap` the capability the route needs
+    /// (`ctl::api::Capability::code`), `peer` the client's IPv4 address
+    ApiDenied { caller: [u8; 8], cap: u8, peer: [u8; 4] },
+    /// Protected VM `vm` of dead node `owner` restarted on `target`
+    /// (`cluster::ha::Outcome::code`); node ids keep their low 32 bits
+    HaEvacuate { vm: u64, owner: u32, target: u32, outcome: u8 },
+    /// Fencing step for `node` (`cluster::ha::FenceAction::code`)
+    HaFence { node: u64, action: u8 },
+    /// Signature check of an FPGA bitstream for `region` of the card at the
+    /// address (`hv::imgsig::Verdict::code`); `refused` under strict policy
+    FpgaLoad { seg: u16, bus: u8, dev: u8, func: u8, region: u8, verdict: u8, refused: bool },
+    /// Signature check of a hypervisor plugin image; `name` is the first
+    /// 8 bytes of its file name and `refused` is set unless it is trusted
+    PluginLoad { name: [u8; 8], verdict: u8, refused: bool },
+    /// The A/B slot on trial (`from`) went `boots` boots without being
+    /// confirmed, and the loader went back to slot `to` (0 = a, 1 = b)
+    SlotRollback { from: u8, to: u8, boots: u8 },
+}
+
+/// Filter names, indexed by `AuditKind::code`.
+pub const KIND_NAMES: [&str; 27] = [
+    "boot_start", "boot_ready", "vm_create", "vm_start", "vm_stop", "vm_destroy", "iommu_domain_create",
+    "iommu_assign_add", "iommu_assign_del", "migrate_start", "migrate_scan", "migrate_stop", "tpm_pcr_extend", "cluster_mode",
+    "iommu_fault", "iommu_quarantine", "pci_cfg_write", "guest_image_sig",
+    "host_watchdog", "vm_heartbeat", "vm_state", "api_denied", "ha_evacuate", "ha_fence", "fpga_load",
+    "plugin_load", "slot_rollback",
+];
+
+impl AuditKind {
+    pub fn code(&self) -> u8 {
+        match self {
+            AuditKind::BootStart => 0,
+            AuditKind::BootReady => 1,
+            AuditKind::VmCreate(_) => 2,
+            AuditKind::VmStart(_) => 3,
+            AuditKind::VmStop(_) => 4,
+            AuditKind::VmDestroy(_) => 5,
+            AuditKind::IommuDomainCreate(_) => 6,
+            AuditKind::IommuAssignAdded { .. } => 7,
+            AuditKind::IommuAssignRemoved { .. } => 8,
+            AuditKind::MigrateStart(_) => 9,
+            AuditKind::MigrateScan(..) => 10,
+            AuditKind::MigrateStop(_) => 11,
+            AuditKind::TpmPcrExtend(_) => 12,
+            AuditKind::ClusterMode { .. } => 13,
+            AuditKind::IommuFault { .. } => 14,
+            AuditKind::IommuQuarantine { .. } => 15,
+            AuditKind::PciConfigWrite { .. } => 16,
+            AuditKind::GuestImageSig { .. } => 17,
+            AuditKind::HostWatchdog { .. } => 18,
+            AuditKind::VmHeartbeat { .. } => 19,
+            AuditKind::VmState { .. } => 20,
+            AuditKind::ApiDenied { .. } => 21,
+            AuditKind::HaEvacuate { .. } => 22,
+            AuditKind::HaFence { .. } => 23,
+            AuditKind::FpgaLoad { .. } => 24,
+            AuditKind::PluginLoad { .. } => 25,
+            AuditKind::SlotRollback { .. } => 26,
+        }
+    }
+
+    pub fn name(&self) -> &'static str { KIND_NAMES[self.code() as usize] }
+
+    /// Flatten to (code, a, b) for persistence.
+    fn pack(&self) -> (u8, u64, u64) {
+        let bdf = |seg: u16, bus: u8, dev: u8, func: u8| (seg as u64) << 24 | (bus as u64) << 16 | (dev as u64) << 8 | func as u64;
+        let (a, b) = match *self {
+            AuditKind::BootStart | AuditKind::BootReady => (0, 0),
+            AuditKind::VmCreate(id) | AuditKind::VmStart(id) | AuditKind::VmStop(id) | AuditKind::VmDestroy(id) => (id, 0),
+            AuditKind::IommuDomainCreate(d) => (d as u64, 0),
+            AuditKind::IommuAssignAdded { seg, bus, dev, func, dom } | AuditKind::IommuAssignRemoved { seg, bus, dev, func, dom }
+            | AuditKind::IommuQuarantine { seg, bus, dev, func, dom } => (bdf(seg, bus, dev, func), dom as u64),
+            AuditKind::IommuFault { seg, bus, dev, func, amd, reason, write, addr } =>
+                (bdf(seg, bus, dev, func) | (reason as u64) << 40 | (write as u64) << 48 | (amd as u64) << 49, addr),
+            AuditKind::PciConfigWrite { seg, bus, dev, func, off, old, new, denied } =>
+                (bdf(seg, bus, dev, func) | (off as u64) << 40 | (denied as u64) << 56, (old as u64) << 32 | new as u64),
+            AuditKind::MigrateStart(id) | AuditKind::MigrateStop(id) => (id, 0),
+            AuditKind::MigrateScan(id, pages) => (id, pages),
+            AuditKind::TpmPcrExtend(pcr) => (pcr as u64, 0),
+            AuditKind::ClusterMode { mode, term } => (mode as u64, term),
+            AuditKind::GuestImageSig { vm, verdict, refused } => (vm, verdict as u64 | (refused as u64) << 8),
+            AuditKind::HostWatchdog { backend, timeout_s } => (backend as u64, timeout_s as u64),
+            AuditKind::VmHeartbeat { vm, action, missed, ok } => (vm, action as u64 | (ok as u64) << 8 | (missed as u64) << 32),
+            AuditKind::VmState { vm, from, to } => (vm, from as u64 | (to as u64) << 8),
+            AuditKind::ApiDenied { caller, cap, peer } => (u64::from_le_bytes(caller), cap as u64 | (u32::from_be_bytes(peer) as u64) << 8),
+            AuditKind::HaEvacuate { vm, owner, target, outcome } => (vm | (outcome as u64) << 56, owner as u64 | (target as u64) << 32),
+            AuditKind::HaFence { node, action } => (node, action as u64),
+            AuditKind::FpgaLoad { seg, bus, dev, func, region, verdict, refused } =>
+                (bdf(seg, bus, dev, func) | (region as u64) << 40, verdict as u64 | (refused as u64) << 8),
+            AuditKind::PluginLoad { name, verdict, refused } => (u64::from_le_bytes(name), verdict as u64 | (refused as u64) << 8),
+            AuditKind::SlotRollback { from, to, boots } => (from as u64, to as u64 | (boots as u64) << 8),
+        };
+        (self.code(), a, b)
+    }
+
+    fn unpack(code: u8, a: u64, b: u64) -> Option<AuditKind> {
+        let (seg, bus, dev, func) = ((a >> 24) as u16, (a >> 16) as u8, (a >> 8) as u8, a as u8);
+        Some(match code {
+            0 => AuditKind::BootStart,
+            1 => AuditKind::BootReady,
+            2 => AuditKind::VmCreate(a),
+            3 => AuditKind::VmStart(a),
+            4 => AuditKind::VmStop(a),
+            5 => AuditKind::VmDestroy(a),
+            6 => AuditKind::IommuDomainCreate(a as u16),
+            7 => AuditKind::IommuAssignAdded { seg, bus, dev, func, dom: b as u16 },
+            8 => AuditKind::IommuAssignRemoved { seg, bus, dev, func, dom: b as u16 },
+            9 => AuditKind::MigrateStart(a),
+            10 => AuditKind::MigrateScan(a, b),
+            11 => AuditKind::MigrateStop(a),
+            12 => AuditKind::TpmPcrExtend(a as u32),
+            13 => AuditKind::ClusterMode { mode: a as u8, term: b },
+            14 => AuditKind::IommuFault { seg, bus, dev, func, amd: (a >> 49) & 1 != 0, reason: (a >> 40) as u8, write: (a >> 48) & 1 != 0, addr: b },
+            15 => AuditKind::IommuQuarantine { seg, bus, dev, func, dom: b as u16 },
+            16 => AuditKind::PciConfigWrite { seg, bus, dev, func, off: (a >> 40) as u16 & 0xFFF, old: (b >> 32) as u32, new: b as u32, denied: (a >> 56) & 1 != 0 },
+            17 => AuditKind::GuestImageSig { vm: a, verdict: b as u8, refused: (b >> 8) & 1 != 0 },
+            18 => AuditKind::HostWatchdog { backend: a as u8, timeout_s: b as u32 },
+            19 => AuditKind::VmHeartbeat { vm: a, action: b as u8, missed: (b >> 32) as u32, ok: (b >> 8) & 1 != 0 },
+            20 => AuditKind::VmState { vm: a, from: b as u8, to: (b >> 8) as u8 },
+            21 => AuditKind::ApiDenied { caller: a.to_le_bytes(), cap: b as u8, peer: ((b >> 8) as u32).to_be_bytes() },
+            22 => AuditKind::HaEvacuate { vm: a & ((1 << 56) - 1), owner: b as u32, target: (b >> 32) as u32, outcome: (a >> 56) as u8 },
+            23 => AuditKind::HaFence { node: a, action: b as u8 },
+            24 => AuditKind::FpgaLoad { seg, bus, dev, func, region: (a >> 40) as u8, verdict: b as u8, refused: (b >> 8) & 1 != 0 },
+            25 => AuditKind::PluginLoad { name: a.to_le_bytes(), verdict: b as u8, refused: (b >> 8) & 1 != 0 },
+            26 => AuditKind::SlotRollback { from: a as u8, to: b as u8, boots: (b >> 8) as u8 },
+            _ => return None,
+        })
+    }
+}
+
+/// One audit event as seen by readers.
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub seq: u64,
+    /// `clock::now_ms` when recorded (0 before time calibration)
+    pub t_ms: u64,
+    pub kind: AuditKind,
 }
 
-const AUDIT_CAP: usize = 256;
-static AUDIT_WIDX: AtomicUsize = AtomicUsize::new(0);
-static mut AUDIT_BUF: [AuditKind; AUDIT_CAP] = [AuditKind::BootStart; AUDIT_CAP];
+#[derive(Clone, Copy)]
+struct Slot { seq: u64, t_ms: u64, kind: AuditKind }
+
+/// Largest configurable ring
+pub const AUDIT_CAP_MAX: usize = 1024;
+pub const AUDIT_CAP_MIN: usize = 16;
+const AUDIT_CAP_DEFAULT: usize = 256;
+const EMPTY: u64 = u64::MAX;
+
+static AUDIT_WIDX: AtomicU64 = AtomicU64::new(0);
+static AUDIT_CAP: AtomicUsize = AtomicUsize::new(AUDIT_CAP_DEFAULT);
+static mut AUDIT_BUF: [Slot; AUDIT_CAP_MAX] = [Slot { seq: EMPTY, t_ms: 0, kind: AuditKind::BootStart }; AUDIT_CAP_MAX];
+/// Staging area for re-homing the ring on resize (too large for the stack)
+static mut RESIZE_BUF: [Slot; AUDIT_CAP_MAX] = [Slot { seq: EMPTY, t_ms: 0, kind: AuditKind::BootStart }; AUDIT_CAP_MAX];
 
 /// Append an audit event to the ring buffer.
 pub fn record(event: AuditKind) {
-    let i = AUDIT_WIDX.fetch_add(1, Ordering::Relaxed) % AUDIT_CAP;
-    unsafe { core::ptr::write_volatile(&mut AUDIT_BUF[i], event); }
+    let seq = AUDIT_WIDX.fetch_add(1, Ordering::Relaxed);
+    let i = (seq % AUDIT_CAP.load(Ordering::Relaxed) as u64) as usize;
+    unsafe { core::ptr::write_volatile(&mut AUDIT_BUF[i], Slot { seq, t_ms: crate::time::clock::now_ms(), kind: event }); }
+}
+
+/// Sequence number the next event will get.
+pub fn head() -> u64 { AUDIT_WIDX.load(Ordering::Relaxed) }
+
+/// Oldest sequence number still retained in RAM.
+pub fn oldest() -> u64 { head().saturating_sub(AUDIT_CAP.load(Ordering::Relaxed) as u64) }
+
+/// Server-side selection for `query`.
+#[derive(Clone, Copy, Debug)]
+pub struct Filter {
+    /// Bit per `AuditKind::code`; 0 selects every kind
+    pub kinds: u32,
+    pub since_ms: u64,
+    pub until_ms: u64,
+}
+
+impl Filter {
+    pub const ALL: Filter = Filter { kinds: 0, since_ms: 0, until_ms: u64::MAX };
+
+    /// Bit for a kind name from `KIND_NAMES`.
+    pub fn kind_bit(name: &str) -> Option<u32> {
+        KIND_NAMES.iter().position(|k| *k == name).map(|i| 1u32 << i)
+    }
+
+    fn matches(&self, r: &Record) -> bool {
+        (self.kinds == 0 || self.kinds & (1 << r.kind.code()) != 0) && r.t_ms >= self.since_ms && r.t_ms <= self.until_ms
+    }
+}
+
+/// Outcome of one `query` page.
+#[derive(Clone, Copy, Debug)]
+pub struct Page {
+    /// Cursor for the next page
+    pub next: u64,
+    /// Events delivered to the callback
+    pub returned: usize,
+    /// Events between the cursor and the retained window that were overwritten
+    pub lost: u64,
+    /// More events are available at `next`
+    pub more: bool,
+}
+
+/// Deliver up to `limit` events with `seq >= cursor` that pass `filter`.
+pub fn query(cursor: u64, filter: &Filter, limit: usize, mut f: impl FnMut(&Record)) -> Page {
+    let end = head();
+    let cap = AUDIT_CAP.load(Ordering::Relaxed) as u64;
+    let start = cursor.max(end.saturating_sub(cap));
+    let lost = start - cursor.min(start);
+    let mut returned = 0;
+    let mut seq = start;
+    while seq < end {
+        if returned == limit { return Page { next: seq, returned, lost, more: true }; }
+        let s = unsafe { core::ptr::read_volatile(&AUDIT_BUF[(seq % cap) as usize]) };
+        seq += 1;
+        // A slot whose seq differs was overwritten concurrently or predates a resize.
+        if s.seq != seq - 1 { continue; }
+        let r = Record { seq: s.seq, t_ms: s.t_ms, kind: s.kind };
+        if filter.matches(&r) { f(&r); returned += 1; }
+    }
+    Page { next: end, returned, lost, more: false }
+}
+
+fn put(buf: &mut [u8], n: &mut usize, s: &[u8]) { for &b in s { buf[*n] = b; *n += 1; } }
+
+fn put_bdf(buf: &mut [u8], n: &mut usize, seg: u16, bus: u8, dev: u8, func: u8) {
+    *n += crate::util::format::u32_dec(seg as u32, &mut buf[*n..]);
+    put(buf, n, b":");
+    *n += crate::util::format::u32_dec(bus as u32, &mut buf[*n..]);
+    put(buf, n, b":");
+    *n += crate::util::format::u32_dec(dev as u32, &mut buf[*n..]);
+    put(buf, n, b".");
+    *n += crate::util::format::u32_dec(func as u32, &mut buf[*n..]);
+}
+
+fn image_sig_name(verdict: u8) -> &'static [u8] {
+    match verdict { 0 => b"unsigned", 1 => b"trusted", 2 => b"untrusted", 3 => b"malformed", _ => b"?" }
+}
+
+fn slot_name(slot: u8) -> &'static [u8] {
+    match slot { 0 => b"a", 1 => b"b", _ => b"?" }
+}
+
+fn watchdog_backend_name(backend: u8) -> &'static [u8] {
+    match backend { 0 => b"uefi", 1 => b"wdat", 2 => b"tco", _ => b"?" }
+}
+
+fn heartbeat_action_name(action: u8) -> &'static [u8] {
+    match action { 0 => b"log", 1 => b"pause", 2 => b"restart", _ => b"?" }
+}
+
+fn vm_state_name(state: u8) -> &'static [u8] {
+    match state { 0 => b"created", 1 => b"running", 2 => b"paused", 3 => b"migrating", 4 => b"stopped", _ => b"?" }
+}
+
+fn api_cap_name(cap: u8) -> &'static [u8] {
+    match cap {
+        0 => b"vm.read", 1 => b"vm.create", 2 => b"vm.start", 3 => b"vm.destroy", 4 => b"migrate.execute",
+        5 => b"metrics.read", 6 => b"attest.read", 7 => b"iommu.modify", 8 => b"audit.read", 9 => b"audit.write", 10 => b"cluster.read", 11 => b"carbon.write", 12 => b"power.write", 13 => b"network.write", 14 => b"storage.write", _ => b"?",
+    }
+}
+
+/// NUL-padded short name from an `ApiDenied` (token) or `PluginLoad`
+/// record; "-" when empty.
+fn api_caller(caller: &[u8; 8]) -> &[u8] {
+    let len = caller.iter().position(|&b| b == 0).unwrap_or(8);
+    if len == 0 { b"-" } else { &caller[..len] }
+}
+
+fn put_ipv4(buf: &mut [u8], n: &mut usize, ip: [u8; 4]) {
+    for (k, v) in ip.iter().enumerate() {
+        if k != 0 { put(buf, n, b"."); }
+        *n += crate::util::format::u32_dec(*v as u32, &mut buf[*n..]);
+    }
+}
+
+fn ha_outcome_name(outcome: u8) -> &'static [u8] {
+    match outcome { 0 => b"restarted", 1 => b"failed", 2 => b"no-target", 3 => b"lost", _ => b"?" }
+}
+
+fn ha_fence_name(action: u8) -> &'static [u8] {
+    match action { 0 => b"cleared", 1 => b"refused", 2 => b"self", _ => b"?" }
+}
+
+fn cluster_mode_name(mode: u8) -> &'static [u8] {
+    match mode { 0 => b"standalone", 1 => b"full", 2 => b"degraded(witness-lost)", 3 => b"degraded(no-witness)", 4 => b"degraded(peer-lost)", 5 => b"no-quorum", _ => b"?" }
+}
+
+/// Short name for a VT-d fault reason (`amd` false) or AMD-Vi event code (`amd` true).
+pub fn iommu_fault_name(amd: bool, reason: u8) -> &'static str {
+    if amd {
+        match reason {
+            0x1 => "illegal_dte", 0x2 => "io_page_fault", 0x3 => "dte_hw_error", 0x4 => "pt_hw_error",
+            0x5 => "illegal_cmd", 0x6 => "cmd_hw_error", 0x7 => "iotlb_inv_timeout", 0x8 => "invalid_dev_req",
+            _ => "event",
+        }
+    } else {
+        match reason {
+            0x1 => "root_not_present", 0x2 => "ctx_not_present", 0x3 => "ctx_invalid", 0x4 => "addr_beyond_aw",
+            0x5 => "write_denied", 0x6 => "read_denied", 0x7 => "pt_access", 0x8 => "root_access",
+            0x9 => "ctx_access", 0xA => "root_reserved", 0xB => "ctx_reserved", 0xC => "pte_reserved",
+            0xD => "tt_blocked",
+            0x20..=0x26 => "intr_remap",
+            _ => "fault",
+        }
+    }
+}
+
+/// Console form: `audit: <kind> <fields>` (no line terminator).
+pub fn render_text(kind: &AuditKind, buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    put(buf, &mut n, b"audit: ");
+    put(buf, &mut n, kind.name().as_bytes());
+    match *kind {
+        AuditKind::BootStart | AuditKind::BootReady => {}
+        AuditKind::VmCreate(id) | AuditKind::VmStart(id) | AuditKind::VmStop(id) | AuditKind::VmDestroy(id)
+        | AuditKind::MigrateStart(id) | AuditKind::MigrateStop(id) => {
+            put(buf, &mut n, b" id=");
+            n += crate::util::format::u32_dec(id as u32, &mut buf[n..]);
+        }
+        AuditKind::IommuDomainCreate(dom) => {
+            put(buf, &mut n, b" id=");
+            n += crate::util::format::u32_dec(dom as u32, &mut buf[n..]);
+        }
+        AuditKind::IommuAssignAdded { seg, bus, dev, func, dom } | AuditKind::IommuAssignRemoved { seg, bus, dev, func, dom }
+        | AuditKind::IommuQuarantine { seg, bus, dev, func, dom } => {
+            put(buf, &mut n, b" bdf=");
+            put_bdf(buf, &mut n, seg, bus, dev, func);
+            put(buf, &mut n, b" dom=");
+            n += crate::util::format::u32_dec(dom as u32, &mut buf[n..]);
+        }
+        AuditKind::IommuFault { seg, bus, dev, func, amd, reason, write, addr } => {
+            put(buf, &mut n, b" bdf=");
+            put_bdf(buf, &mut n, seg, bus, dev, func);
+            put(buf, &mut n, if amd { b" src=amdvi" } else { b" src=vtd" });
+            put(buf, &mut n, b" type=");
+            put(buf, &mut n, iommu_fault_name(amd, reason).as_bytes());
+            put(buf, &mut n, if write { b" write" } else { b" read" });
+            put(buf, &mut n, b" addr=0x");
+            n += crate::util::format::u64_hex(addr, &mut buf[n..]);
+        }
+        AuditKind::PciConfigWrite { seg, bus, dev, func, off, old, new, denied } => {
+            put(buf, &mut n, b" bdf=");
+            put_bdf(buf, &mut n, seg, bus, dev, func);
+            put(buf, &mut n, b" off=0x");
+            n += crate::util::format::u64_hex(off as u64, &mut buf[n..]);
+            put(buf, &mut n, b" old=0x");
+            n += crate::util::format::u64_hex(old as u64, &mut buf[n..]);
+            put(buf, &mut n, b" new=0x");
+            n += crate::util::format::u64_hex(new as u64, &mut buf[n..]);
+            if denied { put(buf, &mut n, b" denied"); }
+        }
+        AuditKind::MigrateScan(id, pages) => {
+            put(buf, &mut n, b" id=");
+            n += crate::util::format::u32_dec(id as u32, &mut buf[n..]);
+            put(buf, &mut n, b" pages=");
+            n += crate::util::format::u32_dec(pages as u32, &mut buf[n..]);
+        }
+        AuditKind::TpmPcrExtend(pcr) => {
+            put(buf, &mut n, b" pcr=");
+            n += crate::util::format::u32_dec(pcr, &mut buf[n..]);
+        }
+        AuditKind::ClusterMode { mode, term } => {
+            put(buf, &mut n, b" mode=");
+            put(buf, &mut n, cluster_mode_name(mode));
+            put(buf, &mut n, b" term=");
+            n += crate::util::format::u64_dec(term, &mut buf[n..]);
+        }
+        AuditKind::GuestImageSig { vm, verdict, refused } => {
+            put(buf, &mut n, b" id=");
+            n += crate::util::format::u64_dec(vm, &mut buf[n..]);
+            put(buf, &mut n, b" sig=");
+            put(buf, &mut n, image_sig_name(verdict));
+            if refused { put(buf, &mut n, b" refused"); }
+        }
+        AuditKind::HostWatchdog { backend, timeout_s } => {
+            put(buf, &mut n, b" backend=");
+            put(buf, &mut n, watchdog_backend_name(backend));
+            if timeout_s == 0 {
+                put(buf, &mut n, b" off");
+            } else {
+                put(buf, &mut n, b" timeout=");
+                n += crate::util::format::u64_dec(timeout_s as u64, &mut buf[n..]);
+                put(buf, &mut n, b"s");
+            }
+        }
+        AuditKind::VmHeartbeat { vm, action, missed, ok } => {
+            put(buf, &mut n, b" id=");
+            n += crate::util::format::u64_dec(vm, &mut buf[n..]);
+            put(buf, &mut n, b" action=");
+            put(buf, &mut n, heartbeat_action_name(action));
+            put(buf, &mut n, b" missed=");
+            n += crate::util::format::u64_dec(missed as u64, &mut buf[n..]);
+            if !ok { put(buf, &mut n, b" failed"); }
+        }
+        AuditKind::VmState { vm, from, to } => {
+            put(buf, &mut n, b" id=");
+            n += crate::util::format::u64_dec(vm, &mut buf[n..]);
+            put(buf, &mut n, b" ");
+            put(buf, &mut n, vm_state_name(from));
+            put(buf, &mut n, b"->");
+            put(buf, &mut n, vm_state_name(to));
+        }
+        AuditKind::ApiDenied { caller, cap, peer } => {
+            put(buf, &mut n, b" caller=");
+            put(buf, &mut n, api_caller(&caller));
+            put(buf, &mut n, b" cap=");
+            put(buf, &mut n, api_cap_name(cap));
+            put(buf, &mut n, b" peer=");
+            put_ipv4(buf, &mut n, peer);
+        }
+        AuditKind::HaEvacuate { vm, owner, target, outcome } => {
+            put(buf, &mut n, b" id=");
+            n += crate::util::format::u64_dec(vm, &mut buf[n..]);
+            put(buf, &mut n, b" owner=");
+            n += crate::util::format::u64_dec(owner as u64, &mut buf[n..]);
+            put(buf, &mut n, b" target=");
+            n += crate::util::format::u64_dec(target as u64, &mut buf[n..]);
+            put(buf, &mut n, b" ");
+            put(buf, &mut n, ha_outcome_name(outcome));
+        }
+        AuditKind::HaFence { node, action } => {
+            put(buf, &mut n, b" node=");
+            n += crate::util::format::u64_dec(node, &mut buf[n..]);
+            put(buf, &mut n, b" ");
+            put(buf, &mut n, ha_fence_name(action));
+        }
+        AuditKind::FpgaLoad { seg, bus, dev, func, region, verdict, refused } => {
+            put(buf, &mut n, b" bdf=");
+            put_bdf(buf, &mut n, seg, bus, dev, func);
+            put(buf, &mut n, b" region=");
+            n += crate::util::format::u32_dec(region as u32, &mut buf[n..]);
+            put(buf, &mut n, b" sig=");
+            put(buf, &mut n, image_sig_name(verdict));
+            if refused { put(buf, &mut n, b" refused"); }
+        }
+        AuditKind::PluginLoad { name, verdict, refused } => {
+            put(buf, &mut n, b" name=");
+            put(buf, &mut n, api_caller(&name));
+            put(buf, &mut n, b" sig=");
+            put(buf, &mut n, image_sig_name(verdict));
+            if refused { put(buf, &mut n, b" refused"); }
+        }
+        AuditKind::SlotRollback { from, to, boots } => {
+            put(buf, &mut n, b" from=");
+            put(buf, &mut n, slot_name(from));
+            put(buf, &mut n, b" to=");
+            put(buf, &mut n, slot_name(to));
+            put(buf, &mut n, b" boots=");
+            n += crate::util::format::u32_dec(boots as u32, &mut buf[n..]);
+        }
+    }
+    n
+}
+
+/// One JSON object per record, for archiving tools (no line terminator).
+pub fn render_json(r: &Record, buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    let num = |buf: &mut [u8], n: &mut usize, key: &[u8], v: u64| {
+        put(buf, n, b",\"");
+        put(buf, n, key);
+        put(buf, n, b"\":");
+        *n += crate::util::format::u64_dec(v, &mut buf[*n..]);
+    };
+    put(buf, &mut n, b"{\"seq\":");
+    n += crate::util::format::u64_dec(r.seq, &mut buf[n..]);
+    num(buf, &mut n, b"t_ms", r.t_ms);
+    put(buf, &mut n, b",\"kind\":\"");
+    put(buf, &mut n, r.kind.name().as_bytes());
+    put(buf, &mut n, b"\"");
+    match r.kind {
+        AuditKind::BootStart | AuditKind::BootReady => {}
+        AuditKind::VmCreate(id) | AuditKind::VmStart(id) | AuditKind::VmStop(id) | AuditKind::VmDestroy(id)
+        | AuditKind::MigrateStart(id) | AuditKind::MigrateStop(id) => num(buf, &mut n, b"vm", id),
+        AuditKind::IommuDomainCreate(dom) => num(buf, &mut n, b"dom", dom as u64),
+        AuditKind::IommuAssignAdded { seg, bus, dev, func, dom } | AuditKind::IommuAssignRemoved { seg, bus, dev, func, dom }
+        | AuditKind::IommuQuarantine { seg, bus, dev, func, dom } => {
+            put(buf, &mut n, b",\"bdf\":\"");
+            put_bdf(buf, &mut n, seg, bus, dev, func);
+            put(buf, &mut n, b"\"");
+            num(buf, &mut n, b"dom", dom as u64);
+        }
+        AuditKind::IommuFault { seg, bus, dev, func, amd, reason, write, addr } => {
+            put(buf, &mut n, b",\"bdf\":\"");
+            put_bdf(buf, &mut n, seg, bus, dev, func);
+            put(buf, &mut n, if amd { b"\",\"src\":\"amdvi" } else { b"\",\"src\":\"vtd" });
+            put(buf, &mut n, b"\",\"type\":\"");
+            put(buf, &mut n, iommu_fault_name(amd, reason).as_bytes());
+            put(buf, &mut n, b"\"");
+            num(buf, &mut n, b"reason", reason as u64);
+            put(buf, &mut n, if write { b",\"write\":true" } else { b",\"write\":false" });
+            num(buf, &mut n, b"addr", addr);
+        }
+        AuditKind::PciConfigWrite { seg, bus, dev, func, off, old, new, denied } => {
+            put(buf, &mut n, b",\"bdf\":\"");
+            put_bdf(buf, &mut n, seg, bus, dev, func);
+            put(buf, &mut n, b"\"");
+            num(buf, &mut n, b"off", off as u64);
+            num(buf, &mut n, b"old", old as u64);
+            num(buf, &mut n, b"new", new as u64);
+            put(buf, &mut n, if denied { b",\"denied\":true" } else { b",\"denied\":false" });
+        }
+        AuditKind::MigrateScan(id, pages) => { num(buf, &mut n, b"vm", id); num(buf, &mut n, b"pages", pages); }
+        AuditKind::TpmPcrExtend(pcr) => num(buf, &mut n, b"pcr", pcr as u64),
+        AuditKind::ClusterMode { mode, term } => {
+            put(buf, &mut n, b",\"mode\":\"");
+            put(buf, &mut n, cluster_mode_name(mode));
+            put(buf, &mut n, b"\"");
+            num(buf, &mut n, b"term", term);
+        }
+        AuditKind::GuestImageSig { vm, verdict, refused } => {
+            num(buf, &mut n, b"vm", vm);
+            put(buf, &mut n, b",\"sig\":\"");
+            put(buf, &mut n, image_sig_name(verdict));
+            put(buf, &mut n, if refused { b"\",\"refused\":true" } else { b"\",\"refused\":false" });
+        }
+        AuditKind::HostWatchdog { backend, timeout_s } => {
+            put(buf, &mut n, b",\"backend\":\"");
+            put(buf, &mut n, watchdog_backend_name(backend));
+            put(buf, &mut n, b"\"");
+            num(buf, &mut n, b"timeout_s", timeout_s as u64);
+        }
+        AuditKind::VmHeartbeat { vm, action, missed, ok } => {
+            num(buf, &mut n, b"vm", vm);
+            put(buf, &mut n, b",\"action\":\"");
+            put(buf, &mut n, heartbeat_action_name(action));
+            put(buf, &mut n, b"\"");
+            num(buf, &mut n, b"missed", missed as u64);
+            put(buf, &mut n, if ok { b",\"ok\":true" } else { b",\"ok\":false" });
+        }
+        AuditKind::VmState { vm, from, to } => {
+            num(buf, &mut n, b"vm", vm);
+            put(buf, &mut n, b",\"from\":\"");
+            put(buf, &mut n, vm_state_name(from));
+            put(buf, &mut n, b"\",\"to\":\"");
+            put(buf, &mut n, vm_state_name(to));
+            put(buf, &mut n, b"\"");
+        }
+        AuditKind::ApiDenied { caller, cap, peer } => {
+            put(buf, &mut n, b",\"caller\":\"");
+            put(buf, &mut n, api_caller(&caller));
+            put(buf, &mut n, b"\",\"cap\":\"");
+            put(buf, &mut n, api_cap_name(cap));
+            put(buf, &mut n, b"\",\"peer\":\"");
+            put_ipv4(buf, &mut n, peer);
+            put(buf, &mut n, b"\"");
+        }
+        AuditKind::HaEvacuate { vm, owner, target, outcome } => {
+            num(buf, &mut n, b"vm", vm);
+            num(buf, &mut n, b"owner", owner as u64);
+            num(buf, &mut n, b"target", target as u64);
+            put(buf, &mut n, b",\"outcome\":\"");
+            put(buf, &mut n, ha_outcome_name(outcome));
+            put(buf, &mut n, b"\"");
+        }
+        AuditKind::HaFence { node, action } => {
+            num(buf, &mut n, b"node", node);
+            put(buf, &mut n, b",\"action\":\"");
+            put(buf, &mut n, ha_fence_name(action));
+            put(buf, &mut n, b"\"");
+        }
+        AuditKind::FpgaLoad { seg, bus, dev, func, region, verdict, refused } => {
+            put(buf, &mut n, b",\"bdf\":\"");
+            put_bdf(buf, &mut n, seg, bus, dev, func);
+            put(buf, &mut n, b"\"");
+            num(buf, &mut n, b"region", region as u64);
+            put(buf, &mut n, b",\"sig\":\"");
+            put(buf, &mut n, image_sig_name(verdict));
+            put(buf, &mut n, if refused { b"\",\"refused\":true" } else { b"\",\"refused\":false" });
+        }
+        AuditKind::PluginLoad { name, verdict, refused } => {
+            put(buf, &mut n, b",\"name\":\"");
+            put(buf, &mut n, api_caller(&name));
+            put(buf, &mut n, b"\",\"sig\":\"");
+            put(buf, &mut n, image_sig_name(verdict));
+            put(buf, &mut n, if refused { b"\",\"refused\":true" } else { b"\",\"refused\":false" });
+        }
+        AuditKind::SlotRollback { from, to, boots } => {
+            put(buf, &mut n, b",\"from\":\"");
+            put(buf, &mut n, slot_name(from));
+            put(buf, &mut n, b"\",\"to\":\"");
+            put(buf, &mut n, slot_name(to));
+            put(buf, &mut n, b"\"");
+            num(buf, &mut n, b"boots", boots as u64);
+        }
+    }
+    put(buf, &mut n, b"}");
+    n
 }
 
 /// Dump recent audit events to the UEFI text console.
 pub fn dump(system_table: &mut SystemTable<Boot>) {
     let stdout = system_table.stdout();
     let mut buf = [0u8; 160];
-    let cur = AUDIT_WIDX.load(Ordering::Relaxed);
-    let start = cur.saturating_sub(AUDIT_CAP);
-    for idx in start..cur {
-        let ev = unsafe { core::ptr::read_volatile(&AUDIT_BUF[idx % AUDIT_CAP]) };
-        let mut n = 0;
-        match ev {
-            AuditKind::BootStart => { for &b in b"audit: boot_start" { buf[n] = b; n += 1; } }
-            AuditKind::BootReady => { for &b in b"audit: boot_ready" { buf[n] = b; n += 1; } }
-            AuditKind::VmCreate(id) => {
-                for &b in b"audit: vm_create id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-            }
-            AuditKind::VmStart(id) => {
-                for &b in b"audit: vm_start id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-            }
-            AuditKind::VmStop(id) => {
-                for &b in b"audit: vm_stop id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-            }
-            AuditKind::VmDestroy(id) => {
-                for &b in b"audit: vm_destroy id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-            }
-            AuditKind::IommuDomainCreate(dom) => {
-                for &b in b"audit: iommu_domain_create id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
-            }
-            AuditKind::IommuAssignAdded { seg, bus, dev, func, dom } => {
-                for &b in b"audit: iommu_assign_add bdf=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
-                buf[n] = b':'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
-                buf[n] = b':'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
-                buf[n] = b'.'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
-                for &b in b" dom=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
-            }
-            AuditKind::IommuAssignRemoved { seg, bus, dev, func, dom } => {
-                for &b in b"audit: iommu_assign_del bdf=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
-                buf[n] = b':'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
-                buf[n] = b':'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
-                buf[n] = b'.'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
-                for &b in b" dom=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
-            }
-                AuditKind::MigrateStart(id) => {
-                    for &b in b"audit: migrate_start id=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-                }
-                AuditKind::MigrateScan(id, pages) => {
-                    for &b in b"audit: migrate_scan id=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-                    for &b in b" pages=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(pages as u32, &mut buf[n..]);
-                }
-                AuditKind::MigrateStop(id) => {
-                    for &b in b"audit: migrate_stop id=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-                }
-        }
+    let _ = query(0, &Filter::ALL, usize::MAX, |r| {
+        let mut n = render_text(&r.kind, &mut buf);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    });
+}
+
+// ---- Retention ----
+
+/// Where retained events are copied besides RAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Persist { None, Variable }
+
+impl Persist {
+    pub fn name(self) -> &'static str { match self { Persist::None => "none", Persist::Variable => "var" } }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Retention { pub capacity: usize, pub persist: Persist }
+
+static PERSIST: AtomicU8 = AtomicU8::new(0);
+/// Next sequence number not yet persisted
+static FLUSHED: AtomicU64 = AtomicU64::new(0);
+static LAST_FLUSH_MS: AtomicU64 = AtomicU64::new(0);
+
+pub const FLUSH_INTERVAL_MS: u64 = 5000;
+/// Persisted events per segment variable
+pub const SEG_RECS: usize = 32;
+/// Segment variables in rotation; the oldest is overwritten once all are full
+pub const SEGMENTS: usize = 8;
+/// Events retained in the persisted log
+pub const PERSIST_MAX: usize = SEG_RECS * SEGMENTS;
+/// Position, seq, t_ms, code (padded to 8), a, b, then the chain hash
+const REC_LEN: usize = 48 + 32;
+/// Magic, version, count u16, first position u64, chain before the first record
+const SEG_HDR: usize = 48;
+const SEG_LEN: usize = SEG_HDR + SEG_RECS * REC_LEN;
+/// Magic, version, segments, records per segment u16, first u64, next u64, base, head
+const ANCHOR_LEN: usize = 24 + 32 + 32;
+const LOG_MAGIC: &[u8; 4] = b"ZAUD";
+const SEG_MAGIC: &[u8; 4] = b"ZASG";
+const LOG_VERSION: u8 = 2;
+const VAR_NS: uefi::table::runtime::VariableVendor = uefi::table::runtime::VariableVendor::GLOBAL_VARIABLE;
+
+pub fn retention() -> Retention {
+    let persist = if PERSIST.load(Ordering::Relaxed) == 1 { Persist::Variable } else { Persist::None };
+    Retention { capacity: AUDIT_CAP.load(Ordering::Relaxed), persist }
+}
+
+/// Apply a retention policy. Shrinking the ring drops the oldest events;
+/// growing it keeps what is already retained.
+pub fn set_retention(r: Retention) -> Result<(), &'static str> {
+    if !(AUDIT_CAP_MIN..=AUDIT_CAP_MAX).contains(&r.capacity) { return Err("audit: size out of range (16..1024)"); }
+    let old = AUDIT_CAP.load(Ordering::Relaxed);
+    if r.capacity != old {
+        // Re-home the retained window so slot = seq % capacity still holds.
+        let end = head();
+        let keep = (old.min(r.capacity)) as u64;
+        unsafe {
+            for i in 0..AUDIT_CAP_MAX { RESIZE_BUF[i].seq = EMPTY; }
+            for seq in end.saturating_sub(keep)..end {
+                let s = core::ptr::read_volatile(&AUDIT_BUF[(seq % old as u64) as usize]);
+                if s.seq == seq { RESIZE_BUF[(seq % r.capacity as u64) as usize] = s; }
+            }
+            for i in 0..AUDIT_CAP_MAX { core::ptr::write_volatile(&mut AUDIT_BUF[i], RESIZE_BUF[i]); }
+        }
+        AUDIT_CAP.store(r.capacity, Ordering::Relaxed);
     }
+    PERSIST.store(if r.persist == Persist::Variable { 1 } else { 0 }, Ordering::Relaxed);
+    Ok(())
 }
 
+/// Save the retention policy so `load_retention` restores it on the next boot.
+pub fn save_retention(system_table: &SystemTable<Boot>) -> Result<(), &'static str> {
+    let r = retention();
+    let mut buf = [0u8; 4];
+    buf[0..2].copy_from_slice(&(r.capacity as u16).to_le_bytes());
+    buf[2] = if r.persist == Persist::Variable { 1 } else { 0 };
+    let attrs = uefi::table::runtime::VariableAttributes::BOOTSERVICE_ACCESS | uefi::table::runtime::VariableAttributes::NON_VOLATILE;
+    system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorAuditCfg"), &VAR_NS, attrs, &buf).map_err(|_| "audit: set_variable failed")
+}
+
+pub fn load_retention(system_table: &SystemTable<Boot>) -> Option<Retention> {
+    let mut buf = [0u8; 4];
+    let (data, _) = system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorAuditCfg"), &VAR_NS, &mut buf).ok()?;
+    if data.len() < 3 { return None; }
+    let r = Retention {
+        capacity: u16::from_le_bytes([data[0], data[1]]) as usize,
+        persist: if data[2] == 1 { Persist::Variable } else { Persist::None },
+    };
+    set_retention(r).ok()?;
+    Some(r)
+}
 
+// ---- Persisted chain ----
+//
+// Persisted events form one hash chain: each record carries
+// SHA-256(previous chain || its first 48 bytes), and positions count up
+// across boots. Records live in `SEGMENTS` rotating variables of `SEG_RECS`
+// each; the anchor variable holds the retained window (`first..next`), the
+// chain value before `first` and the chain head. Editing, reordering or
+// dropping a record, or truncating the log behind the anchor, breaks the
+// recomputed chain.
+
+#[derive(Clone, Copy)]
+struct Chain {
+    loaded: bool,
+    /// Oldest retained position
+    first: u64,
+    /// Next position to write
+    next: u64,
+    /// Chain value before `first`
+    base: [u8; 32],
+    head: [u8; 32],
+    /// The segment holding `next`, header included
+    seg: [u8; SEG_LEN],
+}
+
+static CHAIN: SpinLock<Chain> = SpinLock::new(Chain { loaded: false, first: 0, next: 0, base: [0; 32], head: [0; 32], seg: [0; SEG_LEN] });
+
+fn seg_name(i: usize) -> &'static uefi::CStr16 {
+    match i {
+        0 => uefi::cstr16!("ZerovisorAuditSeg0"), 1 => uefi::cstr16!("ZerovisorAuditSeg1"),
+        2 => uefi::cstr16!("ZerovisorAuditSeg2"), 3 => uefi::cstr16!("ZerovisorAuditSeg3"),
+        4 => uefi::cstr16!("ZerovisorAuditSeg4"), 5 => uefi::cstr16!("ZerovisorAuditSeg5"),
+        6 => uefi::cstr16!("ZerovisorAuditSeg6"), _ => uefi::cstr16!("ZerovisorAuditSeg7"),
+    }
+}
+
+fn seg_of(pos: u64) -> usize { ((pos / SEG_RECS as u64) % SEGMENTS as u64) as usize }
+
+fn le64(b: &[u8], o: usize) -> u64 { let mut x = [0u8; 8]; x.copy_from_slice(&b[o..o + 8]); u64::from_le_bytes(x) }
+
+fn genesis() -> [u8; 32] { crate::util::sha256::sha256(b"zerovisor audit chain v2") }
+
+fn link(prev: &[u8; 32], rec: &[u8]) -> [u8; 32] {
+    let mut h = crate::util::sha256::Sha256::new();
+    h.update(prev);
+    h.update(&rec[..48]);
+    h.finish()
+}
+
+fn var_attrs() -> uefi::table::runtime::VariableAttributes {
+    uefi::table::runtime::VariableAttributes::BOOTSERVICE_ACCESS | uefi::table::runtime::VariableAttributes::NON_VOLATILE
+}
+
+/// Read segment `i` into `buf`; returns its record count if the header is valid.
+fn read_seg(system_table: &SystemTable<Boot>, i: usize, buf: &mut [u8; SEG_LEN]) -> Option<usize> {
+    let (data, _) = system_table.runtime_services().get_variable(seg_name(i), &VAR_NS, buf).ok()?;
+    if data.len() < SEG_HDR || &data[0..4] != SEG_MAGIC || data[4] != LOG_VERSION { return None; }
+    let count = u16::from_le_bytes([data[6], data[7]]) as usize;
+    if count > SEG_RECS || data.len() < SEG_HDR + count * REC_LEN { return None; }
+    Some(count)
+}
+
+fn write_seg(system_table: &SystemTable<Boot>, c: &Chain) -> Result<(), &'static str> {
+    let count = u16::from_le_bytes([c.seg[6], c.seg[7]]) as usize;
+    system_table.runtime_services().set_variable(seg_name(seg_of(le64(&c.seg, 8))), &VAR_NS, var_attrs(), &c.seg[..SEG_HDR + count * REC_LEN])
+        .map_err(|_| "audit: set_variable failed")
+}
+
+struct Anchor { first: u64, next: u64, base: [u8; 32], head: [u8; 32] }
+
+fn read_anchor(system_table: &SystemTable<Boot>) -> Option<Anchor> {
+    let mut buf = [0u8; ANCHOR_LEN];
+    let (data, _) = system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorAuditLog"), &VAR_NS, &mut buf).ok()?;
+    if data.len() < ANCHOR_LEN || &data[0..4] != LOG_MAGIC || data[4] != LOG_VERSION { return None; }
+    if data[5] as usize != SEGMENTS || u16::from_le_bytes([data[6], data[7]]) as usize != SEG_RECS { return None; }
+    let mut a = Anchor { first: le64(data, 8), next: le64(data, 16), base: [0; 32], head: [0; 32] };
+    if a.first > a.next || a.first % SEG_RECS as u64 != 0 { return None; }
+    a.base.copy_from_slice(&data[24..56]);
+    a.head.copy_from_slice(&data[56..88]);
+    Some(a)
+}
+
+fn write_anchor(system_table: &SystemTable<Boot>, c: &Chain) -> Result<(), &'static str> {
+    let mut buf = [0u8; ANCHOR_LEN];
+    buf[0..4].copy_from_slice(LOG_MAGIC);
+    buf[4] = LOG_VERSION;
+    buf[5] = SEGMENTS as u8;
+    buf[6..8].copy_from_slice(&(SEG_RECS as u16).to_le_bytes());
+    buf[8..16].copy_from_slice(&c.first.to_le_bytes());
+    buf[16..24].copy_from_slice(&c.next.to_le_bytes());
+    buf[24..56].copy_from_slice(&c.base);
+    buf[56..88].copy_from_slice(&c.head);
+    system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorAuditLog"), &VAR_NS, var_attrs(), &buf)
+        .map_err(|_| "audit: set_variable failed")
+}
+
+/// Pick up the chain where the anchor left it (possibly in an earlier boot),
+/// or start a new one.
+fn load_chain(system_table: &SystemTable<Boot>, c: &mut Chain) {
+    c.loaded = true;
+    let Some(a) = read_anchor(system_table) else {
+        c.first = 0; c.next = 0; c.base = genesis(); c.head = c.base;
+        return;
+    };
+    c.first = a.first; c.next = a.next; c.base = a.base; c.head = a.head;
+    let slot = (c.next % SEG_RECS as u64) as usize;
+    if slot == 0 { return; }
+    let start = c.next - slot as u64;
+    let ok = matches!(read_seg(system_table, seg_of(c.next), &mut c.seg), Some(n) if n >= slot && le64(&c.seg, 8) == start);
+    if ok {
+        c.seg[6..8].copy_from_slice(&(slot as u16).to_le_bytes());
+    } else {
+        // The open segment no longer matches the anchor. Continue in a fresh
+        // segment; `verify` reports the missing records until they rotate out.
+        c.next = start + SEG_RECS as u64;
+    }
+}
+
+/// Append one event to the open segment, rotating segments as they fill.
+fn append(system_table: &SystemTable<Boot>, c: &mut Chain, r: &Record) -> Result<(), &'static str> {
+    let slot = (c.next % SEG_RECS as u64) as usize;
+    if slot == 0 {
+        if c.next - c.first >= PERSIST_MAX as u64 {
+            // The segment about to be reused holds the oldest records; the
+            // window then starts at the next one, whose header has its base.
+            let mut buf = [0u8; SEG_LEN];
+            let next_first = c.first + SEG_RECS as u64;
+            c.base = match read_seg(system_table, seg_of(next_first), &mut buf) {
+                Some(_) if le64(&buf, 8) == next_first => { let mut b = [0u8; 32]; b.copy_from_slice(&buf[16..48]); b }
+                _ => [0; 32],
+            };
+            c.first = next_first;
+        }
+        c.seg.fill(0);
+        c.seg[0..4].copy_from_slice(SEG_MAGIC);
+        c.seg[4] = LOG_VERSION;
+        c.seg[8..16].copy_from_slice(&c.next.to_le_bytes());
+        c.seg[16..48].copy_from_slice(&c.head);
+    }
+    let (code, a, b) = r.kind.pack();
+    let o = SEG_HDR + slot * REC_LEN;
+    let rec = &mut c.seg[o..o + REC_LEN];
+    rec[0..8].copy_from_slice(&c.next.to_le_bytes());
+    rec[8..16].copy_from_slice(&r.seq.to_le_bytes());
+    rec[16..24].copy_from_slice(&r.t_ms.to_le_bytes());
+    rec[24] = code;
+    rec[32..40].copy_from_slice(&a.to_le_bytes());
+    rec[40..48].copy_from_slice(&b.to_le_bytes());
+    c.head = link(&c.head, rec);
+    rec[48..80].copy_from_slice(&c.head);
+    c.seg[6..8].copy_from_slice(&((slot + 1) as u16).to_le_bytes());
+    c.next += 1;
+    if slot + 1 == SEG_RECS { write_seg(system_table, c)?; }
+    Ok(())
+}
+
+/// Append every event recorded since the last flush to the persisted chain.
+/// Returns how many were written.
+pub fn flush(system_table: &SystemTable<Boot>) -> Result<usize, &'static str> {
+    let end = head();
+    let from = FLUSHED.load(Ordering::Relaxed);
+    let (count, done, res) = CHAIN.lock(|c| {
+        if !c.loaded { load_chain(system_table, c); }
+        let mut count = 0usize;
+        let mut done = from;
+        let mut res = Ok(());
+        let _ = query(from, &Filter::ALL, usize::MAX, |r| {
+            if res.is_err() || r.seq >= end { return; }
+            res = append(system_table, c, r);
+            if res.is_ok() { count += 1; done = r.seq + 1; }
+        });
+        if count != 0 && c.next % SEG_RECS as u64 != 0 && res.is_ok() { res = write_seg(system_table, c); }
+        if count != 0 { res = res.and(write_anchor(system_table, c)); }
+        (count, if res.is_ok() { end } else { done }, res)
+    });
+    FLUSHED.store(done, Ordering::Relaxed);
+    LAST_FLUSH_MS.store(crate::time::clock::now_ms(), Ordering::Relaxed);
+    res.map(|_| count)
+}
+
+/// Periodic persistence; call from idle. Flushes when the policy asks for it,
+/// new events exist and `FLUSH_INTERVAL_MS` passed since the last flush.
+pub fn tick(system_table: &SystemTable<Boot>) {
+    if retention().persist != Persist::Variable || FLUSHED.load(Ordering::Relaxed) == head() { return; }
+    let since = crate::time::clock::now_ms().saturating_sub(LAST_FLUSH_MS.load(Ordering::Relaxed));
+    if crate::time::clock::hz() != 0 && since < FLUSH_INTERVAL_MS { return; }
+    let _ = flush(system_table);
+}
+
+/// Walk the persisted window oldest first, passing each raw record (or
+/// `None` where a segment is missing or does not hold that position).
+fn walk_persisted(system_table: &SystemTable<Boot>, a: &Anchor, mut f: impl FnMut(u64, Option<&[u8]>, Option<&[u8; 32]>)) {
+    let mut buf = [0u8; SEG_LEN];
+    let mut cur: Option<(u64, usize)> = None;
+    for pos in a.first..a.next {
+        let start = pos - pos % SEG_RECS as u64;
+        if cur.map(|(s, _)| s) != Some(start) {
+            cur = Some((start, match read_seg(system_table, seg_of(pos), &mut buf) { Some(n) if le64(&buf, 8) == start => n, _ => 0 }));
+        }
+        let (_, n) = cur.unwrap_or((start, 0));
+        let slot = (pos - start) as usize;
+        if slot >= n { f(pos, None, None); continue; }
+        let mut prev = [0u8; 32];
+        prev.copy_from_slice(&buf[16..48]);
+        let o = SEG_HDR + slot * REC_LEN;
+        f(pos, Some(&buf[o..o + REC_LEN]), if slot == 0 { Some(&prev) } else { None });
+    }
+}
+
+/// Read back the persisted events (from this or earlier boots), oldest first.
+pub fn read_persisted(system_table: &mut SystemTable<Boot>, mut f: impl FnMut(&mut SystemTable<Boot>, &Record)) -> Option<usize> {
+    let a = read_anchor(system_table)?;
+    let mut recs = [None; PERSIST_MAX];
+    let mut count = 0usize;
+    walk_persisted(system_table, &a, |_, rec, _| {
+        let Some(rec) = rec else { return };
+        if let Some(kind) = AuditKind::unpack(rec[24], le64(rec, 32), le64(rec, 40)) {
+            if count < PERSIST_MAX { recs[count] = Some(Record { seq: le64(rec, 8), t_ms: le64(rec, 16), kind }); count += 1; }
+        }
+    });
+    for r in recs.iter().flatten() { f(system_table, r); }
+    Some(count)
+}
+
+/// Outcome of `verify`.
+#[derive(Clone, Copy, Debug)]
+pub struct Verify {
+    /// Retained window of chain positions
+    pub first: u64,
+    pub next: u64,
+    /// Places where the in-boot sequence skips, i.e. events that left the
+    /// RAM ring before they were flushed
+    pub gaps: u64,
+    pub head: [u8; 32],
+    /// First position that failed, and why
+    pub broken: Option<(u64, &'static str)>,
+}
+
+/// Recompute the persisted chain against its anchor.
+pub fn verify(system_table: &SystemTable<Boot>) -> Result<Verify, &'static str> {
+    let a = read_anchor(system_table).ok_or("audit: no persisted chain")?;
+    let mut v = Verify { first: a.first, next: a.next, gaps: 0, head: a.head, broken: None };
+    let mut chain = a.base;
+    let mut prev_seq: Option<u64> = None;
+    walk_persisted(system_table, &a, |pos, rec, seg_prev| {
+        if v.broken.is_some() { return; }
+        let Some(rec) = rec else { v.broken = Some((pos, "record missing")); return };
+        if seg_prev.is_some_and(|p| *p != chain) { v.broken = Some((pos, "segment does not follow the previous one")); return; }
+        if le64(rec, 0) != pos { v.broken = Some((pos, "record out of place")); return; }
+        chain = link(&chain, rec);
+        if rec[48..80] != chain { v.broken = Some((pos, "hash mismatch")); return; }
+        let seq = le64(rec, 8);
+        if let Some(p) = prev_seq { if seq != p + 1 && seq != 0 { v.gaps += 1; } }
+        prev_seq = Some(seq);
+    });
+    if v.broken.is_none() && chain != a.head { v.broken = Some((a.next, "chain head does not match the anchor")); }
+    Ok(v)
+}
@@ -3,13 +3,13 @@
 use core::fmt::Write as _;
 
 #[inline(always)]
-fn read_cr0() -> u64 { let v: u64; unsafe { core::arch::asm!("mov {}, cr0", out(reg) v, options(nostack, preserves_flags)); } v }
+pub(crate) fn read_cr0() -> u64 { let v: u64; unsafe { core::arch::asm!("mov {}, cr0", out(reg) v, options(nostack, preserves_flags)); } v }
 #[inline(always)]
-fn read_cr2() -> u64 { let v: u64; unsafe { core::arch::asm!("mov {}, cr2", out(reg) v, options(nostack, preserves_flags)); } v }
+pub(crate) fn read_cr2() -> u64 { let v: u64; unsafe { core::arch::asm!("mov {}, cr2", out(reg) v, options(nostack, preserves_flags)); } v }
 #[inline(always)]
-fn read_cr3() -> u64 { let v: u64; unsafe { core::arch::asm!("mov {}, cr3", out(reg) v, options(nostack, preserves_flags)); } v }
+pub(crate) fn read_cr3() -> u64 { let v: u64; unsafe { core::arch::asm!("mov {}, cr3", out(reg) v, options(nostack, preserves_flags)); } v }
 #[inline(always)]
-fn read_cr4() -> u64 { let v: u64; unsafe { core::arch::asm!("mov {}, cr4", out(reg) v, options(nostack, preserves_flags)); } v }
+pub(crate) fn read_cr4() -> u64 { let v: u64; unsafe { core::arch::asm!("mov {}, cr4", out(reg) v, options(nostack, preserves_flags)); } v }
 #[inline(always)]
 fn read_rflags() -> u64 { let v: u64; unsafe { core::arch::asm!("pushfq; pop {}", out(reg) v, options(nostack, preserves_flags)); } v }
 #[inline(always)]
@@ -57,7 +57,7 @@ pub fn dump_regs(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot
     ];
     for (lbl, sel) in segs.iter() {
         for &b in *lbl { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(*sel as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(*sel as u32, &mut buf[n..]);
         buf[n] = b' '; n += 1;
     }
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
@@ -69,7 +69,7 @@ pub fn dump_idt(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>
     let stdout = system_table.stdout();
     let mut buf = [0u8; 96]; let mut n = 0;
     for &b in b"IDT limit=" { buf[n] = b; n += 1; }
-    n += crate::firmware::acpi::u32_to_dec(dp.limit as u32, &mut buf[n..]);
+    n += crate::util::format::u32_dec(dp.limit as u32, &mut buf[n..]);
     for &b in b" base=0x" { buf[n] = b; n += 1; }
     n += crate::util::format::u64_hex(dp.base, &mut buf[n..]);
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
@@ -81,7 +81,7 @@ pub fn dump_gdt(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>
     let stdout = system_table.stdout();
     let mut buf = [0u8; 96]; let mut n = 0;
     for &b in b"GDT limit=" { buf[n] = b; n += 1; }
-    n += crate::firmware::acpi::u32_to_dec(dp.limit as u32, &mut buf[n..]);
+    n += crate::util::format::u32_dec(dp.limit as u32, &mut buf[n..]);
     for &b in b" base=0x" { buf[n] = b; n += 1; }
     n += crate::util::format::u64_hex(dp.base, &mut buf[n..]);
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
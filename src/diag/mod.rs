@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-//! Diagnostics modules: audit log, panic reporting, and soft watchdog.
+//! Diagnostics modules: audit log, panic reporting, and host watchdog.
 
 pub mod audit;
 pub mod panic;
@@ -1,12 +1,61 @@
 #![allow(dead_code)]
 
+//! Panic reporting and the persistent crash record.
+//!
+//! On panic, `report_panic` captures the general-purpose registers and RFLAGS
+//! as the handler sees them, CR0/CR2/CR3/CR4, a frame-pointer backtrace, the
+//! panic message and the last `TRACE_EVENTS` trace records. It prints a
+//! summary, then saves the whole record to the `ZerovisorCrash` UEFI
+//! variable. On the next boot `report_last_crash` prints the summary once;
+//! `crash` shows the full record until `crash clear` deletes it.
+//!
+//! The backtrace needs frame pointers (see `.cargo/config.toml`). Return
+//! addresses are absolute; `anchor` is the runtime address of `report_panic`,
+//! so subtracting it and adding the symbol's address from the image map
+//! gives addresses that `addr2line` understands.
+
 use core::fmt::Write as _;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+/// Trace records kept in the crash record
+pub const TRACE_EVENTS: usize = 16;
+/// Return addresses kept in the backtrace
+pub const FRAMES: usize = 16;
+pub const MSG_LEN: usize = 160;
+pub const REG_NAMES: [&str; 17] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15", "rflags",
+];
+
+const MAGIC: &[u8; 4] = b"ZCRS";
+const VERSION: u8 = 1;
+/// Set once the summary was printed at boot
+const FLAG_REPORTED: u8 = 1;
+const OFF_TSC: usize = 8;
+const OFF_CPU: usize = 16;
+const OFF_REGS: usize = 24;
+const OFF_CR: usize = OFF_REGS + 17 * 8;
+const OFF_ANCHOR: usize = OFF_CR + 4 * 8;
+const OFF_FRAMES: usize = OFF_ANCHOR + 8;
+const OFF_MSG_LEN: usize = OFF_FRAMES + FRAMES * 8;
+const OFF_MSG: usize = OFF_MSG_LEN + 8;
+const OFF_TRACE: usize = OFF_MSG + MSG_LEN;
+pub const CRASH_LEN: usize = OFF_TRACE + TRACE_EVENTS * crate::obs::trace::RECORD_LEN;
+/// Largest gap between two frames the backtrace follows
+const MAX_FRAME: u64 = 1 << 20;
+const VAR_NS: uefi::table::runtime::VariableVendor = uefi::table::runtime::VariableVendor::GLOBAL_VARIABLE;
 
 /// Raw pointer to UEFI text output for emergency printing in panic context.
 /// This is a best-effort facility and intentionally uses raw pointers to avoid
 /// lifetime and borrow restrictions during panic unwinding in `no_std`.
 static UEFI_STDOUT_PTR: AtomicPtr<uefi::proto::console::text::Output> = AtomicPtr::new(core::ptr::null_mut());
+/// System table used to reach runtime services when saving the crash record
+static SYSTEM_TABLE_PTR: AtomicPtr<core::ffi::c_void> = AtomicPtr::new(core::ptr::null_mut());
+/// The system table above is the runtime view; Boot Services are gone
+static RUNTIME: AtomicBool = AtomicBool::new(false);
+static IN_PANIC: AtomicBool = AtomicBool::new(false);
 
 /// Install a raw pointer to UEFI Text Output for emergency printing.
 /// Caller must pass a valid pointer obtained from `SystemTable::stdout()`.
@@ -14,19 +63,192 @@ pub unsafe fn install_stdout_ptr(ptr: *mut uefi::proto::console::text::Output) {
     UEFI_STDOUT_PTR.store(ptr, Ordering::Relaxed);
 }
 
-/// Try to print a panic banner using the installed stdout pointer.
+/// Remember the system table so a panic can save its crash record.
+pub fn install_system_table(system_table: &SystemTable<Boot>) {
+    SYSTEM_TABLE_PTR.store(system_table.as_ptr() as *mut core::ffi::c_void, Ordering::Relaxed);
+}
+
+/// After ExitBootServices: drop the firmware console and keep only runtime
+/// services, reached through the runtime system table at `ptr`.
+pub fn enter_runtime(ptr: *const core::ffi::c_void) {
+    UEFI_STDOUT_PTR.store(core::ptr::null_mut(), Ordering::Relaxed);
+    SYSTEM_TABLE_PTR.store(ptr as *mut core::ffi::c_void, Ordering::Relaxed);
+    RUNTIME.store(true, Ordering::Relaxed);
+}
+
+/// Try to print a panic banner using the installed stdout pointer, or the
+/// serial console once firmware output is gone.
 pub fn try_print_emergency(msg: &str) {
     let p = UEFI_STDOUT_PTR.load(Ordering::Relaxed);
-    if p.is_null() { return; }
+    if p.is_null() { crate::obs::console::write_bytes(msg.as_bytes()); return; }
     // SAFETY: The pointer is provided by firmware and assumed to remain valid
     // during program lifetime. We avoid any allocation and keep printing minimal.
     let out = unsafe { &mut *p };
     let _ = out.write_str(msg);
 }
 
-/// Best-effort panic reporter. Avoids allocation and complex formatting.
-pub fn report_panic(_info: &core::panic::PanicInfo) {
+/// RAX..R15 and RFLAGS, in `REG_NAMES` order.
+#[inline(always)]
+fn capture_regs() -> [u64; 17] {
+    let mut r = [0u64; 17];
+    unsafe {
+        core::arch::asm!(
+            "mov [{p}], rax", "mov [{p} + 8], rbx", "mov [{p} + 16], rcx", "mov [{p} + 24], rdx",
+            "mov [{p} + 32], rsi", "mov [{p} + 40], rdi", "mov [{p} + 48], rbp", "mov [{p} + 56], rsp",
+            "mov [{p} + 64], r8", "mov [{p} + 72], r9", "mov [{p} + 80], r10", "mov [{p} + 88], r11",
+            "mov [{p} + 96], r12", "mov [{p} + 104], r13", "mov [{p} + 112], r14", "mov [{p} + 120], r15",
+            "pushfq", "pop qword ptr [{p} + 128]",
+            p = in(reg) r.as_mut_ptr(),
+        );
+    }
+    r
+}
+
+/// Follow the RBP chain, storing return addresses. Stops at a null, misaligned
+/// or non-increasing frame pointer, so a corrupt stack ends the walk early.
+#[inline(always)]
+fn backtrace(out: &mut [u64]) -> usize {
+    let mut rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)); }
+    let mut n = 0;
+    while n < out.len() && rbp != 0 && rbp & 7 == 0 {
+        let (next, ret) = unsafe { (core::ptr::read_volatile(rbp as *const u64), core::ptr::read_volatile((rbp + 8) as *const u64)) };
+        if ret == 0 { break; }
+        out[n] = ret;
+        n += 1;
+        if next <= rbp || next - rbp > MAX_FRAME { break; }
+        rbp = next;
+    }
+    n
+}
+
+fn get(rec: &[u8], off: usize) -> u64 { let mut b = [0u8; 8]; b.copy_from_slice(&rec[off..off + 8]); u64::from_le_bytes(b) }
+fn put(rec: &mut [u8], off: usize, v: u64) { rec[off..off + 8].copy_from_slice(&v.to_le_bytes()); }
+
+fn build(info: &core::panic::PanicInfo, rec: &mut [u8; CRASH_LEN]) {
+    let regs = capture_regs();
+    let mut frames = [0u64; FRAMES];
+    let nframes = backtrace(&mut frames);
+    rec[0..4].copy_from_slice(MAGIC);
+    rec[4] = VERSION;
+    rec[6] = nframes as u8;
+    put(rec, OFF_TSC, crate::time::rdtsc());
+    let cpu = crate::arch::x86::cpuid::cpuid(crate::arch::x86::cpuid::leaf::BASIC_FEATURES, 0).ebx >> 24;
+    put(rec, OFF_CPU, cpu as u64);
+    for (i, v) in regs.iter().enumerate() { put(rec, OFF_REGS + i * 8, *v); }
+    let crs = [super::dump::read_cr0(), super::dump::read_cr2(), super::dump::read_cr3(), super::dump::read_cr4()];
+    for (i, v) in crs.iter().enumerate() { put(rec, OFF_CR + i * 8, *v); }
+    put(rec, OFF_ANCHOR, report_panic as fn(&core::panic::PanicInfo) as usize as u64);
+    for (i, v) in frames.iter().enumerate() { put(rec, OFF_FRAMES + i * 8, *v); }
+    let mut w = crate::util::format::BufWriter::new(&mut rec[OFF_MSG..OFF_MSG + MSG_LEN]);
+    let _ = write!(w, "{}", info);
+    // Keep the message on one line of the report.
+    for b in w.bytes_mut() { if *b == b'\n' || *b == b'\r' { *b = b' '; } }
+    let len = w.len();
+    put(rec, OFF_MSG_LEN, len as u64);
+    let mut count = 0usize;
+    crate::obs::trace::last_raw(TRACE_EVENTS, |r| {
+        let o = OFF_TRACE + count * crate::obs::trace::RECORD_LEN;
+        rec[o..o + r.len()].copy_from_slice(r);
+        count += 1;
+    });
+    rec[7] = count as u8;
+}
+
+/// Runtime access, so a panic after ExitBootServices can still save it.
+fn var_attrs() -> uefi::table::runtime::VariableAttributes {
+    uefi::table::runtime::VariableAttributes::BOOTSERVICE_ACCESS | uefi::table::runtime::VariableAttributes::RUNTIME_ACCESS
+        | uefi::table::runtime::VariableAttributes::NON_VOLATILE
+}
+
+fn save(rec: &[u8; CRASH_LEN]) -> bool {
+    let p = SYSTEM_TABLE_PTR.load(Ordering::Relaxed);
+    if p.is_null() { return false; }
+    let name = uefi::cstr16!("ZerovisorCrash");
+    // SAFETY: installed from the live system table, boot or runtime view.
+    if RUNTIME.load(Ordering::Relaxed) {
+        let Some(st) = (unsafe { SystemTable::<uefi::table::Runtime>::from_ptr(p) }) else { return false };
+        return unsafe { st.runtime_services() }.set_variable(name, &VAR_NS, var_attrs(), rec).is_ok();
+    }
+    let Some(st) = (unsafe { SystemTable::<Boot>::from_ptr(p) }) else { return false };
+    st.runtime_services().set_variable(name, &VAR_NS, var_attrs(), rec).is_ok()
+}
+
+fn load(system_table: &SystemTable<Boot>, rec: &mut [u8; CRASH_LEN]) -> bool {
+    match system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorCrash"), &VAR_NS, rec) {
+        Ok((data, _)) => data.len() == CRASH_LEN && &data[0..4] == MAGIC && data[4] == VERSION,
+        Err(_) => false,
+    }
+}
+
+/// Text of a crash record, one line per call. The summary has the message,
+/// CR2/CR3 and the backtrace; `full` adds every register and the trace tail.
+pub fn render(rec: &[u8; CRASH_LEN], full: bool, mut write_bytes: impl FnMut(&[u8])) {
+    let mut buf = [0u8; 384];
+    let mut n = 0;
+    for &b in b"crash: cpu " { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(get(rec, OFF_CPU), &mut buf[n..]);
+    for &b in b": " { buf[n] = b; n += 1; }
+    let len = (get(rec, OFF_MSG_LEN) as usize).min(MSG_LEN);
+    for &b in &rec[OFF_MSG..OFF_MSG + len] { buf[n] = b; n += 1; }
+    for &b in b"\r\n" { buf[n] = b; n += 1; }
+    write_bytes(&buf[..n]);
+    n = 0;
+    for (i, name) in ["cr0", "cr2", "cr3", "cr4"].iter().enumerate() {
+        if !full && (i == 0 || i == 3) { continue; }
+        for &b in b"  " { buf[n] = b; n += 1; }
+        for &b in name.as_bytes() { buf[n] = b; n += 1; }
+        for &b in b"=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(get(rec, OFF_CR + i * 8), &mut buf[n..]);
+    }
+    for &b in b"  anchor=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(get(rec, OFF_ANCHOR), &mut buf[n..]);
+    for &b in b"\r\n" { buf[n] = b; n += 1; }
+    write_bytes(&buf[..n]);
+    if full {
+        for row in REG_NAMES.chunks(4).enumerate() {
+            n = 0;
+            for (k, name) in row.1.iter().enumerate() {
+                for &b in b"  " { buf[n] = b; n += 1; }
+                for &b in name.as_bytes() { buf[n] = b; n += 1; }
+                for &b in b"=0x" { buf[n] = b; n += 1; }
+                n += crate::util::format::u64_hex(get(rec, OFF_REGS + (row.0 * 4 + k) * 8), &mut buf[n..]);
+            }
+            for &b in b"\r\n" { buf[n] = b; n += 1; }
+            write_bytes(&buf[..n]);
+        }
+    }
+    n = 0;
+    for &b in b"  backtrace:" { buf[n] = b; n += 1; }
+    let frames = (rec[6] as usize).min(FRAMES);
+    if frames == 0 { for &b in b" none" { buf[n] = b; n += 1; } }
+    for i in 0..frames {
+        for &b in b" 0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(get(rec, OFF_FRAMES + i * 8), &mut buf[n..]);
+    }
+    for &b in b"\r\n" { buf[n] = b; n += 1; }
+    write_bytes(&buf[..n]);
+    if !full { return; }
+    let mut line = [0u8; 192];
+    for i in 0..(rec[7] as usize).min(TRACE_EVENTS) {
+        let o = OFF_TRACE + i * crate::obs::trace::RECORD_LEN;
+        let mut raw = [0u8; crate::obs::trace::RECORD_LEN];
+        raw.copy_from_slice(&rec[o..o + crate::obs::trace::RECORD_LEN]);
+        let n = crate::obs::trace::raw_line(&raw, &mut line);
+        write_bytes(&line[..n]);
+    }
+}
+
+/// Best-effort panic reporter. Avoids allocation; the only formatting is the
+/// panic message, into a fixed buffer.
+pub fn report_panic(info: &core::panic::PanicInfo) {
     try_print_emergency("PANIC: unrecoverable error\r\n");
+    // A panic while reporting one: the banner is all we can safely do.
+    if IN_PANIC.swap(true, Ordering::SeqCst) { return; }
+    let mut rec = [0u8; CRASH_LEN];
+    build(info, &mut rec);
+    render(&rec, false, |bytes| try_print_emergency(core::str::from_utf8(bytes).unwrap_or("\r\n")));
+    try_print_emergency(if save(&rec) { "crash record saved\r\n" } else { "crash record not saved\r\n" });
     // Best-effort recent log dump to assist diagnosis
     let p = UEFI_STDOUT_PTR.load(core::sync::atomic::Ordering::Relaxed);
     if !p.is_null() {
@@ -39,4 +261,29 @@ pub fn report_panic(_info: &core::panic::PanicInfo) {
     }
 }
 
+/// Print the summary of a crash record left by an earlier boot, once.
+pub fn report_last_crash(system_table: &mut SystemTable<Boot>) {
+    let mut rec = [0u8; CRASH_LEN];
+    if !load(system_table, &mut rec) || rec[5] & FLAG_REPORTED != 0 { return; }
+    {
+        let stdout = system_table.stdout();
+        let _ = stdout.write_str("previous boot panicked:\r\n");
+        render(&rec, false, |bytes| { let _ = stdout.write_str(core::str::from_utf8(bytes).unwrap_or("\r\n")); });
+        let _ = stdout.write_str("  'crash' shows registers and trace, 'crash clear' discards it\r\n");
+    }
+    rec[5] |= FLAG_REPORTED;
+    let _ = system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorCrash"), &VAR_NS, var_attrs(), &rec);
+}
+
+/// Print the saved crash record in full. Returns false if there is none.
+pub fn show_crash(system_table: &mut SystemTable<Boot>) -> bool {
+    let mut rec = [0u8; CRASH_LEN];
+    if !load(system_table, &mut rec) { return false; }
+    let stdout = system_table.stdout();
+    render(&rec, true, |bytes| { let _ = stdout.write_str(core::str::from_utf8(bytes).unwrap_or("\r\n")); });
+    true
+}
 
+pub fn clear_crash(system_table: &SystemTable<Boot>) -> bool {
+    system_table.runtime_services().delete_variable(uefi::cstr16!("ZerovisorCrash"), &VAR_NS).is_ok()
+}
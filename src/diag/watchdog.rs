@@ -1,35 +1,348 @@
 #![allow(dead_code)]
 
+//! Host watchdog.
+//!
+//! Three backends, tried in this order by `probe`: the ACPI WDAT table
+//! (firmware-described watchdog instructions), the Intel TCO timer found
+//! through the PCH SMBus or LPC function, and the UEFI boot-services
+//! watchdog. Once armed the timer is a deadman: `pet` reloads it from the
+//! console idle loop, so a host that stops reaching the prompt is reset by
+//! hardware. Arming and disarming are audited.
+//!
+//! The TCO timer only resets the platform if firmware left the chipset's
+//! NO_REBOOT strap clear; otherwise expiry just latches the status bits.
+
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
 use core::fmt::Write as _;
 
-/// Configure a firmware watchdog timeout in seconds if supported by UEFI.
-/// Returns true on success or false if not supported or failed.
+use crate::arch::x86::port::{inl, inw, outl, outw};
+use crate::util::spinlock::SpinLock;
+
+/// Minimum time between two reloads from `pet`
+pub const PET_INTERVAL_MS: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend { Uefi, Wdat, Tco }
+
+impl Backend {
+    pub fn code(self) -> u8 { match self { Backend::Uefi => 0, Backend::Wdat => 1, Backend::Tco => 2 } }
+    pub fn name(self) -> &'static str { match self { Backend::Uefi => "uefi", Backend::Wdat => "wdat", Backend::Tco => "tco" } }
+}
+
+// ---- ACPI WDAT ----
+
+const WDAT_PERIOD: usize = 48;
+const WDAT_MAX_COUNT: usize = 52;
+const WDAT_MIN_COUNT: usize = 56;
+const WDAT_FLAGS: usize = 60;
+const WDAT_NR_ENTRIES: usize = 64;
+const WDAT_ENTRIES: usize = 68;
+const WDAT_ENTRY_LEN: usize = 24;
+const WDAT_FLAG_ENABLED: u8 = 1 << 0;
+
+const ACT_RESET: u8 = 0x01;
+const ACT_SET_COUNTDOWN: u8 = 0x06;
+const ACT_SET_RUNNING: u8 = 0x09;
+const ACT_SET_STOPPED: u8 = 0x0B;
+const ACT_SET_REBOOT: u8 = 0x11;
+
+const INS_READ_VALUE: u8 = 0;
+const INS_READ_COUNTDOWN: u8 = 1;
+const INS_WRITE_VALUE: u8 = 2;
+const INS_WRITE_COUNTDOWN: u8 = 3;
+const INS_PRESERVE: u8 = 0x80;
+
+const GAS_MEMORY: u8 = 0;
+const GAS_IO: u8 = 1;
+
+#[derive(Clone, Copy, Debug)]
+struct Wdat {
+    /// Table address (identity mapped)
+    table: usize,
+    entries: usize,
+    /// Milliseconds per count
+    period_ms: u32,
+    min_count: u32,
+    max_count: u32,
+}
+
+fn rd32(p: usize) -> u32 { unsafe { core::ptr::read_unaligned(p as *const u32) } }
+fn rd8(p: usize) -> u8 { unsafe { core::ptr::read_volatile(p as *const u8) } }
+
+fn wdat_find(system_table: &SystemTable<Boot>) -> Option<Wdat> {
+    let hdr = crate::firmware::acpi::find_table(system_table, *b"WDAT")?;
+    let table = hdr as *const crate::firmware::acpi::SdtHeader as usize;
+    let len = rd32(table + 4) as usize;
+    if len < WDAT_ENTRIES || rd8(table + WDAT_FLAGS) & WDAT_FLAG_ENABLED == 0 { return None; }
+    let entries = (rd32(table + WDAT_NR_ENTRIES) as usize).min((len - WDAT_ENTRIES) / WDAT_ENTRY_LEN);
+    let w = Wdat { table, entries, period_ms: rd32(table + WDAT_PERIOD), min_count: rd32(table + WDAT_MIN_COUNT), max_count: rd32(table + WDAT_MAX_COUNT) };
+    if w.period_ms == 0 || w.entries == 0 { return None; }
+    Some(w)
+}
+
+/// Read a GAS register `bytes` wide.
+fn gas_read(space: u8, addr: u64, bytes: u8) -> Option<u32> {
+    Some(match (space, bytes) {
+        (GAS_MEMORY, 1) => unsafe { core::ptr::read_volatile(addr as *const u8) as u32 },
+        (GAS_MEMORY, 2) => unsafe { core::ptr::read_volatile(addr as *const u16) as u32 },
+        (GAS_MEMORY, _) => unsafe { core::ptr::read_volatile(addr as *const u32) },
+        (GAS_IO, 1) => unsafe { crate::arch::x86::port::inb(addr as u16) as u32 },
+        (GAS_IO, 2) => unsafe { inw(addr as u16) as u32 },
+        (GAS_IO, _) => unsafe { inl(addr as u16) },
+        _ => return None,
+    })
+}
+
+fn gas_write(space: u8, addr: u64, bytes: u8, v: u32) -> bool {
+    match (space, bytes) {
+        (GAS_MEMORY, 1) => unsafe { core::ptr::write_volatile(addr as *mut u8, v as u8) },
+        (GAS_MEMORY, 2) => unsafe { core::ptr::write_volatile(addr as *mut u16, v as u16) },
+        (GAS_MEMORY, _) => unsafe { core::ptr::write_volatile(addr as *mut u32, v) },
+        (GAS_IO, 1) => unsafe { crate::arch::x86::port::outb(addr as u16, v as u8) },
+        (GAS_IO, 2) => unsafe { outw(addr as u16, v as u16) },
+        (GAS_IO, _) => unsafe { outl(addr as u16, v) },
+        _ => return false,
+    }
+    true
+}
+
+/// Run every instruction of `action`. `param` is the countdown for the
+/// `*_COUNTDOWN` instructions. Returns the last value read, or `None` if the
+/// action has no instructions or one of them could not be executed.
+fn wdat_run(w: &Wdat, action: u8, param: u32) -> Option<u32> {
+    let mut ran = false;
+    let mut result = 0u32;
+    for i in 0..w.entries {
+        let e = w.table + WDAT_ENTRIES + i * WDAT_ENTRY_LEN;
+        if rd8(e) != action { continue; }
+        let flags = rd8(e + 1);
+        let (space, bit_width, bit_off, access) = (rd8(e + 4), rd8(e + 5), rd8(e + 6), rd8(e + 7));
+        let addr = (rd32(e + 8) as u64) | (rd32(e + 12) as u64) << 32;
+        let (value, mask) = (rd32(e + 16), rd32(e + 20));
+        // Access size 1..3 selects byte/word/dword; 0 means "use the bit width".
+        let bytes = match access { 1 | 2 => access, 3 | 4 => 4, _ => (bit_width / 8).clamp(1, 4) };
+        let shift = (bit_off as u32).min(31);
+        match flags & !INS_PRESERVE {
+            INS_READ_VALUE => result = ((gas_read(space, addr, bytes)? >> shift) & mask == value) as u32,
+            INS_READ_COUNTDOWN => result = (gas_read(space, addr, bytes)? >> shift) & mask,
+            k @ (INS_WRITE_VALUE | INS_WRITE_COUNTDOWN) => {
+                let v = if k == INS_WRITE_COUNTDOWN { param } else { value };
+                let mut x = (v & mask) << shift;
+                if flags & INS_PRESERVE != 0 { x |= gas_read(space, addr, bytes)? & !(mask << shift); }
+                if !gas_write(space, addr, bytes, x) { return None; }
+            }
+            _ => return None,
+        }
+        ran = true;
+    }
+    if ran { Some(result) } else { None }
+}
+
+// ---- Intel TCO ----
+
+const PCI_CFG_ADDR: u16 = 0xCF8;
+const PCI_CFG_DATA: u16 = 0xCFC;
+const INTEL_VID: u32 = 0x8086;
+
+const TCO_RLD: u16 = 0x00;
+const TCO1_STS: u16 = 0x04;
+const TCO2_STS: u16 = 0x06;
+const TCO1_CNT: u16 = 0x08;
+const TCO_TMR: u16 = 0x12;
+const TCO1_STS_TIMEOUT: u16 = 1 << 3;
+const TCO2_STS_SECOND_TO: u16 = 1 << 1;
+const TCO1_CNT_TMR_HLT: u16 = 1 << 11;
+/// Timer ticks are 0.6 s; the platform resets on the second expiry.
+const TCO_TICK_MS: u64 = 600;
+const TCO_TMR_MIN: u16 = 2;
+const TCO_TMR_MAX: u16 = 0x3FF;
+
+fn pci_cfg_read32(bus: u8, dev: u8, func: u8, off: u8) -> u32 {
+    let a = 0x8000_0000u32 | (bus as u32) << 16 | (dev as u32) << 11 | (func as u32) << 8 | (off as u32 & 0xFC);
+    unsafe { outl(PCI_CFG_ADDR, a); inl(PCI_CFG_DATA) }
+}
+
+/// I/O base of the TCO registers: TCOBASE of the PCH SMBus function on 100
+/// series and later, else PMBASE + 0x60 of the ICH LPC bridge.
+fn tco_find() -> Option<u16> {
+    if pci_cfg_read32(0, 0x1F, 4, 0) & 0xFFFF == INTEL_VID && pci_cfg_read32(0, 0x1F, 4, 0x54) & (1 << 8) != 0 {
+        let base = (pci_cfg_read32(0, 0x1F, 4, 0x50) & 0xFFE0) as u16;
+        if base != 0 { return Some(base); }
+    }
+    if pci_cfg_read32(0, 0x1F, 0, 0) & 0xFFFF == INTEL_VID && pci_cfg_read32(0, 0x1F, 0, 0x44) & (1 << 7) != 0 {
+        let pm = (pci_cfg_read32(0, 0x1F, 0, 0x40) & 0xFF80) as u16;
+        if pm != 0 { return Some(pm + 0x60); }
+    }
+    None
+}
+
+fn tco_arm(base: u16, secs: u32) {
+    let ticks = (secs as u64 * 1000 / 2 / TCO_TICK_MS).clamp(TCO_TMR_MIN as u64, TCO_TMR_MAX as u64) as u16;
+    unsafe {
+        outw(base + TCO_TMR, (inw(base + TCO_TMR) & !TCO_TMR_MAX) | ticks);
+        outw(base + TCO_RLD, 1);
+        outw(base + TCO1_STS, TCO1_STS_TIMEOUT);
+        outw(base + TCO2_STS, TCO2_STS_SECOND_TO);
+        outw(base + TCO1_CNT, inw(base + TCO1_CNT) & !TCO1_CNT_TMR_HLT);
+    }
+}
+
+fn tco_disarm(base: u16) {
+    unsafe { outw(base + TCO1_CNT, inw(base + TCO1_CNT) | TCO1_CNT_TMR_HLT) };
+}
+
+fn tco_pet(base: u16) {
+    unsafe {
+        outw(base + TCO_RLD, 1);
+        outw(base + TCO1_STS, TCO1_STS_TIMEOUT);
+    }
+}
+
+// ---- Common ----
+
+#[derive(Clone, Copy, Debug)]
+struct State {
+    probed: bool,
+    backend: Backend,
+    wdat: Option<Wdat>,
+    tco: u16,
+    /// 0 while disarmed
+    timeout_s: u32,
+    last_pet_ms: u64,
+    pets: u64,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State { probed: false, backend: Backend::Uefi, wdat: None, tco: 0, timeout_s: 0, last_pet_ms: 0, pets: 0 });
+
+/// Status for reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub backend: Backend,
+    /// TCO I/O base, or the WDAT table address
+    pub base: u64,
+    pub timeout_s: u32,
+    pub pets: u64,
+}
+
+/// Pick the backend once; later calls return the cached choice.
+pub fn probe(system_table: &SystemTable<Boot>) -> Backend {
+    if let Some(b) = STATE.lock(|s| if s.probed { Some(s.backend) } else { None }) { return b; }
+    let wdat = wdat_find(system_table);
+    let tco = if wdat.is_none() { tco_find().unwrap_or(0) } else { 0 };
+    let backend = if wdat.is_some() { Backend::Wdat } else if tco != 0 { Backend::Tco } else { Backend::Uefi };
+    STATE.lock(|s| { s.probed = true; s.backend = backend; s.wdat = wdat; s.tco = tco; });
+    backend
+}
+
+/// Arm the watchdog with a timeout in seconds. Returns true on success or
+/// false if not supported or failed.
 pub fn arm(system_table: &SystemTable<Boot>, timeout_secs: usize) -> bool {
-    // UEFI Spec defines SetWatchdogTimer via RuntimeServices in some firmwares and
-    // via BootServices in others depending on crate version exposure. The `uefi`
-    // crate 0.28 exposes it on BootServices.
-    let bs = system_table.boot_services();
-    match bs.set_watchdog_timer(timeout_secs, 0x0000, None) {
-        Ok(_) => true,
-        Err(_) => false,
+    if timeout_secs == 0 { return disarm(system_table); }
+    let backend = probe(system_table);
+    let secs = timeout_secs.min(u32::MAX as usize) as u32;
+    let ok = match backend {
+        Backend::Wdat => STATE.lock(|s| {
+            let Some(w) = s.wdat else { return false };
+            let count = (secs as u64 * 1000 / w.period_ms as u64).clamp(w.min_count as u64, w.max_count.max(1) as u64) as u32;
+            let _ = wdat_run(&w, ACT_SET_REBOOT, 0);
+            wdat_run(&w, ACT_SET_COUNTDOWN, count).is_some() && wdat_run(&w, ACT_SET_RUNNING, 0).is_some() && wdat_run(&w, ACT_RESET, 0).is_some()
+        }),
+        Backend::Tco => { tco_arm(STATE.lock(|s| s.tco), secs); true }
+        // UEFI Spec defines SetWatchdogTimer via RuntimeServices in some firmwares and
+        // via BootServices in others depending on crate version exposure. The `uefi`
+        // crate 0.28 exposes it on BootServices.
+        Backend::Uefi => system_table.boot_services().set_watchdog_timer(timeout_secs, 0x0000, None).is_ok(),
+    };
+    if ok {
+        STATE.lock(|s| { s.timeout_s = secs; s.last_pet_ms = crate::time::clock::now_ms(); });
+        crate::diag::audit::record(crate::diag::audit::AuditKind::HostWatchdog { backend: backend.code(), timeout_s: secs });
     }
+    ok
 }
 
-/// Disable firmware watchdog if possible.
+/// Disable the watchdog if possible.
 pub fn disarm(system_table: &SystemTable<Boot>) -> bool {
-    let bs = system_table.boot_services();
-    match bs.set_watchdog_timer(0, 0x0000, None) {
-        Ok(_) => true,
-        Err(_) => false,
+    let backend = probe(system_table);
+    let ok = match backend {
+        Backend::Wdat => STATE.lock(|s| s.wdat.and_then(|w| wdat_run(&w, ACT_SET_STOPPED, 0)).is_some()),
+        Backend::Tco => { tco_disarm(STATE.lock(|s| s.tco)); true }
+        Backend::Uefi => system_table.boot_services().set_watchdog_timer(0, 0x0000, None).is_ok(),
+    };
+    if ok {
+        let was = STATE.lock(|s| core::mem::replace(&mut s.timeout_s, 0));
+        if was != 0 { crate::diag::audit::record(crate::diag::audit::AuditKind::HostWatchdog { backend: backend.code(), timeout_s: 0 }); }
     }
+    ok
 }
 
-/// Print watchdog status line (best-effort; many firmwares do not expose getters).
-pub fn report(system_table: &mut SystemTable<Boot>) {
-    let stdout = system_table.stdout();
-    let _ = stdout.write_str("watchdog: armed (best-effort)\r\n");
+/// Reload an armed watchdog, at most every `PET_INTERVAL_MS`.
+/// Backend state if a reload is due now.
+fn due() -> Option<(Backend, Option<Wdat>, u16, u32)> {
+    let running = crate::time::clock::hz() != 0;
+    let now = crate::time::clock::now_ms();
+    STATE.lock(|s| {
+        if s.timeout_s == 0 { return None; }
+        if running && now.saturating_sub(s.last_pet_ms) < PET_INTERVAL_MS { return None; }
+        s.last_pet_ms = now;
+        s.pets += 1;
+        Some((s.backend, s.wdat, s.tco, s.timeout_s))
+    })
+}
+
+pub fn pet(system_table: &SystemTable<Boot>) {
+    match due() {
+        Some((Backend::Wdat, Some(w), _, _)) => { let _ = wdat_run(&w, ACT_RESET, 0); }
+        Some((Backend::Tco, _, base, _)) => tco_pet(base),
+        Some((Backend::Uefi, _, _, secs)) => { let _ = system_table.boot_services().set_watchdog_timer(secs as usize, 0x0000, None); }
+        _ => {}
+    }
 }
 
+/// `pet` without firmware, for the runtime phase: the UEFI watchdog stops
+/// at ExitBootServices, so only WDAT and TCO are reloaded.
+pub fn pet_hw() {
+    match due() {
+        Some((Backend::Wdat, Some(w), _, _)) => { let _ = wdat_run(&w, ACT_RESET, 0); }
+        Some((Backend::Tco, _, base, _)) => tco_pet(base),
+        _ => {}
+    }
+}
 
+/// Call `pet_hw` from the timer wheel at twice the pet rate, so a reload is
+/// never more than half an interval late; `due` still drops the extra calls.
+pub fn start_timer() -> Result<crate::time::timer::TimerId, &'static str> {
+    crate::time::timer::add("watchdog", PET_INTERVAL_MS / 2, PET_INTERVAL_MS / 2, |_| pet_hw(), 0)
+}
+
+pub fn status(system_table: &SystemTable<Boot>) -> Status {
+    let backend = probe(system_table);
+    STATE.lock(|s| Status {
+        backend,
+        base: match backend { Backend::Wdat => s.wdat.map(|w| w.table as u64).unwrap_or(0), Backend::Tco => s.tco as u64, Backend::Uefi => 0 },
+        timeout_s: s.timeout_s,
+        pets: s.pets,
+    })
+}
+
+/// Print watchdog status line.
+pub fn report(system_table: &mut SystemTable<Boot>) {
+    let st = status(system_table);
+    let mut out = [0u8; 128]; let mut n = 0;
+    for &b in b"watchdog: backend=" { out[n] = b; n += 1; }
+    for &b in st.backend.name().as_bytes() { out[n] = b; n += 1; }
+    if st.base != 0 {
+        for &b in b" base=0x" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(st.base, &mut out[n..]);
+    }
+    if st.timeout_s == 0 {
+        for &b in b" off" { out[n] = b; n += 1; }
+    } else {
+        for &b in b" armed timeout=" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(st.timeout_s as u64, &mut out[n..]);
+        for &b in b"s pets=" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(st.pets, &mut out[n..]);
+    }
+    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+}
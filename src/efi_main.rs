@@ -1,13 +1,18 @@
 #![no_std]
 #![no_main]
 
+// util::rcu boxes its values; the allocator itself lives in the library
+extern crate alloc;
+
 // Entry point and UEFI types
 use uefi::prelude::*;
 
 mod arch;
-mod i18n;
+// Shared with the library so the banner and the CLI use the same catalogs
+use zerovisor::i18n;
 mod firmware;
-mod time;
+// One clock for the whole image: the library's, which the runtime reads too
+use zerovisor::time;
 mod mm;
 mod util;
 mod obs;
@@ -22,6 +27,56 @@ use core::fmt::Write as _;
 /// `uefi` crate and serves as the dynamic library entry used by UEFI firmware.
 #[entry]
 fn efi_main(_image: Handle, mut system_table: SystemTable<Boot>) -> Status {
+    // Heap first, so everything after it can use `alloc`
+    if let Err(e) = zerovisor::mm::heap::install(&system_table) {
+        let stdout = system_table.stdout();
+        let _ = stdout.write_str(e);
+        let _ = stdout.write_str("\r\n");
+    }
+
+    // Mirror console output to serial if a saved setting asks for it
+    {
+        zerovisor::obs::console::install(&mut system_table);
+    }
+
+    // Hand over to the active A/B slot image if this one is only the loader
+    {
+        if let Some(status) = zerovisor::hv::bootslot::boot(&system_table) { return status; }
+    }
+
+    // Settings from \zerovisor.conf, carried on the BootInfo command line
+    {
+        zerovisor::hv::bootcfg::load_and_report(&mut system_table);
+    }
+
+    // Add a locale from the ESP before anything is translated
+    {
+        match i18n::load_catalog(&system_table, i18n::CATALOG_PATH) {
+            Ok(_) | Err("i18n: catalog not found") => {}
+            Err(e) => {
+                let stdout = system_table.stdout();
+                let _ = stdout.write_str(e);
+                let _ = stdout.write_str("\r\n");
+            }
+        }
+    }
+
+    // Register CPU and build capabilities before the setup menu, so the
+    // recovery CLI sees them too; the device probes below add theirs
+    {
+        zerovisor::hv::features::init();
+    }
+
+    // Setup menu; recovery goes straight to the CLI with nothing else brought up
+    {
+        if zerovisor::hv::setup::run(&mut system_table) == zerovisor::hv::setup::Outcome::Recovery {
+            let _ = system_table.stdout().write_str("setup: recovery CLI; device probes, AP bring-up and IOMMU enforcement skipped\r\n");
+            zerovisor::diag::panic::install_system_table(&system_table);
+            zerovisor::ctl::cli::run_cli(&mut system_table);
+            return Status::SUCCESS;
+        }
+    }
+
     // Print a minimal initialization banner to the UEFI console using i18n.
     {
         // Record boot start in audit log for forensics.
@@ -41,7 +96,7 @@ fn efi_main(_image: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
         let stdout = system_table.stdout();
         // Install emergency stdout pointer for panic-time printing (best-effort).
-        unsafe { crate::diag::panic::install_stdout_ptr(core::ptr::from_mut(stdout)); }
+        unsafe { zerovisor::diag::panic::install_stdout_ptr(core::ptr::from_mut(stdout)); }
         let _ = stdout.reset(false);
         let _ = stdout.write_str(i18n::t(lang, i18n::key::BANNER));
         let _ = stdout.write_str(i18n::t(lang, i18n::key::ENV));
@@ -62,6 +117,12 @@ fn efi_main(_image: Handle, mut system_table: SystemTable<Boot>) -> Status {
         }
     }
 
+    // Let a panic save its crash record, and show the one an earlier boot left
+    {
+        zerovisor::diag::panic::install_system_table(&system_table);
+        zerovisor::diag::panic::report_last_crash(&mut system_table);
+    }
+
     // ACPI discovery: Check presence of RSDP and core tables
     {
         use crate::firmware::acpi;
@@ -103,17 +164,19 @@ fn efi_main(_image: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
         // Detect invariant TSC and calibrate; cache the result
         let inv = crate::arch::x86::cpuid::has_invariant_tsc();
-        let hz = crate::time::init_time(&system_table);
+        let clock = time::clock::init(&system_table);
+        let hz = time::tsc_hz();
         let lang = crate::i18n::detect_lang(&system_table);
         let stdout = system_table.stdout();
         let mut buf = [0u8; 64];
         let mut n = 0;
         for &b in b"TSC frequency (approx): " { buf[n] = b; n += 1; }
-        n += firmware::acpi::u32_to_dec((hz / 1_000_000) as u32, &mut buf[n..]);
+        n += util::format::u32_dec((hz / 1_000_000) as u32, &mut buf[n..]);
         for &b in b" MHz\r\n" { buf[n] = b; n += 1; }
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
         // Log invariant TSC flag
         let _ = stdout.write_str(if inv { "TSC: invariant\r\n" } else { "TSC: not invariant\r\n" });
+        let _ = write!(stdout, "Clock: {} at {} Hz\r\n", clock.name(), time::clock::hz());
 
         let _ = stdout.write_str(i18n::t(lang, i18n::key::READY));
         // Record boot ready
@@ -176,11 +239,21 @@ fn efi_main(_image: Handle, mut system_table: SystemTable<Boot>) -> Status {
         zerovisor::iommu::amdv::probe_and_report(&mut system_table);
     }
 
+    // DMA and interrupt remapping, if turned on in the setup menu
+    {
+        zerovisor::hv::setup::enforce(&mut system_table);
+    }
+
     // Security posture (W^X hints, SMEP/SMAP, NXE) best-effort report
     {
         zerovisor::diag::security::report_security(&mut system_table);
     }
 
+    // Measure the hypervisor image and settings into the TPM (PCR 8/9) with an event log
+    {
+        zerovisor::tpm::attest::measure_boot_and_report(&mut system_table);
+    }
+
     // Minimal AP bring-up: prepare a real-mode trampoline and count AP wakeups.
     {
         let lang = i18n::detect_lang(&system_table);
@@ -212,47 +285,28 @@ fn efi_main(_image: Handle, mut system_table: SystemTable<Boot>) -> Status {
                 }
                 // Report mailbox count (write in a short scope to avoid borrow conflicts)
                 {
-                    let mut buf = [0u8; 64];
-                    let mut n = 0;
-                    for &b in crate::i18n::t(lang, crate::i18n::key::SMP_OBSERVED).as_bytes() { buf[n] = b; n += 1; }
-                    let cnt = crate::arch::x86::trampoline::read_mailbox_count(info) as u32;
-                    n += crate::firmware::acpi::u32_to_dec(cnt, &mut buf[n..]);
-                    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
-                    let stdout = system_table.stdout();
-                    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+                    let cnt = crate::arch::x86::trampoline::read_mailbox_count(info) as u64;
+                    let msg = i18n::t_fmt(lang, i18n::key::SMP_OBSERVED, &[i18n::Arg::Dec(cnt)]);
+                    let _ = system_table.stdout().write_str(msg.as_str());
                 }
                 if let Some(madt_hdr2) = crate::firmware::acpi::find_madt(&system_table) {
                     let expected = crate::firmware::acpi::madt_count_logical_cpus_from(madt_hdr2);
                     {
-                        let mut b2 = [0u8; 64];
-                        let mut m2 = 0;
-                        for &b in crate::i18n::t(lang, crate::i18n::key::SMP_EXPECTED).as_bytes() { b2[m2] = b; m2 += 1; }
-                        m2 += crate::firmware::acpi::u32_to_dec(expected, &mut b2[m2..]);
-                        b2[m2] = b'\r'; m2 += 1; b2[m2] = b'\n'; m2 += 1;
-                        let stdout = system_table.stdout();
-                        let _ = stdout.write_str(core::str::from_utf8(&b2[..m2]).unwrap_or("\r\n"));
+                        let msg = i18n::t_fmt(lang, i18n::key::SMP_EXPECTED, &[i18n::Arg::Dec(expected as u64)]);
+                        let _ = system_table.stdout().write_str(msg.as_str());
                     }
                     // Wait for AP IDs to be recorded up to expected-1 (excluding BSP), with timeout
                     let observed = crate::arch::x86::smp::wait_for_ap_ids(&system_table, info, expected.saturating_sub(1), 200_000);
                     {
-                        let mut b3 = [0u8; 64];
-                        let mut m3 = 0;
-                        for &b in crate::i18n::t(lang, crate::i18n::key::SMP_OBSERVED).as_bytes() { b3[m3] = b; m3 += 1; }
-                        m3 += crate::firmware::acpi::u32_to_dec(observed, &mut b3[m3..]);
-                        b3[m3] = b'\r'; m3 += 1; b3[m3] = b'\n'; m3 += 1;
-                        let stdout = system_table.stdout();
-                        let _ = stdout.write_str(core::str::from_utf8(&b3[..m3]).unwrap_or("\r\n"));
+                        let msg = i18n::t_fmt(lang, i18n::key::SMP_OBSERVED, &[i18n::Arg::Dec(observed as u64)]);
+                        let _ = system_table.stdout().write_str(msg.as_str());
                     }
-                    // Signal GO to APs and wait for READY count
+                    // Point the trampoline at the AP runtime, then signal GO and wait for READY count
+                    zerovisor::arch::x86::ap::install(info.phys_base + info.mailbox_offset as u64);
                     let ready = crate::arch::x86::smp::signal_and_wait_ready(&system_table, info, observed, 200_000);
                     {
-                        let mut b4 = [0u8; 64];
-                        let mut m4 = 0;
-                        for &b in crate::i18n::t(lang, crate::i18n::key::SMP_READY).as_bytes() { b4[m4] = b; m4 += 1; }
-                        m4 += crate::firmware::acpi::u32_to_dec(ready, &mut b4[m4..]);
-                        b4[m4] = b'\r'; m4 += 1; b4[m4] = b'\n'; m4 += 1;
-                        let stdout = system_table.stdout();
-                        let _ = stdout.write_str(core::str::from_utf8(&b4[..m4]).unwrap_or("\r\n"));
+                        let msg = i18n::t_fmt(lang, i18n::key::SMP_READY, &[i18n::Arg::Dec(ready as u64)]);
+                        let _ = system_table.stdout().write_str(msg.as_str());
                     }
                 }
 
@@ -269,37 +323,26 @@ fn efi_main(_image: Handle, mut system_table: SystemTable<Boot>) -> Status {
                     let base = info.phys_base as usize + info.mailbox_offset as usize;
                     let cnt16 = unsafe { core::ptr::read_volatile((base + 6) as *const u16) } as u32;
                     {
-                        let mut buf2 = [0u8; 64];
-                        let mut m = 0;
-                        for &b in crate::i18n::t(lang, crate::i18n::key::SMP_LM_COUNT).as_bytes() { buf2[m] = b; m += 1; }
-                        m += crate::firmware::acpi::u32_to_dec(cnt16, &mut buf2[m..]);
-                        buf2[m] = b'\r'; m += 1; buf2[m] = b'\n'; m += 1;
-                        let stdout = system_table.stdout();
-                        let _ = stdout.write_str(core::str::from_utf8(&buf2[..m]).unwrap_or("\r\n"));
+                        let msg = i18n::t_fmt(lang, i18n::key::SMP_LM_COUNT, &[i18n::Arg::Dec(cnt16 as u64)]);
+                        let _ = system_table.stdout().write_str(msg.as_str());
                     }
                     let apic_byte = unsafe { core::ptr::read_volatile((base + 8) as *const u8) } as u32;
                     {
-                        let mut buf3 = [0u8; 64];
-                        let mut m3 = 0;
-                        for &b in crate::i18n::t(lang, crate::i18n::key::SMP_APIC_BYTE).as_bytes() { buf3[m3] = b; m3 += 1; }
-                        m3 += crate::firmware::acpi::u32_to_dec(apic_byte, &mut buf3[m3..]);
-                        buf3[m3] = b'\r'; m3 += 1; buf3[m3] = b'\n'; m3 += 1;
-                        let stdout = system_table.stdout();
-                        let _ = stdout.write_str(core::str::from_utf8(&buf3[..m3]).unwrap_or("\r\n"));
+                        let msg = i18n::t_fmt(lang, i18n::key::SMP_APIC_BYTE, &[i18n::Arg::Dec(apic_byte as u64)]);
+                        let _ = system_table.stdout().write_str(msg.as_str());
                     }
                     // Dump APIC ID list written by APs at mailbox+32 .. (byte array)
                     {
-                        let mut listbuf = [0u8; 128];
+                        let mut listbuf = [0u8; 96];
                         let mut l = 0;
-                        for &b in crate::i18n::t(lang, crate::i18n::key::SMP_AP_IDS).as_bytes() { listbuf[l] = b; l += 1; }
                         for i in 0..16usize {
                             let idb = unsafe { core::ptr::read_volatile((base + 32 + i) as *const u8) } as u32;
                             if i > 0 { listbuf[l] = b','; l += 1; listbuf[l] = b' '; l += 1; }
-                            l += crate::firmware::acpi::u32_to_dec(idb, &mut listbuf[l..]);
+                            l += crate::util::format::u32_dec(idb, &mut listbuf[l..]);
                         }
-                        listbuf[l] = b'\r'; l += 1; listbuf[l] = b'\n'; l += 1;
-                        let stdout = system_table.stdout();
-                        let _ = stdout.write_str(core::str::from_utf8(&listbuf[..l]).unwrap_or("\r\n"));
+                        let list = core::str::from_utf8(&listbuf[..l]).unwrap_or("");
+                        let msg = i18n::t_fmt(lang, i18n::key::SMP_AP_IDS, &[i18n::Arg::Str(list)]);
+                        let _ = system_table.stdout().write_str(msg.as_str());
                     }
                 }
             }
@@ -312,6 +355,28 @@ fn efi_main(_image: Handle, mut system_table: SystemTable<Boot>) -> Status {
         crate::arch::x86::idt::sti();
     }
 
+    // Publish the shared metrics page for agents outside the hypervisor
+    {
+        if zerovisor::obs::page::init(&system_table).is_err() {
+            let _ = system_table.stdout().write_str("metrics page: unavailable\r\n");
+        }
+    }
+
+    // Start the periodic tick and the timers that hang off it
+    {
+        let src = zerovisor::time::timer::init(&system_table);
+        let _ = write!(system_table.stdout(), "timer: {} tick at {} Hz\r\n", src.name(), zerovisor::time::timer::TICK_HZ);
+        // The wheel is empty this early, so neither can run out of timers
+        let _ = zerovisor::diag::watchdog::start_timer();
+        let _ = zerovisor::hv::sched::credit::start_timer();
+        let _ = zerovisor::time::clock::start_timer();
+    }
+
+    // Reaching the CLI counts as a good boot of the running A/B slot
+    {
+        let _ = zerovisor::hv::bootslot::confirm(&system_table);
+    }
+
     // Minimal CLI loop on UEFI console
     {
         zerovisor::ctl::cli::run_cli(&mut system_table);
@@ -322,14 +387,13 @@ fn efi_main(_image: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
 /// Panic handler for `no_std` environment.
 ///
-/// We keep this extremely conservative: in case of a panic before the text
-/// console is fully usable, just halt in a loop to avoid returning control with
-/// an undefined state. Environments with a working console will still show the
-/// last printed banner above.
+/// We keep this extremely conservative: report what we can (a summary on the
+/// console and a crash record in a UEFI variable, see `diag::panic`), then
+/// halt in a loop to avoid returning control with an undefined state.
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     // Best-effort console print without allocations
-    crate::diag::panic::report_panic(info);
+    zerovisor::diag::panic::report_panic(info);
     loop { unsafe { core::arch::asm!("cli; hlt", options(nomem, nostack, preserves_flags)); } }
 }
 
@@ -14,6 +14,11 @@ use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
 use uefi::table::SystemTable;
 use uefi::prelude::Boot;
 
+use crate::util::format::{u32_dec, u64_hex};
+
+pub mod numa;
+pub mod walk;
+
 /// Root System Description Pointer (RSDP) for ACPI 2.0+
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
@@ -92,6 +97,12 @@ fn slice_from_phys<T>(phys: u64, _len: usize) -> Option<&'static T> {
 
 /// Locate RSDP via UEFI Configuration Table. Prefers ACPI 2.0+ GUID.
 pub(crate) fn find_rsdp(system_table: &SystemTable<Boot>) -> Option<Rsdp20> {
+    find_rsdp_addr(system_table).and_then(|phys| slice_from_phys::<Rsdp20>(phys, size_of::<Rsdp20>())).copied()
+}
+
+/// Physical address of the RSDP that `find_rsdp` would return, for handing
+/// on to code that maps the tables itself.
+pub(crate) fn find_rsdp_addr(system_table: &SystemTable<Boot>) -> Option<u64> {
     for entry in system_table.config_table() {
         if entry.guid == ACPI2_GUID || entry.guid == ACPI_GUID {
             let phys = entry.address as u64;
@@ -102,7 +113,7 @@ pub(crate) fn find_rsdp(system_table: &SystemTable<Boot>) -> Option<Rsdp20> {
                 let len = if rsdp.revision >= 2 { rsdp.length as usize } else { 20 };
                 let ptr = rsdp as *const _ as *const u8;
                 let data = unsafe { core::slice::from_raw_parts(ptr, len) };
-                if calc_checksum(data) == 0 { return Some(*rsdp); }
+                if calc_checksum(data) == 0 { return Some(phys); }
             }
         }
     }
@@ -180,20 +191,19 @@ pub(crate) fn find_ivrs(system_table: &SystemTable<Boot>) -> Option<&'static Sdt
     find_table(system_table, SIG_IVRS)
 }
 
-/// Minimal MADT header for iterating APIC structures.
-#[repr(C, packed)]
-pub(crate) struct MadtHeader {
-    header: SdtHeader,
-    lapic_addr: u32,
-    flags: u32,
-    // followed by variable-length APIC structures
+/// ACPI PM timer port and width (true for 32 bits) from the FADT.
+pub(crate) fn fadt_pm_timer(hdr: &'static SdtHeader) -> Option<(u16, bool)> {
+    walk::fadt_pm_timer(sdt_bytes(hdr))
+}
+
+/// Whole table behind `hdr`, as long as its length field says.
+pub(crate) fn sdt_bytes(hdr: &'static SdtHeader) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(hdr as *const SdtHeader as *const u8, hdr.length as usize) }
 }
 
 /// Read Local APIC base address from MADT header.
 pub(crate) fn madt_lapic_base(hdr: &'static SdtHeader) -> u32 {
-    let madt = hdr as *const SdtHeader as *const MadtHeader;
-    let madt = unsafe { &*madt };
-    madt.lapic_addr
+    walk::madt_lapic_base(sdt_bytes(hdr)).unwrap_or(0)
 }
 
 /// Enumerate CPU APIC IDs from MADT and print to the provided writer function.
@@ -201,147 +211,38 @@ pub(crate) fn madt_list_cpus_from<F>(hdr: &'static SdtHeader, mut writer: F)
 where
     F: FnMut(&str),
 {
-    let madt = hdr as *const SdtHeader as *const MadtHeader;
-    let madt = unsafe { &*madt };
-    let base = madt as *const MadtHeader as usize;
-    let total_len = madt.header.length as usize;
-    let mut off = core::mem::size_of::<MadtHeader>();
     let mut count: u32 = 0;
-    while off + 2 <= total_len {
-        let p = (base + off) as *const u8;
-        let etype = unsafe { p.read() };
-        let elen = unsafe { p.add(1).read() } as usize;
-        if elen < 2 || off + elen > total_len { break; }
-        match etype {
-            0 => {
-                // Processor Local APIC
-                if elen >= 8 {
-                    let apic_id = unsafe { p.add(3).read() } as u32;
-                    let _flags = u32::from_le_bytes([
-                        unsafe { p.add(4).read() },
-                        unsafe { p.add(5).read() },
-                        unsafe { p.add(6).read() },
-                        unsafe { p.add(7).read() },
-                    ]);
-                    count += 1;
-                    // Small fixed buffer formatting without allocation
-                    let mut buf = [0u8; 64];
-                    let mut n = 0;
-                    for &b in b"CPU: Local APIC ID=" { buf[n] = b; n += 1; }
-                    // decimal formatting
-                    n += u32_to_dec(apic_id, &mut buf[n..]);
-                    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
-                    writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
-                }
-            }
-            9 => {
-                // Processor Local x2APIC
-                if elen >= 16 {
-                    let apic_id = u32::from_le_bytes([
-                        unsafe { p.add(4).read() },
-                        unsafe { p.add(5).read() },
-                        unsafe { p.add(6).read() },
-                        unsafe { p.add(7).read() },
-                    ]);
-                    count += 1;
-                    let mut buf = [0u8; 64];
-                    let mut n = 0;
-                    for &b in b"CPU: x2APIC ID=" { buf[n] = b; n += 1; }
-                    n += u32_to_dec(apic_id, &mut buf[n..]);
-                    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
-                    writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
-                }
-            }
-            _ => {}
-        }
-        off += elen;
-    }
+    walk::for_each_entry(sdt_bytes(hdr), walk::Layout::Madt, |typ, e| {
+        let Some(apic_id) = walk::madt_processor_id(typ, e) else { return };
+        count += 1;
+        // Small fixed buffer formatting without allocation
+        let mut buf = [0u8; 64];
+        let mut n = 0;
+        let label: &[u8] = if typ == 0 { b"CPU: Local APIC ID=" } else { b"CPU: x2APIC ID=" };
+        for &b in label { buf[n] = b; n += 1; }
+        n += u32_dec(apic_id, &mut buf[n..]);
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    });
     // Print total count
     let mut buf = [0u8; 64];
     let mut n = 0;
     for &b in b"ACPI: CPU count=" { buf[n] = b; n += 1; }
-    n += u32_to_dec(count, &mut buf[n..]);
+    n += u32_dec(count, &mut buf[n..]);
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
     writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
 }
 
 /// Count logical CPUs from MADT (Local APIC and x2APIC entries).
 pub(crate) fn madt_count_logical_cpus_from(hdr: &'static SdtHeader) -> u32 {
-    let madt = hdr as *const SdtHeader as *const MadtHeader;
-    let madt = unsafe { &*madt };
-    let base = madt as *const MadtHeader as usize;
-    let total_len = madt.header.length as usize;
-    let mut off = core::mem::size_of::<MadtHeader>();
     let mut count: u32 = 0;
-    while off + 2 <= total_len {
-        let p = (base + off) as *const u8;
-        let etype = unsafe { p.read() };
-        let elen = unsafe { p.add(1).read() } as usize;
-        if elen < 2 || off + elen > total_len { break; }
-        match etype {
-            0 => {
-                // Processor Local APIC
-                if elen >= 8 { count = count.saturating_add(1); }
-            }
-            9 => {
-                // Processor Local x2APIC
-                if elen >= 16 { count = count.saturating_add(1); }
-            }
-            _ => {}
-        }
-        off += elen;
-    }
+    walk::madt_for_each_processor_id(sdt_bytes(hdr), |_| count = count.saturating_add(1));
     count
 }
 
 /// Iterate processor APIC IDs (both Local APIC and x2APIC) from MADT and call the callback.
-pub(crate) fn madt_for_each_processor_id(mut f: impl FnMut(u32), hdr: &'static SdtHeader) {
-    let madt = hdr as *const SdtHeader as *const MadtHeader;
-    let madt = unsafe { &*madt };
-    let base = madt as *const MadtHeader as usize;
-    let total_len = madt.header.length as usize;
-    let mut off = core::mem::size_of::<MadtHeader>();
-    while off + 2 <= total_len {
-        let p = (base + off) as *const u8;
-        let etype = unsafe { p.read() };
-        let elen = unsafe { p.add(1).read() } as usize;
-        if elen < 2 || off + elen > total_len { break; }
-        match etype {
-            0 => {
-                if elen >= 8 {
-                    let apic_id = unsafe { p.add(3).read() } as u32;
-                    f(apic_id);
-                }
-            }
-            9 => {
-                if elen >= 16 {
-                    let apic_id = u32::from_le_bytes([
-                        unsafe { p.add(4).read() },
-                        unsafe { p.add(5).read() },
-                        unsafe { p.add(6).read() },
-                        unsafe { p.add(7).read() },
-                    ]);
-                    f(apic_id);
-                }
-            }
-            _ => {}
-        }
-        off += elen;
-    }
-}
-
-pub(crate) fn u32_to_dec(mut v: u32, out: &mut [u8]) -> usize {
-    // write decimal to buffer; returns number of bytes written
-    if v == 0 {
-        if !out.is_empty() { out[0] = b'0'; return 1; }
-        return 0;
-    }
-    let mut tmp = [0u8; 10];
-    let mut i = 0;
-    while v > 0 && i < tmp.len() { tmp[i] = b'0' + (v % 10) as u8; v /= 10; i += 1; }
-    let mut n = 0;
-    while i > 0 && n < out.len() { i -= 1; out[n] = tmp[i]; n += 1; }
-    n
+pub(crate) fn madt_for_each_processor_id(f: impl FnMut(u32), hdr: &'static SdtHeader) {
+    walk::madt_for_each_processor_id(sdt_bytes(hdr), f);
 }
 
 /// Minimal MCFG structures
@@ -378,13 +279,13 @@ where
         let mut buf = [0u8; 96];
         let mut n = 0;
         for &b in b"PCIe ECAM: seg=" { buf[n] = b; n += 1; }
-        n += u32_to_dec(a.pci_segment as u32, &mut buf[n..]);
+        n += u32_dec(a.pci_segment as u32, &mut buf[n..]);
         for &b in b" bus=" { buf[n] = b; n += 1; }
-        n += u32_to_dec(a.start_bus as u32, &mut buf[n..]);
+        n += u32_dec(a.start_bus as u32, &mut buf[n..]);
         buf[n] = b'-'; n += 1;
-        n += u32_to_dec(a.end_bus as u32, &mut buf[n..]);
+        n += u32_dec(a.end_bus as u32, &mut buf[n..]);
         for &b in b" base=0x" { buf[n] = b; n += 1; }
-        n += u64_to_hex(a.base_address, &mut buf[n..]);
+        n += u64_hex(a.base_address, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
         off += core::mem::size_of::<McfgAllocation>();
@@ -406,17 +307,6 @@ pub(crate) fn mcfg_for_each_allocation_from(mut f: impl FnMut(&McfgAllocation),
     }
 }
 
-fn u64_to_hex(v: u64, out: &mut [u8]) -> usize {
-    const HEX: &[u8; 16] = b"0123456789ABCDEF";
-    let mut started = false;
-    let mut n = 0;
-    for i in (0..16).rev() {
-        let nyb = ((v >> (i * 4)) & 0xF) as usize;
-        if nyb != 0 || started || i == 0 { started = true; if n < out.len() { out[n] = HEX[nyb]; n += 1; } }
-    }
-    n
-}
-
 // --- DMAR/IVRS minimal summaries (header-only, safe) ---
 
 fn write_ascii_trim(src: &[u8], out: &mut [u8]) -> usize {
@@ -437,9 +327,9 @@ fn sdt_header_summary(label: &[u8; 4], hdr: &'static SdtHeader, out_line: &mut [
     for &b in label.iter() { if n < out_line.len() { out_line[n] = b; n += 1; } }
     if n + 2 <= out_line.len() { out_line[n] = b':'; n += 1; out_line[n] = b' '; n += 1; }
     for &b in b"len=" { if n < out_line.len() { out_line[n] = b; n += 1; } }
-    n += u32_to_dec(hdr.length, &mut out_line[n..]);
+    n += u32_dec(hdr.length, &mut out_line[n..]);
     for &b in b" rev=" { if n < out_line.len() { out_line[n] = b; n += 1; } }
-    n += u32_to_dec(hdr.revision as u32, &mut out_line[n..]);
+    n += u32_dec(hdr.revision as u32, &mut out_line[n..]);
     for &b in b" oem=" { if n < out_line.len() { out_line[n] = b; n += 1; } }
     n += write_ascii_trim(&hdr.oem_id, &mut out_line[n..]);
     for &b in b" table=" { if n < out_line.len() { out_line[n] = b; n += 1; } }
@@ -464,250 +354,79 @@ pub(crate) fn ivrs_summary(mut writer: impl FnMut(&str), hdr: &'static SdtHeader
 
 /// Enumerate DMAR remapping structures (DRHD/RMRR/ATSR) with minimal fields.
 pub(crate) fn dmar_list_structs_from(mut writer: impl FnMut(&str), hdr: &'static SdtHeader) {
-    #[repr(C, packed)]
-    struct DmarTableHeader { header: SdtHeader, host_addr_width: u8, flags: u8, _rsvd: [u8; 10] }
-    let base = hdr as *const SdtHeader as usize;
-    let total_len = hdr.length as usize;
-    let off0 = core::mem::size_of::<DmarTableHeader>();
-    let mut off = off0;
-    while off + 4 <= total_len {
-        let p = (base + off) as *const u8;
-        let t0 = unsafe { p.read() } as u16;
-        let t1 = unsafe { p.add(1).read() } as u16;
-        let typ = t0 | ((t1 as u16) << 8);
-        let l0 = unsafe { p.add(2).read() } as u16;
-        let l1 = unsafe { p.add(3).read() } as u16;
-        let len = (l0 | ((l1 as u16) << 8)) as usize;
-        if len < 4 || off + len > total_len { break; }
+    walk::for_each_entry(sdt_bytes(hdr), walk::Layout::Dmar, |typ, e| {
         // Format a short line: type/len and key fields if known
         let mut buf = [0u8; 128];
         let mut n = 0;
         for &b in b"DMAR: struct type=" { buf[n] = b; n += 1; }
-        n += u32_to_dec(typ as u32, &mut buf[n..]);
+        n += u32_dec(typ as u32, &mut buf[n..]);
         for &b in b" len=" { buf[n] = b; n += 1; }
-        n += u32_to_dec(len as u32, &mut buf[n..]);
-        // Determine header size to locate optional device scope list
-        let mut header_size_for_scopes: usize = 0;
-        match typ {
-            0 => {
-                // DRHD: flags(1), rsvd(1), seg(2), reg_base(8)
-                if len >= 4 + 12 {
-                    let seg_lo = unsafe { p.add(6).read() } as u16;
-                    let seg_hi = unsafe { p.add(7).read() } as u16;
-                    let seg = (seg_lo | (seg_hi << 8)) as u32;
-                    let mut addr: u64 = 0;
-                    for i in 0..8 {
-                        addr |= (unsafe { p.add(8 + i).read() } as u64) << (i * 8);
-                    }
-                    for &b in b" seg=" { buf[n] = b; n += 1; }
-                    n += u32_to_dec(seg, &mut buf[n..]);
+        n += u32_dec(e.len() as u32, &mut buf[n..]);
+        let d = walk::dmar_struct(typ, e);
+        if let Some(d) = d {
+            for &b in b" seg=" { buf[n] = b; n += 1; }
+            n += u32_dec(d.segment as u32, &mut buf[n..]);
+            match typ {
+                walk::DMAR_DRHD => {
                     for &b in b" reg=0x" { buf[n] = b; n += 1; }
-                    n += u64_to_hex(addr, &mut buf[n..]);
-                    header_size_for_scopes = 4 + 12;
+                    n += u64_hex(d.base, &mut buf[n..]);
                 }
-            }
-            1 => {
-                // RMRR: rsvd(2), seg(2), base(8), limit(8)
-                if len >= 4 + 20 {
-                    let seg_lo = unsafe { p.add(4).read() } as u16;
-                    let seg_hi = unsafe { p.add(5).read() } as u16;
-                    let seg = (seg_lo | (seg_hi << 8)) as u32;
-                    let mut base64: u64 = 0; let mut limit64: u64 = 0;
-                    for i in 0..8 { base64 |= (unsafe { p.add(6 + i).read() } as u64) << (i * 8); }
-                    for i in 0..8 { limit64 |= (unsafe { p.add(14 + i).read() } as u64) << (i * 8); }
-                    for &b in b" seg=" { buf[n] = b; n += 1; }
-                    n += u32_to_dec(seg, &mut buf[n..]);
+                walk::DMAR_RMRR => {
                     for &b in b" range=0x" { buf[n] = b; n += 1; }
-                    n += u64_to_hex(base64, &mut buf[n..]);
+                    n += u64_hex(d.base, &mut buf[n..]);
                     for &b in b"-0x" { buf[n] = b; n += 1; }
-                    n += u64_to_hex(limit64, &mut buf[n..]);
-                    header_size_for_scopes = 4 + 20;
+                    n += u64_hex(d.limit, &mut buf[n..]);
                 }
-            }
-            2 => {
-                // ATSR: flags(1) at +4, reserved(1) at +5, segment(2) at +6..+7
-                if len >= 8 {
-                    let flags = unsafe { p.add(4).read() } as u32;
-                    let seg_lo = unsafe { p.add(6).read() } as u16;
-                    let seg_hi = unsafe { p.add(7).read() } as u16;
-                    let seg = (seg_lo | (seg_hi << 8)) as u32;
-                    for &b in b" seg=" { buf[n] = b; n += 1; }
-                    n += u32_to_dec(seg, &mut buf[n..]);
+                _ => {
                     for &b in b" flags=0x" { buf[n] = b; n += 1; }
-                    n += u64_to_hex(flags as u64, &mut buf[n..]);
-                    header_size_for_scopes = 8;
+                    n += u64_hex(d.flags as u64, &mut buf[n..]);
                 }
             }
-            _ => {}
         }
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
         // If this structure carries a device scope list, enumerate shallow info
-        if header_size_for_scopes > 0 && header_size_for_scopes < len {
-            let mut s_off = off + header_size_for_scopes;
-            let end = off + len;
-            while s_off + 6 <= end {
-                let sp = (base + s_off) as *const u8;
-                let s_type = unsafe { sp.read() } as u32;
-                let s_len = (unsafe { sp.add(1).read() } as u32) | ((unsafe { sp.add(2).read() } as u32) << 8);
-                if s_len < 6 || s_off + (s_len as usize) > end { break; }
-                let bus = unsafe { sp.add(4).read() } as u32; // start bus number
-                let mut lbuf = [0u8; 96];
-                let mut m = 0;
-                for &b in b"DMAR:   scope type=" { lbuf[m] = b; m += 1; }
-                m += u32_to_dec(s_type, &mut lbuf[m..]);
-                for &b in b" len=" { lbuf[m] = b; m += 1; }
-                m += u32_to_dec(s_len, &mut lbuf[m..]);
-                for &b in b" bus=" { lbuf[m] = b; m += 1; }
-                m += u32_to_dec(bus, &mut lbuf[m..]);
-                lbuf[m] = b'\r'; m += 1; lbuf[m] = b'\n'; m += 1;
-                writer(core::str::from_utf8(&lbuf[..m]).unwrap_or("\r\n"));
-                s_off += s_len as usize;
-            }
-        }
-        off += len;
-    }
+        walk::dmar_for_each_scope(e, d.map_or(0, |d| d.scopes), |s_type, s_len, bus, _| {
+            let mut lbuf = [0u8; 96];
+            let mut m = 0;
+            for &b in b"DMAR:   scope type=" { lbuf[m] = b; m += 1; }
+            m += u32_dec(s_type as u32, &mut lbuf[m..]);
+            for &b in b" len=" { lbuf[m] = b; m += 1; }
+            m += u32_dec(s_len as u32, &mut lbuf[m..]);
+            for &b in b" bus=" { lbuf[m] = b; m += 1; }
+            m += u32_dec(bus as u32, &mut lbuf[m..]);
+            lbuf[m] = b'\r'; m += 1; lbuf[m] = b'\n'; m += 1;
+            writer(core::str::from_utf8(&lbuf[..m]).unwrap_or("\r\n"));
+        });
+    });
 }
 
 /// Enumerate IVRS entries (type and length only, safe header walk).
 pub(crate) fn ivrs_list_entries_from(mut writer: impl FnMut(&str), hdr: &'static SdtHeader) {
-    #[repr(C, packed)] struct IvrsTableHeader { header: SdtHeader, iv_info: u32 }
-    let base = hdr as *const SdtHeader as usize;
-    let total = hdr.length as usize;
-    let mut off = core::mem::size_of::<IvrsTableHeader>();
-    while off + 4 <= total {
-        let p = (base + off) as *const u8;
-        let entry_type = unsafe { p.read() } as u32;
-        let len_lo = unsafe { p.add(2).read() } as u16;
-        let len_hi = unsafe { p.add(3).read() } as u16;
-        let len = (len_lo | (len_hi << 8)) as usize;
-        if len < 4 || off + len > total { break; }
+    walk::for_each_entry(sdt_bytes(hdr), walk::Layout::Ivrs, |typ, e| {
         let mut buf = [0u8; 96];
         let mut n = 0;
         for &b in b"IVRS: entry type=" { buf[n] = b; n += 1; }
-        n += u32_to_dec(entry_type, &mut buf[n..]);
+        n += u32_dec(typ as u32, &mut buf[n..]);
         for &b in b" len=" { buf[n] = b; n += 1; }
-        n += u32_to_dec(len as u32, &mut buf[n..]);
+        n += u32_dec(e.len() as u32, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
-        off += len;
-    }
+    });
 }
 
 /// Iterate AMD-Vi IVHD entries and invoke closure with (PCI Segment, Register Base Address).
-/// This is a shallow, header-safe traversal based on IVRS table format commonly documented.
-pub(crate) fn ivrs_for_each_ivhd_from(mut f: impl FnMut(u16, u64), hdr: &'static SdtHeader) {
-    #[repr(C, packed)] struct IvrsHeader { header: SdtHeader, iv_info: u32 }
-    let base = hdr as *const SdtHeader as usize;
-    let total = hdr.length as usize;
-    let mut off = core::mem::size_of::<IvrsHeader>();
-    while off + 4 <= total {
-        let p = (base + off) as *const u8;
-        let typ = unsafe { p.read() } as u32;
-        let len = (unsafe { p.add(2).read() } as u16) | ((unsafe { p.add(3).read() } as u16) << 8);
-        let len = len as usize;
-        if len < 4 || off + len > total { break; }
-        // IVHD type typically 0x10/0x11 etc. We accept any >= 0x10 as IVHD-like for bootstrap.
-        if typ >= 0x10 {
-            // Common IVHD header: flags(2), length(2), device id(2), cap offset(1), iommu base(8) ...
-            // We conservatively read segment at +4/+5 (many dumps place PCI segment early),
-            // and base address at a later fixed offset (approx +8 or +24 depending on variant).
-            let seg = (unsafe { p.add(4).read() } as u16) | ((unsafe { p.add(5).read() } as u16) << 8);
-            // Try two plausible base offsets safely within len: prefer +8..+15 then fallback +24..+31.
-            let mut base_addr: u64 = 0;
-            if len >= 16 {
-                for i in 0..8 { base_addr |= (unsafe { p.add(8 + i).read() } as u64) << (i * 8); }
-            }
-            if base_addr == 0 && len >= 32 {
-                for i in 0..8 { base_addr |= (unsafe { p.add(24 + i).read() } as u64) << (i * 8); }
-            }
-            if base_addr != 0 { f(seg, base_addr); }
-        }
-        off += len;
-    }
+pub(crate) fn ivrs_for_each_ivhd_from(f: impl FnMut(u16, u64), hdr: &'static SdtHeader) {
+    walk::ivrs_for_each_ivhd(sdt_bytes(hdr), f);
 }
 
 /// Iterate Intel VT-d DRHD units and invoke the closure with (PCI Segment, Register Base Address).
-/// This performs only a shallow, header-safe walk without dereferencing the register base.
-pub(crate) fn dmar_for_each_drhd_from(mut f: impl FnMut(u16, u64), hdr: &'static SdtHeader) {
-    #[repr(C, packed)]
-    struct DmarTableHeader { header: SdtHeader, host_addr_width: u8, flags: u8, _rsvd: [u8; 10] }
-    let base = hdr as *const SdtHeader as usize;
-    let total_len = hdr.length as usize;
-    let mut off = core::mem::size_of::<DmarTableHeader>();
-    while off + 4 <= total_len {
-        let p = (base + off) as *const u8;
-        let t0 = unsafe { p.read() } as u16;
-        let t1 = unsafe { p.add(1).read() } as u16;
-        let typ = t0 | ((t1 as u16) << 8);
-        let l0 = unsafe { p.add(2).read() } as u16;
-        let l1 = unsafe { p.add(3).read() } as u16;
-        let len = (l0 | ((l1 as u16) << 8)) as usize;
-        if len < 4 || off + len > total_len { break; }
-        if typ == 0 {
-            // DRHD
-            if len >= 4 + 12 {
-                let seg_lo = unsafe { p.add(6).read() } as u16;
-                let seg_hi = unsafe { p.add(7).read() } as u16;
-                let seg = (seg_lo | (seg_hi << 8)) as u16;
-                let mut addr: u64 = 0;
-                for i in 0..8 { addr |= (unsafe { p.add(8 + i).read() } as u64) << (i * 8); }
-                f(seg, addr);
-            }
-        }
-        off += len;
-    }
+/// This performs only a walk of the table without dereferencing the register base.
+pub(crate) fn dmar_for_each_drhd_from(f: impl FnMut(u16, u64), hdr: &'static SdtHeader) {
+    walk::dmar_for_each_drhd(sdt_bytes(hdr), f);
 }
 
 /// Iterate DRHD device scopes and yield (segment, reg_base, bus, dev, func) for each PCI path entry.
-/// Parses only shallow fields as per DMAR Device Scope format: header (type,len,_,bus) + path entries of (dev,func) pairs.
-pub(crate) fn dmar_for_each_device_scope_from(mut f: impl FnMut(u16, u64, u8, u8, u8), hdr: &'static SdtHeader) {
-    #[repr(C, packed)]
-    struct DmarTableHeader { header: SdtHeader, host_addr_width: u8, flags: u8, _rsvd: [u8; 10] }
-    let base = hdr as *const SdtHeader as usize;
-    let total_len = hdr.length as usize;
-    let mut off = core::mem::size_of::<DmarTableHeader>();
-    while off + 4 <= total_len {
-        let p = (base + off) as *const u8;
-        let t0 = unsafe { p.read() } as u16;
-        let t1 = unsafe { p.add(1).read() } as u16;
-        let typ = t0 | ((t1 as u16) << 8);
-        let l0 = unsafe { p.add(2).read() } as u16;
-        let l1 = unsafe { p.add(3).read() } as u16;
-        let len = (l0 | ((l1 as u16) << 8)) as usize;
-        if len < 4 || off + len > total_len { break; }
-        if typ == 0 {
-            // DRHD: parse device scopes
-            let mut header_size_for_scopes: usize = 0;
-            let mut seg: u16 = 0;
-            let mut reg_base: u64 = 0;
-            if len >= 4 + 12 {
-                seg = (unsafe { p.add(6).read() } as u16) | ((unsafe { p.add(7).read() } as u16) << 8);
-                for i in 0..8 { reg_base |= (unsafe { p.add(8 + i).read() } as u64) << (i * 8); }
-                header_size_for_scopes = 4 + 12;
-            }
-            if header_size_for_scopes > 0 && header_size_for_scopes < len {
-                let mut s_off = off + header_size_for_scopes;
-                let end = off + len;
-                while s_off + 6 <= end {
-                    let sp = (base + s_off) as *const u8;
-                    let s_len = (unsafe { sp.add(1).read() } as u16) | ((unsafe { sp.add(2).read() } as u16) << 8);
-                    if s_len < 6 || s_off + (s_len as usize) > end { break; }
-                    let bus = unsafe { sp.add(4).read() } as u8;
-                    // Device path entries follow (dev,func) pairs
-                    let mut path_off = 6usize;
-                    while path_off + 2 <= s_len as usize {
-                        let dev = unsafe { sp.add(path_off).read() } as u8;
-                        let func = unsafe { sp.add(path_off + 1).read() } as u8;
-                        f(seg, reg_base, bus, dev, func);
-                        path_off += 2;
-                    }
-                    s_off += s_len as usize;
-                }
-            }
-        }
-        off += len;
-    }
+pub(crate) fn dmar_for_each_device_scope_from(f: impl FnMut(u16, u64, u8, u8, u8), hdr: &'static SdtHeader) {
+    walk::dmar_for_each_device_scope(sdt_bytes(hdr), f);
 }
-
-
@@ -0,0 +1,303 @@
+#![allow(dead_code)]
+
+//! NUMA topology from SRAT and SLIT.
+//!
+//! SRAT assigns memory ranges and processors (by APIC / x2APIC ID) to
+//! proximity domains; SLIT gives the relative distance between them. Both
+//! are folded into a fixed-size `NumaTopology` with dense node indices in
+//! order of first appearance, so callers never deal with sparse domain
+//! numbers. Without an SRAT the machine is reported as a single node that
+//! owns every processor and all memory.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use super::SdtHeader;
+use crate::util::spinlock::SpinLock;
+
+const SIG_SRAT: [u8; 4] = *b"SRAT";
+const SIG_SLIT: [u8; 4] = *b"SLIT";
+
+// SRAT: header + reserved u32 + reserved u64
+const SRAT_ENTRIES_OFF: usize = 48;
+const SRAT_LAPIC: u8 = 0;
+const SRAT_MEMORY: u8 = 1;
+const SRAT_X2APIC: u8 = 2;
+const SRAT_ENABLED: u32 = 1 << 0;
+const SRAT_MEM_HOTPLUG: u32 = 1 << 1;
+const SRAT_MEM_NONVOLATILE: u32 = 1 << 2;
+// SLIT: header + u64 locality count, then count*count bytes
+const SLIT_MATRIX_OFF: usize = 44;
+
+pub const MAX_NODES: usize = 16;
+pub const MAX_MEM_RANGES: usize = 64;
+pub const MAX_CPUS: usize = 256;
+/// SLIT distance of a node to itself
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Distance assumed between distinct nodes when SLIT is absent
+pub const REMOTE_DISTANCE: u8 = 20;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemRange {
+    pub node: u8,
+    pub base: u64,
+    pub len: u64,
+    pub hotplug: bool,
+    pub nonvolatile: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CpuAffinity {
+    pub node: u8,
+    pub apic_id: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NumaTopology {
+    /// False when no SRAT was found and this is the single-node fallback
+    pub from_srat: bool,
+    /// True when the distance matrix came from SLIT
+    pub from_slit: bool,
+    pub nodes: usize,
+    /// ACPI proximity domain of each node index
+    pub domain: [u32; MAX_NODES],
+    pub mem: [MemRange; MAX_MEM_RANGES],
+    pub mem_count: usize,
+    pub cpus: [CpuAffinity; MAX_CPUS],
+    pub cpu_count: usize,
+    pub distance: [[u8; MAX_NODES]; MAX_NODES],
+}
+
+impl NumaTopology {
+    const EMPTY: NumaTopology = NumaTopology {
+        from_srat: false,
+        from_slit: false,
+        nodes: 0,
+        domain: [0; MAX_NODES],
+        mem: [MemRange { node: 0, base: 0, len: 0, hotplug: false, nonvolatile: false }; MAX_MEM_RANGES],
+        mem_count: 0,
+        cpus: [CpuAffinity { node: 0, apic_id: 0 }; MAX_CPUS],
+        cpu_count: 0,
+        distance: [[0; MAX_NODES]; MAX_NODES],
+    };
+
+    pub fn mem_ranges(&self) -> &[MemRange] { &self.mem[..self.mem_count] }
+
+    pub fn cpu_affinities(&self) -> &[CpuAffinity] { &self.cpus[..self.cpu_count] }
+
+    /// Node owning physical address `pa`, if any SRAT range covers it.
+    pub fn node_of_addr(&self, pa: u64) -> Option<u8> {
+        self.mem_ranges().iter().find(|r| pa >= r.base && pa - r.base < r.len).map(|r| r.node)
+    }
+
+    pub fn node_of_apic(&self, apic_id: u32) -> Option<u8> {
+        self.cpu_affinities().iter().find(|c| c.apic_id == apic_id).map(|c| c.node)
+    }
+
+    pub fn distance(&self, a: u8, b: u8) -> u8 {
+        if (a as usize) < self.nodes && (b as usize) < self.nodes { self.distance[a as usize][b as usize] } else { u8::MAX }
+    }
+
+    /// Bytes of (non-hotplug) memory on `node`.
+    pub fn node_bytes(&self, node: u8) -> u64 {
+        self.mem_ranges().iter().filter(|r| r.node == node && !r.hotplug).fold(0u64, |acc, r| acc.saturating_add(r.len))
+    }
+
+    pub fn node_cpus(&self, node: u8) -> usize {
+        self.cpu_affinities().iter().filter(|c| c.node == node).count()
+    }
+
+    /// Other nodes ordered nearest first, for spilling allocations.
+    pub fn nearest(&self, node: u8, out: &mut [u8; MAX_NODES]) -> usize {
+        let mut n = 0;
+        for i in 0..self.nodes as u8 { if i != node { out[n] = i; n += 1; } }
+        out[..n].sort_unstable_by_key(|&i| self.distance(node, i));
+        n
+    }
+
+    fn node_index(&mut self, domain: u32) -> Option<u8> {
+        if let Some(i) = self.domain[..self.nodes].iter().position(|&d| d == domain) { return Some(i as u8); }
+        if self.nodes == MAX_NODES { return None; }
+        self.domain[self.nodes] = domain;
+        self.nodes += 1;
+        Some((self.nodes - 1) as u8)
+    }
+}
+
+fn rd32(p: usize) -> u32 { unsafe { core::ptr::read_unaligned(p as *const u32) } }
+fn rd64(p: usize) -> u64 { unsafe { core::ptr::read_unaligned(p as *const u64) } }
+
+fn parse_srat(t: &mut NumaTopology, hdr: &'static SdtHeader) {
+    let base = hdr as *const SdtHeader as usize;
+    let total = hdr.length as usize;
+    let mut off = SRAT_ENTRIES_OFF;
+    while off + 2 <= total {
+        let p = base + off;
+        let ty = unsafe { (p as *const u8).read() };
+        let len = unsafe { (p as *const u8).add(1).read() } as usize;
+        if len < 2 || off + len > total { break; }
+        match ty {
+            SRAT_LAPIC if len >= 16 => {
+                let flags = rd32(p + 4);
+                if flags & SRAT_ENABLED != 0 && t.cpu_count < MAX_CPUS {
+                    let lo = unsafe { (p as *const u8).add(2).read() } as u32;
+                    let hi = rd32(p + 8) >> 8;
+                    if let Some(node) = t.node_index(hi << 8 | lo) {
+                        let apic_id = unsafe { (p as *const u8).add(3).read() } as u32;
+                        t.cpus[t.cpu_count] = CpuAffinity { node, apic_id };
+                        t.cpu_count += 1;
+                    }
+                }
+            }
+            SRAT_X2APIC if len >= 24 => {
+                let flags = rd32(p + 12);
+                if flags & SRAT_ENABLED != 0 && t.cpu_count < MAX_CPUS {
+                    if let Some(node) = t.node_index(rd32(p + 4)) {
+                        t.cpus[t.cpu_count] = CpuAffinity { node, apic_id: rd32(p + 8) };
+                        t.cpu_count += 1;
+                    }
+                }
+            }
+            SRAT_MEMORY if len >= 40 => {
+                let flags = rd32(p + 28);
+                let len_bytes = rd64(p + 16);
+                if flags & SRAT_ENABLED != 0 && len_bytes != 0 && t.mem_count < MAX_MEM_RANGES {
+                    if let Some(node) = t.node_index(rd32(p + 2)) {
+                        t.mem[t.mem_count] = MemRange {
+                            node,
+                            base: rd64(p + 8),
+                            len: len_bytes,
+                            hotplug: flags & SRAT_MEM_HOTPLUG != 0,
+                            nonvolatile: flags & SRAT_MEM_NONVOLATILE != 0,
+                        };
+                        t.mem_count += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+        off += len;
+    }
+}
+
+/// Fill the distance matrix from SLIT. Returns false if the table does not
+/// cover every proximity domain SRAT named.
+fn parse_slit(t: &mut NumaTopology, hdr: &'static SdtHeader) -> bool {
+    let base = hdr as *const SdtHeader as usize;
+    let total = hdr.length as usize;
+    if total < SLIT_MATRIX_OFF { return false; }
+    let count = rd64(base + 36);
+    if count == 0 || count > 256 || SLIT_MATRIX_OFF + (count * count) as usize > total { return false; }
+    let count = count as usize;
+    if t.domain[..t.nodes].iter().any(|&d| d as usize >= count) { return false; }
+    for i in 0..t.nodes {
+        for j in 0..t.nodes {
+            let (di, dj) = (t.domain[i] as usize, t.domain[j] as usize);
+            t.distance[i][j] = unsafe { ((base + SLIT_MATRIX_OFF + di * count + dj) as *const u8).read() };
+        }
+    }
+    true
+}
+
+/// Build the topology from firmware tables.
+pub fn discover(system_table: &SystemTable<Boot>) -> NumaTopology {
+    let mut t = NumaTopology::EMPTY;
+    if let Some(srat) = super::find_table(system_table, SIG_SRAT) {
+        parse_srat(&mut t, srat);
+        t.from_srat = t.nodes != 0;
+    }
+    if !t.from_srat {
+        // Single node: every MADT processor, memory left unbounded.
+        t.nodes = 1;
+        if let Some(madt) = super::find_madt(system_table) {
+            super::madt_for_each_processor_id(|id| {
+                if t.cpu_count < MAX_CPUS { t.cpus[t.cpu_count] = CpuAffinity { node: 0, apic_id: id }; t.cpu_count += 1; }
+            }, madt);
+        }
+        t.mem[0] = MemRange { node: 0, base: 0, len: u64::MAX, hotplug: false, nonvolatile: false };
+        t.mem_count = 1;
+    }
+    if t.from_srat {
+        if let Some(slit) = super::find_table(system_table, SIG_SLIT) { t.from_slit = parse_slit(&mut t, slit); }
+    }
+    if !t.from_slit {
+        for i in 0..t.nodes {
+            for j in 0..t.nodes { t.distance[i][j] = if i == j { LOCAL_DISTANCE } else { REMOTE_DISTANCE }; }
+        }
+    }
+    t
+}
+
+static CACHED: SpinLock<Option<NumaTopology>> = SpinLock::new(None);
+
+/// Topology discovered on first use; firmware tables don't change at runtime.
+pub fn topology(system_table: &SystemTable<Boot>) -> NumaTopology {
+    if let Some(t) = CACHED.lock(|c| *c) { return t; }
+    let t = discover(system_table);
+    CACHED.lock(|c| *c = Some(t));
+    t
+}
+
+/// Print nodes, their memory and CPUs, and the distance matrix.
+pub fn report(t: &NumaTopology, mut writer: impl FnMut(&str)) {
+    let mut buf = [0u8; 128];
+    let mut n = 0;
+    for &b in b"NUMA: nodes=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(t.nodes as u32, &mut buf[n..]);
+    let src: &[u8] = if !t.from_srat { b" source=none(single-node)" } else if t.from_slit { b" source=srat+slit" } else { b" source=srat" };
+    for &b in src { buf[n] = b; n += 1; }
+    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+    writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    for node in 0..t.nodes as u8 {
+        let mut n = 0;
+        for &b in b"node " { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(node as u32, &mut buf[n..]);
+        for &b in b" pxm=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(t.domain[node as usize], &mut buf[n..]);
+        for &b in b" cpus=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(t.node_cpus(node) as u32, &mut buf[n..]);
+        if t.from_srat {
+            for &b in b" mem_mib=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(t.node_bytes(node) >> 20, &mut buf[n..]);
+        }
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
+    if t.from_srat {
+        for r in t.mem_ranges() {
+            let mut n = 0;
+            for &b in b"  mem node=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(r.node as u32, &mut buf[n..]);
+            for &b in b" base=0x" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(r.base, &mut buf[n..]);
+            for &b in b" len=0x" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(r.len, &mut buf[n..]);
+            if r.hotplug { for &b in b" hotplug" { buf[n] = b; n += 1; } }
+            if r.nonvolatile { for &b in b" nv" { buf[n] = b; n += 1; } }
+            buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+            writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+        }
+        for c in t.cpu_affinities() {
+            let mut n = 0;
+            for &b in b"  cpu apic=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(c.apic_id, &mut buf[n..]);
+            for &b in b" node=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(c.node as u32, &mut buf[n..]);
+            buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+            writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+        }
+    }
+    // Distance matrix, one row per node
+    for i in 0..t.nodes {
+        let mut n = 0;
+        for &b in b"  dist " { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(i as u32, &mut buf[n..]);
+        buf[n] = b':'; n += 1;
+        for j in 0..t.nodes {
+            buf[n] = b' '; n += 1;
+            n += crate::util::format::u32_dec(t.distance[i][j] as u32, &mut buf[n..]);
+        }
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        writer(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
+}
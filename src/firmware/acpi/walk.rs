@@ -0,0 +1,262 @@
+#![allow(dead_code)]
+
+//! Bounds-checked walkers for the variable-length parts of MADT, DMAR and
+//! IVRS, and the FADT fields we read.
+//!
+//! Firmware tables are input we do not control: a structure length of zero,
+//! one that runs past the table, or a table length larger than the mapping
+//! must end the walk, not read beyond it. The walkers take the whole table
+//! as a byte slice and never look outside it. They depend only on `core`,
+//! so the fuzz targets under `fuzz/` build this file on the host unchanged.
+
+/// Standard SDT header size
+pub const SDT_HEADER_LEN: usize = 36;
+/// MADT: SDT header, Local APIC address, flags
+pub const MADT_ENTRIES: usize = SDT_HEADER_LEN + 8;
+/// DMAR: SDT header, host address width, flags, 10 reserved
+pub const DMAR_ENTRIES: usize = SDT_HEADER_LEN + 12;
+/// IVRS: SDT header, IVinfo, 8 reserved
+pub const IVRS_ENTRIES: usize = SDT_HEADER_LEN + 12;
+
+/// How a table's structures encode their type and length.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// type u8, length u8 (MADT)
+    Madt,
+    /// type u16, length u16 (DMAR)
+    Dmar,
+    /// type u8, flags u8, length u16 (IVRS)
+    Ivrs,
+}
+
+fn le16(b: &[u8], at: usize) -> u16 { u16::from_le_bytes([b[at], b[at + 1]]) }
+fn le32(b: &[u8], at: usize) -> u32 { u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]) }
+fn le64(b: &[u8], at: usize) -> u64 { (le32(b, at) as u64) | ((le32(b, at + 4) as u64) << 32) }
+
+/// The table clipped to its header's length field; empty if even the
+/// header does not fit.
+pub fn table(t: &[u8]) -> &[u8] {
+    if t.len() < SDT_HEADER_LEN { return &[]; }
+    let len = le32(t, 4) as usize;
+    if len < SDT_HEADER_LEN { return &[]; }
+    &t[..len.min(t.len())]
+}
+
+/// Call `f(type, structure)` for each structure of a table laid out as
+/// `layout`. `structure` includes its own type/length header. Stops at the
+/// first structure that is shorter than that header or overruns the table.
+pub fn for_each_entry(t: &[u8], layout: Layout, mut f: impl FnMut(u16, &[u8])) {
+    let t = table(t);
+    let (mut off, hdr) = match layout { Layout::Madt => (MADT_ENTRIES, 2), Layout::Dmar => (DMAR_ENTRIES, 4), Layout::Ivrs => (IVRS_ENTRIES, 4) };
+    while off + hdr <= t.len() {
+        let (typ, len) = match layout {
+            Layout::Madt => (t[off] as u16, t[off + 1] as usize),
+            Layout::Dmar => (le16(t, off), le16(t, off + 2) as usize),
+            Layout::Ivrs => (t[off] as u16, le16(t, off + 2) as usize),
+        };
+        if len < hdr || len > t.len() - off { break; }
+        f(typ, &t[off..off + len]);
+        off += len;
+    }
+}
+
+// ---- MADT ----
+
+/// Local APIC base address from the MADT header.
+pub fn madt_lapic_base(t: &[u8]) -> Option<u32> {
+    let t = table(t);
+    if t.len() < MADT_ENTRIES { return None; }
+    Some(le32(t, SDT_HEADER_LEN))
+}
+
+/// APIC ID of a Processor Local APIC (type 0) or x2APIC (type 9) structure.
+pub fn madt_processor_id(typ: u16, e: &[u8]) -> Option<u32> {
+    match typ {
+        0 if e.len() >= 8 => Some(e[3] as u32),
+        9 if e.len() >= 16 => Some(le32(e, 4)),
+        _ => None,
+    }
+}
+
+/// Call `f` with the APIC ID of each processor structure.
+pub fn madt_for_each_processor_id(t: &[u8], mut f: impl FnMut(u32)) {
+    for_each_entry(t, Layout::Madt, |typ, e| { if let Some(id) = madt_processor_id(typ, e) { f(id); } });
+}
+
+// ---- DMAR ----
+
+pub const DMAR_DRHD: u16 = 0;
+pub const DMAR_RMRR: u16 = 1;
+pub const DMAR_ATSR: u16 = 2;
+
+/// Fixed fields of a remapping structure that the rest of the code uses.
+#[derive(Clone, Copy, Default)]
+pub struct DmarStruct {
+    pub segment: u16,
+    /// DRHD register base or RMRR region base
+    pub base: u64,
+    /// RMRR region limit
+    pub limit: u64,
+    /// ATSR flags
+    pub flags: u8,
+    /// Offset of the device scope list within the structure; 0 if the type
+    /// has none or the structure is too short for its fixed part
+    pub scopes: usize,
+}
+
+/// Decode the fixed part of DRHD, RMRR or ATSR structure `e`.
+pub fn dmar_struct(typ: u16, e: &[u8]) -> Option<DmarStruct> {
+    match typ {
+        // flags(1) rsvd(1) segment(2) register base(8)
+        DMAR_DRHD if e.len() >= 16 => Some(DmarStruct { segment: le16(e, 6), base: le64(e, 8), scopes: 16, ..Default::default() }),
+        // rsvd(2) segment(2) base(8) limit(8)
+        DMAR_RMRR if e.len() >= 24 => Some(DmarStruct { segment: le16(e, 6), base: le64(e, 8), limit: le64(e, 16), scopes: 24, ..Default::default() }),
+        // flags(1) rsvd(1) segment(2)
+        DMAR_ATSR if e.len() >= 8 => Some(DmarStruct { segment: le16(e, 6), flags: e[4], scopes: 8, ..Default::default() }),
+        _ => None,
+    }
+}
+
+/// Call `f(scope type, length, start bus, path)` for each device scope in
+/// `e[scopes..]`. `path` is the (device, function) pairs, possibly with a
+/// trailing odd byte the caller should ignore.
+pub fn dmar_for_each_scope(e: &[u8], scopes: usize, mut f: impl FnMut(u8, usize, u8, &[u8])) {
+    if scopes == 0 || scopes >= e.len() { return; }
+    let mut off = scopes;
+    // type(1) length(1) flags(1) rsvd(1) enumeration id(1) start bus(1) path
+    while off + 6 <= e.len() {
+        let len = e[off + 1] as usize;
+        if len < 6 || len > e.len() - off { break; }
+        f(e[off], len, e[off + 5], &e[off + 6..off + len]);
+        off += len;
+    }
+}
+
+/// Call `f(segment, register base)` for each DRHD.
+pub fn dmar_for_each_drhd(t: &[u8], mut f: impl FnMut(u16, u64)) {
+    for_each_entry(t, Layout::Dmar, |typ, e| {
+        if typ != DMAR_DRHD { return; }
+        if let Some(d) = dmar_struct(typ, e) { f(d.segment, d.base); }
+    });
+}
+
+/// Call `f(segment, register base, bus, device, function)` for each path
+/// entry in each DRHD device scope.
+pub fn dmar_for_each_device_scope(t: &[u8], mut f: impl FnMut(u16, u64, u8, u8, u8)) {
+    for_each_entry(t, Layout::Dmar, |typ, e| {
+        if typ != DMAR_DRHD { return; }
+        let Some(d) = dmar_struct(typ, e) else { return };
+        dmar_for_each_scope(e, d.scopes, |_, _, bus, path| {
+            for p in path.chunks_exact(2) { f(d.segment, d.base, bus, p[0], p[1]); }
+        });
+    });
+}
+
+// ---- IVRS ----
+
+/// Segment and register base of an IVHD block (types 0x10, 0x11, 0x40);
+/// None for IVMD blocks, which describe memory and carry no unit.
+pub fn ivrs_ivhd(typ: u16, e: &[u8]) -> Option<(u16, u64)> {
+    // type(1) flags(1) length(2) device id(2) cap offset(2) base(8) segment(2)
+    if !matches!(typ, 0x10 | 0x11 | 0x40) || e.len() < 18 { return None; }
+    Some((le16(e, 16), le64(e, 8)))
+}
+
+/// Call `f(segment, register base)` for each IVHD with a nonzero base.
+pub fn ivrs_for_each_ivhd(t: &[u8], mut f: impl FnMut(u16, u64)) {
+    for_each_entry(t, Layout::Ivrs, |typ, e| {
+        if let Some((seg, base)) = ivrs_ivhd(typ, e) { if base != 0 { f(seg, base); } }
+    });
+}
+
+// ---- FADT ----
+
+/// ACPI PM timer: I/O port and whether the counter is 32 bits wide (24
+/// otherwise). `X_PM_TMR_BLK` wins when it names a system I/O port; a
+/// memory-mapped timer is not supported and falls back to `PM_TMR_BLK`.
+pub fn fadt_pm_timer(t: &[u8]) -> Option<(u16, bool)> {
+    let t = table(t);
+    // PM_TMR_BLK at 76, flags at 112 (TMR_VAL_EXT is bit 8)
+    if t.len() < 116 { return None; }
+    let wide = le32(t, 112) & (1 << 8) != 0;
+    // X_PM_TMR_BLK: generic address at 208, space id 1 = system I/O
+    if t.len() >= 220 && t[208] == 1 {
+        let port = le64(t, 212);
+        if port != 0 && port <= 0xFFFF { return Some((port as u16, wide)); }
+    }
+    let port = le32(t, 76);
+    if port == 0 || port > 0xFFFF { return None; }
+    Some((port as u16, wide))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A table of `len` bytes with its header length set; the caller fills
+    /// in the structures.
+    fn sdt(buf: &mut [u8], len: usize) -> &mut [u8] {
+        buf[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        &mut buf[..len]
+    }
+
+    #[test]
+    fn ivrs_structures_start_after_reserved_bytes() {
+        let mut buf = [0u8; 128];
+        let t = sdt(&mut buf, IVRS_ENTRIES + 24);
+        assert_eq!(IVRS_ENTRIES, 48);
+        // Nonzero reserved bytes must not be taken for a structure
+        t[40..48].fill(0xAA);
+        let e = &mut t[IVRS_ENTRIES..];
+        e[0] = 0x10;
+        e[2..4].copy_from_slice(&24u16.to_le_bytes());
+        e[8..16].copy_from_slice(&0xFED8_0000u64.to_le_bytes());
+        e[16..18].copy_from_slice(&3u16.to_le_bytes());
+        let mut found = (0, 0, 0);
+        ivrs_for_each_ivhd(t, |seg, base| found = (found.0 + 1, seg, base));
+        assert_eq!(found, (1, 3, 0xFED8_0000));
+    }
+
+    #[test]
+    fn rmrr_fields() {
+        let mut e = [0u8; 24];
+        e[6..8].copy_from_slice(&2u16.to_le_bytes());
+        e[8..16].copy_from_slice(&0x7C00_0000u64.to_le_bytes());
+        e[16..24].copy_from_slice(&0x7C7F_FFFFu64.to_le_bytes());
+        let d = dmar_struct(DMAR_RMRR, &e).unwrap();
+        assert_eq!((d.segment, d.base, d.limit, d.scopes), (2, 0x7C00_0000, 0x7C7F_FFFF, 24));
+    }
+
+    /// A DRHD with two device scopes whose reserved bytes are nonzero.
+    fn drhd_with_scopes(e: &mut [u8; 32]) {
+        e[2..4].copy_from_slice(&32u16.to_le_bytes());
+        e[8..16].copy_from_slice(&0xFED9_0000u64.to_le_bytes());
+        // PCI endpoint 00:02.0 on bus 0, enumeration ID 7
+        e[16..24].copy_from_slice(&[1, 8, 0xFF, 0xFF, 7, 0, 2, 0]);
+        // PCI endpoint 00:1f.3 on bus 0x80
+        e[24..32].copy_from_slice(&[1, 8, 0xFF, 0xFF, 0, 0x80, 0x1F, 3]);
+    }
+
+    #[test]
+    fn device_scope_length_is_one_byte() {
+        let mut e = [0u8; 32];
+        drhd_with_scopes(&mut e);
+        let mut lens = [0usize; 4];
+        let mut n = 0;
+        dmar_for_each_scope(&e, 16, |_, len, _, _| { lens[n] = len; n += 1; });
+        assert_eq!(&lens[..n], &[8, 8]);
+    }
+
+    #[test]
+    fn device_scope_start_bus() {
+        let mut buf = [0u8; 128];
+        let t = sdt(&mut buf, DMAR_ENTRIES + 32);
+        let mut e = [0u8; 32];
+        drhd_with_scopes(&mut e);
+        t[DMAR_ENTRIES..].copy_from_slice(&e);
+        let mut seen = [(0u8, 0u8, 0u8); 4];
+        let mut n = 0;
+        dmar_for_each_device_scope(t, |_, _, bus, dev, func| { seen[n] = (bus, dev, func); n += 1; });
+        assert_eq!(&seen[..n], &[(0, 2, 0), (0x80, 0x1F, 3)]);
+    }
+}
@@ -0,0 +1,238 @@
+#![allow(dead_code)]
+
+//! Guest ACPI tables.
+//!
+//! Builds the smallest table set a modern guest needs to find its CPUs:
+//! RSDP -> XSDT -> {FADT, MADT}, with the FADT pointing at a DSDT that only
+//! opens `\_SB`. The FADT declares a hardware-reduced platform, so the guest
+//! expects no PM timer, PM1 blocks or SCI, none of which are emulated. The
+//! MADT lists one enabled local APIC per vCPU (x2APIC entries past ID 254),
+//! with APIC ID equal to the vCPU index as the vLAPIC model uses. A guest
+//! given a vNUMA topology (`hv::numa`) also gets an SRAT placing its vCPUs
+//! and RAM ranges in proximity domains and a SLIT with their distances.
+//!
+//! Tables live in the legacy BIOS area at `ACPI_GPA`, where the RSDP is also
+//! found by the 16-byte scan of 0xE0000-0xFFFFF that guests fall back to
+//! when the loader did not pass its address.
+
+pub const ACPI_GPA: u64 = 0xE_0000;
+pub const ACPI_LEN: usize = 0x1_0000;
+
+const OEM_ID: [u8; 6] = *b"ZEROVS";
+const OEM_TABLE_ID: [u8; 8] = *b"ZVGUEST ";
+const CREATOR_ID: [u8; 4] = *b"ZVSR";
+
+const HDR_LEN: usize = 36;
+const RSDP_LEN: usize = 36;
+const FADT_LEN: usize = 276;
+const FADT_HW_REDUCED: u32 = 1 << 20;
+// IAPC_BOOT_ARCH
+const IAPC_VGA_NOT_PRESENT: u16 = 1 << 2;
+const MADT_LAPIC: u8 = 0;
+const MADT_X2APIC: u8 = 9;
+const MADT_ENABLED: u32 = 1 << 0;
+/// SRAT: header, reserved u32 (must be 1) and reserved u64
+const SRAT_ENTRIES_OFF: usize = HDR_LEN + 12;
+const SRAT_LAPIC: u8 = 0;
+const SRAT_MEMORY: u8 = 1;
+const SRAT_X2APIC: u8 = 2;
+const SRAT_ENABLED: u32 = 1 << 0;
+
+/// Where each table landed, as guest-physical addresses.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Layout {
+    pub rsdp: u64,
+    pub xsdt: u64,
+    pub fadt: u64,
+    pub madt: u64,
+    pub dsdt: u64,
+    /// 0 without vNUMA
+    pub srat: u64,
+    pub slit: u64,
+    /// Bytes used from `ACPI_GPA`
+    pub bytes: usize,
+}
+
+fn wr16(b: &mut [u8], off: usize, v: u16) { b[off..off + 2].copy_from_slice(&v.to_le_bytes()); }
+fn wr32(b: &mut [u8], off: usize, v: u32) { b[off..off + 4].copy_from_slice(&v.to_le_bytes()); }
+fn wr64(b: &mut [u8], off: usize, v: u64) { b[off..off + 8].copy_from_slice(&v.to_le_bytes()); }
+
+fn checksum(b: &[u8]) -> u8 { 0u8.wrapping_sub(b.iter().fold(0u8, |a, &x| a.wrapping_add(x))) }
+
+/// Standard SDT header; the checksum is filled by `seal` once the body is written.
+fn header(t: &mut [u8], sig: &[u8; 4], len: usize, rev: u8) {
+    t[0..4].copy_from_slice(sig);
+    wr32(t, 4, len as u32);
+    t[8] = rev;
+    t[10..16].copy_from_slice(&OEM_ID);
+    t[16..24].copy_from_slice(&OEM_TABLE_ID);
+    wr32(t, 24, 1);
+    t[28..32].copy_from_slice(&CREATOR_ID);
+    wr32(t, 32, 1);
+}
+
+fn seal(t: &mut [u8]) { t[9] = 0; t[9] = checksum(t); }
+
+/// Minimal DSDT body: `Scope (\_SB) {}`.
+const DSDT_AML: [u8; 6] = [0x10, 0x05, b'_', b'S', b'B', b'_'];
+
+/// Lay the tables out in `out`, which the guest sees at `base_gpa`. With
+/// `vnuma` the SRAT and SLIT describing it are added.
+pub fn build(out: &mut [u8], base_gpa: u64, vcpus: u32, vnuma: Option<&crate::hv::numa::Layout>) -> Result<Layout, &'static str> {
+    let vcpus = vcpus.max(1);
+    let madt_len = HDR_LEN + 8 + (0..vcpus).map(|i| if i < 255 { 8 } else { 16 }).sum::<usize>();
+    let srat_len = vnuma.map_or(0, |v| SRAT_ENTRIES_OFF + (0..vcpus).map(|i| if i < 255 { 16 } else { 24 }).sum::<usize>() + v.ranges().len() * 40);
+    let slit_len = vnuma.map_or(0, |v| HDR_LEN + 8 + v.nodes * v.nodes);
+    // 16-byte aligned pieces: RSDP, XSDT, FADT, DSDT, MADT, then SRAT and SLIT
+    let align = |x: usize| (x + 15) & !15;
+    let rsdp_off = 0;
+    let xsdt_off = align(rsdp_off + RSDP_LEN);
+    let xsdt_len = HDR_LEN + if vnuma.is_some() { 4 } else { 2 } * 8;
+    let fadt_off = align(xsdt_off + xsdt_len);
+    let dsdt_off = align(fadt_off + FADT_LEN);
+    let dsdt_len = HDR_LEN + DSDT_AML.len();
+    let madt_off = align(dsdt_off + dsdt_len);
+    let srat_off = align(madt_off + madt_len);
+    let slit_off = align(srat_off + srat_len);
+    let end = if vnuma.is_some() { slit_off + slit_len } else { madt_off + madt_len };
+    if end > out.len() { return Err("acpi: tables exceed reserved area"); }
+    for b in out[..end].iter_mut() { *b = 0; }
+    let gpa = |off: usize| base_gpa + off as u64;
+
+    // DSDT
+    {
+        let t = &mut out[dsdt_off..dsdt_off + dsdt_len];
+        header(t, b"DSDT", dsdt_len, 2);
+        t[HDR_LEN..].copy_from_slice(&DSDT_AML);
+        seal(t);
+    }
+    // FADT (ACPI 6.x), hardware-reduced
+    {
+        let t = &mut out[fadt_off..fadt_off + FADT_LEN];
+        header(t, b"FACP", FADT_LEN, 6);
+        wr32(t, 40, gpa(dsdt_off) as u32);
+        wr16(t, 109, IAPC_VGA_NOT_PRESENT);
+        wr32(t, 112, FADT_HW_REDUCED);
+        wr64(t, 140, gpa(dsdt_off));
+        wr64(t, 268, u64::from_le_bytes(*b"Zerovisr"));
+        seal(t);
+    }
+    // MADT
+    {
+        let t = &mut out[madt_off..madt_off + madt_len];
+        header(t, b"APIC", madt_len, 5);
+        wr32(t, HDR_LEN, crate::hv::vlapic::APIC_DEFAULT_BASE as u32);
+        wr32(t, HDR_LEN + 4, 0); // no legacy 8259 pair
+        let mut o = HDR_LEN + 8;
+        for id in 0..vcpus {
+            if id < 255 {
+                t[o] = MADT_LAPIC; t[o + 1] = 8;
+                t[o + 2] = id as u8; // ACPI processor UID
+                t[o + 3] = id as u8;
+                wr32(t, o + 4, MADT_ENABLED);
+                o += 8;
+            } else {
+                t[o] = MADT_X2APIC; t[o + 1] = 16;
+                wr32(t, o + 4, id);
+                wr32(t, o + 8, MADT_ENABLED);
+                wr32(t, o + 12, id);
+                o += 16;
+            }
+        }
+        seal(t);
+    }
+    if let Some(v) = vnuma {
+        // SRAT: one affinity entry per vCPU (same IDs as the MADT), then the RAM ranges
+        let t = &mut out[srat_off..srat_off + srat_len];
+        header(t, b"SRAT", srat_len, 3);
+        wr32(t, HDR_LEN, 1);
+        let mut o = SRAT_ENTRIES_OFF;
+        for id in 0..vcpus {
+            let pxm = v.vcpu.get(id as usize).copied().unwrap_or(0) as u32;
+            if id < 255 {
+                t[o] = SRAT_LAPIC; t[o + 1] = 16;
+                t[o + 2] = pxm as u8;
+                t[o + 3] = id as u8;
+                wr32(t, o + 4, SRAT_ENABLED);
+                o += 16;
+            } else {
+                t[o] = SRAT_X2APIC; t[o + 1] = 24;
+                wr32(t, o + 4, pxm);
+                wr32(t, o + 8, id);
+                wr32(t, o + 12, SRAT_ENABLED);
+                o += 24;
+            }
+        }
+        for r in v.ranges() {
+            t[o] = SRAT_MEMORY; t[o + 1] = 40;
+            wr32(t, o + 2, r.node as u32);
+            wr64(t, o + 8, r.gpa);
+            wr64(t, o + 16, r.len);
+            wr32(t, o + 28, SRAT_ENABLED);
+            o += 40;
+        }
+        seal(t);
+        // SLIT
+        let t = &mut out[slit_off..slit_off + slit_len];
+        header(t, b"SLIT", slit_len, 1);
+        wr64(t, HDR_LEN, v.nodes as u64);
+        for i in 0..v.nodes {
+            for j in 0..v.nodes { t[HDR_LEN + 8 + i * v.nodes + j] = v.distance[i][j]; }
+        }
+        seal(t);
+    }
+    // XSDT
+    {
+        let t = &mut out[xsdt_off..xsdt_off + xsdt_len];
+        header(t, b"XSDT", xsdt_len, 1);
+        wr64(t, HDR_LEN, gpa(fadt_off));
+        wr64(t, HDR_LEN + 8, gpa(madt_off));
+        if vnuma.is_some() {
+            wr64(t, HDR_LEN + 16, gpa(srat_off));
+            wr64(t, HDR_LEN + 24, gpa(slit_off));
+        }
+        seal(t);
+    }
+    // RSDP (revision 2): checksum over the first 20 bytes, extended over all 36
+    {
+        let t = &mut out[rsdp_off..rsdp_off + RSDP_LEN];
+        t[0..8].copy_from_slice(b"RSD PTR ");
+        t[9..15].copy_from_slice(&OEM_ID);
+        t[15] = 2;
+        wr32(t, 20, RSDP_LEN as u32);
+        wr64(t, 24, gpa(xsdt_off));
+        t[8] = checksum(&t[..20]);
+        t[32] = checksum(t);
+    }
+    let (srat, slit) = if vnuma.is_some() { (gpa(srat_off), gpa(slit_off)) } else { (0, 0) };
+    Ok(Layout { rsdp: gpa(rsdp_off), xsdt: gpa(xsdt_off), fadt: gpa(fadt_off), madt: gpa(madt_off), dsdt: gpa(dsdt_off), srat, slit, bytes: end })
+}
+
+/// Call `f(signature, gpa, length)` for the RSDP and every table reachable
+/// from it, reading back what the guest will see in `area` (mapped at `base_gpa`).
+pub fn for_each_table(area: &[u8], base_gpa: u64, rsdp_gpa: u64, mut f: impl FnMut([u8; 4], u64, u32)) {
+    let at = |gpa: u64, len: usize| -> Option<&[u8]> {
+        let off = gpa.checked_sub(base_gpa)? as usize;
+        area.get(off..off.checked_add(len)?)
+    };
+    let Some(rsdp) = at(rsdp_gpa, RSDP_LEN) else { return };
+    if &rsdp[0..8] != b"RSD PTR " { return; }
+    f(*b"RSDP", rsdp_gpa, RSDP_LEN as u32);
+    let xsdt_gpa = u64::from_le_bytes(rsdp[24..32].try_into().unwrap_or([0; 8]));
+    let Some(xh) = at(xsdt_gpa, HDR_LEN) else { return };
+    let xlen = u32::from_le_bytes(xh[4..8].try_into().unwrap_or([0; 4]));
+    f(*b"XSDT", xsdt_gpa, xlen);
+    let Some(xsdt) = at(xsdt_gpa, xlen as usize) else { return };
+    for e in xsdt[HDR_LEN..].chunks_exact(8) {
+        let tgpa = u64::from_le_bytes(e.try_into().unwrap_or([0; 8]));
+        let Some(th) = at(tgpa, HDR_LEN) else { continue };
+        let sig = [th[0], th[1], th[2], th[3]];
+        f(sig, tgpa, u32::from_le_bytes(th[4..8].try_into().unwrap_or([0; 4])));
+        if &sig == b"FACP" {
+            if let Some(ft) = at(tgpa, FADT_LEN) {
+                let dgpa = u64::from_le_bytes(ft[140..148].try_into().unwrap_or([0; 8]));
+                if let Some(dh) = at(dgpa, HDR_LEN) { f(*b"DSDT", dgpa, u32::from_le_bytes(dh[4..8].try_into().unwrap_or([0; 4]))); }
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+#![allow(dead_code)]
+
+//! VM admission control.
+//!
+//! Every defined VM holds a reservation of vCPUs, guest memory, huge-page
+//! backed memory and passthrough devices. New VMs are admitted only if the sum
+//! of all reservations stays within host capacity scaled by the configured
+//! overcommit ratios. Huge pages and devices cannot be shared, so they are
+//! never overcommitted.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use core::fmt::Write as _;
+use crate::util::spinlock::SpinLock;
+
+/// Maximum number of concurrently reserved VMs (matches the VM registry).
+pub const MAX_RESERVATIONS: usize = 16;
+
+/// Resource that limited an admission decision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource { Vcpus, Memory, HugePages, Devices, Slots, GuestKeys }
+
+impl Resource {
+    pub fn name(self) -> &'static str {
+        match self {
+            Resource::Vcpus => "vcpus",
+            Resource::Memory => "memory",
+            Resource::HugePages => "hugepages",
+            Resource::Devices => "devices",
+            Resource::Slots => "slots",
+            Resource::GuestKeys => "guest-keys",
+        }
+    }
+}
+
+/// Structured rejection: what was asked for and what the constraining resource had left.
+#[derive(Clone, Copy, Debug)]
+pub struct AdmissionError {
+    pub resource: Resource,
+    pub requested: u64,
+    /// Remaining headroom under the limit at decision time
+    pub available: u64,
+    /// Effective limit (capacity scaled by ratio)
+    pub limit: u64,
+}
+
+impl AdmissionError {
+    /// Format as a single CLI line: `admission: denied resource=<r> requested=<n> available=<n> limit=<n>`.
+    pub fn write_line(&self, out: &mut [u8]) -> usize {
+        let mut n = 0;
+        for &b in b"admission: denied resource=" { out[n] = b; n += 1; }
+        for &b in self.resource.name().as_bytes() { out[n] = b; n += 1; }
+        for &b in b" requested=0x" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(self.requested, &mut out[n..]);
+        for &b in b" available=0x" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(self.available, &mut out[n..]);
+        for &b in b" limit=0x" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(self.limit, &mut out[n..]);
+        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+        n
+    }
+}
+
+/// Resources a VM asks for at creation time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Request {
+    pub vcpus: u32,
+    pub memory_bytes: u64,
+    /// Portion of guest memory that must come from the huge-page pool
+    pub hugepage_bytes: u64,
+    pub devices: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Reservation { vm_id: u64, req: Request }
+
+/// Host capacity and overcommit policy.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub host_cpus: u32,
+    pub host_memory: u64,
+    pub hugepage_pool: u64,
+    pub max_devices: u32,
+    /// vCPU:pCPU overcommit, percent (400 = 4 vCPUs per logical CPU)
+    pub vcpu_ratio_pct: u32,
+    /// Guest memory : host memory, percent (100 = no overcommit)
+    pub mem_ratio_pct: u32,
+    pub initialized: bool,
+}
+
+impl Policy {
+    /// vCPUs admissible across all VMs.
+    pub fn vcpu_limit(&self) -> u64 { (self.host_cpus as u64) * (self.vcpu_ratio_pct as u64) / 100 }
+    /// Guest memory admissible across all VMs.
+    pub fn mem_limit(&self) -> u64 { ((self.host_memory as u128) * (self.mem_ratio_pct as u128) / 100) as u64 }
+}
+
+struct State { policy: Policy, resv: [Option<Reservation>; MAX_RESERVATIONS] }
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    policy: Policy {
+        host_cpus: 0,
+        host_memory: 0,
+        hugepage_pool: 0,
+        max_devices: crate::iommu::state::MAX_ASSIGNMENTS as u32,
+        vcpu_ratio_pct: 400,
+        mem_ratio_pct: 100,
+        initialized: false,
+    },
+    resv: [None; MAX_RESERVATIONS],
+});
+
+/// Discover host capacity (logical CPUs from MADT, free memory from the UEFI map).
+/// Idempotent; ratios and pool sizes set earlier are preserved.
+pub fn init_capacity(system_table: &SystemTable<Boot>) {
+    if STATE.lock(|s| s.policy.initialized) { return; }
+    let cpus = match crate::firmware::acpi::find_madt(system_table) {
+        Some(hdr) => crate::firmware::acpi::madt_count_logical_cpus_from(hdr).max(1),
+        None => 1,
+    };
+    let mem = crate::mm::uefi::conventional_bytes(system_table);
+    STATE.lock(|s| {
+        s.policy.host_cpus = cpus;
+        s.policy.host_memory = mem;
+        s.policy.initialized = true;
+    });
+}
+
+/// Current policy snapshot.
+pub fn policy() -> Policy { STATE.lock(|s| s.policy) }
+
+/// Update overcommit ratios (percent). Zero leaves the value unchanged.
+pub fn set_ratios(vcpu_pct: u32, mem_pct: u32) {
+    STATE.lock(|s| {
+        if vcpu_pct != 0 { s.policy.vcpu_ratio_pct = vcpu_pct; }
+        if mem_pct != 0 { s.policy.mem_ratio_pct = mem_pct; }
+    });
+}
+
+/// Size the huge-page pool that `Request::hugepage_bytes` draws from.
+pub fn set_hugepage_pool(bytes: u64) { STATE.lock(|s| s.policy.hugepage_pool = bytes); }
+
+/// Limit on devices assignable across all VMs.
+pub fn set_max_devices(n: u32) { STATE.lock(|s| s.policy.max_devices = n); }
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Committed { pub vms: u32, pub vcpus: u64, pub memory: u64, pub hugepages: u64, pub devices: u64 }
+
+fn committed_of(resv: &[Option<Reservation>; MAX_RESERVATIONS], skip: u64) -> Committed {
+    let mut c = Committed::default();
+    for r in resv.iter().flatten() {
+        if r.vm_id == skip { continue; }
+        c.vms += 1;
+        c.vcpus += r.req.vcpus as u64;
+        c.memory += r.req.memory_bytes;
+        c.hugepages += r.req.hugepage_bytes;
+        c.devices += r.req.devices as u64;
+    }
+    c
+}
+
+/// Totals across all current reservations.
+pub fn committed() -> Committed { STATE.lock(|s| committed_of(&s.resv, 0)) }
+
+fn check_one(resource: Resource, requested: u64, used: u64, limit: u64) -> Result<(), AdmissionError> {
+    let available = limit.saturating_sub(used);
+    if requested > available { Err(AdmissionError { resource, requested, available, limit }) } else { Ok(()) }
+}
+
+fn check(p: &Policy, c: &Committed, req: &Request) -> Result<(), AdmissionError> {
+    check_one(Resource::Vcpus, req.vcpus as u64, c.vcpus, p.vcpu_limit())?;
+    check_one(Resource::Memory, req.memory_bytes, c.memory, p.mem_limit())?;
+    check_one(Resource::HugePages, req.hugepage_bytes, c.hugepages, p.hugepage_pool)?;
+    check_one(Resource::Devices, req.devices as u64, c.devices, p.max_devices as u64)?;
+    Ok(())
+}
+
+/// Admit `req` for `vm_id`, replacing any reservation the VM already holds.
+/// The decision and the reservation happen under one lock so concurrent
+/// creations cannot jointly overcommit.
+pub fn reserve(vm_id: u64, req: Request) -> Result<(), AdmissionError> {
+    let r = STATE.lock(|s| {
+        let c = committed_of(&s.resv, vm_id);
+        check(&s.policy, &c, &req)?;
+        if let Some(slot) = s.resv.iter_mut().flatten().find(|r| r.vm_id == vm_id) {
+            slot.req = req;
+            return Ok(());
+        }
+        match s.resv.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => { *slot = Some(Reservation { vm_id, req }); Ok(()) }
+            None => Err(AdmissionError { resource: Resource::Slots, requested: 1, available: 0, limit: MAX_RESERVATIONS as u64 }),
+        }
+    });
+    match r {
+        Ok(()) => crate::obs::metrics::Counter::new(&crate::obs::metrics::VM_ADMIT_OK).inc(),
+        Err(_) => crate::obs::metrics::Counter::new(&crate::obs::metrics::VM_ADMIT_REJECTS).inc(),
+    }
+    r
+}
+
+/// Admission check only, without recording anything.
+pub fn would_admit(req: &Request) -> Result<(), AdmissionError> {
+    STATE.lock(|s| { let c = committed_of(&s.resv, 0); check(&s.policy, &c, req) })
+}
+
+/// The reservation held by `vm_id`.
+pub fn reservation(vm_id: u64) -> Option<Request> {
+    STATE.lock(|s| s.resv.iter().flatten().find(|r| r.vm_id == vm_id).map(|r| r.req))
+}
+
+/// Add `n` passthrough devices to an existing reservation.
+pub fn reserve_devices(vm_id: u64, n: u32) -> Result<(), AdmissionError> {
+    let mut req = reservation(vm_id).unwrap_or_default();
+    req.devices = req.devices.saturating_add(n);
+    reserve(vm_id, req)
+}
+
+/// Drop the reservation held by `vm_id`. Returns true if one existed.
+pub fn release(vm_id: u64) -> bool {
+    STATE.lock(|s| {
+        for slot in s.resv.iter_mut() {
+            if matches!(slot, Some(r) if r.vm_id == vm_id) { *slot = None; return true; }
+        }
+        false
+    })
+}
+
+/// Print capacity, ratios, commitments and per-VM reservations.
+pub fn report(system_table: &mut SystemTable<Boot>) {
+    let p = policy();
+    let c = committed();
+    let resv = STATE.lock(|s| s.resv);
+    let stdout = system_table.stdout();
+    let mut buf = [0u8; 192]; let mut n = 0;
+    for &b in b"admission: cpus=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(p.host_cpus, &mut buf[n..]);
+    for &b in b" mem=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(p.host_memory, &mut buf[n..]);
+    for &b in b" hugepool=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(p.hugepage_pool, &mut buf[n..]);
+    for &b in b" devices=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(p.max_devices, &mut buf[n..]);
+    for &b in b" vcpu_ratio=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(p.vcpu_ratio_pct, &mut buf[n..]);
+    for &b in b"% mem_ratio=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(p.mem_ratio_pct, &mut buf[n..]);
+    buf[n] = b'%'; n += 1;
+    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+
+    n = 0;
+    for &b in b"admission: committed vms=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(c.vms, &mut buf[n..]);
+    for &b in b" vcpus=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(c.vcpus as u32, &mut buf[n..]);
+    for &b in b" mem=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(c.memory, &mut buf[n..]);
+    for &b in b" huge=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(c.hugepages, &mut buf[n..]);
+    for &b in b" devices=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(c.devices as u32, &mut buf[n..]);
+    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+
+    for r in resv.iter().flatten() {
+        n = 0;
+        for &b in b"  vm=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(r.vm_id as u32, &mut buf[n..]);
+        for &b in b" vcpus=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(r.req.vcpus, &mut buf[n..]);
+        for &b in b" mem=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(r.req.memory_bytes, &mut buf[n..]);
+        for &b in b" huge=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(r.req.hugepage_bytes, &mut buf[n..]);
+        for &b in b" devices=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(r.req.devices, &mut buf[n..]);
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
+}
@@ -0,0 +1,233 @@
+#![allow(dead_code)]
+
+//! Boot configuration file.
+//!
+//! `\zerovisor.conf` on the ESP holds the settings that would otherwise be
+//! typed after every boot, one `key = value` per line, `#` to end of line
+//! being a comment:
+//!
+//!   console       uefi | serial
+//!   console.port  com1..com4 | <hex port>      (serial only)
+//!   console.baud  <n>, a divisor of 115200      (serial only)
+//!   sink          console | null | buffer | snp | virtio | rdma
+//!   loglevel      info | warn | error
+//!   iommu         permissive | strict
+//!
+//! `sink` is the migration default sink and `iommu` the mode
+//! `iommu ir enable` uses when none is given. The file is checked as a
+//! whole before anything is applied: an unknown key or a bad value rejects
+//! it, and the error names the line. `key=value` tokens in the image's load
+//! options override the file; other tokens there, such as the image name
+//! the shell passes first, are ignored.
+//!
+//! `load` renders the result as one line in a fixed key order and stores
+//! it as the `BootInfo` command line. `apply` reads it back from there, so
+//! the settings in force are exactly the line the handoff carries.
+
+use core::fmt::Write;
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::bootinfo::{self, CMDLINE_MAX};
+use crate::migrate::ExportSink;
+
+pub const PATH: &str = "\\zerovisor.conf";
+/// Larger files are refused rather than read in part
+pub const FILE_MAX: usize = 4096;
+
+const SINKS: [(&str, ExportSink); 6] = [
+    ("console", ExportSink::Console), ("null", ExportSink::Null), ("buffer", ExportSink::Buffer),
+    ("snp", ExportSink::Snp), ("virtio", ExportSink::Virtio), ("rdma", ExportSink::Rdma),
+];
+const LEVELS: [&str; 3] = ["info", "warn", "error"];
+
+/// Settings found; `None` leaves the current behaviour alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Serial mirror on (true) or UEFI console only (false)
+    pub serial: Option<bool>,
+    pub port: Option<u16>,
+    pub baud: Option<u32>,
+    /// Index into the sink names
+    pub sink: Option<u8>,
+    /// 0 info, 1 warn, 2 error
+    pub log_level: Option<u8>,
+    pub iommu_strict: Option<bool>,
+}
+
+/// Why a file was refused: the 1-based line and what was wrong with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub msg: &'static str,
+}
+
+impl Config {
+    /// Take one setting.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        match key {
+            "console" => self.serial = Some(match value { "serial" => true, "uefi" => false, _ => return Err("console must be uefi or serial") }),
+            "console.port" => self.port = Some(crate::arch::x86::uart::parse_port(value).filter(|&p| p != 0).ok_or("console.port must be com1..com4 or a hex port")?),
+            "console.baud" => {
+                let b = value.parse::<u32>().map_err(|_| "console.baud must be a number")?;
+                let base = crate::arch::x86::uart::BASE_BAUD;
+                if b == 0 || b > base || base % b != 0 { return Err("console.baud must divide 115200"); }
+                self.baud = Some(b);
+            }
+            "sink" => self.sink = Some(SINKS.iter().position(|(n, _)| *n == value).ok_or("sink must be console, null, buffer, snp, virtio or rdma")? as u8),
+            "loglevel" => self.log_level = Some(LEVELS.iter().position(|l| *l == value).ok_or("loglevel must be info, warn or error")? as u8),
+            "iommu" => self.iommu_strict = Some(match value { "strict" => true, "permissive" => false, _ => return Err("iommu must be permissive or strict") }),
+            _ => return Err("unknown key"),
+        }
+        Ok(())
+    }
+
+    /// Checks that span keys.
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.serial != Some(true) && (self.port.is_some() || self.baud.is_some()) { return Err("console.port and console.baud need console = serial"); }
+        Ok(())
+    }
+
+    /// Later settings win over earlier ones.
+    fn merge(&mut self, o: &Config) {
+        if o.serial.is_some() { self.serial = o.serial; }
+        if o.port.is_some() { self.port = o.port; }
+        if o.baud.is_some() { self.baud = o.baud; }
+        if o.sink.is_some() { self.sink = o.sink; }
+        if o.log_level.is_some() { self.log_level = o.log_level; }
+        if o.iommu_strict.is_some() { self.iommu_strict = o.iommu_strict; }
+    }
+
+    /// Space-separated `key=value` tokens in a fixed order. Returns the length.
+    pub fn render(&self, out: &mut [u8]) -> usize {
+        let mut n = 0;
+        let mut put = |k: &str, v: &[u8]| {
+            if n + k.len() + v.len() + 2 > out.len() { return; }
+            if n != 0 { out[n] = b' '; n += 1; }
+            out[n..n + k.len()].copy_from_slice(k.as_bytes()); n += k.len();
+            out[n] = b'='; n += 1;
+            out[n..n + v.len()].copy_from_slice(v); n += v.len();
+        };
+        let mut num = [0u8; 20];
+        if let Some(s) = self.serial { put("console", if s { b"serial" } else { b"uefi" }); }
+        if let Some(p) = self.port {
+            match crate::arch::x86::uart::port_name(p) {
+                Some(name) => put("console.port", name.as_bytes()),
+                None => { num[..2].copy_from_slice(b"0x"); let k = crate::util::format::u64_hex(p as u64, &mut num[2..]); put("console.port", &num[..2 + k]); }
+            }
+        }
+        if let Some(b) = self.baud { let k = crate::util::format::u64_dec(b as u64, &mut num); put("console.baud", &num[..k]); }
+        if let Some(s) = self.sink { put("sink", SINKS[s as usize].0.as_bytes()); }
+        if let Some(l) = self.log_level { put("loglevel", LEVELS[l as usize].as_bytes()); }
+        if let Some(s) = self.iommu_strict { put("iommu", if s { b"strict" } else { b"permissive" }); }
+        n
+    }
+}
+
+/// Parse a configuration file.
+pub fn parse_file(text: &[u8]) -> Result<Config, Error> {
+    let text = core::str::from_utf8(text).map_err(|_| Error { line: 0, msg: "not UTF-8" })?;
+    let mut c = Config::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() { continue; }
+        let err = |msg| Error { line: i + 1, msg };
+        let (k, v) = line.split_once('=').ok_or(err("expected key = value"))?;
+        c.set(k.trim(), v.trim()).map_err(err)?;
+    }
+    c.validate().map_err(|msg| Error { line: 0, msg })?;
+    Ok(c)
+}
+
+/// Settings among whitespace-separated tokens, as in load options or a
+/// rendered line. Tokens without `=` or with an unknown key are skipped;
+/// a known key with a bad value is an error.
+pub fn parse_line(line: &str) -> Result<Config, &'static str> {
+    let mut c = Config::default();
+    for tok in line.split_whitespace() {
+        let Some((k, v)) = tok.split_once('=') else { continue };
+        match c.set(k, v) {
+            Ok(()) | Err("unknown key") => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(c)
+}
+
+/// Read the file and the load options, check them and store the result as
+/// the `BootInfo` command line. Returns what was stored; an error leaves
+/// the command line unset, so nothing is applied.
+pub fn load(system_table: &SystemTable<Boot>) -> Result<Config, Error> {
+    let mut c = match crate::hv::loader::read_esp_file(system_table, PATH) {
+        Ok((buf, pages, len)) => {
+            let r = if len > FILE_MAX { Err(Error { line: 0, msg: "file too large" }) }
+                else { parse_file(unsafe { core::slice::from_raw_parts(buf, len) }) };
+            crate::mm::uefi::free_pages(system_table, buf, pages);
+            r?
+        }
+        Err(_) => Config::default(),
+    };
+    let mut opts = [0u8; CMDLINE_MAX];
+    let n = bootinfo::load_options(system_table, &mut opts);
+    let over = parse_line(core::str::from_utf8(&opts[..n]).unwrap_or("")).map_err(|msg| Error { line: 0, msg })?;
+    c.merge(&over);
+    c.validate().map_err(|msg| Error { line: 0, msg })?;
+    let mut line = [0u8; CMDLINE_MAX];
+    let len = c.render(&mut line);
+    if len != 0 { bootinfo::set_cmdline(&line[..len]); }
+    Ok(c)
+}
+
+/// Put the settings on the `BootInfo` command line into effect.
+pub fn apply() -> Result<Config, &'static str> {
+    let mut line = [0u8; CMDLINE_MAX];
+    let n = bootinfo::cmdline(&mut line);
+    let c = parse_line(core::str::from_utf8(&line[..n]).unwrap_or(""))?;
+    match c.serial {
+        Some(true) => {
+            let port = c.port.unwrap_or(crate::arch::x86::uart::COM[0]);
+            crate::obs::console::enable(port, c.baud.unwrap_or(crate::obs::console::DEFAULT_BAUD))?;
+        }
+        Some(false) => crate::obs::console::disable(),
+        None => {}
+    }
+    if let Some(s) = c.sink { crate::migrate::set_default_sink(SINKS[s as usize].1); }
+    match c.log_level {
+        Some(0) => crate::obs::log::set_min_level_info(),
+        Some(1) => crate::obs::log::set_min_level_warn(),
+        Some(_) => crate::obs::log::set_min_level_error(),
+        None => {}
+    }
+    if let Some(s) = c.iommu_strict { crate::iommu::vtd_ir::set_default_strict(s); }
+    Ok(c)
+}
+
+/// Load and apply at boot, printing one line either way.
+pub fn load_and_report(system_table: &mut SystemTable<Boot>) {
+    let res = load(system_table);
+    let mut out = [0u8; 96 + CMDLINE_MAX]; let mut n = 0;
+    let mut put = |b: &[u8], out: &mut [u8]| { let k = b.len().min(out.len() - n); out[n..n + k].copy_from_slice(&b[..k]); n += k; };
+    match res {
+        Ok(c) if c == Config::default() => return,
+        Ok(_) => {
+            let applied = apply();
+            let mut line = [0u8; CMDLINE_MAX];
+            let k = bootinfo::cmdline(&mut line);
+            put(b"config: ", &mut out);
+            put(&line[..k], &mut out);
+            if let Err(e) = applied { put(b" (", &mut out); put(e.as_bytes(), &mut out); put(b")", &mut out); }
+        }
+        Err(e) => {
+            put(b"config: ", &mut out);
+            put(PATH.as_bytes(), &mut out);
+            if e.line != 0 {
+                let mut num = [0u8; 20];
+                let k = crate::util::format::u64_dec(e.line as u64, &mut num);
+                put(b" line ", &mut out); put(&num[..k], &mut out);
+            }
+            put(b": ", &mut out); put(e.msg.as_bytes(), &mut out); put(b"; ignored", &mut out);
+        }
+    }
+    put(b"\r\n", &mut out);
+    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+}
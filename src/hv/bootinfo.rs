@@ -0,0 +1,250 @@
+#![allow(dead_code)]
+
+//! Boot handoff: what the boot phase leaves for the runtime phase.
+//!
+//! `BootInfo` is the one structure that crosses ExitBootServices. The boot
+//! phase fills it from firmware (`collect`), appends the final memory map
+//! once firmware is gone (`set_memory_map`), and `runtime::init_with_bootinfo`
+//! brings the runtime side up from it without calling firmware again.
+//!
+//! The layout is `repr(C)` and versioned so a later stage built separately
+//! can read it: `magic`, `version` and `size` come first and never move. A
+//! reader accepts any version up to its own and a `size` at least as large
+//! as the structure it knows; fields are only ever added at the end, and a
+//! field is meaningful only when its `HAS_*` flag is set. All addresses are
+//! physical and identity-mapped.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use uefi::table::SystemTable;
+
+use crate::util::sha256::DIGEST_LEN;
+use crate::util::spinlock::SpinLock;
+
+pub const MAGIC: [u8; 8] = *b"ZVBOOTI\0";
+pub const VERSION: u16 = 1;
+/// Memory map descriptors kept; the rest are counted in `map_dropped`
+pub const MAP_MAX: usize = 512;
+/// Command line bytes kept, ASCII
+pub const CMDLINE_MAX: usize = 512;
+
+pub const HAS_RSDP: u32 = 1 << 0;
+pub const HAS_FRAMEBUFFER: u32 = 1 << 1;
+pub const HAS_METRICS_PAGE: u32 = 1 << 2;
+pub const HAS_EVENT_LOG: u32 = 1 << 3;
+/// `image_hash` is set
+pub const HAS_IMAGE_HASH: u32 = 1 << 4;
+/// `file_hash` is set
+pub const HAS_FILE_HASH: u32 = 1 << 5;
+pub const HAS_CMDLINE: u32 = 1 << 6;
+/// `map` holds the map returned by ExitBootServices
+pub const HAS_MEMORY_MAP: u32 = 1 << 7;
+
+/// `Framebuffer::format`
+pub const FB_RGB: u32 = 0;
+pub const FB_BGR: u32 = 1;
+pub const FB_BITMASK: u32 = 2;
+/// No linear framebuffer; `base` and `size` are zero
+pub const FB_BLT_ONLY: u32 = 3;
+
+/// One memory map descriptor, in the UEFI memory type numbering.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MapEntry {
+    pub ty: u32,
+    pub _rsvd: u32,
+    pub base: u64,
+    pub pages: u64,
+    pub attr: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Framebuffer {
+    pub base: u64,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per scan line
+    pub stride: u32,
+    pub format: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BootInfo {
+    pub magic: [u8; 8],
+    pub version: u16,
+    pub _rsvd: u16,
+    /// `size_of::<BootInfo>()` of the writer
+    pub size: u32,
+    /// `HAS_*` bits
+    pub flags: u32,
+    pub map_entries: u32,
+    /// Descriptors that did not fit `map`
+    pub map_dropped: u32,
+    pub cmdline_len: u32,
+    pub rsdp: u64,
+    pub metrics_page: u64,
+    pub event_log: u64,
+    pub event_log_len: u64,
+    pub framebuffer: Framebuffer,
+    /// Relocation-normalized digest of the hypervisor image in memory
+    pub image_hash: [u8; DIGEST_LEN],
+    /// Digest of the hypervisor file on the ESP
+    pub file_hash: [u8; DIGEST_LEN],
+    pub cmdline: [u8; CMDLINE_MAX],
+    pub map: [MapEntry; MAP_MAX],
+}
+
+const EMPTY_ENTRY: MapEntry = MapEntry { ty: 0, _rsvd: 0, base: 0, pages: 0, attr: 0 };
+
+impl BootInfo {
+    pub const fn empty() -> BootInfo {
+        BootInfo {
+            magic: MAGIC, version: VERSION, _rsvd: 0, size: size_of::<BootInfo>() as u32, flags: 0,
+            map_entries: 0, map_dropped: 0, cmdline_len: 0, rsdp: 0, metrics_page: 0, event_log: 0, event_log_len: 0,
+            framebuffer: Framebuffer { base: 0, size: 0, width: 0, height: 0, stride: 0, format: 0 },
+            image_hash: [0; DIGEST_LEN], file_hash: [0; DIGEST_LEN], cmdline: [0; CMDLINE_MAX], map: [EMPTY_ENTRY; MAP_MAX],
+        }
+    }
+
+    /// Whether this build can read the structure.
+    pub fn check(&self) -> Result<(), &'static str> {
+        if self.magic != MAGIC { return Err("bootinfo: bad magic"); }
+        if self.version == 0 || self.version > VERSION { return Err("bootinfo: unsupported version"); }
+        if (self.size as usize) < size_of::<BootInfo>() { return Err("bootinfo: structure too small for its version"); }
+        if self.map_entries as usize > MAP_MAX || self.cmdline_len as usize > CMDLINE_MAX { return Err("bootinfo: count out of range"); }
+        Ok(())
+    }
+
+    pub fn has(&self, flag: u32) -> bool { self.flags & flag != 0 }
+
+    /// The memory map, empty unless `HAS_MEMORY_MAP`.
+    pub fn memory_map(&self) -> &[MapEntry] {
+        if !self.has(HAS_MEMORY_MAP) { return &[]; }
+        &self.map[..(self.map_entries as usize).min(MAP_MAX)]
+    }
+
+    pub fn cmdline(&self) -> &str {
+        core::str::from_utf8(&self.cmdline[..(self.cmdline_len as usize).min(CMDLINE_MAX)]).unwrap_or("")
+    }
+}
+
+static INFO: SpinLock<BootInfo> = SpinLock::new(BootInfo::empty());
+/// `collect` has run
+static COLLECTED: AtomicBool = AtomicBool::new(false);
+
+/// Run `f` on the handoff structure, once `collect` has filled it.
+pub fn with<R>(f: impl FnOnce(&BootInfo) -> R) -> Option<R> {
+    if !COLLECTED.load(Ordering::Acquire) { return None; }
+    Some(INFO.lock(|b| f(b)))
+}
+
+/// Linear framebuffer of the active GOP mode. Opened non-exclusively so the
+/// firmware console keeps drawing on it.
+fn framebuffer(system_table: &SystemTable<Boot>) -> Option<Framebuffer> {
+    use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
+    use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+    let bs = system_table.boot_services();
+    let h = bs.get_handle_for_protocol::<GraphicsOutput>().ok()?;
+    let mut gop = unsafe { bs.open_protocol::<GraphicsOutput>(OpenProtocolParams { handle: h, agent: bs.image_handle(), controller: None }, OpenProtocolAttributes::GetProtocol) }.ok()?;
+    let mode = gop.current_mode_info();
+    let (width, height) = mode.resolution();
+    let format = match mode.pixel_format() {
+        PixelFormat::Rgb => FB_RGB,
+        PixelFormat::Bgr => FB_BGR,
+        PixelFormat::Bitmask => FB_BITMASK,
+        PixelFormat::BltOnly => FB_BLT_ONLY,
+    };
+    let mut fb = Framebuffer { width: width as u32, height: height as u32, stride: mode.stride() as u32, format, ..Default::default() };
+    if format != FB_BLT_ONLY {
+        let mut raw = gop.frame_buffer();
+        fb.base = raw.as_mut_ptr() as u64;
+        fb.size = raw.size() as u64;
+    }
+    Some(fb)
+}
+
+/// Load options as ASCII: UCS-2 is narrowed, anything outside printable
+/// ASCII becomes '?', and the first NUL ends it. Returns the length.
+pub fn load_options(system_table: &SystemTable<Boot>, out: &mut [u8]) -> usize {
+    use uefi::proto::loaded_image::LoadedImage;
+    let bs = system_table.boot_services();
+    let Ok(li) = (unsafe { bs.open_protocol_exclusive::<LoadedImage>(bs.image_handle()) }) else { return 0 };
+    let Some(o) = li.load_options_as_bytes() else { return 0 };
+    // The shell passes UCS-2; a loader may pass bytes
+    let wide = o.len() >= 2 && o.len() % 2 == 0 && o.iter().skip(1).step_by(2).all(|&b| b == 0);
+    let mut n = 0;
+    for &b in o.iter().step_by(if wide { 2 } else { 1 }) {
+        if b == 0 || n == out.len() { break; }
+        out[n] = if (0x20..0x7F).contains(&b) { b } else { b'?' };
+        n += 1;
+    }
+    n
+}
+
+/// Set the command line ahead of `collect`, which then keeps it instead of
+/// reading the load options. `hv::bootcfg` puts the validated boot
+/// configuration here. Longer lines are cut at `CMDLINE_MAX`.
+pub fn set_cmdline(line: &[u8]) {
+    let n = line.len().min(CMDLINE_MAX);
+    INFO.lock(|b| {
+        b.cmdline[..n].copy_from_slice(&line[..n]);
+        b.cmdline_len = n as u32;
+        b.flags |= HAS_CMDLINE;
+    });
+}
+
+/// The command line as it stands, whether or not `collect` has run.
+pub fn cmdline(out: &mut [u8; CMDLINE_MAX]) -> usize {
+    INFO.lock(|b| { let n = b.cmdline_len as usize; out[..n].copy_from_slice(&b.cmdline[..n]); n })
+}
+
+/// Fill everything that needs Boot Services. Does not touch the memory map,
+/// which only becomes final at ExitBootServices.
+pub fn collect(system_table: &SystemTable<Boot>) {
+    let rsdp = crate::firmware::acpi::find_rsdp_addr(system_table);
+    let fb = framebuffer(system_table);
+    let page = crate::obs::page::info().map(|i| i.addr);
+    let (log, log_len) = crate::tpm::eventlog::region();
+    let measured = crate::tpm::attest::boot_measurement();
+    let mut line = [0u8; CMDLINE_MAX];
+    let mut line_len = cmdline(&mut line);
+    if line_len == 0 { line_len = load_options(system_table, &mut line); }
+    INFO.lock(|b| {
+        *b = BootInfo::empty();
+        if let Some(a) = rsdp { b.rsdp = a; b.flags |= HAS_RSDP; }
+        if let Some(f) = fb { b.framebuffer = f; b.flags |= HAS_FRAMEBUFFER; }
+        if let Some(a) = page { b.metrics_page = a; b.flags |= HAS_METRICS_PAGE; }
+        if log_len != 0 { b.event_log = log; b.event_log_len = log_len as u64; b.flags |= HAS_EVENT_LOG; }
+        if let Some(m) = measured {
+            b.image_hash = m.image;
+            b.flags |= HAS_IMAGE_HASH;
+            if let Some(f) = m.file { b.file_hash = f; b.flags |= HAS_FILE_HASH; }
+        }
+        if line_len != 0 { b.cmdline = line; b.cmdline_len = line_len as u32; b.flags |= HAS_CMDLINE; }
+    });
+    COLLECTED.store(true, Ordering::Release);
+}
+
+/// Record the final memory map. Needs no firmware, so it runs right after
+/// ExitBootServices.
+pub fn set_memory_map<'a>(entries: impl Iterator<Item = &'a MemoryDescriptor>) {
+    INFO.lock(|b| {
+        let (mut n, mut dropped) = (0usize, 0u32);
+        for d in entries {
+            if n == MAP_MAX { dropped += 1; continue; }
+            b.map[n] = MapEntry { ty: d.ty.0, _rsvd: 0, base: d.phys_start, pages: d.page_count, attr: d.att.bits() };
+            n += 1;
+        }
+        b.map_entries = n as u32;
+        b.map_dropped = dropped;
+        b.flags |= HAS_MEMORY_MAP;
+    });
+}
+
+/// Memory type of a map entry, for code that works in `uefi` types.
+pub fn entry_type(e: &MapEntry) -> MemoryType { MemoryType(e.ty) }
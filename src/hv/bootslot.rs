@@ -0,0 +1,231 @@
+#![allow(dead_code)]
+
+//! A/B hypervisor image slots with automatic rollback.
+//!
+//! Two copies of the hypervisor sit on the ESP, `SLOT_PATHS[0]` (slot a)
+//! and `SLOT_PATHS[1]` (slot b). Whatever image the firmware boot entry
+//! starts acts as the loader: `boot` reads `ZerovisorBootSlot`, picks the
+//! active slot and starts its image with LoadImage/StartImage. Before it
+//! does, it sets the volatile `ZerovisorSlotRun` variable to the slot, so
+//! the started image knows it is a slot image and does not chain again.
+//! With neither slot file present the loader simply carries on as the
+//! hypervisor, which is how a single-image install boots.
+//!
+//! An update writes the new image to the inactive slot and runs
+//! `slot try <a|b>`. That makes the slot active and puts it on trial with
+//! a budget of boots. Each loader pass spends one; `confirm`, called when
+//! the slot image reaches the CLI, or `slot ok` ends the trial. A trial
+//! slot that spends its budget unconfirmed, or whose image does not load,
+//! is abandoned: the previous slot becomes active again, and the next
+//! image to reach `confirm` records a `slot_rollback` audit event.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use uefi::prelude::Boot;
+use uefi::proto::media::file::{File, FileAttribute, FileMode};
+use uefi::table::boot::LoadImageSource;
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+use uefi::table::SystemTable;
+use uefi::{CStr16, Status};
+
+pub const SLOT_A: u8 = 0;
+pub const SLOT_B: u8 = 1;
+pub const SLOT_PATHS: [&str; 2] = ["\\EFI\\zerovisor\\slot_a.efi", "\\EFI\\zerovisor\\slot_b.efi"];
+/// Boots a slot on trial gets unless `slot try` says otherwise
+pub const DEFAULT_BOOTS: u8 = 3;
+pub const MAX_BOOTS: u8 = 16;
+
+const VAR_NS: VariableVendor = VariableVendor::GLOBAL_VARIABLE;
+const MAGIC: &[u8; 4] = b"ZVAB";
+const VERSION: u8 = 1;
+const STATE_LEN: usize = 16;
+/// `RUNNING` before `boot` has looked, or when not started as a slot
+const NONE: u8 = 0xFF;
+
+/// Slot this image was started as, if it was
+static RUNNING: AtomicU8 = AtomicU8::new(NONE);
+
+/// Persisted slot state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct State {
+    pub active: u8,
+    /// Where a rollback goes
+    pub previous: u8,
+    /// `active` has not been confirmed since `slot try`
+    pub trial: bool,
+    /// Loader passes spent on the trial so far
+    pub boots: u8,
+    /// Trial budget
+    pub max_boots: u8,
+    /// A rollback happened and is not in the audit log yet
+    pub unreported: bool,
+    /// Slot abandoned by the last rollback
+    pub rolled_from: u8,
+    pub rollbacks: u32,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State { active: SLOT_A, previous: SLOT_B, trial: false, boots: 0, max_boots: DEFAULT_BOOTS, unreported: false, rolled_from: 0, rollbacks: 0 }
+    }
+}
+
+impl State {
+    fn to_bytes(self) -> [u8; STATE_LEN] {
+        let mut b = [0u8; STATE_LEN];
+        b[0..4].copy_from_slice(MAGIC);
+        b[4] = VERSION;
+        b[5] = self.active;
+        b[6] = self.previous;
+        b[7] = self.trial as u8;
+        b[8] = self.boots;
+        b[9] = self.max_boots;
+        b[10] = self.unreported as u8;
+        b[11] = self.rolled_from;
+        b[12..16].copy_from_slice(&self.rollbacks.to_le_bytes());
+        b
+    }
+
+    fn parse(b: &[u8]) -> Option<State> {
+        if b.len() < STATE_LEN || &b[0..4] != MAGIC || b[4] != VERSION || b[5] > SLOT_B || b[6] > SLOT_B { return None; }
+        Some(State {
+            active: b[5], previous: b[6], trial: b[7] != 0, boots: b[8], max_boots: b[9].clamp(1, MAX_BOOTS),
+            unreported: b[10] != 0, rolled_from: b[11] & 1, rollbacks: u32::from_le_bytes([b[12], b[13], b[14], b[15]]),
+        })
+    }
+
+    /// Give up on the trial slot and go back to the previous one.
+    fn roll_back(&mut self) {
+        self.rolled_from = self.active;
+        self.active = self.previous;
+        self.previous = self.rolled_from;
+        self.trial = false;
+        self.unreported = true;
+        self.rollbacks = self.rollbacks.wrapping_add(1);
+    }
+}
+
+pub fn slot_name(slot: u8) -> &'static str { if slot == SLOT_B { "b" } else { "a" } }
+
+pub fn parse_slot(s: &str) -> Option<u8> {
+    match s { "a" | "A" => Some(SLOT_A), "b" | "B" => Some(SLOT_B), _ => None }
+}
+
+/// Slot this image was started as by the loader.
+pub fn running() -> Option<u8> {
+    match RUNNING.load(Ordering::Relaxed) { NONE => None, s => Some(s) }
+}
+
+/// Saved state, or the default when there is none or it is unreadable.
+pub fn load(system_table: &SystemTable<Boot>) -> State {
+    let mut buf = [0u8; STATE_LEN];
+    match system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorBootSlot"), &VAR_NS, &mut buf) {
+        Ok((d, _)) => State::parse(d).unwrap_or_default(),
+        Err(_) => State::default(),
+    }
+}
+
+fn save(system_table: &SystemTable<Boot>, s: &State) -> Result<(), &'static str> {
+    let attrs = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::NON_VOLATILE;
+    system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorBootSlot"), &VAR_NS, attrs, &s.to_bytes())
+        .map_err(|_| "slot: set_variable failed")
+}
+
+/// Whether the slot's image is on the ESP.
+pub fn present(system_table: &SystemTable<Boot>, slot: u8) -> bool {
+    let mut name_buf = [0u16; 40];
+    let Ok(name) = CStr16::from_str_with_buf(SLOT_PATHS[slot as usize & 1], &mut name_buf) else { return false };
+    let bs = system_table.boot_services();
+    let Ok(mut fs) = bs.get_image_file_system(bs.image_handle()) else { return false };
+    let Ok(mut root) = fs.open_volume() else { return false };
+    root.open(name, FileMode::Read, FileAttribute::empty()).ok().and_then(|h| h.into_regular_file()).is_some()
+}
+
+/// Load and start the slot's image. Returns its exit status, or an error
+/// if it never started.
+fn start(system_table: &SystemTable<Boot>, slot: u8) -> Result<Status, &'static str> {
+    let (buf, pages, len) = crate::hv::loader::read_esp_file(system_table, SLOT_PATHS[slot as usize & 1])?;
+    let bs = system_table.boot_services();
+    let image = bs.load_image(bs.image_handle(), LoadImageSource::FromBuffer { buffer: unsafe { core::slice::from_raw_parts(buf, len) }, file_path: None });
+    // LoadImage copied the image; the staging pages are ours again
+    crate::mm::uefi::free_pages(system_table, buf, pages);
+    let image = image.map_err(|_| "slot: LoadImage refused the image")?;
+    let rs = system_table.runtime_services();
+    rs.set_variable(uefi::cstr16!("ZerovisorSlotRun"), &VAR_NS, VariableAttributes::BOOTSERVICE_ACCESS, &[slot])
+        .map_err(|_| "slot: set_variable failed")?;
+    Ok(match bs.start_image(image) { Ok(()) => Status::SUCCESS, Err(e) => e.status() })
+}
+
+/// Loader step, run first thing at boot. Returns `None` when this image
+/// should go on as the hypervisor: it is a slot image, no slot is
+/// installed, or no slot would start. Otherwise returns the exit status of
+/// the slot image it ran, which the caller hands back to firmware.
+pub fn boot(system_table: &SystemTable<Boot>) -> Option<Status> {
+    let rs = system_table.runtime_services();
+    let mut run = [0u8; 1];
+    if let Ok((d, _)) = rs.get_variable(uefi::cstr16!("ZerovisorSlotRun"), &VAR_NS, &mut run) {
+        if d.len() == 1 && d[0] <= SLOT_B {
+            RUNNING.store(d[0], Ordering::Relaxed);
+            return None;
+        }
+    }
+    let mut s = load(system_table);
+    if !present(system_table, SLOT_A) && !present(system_table, SLOT_B) { return None; }
+    if s.trial {
+        if s.boots >= s.max_boots { s.roll_back(); } else { s.boots += 1; }
+        let _ = save(system_table, &s);
+    }
+    match start(system_table, s.active) {
+        Ok(status) => Some(status),
+        Err(_) if s.trial => {
+            // A trial image that does not even load has failed its trial
+            s.roll_back();
+            let _ = save(system_table, &s);
+            start(system_table, s.active).ok()
+        }
+        Err(_) => None,
+    }
+}
+
+/// Mark the running slot as good: end its trial and record any rollback
+/// that got us here. Returns the state as saved.
+pub fn confirm(system_table: &SystemTable<Boot>) -> State {
+    let mut s = load(system_table);
+    let before = s;
+    if s.trial && running() == Some(s.active) {
+        s.trial = false;
+        s.boots = 0;
+    }
+    if s.unreported {
+        crate::diag::audit::record(crate::diag::audit::AuditKind::SlotRollback { from: s.rolled_from, to: s.active, boots: s.boots });
+        crate::log!(Warn, "slot", "rolled back from slot {} to slot {}", slot_name(s.rolled_from), slot_name(s.active));
+        s.unreported = false;
+        s.boots = 0;
+    }
+    if s != before { let _ = save(system_table, &s); }
+    s
+}
+
+/// Make `slot` active on trial for `max_boots` boots, keeping the current
+/// active slot to fall back to. The image must already be in place.
+pub fn try_slot(system_table: &SystemTable<Boot>, slot: u8, max_boots: u8) -> Result<State, &'static str> {
+    if slot > SLOT_B { return Err("slot: unknown slot"); }
+    if !(1..=MAX_BOOTS).contains(&max_boots) { return Err("slot: boots out of range (1..16)"); }
+    if !present(system_table, slot) { return Err("slot: image not found on the ESP"); }
+    let mut s = load(system_table);
+    if s.active != slot { s.previous = s.active; }
+    s.active = slot;
+    s.trial = true;
+    s.boots = 0;
+    s.max_boots = max_boots;
+    save(system_table, &s)?;
+    Ok(s)
+}
+
+/// End the trial of the active slot by hand.
+pub fn mark_ok(system_table: &SystemTable<Boot>) -> Result<State, &'static str> {
+    let mut s = load(system_table);
+    s.trial = false;
+    s.boots = 0;
+    save(system_table, &s)?;
+    Ok(s)
+}
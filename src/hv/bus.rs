@@ -0,0 +1,144 @@
+#![allow(dead_code)]
+
+//! Virtual device bus: MMIO regions and I/O port ranges per VM.
+//!
+//! Device models register a callback for a guest-physical window or a port
+//! range; the exit path looks the access up here after decoding it. Accesses
+//! that hit nothing read as all-ones and discard writes, as on real hardware.
+
+use crate::util::spinlock::SpinLock;
+
+/// MMIO callback: (vm, vcpu, ctx, offset within region, size in bytes, write value) -> read value.
+/// `ctx` is the value given at registration, so one handler can serve several instances.
+pub type MmioFn = fn(u64, u32, u64, u64, u8, Option<u64>) -> u64;
+/// Port I/O callback: (vm, vcpu, port, size in bytes, write value) -> read value.
+pub type PioFn = fn(u64, u32, u16, u8, Option<u32>) -> u32;
+
+pub const MAX_MMIO: usize = 64;
+pub const MAX_PIO: usize = 64;
+
+#[derive(Clone, Copy)]
+pub struct MmioRegion { pub vm_id: u64, pub base: u64, pub len: u64, pub name: &'static str, pub ctx: u64, pub handler: MmioFn }
+
+#[derive(Clone, Copy)]
+pub struct PioRange { pub vm_id: u64, pub base: u16, pub len: u16, pub name: &'static str, pub handler: PioFn }
+
+static MMIO: SpinLock<[Option<MmioRegion>; MAX_MMIO]> = SpinLock::new([None; MAX_MMIO]);
+static PIO: SpinLock<[Option<PioRange>; MAX_PIO]> = SpinLock::new([None; MAX_PIO]);
+
+/// Register an MMIO window; overlapping windows in the same VM are rejected.
+pub fn register_mmio(vm_id: u64, base: u64, len: u64, name: &'static str, ctx: u64, handler: MmioFn) -> bool {
+    if len == 0 { return false; }
+    MMIO.lock(|t| {
+        let overlap = t.iter().flatten().any(|r| r.vm_id == vm_id && base < r.base + r.len && r.base < base + len);
+        if overlap { return false; }
+        match t.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => { *slot = Some(MmioRegion { vm_id, base, len, name, ctx, handler }); true }
+            None => false,
+        }
+    })
+}
+
+/// Register a port range; overlapping ranges in the same VM are rejected.
+pub fn register_pio(vm_id: u64, base: u16, len: u16, name: &'static str, handler: PioFn) -> bool {
+    if len == 0 { return false; }
+    let end = base as u32 + len as u32;
+    PIO.lock(|t| {
+        let overlap = t.iter().flatten().any(|r| r.vm_id == vm_id && (base as u32) < r.base as u32 + r.len as u32 && (r.base as u32) < end);
+        if overlap { return false; }
+        match t.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => { *slot = Some(PioRange { vm_id, base, len, name, handler }); true }
+            None => false,
+        }
+    })
+}
+
+/// Remove the MMIO window of `vm_id` starting at `base` (e.g. when a BAR moves).
+pub fn unregister_mmio(vm_id: u64, base: u64) -> bool {
+    MMIO.lock(|t| {
+        for r in t.iter_mut() {
+            if matches!(r, Some(x) if x.vm_id == vm_id && x.base == base) { *r = None; return true; }
+        }
+        false
+    })
+}
+
+/// Remove every region and range owned by `vm_id`.
+pub fn unregister_vm(vm_id: u64) {
+    MMIO.lock(|t| { for r in t.iter_mut() { if matches!(r, Some(x) if x.vm_id == vm_id) { *r = None; } } });
+    PIO.lock(|t| { for r in t.iter_mut() { if matches!(r, Some(x) if x.vm_id == vm_id) { *r = None; } } });
+}
+
+pub fn find_mmio(vm_id: u64, gpa: u64) -> Option<MmioRegion> {
+    MMIO.lock(|t| t.iter().flatten().find(|r| r.vm_id == vm_id && gpa >= r.base && gpa < r.base + r.len).copied())
+}
+
+pub fn find_pio(vm_id: u64, port: u16) -> Option<PioRange> {
+    PIO.lock(|t| t.iter().flatten().find(|r| r.vm_id == vm_id && port >= r.base && (port as u32) < r.base as u32 + r.len as u32).copied())
+}
+
+/// Dispatch an MMIO access. Returns None if no device claims `gpa`.
+/// The handler runs without the table lock held so it may register devices itself.
+pub fn mmio_access(vm_id: u64, vcpu: u32, gpa: u64, size: u8, write: Option<u64>) -> Option<u64> {
+    let r = find_mmio(vm_id, gpa)?;
+    Some((r.handler)(vm_id, vcpu, r.ctx, gpa - r.base, size, write))
+}
+
+/// Dispatch a port access. Returns None if no device claims `port`.
+pub fn pio_access(vm_id: u64, vcpu: u32, port: u16, size: u8, write: Option<u32>) -> Option<u32> {
+    let r = find_pio(vm_id, port)?;
+    Some((r.handler)(vm_id, vcpu, port, size, write))
+}
+
+/// Iterate registrations of `vm_id` as (is_mmio, base, len, name).
+pub fn for_each(vm_id: u64, mut f: impl FnMut(bool, u64, u64, &'static str)) {
+    let m = MMIO.lock(|t| *t);
+    for r in m.iter().flatten() { if r.vm_id == vm_id { f(true, r.base, r.len, r.name); } }
+    let p = PIO.lock(|t| *t);
+    for r in p.iter().flatten() { if r.vm_id == vm_id { f(false, r.base as u64, r.len as u64, r.name); } }
+}
+
+// ---- Built-in platform devices ----
+
+fn lapic_mmio(vm_id: u64, vcpu: u32, _ctx: u64, off: u64, _size: u8, write: Option<u64>) -> u64 {
+    match write {
+        Some(v) => { crate::hv::vlapic::mmio_write(vm_id, vcpu, off as u32, v as u32); 0 }
+        None => crate::hv::vlapic::mmio_read(vm_id, vcpu, off as u32) as u64,
+    }
+}
+
+fn hpet_mmio(vm_id: u64, _vcpu: u32, _ctx: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    // Registers are 64-bit; 32-bit accesses address either half.
+    let reg = (off & !7) as u32;
+    let shift = (off & 4) * 8;
+    match write {
+        Some(v) if size >= 8 => { crate::hv::vtime::hpet_mmio(vm_id, reg, Some(v)); 0 }
+        Some(v) => {
+            let cur = crate::hv::vtime::hpet_mmio(vm_id, reg, None);
+            let mask = 0xFFFF_FFFFu64 << shift;
+            crate::hv::vtime::hpet_mmio(vm_id, reg, Some((cur & !mask) | ((v & 0xFFFF_FFFF) << shift)));
+            0
+        }
+        None => {
+            let v = crate::hv::vtime::hpet_mmio(vm_id, reg, None);
+            if size >= 8 { v } else { (v >> shift) & 0xFFFF_FFFF }
+        }
+    }
+}
+
+fn pit_pio(vm_id: u64, _vcpu: u32, port: u16, _size: u8, write: Option<u32>) -> u32 {
+    crate::hv::vtime::pit_io(vm_id, port, write.map(|v| v as u8)).map(|v| v as u32).unwrap_or(0xFF)
+}
+
+fn rtc_pio(vm_id: u64, _vcpu: u32, port: u16, _size: u8, write: Option<u32>) -> u32 {
+    crate::hv::vtime::rtc_io(vm_id, port, write.map(|v| v as u8)).map(|v| v as u32).unwrap_or(0xFF)
+}
+
+/// Register the LAPIC page, HPET, PIT and CMOS RTC for a new VM.
+pub fn attach_platform(vm_id: u64) {
+    let _ = register_mmio(vm_id, crate::hv::vlapic::APIC_DEFAULT_BASE, 0x1000, "lapic", 0, lapic_mmio);
+    let _ = register_mmio(vm_id, crate::hv::vtime::hpet::HPET_BASE, 0x400, "hpet", 0, hpet_mmio);
+    let _ = register_pio(vm_id, crate::hv::vtime::pit::PORT_CH0, 4, "pit", pit_pio);
+    let _ = register_pio(vm_id, crate::hv::vtime::rtc::PORT_INDEX, 2, "rtc", rtc_pio);
+    let _ = crate::hv::vpci::attach(vm_id);
+}
@@ -0,0 +1,304 @@
+#![allow(dead_code)]
+
+//! Carbon-aware deferral of migrations and batch VM starts.
+//!
+//! The grid carbon intensity (gCO2e/kWh) comes from a static 24-hour UTC
+//! schedule, or from a sample pushed through the management API that
+//! overrides the schedule until it expires. Work that is not urgent is
+//! queued here instead of running immediately: `tick` releases the oldest
+//! job once intensity is at or below the threshold, or when the job's
+//! deadline passes. Each released job is charged an energy estimate (vCPU
+//! watts times the batch length for starts, Wh per GiB of guest memory for
+//! migrations), and the difference between the intensity at submission and
+//! at release gives the avoided emissions. With no signal configured nothing
+//! is deferred.
+
+use core::sync::atomic::Ordering;
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::obs::metrics::{CARBON_AVOIDED_MG, CARBON_DEFERRED, CARBON_FORCED, CARBON_INTENSITY_G, CARBON_RELEASED};
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_JOBS: usize = 16;
+/// Released jobs kept for `history`
+pub const HISTORY: usize = 8;
+/// Evaluation period of `tick`
+pub const INTERVAL_MS: u64 = 1000;
+/// Default low-carbon threshold, gCO2e/kWh
+pub const DEFAULT_THRESHOLD_G: u32 = 200;
+/// Default longest deferral, minutes
+pub const DEFAULT_MAX_DEFER_MIN: u32 = 12 * 60;
+/// Default validity of a pushed sample, minutes
+pub const DEFAULT_SAMPLE_MIN: u32 = 60;
+/// Default per-vCPU draw used for batch start estimates, watts
+pub const DEFAULT_VCPU_W: u32 = 10;
+/// Default migration cost, Wh per GiB of guest memory
+pub const DEFAULT_MIG_WH_PER_GIB: u32 = 2;
+/// Default batch length when a start does not give one, hours
+pub const DEFAULT_BATCH_HOURS: u32 = 1;
+/// Intensities above this are rejected as implausible
+pub const MAX_INTENSITY_G: u32 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind { Start, Migrate }
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self { Kind::Start => "start", Kind::Migrate => "migrate" }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source { None, Schedule, Sample }
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self { Source::None => "none", Source::Schedule => "schedule", Source::Sample => "sample" }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub threshold_g: u32,
+    pub max_defer_min: u32,
+    pub vcpu_w: u32,
+    pub mig_wh_per_gib: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Job {
+    pub vm_id: u64,
+    pub kind: Kind,
+    /// Batch length for starts, hours (0 for migrations)
+    pub hours: u32,
+    pub energy_wh: u64,
+    /// Intensity when the job was submitted
+    pub submit_g: u32,
+    pub submit_unix: i64,
+    pub deadline_unix: i64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Done {
+    pub job: Job,
+    pub run_unix: i64,
+    /// Intensity when the job was released
+    pub run_g: u32,
+    /// Released by its deadline rather than a low-carbon window
+    pub forced: bool,
+    pub ok: bool,
+    pub avoided_mg: u64,
+}
+
+struct State {
+    schedule: Option<[u16; 24]>,
+    /// Pushed intensity and the Unix time it expires
+    sample: Option<(u32, i64)>,
+    policy: Policy,
+    jobs: [Option<Job>; MAX_JOBS],
+    history: [Option<Done>; HISTORY],
+    next_done: usize,
+    /// `clock::now_ms` of the last evaluation (0 = none yet)
+    last_ms: u64,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    schedule: None,
+    sample: None,
+    policy: Policy {
+        threshold_g: DEFAULT_THRESHOLD_G, max_defer_min: DEFAULT_MAX_DEFER_MIN,
+        vcpu_w: DEFAULT_VCPU_W, mig_wh_per_gib: DEFAULT_MIG_WH_PER_GIB,
+    },
+    jobs: [None; MAX_JOBS],
+    history: [None; HISTORY],
+    next_done: 0,
+    last_ms: 0,
+});
+
+fn signal(s: &State, now: i64) -> Option<(u32, Source)> {
+    if let Some((g, until)) = s.sample {
+        if now < until { return Some((g, Source::Sample)); }
+    }
+    let sched = s.schedule?;
+    if now <= 0 { return None; }
+    Some((sched[((now / 3600) % 24) as usize] as u32, Source::Schedule))
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub intensity_g: Option<u32>,
+    pub source: Source,
+    pub policy: Policy,
+    pub schedule: Option<[u16; 24]>,
+    /// Expiry of the pushed sample, if one is still valid
+    pub sample_until: Option<i64>,
+    pub queued: usize,
+}
+
+pub fn status(system_table: &SystemTable<Boot>) -> Status {
+    let now = crate::hv::vtime::rtc::host_unix(system_table);
+    STATE.lock(|s| {
+        let sig = signal(s, now);
+        Status {
+            intensity_g: sig.map(|(g, _)| g),
+            source: sig.map(|(_, src)| src).unwrap_or(Source::None),
+            policy: s.policy,
+            schedule: s.schedule,
+            sample_until: s.sample.filter(|&(_, until)| now < until).map(|(_, until)| until),
+            queued: s.jobs.iter().flatten().count(),
+        }
+    })
+}
+
+/// Install (`Some`) or remove (`None`) the hourly UTC schedule.
+pub fn set_schedule(hours: Option<[u16; 24]>) -> Result<(), &'static str> {
+    if let Some(h) = hours {
+        if h.iter().any(|&g| g as u32 > MAX_INTENSITY_G) { return Err("carbon: intensity out of range"); }
+    }
+    STATE.lock(|s| s.schedule = hours);
+    Ok(())
+}
+
+/// Record a measured intensity that overrides the schedule for `valid_min`
+/// minutes.
+pub fn push_sample(system_table: &SystemTable<Boot>, g: u32, valid_min: u32) -> Result<(), &'static str> {
+    if g > MAX_INTENSITY_G { return Err("carbon: intensity out of range"); }
+    if valid_min == 0 { return Err("carbon: sample validity must be non-zero"); }
+    let now = crate::hv::vtime::rtc::host_unix(system_table);
+    if now <= 0 { return Err("carbon: no wall clock"); }
+    STATE.lock(|s| s.sample = Some((g, now + valid_min as i64 * 60)));
+    CARBON_INTENSITY_G.store(g as u64, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn clear_sample() { STATE.lock(|s| s.sample = None); }
+
+pub fn policy() -> Policy { STATE.lock(|s| s.policy) }
+
+pub fn set_policy(p: Policy) -> Result<(), &'static str> {
+    if p.threshold_g > MAX_INTENSITY_G { return Err("carbon: threshold out of range"); }
+    if p.max_defer_min == 0 { return Err("carbon: max-defer must be non-zero"); }
+    STATE.lock(|s| s.policy = p);
+    Ok(())
+}
+
+/// Queue `kind` for VM `vm_id` if the current intensity is above the
+/// threshold. `Ok(None)` means there is no reason to wait and the caller
+/// should run it now; `Ok(Some(deadline))` means it was queued and will run
+/// by that Unix time at the latest.
+pub fn submit(system_table: &SystemTable<Boot>, vm_id: u64, kind: Kind, hours: u32) -> Result<Option<i64>, &'static str> {
+    let vm = crate::hv::vm::find_vm(vm_id).ok_or("carbon: no such vm")?;
+    let now = crate::hv::vtime::rtc::host_unix(system_table);
+    STATE.lock(|s| {
+        let g = match signal(s, now) { Some((g, _)) => g, None => return Ok(None) };
+        if g <= s.policy.threshold_g { return Ok(None); }
+        if s.jobs.iter().flatten().any(|j| j.vm_id == vm_id && j.kind == kind) { return Err("carbon: already queued"); }
+        let slot = s.jobs.iter_mut().find(|j| j.is_none()).ok_or("carbon: queue full")?;
+        let hours = if kind == Kind::Start { hours.max(1) } else { 0 };
+        let energy_wh = match kind {
+            Kind::Start => vm.vcpus as u64 * s.policy.vcpu_w as u64 * hours as u64,
+            Kind::Migrate => vm.memory_bytes.div_ceil(1 << 30) * s.policy.mig_wh_per_gib as u64,
+        };
+        let deadline_unix = now + s.policy.max_defer_min as i64 * 60;
+        *slot = Some(Job { vm_id, kind, hours, energy_wh, submit_g: g, submit_unix: now, deadline_unix });
+        CARBON_DEFERRED.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(deadline_unix))
+    })
+}
+
+/// Drop a queued job; `kind` `None` drops every job of the VM.
+pub fn cancel(vm_id: u64, kind: Option<Kind>) -> usize {
+    STATE.lock(|s| {
+        let mut n = 0;
+        for j in s.jobs.iter_mut() {
+            if matches!(j, Some(q) if q.vm_id == vm_id && kind.map_or(true, |k| q.kind == k)) { *j = None; n += 1; }
+        }
+        n
+    })
+}
+
+pub fn detach_vm(vm_id: u64) { let _ = cancel(vm_id, None); }
+
+/// Visit queued jobs, oldest first.
+pub fn for_each(mut f: impl FnMut(&Job)) {
+    let mut seen_before = i64::MIN;
+    let mut seen_vm = 0u64;
+    loop {
+        // Copy the next job out so `f` can run without the lock held.
+        let next = STATE.lock(|s| {
+            s.jobs.iter().flatten()
+                .filter(|j| (j.submit_unix, j.vm_id) > (seen_before, seen_vm))
+                .min_by_key(|j| (j.submit_unix, j.vm_id))
+                .copied()
+        });
+        let Some(j) = next else { break };
+        seen_before = j.submit_unix; seen_vm = j.vm_id;
+        f(&j);
+    }
+}
+
+/// Visit released jobs, most recent first.
+pub fn history(mut f: impl FnMut(&Done)) {
+    for i in 0..HISTORY {
+        let d = STATE.lock(|s| s.history[(s.next_done + HISTORY - 1 - i) % HISTORY]);
+        if let Some(d) = d { f(&d); }
+    }
+}
+
+/// Total avoided emissions so far, milligrams CO2e.
+pub fn avoided_mg() -> u64 { CARBON_AVOIDED_MG.load(Ordering::Relaxed) }
+
+/// Re-evaluate the signal and release at most one queued job. Runs at most
+/// every `INTERVAL_MS` unless `force`. Returns the released job.
+pub fn tick(system_table: &mut SystemTable<Boot>, force: bool) -> Option<Done> {
+    if crate::time::clock::hz() == 0 { return None; }
+    let now = crate::time::clock::now_ms();
+    let due = STATE.lock(|s| {
+        if s.last_ms != 0 && !force && now.saturating_sub(s.last_ms) < INTERVAL_MS { return false; }
+        s.last_ms = now;
+        true
+    });
+    if !due { return None; }
+    let now = crate::hv::vtime::rtc::host_unix(system_table);
+    let (job, g, forced) = STATE.lock(|s| {
+        let sig = signal(s, now);
+        CARBON_INTENSITY_G.store(sig.map_or(0, |(g, _)| g as u64), Ordering::Relaxed);
+        let low = match sig { Some((g, _)) => g <= s.policy.threshold_g, None => true };
+        let mut pick: Option<usize> = None;
+        for (i, j) in s.jobs.iter().enumerate() {
+            let Some(j) = j else { continue };
+            if !low && now < j.deadline_unix { continue; }
+            if pick.map_or(true, |p| s.jobs[p].map_or(true, |q| j.submit_unix < q.submit_unix)) { pick = Some(i); }
+        }
+        let i = pick?;
+        let job = s.jobs[i].take()?;
+        // Without a signal the job is released as-is, with nothing avoided.
+        Some((job, sig.map_or(job.submit_g, |(g, _)| g), !low))
+    })?;
+
+    let ok = match job.kind {
+        Kind::Start => crate::hv::run::start(system_table, job.vm_id).is_ok(),
+        Kind::Migrate => crate::migrate::start_tracking_by_id(system_table, job.vm_id),
+    };
+    let avoided_mg = if ok { job.energy_wh.saturating_mul(job.submit_g.saturating_sub(g) as u64) } else { 0 };
+    let done = Done { job, run_unix: now, run_g: g, forced, ok, avoided_mg };
+    STATE.lock(|s| { s.history[s.next_done] = Some(done); s.next_done = (s.next_done + 1) % HISTORY; });
+    CARBON_RELEASED.fetch_add(1, Ordering::Relaxed);
+    if forced { CARBON_FORCED.fetch_add(1, Ordering::Relaxed); }
+    CARBON_AVOIDED_MG.fetch_add(avoided_mg, Ordering::Relaxed);
+
+    let mut msg = [0u8; 112]; let mut n = 0;
+    for &b in job.kind.name().as_bytes() { msg[n] = b; n += 1; }
+    for &b in b" vm=" { msg[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(job.vm_id, &mut msg[n..]);
+    for &b in if forced { &b" deadline g="[..] } else { &b" released g="[..] } { msg[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(g as u64, &mut msg[n..]);
+    for &b in b" submit_g=" { msg[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(job.submit_g as u64, &mut msg[n..]);
+    for &b in b" avoided_mg=" { msg[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(avoided_mg, &mut msg[n..]);
+    let text = core::str::from_utf8(&msg[..n]).unwrap_or("job released");
+    if ok { crate::obs::log::info(system_table, "carbon", text) } else { crate::obs::log::warn(system_table, "carbon", text) }
+    Some(done)
+}
@@ -0,0 +1,288 @@
+#![allow(dead_code)]
+
+//! CNI-style network attachments for microVMs.
+//!
+//! A network is the attachment definition a CNI runtime would hand the
+//! `bridge` plugin with `host-local` IPAM: a bridge name, an IPv4 subnet,
+//! the range addresses are given out from, a gateway and routes. Bridges
+//! are segments of the soft switch, told apart by VLAN: every network on a
+//! bridge shares its VLAN, and no two bridges share one. On VLAN 0 the
+//! NICs are trunk ports, as bridge-mode NICs are by default.
+//!
+//! ADD gives a VM an interface on a network. The address is the lowest free
+//! one in the range (the gateway is never handed out) and the MAC is
+//! 52:54 followed by it unless one is given. Nothing is plugged in yet:
+//! `plumb`, run when the VM starts (or at once if it is running), adds a
+//! bridge-mode NIC on the network's VLAN for each attachment. The first
+//! attachment of a Linux guest is also put on its kernel command line as
+//! `ip=`, which is the only address assignment there is; the rest are for
+//! the runtime to configure from the result ADD returns. DEL unplugs the
+//! NIC and frees the address.
+
+use core::fmt::Write as _;
+
+use crate::hv::vdev::{net, switch};
+use crate::util::format::BufWriter;
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_NETWORKS: usize = 8;
+pub const MAX_ATTACHMENTS: usize = net::MAX_NICS;
+pub const MAX_ROUTES: usize = 4;
+pub const NAME_MAX: usize = 32;
+/// Longest Linux interface name
+pub const IFNAME_MAX: usize = 15;
+/// Result format version reported to runtimes
+pub const CNI_VERSION: &str = "1.0.0";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub dst: [u8; 4],
+    pub prefix: u8,
+    /// Next hop; `None` for the network's gateway
+    pub gw: Option<[u8; 4]>,
+}
+
+/// An attachment definition.
+#[derive(Clone, Copy, Debug)]
+pub struct Network {
+    name: [u8; NAME_MAX],
+    name_len: u8,
+    bridge: [u8; IFNAME_MAX],
+    bridge_len: u8,
+    pub vlan: u16,
+    pub subnet: [u8; 4],
+    pub prefix: u8,
+    /// First and last address given out (0.0.0.0 = from the subnet)
+    pub range_start: [u8; 4],
+    pub range_end: [u8; 4],
+    /// 0.0.0.0 = the subnet's first address
+    pub gateway: [u8; 4],
+    routes: [Route; MAX_ROUTES],
+    nroutes: u8,
+}
+
+impl Network {
+    /// Definition with no range, gateway or routes; `define` fills in the defaults.
+    pub fn new(name: &str, bridge: &str, vlan: u16, subnet: [u8; 4], prefix: u8) -> Result<Network, &'static str> {
+        if name.is_empty() || name.len() > NAME_MAX || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.') {
+            return Err("cni: name must be 1..32 of [A-Za-z0-9._-]");
+        }
+        if bridge.is_empty() || bridge.len() > IFNAME_MAX || !bridge.bytes().all(|b| b.is_ascii_graphic() && b != b'/') {
+            return Err("cni: bridge must be an interface name of 1..15 characters");
+        }
+        let mut n = Network {
+            name: [0; NAME_MAX], name_len: name.len() as u8, bridge: [0; IFNAME_MAX], bridge_len: bridge.len() as u8,
+            vlan, subnet, prefix, range_start: [0; 4], range_end: [0; 4], gateway: [0; 4],
+            routes: [Route { dst: [0; 4], prefix: 0, gw: None }; MAX_ROUTES], nroutes: 0,
+        };
+        n.name[..name.len()].copy_from_slice(name.as_bytes());
+        n.bridge[..bridge.len()].copy_from_slice(bridge.as_bytes());
+        Ok(n)
+    }
+
+    pub fn add_route(&mut self, r: Route) -> Result<(), &'static str> {
+        if r.prefix > 32 { return Err("cni: route prefix must be 0..32"); }
+        *self.routes.get_mut(self.nroutes as usize).ok_or("cni: at most 4 routes")? = r;
+        self.nroutes += 1;
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("?") }
+    pub fn bridge(&self) -> &str { core::str::from_utf8(&self.bridge[..self.bridge_len as usize]).unwrap_or("?") }
+    pub fn routes(&self) -> &[Route] { &self.routes[..self.nroutes as usize] }
+    pub fn netmask(&self) -> [u8; 4] { mask(self.prefix).to_be_bytes() }
+
+    fn contains(&self, ip: [u8; 4]) -> bool { u32::from_be_bytes(ip) & mask(self.prefix) == u32::from_be_bytes(self.subnet) }
+}
+
+/// An interface a VM has on a network.
+#[derive(Clone, Copy, Debug)]
+pub struct Attachment {
+    pub vm_id: u64,
+    /// Index into the network table
+    net: usize,
+    if_name: [u8; IFNAME_MAX],
+    if_len: u8,
+    pub mac: [u8; 6],
+    pub ip: [u8; 4],
+    /// NIC index once plumbed
+    pub nic: Option<usize>,
+}
+
+impl Attachment {
+    pub fn if_name(&self) -> &str { core::str::from_utf8(&self.if_name[..self.if_len as usize]).unwrap_or("?") }
+}
+
+struct State {
+    nets: [Option<Network>; MAX_NETWORKS],
+    atts: [Option<Attachment>; MAX_ATTACHMENTS],
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State { nets: [None; MAX_NETWORKS], atts: [None; MAX_ATTACHMENTS] });
+
+fn mask(prefix: u8) -> u32 { if prefix == 0 { 0 } else { u32::MAX << (32 - prefix as u32) } }
+
+/// Parse `a.b.c.d/n`.
+pub fn parse_cidr(s: &str) -> Option<([u8; 4], u8)> {
+    let (ip, prefix) = s.split_once('/')?;
+    let prefix = prefix.parse::<u8>().ok().filter(|&p| p <= 32)?;
+    Some((net::parse_ipv4(ip)?, prefix))
+}
+
+/// Add a network, or replace one of the same name that nothing is attached to.
+pub fn define(mut n: Network) -> Result<(), &'static str> {
+    if n.vlan > switch::VLAN_MAX { return Err("cni: VLAN must be 0..4094"); }
+    if !(8..=30).contains(&n.prefix) { return Err("cni: subnet prefix must be 8..30"); }
+    let base = u32::from_be_bytes(n.subnet);
+    if base & !mask(n.prefix) != 0 { return Err("cni: subnet has host bits set"); }
+    let last = base | !mask(n.prefix);
+    if n.gateway == [0; 4] { n.gateway = (base + 1).to_be_bytes(); }
+    if n.range_start == [0; 4] { n.range_start = (base + 1).to_be_bytes(); }
+    if n.range_end == [0; 4] { n.range_end = (last - 1).to_be_bytes(); }
+    let (lo, hi) = (u32::from_be_bytes(n.range_start), u32::from_be_bytes(n.range_end));
+    if !n.contains(n.gateway) || !n.contains(n.range_start) || !n.contains(n.range_end) { return Err("cni: gateway and range must be inside the subnet"); }
+    if lo > hi || lo == base || hi == last { return Err("cni: range must be ordered and leave out the network and broadcast addresses"); }
+    STATE.lock(|s| {
+        for (i, m) in s.nets.iter().enumerate() {
+            let Some(m) = m else { continue };
+            if m.name() == n.name() {
+                if s.atts.iter().flatten().any(|a| a.net == i) { return Err("cni: network has attachments"); }
+                continue;
+            }
+            if m.bridge() == n.bridge() && m.vlan != n.vlan { return Err("cni: networks on a bridge must share its VLAN"); }
+            if m.bridge() != n.bridge() && m.vlan == n.vlan { return Err("cni: VLAN belongs to another bridge"); }
+        }
+        let i = match s.nets.iter().position(|m| matches!(m, Some(m) if m.name() == n.name())) {
+            Some(i) => i,
+            None => s.nets.iter().position(|m| m.is_none()).ok_or("cni: too many networks")?,
+        };
+        s.nets[i] = Some(n);
+        Ok(())
+    })
+}
+
+/// Remove a network nothing is attached to.
+pub fn undefine(name: &str) -> Result<(), &'static str> {
+    STATE.lock(|s| {
+        let i = s.nets.iter().position(|m| matches!(m, Some(m) if m.name() == name)).ok_or("cni: no such network")?;
+        if s.atts.iter().flatten().any(|a| a.net == i) { return Err("cni: network has attachments"); }
+        s.nets[i] = None;
+        Ok(())
+    })
+}
+
+pub fn network(name: &str) -> Option<Network> { STATE.lock(|s| s.nets.iter().flatten().find(|m| m.name() == name).copied()) }
+
+pub fn for_each_network(mut f: impl FnMut(&Network)) {
+    let snap = STATE.lock(|s| s.nets);
+    for n in snap.iter().flatten() { f(n); }
+}
+
+/// Iterate `vm_id`'s attachments (every VM's for `None`) with their networks.
+pub fn for_each(vm_id: Option<u64>, mut f: impl FnMut(&Attachment, &Network)) {
+    let (nets, atts) = STATE.lock(|s| (s.nets, s.atts));
+    for a in atts.iter().flatten().filter(|a| vm_id.map_or(true, |v| v == a.vm_id)) {
+        if let Some(n) = &nets[a.net] { f(a, n); }
+    }
+}
+
+/// CNI ADD: attach `vm_id` to `network` as `if_name`. Repeating it returns
+/// the same attachment.
+pub fn add(vm_id: u64, network: &str, if_name: &str, mac: Option<[u8; 6]>) -> Result<(Attachment, Network), &'static str> {
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err("cni: no such vm"); }
+    if if_name.is_empty() || if_name.len() > IFNAME_MAX || !if_name.bytes().all(|b| b.is_ascii_graphic() && b != b'/') {
+        return Err("cni: interface name must be 1..15 characters");
+    }
+    let r = STATE.lock(|s| {
+        let ni = s.nets.iter().position(|m| matches!(m, Some(m) if m.name() == network)).ok_or("cni: no such network")?;
+        let n = s.nets[ni].ok_or("cni: no such network")?;
+        if let Some(a) = s.atts.iter().flatten().find(|a| a.vm_id == vm_id && a.if_name() == if_name) {
+            return if a.net == ni && mac.map_or(true, |m| m == a.mac) { Ok((*a, n, false)) } else { Err("cni: interface name in use on the vm") };
+        }
+        let slot = s.atts.iter().position(|a| a.is_none()).ok_or("cni: too many attachments")?;
+        let gw = u32::from_be_bytes(n.gateway);
+        let used = |ip: u32| ip == gw || s.atts.iter().flatten().any(|a| a.net == ni && u32::from_be_bytes(a.ip) == ip);
+        let ip = (u32::from_be_bytes(n.range_start)..=u32::from_be_bytes(n.range_end)).find(|&ip| !used(ip)).ok_or("cni: address range exhausted")?.to_be_bytes();
+        let mac = mac.unwrap_or([0x52, 0x54, ip[0], ip[1], ip[2], ip[3]]);
+        if s.atts.iter().flatten().any(|a| a.mac == mac) { return Err("cni: MAC in use by another attachment"); }
+        let mut a = Attachment { vm_id, net: ni, if_name: [0; IFNAME_MAX], if_len: if_name.len() as u8, mac, ip, nic: None };
+        a.if_name[..if_name.len()].copy_from_slice(if_name.as_bytes());
+        s.atts[slot] = Some(a);
+        Ok((a, n, true))
+    })?;
+    let (a, n, fresh) = r;
+    if fresh {
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::CNI_ADDS).inc();
+        if crate::hv::run::active(vm_id) != 0 {
+            if let Err(e) = plumb(vm_id) { let _ = del(vm_id, network, if_name); return Err(e); }
+        }
+    }
+    let a = STATE.lock(|s| s.atts.iter().flatten().find(|x| x.vm_id == vm_id && x.if_name() == if_name).copied()).unwrap_or(a);
+    Ok((a, n))
+}
+
+/// CNI CHECK: the attachment, if it exists and its NIC is still there.
+pub fn check(vm_id: u64, network: &str, if_name: &str) -> Result<(Attachment, Network), &'static str> {
+    let mut found = None;
+    for_each(Some(vm_id), |a, n| if a.if_name() == if_name && n.name() == network { found = Some((*a, *n)); });
+    let (a, n) = found.ok_or("cni: no such attachment")?;
+    if let Some(i) = a.nic {
+        if !net::get(i).is_some_and(|x| x.vm_id == vm_id && x.mac == a.mac) { return Err("cni: NIC of the attachment is gone"); }
+    }
+    Ok((a, n))
+}
+
+/// CNI DEL: unplug and forget the attachment; nothing to delete is fine.
+pub fn del(vm_id: u64, network: &str, if_name: &str) -> Result<(), &'static str> {
+    let gone = STATE.lock(|s| {
+        let nets = s.nets;
+        let slot = s.atts.iter_mut().find(|a| matches!(a, Some(a) if a.vm_id == vm_id && a.if_name() == if_name
+            && nets[a.net].is_some_and(|n| n.name() == network)))?;
+        slot.take()
+    });
+    if let Some(i) = gone.and_then(|a| a.nic) { let _ = net::remove(vm_id, i); }
+    Ok(())
+}
+
+/// Plug in every attachment of `vm_id` that has no NIC and, before the VM
+/// runs, put the first one's address on a Linux guest's command line.
+/// Returns the NICs added.
+pub fn plumb(vm_id: u64) -> Result<u32, &'static str> {
+    let mut todo = [None; MAX_ATTACHMENTS];
+    let mut first = None;
+    STATE.lock(|s| {
+        for (k, a) in s.atts.iter().enumerate() {
+            let Some(a) = a else { continue };
+            if a.vm_id != vm_id { continue; }
+            let Some(n) = s.nets[a.net] else { continue };
+            if first.is_none() { first = Some((*a, n)); }
+            let live = a.nic.is_some_and(|i| net::get(i).is_some_and(|x| x.vm_id == vm_id && x.mac == a.mac));
+            if !live { todo[k] = Some((*a, n)); }
+        }
+    });
+    let mut added = 0;
+    for (k, t) in todo.iter().enumerate() {
+        let Some((a, n)) = t else { continue };
+        let (idx, _) = net::add(vm_id, a.mac, net::Mode::Bridge, a.ip)?;
+        let port = switch::port(switch::Port::Nic(idx)).ok_or("cni: NIC has no switch port")?;
+        switch::set_port(switch::Port::Nic(idx), switch::PortCfg { vlan: n.vlan, ..port.cfg })?;
+        STATE.lock(|s| if let Some(x) = s.atts[k].as_mut() { x.nic = Some(idx); });
+        added += 1;
+    }
+    let Some((a, n)) = first else { return Ok(added) };
+    let linux = crate::hv::loader::find_image(vm_id).is_some_and(|i| i.kind == crate::hv::loader::ImageKind::Linux);
+    if linux && crate::hv::run::active(vm_id) == 0 {
+        let mut b = [0u8; 96];
+        let mut w = BufWriter::new(&mut b);
+        let (ip, gw, m) = (a.ip, n.gateway, n.netmask());
+        let _ = write!(w, "ip={}.{}.{}.{}::{}.{}.{}.{}:{}.{}.{}.{}::{}:off",
+            ip[0], ip[1], ip[2], ip[3], gw[0], gw[1], gw[2], gw[3], m[0], m[1], m[2], m[3], a.if_name());
+        crate::hv::loader::append_cmdline(vm_id, w.as_str())?;
+    }
+    Ok(added)
+}
+
+/// Drop `vm_id`'s attachments (its NICs go with the VM).
+pub fn forget_vm(vm_id: u64) {
+    STATE.lock(|s| for a in s.atts.iter_mut() { if matches!(a, Some(x) if x.vm_id == vm_id) { *a = None; } });
+}
@@ -0,0 +1,188 @@
+#![allow(dead_code)]
+
+//! Confidential VMs (AMD SEV/SEV-ES/SEV-SNP, Intel TDX).
+//!
+//! A VM is marked confidential when it is created. Marking hands it a
+//! hardware key slot: an ASID from the host's SEV range (SEV-ES and SNP
+//! guests come from the low part below `min_sev_asid`, plain SEV from the
+//! rest) or a TDX private KeyID. Slots are a host resource like vCPUs, so
+//! running out is reported as an admission denial.
+//!
+//! Launching encrypted guests needs the PSP or the TDX module, which are not
+//! driven yet. What exists is enough for policy: `readiness` says what the
+//! host could do, and `allows` keeps host-side features that need to read
+//! or share guest plaintext away from confidential VMs.
+
+use crate::arch::x86::cc;
+use crate::hv::admission::{AdmissionError, Resource};
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_GUESTS: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Kind { #[default] None, Sev, SevEs, SevSnp, Tdx }
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self { Kind::None => "none", Kind::Sev => "sev", Kind::SevEs => "sev-es", Kind::SevSnp => "sev-snp", Kind::Tdx => "tdx" }
+    }
+
+    pub fn parse(s: &str) -> Option<Kind> {
+        match s {
+            "none" => Some(Kind::None),
+            "sev" => Some(Kind::Sev),
+            "sev-es" => Some(Kind::SevEs),
+            "sev-snp" | "snp" => Some(Kind::SevSnp),
+            "tdx" => Some(Kind::Tdx),
+            _ => None,
+        }
+    }
+
+    fn all() -> [Kind; 4] { [Kind::Sev, Kind::SevEs, Kind::SevSnp, Kind::Tdx] }
+}
+
+/// What the host can do for one kind of confidential guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Readiness {
+    /// The CPU lacks the feature
+    Unsupported,
+    /// The CPU has it but firmware left it off
+    Disabled,
+    /// Key slots can be assigned; launching still needs PSP/TDX-module support
+    Plumbed,
+}
+
+impl Readiness {
+    pub fn name(self) -> &'static str {
+        match self { Readiness::Unsupported => "unsupported", Readiness::Disabled => "disabled", Readiness::Plumbed => "plumbed" }
+    }
+}
+
+/// Host features a policy may want to keep away from a confidential VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// Same-page merging compares guest pages
+    PageDedup,
+    /// Live migration copies guest memory in the clear
+    Migrate,
+    /// MMIO tracing decodes guest instructions
+    MmioTrace,
+    /// The GDB stub and core dumps read guest registers and memory
+    Debug,
+}
+
+#[derive(Clone, Copy)]
+struct Guest { vm_id: u64, kind: Kind, key: u32 }
+
+struct State {
+    caps: Option<cc::Caps>,
+    guests: [Option<Guest>; MAX_GUESTS],
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State { caps: None, guests: [None; MAX_GUESTS] });
+
+/// Host capabilities, probed once.
+pub fn caps() -> cc::Caps {
+    if let Some(c) = STATE.lock(|s| s.caps) { return c; }
+    let c = cc::detect();
+    STATE.lock(|s| s.caps = Some(c));
+    c
+}
+
+/// Record each confidential-guest kind with `hv::features`.
+pub fn register_features() {
+    use crate::hv::features::{register, Status};
+    for k in [Kind::Sev, Kind::SevEs, Kind::SevSnp, Kind::Tdx] {
+        match readiness(k) {
+            Readiness::Unsupported => register("confidential", k.name(), Status::Absent, "not supported by the CPU"),
+            Readiness::Disabled => register("confidential", k.name(), Status::Disabled, "not enabled in firmware"),
+            Readiness::Plumbed => register("confidential", k.name(), Status::Available, "key slots only"),
+        }
+    }
+}
+
+pub fn readiness(kind: Kind) -> Readiness {
+    let c = caps();
+    let (has, on) = match kind {
+        Kind::None => (true, true),
+        Kind::Sev => (c.sev.sev, c.sev.enabled),
+        Kind::SevEs => (c.sev.sev_es, c.sev.enabled),
+        Kind::SevSnp => (c.sev.sev_snp, c.sev.enabled),
+        Kind::Tdx => (c.tdx.seamrr && c.tdx.tme, c.tdx.enabled && c.tdx.keyids != 0),
+    };
+    match (has, on) {
+        (false, _) => Readiness::Unsupported,
+        (true, false) => Readiness::Disabled,
+        (true, true) => Readiness::Plumbed,
+    }
+}
+
+/// Key slots (ASIDs or KeyIDs) usable by `kind` on this host, as an inclusive range.
+pub fn key_range(kind: Kind) -> Option<(u32, u32)> {
+    if readiness(kind) != Readiness::Plumbed { return None; }
+    let c = caps();
+    let r = match kind {
+        Kind::None => return None,
+        Kind::Sev => (c.sev.min_sev_asid.max(1), c.sev.max_asid),
+        Kind::SevEs | Kind::SevSnp => (1, c.sev.min_sev_asid.saturating_sub(1)),
+        Kind::Tdx => (c.tdx.keyid_base, c.tdx.keyid_base + c.tdx.keyids - 1),
+    };
+    if r.0 > r.1 { None } else { Some(r) }
+}
+
+fn same_pool(a: Kind, b: Kind) -> bool { (a == Kind::Tdx) == (b == Kind::Tdx) }
+
+/// Mark `vm_id` as a `kind` guest and give it a key slot. Called while the
+/// VM is being created; a host without the feature or without a free slot
+/// denies it. Returns the ASID or KeyID.
+pub fn assign(vm_id: u64, kind: Kind) -> Result<u32, AdmissionError> {
+    let deny = |available: u64, limit: u64| AdmissionError { resource: Resource::GuestKeys, requested: 1, available, limit };
+    let Some((lo, hi)) = key_range(kind) else { return Err(deny(0, 0)) };
+    let limit = (hi - lo + 1) as u64;
+    STATE.lock(|s| {
+        let used = |k: u32| s.guests.iter().flatten().any(|g| same_pool(g.kind, kind) && g.key == k);
+        let key = (lo..=hi).find(|&k| !used(k)).ok_or_else(|| deny(0, limit))?;
+        let slot = s.guests.iter_mut().find(|g| g.is_none()).ok_or_else(|| deny(0, limit))?;
+        *slot = Some(Guest { vm_id, kind, key });
+        Ok(key)
+    })
+}
+
+/// Return `vm_id`'s key slot. Before a slot is reused the hardware key must
+/// be retired (SEV DEACTIVATE+DF_FLUSH, TDH.PHYMEM.CACHE.WB); with no guest
+/// ever launched there is nothing cached under it.
+pub fn release(vm_id: u64) {
+    STATE.lock(|s| { for g in s.guests.iter_mut() { if matches!(g, Some(x) if x.vm_id == vm_id) { *g = None; } } });
+}
+
+pub fn kind_of(vm_id: u64) -> Kind {
+    STATE.lock(|s| s.guests.iter().flatten().find(|g| g.vm_id == vm_id).map(|g| g.kind)).unwrap_or(Kind::None)
+}
+
+pub fn is_confidential(vm_id: u64) -> bool { kind_of(vm_id) != Kind::None }
+
+/// Whether host feature `f` may be used on `vm_id`.
+pub fn allows(vm_id: u64, f: Feature) -> bool {
+    match f {
+        Feature::PageDedup | Feature::Migrate | Feature::MmioTrace | Feature::Debug => !is_confidential(vm_id),
+    }
+}
+
+/// Per-kind host readiness with the key slots still free.
+#[derive(Clone, Copy, Debug)]
+pub struct HostInfo { pub kind: Kind, pub readiness: Readiness, pub keys: Option<(u32, u32)>, pub free: u32 }
+
+pub fn for_each_kind(mut f: impl FnMut(&HostInfo)) {
+    for kind in Kind::all() {
+        let keys = key_range(kind);
+        let used = STATE.lock(|s| s.guests.iter().flatten().filter(|g| match keys { Some((lo, hi)) => same_pool(g.kind, kind) && (lo..=hi).contains(&g.key), None => false }).count() as u32);
+        let free = keys.map(|(lo, hi)| hi - lo + 1 - used).unwrap_or(0);
+        f(&HostInfo { kind, readiness: readiness(kind), keys, free });
+    }
+}
+
+/// Iterate confidential VMs as (vm_id, kind, key slot).
+pub fn for_each_guest(mut f: impl FnMut(u64, Kind, u32)) {
+    let guests = STATE.lock(|s| s.guests);
+    for g in guests.iter().flatten() { f(g.vm_id, g.kind, g.key); }
+}
@@ -0,0 +1,175 @@
+#![allow(dead_code)]
+
+//! Guest crash dumps in ELF core format.
+//!
+//! `write` pauses the VM, then writes an ELF64 `ET_CORE` file to the ESP:
+//! one `PT_NOTE` segment with an `NT_PRSTATUS` note per vCPU (registers in
+//! the Linux x86-64 `user_regs_struct` layout, `pr_pid` = vCPU index + 1),
+//! followed by one `PT_LOAD` segment per region of the guest memory map.
+//! Segments carry the guest-physical address in `p_paddr` and zero in
+//! `p_vaddr`, the layout of QEMU's `dump-guest-memory`, so `crash` and
+//! similar tools read it directly. The VM resumes once the file is written.
+
+use uefi::prelude::Boot;
+use uefi::proto::media::file::{File, FileAttribute, FileMode, RegularFile};
+use uefi::table::SystemTable;
+use uefi::CStr16;
+
+use crate::hv::run;
+
+const PATH_MAX: usize = 128;
+const EHDR_LEN: usize = 64;
+const PHDR_LEN: usize = 56;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+/// Size of `struct elf_prstatus` on x86-64
+const PRSTATUS_LEN: usize = 336;
+/// Offset of `pr_reg` in it
+const PR_REG: usize = 112;
+/// Note header, "CORE\0" padded to 8, descriptor
+const NOTE_LEN: usize = 12 + 8 + PRSTATUS_LEN;
+/// At most this many regions are dumped
+const MAX_REGIONS: usize = 8;
+/// Each segment's data starts page-aligned
+const DATA_ALIGN: u64 = 4096;
+/// Guest memory is written this much at a time
+const CHUNK: usize = 1 << 20;
+/// How long to wait for the vCPUs to park
+const PAUSE_TIMEOUT_US: u64 = 200_000;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Summary {
+    pub vcpus: u32,
+    pub regions: u32,
+    /// Guest memory written
+    pub bytes: u64,
+    /// File size
+    pub file_bytes: u64,
+}
+
+fn put16(b: &mut [u8], off: usize, v: u16) { b[off..off + 2].copy_from_slice(&v.to_le_bytes()); }
+fn put32(b: &mut [u8], off: usize, v: u32) { b[off..off + 4].copy_from_slice(&v.to_le_bytes()); }
+fn put64(b: &mut [u8], off: usize, v: u64) { b[off..off + 8].copy_from_slice(&v.to_le_bytes()); }
+
+fn create_esp_file(system_table: &SystemTable<Boot>, path: &str) -> Result<RegularFile, &'static str> {
+    let mut pbuf = [0u8; PATH_MAX];
+    if path.is_empty() || path.len() > pbuf.len() { return Err("coredump: invalid path"); }
+    for (i, &c) in path.as_bytes().iter().enumerate() { pbuf[i] = if c == b'/' { b'\\' } else { c }; }
+    let p = core::str::from_utf8(&pbuf[..path.len()]).map_err(|_| "coredump: invalid path")?;
+    let mut name_buf = [0u16; PATH_MAX + 2];
+    let name = CStr16::from_str_with_buf(p, &mut name_buf).map_err(|_| "coredump: invalid path")?;
+    let bs = system_table.boot_services();
+    let mut fs = bs.get_image_file_system(bs.image_handle()).map_err(|_| "coredump: ESP not accessible")?;
+    let mut root = fs.open_volume().map_err(|_| "coredump: open volume failed")?;
+    // A smaller dump must not keep the tail of an older one.
+    if let Some(old) = root.open(name, FileMode::ReadWrite, FileAttribute::empty()).ok().and_then(|h| h.into_regular_file()) { let _ = old.delete(); }
+    let h = root.open(name, FileMode::CreateReadWrite, FileAttribute::empty()).map_err(|_| "coredump: cannot create file")?;
+    h.into_regular_file().ok_or("coredump: not a regular file")
+}
+
+/// `NT_PRSTATUS` note for one parked vCPU.
+fn prstatus(out: &mut [u8], vcpu: u32, d: &run::DebugState) {
+    put32(out, 0, 5);
+    put32(out, 4, PRSTATUS_LEN as u32);
+    put32(out, 8, NT_PRSTATUS);
+    out[12..17].copy_from_slice(b"CORE\0");
+    let p = &mut out[20..20 + PRSTATUS_LEN];
+    // pr_cursig: SIGSTOP
+    put16(p, 12, 19);
+    put32(p, 32, vcpu + 1);
+    let g = &d.regs.gpr;
+    // r15 r14 r13 r12 rbp rbx r11 r10 r9 r8 rax rcx rdx rsi rdi
+    let gprs = [g[15], g[14], g[13], g[12], g[5], g[3], g[11], g[10], g[9], g[8], g[0], g[1], g[2], g[6], g[7]];
+    let [cs, ss, ds, es, fs, gs] = d.seg.map(|s| s as u64);
+    // orig_rax rip cs eflags rsp ss fs_base gs_base ds es fs gs
+    let rest = [u64::MAX, d.regs.rip, cs, d.regs.rflags, g[4], ss, d.fs_base, d.gs_base, ds, es, fs, gs];
+    for (k, v) in gprs.iter().chain(rest.iter()).enumerate() { put64(p, PR_REG + 8 * k, *v); }
+}
+
+/// Pause `vm_id` and write its core to `path` on the ESP.
+pub fn write(system_table: &SystemTable<Boot>, vm_id: u64, path: &str) -> Result<Summary, &'static str> {
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err("coredump: no such vm"); }
+    if !crate::hv::confidential::allows(vm_id, crate::hv::confidential::Feature::Debug) { return Err("coredump: not allowed on a confidential VM"); }
+    let img = crate::hv::loader::find_image(vm_id).ok_or("coredump: no image loaded")?;
+    let running = run::active(vm_id) != 0;
+    if running && run::pause(system_table, vm_id, PAUSE_TIMEOUT_US) != 0 {
+        run::unpause(vm_id);
+        return Err("coredump: vCPUs did not pause");
+    }
+    crate::hv::microvm::privatize(vm_id);
+    let res = dump(system_table, vm_id, img.ram_host, img.ram_bytes, path);
+    if running { run::unpause(vm_id); }
+    res
+}
+
+fn dump(system_table: &SystemTable<Boot>, vm_id: u64, ram_host: u64, ram_bytes: u64, path: &str) -> Result<Summary, &'static str> {
+    let mut regions = [(0u64, 0u64); MAX_REGIONS];
+    let mut nr = 0;
+    crate::hv::loader::memory_map(vm_id, |gpa, len, _| {
+        let len = len.min(ram_bytes.saturating_sub(gpa));
+        if nr < MAX_REGIONS && len != 0 { regions[nr] = (gpa, len); nr += 1; }
+    })?;
+    let mut head = [0u8; EHDR_LEN + PHDR_LEN * (1 + MAX_REGIONS) + NOTE_LEN * run::MAX_VCPUS];
+    let notes_off = EHDR_LEN + PHDR_LEN * (1 + nr);
+    let mut nv = 0;
+    run::for_each(vm_id, |i, r| {
+        let Some(d) = run::debug_state(i).filter(|d| d.parked.is_some()) else { return };
+        prstatus(&mut head[notes_off + NOTE_LEN * nv..], r.vcpu, &d);
+        nv += 1;
+    });
+    let notes_len = NOTE_LEN * nv;
+    let head_len = notes_off + notes_len;
+    let align = |x: u64| (x + DATA_ALIGN - 1) & !(DATA_ALIGN - 1);
+    let h = &mut head;
+    h[..4].copy_from_slice(b"\x7fELF");
+    h[4] = 2; // ELFCLASS64
+    h[5] = 1; // little-endian
+    h[6] = 1; // EV_CURRENT
+    put16(h, 16, 4); // ET_CORE
+    put16(h, 18, 62); // EM_X86_64
+    put32(h, 20, 1);
+    put64(h, 32, EHDR_LEN as u64); // e_phoff
+    put16(h, 52, EHDR_LEN as u16);
+    put16(h, 54, PHDR_LEN as u16);
+    put16(h, 56, (1 + nr) as u16);
+    let ph = EHDR_LEN;
+    put32(h, ph, PT_NOTE);
+    put64(h, ph + 8, notes_off as u64);
+    put64(h, ph + 32, notes_len as u64);
+    put64(h, ph + 48, 4);
+    let mut off = head_len as u64;
+    for (k, &(gpa, len)) in regions[..nr].iter().enumerate() {
+        let ph = EHDR_LEN + PHDR_LEN * (1 + k);
+        off = align(off);
+        put32(h, ph, PT_LOAD);
+        put32(h, ph + 4, 0x7); // RWX
+        put64(h, ph + 8, off);
+        put64(h, ph + 24, gpa); // p_paddr
+        put64(h, ph + 32, len);
+        put64(h, ph + 40, len);
+        put64(h, ph + 48, DATA_ALIGN);
+        off += len;
+    }
+
+    let mut f = create_esp_file(system_table, path)?;
+    f.write(&head[..head_len]).map_err(|_| "coredump: write failed")?;
+    let pad = [0u8; DATA_ALIGN as usize];
+    let mut at = head_len as u64;
+    let mut bytes = 0u64;
+    for &(gpa, len) in regions[..nr].iter() {
+        f.write(&pad[..(align(at) - at) as usize]).map_err(|_| "coredump: write failed")?;
+        at = align(at) + len;
+        let mut done = 0u64;
+        while done < len {
+            let take = ((len - done) as usize).min(CHUNK);
+            // Guest RAM is one host extent from `ram_host`; every vCPU is parked.
+            let src = unsafe { core::slice::from_raw_parts((ram_host + gpa + done) as *const u8, take) };
+            f.write(src).map_err(|_| "coredump: write failed")?;
+            done += take as u64;
+        }
+        bytes += len;
+    }
+    f.flush().map_err(|_| "coredump: flush failed")?;
+    Ok(Summary { vcpus: nv as u32, regions: nr as u32, bytes, file_bytes: off })
+}
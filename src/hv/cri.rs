@@ -0,0 +1,478 @@
+#![allow(dead_code)]
+
+//! Kubernetes Container Runtime Interface semantics: every pod is a microVM.
+//!
+//! A CRI shim on the node forwards kubelet's RuntimeService calls to the
+//! management API, which maps them here. RunPodSandbox creates a VM, gives
+//! it a vsock device for the guest agent and, when a CNI network is named,
+//! an `eth0` attachment on it, then loads the pod kernel and starts it. The
+//! sandbox id is the VM id. Containers are processes the agent runs in that
+//! guest: CreateContainer only records the command, StartContainer sends it
+//! as an agent `EXEC`, and StopContainer signals it with `KILL`. The host
+//! keeps no images; `image` is reported back as given, and the pod kernel's
+//! root filesystem has to provide whatever the command runs.
+//!
+//! The agent hands an `EXEC` payload to `sh -c`. A container's working
+//! directory, environment, command and arguments are therefore joined into
+//! one shell line of single-quoted words, `cd <dir> && exec env <k=v>..
+//! <argv>..`, which has to fit in one agent message. `exec` makes the shell
+//! become the process, so signals reach it directly.
+//!
+//! `pump` collects output and exit statuses. The newest `LOG_TAIL` bytes of
+//! output are kept per container. A running container whose sandbox
+//! stopped, or whose agent connection closed, is exited with `UNKNOWN_EXIT`.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::cni;
+use crate::hv::vdev::vsock::{self, MAX_MSG};
+use crate::util::spinlock::SpinLock;
+
+/// One sandbox per VM, and one vsock device per VM
+pub const MAX_SANDBOXES: usize = vsock::MAX_VSOCKS;
+pub const MAX_CONTAINERS: usize = 32;
+/// Longest pod or container name, namespace or uid (a DNS label)
+pub const META_MAX: usize = 63;
+pub const IMAGE_MAX: usize = 128;
+/// Output bytes kept per container
+pub const LOG_TAIL: usize = 512;
+pub const DEFAULT_KERNEL: &str = "pod.bzimage";
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0";
+pub const DEFAULT_MEMORY_MIB: u64 = 256;
+/// How long StartContainer waits for a fresh guest's agent to listen
+pub const AGENT_WAIT_MS: u32 = 10_000;
+/// How long a KILL with SIGKILL has to be answered
+const KILL_WAIT_MS: u32 = 1000;
+const STOP_TIMEOUT_US: u64 = 2_000_000;
+pub const RUNTIME_NAME: &str = "zerovisor";
+pub const RUNTIME_API_VERSION: &str = "v1";
+/// Exit code of a container whose real status was lost
+pub const UNKNOWN_EXIT: i32 = 255;
+pub const SIGKILL: u8 = 9;
+pub const SIGTERM: u8 = 15;
+/// Interface the sandbox network is attached as
+const POD_IF: &str = "eth0";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContainerState { Created, Running, Exited }
+
+impl ContainerState {
+    pub fn name(self) -> &'static str {
+        match self {
+            ContainerState::Created => "CONTAINER_CREATED",
+            ContainerState::Running => "CONTAINER_RUNNING",
+            ContainerState::Exited => "CONTAINER_EXITED",
+        }
+    }
+}
+
+fn copy(dst: &mut [u8], s: &str, what: &'static str) -> Result<u8, &'static str> {
+    if s.is_empty() || s.len() > dst.len() || s.len() > u8::MAX as usize { return Err(what); }
+    dst[..s.len()].copy_from_slice(s.as_bytes());
+    Ok(s.len() as u8)
+}
+
+fn text(b: &[u8], len: u8) -> &str { core::str::from_utf8(&b[..len as usize]).unwrap_or("?") }
+
+/// Pod sandbox metadata and how to boot it.
+#[derive(Clone, Copy, Debug)]
+pub struct PodConfig<'a> {
+    pub name: &'a str,
+    pub namespace: &'a str,
+    pub uid: &'a str,
+    pub attempt: u32,
+    /// ESP path of the pod kernel
+    pub kernel: &'a str,
+    pub cmdline: &'a str,
+    pub vcpus: u32,
+    pub memory_mib: u64,
+    /// CNI network to attach `eth0` to
+    pub network: Option<&'a str>,
+}
+
+impl<'a> PodConfig<'a> {
+    pub fn new(name: &'a str, namespace: &'a str, uid: &'a str) -> PodConfig<'a> {
+        PodConfig { name, namespace, uid, attempt: 0, kernel: DEFAULT_KERNEL, cmdline: DEFAULT_CMDLINE, vcpus: 1, memory_mib: DEFAULT_MEMORY_MIB, network: None }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sandbox {
+    /// Pod sandbox id, which is the VM id
+    pub id: u64,
+    name: [u8; META_MAX],
+    name_len: u8,
+    namespace: [u8; META_MAX],
+    ns_len: u8,
+    uid: [u8; META_MAX],
+    uid_len: u8,
+    pub attempt: u32,
+    /// SANDBOX_READY: the VM runs; cleared by stop
+    pub ready: bool,
+    /// Unix seconds
+    pub created_at: i64,
+    network: [u8; cni::NAME_MAX],
+    net_len: u8,
+}
+
+impl Sandbox {
+    pub fn name(&self) -> &str { text(&self.name, self.name_len) }
+    pub fn namespace(&self) -> &str { text(&self.namespace, self.ns_len) }
+    pub fn uid(&self) -> &str { text(&self.uid, self.uid_len) }
+    pub fn network(&self) -> Option<&str> { (self.net_len != 0).then(|| text(&self.network, self.net_len)) }
+}
+
+/// The shell line a container runs, built up word by word: the working
+/// directory first, then the environment, then the command and arguments.
+#[derive(Clone, Copy)]
+pub struct Command {
+    line: [u8; MAX_MSG],
+    len: usize,
+    argc: usize,
+}
+
+impl Command {
+    pub fn new(working_dir: &str) -> Result<Command, &'static str> {
+        let mut c = Command { line: [0; MAX_MSG], len: 0, argc: 0 };
+        if !working_dir.is_empty() {
+            c.push(b"cd ")?;
+            c.quote(working_dir)?;
+            c.push(b" && ")?;
+        }
+        c.push(b"exec env")?;
+        Ok(c)
+    }
+
+    fn push(&mut self, b: &[u8]) -> Result<(), &'static str> {
+        let end = self.len + b.len();
+        if end > MAX_MSG { return Err("cri: command line longer than an agent message"); }
+        self.line[self.len..end].copy_from_slice(b);
+        self.len = end;
+        Ok(())
+    }
+
+    /// `s` for inside single quotes: each quote in it closes them, is escaped, and reopens them.
+    fn escaped(&mut self, s: &str) -> Result<(), &'static str> {
+        for (i, part) in s.split('\'').enumerate() {
+            if i != 0 { self.push(b"'\\''")?; }
+            self.push(part.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn quote(&mut self, word: &str) -> Result<(), &'static str> {
+        self.push(b"'")?;
+        self.escaped(word)?;
+        self.push(b"'")
+    }
+
+    /// One environment variable; only before the first `arg`.
+    pub fn env(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        if self.argc != 0 { return Err("cri: environment after the command"); }
+        let ok = key.bytes().next().is_some_and(|c| c.is_ascii_alphabetic() || c == b'_')
+            && key.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_');
+        if !ok { return Err("cri: invalid environment variable name"); }
+        self.push(b" '")?;
+        self.push(key.as_bytes())?;
+        self.push(b"=")?;
+        self.escaped(value)?;
+        self.push(b"'")
+    }
+
+    pub fn arg(&mut self, word: &str) -> Result<(), &'static str> {
+        self.push(b" ")?;
+        self.quote(word)?;
+        self.argc += 1;
+        Ok(())
+    }
+
+    pub fn as_str(&self) -> &str { core::str::from_utf8(&self.line[..self.len]).unwrap_or("") }
+}
+
+#[derive(Clone, Copy)]
+pub struct Container {
+    pub id: u32,
+    pub sandbox: u64,
+    name: [u8; META_MAX],
+    name_len: u8,
+    pub attempt: u32,
+    image: [u8; IMAGE_MAX],
+    image_len: u8,
+    line: [u8; MAX_MSG],
+    line_len: u16,
+    pub state: ContainerState,
+    /// Unix seconds; 0 until the event happens
+    pub created_at: i64,
+    pub started_at: i64,
+    pub finished_at: i64,
+    pub exit_code: i32,
+    /// CRI reason for an exited container
+    pub reason: &'static str,
+    /// Agent tag of the `EXEC` while running
+    tag: u16,
+    log: [u8; LOG_TAIL],
+    log_len: u16,
+}
+
+impl Container {
+    pub fn name(&self) -> &str { text(&self.name, self.name_len) }
+    pub fn image(&self) -> &str { text(&self.image, self.image_len) }
+    pub fn command(&self) -> &str { core::str::from_utf8(&self.line[..self.line_len as usize]).unwrap_or("") }
+    /// The newest output, oldest byte first
+    pub fn log(&self) -> &[u8] { &self.log[..self.log_len as usize] }
+
+    fn append(&mut self, data: &[u8]) {
+        let data = &data[data.len().saturating_sub(LOG_TAIL)..];
+        let keep = (self.log_len as usize).min(LOG_TAIL - data.len());
+        self.log.copy_within(self.log_len as usize - keep..self.log_len as usize, 0);
+        self.log[keep..keep + data.len()].copy_from_slice(data);
+        self.log_len = (keep + data.len()) as u16;
+    }
+
+    fn exit(&mut self, code: i32, reason: &'static str, now: i64) {
+        self.state = ContainerState::Exited;
+        self.exit_code = code;
+        self.reason = reason;
+        self.finished_at = now;
+        self.tag = 0;
+    }
+}
+
+struct State {
+    pods: [Option<Sandbox>; MAX_SANDBOXES],
+    ctrs: [Option<Container>; MAX_CONTAINERS],
+    next_ctr: u32,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State { pods: [None; MAX_SANDBOXES], ctrs: [None; MAX_CONTAINERS], next_ctr: 1 });
+
+fn now(system_table: &SystemTable<Boot>) -> i64 { crate::hv::vtime::rtc::host_unix(system_table) }
+
+fn update(id: u32, f: impl FnOnce(&mut Container)) -> bool {
+    STATE.lock(|s| s.ctrs.iter_mut().flatten().find(|c| c.id == id).map(f).is_some())
+}
+
+pub fn sandbox(id: u64) -> Option<Sandbox> { STATE.lock(|s| s.pods.iter().flatten().find(|p| p.id == id).copied()) }
+
+pub fn container(id: u32) -> Option<Container> { STATE.lock(|s| s.ctrs.iter().flatten().find(|c| c.id == id).copied()) }
+
+/// Address of the sandbox's `eth0`, if it has a network.
+pub fn ip(id: u64) -> Option<[u8; 4]> {
+    let mut ip = None;
+    cni::for_each(Some(id), |a, _| if a.if_name() == POD_IF { ip = Some(a.ip); });
+    ip
+}
+
+pub fn for_each_sandbox(mut f: impl FnMut(&Sandbox)) {
+    let pods = STATE.lock(|s| s.pods);
+    for p in pods.iter().flatten() { f(p); }
+}
+
+/// Iterate containers, those of one sandbox for `Some`. Each is copied out
+/// on its own, as the table is large.
+pub fn for_each_container(sandbox: Option<u64>, mut f: impl FnMut(&Container)) {
+    for i in 0..MAX_CONTAINERS {
+        let c = STATE.lock(|s| s.ctrs[i].filter(|c| sandbox.map_or(true, |p| p == c.sandbox)));
+        if let Some(c) = c { f(&c); }
+    }
+}
+
+/// RunPodSandbox. Returns the sandbox id.
+pub fn run_sandbox(system_table: &SystemTable<Boot>, cfg: &PodConfig) -> Result<u64, &'static str> {
+    let mut pod = Sandbox {
+        id: 0, name: [0; META_MAX], name_len: 0, namespace: [0; META_MAX], ns_len: 0, uid: [0; META_MAX], uid_len: 0,
+        attempt: cfg.attempt, ready: true, created_at: 0, network: [0; cni::NAME_MAX], net_len: 0,
+    };
+    pod.name_len = copy(&mut pod.name, cfg.name, "cri: pod name must be 1..63 bytes")?;
+    pod.ns_len = copy(&mut pod.namespace, cfg.namespace, "cri: namespace must be 1..63 bytes")?;
+    pod.uid_len = copy(&mut pod.uid, cfg.uid, "cri: uid must be 1..63 bytes")?;
+    if let Some(n) = cfg.network {
+        let net = cni::network(n).ok_or("cri: no such CNI network")?;
+        pod.net_len = copy(&mut pod.network, net.name(), "cri: no such CNI network")?;
+    }
+    if cfg.vcpus == 0 || cfg.memory_mib == 0 || cfg.memory_mib >> 44 != 0 { return Err("cri: vcpus and memory must be positive"); }
+    STATE.lock(|s| {
+        if s.pods.iter().flatten().any(|p| p.name() == cfg.name && p.namespace() == cfg.namespace && p.uid() == cfg.uid && p.attempt == cfg.attempt) {
+            return Err("cri: pod sandbox already exists");
+        }
+        if s.pods.iter().all(|p| p.is_some()) { return Err("cri: too many pod sandboxes"); }
+        Ok(())
+    })?;
+
+    let config = crate::hv::vm::VmConfig { memory_bytes: cfg.memory_mib << 20, vcpu_count: cfg.vcpus, ..Default::default() };
+    let vm = crate::hv::vm::Vm::try_create(system_table, config, 0, 0).map_err(|_| "cri: admission denied the pod")?;
+    let id = vm.id.0;
+    if let Err(e) = crate::hv::vm::register(&vm, None) {
+        vm.destroy();
+        return Err(e);
+    }
+    let boot = || -> Result<(), &'static str> {
+        vsock::add(id, None)?;
+        if let Some(n) = pod.network() { cni::add(id, n, POD_IF, None)?; }
+        let req = crate::hv::loader::LoadRequest {
+            vm_id: id, path: cfg.kernel, ram_bytes: cfg.memory_mib << 20, flat_load_gpa: crate::hv::loader::DEFAULT_LOAD_GPA, cmdline: cfg.cmdline,
+        };
+        crate::hv::loader::load(system_table, &req)?;
+        if crate::hv::run::start(system_table, id)? == 0 { return Err("cri: no vCPU of the pod started"); }
+        Ok(())
+    };
+    if let Err(e) = boot() {
+        let _ = crate::hv::run::stop(system_table, id, STOP_TIMEOUT_US);
+        let _ = crate::hv::vm::destroy_vm(id);
+        return Err(e);
+    }
+    pod.id = id;
+    pod.created_at = now(system_table);
+    STATE.lock(|s| if let Some(slot) = s.pods.iter_mut().find(|p| p.is_none()) { *slot = Some(pod); });
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::CRI_SANDBOXES).inc();
+    Ok(id)
+}
+
+/// StopPodSandbox: kill the containers, stop the VM and give back its
+/// address. Succeeds for an unknown or stopped sandbox.
+pub fn stop_sandbox(system_table: &SystemTable<Boot>, id: u64) -> Result<(), &'static str> {
+    let Some(pod) = sandbox(id) else { return Ok(()) };
+    if let Some(idx) = vsock::find(id) { vsock::disconnect(idx); }
+    let at = now(system_table);
+    STATE.lock(|s| {
+        for c in s.ctrs.iter_mut().flatten() {
+            if c.sandbox == id && c.state == ContainerState::Running { c.exit(UNKNOWN_EXIT, "SandboxStopped", at); }
+        }
+    });
+    if crate::hv::run::stop(system_table, id, STOP_TIMEOUT_US) != 0 { return Err("cri: pod vcpus still running"); }
+    if let Some(n) = pod.network() { let _ = cni::del(id, n, POD_IF); }
+    STATE.lock(|s| if let Some(p) = s.pods.iter_mut().flatten().find(|p| p.id == id) { p.ready = false; });
+    Ok(())
+}
+
+/// RemovePodSandbox: stop it if needed, destroy the VM and drop its
+/// containers. Succeeds for an unknown sandbox.
+pub fn remove_sandbox(system_table: &SystemTable<Boot>, id: u64) -> Result<(), &'static str> {
+    if sandbox(id).is_none() { return Ok(()); }
+    stop_sandbox(system_table, id)?;
+    if crate::hv::vm::find_vm(id).is_some() { crate::hv::vm::destroy_vm(id)?; }
+    forget_vm(id);
+    Ok(())
+}
+
+/// Drop the sandbox of a VM that is being torn down.
+pub fn forget_vm(vm_id: u64) {
+    STATE.lock(|s| {
+        for p in s.pods.iter_mut() { if matches!(p, Some(x) if x.id == vm_id) { *p = None; } }
+        for c in s.ctrs.iter_mut() { if matches!(c, Some(x) if x.sandbox == vm_id) { *c = None; } }
+    });
+}
+
+/// CreateContainer. Returns the container id.
+pub fn create_container(system_table: &SystemTable<Boot>, sandbox_id: u64, name: &str, attempt: u32, image: &str, cmd: &Command) -> Result<u32, &'static str> {
+    let pod = sandbox(sandbox_id).ok_or("cri: no such pod sandbox")?;
+    if !pod.ready { return Err("cri: pod sandbox is not ready"); }
+    if cmd.argc == 0 { return Err("cri: container has no command"); }
+    let mut c = Container {
+        id: 0, sandbox: sandbox_id, name: [0; META_MAX], name_len: 0, attempt, image: [0; IMAGE_MAX], image_len: 0,
+        line: cmd.line, line_len: cmd.len as u16, state: ContainerState::Created,
+        created_at: now(system_table), started_at: 0, finished_at: 0, exit_code: 0, reason: "", tag: 0, log: [0; LOG_TAIL], log_len: 0,
+    };
+    c.name_len = copy(&mut c.name, name, "cri: container name must be 1..63 bytes")?;
+    c.image_len = copy(&mut c.image, image, "cri: image must be 1..128 bytes")?;
+    STATE.lock(|s| {
+        if s.ctrs.iter().flatten().any(|x| x.sandbox == sandbox_id && x.name() == name && x.attempt == attempt) {
+            return Err("cri: container name in use in the pod");
+        }
+        let slot = s.ctrs.iter().position(|x| x.is_none()).ok_or("cri: too many containers")?;
+        c.id = s.next_ctr;
+        s.next_ctr = s.next_ctr.wrapping_add(1).max(1);
+        s.ctrs[slot] = Some(c);
+        Ok(c.id)
+    })
+}
+
+/// StartContainer: have the agent run the command. Waits for the agent of
+/// a guest that is still booting.
+pub fn start_container(system_table: &mut SystemTable<Boot>, id: u32) -> Result<(), &'static str> {
+    let c = container(id).ok_or("cri: no such container")?;
+    if c.state != ContainerState::Created { return Err("cri: container is not in created state"); }
+    if !sandbox(c.sandbox).is_some_and(|p| p.ready) { return Err("cri: pod sandbox is not ready"); }
+    let idx = vsock::find(c.sandbox).ok_or("cri: pod has no agent channel")?;
+    vsock::wait_connected(system_table, idx, AGENT_WAIT_MS)?;
+    let tag = vsock::send(idx, vsock::MSG_EXEC, c.command().as_bytes())?;
+    let at = now(system_table);
+    update(id, |c| { c.state = ContainerState::Running; c.started_at = at; c.tag = tag; });
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::CRI_CONTAINER_STARTS).inc();
+    Ok(())
+}
+
+/// Send `signal` to a running container and wait up to `wait_ms` for it
+/// to exit. Returns whether it did.
+fn signal(system_table: &mut SystemTable<Boot>, c: &Container, signal: u8, wait_ms: u32) -> bool {
+    let Some(idx) = vsock::find(c.sandbox) else { return false };
+    let t = c.tag.to_le_bytes();
+    if vsock::send(idx, vsock::MSG_KILL, &[t[0], t[1], signal]).is_err() { return false; }
+    for _ in 0..=wait_ms {
+        pump(system_table);
+        if container(c.id).map_or(true, |c| c.state != ContainerState::Running) { return true; }
+        let _ = system_table.boot_services().stall(1000);
+    }
+    false
+}
+
+/// StopContainer: SIGTERM, then SIGKILL after `timeout_s` (at once for 0).
+/// A container the agent never reports on is exited with `UNKNOWN_EXIT`.
+pub fn stop_container(system_table: &mut SystemTable<Boot>, id: u32, timeout_s: u32) -> Result<(), &'static str> {
+    pump(system_table);
+    let c = container(id).ok_or("cri: no such container")?;
+    if c.state != ContainerState::Running { return Ok(()); }
+    if timeout_s != 0 && signal(system_table, &c, SIGTERM, timeout_s.saturating_mul(1000)) { return Ok(()); }
+    if signal(system_table, &c, SIGKILL, KILL_WAIT_MS) { return Ok(()); }
+    let at = now(system_table);
+    update(id, |c| if c.state == ContainerState::Running { c.exit(UNKNOWN_EXIT, "Unknown", at); });
+    Ok(())
+}
+
+/// RemoveContainer, killing it first if it runs. Succeeds for an unknown id.
+pub fn remove_container(system_table: &mut SystemTable<Boot>, id: u32) -> Result<(), &'static str> {
+    if container(id).is_none() { return Ok(()); }
+    stop_container(system_table, id, 0)?;
+    STATE.lock(|s| for c in s.ctrs.iter_mut() { if matches!(c, Some(x) if x.id == id) { *c = None; } });
+    Ok(())
+}
+
+/// Collect agent output and exit statuses, and notice sandboxes whose VM
+/// stopped. Returns how many containers exited.
+pub fn pump(system_table: &SystemTable<Boot>) -> usize {
+    let at = now(system_table);
+    let mut exited = 0;
+    for p in 0..MAX_SANDBOXES {
+        let Some(pod) = STATE.lock(|s| s.pods[p]) else { continue };
+        if !pod.ready { continue; }
+        let up = crate::hv::run::active(pod.id) != 0;
+        let chan = vsock::find(pod.id);
+        for i in 0..MAX_CONTAINERS {
+            let Some((id, tag)) = STATE.lock(|s| s.ctrs[i].filter(|c| c.sandbox == pod.id && c.state == ContainerState::Running).map(|c| (c.id, c.tag))) else { continue };
+            if let Some(idx) = chan {
+                while let Some(m) = vsock::take_reply(idx, tag) {
+                    let done = m.kind != vsock::MSG_OUTPUT;
+                    update(id, |c| match m.kind {
+                        vsock::MSG_OUTPUT => c.append(m.payload()),
+                        vsock::MSG_EXIT => {
+                            let p = m.payload();
+                            let code = if p.len() >= 4 { i32::from_le_bytes([p[0], p[1], p[2], p[3]]) } else { 0 };
+                            c.exit(code, if code == 0 { "Completed" } else { "Error" }, at);
+                        }
+                        _ => { c.append(m.payload()); c.exit(UNKNOWN_EXIT, "Error", at); }
+                    });
+                    if done { exited += 1; break; }
+                }
+            }
+            let lost = !up || chan.map_or(true, |idx| vsock::state(idx) == vsock::ConnState::Closed);
+            if lost {
+                let mut hit = false;
+                update(id, |c| if c.state == ContainerState::Running { c.exit(UNKNOWN_EXIT, if up { "Unknown" } else { "SandboxStopped" }, at); hit = true; });
+                exited += hit as usize;
+            }
+        }
+        if !up { STATE.lock(|s| if let Some(x) = s.pods[p].as_mut() { x.ready = false; }); }
+    }
+    exited
+}
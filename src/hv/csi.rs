@@ -0,0 +1,218 @@
+#![allow(dead_code)]
+
+//! Container Storage Interface semantics over the storage manager.
+//!
+//! A Kubernetes cluster whose nodes are Zerovisor VMs provisions persistent
+//! volumes through the controller calls of CSI, without gRPC: the management
+//! API maps them onto `storage` volumes and virtio-blk disks. A CSI volume
+//! id is the decimal id of a (non-snapshot) storage volume, and a node id a
+//! VM. Snapshots serve only as a content source.
+//!
+//! What the external-provisioner and external-attacher rely on:
+//! - CreateVolume is idempotent by name. A volume that already has the name
+//!   is returned if its size and source agree with the request, and is
+//!   ALREADY_EXISTS otherwise. Names are at most `storage::NAME_MAX` bytes,
+//!   which fits `pvc-<uuid>`.
+//! - Capacity is the smallest whole number of chunks (1 MiB) at or above
+//!   `required_bytes`, else `DEFAULT_BYTES` capped at `limit_bytes`; when
+//!   that exceeds `limit_bytes` the call is OUT_OF_RANGE. A volume made from
+//!   a source has the source's size, which the range has to admit.
+//! - DeleteVolume and ControllerUnpublishVolume succeed when there is
+//!   nothing left to do. ControllerPublishVolume succeeds again for the
+//!   same node and access.
+//! - A writable publish needs the volume unpublished everywhere else; a
+//!   read-only one needs no writer elsewhere.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::storage::{self, VolInfo};
+use crate::hv::vdev::blk::{self, Backing, MAX_DISKS};
+
+/// Plugin name reported to the cluster
+pub const PLUGIN: &str = "csi.zerovisor";
+/// Capacity of a volume whose request gives no `required_bytes`
+pub const DEFAULT_BYTES: u64 = 1 << 30;
+/// Controller capabilities, as CSI names them
+pub const CONTROLLER_CAPS: [&str; 6] = [
+    "CREATE_DELETE_VOLUME", "PUBLISH_UNPUBLISH_VOLUME", "LIST_VOLUMES",
+    "GET_CAPACITY", "CLONE_VOLUME", "LIST_VOLUMES_PUBLISHED_NODES",
+];
+
+/// gRPC status codes the calls fail with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code { InvalidArgument, NotFound, AlreadyExists, OutOfRange, FailedPrecondition, ResourceExhausted, Unavailable }
+
+impl Code {
+    pub fn name(self) -> &'static str {
+        match self {
+            Code::InvalidArgument => "INVALID_ARGUMENT",
+            Code::NotFound => "NOT_FOUND",
+            Code::AlreadyExists => "ALREADY_EXISTS",
+            Code::OutOfRange => "OUT_OF_RANGE",
+            Code::FailedPrecondition => "FAILED_PRECONDITION",
+            Code::ResourceExhausted => "RESOURCE_EXHAUSTED",
+            Code::Unavailable => "UNAVAILABLE",
+        }
+    }
+}
+
+pub type Error = (Code, &'static str);
+
+/// Access modes a block volume can be published with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access { SingleNodeWriter, SingleNodeReaderOnly, MultiNodeReaderOnly }
+
+impl Access {
+    pub fn name(self) -> &'static str {
+        match self {
+            Access::SingleNodeWriter => "SINGLE_NODE_WRITER",
+            Access::SingleNodeReaderOnly => "SINGLE_NODE_READER_ONLY",
+            Access::MultiNodeReaderOnly => "MULTI_NODE_READER_ONLY",
+        }
+    }
+
+    /// A CSI access mode name; the single-writer variants of CSI 1.5 count
+    /// as `SingleNodeWriter`.
+    pub fn parse(s: &str) -> Result<Access, Error> {
+        match s {
+            "SINGLE_NODE_WRITER" | "SINGLE_NODE_SINGLE_WRITER" | "SINGLE_NODE_MULTI_WRITER" => Ok(Access::SingleNodeWriter),
+            "SINGLE_NODE_READER_ONLY" => Ok(Access::SingleNodeReaderOnly),
+            "MULTI_NODE_READER_ONLY" => Ok(Access::MultiNodeReaderOnly),
+            "MULTI_NODE_SINGLE_WRITER" | "MULTI_NODE_MULTI_WRITER" => Err((Code::InvalidArgument, "csi: block volumes have one writer on one node")),
+            _ => Err((Code::InvalidArgument, "csi: unknown access mode")),
+        }
+    }
+
+    pub fn read_only(self) -> bool { self != Access::SingleNodeWriter }
+}
+
+/// What a new volume starts out with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Empty,
+    /// Clone of volume `id`
+    Volume(u32),
+    /// Clone of snapshot `id`
+    Snapshot(u32),
+}
+
+/// Where a published volume shows up in the node.
+#[derive(Clone, Copy, Debug)]
+pub struct Published {
+    /// virtio-blk disk index
+    pub disk: usize,
+    /// Device number on the VM's PCI bus 0
+    pub dev: u8,
+    pub read_only: bool,
+}
+
+/// Bytes for a capacity range (0 = not given).
+pub fn capacity(required: u64, limit: u64) -> Result<u64, Error> {
+    if limit != 0 && required > limit { return Err((Code::InvalidArgument, "csi: required_bytes exceeds limit_bytes")); }
+    let bytes = match (required, limit) {
+        (0, 0) => DEFAULT_BYTES,
+        (0, l) => DEFAULT_BYTES.min(l / storage::CHUNK * storage::CHUNK),
+        (r, _) => r.div_ceil(storage::CHUNK) * storage::CHUNK,
+    };
+    if bytes == 0 || (limit != 0 && bytes > limit) { return Err((Code::OutOfRange, "csi: no whole MiB fits the capacity range")); }
+    Ok(bytes)
+}
+
+fn pool() -> Result<storage::PoolInfo, Error> { storage::info().ok_or((Code::Unavailable, "csi: no storage pool open")) }
+
+fn by_name(name: &str) -> Option<VolInfo> {
+    let mut found = None;
+    storage::for_each(|v| if v.name() == name { found = Some(*v); });
+    found
+}
+
+/// Volume `id`, if it is one CSI can hand out.
+pub fn volume(id: u32) -> Option<VolInfo> { storage::volume(id).filter(|v| !v.snapshot) }
+
+/// CreateVolume. Returns the volume and whether this call made it.
+pub fn create_volume(system_table: &SystemTable<Boot>, name: &str, required: u64, limit: u64, source: Source) -> Result<(VolInfo, bool), Error> {
+    pool()?;
+    storage::check_name(name).map_err(|e| (Code::InvalidArgument, e))?;
+    let from = match source {
+        Source::Empty => None,
+        Source::Volume(id) => Some(volume(id).ok_or((Code::NotFound, "csi: no such source volume"))?),
+        Source::Snapshot(id) => Some(storage::volume(id).filter(|v| v.snapshot).ok_or((Code::NotFound, "csi: no such source snapshot"))?),
+    };
+    let size = match from {
+        None => capacity(required, limit)?,
+        Some(f) => {
+            if required > f.size || (limit != 0 && limit < f.size) { return Err((Code::OutOfRange, "csi: capacity range excludes the source's size")); }
+            f.size
+        }
+    };
+    if let Some(v) = by_name(name) {
+        let same = !v.snapshot && v.size == size && v.parent == from.map_or(0, |f| f.id);
+        return if same { Ok((v, false)) } else { Err((Code::AlreadyExists, "csi: name taken by an incompatible volume")) };
+    }
+    let id = match from {
+        None => storage::create(system_table, name, size >> 20),
+        Some(f) => storage::copy(system_table, f.id, name, false),
+    }.map_err(|e| (Code::ResourceExhausted, e))?;
+    let v = storage::volume(id).ok_or((Code::Unavailable, "csi: volume vanished"))?;
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::CSI_CREATES).inc();
+    Ok((v, true))
+}
+
+/// DeleteVolume; an unknown id is already deleted.
+pub fn delete_volume(system_table: &SystemTable<Boot>, id: u32) -> Result<(), Error> {
+    pool()?;
+    if volume(id).is_none() { return Ok(()); }
+    let mut nodes = [(0u64, false); MAX_DISKS];
+    if published(id, &mut nodes) != 0 { return Err((Code::FailedPrecondition, "csi: volume is published to a node")); }
+    storage::delete(system_table, id).map_err(|e| (Code::FailedPrecondition, e))
+}
+
+/// Fill `out` with the (VM, read-only) disks volume `id` backs; returns the count.
+pub fn published(id: u32, out: &mut [(u64, bool); MAX_DISKS]) -> usize {
+    let mut n = 0;
+    blk::for_each(|_, d| if matches!(d.backing, Backing::Volume { id: v } if v == id) { out[n] = (d.vm_id, d.read_only); n += 1; });
+    n
+}
+
+/// ControllerPublishVolume to VM `vm_id`.
+pub fn publish(system_table: &SystemTable<Boot>, id: u32, vm_id: u64, access: Access, readonly: bool) -> Result<Published, Error> {
+    pool()?;
+    if volume(id).is_none() { return Err((Code::NotFound, "csi: no such volume")); }
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err((Code::NotFound, "csi: no such node")); }
+    let read_only = readonly || access.read_only();
+    let mut here = None;
+    let mut conflict = false;
+    blk::for_each(|disk, d| {
+        if !matches!(d.backing, Backing::Volume { id: v } if v == id) { return; }
+        if d.vm_id == vm_id { here = Some(Published { disk, dev: d.dev, read_only: d.read_only }); }
+        else { conflict |= !read_only || !d.read_only; }
+    });
+    if let Some(p) = here {
+        return if p.read_only == read_only { Ok(p) } else { Err((Code::AlreadyExists, "csi: volume is published to the node with other access")) };
+    }
+    if conflict { return Err((Code::FailedPrecondition, "csi: volume is published to another node")); }
+    let (disk, dev) = blk::add(system_table, vm_id, Backing::Volume { id }, read_only).map_err(|e| (Code::ResourceExhausted, e))?;
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::CSI_PUBLISHES).inc();
+    Ok(Published { disk, dev, read_only })
+}
+
+/// ControllerUnpublishVolume from VM `vm_id`, or from every VM for `None`.
+/// Guest writes that allocated chunks are committed first.
+pub fn unpublish(system_table: &SystemTable<Boot>, id: u32, vm_id: Option<u64>) -> Result<(), Error> {
+    let mut disks = [(0u64, 0usize); MAX_DISKS];
+    let mut n = 0;
+    blk::for_each(|disk, d| {
+        if matches!(d.backing, Backing::Volume { id: v } if v == id) && vm_id.map_or(true, |vm| vm == d.vm_id) { disks[n] = (d.vm_id, disk); n += 1; }
+    });
+    if n == 0 { return Ok(()); }
+    storage::sync(system_table).map_err(|e| (Code::Unavailable, e))?;
+    for &(vm, disk) in &disks[..n] { let _ = blk::remove(vm, disk); }
+    Ok(())
+}
+
+/// GetCapacity: bytes CreateVolume can still provision.
+pub fn available() -> Result<u64, Error> {
+    let p = pool()?;
+    Ok(p.provision_limit.saturating_sub(p.provisioned))
+}
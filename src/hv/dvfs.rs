@@ -0,0 +1,208 @@
+#![allow(dead_code)]
+
+//! DVFS governor: AP performance states from vCPU utilization.
+//!
+//! Each tick reads how much guest time every online AP ran since the last
+//! one (`credit::cpu_stats().busy_us`) and picks a performance level per AP
+//! (0 slowest, 100 fastest) with the active governor:
+//!
+//! - `performance` keeps every AP at full speed (turbo included on HWP);
+//! - `balanced` jumps to full speed above `UP_PCT` utilization and otherwise
+//!   scales the level with load, stepping down at most `DOWN_STEP` per tick;
+//! - `powersave` runs just fast enough for the load, ramping up at most
+//!   `UP_STEP` per tick.
+//!
+//! The level goes through `arch::x86::pstate`. The control MSR is per CPU:
+//! an AP serving a runqueue picks its new value up between slices
+//! (`apply_local`), an idle one gets it as a dispatched job. `off` leaves
+//! the firmware's settings alone; switching to it after another governor
+//! puts every AP back at full speed.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::arch::x86::ap::{self, MAX_APS};
+use crate::arch::x86::pstate::{self, Caps};
+use crate::hv::sched::credit;
+use crate::obs::metrics::{DVFS_TRANSITIONS, DVFS_UTIL_PCT};
+use crate::util::spinlock::SpinLock;
+
+/// Sampling period of `tick`
+pub const INTERVAL_MS: u64 = 100;
+/// `balanced`: utilization at which an AP goes to full speed
+pub const UP_PCT: u8 = 80;
+/// `balanced`: largest drop per tick, in levels
+pub const DOWN_STEP: u8 = 20;
+/// `powersave`: largest rise per tick, in levels
+pub const UP_STEP: u8 = 10;
+/// How long a dispatched change may take on an idle AP
+const JOB_TIMEOUT_US: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Governor { Off, Performance, Balanced, Powersave }
+
+impl Governor {
+    pub fn name(self) -> &'static str {
+        match self { Governor::Off => "off", Governor::Performance => "performance", Governor::Balanced => "balanced", Governor::Powersave => "powersave" }
+    }
+    pub fn parse(s: &str) -> Option<Governor> {
+        match s {
+            "off" => Some(Governor::Off), "performance" => Some(Governor::Performance),
+            "balanced" => Some(Governor::Balanced), "powersave" => Some(Governor::Powersave), _ => None,
+        }
+    }
+
+    /// Next level for an AP at `level` that was `util` percent busy.
+    fn next(self, level: u8, util: u8) -> u8 {
+        match self {
+            Governor::Off | Governor::Performance => 100,
+            Governor::Balanced => {
+                if util >= UP_PCT { return 100; }
+                let want = (util as u32 * 100 / UP_PCT as u32) as u8;
+                want.max(level.saturating_sub(DOWN_STEP))
+            }
+            Governor::Powersave => util.min(level.saturating_add(UP_STEP)).min(100),
+        }
+    }
+
+    fn epp(self) -> u8 {
+        match self { Governor::Off | Governor::Performance => 0, Governor::Balanced => 128, Governor::Powersave => 255 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cpu {
+    /// Utilization over the last tick, percent
+    pub util_pct: u8,
+    pub level: u8,
+    /// Hardware state for `level` (ratio, HWP performance or AMD P-state)
+    pub state: u8,
+    /// State read back on the AP, when it was last changed by a job
+    pub readback: Option<u8>,
+    pub transitions: u64,
+    last_busy_us: u64,
+    applied: bool,
+}
+
+struct State {
+    probed: bool,
+    caps: Option<Caps>,
+    governor: Governor,
+    cpus: [Cpu; MAX_APS],
+    /// `clock::now_ns` of the last sample (0 = none yet)
+    last_ns: u64,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    probed: false, caps: None, governor: Governor::Off,
+    cpus: [Cpu { util_pct: 0, level: 100, state: 0, readback: None, transitions: 0, last_busy_us: 0, applied: true }; MAX_APS],
+    last_ns: 0,
+});
+
+/// Control value waiting for an AP that is serving its runqueue.
+const NONE: AtomicU64 = AtomicU64::new(u64::MAX);
+static PENDING: [AtomicU64; MAX_APS] = [NONE; MAX_APS];
+
+fn probe(s: &mut State) {
+    if s.probed { return; }
+    s.probed = true;
+    s.caps = pstate::probe();
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub governor: Governor,
+    pub caps: Option<Caps>,
+}
+
+pub fn status() -> Status {
+    STATE.lock(|s| { probe(s); Status { governor: s.governor, caps: s.caps } })
+}
+
+pub fn governor() -> Governor { STATE.lock(|s| s.governor) }
+
+/// Switch governors. The new one takes effect at the next `tick`; `Off`
+/// restores full speed there once and then stops touching the APs.
+pub fn set_governor(g: Governor) -> Result<(), &'static str> {
+    STATE.lock(|s| {
+        probe(s);
+        if s.caps.is_none() && g != Governor::Off { return Err("dvfs: no P-state control on this CPU"); }
+        if s.governor != g {
+            if g != Governor::Off { pstate::enable(); }
+            s.governor = g;
+            for c in s.cpus.iter_mut() { c.applied = false; }
+            // Apply at once instead of after a full interval.
+            s.last_ns = 0;
+        }
+        Ok(())
+    })
+}
+
+/// Per-AP view; `f(cpu, &Cpu)` for every online AP.
+pub fn for_each(mut f: impl FnMut(usize, &Cpu)) {
+    for cpu in 0..MAX_APS {
+        if !ap::is_online(cpu) { continue; }
+        let c = STATE.lock(|s| s.cpus[cpu]);
+        f(cpu, &c);
+    }
+}
+
+/// Called by an AP between slices: write a pending control value.
+pub fn apply_local(cpu: usize) {
+    if cpu >= MAX_APS { return; }
+    let raw = PENDING[cpu].swap(u64::MAX, Ordering::AcqRel);
+    if raw != u64::MAX { pstate::write(raw); }
+}
+
+/// Sample utilization and move each AP to its governor's level. Runs at
+/// most every `INTERVAL_MS` unless `force`. Returns the APs changed.
+pub fn tick(system_table: &SystemTable<Boot>, force: bool) -> usize {
+    if crate::time::clock::hz() == 0 { return 0; }
+    let now = crate::time::clock::now_ns();
+    let sample = STATE.lock(|s| {
+        let dt = now.saturating_sub(s.last_ns);
+        let first = s.last_ns == 0;
+        if !first && !force && dt < INTERVAL_MS * 1_000_000 { return None; }
+        s.last_ns = now;
+        if s.governor == Governor::Off && s.cpus.iter().all(|c| c.applied) { return None; }
+        let caps = s.caps?;
+        let dt_us = if first { 0 } else { dt / 1_000 };
+        Some((caps, s.governor, dt_us))
+    });
+    let Some((caps, gov, dt_us)) = sample else { return 0 };
+
+    let mut changed = 0;
+    let mut total_util = 0u32;
+    let mut online = 0u32;
+    for cpu in 0..MAX_APS {
+        if !ap::is_online(cpu) { continue; }
+        let busy = credit::cpu_stats(cpu).busy_us;
+        let (util, raw) = STATE.lock(|s| {
+            let c = &mut s.cpus[cpu];
+            let util = if dt_us == 0 { 0 } else { (busy.saturating_sub(c.last_busy_us).saturating_mul(100) / dt_us).min(100) as u8 };
+            c.last_busy_us = busy;
+            c.util_pct = util;
+            let level = gov.next(c.level, util);
+            if c.applied && level == c.level { return (util, None); }
+            c.level = level;
+            c.state = caps.state(level);
+            c.applied = true;
+            c.transitions += 1;
+            (util, Some(caps.encode(level, gov.epp())))
+        });
+        online += 1;
+        total_util += util as u32;
+        let Some(raw) = raw else { continue };
+        changed += 1;
+        DVFS_TRANSITIONS.fetch_add(1, Ordering::Relaxed);
+        if credit::serving(cpu) {
+            PENDING[cpu].store(raw, Ordering::Release);
+        } else {
+            let back = ap::run_on(system_table, cpu, pstate::job_write, raw, JOB_TIMEOUT_US).ok().filter(|&v| v != u64::MAX).map(|v| v as u8);
+            STATE.lock(|s| s.cpus[cpu].readback = back);
+        }
+    }
+    DVFS_UTIL_PCT.store(if online == 0 { 0 } else { (total_util / online) as u64 }, Ordering::Relaxed);
+    changed
+}
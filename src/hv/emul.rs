@@ -0,0 +1,251 @@
+#![allow(dead_code)]
+
+//! Minimal x86 instruction decoder/emulator for device accesses.
+//!
+//! Covers what compilers and drivers actually emit against MMIO and I/O ports:
+//! MOV (88/89/8A/8B/C6/C7/A0-A3), MOVZX/MOVSX (0F B6/B7/BE/BF), STOS (AA/AB,
+//! with REP) and IN/OUT (E4-E7, EC-EF). The faulting guest-physical address
+//! comes from the exit, so memory operands only need to be sized, not
+//! computed. Everything else is rejected and left to the caller.
+
+use crate::hv::vcpu::GuestRegs;
+
+/// Longest legal x86 instruction.
+pub const MAX_INSN_LEN: usize = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// reg -> [mem]
+    Store,
+    /// imm -> [mem]
+    StoreImm,
+    /// [mem] -> reg (zero- or sign-extended per `ext`)
+    Load,
+    /// AL/AX/EAX/RAX -> [mem], repeated RCX times with REP
+    Stos,
+    /// port -> AL/AX/EAX
+    In,
+    /// AL/AX/EAX -> port
+    Out,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ext { None, Zero, Sign }
+
+#[derive(Clone, Copy, Debug)]
+pub struct Insn {
+    pub len: u8,
+    pub kind: Kind,
+    /// Access width in bytes (memory or port side)
+    pub size: u8,
+    /// Destination register width for loads (differs from `size` for MOVZX/MOVSX)
+    pub reg_size: u8,
+    pub ext: Ext,
+    /// Register operand number (0..15)
+    pub reg: u8,
+    /// Legacy AH/CH/DH/BH encoding (8-bit, no REX)
+    pub high8: bool,
+    pub imm: u64,
+    pub rep: bool,
+    /// Port is in DX rather than an immediate
+    pub port_dx: bool,
+}
+
+/// Length of a ModRM memory operand (ModRM + SIB + displacement), or None for register forms.
+fn modrm_len(bytes: &[u8], i: usize) -> Option<(u8, u8, usize)> {
+    let m = *bytes.get(i)?;
+    let md = m >> 6; let reg = (m >> 3) & 7; let rm = m & 7;
+    if md == 3 { return None; }
+    let mut n = 1usize;
+    if rm == 4 {
+        let sib = *bytes.get(i + 1)?;
+        n += 1;
+        if md == 0 && (sib & 7) == 5 { n += 4; }
+    } else if md == 0 && rm == 5 {
+        // RIP-relative (64-bit) or disp32
+        n += 4;
+    }
+    n += match md { 1 => 1, 2 => 4, _ => 0 };
+    Some((reg, rm, n))
+}
+
+fn read_imm(bytes: &[u8], at: usize, size: usize) -> Option<u64> {
+    if at + size > bytes.len() { return None; }
+    let mut v = 0u64;
+    for k in 0..size { v |= (bytes[at + k] as u64) << (8 * k); }
+    Some(v)
+}
+
+/// Decode one instruction in 64-bit mode.
+pub fn decode(bytes: &[u8]) -> Result<Insn, &'static str> {
+    let bytes = &bytes[..bytes.len().min(MAX_INSN_LEN)];
+    let mut i = 0usize;
+    let mut opsize16 = false; let mut addr32 = false; let mut rep = false;
+    // Legacy prefixes
+    while i < bytes.len() {
+        match bytes[i] {
+            0x66 => opsize16 = true,
+            0x67 => addr32 = true,
+            0xF3 | 0xF2 => rep = true,
+            0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65 | 0xF0 => {}
+            _ => break,
+        }
+        i += 1;
+    }
+    let mut rex = 0u8;
+    if i < bytes.len() && (bytes[i] & 0xF0) == 0x40 { rex = bytes[i]; i += 1; }
+    let rex_w = (rex & 8) != 0; let rex_r = (rex & 4) != 0;
+    let opsz: u8 = if rex_w { 8 } else if opsize16 { 2 } else { 4 };
+    let op = *bytes.get(i).ok_or("emul: truncated")?;
+    i += 1;
+    let mk = |kind, size, reg_size, ext, reg: u8, len: usize, imm| {
+        let high8 = size == 1 && reg_size == 1 && rex == 0 && (4..8).contains(&reg);
+        Insn { len: len as u8, kind, size, reg_size, ext, reg: if high8 { reg - 4 } else { reg }, high8, imm, rep, port_dx: false }
+    };
+    let reg_of = |r: u8| r | if rex_r { 8 } else { 0 };
+    match op {
+        0x88 | 0x89 | 0x8A | 0x8B => {
+            let (reg, _, n) = modrm_len(bytes, i).ok_or("emul: register operand")?;
+            let size = if op & 1 == 0 { 1 } else { opsz };
+            let kind = if op < 0x8A { Kind::Store } else { Kind::Load };
+            Ok(mk(kind, size, size, Ext::None, reg_of(reg), i + n, 0))
+        }
+        0xC6 | 0xC7 => {
+            let (reg, _, n) = modrm_len(bytes, i).ok_or("emul: register operand")?;
+            if reg != 0 { return Err("emul: unsupported C6/C7 form"); }
+            let size = if op == 0xC6 { 1 } else { opsz };
+            let isz = if size == 8 { 4 } else { size as usize };
+            let mut imm = read_imm(bytes, i + n, isz).ok_or("emul: truncated")?;
+            // imm32 is sign-extended for 64-bit stores
+            if size == 8 { imm = imm as u32 as i32 as i64 as u64; }
+            Ok(mk(Kind::StoreImm, size, size, Ext::None, 0, i + n + isz, imm))
+        }
+        0xA0 | 0xA1 | 0xA2 | 0xA3 => {
+            // moffs is 8 bytes in 64-bit mode (4 with 0x67)
+            let moff = if addr32 { 4 } else { 8 };
+            if i + moff > bytes.len() { return Err("emul: truncated"); }
+            let size = if op & 1 == 0 { 1 } else { opsz };
+            let kind = if op < 0xA2 { Kind::Load } else { Kind::Store };
+            Ok(mk(kind, size, size, Ext::None, 0, i + moff, 0))
+        }
+        0xAA | 0xAB => {
+            let size = if op == 0xAA { 1 } else { opsz };
+            Ok(mk(Kind::Stos, size, size, Ext::None, 0, i, 0))
+        }
+        0x0F => {
+            let op2 = *bytes.get(i).ok_or("emul: truncated")?;
+            i += 1;
+            let (size, ext) = match op2 { 0xB6 => (1, Ext::Zero), 0xB7 => (2, Ext::Zero), 0xBE => (1, Ext::Sign), 0xBF => (2, Ext::Sign), _ => return Err("emul: unsupported 0F opcode") };
+            let (reg, _, n) = modrm_len(bytes, i).ok_or("emul: register operand")?;
+            Ok(mk(Kind::Load, size, opsz, ext, reg_of(reg), i + n, 0))
+        }
+        0xE4 | 0xE5 | 0xE6 | 0xE7 => {
+            let port = *bytes.get(i).ok_or("emul: truncated")? as u64;
+            let size = if op & 1 == 0 { 1 } else if opsize16 { 2 } else { 4 };
+            let kind = if op < 0xE6 { Kind::In } else { Kind::Out };
+            Ok(mk(kind, size, size, Ext::None, 0, i + 1, port))
+        }
+        0xEC | 0xED | 0xEE | 0xEF => {
+            let size = if op & 1 == 0 { 1 } else if opsize16 { 2 } else { 4 };
+            let kind = if op < 0xEE { Kind::In } else { Kind::Out };
+            let mut d = mk(kind, size, size, Ext::None, 0, i, 0);
+            d.port_dx = true;
+            Ok(d)
+        }
+        _ => Err("emul: unsupported opcode"),
+    }
+}
+
+#[inline(always)]
+fn mask(size: u8) -> u64 { if size >= 8 { u64::MAX } else { (1u64 << (size as u32 * 8)) - 1 } }
+
+fn get_reg(regs: &GuestRegs, reg: u8, size: u8, high8: bool) -> u64 {
+    if high8 { return (regs.gpr[reg as usize] >> 8) & 0xFF; }
+    regs.gpr[reg as usize] & mask(size)
+}
+
+/// Register write with x86-64 width rules: 32-bit writes zero the upper half,
+/// 8/16-bit writes preserve it.
+fn set_reg(regs: &mut GuestRegs, reg: u8, size: u8, high8: bool, v: u64) {
+    let r = &mut regs.gpr[reg as usize];
+    if high8 { *r = (*r & !0xFF00) | ((v & 0xFF) << 8); return; }
+    match size {
+        8 => *r = v,
+        4 => *r = v & 0xFFFF_FFFF,
+        _ => { let m = mask(size); *r = (*r & !m) | (v & m); }
+    }
+}
+
+fn extend(v: u64, from: u8, ext: Ext) -> u64 {
+    match ext {
+        Ext::Sign => match from { 1 => v as u8 as i8 as i64 as u64, 2 => v as u16 as i16 as i64 as u64, 4 => v as u32 as i32 as i64 as u64, _ => v },
+        _ => v & mask(from),
+    }
+}
+
+/// Execute a decoded memory-form instruction against the device at `gpa`,
+/// updating registers and RIP. Unclaimed reads return all-ones.
+pub fn exec_mmio(insn: &Insn, regs: &mut GuestRegs, vm_id: u64, vcpu: u32, gpa: u64) -> Result<(), &'static str> {
+    let rip = regs.rip;
+    match insn.kind {
+        Kind::Load => {
+            let raw = crate::hv::bus::mmio_access(vm_id, vcpu, gpa, insn.size, None).unwrap_or(u64::MAX) & mask(insn.size);
+            crate::hv::mmiotrace::access(vm_id, vcpu, gpa, insn.size, false, raw, rip);
+            set_reg(regs, insn.reg, insn.reg_size, insn.high8, extend(raw, insn.size, insn.ext));
+        }
+        Kind::Store => {
+            let v = get_reg(regs, insn.reg, insn.size, insn.high8);
+            let _ = crate::hv::bus::mmio_access(vm_id, vcpu, gpa, insn.size, Some(v));
+            crate::hv::mmiotrace::access(vm_id, vcpu, gpa, insn.size, true, v, rip);
+        }
+        Kind::StoreImm => {
+            let v = insn.imm & mask(insn.size);
+            let _ = crate::hv::bus::mmio_access(vm_id, vcpu, gpa, insn.size, Some(v));
+            crate::hv::mmiotrace::access(vm_id, vcpu, gpa, insn.size, true, v, rip);
+        }
+        Kind::Stos => {
+            let v = regs.gpr[GuestRegs::RAX] & mask(insn.size);
+            let down = (regs.rflags & (1 << 10)) != 0;
+            let step = insn.size as u64;
+            let mut count = if insn.rep { regs.gpr[GuestRegs::RCX] } else { 1 };
+            if count == 0 { regs.rip = regs.rip.wrapping_add(insn.len as u64); return Ok(()); }
+            // Stay within the faulting page; the guest re-executes for the rest.
+            let room = if down { (gpa & 0xFFF) / step + 1 } else { (0x1000 - (gpa & 0xFFF)) / step };
+            let n = count.min(room.max(1));
+            let mut a = gpa;
+            for _ in 0..n {
+                let _ = crate::hv::bus::mmio_access(vm_id, vcpu, a, insn.size, Some(v));
+                crate::hv::mmiotrace::access(vm_id, vcpu, a, insn.size, true, v, rip);
+                a = if down { a.wrapping_sub(step) } else { a.wrapping_add(step) };
+            }
+            let delta = n * step;
+            let rdi = &mut regs.gpr[GuestRegs::RDI];
+            *rdi = if down { rdi.wrapping_sub(delta) } else { rdi.wrapping_add(delta) };
+            if insn.rep { count -= n; regs.gpr[GuestRegs::RCX] = count; }
+            if count != 0 { return Ok(()); }
+        }
+        Kind::In | Kind::Out => return Err("emul: port instruction on MMIO path"),
+    }
+    regs.rip = regs.rip.wrapping_add(insn.len as u64);
+    Ok(())
+}
+
+/// Execute IN/OUT, updating registers and RIP.
+pub fn exec_pio(insn: &Insn, regs: &mut GuestRegs, vm_id: u64, vcpu: u32) -> Result<(), &'static str> {
+    let port = if insn.port_dx { regs.gpr[GuestRegs::RDX] as u16 } else { insn.imm as u16 };
+    pio(regs, vm_id, vcpu, port, insn.size, insn.kind == Kind::In)?;
+    regs.rip = regs.rip.wrapping_add(insn.len as u64);
+    Ok(())
+}
+
+/// Perform one port access on behalf of the guest accumulator (no RIP update).
+pub fn pio(regs: &mut GuestRegs, vm_id: u64, vcpu: u32, port: u16, size: u8, is_in: bool) -> Result<(), &'static str> {
+    if is_in {
+        let v = crate::hv::bus::pio_access(vm_id, vcpu, port, size, None).unwrap_or(u32::MAX) as u64;
+        set_reg(regs, GuestRegs::RAX as u8, size, false, v & mask(size));
+    } else {
+        let v = (regs.gpr[GuestRegs::RAX] & mask(size)) as u32;
+        let _ = crate::hv::bus::pio_access(vm_id, vcpu, port, size, Some(v));
+    }
+    Ok(())
+}
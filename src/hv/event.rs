@@ -0,0 +1,225 @@
+#![allow(dead_code)]
+
+//! Guest event injection.
+//!
+//! Pending interrupts come from the per-vCPU virtual LAPIC (`hv::vlapic`).
+//! Before each VM entry the caller asks `vmx_deliver_pending` or
+//! `svm_deliver_pending` to move the highest deliverable vector into the
+//! guest. When APICv (virtual-interrupt delivery) or AVIC is available the
+//! vector is posted to the virtualized APIC state and the CPU evaluates it;
+//! otherwise it is injected through the VM-entry interruption field (VMX) or
+//! EVENTINJ (SVM), and an interrupt window is requested if the guest cannot
+//! take it yet.
+
+use crate::arch::x86::vm::{svm, vmcs};
+
+/// Event class, encoded per the VMX interruption-type / SVM EVENTINJ type fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind { ExtInterrupt, Nmi, HwException, SwInterrupt, SwException }
+
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    pub vector: u8,
+    pub kind: EventKind,
+    pub error_code: Option<u32>,
+    /// Instruction length for software interrupts/exceptions (VMX only)
+    pub instr_len: u32,
+}
+
+impl Event {
+    pub fn interrupt(vector: u8) -> Self { Event { vector, kind: EventKind::ExtInterrupt, error_code: None, instr_len: 0 } }
+    pub fn nmi() -> Self { Event { vector: 2, kind: EventKind::Nmi, error_code: None, instr_len: 0 } }
+    pub fn exception(vector: u8, error_code: Option<u32>) -> Self { Event { vector, kind: EventKind::HwException, error_code, instr_len: 0 } }
+}
+
+/// APIC virtualization features the host offers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ApicvCaps {
+    pub tpr_shadow: bool,
+    pub virt_apic_access: bool,
+    pub virt_x2apic: bool,
+    pub apic_reg_virt: bool,
+    pub virt_intr_delivery: bool,
+    pub posted_interrupts: bool,
+    pub avic: bool,
+    pub x2avic: bool,
+}
+
+impl ApicvCaps {
+    /// Interrupt delivery can bypass software injection.
+    pub fn accelerated(&self) -> bool { (self.virt_intr_delivery && self.apic_reg_virt) || self.avic }
+}
+
+/// Probe APICv (VMX capability MSRs) and AVIC (CPUID 0x8000000A).
+pub fn detect_caps() -> ApicvCaps {
+    use crate::hv::features;
+    let mut c = ApicvCaps::default();
+    if features::available("vmx") {
+        // Allowed-1 settings live in the high dword of each control MSR.
+        let pin1 = (unsafe { crate::arch::x86::msr::rdmsr(vmcs::IA32_VMX_PINBASED_CTLS) } >> 32) as u32;
+        let pri1 = (unsafe { crate::arch::x86::msr::rdmsr(vmcs::IA32_VMX_PROCBASED_CTLS) } >> 32) as u32;
+        c.tpr_shadow = (pri1 & vmcs::PROC_TPR_SHADOW) != 0;
+        if (pri1 & vmcs::PROC_ACTIVATE_SECONDARY) != 0 {
+            let sec1 = (unsafe { crate::arch::x86::msr::rdmsr(vmcs::IA32_VMX_PROCBASED_CTLS2) } >> 32) as u32;
+            c.virt_apic_access = (sec1 & vmcs::PROC2_VIRT_APIC_ACCESSES) != 0;
+            c.virt_x2apic = (sec1 & vmcs::PROC2_VIRT_X2APIC) != 0;
+            c.apic_reg_virt = (sec1 & vmcs::PROC2_APIC_REG_VIRT) != 0;
+            c.virt_intr_delivery = (sec1 & vmcs::PROC2_VIRT_INTR_DELIVERY) != 0;
+        }
+        // Posted interrupts additionally require virtual-interrupt delivery.
+        c.posted_interrupts = (pin1 & vmcs::PIN_POSTED_INTERRUPTS) != 0 && c.virt_intr_delivery;
+    }
+    if features::available("svm") {
+        let r = crate::arch::x86::cpuid::cpuid(crate::arch::x86::cpuid::leaf::AMD_SVM, 0);
+        c.avic = (r.edx & svm::SVM_FEAT_AVIC) != 0;
+        c.x2avic = (r.edx & svm::SVM_FEAT_X2AVIC) != 0;
+    }
+    c
+}
+
+/// Record APICv, posted interrupts and AVIC with `hv::features`; needs the
+/// CPU features registered first.
+pub fn register_features() {
+    use crate::hv::features::register_flag;
+    let c = detect_caps();
+    register_flag("event", "apicv", c.virt_intr_delivery && c.apic_reg_virt, "no virtual-interrupt delivery");
+    register_flag("event", "posted-interrupts", c.posted_interrupts, "not supported by the CPU");
+    register_flag("event", "avic", c.avic, "not supported by the CPU");
+}
+
+// ---- VMX ----
+
+/// Build the VM-entry interruption-information field.
+pub fn vmx_intr_info(ev: &Event) -> u32 {
+    let ty: u32 = match ev.kind {
+        EventKind::ExtInterrupt => 0,
+        EventKind::Nmi => 2,
+        EventKind::HwException => 3,
+        EventKind::SwInterrupt => 4,
+        EventKind::SwException => 6,
+    };
+    let mut info = (ev.vector as u32) | (ty << 8) | (1 << 31);
+    if ev.error_code.is_some() { info |= 1 << 11; }
+    info
+}
+
+/// Guest can accept an external interrupt: RFLAGS.IF set, no STI/MOV-SS blocking.
+pub fn vmx_guest_interruptible() -> bool {
+    let rflags = vmcs::vmread(vmcs::VMCS_GUEST_RFLAGS).unwrap_or(0);
+    let intr = vmcs::vmread(vmcs::VMCS_GUEST_INTERRUPTIBILITY).unwrap_or(0);
+    (rflags & (1 << 9)) != 0 && (intr & 0x3) == 0
+}
+
+/// Program the VM-entry fields of the current VMCS to inject `ev`.
+pub fn vmx_inject(ev: &Event) -> Result<(), &'static str> {
+    if let Some(ec) = ev.error_code { vmcs::vmwrite(vmcs::VMCS_ENTRY_EXCEPTION_ERRCODE, ec as u64)?; }
+    if matches!(ev.kind, EventKind::SwInterrupt | EventKind::SwException) {
+        vmcs::vmwrite(vmcs::VMCS_ENTRY_INSTR_LEN, ev.instr_len as u64)?;
+    }
+    vmcs::vmwrite(vmcs::VMCS_ENTRY_INTR_INFO, vmx_intr_info(ev) as u64)?;
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::VIRQ_INJECTED).inc();
+    Ok(())
+}
+
+/// Toggle interrupt-window exiting so we regain control once the guest sets IF.
+pub fn vmx_request_irq_window(enable: bool) -> Result<(), &'static str> {
+    let ctl = vmcs::vmread(vmcs::VMCS_PROCBASED_CTLS)? as u32;
+    let v = if enable { ctl | vmcs::PROC_INTR_WINDOW_EXITING } else { ctl & !vmcs::PROC_INTR_WINDOW_EXITING };
+    if v != ctl { vmcs::vmwrite(vmcs::VMCS_PROCBASED_CTLS, v as u64)?; }
+    Ok(())
+}
+
+/// Deliver the highest pending event of (vm, vcpu) into the current VMCS.
+/// Returns the vector delivered or posted, if any.
+pub fn vmx_deliver_pending(vm_id: u64, vcpu_id: u32, caps: &ApicvCaps) -> Result<Option<u8>, &'static str> {
+    // An event still sitting in the entry field has not been delivered yet.
+    if (vmcs::vmread(vmcs::VMCS_ENTRY_INTR_INFO)? & (1 << 31)) != 0 { return Ok(None); }
+    let nmi = crate::hv::vlapic::with(vm_id, vcpu_id, |l| core::mem::replace(&mut l.nmi_pending, false)).unwrap_or(false);
+    if nmi { vmx_inject(&Event::nmi())?; return Ok(Some(2)); }
+    let now = crate::time::rdtsc();
+    if caps.virt_intr_delivery {
+        // Virtual-interrupt delivery: publish RVI/SVI and let the CPU evaluate priority.
+        let (rvi, svi) = crate::hv::vlapic::with(vm_id, vcpu_id, |l| {
+            let _ = l.tick(now);
+            (l.highest_irr().unwrap_or(0), l.highest_isr().unwrap_or(0))
+        }).unwrap_or((0, 0));
+        vmcs::vmwrite(vmcs::VMCS_GUEST_INTR_STATUS, (rvi as u64) | ((svi as u64) << 8))?;
+        if rvi != 0 { crate::obs::metrics::Counter::new(&crate::obs::metrics::VIRQ_ACCEL).inc(); }
+        return Ok(if rvi != 0 { Some(rvi) } else { None });
+    }
+    let ready = crate::hv::vlapic::with(vm_id, vcpu_id, |l| { let _ = l.tick(now); l.deliverable() }).flatten();
+    if ready.is_none() { vmx_request_irq_window(false)?; return Ok(None); }
+    if !vmx_guest_interruptible() { vmx_request_irq_window(true)?; return Ok(None); }
+    let vec = crate::hv::vlapic::with(vm_id, vcpu_id, |l| l.ack()).flatten();
+    match vec {
+        Some(v) => { vmx_inject(&Event::interrupt(v))?; vmx_request_irq_window(false)?; Ok(Some(v)) }
+        None => Ok(None),
+    }
+}
+
+// ---- SVM ----
+
+/// Build the VMCB EVENTINJ value.
+pub fn svm_eventinj(ev: &Event) -> u64 {
+    let ty: u64 = match ev.kind {
+        EventKind::ExtInterrupt => 0,
+        EventKind::Nmi => 2,
+        EventKind::HwException | EventKind::SwException => 3,
+        EventKind::SwInterrupt => 4,
+    };
+    let mut v = (ev.vector as u64) | (ty << 8) | (1 << 31);
+    if let Some(ec) = ev.error_code { v |= (1 << 11) | ((ec as u64) << 32); }
+    v
+}
+
+#[inline(always)]
+fn vmcb_rd(vmcb: *mut u8, off: usize) -> u64 { unsafe { core::ptr::read_volatile(vmcb.add(off) as *const u64) } }
+#[inline(always)]
+fn vmcb_wr(vmcb: *mut u8, off: usize, v: u64) { unsafe { core::ptr::write_volatile(vmcb.add(off) as *mut u64, v) } }
+
+/// Write EVENTINJ for the next VMRUN.
+pub fn svm_inject(vmcb: *mut u8, ev: &Event) {
+    vmcb_wr(vmcb, svm::VMCB_EVENTINJ, svm_eventinj(ev));
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::VIRQ_INJECTED).inc();
+}
+
+/// Queue a virtual interrupt through V_IRQ; the CPU delivers it once the guest is interruptible.
+pub fn svm_set_virq(vmcb: *mut u8, vector: u8) {
+    let ctl = vmcb_rd(vmcb, svm::VMCB_INT_CTL);
+    let prio = ((vector as u64) >> 4) & 0xF;
+    let ctl = (ctl & !(0xF << 16)) | svm::INT_CTL_V_IRQ | (prio << 16) | svm::INT_CTL_V_INTR_MASKING;
+    vmcb_wr(vmcb, svm::VMCB_INT_VECTOR, vector as u64);
+    vmcb_wr(vmcb, svm::VMCB_INT_CTL, ctl);
+}
+
+/// Set a vector in the IRR of an AVIC backing page (same layout as the xAPIC page).
+///
+/// # Safety
+/// `backing_page` must point to a mapped, 4 KiB aligned AVIC backing page.
+pub unsafe fn avic_set_irr(backing_page: *mut u8, vector: u8) {
+    let off = crate::hv::vlapic::REG_IRR as usize + ((vector as usize >> 5) << 4);
+    let p = backing_page.add(off) as *const core::sync::atomic::AtomicU32;
+    (*p).fetch_or(1 << (vector & 31), core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Deliver the highest pending event of (vm, vcpu) into `vmcb`.
+/// With AVIC and a backing page the vector is posted there instead.
+pub fn svm_deliver_pending(vmcb: *mut u8, avic_backing: Option<*mut u8>, vm_id: u64, vcpu_id: u32) -> Option<u8> {
+    if (vmcb_rd(vmcb, svm::VMCB_EVENTINJ) & (1 << 31)) != 0 { return None; }
+    let nmi = crate::hv::vlapic::with(vm_id, vcpu_id, |l| core::mem::replace(&mut l.nmi_pending, false)).unwrap_or(false);
+    if nmi { svm_inject(vmcb, &Event::nmi()); return Some(2); }
+    let now = crate::time::rdtsc();
+    if let Some(page) = avic_backing {
+        let vec = crate::hv::vlapic::with(vm_id, vcpu_id, |l| { let _ = l.tick(now); l.ack() }).flatten()?;
+        // The caller passes the backing page it installed in this VMCB.
+        unsafe { avic_set_irr(page, vec) };
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::VIRQ_ACCEL).inc();
+        return Some(vec);
+    }
+    // V_IRQ still pending means the guest has not taken the previous vector.
+    if (vmcb_rd(vmcb, svm::VMCB_INT_CTL) & svm::INT_CTL_V_IRQ) != 0 { return None; }
+    let vec = crate::hv::vlapic::with(vm_id, vcpu_id, |l| { let _ = l.tick(now); l.ack() }).flatten()?;
+    svm_set_virq(vmcb, vec);
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::VIRQ_INJECTED).inc();
+    Some(vec)
+}
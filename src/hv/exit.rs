@@ -0,0 +1,122 @@
+#![allow(dead_code)]
+
+//! Device-access exits: EPT/NPT violations on emulated regions and port I/O.
+//!
+//! The instruction at guest RIP is fetched through the guest's own page
+//! tables, decoded by `hv::emul` and executed against `hv::bus`. Port I/O
+//! uses the exit qualification directly and only falls back to decoding when
+//! the hardware does not report the access.
+
+use crate::hv::vcpu::GuestRegs;
+
+/// Paging state needed to fetch guest instructions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GuestPaging {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+/// Outcome of an emulated exit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Access emulated; registers and RIP updated
+    Handled,
+    /// No device claims the address; caller decides (inject #GP/#PF, or map RAM)
+    Unclaimed,
+}
+
+fn read_gpa_u64(vm_id: u64, gpa: u64) -> Option<u64> {
+    let h = crate::hv::loader::gpa_to_host(vm_id, gpa)?;
+    Some(unsafe { core::ptr::read_volatile(h as *const u64) })
+}
+
+/// Walk the guest's 4-level page tables (no PCID/LA57). Returns the guest-physical address.
+pub fn gva_to_gpa(vm_id: u64, paging: &GuestPaging, gva: u64) -> Option<u64> {
+    // Paging disabled: linear == physical.
+    if (paging.cr0 & (1 << 31)) == 0 { return Some(gva); }
+    if (paging.efer & (1 << 10)) == 0 { return None; } // only long mode (LMA) is supported
+    let mut table = paging.cr3 & 0x000F_FFFF_FFFF_F000;
+    for level in (0..4).rev() {
+        let idx = (gva >> (12 + 9 * level)) & 0x1FF;
+        let e = read_gpa_u64(vm_id, table + idx * 8)?;
+        if (e & 1) == 0 { return None; }
+        let addr = e & 0x000F_FFFF_FFFF_F000;
+        // 1 GiB / 2 MiB leaves
+        if (level == 2 || level == 1) && (e & (1 << 7)) != 0 {
+            let span = 1u64 << (12 + 9 * level);
+            return Some((addr & !(span - 1)) | (gva & (span - 1)));
+        }
+        if level == 0 { return Some(addr | (gva & 0xFFF)); }
+        table = addr;
+    }
+    None
+}
+
+/// Copy up to `out.len()` instruction bytes from guest RIP, stopping at an unmapped page.
+pub fn fetch(vm_id: u64, paging: &GuestPaging, rip: u64, out: &mut [u8]) -> usize {
+    let mut n = 0usize;
+    while n < out.len() {
+        let va = rip.wrapping_add(n as u64);
+        let gpa = match gva_to_gpa(vm_id, paging, va) { Some(g) => g, None => break };
+        let host = match crate::hv::loader::gpa_to_host(vm_id, gpa) { Some(h) => h, None => break };
+        let chunk = ((0x1000 - (va & 0xFFF)) as usize).min(out.len() - n);
+        for k in 0..chunk { out[n + k] = unsafe { core::ptr::read_volatile((host + k as u64) as *const u8) }; }
+        n += chunk;
+    }
+    n
+}
+
+/// Handle an EPT/NPT violation at `gpa`.
+pub fn handle_mmio(vm_id: u64, vcpu: u32, paging: &GuestPaging, regs: &mut GuestRegs, gpa: u64) -> Result<Outcome, &'static str> {
+    if crate::hv::bus::find_mmio(vm_id, gpa).is_none() { return Ok(Outcome::Unclaimed); }
+    let mut bytes = [0u8; crate::hv::emul::MAX_INSN_LEN];
+    let n = fetch(vm_id, paging, regs.rip, &mut bytes);
+    if n == 0 { return Err("exit: instruction fetch failed"); }
+    let insn = match crate::hv::emul::decode(&bytes[..n]) {
+        Ok(i) => i,
+        Err(e) => { crate::obs::metrics::Counter::new(&crate::obs::metrics::EMUL_FAIL).inc(); return Err(e); }
+    };
+    crate::hv::emul::exec_mmio(&insn, regs, vm_id, vcpu, gpa)?;
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::EMUL_MMIO).inc();
+    Ok(Outcome::Handled)
+}
+
+/// Decoded VMX I/O exit qualification.
+#[derive(Clone, Copy, Debug)]
+pub struct IoQual { pub port: u16, pub size: u8, pub is_in: bool, pub string: bool, pub rep: bool }
+
+pub fn vmx_io_qual(q: u64) -> IoQual {
+    IoQual {
+        size: ((q & 7) + 1) as u8,
+        is_in: (q & (1 << 3)) != 0,
+        string: (q & (1 << 4)) != 0,
+        rep: (q & (1 << 5)) != 0,
+        port: (q >> 16) as u16,
+    }
+}
+
+/// SVM IOIO EXITINFO1 uses a different layout (type bit 0, sizes one-hot in 6:4).
+pub fn svm_io_qual(info1: u64) -> IoQual {
+    let size = if (info1 & (1 << 4)) != 0 { 1 } else if (info1 & (1 << 5)) != 0 { 2 } else { 4 };
+    IoQual { size, is_in: (info1 & 1) != 0, string: (info1 & (1 << 2)) != 0, rep: (info1 & (1 << 3)) != 0, port: (info1 >> 16) as u16 }
+}
+
+/// Handle a port I/O exit. `insn_len` is the exit-reported instruction length
+/// (0 if unknown, in which case the instruction is decoded to find it).
+pub fn handle_io(vm_id: u64, vcpu: u32, paging: &GuestPaging, regs: &mut GuestRegs, q: IoQual, insn_len: u8) -> Result<Outcome, &'static str> {
+    if q.string { return Err("exit: INS/OUTS not emulated"); }
+    if insn_len != 0 {
+        crate::hv::emul::pio(regs, vm_id, vcpu, q.port, q.size, q.is_in)?;
+        regs.rip = regs.rip.wrapping_add(insn_len as u64);
+    } else {
+        let mut bytes = [0u8; crate::hv::emul::MAX_INSN_LEN];
+        let n = fetch(vm_id, paging, regs.rip, &mut bytes);
+        let insn = crate::hv::emul::decode(&bytes[..n]).map_err(|e| { crate::obs::metrics::Counter::new(&crate::obs::metrics::EMUL_FAIL).inc(); e })?;
+        crate::hv::emul::exec_pio(&insn, regs, vm_id, vcpu)?;
+    }
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::EMUL_PIO).inc();
+    // Unclaimed ports behave as an empty ISA bus (reads all-ones), so the access is still complete.
+    Ok(Outcome::Handled)
+}
@@ -0,0 +1,140 @@
+#![allow(dead_code)]
+
+//! Host capability registry.
+//!
+//! Each subsystem records what it can offer on this host as it initializes:
+//! CPU virtualization extensions, confidential-computing modes, IOMMUs, the
+//! TPM and the optional build features. Code that depends on a capability
+//! asks `available` at run time instead of testing the CPU or a `cfg!` flag
+//! itself, so the answer is the same everywhere, it accounts for firmware
+//! having switched something off, and it is what `version` and
+//! `GET /v1/capabilities` report.
+//!
+//! A feature that was never registered is `Absent`. Registering a name
+//! again replaces the entry, so a subsystem can update its capabilities
+//! when it learns more (after a device probe, say).
+
+use alloc::vec::Vec;
+
+use crate::arch::x86::cpuid;
+use crate::arch::x86::msr::rdmsr;
+use crate::arch::x86::vm::vmcs;
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_FEATURES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Usable now
+    Available,
+    /// Present, but switched off by firmware or missing a prerequisite
+    Disabled,
+    /// Not built in, or the hardware lacks it
+    Absent,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self { Status::Available => "available", Status::Disabled => "disabled", Status::Absent => "absent" }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Feature {
+    pub name: &'static str,
+    /// Subsystem that registered it
+    pub subsystem: &'static str,
+    pub status: Status,
+    /// Why it is not available, or a short qualifier; may be empty
+    pub note: &'static str,
+}
+
+static REGISTRY: SpinLock<Vec<Feature>> = SpinLock::new(Vec::new());
+
+/// Record `name` as offered by `subsystem`, replacing an earlier entry.
+pub fn register(subsystem: &'static str, name: &'static str, status: Status, note: &'static str) {
+    let f = Feature { name, subsystem, status, note };
+    let added = REGISTRY.lock(|r| {
+        if let Some(e) = r.iter_mut().find(|e| e.name == name) { *e = f; return true; }
+        if r.len() >= MAX_FEATURES || r.try_reserve(1).is_err() { return false; }
+        r.push(f);
+        true
+    });
+    if !added { crate::log!(Warn, "features", "registry full, {} not recorded", name); }
+}
+
+/// Shorthand for features that are either there or not.
+pub fn register_flag(subsystem: &'static str, name: &'static str, present: bool, missing: &'static str) {
+    if present { register(subsystem, name, Status::Available, ""); } else { register(subsystem, name, Status::Absent, missing); }
+}
+
+pub fn status(name: &str) -> Status { find(name).map_or(Status::Absent, |f| f.status) }
+
+pub fn available(name: &str) -> bool { status(name) == Status::Available }
+
+pub fn find(name: &str) -> Option<Feature> { REGISTRY.lock(|r| r.iter().find(|f| f.name == name).copied()) }
+
+/// Features in registration order.
+pub fn for_each(mut f: impl FnMut(&Feature)) {
+    let r = REGISTRY.lock(|r| r.clone());
+    for e in r.iter() { f(e); }
+}
+
+/// Register what no other init path covers: the CPU's virtualization
+/// extensions, then the subsystems that probe lazily or are build options.
+/// Device subsystems (IOMMUs, TPM) add theirs when they are probed.
+pub fn init() {
+    probe_cpu();
+    crate::hv::event::register_features();
+    crate::hv::confidential::register_features();
+    crate::migrate::register_features();
+}
+
+fn probe_cpu() {
+    const NO_HW: &str = "not supported by the CPU";
+    let vmx = cpuid::has_vmx();
+    // IA32_FEATURE_CONTROL locked without VMX outside SMX
+    let vmx_locked = vmx && { let fc = unsafe { rdmsr(0x3A) }; fc & 1 != 0 && fc & (1 << 2) == 0 };
+    match (vmx, vmx_locked) {
+        (false, _) => register("cpu", "vmx", Status::Absent, NO_HW),
+        (true, true) => register("cpu", "vmx", Status::Disabled, "locked off in firmware"),
+        (true, false) => register("cpu", "vmx", Status::Available, ""),
+    }
+    let sec1 = if vmx && (unsafe { rdmsr(vmcs::IA32_VMX_PROCBASED_CTLS) } >> 32) as u32 & vmcs::PROC_ACTIVATE_SECONDARY != 0 {
+        (unsafe { rdmsr(vmcs::IA32_VMX_PROCBASED_CTLS2) } >> 32) as u32
+    } else { 0 };
+    let ept_cap = if vmx { unsafe { rdmsr(crate::arch::x86::msr::IA32_VMX_EPT_VPID_CAP) } } else { 0 };
+    let ept = sec1 & vmcs::PROC2_ENABLE_EPT != 0;
+    let ept_ad = ept && ept_cap & (1 << 21) != 0;
+    let vmx_sub = |name: &'static str, has: bool| {
+        if !has { register("cpu", name, Status::Absent, NO_HW); }
+        else if vmx_locked { register("cpu", name, Status::Disabled, "needs vmx"); }
+        else { register("cpu", name, Status::Available, ""); }
+    };
+    vmx_sub("ept", ept);
+    vmx_sub("ept-ad", ept_ad);
+    vmx_sub("vpid", sec1 & vmcs::PROC2_ENABLE_VPID != 0);
+    vmx_sub("unrestricted-guest", sec1 & vmcs::PROC2_UNRESTRICTED_GUEST != 0);
+    // PML logs through the EPT accessed/dirty flags
+    vmx_sub("pml", sec1 & vmcs::PROC2_ENABLE_PML != 0 && ept_ad);
+
+    let svm = cpuid::has_svm();
+    // VM_CR.SVMDIS
+    let svm_off = svm && unsafe { rdmsr(0xC001_0114) } & (1 << 4) != 0;
+    match (svm, svm_off) {
+        (false, _) => register("cpu", "svm", Status::Absent, NO_HW),
+        (true, true) => register("cpu", "svm", Status::Disabled, "disabled in firmware"),
+        (true, false) => register("cpu", "svm", Status::Available, ""),
+    }
+    let npt = svm && cpuid::has_npt();
+    match (npt, svm_off) {
+        (false, _) => register("cpu", "npt", Status::Absent, NO_HW),
+        (true, true) => register("cpu", "npt", Status::Disabled, "needs svm"),
+        (true, false) => register("cpu", "npt", Status::Available, ""),
+    }
+
+    register_flag("cpu", "x2apic", cpuid::has_x2apic(), NO_HW);
+    register_flag("cpu", "tsc-deadline", cpuid::has_tsc_deadline(), NO_HW);
+    register_flag("cpu", "invariant-tsc", cpuid::has_invariant_tsc(), NO_HW);
+    register_flag("cpu", "1g-pages", cpuid::has_page1gb(), NO_HW);
+}
@@ -0,0 +1,360 @@
+#![allow(dead_code)]
+
+//! FPGA partial reconfiguration and region assignment.
+//!
+//! Cards following the Device Feature List layout (OPAE: Intel PAC, N3000,
+//! D5005 and the like) describe themselves in a chain of 64-bit feature
+//! headers starting at BAR0 of the PF. The FPGA Management Engine (FME)
+//! heads the chain; its header lists up to four ports, and its private
+//! partial-reconfiguration (PR) feature rewrites the region behind port `n`
+//! from a raw bitstream pushed one dword at a time. A region is that port
+//! and the accelerator loaded into it.
+//!
+//! Bitstreams are read from the ESP and carry the trailer guest images use
+//! (`hv::imgsig`); they are checked against the same trusted keys under the
+//! same policy, and every check is audited as `fpga_load`. A VM gets a
+//! region as a PCI function: its port is switched to SR-IOV access, shows
+//! up as a VF of the card, and that VF is attached through `hv::sriov`, so
+//! the accelerator's DMA stays inside the VM's IOMMU domain. Releasing a
+//! region, or destroying its VM, soft-resets the port so nothing the guest
+//! left in the accelerator survives to the next holder.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::imgsig::{self, Verdict};
+use crate::hv::sriov::{self, Bdf};
+use crate::iommu::{mmio_read16, mmio_read32};
+use crate::util::spinlock::SpinLock;
+
+/// PCI class of processing accelerators, which DFL cards report
+pub const CLASS_ACCELERATOR: u8 = 0x12;
+pub const MAX_CARDS: usize = 4;
+pub const MAX_REGIONS: usize = 4;
+/// Longest ESP path remembered for a loaded bitstream
+pub const PATH_LEN: usize = 48;
+
+// Feature header (DFH) fields
+const DFH_TYPE_SHIFT: u32 = 60;
+const DFH_TYPE_PRIVATE: u64 = 3;
+const DFH_TYPE_FIU: u64 = 4;
+const DFH_EOL: u64 = 1 << 40;
+const FIU_FME: u64 = 0;
+const FME_FEATURE_PR: u64 = 5;
+/// Bound on the feature walk, against a corrupt chain
+const MAX_FEATURES: usize = 64;
+
+// FME header
+const FME_PORT_OFST: usize = 0x38;
+const PORT_OFST_MASK: u64 = 0xFF_FFFF;
+const PORT_BAR_SHIFT: u32 = 32;
+/// Port is reached through a VF rather than the PF
+const PORT_ACC_VF: u64 = 1 << 55;
+const PORT_IMPLEMENTED: u64 = 1 << 60;
+
+// Port header
+const PORT_CTRL: usize = 0x38;
+const PORT_SFTRST: u64 = 1 << 0;
+const PORT_SFTRST_ACK: u64 = 1 << 4;
+
+// PR feature
+const PR_CTRL: usize = 0x08;
+const PR_STS: usize = 0x10;
+const PR_DATA: usize = 0x18;
+const PR_ERR: usize = 0x20;
+const PR_CTRL_RST: u64 = 1 << 0;
+const PR_CTRL_RSTACK: u64 = 1 << 4;
+const PR_CTRL_RGN_SHIFT: u32 = 7;
+const PR_CTRL_RGN_MASK: u64 = 0x7 << PR_CTRL_RGN_SHIFT;
+const PR_CTRL_START: u64 = 1 << 12;
+const PR_CTRL_COMPLETE: u64 = 1 << 13;
+const PR_STS_CREDIT: u64 = 0x1FF;
+const PR_STS_BUSY: u64 = 1 << 16;
+const PR_STS_HOST_SHIFT: u32 = 24;
+const PR_ERR_INCOMPATIBLE: u64 = 1 << 2;
+const PR_ERR_CRC: u64 = 1 << 1;
+const PR_TIMEOUT_US: u64 = 8_000_000;
+const RESET_TIMEOUT_US: u64 = 10_000;
+
+fn rd64(addr: usize) -> u64 { unsafe { core::ptr::read_volatile(addr as *const u64) } }
+fn wr64(addr: usize, v: u64) { unsafe { core::ptr::write_volatile(addr as *mut u64, v) } }
+
+/// Spin until `done` or `us` microseconds pass.
+fn wait(us: u64, done: impl FnMut() -> bool) -> bool {
+    crate::time::clock::spin_until(crate::time::clock::Deadline::after_us(us), done)
+}
+
+/// A card found at a PF.
+#[derive(Clone, Copy, Debug)]
+pub struct Card {
+    pub bdf: Bdf,
+    pub vendor: u16,
+    pub device: u16,
+    /// FME header
+    fme: usize,
+    /// PR feature, if the card can be reconfigured
+    pr: Option<usize>,
+    /// Host address of each PF BAR
+    bars: [u64; 6],
+    pub regions: u8,
+}
+
+impl Card {
+    fn port_ofst(&self, region: u8) -> u64 { rd64(self.fme + FME_PORT_OFST + region as usize * 8) }
+
+    pub fn implemented(&self, region: u8) -> bool { region < self.regions && self.port_ofst(region) & PORT_IMPLEMENTED != 0 }
+
+    /// The port is handed out as a VF.
+    pub fn vf_access(&self, region: u8) -> bool { self.port_ofst(region) & PORT_ACC_VF != 0 }
+
+    pub fn reconfigurable(&self) -> bool { self.pr.is_some() }
+
+    /// VF number of `region`: VF-access ports are numbered in port order.
+    fn vf_index(&self, region: u8) -> u16 { (0..region).filter(|&r| self.implemented(r) && self.vf_access(r)).count() as u16 }
+
+    fn vf_ports(&self) -> u16 { (0..self.regions).filter(|&r| self.implemented(r) && self.vf_access(r)).count() as u16 }
+
+    /// Port header of `region`, wherever it is reachable from the host.
+    fn port(&self, region: u8) -> Option<usize> {
+        let o = self.port_ofst(region);
+        if o & PORT_ACC_VF == 0 {
+            let bar = self.bars.get(((o >> PORT_BAR_SHIFT) & 7) as usize).copied().filter(|&b| b != 0)?;
+            return Some((bar + (o & PORT_OFST_MASK)) as usize);
+        }
+        // A VF's feature list starts with its port at BAR0.
+        let pf = sriov::pf(self.bdf)?;
+        let k = self.vf_index(region);
+        if k >= pf.num_vfs || pf.bar_base[0] == 0 { return None; }
+        Some((pf.bar_base[0] + k as u64 * pf.bar_size[0]) as usize)
+    }
+}
+
+/// Pulse the port's soft reset. False if the port did not acknowledge.
+fn reset_port(port: usize) -> bool {
+    wr64(port + PORT_CTRL, rd64(port + PORT_CTRL) | PORT_SFTRST);
+    let acked = wait(RESET_TIMEOUT_US, || rd64(port + PORT_CTRL) & PORT_SFTRST_ACK != 0);
+    wr64(port + PORT_CTRL, rd64(port + PORT_CTRL) & !PORT_SFTRST);
+    acked
+}
+
+/// Read the FME out of the card at `bdf`, if it has one.
+pub fn probe(system_table: &SystemTable<Boot>, bdf: Bdf) -> Option<Card> {
+    let cfg = crate::iommu::ecam_cfg_base(system_table, bdf.seg, bdf.bus, bdf.dev, bdf.func)?;
+    let vendor = mmio_read16(cfg + crate::hv::vpci::CFG_VENDOR);
+    if vendor == 0xFFFF { return None; }
+    let mut bars = [0u64; 6];
+    let mut i = 0;
+    while i < 6 {
+        let lo = mmio_read32(cfg + crate::hv::vpci::CFG_BAR0 + i * 4);
+        let wide = lo & 0b111 == 0b100 && i < 5;
+        if lo & 1 == 0 {
+            let hi = if wide { mmio_read32(cfg + crate::hv::vpci::CFG_BAR0 + i * 4 + 4) as u64 } else { 0 };
+            bars[i] = hi << 32 | (lo & !0xF) as u64;
+        }
+        i += if wide { 2 } else { 1 };
+    }
+    if bars[0] == 0 { return None; }
+    let fme = bars[0] as usize;
+    let dfh = rd64(fme);
+    if dfh >> DFH_TYPE_SHIFT != DFH_TYPE_FIU || dfh & 0xFFF != FIU_FME { return None; }
+    let mut card = Card { bdf, vendor, device: mmio_read16(cfg + crate::hv::vpci::CFG_DEVICE), fme, pr: None, bars, regions: 0 };
+    card.regions = (0..MAX_REGIONS as u8).take_while(|&r| card.port_ofst(r) & PORT_IMPLEMENTED != 0).count() as u8;
+    let mut at = fme;
+    for _ in 0..MAX_FEATURES {
+        let h = rd64(at);
+        // The FME's own features end where the next unit (a port) begins.
+        if at != fme && h >> DFH_TYPE_SHIFT == DFH_TYPE_FIU { break; }
+        if h >> DFH_TYPE_SHIFT == DFH_TYPE_PRIVATE && h & 0xFFF == FME_FEATURE_PR { card.pr = Some(at); }
+        let next = ((h >> 16) & 0xFF_FFFF) as usize;
+        if h & DFH_EOL != 0 || next == 0 { break; }
+        at += next;
+    }
+    Some(card)
+}
+
+/// Call `f` for every DFL card among the accelerators MCFG covers. Only
+/// accelerator-class functions are probed, as probing reads BAR0.
+pub fn for_each_card(system_table: &SystemTable<Boot>, mut f: impl FnMut(&Card)) {
+    let Some(mcfg) = crate::firmware::acpi::find_mcfg(system_table) else { return };
+    let mut found: [Option<Bdf>; MAX_CARDS] = [None; MAX_CARDS];
+    let mut n = 0;
+    crate::firmware::acpi::mcfg_for_each_allocation_from(|a| {
+        let mut bus = a.start_bus;
+        loop {
+            for dev in 0u8..32 {
+                for func in 0u8..8 {
+                    let cfg = crate::iommu::ecam_fn_base(a.base_address, a.start_bus, bus, dev, func);
+                    if mmio_read16(cfg) == 0xFFFF || crate::iommu::mmio_read8(cfg + crate::hv::vpci::CFG_CLASS) != CLASS_ACCELERATOR { continue; }
+                    if n < MAX_CARDS { found[n] = Some(Bdf { seg: a.pci_segment, bus, dev, func }); n += 1; }
+                }
+            }
+            if bus >= a.end_bus { break; }
+            bus += 1;
+        }
+    }, mcfg);
+    for &bdf in found.iter().flatten() {
+        if let Some(c) = probe(system_table, bdf) { f(&c); }
+    }
+}
+
+/// What a region holds.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub card: Bdf,
+    pub index: u8,
+    /// ESP path of the bitstream loaded since boot
+    pub path: [u8; PATH_LEN],
+    pub path_len: usize,
+    pub verdict: Option<Verdict>,
+    pub vm_id: Option<u64>,
+    pub vf: Option<Bdf>,
+}
+
+impl Region {
+    pub fn path(&self) -> &str { core::str::from_utf8(&self.path[..self.path_len]).unwrap_or("") }
+}
+
+static REGIONS: SpinLock<[Option<Region>; MAX_CARDS * MAX_REGIONS]> = SpinLock::new([None; MAX_CARDS * MAX_REGIONS]);
+
+fn with_region<R>(card: Bdf, index: u8, f: impl FnOnce(&mut Region) -> R) -> Result<R, &'static str> {
+    REGIONS.lock(|t| {
+        if let Some(r) = t.iter_mut().flatten().find(|r| r.card == card && r.index == index) { return Ok(f(r)); }
+        let slot = t.iter_mut().find(|r| r.is_none()).ok_or("fpga: region table full")?;
+        let r = slot.insert(Region { card, index, path: [0; PATH_LEN], path_len: 0, verdict: None, vm_id: None, vf: None });
+        Ok(f(r))
+    })
+}
+
+pub fn region(card: Bdf, index: u8) -> Option<Region> {
+    REGIONS.lock(|t| t.iter().flatten().find(|r| r.card == card && r.index == index).copied())
+}
+
+pub fn for_each_region(mut f: impl FnMut(&Region)) {
+    let snap = REGIONS.lock(|t| *t);
+    for r in snap.iter().flatten() { f(r); }
+}
+
+/// Push `data` through the PR engine into region `index`.
+fn program(pr: usize, index: u8, data: &[u8]) -> Result<(), &'static str> {
+    // Clear stale errors (write-one-to-clear), then reset the engine.
+    let err = rd64(pr + PR_ERR);
+    if err != 0 { wr64(pr + PR_ERR, err); }
+    let mut ctrl = rd64(pr + PR_CTRL) & !PR_CTRL_RGN_MASK;
+    ctrl |= (index as u64) << PR_CTRL_RGN_SHIFT;
+    wr64(pr + PR_CTRL, ctrl | PR_CTRL_RST);
+    if !wait(RESET_TIMEOUT_US, || rd64(pr + PR_CTRL) & PR_CTRL_RSTACK != 0) { return Err("fpga: PR engine did not leave reset"); }
+    wr64(pr + PR_CTRL, rd64(pr + PR_CTRL) & !PR_CTRL_RST);
+    if !wait(PR_TIMEOUT_US, || (rd64(pr + PR_STS) >> PR_STS_HOST_SHIFT) & 0xF == 0) { return Err("fpga: PR engine busy"); }
+    wr64(pr + PR_CTRL, rd64(pr + PR_CTRL) | PR_CTRL_START);
+
+    let mut credit = 0u64;
+    for w in data.chunks_exact(4) {
+        while credit == 0 {
+            if !wait(PR_TIMEOUT_US, || rd64(pr + PR_STS) & PR_STS_CREDIT != 0) { return Err("fpga: PR engine stalled"); }
+            credit = rd64(pr + PR_STS) & PR_STS_CREDIT;
+        }
+        wr64(pr + PR_DATA, u32::from_le_bytes([w[0], w[1], w[2], w[3]]) as u64);
+        credit -= 1;
+    }
+
+    wr64(pr + PR_CTRL, rd64(pr + PR_CTRL) | PR_CTRL_COMPLETE);
+    if !wait(PR_TIMEOUT_US, || rd64(pr + PR_STS) & PR_STS_BUSY == 0) { return Err("fpga: PR did not complete"); }
+    let err = rd64(pr + PR_ERR);
+    if err != 0 {
+        wr64(pr + PR_ERR, err);
+        return Err(if err & PR_ERR_INCOMPATIBLE != 0 { "fpga: bitstream does not fit this FME" } else if err & PR_ERR_CRC != 0 { "fpga: bitstream CRC error" } else { "fpga: PR failed" });
+    }
+    Ok(())
+}
+
+/// Load the partial bitstream at `path` on the ESP into region `index` of
+/// the card at `bdf`. The region must not be assigned.
+pub fn load(system_table: &mut SystemTable<Boot>, bdf: Bdf, index: u8, path: &str) -> Result<Verdict, &'static str> {
+    let card = probe(system_table, bdf).ok_or("fpga: no DFL FPGA at that address")?;
+    let pr = card.pr.ok_or("fpga: card has no partial-reconfiguration engine")?;
+    if !card.implemented(index) { return Err("fpga: no such region"); }
+    if region(bdf, index).is_some_and(|r| r.vm_id.is_some()) { return Err("fpga: region is assigned; release it first"); }
+    let (buf, pages, size) = crate::hv::loader::read_esp_file(system_table, path)?;
+    let img = unsafe { core::slice::from_raw_parts(buf, size) };
+    let (verdict, len) = imgsig::check(system_table, img);
+    let refused = imgsig::strict() && !matches!(verdict, Verdict::Trusted { .. });
+    crate::diag::audit::record(crate::diag::audit::AuditKind::FpgaLoad {
+        seg: bdf.seg, bus: bdf.bus, dev: bdf.dev, func: bdf.func, region: index, verdict: verdict.code(), refused,
+    });
+    let r = if refused {
+        Err(if verdict == Verdict::Unsigned { "fpga: bitstream is unsigned (strict signature policy)" } else { "fpga: bitstream signature not trusted (strict signature policy)" })
+    } else if len == 0 || len % 4 != 0 {
+        Err("fpga: bitstream length is not a whole number of dwords")
+    } else {
+        // Hold the port in reset while its region is rewritten.
+        let port = card.port(index);
+        if let Some(p) = port { wr64(p + PORT_CTRL, rd64(p + PORT_CTRL) | PORT_SFTRST); }
+        let r = program(pr, index, &img[..len]);
+        if let Some(p) = port { wr64(p + PORT_CTRL, rd64(p + PORT_CTRL) & !PORT_SFTRST); }
+        r
+    };
+    crate::mm::uefi::free_pages(system_table, buf, pages);
+    r?;
+    let n = path.len().min(PATH_LEN);
+    with_region(bdf, index, |r| { r.path[..n].copy_from_slice(&path.as_bytes()[..n]); r.path_len = n; r.verdict = Some(verdict); })?;
+    crate::obs::log::info(system_table, "fpga", "region reconfigured");
+    Ok(verdict)
+}
+
+/// Give region `index` of the card at `bdf` to `vm_id` as a PCI function.
+/// Switching a port to VF access restarts SR-IOV on the card, so it fails
+/// while another region is assigned and still on the PF side.
+pub fn assign(system_table: &mut SystemTable<Boot>, vm_id: u64, bdf: Bdf, index: u8) -> Result<Bdf, &'static str> {
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err("fpga: no such vm"); }
+    let card = probe(system_table, bdf).ok_or("fpga: no DFL FPGA at that address")?;
+    if !card.implemented(index) { return Err("fpga: no such region"); }
+    if region(bdf, index).is_some_and(|r| r.vm_id.is_some()) { return Err("fpga: region already assigned"); }
+    if !card.vf_access(index) {
+        if sriov::pf(bdf).is_some() { sriov::disable(bdf)?; }
+        let at = card.fme + FME_PORT_OFST + index as usize * 8;
+        wr64(at, rd64(at) | PORT_ACC_VF);
+    }
+    let want = card.vf_ports();
+    if sriov::pf(bdf).map_or(true, |p| p.num_vfs != want) {
+        if sriov::pf(bdf).is_some() { sriov::disable(bdf)?; }
+        sriov::enable(system_table, bdf, want)?;
+    }
+    let pf = sriov::pf(bdf).ok_or("fpga: SR-IOV did not come up")?;
+    let vf = pf.vf_bdf(card.vf_index(index));
+    if let Some(p) = card.port(index) { reset_port(p); }
+    sriov::attach(system_table, vm_id, vf)?;
+    with_region(bdf, index, |r| { r.vm_id = Some(vm_id); r.vf = Some(vf); })?;
+    Ok(vf)
+}
+
+/// Take region `index` of the card at `bdf` back from `vm_id` and reset it.
+pub fn release(system_table: &mut SystemTable<Boot>, vm_id: u64, bdf: Bdf, index: u8) -> Result<(), &'static str> {
+    let r = region(bdf, index).filter(|r| r.vm_id == Some(vm_id)).ok_or("fpga: region not assigned to this vm")?;
+    if let Some(vf) = r.vf { let _ = sriov::detach(system_table, vm_id, vf); }
+    let reset = probe(system_table, bdf).and_then(|c| c.port(index)).map(reset_port);
+    with_region(bdf, index, |r| { r.vm_id = None; r.vf = None; })?;
+    if reset == Some(false) { crate::obs::log::warn(system_table, "fpga", "port did not acknowledge reset"); }
+    Ok(())
+}
+
+/// Reset every region of a destroyed VM. Its VFs are released by
+/// `sriov::detach_vm`, which runs after this.
+pub fn detach_vm(vm_id: u64) {
+    let mut held = [None; MAX_CARDS * MAX_REGIONS];
+    REGIONS.lock(|t| for (h, r) in held.iter_mut().zip(t.iter_mut().flatten()) {
+        if r.vm_id == Some(vm_id) { *h = Some((r.vf, r.card)); r.vm_id = None; r.vf = None; }
+    });
+    for &(vf, card) in held.iter().flatten() {
+        // The VF's BAR0 is the port; reset it directly, without MCFG.
+        let Some(pf) = sriov::pf(card) else { continue };
+        let Some(k) = vf.and_then(|vf| pf.vf_index(vf)) else { continue };
+        if pf.bar_base[0] != 0 { reset_port((pf.bar_base[0] + k as u64 * pf.bar_size[0]) as usize); }
+    }
+}
+
+/// Release every region `vm_id` holds. Returns how many.
+pub fn release_all(system_table: &mut SystemTable<Boot>, vm_id: u64) -> u32 {
+    let mut held = [None; MAX_CARDS * MAX_REGIONS];
+    REGIONS.lock(|t| for (h, r) in held.iter_mut().zip(t.iter().flatten()) { if r.vm_id == Some(vm_id) { *h = Some((r.card, r.index)); } });
+    held.iter().flatten().filter(|&&(card, k)| release(system_table, vm_id, card, k).is_ok()).count() as u32
+}
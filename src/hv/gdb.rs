@@ -0,0 +1,401 @@
+#![allow(dead_code)]
+
+//! GDB remote serial protocol stub for guest debugging.
+//!
+//! `attach` binds a running VM to a 16550 COM port (polled, 115200 8N1) and
+//! halts it; `target remote /dev/ttyS0` on the other end takes over. The CLI
+//! idle loop calls `poll`, which answers packets and reports stops. The VM
+//! is debugged all-stop: when one vCPU stops, `hv::run` parks the others.
+//! The debugger sees a single thread, the vCPU that stopped (the first one
+//! after an interrupt); stepping runs only that vCPU.
+//!
+//! Packets: qSupported, ?, g/G, m/M, c/s, Z0-Z2/Z4 and z, H, D, k and the
+//! Ctrl-C interrupt. Software breakpoints patch INT3 into guest memory at
+//! the guest-physical address the breakpoint translated to when it was set.
+//! Instruction breakpoints and write or access watchpoints use DR0-3; x86
+//! has no read-only watchpoint, so Z3 is not offered. G updates the general
+//! registers, RIP and RFLAGS; segment selectors are read-only. Memory is
+//! accessed through the guest page tables as of the stop (long mode or
+//! paging off).
+//!
+//! virtio-console has no queues yet, so serial is the only transport.
+
+use crate::arch::x86::uart::{self, getc, putc};
+use crate::hv::run::{self, StopReason};
+use crate::hv::vcpu::GuestRegs;
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_SESSIONS: usize = 2;
+/// Largest packet payload, advertised as PacketSize
+pub const PACKET_MAX: usize = 1024;
+pub const MAX_SW_BREAKS: usize = 32;
+
+/// GDB's amd64 register order mapped to the x86 encoding of `GuestRegs::gpr`
+const GDB_GPR: [usize; 16] = [0, 3, 1, 2, 6, 7, 5, 4, 8, 9, 10, 11, 12, 13, 14, 15];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rx { Idle, Data, Sum1, Sum2 }
+
+#[derive(Clone, Copy, Debug)]
+struct SwBreak { gva: u64, gpa: u64, orig: u8 }
+
+#[derive(Clone, Copy, Debug)]
+struct Session {
+    vm_id: u64,
+    port: u16,
+    /// Run slot of the vCPU the debugger is looking at
+    slot: usize,
+    /// A stop reply is owed for the last `c`, `s`, `?` or interrupt
+    waiting: bool,
+    rx: Rx,
+    buf: [u8; PACKET_MAX],
+    len: usize,
+    sum: u8,
+    sum_hi: u8,
+    sw: [Option<SwBreak>; MAX_SW_BREAKS],
+    /// DR0-3 and DR7 as set through Z1/Z2/Z4
+    dr: [u64; 4],
+    dr7: u64,
+    packets: u64,
+}
+
+/// Attached VM and its port, for `vm gdb`.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionInfo { pub vm_id: u64, pub port: u16, pub waiting: bool, pub breaks: u32, pub packets: u64 }
+
+static SESSIONS: SpinLock<[Option<Session>; MAX_SESSIONS]> = SpinLock::new([None; MAX_SESSIONS]);
+
+/// `com1`..`com4` or a hex I/O port base.
+pub fn parse_port(s: &str) -> Option<u16> { uart::parse_port(s) }
+
+fn send(port: u16, data: &[u8]) {
+    putc(port, b'$');
+    let mut sum = 0u8;
+    for &b in data { putc(port, b); sum = sum.wrapping_add(b); }
+    putc(port, b'#');
+    putc(port, HEX[(sum >> 4) as usize]);
+    putc(port, HEX[(sum & 15) as usize]);
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn unhex(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn hex_u64(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 { return None; }
+    s.iter().try_fold(0u64, |v, &c| Some((v << 4) | unhex(c)? as u64))
+}
+
+/// Bytes of `v`, little-endian, as hex.
+fn put_le(out: &mut [u8], n: &mut usize, v: u64, bytes: usize) {
+    for k in 0..bytes {
+        let b = (v >> (8 * k)) as u8;
+        out[*n] = HEX[(b >> 4) as usize];
+        out[*n + 1] = HEX[(b & 15) as usize];
+        *n += 2;
+    }
+}
+
+fn get_le(s: &[u8], bytes: usize) -> Option<u64> {
+    let mut v = 0u64;
+    for k in 0..bytes { v |= (((unhex(s[2 * k])? << 4) | unhex(s[2 * k + 1])?) as u64) << (8 * k); }
+    Some(v)
+}
+
+/// Attach the debugger on `port` to `vm_id` and halt the VM.
+pub fn attach(vm_id: u64, port: u16) -> Result<(), &'static str> {
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err("gdb: no such vm"); }
+    if !crate::hv::confidential::allows(vm_id, crate::hv::confidential::Feature::Debug) { return Err("gdb: not allowed on a confidential VM"); }
+    if run::active(vm_id) == 0 { return Err("gdb: vm is not running (vm run)"); }
+    SESSIONS.lock(|t| {
+        if t.iter().flatten().any(|s| s.vm_id == vm_id) { return Err("gdb: vm already attached"); }
+        if t.iter().flatten().any(|s| s.port == port) { return Err("gdb: port already in use"); }
+        if crate::obs::console::serial().is_some_and(|(p, _)| p == port) { return Err("gdb: port in use by the console"); }
+        let slot = t.iter_mut().find(|s| s.is_none()).ok_or("gdb: too many sessions")?;
+        uart::init(port, uart::BASE_BAUD).map_err(|_| "gdb: no UART at that port")?;
+        *slot = Some(Session {
+            vm_id, port, slot: 0, waiting: false, rx: Rx::Idle, buf: [0; PACKET_MAX], len: 0, sum: 0, sum_hi: 0,
+            sw: [None; MAX_SW_BREAKS], dr: [0; 4], dr7: 0, packets: 0,
+        });
+        Ok(())
+    })?;
+    run::debug_attach(vm_id, true);
+    run::debug_halt(vm_id);
+    Ok(())
+}
+
+/// Remove breakpoints, resume the VM and free the port.
+pub fn detach(vm_id: u64) -> Result<(), &'static str> {
+    let s = SESSIONS.lock(|t| t.iter_mut().find(|s| matches!(s, Some(s) if s.vm_id == vm_id)).and_then(|s| s.take())).ok_or("gdb: vm not attached")?;
+    for b in s.sw.iter().flatten() { let _ = write_gpa(vm_id, b.gpa, b.orig); }
+    run::debug_attach(vm_id, false);
+    Ok(())
+}
+
+pub fn for_each(mut f: impl FnMut(&SessionInfo)) {
+    let t = SESSIONS.lock(|t| t.map(|s| s.map(|s| SessionInfo { vm_id: s.vm_id, port: s.port, waiting: s.waiting, breaks: s.sw.iter().flatten().count() as u32 + (s.dr7 & 0x55).count_ones(), packets: s.packets })));
+    for s in t.iter().flatten() { f(s); }
+}
+
+/// Serve every session: take received bytes, answer packets and report
+/// stops. Called from the CLI idle loop.
+pub fn poll() {
+    let mut gone = [None; MAX_SESSIONS];
+    SESSIONS.lock(|t| {
+        for (k, s) in t.iter_mut().enumerate() {
+            let Some(s) = s.as_mut() else { continue };
+            if !serve(s) { gone[k] = Some(s.vm_id); }
+        }
+    });
+    for vm_id in gone.iter().flatten() { let _ = detach(*vm_id); }
+}
+
+/// One round for one session. False once the session should end.
+fn serve(s: &mut Session) -> bool {
+    for _ in 0..PACKET_MAX {
+        let Some(c) = getc(s.port) else { break };
+        match (s.rx, c) {
+            (Rx::Idle, b'$') => { s.rx = Rx::Data; s.len = 0; s.sum = 0; }
+            // Interrupt from the debugger
+            (Rx::Idle, 0x03) => { run::debug_halt(s.vm_id); s.waiting = true; }
+            // Acks; nothing is retransmitted
+            (Rx::Idle, _) => {}
+            (Rx::Data, b'#') => s.rx = Rx::Sum1,
+            (Rx::Data, _) => {
+                if s.len == PACKET_MAX { s.rx = Rx::Idle; putc(s.port, b'-'); continue; }
+                s.buf[s.len] = c;
+                s.len += 1;
+                s.sum = s.sum.wrapping_add(c);
+            }
+            (Rx::Sum1, _) => { s.sum_hi = c; s.rx = Rx::Sum2; }
+            (Rx::Sum2, _) => {
+                s.rx = Rx::Idle;
+                let ok = matches!((unhex(s.sum_hi), unhex(c)), (Some(h), Some(l)) if (h << 4 | l) == s.sum);
+                putc(s.port, if ok { b'+' } else { b'-' });
+                if !ok { continue; }
+                s.packets += 1;
+                if !packet(s) { return false; }
+            }
+        }
+    }
+    if s.waiting { report_stop(s) } else { true }
+}
+
+/// Send the stop reply once every live vCPU has parked. False if the VM is
+/// gone.
+fn report_stop(s: &mut Session) -> bool {
+    let (mut live, mut parked, mut first, mut hit) = (0, 0, None, None);
+    run::for_each(s.vm_id, |i, r| {
+        if !matches!(r.state, run::RunState::Stopped | run::RunState::Failed) { live += 1; }
+        let Some(d) = run::debug_state(i) else { return };
+        let Some(why) = d.parked else { return };
+        parked += 1;
+        first = first.or(Some(i));
+        if why != StopReason::Halt && hit.is_none() { hit = Some(i); }
+    });
+    if live == 0 {
+        send(s.port, b"W00");
+        return false;
+    }
+    if parked < live { return true; }
+    s.waiting = false;
+    s.slot = hit.or(first).unwrap_or(0);
+    let mut out = [0u8; 64];
+    let n = stop_reply(s, &mut out);
+    send(s.port, &out[..n]);
+    true
+}
+
+fn stop_reply(s: &Session, out: &mut [u8]) -> usize {
+    let mut n = 0;
+    let d = run::debug_state(s.slot);
+    let why = d.and_then(|d| d.parked).unwrap_or(StopReason::Halt);
+    let sig: &[u8] = if why == StopReason::Halt { b"T02" } else { b"T05" };
+    for &b in sig { out[n] = b; n += 1; }
+    let (tag, addr): (&[u8], Option<u64>) = match why {
+        StopReason::SwBreak => (b"swbreak:", None),
+        StopReason::HwBreak(_) => (b"hwbreak:", None),
+        StopReason::Watch(k) => {
+            let write_only = ((s.dr7 >> (16 + 4 * k as u64)) & 3) == 1;
+            (if write_only { b"watch:" } else { b"awatch:" }, Some(s.dr[k as usize]))
+        }
+        _ => (b"", None),
+    };
+    for &b in tag { out[n] = b; n += 1; }
+    if let Some(a) = addr { n += crate::util::format::u64_hex(a, &mut out[n..]); }
+    if !tag.is_empty() { out[n] = b';'; n += 1; }
+    n
+}
+
+/// Handle the packet in `s.buf`. False once the session should end.
+fn packet(s: &mut Session) -> bool {
+    let len = s.len;
+    let p = s.buf;
+    let p = &p[..len];
+    let mut out = [0u8; PACKET_MAX];
+    let n = match p.first().copied().unwrap_or(0) {
+        b'?' => { run::debug_halt(s.vm_id); s.waiting = true; return true; }
+        b'q' if p.starts_with(b"qSupported") => copy(&mut out, b"PacketSize=400;swbreak+;hwbreak+"),
+        b'q' if p == b"qAttached" => copy(&mut out, b"1"),
+        b'H' => copy(&mut out, b"OK"),
+        b'g' => read_regs(s, &mut out),
+        b'G' => reply(&mut out, write_regs(s, &p[1..])),
+        b'm' => read_mem(s, &p[1..], &mut out),
+        b'M' => reply(&mut out, write_mem(s, &p[1..])),
+        b'c' | b's' => {
+            let step = p[0] == b's';
+            if p.len() > 1 {
+                let Some(addr) = hex_u64(&p[1..]) else { return send_err(s) };
+                let Some(mut regs) = run::debug_state(s.slot).map(|d| d.regs) else { return send_err(s) };
+                regs.rip = addr;
+                if run::debug_set_regs(s.slot, &regs).is_err() { return send_err(s); }
+            }
+            if run::debug_resume(s.vm_id, if step { Some(s.slot) } else { None }).is_err() { return send_err(s); }
+            s.waiting = true;
+            return true;
+        }
+        b'Z' | b'z' => {
+            match breakpoint(s, p[0] == b'Z', &p[1..]) {
+                Some(r) => reply(&mut out, r),
+                // Unsupported kind: empty reply
+                None => 0,
+            }
+        }
+        b'D' => { send(s.port, b"OK"); return false; }
+        b'k' => return false,
+        _ => 0,
+    };
+    send(s.port, &out[..n]);
+    true
+}
+
+fn copy(out: &mut [u8], b: &[u8]) -> usize { out[..b.len()].copy_from_slice(b); b.len() }
+
+fn reply(out: &mut [u8], r: Result<(), &'static str>) -> usize {
+    copy(out, if r.is_ok() { b"OK" } else { b"E01" })
+}
+
+fn send_err(s: &Session) -> bool { send(s.port, b"E01"); true }
+
+fn read_regs(s: &Session, out: &mut [u8]) -> usize {
+    let Some(d) = run::debug_state(s.slot).filter(|d| d.parked.is_some()) else { return copy(out, b"E01") };
+    let mut n = 0;
+    for &r in GDB_GPR.iter() { put_le(out, &mut n, d.regs.gpr[r], 8); }
+    put_le(out, &mut n, d.regs.rip, 8);
+    put_le(out, &mut n, d.regs.rflags, 4);
+    for &sel in d.seg.iter() { put_le(out, &mut n, sel as u64, 4); }
+    n
+}
+
+fn write_regs(s: &Session, p: &[u8]) -> Result<(), &'static str> {
+    if p.len() < 2 * (17 * 8 + 4) { return Err("gdb: short G packet"); }
+    let d = run::debug_state(s.slot).ok_or("gdb: no vCPU")?;
+    let mut regs: GuestRegs = d.regs;
+    for (k, &r) in GDB_GPR.iter().enumerate() { regs.gpr[r] = get_le(&p[16 * k..], 8).ok_or("gdb: bad hex")?; }
+    regs.rip = get_le(&p[16 * 16..], 8).ok_or("gdb: bad hex")?;
+    regs.rflags = (regs.rflags & !0xFFFF_FFFF) | get_le(&p[16 * 17..], 4).ok_or("gdb: bad hex")?;
+    run::debug_set_regs(s.slot, &regs)
+}
+
+/// `addr,len` from the front of `p`; the rest after `:` is returned too.
+fn addr_len(p: &[u8]) -> Option<(u64, usize, &[u8])> {
+    let comma = p.iter().position(|&c| c == b',')?;
+    let end = p.iter().position(|&c| c == b':').unwrap_or(p.len());
+    let rest = if end < p.len() { &p[end + 1..] } else { &p[p.len()..] };
+    Some((hex_u64(&p[..comma])?, hex_u64(&p[comma + 1..end])? as usize, rest))
+}
+
+/// Guest-physical address of `gva` under the paging of the stopped vCPU.
+fn translate(s: &Session, gva: u64) -> Option<u64> {
+    let d = run::debug_state(s.slot).filter(|d| d.parked.is_some())?;
+    crate::hv::exit::gva_to_gpa(s.vm_id, &d.paging, gva)
+}
+
+fn read_mem(s: &Session, p: &[u8], out: &mut [u8]) -> usize {
+    let Some((addr, len, _)) = addr_len(p) else { return copy(out, b"E01") };
+    let len = len.min(PACKET_MAX / 2);
+    let mut n = 0;
+    for k in 0..len {
+        let va = addr.wrapping_add(k as u64);
+        let Some(host) = translate(s, va).and_then(|g| crate::hv::loader::gpa_to_host(s.vm_id, g)) else { break };
+        let b = unsafe { core::ptr::read_volatile(host as *const u8) };
+        put_le(out, &mut n, b as u64, 1);
+    }
+    if n == 0 && len != 0 { copy(out, b"E14") } else { n }
+}
+
+fn write_gpa(vm_id: u64, gpa: u64, b: u8) -> Result<(), &'static str> {
+    // Writing through a page KSM shares would change every VM sharing it.
+    let _ = crate::hv::ksm::write_fault(vm_id, gpa);
+    let host = crate::hv::loader::gpa_to_host(vm_id, gpa).ok_or("gdb: address outside guest RAM")?;
+    unsafe { core::ptr::write_volatile(host as *mut u8, b); }
+    Ok(())
+}
+
+fn write_mem(s: &Session, p: &[u8]) -> Result<(), &'static str> {
+    let (addr, len, data) = addr_len(p).ok_or("gdb: bad M packet")?;
+    if data.len() < 2 * len { return Err("gdb: short M packet"); }
+    for k in 0..len {
+        let gpa = translate(s, addr.wrapping_add(k as u64)).ok_or("gdb: address not mapped")?;
+        write_gpa(s.vm_id, gpa, get_le(&data[2 * k..], 1).ok_or("gdb: bad hex")? as u8)?;
+    }
+    Ok(())
+}
+
+/// Z/z `type,addr,kind`. `None` for types not supported.
+fn breakpoint(s: &mut Session, insert: bool, p: &[u8]) -> Option<Result<(), &'static str>> {
+    let kind = *p.first()?;
+    let (addr, len, _) = addr_len(p.get(2..)?)?;
+    Some(match kind {
+        b'0' => sw_break(s, insert, addr),
+        // Instruction, write and access breakpoints in DR0-3
+        b'1' => hw_break(s, insert, addr, 0b00, 1),
+        b'2' => hw_break(s, insert, addr, 0b01, len),
+        b'4' => hw_break(s, insert, addr, 0b11, len),
+        _ => return None,
+    })
+}
+
+fn sw_break(s: &mut Session, insert: bool, gva: u64) -> Result<(), &'static str> {
+    let at = s.sw.iter().position(|b| matches!(b, Some(b) if b.gva == gva));
+    if !insert {
+        let Some(i) = at else { return Ok(()) };
+        let Some(b) = s.sw[i].take() else { return Ok(()) };
+        return write_gpa(s.vm_id, b.gpa, b.orig);
+    }
+    if at.is_some() { return Ok(()); }
+    let i = s.sw.iter().position(|b| b.is_none()).ok_or("gdb: too many breakpoints")?;
+    let gpa = translate(s, gva).ok_or("gdb: address not mapped")?;
+    let host = crate::hv::loader::gpa_to_host(s.vm_id, gpa).ok_or("gdb: address outside guest RAM")?;
+    let orig = unsafe { core::ptr::read_volatile(host as *const u8) };
+    write_gpa(s.vm_id, gpa, 0xCC)?;
+    s.sw[i] = Some(SwBreak { gva, gpa, orig });
+    Ok(())
+}
+
+fn hw_break(s: &mut Session, insert: bool, addr: u64, rw: u64, len: usize) -> Result<(), &'static str> {
+    let len_bits = match len { 1 => 0b00, 2 => 0b01, 8 => 0b10, 4 => 0b11, _ => return Err("gdb: unsupported watch length") };
+    if addr & (len as u64 - 1) != 0 { return Err("gdb: unaligned watchpoint"); }
+    let ctl = rw | (len_bits << 2);
+    let used = |n: usize| (s.dr7 >> (2 * n)) & 1 != 0;
+    let same = |n: usize| used(n) && s.dr[n] == addr && ((s.dr7 >> (16 + 4 * n)) & 0xF) == ctl;
+    if insert {
+        if (0..4).any(same) { return Ok(()); }
+        let n = (0..4).find(|&n| !used(n)).ok_or("gdb: all debug registers in use")?;
+        s.dr[n] = addr;
+        s.dr7 = (s.dr7 & !(0xF << (16 + 4 * n))) | (ctl << (16 + 4 * n)) | (1 << (2 * n));
+    } else {
+        let Some(n) = (0..4).find(|&n| same(n)) else { return Ok(()) };
+        s.dr[n] = 0;
+        s.dr7 &= !((0xF << (16 + 4 * n)) | (1 << (2 * n)));
+    }
+    run::debug_set_hw(s.vm_id, s.dr, s.dr7);
+    Ok(())
+}
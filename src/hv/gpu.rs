@@ -0,0 +1,227 @@
+#![allow(dead_code)]
+
+//! vGPU slices: SR-IOV GPUs cut into VFs and passed through to VMs.
+//!
+//! A GPU is a display-class function (PCI class 03h) with an SR-IOV
+//! capability. Carving one into `n` slices enables `n` VFs through
+//! `hv::sriov`; each slice is one VF with a `1/n` profile, and its
+//! framebuffer is the largest VF BAR, which such devices size by the VF
+//! count. Attaching a slice attaches its VF as `vm attach` does, in the
+//! VM's IOMMU domain, so the slice's DMA reaches nothing but that guest's
+//! RAM. Carving and holders are read back from `sriov`, so VFs enabled or
+//! attached with the `pci sriov` and `vm attach` commands show up here too.
+//!
+//! The engines behind a VF are not visible to the host; utilization is what
+//! passes through the hypervisor: guest BAR accesses `sriov` forwards,
+//! turned into a rate each tick, and the DMA faults the IOMMU logged for the
+//! VF.
+
+use core::sync::atomic::Ordering;
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::sriov::{self, Bdf, Pf};
+use crate::hv::vpci::{CFG_CLASS, CFG_DEVICE, CFG_VENDOR};
+use crate::iommu::{mmio_read16, mmio_read8};
+use crate::obs::metrics::{GPU_SLICES_ATTACHED, GPU_SLICE_ATTACHES};
+use crate::util::spinlock::SpinLock;
+
+pub const CLASS_DISPLAY: u8 = 0x03;
+/// Slices tracked for utilization, one per attachable VF
+pub const MAX_SLICES: usize = sriov::MAX_ATTACHED;
+/// Sampling period of `tick`
+pub const INTERVAL_MS: u64 = 1000;
+
+/// An SR-IOV capable GPU.
+#[derive(Clone, Copy, Debug)]
+pub struct Gpu {
+    pub bdf: Bdf,
+    pub vendor: u16,
+    pub device: u16,
+    pub total_vfs: u16,
+    /// Slices carved (0 while whole)
+    pub slices: u16,
+    /// Framebuffer aperture of each slice
+    pub fb_bytes: u64,
+}
+
+/// One VF of a carved GPU.
+#[derive(Clone, Copy, Debug)]
+pub struct Slice {
+    pub gpu: Bdf,
+    pub index: u16,
+    pub vf: Bdf,
+    /// VM holding the slice
+    pub vm_id: Option<u64>,
+    /// Guest BAR accesses since it was attached
+    pub mmio_ops: u64,
+    /// Over the last tick
+    pub mmio_ops_per_s: u64,
+    /// IOMMU faults logged for the VF
+    pub faults: u32,
+    pub quarantined: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Rate { vf: Bdf, last_ops: u64, per_s: u64 }
+
+struct State {
+    rates: [Option<Rate>; MAX_SLICES],
+    /// `clock::now_ns` of the last sample (0 = none yet)
+    last_ns: u64,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State { rates: [None; MAX_SLICES], last_ns: 0 });
+
+fn gpu_of(pf: &Pf) -> Option<(u16, u64)> {
+    if pf.class != CLASS_DISPLAY { return None; }
+    Some((pf.num_vfs, pf.bar_size.iter().copied().max().unwrap_or(0)))
+}
+
+/// Call `f` for every SR-IOV capable GPU on the PCI buses MCFG covers.
+pub fn for_each_gpu(system_table: &SystemTable<Boot>, mut f: impl FnMut(&Gpu)) {
+    let Some(mcfg) = crate::firmware::acpi::find_mcfg(system_table) else { return };
+    crate::firmware::acpi::mcfg_for_each_allocation_from(|a| {
+        let mut bus = a.start_bus;
+        loop {
+            for dev in 0u8..32 {
+                for func in 0u8..8 {
+                    let cfg = crate::iommu::ecam_fn_base(a.base_address, a.start_bus, bus, dev, func);
+                    let vendor = mmio_read16(cfg + CFG_VENDOR);
+                    if vendor == 0xFFFF || mmio_read8(cfg + CFG_CLASS) != CLASS_DISPLAY { continue; }
+                    let Some((total_vfs, _)) = sriov::probe(cfg) else { continue };
+                    let bdf = Bdf { seg: a.pci_segment, bus, dev, func };
+                    let (slices, fb_bytes) = sriov::pf(bdf).and_then(|p| gpu_of(&p)).unwrap_or((0, 0));
+                    f(&Gpu { bdf, vendor, device: mmio_read16(cfg + CFG_DEVICE), total_vfs, slices, fb_bytes });
+                }
+            }
+            if bus >= a.end_bus { break; }
+            bus += 1;
+        }
+    }, mcfg);
+}
+
+/// The GPU at `bdf`, if it is one.
+pub fn find(system_table: &SystemTable<Boot>, bdf: Bdf) -> Option<Gpu> {
+    let mut found = None;
+    for_each_gpu(system_table, |g| if g.bdf == bdf { found = Some(*g); });
+    found
+}
+
+/// Cut the GPU at `bdf` into `slices` VFs, or put it back whole with 0.
+/// Fails while any of its slices is attached.
+pub fn carve(system_table: &mut SystemTable<Boot>, bdf: Bdf, slices: u16) -> Result<Gpu, &'static str> {
+    let mut g = find(system_table, bdf).ok_or("gpu: not an SR-IOV capable GPU")?;
+    if slices > g.total_vfs { return Err("gpu: more slices than the GPU has VFs"); }
+    if slices == 0 {
+        if g.slices != 0 { sriov::disable(bdf)?; }
+        g.slices = 0;
+        g.fb_bytes = 0;
+        crate::obs::log::info(system_table, "gpu", "slices removed");
+    } else {
+        let pf = sriov::enable(system_table, bdf, slices)?;
+        (g.slices, g.fb_bytes) = gpu_of(&pf).unwrap_or((0, 0));
+        crate::obs::log::info(system_table, "gpu", "slices carved");
+    }
+    Ok(g)
+}
+
+/// Call `f` for every slice of every carved GPU, with its holder, faults
+/// and last sampled rate.
+pub fn for_each_slice(mut f: impl FnMut(&Slice)) {
+    let mut pfs: [Option<Pf>; sriov::MAX_PFS] = [None; sriov::MAX_PFS];
+    let mut k = 0;
+    sriov::for_each_pf(|p| if gpu_of(p).is_some() && k < pfs.len() { pfs[k] = Some(*p); k += 1; });
+    for pf in pfs.iter().flatten() {
+        for index in 0..pf.num_vfs {
+            let vf = pf.vf_bdf(index);
+            let mut sl = Slice { gpu: pf.bdf, index, vf, vm_id: None, mmio_ops: 0, mmio_ops_per_s: 0, faults: 0, quarantined: false };
+            sriov::for_each_attached(|a| if a.vf == vf { sl.vm_id = Some(a.vm_id); });
+            sl.mmio_ops = sriov::mmio_ops(vf).unwrap_or(0);
+            if sl.vm_id.is_some() {
+                sl.mmio_ops_per_s = STATE.lock(|s| s.rates.iter().flatten().find(|r| r.vf == vf).map_or(0, |r| r.per_s));
+            }
+            crate::iommu::fault::for_each_device(|d| {
+                if (d.seg, d.bus, d.dev, d.func) == (vf.seg, vf.bus, vf.dev, vf.func) { sl.faults = d.faults; sl.quarantined = d.quarantined; }
+            });
+            f(&sl);
+        }
+    }
+}
+
+fn slice(gpu: Bdf, index: u16) -> Option<Slice> {
+    let mut found = None;
+    for_each_slice(|sl| if sl.gpu == gpu && sl.index == index { found = Some(*sl); });
+    found
+}
+
+/// Give `vm_id` slice `index` of the GPU at `gpu`, or its first free one.
+pub fn attach(system_table: &mut SystemTable<Boot>, vm_id: u64, gpu: Bdf, index: Option<u16>) -> Result<Slice, &'static str> {
+    if sriov::pf(gpu).and_then(|p| gpu_of(&p)).is_none() { return Err("gpu: not carved (gpu carve first)"); }
+    let mut sl = match index {
+        Some(i) => slice(gpu, i).ok_or("gpu: no such slice")?,
+        None => {
+            let mut free = None;
+            for_each_slice(|sl| if sl.gpu == gpu && sl.vm_id.is_none() && free.is_none() { free = Some(*sl); });
+            free.ok_or("gpu: every slice is attached")?
+        }
+    };
+    if sl.vm_id.is_some() { return Err("gpu: slice already attached"); }
+    sriov::attach(system_table, vm_id, sl.vf)?;
+    STATE.lock(|s| for r in s.rates.iter_mut() { if matches!(r, Some(x) if x.vf == sl.vf) { *r = None; } });
+    GPU_SLICE_ATTACHES.fetch_add(1, Ordering::Relaxed);
+    sl.vm_id = Some(vm_id);
+    Ok(sl)
+}
+
+/// Take slice `index` of the GPU at `gpu` away from `vm_id`.
+pub fn detach(system_table: &mut SystemTable<Boot>, vm_id: u64, gpu: Bdf, index: u16) -> Result<(), &'static str> {
+    let sl = slice(gpu, index).ok_or("gpu: no such slice")?;
+    sriov::detach(system_table, vm_id, sl.vf)
+}
+
+/// Take every slice `vm_id` holds away from it. Returns how many.
+pub fn detach_all(system_table: &mut SystemTable<Boot>, vm_id: u64) -> u32 {
+    let mut vfs = [None; MAX_SLICES];
+    let mut k = 0;
+    for_each_slice(|sl| if sl.vm_id == Some(vm_id) && k < MAX_SLICES { vfs[k] = Some(sl.vf); k += 1; });
+    vfs.iter().flatten().filter(|&&vf| sriov::detach(system_table, vm_id, vf).is_ok()).count() as u32
+}
+
+/// Turn the access counters of attached slices into rates. Runs at most
+/// every `INTERVAL_MS` unless `force`. Returns the slices attached.
+pub fn tick(force: bool) -> u32 {
+    if crate::time::clock::hz() == 0 { return 0; }
+    let now = crate::time::clock::now_ns();
+    let dt = STATE.lock(|s| {
+        let dt = now.saturating_sub(s.last_ns);
+        if s.last_ns != 0 && !force && dt < INTERVAL_MS * 1_000_000 { return None; }
+        let first = s.last_ns == 0;
+        s.last_ns = now;
+        Some(if first { 0 } else { dt })
+    });
+    let Some(dt) = dt else { return 0 };
+    // Attached VFs of GPUs, however they were attached.
+    let mut live: [Option<(Bdf, u64)>; MAX_SLICES] = [None; MAX_SLICES];
+    let mut attached = 0usize;
+    sriov::for_each_attached(|a| {
+        if attached == MAX_SLICES || !sriov::pf(a.pf).is_some_and(|p| p.class == CLASS_DISPLAY) { return; }
+        live[attached] = sriov::mmio_ops(a.vf).map(|ops| (a.vf, ops));
+        attached += 1;
+    });
+    STATE.lock(|s| {
+        for r in s.rates.iter_mut() { if matches!(r, Some(x) if !live.iter().flatten().any(|l| l.0 == x.vf)) { *r = None; } }
+        for &(vf, ops) in live.iter().flatten() {
+            let r = match s.rates.iter().position(|r| matches!(r, Some(x) if x.vf == vf)) {
+                Some(i) => s.rates[i].as_mut(),
+                None => s.rates.iter_mut().find(|r| r.is_none()).map(|r| r.insert(Rate { vf, last_ops: ops, per_s: 0 })),
+            };
+            let Some(r) = r else { continue };
+            let delta = ops.saturating_sub(r.last_ops);
+            r.per_s = if dt == 0 { 0 } else { (delta as u128 * 1_000_000_000 / dt as u128) as u64 };
+            r.last_ops = ops;
+        }
+    });
+    GPU_SLICES_ATTACHED.store(attached as u64, Ordering::Relaxed);
+    attached as u32
+}
@@ -0,0 +1,187 @@
+#![allow(dead_code)]
+
+//! Per-VM guest heartbeats and the policy applied when they stop.
+//!
+//! A guest agent proves liveness by sending `PING` on the vsock control
+//! channel (`vdev::vsock`); each one counts as a beat. A VM with a policy
+//! misses one beat per `interval_ms` without one, and after `misses` in a row
+//! `tick` applies the action: log it, pause the VM (held until the operator
+//! resumes it), or stop it, reload its image and start it again. Every action
+//! is audited. The clock only runs while the VM is running and no vCPU is
+//! parked, so a stopped or debugged VM is never acted on.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::diag::audit::{self, AuditKind};
+use crate::hv::run;
+use crate::time::clock::now_ms;
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_POLICIES: usize = 16;
+pub const DEFAULT_MISSES: u32 = 3;
+pub const MIN_INTERVAL_MS: u32 = 100;
+const STOP_TIMEOUT_US: u64 = 200_000;
+const PAUSE_TIMEOUT_US: u64 = 200_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action { Log, Pause, Restart }
+
+impl Action {
+    pub fn code(self) -> u8 { match self { Action::Log => 0, Action::Pause => 1, Action::Restart => 2 } }
+    pub fn name(self) -> &'static str { match self { Action::Log => "log", Action::Pause => "pause", Action::Restart => "restart" } }
+    pub fn parse(s: &str) -> Option<Action> {
+        match s { "log" => Some(Action::Log), "pause" => Some(Action::Pause), "restart" => Some(Action::Restart), _ => None }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub vm_id: u64,
+    pub interval_ms: u32,
+    pub misses: u32,
+    pub action: Action,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub policy: Policy,
+    /// Time of the last beat, or of arming, resuming or restarting
+    pub last_ms: u64,
+    pub beats: u64,
+    /// Consecutive intervals without a beat
+    pub missed: u32,
+    /// Actions taken
+    pub actions: u64,
+    /// Paused by the policy and waiting for `resume`
+    pub held: bool,
+}
+
+static TABLE: SpinLock<[Option<Entry>; MAX_POLICIES]> = SpinLock::new([None; MAX_POLICIES]);
+
+/// Install or replace the policy of a VM. Its first interval starts now.
+pub fn set(policy: Policy) -> Result<(), &'static str> {
+    if crate::hv::vm::find_vm(policy.vm_id).is_none() { return Err("heartbeat: no such vm"); }
+    if policy.interval_ms < MIN_INTERVAL_MS { return Err("heartbeat: interval too short"); }
+    if policy.misses == 0 { return Err("heartbeat: misses must be at least 1"); }
+    let e = Entry { policy, last_ms: now_ms(), beats: 0, missed: 0, actions: 0, held: false };
+    TABLE.lock(|t| {
+        if let Some(s) = t.iter_mut().find(|s| matches!(s, Some(x) if x.policy.vm_id == policy.vm_id)) {
+            let prev = s.map(|x| (x.beats, x.actions)).unwrap_or((0, 0));
+            *s = Some(Entry { beats: prev.0, actions: prev.1, ..e });
+            return Ok(());
+        }
+        let s = t.iter_mut().find(|s| s.is_none()).ok_or("heartbeat: policy table full")?;
+        *s = Some(e);
+        Ok(())
+    })
+}
+
+/// Remove the policy of a VM. A VM the policy paused stays paused.
+pub fn clear(vm_id: u64) -> bool {
+    TABLE.lock(|t| match t.iter_mut().find(|s| matches!(s, Some(x) if x.policy.vm_id == vm_id)) {
+        Some(s) => { *s = None; true }
+        None => false,
+    })
+}
+
+/// Record a beat from the guest agent of `vm_id`.
+pub fn beat(vm_id: u64) {
+    let now = now_ms();
+    TABLE.lock(|t| {
+        if let Some(e) = t.iter_mut().flatten().find(|e| e.policy.vm_id == vm_id) {
+            e.beats += 1;
+            e.last_ms = now;
+            e.missed = 0;
+        }
+    });
+}
+
+/// Unpause a VM its policy paused and restart the count.
+pub fn resume(vm_id: u64) -> Result<(), &'static str> {
+    let held = TABLE.lock(|t| {
+        let e = t.iter_mut().flatten().find(|e| e.policy.vm_id == vm_id)?;
+        let was = e.held;
+        e.held = false;
+        e.missed = 0;
+        e.last_ms = now_ms();
+        Some(was)
+    });
+    match held {
+        None => Err("heartbeat: no policy for vm"),
+        Some(false) => Err("heartbeat: vm not paused by its policy"),
+        Some(true) => { run::unpause(vm_id); Ok(()) }
+    }
+}
+
+/// Whether the VM is running with every vCPU in the guest.
+fn live(vm_id: u64) -> bool {
+    if run::active(vm_id) == 0 { return false; }
+    let mut parked = false;
+    run::for_each(vm_id, |i, _| if run::debug_state(i).is_some_and(|d| d.parked.is_some()) { parked = true; });
+    !parked
+}
+
+fn restart(system_table: &SystemTable<Boot>, vm_id: u64) -> Result<u32, &'static str> {
+    if run::stop(system_table, vm_id, STOP_TIMEOUT_US) != 0 { return Err("heartbeat: vCPUs did not stop"); }
+    crate::hv::loader::reload(system_table, vm_id)?;
+    run::start(system_table, vm_id)
+}
+
+/// Count missed beats and act on VMs past their limit. Returns the number
+/// of actions taken.
+pub fn tick(system_table: &mut SystemTable<Boot>) -> usize {
+    let now = now_ms();
+    if now == 0 { return 0; }
+    let mut due: [Option<(Policy, u32)>; MAX_POLICIES] = [None; MAX_POLICIES];
+    let mut ids = [None; MAX_POLICIES];
+    TABLE.lock(|t| for (i, e) in t.iter().enumerate() { ids[i] = e.filter(|e| !e.held).map(|e| e.policy.vm_id); });
+    let mut running = [false; MAX_POLICIES];
+    for (i, id) in ids.iter().enumerate() { if let Some(id) = *id { running[i] = live(id); } }
+    TABLE.lock(|t| {
+        for (i, s) in t.iter_mut().enumerate() {
+            let Some(e) = s.as_mut() else { continue };
+            if e.held || ids[i] != Some(e.policy.vm_id) { continue; }
+            if !running[i] { e.last_ms = now; e.missed = 0; continue; }
+            e.missed = (now.saturating_sub(e.last_ms) / e.policy.interval_ms as u64).min(u32::MAX as u64) as u32;
+            if e.missed < e.policy.misses { continue; }
+            due[i] = Some((e.policy, e.missed));
+            e.actions += 1;
+            e.missed = 0;
+            e.last_ms = now;
+            if e.policy.action == Action::Pause { e.held = true; }
+        }
+    });
+    let mut taken = 0;
+    for (p, missed) in due.iter().flatten() {
+        let res = match p.action {
+            Action::Log => Ok(()),
+            Action::Pause => if run::pause(system_table, p.vm_id, PAUSE_TIMEOUT_US) == 0 { Ok(()) } else { Err("heartbeat: vCPUs did not pause") },
+            Action::Restart => restart(system_table, p.vm_id).map(|_| ()),
+        };
+        if res.is_err() && p.action == Action::Pause {
+            run::unpause(p.vm_id);
+            TABLE.lock(|t| if let Some(e) = t.iter_mut().flatten().find(|e| e.policy.vm_id == p.vm_id) { e.held = false; });
+        }
+        audit::record(AuditKind::VmHeartbeat { vm: p.vm_id, action: p.action.code(), missed: *missed, ok: res.is_ok() });
+        let mut msg = [0u8; 128]; let mut n = 0;
+        for &b in b"vm " { msg[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(p.vm_id, &mut msg[n..]);
+        for &b in b" missed " { msg[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(*missed as u64, &mut msg[n..]);
+        for &b in b" heartbeats, action=" { msg[n] = b; n += 1; }
+        for &b in p.action.name().as_bytes() { msg[n] = b; n += 1; }
+        if let Err(e) = res {
+            for &b in b" failed: " { msg[n] = b; n += 1; }
+            for &b in e.as_bytes().iter().take(msg.len() - n) { msg[n] = b; n += 1; }
+        }
+        crate::obs::log::warn(system_table, "heartbeat", core::str::from_utf8(&msg[..n]).unwrap_or("action"));
+        taken += 1;
+    }
+    taken
+}
+
+pub fn for_each(mut f: impl FnMut(&Entry)) {
+    let t = TABLE.lock(|t| *t);
+    for e in t.iter().flatten() { f(e); }
+}
@@ -0,0 +1,153 @@
+#![allow(dead_code)]
+
+//! Guest image signatures.
+//!
+//! A signed kernel or flat image carries a trailer in its last 80 bytes:
+//!   +0   magic "ZVSIG001"
+//!   +8   algorithm, u32 LE (1 = Ed25519)
+//!   +12  reserved, zero
+//!   +16  signature (64 bytes) over every byte before the trailer
+//! The trailer is stripped before the image is placed, so a bzImage boots
+//! exactly as it was built.
+//!
+//! Trusted public keys live in the `ZerovisorGuestKeys` variable as a plain
+//! array of 32-byte Ed25519 keys. In strict mode `vm load` refuses anything
+//! that does not verify against one of them; in permissive mode every image
+//! loads and the verdict is only reported. The mode persists in
+//! `ZerovisorImgPolicy`. Whoever can write boot-services variables can also
+//! change the keys, so strict mode is only as strong as the platform's own
+//! Secure Boot.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+use uefi::table::SystemTable;
+
+use crate::util::ed25519::{self, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+
+pub const MAGIC: &[u8; 8] = b"ZVSIG001";
+pub const ALG_ED25519: u32 = 1;
+pub const TRAILER_LEN: usize = 16 + SIGNATURE_LEN;
+pub const MAX_KEYS: usize = 8;
+
+const VAR_NS: VariableVendor = VariableVendor::GLOBAL_VARIABLE;
+
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Outcome of checking one image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// No trailer
+    Unsigned,
+    /// Verified with trusted key `key`
+    Trusted { key: u8 },
+    /// Well-formed, but no trusted key verifies it
+    Untrusted,
+    /// Trailer with an unknown algorithm or non-zero reserved field
+    Malformed,
+}
+
+impl Verdict {
+    pub fn name(self) -> &'static str {
+        match self { Verdict::Unsigned => "unsigned", Verdict::Trusted { .. } => "trusted", Verdict::Untrusted => "untrusted", Verdict::Malformed => "malformed" }
+    }
+
+    /// Code used in the audit log.
+    pub fn code(self) -> u8 {
+        match self { Verdict::Unsigned => 0, Verdict::Trusted { .. } => 1, Verdict::Untrusted => 2, Verdict::Malformed => 3 }
+    }
+}
+
+pub fn strict() -> bool { STRICT.load(Ordering::Relaxed) }
+
+pub fn set_strict(on: bool) { STRICT.store(on, Ordering::Relaxed); }
+
+pub fn save_policy(system_table: &SystemTable<Boot>) -> Result<(), &'static str> {
+    let attrs = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::NON_VOLATILE;
+    system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorImgPolicy"), &VAR_NS, attrs, &[strict() as u8])
+        .map_err(|_| "imgsig: set_variable failed")
+}
+
+/// Restore the saved mode; without one the policy stays permissive.
+pub fn load_policy(system_table: &SystemTable<Boot>) {
+    let mut buf = [0u8; 4];
+    if let Ok((d, _)) = system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorImgPolicy"), &VAR_NS, &mut buf) {
+        if let Some(&b) = d.first() { set_strict(b != 0); }
+    }
+}
+
+/// The trusted keys and how many there are.
+pub fn keys(system_table: &SystemTable<Boot>) -> ([[u8; PUBLIC_KEY_LEN]; MAX_KEYS], usize) {
+    let mut buf = [0u8; PUBLIC_KEY_LEN * MAX_KEYS];
+    let mut out = [[0u8; PUBLIC_KEY_LEN]; MAX_KEYS];
+    let n = match system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorGuestKeys"), &VAR_NS, &mut buf) {
+        Ok((d, _)) => {
+            let n = d.len() / PUBLIC_KEY_LEN;
+            for (k, c) in out.iter_mut().zip(d.chunks_exact(PUBLIC_KEY_LEN)) { k.copy_from_slice(c); }
+            n
+        }
+        Err(_) => 0,
+    };
+    (out, n)
+}
+
+fn store_keys(system_table: &SystemTable<Boot>, keys: &[[u8; PUBLIC_KEY_LEN]]) -> Result<(), &'static str> {
+    let mut buf = [0u8; PUBLIC_KEY_LEN * MAX_KEYS];
+    for (c, k) in buf.chunks_exact_mut(PUBLIC_KEY_LEN).zip(keys) { c.copy_from_slice(k); }
+    let attrs = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::NON_VOLATILE;
+    // An empty write deletes the variable
+    system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorGuestKeys"), &VAR_NS, attrs, &buf[..keys.len() * PUBLIC_KEY_LEN])
+        .map_err(|_| "imgsig: set_variable failed")
+}
+
+/// Trust `key`. Returns its index.
+pub fn add_key(system_table: &SystemTable<Boot>, key: &[u8; PUBLIC_KEY_LEN]) -> Result<usize, &'static str> {
+    let (mut ks, n) = keys(system_table);
+    if let Some(i) = ks[..n].iter().position(|k| k == key) { return Ok(i); }
+    if n == MAX_KEYS { return Err("imgsig: key store full"); }
+    ks[n] = *key;
+    store_keys(system_table, &ks[..n + 1])?;
+    Ok(n)
+}
+
+pub fn remove_key(system_table: &SystemTable<Boot>, idx: usize) -> Result<(), &'static str> {
+    let (mut ks, n) = keys(system_table);
+    if idx >= n { return Err("imgsig: no such key"); }
+    ks.copy_within(idx + 1..n, idx);
+    store_keys(system_table, &ks[..n - 1])
+}
+
+pub fn clear_keys(system_table: &SystemTable<Boot>) -> Result<(), &'static str> { store_keys(system_table, &[]) }
+
+/// Check `img` against the trusted keys. Returns the verdict and the length
+/// of the image proper (without the trailer, if there is one).
+pub fn check(system_table: &SystemTable<Boot>, img: &[u8]) -> (Verdict, usize) {
+    if img.len() < TRAILER_LEN { return (Verdict::Unsigned, img.len()); }
+    let body = img.len() - TRAILER_LEN;
+    let t = &img[body..];
+    if &t[..8] != MAGIC { return (Verdict::Unsigned, img.len()); }
+    let alg = u32::from_le_bytes([t[8], t[9], t[10], t[11]]);
+    if alg != ALG_ED25519 || t[12..16] != [0; 4] { return (Verdict::Malformed, body); }
+    let mut sig = [0u8; SIGNATURE_LEN];
+    sig.copy_from_slice(&t[16..]);
+    let (ks, n) = keys(system_table);
+    match ks[..n].iter().position(|k| ed25519::verify(k, &img[..body], &sig)) {
+        Some(i) => (Verdict::Trusted { key: i as u8 }, body),
+        None => (Verdict::Untrusted, body),
+    }
+}
+
+/// Apply the policy to an image about to be loaded into `vm_id`. Every
+/// decision is audited; strict mode refuses anything not `Trusted`.
+pub fn admit(system_table: &SystemTable<Boot>, vm_id: u64, img: &[u8]) -> Result<(Verdict, usize), &'static str> {
+    let (v, len) = check(system_table, img);
+    let refused = strict() && !matches!(v, Verdict::Trusted { .. });
+    crate::diag::audit::record(crate::diag::audit::AuditKind::GuestImageSig { vm: vm_id, verdict: v.code(), refused });
+    if refused {
+        return Err(match v {
+            Verdict::Unsigned => "loader: image is unsigned (strict signature policy)",
+            _ => "loader: image signature not trusted (strict signature policy)",
+        });
+    }
+    Ok((v, len))
+}
@@ -0,0 +1,200 @@
+#![allow(dead_code)]
+
+//! Information-flow labels for VMs and the channels between them.
+//!
+//! A label is a confidentiality level with a set of compartments and an
+//! integrity level. Data may flow from `a` to `b` when `b` is at least as
+//! confidential (level and compartments) and at most as trusted: secrets do
+//! not leak down, and low-integrity input does not reach high-integrity
+//! consumers. Anything never labelled carries `Label::BOTTOM`, so a host
+//! where nobody assigns labels behaves exactly as before.
+//!
+//! Labelled objects are VMs, zero-copy segments, the host network every
+//! virtual NIC joins, and migration destinations (by MAC). Channels are
+//! checked when they are set up: a read-only segment attachment is a flow
+//! from the segment to the VM, a read-write one or a NIC is a flow both
+//! ways, and migration is a flow from the VM to the destination. Relabelling
+//! an object re-checks the channels it already has and is refused if any
+//! would break.
+
+use crate::util::spinlock::SpinLock;
+
+/// Highest confidentiality and integrity level
+pub const LEVEL_MAX: u8 = 3;
+pub const MAX_LABELS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Label {
+    /// Confidentiality, 0 (public) to `LEVEL_MAX`
+    pub conf: u8,
+    /// Compartments, one bit each
+    pub cats: u16,
+    /// Integrity, 0 (untrusted) to `LEVEL_MAX`
+    pub integ: u8,
+}
+
+impl Label {
+    pub const BOTTOM: Label = Label { conf: 0, cats: 0, integ: 0 };
+
+    pub fn can_flow_to(self, to: Label) -> bool {
+        self.conf <= to.conf && self.cats & !to.cats == 0 && self.integ >= to.integ
+    }
+
+    /// Apply `conf=<n>`, `cats=<hex>` and `integ=<n>` words to `base`.
+    /// `None` on any other word or an out-of-range value.
+    pub fn parse_words<'a>(base: Label, words: impl Iterator<Item = &'a str>) -> Option<Label> {
+        let mut l = base;
+        for w in words {
+            if let Some(v) = w.strip_prefix("conf=") { l.conf = v.parse().ok().filter(|&x| x <= LEVEL_MAX)?; }
+            else if let Some(v) = w.strip_prefix("cats=") { l.cats = u16::from_str_radix(v.trim_start_matches("0x"), 16).ok()?; }
+            else if let Some(v) = w.strip_prefix("integ=") { l.integ = v.parse().ok().filter(|&x| x <= LEVEL_MAX)?; }
+            else { return None; }
+        }
+        Some(l)
+    }
+
+    /// `conf=<n> cats=0x<hex> integ=<n>`; returns bytes written.
+    pub fn write(&self, out: &mut [u8]) -> usize {
+        let mut n = 0;
+        for &b in b"conf=" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(self.conf as u64, &mut out[n..]);
+        for &b in b" cats=0x" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(self.cats as u64, &mut out[n..]);
+        for &b in b" integ=" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(self.integ as u64, &mut out[n..]);
+        n
+    }
+}
+
+const NAME_MAX: usize = crate::hv::zero_copy::NAME_MAX;
+
+/// Something that can carry a label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Object {
+    Vm(u64),
+    /// Zero-copy segment by name
+    Segment([u8; NAME_MAX], usize),
+    /// The network shared by every virtual NIC and the uplink
+    Network,
+    /// Migration destination by MAC
+    Dest([u8; 6]),
+}
+
+impl Object {
+    pub fn segment(name: &str) -> Option<Object> {
+        if name.is_empty() || name.len() > NAME_MAX { return None; }
+        let mut b = [0u8; NAME_MAX];
+        b[..name.len()].copy_from_slice(name.as_bytes());
+        Some(Object::Segment(b, name.len()))
+    }
+
+    /// `vm=<id>`, `shm=<name>`, `net` or `dest=<mac>`
+    pub fn parse(s: &str) -> Option<Object> {
+        if let Some(v) = s.strip_prefix("vm=") { return v.parse().ok().map(Object::Vm); }
+        if let Some(v) = s.strip_prefix("shm=") { return Object::segment(v); }
+        if let Some(v) = s.strip_prefix("dest=") { return crate::hv::vdev::net::parse_mac(v).map(Object::Dest); }
+        if s == "net" { return Some(Object::Network); }
+        None
+    }
+
+    /// Same form `parse` accepts; returns bytes written.
+    pub fn write(&self, out: &mut [u8]) -> usize {
+        let mut n = 0;
+        match *self {
+            Object::Vm(id) => {
+                for &b in b"vm=" { out[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(id, &mut out[n..]);
+            }
+            Object::Segment(name, len) => {
+                for &b in b"shm=" { out[n] = b; n += 1; }
+                for &b in &name[..len] { out[n] = b; n += 1; }
+            }
+            Object::Network => for &b in b"net" { out[n] = b; n += 1; },
+            Object::Dest(mac) => {
+                for &b in b"dest=" { out[n] = b; n += 1; }
+                for (i, m) in mac.iter().enumerate() {
+                    if i != 0 { out[n] = b':'; n += 1; }
+                    const H: &[u8; 16] = b"0123456789abcdef";
+                    out[n] = H[(m >> 4) as usize]; out[n + 1] = H[(m & 15) as usize]; n += 2;
+                }
+            }
+        }
+        n
+    }
+}
+
+static LABELS: SpinLock<[Option<(Object, Label)>; MAX_LABELS]> = SpinLock::new([None; MAX_LABELS]);
+
+pub fn label_of(o: Object) -> Label {
+    LABELS.lock(|t| t.iter().flatten().find(|(x, _)| *x == o).map(|(_, l)| *l)).unwrap_or(Label::BOTTOM)
+}
+
+/// Refuse a flow from `from` to `to` that the labels do not allow.
+pub fn check(from: Object, to: Object) -> Result<(), &'static str> {
+    if label_of(from).can_flow_to(label_of(to)) { Ok(()) } else { Err("info-flow: labels do not allow this channel") }
+}
+
+/// Refuse a two-way channel unless both directions are allowed.
+pub fn check_both(a: Object, b: Object) -> Result<(), &'static str> {
+    check(a, b)?;
+    check(b, a)
+}
+
+/// Channels `o` takes part in right now, as (peer, both ways, `o` is the source).
+fn for_each_channel(o: Object, mut f: impl FnMut(Object, bool, bool)) {
+    use crate::hv::zero_copy::{self, Perm};
+    match o {
+        Object::Vm(id) => {
+            zero_copy::for_each(|i, s| {
+                let Some(seg) = Object::segment(s.name()) else { return };
+                zero_copy::for_each_attach(i, |a| if a.vm_id == id { f(seg, a.perm == Perm::Rw, false) });
+            });
+            let mut nic = false;
+            crate::hv::vdev::net::for_each(|_, n| nic |= n.vm_id == id);
+            if nic { f(Object::Network, true, true); }
+        }
+        Object::Segment(..) => {
+            zero_copy::for_each(|i, s| {
+                if Object::segment(s.name()) != Some(o) { return; }
+                zero_copy::for_each_attach(i, |a| f(Object::Vm(a.vm_id), a.perm == Perm::Rw, true));
+            });
+        }
+        Object::Network => crate::hv::vdev::net::for_each(|_, n| f(Object::Vm(n.vm_id), true, true)),
+        // Only checked when a migration starts
+        Object::Dest(_) => {}
+    }
+}
+
+/// Label `o` (or return it to `BOTTOM` with `None`). Refused if a channel
+/// `o` already has would violate the new label.
+pub fn set_label(o: Object, label: Option<Label>) -> Result<(), &'static str> {
+    let new = label.unwrap_or(Label::BOTTOM);
+    let mut ok = true;
+    for_each_channel(o, |peer, both, src| {
+        let p = label_of(peer);
+        let fwd = if src { new.can_flow_to(p) } else { p.can_flow_to(new) };
+        let back = if src { p.can_flow_to(new) } else { new.can_flow_to(p) };
+        ok &= fwd && (!both || back);
+    });
+    if !ok { return Err("info-flow: an existing channel would violate the new label"); }
+    LABELS.lock(|t| {
+        if let Some(slot) = t.iter_mut().find(|s| matches!(s, Some((x, _)) if *x == o)) {
+            *slot = label.map(|l| (o, l));
+            return Ok(());
+        }
+        let Some(l) = label else { return Ok(()) };
+        let slot = t.iter_mut().find(|s| s.is_none()).ok_or("info-flow: label table full")?;
+        *slot = Some((o, l));
+        Ok(())
+    })
+}
+
+/// Drop the label of an object that no longer exists.
+pub fn forget(o: Object) {
+    LABELS.lock(|t| { for s in t.iter_mut() { if matches!(s, Some((x, _)) if *x == o) { *s = None; } } });
+}
+
+pub fn for_each(mut f: impl FnMut(Object, Label)) {
+    let t = LABELS.lock(|t| *t);
+    for (o, l) in t.iter().flatten() { f(*o, *l); }
+}
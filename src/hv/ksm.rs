@@ -0,0 +1,375 @@
+#![allow(dead_code)]
+
+//! Same-page merging of guest RAM across VMs (KSM).
+//!
+//! An opt-in background task walks the RAM of every loaded image a few
+//! pages per step and hashes each page. A page whose hash is unchanged on
+//! the next pass is taken as idle; if it matches a page already shared, or
+//! another idle page, the candidates are write-protected in stage 2. Once
+//! every vCPU of the VMs involved has flushed (`run::ept_synced`) the
+//! contents are compared byte for byte and equal pages are pointed at one
+//! read-only frame marked shared. A write to a shared page faults and is
+//! resolved in the exit path by copying the shared frame back into the
+//! VM's own frame, which stays in its RAM extent throughout, so breaking
+//! never allocates.
+//!
+//! Shared frames and the page tables needed to split large leaves come
+//! from a small reserve that `refill` tops up from firmware while Boot
+//! Services are available; the background step cannot call firmware.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::mm::stage2::{self, Format};
+use crate::obs::metrics::{self, Counter};
+use crate::util::spinlock::SpinLock;
+
+pub const PAGE: u64 = 4096;
+pub const MAX_STABLE: usize = 512;
+pub const MAX_SHARED: usize = 2048;
+const MAX_UNSTABLE: usize = 1024;
+const MAX_PENDING: usize = 32;
+/// Frames `refill` keeps on hand
+const RESERVE_LOW: usize = 32;
+const RESERVE_MAX: usize = MAX_STABLE + RESERVE_LOW;
+/// Pages hashed per background step
+const PAGES_PER_STEP: u64 = 64;
+const TASK: &str = "ksm";
+
+/// A frame holding the contents of merged pages.
+#[derive(Clone, Copy)]
+struct Stable { hash: u64, frame: u64, refs: u32 }
+
+/// A guest page mapped onto a stable frame.
+#[derive(Clone, Copy)]
+struct Shared { vm_id: u64, gpa: u64, stable: usize }
+
+/// Last hash of a page, kept direct-mapped by (vm, gpa).
+#[derive(Clone, Copy)]
+struct Unstable { vm_id: u64, gpa: u64, hash: u64, idle: bool }
+
+/// Write-protected candidates waiting for every vCPU to flush.
+#[derive(Clone, Copy)]
+struct Pending {
+    a: (u64, u64),
+    /// Second page of a new pair, or None when `a` joins `stable`
+    b: Option<(u64, u64)>,
+    stable: usize,
+    hash: u64,
+    gen: u64,
+}
+
+/// Counters and current sharing, for reporting.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub enabled: bool,
+    pub scanned: u64,
+    /// Completed passes over all guest RAM
+    pub rounds: u64,
+    pub merges: u64,
+    pub cow_breaks: u64,
+    /// Candidates that differed on the byte compare or were written first
+    pub mismatches: u64,
+    /// Frames holding merged contents
+    pub stable: u32,
+    /// Guest pages mapped onto them
+    pub shared: u32,
+    pub reserve: u32,
+}
+
+impl Stats {
+    /// Guest RAM no longer needing a frame of its own, in bytes.
+    pub fn saved(&self) -> u64 { (self.shared as u64).saturating_sub(self.stable as u64) * PAGE }
+}
+
+struct State {
+    stable: [Option<Stable>; MAX_STABLE],
+    shared: [Option<Shared>; MAX_SHARED],
+    unstable: [Option<Unstable>; MAX_UNSTABLE],
+    pending: [Option<Pending>; MAX_PENDING],
+    reserve: [u64; RESERVE_MAX],
+    reserve_len: usize,
+    /// Scan position: image slot and guest-physical address
+    cursor: (usize, u64),
+    stats: Stats,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    stable: [None; MAX_STABLE],
+    shared: [None; MAX_SHARED],
+    unstable: [None; MAX_UNSTABLE],
+    pending: [None; MAX_PENDING],
+    reserve: [0; RESERVE_MAX],
+    reserve_len: 0,
+    cursor: (0, 0),
+    stats: Stats { enabled: false, scanned: 0, rounds: 0, merges: 0, cow_breaks: 0, mismatches: 0, stable: 0, shared: 0, reserve: 0 },
+});
+
+/// Guest RAM of one VM as the scanner needs it.
+#[derive(Clone, Copy)]
+struct Ram { vm_id: u64, root: u64, host: u64, bytes: u64, fmt: Format }
+
+fn ram_of(vm_id: u64) -> Option<Ram> {
+    let g = crate::hv::loader::find_image(vm_id).filter(|g| g.root_phys != 0)?;
+    Some(Ram { vm_id, root: g.root_phys, host: g.ram_host, bytes: g.ram_bytes, fmt: crate::hv::loader::stage2_format(vm_id)? })
+}
+
+/// FNV-1a over the page, a word at a time.
+fn hash(hpa: u64) -> u64 {
+    let p = hpa as *const u64;
+    let mut h = 0xCBF2_9CE4_8422_2325u64;
+    for i in 0..(PAGE / 8) as usize {
+        h ^= unsafe { p.add(i).read_volatile() };
+        h = h.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    h
+}
+
+fn same(a: u64, b: u64) -> bool {
+    unsafe { core::slice::from_raw_parts(a as *const u8, PAGE as usize) == core::slice::from_raw_parts(b as *const u8, PAGE as usize) }
+}
+
+fn copy(from: u64, to: u64) { unsafe { core::ptr::copy_nonoverlapping(from as *const u8, to as *mut u8, PAGE as usize); } }
+
+fn take_frame(s: &mut State) -> Option<u64> {
+    if s.reserve_len == 0 { return None; }
+    s.reserve_len -= 1;
+    Some(s.reserve[s.reserve_len])
+}
+
+fn put_frame(s: &mut State, f: u64) {
+    if s.reserve_len < RESERVE_MAX { s.reserve[s.reserve_len] = f; s.reserve_len += 1; }
+}
+
+fn slot_of(vm_id: u64, gpa: u64) -> usize { ((gpa >> 12) ^ vm_id.wrapping_mul(0x9E37_79B9)) as usize % MAX_UNSTABLE }
+
+fn in_pending(s: &State, page: (u64, u64)) -> Option<usize> {
+    s.pending.iter().position(|p| matches!(p, Some(p) if p.a == page || p.b == Some(page)))
+}
+
+/// Drop one mapping of stable frame `k`, freeing it with the last.
+fn unref(s: &mut State, k: usize) {
+    let Some(st) = s.stable[k].as_mut() else { return };
+    st.refs = st.refs.saturating_sub(1);
+    if st.refs == 0 {
+        let f = st.frame;
+        s.stable[k] = None;
+        put_frame(s, f);
+    }
+}
+
+/// Map `page` back onto its own frame, writable.
+fn restore(page: (u64, u64)) {
+    if let Some(r) = ram_of(page.0) { let _ = stage2::remap_page(r.fmt, r.root, page.1, r.host + page.1, true, false); }
+}
+
+/// Write-protect one page so its contents hold still for the compare.
+fn protect(s: &mut State, r: &Ram, gpa: u64) -> bool {
+    let mut alloc = || take_frame(s).map(|t| { unsafe { core::ptr::write_bytes(t as *mut u8, 0, PAGE as usize); } t });
+    if stage2::demote_with(r.root, gpa, PAGE, &mut alloc).is_err() { return false; }
+    stage2::remap_page(r.fmt, r.root, gpa, r.host + gpa, false, false)
+}
+
+/// Point `page` at stable frame `k`.
+fn share(s: &mut State, page: (u64, u64), k: usize) -> bool {
+    let Some(r) = ram_of(page.0) else { return false };
+    let Some(i) = s.shared.iter().position(|e| e.is_none()) else { return false };
+    let Some(st) = s.stable[k].as_mut() else { return false };
+    if !stage2::remap_page(r.fmt, r.root, page.1, st.frame, false, true) { return false; }
+    st.refs += 1;
+    s.shared[i] = Some(Shared { vm_id: page.0, gpa: page.1, stable: k });
+    s.stats.merges += 1;
+    Counter::new(&metrics::KSM_MERGES).inc();
+    true
+}
+
+/// Finish pending merges whose VMs have flushed. False if one had to wait
+/// for a frame.
+fn settle(s: &mut State) -> bool {
+    let mut ok = true;
+    for i in 0..MAX_PENDING {
+        let Some(p) = s.pending[i] else { continue };
+        let synced = crate::hv::run::ept_synced(p.a.0, p.gen) && p.b.map(|b| crate::hv::run::ept_synced(b.0, p.gen)).unwrap_or(true);
+        if !synced { continue; }
+        let Some(ra) = ram_of(p.a.0) else { s.pending[i] = None; if let Some(b) = p.b { restore(b); } continue };
+        let merged = match p.b {
+            None => match s.stable[p.stable] {
+                Some(st) if same(ra.host + p.a.1, st.frame) => share(s, p.a, p.stable),
+                _ => false,
+            },
+            Some(b) => match ram_of(b.0) {
+                Some(rb) if same(ra.host + p.a.1, rb.host + b.1) => {
+                    let Some(k) = s.stable.iter().position(|e| e.is_none()) else { ok = false; continue };
+                    let Some(f) = take_frame(s) else { ok = false; continue };
+                    copy(ra.host + p.a.1, f);
+                    s.stable[k] = Some(Stable { hash: p.hash, frame: f, refs: 0 });
+                    let m = share(s, p.a, k) & share(s, b, k);
+                    if s.stable[k].map(|st| st.refs == 0).unwrap_or(false) { s.stable[k] = None; put_frame(s, f); }
+                    m
+                }
+                _ => false,
+            },
+        };
+        if !merged {
+            s.stats.mismatches += 1;
+            for page in [Some(p.a), p.b].into_iter().flatten() {
+                if !s.shared.iter().flatten().any(|e| (e.vm_id, e.gpa) == page) { restore(page); }
+            }
+        }
+        s.pending[i] = None;
+    }
+    ok
+}
+
+/// Hash one page and queue it for merging if it is idle and has a twin.
+fn visit(s: &mut State, r: &Ram, gpa: u64) {
+    let Some(leaf) = stage2::page(r.root, gpa) else { return };
+    if leaf.shared || !leaf.writable || in_pending(s, (r.vm_id, gpa)).is_some() { return; }
+    let h = hash(r.host + gpa);
+    s.stats.scanned += 1;
+    Counter::new(&metrics::KSM_SCANNED).inc();
+    let slot = slot_of(r.vm_id, gpa);
+    let idle = matches!(s.unstable[slot], Some(u) if u.vm_id == r.vm_id && u.gpa == gpa && u.hash == h);
+    s.unstable[slot] = Some(Unstable { vm_id: r.vm_id, gpa, hash: h, idle });
+    if !idle { return; }
+    let Some(pi) = s.pending.iter().position(|p| p.is_none()) else { return };
+    // Taken after protecting, so it covers the write-protection.
+    let queue = |s: &mut State, a, b, stable| s.pending[pi] = Some(Pending { a, b, stable, hash: h, gen: stage2::generation() });
+    if let Some(k) = s.stable.iter().position(|e| matches!(e, Some(st) if st.hash == h)) {
+        if protect(s, r, gpa) { queue(s, (r.vm_id, gpa), None, k); }
+        return;
+    }
+    let twin = s.unstable.iter().enumerate().find_map(|(i, u)| match u {
+        Some(u) if i != slot && u.idle && u.hash == h => Some((i, *u)),
+        _ => None,
+    });
+    let Some((ti, t)) = twin else { return };
+    let Some(rt) = ram_of(t.vm_id) else { s.unstable[ti] = None; return };
+    if in_pending(s, (t.vm_id, t.gpa)).is_some() || !matches!(stage2::page(rt.root, t.gpa), Some(l) if l.writable && !l.shared) { return; }
+    if !protect(s, r, gpa) { return; }
+    if !protect(s, &rt, t.gpa) { restore((r.vm_id, gpa)); return; }
+    s.unstable[slot] = None;
+    s.unstable[ti] = None;
+    queue(s, (r.vm_id, gpa), Some((t.vm_id, t.gpa)), 0);
+}
+
+/// Background step: settle pending merges, then hash the next pages.
+fn step() -> bool {
+    let images = crate::hv::loader::images();
+    if images.iter().flatten().all(|g| g.root_phys == 0) { return STATE.lock(|s| s.pending.iter().any(|p| p.is_some())); }
+    STATE.lock(|s| {
+        if !s.stats.enabled { return false; }
+        if !settle(s) { return true; }
+        let mut budget = PAGES_PER_STEP;
+        while budget != 0 {
+            let (slot, gpa) = s.cursor;
+            let img = match images.get(slot) {
+                Some(Some(g)) if g.root_phys != 0 && gpa < g.ram_bytes && crate::hv::confidential::allows(g.vm_id, crate::hv::confidential::Feature::PageDedup) => *g,
+                Some(_) => { s.cursor = (slot + 1, 0); continue; }
+                None => { s.cursor = (0, 0); s.stats.rounds += 1; continue; }
+            };
+            let Some(fmt) = crate::hv::loader::stage2_format(img.vm_id) else { s.cursor = (slot + 1, 0); continue };
+            let r = Ram { vm_id: img.vm_id, root: img.root_phys, host: img.ram_host, bytes: img.ram_bytes, fmt };
+            visit(s, &r, gpa);
+            s.cursor = (slot, gpa + PAGE);
+            budget -= 1;
+        }
+        true
+    })
+}
+
+// ---- Exit path ----
+
+/// Resolve a write fault on `gpa` of `vm_id` if KSM caused it: abandon a
+/// pending merge, or break sharing by copying into the VM's own frame.
+/// True if the guest can retry the access.
+pub fn write_fault(vm_id: u64, gpa: u64) -> bool {
+    let gpa = gpa & !(PAGE - 1);
+    let Some(r) = ram_of(vm_id) else { return false };
+    STATE.lock(|s| {
+        if let Some(i) = in_pending(s, (vm_id, gpa)) {
+            let p = s.pending[i].take().unwrap_or(Pending { a: (vm_id, gpa), b: None, stable: 0, hash: 0, gen: 0 });
+            s.stats.mismatches += 1;
+            for page in [Some(p.a), p.b].into_iter().flatten() { restore(page); }
+            return true;
+        }
+        if let Some(i) = s.shared.iter().position(|e| matches!(e, Some(e) if e.vm_id == vm_id && e.gpa == gpa)) {
+            let Some(e) = s.shared[i].take() else { return false };
+            if let Some(st) = s.stable[e.stable] { copy(st.frame, r.host + gpa); }
+            let _ = stage2::remap_page(r.fmt, r.root, gpa, r.host + gpa, true, false);
+            unref(s, e.stable);
+            s.stats.cow_breaks += 1;
+            Counter::new(&metrics::KSM_COW_BREAKS).inc();
+            return true;
+        }
+        // Another vCPU broke it first; this one faulted on a stale translation.
+        matches!(stage2::page(r.root, gpa), Some(l) if l.writable && l.level == 1)
+    })
+}
+
+// ---- Management ----
+
+/// Start or stop scanning. Stopping leaves merged pages shared; they come
+/// apart as guests write them.
+pub fn enable(on: bool) -> bool {
+    if on && crate::hv::sched::background::register(TASK, crate::hv::sched::background::TaskKind::PageDedup, step).is_none() { return false; }
+    STATE.lock(|s| s.stats.enabled = on);
+    let _ = crate::hv::sched::background::set_enabled(TASK, on);
+    true
+}
+
+/// Top the frame reserve up from firmware.
+pub fn refill(system_table: &SystemTable<Boot>) {
+    if !STATE.lock(|s| s.stats.enabled && s.reserve_len < RESERVE_LOW) { return; }
+    loop {
+        let Some(f) = crate::mm::uefi::alloc_pages(system_table, 1, uefi::table::boot::MemoryType::LOADER_DATA) else { return };
+        let full = STATE.lock(|s| { put_frame(s, f as u64); s.reserve_len >= RESERVE_LOW });
+        if full { return; }
+    }
+}
+
+/// Unmap `gpa` of `vm_id` from its stable frame before the page leaves the
+/// guest (ballooned out).
+pub fn unshare(vm_id: u64, gpa: u64) {
+    STATE.lock(|s| {
+        if let Some(i) = s.shared.iter().position(|e| matches!(e, Some(e) if e.vm_id == vm_id && e.gpa == gpa)) {
+            let k = s.shared[i].map(|e| e.stable).unwrap_or(0);
+            s.shared[i] = None;
+            unref(s, k);
+        }
+    });
+}
+
+/// Forget `vm_id` before its RAM goes away. Its stage-2 table is left as
+/// is; a partner page of a pending pair gets its own frame back.
+pub fn detach_vm(vm_id: u64) {
+    STATE.lock(|s| {
+        for i in 0..MAX_SHARED {
+            let Some(e) = s.shared[i] else { continue };
+            if e.vm_id != vm_id { continue; }
+            s.shared[i] = None;
+            unref(s, e.stable);
+        }
+        for i in 0..MAX_PENDING {
+            let Some(p) = s.pending[i] else { continue };
+            if p.a.0 != vm_id && p.b.map(|b| b.0) != Some(vm_id) { continue; }
+            s.pending[i] = None;
+            for page in [Some(p.a), p.b].into_iter().flatten() { if page.0 != vm_id { restore(page); } }
+        }
+        for u in s.unstable.iter_mut() { if matches!(u, Some(x) if x.vm_id == vm_id) { *u = None; } }
+    });
+}
+
+pub fn stats() -> Stats {
+    STATE.lock(|s| Stats {
+        stable: s.stable.iter().flatten().count() as u32,
+        shared: s.shared.iter().flatten().count() as u32,
+        reserve: s.reserve_len as u32,
+        ..s.stats
+    })
+}
+
+/// Pages of `vm_id` currently mapped onto shared frames.
+pub fn shared_pages(vm_id: u64) -> u32 {
+    STATE.lock(|s| s.shared.iter().flatten().filter(|e| e.vm_id == vm_id).count() as u32)
+}
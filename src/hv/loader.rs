@@ -0,0 +1,539 @@
+#![allow(dead_code)]
+
+//! Guest image loader.
+//!
+//! Reads a kernel image from the ESP the hypervisor was started from, copies
+//! it into freshly allocated guest RAM, builds a second-level mapping (EPT or
+//! NPT) for that RAM and prepares the initial vCPU register state. Two image
+//! formats are recognised:
+//! - Linux bzImage using the 64-bit boot protocol (2.12+): a zero page with
+//!   the setup header, an E820 map, the command line and the ACPI RSDP
+//!   address is built for it.
+//! - Flat binary: copied as-is and entered in long mode at its load address.
+//!
+//! Before anything is placed the image goes through the signature policy in
+//! `imgsig`, which may refuse it.
+//!
+//! Guest-physical layout in low memory:
+//!   0x00500  GDT (null, null, code64 = 0x10, data = 0x18)
+//!   0x07000  zero page (Linux); stack top for flat images
+//!   0x09000  PML4, 0x0A000 PDPT, 0x0B000-0x0EFFF PDs (identity 0-4GiB, 2MiB pages)
+//!   0x20000  kernel command line
+//!   0xE0000  guest ACPI tables (RSDP first), reserved in E820
+//!   0x100000 default kernel/flat image load address
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use uefi::table::boot::MemoryType;
+use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode};
+use uefi::CStr16;
+
+use crate::hv::vcpu::BootRegs;
+use crate::hv::vm::HvVendor;
+use crate::mm::stage2::{self, Format};
+use crate::util::spinlock::SpinLock;
+
+pub const GDT_GPA: u64 = 0x500;
+pub const ZERO_PAGE_GPA: u64 = 0x7000;
+pub const PML4_GPA: u64 = 0x9000;
+pub const CMDLINE_GPA: u64 = 0x2_0000;
+pub const CMDLINE_MAX: usize = 4096;
+pub const DEFAULT_LOAD_GPA: u64 = 0x10_0000;
+
+/// Minimum guest RAM the fixed low-memory layout needs.
+const MIN_GUEST_RAM: u64 = 16 << 20;
+const TWO_MB: u64 = 2 << 20;
+const ONE_GB: u64 = 1 << 30;
+
+const BOOT_CS: u16 = 0x10;
+const BOOT_DS: u16 = 0x18;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind { Flat, Linux }
+
+/// A guest image loaded into a VM's RAM.
+#[derive(Clone, Copy, Debug)]
+pub struct GuestImage {
+    pub vm_id: u64,
+    pub kind: ImageKind,
+    /// Host-physical base of guest RAM (2MiB aligned, guest-physical 0),
+    /// an extent of `mm::guest`
+    pub ram_host: u64,
+    pub ram_bytes: u64,
+    pub load_gpa: u64,
+    pub image_bytes: u64,
+    /// Second-level page table root mapping guest RAM
+    pub root_phys: u64,
+    /// Linux boot protocol version (0 for flat images)
+    pub boot_version: u16,
+    /// Guest-physical address of the RSDP
+    pub acpi_rsdp: u64,
+    pub regs: BootRegs,
+    /// What the signature check found (see `imgsig`)
+    pub signature: crate::hv::imgsig::Verdict,
+}
+
+pub const MAX_IMAGES: usize = 16;
+static IMAGES: SpinLock<[Option<GuestImage>; MAX_IMAGES]> = SpinLock::new([None; MAX_IMAGES]);
+
+/// Longest path and command line `reload` can repeat
+pub const ORIGIN_PATH_MAX: usize = 128;
+pub const ORIGIN_CMDLINE_MAX: usize = 512;
+
+/// The request behind a VM's current image, kept so `reload` can repeat it.
+#[derive(Clone, Copy)]
+struct Origin {
+    vm_id: u64,
+    path: [u8; ORIGIN_PATH_MAX],
+    path_len: usize,
+    cmdline: [u8; ORIGIN_CMDLINE_MAX],
+    cmdline_len: usize,
+    ram_bytes: u64,
+    flat_load_gpa: u64,
+}
+
+static ORIGINS: SpinLock<[Option<Origin>; MAX_IMAGES]> = SpinLock::new([None; MAX_IMAGES]);
+
+fn remember(req: &LoadRequest) {
+    let o = if req.path.len() <= ORIGIN_PATH_MAX && req.cmdline.len() <= ORIGIN_CMDLINE_MAX {
+        let mut o = Origin { vm_id: req.vm_id, path: [0; ORIGIN_PATH_MAX], path_len: req.path.len(), cmdline: [0; ORIGIN_CMDLINE_MAX], cmdline_len: req.cmdline.len(), ram_bytes: req.ram_bytes, flat_load_gpa: req.flat_load_gpa };
+        o.path[..o.path_len].copy_from_slice(req.path.as_bytes());
+        o.cmdline[..o.cmdline_len].copy_from_slice(req.cmdline.as_bytes());
+        Some(o)
+    } else {
+        None
+    };
+    ORIGINS.lock(|t| {
+        for slot in t.iter_mut() { if matches!(slot, Some(x) if x.vm_id == req.vm_id) { *slot = None; } }
+        if let (Some(o), Some(slot)) = (o, t.iter_mut().find(|x| x.is_none())) { *slot = Some(o); }
+    });
+}
+
+fn forget(vm_id: u64) {
+    ORIGINS.lock(|t| { for slot in t.iter_mut() { if matches!(slot, Some(x) if x.vm_id == vm_id) { *slot = None; } } });
+}
+
+/// Load options supplied by the control plane.
+#[derive(Clone, Copy, Debug)]
+pub struct LoadRequest<'a> {
+    pub vm_id: u64,
+    pub path: &'a str,
+    pub ram_bytes: u64,
+    /// Load address for flat images; ignored for bzImage
+    pub flat_load_gpa: u64,
+    pub cmdline: &'a str,
+}
+
+#[inline(always)]
+fn rd16(b: &[u8], off: usize) -> u16 { (b[off] as u16) | ((b[off + 1] as u16) << 8) }
+#[inline(always)]
+fn rd32(b: &[u8], off: usize) -> u32 { (rd16(b, off) as u32) | ((rd16(b, off + 2) as u32) << 16) }
+#[inline(always)]
+fn rd64(b: &[u8], off: usize) -> u64 { (rd32(b, off) as u64) | ((rd32(b, off + 4) as u64) << 32) }
+
+#[inline(always)]
+fn wr16(b: &mut [u8], off: usize, v: u16) { b[off] = v as u8; b[off + 1] = (v >> 8) as u8; }
+#[inline(always)]
+fn wr32(b: &mut [u8], off: usize, v: u32) { wr16(b, off, v as u16); wr16(b, off + 2, (v >> 16) as u16); }
+#[inline(always)]
+fn wr64(b: &mut [u8], off: usize, v: u64) { wr32(b, off, v as u32); wr32(b, off + 4, (v >> 32) as u32); }
+
+/// View `len` bytes of guest RAM at `gpa` (identity-mapped host memory).
+fn guest_slice<'a>(ram_host: u64, ram_bytes: u64, gpa: u64, len: usize) -> Option<&'a mut [u8]> {
+    if gpa.checked_add(len as u64)? > ram_bytes { return None; }
+    Some(unsafe { core::slice::from_raw_parts_mut((ram_host + gpa) as *mut u8, len) })
+}
+
+/// Read a whole file from the image's ESP into a page allocation.
+/// Returns (buffer, pages, length); the caller frees the pages.
+pub(crate) fn read_esp_file(system_table: &SystemTable<Boot>, path: &str) -> Result<(*mut u8, usize, usize), &'static str> {
+    // UEFI paths use backslashes; accept forward slashes for convenience.
+    let mut pbuf = [0u8; 128];
+    if path.is_empty() || path.len() > pbuf.len() { return Err("loader: invalid path"); }
+    for (i, &c) in path.as_bytes().iter().enumerate() { pbuf[i] = if c == b'/' { b'\\' } else { c }; }
+    let p = core::str::from_utf8(&pbuf[..path.len()]).map_err(|_| "loader: invalid path")?;
+    let mut name_buf = [0u16; 130];
+    let name = CStr16::from_str_with_buf(p, &mut name_buf).map_err(|_| "loader: invalid path")?;
+    let bs = system_table.boot_services();
+    let mut fs = bs.get_image_file_system(bs.image_handle()).map_err(|_| "loader: ESP not accessible")?;
+    let mut root = fs.open_volume().map_err(|_| "loader: open volume failed")?;
+    let handle = root.open(name, FileMode::Read, FileAttribute::empty()).map_err(|_| "loader: file not found")?;
+    let mut file = handle.into_regular_file().ok_or("loader: not a regular file")?;
+    let mut info_buf = [0u8; 512];
+    let size = file.get_info::<FileInfo>(&mut info_buf).map_err(|_| "loader: file info failed")?.file_size() as usize;
+    if size == 0 { return Err("loader: empty image"); }
+    let pages = (size + 4095) / 4096;
+    let buf = crate::mm::uefi::alloc_pages(system_table, pages, MemoryType::LOADER_DATA).ok_or("loader: staging alloc failed")?;
+    let dst = unsafe { core::slice::from_raw_parts_mut(buf, size) };
+    let mut got = 0usize;
+    while got < size {
+        match file.read(&mut dst[got..]) {
+            Ok(0) => break,
+            Ok(n) => got += n,
+            Err(_) => break,
+        }
+    }
+    if got != size {
+        crate::mm::uefi::free_pages(system_table, buf, pages);
+        return Err("loader: short read");
+    }
+    Ok((buf, pages, size))
+}
+
+/// GDT, identity page tables (0-4GiB) and long-mode register defaults.
+fn build_long_mode_env(ram_host: u64, ram_bytes: u64) -> Option<BootRegs> {
+    let gdt = guest_slice(ram_host, ram_bytes, GDT_GPA, 32)?;
+    wr64(gdt, 0, 0);
+    wr64(gdt, 8, 0);
+    wr64(gdt, 16, 0x00AF_9A00_0000_FFFF); // code64
+    wr64(gdt, 24, 0x00CF_9200_0000_FFFF); // data
+    let pt = guest_slice(ram_host, ram_bytes, PML4_GPA, 6 * 4096)?;
+    for b in pt.iter_mut() { *b = 0; }
+    let pdpt_gpa = PML4_GPA + 0x1000;
+    wr64(pt, 0, pdpt_gpa | 0x3);
+    for i in 0..4usize {
+        let pd_gpa = PML4_GPA + 0x2000 + (i as u64) * 0x1000;
+        wr64(pt, 0x1000 + i * 8, pd_gpa | 0x3);
+        for j in 0..512usize {
+            let phys = ((i as u64) << 30) | ((j as u64) << 21);
+            wr64(pt, 0x2000 + i * 0x1000 + j * 8, phys | 0x83); // P | RW | PS
+        }
+    }
+    Some(BootRegs {
+        rip: 0,
+        rsp: ZERO_PAGE_GPA,
+        rsi: 0,
+        rflags: 0x2,
+        cr0: 0x8000_0011, // PG | ET | PE
+        cr3: PML4_GPA,
+        cr4: 0x20, // PAE
+        efer: 0x500, // LME | LMA
+        gdtr_base: GDT_GPA,
+        gdtr_limit: 31,
+        cs: BOOT_CS,
+        ds: BOOT_DS,
+    })
+}
+
+/// Validate a bzImage, place it into guest RAM and build the zero page.
+/// Returns (load_gpa, kernel_bytes, protocol_version).
+fn place_linux(img: &[u8], ram_host: u64, ram_bytes: u64, cmdline: &str, rsdp: u64) -> Result<(u64, u64, u16), &'static str> {
+    if img.len() < 0x1000 { return Err("loader: bzImage too small"); }
+    if rd16(img, 0x1FE) != 0xAA55 || &img[0x202..0x206] != b"HdrS" { return Err("loader: not a bzImage"); }
+    let version = rd16(img, 0x206);
+    if version < 0x020C { return Err("loader: boot protocol < 2.12"); }
+    if (rd16(img, 0x236) & 0x1) == 0 { return Err("loader: kernel lacks 64-bit entry"); }
+    let setup_sects = if img[0x1F1] == 0 { 4usize } else { img[0x1F1] as usize };
+    let kernel_off = (setup_sects + 1) * 512;
+    if kernel_off >= img.len() { return Err("loader: truncated bzImage"); }
+    let kernel = &img[kernel_off..];
+    let init_size = rd32(img, 0x260) as u64;
+    let align = rd32(img, 0x230) as u64;
+    let relocatable = img[0x234] != 0;
+    let pref = rd64(img, 0x258);
+    let mut load = if pref >= DEFAULT_LOAD_GPA { pref } else { DEFAULT_LOAD_GPA };
+    if relocatable && align.is_power_of_two() {
+        load = load.checked_add(align - 1).ok_or("loader: bad pref_address")? & !(align - 1);
+    }
+    let need = core::cmp::max(init_size, kernel.len() as u64);
+    let end = load.checked_add(need).ok_or("loader: bad pref_address")?;
+    if end > ram_bytes { return Err("loader: guest RAM too small for kernel"); }
+    let dst = guest_slice(ram_host, ram_bytes, load, kernel.len()).ok_or("loader: kernel outside guest RAM")?;
+    dst.copy_from_slice(kernel);
+
+    // Command line (NUL terminated)
+    let cl = guest_slice(ram_host, ram_bytes, CMDLINE_GPA, CMDLINE_MAX).ok_or("loader: cmdline outside guest RAM")?;
+    let cl_len = core::cmp::min(cmdline.len(), CMDLINE_MAX - 1);
+    cl[..cl_len].copy_from_slice(&cmdline.as_bytes()[..cl_len]);
+    cl[cl_len] = 0;
+
+    // Zero page: setup header copy plus loader-owned fields
+    let zp = guest_slice(ram_host, ram_bytes, ZERO_PAGE_GPA, 4096).ok_or("loader: zero page outside guest RAM")?;
+    for b in zp.iter_mut() { *b = 0; }
+    let hdr_end = core::cmp::min(0x202 + img[0x201] as usize, 0x1000);
+    zp[0x1F1..hdr_end].copy_from_slice(&img[0x1F1..hdr_end]);
+    zp[0x210] = 0xFF; // type_of_loader: undefined
+    zp[0x211] |= 0x01; // loadflags: LOADED_HIGH
+    wr32(zp, 0x214, load as u32); // code32_start
+    wr32(zp, 0x218, 0); // ramdisk_image
+    wr32(zp, 0x21C, 0); // ramdisk_size
+    wr32(zp, 0x228, CMDLINE_GPA as u32); // cmd_line_ptr
+    wr64(zp, 0x070, rsdp); // acpi_rsdp_addr
+    let e820 = e820(ram_bytes);
+    for (i, &(addr, size, ty)) in e820.iter().enumerate() {
+        let off = 0x2D0 + i * 20;
+        wr64(zp, off, addr); wr64(zp, off + 8, size); wr32(zp, off + 16, ty);
+    }
+    zp[0x1E8] = e820.len() as u8;
+    Ok((load, kernel.len() as u64, version))
+}
+
+/// E820 map of a Linux guest as (address, size, type): low RAM, legacy
+/// holes (ACPI tables and BIOS area), then everything above 1MiB.
+fn e820(ram_bytes: u64) -> [(u64, u64, u32); 4] {
+    [
+        (0, 0x9_FC00, 1),
+        (0x9_FC00, 0x400, 2),
+        (crate::hv::acpi::ACPI_GPA, 0x10_0000 - crate::hv::acpi::ACPI_GPA, 2),
+        (DEFAULT_LOAD_GPA, ram_bytes - DEFAULT_LOAD_GPA, 1),
+    ]
+}
+
+/// Call `f(gpa, len, ram)` for each region of the guest's memory map, in
+/// address order; `ram` is false for the reserved holes. A flat image sees
+/// all of its RAM as one region.
+pub fn memory_map(vm_id: u64, mut f: impl FnMut(u64, u64, bool)) -> Result<(), &'static str> {
+    let img = find_image(vm_id).ok_or("loader: no image loaded")?;
+    match img.kind {
+        ImageKind::Linux => for (addr, size, ty) in e820(img.ram_bytes) { f(addr, size, ty == 1); },
+        ImageKind::Flat => f(0, img.ram_bytes, true),
+    }
+    Ok(())
+}
+
+/// Release guest RAM held by a previously loaded image.
+fn release(img: &GuestImage) { crate::mm::guest::free(img.vm_id, img.ram_host); }
+
+/// Undo a failed load: free the new RAM extent and restore the reservation.
+fn abandon(vm_id: u64, ram_host: u64, prev_resv: u64) {
+    crate::mm::guest::free(vm_id, ram_host);
+    let _ = crate::mm::guest::reserve(vm_id, prev_resv);
+}
+
+/// Load an image from the ESP into the given VM and prepare vCPU0 state.
+pub fn load(system_table: &SystemTable<Boot>, req: &LoadRequest) -> Result<GuestImage, &'static str> {
+    let info = crate::hv::vm::find_vm(req.vm_id).ok_or("loader: unknown vm id")?;
+    if crate::hv::wasm::is_wasm(req.vm_id) { return Err("loader: vm runs a wasm module"); }
+    let ram_bytes = (core::cmp::max(req.ram_bytes, MIN_GUEST_RAM) + TWO_MB - 1) & !(TWO_MB - 1);
+    let (stage, stage_pages, file_len) = read_esp_file(system_table, req.path)?;
+    let file = unsafe { core::slice::from_raw_parts(stage as *const u8, file_len) };
+    let (signature, len) = match crate::hv::imgsig::admit(system_table, req.vm_id, file) {
+        Ok(v) => v,
+        Err(e) => { crate::mm::uefi::free_pages(system_table, stage, stage_pages); return Err(e); }
+    };
+    let img = &file[..len];
+
+    // Guest RAM: one extent aligned for the largest stage-2 leaves (1GiB
+    // when the guest is that big, else 2MiB), reserved on top of what a
+    // previously loaded image holds until it is replaced. A pinned VM's
+    // extent goes to the NUMA nodes of its CPUs.
+    let fmt = stage2_format(req.vm_id);
+    let caps = fmt.map(stage2::caps).unwrap_or_default();
+    let prev_resv = crate::mm::guest::reservation(req.vm_id);
+    let _ = crate::mm::guest::scan(system_table);
+    let pages = ram_bytes / 4096;
+    let want = crate::hv::numa::plan(system_table, info.affinity, ram_bytes);
+    let alloc = |align: u64| match &want {
+        Some(w) => crate::mm::guest::alloc_spread(system_table, req.vm_id, pages, align / 4096, w),
+        None => crate::mm::guest::alloc(system_table, req.vm_id, pages, align / 4096),
+    };
+    // Over the overcommit limit: ask ballooned guests for the difference so
+    // a retry can fit, and fail this load.
+    let short = crate::mm::guest::shortfall(req.vm_id, crate::mm::guest::backed(req.vm_id) + ram_bytes);
+    if short != 0 { let _ = crate::hv::vdev::balloon::reclaim(short); }
+    let ram_host = match crate::mm::guest::reserve(req.vm_id, crate::mm::guest::backed(req.vm_id) + ram_bytes).and_then(|_| {
+        if caps.large_page_1g && ram_bytes >= ONE_GB { alloc(ONE_GB).or_else(|_| alloc(TWO_MB)) } else { alloc(TWO_MB) }
+    }) {
+        Ok(b) => b,
+        Err(e) => {
+            crate::mm::uefi::free_pages(system_table, stage, stage_pages);
+            let _ = crate::mm::guest::reserve(req.vm_id, prev_resv);
+            return Err(e);
+        }
+    };
+    unsafe { core::ptr::write_bytes(ram_host as *mut u8, 0, ram_bytes as usize); }
+
+    let vnuma = if crate::hv::numa::vnuma(req.vm_id) { crate::hv::numa::layout(system_table, info.affinity, ram_host, ram_bytes, info.vcpus) } else { None };
+    let acpi = guest_slice(ram_host, ram_bytes, crate::hv::acpi::ACPI_GPA, crate::hv::acpi::ACPI_LEN)
+        .ok_or("loader: ACPI area outside guest RAM")
+        .and_then(|area| crate::hv::acpi::build(area, crate::hv::acpi::ACPI_GPA, info.vcpus, vnuma.as_ref()));
+    let acpi = match acpi {
+        Ok(l) => l,
+        Err(e) => {
+            crate::mm::uefi::free_pages(system_table, stage, stage_pages);
+            abandon(req.vm_id, ram_host, prev_resv);
+            return Err(e);
+        }
+    };
+
+    let is_linux = len >= 0x206 && &img[0x202..0x206] == b"HdrS";
+    let placed = if is_linux {
+        place_linux(img, ram_host, ram_bytes, req.cmdline, acpi.rsdp).map(|(gpa, n, v)| (ImageKind::Linux, gpa, n, v))
+    } else {
+        match guest_slice(ram_host, ram_bytes, req.flat_load_gpa, len) {
+            Some(dst) if req.flat_load_gpa >= CMDLINE_GPA + CMDLINE_MAX as u64 => { dst.copy_from_slice(img); Ok((ImageKind::Flat, req.flat_load_gpa, len as u64, 0)) }
+            Some(_) => Err("loader: flat load address overlaps boot structures"),
+            None => Err("loader: flat image outside guest RAM"),
+        }
+    };
+    crate::mm::uefi::free_pages(system_table, stage, stage_pages);
+    let (kind, load_gpa, image_bytes, boot_version) = match placed {
+        Ok(v) => v,
+        Err(e) => { abandon(req.vm_id, ram_host, prev_resv); return Err(e); }
+    };
+
+    let mut regs = match build_long_mode_env(ram_host, ram_bytes) {
+        Some(r) => r,
+        None => { abandon(req.vm_id, ram_host, prev_resv); return Err("loader: boot structures outside guest RAM"); }
+    };
+    match kind {
+        // 64-bit entry point sits 0x200 past the protected-mode kernel start
+        ImageKind::Linux => { regs.rip = load_gpa + 0x200; regs.rsi = ZERO_PAGE_GPA; }
+        ImageKind::Flat => { regs.rip = load_gpa; }
+    }
+
+    let root = fmt.and_then(|f| stage2::build_offset(system_table, f, ram_bytes, ram_host, caps));
+    let root_phys = root.map(|p| p as u64).unwrap_or(0);
+    if root_phys != 0 { let _ = crate::hv::vm::set_vm_pml4(req.vm_id, root_phys); }
+
+    let gi = GuestImage {
+        vm_id: req.vm_id, kind, ram_host, ram_bytes,
+        load_gpa, image_bytes, root_phys, boot_version, acpi_rsdp: acpi.rsdp, regs, signature,
+    };
+    let prev = IMAGES.lock(|arr| {
+        for slot in arr.iter_mut() {
+            if let Some(cur) = slot { if cur.vm_id == req.vm_id { let old = *cur; *slot = Some(gi); return Ok(Some(old)); } }
+        }
+        for slot in arr.iter_mut() { if slot.is_none() { *slot = Some(gi); return Ok(None); } }
+        Err("loader: image table full")
+    });
+    match prev {
+        Ok(Some(old)) => {
+            crate::hv::ksm::detach_vm(req.vm_id);
+            release(&old);
+            // The new RAM is mapped whole; nothing is ballooned out of it yet.
+            crate::hv::vdev::balloon::forget_held(req.vm_id);
+            let _ = crate::mm::guest::reserve(req.vm_id, ram_bytes);
+        }
+        Ok(None) => {}
+        Err(e) => { abandon(req.vm_id, ram_host, prev_resv); return Err(e); }
+    }
+    crate::hv::numa::install(req.vm_id, vnuma);
+    crate::hv::zero_copy::rebind(system_table, req.vm_id);
+    remember(req);
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::VM_IMAGE_LOADS).inc();
+    Ok(gi)
+}
+
+/// Give `vm_id`, which has no image yet, one like `proto` without reading a
+/// file: a fresh RAM extent whose contents are left to the caller
+/// (`hv::microvm` maps it onto a template) and a stage-2 table mapping it.
+pub fn adopt(system_table: &SystemTable<Boot>, vm_id: u64, proto: &GuestImage) -> Result<GuestImage, &'static str> {
+    crate::hv::vm::find_vm(vm_id).ok_or("loader: unknown vm id")?;
+    if find_image(vm_id).is_some() { return Err("loader: vm already has an image"); }
+    let fmt = stage2_format(vm_id).ok_or("loader: no stage-2 format for this CPU")?;
+    let prev_resv = crate::mm::guest::reservation(vm_id);
+    let _ = crate::mm::guest::scan(system_table);
+    let ram_host = crate::mm::guest::reserve(vm_id, crate::mm::guest::backed(vm_id) + proto.ram_bytes)
+        .and_then(|_| crate::mm::guest::alloc(system_table, vm_id, proto.ram_bytes / 4096, TWO_MB / 4096))
+        .map_err(|e| { let _ = crate::mm::guest::reserve(vm_id, prev_resv); e })?;
+    let Some(root) = stage2::build_offset(system_table, fmt, proto.ram_bytes, ram_host, stage2::caps(fmt)) else {
+        abandon(vm_id, ram_host, prev_resv);
+        return Err("loader: out of memory for page tables");
+    };
+    let gi = GuestImage { vm_id, ram_host, root_phys: root as u64, ..*proto };
+    let placed = IMAGES.lock(|arr| arr.iter_mut().find(|s| s.is_none()).map(|s| *s = Some(gi)).is_some());
+    if !placed { abandon(vm_id, ram_host, prev_resv); return Err("loader: image table full"); }
+    let _ = crate::hv::vm::set_vm_pml4(vm_id, gi.root_phys);
+    crate::hv::zero_copy::rebind(system_table, vm_id);
+    Ok(gi)
+}
+
+/// Load the VM's image again from the same file, command line and RAM size,
+/// giving it fresh RAM. The VM must not be running.
+pub fn reload(system_table: &SystemTable<Boot>, vm_id: u64) -> Result<GuestImage, &'static str> {
+    origin(vm_id, |req| load(system_table, req))?
+}
+
+/// Run `f` on the request that loaded the VM's current image.
+pub fn origin<R>(vm_id: u64, f: impl FnOnce(&LoadRequest) -> R) -> Result<R, &'static str> {
+    let o = ORIGINS.lock(|t| t.iter().flatten().find(|o| o.vm_id == vm_id).copied()).ok_or("loader: no reloadable image")?;
+    let path = core::str::from_utf8(&o.path[..o.path_len]).map_err(|_| "loader: invalid path")?;
+    let cmdline = core::str::from_utf8(&o.cmdline[..o.cmdline_len]).map_err(|_| "loader: invalid cmdline")?;
+    Ok(f(&LoadRequest { vm_id, path, ram_bytes: o.ram_bytes, flat_load_gpa: o.flat_load_gpa, cmdline }))
+}
+
+/// Rewrite the command line of `vm_id`'s Linux image as the one it was
+/// loaded with followed by `extra`. Calling it again replaces `extra`.
+pub fn append_cmdline(vm_id: u64, extra: &str) -> Result<(), &'static str> {
+    let img = find_image(vm_id).ok_or("loader: no image loaded")?;
+    if img.kind != ImageKind::Linux { return Err("loader: not a Linux image"); }
+    let cl = guest_slice(img.ram_host, img.ram_bytes, CMDLINE_GPA, CMDLINE_MAX).ok_or("loader: cmdline outside guest RAM")?;
+    origin(vm_id, |req| {
+        let base = req.cmdline.as_bytes();
+        let n = base.len() + 1 + extra.len();
+        if n >= CMDLINE_MAX { return Err("loader: cmdline too long"); }
+        cl[..base.len()].copy_from_slice(base);
+        cl[base.len()] = b' ';
+        cl[base.len() + 1..n].copy_from_slice(extra.as_bytes());
+        cl[n] = 0;
+        Ok(())
+    })?
+}
+
+/// Look up the image loaded for a VM.
+pub fn find_image(vm_id: u64) -> Option<GuestImage> {
+    IMAGES.lock(|arr| arr.iter().flatten().find(|g| g.vm_id == vm_id).copied())
+}
+
+/// Snapshot of all loaded images.
+pub fn images() -> [Option<GuestImage>; MAX_IMAGES] { IMAGES.lock(|arr| *arr) }
+
+/// Stage-2 table format of `vm_id`'s CPU vendor.
+pub fn stage2_format(vm_id: u64) -> Option<Format> {
+    match crate::hv::vm::find_vm(vm_id)?.vendor {
+        HvVendor::Intel => Some(Format::Ept),
+        HvVendor::Amd => Some(Format::Npt),
+        HvVendor::Unknown => None,
+    }
+}
+
+/// Translate a guest-physical address to a host address. VMs with a loaded
+/// image use its RAM block; others run on the identity map built at creation.
+pub fn gpa_to_host(vm_id: u64, gpa: u64) -> Option<u64> {
+    match find_image(vm_id) {
+        Some(img) => {
+            if gpa >= img.ram_bytes { return None; }
+            // A page still on a microVM template has nothing in the VM's frame yet.
+            let _ = crate::hv::microvm::write_fault(vm_id, gpa);
+            Some(img.ram_host + gpa)
+        }
+        None => Some(gpa),
+    }
+}
+
+/// Drop the image for a VM and free its guest RAM.
+pub fn unload(vm_id: u64) -> bool {
+    let old = IMAGES.lock(|arr| {
+        for slot in arr.iter_mut() {
+            if let Some(cur) = slot { if cur.vm_id == vm_id { let o = *cur; *slot = None; return Some(o); } }
+        }
+        None
+    });
+    forget(vm_id);
+    match old { Some(o) => { release(&o); true } None => false }
+}
+
+/// Program the current VMCS guest-state area from `regs`.
+/// The caller must have executed VMPTRLD for the target vCPU's VMCS.
+/// RSI is a general-purpose register and is loaded by the entry path, not here.
+pub fn program_vmcs_guest_state(regs: &BootRegs) -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    vmwrite(VMCS_GUEST_CR0, regs.cr0)?;
+    vmwrite(VMCS_GUEST_CR3, regs.cr3)?;
+    vmwrite(VMCS_GUEST_CR4, regs.cr4)?;
+    vmwrite(VMCS_GUEST_IA32_EFER, regs.efer)?;
+    vmwrite(VMCS_GUEST_GDTR_BASE, regs.gdtr_base)?;
+    vmwrite(VMCS_GUEST_GDTR_LIMIT, regs.gdtr_limit as u64)?;
+    vmwrite(VMCS_GUEST_CS_SELECTOR, regs.cs as u64)?;
+    vmwrite(VMCS_GUEST_CS_LIMIT, 0xFFFF_FFFF)?;
+    vmwrite(VMCS_GUEST_CS_AR, AR_CODE64)?;
+    for &(sel, ar) in &[(VMCS_GUEST_DS_SELECTOR, VMCS_GUEST_DS_AR), (VMCS_GUEST_ES_SELECTOR, VMCS_GUEST_ES_AR), (VMCS_GUEST_SS_SELECTOR, VMCS_GUEST_SS_AR)] {
+        vmwrite(sel, regs.ds as u64)?;
+        vmwrite(ar, AR_DATA)?;
+    }
+    vmwrite(VMCS_GUEST_RSP, regs.rsp)?;
+    vmwrite(VMCS_GUEST_RIP, regs.rip)?;
+    vmwrite(VMCS_GUEST_RFLAGS, regs.rflags)?;
+    Ok(())
+}
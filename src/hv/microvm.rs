@@ -0,0 +1,561 @@
+#![allow(dead_code)]
+
+//! MicroVM fast start from pre-warmed templates.
+//!
+//! A template is a guest captured right after `loader::load` prepared it
+//! and before its first instruction: the memory image (kernel placed, boot
+//! parameters, command line and ACPI tables written), the boot vCPU state,
+//! and the devices to plug in (a vsock device, an `eth0` on a CNI network).
+//! Nothing has run, so there is no live device or interrupt state to carry
+//! over; what a clone skips is the ESP read, the signature check, zeroing
+//! RAM and building the boot structures.
+//!
+//! Each template lives in a storage volume, `microvm-<name>`, whose first
+//! chunk holds a header and whose later chunks hold guest RAM from
+//! guest-physical 0; zero runs are left unwritten. `open` reads one back
+//! after a restart. Its contents cannot be checked against the image
+//! signature again, so the strict signature policy refuses it.
+//!
+//! While open, the memory image is kept in host frames of its own. A clone
+//! gets a RAM extent and a stage-2 table of its own (`loader::adopt`), but
+//! every page is mapped read-only onto the template frame and marked shared.
+//! The first guest write to a page faults and `write_fault` copies the
+//! template page into the clone's frame and maps that writable. Host-side
+//! accesses through `loader::gpa_to_host` copy the page first as well, and
+//! `privatize` copies all of them for code that uses the extent directly
+//! (core dumps, device DMA through the IOMMU).
+//!
+//! Each template keeps up to `pool_target` clones created, cloned and with
+//! their devices plugged in; `refill` tops the pools up from the idle loop,
+//! one clone per call. `start` takes a pooled clone, or makes one if the
+//! pool is empty, and starts its vCPUs. The time from the call to the
+//! vCPUs running is recorded against `TARGET_US`.
+
+use uefi::prelude::Boot;
+use uefi::table::boot::MemoryType;
+use uefi::table::SystemTable;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::hv::cni;
+use crate::hv::imgsig::Verdict;
+use crate::hv::loader::{self, GuestImage, ImageKind};
+use crate::hv::storage;
+use crate::hv::vcpu::BootRegs;
+use crate::mm::stage2::{self, Format};
+use crate::obs::metrics::{self, Counter};
+use crate::util::spinlock::SpinLock;
+
+pub const PAGE: u64 = 4096;
+pub const MAX_TEMPLATES: usize = 8;
+pub const NAME_MAX: usize = 32;
+/// Clones alive at once, pooled or started: one image each
+pub const MAX_CLONES: usize = loader::MAX_IMAGES;
+/// Warm clones a template may keep
+pub const POOL_MAX: u32 = 8;
+/// Start latency aimed for
+pub const TARGET_US: u64 = 100_000;
+pub const VOLUME_PREFIX: &str = "microvm-";
+pub const DEFAULT_MEMORY_MIB: u64 = 256;
+const MAGIC: [u8; 8] = *b"ZVMTMPL\0";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 512;
+/// Guest RAM is written to the volume in runs of this size
+const RUN: usize = 64 * 1024;
+const STOP_TIMEOUT_US: u64 = 2_000_000;
+/// Interface a template's network is attached as
+const NET_IF: &str = "eth0";
+
+/// How to build a template.
+#[derive(Clone, Copy, Debug)]
+pub struct TemplateConfig<'a> {
+    pub name: &'a str,
+    /// ESP path of the kernel or flat image
+    pub kernel: &'a str,
+    pub cmdline: &'a str,
+    pub memory_mib: u64,
+    pub vcpus: u32,
+    pub vsock: bool,
+    /// CNI network for `eth0`
+    pub network: Option<&'a str>,
+}
+
+impl<'a> TemplateConfig<'a> {
+    pub fn new(name: &'a str, kernel: &'a str) -> Self {
+        TemplateConfig { name, kernel, cmdline: "", memory_mib: DEFAULT_MEMORY_MIB, vcpus: 1, vsock: true, network: None }
+    }
+}
+
+/// Start latencies of one template, in microseconds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Latency {
+    pub starts: u64,
+    /// Starts served by a pooled clone
+    pub pool_hits: u64,
+    /// Starts at or under `TARGET_US`
+    pub on_target: u64,
+    pub last_us: u64,
+    pub best_us: u64,
+    pub worst_us: u64,
+}
+
+impl Latency {
+    fn note(&mut self, us: u64, pooled: bool) {
+        self.best_us = if self.starts == 0 { us } else { self.best_us.min(us) };
+        self.worst_us = self.worst_us.max(us);
+        self.last_us = us;
+        self.starts += 1;
+        if pooled { self.pool_hits += 1; }
+        if us <= TARGET_US { self.on_target += 1; }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Template {
+    pub id: u32,
+    name: [u8; NAME_MAX],
+    name_len: u8,
+    /// Storage volume holding it
+    pub volume: u32,
+    /// Host frames of the memory image, `image.ram_bytes` long
+    frames: u64,
+    /// What clones are given; `vm_id`, `ram_host` and `root_phys` are unused
+    pub image: GuestImage,
+    pub vcpus: u32,
+    pub vsock: bool,
+    network: [u8; cni::NAME_MAX],
+    net_len: u8,
+    /// Clones to keep warm
+    pub pool_target: u32,
+    /// Clones mapping the frames, pooled or started
+    pub clones: u32,
+    /// Why `refill` last gave up on the pool (it sets the target to 0)
+    pub pool_error: Option<&'static str>,
+    pub latency: Latency,
+}
+
+impl Template {
+    pub fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("?") }
+
+    pub fn network(&self) -> Option<&str> {
+        if self.net_len == 0 { None } else { core::str::from_utf8(&self.network[..self.net_len as usize]).ok() }
+    }
+
+    pub fn memory_mib(&self) -> u64 { self.image.ram_bytes >> 20 }
+}
+
+/// A VM whose RAM starts out on a template.
+#[derive(Clone, Copy)]
+struct Instance {
+    vm_id: u64,
+    template: u32,
+    fmt: Format,
+    root: u64,
+    host: u64,
+    bytes: u64,
+    frames: u64,
+    /// Waiting in the pool, not started
+    warm: bool,
+}
+
+struct State {
+    templates: [Option<Template>; MAX_TEMPLATES],
+    clones: [Option<Instance>; MAX_CLONES],
+    next_id: u32,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State { templates: [None; MAX_TEMPLATES], clones: [None; MAX_CLONES], next_id: 1 });
+/// Clones alive; lets `write_fault` skip the lock for everyone else
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+fn valid_name(name: &str) -> bool { name.len() <= NAME_MAX && crate::hv::vm::valid_name(name) }
+
+fn volume_name(name: &str, out: &mut [u8; VOLUME_PREFIX.len() + NAME_MAX]) -> usize {
+    out[..VOLUME_PREFIX.len()].copy_from_slice(VOLUME_PREFIX.as_bytes());
+    out[VOLUME_PREFIX.len()..VOLUME_PREFIX.len() + name.len()].copy_from_slice(name.as_bytes());
+    VOLUME_PREFIX.len() + name.len()
+}
+
+pub fn template(id: u32) -> Option<Template> { STATE.lock(|s| s.templates.iter().flatten().find(|t| t.id == id).copied()) }
+
+/// Template id for a decimal id or a name.
+pub fn find(sel: &str) -> Option<u32> {
+    STATE.lock(|s| {
+        let by_id = sel.parse::<u32>().ok();
+        s.templates.iter().flatten().find(|t| Some(t.id) == by_id || t.name() == sel).map(|t| t.id)
+    })
+}
+
+pub fn for_each_template(mut f: impl FnMut(&Template)) {
+    let snap = STATE.lock(|s| s.templates);
+    for t in snap.iter().flatten() { f(t); }
+}
+
+/// Warm clones of template `id`.
+pub fn pooled(id: u32) -> u32 { STATE.lock(|s| s.clones.iter().flatten().filter(|c| c.template == id && c.warm).count() as u32) }
+
+/// Template the RAM of `vm_id` came from, if it is a clone.
+pub fn clone_of(vm_id: u64) -> Option<u32> { STATE.lock(|s| s.clones.iter().flatten().find(|c| c.vm_id == vm_id).map(|c| c.template)) }
+
+// ---- Templates ----
+
+fn alloc_frames(system_table: &SystemTable<Boot>, bytes: u64) -> Result<u64, &'static str> {
+    crate::mm::uefi::alloc_pages(system_table, (bytes / PAGE) as usize, MemoryType::LOADER_DATA)
+        .map(|p| p as u64).ok_or("microvm: out of memory for the template image")
+}
+
+fn free_frames(system_table: &SystemTable<Boot>, frames: u64, bytes: u64) {
+    crate::mm::uefi::free_pages(system_table, frames as *mut u8, (bytes / PAGE) as usize);
+}
+
+fn encode(t: &Template, b: &mut [u8; HEADER_LEN]) {
+    let g = &t.image;
+    let r = &g.regs;
+    b[..8].copy_from_slice(&MAGIC);
+    b[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    b[12..16].copy_from_slice(&t.vcpus.to_le_bytes());
+    for (i, v) in [g.ram_bytes, g.load_gpa, g.image_bytes, g.acpi_rsdp].iter().enumerate() {
+        b[16 + i * 8..24 + i * 8].copy_from_slice(&v.to_le_bytes());
+    }
+    b[48] = match g.kind { ImageKind::Flat => 0, ImageKind::Linux => 1 };
+    b[49] = g.signature.code();
+    b[50] = if let Verdict::Trusted { key } = g.signature { key } else { 0 };
+    b[51] = t.vsock as u8;
+    b[52..54].copy_from_slice(&g.boot_version.to_le_bytes());
+    b[54] = t.net_len;
+    for (i, v) in [r.rip, r.rsp, r.rsi, r.rflags, r.cr0, r.cr3, r.cr4, r.efer, r.gdtr_base].iter().enumerate() {
+        b[56 + i * 8..64 + i * 8].copy_from_slice(&v.to_le_bytes());
+    }
+    b[128..130].copy_from_slice(&r.gdtr_limit.to_le_bytes());
+    b[130..132].copy_from_slice(&r.cs.to_le_bytes());
+    b[132..134].copy_from_slice(&r.ds.to_le_bytes());
+    b[136..136 + cni::NAME_MAX].copy_from_slice(&t.network);
+    let crc = crate::util::crc32::crc32(&b[..HEADER_LEN - 4]);
+    b[HEADER_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// The template a volume header describes; `frames`, `id` and the name are the caller's.
+fn decode(b: &[u8; HEADER_LEN]) -> Result<Template, &'static str> {
+    let u16at = |o: usize| u16::from_le_bytes([b[o], b[o + 1]]);
+    let u32at = |o: usize| u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]]);
+    let u64at = |o: usize| { let mut v = [0u8; 8]; v.copy_from_slice(&b[o..o + 8]); u64::from_le_bytes(v) };
+    if b[..8] != MAGIC { return Err("microvm: volume holds no template"); }
+    if u32at(8) != VERSION { return Err("microvm: unsupported template version"); }
+    if u32at(HEADER_LEN - 4) != crate::util::crc32::crc32(&b[..HEADER_LEN - 4]) { return Err("microvm: template header is corrupt"); }
+    let kind = match b[48] { 0 => ImageKind::Flat, 1 => ImageKind::Linux, _ => return Err("microvm: unknown image kind") };
+    let signature = match b[49] { 0 => Verdict::Unsigned, 1 => Verdict::Trusted { key: b[50] }, 2 => Verdict::Untrusted, _ => Verdict::Malformed };
+    let regs = BootRegs {
+        rip: u64at(56), rsp: u64at(64), rsi: u64at(72), rflags: u64at(80), cr0: u64at(88), cr3: u64at(96), cr4: u64at(104),
+        efer: u64at(112), gdtr_base: u64at(120), gdtr_limit: u16at(128), cs: u16at(130), ds: u16at(132),
+    };
+    let ram_bytes = u64at(16);
+    if ram_bytes == 0 || ram_bytes % (2 << 20) != 0 { return Err("microvm: template RAM size is invalid"); }
+    let net_len = b[54];
+    if net_len as usize > cni::NAME_MAX { return Err("microvm: template header is corrupt"); }
+    let mut network = [0u8; cni::NAME_MAX];
+    network.copy_from_slice(&b[136..136 + cni::NAME_MAX]);
+    let image = GuestImage {
+        vm_id: 0, kind, ram_host: 0, ram_bytes, load_gpa: u64at(24), image_bytes: u64at(32), root_phys: 0,
+        boot_version: u16at(52), acpi_rsdp: u64at(40), regs, signature,
+    };
+    Ok(Template {
+        id: 0, name: [0; NAME_MAX], name_len: 0, volume: 0, frames: 0, image, vcpus: u32at(12).max(1), vsock: b[51] != 0,
+        network, net_len, pool_target: 0, clones: 0, pool_error: None, latency: Latency::default(),
+    })
+}
+
+/// Write header and memory image to volume `vol`, skipping zero runs.
+fn persist(system_table: &SystemTable<Boot>, t: &Template, vol: u32) -> Result<(), &'static str> {
+    let mut dev = storage::open_dev(system_table)?;
+    let mut hdr = [0u8; HEADER_LEN];
+    encode(t, &mut hdr);
+    if !storage::write(&mut dev, vol, 0, &hdr) { return Err("microvm: cannot write the template volume"); }
+    let mut off = 0u64;
+    while off < t.image.ram_bytes {
+        let run = unsafe { core::slice::from_raw_parts((t.frames + off) as *const u8, RUN) };
+        if run.iter().any(|&b| b != 0) && !storage::write(&mut dev, vol, storage::CHUNK + off, run) {
+            return Err("microvm: cannot write the template volume");
+        }
+        off += RUN as u64;
+    }
+    drop(dev);
+    storage::sync(system_table)
+}
+
+fn insert(mut t: Template) -> Result<u32, &'static str> {
+    STATE.lock(|s| {
+        if s.templates.iter().flatten().any(|o| o.name() == t.name()) { return Err("microvm: template name in use"); }
+        let slot = s.templates.iter().position(|o| o.is_none()).ok_or("microvm: too many templates")?;
+        t.id = s.next_id;
+        s.next_id += 1;
+        s.templates[slot] = Some(t);
+        Ok(t.id)
+    })
+}
+
+/// Build a template: load the kernel into a scratch VM, keep its RAM and
+/// boot state, and store both in a new volume. Needs an open storage pool.
+pub fn create(system_table: &SystemTable<Boot>, cfg: &TemplateConfig) -> Result<u32, &'static str> {
+    if !valid_name(cfg.name) { return Err("microvm: name must be 1..32 of [A-Za-z0-9_.-] starting with a letter"); }
+    if cfg.vcpus == 0 || cfg.memory_mib == 0 || cfg.memory_mib >> 44 != 0 { return Err("microvm: vcpus and memory must be positive"); }
+    if storage::info().is_none() { return Err("microvm: no storage pool open"); }
+    if find(cfg.name).is_some() { return Err("microvm: template name in use"); }
+    let mut network = [0u8; cni::NAME_MAX];
+    let mut net_len = 0;
+    if let Some(n) = cfg.network {
+        let net = cni::network(n).ok_or("microvm: no such CNI network")?;
+        net_len = net.name().len() as u8;
+        network[..net.name().len()].copy_from_slice(net.name().as_bytes());
+    }
+
+    // Scratch VM: the loader does the signature check and builds the
+    // boot structures for this vCPU count.
+    let config = crate::hv::vm::VmConfig { memory_bytes: cfg.memory_mib << 20, vcpu_count: cfg.vcpus, ..Default::default() };
+    let vm = crate::hv::vm::Vm::try_create(system_table, config, 0, 0).map_err(|_| "microvm: admission denied the scratch VM")?;
+    let scratch = vm.id.0;
+    if let Err(e) = crate::hv::vm::register(&vm, None) {
+        vm.destroy();
+        return Err(e);
+    }
+    let req = loader::LoadRequest { vm_id: scratch, path: cfg.kernel, ram_bytes: cfg.memory_mib << 20, flat_load_gpa: loader::DEFAULT_LOAD_GPA, cmdline: cfg.cmdline };
+    let loaded = loader::load(system_table, &req).and_then(|img| {
+        let frames = alloc_frames(system_table, img.ram_bytes)?;
+        unsafe { core::ptr::copy_nonoverlapping(img.ram_host as *const u8, frames as *mut u8, img.ram_bytes as usize); }
+        Ok((img, frames))
+    });
+    let _ = crate::hv::vm::destroy_vm(scratch);
+    let (img, frames) = loaded?;
+    let mut t = Template {
+        id: 0, name: [0; NAME_MAX], name_len: cfg.name.len() as u8, volume: 0, frames, image: img, vcpus: cfg.vcpus, vsock: cfg.vsock,
+        network, net_len, pool_target: 0, clones: 0, pool_error: None, latency: Latency::default(),
+    };
+    t.name[..cfg.name.len()].copy_from_slice(cfg.name.as_bytes());
+
+    let mut vname = [0u8; VOLUME_PREFIX.len() + NAME_MAX];
+    let n = volume_name(cfg.name, &mut vname);
+    let vname = core::str::from_utf8(&vname[..n]).unwrap_or("?");
+    let vol = match storage::create(system_table, vname, (img.ram_bytes + storage::CHUNK) >> 20) {
+        Ok(v) => v,
+        Err(e) => { free_frames(system_table, frames, img.ram_bytes); return Err(e); }
+    };
+    t.volume = vol;
+    let stored = persist(system_table, &t, vol).and_then(|_| insert(t));
+    if stored.is_err() {
+        let _ = storage::delete(system_table, vol);
+        free_frames(system_table, frames, img.ram_bytes);
+    }
+    stored
+}
+
+/// Read back the template stored in volume `sel` (id or name).
+pub fn open(system_table: &SystemTable<Boot>, sel: &str) -> Result<u32, &'static str> {
+    let vol = storage::find(sel).and_then(storage::volume).ok_or("microvm: no such volume")?;
+    let name = vol.name().strip_prefix(VOLUME_PREFIX).filter(|n| valid_name(n)).ok_or("microvm: not a template volume")?;
+    if STATE.lock(|s| s.templates.iter().flatten().any(|t| t.volume == vol.id)) { return Err("microvm: template already open"); }
+    if crate::hv::imgsig::strict() { return Err("microvm: strict signature policy admits only templates built this boot"); }
+    let mut dev = storage::open_dev(system_table)?;
+    let mut hdr = [0u8; HEADER_LEN];
+    if !storage::read(&mut dev, vol.id, 0, &mut hdr) { return Err("microvm: cannot read the template volume"); }
+    let mut t = decode(&hdr)?;
+    if vol.size < t.image.ram_bytes + storage::CHUNK { return Err("microvm: template volume is too small"); }
+    if let Some(n) = t.network() { if cni::network(n).is_none() { return Err("microvm: template's CNI network is gone"); } }
+    let frames = alloc_frames(system_table, t.image.ram_bytes)?;
+    let ram = unsafe { core::slice::from_raw_parts_mut(frames as *mut u8, t.image.ram_bytes as usize) };
+    if !storage::read(&mut dev, vol.id, storage::CHUNK, ram) {
+        free_frames(system_table, frames, t.image.ram_bytes);
+        return Err("microvm: cannot read the template volume");
+    }
+    drop(dev);
+    t.frames = frames;
+    t.volume = vol.id;
+    t.name_len = name.len() as u8;
+    t.name[..name.len()].copy_from_slice(name.as_bytes());
+    let id = insert(t);
+    if id.is_err() { free_frames(system_table, frames, t.image.ram_bytes); }
+    id
+}
+
+/// Drop template `id` and its warm clones; `purge` deletes its volume too.
+/// Fails while a started clone still maps it.
+pub fn remove(system_table: &SystemTable<Boot>, id: u32, purge: bool) -> Result<(), &'static str> {
+    let t = template(id).ok_or("microvm: no such template")?;
+    if t.clones > pooled(id) { return Err("microvm: started clones still use the template"); }
+    set_pool(id, 0)?;
+    let t = STATE.lock(|s| {
+        let slot = s.templates.iter_mut().find(|o| matches!(o, Some(o) if o.id == id))?;
+        if slot.map_or(0, |o| o.clones) != 0 { return None; }
+        slot.take()
+    }).ok_or("microvm: clones still use the template")?;
+    free_frames(system_table, t.frames, t.image.ram_bytes);
+    if purge { storage::delete(system_table, t.volume)?; }
+    Ok(())
+}
+
+// ---- Clones ----
+
+/// A new VM on template `id`: own RAM and stage-2 table, every page mapped
+/// read-only onto the template, devices plugged in. Not started.
+fn clone_vm(system_table: &SystemTable<Boot>, id: u32, warm: bool) -> Result<u64, &'static str> {
+    let t = template(id).ok_or("microvm: no such template")?;
+    let config = crate::hv::vm::VmConfig { memory_bytes: t.image.ram_bytes, vcpu_count: t.vcpus, ..Default::default() };
+    let vm = crate::hv::vm::Vm::try_create(system_table, config, 0, 0).map_err(|_| "microvm: admission denied the clone")?;
+    let vm_id = vm.id.0;
+    if let Err(e) = crate::hv::vm::register(&vm, None) {
+        vm.destroy();
+        return Err(e);
+    }
+    let setup = || -> Result<(), &'static str> {
+        let fmt = loader::stage2_format(vm_id).ok_or("microvm: no stage-2 format for this CPU")?;
+        let img = loader::adopt(system_table, vm_id, &t.image)?;
+        stage2::demote(system_table, img.root_phys, 0, img.ram_bytes)?;
+        let c = Instance { vm_id, template: id, fmt, root: img.root_phys, host: img.ram_host, bytes: img.ram_bytes, frames: t.frames, warm };
+        STATE.lock(|s| {
+            let tm = s.templates.iter_mut().flatten().find(|o| o.id == id).ok_or("microvm: template went away")?;
+            let slot = s.clones.iter().position(|o| o.is_none()).ok_or("microvm: too many clones")?;
+            let mut gpa = 0;
+            while gpa < c.bytes {
+                if !stage2::remap_page(fmt, c.root, gpa, c.frames + gpa, false, true) { return Err("microvm: guest RAM is not mapped by 4KiB pages"); }
+                gpa += PAGE;
+            }
+            tm.clones += 1;
+            s.clones[slot] = Some(c);
+            LIVE.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })?;
+        if t.vsock { crate::hv::vdev::vsock::add(vm_id, None)?; }
+        if let Some(n) = t.network() { cni::add(vm_id, n, NET_IF, None)?; }
+        Ok(())
+    };
+    if let Err(e) = setup() {
+        let _ = crate::hv::vm::destroy_vm(vm_id);
+        return Err(e);
+    }
+    Counter::new(&metrics::MICROVM_CLONES).inc();
+    Ok(vm_id)
+}
+
+/// Give `vm_id` its own copy of the page holding `gpa` if the page is still
+/// on its template; called for guest writes that fault in stage 2 and for
+/// host accesses. True if the access can be retried.
+pub fn write_fault(vm_id: u64, gpa: u64) -> bool {
+    if LIVE.load(Ordering::Relaxed) == 0 { return false; }
+    let gpa = gpa & !(PAGE - 1);
+    STATE.lock(|s| {
+        let Some(c) = s.clones.iter().flatten().find(|c| c.vm_id == vm_id) else { return false };
+        if gpa >= c.bytes { return false; }
+        match stage2::page(c.root, gpa) {
+            Some(l) if l.shared && l.hpa == c.frames + gpa => {
+                unsafe { core::ptr::copy_nonoverlapping((c.frames + gpa) as *const u8, (c.host + gpa) as *mut u8, PAGE as usize); }
+                let _ = stage2::remap_page(c.fmt, c.root, gpa, c.host + gpa, true, false);
+                Counter::new(&metrics::MICROVM_COW_BREAKS).inc();
+                true
+            }
+            // Copied already; this vCPU faulted on a stale translation.
+            Some(l) => l.writable && l.level == 1,
+            None => false,
+        }
+    })
+}
+
+/// Copy every page `vm_id` still shares with its template.
+pub fn privatize(vm_id: u64) {
+    let Some(bytes) = STATE.lock(|s| s.clones.iter().flatten().find(|c| c.vm_id == vm_id).map(|c| c.bytes)) else { return };
+    let mut gpa = 0;
+    while gpa < bytes {
+        let _ = write_fault(vm_id, gpa);
+        gpa += PAGE;
+    }
+}
+
+/// Forget `vm_id` before its RAM goes away.
+pub fn forget_vm(vm_id: u64) {
+    STATE.lock(|s| {
+        let Some(i) = s.clones.iter().position(|c| matches!(c, Some(c) if c.vm_id == vm_id)) else { return };
+        let Some(c) = s.clones[i].take() else { return };
+        if let Some(t) = s.templates.iter_mut().flatten().find(|t| t.id == c.template) { t.clones = t.clones.saturating_sub(1); }
+        LIVE.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+// ---- Pool ----
+
+/// Keep `target` warm clones of template `id`. Lowering it destroys the surplus.
+pub fn set_pool(id: u32, target: u32) -> Result<(), &'static str> {
+    if target > POOL_MAX { return Err("microvm: pool target is at most 8"); }
+    STATE.lock(|s| s.templates.iter_mut().flatten().find(|t| t.id == id).map(|t| { t.pool_target = target; t.pool_error = None; })).ok_or("microvm: no such template")?;
+    while pooled(id) > target {
+        let Some(vm) = take_warm(id) else { break };
+        let _ = crate::hv::vm::destroy_vm(vm);
+    }
+    Ok(())
+}
+
+fn take_warm(id: u32) -> Option<u64> {
+    STATE.lock(|s| {
+        let c = s.clones.iter_mut().flatten().find(|c| c.template == id && c.warm)?;
+        c.warm = false;
+        Some(c.vm_id)
+    })
+}
+
+/// Make one warm clone for the first template short of its target.
+/// Returns whether one was made.
+pub fn refill(system_table: &SystemTable<Boot>) -> bool {
+    let short = STATE.lock(|s| {
+        s.templates.iter().flatten().find(|t| s.clones.iter().flatten().filter(|c| c.template == t.id && c.warm).count() < t.pool_target as usize).map(|t| t.id)
+    });
+    let Some(id) = short else { return false };
+    match clone_vm(system_table, id, true) {
+        Ok(_) => true,
+        // Out of room: stop trying until the target is set again.
+        Err(e) => {
+            STATE.lock(|s| if let Some(t) = s.templates.iter_mut().flatten().find(|t| t.id == id) { t.pool_target = 0; t.pool_error = Some(e); });
+            false
+        }
+    }
+}
+
+/// A started clone.
+#[derive(Clone, Copy, Debug)]
+pub struct Started {
+    pub vm_id: u64,
+    pub vcpus: u32,
+    /// From the call to the vCPUs running
+    pub us: u64,
+    /// Taken from the pool rather than cloned on demand
+    pub pooled: bool,
+}
+
+/// Start a clone of template `id`, named `name` if given.
+pub fn start(system_table: &SystemTable<Boot>, id: u32, name: Option<&str>) -> Result<Started, &'static str> {
+    let t0 = crate::time::clock::now_ns();
+    if template(id).is_none() { return Err("microvm: no such template"); }
+    if let Some(n) = name {
+        if !crate::hv::vm::valid_name(n) { return Err("vm: invalid name"); }
+        if crate::hv::vm::find_vm_by_name(n).is_some() { return Err("vm: name in use"); }
+    }
+    let (vm_id, pooled) = match take_warm(id) {
+        Some(vm) => (vm, true),
+        None => (clone_vm(system_table, id, false)?, false),
+    };
+    let run = || -> Result<u32, &'static str> {
+        if let Some(n) = name { crate::hv::vm::rename(vm_id, n)?; }
+        match crate::hv::run::start(system_table, vm_id)? {
+            0 => Err("microvm: no vCPU of the clone started"),
+            n => Ok(n),
+        }
+    };
+    let vcpus = match run() {
+        Ok(n) => n,
+        Err(e) => {
+            let _ = crate::hv::run::stop(system_table, vm_id, STOP_TIMEOUT_US);
+            let _ = crate::hv::vm::destroy_vm(vm_id);
+            return Err(e);
+        }
+    };
+    let us = crate::time::clock::elapsed_ns(t0) / 1_000;
+    STATE.lock(|s| if let Some(t) = s.templates.iter_mut().flatten().find(|t| t.id == id) { t.latency.note(us, pooled); });
+    Counter::new(&metrics::MICROVM_STARTS).inc();
+    if pooled { Counter::new(&metrics::MICROVM_POOL_HITS).inc(); }
+    Ok(Started { vm_id, vcpus, us, pooled })
+}
@@ -0,0 +1,97 @@
+#![allow(dead_code)]
+
+//! Guest MMIO access tracing for device bring-up.
+//!
+//! A filter selects a guest-physical window of one VM. Every emulated access
+//! inside it (claimed by a device or not) is emitted into the trace ring as
+//! `Event::GuestMmio` with value, size, RIP and vCPU. The trace ring is small,
+//! so each filter is rate-limited by a token bucket; accesses over the limit
+//! are only counted. With no filter installed the exit path pays a single
+//! atomic load.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_FILTERS: usize = 4;
+/// Events per second per filter when no rate is given
+pub const DEFAULT_RATE: u32 = 200;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Filter {
+    pub vm_id: u64,
+    pub base: u64,
+    pub len: u64,
+    /// Events per second (bucket depth is one second's worth)
+    pub rate: u32,
+    /// Accesses emitted to the trace ring
+    pub logged: u64,
+    /// Accesses in range but over the rate limit
+    pub suppressed: u64,
+    tokens: u32,
+    /// `clock::now_ns` the tokens were refilled up to
+    last_refill: u64,
+}
+
+static FILTERS: SpinLock<[Option<Filter>; MAX_FILTERS]> = SpinLock::new([None; MAX_FILTERS]);
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+fn recount(t: &[Option<Filter>; MAX_FILTERS]) { ACTIVE.store(t.iter().flatten().count(), Ordering::Relaxed); }
+
+/// Install (or replace) the filter of `vm_id` covering `base..base+len`.
+pub fn set(vm_id: u64, base: u64, len: u64, rate: u32) -> Result<(), &'static str> {
+    if len == 0 || base.checked_add(len).is_none() { return Err("mmio-trace: invalid range"); }
+    if !crate::hv::confidential::allows(vm_id, crate::hv::confidential::Feature::MmioTrace) { return Err("mmio-trace: not allowed on a confidential vm"); }
+    let rate = if rate == 0 { DEFAULT_RATE } else { rate };
+    FILTERS.lock(|t| {
+        let slot = t.iter().position(|f| matches!(f, Some(f) if f.vm_id == vm_id && f.base == base))
+            .or_else(|| t.iter().position(|f| f.is_none()))
+            .ok_or("mmio-trace: too many filters")?;
+        t[slot] = Some(Filter { vm_id, base, len, rate, logged: 0, suppressed: 0, tokens: rate, last_refill: crate::time::clock::now_ns() });
+        recount(t);
+        Ok(())
+    })
+}
+
+/// Remove the filters of `vm_id`. Returns how many were removed.
+pub fn clear_vm(vm_id: u64) -> usize {
+    FILTERS.lock(|t| {
+        let mut n = 0;
+        for f in t.iter_mut() { if matches!(f, Some(x) if x.vm_id == vm_id) { *f = None; n += 1; } }
+        recount(t);
+        n
+    })
+}
+
+pub fn detach_vm(vm_id: u64) { let _ = clear_vm(vm_id); }
+
+pub fn for_each(mut f: impl FnMut(&Filter)) {
+    let snap = FILTERS.lock(|t| *t);
+    for x in snap.iter().flatten() { f(x); }
+}
+
+/// Record one emulated access. `value` is the value written, or the value
+/// returned to the guest for reads.
+#[inline]
+pub fn access(vm_id: u64, vcpu: u32, gpa: u64, size: u8, write: bool, value: u64, rip: u64) {
+    if ACTIVE.load(Ordering::Relaxed) == 0 { return; }
+    let emit = FILTERS.lock(|t| {
+        let f = match t.iter_mut().flatten().find(|f| f.vm_id == vm_id && gpa >= f.base && gpa - f.base < f.len) { Some(f) => f, None => return false };
+        if crate::time::clock::hz() != 0 {
+            // Refill whole tokens only, keeping the remainder of the interval.
+            let per_token = (1_000_000_000 / f.rate as u64).max(1);
+            let add = crate::time::clock::now_ns().saturating_sub(f.last_refill) / per_token;
+            if add != 0 {
+                f.tokens = (f.tokens as u64 + add).min(f.rate as u64) as u32;
+                f.last_refill = f.last_refill.wrapping_add(add * per_token);
+            }
+        }
+        if f.tokens == 0 { f.suppressed += 1; return false; }
+        f.tokens -= 1;
+        f.logged += 1;
+        true
+    });
+    if emit {
+        crate::obs::trace::emit(crate::obs::trace::Event::GuestMmio { vm: vm_id as u32, vcpu: vcpu as u16, size, write, gpa, value, rip });
+    }
+}
@@ -1,4 +1,45 @@
 pub mod vm;
 pub mod vcpu;
-
-
+pub mod loader;
+pub mod imgsig;
+pub mod ksm;
+pub mod zero_copy;
+pub mod info_flow;
+pub mod admission;
+pub mod confidential;
+pub mod vlapic;
+pub mod event;
+pub mod sched;
+pub mod vtime;
+pub mod bus;
+pub mod emul;
+pub mod mmiotrace;
+pub mod power;
+pub mod carbon;
+pub mod dvfs;
+pub mod thermal;
+pub mod numa;
+pub mod exit;
+pub mod vpci;
+pub mod sriov;
+pub mod gpu;
+pub mod fpga;
+pub mod vdev;
+pub mod storage;
+pub mod csi;
+pub mod cni;
+pub mod cri;
+pub mod microvm;
+pub mod wasm;
+pub mod plugin;
+pub mod features;
+pub mod acpi;
+pub mod run;
+pub mod gdb;
+pub mod coredump;
+pub mod heartbeat;
+pub mod runtime;
+pub mod bootinfo;
+pub mod bootslot;
+pub mod bootcfg;
+pub mod setup;
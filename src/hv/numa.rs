@@ -0,0 +1,221 @@
+#![allow(dead_code)]
+
+//! NUMA placement of guest RAM and the vNUMA topology a guest sees.
+//!
+//! A VM pinned to CPUs (`sched::affinity`) gets its RAM from the SRAT nodes
+//! those CPUs sit on, split in proportion to how many of its online APs
+//! each node has (`plan`). Unpinned VMs keep the allocator's default
+//! placement. Guest RAM is a single host extent, so a VM spanning nodes can
+//! only be split where two node ranges meet; `spread` reports where its
+//! frames really are.
+//!
+//! With vNUMA on, the loader describes that placement to the guest in an
+//! SRAT and SLIT (`hv::acpi`). Every host node that backs part of guest RAM
+//! or holds one of the pinned CPUs becomes a guest proximity domain, guest
+//! ranges take the node of the frames behind them, and vCPUs are shared out
+//! over the nodes with pinned CPUs in the same proportion as the RAM. Those
+//! vCPUs are then homed on APs of their node (`vcpu_apics`), so the tables
+//! stay true while the VM runs. Switching vNUMA takes effect at the next
+//! `vm load`.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::arch::x86::ap;
+use crate::firmware::acpi::numa::{NumaTopology, MAX_NODES};
+use crate::hv::sched::affinity;
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_VNODES: usize = 8;
+pub const MAX_VRANGES: usize = 16;
+pub const MAX_VCPUS: usize = crate::hv::run::MAX_VCPUS;
+/// Node shares are cut on large-page boundaries.
+const SHARE_ALIGN: u64 = 2 << 20;
+
+/// A stretch of guest RAM and the guest node it belongs to.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VRange { pub gpa: u64, pub len: u64, pub node: u8 }
+
+/// Topology exposed to a guest.
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub nodes: usize,
+    /// Host node behind each guest node
+    pub host: [u8; MAX_VNODES],
+    /// Pinned CPUs of each guest node, as an APIC ID mask
+    pub apics: [u64; MAX_VNODES],
+    pub ranges: [VRange; MAX_VRANGES],
+    pub range_count: usize,
+    /// Guest node of each vCPU
+    pub vcpu: [u8; MAX_VCPUS],
+    pub vcpus: usize,
+    pub distance: [[u8; MAX_VNODES]; MAX_VNODES],
+}
+
+impl Layout {
+    pub fn ranges(&self) -> &[VRange] { &self.ranges[..self.range_count] }
+
+    /// APs vCPU `v` may be homed on, or None if it has no node.
+    pub fn vcpu_apics(&self, v: u32) -> Option<u64> {
+        let v = v as usize;
+        if v >= self.vcpus { return None; }
+        Some(self.apics[self.vcpu[v] as usize]).filter(|&m| m != 0)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry { vm_id: u64, vnuma: bool, layout: Option<Layout> }
+
+static TABLE: SpinLock<[Option<Entry>; crate::mm::guest::MAX_VMS]> = SpinLock::new([None; crate::mm::guest::MAX_VMS]);
+
+/// Online APs inside `mask` on each node, and their APIC IDs as masks.
+/// All zero for an unpinned VM or without an SRAT.
+fn pinned(topo: &NumaTopology, mask: u64) -> ([u32; MAX_NODES], [u64; MAX_NODES]) {
+    let mut count = [0u32; MAX_NODES];
+    let mut apics = [0u64; MAX_NODES];
+    if mask == 0 || !topo.from_srat { return (count, apics); }
+    ap::for_each_online(|_, apic, _| {
+        if apic >= 64 || !affinity::allows(mask, apic) { return; }
+        let Some(node) = topo.node_of_apic(apic) else { return };
+        if (node as usize) < MAX_NODES { count[node as usize] += 1; apics[node as usize] |= 1u64 << apic; }
+    });
+    (count, apics)
+}
+
+/// Bytes of `bytes` each host node should back for a VM pinned to
+/// `mask`: shares follow the pinned APs per node, the rounding goes to the
+/// node with the most. None when there is nothing to steer.
+pub fn plan(system_table: &SystemTable<Boot>, mask: u64, bytes: u64) -> Option<[u64; MAX_NODES]> {
+    let topo = crate::firmware::acpi::numa::topology(system_table);
+    let (count, _) = pinned(&topo, mask);
+    let total: u64 = count.iter().map(|&c| c as u64).sum();
+    if total == 0 { return None; }
+    let mut want = [0u64; MAX_NODES];
+    for (w, &c) in want.iter_mut().zip(count.iter()) {
+        *w = ((bytes as u128 * c as u128 / total as u128) as u64) & !(SHARE_ALIGN - 1);
+    }
+    let top = (0..MAX_NODES).max_by_key(|&n| count[n]).unwrap_or(0);
+    want[top] += bytes - want.iter().sum::<u64>();
+    Some(want)
+}
+
+/// Describe guest RAM backed at `ram_host` and `vcpus` vCPUs pinned to
+/// `mask`. None if the guest would need more nodes or ranges than the
+/// tables hold.
+pub fn layout(system_table: &SystemTable<Boot>, mask: u64, ram_host: u64, ram_bytes: u64, vcpus: u32) -> Option<Layout> {
+    let topo = crate::firmware::acpi::numa::topology(system_table);
+    if !topo.from_srat { return None; }
+    let mut l = Layout {
+        nodes: 0, host: [0; MAX_VNODES], apics: [0; MAX_VNODES],
+        ranges: [VRange::default(); MAX_VRANGES], range_count: 0,
+        vcpu: [0; MAX_VCPUS], vcpus: (vcpus.max(1) as usize).min(MAX_VCPUS),
+        distance: [[0; MAX_VNODES]; MAX_VNODES],
+    };
+    let vnode = |l: &mut Layout, host: u8| -> Option<u8> {
+        if let Some(i) = l.host[..l.nodes].iter().position(|&h| h == host) { return Some(i as u8); }
+        if l.nodes == MAX_VNODES { return None; }
+        l.host[l.nodes] = host;
+        l.nodes += 1;
+        Some((l.nodes - 1) as u8)
+    };
+
+    // Guest ranges in address order, each on the node of its frames. A gap
+    // no SRAT range covers stays with the node before it.
+    let end = ram_host.saturating_add(ram_bytes);
+    let mut pa = ram_host;
+    let mut last: Option<u8> = None;
+    while pa < end {
+        let covering = topo.mem_ranges().iter().find(|r| pa >= r.base && pa - r.base < r.len);
+        let (stop, host) = match covering {
+            Some(r) => (r.base.saturating_add(r.len).min(end), Some(r.node)),
+            None => (topo.mem_ranges().iter().map(|r| r.base).filter(|&b| b > pa).min().unwrap_or(end).min(end), None),
+        };
+        let host = host.or(last.map(|v| l.host[v as usize])).unwrap_or(0);
+        let v = vnode(&mut l, host)?;
+        let gpa = pa - ram_host;
+        match l.ranges[..l.range_count].last_mut() {
+            Some(prev) if prev.node == v => prev.len += stop - pa,
+            _ => {
+                if l.range_count == MAX_VRANGES { return None; }
+                l.ranges[l.range_count] = VRange { gpa, len: stop - pa, node: v };
+                l.range_count += 1;
+            }
+        }
+        last = Some(v);
+        pa = stop;
+    }
+
+    // vCPUs over the nodes with pinned APs, in proportion to their count.
+    let (count, apics) = pinned(&topo, mask);
+    let total: u32 = count.iter().sum();
+    for v in 0..l.vcpus {
+        let node = if total == 0 {
+            last.map(|v| l.host[v as usize]).unwrap_or(0)
+        } else {
+            let at = (v as u64 * total as u64 / l.vcpus as u64) as u32;
+            let mut acc = 0;
+            (0..MAX_NODES).find(|&n| { acc += count[n]; at < acc }).unwrap_or(0) as u8
+        };
+        l.vcpu[v] = vnode(&mut l, node)?;
+    }
+    for i in 0..l.nodes {
+        l.apics[i] = apics.get(l.host[i] as usize).copied().unwrap_or(0);
+        for j in 0..l.nodes { l.distance[i][j] = topo.distance(l.host[i], l.host[j]); }
+    }
+    Some(l)
+}
+
+fn with_entry<R>(vm_id: u64, f: impl FnOnce(&mut Entry) -> R) -> Result<R, &'static str> {
+    TABLE.lock(|t| {
+        if let Some(e) = t.iter_mut().flatten().find(|e| e.vm_id == vm_id) { return Ok(f(e)); }
+        let slot = t.iter_mut().find(|e| e.is_none()).ok_or("numa: table full")?;
+        let e = slot.insert(Entry { vm_id, vnuma: false, layout: None });
+        Ok(f(e))
+    })
+}
+
+/// Ask for (or stop asking for) vNUMA tables at the VM's next load.
+pub fn set_vnuma(vm_id: u64, on: bool) -> Result<(), &'static str> {
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err("numa: no such vm"); }
+    with_entry(vm_id, |e| e.vnuma = on)
+}
+
+pub fn vnuma(vm_id: u64) -> bool { TABLE.lock(|t| t.iter().flatten().any(|e| e.vm_id == vm_id && e.vnuma)) }
+
+/// Topology the loader gave the guest, if any.
+pub fn layout_of(vm_id: u64) -> Option<Layout> { TABLE.lock(|t| t.iter().flatten().find(|e| e.vm_id == vm_id).and_then(|e| e.layout)) }
+
+/// Record the topology a load exposed (None when it exposed none).
+pub fn install(vm_id: u64, layout: Option<Layout>) {
+    if layout.is_none() && layout_of(vm_id).is_none() { return; }
+    let _ = with_entry(vm_id, |e| e.layout = layout);
+}
+
+pub fn detach_vm(vm_id: u64) {
+    TABLE.lock(|t| for e in t.iter_mut() { if matches!(e, Some(x) if x.vm_id == vm_id) { *e = None; } });
+}
+
+/// Where a VM's guest RAM is, in bytes per host node; `other` is what no
+/// SRAT range covers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Spread {
+    pub bytes: [u64; MAX_NODES],
+    pub other: u64,
+}
+
+pub fn spread(system_table: &SystemTable<Boot>, vm_id: u64) -> Spread {
+    let topo = crate::firmware::acpi::numa::topology(system_table);
+    let mut s = Spread::default();
+    crate::mm::guest::for_each_extent(|e| {
+        if e.vm_id != vm_id { return; }
+        let (lo, hi) = (e.base, e.base + e.pages * crate::mm::guest::PAGE);
+        let mut covered = 0;
+        for r in topo.mem_ranges().iter().filter(|r| (r.node as usize) < MAX_NODES) {
+            let a = lo.max(r.base);
+            let b = hi.min(r.base.saturating_add(r.len));
+            if b > a { s.bytes[r.node as usize] += b - a; covered += b - a; }
+        }
+        s.other += (hi - lo).saturating_sub(covered);
+    });
+    s
+}
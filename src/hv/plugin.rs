@@ -0,0 +1,420 @@
+#![allow(dead_code)]
+
+//! Loadable policy and device plugins.
+//!
+//! A plugin is a PE32+ image for x86-64, as the UEFI target of any
+//! toolchain produces, with the signature trailer of `hv::imgsig`. It runs
+//! inside the hypervisor with all of its privileges, so unlike guest images
+//! it is refused unless a trusted key verifies it, whatever the signature
+//! policy says. `load` copies the sections into loader code pages, applies
+//! the base relocations and calls the entry point once as an `InitFn`. The
+//! plugin fills in a `Descriptor`: its name, the ABI version it was built
+//! against and the hooks it implements.
+//!
+//! The ABI is the C calling convention of the target. `ABI_MAJOR` changes
+//! break it; a plugin is accepted if its major matches and its minor is not
+//! newer than the host's. Minors only append fields: the host zeroes the
+//! descriptor and sets its `size`, and a plugin writes no field past it.
+//!
+//! Hooks, all optional and called only while the plugin is enabled, on any
+//! CPU and without hypervisor locks held:
+//! - `vm_event(vm, event)` after a VM is created, changes state
+//!   (`VmState::code`) or is destroyed (`EVENT_DESTROYED`).
+//! - `sched_hint(vm, hint)` when a VM starts running. `hint` holds the
+//!   credit-scheduler weight and cap; returning 1 asks for the (possibly
+//!   changed) values to be applied. Plugins are asked in load order, each
+//!   seeing the previous one's hint.
+//! - `mmio(ctx, vm, vcpu, offset, size, write, value)` for windows the
+//!   plugin registered with `HostApi::register_mmio`, typically from its
+//!   `vm_event` hook. A disabled plugin's windows read as all-ones and
+//!   drop writes.
+//!
+//! A plugin cannot be unloaded: code it handed out may still be referenced.
+
+use uefi::prelude::Boot;
+use uefi::table::boot::MemoryType;
+use uefi::table::SystemTable;
+
+use crate::hv::imgsig::{self, Verdict};
+use crate::hv::vm::VmState;
+use crate::obs::metrics::{self, Counter};
+use crate::util::spinlock::SpinLock;
+
+pub const ABI_MAJOR: u16 = 1;
+pub const ABI_MINOR: u16 = 0;
+pub const MAX_PLUGINS: usize = 8;
+pub const NAME_MAX: usize = 32;
+/// MMIO windows across all plugins
+pub const MAX_WINDOWS: usize = 32;
+/// Largest image a plugin may map
+pub const MAX_IMAGE: u32 = 4 << 20;
+/// `vm_event` code after the VM is gone
+pub const EVENT_DESTROYED: u32 = 0xFF;
+
+/// Services the host offers plugins. `handle` is the value `init` got.
+#[repr(C)]
+pub struct HostApi {
+    pub abi_major: u16,
+    pub abi_minor: u16,
+    pub size: u32,
+    /// Add a line to the host log under the plugin's name
+    pub log: extern "C" fn(handle: u32, msg: *const u8, len: usize),
+    /// TSC frequency in Hz (0 before calibration)
+    pub tsc_hz: extern "C" fn() -> u64,
+    /// `VmState::code` of a VM, or -1 if there is no such VM
+    pub vm_state: extern "C" fn(vm_id: u64) -> i32,
+    /// Claim guest-physical `base..base + len` of a VM for the `mmio` hook,
+    /// which gets `ctx` back. 0 on success, -1 otherwise.
+    pub register_mmio: extern "C" fn(handle: u32, vm_id: u64, base: u64, len: u64, ctx: u64) -> i32,
+    /// Give a window back. 0 on success, -1 if the plugin does not own it.
+    pub unregister_mmio: extern "C" fn(handle: u32, vm_id: u64, base: u64) -> i32,
+}
+
+/// Credit-scheduler parameters a plugin may propose.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SchedHint { pub weight: u32, pub cap: u32 }
+
+/// Entry point of a plugin image. Returns 0 on success.
+pub type InitFn = extern "C" fn(host: *const HostApi, handle: u32, desc: *mut Descriptor) -> i32;
+pub type VmEventFn = extern "C" fn(vm_id: u64, event: u32);
+pub type SchedHintFn = extern "C" fn(vm_id: u64, hint: *mut SchedHint) -> i32;
+/// `write` is 1 for a store of `value`; the result of a load is returned.
+pub type MmioFn = extern "C" fn(ctx: u64, vm_id: u64, vcpu: u32, offset: u64, size: u8, write: u8, value: u64) -> u64;
+
+/// What `init` reports back.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Descriptor {
+    pub abi_major: u16,
+    pub abi_minor: u16,
+    /// Bytes of this structure the host provides
+    pub size: u32,
+    /// NUL-padded, `[A-Za-z0-9_.-]`
+    pub name: [u8; NAME_MAX],
+    pub version: u32,
+    pub vm_event: Option<VmEventFn>,
+    pub sched_hint: Option<SchedHintFn>,
+    pub mmio: Option<MmioFn>,
+}
+
+/// A loaded plugin.
+#[derive(Clone, Copy)]
+pub struct Plugin {
+    pub handle: u32,
+    name: [u8; NAME_MAX],
+    name_len: u8,
+    pub version: u32,
+    pub abi_minor: u16,
+    pub signature: Verdict,
+    pub enabled: bool,
+    /// Image location and size in pages
+    pub base: u64,
+    pub pages: usize,
+    /// Hook invocations
+    pub calls: u64,
+    vm_event: Option<VmEventFn>,
+    sched_hint: Option<SchedHintFn>,
+    mmio: Option<MmioFn>,
+    /// Still in `init`
+    loading: bool,
+}
+
+impl Plugin {
+    pub fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("?") }
+
+    /// Hooks the plugin implements, as `vm_event`, `sched_hint`, `mmio`.
+    pub fn hooks(&self) -> [bool; 3] { [self.vm_event.is_some(), self.sched_hint.is_some(), self.mmio.is_some()] }
+}
+
+#[derive(Clone, Copy)]
+struct Window { plugin: u32, vm_id: u64, base: u64, ctx: u64 }
+
+static PLUGINS: SpinLock<[Option<Plugin>; MAX_PLUGINS]> = SpinLock::new([None; MAX_PLUGINS]);
+static WINDOWS: SpinLock<[Option<Window>; MAX_WINDOWS]> = SpinLock::new([None; MAX_WINDOWS]);
+
+static HOST: HostApi = HostApi {
+    abi_major: ABI_MAJOR,
+    abi_minor: ABI_MINOR,
+    size: core::mem::size_of::<HostApi>() as u32,
+    log: host_log,
+    tsc_hz: host_tsc_hz,
+    vm_state: host_vm_state,
+    register_mmio: host_register_mmio,
+    unregister_mmio: host_unregister_mmio,
+};
+
+pub fn for_each(mut f: impl FnMut(&Plugin)) {
+    let t = PLUGINS.lock(|t| *t);
+    for p in t.iter().flatten().filter(|p| !p.loading) { f(p); }
+}
+
+pub fn find(name: &str) -> Option<Plugin> { PLUGINS.lock(|t| t.iter().flatten().find(|p| !p.loading && p.name() == name).copied()) }
+
+/// Enable or disable plugin `name`.
+pub fn set_enabled(name: &str, on: bool) -> Result<(), &'static str> {
+    PLUGINS.lock(|t| match t.iter_mut().flatten().find(|p| !p.loading && p.name() == name) {
+        Some(p) => { p.enabled = on; Ok(()) }
+        None => Err("plugin: no such plugin"),
+    })
+}
+
+// ---- Loading ----
+
+fn rd16(b: &[u8], o: usize) -> Option<u16> { Some(u16::from_le_bytes(b.get(o..o + 2)?.try_into().ok()?)) }
+fn rd32(b: &[u8], o: usize) -> Option<u32> { Some(u32::from_le_bytes(b.get(o..o + 4)?.try_into().ok()?)) }
+fn rd64(b: &[u8], o: usize) -> Option<u64> { Some(u64::from_le_bytes(b.get(o..o + 8)?.try_into().ok()?)) }
+
+/// Layout of a PE32+ image.
+struct Pe { entry: u32, image_base: u64, size: u32, headers: u32, reloc: (u32, u32), sections: usize, nsec: usize }
+
+fn parse_pe(img: &[u8]) -> Option<Pe> {
+    if img.get(..2)? != b"MZ" { return None; }
+    let pe = rd32(img, 0x3C)? as usize;
+    if img.get(pe..pe + 4)? != b"PE\0\0" { return None; }
+    let coff = pe + 4;
+    if rd16(img, coff)? != 0x8664 { return None; }
+    let nsec = rd16(img, coff + 2)? as usize;
+    let opt = coff + 20;
+    let opt_len = rd16(img, coff + 16)? as usize;
+    if rd16(img, opt)? != 0x20B || opt_len < 112 { return None; }
+    let dirs = rd32(img, opt + 108)? as usize;
+    let reloc = if dirs > 5 && opt_len >= 112 + 6 * 8 { (rd32(img, opt + 152)?, rd32(img, opt + 156)?) } else { (0, 0) };
+    Some(Pe {
+        entry: rd32(img, opt + 16)?, image_base: rd64(img, opt + 24)?, size: rd32(img, opt + 56)?, headers: rd32(img, opt + 60)?,
+        reloc, sections: opt + opt_len, nsec,
+    })
+}
+
+/// Copy headers and sections of `img` to `dst` (zeroed, `pe.size` bytes).
+fn map_sections(img: &[u8], pe: &Pe, dst: &mut [u8]) -> Result<(), &'static str> {
+    const BAD: &str = "plugin: section outside the image";
+    let h = pe.headers as usize;
+    if h > img.len() || h > dst.len() { return Err(BAD); }
+    dst[..h].copy_from_slice(&img[..h]);
+    for k in 0..pe.nsec {
+        let s = pe.sections + k * 40;
+        let (Some(vsize), Some(va), Some(raw), Some(ptr)) = (rd32(img, s + 8), rd32(img, s + 12), rd32(img, s + 16), rd32(img, s + 20)) else { return Err(BAD) };
+        let n = if vsize == 0 { raw } else { raw.min(vsize) } as usize;
+        let (va, ptr) = (va as usize, ptr as usize);
+        if va.checked_add(vsize.max(raw) as usize).map_or(true, |e| e > dst.len()) || ptr.checked_add(n).map_or(true, |e| e > img.len()) { return Err(BAD); }
+        dst[va..va + n].copy_from_slice(&img[ptr..ptr + n]);
+    }
+    Ok(())
+}
+
+/// Apply the base relocations of an image mapped at `dst` for `delta`.
+fn relocate(dst: &mut [u8], pe: &Pe, delta: u64) -> Result<(), &'static str> {
+    let (rva, len) = (pe.reloc.0 as usize, pe.reloc.1 as usize);
+    if len == 0 { return if delta == 0 { Ok(()) } else { Err("plugin: image is not relocatable") }; }
+    let end = rva.checked_add(len).filter(|&e| e <= dst.len()).ok_or("plugin: relocations outside the image")?;
+    let mut at = rva;
+    while at + 8 <= end {
+        let (Some(page), Some(block)) = (rd32(dst, at), rd32(dst, at + 4)) else { break };
+        let block = block as usize;
+        if block < 8 || at + block > end { return Err("plugin: bad relocation block"); }
+        for e in (at + 8..at + block).step_by(2) {
+            let Some(v) = rd16(dst, e) else { break };
+            let off = page as usize + (v & 0xFFF) as usize;
+            match v >> 12 {
+                0 => {}
+                10 => {
+                    let cur = rd64(dst, off).ok_or("plugin: relocation outside the image")?;
+                    dst[off..off + 8].copy_from_slice(&cur.wrapping_add(delta).to_le_bytes());
+                }
+                _ => return Err("plugin: unsupported relocation type"),
+            }
+        }
+        at += block;
+    }
+    Ok(())
+}
+
+fn valid_name(n: &[u8]) -> bool {
+    !n.is_empty() && n.len() <= NAME_MAX && n.iter().all(|&c| c.is_ascii_alphanumeric() || matches!(c, b'_' | b'.' | b'-'))
+}
+
+/// Load, verify and initialize the plugin at ESP `path`. It starts enabled.
+pub fn load(system_table: &SystemTable<Boot>, path: &str) -> Result<Plugin, &'static str> {
+    let (stage, stage_pages, file_len) = crate::hv::loader::read_esp_file(system_table, path)?;
+    let file = unsafe { core::slice::from_raw_parts(stage as *const u8, file_len) };
+    let res = map(system_table, path, file);
+    crate::mm::uefi::free_pages(system_table, stage, stage_pages);
+    let (handle, base, pages, entry, signature) = match res {
+        Ok(v) => v,
+        Err(e) => { Counter::new(&metrics::PLUGIN_REJECTS).inc(); return Err(e); }
+    };
+    let mut desc = Descriptor {
+        abi_major: 0, abi_minor: 0, size: core::mem::size_of::<Descriptor>() as u32, name: [0; NAME_MAX], version: 0,
+        vm_event: None, sched_hint: None, mmio: None,
+    };
+    let init: InitFn = unsafe { core::mem::transmute::<usize, InitFn>(entry as usize) };
+    let rc = init(&HOST, handle, &mut desc);
+    let name_len = desc.name.iter().position(|&c| c == 0).unwrap_or(NAME_MAX);
+    let name = &desc.name[..name_len];
+    let err = if rc != 0 { Some("plugin: init failed") }
+        else if desc.abi_major != ABI_MAJOR || desc.abi_minor > ABI_MINOR { Some("plugin: incompatible ABI version") }
+        else if !valid_name(name) { Some("plugin: invalid name") }
+        else if PLUGINS.lock(|t| t.iter().flatten().any(|p| !p.loading && &p.name[..p.name_len as usize] == name)) { Some("plugin: name in use") }
+        else { None };
+    if let Some(e) = err {
+        drop_windows(|w| w.plugin == handle);
+        PLUGINS.lock(|t| t[handle as usize] = None);
+        crate::mm::uefi::free_pages(system_table, base as *mut u8, pages);
+        Counter::new(&metrics::PLUGIN_REJECTS).inc();
+        return Err(e);
+    }
+    let p = Plugin {
+        handle, name: desc.name, name_len: name_len as u8, version: desc.version, abi_minor: desc.abi_minor, signature,
+        enabled: true, base, pages, calls: 0, vm_event: desc.vm_event, sched_hint: desc.sched_hint, mmio: desc.mmio, loading: false,
+    };
+    PLUGINS.lock(|t| t[handle as usize] = Some(p));
+    Counter::new(&metrics::PLUGIN_LOADS).inc();
+    crate::log!(Info, "plugin", "{} v{} loaded", p.name(), p.version);
+    Ok(p)
+}
+
+/// Verify `file`, reserve a slot and map the image. Returns the slot, the
+/// image base and pages, the entry address and the verdict.
+fn map(system_table: &SystemTable<Boot>, path: &str, file: &[u8]) -> Result<(u32, u64, usize, u64, Verdict), &'static str> {
+    let (signature, len) = imgsig::check(system_table, file);
+    let trusted = matches!(signature, Verdict::Trusted { .. });
+    let mut short = [0u8; 8];
+    let stem = path.rsplit(['/', '\\']).next().unwrap_or(path).as_bytes();
+    let k = stem.len().min(8);
+    short[..k].copy_from_slice(&stem[..k]);
+    crate::diag::audit::record(crate::diag::audit::AuditKind::PluginLoad { name: short, verdict: signature.code(), refused: !trusted });
+    if !trusted { return Err("plugin: image signature not trusted"); }
+    let img = &file[..len];
+    let pe = parse_pe(img).ok_or("plugin: not a PE32+ x86-64 image")?;
+    if pe.size == 0 || pe.size > MAX_IMAGE || pe.entry >= pe.size { return Err("plugin: bad image layout"); }
+    let handle = PLUGINS.lock(|t| {
+        let i = t.iter().position(|p| p.is_none())?;
+        t[i] = Some(Plugin {
+            handle: i as u32, name: [0; NAME_MAX], name_len: 0, version: 0, abi_minor: 0, signature, enabled: false,
+            base: 0, pages: 0, calls: 0, vm_event: None, sched_hint: None, mmio: None, loading: true,
+        });
+        Some(i as u32)
+    }).ok_or("plugin: too many plugins")?;
+    let pages = (pe.size as usize).div_ceil(4096);
+    let Some(base) = crate::mm::uefi::alloc_pages(system_table, pages, MemoryType::LOADER_CODE) else {
+        PLUGINS.lock(|t| t[handle as usize] = None);
+        return Err("plugin: out of memory");
+    };
+    let dst = unsafe { core::slice::from_raw_parts_mut(base, pe.size as usize) };
+    dst.fill(0);
+    let mapped = map_sections(img, &pe, dst).and_then(|_| relocate(dst, &pe, (base as u64).wrapping_sub(pe.image_base)));
+    if let Err(e) = mapped {
+        crate::mm::uefi::free_pages(system_table, base, pages);
+        PLUGINS.lock(|t| t[handle as usize] = None);
+        return Err(e);
+    }
+    Ok((handle, base as u64, pages, base as u64 + pe.entry as u64, signature))
+}
+
+// ---- Hooks ----
+
+fn charge(handle: u32) {
+    Counter::new(&metrics::PLUGIN_CALLS).inc();
+    PLUGINS.lock(|t| if let Some(p) = t[handle as usize].as_mut() { p.calls += 1; });
+}
+
+fn enabled() -> [Option<Plugin>; MAX_PLUGINS] {
+    let mut t = PLUGINS.lock(|t| *t);
+    for p in t.iter_mut() { if !matches!(p, Some(x) if x.enabled && !x.loading) { *p = None; } }
+    t
+}
+
+/// Tell enabled plugins about a VM lifecycle event, and collect scheduling
+/// hints when the VM starts running.
+pub fn vm_event(vm_id: u64, event: u32) {
+    let t = enabled();
+    if t.iter().all(|p| p.is_none()) { return; }
+    for p in t.iter().flatten() {
+        if let Some(f) = p.vm_event { charge(p.handle); f(vm_id, event); }
+    }
+    if event != VmState::Running.code() as u32 { return; }
+    let cur = crate::hv::sched::credit::params(vm_id);
+    let mut hint = SchedHint { weight: cur.weight, cap: cur.cap };
+    let mut apply = false;
+    for p in t.iter().flatten() {
+        if let Some(f) = p.sched_hint { charge(p.handle); apply |= f(vm_id, &mut hint) == 1; }
+    }
+    if !apply || (hint.weight, hint.cap) == (cur.weight, cur.cap) { return; }
+    match crate::hv::sched::credit::set_params(vm_id, crate::hv::sched::credit::Params { weight: hint.weight, cap: hint.cap }) {
+        Ok(()) => crate::log!(Info, "plugin", "vm {} sched weight={} cap={} by hint", vm_id, hint.weight, hint.cap),
+        Err(e) => crate::log!(Warn, "plugin", "vm {} hint refused: {}", vm_id, e),
+    }
+}
+
+/// Report a destroyed VM and drop the plugin windows it had.
+pub fn detach_vm(vm_id: u64) {
+    drop_windows(|w| w.vm_id == vm_id);
+    vm_event(vm_id, EVENT_DESTROYED);
+}
+
+fn drop_windows(mut which: impl FnMut(&Window) -> bool) {
+    let mut gone = [None; MAX_WINDOWS];
+    WINDOWS.lock(|t| {
+        for (k, w) in t.iter_mut().enumerate() {
+            if w.as_ref().is_some_and(&mut which) { gone[k] = w.take(); }
+        }
+    });
+    for w in gone.iter().flatten() { let _ = crate::hv::bus::unregister_mmio(w.vm_id, w.base); }
+}
+
+/// Bus handler for plugin windows; `slot` indexes `WINDOWS`.
+fn window_mmio(vm_id: u64, vcpu: u32, slot: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    let ones = if size >= 8 { u64::MAX } else { (1u64 << (size as u32 * 8)) - 1 };
+    let Some(w) = WINDOWS.lock(|t| t.get(slot as usize).copied().flatten()) else { return ones };
+    let Some(f) = PLUGINS.lock(|t| t[w.plugin as usize].filter(|p| p.enabled && !p.loading).and_then(|p| p.mmio)) else {
+        return if write.is_some() { 0 } else { ones };
+    };
+    charge(w.plugin);
+    f(w.ctx, vm_id, vcpu, off, size, write.is_some() as u8, write.unwrap_or(0))
+}
+
+// ---- Host API ----
+
+fn live(handle: u32) -> bool { PLUGINS.lock(|t| t.get(handle as usize).is_some_and(|p| p.is_some())) }
+
+extern "C" fn host_log(handle: u32, msg: *const u8, len: usize) {
+    if msg.is_null() { return; }
+    let b = unsafe { core::slice::from_raw_parts(msg, len.min(160)) };
+    let text = core::str::from_utf8(b).unwrap_or("(not UTF-8)");
+    let p = PLUGINS.lock(|t| t.get(handle as usize).copied().flatten());
+    match p {
+        Some(p) if !p.loading => crate::log!(Info, "plugin", "{}: {}", p.name(), text),
+        Some(_) => crate::log!(Info, "plugin", "#{}: {}", handle, text),
+        None => {}
+    }
+}
+
+extern "C" fn host_tsc_hz() -> u64 { crate::time::tsc_hz() }
+
+extern "C" fn host_vm_state(vm_id: u64) -> i32 { crate::hv::vm::find_vm(vm_id).map_or(-1, |v| v.state.code() as i32) }
+
+extern "C" fn host_register_mmio(handle: u32, vm_id: u64, base: u64, len: u64, ctx: u64) -> i32 {
+    if !live(handle) || crate::hv::vm::find_vm(vm_id).is_none() { return -1; }
+    let Some(slot) = WINDOWS.lock(|t| {
+        let k = t.iter().position(|w| w.is_none())?;
+        t[k] = Some(Window { plugin: handle, vm_id, base, ctx });
+        Some(k)
+    }) else { return -1 };
+    if crate::hv::bus::register_mmio(vm_id, base, len, "plugin", slot as u64, window_mmio) { return 0; }
+    WINDOWS.lock(|t| t[slot] = None);
+    -1
+}
+
+extern "C" fn host_unregister_mmio(handle: u32, vm_id: u64, base: u64) -> i32 {
+    let found = WINDOWS.lock(|t| match t.iter_mut().find(|w| matches!(w, Some(x) if x.plugin == handle && x.vm_id == vm_id && x.base == base)) {
+        Some(w) => { *w = None; true }
+        None => false,
+    });
+    if !found { return -1; }
+    let _ = crate::hv::bus::unregister_mmio(vm_id, base);
+    0
+}
+
+/// Windows the plugin `handle` holds.
+pub fn windows(handle: u32) -> usize { WINDOWS.lock(|t| t.iter().flatten().filter(|w| w.plugin == handle).count()) }
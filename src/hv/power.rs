@@ -0,0 +1,278 @@
+#![allow(dead_code)]
+
+//! Host power capping with priority-ordered VM throttling.
+//!
+//! A configured cap is programmed into the RAPL package PL1 limit where the
+//! CPU allows it (Intel, not locked by firmware); the hardware limit alone
+//! slows every VM equally, so the cap is also enforced in software. Each tick
+//! samples package energy; while draw is above the cap the scheduling quota
+//! of the lowest priority class that is still above its floor is cut one
+//! step, and once draw falls below the cap minus hysteresis quotas are given
+//! back, highest priority class first. Only VMs with a configured budget take
+//! part; the vCPU scheduler reads `quota_pct`.
+
+use core::sync::atomic::Ordering;
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::arch::x86::rapl::{self, Kind, Units};
+use crate::obs::metrics::{POWER_CAP_MW, POWER_DRAW_MW, POWER_THROTTLE_EVENTS};
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_BUDGETS: usize = 16;
+/// Sampling period of `tick`
+pub const INTERVAL_MS: u64 = 250;
+/// Quota change per tick, in percent
+pub const STEP_PCT: u8 = 10;
+/// Draw must fall this far (percent of cap) below the cap before quotas are restored
+pub const HYSTERESIS_PCT: u64 = 5;
+/// Default lowest quota a throttled VM is cut to
+pub const DEFAULT_FLOOR_PCT: u8 = 20;
+/// RAPL averaging window used when programming PL1
+const PL1_WINDOW_US: u64 = 1_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prio { High, Normal, Low }
+
+impl Prio {
+    pub fn name(self) -> &'static str {
+        match self { Prio::High => "high", Prio::Normal => "normal", Prio::Low => "low" }
+    }
+    pub fn parse(s: &str) -> Option<Prio> {
+        match s { "high" => Some(Prio::High), "normal" => Some(Prio::Normal), "low" => Some(Prio::Low), _ => None }
+    }
+}
+
+/// Throttle order: first class cut when over the cap.
+const THROTTLE_ORDER: [Prio; 3] = [Prio::Low, Prio::Normal, Prio::High];
+/// Restore order: first class given quota back when under the cap.
+const RESTORE_ORDER: [Prio; 3] = [Prio::High, Prio::Normal, Prio::Low];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    pub vm_id: u64,
+    pub prio: Prio,
+    /// Quota is never cut below this
+    pub floor_pct: u8,
+    /// Current share of scheduled time, 100 = unthrottled
+    pub quota_pct: u8,
+    /// Total time spent below 100%
+    pub throttle_us: u64,
+}
+
+struct State {
+    probed: bool,
+    kind: Kind,
+    units: Option<Units>,
+    cap_mw: u64,
+    /// PL1 was programmed from the cap
+    hw_limit: bool,
+    /// Firmware value of the package limit, restored when the cap is removed
+    saved_limit: Option<u64>,
+    last_raw: u32,
+    /// `clock::now_ns` of the last sample (0 = none yet)
+    last_ns: u64,
+    draw_mw: u64,
+    /// Warned that the cap is exceeded with every VM at its floor
+    exhausted: bool,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    probed: false, kind: Kind::None, units: None, cap_mw: 0, hw_limit: false, saved_limit: None,
+    last_raw: 0, last_ns: 0, draw_mw: 0, exhausted: false,
+});
+static BUDGETS: SpinLock<[Option<Budget>; MAX_BUDGETS]> = SpinLock::new([None; MAX_BUDGETS]);
+
+fn probe(s: &mut State) {
+    if s.probed { return; }
+    s.probed = true;
+    s.kind = rapl::detect();
+    s.units = rapl::units(s.kind);
+    if s.units.is_none() { s.kind = Kind::None; }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub kind: Kind,
+    pub cap_mw: u64,
+    pub draw_mw: u64,
+    pub hw_limit: bool,
+    pub tdp_mw: Option<u64>,
+    /// PL1 currently in effect, as read back from the CPU
+    pub pl1: Option<rapl::Pl1>,
+}
+
+pub fn status() -> Status {
+    STATE.lock(|s| {
+        probe(s);
+        let (tdp_mw, pl1) = match s.units {
+            Some(u) => (rapl::tdp_mw(s.kind, u), rapl::read_pl1(s.kind, u)),
+            None => (None, None),
+        };
+        Status { kind: s.kind, cap_mw: s.cap_mw, draw_mw: s.draw_mw, hw_limit: s.hw_limit, tdp_mw, pl1 }
+    })
+}
+
+/// Set (`Some`) or remove (`None`) the host cap. Returns whether the cap was
+/// also programmed into the package limit; `Err` only for invalid caps.
+pub fn set_cap(mw: Option<u64>) -> Result<bool, &'static str> {
+    if mw == Some(0) { return Err("power: cap must be non-zero"); }
+    let hw = STATE.lock(|s| {
+        probe(s);
+        match mw {
+            Some(mw) => {
+                s.cap_mw = mw;
+                s.exhausted = false;
+                if s.saved_limit.is_none() { s.saved_limit = rapl::pkg_limit_raw(s.kind); }
+                s.hw_limit = match s.units {
+                    Some(u) if s.kind.can_limit() => rapl::write_pl1(s.kind, u, mw, PL1_WINDOW_US).is_ok(),
+                    _ => false,
+                };
+            }
+            None => {
+                s.cap_mw = 0;
+                if s.hw_limit { if let Some(raw) = s.saved_limit { let _ = rapl::restore_pkg_limit(s.kind, raw); } }
+                s.hw_limit = false;
+                s.saved_limit = None;
+            }
+        }
+        POWER_CAP_MW.store(s.cap_mw, Ordering::Relaxed);
+        s.hw_limit
+    });
+    if mw.is_none() {
+        BUDGETS.lock(|t| { for b in t.iter_mut().flatten() { b.quota_pct = 100; } });
+    }
+    Ok(hw)
+}
+
+pub fn cap_mw() -> u64 { STATE.lock(|s| s.cap_mw) }
+
+/// Configure (or update) the budget of `vm_id`. Keeps the current quota.
+pub fn set_vm(vm_id: u64, prio: Prio, floor_pct: u8) -> Result<(), &'static str> {
+    if floor_pct == 0 || floor_pct > 100 { return Err("power: floor must be 1..100"); }
+    BUDGETS.lock(|t| {
+        if let Some(b) = t.iter_mut().flatten().find(|b| b.vm_id == vm_id) {
+            b.prio = prio;
+            b.floor_pct = floor_pct;
+            b.quota_pct = b.quota_pct.max(floor_pct);
+            return Ok(());
+        }
+        let slot = t.iter_mut().find(|b| b.is_none()).ok_or("power: too many budgets")?;
+        *slot = Some(Budget { vm_id, prio, floor_pct, quota_pct: 100, throttle_us: 0 });
+        Ok(())
+    })
+}
+
+/// Remove the budget of `vm_id`; the VM runs unthrottled afterwards.
+pub fn clear_vm(vm_id: u64) -> bool {
+    BUDGETS.lock(|t| {
+        for b in t.iter_mut() { if matches!(b, Some(x) if x.vm_id == vm_id) { *b = None; return true; } }
+        false
+    })
+}
+
+pub fn detach_vm(vm_id: u64) {
+    let _ = clear_vm(vm_id);
+    crate::obs::metrics::vm_throttle_forget(vm_id);
+}
+
+/// Share of scheduled time `vm_id` may use, in percent.
+pub fn quota_pct(vm_id: u64) -> u8 {
+    BUDGETS.lock(|t| t.iter().flatten().find(|b| b.vm_id == vm_id).map(|b| b.quota_pct).unwrap_or(100))
+}
+
+pub fn for_each(mut f: impl FnMut(&Budget)) {
+    let snap = BUDGETS.lock(|t| *t);
+    for b in snap.iter().flatten() { f(b); }
+}
+
+/// Outcome of one enforcement step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action { None, Throttled(Prio, u8), Restored(Prio, u8), Exhausted }
+
+/// Cut (`down`) or restore one step of quota for the first class in `order`
+/// that still has room. Returns the class and its new lowest/highest quota.
+fn step_class(t: &mut [Option<Budget>; MAX_BUDGETS], order: &[Prio; 3], down: bool) -> Option<(Prio, u8)> {
+    for &p in order.iter() {
+        let mut moved = false;
+        let mut q = if down { 100 } else { 0 };
+        for b in t.iter_mut().flatten().filter(|b| b.prio == p) {
+            let nq = if down { b.quota_pct.saturating_sub(STEP_PCT).max(b.floor_pct) } else { (b.quota_pct + STEP_PCT).min(100) };
+            if nq != b.quota_pct { b.quota_pct = nq; moved = true; }
+            q = if down { q.min(nq) } else { q.max(nq) };
+        }
+        if moved { return Some((p, q)); }
+    }
+    None
+}
+
+/// Sample package power and adjust VM quotas. Runs at most every
+/// `INTERVAL_MS` unless `force`. Returns the sampled draw in mW.
+pub fn tick(system_table: &mut SystemTable<Boot>, force: bool) -> Option<u64> {
+    if crate::time::clock::hz() == 0 { return None; }
+    let now = crate::time::clock::now_ns();
+    let sample = STATE.lock(|s| {
+        probe(s);
+        let u = s.units?;
+        let raw = rapl::pkg_energy_raw(s.kind)?;
+        let dt = now.saturating_sub(s.last_ns);
+        if s.last_ns != 0 && !force && dt < INTERVAL_MS * 1_000_000 { return None; }
+        let first = s.last_ns == 0;
+        let delta = raw.wrapping_sub(s.last_raw) as u64;
+        s.last_raw = raw;
+        s.last_ns = now;
+        if first { return None; }
+        let dt_us = dt / 1_000;
+        if dt_us == 0 { return None; }
+        s.draw_mw = rapl::energy_to_uj(u, delta).saturating_mul(1000) / dt_us;
+        POWER_DRAW_MW.store(s.draw_mw, Ordering::Relaxed);
+        Some((s.draw_mw, s.cap_mw, dt_us))
+    });
+    let (draw, cap, dt_us) = sample?;
+
+    let (action, vms, nvms) = BUDGETS.lock(|t| {
+        let action = if cap == 0 {
+            Action::None
+        } else if draw > cap {
+            match step_class(t, &THROTTLE_ORDER, true) { Some((p, q)) => Action::Throttled(p, q), None => Action::Exhausted }
+        } else if draw * 100 < cap * (100 - HYSTERESIS_PCT) {
+            match step_class(t, &RESTORE_ORDER, false) { Some((p, q)) => Action::Restored(p, q), None => Action::None }
+        } else {
+            Action::None
+        };
+        // Charge the elapsed interval to every VM that ran throttled through it.
+        let mut throttled = [0u64; MAX_BUDGETS];
+        let mut n = 0;
+        for b in t.iter_mut().flatten() {
+            if b.quota_pct < 100 { b.throttle_us = b.throttle_us.saturating_add(dt_us); throttled[n] = b.vm_id; n += 1; }
+        }
+        (action, throttled, n)
+    });
+    for &vm in vms[..nvms].iter() { crate::obs::metrics::vm_throttle_account(vm, dt_us); }
+
+    let exhausted_before = STATE.lock(|s| { let was = s.exhausted; s.exhausted = action == Action::Exhausted; was });
+    let mut msg = [0u8; 96]; let mut n = 0;
+    match action {
+        Action::Throttled(p, q) | Action::Restored(p, q) => {
+            let down = matches!(action, Action::Throttled(..));
+            if down { POWER_THROTTLE_EVENTS.fetch_add(1, Ordering::Relaxed); }
+            for &b in if down { &b"throttle "[..] } else { &b"restore "[..] } { msg[n] = b; n += 1; }
+            for &b in p.name().as_bytes() { msg[n] = b; n += 1; }
+            for &b in b" quota=" { msg[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(q as u64, &mut msg[n..]);
+            for &b in b"% draw_mw=" { msg[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(draw, &mut msg[n..]);
+            for &b in b" cap_mw=" { msg[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(cap, &mut msg[n..]);
+            let text = core::str::from_utf8(&msg[..n]).unwrap_or("quota change");
+            if down { crate::obs::log::warn(system_table, "power", text) } else { crate::obs::log::info(system_table, "power", text) }
+        }
+        Action::Exhausted if !exhausted_before => {
+            for &b in b"cap exceeded with all budgets at floor draw_mw=" { msg[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(draw, &mut msg[n..]);
+            crate::obs::log::error(system_table, "power", core::str::from_utf8(&msg[..n]).unwrap_or("cap exceeded"));
+        }
+        _ => {}
+    }
+    Some(draw)
+}
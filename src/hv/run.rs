@@ -0,0 +1,1025 @@
+#![allow(dead_code)]
+
+//! Multi-vCPU guest execution on the application processors (VMX).
+//!
+//! `start` homes every vCPU of a VM on an AP in VMX root operation, spreading
+//! them over the least loaded ones inside the VM's affinity mask
+//! (`hv::sched::affinity`), and makes sure each such AP runs a
+//! long-lived `ap` job that serves its runqueue in the credit scheduler
+//! (`hv::sched::credit`). Several vCPUs, of one VM or of different VMs, can
+//! share an AP; each keeps its own VMCS, loaded with VMPTRLD when picked.
+//! A slice lasts until the end the scheduler picked (one tick, or less for
+//! real-time vCPUs and around their releases), enforced by the LAPIC
+//! TSC-deadline timer (external-interrupt exiting), or by the VMX
+//! preemption timer on CPUs without that mode.
+//!
+//! vCPU 0 enters at the loader's boot state. The others sit in
+//! wait-for-SIPI, as on real hardware, until the guest's BSP sends INIT and
+//! STARTUP through its virtual LAPIC. A SIPI starts the vCPU in real mode at
+//! `vector << 12` under the unrestricted-guest control. Halted and waiting
+//! vCPUs are passed over by the scheduler until they have work.
+//!
+//! `repin` moves vCPUs left outside a new affinity mask. The AP a vCPU is
+//! homed on clears its VMCS at the next pick and hands it to the new AP's
+//! runqueue; the VMCS is loaded there with fresh host state.
+//!
+//! Exits are handled on the AP that took them. Port I/O and MMIO go through
+//! `hv::exit`; CPUID, MSR, CR and HLT exits are handled here. Any other exit
+//! stops the vCPU, with the reason kept for `vm vcpus`.
+//!
+//! vCPUs under the debugger (`hv::gdb`) also exit on #BP and #DB, and on the
+//! monitor trap flag while single-stepping. Such a stop parks the vCPU,
+//! and every other vCPU of the VM, until the debugger resumes them; the
+//! scheduler passes over parked vCPUs. Parking captures the guest state the
+//! debugger reads, since a VMCS can only be read on the AP it is loaded on.
+//! `pause` parks a VM the same way for the host, e.g. for a core dump.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::arch::x86::ap::{self, MAX_APS};
+use crate::arch::x86::lapic;
+use crate::arch::x86::vm::percpu;
+use crate::arch::x86::vm::vmcs::{self, vmread, vmwrite};
+use crate::arch::x86::vm::vmx;
+use crate::hv::exit::GuestPaging;
+use crate::hv::sched::{affinity, credit};
+use crate::hv::vcpu::{BootRegs, GuestRegs};
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_VCPUS: usize = credit::MAX_ENTITIES;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_PG: u64 = 1 << 31;
+const CR4_VMXE: u64 = 1 << 13;
+const RFLAGS_RF: u64 = 1 << 16;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+/// CR0 after INIT: CD | NW | ET
+const CR0_RESET: u64 = 0x6000_0010;
+const RSP: usize = 4;
+
+const MSR_APIC_BASE: u32 = 0x1B;
+const MSR_SYSENTER_CS: u32 = 0x174;
+const MSR_SYSENTER_ESP: u32 = 0x175;
+const MSR_SYSENTER_EIP: u32 = 0x176;
+const MSR_EFER: u32 = 0xC000_0080;
+const MSR_FS_BASE: u32 = 0xC000_0100;
+const MSR_GS_BASE: u32 = 0xC000_0101;
+/// STAR, LSTAR, CSTAR, SFMASK and KERNEL_GS_BASE are not used by the host on
+/// an AP, so the guest's values live directly in the hardware MSRs.
+const MSR_PASSTHROUGH: [u32; 5] = [0xC000_0081, 0xC000_0082, 0xC000_0083, 0xC000_0084, 0xC000_0102];
+
+/// LAPIC timer vector that ends a slice
+const TICK_VECTOR: u8 = 0xEC;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunState { WaitForSipi, Running, Halted, Stopped, Failed }
+
+impl RunState {
+    pub fn name(self) -> &'static str {
+        match self {
+            RunState::WaitForSipi => "wait-sipi",
+            RunState::Running => "running",
+            RunState::Halted => "halted",
+            RunState::Stopped => "stopped",
+            RunState::Failed => "failed",
+        }
+    }
+
+    fn live(self) -> bool { !matches!(self, RunState::Stopped | RunState::Failed) }
+}
+
+/// Placement and progress of one vCPU.
+#[derive(Clone, Copy, Debug)]
+pub struct VcpuRun {
+    pub vm_id: u64,
+    pub vcpu: u32,
+    /// AP index the vCPU is homed on
+    pub cpu: usize,
+    pub state: RunState,
+    pub exits: u64,
+    /// Basic reason of the most recent exit
+    pub last_exit: u32,
+    pub rip: u64,
+    pub error: Option<&'static str>,
+    vmcs: u64,
+    ept_root: u64,
+    boot: BootRegs,
+    /// Guest registers between slices
+    regs: GuestRegs,
+    /// Controls and initial guest state written
+    ready: bool,
+    /// VMCS cleared and given this AP's host state
+    hosted: bool,
+    launched: bool,
+    /// AP to hand the vCPU to at its next pick
+    move_to: Option<usize>,
+    /// `stage2::generation` when this AP last flushed the guest's EPT
+    ept_gen: u64,
+}
+
+impl VcpuRun {
+    /// AP the vCPU is about to move to, if `repin` asked for a move.
+    pub fn pending_move(&self) -> Option<usize> { self.move_to }
+}
+
+/// How a slice ended.
+enum Slice {
+    /// The vCPU ran; `preempted` if the tick expired
+    Ran { preempted: bool },
+    /// Halted or waiting for SIPI, nothing to run
+    Blocked,
+    /// Stop requested
+    Done,
+    /// VMCS cleared for a move to another AP
+    Moved(usize),
+}
+
+static RUNS: SpinLock<[Option<VcpuRun>; MAX_VCPUS]> = SpinLock::new([None; MAX_VCPUS]);
+const NO_STOP: AtomicBool = AtomicBool::new(false);
+static STOP: [AtomicBool; MAX_VCPUS] = [NO_STOP; MAX_VCPUS];
+const OUTSIDE: AtomicU64 = AtomicU64::new(u64::MAX);
+/// `stage2::generation` each slot's vCPU entered the guest under; MAX
+/// between slices, when the next entry flushes anyway.
+static ENTRY_GEN: [AtomicU64; MAX_VCPUS] = [OUTSIDE; MAX_VCPUS];
+/// Park requests from the debugger, taken at the vCPU's next exit
+static DBG_REQ: [AtomicBool; MAX_VCPUS] = [NO_STOP; MAX_VCPUS];
+
+fn update(i: usize, f: impl FnOnce(&mut VcpuRun)) { RUNS.lock(|t| { if let Some(r) = t[i].as_mut() { f(r); } }); }
+
+/// Call `f(slot, run)` for every vCPU of `vm_id` that has been started, in slot order.
+pub fn for_each(vm_id: u64, mut f: impl FnMut(usize, &VcpuRun)) {
+    let t = RUNS.lock(|t| *t);
+    for (i, r) in t.iter().enumerate().filter_map(|(i, r)| Some((i, r.as_ref()?))) { if r.vm_id == vm_id { f(i, r); } }
+}
+
+/// vCPUs of `vm_id` not yet stopped or failed.
+pub fn active(vm_id: u64) -> u32 { RUNS.lock(|t| t.iter().flatten().filter(|r| r.vm_id == vm_id && r.state.live()).count() as u32) + crate::hv::wasm::active(vm_id) }
+
+/// True once no vCPU of `vm_id` can still hold translations from before
+/// stage-2 generation `gen`.
+pub fn ept_synced(vm_id: u64, gen: u64) -> bool {
+    RUNS.lock(|t| (0..MAX_VCPUS).all(|i| !matches!(t[i], Some(r) if r.vm_id == vm_id) || ENTRY_GEN[i].load(Ordering::SeqCst) >= gen))
+}
+
+/// vCPUs of any VM not yet stopped or failed.
+pub fn active_total() -> u32 { RUNS.lock(|t| t.iter().flatten().filter(|r| r.state.live()).count() as u32) }
+
+/// Home each of the vCPUs numbered in `vcpus` on the enabled, online,
+/// non-RT-reserved AP inside `affinity` with the fewest vCPUs, so a VM
+/// spreads out before APs are shared. A guest given a vNUMA topology keeps
+/// each vCPU on the APs of its node while one of them is usable. VMs with a
+/// real-time reservation are placed by the RT admission test instead.
+fn place(vm_id: u64, affinity: u64, vcpus: &[u32], cpus: &mut [usize]) -> Result<(), &'static str> {
+    if let Some(r) = crate::hv::sched::rt::reservation(vm_id) {
+        return crate::hv::sched::rt::first_fit(vm_id, r.util_ppm(), vcpus.len(), true, affinity, cpus);
+    }
+    let mut load = [usize::MAX; MAX_APS];
+    let mut apics = [0u32; MAX_APS];
+    ap::for_each_online(|cpu, apic, _| {
+        if percpu::is_root(cpu) && !crate::hv::sched::is_rt_reserved(apic) && affinity::allows(affinity, apic) { load[cpu] = credit::load(cpu); apics[cpu] = apic; }
+    });
+    let vnuma = crate::hv::numa::layout_of(vm_id);
+    for (c, &v) in cpus.iter_mut().zip(vcpus.iter()) {
+        let home = vnuma.and_then(|t| t.vcpu_apics(v)).filter(|&m| (0..MAX_APS).any(|i| load[i] != usize::MAX && affinity::allows(m, apics[i])));
+        let (cpu, l) = load.iter().enumerate().filter(|&(i, _)| home.map_or(true, |m| affinity::allows(m, apics[i])))
+            .min_by_key(|&(_, &l)| l).map(|(i, &l)| (i, l)).unwrap_or((0, usize::MAX));
+        if l == usize::MAX { return Err("run: no VMX-enabled CPU available for vCPUs"); }
+        *c = cpu;
+        load[cpu] += 1;
+    }
+    Ok(())
+}
+
+/// Free the VMCS pages of finished vCPUs of `vm_id`. Their slots stay for
+/// inspection unless `remove` is set.
+fn reap(system_table: &SystemTable<Boot>, vm_id: u64, remove: bool) {
+    let mut gone = [0u64; MAX_VCPUS];
+    RUNS.lock(|t| {
+        for (i, s) in t.iter_mut().enumerate() {
+            let Some(r) = s.as_mut() else { continue };
+            if r.vm_id != vm_id || r.state.live() { continue; }
+            gone[i] = core::mem::replace(&mut r.vmcs, 0);
+            if remove { *s = None; }
+        }
+    });
+    for &p in gone.iter() { if p != 0 { vmcs::free_vmcs_region(system_table, p as *mut u8); } }
+}
+
+/// Start all vCPUs of a VM with a loaded image under the credit scheduler,
+/// or the module of a WebAssembly VM. Returns the number of vCPUs started.
+pub fn start(system_table: &SystemTable<Boot>, vm_id: u64) -> Result<u32, &'static str> {
+    let info = crate::hv::vm::find_vm(vm_id).ok_or("run: no such vm")?;
+    if crate::hv::wasm::is_wasm(vm_id) { return crate::hv::wasm::start(vm_id); }
+    if info.vendor != crate::hv::vm::HvVendor::Intel { return Err("run: multi-vCPU execution needs VMX"); }
+    let img = crate::hv::loader::find_image(vm_id).ok_or("run: no image loaded (vm load)")?;
+    if img.root_phys == 0 { return Err("run: guest has no EPT"); }
+    if active(vm_id) != 0 { return Err("run: vm already running"); }
+    crate::hv::cni::plumb(vm_id)?;
+    reap(system_table, vm_id, true);
+    percpu::enable_all(system_table)?;
+    let vcpus = (info.vcpus.max(1) as usize).min(MAX_VCPUS);
+    let mut cpus = [0usize; MAX_VCPUS];
+    let mut ids = [0u32; MAX_VCPUS];
+    for (v, id) in ids.iter_mut().enumerate() { *id = v as u32; }
+    place(vm_id, info.affinity, &ids[..vcpus], &mut cpus)?;
+    let mut started = 0;
+    for v in 0..vcpus {
+        let Some(vmcs) = vmcs::alloc_vmcs_region(system_table) else { request_stop(vm_id); return Err("run: out of memory for VMCS"); };
+        let run = VcpuRun {
+            vm_id, vcpu: v as u32, cpu: cpus[v],
+            state: if v == 0 { RunState::Running } else { RunState::WaitForSipi },
+            exits: 0, last_exit: 0, rip: 0, error: None,
+            vmcs: vmcs as u64, ept_root: img.root_phys, boot: img.regs,
+            regs: GuestRegs::default(), ready: false, hosted: false, launched: false, move_to: None, ept_gen: 0,
+        };
+        let slot = RUNS.lock(|t| {
+            let i = t.iter().position(|s| s.is_none())?;
+            t[i] = Some(run);
+            Some(i)
+        });
+        let Some(i) = slot else { vmcs::free_vmcs_region(system_table, vmcs); request_stop(vm_id); return Err("run: vCPU table full"); };
+        STOP[i].store(false, Ordering::Release);
+        DBG_REQ[i].store(false, Ordering::Release);
+        DEBUG.lock(|d| d[i] = NO_DEBUG);
+        let res = credit::attach(i, vm_id, cpus[v]).and_then(|first| if first { ap::dispatch_within(cpus[v], info.affinity, pcpu_job, cpus[v] as u64).map(|_| ()) } else { Ok(()) });
+        if let Err(e) = res {
+            credit::detach(i);
+            let _ = credit::retire(cpus[v]);
+            update(i, |r| { r.state = RunState::Failed; r.error = Some(e); });
+            continue;
+        }
+        started += 1;
+    }
+    crate::obs::trace::emit(crate::obs::trace::Event::VmStart(vm_id));
+    if started != 0 { crate::hv::vm::note(vm_id, crate::hv::vm::VmState::Running); }
+    Ok(started)
+}
+
+/// Ask every vCPU of `vm_id` to leave the guest and wait up to `timeout_us`
+/// for their AP jobs to drop them. Returns how many are still live.
+pub fn stop(system_table: &SystemTable<Boot>, vm_id: u64, timeout_us: u64) -> u32 {
+    request_stop(vm_id);
+    let mut waited = 0;
+    while active(vm_id) != 0 && waited < timeout_us {
+        let _ = system_table.boot_services().stall(100);
+        waited += 100;
+    }
+    let left = active(vm_id);
+    if left == 0 {
+        reap(system_table, vm_id, false);
+        crate::hv::vm::note(vm_id, crate::hv::vm::VmState::Stopped);
+    }
+    left
+}
+
+/// Re-place the live vCPUs of `vm_id` that are homed outside `mask`; each
+/// moves at its next pick. Returns the number of vCPUs that will move.
+pub fn repin(vm_id: u64, mask: u64) -> Result<u32, &'static str> {
+    let mut slots = [usize::MAX; MAX_VCPUS];
+    let mut ids = [0u32; MAX_VCPUS];
+    let mut n = 0;
+    RUNS.lock(|t| {
+        for (i, s) in t.iter().enumerate() {
+            let Some(r) = s.as_ref() else { continue };
+            if r.vm_id != vm_id || !r.state.live() { continue; }
+            if !ap::apic_id(r.cpu).is_some_and(|a| affinity::allows(mask, a)) { slots[n] = i; ids[n] = r.vcpu; n += 1; }
+        }
+    });
+    if n == 0 { return Ok(0); }
+    let mut cpus = [0usize; MAX_VCPUS];
+    place(vm_id, mask, &ids[..n], &mut cpus)?;
+    RUNS.lock(|t| {
+        for k in 0..n {
+            if let Some(r) = t[slots[k]].as_mut() { r.move_to = Some(cpus[k]); }
+        }
+    });
+    Ok(n as u32)
+}
+
+/// Flag every vCPU of `vm_id` to stop at its next exit. A WebAssembly VM
+/// stops at once.
+pub fn request_stop(vm_id: u64) {
+    crate::hv::wasm::halt(vm_id);
+    RUNS.lock(|t| { for (i, s) in t.iter().enumerate() { if matches!(s, Some(r) if r.vm_id == vm_id) { STOP[i].store(true, Ordering::Release); } } });
+}
+
+/// Why a vCPU under the debugger stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// Asked to by the debugger, or another vCPU of the VM stopped
+    Halt,
+    /// INT3 at RIP
+    SwBreak,
+    /// Instruction breakpoint in DR<n> at RIP
+    HwBreak(u8),
+    /// Data breakpoint in DR<n> hit by the instruction before RIP
+    Watch(u8),
+    /// One instruction executed
+    Step,
+}
+
+impl StopReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            StopReason::Halt => "halt",
+            StopReason::SwBreak => "swbreak",
+            StopReason::HwBreak(_) => "hwbreak",
+            StopReason::Watch(_) => "watch",
+            StopReason::Step => "step",
+        }
+    }
+}
+
+/// Debugger view of one vCPU; `pause` uses the same parking.
+#[derive(Clone, Copy, Debug)]
+pub struct DebugState {
+    pub attached: bool,
+    /// Held parked by `pause`, debugger or not
+    pub hold: bool,
+    /// Why the vCPU is parked; `None` while it runs
+    pub parked: Option<StopReason>,
+    /// Guest state captured when the vCPU parked
+    pub regs: GuestRegs,
+    /// CS, SS, DS, ES, FS, GS
+    pub seg: [u16; 6],
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub paging: GuestPaging,
+    /// DR0-3 and DR7 as the debugger set them
+    pub dr: [u64; 4],
+    pub dr7: u64,
+    /// Run one instruction at the next entry
+    step: bool,
+    /// Set RFLAGS.RF at the next entry, to get past an instruction breakpoint
+    rf: bool,
+    /// `regs` edited while parked
+    regs_dirty: bool,
+    /// Exception bitmap, DR7 or MTF to rewrite at the next entry
+    apply: bool,
+}
+
+const NO_DEBUG: DebugState = DebugState {
+    attached: false, hold: false, parked: None,
+    regs: GuestRegs { gpr: [0; 16], rip: 0, rflags: 0 },
+    seg: [0; 6], fs_base: 0, gs_base: 0,
+    paging: GuestPaging { cr0: 0, cr3: 0, cr4: 0, efer: 0 },
+    dr: [0; 4], dr7: 0, step: false, rf: false, regs_dirty: false, apply: false,
+};
+
+static DEBUG: SpinLock<[DebugState; MAX_VCPUS]> = SpinLock::new([NO_DEBUG; MAX_VCPUS]);
+
+/// Put the live vCPUs of `vm_id` under the debugger, or release them:
+/// breakpoints are dropped and parked vCPUs resume. While attached, the
+/// debugger owns DR7 and the guest's own #BP and #DB are not delivered.
+/// Returns the number of vCPUs affected.
+pub fn debug_attach(vm_id: u64, on: bool) -> u32 {
+    let mut n = 0;
+    for_each(vm_id, |i, r| {
+        if !r.state.live() { return; }
+        DBG_REQ[i].store(false, Ordering::Release);
+        DEBUG.lock(|d| {
+            let s = d[i];
+            // A paused vCPU stays parked.
+            d[i] = DebugState { attached: on, hold: s.hold, parked: if s.hold { s.parked } else { None }, apply: true, ..s };
+            if !on { d[i].dr = [0; 4]; d[i].dr7 = 0; d[i].step = false; d[i].rf = false; }
+        });
+        n += 1;
+    });
+    n
+}
+
+/// Ask every running vCPU of `vm_id` under the debugger to park at its next exit.
+pub fn debug_halt(vm_id: u64) {
+    for_each(vm_id, |i, _| { if DEBUG.lock(|d| d[i].attached && d[i].parked.is_none()) { DBG_REQ[i].store(true, Ordering::Release); } });
+}
+
+pub fn debug_state(slot: usize) -> Option<DebugState> {
+    if slot >= MAX_VCPUS { return None; }
+    Some(DEBUG.lock(|d| d[slot]))
+}
+
+/// Replace the registers of a parked vCPU; they are loaded when it resumes.
+pub fn debug_set_regs(slot: usize, regs: &GuestRegs) -> Result<(), &'static str> {
+    DEBUG.lock(|d| {
+        let s = d.get_mut(slot).ok_or("run: no such vCPU")?;
+        if s.parked.is_none() { return Err("run: vCPU is not parked"); }
+        s.regs = *regs;
+        s.regs_dirty = true;
+        Ok(())
+    })
+}
+
+/// Set DR0-3 and DR7 on every vCPU of `vm_id` under the debugger.
+pub fn debug_set_hw(vm_id: u64, dr: [u64; 4], dr7: u64) {
+    for_each(vm_id, |i, _| DEBUG.lock(|d| if d[i].attached { d[i].dr = dr; d[i].dr7 = dr7; d[i].apply = true; }));
+}
+
+/// Resume the parked vCPUs of `vm_id`. With `step`, only that slot runs,
+/// for one instruction, and the others stay parked.
+pub fn debug_resume(vm_id: u64, step: Option<usize>) -> Result<(), &'static str> {
+    if step.is_some() && !monitor_trap_supported() { return Err("run: CPU lacks the monitor trap flag"); }
+    for_each(vm_id, |i, _| {
+        if step.is_some_and(|k| k != i) { return; }
+        DBG_REQ[i].store(false, Ordering::Release);
+        DEBUG.lock(|d| {
+            let s = &mut d[i];
+            let Some(why) = s.parked else { return };
+            s.rf = matches!(why, StopReason::HwBreak(_));
+            s.step = step.is_some();
+            s.apply = true;
+            s.parked = None;
+        });
+    });
+    Ok(())
+}
+
+/// Park every live vCPU of `vm_id` regardless of the debugger and wait up
+/// to `timeout_us` for them. Returns how many have not parked.
+pub fn pause(system_table: &SystemTable<Boot>, vm_id: u64, timeout_us: u64) -> u32 {
+    if crate::hv::wasm::set_paused(vm_id, true) { crate::hv::vm::note(vm_id, crate::hv::vm::VmState::Paused); return 0; }
+    for_each(vm_id, |i, r| {
+        if !r.state.live() { return; }
+        DEBUG.lock(|d| d[i].hold = true);
+        DBG_REQ[i].store(true, Ordering::Release);
+    });
+    let unparked = || {
+        let mut n = 0;
+        for_each(vm_id, |i, r| { if r.state.live() && DEBUG.lock(|d| d[i].parked.is_none()) { n += 1; } });
+        n
+    };
+    let mut waited = 0;
+    while unparked() != 0 && waited < timeout_us {
+        let _ = system_table.boot_services().stall(100);
+        waited += 100;
+    }
+    let left = unparked();
+    if left == 0 { crate::hv::vm::note(vm_id, crate::hv::vm::VmState::Paused); }
+    left
+}
+
+/// Undo `pause`. vCPUs the debugger has stopped stay parked for it.
+pub fn unpause(vm_id: u64) {
+    let _ = crate::hv::wasm::set_paused(vm_id, false);
+    for_each(vm_id, |i, _| DEBUG.lock(|d| {
+        let s = &mut d[i];
+        if !s.hold { return; }
+        s.hold = false;
+        if !s.attached {
+            DBG_REQ[i].store(false, Ordering::Release);
+            s.parked = None;
+        }
+    }));
+    if crate::hv::vm::find_vm(vm_id).is_some_and(|v| v.state == crate::hv::vm::VmState::Paused) { crate::hv::vm::note(vm_id, crate::hv::vm::VmState::Running); }
+}
+
+fn monitor_trap_supported() -> bool {
+    use crate::arch::x86::vm::vmcs::*;
+    let basic = unsafe { crate::arch::x86::msr::rdmsr(crate::arch::x86::msr::IA32_VMX_BASIC) };
+    let msr = if (basic & (1 << 55)) != 0 { IA32_VMX_TRUE_PROCBASED_CTLS } else { IA32_VMX_PROCBASED_CTLS };
+    (adjust_controls(msr, PROC_MONITOR_TRAP) & PROC_MONITOR_TRAP) != 0
+}
+
+/// AP job: serve this AP's runqueue until no vCPU is homed here.
+fn pcpu_job(cpu: u64) -> u64 {
+    let cpu = cpu as usize;
+    let host = percpu::host(cpu);
+    let deadline = host.is_some() && lapic::setup_tsc_deadline_timer(TICK_VECTOR);
+    let preempt = if deadline { None } else { preemption_rate() };
+    loop {
+        crate::hv::dvfs::apply_local(cpu);
+        crate::hv::thermal::sample_local(cpu);
+        let Some((i, end)) = credit::pick(cpu, crate::time::rdtsc(), wants_cpu) else {
+            if credit::retire(cpu) { return 0; }
+            core::hint::spin_loop();
+            continue;
+        };
+        let t0 = crate::time::rdtsc();
+        if crate::obs::trace::enabled(crate::obs::trace::CAT_SCHED) {
+            if let Some((vm, vcpu)) = RUNS.lock(|t| t[i].map(|r| (r.vm_id, r.vcpu))) {
+                crate::obs::trace::emit(crate::obs::trace::Event::SchedPick { slot: i as u16, vm: vm as u32, vcpu: vcpu as u16, slice: end.saturating_sub(t0) });
+            }
+        }
+        let res = match host {
+            Some(h) => run_slice(i, &h, end, deadline, preempt),
+            None => Err("run: cpu not in VMX root operation"),
+        };
+        if deadline { lapic::set_tsc_deadline(0); }
+        match res {
+            Ok(Slice::Ran { preempted }) => credit::put(cpu, i, crate::time::rdtsc().wrapping_sub(t0).max(1), preempted),
+            Ok(Slice::Blocked) => { credit::put(cpu, i, 0, false); core::hint::spin_loop(); }
+            Ok(Slice::Done) => finish(i, None),
+            Ok(Slice::Moved(to)) => {
+                let mask = RUNS.lock(|t| t[i].map(|r| r.vm_id)).and_then(crate::hv::vm::find_vm).map(|v| v.affinity).unwrap_or(0);
+                let res = credit::rehome(i, to).and_then(|first| if first { ap::dispatch_within(to, mask, pcpu_job, to as u64).map(|_| ()) } else { Ok(()) });
+                if let Err(e) = res { finish(i, Some(e)); }
+            }
+            Err(e) => finish(i, Some(e)),
+        }
+    }
+}
+
+/// Whether the vCPU in slot `i` has anything to do: running, a stop, a move
+/// or a park request to act on, a latched INIT/SIPI, or an interrupt for a
+/// halted vCPU. Parked vCPUs wait for the debugger.
+fn wants_cpu(i: usize) -> bool {
+    if STOP[i].load(Ordering::Acquire) || DBG_REQ[i].load(Ordering::Acquire) { return true; }
+    let Some((vm_id, vcpu, state, moving)) = RUNS.lock(|t| t[i].map(|r| (r.vm_id, r.vcpu, r.state, r.move_to.is_some()))) else { return false };
+    if moving { return true; }
+    if DEBUG.lock(|d| d[i].parked.is_some()) { return false; }
+    match state {
+        RunState::Running => true,
+        RunState::WaitForSipi => crate::hv::vlapic::with(vm_id, vcpu, |l| l.init_pending || l.sipi_vector.is_some()).unwrap_or(false),
+        RunState::Halted => {
+            let now = crate::time::rdtsc();
+            crate::hv::vlapic::with(vm_id, vcpu, |l| { let _ = l.tick(now); l.init_pending || l.deliverable().is_some() || l.nmi_pending }).unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+/// Retire a vCPU: clear its VMCS and take it off the runqueue.
+fn finish(i: usize, error: Option<&'static str>) {
+    if let Some(vmcs) = RUNS.lock(|t| t[i].map(|r| r.vmcs)) { let _ = vmx::vmclear(vmcs); }
+    update(i, |s| match error { None => s.state = RunState::Stopped, Some(e) => { s.state = RunState::Failed; s.error = Some(e); } });
+    credit::detach(i);
+}
+
+/// VMX preemption-timer rate (TSC shift) if the timer is available.
+fn preemption_rate() -> Option<u64> {
+    use crate::arch::x86::vm::vmcs::*;
+    let basic = unsafe { crate::arch::x86::msr::rdmsr(crate::arch::x86::msr::IA32_VMX_BASIC) };
+    let msr = if (basic & (1 << 55)) != 0 { IA32_VMX_TRUE_PINBASED_CTLS } else { IA32_VMX_PINBASED_CTLS };
+    if (adjust_controls(msr, PIN_PREEMPTION_TIMER) & PIN_PREEMPTION_TIMER) == 0 { return None; }
+    Some(unsafe { crate::arch::x86::msr::rdmsr(IA32_VMX_MISC) } & 0x1F)
+}
+
+/// Run the vCPU in slot `i` on this AP until `end` (TSC), it blocks, or a
+/// stop is requested. The slot's copy is written back afterwards.
+fn run_slice(i: usize, host: &percpu::HostCpu, end: u64, deadline: bool, preempt: Option<u64>) -> Result<Slice, &'static str> {
+    let mut r = RUNS.lock(|t| t[i]).ok_or("run: vCPU slot vanished")?;
+    if STOP[i].load(Ordering::Acquire) { return Ok(Slice::Done); }
+    if let Some(to) = r.move_to {
+        // The VMCS may be active on this AP only; clear it before it moves.
+        if r.hosted { vmx::vmclear(r.vmcs)?; }
+        update(i, |s| { s.cpu = to; s.hosted = false; s.launched = false; s.move_to = None; });
+        return Ok(Slice::Moved(to));
+    }
+    if !r.hosted { vmx::vmclear(r.vmcs)?; }
+    vmx::vmptrld(r.vmcs)?;
+    let res = (|| {
+        if !r.hosted {
+            vmx::write_host_state(host.gdt_base, host.tr_sel, host.tr_base)?;
+            r.hosted = true;
+            r.launched = false;
+            r.ept_gen = 0;
+        }
+        if !r.ready {
+            write_controls(r.ept_root, preempt.is_some())?;
+            if r.state == RunState::Running {
+                program_long_mode(&r.boot)?;
+                r.regs.gpr[GuestRegs::RSI] = r.boot.rsi;
+                r.regs.rip = r.boot.rip;
+                r.regs.gpr[RSP] = r.boot.rsp;
+            }
+            r.ready = true;
+        }
+        if deadline { lapic::set_tsc_deadline(end); }
+        drive(i, &mut r, end, preempt)
+    })();
+    ENTRY_GEN[i].store(u64::MAX, Ordering::SeqCst);
+    // Keep a move `repin` requested while the slice ran.
+    RUNS.lock(|t| { if let Some(s) = t[i].as_mut() { r.move_to = s.move_to; *s = r; } });
+    res
+}
+
+/// Execution controls, EPT and CR ownership for a fresh VMCS.
+fn write_controls(ept_root: u64, preempt: bool) -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    let basic = unsafe { crate::arch::x86::msr::rdmsr(crate::arch::x86::msr::IA32_VMX_BASIC) };
+    let tru = (basic & (1 << 55)) != 0;
+    let pick = |true_msr: u32, msr: u32| if tru { true_msr } else { msr };
+    let timer = if preempt { PIN_PREEMPTION_TIMER } else { 0 };
+    let pin = adjust_controls(pick(IA32_VMX_TRUE_PINBASED_CTLS, IA32_VMX_PINBASED_CTLS), PIN_EXT_INTR_EXITING | PIN_NMI_EXITING | timer);
+    let proc1 = adjust_controls(pick(IA32_VMX_TRUE_PROCBASED_CTLS, IA32_VMX_PROCBASED_CTLS), PROC_HLT_EXITING | PROC_UNCOND_IO_EXITING | PROC_ACTIVATE_SECONDARY);
+    let proc2 = adjust_controls(IA32_VMX_PROCBASED_CTLS2, PROC2_ENABLE_EPT | PROC2_UNRESTRICTED_GUEST);
+    if (proc2 & PROC2_UNRESTRICTED_GUEST) == 0 { return Err("run: CPU lacks unrestricted guest"); }
+    let exit = adjust_controls(pick(IA32_VMX_TRUE_EXIT_CTLS, IA32_VMX_EXIT_CTLS), EXIT_HOST_ADDR_SPACE | EXIT_ACK_INTR | EXIT_SAVE_EFER | EXIT_LOAD_EFER);
+    let entry = adjust_controls(pick(IA32_VMX_TRUE_ENTRY_CTLS, IA32_VMX_ENTRY_CTLS), ENTRY_LOAD_EFER);
+    vmwrite(VMCS_PINBASED_CTLS, pin as u64)?;
+    vmwrite(VMCS_PROCBASED_CTLS, proc1 as u64)?;
+    vmwrite(VMCS_SECONDARY_CTLS, proc2 as u64)?;
+    vmwrite(VMCS_EXIT_CTLS, exit as u64)?;
+    vmwrite(VMCS_ENTRY_CTLS, entry as u64)?;
+    vmwrite(VMCS_EXCEPTION_BITMAP, 0)?;
+    vmwrite(VMCS_CR3_TARGET_COUNT, 0)?;
+    vmwrite(VMCS_EXIT_MSR_STORE_COUNT, 0)?;
+    vmwrite(VMCS_EXIT_MSR_LOAD_COUNT, 0)?;
+    vmwrite(VMCS_ENTRY_MSR_LOAD_COUNT, 0)?;
+    vmwrite(VMCS_LINK_POINTER, u64::MAX)?;
+    vmwrite(VMCS_EPT_POINTER, crate::mm::ept::eptp_from_pml4(ept_root))?;
+    // The guest owns every CR0/CR4 bit except those VMX pins.
+    let (f0, f1) = cr_fixed(0);
+    vmwrite(VMCS_CR0_MASK, (f0 & !(CR0_PE | CR0_PG)) | (!f1 & 0xFFFF_FFFF))?;
+    let (f0, f1) = cr_fixed(4);
+    vmwrite(VMCS_CR4_MASK, f0 | (!f1 & 0xFFFF_FFFF))
+}
+
+/// Fixed-0/fixed-1 MSR pair for CR0 or CR4.
+fn cr_fixed(cr: u8) -> (u64, u64) {
+    use crate::arch::x86::msr::rdmsr;
+    unsafe { if cr == 0 { (rdmsr(0x486), rdmsr(0x487)) } else { (rdmsr(0x488), rdmsr(0x489)) } }
+}
+
+/// CR0 the hardware runs with for a guest-visible value (PE/PG may be clear
+/// under unrestricted guest).
+fn hw_cr0(v: u64) -> u64 { let (f0, f1) = cr_fixed(0); (v | (f0 & !(CR0_PE | CR0_PG))) & f1 }
+
+fn hw_cr4(v: u64) -> u64 { let (f0, f1) = cr_fixed(4); (v | f0 | CR4_VMXE) & f1 }
+
+fn set_cr0(v: u64) -> Result<(), &'static str> {
+    vmwrite(vmcs::VMCS_CR0_SHADOW, v)?;
+    vmwrite(vmcs::VMCS_GUEST_CR0, hw_cr0(v))?;
+    // A MOV to CR0 that exits bypasses the CPU's own EFER.LMA update.
+    let efer = vmread(vmcs::VMCS_GUEST_IA32_EFER)?;
+    let lma = (v & CR0_PG) != 0 && (efer & EFER_LME) != 0;
+    vmwrite(vmcs::VMCS_GUEST_IA32_EFER, if lma { efer | EFER_LMA } else { efer & !EFER_LMA })?;
+    let entry = vmread(vmcs::VMCS_ENTRY_CTLS)? as u32;
+    let entry = if lma { entry | vmcs::ENTRY_IA32E_GUEST } else { entry & !vmcs::ENTRY_IA32E_GUEST };
+    vmwrite(vmcs::VMCS_ENTRY_CTLS, entry as u64)
+}
+
+fn set_cr4(v: u64) -> Result<(), &'static str> {
+    vmwrite(vmcs::VMCS_CR4_SHADOW, v)?;
+    vmwrite(vmcs::VMCS_GUEST_CR4, hw_cr4(v))
+}
+
+/// Guest-state fields shared by both entry modes.
+fn write_common_guest_state() -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    vmwrite(VMCS_GUEST_LDTR_SELECTOR, 0)?;
+    vmwrite(VMCS_GUEST_LDTR_BASE, 0)?;
+    vmwrite(VMCS_GUEST_LDTR_LIMIT, 0)?;
+    vmwrite(VMCS_GUEST_LDTR_AR, AR_UNUSABLE)?;
+    vmwrite(VMCS_GUEST_TR_SELECTOR, 0)?;
+    vmwrite(VMCS_GUEST_TR_BASE, 0)?;
+    vmwrite(VMCS_GUEST_TR_AR, AR_TSS_BUSY)?;
+    vmwrite(VMCS_GUEST_DR7, 0x400)?;
+    vmwrite(VMCS_GUEST_DEBUGCTL, 0)?;
+    vmwrite(VMCS_GUEST_SYSENTER_CS, 0)?;
+    vmwrite(VMCS_GUEST_SYSENTER_ESP, 0)?;
+    vmwrite(VMCS_GUEST_SYSENTER_EIP, 0)?;
+    vmwrite(VMCS_GUEST_ACTIVITY_STATE, 0)?;
+    vmwrite(VMCS_GUEST_INTERRUPTIBILITY, 0)?;
+    vmwrite(VMCS_GUEST_PENDING_DBG, 0)?;
+    vmwrite(VMCS_ENTRY_INTR_INFO, 0)?;
+    Ok(())
+}
+
+/// Long-mode boot state from the loader, for vCPU 0.
+fn program_long_mode(b: &BootRegs) -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    write_common_guest_state()?;
+    crate::hv::loader::program_vmcs_guest_state(b)?;
+    for f in [VMCS_GUEST_ES_BASE, VMCS_GUEST_CS_BASE, VMCS_GUEST_SS_BASE, VMCS_GUEST_DS_BASE, VMCS_GUEST_FS_BASE, VMCS_GUEST_GS_BASE, VMCS_GUEST_IDTR_BASE] { vmwrite(f, 0)?; }
+    for f in [VMCS_GUEST_ES_LIMIT, VMCS_GUEST_SS_LIMIT, VMCS_GUEST_DS_LIMIT, VMCS_GUEST_FS_LIMIT, VMCS_GUEST_GS_LIMIT] { vmwrite(f, 0xFFFF_FFFF)?; }
+    vmwrite(VMCS_GUEST_FS_SELECTOR, b.ds as u64)?;
+    vmwrite(VMCS_GUEST_GS_SELECTOR, b.ds as u64)?;
+    vmwrite(VMCS_GUEST_FS_AR, AR_DATA)?;
+    vmwrite(VMCS_GUEST_GS_AR, AR_DATA)?;
+    vmwrite(VMCS_GUEST_TR_LIMIT, 0x67)?;
+    vmwrite(VMCS_GUEST_IDTR_LIMIT, 0)?;
+    vmwrite(VMCS_GUEST_IA32_EFER, b.efer)?;
+    set_cr4(b.cr4)?;
+    set_cr0(b.cr0)
+}
+
+/// Real-mode state a STARTUP IPI leaves an AP in: CS = vector << 8, IP = 0.
+fn program_real_mode(vector: u8) -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    write_common_guest_state()?;
+    vmwrite(VMCS_GUEST_CS_SELECTOR, (vector as u64) << 8)?;
+    vmwrite(VMCS_GUEST_CS_BASE, (vector as u64) << 12)?;
+    vmwrite(VMCS_GUEST_CS_LIMIT, 0xFFFF)?;
+    vmwrite(VMCS_GUEST_CS_AR, AR_CODE16)?;
+    for &(sel, base, limit, ar) in &[
+        (VMCS_GUEST_ES_SELECTOR, VMCS_GUEST_ES_BASE, VMCS_GUEST_ES_LIMIT, VMCS_GUEST_ES_AR),
+        (VMCS_GUEST_SS_SELECTOR, VMCS_GUEST_SS_BASE, VMCS_GUEST_SS_LIMIT, VMCS_GUEST_SS_AR),
+        (VMCS_GUEST_DS_SELECTOR, VMCS_GUEST_DS_BASE, VMCS_GUEST_DS_LIMIT, VMCS_GUEST_DS_AR),
+        (VMCS_GUEST_FS_SELECTOR, VMCS_GUEST_FS_BASE, VMCS_GUEST_FS_LIMIT, VMCS_GUEST_FS_AR),
+        (VMCS_GUEST_GS_SELECTOR, VMCS_GUEST_GS_BASE, VMCS_GUEST_GS_LIMIT, VMCS_GUEST_GS_AR),
+    ] {
+        vmwrite(sel, 0)?;
+        vmwrite(base, 0)?;
+        vmwrite(limit, 0xFFFF)?;
+        vmwrite(ar, AR_DATA16)?;
+    }
+    vmwrite(VMCS_GUEST_TR_LIMIT, 0xFFFF)?;
+    vmwrite(VMCS_GUEST_GDTR_BASE, 0)?;
+    vmwrite(VMCS_GUEST_GDTR_LIMIT, 0xFFFF)?;
+    vmwrite(VMCS_GUEST_IDTR_BASE, 0)?;
+    vmwrite(VMCS_GUEST_IDTR_LIMIT, 0xFFFF)?;
+    vmwrite(VMCS_GUEST_CR3, 0)?;
+    vmwrite(VMCS_GUEST_IA32_EFER, 0)?;
+    vmwrite(VMCS_GUEST_RSP, 0)?;
+    vmwrite(VMCS_GUEST_RFLAGS, 0x2)?;
+    set_cr4(0)?;
+    set_cr0(CR0_RESET)
+}
+
+fn host_eoi() {
+    if crate::arch::x86::lapic::is_x2apic_enabled() {
+        unsafe { crate::arch::x86::msr::wrmsr(0x80B, 0); }
+    } else if let Some(base) = crate::arch::x86::lapic::apic_base_via_msr() {
+        crate::arch::x86::lapic::eoi(base);
+    }
+}
+
+/// Host CPUID with VMX and XSAVE hidden, the hypervisor bit set and the
+/// APIC IDs replaced by the vCPU index.
+fn emulate_cpuid(regs: &mut GuestRegs, vcpu: u32) {
+    let (leaf, sub) = (regs.gpr[GuestRegs::RAX] as u32, regs.gpr[GuestRegs::RCX] as u32);
+    let mut r = crate::arch::x86::cpuid::cpuid(leaf, sub);
+    match leaf {
+        1 => {
+            r.ecx &= !((1 << 5) | (1 << 26) | (1 << 27) | (1 << 28));
+            r.ecx |= 1 << 31;
+            r.ebx = (r.ebx & 0x00FF_FFFF) | (vcpu << 24);
+        }
+        0xB | 0x1F => r.edx = vcpu,
+        0xD => r = crate::arch::x86::cpuid::CpuidResult::default(),
+        _ => {}
+    }
+    regs.gpr[GuestRegs::RAX] = r.eax as u64;
+    regs.gpr[3] = r.ebx as u64;
+    regs.gpr[GuestRegs::RCX] = r.ecx as u64;
+    regs.gpr[GuestRegs::RDX] = r.edx as u64;
+}
+
+fn read_msr(vm_id: u64, vcpu: u32, msr: u32) -> Result<u64, &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    Ok(match msr {
+        MSR_APIC_BASE => crate::hv::vlapic::with(vm_id, vcpu, |l| l.apic_base).unwrap_or(0),
+        0x800..=0x8FF => { let now = crate::time::rdtsc(); crate::hv::vlapic::with(vm_id, vcpu, |l| l.msr_read(msr, now)).unwrap_or(0) }
+        MSR_EFER => vmread(VMCS_GUEST_IA32_EFER)?,
+        MSR_FS_BASE => vmread(VMCS_GUEST_FS_BASE)?,
+        MSR_GS_BASE => vmread(VMCS_GUEST_GS_BASE)?,
+        MSR_SYSENTER_CS => vmread(VMCS_GUEST_SYSENTER_CS)?,
+        MSR_SYSENTER_ESP => vmread(VMCS_GUEST_SYSENTER_ESP)?,
+        MSR_SYSENTER_EIP => vmread(VMCS_GUEST_SYSENTER_EIP)?,
+        m if MSR_PASSTHROUGH.contains(&m) => unsafe { crate::arch::x86::msr::rdmsr(m) },
+        // Everything else reads as zero.
+        _ => 0,
+    })
+}
+
+fn write_msr(vm_id: u64, vcpu: u32, msr: u32, val: u64) -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    match msr {
+        MSR_APIC_BASE => { let _ = crate::hv::vlapic::with(vm_id, vcpu, |l| l.write_apic_base(val)); }
+        0x800..=0x8FF => {
+            let now = crate::time::rdtsc();
+            if let Some(Some(ipi)) = crate::hv::vlapic::with(vm_id, vcpu, |l| l.msr_write(msr, val, now)) { let _ = crate::hv::vlapic::route_ipi(vm_id, vcpu, ipi); }
+        }
+        MSR_EFER => {
+            // LMA is owned by the CPU (and `set_cr0`), not by WRMSR.
+            let cur = vmread(VMCS_GUEST_IA32_EFER)?;
+            vmwrite(VMCS_GUEST_IA32_EFER, (val & !EFER_LMA) | (cur & EFER_LMA))?;
+        }
+        MSR_FS_BASE => vmwrite(VMCS_GUEST_FS_BASE, val)?,
+        MSR_GS_BASE => vmwrite(VMCS_GUEST_GS_BASE, val)?,
+        MSR_SYSENTER_CS => vmwrite(VMCS_GUEST_SYSENTER_CS, val)?,
+        MSR_SYSENTER_ESP => vmwrite(VMCS_GUEST_SYSENTER_ESP, val)?,
+        MSR_SYSENTER_EIP => vmwrite(VMCS_GUEST_SYSENTER_EIP, val)?,
+        m if MSR_PASSTHROUGH.contains(&m) => unsafe { crate::arch::x86::msr::wrmsr(m, val) },
+        _ => {}
+    }
+    Ok(())
+}
+
+/// MOV to/from a control register (exit qualification layout per the SDM).
+fn cr_access(regs: &mut GuestRegs, qual: u64) -> Result<(), &'static str> {
+    let cr = qual & 0xF;
+    let to_cr = ((qual >> 4) & 3) == 0;
+    let from_cr = ((qual >> 4) & 3) == 1;
+    let reg = ((qual >> 8) & 0xF) as usize;
+    match (cr, to_cr, from_cr) {
+        (0, true, _) => set_cr0(regs.gpr[reg]),
+        (3, true, _) => vmwrite(vmcs::VMCS_GUEST_CR3, regs.gpr[reg]),
+        (3, _, true) => { regs.gpr[reg] = vmread(vmcs::VMCS_GUEST_CR3)?; Ok(()) }
+        (4, true, _) => set_cr4(regs.gpr[reg]),
+        _ => Err("run: unsupported control register access"),
+    }
+}
+
+fn paging() -> Result<GuestPaging, &'static str> {
+    Ok(GuestPaging {
+        cr0: vmread(vmcs::VMCS_CR0_SHADOW)?,
+        cr3: vmread(vmcs::VMCS_GUEST_CR3)?,
+        cr4: vmread(vmcs::VMCS_CR4_SHADOW)?,
+        efer: vmread(vmcs::VMCS_GUEST_IA32_EFER)?,
+    })
+}
+
+/// Apply latched INIT/STARTUP and wake a halted vCPU. Returns false if the
+/// vCPU has nothing to run.
+fn runnable(r: &mut VcpuRun) -> Result<bool, &'static str> {
+    let (vm_id, vcpu) = (r.vm_id, r.vcpu);
+    let (init, sipi) = crate::hv::vlapic::with(vm_id, vcpu, |l| {
+        let init = core::mem::replace(&mut l.init_pending, false);
+        if init && vcpu != 0 { l.reset(); }
+        (init, l.sipi_vector.take())
+    }).unwrap_or((false, None));
+    if init && vcpu != 0 { r.state = RunState::WaitForSipi; }
+    match r.state {
+        RunState::WaitForSipi => {
+            let Some(v) = sipi else { return Ok(false) };
+            program_real_mode(v)?;
+            r.regs = GuestRegs::default();
+            r.state = RunState::Running;
+        }
+        RunState::Halted => {
+            let now = crate::time::rdtsc();
+            let wake = crate::hv::vlapic::with(vm_id, vcpu, |l| { let _ = l.tick(now); l.deliverable().is_some() || l.nmi_pending }).unwrap_or(false);
+            if !wake { return Ok(false); }
+            r.state = RunState::Running;
+        }
+        _ => {}
+    }
+    Ok(true)
+}
+
+/// Guest entry/exit loop for one slice, with the vCPU's VMCS current.
+fn drive(i: usize, r: &mut VcpuRun, end: u64, preempt: Option<u64>) -> Result<Slice, &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    let (vm_id, vcpu) = (r.vm_id, r.vcpu);
+    let caps = crate::hv::event::ApicvCaps::default();
+    let mut ran = false;
+    loop {
+        if STOP[i].load(Ordering::Acquire) { return Ok(Slice::Done); }
+        if DBG_REQ[i].swap(false, Ordering::AcqRel) { park(i, r, StopReason::Halt)?; }
+        if !debug_entry(i, r)? || !runnable(r)? { return Ok(if ran { Slice::Ran { preempted: false } } else { Slice::Blocked }); }
+        crate::hv::event::vmx_deliver_pending(vm_id, vcpu, &caps)?;
+        vmwrite(VMCS_GUEST_RIP, r.regs.rip)?;
+        vmwrite(VMCS_GUEST_RSP, r.regs.gpr[RSP])?;
+        if let Some(rate) = preempt {
+            let left = end.saturating_sub(crate::time::rdtsc()) >> rate;
+            vmwrite(VMCS_PREEMPTION_TIMER, left.clamp(1, u32::MAX as u64))?;
+        }
+        // Publish the generation before checking it: a table change that
+        // lands after the check then shows up in `ept_synced` as unflushed.
+        ENTRY_GEN[i].store(r.ept_gen, Ordering::SeqCst);
+        let gen = crate::mm::stage2::generation();
+        if gen != r.ept_gen {
+            vmx::invept(crate::mm::ept::eptp_from_pml4(r.ept_root))?;
+            r.ept_gen = gen;
+            ENTRY_GEN[i].store(gen, Ordering::SeqCst);
+        }
+        vmx::enter(&mut r.regs.gpr, r.launched)?;
+        r.launched = true;
+        ran = true;
+        let t_exit = crate::time::clock::now_ns();
+        let reason = (vmread(VMCS_EXIT_REASON)? & 0xFFFF) as u32;
+        r.regs.rip = vmread(VMCS_GUEST_RIP)?;
+        r.regs.rflags = vmread(VMCS_GUEST_RFLAGS)?;
+        r.regs.gpr[RSP] = vmread(VMCS_GUEST_RSP)?;
+        r.exits += 1;
+        r.last_exit = reason;
+        crate::obs::trace::emit(crate::obs::trace::Event::VmExit { vm: vm_id as u32, vcpu: vcpu as u16, reason: reason as u16, rip: r.regs.rip });
+        if !debug_exit(i, r, reason)? { handle_exit(r, reason)?; }
+        crate::obs::metrics::EXIT_HANDLING_NS.observe_since(t_exit);
+        r.rip = r.regs.rip;
+        if crate::time::rdtsc() >= end { return Ok(Slice::Ran { preempted: true }); }
+    }
+}
+
+/// Handle one VM exit of `r` (the tick interrupt needs only an EOI).
+fn handle_exit(r: &mut VcpuRun, reason: u32) -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    let (vm_id, vcpu) = (r.vm_id, r.vcpu);
+    let regs = &mut r.regs;
+    let len = vmread(VMCS_EXIT_INSTR_LEN)?;
+    let qual = vmread(VMCS_EXIT_QUALIFICATION)?;
+    match reason {
+        EXIT_EXTERNAL_INTR => host_eoi(),
+        EXIT_INTR_WINDOW | EXIT_PREEMPTION_TIMER => {}
+        EXIT_CPUID => { emulate_cpuid(regs, vcpu); regs.rip += len; }
+        EXIT_HLT => { regs.rip += len; r.state = RunState::Halted; }
+        EXIT_CR_ACCESS => { cr_access(regs, qual)?; regs.rip += len; }
+        EXIT_IO => { crate::hv::exit::handle_io(vm_id, vcpu, &paging()?, regs, crate::hv::exit::vmx_io_qual(qual), len as u8)?; }
+        EXIT_RDMSR => {
+            let v = read_msr(vm_id, vcpu, regs.gpr[GuestRegs::RCX] as u32)?;
+            regs.gpr[GuestRegs::RAX] = v & 0xFFFF_FFFF;
+            regs.gpr[GuestRegs::RDX] = v >> 32;
+            regs.rip += len;
+        }
+        EXIT_WRMSR => {
+            let v = (regs.gpr[GuestRegs::RDX] << 32) | (regs.gpr[GuestRegs::RAX] & 0xFFFF_FFFF);
+            write_msr(vm_id, vcpu, regs.gpr[GuestRegs::RCX] as u32, v)?;
+            regs.rip += len;
+        }
+        EXIT_EPT_VIOLATION => {
+            let gpa = vmread(VMCS_GUEST_PHYS_ADDR)?;
+            // Write to a page KSM shares or is about to merge, or one still on a
+            // microVM template: retry on a private copy.
+            if (qual & 2) != 0 && (crate::hv::ksm::write_fault(vm_id, gpa) || crate::hv::microvm::write_fault(vm_id, gpa)) { return Ok(()); }
+            if crate::hv::exit::handle_mmio(vm_id, vcpu, &paging()?, regs, gpa)? == crate::hv::exit::Outcome::Unclaimed {
+                return Err("run: guest access to unmapped memory");
+            }
+        }
+        EXIT_TRIPLE_FAULT => return Err("run: guest triple fault"),
+        _ => return Err("run: unhandled VM exit"),
+    }
+    Ok(())
+}
+
+/// Park the vCPU in slot `i`, capturing the state the debugger reads. Does
+/// nothing if it is already parked or nobody holds it any more.
+fn park(i: usize, r: &VcpuRun, why: StopReason) -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    let mut seg = [0u16; 6];
+    for (s, f) in seg.iter_mut().zip([VMCS_GUEST_CS_SELECTOR, VMCS_GUEST_SS_SELECTOR, VMCS_GUEST_DS_SELECTOR, VMCS_GUEST_ES_SELECTOR, VMCS_GUEST_FS_SELECTOR, VMCS_GUEST_GS_SELECTOR]) {
+        *s = vmread(f)? as u16;
+    }
+    let mut regs = r.regs;
+    regs.rflags = vmread(VMCS_GUEST_RFLAGS)?;
+    let (fs_base, gs_base, paging) = (vmread(VMCS_GUEST_FS_BASE)?, vmread(VMCS_GUEST_GS_BASE)?, paging()?);
+    let parked = DEBUG.lock(|d| {
+        let s = &mut d[i];
+        if (!s.attached && !s.hold) || s.parked.is_some() { return false; }
+        *s = DebugState { parked: Some(why), regs, seg, fs_base, gs_base, paging, step: false, regs_dirty: false, ..*s };
+        true
+    });
+    // All-stop: the debugger sees the whole VM halted.
+    if parked && why != StopReason::Halt { debug_halt(r.vm_id); }
+    Ok(())
+}
+
+/// Apply what the debugger changed before entering the guest. False while
+/// the vCPU is parked.
+fn debug_entry(i: usize, r: &mut VcpuRun) -> Result<bool, &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    let Some(s) = DEBUG.lock(|d| {
+        let s = d[i];
+        if s.parked.is_some() { return None; }
+        d[i].apply = false;
+        d[i].regs_dirty = false;
+        d[i].rf = false;
+        Some(s)
+    }) else { return Ok(false) };
+    if s.regs_dirty {
+        r.regs = s.regs;
+        vmwrite(VMCS_GUEST_RFLAGS, s.regs.rflags)?;
+    }
+    if s.rf { vmwrite(VMCS_GUEST_RFLAGS, vmread(VMCS_GUEST_RFLAGS)? | RFLAGS_RF)?; }
+    if s.apply {
+        vmwrite(VMCS_EXCEPTION_BITMAP, if s.attached { (1 << 1) | (1 << 3) } else { 0 })?;
+        vmwrite(VMCS_GUEST_DR7, if s.attached { s.dr7 | 0x400 } else { 0x400 })?;
+        let proc1 = vmread(VMCS_PROCBASED_CTLS)? as u32;
+        let proc1 = if s.step { proc1 | PROC_MONITOR_TRAP } else { proc1 & !PROC_MONITOR_TRAP };
+        vmwrite(VMCS_PROCBASED_CTLS, proc1 as u64)?;
+    }
+    // DR0-3 are not in the VMCS and other vCPUs share the AP.
+    if s.attached && (s.dr7 & 0xFF) != 0 {
+        unsafe {
+            core::arch::asm!("mov dr0, {}", "mov dr1, {}", "mov dr2, {}", "mov dr3, {}",
+                in(reg) s.dr[0], in(reg) s.dr[1], in(reg) s.dr[2], in(reg) s.dr[3], options(nostack, preserves_flags));
+        }
+    }
+    Ok(true)
+}
+
+/// Turn a #BP, #DB or monitor-trap exit into a stop for the debugger. A #DB
+/// no breakpoint of the debugger explains, or one taken after a detach,
+/// goes back to the guest. False if the exit is not a debug event.
+fn debug_exit(i: usize, r: &mut VcpuRun, reason: u32) -> Result<bool, &'static str> {
+    use crate::arch::x86::vm::vmcs::*;
+    let why = match reason {
+        EXIT_MONITOR_TRAP => StopReason::Step,
+        EXIT_EXCEPTION_NMI => {
+            let info = vmread(VMCS_EXIT_INTR_INFO)?;
+            let vector = info & 0xFF;
+            // Type 2 is an NMI, still fatal as before
+            if ((info >> 8) & 7) == 2 || (vector != 1 && vector != 3) { return Ok(false); }
+            let (attached, dr7) = DEBUG.lock(|d| (d[i].attached, d[i].dr7));
+            let qual = vmread(VMCS_EXIT_QUALIFICATION)?;
+            let hit = if vector == 1 { (0..4u8).find(|&n| (qual & (1 << n)) != 0) } else { None };
+            if !attached || (vector == 1 && hit.is_none()) {
+                vmwrite(VMCS_ENTRY_INTR_INFO, info & 0x8000_07FF)?;
+                if vector == 3 { vmwrite(VMCS_ENTRY_INSTR_LEN, vmread(VMCS_EXIT_INSTR_LEN)?)?; }
+                return Ok(true);
+            }
+            match hit {
+                None => StopReason::SwBreak,
+                // R/W bits 00: instruction breakpoint
+                Some(n) if ((dr7 >> (16 + 4 * n)) & 3) == 0 => StopReason::HwBreak(n),
+                Some(n) => StopReason::Watch(n),
+            }
+        }
+        _ => return Ok(false),
+    };
+    park(i, r, why)?;
+    Ok(true)
+}
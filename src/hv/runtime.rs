@@ -0,0 +1,246 @@
+#![allow(dead_code)]
+
+//! Runtime phase: the hypervisor after ExitBootServices.
+//!
+//! `enter` does the last work that needs Boot Services: it reserves the DMA
+//! pool, builds identity page tables that cover the memory map and the 4 GiB
+//! MMIO hole, flushes the audit log and collects the boot handoff
+//! (`hv::bootinfo`). It moves the heap onto its arena (`mm::heap`) and exits
+//! boot services. The final memory map goes into the handoff, and
+//! `init_with_bootinfo` takes it into the guest frame pool
+//! (`mm::guest::adopt`). CR3 switches to our tables, and the firmware
+//! console is dropped. From then on
+//! `run` owns the boot CPU. It polls the serial console for a small command
+//! set and runs the housekeeping that needs no firmware. UEFI runtime
+//! services (variables, reset) stay available through the runtime system
+//! table.
+//!
+//! Everything that still takes `SystemTable<Boot>` is left behind: the full
+//! CLI, file-backed disks, the UEFI network uplink, TPM and the UEFI
+//! watchdog. Running VMs keep running on their CPUs, but their virtio
+//! backends are no longer pumped. There is no way back.
+
+use core::convert::Infallible;
+use uefi::prelude::Boot;
+use uefi::table::boot::MemoryType;
+use uefi::table::{Runtime, SystemTable};
+
+use crate::hv::bootinfo::{self, BootInfo, MapEntry};
+use crate::obs::console;
+use crate::util::spinlock::SpinLock;
+
+/// DMA pool reserved for drivers that outlive Boot Services (1 MiB)
+pub const DMA_PAGES: usize = 256;
+const GIB: u64 = 1 << 30;
+/// `mm::paging::build_identity_2m` fills a single PDPT
+const MAP_MAX: u64 = 512 * GIB;
+const LINE_MAX: usize = 160;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Info {
+    /// `clock::now_ms` on entry
+    pub entered_ms: u64,
+    pub pml4: u64,
+    /// Identity-mapped bytes from 0
+    pub mapped: u64,
+    pub map_entries: u32,
+    /// Pages of the final map adopted into the guest pool
+    pub adopted_pages: u64,
+    /// The pool was filled from the boot handoff, not straight from the map
+    pub handoff: bool,
+}
+
+static INFO: SpinLock<Option<Info>> = SpinLock::new(None);
+
+pub fn active() -> bool { INFO.lock(|i| i.is_some()) }
+pub fn info() -> Option<Info> { INFO.lock(|i| *i) }
+
+/// End of the highest descriptor or 4 GiB, whichever is higher, rounded up
+/// to a GiB.
+fn map_limit(system_table: &SystemTable<Boot>) -> Option<u64> {
+    let mut end = 4 * GIB;
+    if !crate::mm::uefi::for_each_map_entry(system_table, |d| {
+        end = end.max(d.phys_start.saturating_add(d.page_count.saturating_mul(4096)));
+    }) { return None; }
+    Some(((end + GIB - 1) & !(GIB - 1)).min(MAP_MAX))
+}
+
+/// Take a final memory map into the guest pool. Returns the entries seen
+/// and the pages adopted.
+pub fn init_with_memory_map(map: &[MapEntry]) -> (u32, u64) {
+    let pages = crate::mm::guest::adopt(map.iter().map(|e| (bootinfo::entry_type(e), e.base, e.pages)));
+    (map.len() as u32, pages)
+}
+
+/// Bring the runtime side up from a boot handoff: check that this build
+/// can read it, then adopt its memory map. A map with dropped descriptors
+/// is refused, since adopting it would lose memory.
+pub fn init_with_bootinfo(bi: &BootInfo) -> Result<(u32, u64), &'static str> {
+    bi.check()?;
+    if !bi.has(bootinfo::HAS_MEMORY_MAP) { return Err("runtime: boot info has no memory map"); }
+    if bi.map_dropped != 0 { return Err("runtime: boot info memory map truncated"); }
+    Ok(init_with_memory_map(bi.memory_map()))
+}
+
+/// Leave Boot Services and run the runtime phase. Returns only if a step
+/// before ExitBootServices failed; nothing has changed then.
+pub fn enter(system_table: &mut SystemTable<Boot>) -> Result<Infallible, &'static str> {
+    if active() { return Err("runtime: already entered"); }
+    if console::serial().is_none() { return Err("runtime: turn on the serial console first (console serial on)"); }
+    crate::mm::dma::reserve(system_table, DMA_PAGES)?;
+    let limit = map_limit(system_table).ok_or("runtime: memory map unavailable")?;
+    let pml4 = crate::mm::paging::build_identity_2m(system_table, limit).ok_or("runtime: page table allocation failed")? as u64;
+    let _ = crate::diag::audit::flush(system_table);
+    bootinfo::collect(system_table);
+    crate::obs::log::info(system_table, "runtime", "exiting boot services");
+    // SAFETY: the caller's table is never used again; `run` does not return.
+    let st = unsafe { system_table.unsafe_clone() };
+    crate::mm::heap::enter_runtime();
+    let (rt, map) = st.exit_boot_services(MemoryType::LOADER_DATA);
+    // Boot Services, ConOut and ConIn are gone from here on.
+    console::detach_firmware();
+    crate::diag::panic::enter_runtime(rt.as_ptr());
+    bootinfo::set_memory_map(map.entries());
+    let (entries, adopted_pages, handoff) = match bootinfo::with(init_with_bootinfo) {
+        Some(Ok((e, p))) => (e, p, true),
+        // Adopt straight from firmware's map rather than lose the memory
+        _ => {
+            let mut entries = 0u32;
+            let pages = crate::mm::guest::adopt(map.entries().inspect(|_| entries += 1).map(|d| (d.ty, d.phys_start, d.page_count)));
+            (entries, pages, false)
+        }
+    };
+    // SAFETY: the new tables identity-map everything the old ones did below
+    // `limit`, and live in LOADER_DATA.
+    unsafe { core::arch::asm!("mov cr3, {}", in(reg) pml4, options(nostack, preserves_flags)); }
+    INFO.lock(|i| *i = Some(Info { entered_ms: crate::time::clock::now_ms(), pml4, mapped: limit, map_entries: entries, adopted_pages, handoff }));
+    run(rt)
+}
+
+/// Housekeeping that needs no firmware.
+fn idle() {
+    let _ = crate::hv::sched::background::run();
+    crate::hv::gdb::poll();
+    crate::obs::page::tick();
+    // Pets the hardware watchdog, among the other timers
+    crate::time::timer::poll();
+}
+
+fn pause_ms(ms: u64) { crate::time::clock::delay_us(ms * 1000) }
+
+/// Serial command loop of the runtime phase.
+pub fn run(rt: SystemTable<Runtime>) -> ! {
+    console::write_bytes(b"runtime: boot services exited; type 'help'\r\n> ");
+    let mut line = [0u8; LINE_MAX];
+    let mut len = 0usize;
+    let mut last_cr = false;
+    loop {
+        while let Some(b) = console::read_byte() {
+            // A CR LF pair ends one line, not two.
+            if b == b'\n' && last_cr { last_cr = false; continue; }
+            last_cr = b == b'\r';
+            console::echo(b);
+            match b {
+                b'\r' | b'\n' => {
+                    console::write_bytes(b"\r\n");
+                    if let Ok(cmd) = core::str::from_utf8(&line[..len]) { command(&rt, cmd.trim()); }
+                    len = 0;
+                    console::write_bytes(b"> ");
+                }
+                0x08 | 0x7F => { if len > 0 { len -= 1; } }
+                0x20..=0x7E if len < line.len() => { line[len] = b; len += 1; }
+                _ => {}
+            }
+        }
+        idle();
+        pause_ms(1);
+    }
+}
+
+fn command(rt: &SystemTable<Runtime>, cmd: &str) {
+    match cmd {
+        "" => {}
+        "help" => console::write_bytes(b"Commands: help | info | logs | trace | metrics | timer stats | reset | poweroff\r\n"),
+        "info" => report(),
+        "logs" => crate::obs::log::dump_with_writer(console::write_bytes),
+        "trace" => crate::obs::trace::dump_with_writer(console::write_bytes),
+        "metrics" => crate::obs::prom::render(|s| console::write_bytes(s.as_bytes())),
+        "timer" | "timer stats" => crate::time::timer::render(|s| console::write_bytes(s.as_bytes())),
+        // SAFETY: runtime services stay mapped 1:1; we never call SetVirtualAddressMap.
+        "reset" => unsafe { rt.runtime_services() }.reset(uefi::table::runtime::ResetType::COLD, uefi::Status::SUCCESS, None),
+        "poweroff" => unsafe { rt.runtime_services() }.reset(uefi::table::runtime::ResetType::SHUTDOWN, uefi::Status::SUCCESS, None),
+        _ => console::write_bytes(b"runtime: unknown command; the full CLI needs Boot Services\r\n"),
+    }
+}
+
+fn report() {
+    let Some(i) = info() else { return };
+    let mut out = [0u8; 256]; let mut n = 0;
+    for &b in b"runtime: cr3=0x" { out[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(i.pml4, &mut out[n..]);
+    for &b in b" mapped=" { out[n] = b; n += 1; }
+    n += crate::util::format::size(i.mapped, &mut out[n..]);
+    for &b in b" map_entries=" { out[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(i.map_entries as u64, &mut out[n..]);
+    for &b in b" adopted=" { out[n] = b; n += 1; }
+    n += crate::util::format::size(i.adopted_pages * 4096, &mut out[n..]);
+    if !i.handoff { for &b in b" (no handoff)" { out[n] = b; n += 1; } }
+    for &b in b"\r\n" { out[n] = b; n += 1; }
+    console::write_bytes(&out[..n]);
+    bootinfo::with(|bi| report_bootinfo(bi, &mut out));
+    let g = crate::mm::guest::stats();
+    let (dma_total, dma_free) = crate::mm::dma::usage();
+    n = 0;
+    for &b in b"  guest pool=" { out[n] = b; n += 1; }
+    n += crate::util::format::size(g.pool, &mut out[n..]);
+    for &b in b" free=" { out[n] = b; n += 1; }
+    n += crate::util::format::size(g.free, &mut out[n..]);
+    for &b in b"  dma pages=" { out[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(dma_free as u64, &mut out[n..]);
+    out[n] = b'/'; n += 1;
+    n += crate::util::format::u64_dec(dma_total as u64, &mut out[n..]);
+    for &b in b"  vcpus running=" { out[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(crate::hv::run::active_total() as u64, &mut out[n..]);
+    for &b in b"\r\n" { out[n] = b; n += 1; }
+    console::write_bytes(&out[..n]);
+}
+
+fn report_bootinfo(bi: &BootInfo, out: &mut [u8; 256]) {
+    let mut n = 0;
+    for &b in b"  handoff v" { out[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(bi.version as u64, &mut out[n..]);
+    if bi.has(bootinfo::HAS_RSDP) {
+        for &b in b" rsdp=0x" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(bi.rsdp, &mut out[n..]);
+    }
+    if bi.has(bootinfo::HAS_FRAMEBUFFER) {
+        let f = bi.framebuffer;
+        for &b in b" fb=" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(f.width as u64, &mut out[n..]);
+        out[n] = b'x'; n += 1;
+        n += crate::util::format::u64_dec(f.height as u64, &mut out[n..]);
+        for &b in b"@0x" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(f.base, &mut out[n..]);
+    }
+    if bi.has(bootinfo::HAS_METRICS_PAGE) {
+        for &b in b" metrics=0x" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(bi.metrics_page, &mut out[n..]);
+    }
+    if bi.has(bootinfo::HAS_EVENT_LOG) {
+        for &b in b" eventlog=" { out[n] = b; n += 1; }
+        n += crate::util::format::size(bi.event_log_len, &mut out[n..]);
+    }
+    for &b in b" measured=" { out[n] = b; n += 1; }
+    for &b in if bi.has(bootinfo::HAS_IMAGE_HASH) { b"yes".as_slice() } else { b"no".as_slice() } { out[n] = b; n += 1; }
+    if bi.map_dropped != 0 {
+        for &b in b" map_dropped=" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(bi.map_dropped as u64, &mut out[n..]);
+    }
+    for &b in b"\r\n" { out[n] = b; n += 1; }
+    console::write_bytes(&out[..n]);
+    if bi.has(bootinfo::HAS_CMDLINE) {
+        console::write_bytes(b"  cmdline: ");
+        console::write_bytes(bi.cmdline().as_bytes());
+        console::write_bytes(b"\r\n");
+    }
+}
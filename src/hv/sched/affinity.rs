@@ -0,0 +1,52 @@
+#![allow(dead_code)]
+
+//! Per-VM CPU affinity.
+//!
+//! A VM's affinity is a mask of host APIC IDs (bit n = APIC ID n, so only
+//! IDs below 64 can be named) kept in its `VmConfig`; 0 means any CPU. vCPU
+//! placement in `hv::run` and the real-time admission test only consider
+//! APs inside the mask, and the AP jobs serving those vCPUs are dispatched
+//! with `ap::dispatch_within`, which refuses CPUs outside it. Masks are
+//! checked against the processors listed in the firmware's MADT.
+//!
+//! Changing the mask of a running VM re-places the vCPUs that fall outside
+//! it; each is handed over by the AP it is homed on at its next scheduling
+//! point.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+/// True if `mask` lets a vCPU run on the CPU with APIC ID `apic`.
+pub fn allows(mask: u64, apic: u32) -> bool { mask == 0 || (apic < 64 && (mask & (1u64 << apic)) != 0) }
+
+/// Processors the MADT lists, as an APIC ID mask (IDs below 64 only).
+pub fn madt_mask(system_table: &SystemTable<Boot>) -> Option<u64> {
+    let hdr = crate::firmware::acpi::find_madt(system_table)?;
+    let mut m = 0u64;
+    crate::firmware::acpi::madt_for_each_processor_id(|id| { if id < 64 { m |= 1u64 << id; } }, hdr);
+    Some(m)
+}
+
+/// Check that `mask` only names processors the MADT lists and leaves at
+/// least one CPU other than the BSP, which never runs vCPUs.
+pub fn validate(system_table: &SystemTable<Boot>, mask: u64) -> Result<(), &'static str> {
+    if mask == 0 { return Ok(()); }
+    let madt = madt_mask(system_table).ok_or("pin: no MADT to check the mask against")?;
+    if (mask & !madt) != 0 { return Err("pin: mask names a CPU the MADT does not list"); }
+    let bsp = super::current_cpu();
+    if bsp < 64 && (mask & !(1u64 << bsp)) == 0 { return Err("pin: mask leaves no application processor"); }
+    Ok(())
+}
+
+/// Set the affinity of `vm_id` and move any running vCPUs outside it.
+/// Returns the number of vCPUs that will move.
+pub fn pin(system_table: &SystemTable<Boot>, vm_id: u64, mask: u64) -> Result<u32, &'static str> {
+    let info = crate::hv::vm::find_vm(vm_id).ok_or("pin: no such vm")?;
+    validate(system_table, mask)?;
+    crate::hv::vm::set_vm_affinity(vm_id, mask);
+    if crate::hv::run::active(vm_id) == 0 { return Ok(0); }
+    match crate::hv::run::repin(vm_id, mask) {
+        Ok(n) => Ok(n),
+        Err(e) => { crate::hv::vm::set_vm_affinity(vm_id, info.affinity); Err(e) }
+    }
+}
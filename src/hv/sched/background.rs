@@ -0,0 +1,177 @@
+#![allow(dead_code)]
+
+//! Background task class for hypervisor housekeeping.
+//!
+//! Page dedup scans, NUMA page migration, metrics aggregation and compression
+//! workers register a step function here. Steps run only while the CPU budget
+//! for the current accounting window has headroom and never on RT-reserved
+//! cores. Each step should do a bounded slice of work and report whether more
+//! is pending. CPU time is charged per task and exported through
+//! `obs::metrics`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::util::spinlock::SpinLock;
+
+/// Maximum number of registered background tasks.
+pub const MAX_TASKS: usize = crate::obs::metrics::MAX_TASK_ACCT;
+
+/// Work category, used for reporting only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskKind { PageDedup, NumaMigrate, MetricsAggregate, Compression, Other }
+
+impl TaskKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskKind::PageDedup => "dedup",
+            TaskKind::NumaMigrate => "numa",
+            TaskKind::MetricsAggregate => "metrics",
+            TaskKind::Compression => "compress",
+            TaskKind::Other => "other",
+        }
+    }
+}
+
+/// One step of background work. Returns true if more work is pending.
+pub type StepFn = fn() -> bool;
+
+#[derive(Clone, Copy)]
+pub struct Task {
+    pub name: &'static str,
+    pub kind: TaskKind,
+    pub step: StepFn,
+    pub enabled: bool,
+    /// Task reported pending work on its last step (or has never run)
+    pub busy: bool,
+    pub runs: u64,
+    /// CPU time spent in `step`
+    pub run_ns: u64,
+}
+
+/// CPU budget: `pct` percent of each `window_us` accounting window.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget { pub pct: u32, pub window_us: u64 }
+
+struct State {
+    tasks: [Option<Task>; MAX_TASKS],
+    budget: Budget,
+    window_start_ns: u64,
+    used_ns: u64,
+    next: usize,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    tasks: [None; MAX_TASKS],
+    budget: Budget { pct: 10, window_us: 100_000 },
+    window_start_ns: 0,
+    used_ns: 0,
+    next: 0,
+});
+
+/// Guards against re-entry from nested idle hooks.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Register a background task; returns its slot.
+pub fn register(name: &'static str, kind: TaskKind, step: StepFn) -> Option<usize> {
+    STATE.lock(|s| {
+        if let Some(i) = s.tasks.iter().position(|t| matches!(t, Some(t) if t.name == name)) { return Some(i); }
+        let i = s.tasks.iter().position(|t| t.is_none())?;
+        s.tasks[i] = Some(Task { name, kind, step, enabled: true, busy: true, runs: 0, run_ns: 0 });
+        Some(i)
+    })
+}
+
+/// Enable or disable a task by name.
+pub fn set_enabled(name: &str, on: bool) -> bool {
+    STATE.lock(|s| {
+        for t in s.tasks.iter_mut().flatten() {
+            if t.name == name { t.enabled = on; if on { t.busy = true; } return true; }
+        }
+        false
+    })
+}
+
+/// Mark a task as having new work (e.g. after a VM was created).
+pub fn kick(name: &str) {
+    STATE.lock(|s| { for t in s.tasks.iter_mut().flatten() { if t.name == name { t.busy = true; } } });
+}
+
+/// Set the CPU budget. `pct` is clamped to 1..=100; `window_us` to at least 1 ms.
+pub fn set_budget(pct: u32, window_us: u64) {
+    STATE.lock(|s| {
+        s.budget = Budget { pct: pct.clamp(1, 100), window_us: window_us.max(1000) };
+        s.used_ns = 0;
+        s.window_start_ns = 0;
+    });
+}
+
+pub fn budget() -> Budget { STATE.lock(|s| s.budget) }
+
+/// Snapshot of registered tasks.
+pub fn tasks() -> [Option<Task>; MAX_TASKS] { STATE.lock(|s| s.tasks) }
+
+/// Nanoseconds still available in the current window (rolls the window over if due).
+fn headroom(s: &mut State, now: u64) -> u64 {
+    let window = s.budget.window_us.saturating_mul(1_000);
+    if s.window_start_ns == 0 || now.saturating_sub(s.window_start_ns) >= window {
+        s.window_start_ns = now;
+        s.used_ns = 0;
+    }
+    let allowed = ((window as u128) * (s.budget.pct as u128) / 100) as u64;
+    allowed.saturating_sub(s.used_ns)
+}
+
+/// Run background steps on the current CPU until the budget is spent or no
+/// task has pending work. Returns the number of steps executed.
+pub fn run() -> u32 {
+    let cpu = super::current_cpu();
+    if super::is_rt_reserved(cpu) {
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::BG_RT_SKIPS).inc();
+        return 0;
+    }
+    // Without a running clock the budget cannot be enforced; do nothing.
+    if crate::time::clock::hz() == 0 { return 0; }
+    if RUNNING.swap(true, Ordering::Acquire) { return 0; }
+    let mut steps = 0u32;
+    loop {
+        let now = crate::time::clock::now_ns();
+        // Pick the next busy task round-robin while holding the lock, run it without.
+        let pick = STATE.lock(|s| {
+            if headroom(s, now) == 0 { return Err(true); }
+            for k in 0..MAX_TASKS {
+                let i = (s.next + k) % MAX_TASKS;
+                if let Some(t) = s.tasks[i] {
+                    if t.enabled && t.busy { s.next = (i + 1) % MAX_TASKS; return Ok((i, t.name, t.step)); }
+                }
+            }
+            Err(false)
+        });
+        let (slot, name, step) = match pick {
+            Ok(p) => p,
+            Err(throttled) => {
+                if throttled { crate::obs::metrics::Counter::new(&crate::obs::metrics::BG_THROTTLED).inc(); }
+                break;
+            }
+        };
+        let t0 = crate::time::clock::now_ns();
+        let more = step();
+        let dt = crate::time::clock::elapsed_ns(t0);
+        STATE.lock(|s| {
+            s.used_ns = s.used_ns.saturating_add(dt);
+            if let Some(t) = s.tasks[slot].as_mut() { t.busy = more; t.runs += 1; t.run_ns = t.run_ns.wrapping_add(dt); }
+        });
+        crate::obs::metrics::task_account(slot, name, dt);
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::BG_RUNS).inc();
+        steps += 1;
+    }
+    RUNNING.store(false, Ordering::Release);
+    steps
+}
+
+/// Share of the current window consumed so far, in percent of the window.
+pub fn window_usage_pct() -> u32 {
+    if crate::time::clock::hz() == 0 { return 0; }
+    STATE.lock(|s| {
+        let window = s.budget.window_us.saturating_mul(1_000);
+        if window == 0 { 0 } else { ((s.used_ns as u128) * 100 / (window as u128)) as u32 }
+    })
+}
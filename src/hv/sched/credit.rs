@@ -0,0 +1,428 @@
+#![allow(dead_code)]
+
+//! Credit scheduler for guest vCPUs.
+//!
+//! Every AP hosting vCPUs has its own runqueue; a vCPU stays on the AP it was
+//! placed on. Once per accounting period each VM earns credit in proportion
+//! to its weight, split evenly among its vCPUs, and a vCPU pays for the time
+//! it spends in the guest. vCPUs with credit left (UNDER) are picked before
+//! those that have used theirs up (OVER); within a priority the queue is
+//! FIFO. Credit is bounded by one period either way so an idle vCPU cannot
+//! hoard it.
+//!
+//! A VM's cap limits it to `cap` percent of one CPU per period (0 = no cap).
+//! The power-capping quota from `hv::power`, or the thermal quota of the
+//! hottest package the VM's vCPUs are homed on (`hv::thermal`) if lower,
+//! scales the weight and acts as an additional cap. Once a VM has used its limit its vCPUs are parked until
+//! the next period. Slices end on the LAPIC timer tick driven by `hv::run`;
+//! periods also roll over from the host timer wheel (`period_tick`).
+//!
+//! vCPUs of VMs with a real-time reservation share the same runqueues but
+//! are picked by the `rt` class first and earn no credit.
+
+use crate::arch::x86::ap::MAX_APS;
+use crate::obs::metrics::{self, Counter};
+use crate::util::spinlock::SpinLock;
+
+/// Schedulable vCPUs, indexed by `hv::run` slot.
+pub const MAX_ENTITIES: usize = 64;
+pub const MAX_DOMAINS: usize = 16;
+pub const DEFAULT_WEIGHT: u32 = 256;
+pub const MAX_WEIGHT: u32 = 65535;
+/// Highest cap, in percent of one CPU
+pub const MAX_CAP: u32 = 100 * MAX_APS as u32;
+/// Time slice before the timer preempts a vCPU
+pub const TICK_US: u64 = 10_000;
+/// Credit is handed out once per period
+pub const PERIOD_US: u64 = 30_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prio { Under, Over }
+
+impl Prio {
+    pub fn name(self) -> &'static str {
+        match self { Prio::Under => "under", Prio::Over => "over" }
+    }
+}
+
+/// Per-VM scheduling parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params { pub weight: u32, pub cap: u32 }
+
+impl Params {
+    pub const DEFAULT: Params = Params { weight: DEFAULT_WEIGHT, cap: 0 };
+}
+
+#[derive(Clone, Copy)]
+struct Domain {
+    vm_id: u64,
+    params: Params,
+    /// Guest time allowed this period in microseconds (0 = unlimited)
+    limit_us: u64,
+    used_us: u64,
+    parked: bool,
+    run_us: u64,
+    parks: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Entity { vm_id: u64, cpu: usize, credit: i64 }
+
+#[derive(Clone, Copy)]
+struct RunQueue {
+    slots: [u8; MAX_ENTITIES],
+    len: usize,
+    current: Option<u8>,
+    last: Option<u8>,
+    /// An `hv::run` job is serving this queue
+    job: bool,
+    slices: u64,
+    switches: u64,
+    preempts: u64,
+    /// Guest time run from this queue, in microseconds; never cleared
+    busy_us: u64,
+}
+
+impl RunQueue {
+    const EMPTY: RunQueue = RunQueue { slots: [0; MAX_ENTITIES], len: 0, current: None, last: None, job: false, slices: 0, switches: 0, preempts: 0, busy_us: 0 };
+
+    fn push(&mut self, slot: usize) {
+        if self.len < MAX_ENTITIES { self.slots[self.len] = slot as u8; self.len += 1; }
+    }
+
+    fn remove_at(&mut self, k: usize) -> usize {
+        let slot = self.slots[k] as usize;
+        self.slots.copy_within(k + 1..self.len, k);
+        self.len -= 1;
+        slot
+    }
+}
+
+struct State {
+    ents: [Option<Entity>; MAX_ENTITIES],
+    doms: [Option<Domain>; MAX_DOMAINS],
+    rqs: [RunQueue; MAX_APS],
+    period_start: u64,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    ents: [None; MAX_ENTITIES],
+    doms: [None; MAX_DOMAINS],
+    rqs: [RunQueue::EMPTY; MAX_APS],
+    period_start: 0,
+});
+
+fn us_to_tsc(us: u64, hz: u64) -> u64 { ((hz as u128) * (us as u128) / 1_000_000) as u64 }
+
+fn tsc_to_us(tsc: u64, hz: u64) -> u64 { if hz == 0 { 0 } else { ((tsc as u128) * 1_000_000 / (hz as u128)) as u64 } }
+
+fn domain(s: &mut State, vm_id: u64) -> Option<&mut Domain> { s.doms.iter_mut().flatten().find(|d| d.vm_id == vm_id) }
+
+fn new_domain(vm_id: u64, params: Params) -> Domain {
+    Domain { vm_id, params, limit_us: 0, used_us: 0, parked: false, run_us: 0, parks: 0 }
+}
+
+/// Find or create the domain of `vm_id`.
+/// Power or thermal quota of `vm_id`, whichever is lower, in percent.
+fn quota_pct(s: &State, vm_id: u64) -> u64 {
+    let mut q = crate::hv::power::quota_pct(vm_id);
+    for (i, e) in s.ents.iter().enumerate() {
+        let Some(e) = e else { continue };
+        if e.vm_id == vm_id && !super::rt::is_rt(i) { q = q.min(crate::hv::thermal::cpu_quota_pct(e.cpu)); }
+    }
+    q as u64
+}
+
+fn domain_or_insert(s: &mut State, vm_id: u64) -> Option<&mut Domain> {
+    if !s.doms.iter().flatten().any(|d| d.vm_id == vm_id) {
+        let free = s.doms.iter_mut().find(|d| d.is_none())?;
+        *free = Some(new_domain(vm_id, Params::DEFAULT));
+    }
+    domain(s, vm_id)
+}
+
+/// Start a new period if the current one is over: hand out credit and unpark.
+fn account(s: &mut State, now: u64, hz: u64) {
+    if s.period_start != 0 && now.wrapping_sub(s.period_start) < us_to_tsc(PERIOD_US, hz) { return; }
+    s.period_start = now;
+    Counter::new(&metrics::SCHED_PERIODS).inc();
+    let pcpus = s.rqs.iter().filter(|q| q.job).count().max(1) as u64;
+    let total = PERIOD_US * pcpus;
+    let mut vcpus = [0u64; MAX_DOMAINS];
+    let mut weight = [0u64; MAX_DOMAINS];
+    for (k, d) in s.doms.iter().enumerate() {
+        let Some(d) = d else { continue };
+        vcpus[k] = s.ents.iter().enumerate().filter(|(i, e)| matches!(e, Some(e) if e.vm_id == d.vm_id) && !super::rt::is_rt(*i)).count() as u64;
+        let quota = quota_pct(s, d.vm_id);
+        if vcpus[k] != 0 { weight[k] = (d.params.weight as u64 * quota / 100).max(1); }
+    }
+    let sum: u64 = weight.iter().sum();
+    for k in 0..MAX_DOMAINS {
+        let Some(vm_id) = s.doms[k].map(|d| d.vm_id) else { continue };
+        let quota = quota_pct(s, vm_id);
+        let Some(d) = s.doms[k].as_mut() else { continue };
+        d.used_us = 0;
+        d.parked = false;
+        if vcpus[k] == 0 { d.limit_us = 0; continue; }
+        // Power or thermal quota: `quota` percent of each vCPU's CPU time.
+        let mut limit = if quota < 100 { PERIOD_US * quota * vcpus[k] / 100 } else { 0 };
+        if d.params.cap != 0 {
+            let cap = PERIOD_US * d.params.cap as u64 / 100;
+            limit = if limit == 0 { cap } else { limit.min(cap) };
+        }
+        d.limit_us = limit;
+        let mut share = if sum == 0 { 0 } else { total * weight[k] / sum };
+        if limit != 0 { share = share.min(limit); }
+        let per = (share / vcpus[k]) as i64;
+        let vm_id = d.vm_id;
+        for (i, e) in s.ents.iter_mut().enumerate() {
+            let Some(e) = e.as_mut() else { continue };
+            if e.vm_id == vm_id && !super::rt::is_rt(i) { e.credit = (e.credit + per).min(PERIOD_US as i64); }
+        }
+    }
+}
+
+/// Roll the period over from the timer wheel. `pick` only does it when an AP
+/// looks for work, so without this a VM parked on its cap waits for the
+/// next pick to be unparked.
+pub fn period_tick(_: u64) {
+    let hz = crate::time::tsc_hz();
+    if hz == 0 { return; }
+    STATE.lock(|s| account(s, crate::time::rdtsc(), hz));
+}
+
+/// Arm `period_tick` once per slice, so a period ends at most one slice late.
+pub fn start_timer() -> Result<crate::time::timer::TimerId, &'static str> {
+    crate::time::timer::add("sched-period", TICK_US / 1000, TICK_US / 1000, period_tick, 0)
+}
+
+/// Add the vCPU in run slot `slot` to the runqueue of AP `cpu`. Returns true
+/// if the AP has no scheduling job yet and the caller must dispatch one.
+pub fn attach(slot: usize, vm_id: u64, cpu: usize) -> Result<bool, &'static str> {
+    if slot >= MAX_ENTITIES || cpu >= MAX_APS { return Err("sched: vCPU or CPU out of range"); }
+    STATE.lock(|s| {
+        if domain_or_insert(s, vm_id).is_none() { return Err("sched: too many VMs"); }
+        s.ents[slot] = Some(Entity { vm_id, cpu, credit: 0 });
+        super::rt::attach(slot, vm_id, cpu);
+        let q = &mut s.rqs[cpu];
+        q.push(slot);
+        Ok(!core::mem::replace(&mut q.job, true))
+    })
+}
+
+/// Remove a vCPU from scheduling.
+pub fn detach(slot: usize) {
+    if slot >= MAX_ENTITIES { return; }
+    STATE.lock(|s| {
+        let Some(e) = s.ents[slot].take() else { return };
+        super::rt::detach(slot);
+        let q = &mut s.rqs[e.cpu];
+        if let Some(k) = q.slots[..q.len].iter().position(|&x| x as usize == slot) { q.remove_at(k); }
+        if q.current == Some(slot as u8) { q.current = None; }
+        if q.last == Some(slot as u8) { q.last = None; }
+    });
+}
+
+/// Move a picked vCPU to the runqueue of AP `to`. Returns true if that AP
+/// has no scheduling job yet and the caller must dispatch one.
+pub fn rehome(slot: usize, to: usize) -> Result<bool, &'static str> {
+    if slot >= MAX_ENTITIES || to >= MAX_APS { return Err("sched: vCPU or CPU out of range"); }
+    STATE.lock(|s| {
+        let e = s.ents[slot].as_mut().ok_or("sched: vCPU not scheduled")?;
+        let from = core::mem::replace(&mut e.cpu, to);
+        let q = &mut s.rqs[from];
+        if let Some(k) = q.slots[..q.len].iter().position(|&x| x as usize == slot) { q.remove_at(k); }
+        if q.current == Some(slot as u8) { q.current = None; }
+        if q.last == Some(slot as u8) { q.last = None; }
+        super::rt::rehome(slot, to);
+        let q = &mut s.rqs[to];
+        q.push(slot);
+        Ok(!core::mem::replace(&mut q.job, true))
+    })
+}
+
+/// Called by the job of AP `cpu` when its queue looks empty. Returns true
+/// (and releases the queue) if no vCPU is homed there any more.
+pub fn retire(cpu: usize) -> bool {
+    if cpu >= MAX_APS { return true; }
+    STATE.lock(|s| {
+        if s.ents.iter().flatten().any(|e| e.cpu == cpu) { return false; }
+        s.rqs[cpu].job = false;
+        true
+    })
+}
+
+/// Take the next vCPU to run on AP `cpu` off its queue, skipping those for
+/// which `wants` is false: an RT vCPU if one is eligible, else the first
+/// UNDER vCPU of an unparked VM, else the first OVER one. Returns the run
+/// slot and the TSC at which its slice ends.
+pub fn pick(cpu: usize, now: u64, wants: impl Fn(usize) -> bool) -> Option<(usize, u64)> {
+    if cpu >= MAX_APS { return None; }
+    let hz = crate::time::tsc_hz();
+    STATE.lock(|s| {
+        account(s, now, hz);
+        let q = s.rqs[cpu];
+        let rt = super::rt::pick(&q.slots[..q.len], now, &wants);
+        let (k, end) = match rt {
+            Some(p) => p,
+            None => {
+                let mut best: Option<(usize, Prio)> = None;
+                for k in 0..q.len {
+                    let slot = q.slots[k] as usize;
+                    let Some(e) = s.ents[slot] else { continue };
+                    if s.doms.iter().flatten().any(|d| d.vm_id == e.vm_id && d.parked) { continue; }
+                    if super::rt::is_rt(slot) || !wants(slot) { continue; }
+                    let p = if e.credit > 0 { Prio::Under } else { Prio::Over };
+                    if p == Prio::Under { best = Some((k, p)); break; }
+                    if best.is_none() { best = Some((k, p)); }
+                }
+                let (k, _) = best?;
+                // Yield to RT vCPUs homed here at their next period boundary.
+                let mut end = now.wrapping_add(us_to_tsc(TICK_US, hz));
+                if let Some(r) = super::rt::next_release(cpu) { if r > now { end = end.min(r); } }
+                (k, end)
+            }
+        };
+        let q = &mut s.rqs[cpu];
+        let slot = q.remove_at(k);
+        q.current = Some(slot as u8);
+        Some((slot, end))
+    })
+}
+
+/// Put a picked vCPU back at the tail of its queue, charging it `ran_tsc`
+/// TSC ticks of guest time. `ran_tsc == 0` means it was not runnable.
+pub fn put(cpu: usize, slot: usize, ran_tsc: u64, preempted: bool) {
+    if cpu >= MAX_APS || slot >= MAX_ENTITIES { return; }
+    let us = tsc_to_us(ran_tsc, crate::time::tsc_hz());
+    let parked = STATE.lock(|s| {
+        let Some(e) = s.ents[slot].as_mut() else { return None };
+        let rt = super::rt::charge(slot, ran_tsc);
+        if !rt { e.credit = (e.credit - us as i64).max(-(PERIOD_US as i64)); }
+        let vm_id = e.vm_id;
+        let q = &mut s.rqs[cpu];
+        if q.current == Some(slot as u8) { q.current = None; }
+        q.push(slot);
+        if ran_tsc == 0 { return None; }
+        q.slices += 1;
+        q.busy_us = q.busy_us.saturating_add(us);
+        if q.last != Some(slot as u8) { q.switches += 1; q.last = Some(slot as u8); Counter::new(&metrics::SCHED_SWITCHES).inc(); }
+        if preempted { q.preempts += 1; Counter::new(&metrics::SCHED_PREEMPTS).inc(); }
+        Counter::new(&metrics::SCHED_SLICES).inc();
+        let d = domain(s, vm_id)?;
+        d.used_us = d.used_us.saturating_add(us);
+        d.run_us = d.run_us.saturating_add(us);
+        let park = !rt && d.limit_us != 0 && !d.parked && d.used_us >= d.limit_us;
+        if park { d.parked = true; d.parks += 1; }
+        Some((vm_id, park))
+    });
+    if let Some((vm_id, park)) = parked {
+        if park { Counter::new(&metrics::SCHED_CAP_PARKS).inc(); }
+        metrics::vm_sched_account(vm_id, us, park);
+    }
+}
+
+/// vCPUs homed on AP `cpu`.
+pub fn load(cpu: usize) -> usize { STATE.lock(|s| s.ents.iter().flatten().filter(|e| e.cpu == cpu).count()) }
+
+/// Set the weight and cap of `vm_id`. Takes effect at the next period.
+pub fn set_params(vm_id: u64, params: Params) -> Result<(), &'static str> {
+    if params.weight == 0 || params.weight > MAX_WEIGHT { return Err("sched: weight must be 1..65535"); }
+    if params.cap > MAX_CAP { return Err("sched: cap exceeds the CPUs available"); }
+    STATE.lock(|s| {
+        let d = domain_or_insert(s, vm_id).ok_or("sched: too many VMs")?;
+        d.params = params;
+        Ok(())
+    })
+}
+
+pub fn params(vm_id: u64) -> Params {
+    STATE.lock(|s| s.doms.iter().flatten().find(|d| d.vm_id == vm_id).map(|d| d.params).unwrap_or(Params::DEFAULT))
+}
+
+/// Drop the parameters and statistics of a destroyed VM.
+pub fn forget(vm_id: u64) {
+    STATE.lock(|s| { for d in s.doms.iter_mut() { if matches!(d, Some(x) if x.vm_id == vm_id) { *d = None; } } });
+    super::rt::clear(vm_id);
+    metrics::vm_sched_forget(vm_id);
+}
+
+/// Remaining credit of a vCPU, in microseconds.
+pub fn credit(slot: usize) -> Option<i64> {
+    if slot >= MAX_ENTITIES { return None; }
+    STATE.lock(|s| s.ents[slot].map(|e| e.credit))
+}
+
+/// Runqueue statistics of one AP.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuStats {
+    /// vCPUs homed on the AP
+    pub vcpus: usize,
+    /// vCPUs waiting in the queue
+    pub queued: usize,
+    /// Run slot currently in the guest or being examined
+    pub current: Option<usize>,
+    pub slices: u64,
+    pub switches: u64,
+    pub preempts: u64,
+    /// Guest time run on the AP, in microseconds (for utilization deltas)
+    pub busy_us: u64,
+}
+
+pub fn cpu_stats(cpu: usize) -> CpuStats {
+    if cpu >= MAX_APS { return CpuStats::default(); }
+    STATE.lock(|s| {
+        let q = &s.rqs[cpu];
+        CpuStats {
+            vcpus: s.ents.iter().flatten().filter(|e| e.cpu == cpu).count(),
+            queued: q.len,
+            current: q.current.map(|c| c as usize),
+            slices: q.slices,
+            switches: q.switches,
+            preempts: q.preempts,
+            busy_us: q.busy_us,
+        }
+    })
+}
+
+/// Scheduling state of one VM.
+#[derive(Clone, Copy, Debug)]
+pub struct DomStats {
+    pub vm_id: u64,
+    pub params: Params,
+    pub vcpus: usize,
+    /// Sum of the vCPUs' credit, in microseconds
+    pub credit: i64,
+    pub limit_us: u64,
+    pub run_us: u64,
+    pub parks: u64,
+    pub parked: bool,
+}
+
+/// Snapshot of every VM known to the scheduler.
+pub fn domains() -> [Option<DomStats>; MAX_DOMAINS] {
+    STATE.lock(|s| {
+        let mut out = [None; MAX_DOMAINS];
+        for (o, d) in out.iter_mut().zip(s.doms.iter()) {
+            let Some(d) = d else { continue };
+            let mine = || s.ents.iter().flatten().filter(|e| e.vm_id == d.vm_id);
+            *o = Some(DomStats {
+                vm_id: d.vm_id, params: d.params,
+                vcpus: mine().count(), credit: mine().map(|e| e.credit).sum(),
+                limit_us: d.limit_us, run_us: d.run_us, parks: d.parks, parked: d.parked,
+            });
+        }
+        out
+    })
+}
+
+/// True while an AP job is serving `cpu`'s queue.
+pub fn serving(cpu: usize) -> bool { cpu < MAX_APS && STATE.lock(|s| s.rqs[cpu].job) }
+
+/// Clear the per-queue counters (with `metrics clear`).
+pub fn reset_stats() {
+    STATE.lock(|s| {
+        for q in s.rqs.iter_mut() { q.slices = 0; q.switches = 0; q.preempts = 0; }
+        for d in s.doms.iter_mut().flatten() { d.run_us = 0; d.parks = 0; }
+    });
+}
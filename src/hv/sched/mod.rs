@@ -0,0 +1,32 @@
+#![allow(dead_code)]
+
+//! CPU scheduling policy shared by guest vCPUs and hypervisor-internal work.
+//!
+//! Cores can be reserved for real-time guests; nothing else is scheduled on
+//! them. Guests with a reservation run in the `rt` class, the other vCPUs
+//! share the remaining cores under the `credit` scheduler, and hypervisor
+//! housekeeping runs in the `background` class. Per-VM CPU `affinity`
+//! restricts where both classes place a VM's vCPUs.
+
+pub mod affinity;
+pub mod background;
+pub mod credit;
+pub mod rt;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Bitmask of logical CPUs (by APIC ID, 0..63) reserved for RT vCPUs.
+static RT_RESERVED: AtomicU64 = AtomicU64::new(0);
+
+/// Replace the RT-reserved core mask.
+pub fn set_rt_reserved(mask: u64) { RT_RESERVED.store(mask, Ordering::Relaxed); }
+
+pub fn rt_reserved() -> u64 { RT_RESERVED.load(Ordering::Relaxed) }
+
+/// True if `cpu` is reserved for real-time vCPUs.
+pub fn is_rt_reserved(cpu: u32) -> bool { cpu < 64 && (rt_reserved() & (1u64 << cpu)) != 0 }
+
+/// Initial APIC ID of the executing CPU (CPUID.1:EBX[31:24]).
+pub fn current_cpu() -> u32 {
+    crate::arch::x86::cpuid::cpuid(crate::arch::x86::cpuid::leaf::BASIC_FEATURES, 0).ebx >> 24
+}
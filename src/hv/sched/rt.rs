@@ -0,0 +1,256 @@
+#![allow(dead_code)]
+
+//! Real-time vCPU class: EDF scheduling of (period, budget) reservations.
+//!
+//! A VM with a reservation gets `budget` microseconds of CPU time in every
+//! `period` for each of its vCPUs. On an AP, an RT vCPU with budget left
+//! always runs before credit-scheduled vCPUs, earliest deadline first; the
+//! deadline is the end of the vCPU's current period. Budgets are refilled at
+//! period boundaries and are hard limits, so an RT guest cannot take more
+//! than it reserved. Credit slices on an AP hosting RT vCPUs end at the next
+//! RT period boundary, which bounds how long a released RT vCPU waits.
+//!
+//! Admission is partitioned EDF: the reservations homed on one AP may use at
+//! most `MAX_UTIL_PPM` of it. RT vCPUs are placed on RT-reserved cores when
+//! any are reserved, otherwise on any VMX-capable AP, first fit, within the
+//! VM's affinity mask. `set` checks the current placement of a running VM
+//! or a trial placement of one that is not running; `hv::run` repeats the
+//! check when the VM starts.
+
+use crate::arch::x86::ap::{self, MAX_APS};
+use crate::obs::metrics::{self, Counter};
+use crate::util::spinlock::SpinLock;
+
+use super::credit::MAX_ENTITIES;
+
+pub const MAX_RESV: usize = 16;
+/// EDF utilization bound per AP, in parts per million; the rest is left for
+/// exit handling and credit vCPUs.
+pub const MAX_UTIL_PPM: u64 = 950_000;
+pub const MIN_PERIOD_US: u64 = 100;
+pub const MAX_PERIOD_US: u64 = 1_000_000;
+
+/// CPU time reserved for each vCPU of a VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reservation { pub period_us: u64, pub budget_us: u64 }
+
+impl Reservation {
+    /// Share of one CPU per vCPU, in parts per million.
+    pub fn util_ppm(&self) -> u64 { if self.period_us == 0 { 0 } else { self.budget_us * 1_000_000 / self.period_us } }
+}
+
+#[derive(Clone, Copy)]
+struct Resv { vm_id: u64, r: Reservation }
+
+#[derive(Clone, Copy)]
+struct RtEnt {
+    vm_id: u64,
+    cpu: usize,
+    util_ppm: u64,
+    period: u64,
+    budget: u64,
+    remaining: u64,
+    deadline: u64,
+    runs: u64,
+    /// Budget used up before the period ended
+    exhausted: u64,
+    /// Period ended with budget left while the vCPU wanted to run
+    misses: u64,
+}
+
+struct State {
+    resv: [Option<Resv>; MAX_RESV],
+    ents: [Option<RtEnt>; MAX_ENTITIES],
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State { resv: [None; MAX_RESV], ents: [None; MAX_ENTITIES] });
+
+fn us_to_tsc(us: u64) -> u64 { ((crate::time::tsc_hz() as u128) * (us as u128) / 1_000_000) as u64 }
+
+pub fn reservation(vm_id: u64) -> Option<Reservation> {
+    STATE.lock(|s| s.resv.iter().flatten().find(|r| r.vm_id == vm_id).map(|r| r.r))
+}
+
+/// True if the vCPU in run slot `slot` belongs to the RT class.
+pub fn is_rt(slot: usize) -> bool { slot < MAX_ENTITIES && STATE.lock(|s| s.ents[slot].is_some()) }
+
+/// RT utilization homed on `cpu`, ignoring the vCPUs of `except_vm`.
+fn util_on(s: &State, cpu: usize, except_vm: u64) -> u64 {
+    s.ents.iter().flatten().filter(|e| e.cpu == cpu && e.vm_id != except_vm).map(|e| e.util_ppm).sum()
+}
+
+/// RT utilization homed on AP `cpu`, in parts per million.
+pub fn cpu_util_ppm(cpu: usize) -> u64 { STATE.lock(|s| util_on(s, cpu, u64::MAX)) }
+
+/// APs RT vCPUs may be homed on: RT-reserved cores if any are reserved,
+/// inside the `affinity` mask.
+fn eligible(cpu: usize, apic: u32, need_root: bool, affinity: u64) -> bool {
+    let rt_cores = super::rt_reserved() != 0;
+    (!need_root || crate::arch::x86::vm::percpu::is_root(cpu))
+        && (!rt_cores || super::is_rt_reserved(apic))
+        && super::affinity::allows(affinity, apic)
+}
+
+/// First-fit placement of `vcpus` vCPUs of `vm_id`, each needing `util`
+/// ppm, on top of the RT load already homed on each AP. Fills `cpus`.
+pub fn first_fit(vm_id: u64, util: u64, vcpus: usize, need_root: bool, affinity: u64, cpus: &mut [usize]) -> Result<(), &'static str> {
+    let mut load = [u64::MAX; MAX_APS];
+    STATE.lock(|s| ap::for_each_online(|cpu, apic, _| { if eligible(cpu, apic, need_root, affinity) { load[cpu] = util_on(s, cpu, vm_id); } }));
+    for c in cpus.iter_mut().take(vcpus) {
+        let Some(cpu) = (0..MAX_APS).find(|&cpu| load[cpu] != u64::MAX && load[cpu] + util <= MAX_UTIL_PPM) else {
+            Counter::new(&metrics::SCHED_RT_REJECTS).inc();
+            return Err("rt: reservation does not fit on the available CPUs");
+        };
+        *c = cpu;
+        load[cpu] += util;
+    }
+    Ok(())
+}
+
+/// Set (or replace) the reservation of `vm_id` after the admission test.
+/// `vcpus` is the VM's vCPU count. Running vCPUs pick up the new
+/// parameters at their next period.
+pub fn set(vm_id: u64, r: Reservation, vcpus: usize, affinity: u64) -> Result<(), &'static str> {
+    if r.period_us < MIN_PERIOD_US || r.period_us > MAX_PERIOD_US { return Err("rt: period must be 100..1000000 us"); }
+    if r.budget_us == 0 || r.budget_us > r.period_us { return Err("rt: budget must be 1..period us"); }
+    let util = r.util_ppm();
+    if util > MAX_UTIL_PPM { Counter::new(&metrics::SCHED_RT_REJECTS).inc(); return Err("rt: budget/period exceeds the per-CPU bound"); }
+    // Running vCPUs are tested where they are homed.
+    let homed = STATE.lock(|s| {
+        let mut over = false;
+        let mut any = false;
+        for e in s.ents.iter().flatten().filter(|e| e.vm_id == vm_id) {
+            any = true;
+            let mine = s.ents.iter().flatten().filter(|x| x.vm_id == vm_id && x.cpu == e.cpu).count() as u64;
+            if util_on(s, e.cpu, vm_id) + util * mine > MAX_UTIL_PPM { over = true; }
+        }
+        if any { Some(over) } else { None }
+    });
+    match homed {
+        Some(true) => { Counter::new(&metrics::SCHED_RT_REJECTS).inc(); return Err("rt: reservation does not fit on the CPUs the VM runs on"); }
+        Some(false) => {}
+        None => {
+            let mut cpus = [0usize; MAX_ENTITIES];
+            first_fit(vm_id, util, vcpus.min(MAX_ENTITIES), false, affinity, &mut cpus)?;
+        }
+    }
+    let (period, budget) = (us_to_tsc(r.period_us), us_to_tsc(r.budget_us));
+    STATE.lock(|s| {
+        match s.resv.iter_mut().flatten().find(|x| x.vm_id == vm_id) {
+            Some(x) => x.r = r,
+            None => *s.resv.iter_mut().find(|x| x.is_none()).ok_or("rt: too many reservations")? = Some(Resv { vm_id, r }),
+        }
+        for e in s.ents.iter_mut().flatten().filter(|e| e.vm_id == vm_id) {
+            e.util_ppm = util;
+            e.period = period;
+            e.budget = budget;
+            e.remaining = e.remaining.min(budget);
+        }
+        Ok(())
+    })
+}
+
+/// Drop the reservation of `vm_id`; its vCPUs return to the credit class.
+pub fn clear(vm_id: u64) -> bool {
+    STATE.lock(|s| {
+        let mut found = false;
+        for x in s.resv.iter_mut() { if matches!(x, Some(r) if r.vm_id == vm_id) { *x = None; found = true; } }
+        for e in s.ents.iter_mut() { if matches!(e, Some(x) if x.vm_id == vm_id) { *e = None; } }
+        found
+    })
+}
+
+/// Enter a newly attached vCPU into the RT class if its VM has a
+/// reservation. Its first period starts now.
+pub fn attach(slot: usize, vm_id: u64, cpu: usize) {
+    let Some(r) = reservation(vm_id) else { return };
+    if slot >= MAX_ENTITIES { return; }
+    let (period, budget) = (us_to_tsc(r.period_us), us_to_tsc(r.budget_us));
+    let now = crate::time::rdtsc();
+    STATE.lock(|s| s.ents[slot] = Some(RtEnt {
+        vm_id, cpu, util_ppm: r.util_ppm(), period, budget, remaining: budget,
+        deadline: now.wrapping_add(period), runs: 0, exhausted: 0, misses: 0,
+    }));
+}
+
+/// Follow a vCPU moved to AP `cpu`.
+pub fn rehome(slot: usize, cpu: usize) {
+    if slot < MAX_ENTITIES { STATE.lock(|s| { if let Some(e) = s.ents[slot].as_mut() { e.cpu = cpu; } }); }
+}
+
+pub fn detach(slot: usize) {
+    if slot < MAX_ENTITIES { STATE.lock(|s| s.ents[slot] = None); }
+}
+
+/// Start a new period for `e` if its deadline has passed. `wants` reports
+/// whether the vCPU has work, to count a miss when budget is left over.
+fn replenish(e: &mut RtEnt, now: u64, wants: impl FnOnce() -> bool) {
+    if e.period == 0 || now < e.deadline { return; }
+    if e.remaining != 0 && wants() { e.misses += 1; Counter::new(&metrics::SCHED_RT_MISSES).inc(); }
+    let periods = (now - e.deadline) / e.period + 1;
+    e.deadline = e.deadline.wrapping_add(periods * e.period);
+    e.remaining = e.budget;
+}
+
+/// Earliest-deadline RT vCPU among the queued run slots that has budget and
+/// wants to run. Returns its index in `queued` and the TSC its slice ends.
+pub fn pick(queued: &[u8], now: u64, wants: &impl Fn(usize) -> bool) -> Option<(usize, u64)> {
+    STATE.lock(|s| {
+        let mut best: Option<(usize, u64)> = None;
+        for (k, &slot) in queued.iter().enumerate() {
+            let Some(e) = s.ents[slot as usize].as_mut() else { continue };
+            replenish(e, now, || wants(slot as usize));
+            if e.remaining == 0 || best.is_some_and(|(_, d)| e.deadline >= d) || !wants(slot as usize) { continue; }
+            best = Some((k, e.deadline));
+        }
+        let (k, deadline) = best?;
+        let e = s.ents[queued[k] as usize].as_mut()?;
+        e.runs += 1;
+        Some((k, now.wrapping_add(e.remaining.min(deadline - now))))
+    })
+}
+
+/// Next RT period boundary on `cpu`, where a credit slice must yield.
+pub fn next_release(cpu: usize) -> Option<u64> {
+    STATE.lock(|s| s.ents.iter().flatten().filter(|e| e.cpu == cpu).map(|e| e.deadline).min())
+}
+
+/// Charge `ran_tsc` of guest time to an RT vCPU. Returns false if the slot
+/// is not in the RT class.
+pub fn charge(slot: usize, ran_tsc: u64) -> bool {
+    if slot >= MAX_ENTITIES { return false; }
+    STATE.lock(|s| {
+        let Some(e) = s.ents[slot].as_mut() else { return false };
+        if ran_tsc == 0 { return true; }
+        e.remaining = e.remaining.saturating_sub(ran_tsc);
+        if e.remaining == 0 { e.exhausted += 1; Counter::new(&metrics::SCHED_RT_EXHAUSTED).inc(); }
+        true
+    })
+}
+
+/// Reservation and counters of one VM.
+#[derive(Clone, Copy, Debug)]
+pub struct RtStats {
+    pub vm_id: u64,
+    pub resv: Reservation,
+    /// vCPUs currently in the RT class
+    pub vcpus: usize,
+    pub runs: u64,
+    pub exhausted: u64,
+    pub misses: u64,
+}
+
+pub fn stats() -> [Option<RtStats>; MAX_RESV] {
+    STATE.lock(|s| {
+        let mut out = [None; MAX_RESV];
+        for (o, r) in out.iter_mut().zip(s.resv.iter()) {
+            let Some(r) = r else { continue };
+            let mut st = RtStats { vm_id: r.vm_id, resv: r.r, vcpus: 0, runs: 0, exhausted: 0, misses: 0 };
+            for e in s.ents.iter().flatten().filter(|e| e.vm_id == r.vm_id) {
+                st.vcpus += 1; st.runs += e.runs; st.exhausted += e.exhausted; st.misses += e.misses;
+            }
+            *o = Some(st);
+        }
+        out
+    })
+}
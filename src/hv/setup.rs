@@ -0,0 +1,227 @@
+#![allow(dead_code)]
+
+//! Boot-time setup menu.
+//!
+//! For machines where the UEFI console is all there is, `run` offers a few
+//! settings before the hypervisor brings anything up. It waits `timeout`
+//! seconds for `s` (or F2); without it the boot goes on untouched. The menu
+//! itself changes one thing per key and saves it at once:
+//!
+//!   1  IOMMU enforcement on/off (`ZerovisorSetup`, applied by `enforce`)
+//!   2  language, cycling auto, en, ja, zh and a loaded catalog (`ZerovisorLang`)
+//!   3  console, cycling UEFI only and serial on com1..com4 (`ZerovisorConsole`)
+//!   4  recovery CLI: straight to the prompt, skipping the probes, AP
+//!      bring-up and IOMMU enforcement, so a setting that stops the boot
+//!      can be undone
+//!
+//! Enforcement turns on DMA remapping with the saved IOMMU layout, and
+//! interrupt remapping in strict mode. A device with no assignment in that
+//! layout loses DMA, firmware's disk and USB controllers included, which is
+//! why the menu runs before it.
+
+use core::fmt::Write;
+use uefi::prelude::Boot;
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+use uefi::table::SystemTable;
+
+use crate::i18n::{self, Lang};
+
+/// Seconds the prompt waits unless the saved settings say otherwise
+pub const DEFAULT_TIMEOUT: u8 = 3;
+pub const MAX_TIMEOUT: u8 = 30;
+/// An open menu left alone this long continues the boot
+pub const MENU_IDLE_SECS: u32 = 60;
+
+const VAR_NS: VariableVendor = VariableVendor::GLOBAL_VARIABLE;
+const MAGIC: &[u8; 4] = b"ZVSU";
+const VERSION: u8 = 1;
+const SETTINGS_LEN: usize = 8;
+const FLAG_IOMMU_ENFORCE: u8 = 1 << 0;
+
+/// What the boot does after the menu.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Boot,
+    Recovery,
+}
+
+/// Saved settings of the menu's own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub iommu_enforce: bool,
+    /// Prompt wait in seconds; 0 skips the prompt
+    pub timeout: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Settings { Settings { iommu_enforce: false, timeout: DEFAULT_TIMEOUT } }
+}
+
+impl Settings {
+    fn to_bytes(self) -> [u8; SETTINGS_LEN] {
+        let mut b = [0u8; SETTINGS_LEN];
+        b[0..4].copy_from_slice(MAGIC);
+        b[4] = VERSION;
+        b[5] = if self.iommu_enforce { FLAG_IOMMU_ENFORCE } else { 0 };
+        b[6] = self.timeout;
+        b
+    }
+
+    fn parse(b: &[u8]) -> Option<Settings> {
+        if b.len() < SETTINGS_LEN || &b[0..4] != MAGIC || b[4] != VERSION { return None; }
+        Some(Settings { iommu_enforce: b[5] & FLAG_IOMMU_ENFORCE != 0, timeout: b[6].min(MAX_TIMEOUT) })
+    }
+}
+
+/// Saved settings, or the defaults when there are none or they are unreadable.
+pub fn load(system_table: &SystemTable<Boot>) -> Settings {
+    let mut buf = [0u8; SETTINGS_LEN];
+    match system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorSetup"), &VAR_NS, &mut buf) {
+        Ok((d, _)) => Settings::parse(d).unwrap_or_default(),
+        Err(_) => Settings::default(),
+    }
+}
+
+pub fn save(system_table: &SystemTable<Boot>, s: &Settings) -> Result<(), &'static str> {
+    if s.timeout > MAX_TIMEOUT { return Err("setup: timeout out of range (0..30)"); }
+    let attrs = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::NON_VOLATILE;
+    system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorSetup"), &VAR_NS, attrs, &s.to_bytes())
+        .map_err(|_| "setup: set_variable failed")
+}
+
+/// A key from the UEFI console or the serial line, as ASCII. Escape is
+/// 0x1B and F2 reads as `s`.
+fn key(system_table: &mut SystemTable<Boot>) -> Option<u8> {
+    match system_table.stdin().read_key() {
+        Ok(Some(Key::Printable(c))) => {
+            let c: char = c.into();
+            if c.is_ascii() { Some(c as u8) } else { None }
+        }
+        Ok(Some(Key::Special(s))) if s == ScanCode::ESCAPE => Some(0x1B),
+        Ok(Some(Key::Special(s))) if s == ScanCode::FUNCTION_2 => Some(b's'),
+        Ok(Some(Key::Special(_))) => None,
+        _ => crate::obs::console::read_byte(),
+    }
+}
+
+/// Wait up to `secs` seconds for a key.
+fn wait_key(system_table: &mut SystemTable<Boot>, secs: u32) -> Option<u8> {
+    for _ in 0..secs * 100 {
+        if let Some(k) = key(system_table) { return Some(k); }
+        system_table.boot_services().stall(10_000);
+    }
+    None
+}
+
+/// Index of a language in the menu's cycle: auto, en, ja, zh, catalog.
+fn lang_index(l: Option<Lang>) -> u8 {
+    match l { None => 0, Some(Lang::En) => 1, Some(Lang::Ja) => 2, Some(Lang::Zh) => 3, Some(Lang::Ext) => 4 }
+}
+
+fn next_lang(l: Option<Lang>) -> Option<Lang> {
+    let last = if i18n::ext_tag().is_empty() { 3 } else { 4 };
+    match (lang_index(l) + 1) % (last + 1) { 1 => Some(Lang::En), 2 => Some(Lang::Ja), 3 => Some(Lang::Zh), 4 => Some(Lang::Ext), _ => None }
+}
+
+/// Move the console one step along UEFI only, com1, .., com4, UEFI only,
+/// skipping ports with no UART behind them, and save the result.
+fn next_console(system_table: &SystemTable<Boot>) -> Result<(), &'static str> {
+    use crate::arch::x86::uart::COM;
+    let (start, baud) = match crate::obs::console::serial() {
+        Some((port, baud)) => (COM.iter().position(|&p| p == port).map_or(COM.len(), |i| i + 1), baud),
+        None => (0, crate::obs::console::DEFAULT_BAUD),
+    };
+    crate::obs::console::disable();
+    for &port in &COM[start.min(COM.len())..] {
+        if crate::obs::console::enable(port, baud).is_ok() { break; }
+    }
+    crate::obs::console::save(system_table)
+}
+
+fn render(system_table: &mut SystemTable<Boot>, s: &Settings, note: &str) {
+    let lang = i18n::current_override(system_table);
+    let shown = i18n::lang_tag(i18n::detect_lang(system_table));
+    let out = system_table.stdout();
+    let _ = out.write_str("\r\nZerovisor setup\r\n");
+    let _ = write!(out, "  1  IOMMU enforcement  {}\r\n", if s.iommu_enforce { "on" } else { "off" });
+    match lang {
+        None => { let _ = write!(out, "  2  Language           auto ({})\r\n", shown); }
+        Some(_) => { let _ = write!(out, "  2  Language           {}\r\n", shown); }
+    }
+    match crate::obs::console::serial() {
+        Some((port, baud)) => match crate::arch::x86::uart::port_name(port) {
+            Some(name) => { let _ = write!(out, "  3  Console            serial {} {}\r\n", name, baud); }
+            None => { let _ = write!(out, "  3  Console            serial {:#x} {}\r\n", port, baud); }
+        },
+        None => { let _ = out.write_str("  3  Console            uefi\r\n"); }
+    }
+    let _ = out.write_str("  4  Recovery CLI\r\n  Enter  continue boot\r\n");
+    if !note.is_empty() { let _ = write!(out, "{}\r\n", note); }
+}
+
+/// The menu proper. Returns once the operator continues or leaves it idle.
+fn menu(system_table: &mut SystemTable<Boot>) -> Outcome {
+    let mut s = load(system_table);
+    let mut note = "";
+    loop {
+        render(system_table, &s, note);
+        note = "";
+        match wait_key(system_table, MENU_IDLE_SECS) {
+            None | Some(b'\r') | Some(b'\n') | Some(0x1B) => return Outcome::Boot,
+            Some(b'1') => {
+                s.iommu_enforce = !s.iommu_enforce;
+                if let Err(e) = save(system_table, &s) { note = e; }
+            }
+            Some(b'2') => {
+                i18n::set_lang_override(next_lang(i18n::current_override(system_table)));
+                i18n::save_lang_override(system_table);
+            }
+            Some(b'3') => {
+                if let Err(e) = next_console(system_table) { note = e; }
+            }
+            Some(b'4') => return Outcome::Recovery,
+            Some(_) => {}
+        }
+    }
+}
+
+/// Offer the menu and run it if asked for. Called early in boot, before
+/// anything the menu can turn off.
+pub fn run(system_table: &mut SystemTable<Boot>) -> Outcome {
+    let timeout = load(system_table).timeout;
+    if timeout == 0 { return Outcome::Boot; }
+    let _ = system_table.stdin().reset(false);
+    let mut asked = false;
+    for left in (1..=timeout).rev() {
+        let _ = write!(system_table.stdout(), "\rPress s for setup ({}) ", left);
+        match wait_key(system_table, 1) {
+            Some(b's') | Some(b'S') => { asked = true; break; }
+            Some(b'\r') | Some(b'\n') | Some(0x1B) => break,
+            _ => {}
+        }
+    }
+    let _ = system_table.stdout().write_str("\r\n");
+    if asked { menu(system_table) } else { Outcome::Boot }
+}
+
+/// Bring up DMA and interrupt remapping if the saved settings ask for it:
+/// restore the saved IOMMU layout, apply it with translation on, then turn
+/// interrupt remapping on in strict mode. Needs the units probed first.
+pub fn enforce(system_table: &mut SystemTable<Boot>) {
+    if !load(system_table).iommu_enforce { return; }
+    match crate::iommu::blob::load_var(system_table) {
+        Ok(_) | Err("iommu: no saved state") => {}
+        Err(e) => crate::log!(Warn, "setup", "saved IOMMU layout not restored: {}", e),
+    }
+    if crate::firmware::acpi::find_dmar(system_table).is_some() {
+        crate::iommu::vtd::apply_safe(system_table);
+        if let Err(e) = crate::iommu::vtd_ir::enable(system_table, true) {
+            crate::log!(Warn, "setup", "interrupt remapping not enabled: {}", e);
+        }
+    } else if crate::firmware::acpi::find_ivrs(system_table).is_some() {
+        crate::iommu::amdv::enable_translation_all(system_table);
+    } else {
+        crate::log!(Warn, "setup", "IOMMU enforcement is on but no IOMMU was found");
+    }
+}
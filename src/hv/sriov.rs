@@ -0,0 +1,424 @@
+#![allow(dead_code)]
+
+//! SR-IOV virtual functions and their passthrough to guests.
+//!
+//! `enable` programs the SR-IOV extended capability of a physical function
+//! (NumVFs, VF Enable, VF memory space) and records where its VFs and their
+//! BARs live. `attach` hands one VF to a VM: the VF joins an IOMMU domain
+//! owned by the VM whose only mapping is the VM's RAM at guest-physical 0,
+//! bus mastering is turned on, and the VF appears on the guest's PCI bus with
+//! its own BAR layout. Guest BAR accesses trap and are forwarded to the VF's
+//! host BAR. VFs have no INTx, so the guest sees interrupts only once MSI-X
+//! is modelled. Forwarded accesses are counted per attached VF.
+//!
+//! `attach_function` does the same for a function without SR-IOV, such as
+//! an NVMe controller, which then goes to one VM whole.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::iommu::{mmio_read16, mmio_read32, mmio_read8, mmio_write16, mmio_write32};
+use crate::util::spinlock::SpinLock;
+
+pub const EXT_CAP_SRIOV: u16 = 0x0010;
+pub const MAX_PFS: usize = 8;
+pub const MAX_ATTACHED: usize = 16;
+
+// Offsets within the SR-IOV capability
+const SRIOV_CTRL: usize = 0x08;
+const SRIOV_TOTAL_VFS: usize = 0x0E;
+const SRIOV_NUM_VFS: usize = 0x10;
+const SRIOV_VF_OFFSET: usize = 0x14;
+const SRIOV_VF_STRIDE: usize = 0x16;
+const SRIOV_VF_DID: usize = 0x1A;
+const SRIOV_VF_BAR0: usize = 0x24;
+const CTRL_VF_ENABLE: u16 = 1 << 0;
+const CTRL_VF_MSE: u16 = 1 << 3;
+
+/// PCI bus/device/function with segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bdf { pub seg: u16, pub bus: u8, pub dev: u8, pub func: u8 }
+
+impl Bdf {
+    pub fn rid(self) -> u16 { (self.bus as u16) << 8 | (self.dev as u16) << 3 | self.func as u16 }
+
+    pub fn from_rid(seg: u16, rid: u16) -> Bdf { Bdf { seg, bus: (rid >> 8) as u8, dev: ((rid >> 3) & 0x1F) as u8, func: (rid & 7) as u8 } }
+
+    /// Parse `[seg:]bus:dev.func` in hex.
+    pub fn parse(s: &str) -> Option<Bdf> {
+        let (head, func) = s.rsplit_once('.')?;
+        let mut parts = head.rsplit(':');
+        let dev = u8::from_str_radix(parts.next()?, 16).ok()?;
+        let bus = u8::from_str_radix(parts.next()?, 16).ok()?;
+        let seg = match parts.next() { Some(p) => u16::from_str_radix(p, 16).ok()?, None => 0 };
+        if parts.next().is_some() { return None; }
+        let func = u8::from_str_radix(func, 16).ok()?;
+        if dev > 31 || func > 7 { return None; }
+        Some(Bdf { seg, bus, dev, func })
+    }
+
+    /// Write as `ssss:bb:dd.f`; returns bytes written.
+    pub fn fmt(self, out: &mut [u8]) -> usize {
+        const H: &[u8; 16] = b"0123456789abcdef";
+        let mut n = 0;
+        for sh in [12u32, 8, 4, 0] { out[n] = H[((self.seg >> sh) & 0xF) as usize]; n += 1; }
+        out[n] = b':'; n += 1;
+        out[n] = H[(self.bus >> 4) as usize]; out[n + 1] = H[(self.bus & 0xF) as usize]; n += 2;
+        out[n] = b':'; n += 1;
+        out[n] = H[(self.dev >> 4) as usize]; out[n + 1] = H[(self.dev & 0xF) as usize]; n += 2;
+        out[n] = b'.'; n += 1;
+        out[n] = H[self.func as usize]; n += 1;
+        n
+    }
+}
+
+/// A physical function with VFs enabled.
+#[derive(Clone, Copy, Debug)]
+pub struct Pf {
+    pub bdf: Bdf,
+    pub vendor: u16,
+    /// PCI base class of the PF (03h for a GPU)
+    pub class: u8,
+    pub vf_device: u16,
+    pub total_vfs: u16,
+    pub num_vfs: u16,
+    pub first_offset: u16,
+    pub stride: u16,
+    /// Host base of VF 0's BARs; VF n adds n * size
+    pub bar_base: [u64; 6],
+    /// Per-VF BAR size (0 = not implemented or upper half of a 64-bit BAR)
+    pub bar_size: [u64; 6],
+    pub bar64: [bool; 6],
+    cfg: usize,
+    cap: usize,
+}
+
+impl Pf {
+    pub fn vf_bdf(&self, n: u16) -> Bdf {
+        Bdf::from_rid(self.bdf.seg, self.bdf.rid().wrapping_add(self.first_offset).wrapping_add(n.wrapping_mul(self.stride)))
+    }
+
+    /// VF number of `bdf`, if it is one of ours.
+    pub fn vf_index(&self, bdf: Bdf) -> Option<u16> {
+        (0..self.num_vfs).find(|&n| self.vf_bdf(n) == bdf)
+    }
+}
+
+/// A VF, or a whole function, handed to a VM.
+#[derive(Clone, Copy, Debug)]
+pub struct Attached {
+    pub vm_id: u64,
+    pub vf: Bdf,
+    /// PF of the VF; the function itself when passed through whole
+    pub pf: Bdf,
+    pub domid: u16,
+    /// Device number on the guest bus
+    pub guest_dev: u8,
+    /// Host BARs of this VF
+    pub bar_host: [u64; 6],
+    pub bar_size: [u64; 6],
+    cfg: usize,
+}
+
+static PFS: SpinLock<[Option<Pf>; MAX_PFS]> = SpinLock::new([None; MAX_PFS]);
+static ATTACHED: SpinLock<[Option<Attached>; MAX_ATTACHED]> = SpinLock::new([None; MAX_ATTACHED]);
+/// Guest BAR accesses forwarded, per `ATTACHED` slot
+const NO_OPS: AtomicU64 = AtomicU64::new(0);
+static OPS: [AtomicU64; MAX_ATTACHED] = [NO_OPS; MAX_ATTACHED];
+
+/// TotalVFs and NumVFs of the function whose config space is at `cfg`, if
+/// it has an SR-IOV capability.
+pub fn probe(cfg: usize) -> Option<(u16, u16)> {
+    let cap = crate::iommu::pcicap::find_ext_cap(cfg, EXT_CAP_SRIOV)?;
+    Some((mmio_read16(cfg + cap + SRIOV_TOTAL_VFS), mmio_read16(cfg + cap + SRIOV_NUM_VFS)))
+}
+
+/// Size the VF BARs (per VF) by the all-ones probe; VF memory space must be off.
+fn size_vf_bars(cfg: usize, cap: usize) -> ([u64; 6], [u64; 6], [bool; 6]) {
+    let (mut base, mut size, mut is64) = ([0u64; 6], [0u64; 6], [false; 6]);
+    let mut i = 0;
+    while i < 6 {
+        let off = cfg + cap + SRIOV_VF_BAR0 + i * 4;
+        let lo = mmio_read32(off);
+        let wide = (lo & 0b110) == 0b100 && i < 5;
+        mmio_write32(off, 0xFFFF_FFFF);
+        let mut mask = (mmio_read32(off) & !0xF) as u64;
+        mmio_write32(off, lo);
+        let mut b = (lo & !0xF) as u64;
+        if wide {
+            let hi = mmio_read32(off + 4);
+            mmio_write32(off + 4, 0xFFFF_FFFF);
+            mask |= (mmio_read32(off + 4) as u64) << 32;
+            mmio_write32(off + 4, hi);
+            b |= (hi as u64) << 32;
+        } else {
+            mask |= 0xFFFF_FFFF_0000_0000;
+        }
+        if mask != 0xFFFF_FFFF_0000_0000 && (lo & 1) == 0 {
+            base[i] = b;
+            size[i] = (!mask).wrapping_add(1);
+            is64[i] = wide;
+        }
+        i += if wide { 2 } else { 1 };
+    }
+    (base, size, is64)
+}
+
+/// Enable `num_vfs` VFs on the PF at `bdf`. BARs must have been assigned by firmware.
+pub fn enable(system_table: &mut SystemTable<Boot>, bdf: Bdf, num_vfs: u16) -> Result<Pf, &'static str> {
+    let cfg = crate::iommu::ecam_cfg_base(system_table, bdf.seg, bdf.bus, bdf.dev, bdf.func).ok_or("sriov: no ECAM window for device")?;
+    let vendor = mmio_read16(cfg);
+    if vendor == 0xFFFF { return Err("sriov: no device at address"); }
+    let cap = crate::iommu::pcicap::find_ext_cap(cfg, EXT_CAP_SRIOV).ok_or("sriov: device has no SR-IOV capability")?;
+    if ATTACHED.lock(|t| t.iter().flatten().any(|a| a.pf == bdf)) { return Err("sriov: VFs of this PF are attached to VMs"); }
+    let total = mmio_read16(cfg + cap + SRIOV_TOTAL_VFS);
+    if num_vfs == 0 || num_vfs > total { return Err("sriov: numvfs must be 1..TotalVFs"); }
+
+    let ctrl = mmio_read16(cfg + cap + SRIOV_CTRL);
+    if ctrl & CTRL_VF_ENABLE != 0 {
+        mmio_write16(cfg + cap + SRIOV_CTRL, ctrl & !(CTRL_VF_ENABLE | CTRL_VF_MSE));
+        // VF Enable may not be set again within 1 s of being cleared.
+        let _ = system_table.boot_services().stall(1_000_000);
+    }
+    mmio_write16(cfg + cap + SRIOV_NUM_VFS, num_vfs);
+    let (bar_base, bar_size, bar64) = size_vf_bars(cfg, cap);
+    let mut pf = Pf {
+        bdf, vendor, class: mmio_read8(cfg + crate::hv::vpci::CFG_CLASS), vf_device: mmio_read16(cfg + cap + SRIOV_VF_DID), total_vfs: total, num_vfs,
+        first_offset: mmio_read16(cfg + cap + SRIOV_VF_OFFSET), stride: mmio_read16(cfg + cap + SRIOV_VF_STRIDE),
+        bar_base, bar_size, bar64, cfg, cap,
+    };
+    if pf.bar_size.iter().all(|&s| s == 0) { return Err("sriov: PF reports no VF BARs"); }
+    if (0..6).any(|i| pf.bar_size[i] != 0 && pf.bar_base[i] == 0) { return Err("sriov: firmware did not assign VF BARs"); }
+    let last = pf.vf_bdf(num_vfs - 1);
+    if crate::iommu::ecam_cfg_base(system_table, last.seg, last.bus, last.dev, last.func).is_none() {
+        return Err("sriov: VF bus range not covered by MCFG");
+    }
+    mmio_write16(cfg + cap + SRIOV_CTRL, ctrl | CTRL_VF_ENABLE | CTRL_VF_MSE);
+    // VFs may not be accessed for 100 ms after VF Enable.
+    let _ = system_table.boot_services().stall(100_000);
+    pf.num_vfs = mmio_read16(cfg + cap + SRIOV_NUM_VFS);
+    PFS.lock(|t| {
+        let slot = t.iter().position(|p| matches!(p, Some(p) if p.bdf == bdf)).or_else(|| t.iter().position(|p| p.is_none()));
+        match slot { Some(i) => { t[i] = Some(pf); Ok(()) } None => Err("sriov: too many PFs") }
+    })?;
+    crate::obs::log::info(system_table, "sriov", "VFs enabled");
+    Ok(pf)
+}
+
+/// Disable the VFs of the PF at `bdf`. Fails while any is attached.
+pub fn disable(bdf: Bdf) -> Result<(), &'static str> {
+    if ATTACHED.lock(|t| t.iter().flatten().any(|a| a.pf == bdf)) { return Err("sriov: VFs of this PF are attached to VMs"); }
+    let pf = PFS.lock(|t| {
+        let i = t.iter().position(|p| matches!(p, Some(p) if p.bdf == bdf))?;
+        t[i].take()
+    }).ok_or("sriov: VFs not enabled on this PF")?;
+    let ctrl = mmio_read16(pf.cfg + pf.cap + SRIOV_CTRL);
+    mmio_write16(pf.cfg + pf.cap + SRIOV_CTRL, ctrl & !(CTRL_VF_ENABLE | CTRL_VF_MSE));
+    mmio_write16(pf.cfg + pf.cap + SRIOV_NUM_VFS, 0);
+    Ok(())
+}
+
+/// The PF at `bdf`, if its VFs are enabled.
+pub fn pf(bdf: Bdf) -> Option<Pf> { PFS.lock(|t| t.iter().flatten().find(|p| p.bdf == bdf).copied()) }
+
+/// Guest BAR accesses forwarded to `vf` since it was attached.
+pub fn mmio_ops(vf: Bdf) -> Option<u64> {
+    let slot = ATTACHED.lock(|t| t.iter().position(|a| matches!(a, Some(a) if a.vf == vf)))?;
+    Some(OPS[slot].load(Ordering::Relaxed))
+}
+
+pub fn for_each_pf(mut f: impl FnMut(&Pf)) {
+    let snap = PFS.lock(|t| *t);
+    for p in snap.iter().flatten() { f(p); }
+}
+
+pub fn for_each_attached(mut f: impl FnMut(&Attached)) {
+    let snap = ATTACHED.lock(|t| *t);
+    for a in snap.iter().flatten() { f(a); }
+}
+
+// ---- Guest side ----
+
+/// Forward a trapped guest access to the VF's host BAR. `ctx` is slot * 8 + BAR.
+fn bar_mmio(_vm_id: u64, _vcpu: u32, ctx: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    let (slot, bar) = ((ctx / 8) as usize, (ctx % 8) as usize);
+    let host = ATTACHED.lock(|t| t.get(slot).and_then(|a| a.as_ref()).map(|a| (a.bar_host[bar], a.bar_size[bar])));
+    let (base, len) = match host { Some(h) => h, None => return !0 };
+    if off + size as u64 > len { return !0; }
+    OPS[slot].fetch_add(1, Ordering::Relaxed);
+    let a = (base + off) as usize;
+    unsafe {
+        match (size, write) {
+            (1, Some(v)) => { core::ptr::write_volatile(a as *mut u8, v as u8); 0 }
+            (2, Some(v)) => { core::ptr::write_volatile(a as *mut u16, v as u16); 0 }
+            (4, Some(v)) => { core::ptr::write_volatile(a as *mut u32, v as u32); 0 }
+            (8, Some(v)) => { core::ptr::write_volatile(a as *mut u64, v); 0 }
+            (1, None) => core::ptr::read_volatile(a as *const u8) as u64,
+            (2, None) => core::ptr::read_volatile(a as *const u16) as u64,
+            (4, None) => core::ptr::read_volatile(a as *const u32) as u64,
+            (8, None) => core::ptr::read_volatile(a as *const u64),
+            _ => !0,
+        }
+    }
+}
+
+fn on_bar(vm_id: u64, ctx: u64, bar: usize, old: u64, new: u64) {
+    let slot = ctx as usize;
+    let len = ATTACHED.lock(|t| t.get(slot).and_then(|a| a.as_ref()).map(|a| a.bar_size[bar]).unwrap_or(0));
+    if old != 0 { let _ = crate::hv::bus::unregister_mmio(vm_id, old); }
+    if new != 0 && len != 0 { let _ = crate::hv::bus::register_mmio(vm_id, new, len, "sriov-vf", ctx * 8 + bar as u64, bar_mmio); }
+}
+
+/// Set or clear bus mastering in the VF's command register.
+fn set_bus_master(cfg: usize, on: bool) {
+    let cmd = mmio_read16(cfg + crate::hv::vpci::CFG_COMMAND);
+    let m = crate::hv::vpci::CMD_MASTER;
+    mmio_write16(cfg + crate::hv::vpci::CFG_COMMAND, if on { cmd | m } else { cmd & !m });
+}
+
+/// Pass VF `vf` through to `vm_id`. The VM must have its own RAM (a loaded image).
+pub fn attach(system_table: &mut SystemTable<Boot>, vm_id: u64, vf: Bdf) -> Result<Attached, &'static str> {
+    let (pf, n) = PFS.lock(|t| t.iter().flatten().find_map(|p| p.vf_index(vf).map(|n| (*p, n))))
+        .ok_or("sriov: not a VF of an enabled PF (pci sriov enable first)")?;
+    let mut bar_host = [0u64; 6];
+    for i in 0..6 {
+        if pf.bar_size[i] != 0 { bar_host[i] = pf.bar_base[i] + n as u64 * pf.bar_size[i]; }
+    }
+    // VF vendor/device IDs read as all-ones; the guest sees the PF's vendor and the VF device ID.
+    attach_bars(system_table, vm_id, vf, pf.bdf, (pf.vendor, pf.vf_device), (bar_host, pf.bar_size, pf.bar64))
+}
+
+/// Pass the whole function at `bdf` through to `vm_id`, for devices that
+/// go to a guest without SR-IOV. Its memory BARs are sized with
+/// `pcicfg::probe_bars`, so the function has to be on the config-write
+/// allowlist; I/O BARs are not forwarded.
+pub fn attach_function(system_table: &mut SystemTable<Boot>, vm_id: u64, bdf: Bdf) -> Result<Attached, &'static str> {
+    if pf(bdf).is_some() { return Err("sriov: function has VFs enabled (pci sriov disable first)"); }
+    if PFS.lock(|t| t.iter().flatten().any(|p| p.vf_index(bdf).is_some())) { return Err("sriov: function is a VF (vm attach vf= instead)"); }
+    if ATTACHED.lock(|t| t.iter().flatten().any(|a| a.vf == bdf)) { return Err("sriov: function already attached"); }
+    let cfg = crate::iommu::ecam_cfg_base(system_table, bdf.seg, bdf.bus, bdf.dev, bdf.func).ok_or("sriov: no ECAM window for device")?;
+    if mmio_read16(cfg) == 0xFFFF { return Err("sriov: no device at address"); }
+    if mmio_read8(cfg + crate::hv::vpci::CFG_HEADER_TYPE) & 0x7F != 0 { return Err("sriov: not an endpoint function"); }
+    let (mut base, mut size, mut is64) = ([0u64; 6], [0u64; 6], [false; 6]);
+    crate::iommu::pcicfg::probe_bars(system_table, bdf.seg, bdf.bus, bdf.dev, bdf.func, |b| {
+        if b.kind == crate::iommu::pcicfg::BarKind::Io { return; }
+        let i = b.index as usize;
+        (base[i], size[i], is64[i]) = (b.addr, b.size, b.kind == crate::iommu::pcicfg::BarKind::Mem64);
+    })?;
+    if size.iter().all(|&s| s == 0) { return Err("sriov: function has no memory BARs"); }
+    if (0..6).any(|i| size[i] != 0 && base[i] == 0) { return Err("sriov: firmware did not assign the BARs"); }
+    let ids = (mmio_read16(cfg), mmio_read16(cfg + crate::hv::vpci::CFG_DEVICE));
+    attach_bars(system_table, vm_id, bdf, bdf, ids, (base, size, is64))
+}
+
+/// Common part of `attach` and `attach_function`: IOMMU domain, guest PCI
+/// function and BAR forwarding for `vf`. `bars` are the host base, size and
+/// 64-bit flag of each BAR.
+fn attach_bars(system_table: &mut SystemTable<Boot>, vm_id: u64, vf: Bdf, pf: Bdf, ids: (u16, u16), bars: ([u64; 6], [u64; 6], [bool; 6])) -> Result<Attached, &'static str> {
+    let (bar_host, bar_size, bar64) = bars;
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err("sriov: no such vm"); }
+    let img = crate::hv::loader::find_image(vm_id).ok_or("sriov: vm has no guest RAM yet (vm load first)")?;
+    if ATTACHED.lock(|t| t.iter().flatten().any(|a| a.vf == vf)) { return Err("sriov: VF already attached"); }
+    if crate::iommu::state::find_domain_for_bdf(vf.seg, vf.bus, vf.dev, vf.func).is_some() { return Err("sriov: VF already assigned to an IOMMU domain"); }
+    let cfg = crate::iommu::ecam_cfg_base(system_table, vf.seg, vf.bus, vf.dev, vf.func).ok_or("sriov: no ECAM window for VF")?;
+
+    let total: u64 = bar_size.iter().sum();
+    if total > crate::hv::vpci::MMIO_SLOT_SIZE { return Err("sriov: VF BARs exceed the guest MMIO slot"); }
+    let slot = ATTACHED.lock(|t| {
+        let i = t.iter().position(|a| a.is_none())?;
+        t[i] = Some(Attached { vm_id, vf, pf, domid: 0, guest_dev: 0, bar_host, bar_size, cfg });
+        OPS[i].store(0, Ordering::Relaxed);
+        Some(i)
+    }).ok_or("sriov: too many attached VFs")?;
+    let fail = |e: &'static str| { ATTACHED.lock(|t| t[slot] = None); Err(e) };
+
+    // One IOMMU domain per VM, shared by all of its VFs.
+    let existing = ATTACHED.lock(|t| t.iter().flatten().find(|a| a.vm_id == vm_id && a.domid != 0).map(|a| a.domid));
+    let domid = match existing {
+        Some(d) => d,
+        None => {
+            let d = match crate::iommu::state::create_domain() { Some(d) => d, None => return fail("sriov: no free IOMMU domain") };
+            // The VF reads guest RAM from the extent, not through stage 2.
+            crate::hv::microvm::privatize(vm_id);
+            if !crate::iommu::state::add_mapping(d, 0, img.ram_host, img.ram_bytes, true, true, false) {
+                let _ = crate::iommu::state::destroy_domain(d);
+                return fail("sriov: cannot map guest RAM");
+            }
+            crate::iommu::vtd::apply_mappings(system_table);
+            crate::iommu::amdv::apply_mappings(system_table);
+            d
+        }
+    };
+    if !crate::iommu::state::assign_device(vf.seg, vf.bus, vf.dev, vf.func, domid) {
+        if existing.is_none() { let _ = crate::iommu::state::destroy_domain(domid); }
+        return fail("sriov: IOMMU assignment failed");
+    }
+    crate::iommu::vtd::apply_and_refresh(system_table);
+    crate::iommu::amdv::apply_assignments(system_table);
+
+    // Guest view: the function's identity and BAR layout.
+    let mut c = crate::hv::vpci::Config::new(ids.0, ids.1, mmio_read8(cfg + crate::hv::vpci::CFG_CLASS),
+        mmio_read8(cfg + crate::hv::vpci::CFG_SUBCLASS), mmio_read8(cfg + crate::hv::vpci::CFG_REVISION));
+    c.cfg[crate::hv::vpci::CFG_PROG_IF] = mmio_read8(cfg + crate::hv::vpci::CFG_PROG_IF);
+    c.put16(crate::hv::vpci::CFG_SUBSYS_VENDOR, mmio_read16(cfg + crate::hv::vpci::CFG_SUBSYS_VENDOR));
+    c.put16(crate::hv::vpci::CFG_SUBSYS_ID, mmio_read16(cfg + crate::hv::vpci::CFG_SUBSYS_ID));
+    for i in 0..6 {
+        if bar_size[i] == 0 { continue; }
+        if bar64[i] { c.bar64(i, bar_size[i]); } else { c.bar32(i, bar_size[i]); }
+    }
+    ATTACHED.lock(|t| if let Some(a) = t[slot].as_mut() { a.domid = domid; });
+    let dev = match crate::hv::vpci::add(vm_id, c, slot as u64, on_bar) {
+        Some(d) => d,
+        None => {
+            let _ = crate::iommu::state::unassign_device(vf.seg, vf.bus, vf.dev, vf.func);
+            if existing.is_none() { let _ = crate::iommu::state::destroy_domain(domid); }
+            return fail("sriov: no free guest PCI slot");
+        }
+    };
+    set_bus_master(cfg, true);
+    // Messages the VF has enabled so far go through the remapping table too.
+    if crate::iommu::vtd_ir::is_enabled() { let _ = crate::iommu::vtd_ir::route_device(system_table, vf.seg, vf.bus, vf.dev, vf.func); }
+    crate::obs::log::info(system_table, "sriov", "VF attached to vm");
+    let a = ATTACHED.lock(|t| { let a = t[slot].as_mut().map(|a| { a.guest_dev = dev; *a }); a });
+    a.ok_or("sriov: VF detached concurrently")
+}
+
+/// Stop the VF's DMA and release its IOMMU assignment; drops the VM's domain
+/// with its last VF. Does not touch the guest bus.
+fn release(a: &Attached) {
+    set_bus_master(a.cfg, false);
+    let _ = crate::iommu::state::unassign_device(a.vf.seg, a.vf.bus, a.vf.dev, a.vf.func);
+    let last = !ATTACHED.lock(|t| t.iter().flatten().any(|x| x.vm_id == a.vm_id));
+    if last && a.domid != 0 { let _ = crate::iommu::state::destroy_domain(a.domid); }
+}
+
+/// Take VF `vf` away from `vm_id`.
+pub fn detach(system_table: &mut SystemTable<Boot>, vm_id: u64, vf: Bdf) -> Result<(), &'static str> {
+    let a = ATTACHED.lock(|t| {
+        let i = t.iter().position(|a| matches!(a, Some(a) if a.vm_id == vm_id && a.vf == vf))?;
+        let a = t[i];
+        t[i] = None;
+        a
+    }).ok_or("sriov: VF not attached to this vm")?;
+    crate::hv::vpci::remove(vm_id, a.guest_dev);
+    crate::iommu::vtd_ir::unroute(system_table, vf.seg, vf.rid());
+    release(&a);
+    crate::iommu::vtd::apply_and_refresh(system_table);
+    crate::iommu::amdv::apply_assignments(system_table);
+    Ok(())
+}
+
+/// Release every VF of a destroyed VM. Stale context entries are rewritten by
+/// the next IOMMU apply; the VFs cannot DMA meanwhile as bus mastering is off.
+pub fn detach_vm(vm_id: u64) {
+    loop {
+        let a = ATTACHED.lock(|t| {
+            let i = t.iter().position(|a| matches!(a, Some(a) if a.vm_id == vm_id))?;
+            let a = t[i];
+            t[i] = None;
+            a
+        });
+        match a { Some(a) => release(&a), None => break }
+    }
+}
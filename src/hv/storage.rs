@@ -0,0 +1,652 @@
+#![allow(dead_code)]
+
+//! Storage manager: thin volumes with copy-on-write snapshots.
+//!
+//! A pool is a range of blocks on a host disk (Block I/O, the same disks
+//! `vm blk add disk=` uses, or a namespace `crate::nvme` drives) cut into
+//! 1 MiB chunks. A volume is a map from
+//! its own chunks to pool chunks. An unmapped chunk reads as zeros and gets
+//! a pool chunk on its first write, so a volume only takes the space the
+//! guest has written. A snapshot is a read-only copy of a volume's map and
+//! a clone a writable one. Both are instant and share every chunk with the
+//! source. Writing to a shared chunk first copies it to a fresh one. How
+//! many maps use a chunk is not stored; it is recounted when the pool opens.
+//!
+//! On disk, block 0 and block 1 are the headers of metadata copies A and B.
+//! Chunk 0 holds nothing else. Each copy's volume table and maps follow in
+//! its own run of chunks, and data chunks come after both. An update goes
+//! to the older copy with the next generation, payload first and header
+//! last, so a torn write leaves the other copy intact. Opening takes the
+//! newest copy whose CRCs check out. Version 1 volume records were 64
+//! bytes with 32-byte names; version 2 records are 160 bytes with 128-byte
+//! names. A version 1 pool is read as is and written as version 2 from its
+//! first update.
+//!
+//! The metadata is written after every management operation. Chunks a guest
+//! write allocates are written on its next FLUSH (or `storage sync`), like a
+//! disk's write cache: after a crash, writes since the last flush that
+//! needed a new chunk are gone, and nothing else is.
+
+use alloc::vec::Vec;
+use uefi::prelude::Boot;
+use uefi::proto::media::block::BlockIO;
+use uefi::table::boot::ScopedProtocol;
+use uefi::table::SystemTable;
+
+use crate::util::spinlock::SpinLock;
+
+/// Allocation unit (1 MiB)
+pub const CHUNK: u64 = 1 << 20;
+pub const MAX_VOLUMES: usize = 64;
+pub const NAME_MAX: usize = 128;
+/// The metadata area has room for this many map entries per pool chunk,
+/// so volumes can be provisioned up to this many times the pool.
+pub const OVERCOMMIT: u64 = 4;
+/// Smallest pool, in chunks
+pub const MIN_CHUNKS: u64 = 16;
+
+const MAGIC: [u8; 8] = *b"ZVPOOL\0\x01";
+const VERSION: u32 = 2;
+const HDR_LEN: usize = 64;
+const REC_LEN: usize = 160;
+/// Record and name length of version 1
+const V1_REC_LEN: usize = 64;
+const V1_NAME_MAX: usize = 32;
+const F_SNAPSHOT: u32 = 1;
+/// Copy buffer; also the largest host block size handled
+const BOUNCE: usize = 4096;
+
+struct Vol {
+    id: u32,
+    name: [u8; NAME_MAX],
+    name_len: u8,
+    /// Read-only copy
+    snapshot: bool,
+    /// Volume this one was snapshotted or cloned from (0 = none)
+    parent: u32,
+    /// Pool chunk for each of the volume's chunks (0 = unmapped)
+    map: Vec<u32>,
+}
+
+impl Vol {
+    fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("?") }
+}
+
+/// What a pool sits on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Device {
+    /// Host Block I/O handle (index from `host_disks`)
+    Disk(usize),
+    /// Namespace `nsid` of NVMe controller `ctrl`
+    Nvme { ctrl: usize, nsid: u32 },
+}
+
+struct Pool {
+    device: Device,
+    lba: u64,
+    block_size: u32,
+    /// Chunks in the pool, metadata included
+    chunks: u32,
+    /// Chunks per metadata copy
+    meta_chunks: u32,
+    generation: u64,
+    next_id: u32,
+    vols: Vec<Vol>,
+    /// Maps using each chunk
+    refs: Vec<u16>,
+    /// Chunks allocated since the metadata was last written
+    dirty: bool,
+    /// Where the next free-chunk search starts
+    hint: u32,
+}
+
+impl Pool {
+    fn data_start(&self) -> u32 { 1 + 2 * self.meta_chunks }
+
+    /// Map entries the metadata area holds.
+    fn map_cap(&self) -> usize { ((self.meta_chunks as u64 * CHUNK) as usize - MAX_VOLUMES * REC_LEN) / 4 }
+
+    fn map_used(&self) -> usize { self.vols.iter().map(|v| v.map.len()).sum() }
+
+    fn vol(&self, id: u32) -> Option<usize> { self.vols.iter().position(|v| v.id == id) }
+
+    fn alloc(&mut self) -> Option<u32> {
+        let (lo, hi) = (self.data_start(), self.chunks);
+        let start = self.hint.clamp(lo, hi - 1);
+        let c = (start..hi).chain(lo..start).find(|&c| self.refs[c as usize] == 0)?;
+        self.refs[c as usize] = 1;
+        self.hint = c + 1;
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::STORAGE_CHUNK_ALLOCS).inc();
+        Some(c)
+    }
+
+    /// Add a volume sharing `map`, counting its chunks.
+    fn add(&mut self, name: &str, snapshot: bool, parent: u32, map: Vec<u32>) -> Result<u32, &'static str> {
+        check_name(name)?;
+        if self.vols.iter().any(|v| v.name() == name) { return Err("storage: name already in use"); }
+        if self.vols.len() == MAX_VOLUMES { return Err("storage: too many volumes"); }
+        if self.map_used() + map.len() > self.map_cap() { return Err("storage: pool is provisioned to its limit"); }
+        for &c in map.iter().filter(|&&c| c != 0) { self.refs[c as usize] += 1; }
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut v = Vol { id, name: [0; NAME_MAX], name_len: name.len() as u8, snapshot, parent, map };
+        v.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.vols.push(v);
+        Ok(id)
+    }
+}
+
+static POOL: SpinLock<Option<Pool>> = SpinLock::new(None);
+
+pub(crate) fn check_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.len() > NAME_MAX || name.parse::<u32>().is_ok()
+        || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.') {
+        return Err("storage: name must be 1..128 of [A-Za-z0-9._-] and not a number");
+    }
+    Ok(())
+}
+
+// ---- Disk access ----
+
+enum Io<'a> {
+    Blocks { io: ScopedProtocol<'a, BlockIO>, media_id: u32 },
+    Nvme { ctrl: usize, nsid: u32 },
+}
+
+/// The pool's extent, opened for one pass.
+pub(crate) struct Dev<'a> {
+    io: Io<'a>,
+    lba: u64,
+    bs: u64,
+}
+
+impl Dev<'_> {
+    fn read(&mut self, off: u64, buf: &mut [u8]) -> bool {
+        if off % self.bs != 0 || buf.len() as u64 % self.bs != 0 { return false; }
+        let lba = self.lba + off / self.bs;
+        match &mut self.io {
+            Io::Blocks { io, media_id } => io.read_blocks(*media_id, lba, buf).is_ok(),
+            Io::Nvme { ctrl, nsid } => crate::nvme::read(*ctrl, *nsid, lba, buf).is_ok(),
+        }
+    }
+
+    fn write(&mut self, off: u64, buf: &[u8]) -> bool {
+        if off % self.bs != 0 || buf.len() as u64 % self.bs != 0 { return false; }
+        let lba = self.lba + off / self.bs;
+        match &mut self.io {
+            Io::Blocks { io, media_id } => io.write_blocks(*media_id, lba, buf).is_ok(),
+            Io::Nvme { ctrl, nsid } => crate::nvme::write(*ctrl, *nsid, lba, buf).is_ok(),
+        }
+    }
+
+    fn flush(&mut self) -> bool {
+        match &mut self.io {
+            Io::Blocks { io, .. } => io.flush_blocks().is_ok(),
+            Io::Nvme { ctrl, nsid } => crate::nvme::flush(*ctrl, *nsid).is_ok(),
+        }
+    }
+}
+
+/// Whether `device` is an NVMe namespace a guest has as its disk.
+fn guest_disk(device: Device) -> bool {
+    let Device::Nvme { ctrl, nsid } = device else { return false };
+    let mut used = false;
+    crate::hv::vdev::blk::for_each(|_, d| used |= matches!(d.backing, crate::hv::vdev::blk::Backing::Nvme { ctrl: c, nsid: n } if c == ctrl && n == nsid));
+    used
+}
+
+/// Open `device` at `lba`. Also returns the blocks from `lba` to the end.
+fn open_device(system_table: &SystemTable<Boot>, device: Device, lba: u64) -> Result<(Dev<'_>, u64), &'static str> {
+    let disk = match device {
+        Device::Disk(d) => d,
+        Device::Nvme { ctrl, nsid } => {
+            let ns = crate::nvme::namespace(ctrl, nsid).ok_or("storage: no such NVMe namespace")?;
+            if lba >= ns.blocks { return Err("storage: lba past end of disk"); }
+            return Ok((Dev { io: Io::Nvme { ctrl, nsid }, lba, bs: ns.block_size as u64 }, ns.blocks - lba));
+        }
+    };
+    let h = crate::hv::vdev::blk::host_disk_handle(system_table, disk).ok_or("storage: no such host disk")?;
+    let io = crate::hv::vdev::blk::open_blockio(system_table, h).ok_or("storage: Block I/O open failed")?;
+    let m = io.media();
+    let bs = m.block_size() as u64;
+    if !m.is_media_present() { return Err("storage: no media"); }
+    if m.is_read_only() { return Err("storage: disk is read-only"); }
+    if bs < 512 || bs > BOUNCE as u64 || !bs.is_power_of_two() { return Err("storage: unsupported block size"); }
+    let blocks = m.last_block().saturating_add(1);
+    if lba >= blocks { return Err("storage: lba past end of disk"); }
+    let media_id = m.media_id();
+    Ok((Dev { io: Io::Blocks { io, media_id }, lba, bs }, blocks - lba))
+}
+
+/// The open pool's disk, for I/O on its volumes.
+pub(crate) fn open_dev(system_table: &SystemTable<Boot>) -> Result<Dev<'_>, &'static str> {
+    let (device, lba) = POOL.lock(|p| p.as_ref().map(|p| (p.device, p.lba))).ok_or("storage: no pool open")?;
+    open_device(system_table, device, lba).map(|d| d.0)
+}
+
+// ---- Metadata ----
+
+struct Header {
+    version: u32,
+    block_size: u32,
+    chunks: u32,
+    meta_chunks: u32,
+    generation: u64,
+    nvols: u32,
+    next_id: u32,
+    payload_len: u32,
+    payload_crc: u32,
+}
+
+fn encode_header(h: &Header, b: &mut [u8]) {
+    b[..HDR_LEN].fill(0);
+    b[0..8].copy_from_slice(&MAGIC);
+    b[8..12].copy_from_slice(&h.version.to_le_bytes());
+    b[12..16].copy_from_slice(&h.block_size.to_le_bytes());
+    b[16..20].copy_from_slice(&h.chunks.to_le_bytes());
+    b[20..24].copy_from_slice(&h.meta_chunks.to_le_bytes());
+    b[24..32].copy_from_slice(&h.generation.to_le_bytes());
+    b[32..36].copy_from_slice(&h.nvols.to_le_bytes());
+    b[36..40].copy_from_slice(&h.next_id.to_le_bytes());
+    b[40..44].copy_from_slice(&h.payload_len.to_le_bytes());
+    b[44..48].copy_from_slice(&h.payload_crc.to_le_bytes());
+    let crc = crate::util::crc32::crc32(&b[..60]);
+    b[60..64].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn le32(b: &[u8], o: usize) -> u32 { u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]]) }
+
+fn decode_header(b: &[u8]) -> Option<Header> {
+    if b[0..8] != MAGIC || !(1..=VERSION).contains(&le32(b, 8)) || le32(b, 60) != crate::util::crc32::crc32(&b[..60]) { return None; }
+    let mut g = [0u8; 8];
+    g.copy_from_slice(&b[24..32]);
+    Some(Header {
+        version: le32(b, 8), block_size: le32(b, 12), chunks: le32(b, 16), meta_chunks: le32(b, 20), generation: u64::from_le_bytes(g),
+        nvols: le32(b, 32), next_id: le32(b, 36), payload_len: le32(b, 40), payload_crc: le32(b, 44),
+    })
+}
+
+/// Volume records, each followed by its map.
+fn payload(p: &Pool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(p.vols.len() * REC_LEN + p.map_used() * 4);
+    for v in &p.vols {
+        let mut rec = [0u8; REC_LEN];
+        rec[0..4].copy_from_slice(&v.id.to_le_bytes());
+        rec[4..8].copy_from_slice(&(if v.snapshot { F_SNAPSHOT } else { 0 }).to_le_bytes());
+        rec[8..12].copy_from_slice(&v.parent.to_le_bytes());
+        rec[12..16].copy_from_slice(&(v.map.len() as u32).to_le_bytes());
+        rec[16] = v.name_len;
+        rec[17..17 + NAME_MAX].copy_from_slice(&v.name);
+        out.extend_from_slice(&rec);
+        for &c in &v.map { out.extend_from_slice(&c.to_le_bytes()); }
+    }
+    out
+}
+
+/// Rebuild volumes and share counts from a payload of format `version`;
+/// false if it does not fit the pool.
+fn parse(p: &mut Pool, version: u32, nvols: u32, d: &[u8]) -> bool {
+    let (rec_len, name_max) = if version == 1 { (V1_REC_LEN, V1_NAME_MAX) } else { (REC_LEN, NAME_MAX) };
+    let mut o = 0usize;
+    for _ in 0..nvols {
+        let Some(rec) = d.get(o..o + rec_len) else { return false };
+        let n = le32(rec, 12) as usize;
+        let Some(raw) = d.get(o + rec_len..o + rec_len + n * 4) else { return false };
+        let name_len = rec[16];
+        if name_len as usize > name_max { return false; }
+        let mut v = Vol { id: le32(rec, 0), name: [0; NAME_MAX], name_len, snapshot: le32(rec, 4) & F_SNAPSHOT != 0, parent: le32(rec, 8), map: Vec::with_capacity(n) };
+        v.name[..name_max].copy_from_slice(&rec[17..17 + name_max]);
+        for k in 0..n {
+            let c = le32(raw, k * 4);
+            if c != 0 {
+                if c < p.data_start() || c >= p.chunks { return false; }
+                p.refs[c as usize] += 1;
+            }
+            v.map.push(c);
+        }
+        p.vols.push(v);
+        o += rec_len + n * 4;
+    }
+    true
+}
+
+/// Write the metadata as the next generation into the older copy.
+fn persist(dev: &mut Dev, p: &mut Pool) -> Result<(), &'static str> {
+    let generation = p.generation + 1;
+    let copy = generation & 1;
+    let mut data = payload(p);
+    let len = data.len();
+    if len as u64 > p.meta_chunks as u64 * CHUNK { return Err("storage: metadata area full"); }
+    let bs = dev.bs as usize;
+    data.resize(len.div_ceil(bs).max(1) * bs, 0);
+    let h = Header {
+        version: VERSION, block_size: p.block_size, chunks: p.chunks, meta_chunks: p.meta_chunks, generation,
+        nvols: p.vols.len() as u32, next_id: p.next_id, payload_len: len as u32, payload_crc: crate::util::crc32::crc32(&data[..len]),
+    };
+    let mut hb = [0u8; BOUNCE];
+    encode_header(&h, &mut hb);
+    let ok = dev.write((1 + copy * p.meta_chunks as u64) * CHUNK, &data) && dev.flush()
+        && dev.write(copy * dev.bs, &hb[..bs]) && dev.flush();
+    if !ok { p.dirty = true; return Err("storage: metadata write failed"); }
+    p.generation = generation;
+    p.dirty = false;
+    Ok(())
+}
+
+/// Read the newest intact metadata copy.
+fn load(dev: &mut Dev, device: Device, blocks: u64) -> Result<Pool, &'static str> {
+    let bs = dev.bs as usize;
+    let mut hb = [0u8; 2 * BOUNCE];
+    if !dev.read(0, &mut hb[..2 * bs]) { return Err("storage: read failed"); }
+    let mut heads = [decode_header(&hb[..bs]), decode_header(&hb[bs..2 * bs])];
+    if heads[0].as_ref().map_or(0, |h| h.generation) < heads[1].as_ref().map_or(0, |h| h.generation) { heads.swap(0, 1); }
+    if heads.iter().all(|h| h.is_none()) { return Err("storage: no pool on this disk"); }
+    for h in heads.iter().flatten() {
+        if h.block_size as u64 != dev.bs || h.chunks as u64 > blocks * dev.bs / CHUNK || 1 + 2 * h.meta_chunks as u64 >= h.chunks as u64 { continue; }
+        if h.payload_len as u64 > h.meta_chunks as u64 * CHUNK { continue; }
+        let len = h.payload_len as usize;
+        let mut data = Vec::new();
+        data.resize(len.div_ceil(bs).max(1) * bs, 0u8);
+        if !dev.read((1 + (h.generation & 1) * h.meta_chunks as u64) * CHUNK, &mut data) { continue; }
+        if crate::util::crc32::crc32(&data[..len]) != h.payload_crc { continue; }
+        let mut refs = Vec::new();
+        refs.resize(h.chunks as usize, 0u16);
+        let mut p = Pool {
+            device, lba: dev.lba, block_size: h.block_size, chunks: h.chunks, meta_chunks: h.meta_chunks, generation: h.generation,
+            next_id: h.next_id, vols: Vec::new(), refs, dirty: false, hint: 0,
+        };
+        if parse(&mut p, h.version, h.nvols, &data[..len]) { return Ok(p); }
+    }
+    Err("storage: pool metadata is corrupt")
+}
+
+// ---- Pool management ----
+
+/// Capacity and usage of the open pool.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolInfo {
+    pub device: Device,
+    pub lba: u64,
+    pub block_size: u32,
+    pub generation: u64,
+    /// Bytes of data chunks
+    pub capacity: u64,
+    /// Bytes of data chunks in use
+    pub used: u64,
+    /// Sum of volume sizes
+    pub provisioned: u64,
+    /// Largest `provisioned` the metadata area allows
+    pub provision_limit: u64,
+    pub volumes: usize,
+    /// Allocations not written to the metadata yet
+    pub dirty: bool,
+}
+
+fn pool_info(p: &Pool) -> PoolInfo {
+    PoolInfo {
+        device: p.device, lba: p.lba, block_size: p.block_size, generation: p.generation,
+        capacity: (p.chunks - p.data_start()) as u64 * CHUNK,
+        used: p.refs.iter().filter(|&&r| r != 0).count() as u64 * CHUNK,
+        provisioned: p.map_used() as u64 * CHUNK,
+        provision_limit: p.map_cap() as u64 * CHUNK,
+        volumes: p.vols.len(),
+        dirty: p.dirty,
+    }
+}
+
+pub fn info() -> Option<PoolInfo> { POOL.lock(|p| p.as_ref().map(pool_info)) }
+
+/// Make a pool of `count` blocks (0 = to the end of the disk) at `lba` on
+/// `device`, and open it. An existing pool there is only overwritten with
+/// `force`.
+pub fn format(system_table: &SystemTable<Boot>, device: Device, lba: u64, count: u64, force: bool) -> Result<PoolInfo, &'static str> {
+    if POOL.lock(|p| p.is_some()) { return Err("storage: a pool is already open"); }
+    if guest_disk(device) { return Err("storage: namespace is a vm disk"); }
+    let (mut dev, avail) = open_device(system_table, device, lba)?;
+    let count = if count == 0 { avail } else { count };
+    if count > avail { return Err("storage: pool past end of disk"); }
+    let chunks = count * dev.bs / CHUNK;
+    if chunks < MIN_CHUNKS { return Err("storage: pool must be at least 16 MiB"); }
+    if chunks > u32::MAX as u64 { return Err("storage: pool too large"); }
+    let bs = dev.bs as usize;
+    let mut hb = [0u8; 2 * BOUNCE];
+    if !dev.read(0, &mut hb[..2 * bs]) { return Err("storage: read failed"); }
+    if !force && (decode_header(&hb[..bs]).is_some() || decode_header(&hb[bs..2 * bs]).is_some()) {
+        return Err("storage: disk already holds a pool (force to overwrite)");
+    }
+    let meta_chunks = (MAX_VOLUMES * REC_LEN) as u64 + OVERCOMMIT * chunks * 4;
+    let meta_chunks = meta_chunks.div_ceil(CHUNK);
+    if 1 + 2 * meta_chunks + MIN_CHUNKS / 2 > chunks { return Err("storage: pool too small for its metadata"); }
+    // Wipe both headers so no older generation can win at the next open.
+    hb.fill(0);
+    if !dev.write(0, &hb[..2 * bs]) { return Err("storage: write failed"); }
+    let mut refs = Vec::new();
+    refs.resize(chunks as usize, 0u16);
+    let mut p = Pool {
+        device, lba, block_size: dev.bs as u32, chunks: chunks as u32, meta_chunks: meta_chunks as u32, generation: 0,
+        next_id: 1, vols: Vec::new(), refs, dirty: false, hint: 0,
+    };
+    persist(&mut dev, &mut p)?;
+    let info = pool_info(&p);
+    POOL.lock(|slot| *slot = Some(p));
+    Ok(info)
+}
+
+/// Open the pool at `lba` on `device`.
+pub fn open(system_table: &SystemTable<Boot>, device: Device, lba: u64) -> Result<PoolInfo, &'static str> {
+    if POOL.lock(|p| p.is_some()) { return Err("storage: a pool is already open"); }
+    if guest_disk(device) { return Err("storage: namespace is a vm disk"); }
+    let (mut dev, avail) = open_device(system_table, device, lba)?;
+    let p = load(&mut dev, device, avail)?;
+    let info = pool_info(&p);
+    POOL.lock(|slot| *slot = Some(p));
+    Ok(info)
+}
+
+/// Write pending allocations and forget the pool. Fails while a volume is attached.
+pub fn close(system_table: &SystemTable<Boot>) -> Result<(), &'static str> {
+    if attached(None) { return Err("storage: a volume is attached to a VM"); }
+    sync(system_table)?;
+    POOL.lock(|p| *p = None);
+    Ok(())
+}
+
+/// Write the metadata if guest writes allocated chunks since it was last written.
+pub fn sync(system_table: &SystemTable<Boot>) -> Result<(), &'static str> {
+    let mut dev = open_dev(system_table)?;
+    if flush(&mut dev) { Ok(()) } else { Err("storage: metadata write failed") }
+}
+
+/// Whether volume `id` (any volume for `None`) backs a virtio-blk disk.
+fn attached(id: Option<u32>) -> bool {
+    let mut found = false;
+    crate::hv::vdev::blk::for_each(|_, d| {
+        if let crate::hv::vdev::blk::Backing::Volume { id: v } = d.backing { found |= id.map_or(true, |id| id == v); }
+    });
+    found
+}
+
+/// Change the pool under its lock, then write the metadata.
+fn update<T>(system_table: &SystemTable<Boot>, f: impl FnOnce(&mut Pool) -> Result<T, &'static str>) -> Result<T, &'static str> {
+    let mut dev = open_dev(system_table)?;
+    POOL.lock(|p| {
+        let p = p.as_mut().ok_or("storage: no pool open")?;
+        let r = f(p)?;
+        persist(&mut dev, p)?;
+        Ok(r)
+    })
+}
+
+// ---- Volumes ----
+
+/// A volume for reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct VolInfo {
+    pub id: u32,
+    pub name: [u8; NAME_MAX],
+    pub name_len: u8,
+    pub snapshot: bool,
+    pub parent: u32,
+    /// Bytes the guest sees
+    pub size: u64,
+    /// Bytes with a chunk behind them
+    pub allocated: u64,
+    /// Bytes in chunks no other volume uses (what deleting it frees)
+    pub exclusive: u64,
+}
+
+impl VolInfo {
+    pub fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("?") }
+}
+
+fn vol_info(p: &Pool, v: &Vol) -> VolInfo {
+    let mapped = v.map.iter().filter(|&&c| c != 0);
+    VolInfo {
+        id: v.id, name: v.name, name_len: v.name_len, snapshot: v.snapshot, parent: v.parent,
+        size: v.map.len() as u64 * CHUNK,
+        allocated: mapped.clone().count() as u64 * CHUNK,
+        exclusive: mapped.filter(|&&c| p.refs[c as usize] == 1).count() as u64 * CHUNK,
+    }
+}
+
+pub fn volume(id: u32) -> Option<VolInfo> {
+    POOL.lock(|p| { let p = p.as_ref()?; p.vol(id).map(|i| vol_info(p, &p.vols[i])) })
+}
+
+pub fn for_each(mut f: impl FnMut(&VolInfo)) {
+    let snap: Vec<VolInfo> = POOL.lock(|p| p.as_ref().map(|p| p.vols.iter().map(|v| vol_info(p, v)).collect()).unwrap_or_default());
+    for v in &snap { f(v); }
+}
+
+/// Volume id for a decimal id or a name.
+pub fn find(sel: &str) -> Option<u32> {
+    POOL.lock(|p| {
+        let p = p.as_ref()?;
+        match sel.parse::<u32>() {
+            Ok(id) => p.vol(id).map(|_| id),
+            Err(_) => p.vols.iter().find(|v| v.name() == sel).map(|v| v.id),
+        }
+    })
+}
+
+/// New empty volume of `size_mib` MiB. Returns its id.
+pub fn create(system_table: &SystemTable<Boot>, name: &str, size_mib: u64) -> Result<u32, &'static str> {
+    if size_mib == 0 || size_mib > u32::MAX as u64 { return Err("storage: size must be 1 MiB or more"); }
+    update(system_table, |p| {
+        let n = (size_mib * (1 << 20)).div_ceil(CHUNK) as usize;
+        if p.map_used() + n > p.map_cap() { return Err("storage: pool is provisioned to its limit"); }
+        let mut map = Vec::new();
+        map.resize(n, 0u32);
+        p.add(name, false, 0, map)
+    })
+}
+
+/// Read-only (`snapshot`) or writable copy of volume `from`, sharing all its chunks.
+pub fn copy(system_table: &SystemTable<Boot>, from: u32, name: &str, snapshot: bool) -> Result<u32, &'static str> {
+    update(system_table, |p| {
+        let i = p.vol(from).ok_or("storage: no such volume")?;
+        let map = p.vols[i].map.clone();
+        let id = p.add(name, snapshot, from, map)?;
+        if snapshot {
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::STORAGE_SNAPSHOTS).inc();
+        }
+        Ok(id)
+    })
+}
+
+/// Delete volume `id`, freeing the chunks only it used.
+pub fn delete(system_table: &SystemTable<Boot>, id: u32) -> Result<(), &'static str> {
+    if attached(Some(id)) { return Err("storage: volume is attached to a VM"); }
+    update(system_table, |p| {
+        let i = p.vol(id).ok_or("storage: no such volume")?;
+        let v = p.vols.remove(i);
+        for &c in v.map.iter().filter(|&&c| c != 0) { p.refs[c as usize] -= 1; }
+        Ok(())
+    })
+}
+
+// ---- Guest I/O (virtio-blk) ----
+
+/// Size in bytes, pool block size and whether the volume is a snapshot.
+pub(crate) fn geometry(id: u32) -> Option<(u64, u32, bool)> {
+    POOL.lock(|p| { let p = p.as_ref()?; let v = &p.vols[p.vol(id)?]; Some((v.map.len() as u64 * CHUNK, p.block_size, v.snapshot)) })
+}
+
+/// Read `buf.len()` bytes of volume `id` at `off`.
+pub(crate) fn read(dev: &mut Dev, id: u32, mut off: u64, buf: &mut [u8]) -> bool {
+    let mut done = 0usize;
+    while done < buf.len() {
+        let inner = off % CHUNK;
+        let n = ((CHUNK - inner) as usize).min(buf.len() - done);
+        let c = POOL.lock(|p| { let p = p.as_ref()?; p.vols[p.vol(id)?].map.get((off / CHUNK) as usize).copied() });
+        match c {
+            None => return false,
+            Some(0) => buf[done..done + n].fill(0),
+            Some(c) => if !dev.read(c as u64 * CHUNK + inner, &mut buf[done..done + n]) { return false; },
+        }
+        done += n;
+        off += n as u64;
+    }
+    true
+}
+
+/// Write `buf` to volume `id` at `off`, copying shared chunks first.
+pub(crate) fn write(dev: &mut Dev, id: u32, mut off: u64, buf: &[u8]) -> bool {
+    let mut done = 0usize;
+    while done < buf.len() {
+        let inner = off % CHUNK;
+        let n = ((CHUNK - inner) as usize).min(buf.len() - done);
+        let Some(c) = target(dev, id, (off / CHUNK) as usize) else { return false };
+        if !dev.write(c as u64 * CHUNK + inner, &buf[done..done + n]) { return false; }
+        done += n;
+        off += n as u64;
+    }
+    true
+}
+
+/// Pool chunk that takes writes to chunk `vc` of volume `id`: the mapped
+/// one if no other volume uses it, else a fresh chunk holding its contents
+/// (or zeros).
+fn target(dev: &mut Dev, id: u32, vc: usize) -> Option<u32> {
+    let (old, new) = POOL.lock(|p| {
+        let p = p.as_mut()?;
+        let i = p.vol(id)?;
+        if p.vols[i].snapshot { return None; }
+        let old = *p.vols[i].map.get(vc)?;
+        if old != 0 && p.refs[old as usize] == 1 { return Some((old, old)); }
+        let new = p.alloc()?;
+        if old != 0 { p.refs[old as usize] -= 1; }
+        p.vols[i].map[vc] = new;
+        p.dirty = true;
+        Some((old, new))
+    })?;
+    if old == new { return Some(new); }
+    let mut buf = [0u8; BOUNCE];
+    let mut ok = true;
+    let mut o = 0u64;
+    while ok && o < CHUNK {
+        ok = (old == 0 || dev.read(old as u64 * CHUNK + o, &mut buf)) && dev.write(new as u64 * CHUNK + o, &buf);
+        o += BOUNCE as u64;
+    }
+    if ok {
+        if old != 0 { crate::obs::metrics::Counter::new(&crate::obs::metrics::STORAGE_COW_COPIES).inc(); }
+        return Some(new);
+    }
+    // Put the old mapping back; the fresh chunk was never visible.
+    POOL.lock(|p| if let Some(p) = p.as_mut() {
+        if let Some(i) = p.vol(id) { p.vols[i].map[vc] = old; }
+        p.refs[new as usize] = 0;
+        if old != 0 { p.refs[old as usize] += 1; }
+    });
+    None
+}
+
+/// Guest FLUSH: write the metadata if allocations are pending, then flush the disk.
+pub(crate) fn flush(dev: &mut Dev) -> bool {
+    let ok = POOL.lock(|p| match p.as_mut() {
+        Some(p) if p.dirty => persist(dev, p).is_ok(),
+        Some(_) => true,
+        None => false,
+    });
+    ok && dev.flush()
+}
@@ -0,0 +1,254 @@
+#![allow(dead_code)]
+
+//! Thermal protection: back best-effort VMs off a hot package.
+//!
+//! Each tick reads every package's temperature (`arch::x86::thermal`), from
+//! the BSP for its own package and through a CPU of the package for the
+//! others. An AP serving a runqueue takes the reading between slices
+//! (`sample_local`), so its value can be one tick old. While a package is at
+//! or above the trip point the scheduling quota of the vCPUs homed on it is
+//! cut by `STEP_PCT` per tick, down to the floor; once it has cooled to the
+//! trip point minus the hysteresis the quota is given back a step at a
+//! time. The credit scheduler applies the quota to a VM through the lowest
+//! one among the packages its vCPUs run on; real-time vCPUs are picked
+//! outside credit and are never cut.
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::arch::x86::ap::{self, MAX_APS};
+use crate::arch::x86::thermal::{self, Kind};
+use crate::hv::sched::credit;
+use crate::obs::metrics::{THERMAL_MAX_C, THERMAL_THROTTLE_EVENTS};
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_PACKAGES: usize = 8;
+/// Sampling period of `tick`
+pub const INTERVAL_MS: u64 = 500;
+/// Quota change per tick, in percent
+pub const STEP_PCT: u8 = 10;
+/// Default lowest quota of a throttled package
+pub const DEFAULT_FLOOR_PCT: u8 = 30;
+/// Default distance below TjMax of the trip point
+pub const DEFAULT_MARGIN_C: i32 = 10;
+/// Trip point when the sensor has no TjMax
+pub const DEFAULT_TRIP_C: i32 = 85;
+pub const DEFAULT_HYSTERESIS_C: i32 = 5;
+/// How long a dispatched reading may take on an idle AP
+const JOB_TIMEOUT_US: u64 = 1000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub enabled: bool,
+    pub trip_c: i32,
+    pub hysteresis_c: i32,
+    pub floor_pct: u8,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Package {
+    pub id: u32,
+    pub temp_c: Option<i32>,
+    pub max_c: Option<i32>,
+    /// Quota of best-effort vCPUs homed on the package, percent
+    pub quota_pct: u8,
+    pub throttles: u64,
+    /// Logged that the package stays hot with the quota at the floor
+    exhausted: bool,
+}
+
+struct State {
+    probed: bool,
+    kind: Kind,
+    cfg: Config,
+    pkgs: [Option<Package>; MAX_PACKAGES],
+    /// `clock::now_ms` of the last reading (0 = none yet)
+    last_ms: u64,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    probed: false,
+    kind: Kind::None,
+    cfg: Config { enabled: false, trip_c: DEFAULT_TRIP_C, hysteresis_c: DEFAULT_HYSTERESIS_C, floor_pct: DEFAULT_FLOOR_PCT },
+    pkgs: [None; MAX_PACKAGES],
+    last_ms: 0,
+});
+
+/// Package slot of each AP (`u8::MAX` before the first tick sees it).
+const NO_PKG: AtomicU8 = AtomicU8::new(u8::MAX);
+static PKG_OF: [AtomicU8; MAX_APS] = [NO_PKG; MAX_APS];
+/// Quota per package slot, read by the scheduler
+const FULL: AtomicU8 = AtomicU8::new(100);
+static QUOTA: [AtomicU8; MAX_PACKAGES] = [FULL; MAX_PACKAGES];
+/// Reading an AP left between slices, `u32::MAX` when none is pending
+const NO_READING: AtomicU32 = AtomicU32::new(u32::MAX);
+static READING: [AtomicU32; MAX_PACKAGES] = [NO_READING; MAX_PACKAGES];
+const NO_WANT: AtomicU8 = AtomicU8::new(0);
+static WANT: [AtomicU8; MAX_APS] = [NO_WANT; MAX_APS];
+
+fn probe(s: &mut State) {
+    if s.probed { return; }
+    s.probed = true;
+    s.kind = thermal::probe();
+    if let Some(tj) = thermal::tjmax() { s.cfg.trip_c = tj as i32 - DEFAULT_MARGIN_C; }
+}
+
+/// Quota for a best-effort vCPU homed on AP `cpu`, percent.
+pub fn cpu_quota_pct(cpu: usize) -> u8 {
+    if cpu >= MAX_APS { return 100; }
+    let p = PKG_OF[cpu].load(Ordering::Relaxed) as usize;
+    if p < MAX_PACKAGES { QUOTA[p].load(Ordering::Relaxed) } else { 100 }
+}
+
+/// Called by an AP between slices: take a reading if one was asked for.
+pub fn sample_local(cpu: usize) {
+    if cpu >= MAX_APS || WANT[cpu].swap(0, Ordering::AcqRel) == 0 { return; }
+    let p = PKG_OF[cpu].load(Ordering::Relaxed) as usize;
+    if p >= MAX_PACKAGES { return; }
+    if let Some(t) = thermal::read() { READING[p].store(t as u32, Ordering::Release); }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub kind: Kind,
+    pub tjmax: Option<u8>,
+    pub cfg: Config,
+}
+
+pub fn status() -> Status {
+    STATE.lock(|s| { probe(s); Status { kind: s.kind, tjmax: thermal::tjmax(), cfg: s.cfg } })
+}
+
+/// Change the protection settings. Disabling restores every quota at once.
+pub fn configure(cfg: Config) -> Result<(), &'static str> {
+    if cfg.hysteresis_c < 0 { return Err("thermal: hysteresis must not be negative"); }
+    if cfg.floor_pct == 0 || cfg.floor_pct > 100 { return Err("thermal: floor must be 1..100"); }
+    STATE.lock(|s| {
+        probe(s);
+        if cfg.enabled && s.kind == Kind::None { return Err("thermal: no temperature sensor"); }
+        s.cfg = cfg;
+        if !cfg.enabled {
+            for (k, p) in s.pkgs.iter_mut().enumerate() {
+                if let Some(p) = p { p.quota_pct = 100; p.exhausted = false; }
+                QUOTA[k].store(100, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    })
+}
+
+pub fn for_each(mut f: impl FnMut(&Package)) {
+    for k in 0..MAX_PACKAGES {
+        let p = STATE.lock(|s| s.pkgs[k]);
+        if let Some(p) = p { f(&p); }
+    }
+}
+
+/// Slot of package `id`, adding it if new.
+fn slot(s: &mut State, id: u32) -> Option<usize> {
+    if let Some(k) = s.pkgs.iter().position(|p| matches!(p, Some(p) if p.id == id)) { return Some(k); }
+    let k = s.pkgs.iter().position(|p| p.is_none())?;
+    s.pkgs[k] = Some(Package { id, quota_pct: 100, ..Package::default() });
+    Some(k)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action { None, Throttled(u8), Restored(u8), Exhausted }
+
+/// Read the sensors and adjust package quotas. Runs at most every
+/// `INTERVAL_MS` unless `force`. Returns the hottest reading.
+pub fn tick(system_table: &mut SystemTable<Boot>, force: bool) -> Option<i32> {
+    if crate::time::clock::hz() == 0 { return None; }
+    let now = crate::time::clock::now_ms();
+    let due = STATE.lock(|s| {
+        probe(s);
+        if s.kind == Kind::None { return false; }
+        if s.last_ms != 0 && !force && now.saturating_sub(s.last_ms) < INTERVAL_MS { return false; }
+        s.last_ms = now;
+        true
+    });
+    if !due { return None; }
+
+    // Map APs to packages and pick one reader per package.
+    let shift = thermal::package_shift();
+    let bsp_pkg = (crate::arch::x86::cpuid::cpuid(1, 0).ebx >> 24) >> shift;
+    let mut reader: [Option<usize>; MAX_PACKAGES] = [None; MAX_PACKAGES];
+    let bsp_slot = STATE.lock(|s| slot(s, bsp_pkg));
+    for cpu in 0..MAX_APS {
+        let Some(apic) = ap::apic_id(cpu) else { continue };
+        let Some(k) = STATE.lock(|s| slot(s, apic >> shift)) else { continue };
+        PKG_OF[cpu].store(k as u8, Ordering::Relaxed);
+        if Some(k) != bsp_slot && (reader[k].is_none() || !credit::serving(cpu)) { reader[k] = Some(cpu); }
+    }
+
+    let mut temps: [Option<i32>; MAX_PACKAGES] = [None; MAX_PACKAGES];
+    if let Some(k) = bsp_slot { temps[k] = thermal::read(); }
+    for k in 0..MAX_PACKAGES {
+        let Some(cpu) = reader[k] else { continue };
+        if credit::serving(cpu) {
+            let t = READING[k].swap(u32::MAX, Ordering::AcqRel);
+            if t != u32::MAX { temps[k] = Some(t as i32); }
+            WANT[cpu].store(1, Ordering::Release);
+        } else if let Ok(t) = ap::run_on(system_table, cpu, thermal::job_read, 0, JOB_TIMEOUT_US) {
+            if t != u64::MAX { temps[k] = Some(t as u32 as i32); }
+        }
+    }
+
+    let mut hottest: Option<i32> = None;
+    for k in 0..MAX_PACKAGES {
+        let Some(t) = temps[k] else { continue };
+        hottest = Some(hottest.map_or(t, |h| h.max(t)));
+        let (action, id) = STATE.lock(|s| {
+            let cfg = s.cfg;
+            let Some(p) = s.pkgs[k].as_mut() else { return (Action::None, 0) };
+            p.temp_c = Some(t);
+            p.max_c = Some(p.max_c.map_or(t, |m| m.max(t)));
+            let action = if !cfg.enabled {
+                Action::None
+            } else if t >= cfg.trip_c {
+                if p.quota_pct > cfg.floor_pct {
+                    p.quota_pct = p.quota_pct.saturating_sub(STEP_PCT).max(cfg.floor_pct);
+                    p.throttles += 1;
+                    Action::Throttled(p.quota_pct)
+                } else if !p.exhausted {
+                    p.exhausted = true;
+                    Action::Exhausted
+                } else {
+                    Action::None
+                }
+            } else if t <= cfg.trip_c - cfg.hysteresis_c && p.quota_pct < 100 {
+                p.exhausted = false;
+                p.quota_pct = p.quota_pct.saturating_add(STEP_PCT).min(100);
+                Action::Restored(p.quota_pct)
+            } else {
+                Action::None
+            };
+            QUOTA[k].store(p.quota_pct, Ordering::Relaxed);
+            (action, p.id)
+        });
+        if action == Action::None { continue; }
+        let mut msg = [0u8; 96]; let mut n = 0;
+        for &b in b"package " { msg[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(id, &mut msg[n..]);
+        for &b in b" temp_c=" { msg[n] = b; n += 1; }
+        n += crate::util::format::i64_dec(t as i64, &mut msg[n..]);
+        match action {
+            Action::Throttled(q) | Action::Restored(q) => {
+                let down = matches!(action, Action::Throttled(_));
+                if down { THERMAL_THROTTLE_EVENTS.fetch_add(1, Ordering::Relaxed); }
+                for &b in if down { &b" throttle quota="[..] } else { &b" restore quota="[..] } { msg[n] = b; n += 1; }
+                n += crate::util::format::u32_dec(q as u32, &mut msg[n..]);
+                msg[n] = b'%'; n += 1;
+                let text = core::str::from_utf8(&msg[..n]).unwrap_or("quota change");
+                if down { crate::obs::log::warn(system_table, "thermal", text) } else { crate::obs::log::info(system_table, "thermal", text) }
+            }
+            _ => {
+                for &b in b" still hot at floor" { msg[n] = b; n += 1; }
+                crate::obs::log::error(system_table, "thermal", core::str::from_utf8(&msg[..n]).unwrap_or("hot at floor"));
+            }
+        }
+    }
+    THERMAL_MAX_C.store(hottest.map_or(0, |t| t.max(0) as u64), Ordering::Relaxed);
+    hottest
+}
@@ -3,6 +3,41 @@
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum VcpuState { Created, Running, Stopped }
 
+/// Architectural register state a vCPU enters the guest with.
+/// Filled by the image loader; consumed when guest state is programmed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BootRegs {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rsi: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub gdtr_base: u64,
+    pub gdtr_limit: u16,
+    pub cs: u16,
+    pub ds: u16,
+}
+
+/// General-purpose register file saved on VM exit, indexed by the x86
+/// register encoding (0 = RAX, 1 = RCX, 2 = RDX, 3 = RBX, 4 = RSP, ... 15 = R15).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GuestRegs {
+    pub gpr: [u64; 16],
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl GuestRegs {
+    pub const RAX: usize = 0;
+    pub const RCX: usize = 1;
+    pub const RDX: usize = 2;
+    pub const RSI: usize = 6;
+    pub const RDI: usize = 7;
+}
+
 #[derive(Debug)]
 pub struct Vcpu {
     pub id: u32,
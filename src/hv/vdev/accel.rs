@@ -0,0 +1,567 @@
+#![allow(dead_code)]
+
+//! Accelerator job queue (virtio transport, private device type 63).
+//!
+//! Guests reach host accelerators (QPU front ends, DMA and crypto engines,
+//! or the built-in host engine) through one virtqueue: a job is a
+//! descriptor chain, the notify register is the doorbell, and the job comes
+//! back on the used ring when the engine is done with it, in whatever order
+//! engines finish. A chain is
+//!
+//! - a device-readable 16-byte header: le16 engine, le16 opcode, le32
+//!   flags (must be 0), le64 argument;
+//! - the job's input, device-readable;
+//! - its output, device-writable;
+//! - a device-writable 8-byte completion: le32 status, le32 bytes written.
+//!
+//! The doorbell only marks the device pending. `pump` validates each chain
+//! (engine, opcode, sizes, buffers inside guest RAM) and forwards it to its
+//! engine, within the VM's quota: jobs in flight, bytes of payload per
+//! second, and the size of one job. A job the rate limit holds back stays
+//! with the device until the budget refills, and the doorbell is not
+//! looked at again until then. Completions are collected from the engines
+//! on the same pass and raise the device's INTx.
+//!
+//! Engines register an `EngineOps` table; `submit` takes a job or reports
+//! the engine full, `poll` hands back one finished job. Jobs carry guest
+//! addresses; an engine that DMAs translates them through
+//! `hv::loader::gpa_to_host`. A driver reset or the VM going away cancels
+//! what the engines still hold, and a completion that arrives for a job of
+//! an earlier reset is dropped.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use crate::obs::metrics::{Counter, ACCEL_JOBS, ACCEL_JOBS_REJECTED};
+use crate::util::spinlock::SpinLock;
+use super::{Chain, Kick, Seg, Transport};
+
+/// Not allocated by the virtio specification; private to this hypervisor.
+pub const VIRTIO_ID_ACCEL: u16 = 63;
+pub const MAX_DEVS: usize = 8;
+pub const MAX_ENGINES: usize = 4;
+pub const QUEUE_MAX: u16 = 64;
+pub const JOBQ: u16 = 0;
+/// ISA line used for INTx until the guest reprograms it
+pub const DEFAULT_IRQ: u8 = 10;
+pub const HEADER_LEN: usize = 16;
+pub const COMPLETION_LEN: usize = 8;
+
+/// Default quota of a new device
+pub const DEFAULT_INFLIGHT: u32 = 8;
+pub const DEFAULT_MAX_JOB: u32 = 1 << 20;
+
+// Completion status
+pub const S_OK: u32 = 0;
+/// Malformed chain, unknown engine or opcode
+pub const S_INVALID: u32 = 1;
+/// Payload over the per-job quota
+pub const S_TOO_BIG: u32 = 2;
+/// A buffer outside guest RAM
+pub const S_FAULT: u32 = 3;
+/// The engine failed the job
+pub const S_IO: u32 = 4;
+
+// Opcodes of the host engine
+pub const OP_NOP: u16 = 0;
+/// Copy the input to the output
+pub const OP_COPY: u16 = 1;
+/// le32 CRC-32 of the input
+pub const OP_CRC32: u16 = 2;
+
+/// engines, max_inflight, max_job_bytes, reserved, opcode mask per engine
+const CONFIG_LEN: u32 = 16 + 4 * MAX_ENGINES as u32;
+
+/// A job as an engine sees it.
+#[derive(Clone, Copy, Debug)]
+pub struct Job {
+    /// Opaque to the engine; handed back with the completion
+    pub tag: u64,
+    pub vm_id: u64,
+    pub opcode: u16,
+    pub arg: u64,
+    /// Payload buffers (the header and completion are not included)
+    pub chain: Chain,
+    pub in_bytes: u32,
+    pub out_bytes: u32,
+}
+
+impl Job {
+    pub fn input(&self) -> impl Iterator<Item = &Seg> { self.chain.iter().filter(|s| !s.writable) }
+    pub fn output(&self) -> impl Iterator<Item = &Seg> { self.chain.iter().filter(|s| s.writable) }
+}
+
+/// A finished job.
+#[derive(Clone, Copy, Debug)]
+pub struct Done { pub tag: u64, pub status: u32, pub written: u32 }
+
+/// What an engine driver provides.
+#[derive(Clone, Copy)]
+pub struct EngineOps {
+    /// Take `job`; false if the engine has no room for it now
+    pub submit: fn(ctx: u64, job: &Job) -> bool,
+    /// Next finished job, if any
+    pub poll: fn(ctx: u64) -> Option<Done>,
+    /// Drop every job of `vm_id` the engine still holds
+    pub cancel: fn(ctx: u64, vm_id: u64),
+    /// Bit n set if opcode n is supported
+    pub opcodes: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EngineStats { pub submitted: u64, pub completed: u64, pub failed: u64 }
+
+#[derive(Clone, Copy)]
+struct Engine { name: &'static str, ops: EngineOps, ctx: u64, stats: EngineStats }
+
+static ENGINES: SpinLock<[Option<Engine>; MAX_ENGINES]> = SpinLock::new([
+    Some(Engine { name: "host", ops: HOST_OPS, ctx: 0, stats: EngineStats { submitted: 0, completed: 0, failed: 0 } }),
+    None, None, None,
+]);
+
+/// Per-VM limits. `bytes_per_s` 0 = unlimited.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota { pub inflight: u32, pub bytes_per_s: u64, pub max_job: u32 }
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub submitted: u64,
+    pub completed: u64,
+    /// Failed before reaching an engine
+    pub rejected: u64,
+    /// Passes a job waited for the rate limit
+    pub throttled: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Dev {
+    t: Transport,
+    /// Bumped by every reset; part of each job's tag
+    gen: u16,
+    quota: Quota,
+    inflight: u32,
+    /// Rate budget left, bytes
+    tokens: u64,
+    /// `clock::now_ns` of the last refill (0 = none yet)
+    last_ns: u64,
+    /// Validated job waiting for budget or an engine
+    held: Option<(u16, Job)>,
+    /// Completion record of each job in the ring, by head (0 = none)
+    completion: [u64; QUEUE_MAX as usize],
+    /// Doorbell rung since the last pass
+    pending: bool,
+    stats: Stats,
+}
+
+static DEVS: SpinLock<[Option<Dev>; MAX_DEVS]> = SpinLock::new([None; MAX_DEVS]);
+static NEXT_GEN: AtomicU16 = AtomicU16::new(1);
+
+fn tag(idx: usize, gen: u16, head: u16) -> u64 { ((idx as u64) << 32) | ((gen as u64) << 16) | head as u64 }
+fn untag(tag: u64) -> (usize, u16, u16) { ((tag >> 32) as usize, (tag >> 16) as u16, tag as u16) }
+
+// ---- Guest-facing side (runs in the exit path) ----
+
+fn device_cfg_read(d: &Dev, off: u64, size: u8) -> u64 {
+    let mut cfg = [0u8; CONFIG_LEN as usize];
+    let masks = ENGINES.lock(|e| {
+        let mut m = [0u32; MAX_ENGINES];
+        for (o, e) in m.iter_mut().zip(e.iter()) { if let Some(e) = e { *o = e.ops.opcodes; } }
+        m
+    });
+    let engines = masks.iter().rposition(|&m| m != 0).map_or(0, |i| i + 1) as u32;
+    cfg[0..4].copy_from_slice(&engines.to_le_bytes());
+    cfg[4..8].copy_from_slice(&d.quota.inflight.to_le_bytes());
+    cfg[8..12].copy_from_slice(&d.quota.max_job.to_le_bytes());
+    for (i, m) in masks.iter().enumerate() { cfg[16 + i * 4..20 + i * 4].copy_from_slice(&m.to_le_bytes()); }
+    let mut v = 0u64;
+    for i in 0..size as usize {
+        let o = off as usize + i;
+        if o < cfg.len() { v |= (cfg[o] as u64) << (i * 8); }
+    }
+    v
+}
+
+fn cancel_engines(vm_id: u64) {
+    let engines = ENGINES.lock(|e| *e);
+    for e in engines.iter().flatten() { (e.ops.cancel)(e.ctx, vm_id); }
+}
+
+fn mmio(_vm_id: u64, _vcpu: u32, ctx: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    let (v, reset) = DEVS.lock(|t| {
+        let d = match t.get_mut(ctx as usize).and_then(|d| d.as_mut()) { Some(d) => d, None => return (0, None) };
+        if (super::DEVICE_OFF..super::NOTIFY_OFF).contains(&off) {
+            return (if write.is_none() { device_cfg_read(d, off - super::DEVICE_OFF, size) } else { 0 }, None);
+        }
+        let (v, kick) = d.t.mmio(off, size, write);
+        match kick {
+            Kick::Queue(JOBQ) => { d.pending = true; (v, None) }
+            Kick::Reset => {
+                d.gen = NEXT_GEN.fetch_add(1, Ordering::Relaxed);
+                d.inflight = 0;
+                d.held = None;
+                d.pending = false;
+                (v, Some(d.t.vm_id))
+            }
+            _ => (v, None),
+        }
+    });
+    if let Some(vm) = reset { cancel_engines(vm); }
+    v
+}
+
+fn on_bar(vm_id: u64, ctx: u64, _bar: usize, old: u64, new: u64) {
+    if old != 0 { let _ = crate::hv::bus::unregister_mmio(vm_id, old); }
+    if new != 0 { let _ = crate::hv::bus::register_mmio(vm_id, new, super::BAR_SIZE, "virtio-accel", ctx, mmio); }
+    let _ = DEVS.lock(|t| t.get_mut(ctx as usize).and_then(|d| d.as_mut()).map(|d| d.t.bar = new));
+}
+
+// ---- Host side ----
+
+/// True if every byte of `s` is backed guest RAM.
+fn backed(vm_id: u64, s: &Seg) -> bool {
+    let end = s.gpa.saturating_add(s.len as u64);
+    let mut a = s.gpa & !0xFFF;
+    while a < end {
+        if crate::hv::loader::gpa_to_host(vm_id, a).is_none() { return false; }
+        a += 0x1000;
+    }
+    true
+}
+
+/// Write the completion record of the job at `head` and hand the chain back.
+fn complete(d: &mut Dev, head: u16, status: u32, written: u32) {
+    let vm = d.t.vm_id;
+    let mut rec = [0u8; COMPLETION_LEN];
+    rec[0..4].copy_from_slice(&status.to_le_bytes());
+    rec[4..8].copy_from_slice(&written.to_le_bytes());
+    if let Some(&gpa) = d.completion.get(head as usize).filter(|&&g| g != 0) { let _ = super::write_guest(vm, gpa, &rec); }
+    let _ = d.t.queues[JOBQ as usize].push_used(vm, head, written + COMPLETION_LEN as u32);
+}
+
+/// Check a chain against the ABI and the quota. Ok is the job; Err is the
+/// status to fail it with.
+fn validate(d: &Dev, idx: usize, chain: &Chain, masks: &[u32; MAX_ENGINES]) -> Result<(u16, Job), u32> {
+    let vm = d.t.vm_id;
+    let n = chain.n;
+    if n < 2 { return Err(S_INVALID); }
+    let (first, last) = (chain.segs[0], chain.segs[n - 1]);
+    if first.writable || (first.len as usize) < HEADER_LEN || !last.writable || (last.len as usize) < COMPLETION_LEN { return Err(S_INVALID); }
+    let mut h = [0u8; HEADER_LEN];
+    if !super::read_guest(vm, first.gpa, &mut h) { return Err(S_FAULT); }
+    let engine = u16::from_le_bytes([h[0], h[1]]);
+    let opcode = u16::from_le_bytes([h[2], h[3]]);
+    let flags = u32::from_le_bytes([h[4], h[5], h[6], h[7]]);
+    let mut a = [0u8; 8];
+    a.copy_from_slice(&h[8..16]);
+    if flags != 0 || engine as usize >= MAX_ENGINES || opcode >= 32 || masks[engine as usize] & (1 << opcode) == 0 { return Err(S_INVALID); }
+
+    // Payload: readable input, then writable output; nothing readable after
+    // the output starts.
+    let mut job = Job { tag: tag(idx, d.gen, chain.head), vm_id: vm, opcode, arg: u64::from_le_bytes(a), chain: Chain { head: chain.head, ..Chain::default() }, in_bytes: 0, out_bytes: 0 };
+    let mut seen_out = false;
+    for s in &chain.segs[1..n - 1] {
+        if s.writable { seen_out = true; } else if seen_out { return Err(S_INVALID); }
+        let total = if s.writable { &mut job.out_bytes } else { &mut job.in_bytes };
+        *total = total.saturating_add(s.len);
+        if job.in_bytes.saturating_add(job.out_bytes) > d.quota.max_job { return Err(S_TOO_BIG); }
+        if !backed(vm, s) { return Err(S_FAULT); }
+        job.chain.segs[job.chain.n] = *s;
+        job.chain.n += 1;
+    }
+    if !backed(vm, &last) { return Err(S_FAULT); }
+    Ok((engine, job))
+}
+
+/// Refill the rate budget; true if `bytes` may go now. A job larger than a
+/// second's budget goes once the budget is full.
+fn budget(d: &mut Dev, bytes: u64, now_ns: u64) -> bool {
+    let rate = d.quota.bytes_per_s;
+    if rate == 0 { return true; }
+    if d.last_ns != 0 {
+        let dt = now_ns.saturating_sub(d.last_ns);
+        d.tokens = (d.tokens as u128 + rate as u128 * dt as u128 / 1_000_000_000).min(rate as u128) as u64;
+    }
+    d.last_ns = now_ns;
+    d.tokens >= bytes.min(rate)
+}
+
+/// Forward queued jobs to their engines and return finished ones to the
+/// guests. Returns the jobs completed.
+pub fn pump(limit: usize) -> usize {
+    let engines = ENGINES.lock(|e| *e);
+    let mut masks = [0u32; MAX_ENGINES];
+    for (m, e) in masks.iter_mut().zip(engines.iter()) { if let Some(e) = e { *m = e.ops.opcodes; } }
+    let now = crate::time::clock::now_ns();
+
+    // Completions first, so the slots they free count towards this pass.
+    let mut completed = 0usize;
+    for (k, e) in engines.iter().enumerate() {
+        let Some(e) = e else { continue };
+        while limit == 0 || completed < limit {
+            let Some(done) = (e.ops.poll)(e.ctx) else { break };
+            let ok = done.status == S_OK;
+            ENGINES.lock(|t| if let Some(x) = t[k].as_mut() { if ok { x.stats.completed += 1; } else { x.stats.failed += 1; } });
+            let (idx, gen, head) = untag(done.tag);
+            let live = DEVS.lock(|t| {
+                let Some(d) = t.get_mut(idx).and_then(|d| d.as_mut()).filter(|d| d.gen == gen) else { return false };
+                complete(d, head, done.status, if ok { done.written } else { 0 });
+                d.inflight = d.inflight.saturating_sub(1);
+                d.stats.completed += 1;
+                d.t.interrupt();
+                true
+            });
+            if live { completed += 1; Counter::new(&ACCEL_JOBS).inc(); }
+        }
+    }
+
+    for idx in 0..MAX_DEVS {
+        loop {
+            let step = DEVS.lock(|t| {
+                let d = t[idx].as_mut()?;
+                if !d.t.driver_ok() || d.inflight >= d.quota.inflight { return None; }
+                let (engine, job) = match d.held.take() {
+                    Some(h) => h,
+                    None => {
+                        if !d.pending { return None; }
+                        let Some(chain) = d.t.queues[JOBQ as usize].pop(d.t.vm_id) else { d.pending = false; return None };
+                        let last = chain.segs[chain.n.max(1) - 1];
+                        if let Some(c) = d.completion.get_mut(chain.head as usize) {
+                            *c = if last.writable && last.len as usize >= COMPLETION_LEN { last.gpa } else { 0 };
+                        }
+                        match validate(d, idx, &chain, &masks) {
+                            Ok(j) => j,
+                            Err(status) => {
+                                complete(d, chain.head, status, 0);
+                                d.stats.rejected += 1;
+                                d.t.interrupt();
+                                return Some(None);
+                            }
+                        }
+                    }
+                };
+                if !budget(d, job.in_bytes as u64 + job.out_bytes as u64, now) {
+                    d.stats.throttled += 1;
+                    d.held = Some((engine, job));
+                    return None;
+                }
+                Some(Some((engine, job)))
+            });
+            let (engine, job) = match step {
+                None => break,
+                Some(None) => { Counter::new(&ACCEL_JOBS_REJECTED).inc(); continue; }
+                Some(Some(j)) => j,
+            };
+            let submitted = engines[engine as usize].is_some_and(|e| (e.ops.submit)(e.ctx, &job));
+            let bytes = job.in_bytes as u64 + job.out_bytes as u64;
+            let gen = untag(job.tag).1;
+            DEVS.lock(|t| {
+                let Some(d) = t[idx].as_mut().filter(|d| d.gen == gen) else { return };
+                if submitted {
+                    d.inflight += 1;
+                    d.stats.submitted += 1;
+                    d.stats.bytes += bytes;
+                    d.tokens = d.tokens.saturating_sub(bytes.min(d.quota.bytes_per_s));
+                } else {
+                    // Engine full: keep the job for the next pass.
+                    d.held = Some((engine, job));
+                }
+            });
+            if !submitted { break; }
+            ENGINES.lock(|t| if let Some(x) = t[engine as usize].as_mut() { x.stats.submitted += 1; });
+        }
+    }
+    completed
+}
+
+// ---- Host engine ----
+
+/// Jobs the host engine holds; it runs one per `poll`.
+const HOST_DEPTH: usize = 16;
+const HOST_OPS: EngineOps = EngineOps {
+    submit: host_submit,
+    poll: host_poll,
+    cancel: host_cancel,
+    opcodes: (1 << OP_NOP) | (1 << OP_COPY) | (1 << OP_CRC32),
+};
+
+/// Ring of jobs in submission order; a cancelled job leaves a hole.
+struct HostQueue { jobs: [Option<Job>; HOST_DEPTH], head: usize, len: usize }
+
+static HOST: SpinLock<HostQueue> = SpinLock::new(HostQueue { jobs: [None; HOST_DEPTH], head: 0, len: 0 });
+
+fn host_submit(_: u64, job: &Job) -> bool {
+    HOST.lock(|q| {
+        if q.len == HOST_DEPTH { return false; }
+        q.jobs[(q.head + q.len) % HOST_DEPTH] = Some(*job);
+        q.len += 1;
+        true
+    })
+}
+
+fn host_cancel(_: u64, vm_id: u64) {
+    HOST.lock(|q| for j in q.jobs.iter_mut() { if matches!(j, Some(x) if x.vm_id == vm_id) { *j = None; } });
+}
+
+/// Run the oldest job. The queue lock is held throughout so a cancel cannot
+/// free the guest buffers under it.
+fn host_poll(_: u64) -> Option<Done> {
+    HOST.lock(|q| {
+        let job = loop {
+            if q.len == 0 { return None; }
+            let j = q.jobs[q.head].take();
+            q.head = (q.head + 1) % HOST_DEPTH;
+            q.len -= 1;
+            if let Some(j) = j { break j; }
+        };
+        let vm = job.vm_id;
+        let (status, written) = match job.opcode {
+            OP_NOP => (S_OK, 0),
+            OP_COPY => {
+                let mut buf = [0u8; 512];
+                let mut outs = job.output();
+                let mut cur = outs.next().copied();
+                let (mut at, mut written, mut ok) = (0u32, 0u32, true);
+                'copy: for s in job.input() {
+                    let mut off = 0u32;
+                    while off < s.len {
+                        let Some(o) = cur else { break 'copy };
+                        let take = (s.len - off).min(o.len - at).min(buf.len() as u32) as usize;
+                        ok &= super::read_guest(vm, s.gpa + off as u64, &mut buf[..take])
+                            && super::write_guest(vm, o.gpa + at as u64, &buf[..take]);
+                        off += take as u32;
+                        at += take as u32;
+                        written += take as u32;
+                        if at == o.len { cur = outs.next().copied(); at = 0; }
+                    }
+                }
+                if ok { (S_OK, written) } else { (S_IO, 0) }
+            }
+            OP_CRC32 => {
+                let mut c = 0u32;
+                let mut buf = [0u8; 512];
+                let mut ok = true;
+                for s in job.input() {
+                    let mut off = 0u32;
+                    while ok && off < s.len {
+                        let take = (s.len - off).min(buf.len() as u32) as usize;
+                        ok = super::read_guest(vm, s.gpa + off as u64, &mut buf[..take]);
+                        c = crate::util::crc32::crc32_update(c, &buf[..take]);
+                        off += take as u32;
+                    }
+                }
+                let crc = c.to_le_bytes();
+                match job.output().next() {
+                    Some(o) if ok && o.len >= 4 && super::write_guest(vm, o.gpa, &crc) => (S_OK, 4),
+                    _ => (S_IO, 0),
+                }
+            }
+            _ => (S_INVALID, 0),
+        };
+        Some(Done { tag: job.tag, status, written })
+    })
+}
+
+// ---- Management ----
+
+/// Add an engine for guests to submit to. Returns its index.
+pub fn register(name: &'static str, ops: EngineOps, ctx: u64) -> Result<u16, &'static str> {
+    ENGINES.lock(|t| {
+        if t.iter().flatten().any(|e| e.name == name) { return Err("vaccel: engine name taken"); }
+        let i = t.iter().position(|e| e.is_none()).ok_or("vaccel: too many engines")?;
+        t[i] = Some(Engine { name, ops, ctx, stats: EngineStats::default() });
+        Ok(i as u16)
+    })
+}
+
+/// Plug a job queue into `vm_id`'s PCI bus (one per VM). Returns (device index, PCI device number).
+pub fn add(vm_id: u64) -> Result<(usize, u8), &'static str> {
+    let idx = DEVS.lock(|t| {
+        if t.iter().flatten().any(|d| d.t.vm_id == vm_id) { return Err("vaccel: VM already has a job queue"); }
+        let i = t.iter().position(|d| d.is_none()).ok_or("vaccel: too many job queues")?;
+        t[i] = Some(Dev {
+            t: Transport::new(vm_id, 1, QUEUE_MAX, 0),
+            gen: NEXT_GEN.fetch_add(1, Ordering::Relaxed),
+            quota: Quota { inflight: DEFAULT_INFLIGHT, bytes_per_s: 0, max_job: DEFAULT_MAX_JOB },
+            inflight: 0, tokens: 0, last_ns: 0, held: None, completion: [0; QUEUE_MAX as usize],
+            pending: false, stats: Stats::default(),
+        });
+        Ok(i)
+    })?;
+    // Processing accelerator, other
+    let cfg = super::pci_config(VIRTIO_ID_ACCEL, 0x12, 0x00, CONFIG_LEN, DEFAULT_IRQ);
+    match crate::hv::vpci::add(vm_id, cfg, idx as u64, on_bar) {
+        Some(dev) => {
+            DEVS.lock(|t| if let Some(d) = t[idx].as_mut() { d.t.dev = dev; });
+            Ok((idx, dev))
+        }
+        None => {
+            DEVS.lock(|t| t[idx] = None);
+            Err("vaccel: no free PCI slot")
+        }
+    }
+}
+
+/// Remove the job queue of `vm_id` and cancel what its engines still hold.
+pub fn detach_vm(vm_id: u64) {
+    let had = DEVS.lock(|t| {
+        let mut had = false;
+        for d in t.iter_mut() { if matches!(d, Some(x) if x.t.vm_id == vm_id) { *d = None; had = true; } }
+        had
+    });
+    if had { cancel_engines(vm_id); }
+}
+
+/// Device index of `vm_id`.
+pub fn find(vm_id: u64) -> Option<usize> {
+    DEVS.lock(|t| t.iter().position(|d| matches!(d, Some(d) if d.t.vm_id == vm_id)))
+}
+
+/// Change the quota of `vm_id`'s job queue. Jobs already in flight are not
+/// recalled when `inflight` drops below them.
+pub fn set_quota(vm_id: u64, q: Quota) -> Result<(), &'static str> {
+    if q.inflight == 0 || q.inflight > QUEUE_MAX as u32 { return Err("vaccel: inflight must be 1..64"); }
+    if q.max_job == 0 { return Err("vaccel: max job size must not be 0"); }
+    DEVS.lock(|t| match t.iter_mut().flatten().find(|d| d.t.vm_id == vm_id) {
+        Some(d) => {
+            d.quota = q;
+            d.tokens = d.tokens.min(q.bytes_per_s);
+            // The driver reads the limits from device config.
+            d.t.config_changed();
+            Ok(())
+        }
+        None => Err("vaccel: vm has no job queue"),
+    })
+}
+
+/// Job queue state for reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct AccelInfo {
+    pub vm_id: u64,
+    pub dev: u8,
+    pub driver_ok: bool,
+    pub quota: Quota,
+    pub inflight: u32,
+    /// A job is waiting for budget or an engine
+    pub held: bool,
+    pub stats: Stats,
+}
+
+/// Iterate job queues as (index, info).
+pub fn for_each(mut f: impl FnMut(usize, &AccelInfo)) {
+    let mut snap: [Option<AccelInfo>; MAX_DEVS] = [None; MAX_DEVS];
+    DEVS.lock(|t| {
+        for (i, d) in t.iter().enumerate() {
+            snap[i] = d.as_ref().map(|d| AccelInfo {
+                vm_id: d.t.vm_id, dev: d.t.dev, driver_ok: d.t.driver_ok(), quota: d.quota,
+                inflight: d.inflight, held: d.held.is_some(), stats: d.stats,
+            });
+        }
+    });
+    for (i, d) in snap.iter().enumerate() { if let Some(d) = d { f(i, d); } }
+}
+
+/// Iterate engines as (index, name, opcode mask, stats).
+pub fn for_each_engine(mut f: impl FnMut(usize, &str, u32, &EngineStats)) {
+    let snap = ENGINES.lock(|t| *t);
+    for (i, e) in snap.iter().enumerate() { if let Some(e) = e { f(i, e.name, e.ops.opcodes, &e.stats); } }
+}
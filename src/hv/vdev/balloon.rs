@@ -0,0 +1,413 @@
+#![allow(dead_code)]
+
+//! virtio-balloon device model (virtio device type 5).
+//!
+//! The host sets a target in `num_pages`; the guest driver inflates by
+//! posting page frame numbers on the inflate queue and deflates through the
+//! deflate queue. Notifies only mark the device pending: `pump`, which can
+//! allocate page tables, unmaps inflated pages from the guest's stage-2
+//! table and maps deflated ones back before acknowledging the chain, as
+//! MUST_TELL_HOST asks. The frames stay in the VM's RAM extent; what the
+//! balloon holds is reported to `mm::guest` as lent, which takes it off the
+//! overcommit limit so other VMs can reserve it. Other vCPUs pick up the
+//! change through the stage-2 generation at their next entry.
+//!
+//! `reclaim` and `relax` are the policy hooks for memory pressure: they
+//! raise or lower targets across all balloons, preferring guests that
+//! report free memory through the statistics queue, and never push a guest
+//! below `FLOOR_PCT` of its RAM. A driver reset maps everything back.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::util::spinlock::SpinLock;
+use super::{Kick, Transport};
+
+pub const VIRTIO_ID_BALLOON: u16 = 5;
+pub const MAX_BALLOONS: usize = 8;
+pub const QUEUE_MAX: u16 = 128;
+pub const INFLATEQ: u16 = 0;
+pub const DEFLATEQ: u16 = 1;
+pub const STATSQ: u16 = 2;
+/// ISA line used for INTx until the guest reprograms it
+pub const DEFAULT_IRQ: u8 = 9;
+pub const PAGE: u64 = 4096;
+/// Share of its RAM `reclaim` always leaves a guest
+pub const FLOOR_PCT: u64 = 25;
+
+pub const F_MUST_TELL_HOST: u64 = 1 << 0;
+pub const F_STATS_VQ: u64 = 1 << 1;
+
+/// num_pages, actual
+const CONFIG_LEN: u32 = 8;
+const CFG_ACTUAL: u64 = 4;
+
+// virtio_balloon_stat tags
+const TAG_MEMFREE: u16 = 4;
+const TAG_MEMTOT: u16 = 5;
+const TAG_AVAIL: u16 = 6;
+/// le16 tag + le64 value
+const STAT_LEN: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Pages unmapped on inflate
+    pub inflated: u64,
+    /// Pages mapped back on deflate or reset
+    pub deflated: u64,
+    /// Frame numbers outside guest RAM, or already in the requested state
+    pub ignored: u64,
+    /// Stage-2 updates that failed for lack of table memory
+    pub errors: u64,
+    pub stat_updates: u64,
+}
+
+/// Memory figures the guest last reported, in bytes (0 = not reported).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GuestMem { pub free: u64, pub available: u64, pub total: u64 }
+
+#[derive(Clone, Copy)]
+struct Balloon {
+    t: Transport,
+    /// Pages the host wants in the balloon (config `num_pages`)
+    target: u32,
+    /// Pages the driver says it holds (config `actual`)
+    actual: u32,
+    /// Pages currently unmapped from the guest
+    held: u64,
+    /// Queue work for `pump`
+    pending: bool,
+    /// Driver reset; map everything back
+    refill: bool,
+    /// Statistics buffer kept until the next refresh
+    stats_head: Option<u16>,
+    mem: GuestMem,
+    stats: Stats,
+}
+
+static BALLOONS: SpinLock<[Option<Balloon>; MAX_BALLOONS]> = SpinLock::new([None; MAX_BALLOONS]);
+
+// ---- Guest-facing side (runs in the exit path) ----
+
+fn device_cfg_read(b: &Balloon, off: u64, size: u8) -> u64 {
+    let mut cfg = [0u8; CONFIG_LEN as usize];
+    cfg[0..4].copy_from_slice(&b.target.to_le_bytes());
+    cfg[4..8].copy_from_slice(&b.actual.to_le_bytes());
+    let mut v = 0u64;
+    for i in 0..size as usize {
+        let o = off as usize + i;
+        if o < cfg.len() { v |= (cfg[o] as u64) << (i * 8); }
+    }
+    v
+}
+
+/// Take the statistics buffer the driver posted and parse it.
+fn take_stats(b: &mut Balloon) {
+    let vm = b.t.vm_id;
+    while let Some(chain) = b.t.queues[STATSQ as usize].pop(vm) {
+        // Only one buffer is outstanding at a time; a second replaces it.
+        if let Some(old) = b.stats_head.take() { let _ = b.t.queues[STATSQ as usize].push_used(vm, old, 0); }
+        let mut buf = [0u8; STAT_LEN * 16];
+        let n = chain.read(vm, &mut buf);
+        for e in buf[..n - n % STAT_LEN].chunks(STAT_LEN) {
+            let tag = u16::from_le_bytes([e[0], e[1]]);
+            let mut v = [0u8; 8];
+            v.copy_from_slice(&e[2..10]);
+            let v = u64::from_le_bytes(v);
+            match tag {
+                TAG_MEMFREE => b.mem.free = v,
+                TAG_MEMTOT => b.mem.total = v,
+                TAG_AVAIL => b.mem.available = v,
+                _ => {}
+            }
+        }
+        b.stats_head = Some(chain.head);
+        b.stats.stat_updates += 1;
+    }
+}
+
+fn mmio(_vm_id: u64, _vcpu: u32, ctx: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    BALLOONS.lock(|t| {
+        let b = match t.get_mut(ctx as usize).and_then(|b| b.as_mut()) { Some(b) => b, None => return 0 };
+        if (super::DEVICE_OFF..super::NOTIFY_OFF).contains(&off) {
+            let o = off - super::DEVICE_OFF;
+            return match write {
+                None => device_cfg_read(b, o, size),
+                Some(v) => { if o == CFG_ACTUAL { b.actual = v as u32; } 0 }
+            };
+        }
+        let (v, kick) = b.t.mmio(off, size, write);
+        match kick {
+            Kick::Queue(INFLATEQ) | Kick::Queue(DEFLATEQ) => b.pending = true,
+            Kick::Queue(STATSQ) => take_stats(b),
+            Kick::Reset => {
+                b.actual = 0;
+                b.stats_head = None;
+                b.refill = b.held != 0;
+                b.pending = b.refill;
+            }
+            _ => {}
+        }
+        v
+    })
+}
+
+fn on_bar(vm_id: u64, ctx: u64, _bar: usize, old: u64, new: u64) {
+    if old != 0 { let _ = crate::hv::bus::unregister_mmio(vm_id, old); }
+    if new != 0 { let _ = crate::hv::bus::register_mmio(vm_id, new, super::BAR_SIZE, "virtio-balloon", ctx, mmio); }
+    let _ = BALLOONS.lock(|t| t.get_mut(ctx as usize).and_then(|b| b.as_mut()).map(|b| b.t.bar = new));
+}
+
+// ---- Host side ----
+
+/// Guest RAM of one VM as `pump` needs it.
+#[derive(Clone, Copy)]
+struct Ram { root: u64, host: u64, bytes: u64, fmt: crate::mm::stage2::Format, caps: crate::mm::ept::EptCaps }
+
+fn ram_of(vm_id: u64) -> Option<Ram> {
+    let img = crate::hv::loader::find_image(vm_id).filter(|g| g.root_phys != 0)?;
+    let fmt = crate::hv::loader::stage2_format(vm_id)?;
+    Some(Ram { root: img.root_phys, host: img.ram_host, bytes: img.ram_bytes, fmt, caps: crate::mm::stage2::caps(fmt) })
+}
+
+/// Apply the frame numbers of one chain: unmap them (inflate) or map them
+/// back (deflate). Returns the pages changed.
+fn apply(st: &SystemTable<Boot>, b: &mut Balloon, ram: Option<Ram>, chain: &super::Chain, inflate: bool) -> u64 {
+    let vm = b.t.vm_id;
+    let mut changed = 0u64;
+    for s in chain.iter().filter(|s| !s.writable) {
+        let mut off = 0u64;
+        while off + 4 <= s.len as u64 {
+            let mut buf = [0u8; 256];
+            let take = (((s.len as u64 - off) as usize).min(buf.len())) & !3;
+            if !super::read_guest(vm, s.gpa + off, &mut buf[..take]) { return changed; }
+            for p in buf[..take].chunks(4) {
+                let gpa = (u32::from_le_bytes([p[0], p[1], p[2], p[3]]) as u64) << 12;
+                let r = match ram {
+                    Some(r) if gpa < r.bytes => if inflate {
+                        crate::hv::ksm::unshare(vm, gpa);
+                        crate::mm::stage2::unmap(st, r.root, gpa, PAGE)
+                    } else {
+                        crate::mm::stage2::fill_offset(st, r.fmt, r.root, gpa, PAGE, r.host, r.caps)
+                    },
+                    _ => Ok(0),
+                };
+                match r {
+                    Ok(0) => b.stats.ignored += 1,
+                    Ok(_) => changed += 1,
+                    Err(_) => b.stats.errors += 1,
+                }
+            }
+            off += take as u64;
+        }
+    }
+    changed
+}
+
+/// Service inflate and deflate requests and driver resets. Returns the
+/// chains completed.
+pub fn pump(system_table: &mut SystemTable<Boot>, limit: usize) -> usize {
+    let st: &SystemTable<Boot> = system_table;
+    let mut completed = 0usize;
+    for idx in 0..MAX_BALLOONS {
+        let vm_id = match BALLOONS.lock(|t| t[idx].as_ref().filter(|b| b.pending).map(|b| b.t.vm_id)) { Some(v) => v, None => continue };
+        let ram = ram_of(vm_id);
+        let held = BALLOONS.lock(|t| {
+            let b = t[idx].as_mut()?;
+            b.pending = false;
+            if b.refill {
+                b.refill = false;
+                if let Some(r) = ram {
+                    match crate::mm::stage2::fill_offset(st, r.fmt, r.root, 0, r.bytes, r.host, r.caps) {
+                        Ok(n) => { b.stats.deflated += n as u64; crate::obs::metrics::Counter::new(&crate::obs::metrics::BALLOON_DEFLATED).add(n as u64); }
+                        Err(_) => b.stats.errors += 1,
+                    }
+                }
+                b.held = 0;
+            }
+            let mut n = 0usize;
+            for (q, inflate) in [(INFLATEQ, true), (DEFLATEQ, false)] {
+                while limit == 0 || completed + n < limit {
+                    let chain = match b.t.queues[q as usize].pop(vm_id) { Some(c) => c, None => break };
+                    let pages = apply(st, b, ram, &chain, inflate);
+                    if inflate {
+                        b.held += pages;
+                        b.stats.inflated += pages;
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::BALLOON_INFLATED).add(pages);
+                    } else {
+                        b.held = b.held.saturating_sub(pages);
+                        b.stats.deflated += pages;
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::BALLOON_DEFLATED).add(pages);
+                    }
+                    let _ = b.t.queues[q as usize].push_used(vm_id, chain.head, 0);
+                    n += 1;
+                }
+                // Out of budget: come back for the rest.
+                if b.t.queues[q as usize].has_avail(vm_id) { b.pending = true; }
+            }
+            if n != 0 { b.t.interrupt(); }
+            completed += n;
+            Some(b.held)
+        });
+        if let Some(h) = held { let _ = crate::mm::guest::set_lent(vm_id, h * PAGE); }
+    }
+    completed
+}
+
+// ---- Management ----
+
+/// Plug a balloon into `vm_id`'s PCI bus (one per VM). Returns (balloon index, PCI device number).
+pub fn add(vm_id: u64) -> Result<(usize, u8), &'static str> {
+    let idx = BALLOONS.lock(|t| {
+        if t.iter().flatten().any(|b| b.t.vm_id == vm_id) { return Err("vballoon: VM already has a balloon"); }
+        let i = t.iter().position(|b| b.is_none()).ok_or("vballoon: too many balloons")?;
+        t[i] = Some(Balloon {
+            t: Transport::new(vm_id, 3, QUEUE_MAX, F_MUST_TELL_HOST | F_STATS_VQ),
+            target: 0, actual: 0, held: 0, pending: false, refill: false, stats_head: None,
+            mem: GuestMem::default(), stats: Stats::default(),
+        });
+        Ok(i)
+    })?;
+    let cfg = super::pci_config(VIRTIO_ID_BALLOON, 0xFF, 0x00, CONFIG_LEN, DEFAULT_IRQ);
+    match crate::hv::vpci::add(vm_id, cfg, idx as u64, on_bar) {
+        Some(dev) => {
+            BALLOONS.lock(|t| if let Some(b) = t[idx].as_mut() { b.t.dev = dev; });
+            Ok((idx, dev))
+        }
+        None => {
+            BALLOONS.lock(|t| t[idx] = None);
+            Err("vballoon: no free PCI slot")
+        }
+    }
+}
+
+/// Remove the balloon of `vm_id` (its RAM goes with the VM).
+pub fn detach_vm(vm_id: u64) {
+    BALLOONS.lock(|t| { for b in t.iter_mut() { if matches!(b, Some(x) if x.t.vm_id == vm_id) { *b = None; } } });
+}
+
+/// Drop the held-page count of `vm_id` after its RAM was replaced.
+pub fn forget_held(vm_id: u64) {
+    BALLOONS.lock(|t| {
+        for b in t.iter_mut().flatten().filter(|b| b.t.vm_id == vm_id) { b.held = 0; b.refill = false; }
+    });
+    let _ = crate::mm::guest::set_lent(vm_id, 0);
+}
+
+/// Balloon index of `vm_id`.
+pub fn find(vm_id: u64) -> Option<usize> {
+    BALLOONS.lock(|t| t.iter().position(|b| matches!(b, Some(b) if b.t.vm_id == vm_id)))
+}
+
+/// Set `b`'s target and tell the driver.
+fn retarget(b: &mut Balloon, pages: u32) {
+    if b.target == pages { return; }
+    b.target = pages;
+    b.t.config_changed();
+}
+
+/// Ask the guest of `vm_id` to hold `bytes` in its balloon (rounded down to
+/// pages). Returns the target in pages.
+pub fn set_target(vm_id: u64, bytes: u64) -> Result<u32, &'static str> {
+    let ram = crate::hv::loader::find_image(vm_id).map(|g| g.ram_bytes).ok_or("vballoon: vm has no guest RAM image")?;
+    if bytes > ram { return Err("vballoon: target exceeds guest RAM"); }
+    let pages = (bytes / PAGE) as u32;
+    BALLOONS.lock(|t| match t.iter_mut().flatten().find(|b| b.t.vm_id == vm_id) {
+        Some(b) => { retarget(b, pages); Ok(pages) }
+        None => Err("vballoon: vm has no balloon"),
+    })
+}
+
+/// Hand the statistics buffer back so the driver reports fresh figures.
+pub fn request_stats(vm_id: u64) {
+    BALLOONS.lock(|t| {
+        for b in t.iter_mut().flatten().filter(|b| b.t.vm_id == vm_id) {
+            if let Some(h) = b.stats_head.take() {
+                let _ = b.t.queues[STATSQ as usize].push_used(vm_id, h, 0);
+                b.t.interrupt();
+            }
+        }
+    });
+}
+
+/// Current target and how far it may still rise, per balloon, in pages.
+fn headroom() -> [Option<(u32, u64)>; MAX_BALLOONS] {
+    let snap = BALLOONS.lock(|t| *t);
+    let mut out = [None; MAX_BALLOONS];
+    for (o, b) in out.iter_mut().zip(snap.iter()) {
+        let Some(b) = b else { continue };
+        if !b.t.driver_ok() { continue; }
+        let Some(ram) = crate::hv::loader::find_image(b.t.vm_id).map(|g| g.ram_bytes / PAGE) else { continue };
+        let mut max = ram * (100 - FLOOR_PCT) / 100;
+        // A guest that reports its free memory is not asked for more than that.
+        if b.mem.free != 0 { max = max.min(b.actual as u64 + b.mem.free / PAGE); }
+        *o = Some((b.target, max.saturating_sub(b.target as u64)));
+    }
+    out
+}
+
+/// Raise balloon targets to take back up to `bytes` from the guests, the
+/// ones with the most room first. Returns the bytes asked for.
+pub fn reclaim(bytes: u64) -> u64 {
+    let mut room = headroom();
+    let mut want = (bytes + PAGE - 1) / PAGE;
+    let mut asked = 0u64;
+    while want != 0 {
+        let Some(i) = (0..MAX_BALLOONS).filter(|&i| matches!(room[i], Some((_, r)) if r != 0)).max_by_key(|&i| room[i].map(|(_, r)| r)) else { break };
+        let (target, r) = room[i].unwrap_or((0, 0));
+        let take = r.min(want);
+        BALLOONS.lock(|t| if let Some(b) = t[i].as_mut() { retarget(b, target + take as u32); });
+        room[i] = Some((target + take as u32, r - take));
+        want -= take;
+        asked += take;
+    }
+    asked * PAGE
+}
+
+/// Lower balloon targets to give up to `bytes` back to the guests, the
+/// largest balloons first. Returns the bytes released.
+pub fn relax(bytes: u64) -> u64 {
+    let mut targets = BALLOONS.lock(|t| {
+        let mut out = [0u32; MAX_BALLOONS];
+        for (o, b) in out.iter_mut().zip(t.iter()) { if let Some(b) = b { *o = b.target; } }
+        out
+    });
+    let mut want = (bytes + PAGE - 1) / PAGE;
+    let mut given = 0u64;
+    while want != 0 {
+        let Some(i) = (0..MAX_BALLOONS).filter(|&i| targets[i] != 0).max_by_key(|&i| targets[i]) else { break };
+        let take = (targets[i] as u64).min(want);
+        targets[i] -= take as u32;
+        BALLOONS.lock(|t| if let Some(b) = t[i].as_mut() { retarget(b, targets[i]); });
+        want -= take;
+        given += take;
+    }
+    given * PAGE
+}
+
+/// Balloon state for reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct BalloonInfo {
+    pub vm_id: u64,
+    pub dev: u8,
+    pub driver_ok: bool,
+    pub target_pages: u32,
+    pub actual_pages: u32,
+    pub held_pages: u64,
+    pub mem: GuestMem,
+    pub stats: Stats,
+}
+
+/// Iterate balloons as (index, info).
+pub fn for_each(mut f: impl FnMut(usize, &BalloonInfo)) {
+    let mut snap: [Option<BalloonInfo>; MAX_BALLOONS] = [None; MAX_BALLOONS];
+    BALLOONS.lock(|t| {
+        for (i, b) in t.iter().enumerate() {
+            snap[i] = b.as_ref().map(|b| BalloonInfo {
+                vm_id: b.t.vm_id, dev: b.t.dev, driver_ok: b.t.driver_ok(), target_pages: b.target,
+                actual_pages: b.actual, held_pages: b.held, mem: b.mem, stats: b.stats,
+            });
+        }
+    });
+    for (i, b) in snap.iter().enumerate() { if let Some(b) = b { f(i, b); } }
+}
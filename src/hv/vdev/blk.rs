@@ -0,0 +1,474 @@
+#![allow(dead_code)]
+
+//! virtio-blk device model (virtio device type 2).
+//!
+//! A disk is backed by a file on the ESP the hypervisor was started from, by
+//! a range of blocks on a host disk reached through UEFI Block I/O (the host
+//! virtio-blk disk shows up there once firmware has bound it), by a thin
+//! volume of the `storage` pool, or by a namespace of a controller the
+//! `nvme` driver owns. Most of them need boot services, so the exit path
+//! only marks the request queue as pending; `pump` (CLI idle loop or `vm blk pump`) pops the chains, does
+//! the I/O through a 4KiB bounce buffer and completes them.
+//!
+//! Supported requests: IN, OUT, FLUSH and GET_ID. A read-only disk
+//! advertises VIRTIO_BLK_F_RO and fails OUT with IOERR.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode, RegularFile};
+use uefi::CStr16;
+
+use crate::util::spinlock::SpinLock;
+use super::{Chain, Kick, Transport};
+
+pub const VIRTIO_ID_BLOCK: u16 = 2;
+pub const MAX_DISKS: usize = 8;
+pub const QUEUE_MAX: u16 = 128;
+pub const REQQ: u16 = 0;
+pub const SECTOR: u64 = 512;
+/// ISA line used for INTx until the guest reprograms it
+pub const DEFAULT_IRQ: u8 = 10;
+pub const PATH_MAX: usize = 96;
+
+pub const F_SEG_MAX: u64 = 1 << 2;
+pub const F_RO: u64 = 1 << 5;
+pub const F_BLK_SIZE: u64 = 1 << 6;
+pub const F_FLUSH: u64 = 1 << 9;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// capacity, size_max, seg_max, geometry, blk_size
+const CONFIG_LEN: u32 = 24;
+const ID_LEN: usize = 20;
+const BOUNCE: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Backing {
+    /// File on the boot ESP (`\`-separated path)
+    File { path: [u8; PATH_MAX], len: usize },
+    /// Host Block I/O handle `disk` (index from `host_disks`), blocks `lba..lba+count`
+    Extent { disk: usize, lba: u64, count: u64 },
+    /// Volume `id` of the open storage pool
+    Volume { id: u32 },
+    /// Namespace `nsid` of NVMe controller `ctrl`, whole
+    Nvme { ctrl: usize, nsid: u32 },
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Disk {
+    t: Transport,
+    backing: Backing,
+    read_only: bool,
+    /// Size in 512-byte sectors
+    capacity: u64,
+    /// Host block size; guest requests must be aligned to it
+    block_size: u32,
+    /// Driver notified the request queue since the last pump
+    pending: bool,
+    stats: Stats,
+}
+
+static DISKS: SpinLock<[Option<Disk>; MAX_DISKS]> = SpinLock::new([None; MAX_DISKS]);
+
+// ---- Guest-facing side (runs in the exit path) ----
+
+fn device_cfg_read(d: &Disk, off: u64, size: u8) -> u64 {
+    let mut cfg = [0u8; CONFIG_LEN as usize];
+    cfg[0..8].copy_from_slice(&d.capacity.to_le_bytes());
+    cfg[8..12].copy_from_slice(&(BOUNCE as u32).to_le_bytes()); // size_max (unused without F_SIZE_MAX)
+    cfg[12..16].copy_from_slice(&((super::MAX_CHAIN - 2) as u32).to_le_bytes());
+    cfg[20..24].copy_from_slice(&d.block_size.to_le_bytes());
+    let mut v = 0u64;
+    for i in 0..size as usize {
+        let o = off as usize + i;
+        if o < cfg.len() { v |= (cfg[o] as u64) << (i * 8); }
+    }
+    v
+}
+
+fn mmio(_vm_id: u64, _vcpu: u32, ctx: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    DISKS.lock(|t| {
+        let d = match t.get_mut(ctx as usize).and_then(|d| d.as_mut()) { Some(d) => d, None => return 0 };
+        if (super::DEVICE_OFF..super::NOTIFY_OFF).contains(&off) {
+            return if write.is_none() { device_cfg_read(d, off - super::DEVICE_OFF, size) } else { 0 };
+        }
+        let (v, kick) = d.t.mmio(off, size, write);
+        match kick {
+            Kick::Queue(REQQ) => d.pending = true,
+            Kick::Reset => d.pending = false,
+            _ => {}
+        }
+        v
+    })
+}
+
+fn on_bar(vm_id: u64, ctx: u64, _bar: usize, old: u64, new: u64) {
+    if old != 0 { let _ = crate::hv::bus::unregister_mmio(vm_id, old); }
+    if new != 0 { let _ = crate::hv::bus::register_mmio(vm_id, new, super::BAR_SIZE, "virtio-blk", ctx, mmio); }
+    let _ = DISKS.lock(|t| t.get_mut(ctx as usize).and_then(|d| d.as_mut()).map(|d| d.t.bar = new));
+}
+
+// ---- Backing stores ----
+
+/// Open `path` on the boot ESP.
+fn open_esp_file(system_table: &SystemTable<Boot>, path: &[u8], writable: bool) -> Result<RegularFile, &'static str> {
+    let mut pbuf = [0u8; PATH_MAX];
+    if path.is_empty() || path.len() > pbuf.len() { return Err("vblk: invalid path"); }
+    for (i, &c) in path.iter().enumerate() { pbuf[i] = if c == b'/' { b'\\' } else { c }; }
+    let p = core::str::from_utf8(&pbuf[..path.len()]).map_err(|_| "vblk: invalid path")?;
+    let mut name_buf = [0u16; PATH_MAX + 2];
+    let name = CStr16::from_str_with_buf(p, &mut name_buf).map_err(|_| "vblk: invalid path")?;
+    let bs = system_table.boot_services();
+    let mut fs = bs.get_image_file_system(bs.image_handle()).map_err(|_| "vblk: ESP not accessible")?;
+    let mut root = fs.open_volume().map_err(|_| "vblk: open volume failed")?;
+    let mode = if writable { FileMode::ReadWrite } else { FileMode::Read };
+    let handle = root.open(name, mode, FileAttribute::empty()).map_err(|_| "vblk: file not found")?;
+    handle.into_regular_file().ok_or("vblk: not a regular file")
+}
+
+/// Block I/O handle `idx` in firmware enumeration order.
+pub(crate) fn host_disk_handle(system_table: &SystemTable<Boot>, idx: usize) -> Option<uefi::Handle> {
+    use uefi::table::boot::SearchType;
+    let handles = system_table.boot_services().locate_handle_buffer(SearchType::ByProtocol(&BlockIO::GUID)).ok()?;
+    handles.get(idx).copied()
+}
+
+/// Open Block I/O on `h` without disconnecting the drivers above it (the ESP
+/// file system may sit on the same disk).
+pub(crate) fn open_blockio(system_table: &SystemTable<Boot>, h: uefi::Handle) -> Option<uefi::table::boot::ScopedProtocol<'_, BlockIO>> {
+    use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+    let bs = system_table.boot_services();
+    unsafe { bs.open_protocol::<BlockIO>(OpenProtocolParams { handle: h, agent: bs.image_handle(), controller: None }, OpenProtocolAttributes::GetProtocol) }.ok()
+}
+
+/// Host disk as seen through Block I/O.
+#[derive(Clone, Copy, Debug)]
+pub struct HostDisk {
+    pub block_size: u32,
+    pub last_block: u64,
+    pub logical_partition: bool,
+    pub read_only: bool,
+    pub present: bool,
+}
+
+/// Enumerate Block I/O handles as (index, info).
+pub fn host_disks(system_table: &SystemTable<Boot>, mut f: impl FnMut(usize, &HostDisk)) {
+    use uefi::table::boot::SearchType;
+    let handles = match system_table.boot_services().locate_handle_buffer(SearchType::ByProtocol(&BlockIO::GUID)) { Ok(h) => h, Err(_) => return };
+    for (i, h) in handles.iter().enumerate() {
+        if let Some(b) = open_blockio(system_table, *h) {
+            let m = b.media();
+            f(i, &HostDisk { block_size: m.block_size(), last_block: m.last_block(), logical_partition: m.is_logical_partition(), read_only: m.is_read_only(), present: m.is_media_present() });
+        }
+    }
+}
+
+/// A backing store opened for one pump pass.
+enum Open<'a> {
+    File(RegularFile),
+    Blocks { io: uefi::table::boot::ScopedProtocol<'a, BlockIO>, media_id: u32, lba: u64, block_size: u64 },
+    Volume { dev: crate::hv::storage::Dev<'a>, id: u32 },
+    Nvme { ctrl: usize, nsid: u32, block_size: u64 },
+}
+
+impl Open<'_> {
+    fn read(&mut self, off: u64, buf: &mut [u8]) -> bool {
+        match self {
+            Open::File(f) => {
+                if f.set_position(off).is_err() { return false; }
+                let mut got = 0usize;
+                while got < buf.len() {
+                    match f.read(&mut buf[got..]) { Ok(0) | Err(_) => break, Ok(n) => got += n }
+                }
+                got == buf.len()
+            }
+            Open::Blocks { io, media_id, lba, block_size } => {
+                if off % *block_size != 0 || buf.len() as u64 % *block_size != 0 { return false; }
+                io.read_blocks(*media_id, *lba + off / *block_size, buf).is_ok()
+            }
+            Open::Volume { dev, id } => crate::hv::storage::read(dev, *id, off, buf),
+            Open::Nvme { ctrl, nsid, block_size } => off % *block_size == 0 && crate::nvme::read(*ctrl, *nsid, off / *block_size, buf).is_ok(),
+        }
+    }
+
+    fn write(&mut self, off: u64, buf: &[u8]) -> bool {
+        match self {
+            Open::File(f) => f.set_position(off).is_ok() && f.write(buf).is_ok(),
+            Open::Blocks { io, media_id, lba, block_size } => {
+                if off % *block_size != 0 || buf.len() as u64 % *block_size != 0 { return false; }
+                io.write_blocks(*media_id, *lba + off / *block_size, buf).is_ok()
+            }
+            Open::Volume { dev, id } => crate::hv::storage::write(dev, *id, off, buf),
+            Open::Nvme { ctrl, nsid, block_size } => off % *block_size == 0 && crate::nvme::write(*ctrl, *nsid, off / *block_size, buf).is_ok(),
+        }
+    }
+
+    fn flush(&mut self) -> bool {
+        match self {
+            Open::File(f) => f.flush().is_ok(),
+            Open::Blocks { io, .. } => io.flush_blocks().is_ok(),
+            Open::Volume { dev, .. } => crate::hv::storage::flush(dev),
+            Open::Nvme { ctrl, nsid, .. } => crate::nvme::flush(*ctrl, *nsid).is_ok(),
+        }
+    }
+}
+
+fn open_backing<'a>(system_table: &'a SystemTable<Boot>, d: &Disk) -> Result<Open<'a>, &'static str> {
+    match d.backing {
+        Backing::File { path, len } => open_esp_file(system_table, &path[..len], !d.read_only).map(Open::File),
+        Backing::Extent { disk, lba, .. } => {
+            let h = host_disk_handle(system_table, disk).ok_or("vblk: no such host disk")?;
+            let io = open_blockio(system_table, h).ok_or("vblk: Block I/O open failed")?;
+            let media_id = io.media().media_id();
+            let block_size = io.media().block_size() as u64;
+            Ok(Open::Blocks { io, media_id, lba, block_size })
+        }
+        Backing::Volume { id } => crate::hv::storage::open_dev(system_table).map(|dev| Open::Volume { dev, id }),
+        Backing::Nvme { ctrl, nsid } => {
+            let ns = crate::nvme::namespace(ctrl, nsid).ok_or("vblk: no such NVMe namespace")?;
+            Ok(Open::Nvme { ctrl, nsid, block_size: ns.block_size as u64 })
+        }
+    }
+}
+
+// ---- Request processing ----
+
+/// Data segments of a request: readable bytes after the 16-byte header and
+/// writable bytes before the trailing status byte.
+fn data_ranges(chain: &Chain) -> ([(u64, u32); super::MAX_CHAIN], usize) {
+    let mut out = [(0u64, 0u32); super::MAX_CHAIN];
+    let mut n = 0usize;
+    let mut skip = 16u64;
+    let last = chain.n - 1;
+    for (i, s) in chain.iter().enumerate() {
+        let (mut gpa, mut len) = (s.gpa, s.len as u64);
+        if !s.writable {
+            let k = skip.min(len);
+            gpa += k; len -= k; skip -= k;
+        } else if i == last {
+            len = len.saturating_sub(1);
+        }
+        if len != 0 { out[n] = (gpa, len as u32); n += 1; }
+    }
+    (out, n)
+}
+
+/// Execute one request; returns (status, bytes written to guest buffers).
+fn execute(d: &mut Disk, io: &mut Open, chain: &Chain, idx: usize) -> (u8, u32) {
+    let vm = d.t.vm_id;
+    let mut hdr = [0u8; 16];
+    if chain.read(vm, &mut hdr) < 16 { return (S_IOERR, 0); }
+    let typ = u32::from_le_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]);
+    let sector = u64::from_le_bytes([hdr[8], hdr[9], hdr[10], hdr[11], hdr[12], hdr[13], hdr[14], hdr[15]]);
+    let (ranges, nr) = data_ranges(chain);
+    let total: u64 = ranges[..nr].iter().map(|r| r.1 as u64).sum();
+    match typ {
+        T_IN | T_OUT => {
+            if typ == T_OUT && d.read_only { return (S_IOERR, 0); }
+            match sector.checked_mul(SECTOR).and_then(|o| o.checked_add(total)) {
+                Some(end) if end <= d.capacity * SECTOR => {}
+                _ => return (S_IOERR, 0),
+            }
+            let mut off = sector * SECTOR;
+            let mut buf = [0u8; BOUNCE];
+            for &(gpa, len) in &ranges[..nr] {
+                let mut done = 0u64;
+                while done < len as u64 {
+                    let n = ((len as u64 - done) as usize).min(BOUNCE);
+                    let ok = if typ == T_IN {
+                        io.read(off, &mut buf[..n]) && super::write_guest(vm, gpa + done, &buf[..n])
+                    } else {
+                        super::read_guest(vm, gpa + done, &mut buf[..n]) && io.write(off, &buf[..n])
+                    };
+                    if !ok { return (S_IOERR, 0); }
+                    off += n as u64; done += n as u64;
+                }
+            }
+            if typ == T_IN { d.stats.reads += 1; d.stats.bytes_read += total; (S_OK, total as u32) }
+            else { d.stats.writes += 1; d.stats.bytes_written += total; (S_OK, 0) }
+        }
+        T_FLUSH => {
+            d.stats.flushes += 1;
+            if d.read_only || io.flush() { (S_OK, 0) } else { (S_IOERR, 0) }
+        }
+        T_GET_ID => {
+            let mut id = [0u8; ID_LEN];
+            let mut n = 0usize;
+            for &b in b"zvblk-" { id[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(vm, &mut id[n..]);
+            id[n] = b'-'; n += 1;
+            let _ = crate::util::format::u64_dec(idx as u64, &mut id[n..]);
+            let take = (total as usize).min(ID_LEN);
+            let mut wrote = 0u32;
+            if take != 0 && super::write_guest(vm, ranges[0].0, &id[..take.min(ranges[0].1 as usize)]) { wrote = take.min(ranges[0].1 as usize) as u32; }
+            (S_OK, wrote)
+        }
+        _ => (S_UNSUPP, 0),
+    }
+}
+
+/// Complete up to `limit` pending requests across all disks (0 = all).
+/// Returns the number of requests completed.
+pub fn pump(system_table: &mut SystemTable<Boot>, limit: usize) -> usize {
+    let st: &SystemTable<Boot> = system_table;
+    let mut completed = 0usize;
+    for idx in 0..MAX_DISKS {
+        let want = DISKS.lock(|t| t[idx].as_ref().map(|d| d.t.driver_ok() && (d.pending || d.t.queues[REQQ as usize].has_avail(d.t.vm_id))).unwrap_or(false));
+        if !want { continue; }
+        let snapshot = match DISKS.lock(|t| t[idx]) { Some(d) => d, None => continue };
+        let mut io = match open_backing(st, &snapshot) { Ok(io) => Some(io), Err(_) => None };
+        completed += DISKS.lock(|t| {
+            let d = match t[idx].as_mut() { Some(d) => d, None => return 0 };
+            d.pending = false;
+            let vm = d.t.vm_id;
+            let mut n = 0usize;
+            while limit == 0 || completed + n < limit {
+                let chain = match d.t.queues[REQQ as usize].pop(vm) { Some(c) => c, None => break };
+                let last = chain.segs[chain.n.saturating_sub(1)];
+                if chain.n < 2 || !last.writable || last.len == 0 {
+                    // No status byte to report through: hand the chain back untouched.
+                    let _ = d.t.queues[REQQ as usize].push_used(vm, chain.head, 0);
+                    d.stats.errors += 1;
+                    n += 1;
+                    continue;
+                }
+                let (status, wrote) = match io.as_mut() { Some(io) => execute(d, io, &chain, idx), None => (S_IOERR, 0) };
+                if status != S_OK {
+                    d.stats.errors += 1;
+                    crate::obs::metrics::Counter::new(&crate::obs::metrics::VBLK_ERRORS).inc();
+                }
+                let _ = super::write_guest(vm, last.gpa + last.len as u64 - 1, &[status]);
+                let _ = d.t.queues[REQQ as usize].push_used(vm, chain.head, wrote + 1);
+                crate::obs::metrics::Counter::new(&crate::obs::metrics::VBLK_REQS).inc();
+                n += 1;
+            }
+            if n != 0 { d.t.interrupt(); }
+            // Stopped by the limit with work left: pick it up next pass.
+            if d.t.queues[REQQ as usize].has_avail(vm) { d.pending = true; }
+            n
+        });
+        if limit != 0 && completed >= limit { break; }
+    }
+    completed
+}
+
+// ---- Management ----
+
+/// Plug a disk into `vm_id`'s PCI bus. Returns (disk index, PCI device number).
+pub fn add(system_table: &SystemTable<Boot>, vm_id: u64, backing: Backing, read_only: bool) -> Result<(usize, u8), &'static str> {
+    let (capacity, block_size, read_only) = match backing {
+        Backing::File { path, len } => {
+            let mut f = open_esp_file(system_table, &path[..len], false)?;
+            let mut info_buf = [0u8; 512];
+            let size = f.get_info::<FileInfo>(&mut info_buf).map_err(|_| "vblk: file info failed")?.file_size();
+            if size < SECTOR { return Err("vblk: file smaller than one sector"); }
+            (size / SECTOR, SECTOR as u32, read_only)
+        }
+        Backing::Extent { disk, lba, count } => {
+            let h = host_disk_handle(system_table, disk).ok_or("vblk: no such host disk")?;
+            let io = open_blockio(system_table, h).ok_or("vblk: Block I/O open failed")?;
+            let m = io.media();
+            let bs = m.block_size() as u64;
+            if bs < SECTOR || bs > BOUNCE as u64 || bs % SECTOR != 0 { return Err("vblk: unsupported host block size"); }
+            let blocks = m.last_block().saturating_add(1);
+            if lba >= blocks { return Err("vblk: extent starts past end of disk"); }
+            let count = if count == 0 { blocks - lba } else { count };
+            if lba.checked_add(count).map(|e| e > blocks).unwrap_or(true) { return Err("vblk: extent past end of disk"); }
+            (count * bs / SECTOR, bs as u32, read_only || m.is_read_only())
+        }
+        Backing::Volume { id } => {
+            let (size, bs, snapshot) = crate::hv::storage::geometry(id).ok_or("vblk: no such volume")?;
+            (size / SECTOR, bs, read_only || snapshot)
+        }
+        Backing::Nvme { ctrl, nsid } => {
+            let ns = crate::nvme::namespace(ctrl, nsid).ok_or("vblk: no such NVMe namespace")?;
+            if crate::hv::storage::info().is_some_and(|p| p.device == crate::hv::storage::Device::Nvme { ctrl, nsid }) {
+                return Err("vblk: namespace holds the storage pool");
+            }
+            (ns.blocks * ns.block_size as u64 / SECTOR, ns.block_size, read_only)
+        }
+    };
+    let mut features = F_SEG_MAX | F_BLK_SIZE | F_FLUSH;
+    if read_only { features |= F_RO; }
+    let idx = DISKS.lock(|t| {
+        let i = t.iter().position(|d| d.is_none()).ok_or("vblk: too many disks")?;
+        t[i] = Some(Disk { t: Transport::new(vm_id, 1, QUEUE_MAX, features), backing, read_only, capacity, block_size, pending: false, stats: Stats::default() });
+        Ok(i)
+    })?;
+    let cfg = super::pci_config(VIRTIO_ID_BLOCK, 0x01, 0x00, CONFIG_LEN, DEFAULT_IRQ);
+    match crate::hv::vpci::add(vm_id, cfg, idx as u64, on_bar) {
+        Some(dev) => {
+            DISKS.lock(|t| if let Some(d) = t[idx].as_mut() { d.t.dev = dev; });
+            Ok((idx, dev))
+        }
+        None => {
+            DISKS.lock(|t| t[idx] = None);
+            Err("vblk: no free PCI slot")
+        }
+    }
+}
+
+/// Unplug disk `idx` of `vm_id`. The guest is not told; it should have
+/// stopped using the disk first.
+pub fn remove(vm_id: u64, idx: usize) -> Result<(), &'static str> {
+    let dev = DISKS.lock(|t| t.get(idx).copied().flatten().filter(|d| d.t.vm_id == vm_id).map(|d| d.t.dev)).ok_or("vblk: no such disk")?;
+    crate::hv::vpci::remove(vm_id, dev);
+    DISKS.lock(|t| t[idx] = None);
+    Ok(())
+}
+
+/// Remove every disk of `vm_id` (PCI functions are torn down with the bus).
+pub fn detach_vm(vm_id: u64) {
+    DISKS.lock(|t| { for d in t.iter_mut() { if matches!(d, Some(x) if x.t.vm_id == vm_id) { *d = None; } } });
+}
+
+/// Disk state for reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct DiskInfo {
+    pub vm_id: u64,
+    pub dev: u8,
+    pub bar: u64,
+    pub backing: Backing,
+    pub read_only: bool,
+    pub capacity: u64,
+    pub block_size: u32,
+    pub driver_ok: bool,
+    pub stats: Stats,
+}
+
+/// Iterate disks as (index, info).
+pub fn for_each(mut f: impl FnMut(usize, &DiskInfo)) {
+    let mut snap: [Option<DiskInfo>; MAX_DISKS] = [None; MAX_DISKS];
+    DISKS.lock(|t| {
+        for (i, d) in t.iter().enumerate() {
+            snap[i] = d.as_ref().map(|d| DiskInfo { vm_id: d.t.vm_id, dev: d.t.dev, bar: d.t.bar, backing: d.backing, read_only: d.read_only, capacity: d.capacity, block_size: d.block_size, driver_ok: d.t.driver_ok(), stats: d.stats });
+        }
+    });
+    for (i, d) in snap.iter().enumerate() { if let Some(d) = d { f(i, d); } }
+}
+
+/// Build a file backing from a path string.
+pub fn file_backing(path: &str) -> Option<Backing> {
+    if path.is_empty() || path.len() > PATH_MAX { return None; }
+    let mut p = [0u8; PATH_MAX];
+    p[..path.len()].copy_from_slice(path.as_bytes());
+    Some(Backing::File { path: p, len: path.len() })
+}
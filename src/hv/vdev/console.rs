@@ -0,0 +1,361 @@
+#![allow(dead_code)]
+
+//! virtio-console device model (virtio device type 3), single port.
+//!
+//! Guest output is drained in the exit that carries the transmit notify
+//! (or written byte by byte through the emergency-write register, which
+//! works before the driver has set up its queues) and appended to a
+//! per-VM log ring. `pump` copies new log bytes to the UEFI text console:
+//! prefixed with `[vm <id>]` for consoles with echo enabled, verbatim for
+//! the console attached with `attach`. Escape sequences and other control
+//! bytes the text console cannot render are filtered on the way out; the
+//! log keeps the raw bytes.
+//!
+//! Keyboard input while attached is queued per console and delivered into
+//! the guest's receive buffers as they become available.
+
+use core::fmt::Write as _;
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::util::spinlock::SpinLock;
+use super::{Kick, Transport};
+
+pub const VIRTIO_ID_CONSOLE: u16 = 3;
+pub const MAX_CONSOLES: usize = 8;
+pub const QUEUE_MAX: u16 = 64;
+pub const RXQ: u16 = 0;
+pub const TXQ: u16 = 1;
+/// ISA line used for INTx until the guest reprograms it
+pub const DEFAULT_IRQ: u8 = 5;
+/// Captured output kept per console
+pub const LOG_SIZE: usize = 8192;
+const INPUT_SIZE: usize = 256;
+
+pub const F_SIZE: u64 = 1 << 0;
+pub const F_EMERG_WRITE: u64 = 1 << 2;
+
+/// cols, rows, max_nr_ports, emerg_wr
+const CONFIG_LEN: u32 = 12;
+const CFG_EMERG_WR: u64 = 8;
+pub const COLS: u16 = 80;
+pub const ROWS: u16 = 25;
+
+/// Ctrl-] leaves an attached console.
+pub const DETACH_KEY: char = '\u{1d}';
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    /// Input dropped because the input queue was full
+    pub rx_drops: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Console {
+    t: Transport,
+    log: [u8; LOG_SIZE],
+    /// Total bytes ever appended to `log`
+    written: u64,
+    /// Bytes of `log` already copied to the text console
+    shown: u64,
+    echo: bool,
+    input: [u8; INPUT_SIZE],
+    in_head: usize,
+    in_len: usize,
+    /// Output filter state: 0 text, 1 after ESC, 2 inside a CSI sequence
+    esc: u8,
+    line_start: bool,
+    stats: Stats,
+}
+
+impl Console {
+    fn append(&mut self, data: &[u8]) {
+        for &b in data {
+            self.log[(self.written % LOG_SIZE as u64) as usize] = b;
+            self.written += 1;
+        }
+        self.stats.tx_bytes += data.len() as u64;
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::VCON_TX_BYTES).add(data.len() as u64);
+    }
+
+    /// Copy the log tail of up to `max` bytes into `out`; returns bytes copied.
+    fn tail(&self, max: usize, out: &mut [u8]) -> usize {
+        let avail = (self.written.min(LOG_SIZE as u64) as usize).min(max).min(out.len());
+        let start = self.written - avail as u64;
+        for i in 0..avail { out[i] = self.log[((start + i as u64) % LOG_SIZE as u64) as usize]; }
+        avail
+    }
+}
+
+static CONSOLES: SpinLock<[Option<Console>; MAX_CONSOLES]> = SpinLock::new([None; MAX_CONSOLES]);
+/// Console index attached to the text console, if any
+static ATTACHED: SpinLock<Option<usize>> = SpinLock::new(None);
+
+// ---- Guest-facing side (runs in the exit path) ----
+
+fn device_cfg_read(off: u64, size: u8) -> u64 {
+    let mut cfg = [0u8; CONFIG_LEN as usize];
+    cfg[0..2].copy_from_slice(&COLS.to_le_bytes());
+    cfg[2..4].copy_from_slice(&ROWS.to_le_bytes());
+    cfg[4..8].copy_from_slice(&1u32.to_le_bytes());
+    let mut v = 0u64;
+    for i in 0..size as usize {
+        let o = off as usize + i;
+        if o < cfg.len() { v |= (cfg[o] as u64) << (i * 8); }
+    }
+    v
+}
+
+/// Drain the transmit queue into the log.
+fn process_tx(c: &mut Console) {
+    let vm = c.t.vm_id;
+    let mut buf = [0u8; 256];
+    let mut done = 0u32;
+    while let Some(chain) = c.t.queues[TXQ as usize].pop(vm) {
+        for s in chain.iter().filter(|s| !s.writable) {
+            let mut off = 0u64;
+            while off < s.len as u64 {
+                let take = ((s.len as u64 - off) as usize).min(buf.len());
+                if !super::read_guest(vm, s.gpa + off, &mut buf[..take]) { break; }
+                c.append(&buf[..take]);
+                off += take as u64;
+            }
+        }
+        let _ = c.t.queues[TXQ as usize].push_used(vm, chain.head, 0);
+        done += 1;
+    }
+    if done != 0 { c.t.interrupt(); }
+}
+
+/// Move queued input into guest receive buffers while both are available.
+fn deliver_input(c: &mut Console) {
+    if !c.t.driver_ok() { return; }
+    let vm = c.t.vm_id;
+    let mut delivered = 0u32;
+    while c.in_len != 0 {
+        let chain = match c.t.queues[RXQ as usize].pop(vm) { Some(ch) => ch, None => break };
+        let mut buf = [0u8; INPUT_SIZE];
+        let n = c.in_len.min(chain.writable_len());
+        for (i, b) in buf[..n].iter_mut().enumerate() { *b = c.input[(c.in_head + i) % INPUT_SIZE]; }
+        let wrote = chain.write(vm, &buf[..n]);
+        c.in_head = (c.in_head + wrote) % INPUT_SIZE;
+        c.in_len -= wrote;
+        c.stats.rx_bytes += wrote as u64;
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::VCON_RX_BYTES).add(wrote as u64);
+        let _ = c.t.queues[RXQ as usize].push_used(vm, chain.head, wrote as u32);
+        delivered += 1;
+    }
+    if delivered != 0 { c.t.interrupt(); }
+}
+
+fn mmio(_vm_id: u64, _vcpu: u32, ctx: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    CONSOLES.lock(|t| {
+        let c = match t.get_mut(ctx as usize).and_then(|c| c.as_mut()) { Some(c) => c, None => return 0 };
+        if (super::DEVICE_OFF..super::NOTIFY_OFF).contains(&off) {
+            let o = off - super::DEVICE_OFF;
+            return match write {
+                None => device_cfg_read(o, size),
+                Some(v) => { if o == CFG_EMERG_WR { c.append(&[v as u8]); } 0 }
+            };
+        }
+        let (v, kick) = c.t.mmio(off, size, write);
+        match kick {
+            Kick::Queue(TXQ) => process_tx(c),
+            Kick::Queue(RXQ) | Kick::Ready => deliver_input(c),
+            Kick::Reset => { c.in_len = 0; c.in_head = 0; }
+            _ => {}
+        }
+        v
+    })
+}
+
+fn on_bar(vm_id: u64, ctx: u64, _bar: usize, old: u64, new: u64) {
+    if old != 0 { let _ = crate::hv::bus::unregister_mmio(vm_id, old); }
+    if new != 0 { let _ = crate::hv::bus::register_mmio(vm_id, new, super::BAR_SIZE, "virtio-console", ctx, mmio); }
+    let _ = CONSOLES.lock(|t| t.get_mut(ctx as usize).and_then(|c| c.as_mut()).map(|c| c.t.bar = new));
+}
+
+// ---- Management ----
+
+/// Plug a console into `vm_id`'s PCI bus (one per VM). Returns (console index, PCI device number).
+pub fn add(vm_id: u64) -> Result<(usize, u8), &'static str> {
+    let idx = CONSOLES.lock(|t| {
+        if t.iter().flatten().any(|c| c.t.vm_id == vm_id) { return Err("vcon: VM already has a console"); }
+        let i = t.iter().position(|c| c.is_none()).ok_or("vcon: too many consoles")?;
+        t[i] = Some(Console {
+            t: Transport::new(vm_id, 2, QUEUE_MAX, F_SIZE | F_EMERG_WRITE),
+            log: [0; LOG_SIZE], written: 0, shown: 0, echo: true,
+            input: [0; INPUT_SIZE], in_head: 0, in_len: 0, esc: 0, line_start: true, stats: Stats::default(),
+        });
+        Ok(i)
+    })?;
+    let cfg = super::pci_config(VIRTIO_ID_CONSOLE, 0x07, 0x80, CONFIG_LEN, DEFAULT_IRQ);
+    match crate::hv::vpci::add(vm_id, cfg, idx as u64, on_bar) {
+        Some(dev) => {
+            CONSOLES.lock(|t| if let Some(c) = t[idx].as_mut() { c.t.dev = dev; });
+            Ok((idx, dev))
+        }
+        None => {
+            CONSOLES.lock(|t| t[idx] = None);
+            Err("vcon: no free PCI slot")
+        }
+    }
+}
+
+/// Remove the console of `vm_id` (the PCI function is torn down with the bus).
+pub fn detach_vm(vm_id: u64) {
+    CONSOLES.lock(|t| { for c in t.iter_mut() { if matches!(c, Some(x) if x.t.vm_id == vm_id) { *c = None; } } });
+}
+
+/// Console index of `vm_id`.
+pub fn find(vm_id: u64) -> Option<usize> {
+    CONSOLES.lock(|t| t.iter().position(|c| matches!(c, Some(c) if c.t.vm_id == vm_id)))
+}
+
+pub fn set_echo(idx: usize, on: bool) -> bool {
+    CONSOLES.lock(|t| match t.get_mut(idx).and_then(|c| c.as_mut()) { Some(c) => { c.echo = on; true } None => false })
+}
+
+/// Queue `data` as keyboard input for console `idx`. Returns bytes accepted.
+pub fn send_input(idx: usize, data: &[u8]) -> usize {
+    CONSOLES.lock(|t| {
+        let c = match t.get_mut(idx).and_then(|c| c.as_mut()) { Some(c) => c, None => return 0 };
+        let mut n = 0usize;
+        for &b in data {
+            if c.in_len == INPUT_SIZE { c.stats.rx_drops += 1; continue; }
+            c.input[(c.in_head + c.in_len) % INPUT_SIZE] = b;
+            c.in_len += 1;
+            n += 1;
+        }
+        deliver_input(c);
+        n
+    })
+}
+
+/// Copy up to `max` bytes of the newest captured output of console `idx` into `out`.
+pub fn log_tail(idx: usize, max: usize, out: &mut [u8]) -> usize {
+    CONSOLES.lock(|t| t.get(idx).and_then(|c| c.as_ref()).map(|c| c.tail(max, out)).unwrap_or(0))
+}
+
+/// Console state for reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct ConsoleInfo {
+    pub vm_id: u64,
+    pub dev: u8,
+    pub driver_ok: bool,
+    pub echo: bool,
+    pub attached: bool,
+    /// Total output bytes captured
+    pub written: u64,
+    pub pending_input: usize,
+    pub stats: Stats,
+}
+
+/// Iterate consoles as (index, info).
+pub fn for_each(mut f: impl FnMut(usize, &ConsoleInfo)) {
+    let att = ATTACHED.lock(|a| *a);
+    let mut snap: [Option<ConsoleInfo>; MAX_CONSOLES] = [None; MAX_CONSOLES];
+    CONSOLES.lock(|t| {
+        for (i, c) in t.iter().enumerate() {
+            snap[i] = c.as_ref().map(|c| ConsoleInfo {
+                vm_id: c.t.vm_id, dev: c.t.dev, driver_ok: c.t.driver_ok(), echo: c.echo, attached: att == Some(i),
+                written: c.written, pending_input: c.in_len, stats: c.stats,
+            });
+        }
+    });
+    for (i, c) in snap.iter().enumerate() { if let Some(c) = c { f(i, c); } }
+}
+
+// ---- Host side (text console) ----
+
+/// Render new output of one console into `out`; returns bytes rendered.
+/// Stops early when `out` is nearly full; the rest follows on the next call.
+fn render(c: &mut Console, raw: bool, out: &mut [u8]) -> usize {
+    let mut n = 0usize;
+    if c.written - c.shown > LOG_SIZE as u64 {
+        // Overwritten before it could be shown.
+        for &b in b"\r\n[output lost]\r\n" { out[n] = b; n += 1; }
+        c.shown = c.written - LOG_SIZE as u64;
+        c.line_start = true;
+    }
+    while c.shown < c.written && n + 32 < out.len() {
+        let b = c.log[(c.shown % LOG_SIZE as u64) as usize];
+        c.shown += 1;
+        match (c.esc, b) {
+            (0, 0x1B) => { c.esc = 1; continue; }
+            (1, b'[') => { c.esc = 2; continue; }
+            (1, _) => { c.esc = 0; continue; }
+            (2, 0x40..=0x7E) => { c.esc = 0; continue; }
+            (2, _) => continue,
+            _ => {}
+        }
+        if !(b == b'\r' || b == b'\n' || b == b'\t' || b == 0x08 || (0x20..0x7F).contains(&b)) { continue; }
+        if !raw && c.line_start && b != b'\r' && b != b'\n' {
+            for &p in b"[vm " { out[n] = p; n += 1; }
+            n += crate::util::format::u64_dec(c.t.vm_id, &mut out[n..]);
+            out[n] = b']'; n += 1; out[n] = b' '; n += 1;
+        }
+        out[n] = b; n += 1;
+        if b == b'\n' { c.line_start = true; } else if b != b'\r' { c.line_start = false; }
+    }
+    n
+}
+
+/// Copy new guest output to the text console. Returns bytes written.
+pub fn pump(system_table: &mut SystemTable<Boot>) -> usize {
+    let att = ATTACHED.lock(|a| *a);
+    let mut total = 0usize;
+    for idx in 0..MAX_CONSOLES {
+        loop {
+            let mut out = [0u8; 256];
+            let n = CONSOLES.lock(|t| match t[idx].as_mut() {
+                Some(c) if c.echo || att == Some(idx) => render(c, att == Some(idx), &mut out),
+                Some(c) => { c.shown = c.written; 0 }
+                None => 0,
+            });
+            if n == 0 { break; }
+            let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or(""));
+            total += n;
+        }
+    }
+    total
+}
+
+/// Connect the text console to console `idx` until Ctrl-] is pressed.
+/// The last `replay` bytes of captured output are shown first. Device
+/// pumps and cluster heartbeats keep running while attached.
+pub fn attach(system_table: &mut SystemTable<Boot>, idx: usize, replay: usize) -> Result<(), &'static str> {
+    let ok = CONSOLES.lock(|t| match t.get_mut(idx).and_then(|c| c.as_mut()) {
+        Some(c) => {
+            c.shown = c.written - (c.written.min(replay.min(LOG_SIZE) as u64));
+            c.esc = 0;
+            true
+        }
+        None => false,
+    });
+    if !ok { return Err("vcon: no such console"); }
+    ATTACHED.lock(|a| *a = Some(idx));
+    let _ = system_table.stdin().reset(false);
+    loop {
+        let mut busy = pump(system_table) != 0;
+        let key = system_table.stdin().read_key();
+        if let Ok(Some(uefi::proto::console::text::Key::Printable(ch))) = key {
+            let c: char = ch.into();
+            if c == DETACH_KEY { break; }
+            // Terminals send DEL for backspace; guests expect CR for Enter.
+            let b = match c { '\u{8}' => 0x7F, '\n' => b'\r', c if c.is_ascii() => c as u8, _ => continue };
+            let _ = send_input(idx, &[b]);
+            busy = true;
+        }
+        if CONSOLES.lock(|t| t[idx].is_none()) { break; }
+        let _ = crate::hv::vdev::net::pump(system_table, 16);
+        let _ = crate::hv::vdev::blk::pump(system_table, 8);
+        let _ = crate::hv::vdev::balloon::pump(system_table, 8);
+        let _ = crate::cluster::tick(system_table, false);
+        if !busy { let _ = system_table.boot_services().stall(1000); }
+    }
+    ATTACHED.lock(|a| *a = None);
+    Ok(())
+}
@@ -0,0 +1,402 @@
+#![allow(dead_code)]
+
+//! Guest-facing virtio devices over the modern (1.x) PCI transport.
+//!
+//! `Transport` holds the common configuration, ISR and split virtqueue state
+//! for one device; device models keep one next to their own state and route
+//! BAR accesses through `Transport::mmio`. All queue addresses are
+//! guest-physical and accessed through `hv::loader::gpa_to_host`. Interrupts
+//! use legacy INTx until MSI-X is modelled.
+
+pub mod net;
+pub mod nat;
+pub mod switch;
+pub mod qos;
+pub mod blk;
+pub mod console;
+pub mod vsock;
+pub mod balloon;
+pub mod accel;
+
+/// Layout of the single 64-bit memory BAR (BAR 0).
+pub const BAR_SIZE: u64 = 0x4000;
+pub const COMMON_OFF: u64 = 0x0000;
+pub const ISR_OFF: u64 = 0x1000;
+pub const DEVICE_OFF: u64 = 0x2000;
+pub const NOTIFY_OFF: u64 = 0x3000;
+pub const NOTIFY_MULT: u32 = 4;
+
+pub const MAX_QUEUES: usize = 4;
+/// Descriptors followed per chain before the chain is treated as malformed.
+pub const MAX_CHAIN: usize = 16;
+
+pub const VIRTIO_PCI_VENDOR: u16 = 0x1AF4;
+/// Modern device IDs are 0x1040 + virtio device type.
+pub const VIRTIO_PCI_DEVICE_BASE: u16 = 0x1040;
+
+pub const F_VERSION_1: u64 = 1 << 32;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 0x80;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const DESC_F_INDIRECT: u16 = 4;
+
+// virtio_pci_cap.cfg_type
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_ISR: u8 = 3;
+const CAP_DEVICE: u8 = 4;
+
+// ---- Guest memory ----
+
+/// Copy guest-physical memory into `out`, page by page. False if any page is unbacked.
+pub fn read_guest(vm_id: u64, gpa: u64, out: &mut [u8]) -> bool {
+    let mut done = 0usize;
+    while done < out.len() {
+        let Some(a) = gpa.checked_add(done as u64) else { return false };
+        let h = match crate::hv::loader::gpa_to_host(vm_id, a) { Some(h) => h, None => return false };
+        let chunk = ((0x1000 - (a & 0xFFF)) as usize).min(out.len() - done);
+        unsafe { core::ptr::copy_nonoverlapping(h as *const u8, out[done..].as_mut_ptr(), chunk); }
+        done += chunk;
+    }
+    true
+}
+
+/// Copy `data` into guest-physical memory, page by page.
+pub fn write_guest(vm_id: u64, gpa: u64, data: &[u8]) -> bool {
+    let mut done = 0usize;
+    while done < data.len() {
+        let Some(a) = gpa.checked_add(done as u64) else { return false };
+        let h = match crate::hv::loader::gpa_to_host(vm_id, a) { Some(h) => h, None => return false };
+        let chunk = ((0x1000 - (a & 0xFFF)) as usize).min(data.len() - done);
+        unsafe { core::ptr::copy_nonoverlapping(data[done..].as_ptr(), h as *mut u8, chunk); }
+        done += chunk;
+    }
+    true
+}
+
+fn read_u16(vm_id: u64, gpa: u64) -> Option<u16> {
+    let mut b = [0u8; 2];
+    if read_guest(vm_id, gpa, &mut b) { Some(u16::from_le_bytes(b)) } else { None }
+}
+
+fn write_u16(vm_id: u64, gpa: u64, v: u16) -> bool { write_guest(vm_id, gpa, &v.to_le_bytes()) }
+
+// ---- Virtqueues ----
+
+/// One buffer of a descriptor chain.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Seg { pub gpa: u64, pub len: u32, pub writable: bool }
+
+/// A descriptor chain popped from the available ring.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chain { pub head: u16, pub segs: [Seg; MAX_CHAIN], pub n: usize }
+
+impl Chain {
+    pub fn iter(&self) -> impl Iterator<Item = &Seg> { self.segs[..self.n].iter() }
+
+    /// Gather the device-readable part of the chain into `out`; returns bytes copied.
+    pub fn read(&self, vm_id: u64, out: &mut [u8]) -> usize {
+        let mut n = 0usize;
+        for s in self.iter().filter(|s| !s.writable) {
+            let take = (s.len as usize).min(out.len() - n);
+            if take == 0 || !read_guest(vm_id, s.gpa, &mut out[n..n + take]) { break; }
+            n += take;
+        }
+        n
+    }
+
+    /// Scatter `data` into the device-writable part; returns bytes written.
+    pub fn write(&self, vm_id: u64, data: &[u8]) -> usize {
+        let mut n = 0usize;
+        for s in self.iter().filter(|s| s.writable) {
+            let take = (s.len as usize).min(data.len() - n);
+            if take == 0 || !write_guest(vm_id, s.gpa, &data[n..n + take]) { break; }
+            n += take;
+        }
+        n
+    }
+
+    pub fn writable_len(&self) -> usize { self.iter().filter(|s| s.writable).map(|s| s.len as usize).sum() }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Queue {
+    /// Maximum size offered to the driver
+    pub max: u16,
+    pub size: u16,
+    pub ready: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    pub msix: u16,
+    /// Next available-ring index to consume
+    pub last_avail: u16,
+    /// Next used-ring index to fill
+    pub used_idx: u16,
+}
+
+impl Queue {
+    const fn new(max: u16) -> Self {
+        Queue { max, size: max, ready: false, desc: 0, driver: 0, device: 0, msix: 0xFFFF, last_avail: 0, used_idx: 0 }
+    }
+
+    /// Pop the next available chain. Indirect descriptors are not offered and are rejected.
+    /// A malformed chain is returned to the driver unused and `None` is reported.
+    pub fn pop(&mut self, vm_id: u64) -> Option<Chain> {
+        if !self.ready || self.size == 0 { return None; }
+        let avail_idx = read_u16(vm_id, self.driver.checked_add(2)?)?;
+        if avail_idx == self.last_avail { return None; }
+        let slot = (self.last_avail % self.size) as u64;
+        let head = read_u16(vm_id, self.driver.checked_add(4 + slot * 2)?)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        let chain = self.walk(vm_id, head);
+        if chain.is_none() { self.push_used(vm_id, head, 0); }
+        chain
+    }
+
+    /// Follow the descriptor chain starting at `head`. None if it is malformed.
+    fn walk(&self, vm_id: u64, head: u16) -> Option<Chain> {
+        let mut chain = Chain { head, ..Default::default() };
+        let mut idx = head;
+        loop {
+            if idx >= self.size || chain.n >= MAX_CHAIN { return None; }
+            let mut d = [0u8; 16];
+            if !read_guest(vm_id, self.desc.checked_add(idx as u64 * 16)?, &mut d) { return None; }
+            let addr = u64::from_le_bytes([d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]]);
+            let len = u32::from_le_bytes([d[8], d[9], d[10], d[11]]);
+            let flags = u16::from_le_bytes([d[12], d[13]]);
+            let next = u16::from_le_bytes([d[14], d[15]]);
+            if (flags & DESC_F_INDIRECT) != 0 { return None; }
+            chain.segs[chain.n] = Seg { gpa: addr, len, writable: (flags & DESC_F_WRITE) != 0 };
+            chain.n += 1;
+            if (flags & DESC_F_NEXT) == 0 { break; }
+            idx = next;
+        }
+        Some(chain)
+    }
+
+    /// Return a chain to the driver with `len` bytes written.
+    pub fn push_used(&mut self, vm_id: u64, head: u16, len: u32) -> bool {
+        if !self.ready || self.size == 0 { return false; }
+        let slot = (self.used_idx % self.size) as u64;
+        let mut e = [0u8; 8];
+        e[..4].copy_from_slice(&(head as u32).to_le_bytes());
+        e[4..].copy_from_slice(&len.to_le_bytes());
+        let Some(at) = self.device.checked_add(4 + slot * 8) else { return false };
+        if !write_guest(vm_id, at, &e) { return false; }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        write_u16(vm_id, self.device + 2, self.used_idx)
+    }
+
+    /// True if the driver has chains we have not consumed.
+    pub fn has_avail(&self, vm_id: u64) -> bool {
+        self.ready && self.driver.checked_add(2).and_then(|a| read_u16(vm_id, a)).map(|i| i != self.last_avail).unwrap_or(false)
+    }
+}
+
+// ---- Transport ----
+
+/// What a BAR write asks of the device model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kick {
+    None,
+    /// Driver notified queue n
+    Queue(u16),
+    /// Driver wrote status 0; device state must be reset
+    Reset,
+    /// DRIVER_OK was set
+    Ready,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Transport {
+    pub vm_id: u64,
+    /// PCI device number on the guest bus
+    pub dev: u8,
+    /// Current BAR 0 base (0 = unmapped)
+    pub bar: u64,
+    pub device_features: u64,
+    pub driver_features: u64,
+    dfsel: u32,
+    gfsel: u32,
+    pub status: u8,
+    pub config_generation: u8,
+    qsel: u16,
+    pub queues: [Queue; MAX_QUEUES],
+    pub nqueues: u16,
+    pub isr: u8,
+    msix_config: u16,
+}
+
+impl Transport {
+    pub fn new(vm_id: u64, nqueues: u16, queue_max: u16, device_features: u64) -> Self {
+        Transport {
+            vm_id, dev: 0, bar: 0,
+            device_features: device_features | F_VERSION_1,
+            driver_features: 0, dfsel: 0, gfsel: 0, status: 0, config_generation: 0, qsel: 0,
+            queues: [Queue::new(queue_max); MAX_QUEUES],
+            nqueues: nqueues.min(MAX_QUEUES as u16),
+            isr: 0, msix_config: 0xFFFF,
+        }
+    }
+
+    pub fn driver_ok(&self) -> bool { (self.status & STATUS_DRIVER_OK) != 0 && (self.status & STATUS_FAILED) == 0 }
+
+    pub fn negotiated(&self, feature: u64) -> bool { (self.driver_features & feature) != 0 }
+
+    fn reset(&mut self) {
+        let max = self.queues[0].max;
+        self.driver_features = 0; self.dfsel = 0; self.gfsel = 0; self.status = 0; self.qsel = 0; self.isr = 0;
+        self.queues = [Queue::new(max); MAX_QUEUES];
+    }
+
+    fn cur_queue(&mut self) -> Option<&mut Queue> {
+        if self.qsel < self.nqueues { Some(&mut self.queues[self.qsel as usize]) } else { None }
+    }
+
+    /// Common configuration read.
+    fn common_read(&mut self, off: u64) -> u64 {
+        let q = if self.qsel < self.nqueues { Some(self.queues[self.qsel as usize]) } else { None };
+        match off {
+            0x00 => self.dfsel as u64,
+            0x04 => if self.dfsel < 2 { (self.device_features >> (32 * self.dfsel)) & 0xFFFF_FFFF } else { 0 },
+            0x08 => self.gfsel as u64,
+            0x0C => if self.gfsel < 2 { (self.driver_features >> (32 * self.gfsel)) & 0xFFFF_FFFF } else { 0 },
+            0x10 => self.msix_config as u64,
+            0x12 => self.nqueues as u64,
+            0x14 => self.status as u64,
+            0x15 => self.config_generation as u64,
+            0x16 => self.qsel as u64,
+            0x18 => q.map(|q| q.size as u64).unwrap_or(0),
+            0x1A => q.map(|q| q.msix as u64).unwrap_or(0xFFFF),
+            0x1C => q.map(|q| q.ready as u64).unwrap_or(0),
+            0x1E => self.qsel as u64, // notify offset = queue index
+            0x20 => q.map(|q| q.desc).unwrap_or(0),
+            0x24 => q.map(|q| q.desc >> 32).unwrap_or(0),
+            0x28 => q.map(|q| q.driver).unwrap_or(0),
+            0x2C => q.map(|q| q.driver >> 32).unwrap_or(0),
+            0x30 => q.map(|q| q.device).unwrap_or(0),
+            0x34 => q.map(|q| q.device >> 32).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn common_write(&mut self, off: u64, size: u8, v: u64) -> Kick {
+        let set_lo = |old: u64, v: u64| (old & !0xFFFF_FFFF) | (v & 0xFFFF_FFFF);
+        let set_hi = |old: u64, v: u64| (old & 0xFFFF_FFFF) | (v << 32);
+        match off {
+            0x00 => self.dfsel = v as u32,
+            0x08 => self.gfsel = v as u32,
+            0x0C => {
+                // Only features we offer can be accepted.
+                if self.gfsel < 2 && (self.status & STATUS_FEATURES_OK) == 0 {
+                    let sh = 32 * self.gfsel;
+                    let accepted = ((v & 0xFFFF_FFFF) << sh) & self.device_features;
+                    self.driver_features = (self.driver_features & !(0xFFFF_FFFFu64 << sh)) | accepted;
+                }
+            }
+            0x10 => self.msix_config = v as u16,
+            0x14 => {
+                let v = v as u8;
+                if v == 0 { self.reset(); return Kick::Reset; }
+                // FEATURES_OK only sticks for a modern driver.
+                let v = if (v & STATUS_FEATURES_OK) != 0 && !self.negotiated(F_VERSION_1) { v & !STATUS_FEATURES_OK } else { v };
+                let was_ok = self.driver_ok();
+                self.status = v;
+                if !was_ok && self.driver_ok() { return Kick::Ready; }
+            }
+            0x16 => self.qsel = v as u16,
+            0x18 => if let Some(q) = self.cur_queue() { if !q.ready && v as u16 <= q.max && (v as u16).is_power_of_two() { q.size = v as u16; } },
+            0x1A => if let Some(q) = self.cur_queue() { q.msix = v as u16; },
+            0x1C => if let Some(q) = self.cur_queue() { if v == 1 { q.ready = true; } },
+            0x20 => if let Some(q) = self.cur_queue() { q.desc = if size == 8 { v } else { set_lo(q.desc, v) }; },
+            0x24 => if let Some(q) = self.cur_queue() { q.desc = set_hi(q.desc, v); },
+            0x28 => if let Some(q) = self.cur_queue() { q.driver = if size == 8 { v } else { set_lo(q.driver, v) }; },
+            0x2C => if let Some(q) = self.cur_queue() { q.driver = set_hi(q.driver, v); },
+            0x30 => if let Some(q) = self.cur_queue() { q.device = if size == 8 { v } else { set_lo(q.device, v) }; },
+            0x34 => if let Some(q) = self.cur_queue() { q.device = set_hi(q.device, v); },
+            _ => {}
+        }
+        Kick::None
+    }
+
+    /// Handle a BAR access other than device-specific config. Reads return the
+    /// value; writes return what the device model must do next.
+    pub fn mmio(&mut self, off: u64, size: u8, write: Option<u64>) -> (u64, Kick) {
+        match (off, write) {
+            (o, None) if o < ISR_OFF => (self.common_read(o), Kick::None),
+            (o, Some(v)) if o < ISR_OFF => (0, self.common_write(o, size, v)),
+            (ISR_OFF, None) => {
+                // Reading ISR acknowledges the interrupt.
+                let v = self.isr as u64;
+                self.isr = 0;
+                let _ = crate::hv::vpci::set_intx(self.vm_id, self.dev, false);
+                (v, Kick::None)
+            }
+            (o, Some(_)) if (NOTIFY_OFF..BAR_SIZE).contains(&o) => {
+                let q = ((o - NOTIFY_OFF) / NOTIFY_MULT as u64) as u16;
+                (0, if q < self.nqueues { Kick::Queue(q) } else { Kick::None })
+            }
+            _ => (0, Kick::None),
+        }
+    }
+
+    /// Raise the used-buffer interrupt (INTx on the line the guest programmed).
+    pub fn interrupt(&mut self) {
+        self.isr |= 1;
+        self.raise();
+    }
+
+    /// Raise the configuration-change interrupt.
+    pub fn config_changed(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.isr |= 2;
+        self.raise();
+    }
+
+    fn raise(&self) {
+        if !crate::hv::vpci::set_intx(self.vm_id, self.dev, true) { return; }
+        let line = crate::hv::vpci::int_line(self.vm_id, self.dev).unwrap_or(0);
+        if line != 0 && line < 16 {
+            let _ = crate::hv::vlapic::raise(self.vm_id, 0, crate::hv::vtime::LEGACY_IRQ_VECTOR_BASE + line, false);
+        }
+    }
+}
+
+/// PCI config space for a modern virtio device of `virtio_type` with the
+/// standard capability layout over BAR 0. `device_cfg_len` is the size of
+/// the device-specific configuration structure.
+pub fn pci_config(virtio_type: u16, class: u8, subclass: u8, device_cfg_len: u32, irq_line: u8) -> crate::hv::vpci::Config {
+    let mut c = crate::hv::vpci::Config::new(VIRTIO_PCI_VENDOR, VIRTIO_PCI_DEVICE_BASE + virtio_type, class, subclass, 1);
+    c.put16(crate::hv::vpci::CFG_SUBSYS_VENDOR, VIRTIO_PCI_VENDOR);
+    c.put16(crate::hv::vpci::CFG_SUBSYS_ID, 0x40);
+    c.bar64(0, BAR_SIZE);
+    c.intx(irq_line);
+    let cap = |cfg_type: u8, off: u64, len: u32, extra: Option<u32>| {
+        // cap_len, cfg_type, bar, id, pad[2], offset, length [, notify_off_multiplier]
+        let mut b = [0u8; 18];
+        let total = if extra.is_some() { 20u8 } else { 16u8 };
+        b[0] = total; b[1] = cfg_type; b[2] = 0;
+        b[6..10].copy_from_slice(&(off as u32).to_le_bytes());
+        b[10..14].copy_from_slice(&len.to_le_bytes());
+        if let Some(m) = extra { b[14..18].copy_from_slice(&m.to_le_bytes()); }
+        (b, total as usize - 2)
+    };
+    for (t, off, len, extra) in [
+        (CAP_COMMON, COMMON_OFF, 0x38, None),
+        (CAP_ISR, ISR_OFF, 1, None),
+        (CAP_DEVICE, DEVICE_OFF, device_cfg_len, None),
+        (CAP_NOTIFY, NOTIFY_OFF, (MAX_QUEUES as u32) * NOTIFY_MULT, Some(NOTIFY_MULT)),
+    ] {
+        let (b, n) = cap(t, off, len, extra);
+        let _ = c.add_cap(crate::hv::vpci::CAP_ID_VENDOR, &b[..n]);
+    }
+    c
+}
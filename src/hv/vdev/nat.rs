@@ -0,0 +1,215 @@
+#![allow(dead_code)]
+
+//! Minimal IPv4 NAT for guest NICs in NAT mode.
+//!
+//! Guests sit behind a virtual router (`ROUTER_MAC`) that answers every ARP
+//! request except for the guest's own address. Outbound TCP, UDP and ICMP
+//! echo get the host's IPv4 address and a port (or echo id) from a small
+//! mapping table; replies addressed to a mapped port are translated back.
+//! There is no DHCP server: guests are configured statically.
+
+use crate::util::spinlock::SpinLock;
+
+/// MAC address of the virtual router as seen by guests.
+pub const ROUTER_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x35, 0x02];
+pub const MAX_MAPPINGS: usize = 64;
+/// Host-side ports handed out as `PORT_BASE + slot`.
+pub const PORT_BASE: u16 = 40000;
+
+const ETH_HDR: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+/// Host side of the NAT.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HostSide {
+    pub ip: [u8; 4],
+    pub mac: [u8; 6],
+    /// Next-hop router on the uplink
+    pub gw_mac: [u8; 6],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Mapping {
+    pub vm_id: u64,
+    /// Guest NIC (index into the vdev::net table)
+    pub nic: usize,
+    pub proto: u8,
+    pub guest_ip: [u8; 4],
+    pub guest_mac: [u8; 6],
+    pub guest_port: u16,
+    last_use: u64,
+}
+
+struct State {
+    host: HostSide,
+    map: [Option<Mapping>; MAX_MAPPINGS],
+    clock: u64,
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State { host: HostSide { ip: [0; 4], mac: [0; 6], gw_mac: [0; 6] }, map: [None; MAX_MAPPINGS], clock: 0 });
+
+pub fn set_host(h: HostSide) { STATE.lock(|s| s.host = h); }
+pub fn host() -> HostSide { STATE.lock(|s| s.host) }
+
+/// Drop every mapping of `vm_id` (VM destroyed or NIC removed).
+pub fn forget_vm(vm_id: u64) {
+    STATE.lock(|s| { for m in s.map.iter_mut() { if matches!(m, Some(x) if x.vm_id == vm_id) { *m = None; } } });
+}
+
+pub fn mappings() -> usize { STATE.lock(|s| s.map.iter().flatten().count()) }
+
+/// What to do with a frame after outbound translation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Drop,
+    /// Frame was rewritten into a reply for the guest itself
+    Reply,
+    /// Frame was translated and goes to the uplink
+    Forward,
+}
+
+#[inline(always)]
+fn be16(b: &[u8], off: usize) -> u16 { u16::from_be_bytes([b[off], b[off + 1]]) }
+#[inline(always)]
+fn put16(b: &mut [u8], off: usize, v: u16) { b[off..off + 2].copy_from_slice(&v.to_be_bytes()); }
+
+/// Incremental checksum update (RFC 1624) for replacing `old` by `new` (even lengths).
+fn csum_replace(csum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = (!csum) as u32;
+    for i in (0..old.len()).step_by(2) {
+        sum += (!be16(old, i)) as u32;
+        sum += be16(new, i) as u32;
+    }
+    while (sum >> 16) != 0 { sum = (sum & 0xFFFF) + (sum >> 16); }
+    !(sum as u16)
+}
+
+fn ipv4_header_csum(h: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for i in (0..h.len()).step_by(2) { if i != 10 { sum += be16(h, i) as u32; } }
+    while (sum >> 16) != 0 { sum = (sum & 0xFFFF) + (sum >> 16); }
+    !(sum as u16)
+}
+
+/// Offsets of the L4 port/id field and checksum for `proto`, relative to the L4 header.
+/// `dst` selects the destination port (inbound) instead of the source port.
+fn l4_fields(proto: u8, dst: bool) -> Option<(usize, usize, bool)> {
+    // (port offset, checksum offset, checksum covers pseudo-header)
+    match proto {
+        PROTO_TCP => Some((if dst { 2 } else { 0 }, 16, true)),
+        PROTO_UDP => Some((if dst { 2 } else { 0 }, 6, true)),
+        PROTO_ICMP => Some((4, 2, false)),
+        _ => None,
+    }
+}
+
+/// Rewrite one IPv4 address (src or dst) and one L4 port, fixing both checksums.
+fn rewrite(f: &mut [u8], ihl: usize, proto: u8, ip_off: usize, new_ip: [u8; 4], port_off: usize, new_port: u16) -> bool {
+    let (_, csum_off, pseudo) = match l4_fields(proto, false) { Some(x) => x, None => return false };
+    let l4 = ETH_HDR + ihl;
+    if f.len() < l4 + csum_off + 2 || f.len() < l4 + port_off + 2 { return false; }
+    let mut old_ip = [0u8; 4];
+    old_ip.copy_from_slice(&f[ETH_HDR + ip_off..ETH_HDR + ip_off + 4]);
+    let old_port = be16(f, l4 + port_off);
+    let mut c = be16(f, l4 + csum_off);
+    // A zero UDP checksum means "not computed" and must stay zero.
+    let skip = proto == PROTO_UDP && c == 0;
+    if !skip {
+        if pseudo { c = csum_replace(c, &old_ip, &new_ip); }
+        c = csum_replace(c, &old_port.to_be_bytes(), &new_port.to_be_bytes());
+        put16(f, l4 + csum_off, c);
+    }
+    f[ETH_HDR + ip_off..ETH_HDR + ip_off + 4].copy_from_slice(&new_ip);
+    put16(f, l4 + port_off, new_port);
+    let h = ipv4_header_csum(&f[ETH_HDR..ETH_HDR + ihl]);
+    put16(f, ETH_HDR + 10, h);
+    true
+}
+
+fn ipv4_parts(f: &[u8]) -> Option<(usize, u8)> {
+    if f.len() < ETH_HDR + 20 || be16(f, 12) != ETHERTYPE_IPV4 { return None; }
+    let ihl = ((f[ETH_HDR] & 0x0F) as usize) * 4;
+    if (f[ETH_HDR] >> 4) != 4 || ihl < 20 || f.len() < ETH_HDR + ihl + 8 { return None; }
+    // Fragments other than the first carry no L4 header; not translated.
+    if (be16(f, ETH_HDR + 6) & 0x1FFF) != 0 { return None; }
+    Some((ihl, f[ETH_HDR + 9]))
+}
+
+/// Translate a frame sent by a guest NIC in NAT mode.
+pub fn outbound(f: &mut [u8], vm_id: u64, nic: usize, guest_mac: [u8; 6], guest_ip: [u8; 4]) -> Action {
+    if f.len() < ETH_HDR { return Action::Drop; }
+    if be16(f, 12) == ETHERTYPE_ARP {
+        // Proxy-ARP for everything but the guest itself.
+        if f.len() < ETH_HDR + 28 || be16(f, ETH_HDR + 6) != 1 { return Action::Drop; }
+        let mut tpa = [0u8; 4]; tpa.copy_from_slice(&f[ETH_HDR + 24..ETH_HDR + 28]);
+        if tpa == guest_ip { return Action::Drop; }
+        let mut sha = [0u8; 6]; sha.copy_from_slice(&f[ETH_HDR + 8..ETH_HDR + 14]);
+        let mut spa = [0u8; 4]; spa.copy_from_slice(&f[ETH_HDR + 14..ETH_HDR + 18]);
+        put16(f, ETH_HDR + 6, 2);
+        f[ETH_HDR + 8..ETH_HDR + 14].copy_from_slice(&ROUTER_MAC);
+        f[ETH_HDR + 14..ETH_HDR + 18].copy_from_slice(&tpa);
+        f[ETH_HDR + 18..ETH_HDR + 24].copy_from_slice(&sha);
+        f[ETH_HDR + 24..ETH_HDR + 28].copy_from_slice(&spa);
+        f[0..6].copy_from_slice(&guest_mac);
+        f[6..12].copy_from_slice(&ROUTER_MAC);
+        return Action::Reply;
+    }
+    let (ihl, proto) = match ipv4_parts(f) { Some(p) => p, None => return Action::Drop };
+    if f[ETH_HDR + 12..ETH_HDR + 16] != guest_ip { return Action::Drop; }
+    let (port_off, _, _) = match l4_fields(proto, false) { Some(x) => x, None => return Action::Drop };
+    // Only echo requests are translated for ICMP.
+    if proto == PROTO_ICMP && f[ETH_HDR + ihl] != 8 { return Action::Drop; }
+    let guest_port = be16(f, ETH_HDR + ihl + port_off);
+    let (host, slot) = STATE.lock(|s| {
+        s.clock += 1;
+        let now = s.clock;
+        let found = s.map.iter().position(|m| matches!(m, Some(m) if m.vm_id == vm_id && m.nic == nic && m.proto == proto && m.guest_port == guest_port));
+        let slot = match found {
+            Some(i) => i,
+            None => {
+                // Free slot, else evict the least recently used mapping.
+                let i = s.map.iter().position(|m| m.is_none()).unwrap_or_else(|| {
+                    s.map.iter().enumerate().min_by_key(|(_, m)| m.map(|m| m.last_use).unwrap_or(0)).map(|(i, _)| i).unwrap_or(0)
+                });
+                s.map[i] = Some(Mapping { vm_id, nic, proto, guest_ip, guest_mac, guest_port, last_use: now });
+                i
+            }
+        };
+        if let Some(m) = s.map[slot].as_mut() { m.last_use = now; }
+        (s.host, slot)
+    });
+    if host.ip == [0; 4] { return Action::Drop; }
+    if !rewrite(f, ihl, proto, 12, host.ip, port_off, PORT_BASE + slot as u16) { return Action::Drop; }
+    f[0..6].copy_from_slice(&host.gw_mac);
+    f[6..12].copy_from_slice(&host.mac);
+    Action::Forward
+}
+
+/// Translate a frame from the uplink addressed to a NAT mapping. Returns the
+/// owning (vm, nic) after rewriting the frame for the guest.
+pub fn inbound(f: &mut [u8]) -> Option<(u64, usize)> {
+    let (ihl, proto) = ipv4_parts(f)?;
+    let (port_off, _, _) = l4_fields(proto, true)?;
+    if proto == PROTO_ICMP && f[ETH_HDR + ihl] != 0 { return None; }
+    let host = host();
+    if host.ip == [0; 4] || f[ETH_HDR + 16..ETH_HDR + 20] != host.ip { return None; }
+    let port = be16(f, ETH_HDR + ihl + port_off);
+    let slot = port.checked_sub(PORT_BASE)? as usize;
+    if slot >= MAX_MAPPINGS { return None; }
+    let m = STATE.lock(|s| {
+        s.clock += 1;
+        let now = s.clock;
+        let m = s.map[slot].as_mut()?;
+        if m.proto != proto { return None; }
+        m.last_use = now;
+        Some(*m)
+    })?;
+    if !rewrite(f, ihl, proto, 16, m.guest_ip, port_off, m.guest_port) { return None; }
+    f[0..6].copy_from_slice(&m.guest_mac);
+    f[6..12].copy_from_slice(&ROUTER_MAC);
+    Some((m.vm_id, m.nic))
+}
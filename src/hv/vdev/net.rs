@@ -0,0 +1,445 @@
+#![allow(dead_code)]
+
+//! virtio-net device model (virtio device type 1).
+//!
+//! The TX queue is drained in the MMIO exit that carries the driver's notify
+//! write; frames go to a shared outbound ring. `pump` (run from the CLI idle
+//! loop or `vm net pump`) moves that ring to the uplink, bridges frames
+//! between local guests and feeds received frames into guest RX queues.
+//! Frames that arrive while a guest has no RX buffers wait in a small
+//! per-NIC backlog and are delivered on the next RX notify.
+//!
+//! In bridge mode guest frames go through the soft switch (`switch`), which
+//! learns addresses and keeps VLANs apart; in NAT mode they pass through
+//! `nat` and the guest sees a virtual router. Either way a VM's transmit
+//! rate and the outbound queue its frames wait in follow its `qos`.
+
+use crate::util::spinlock::SpinLock;
+use super::{nat, qos, switch, Kick, Transport};
+
+pub const VIRTIO_ID_NET: u16 = 1;
+pub const MAX_NICS: usize = 8;
+pub const QUEUE_MAX: u16 = 256;
+pub const RXQ: u16 = 0;
+pub const TXQ: u16 = 1;
+
+pub const F_MTU: u64 = 1 << 3;
+pub const F_MAC: u64 = 1 << 5;
+pub const F_STATUS: u64 = 1 << 16;
+
+/// virtio_net_hdr including num_buffers (always present with VERSION_1)
+const HDR_LEN: usize = 12;
+pub const MTU: u16 = 1500;
+pub const FRAME_MAX: usize = 1514;
+/// Size of the virtio_net_config exposed (mac, status, max pairs, mtu)
+const CONFIG_LEN: u32 = 12;
+/// ISA line used for INTx until the guest reprograms it
+pub const DEFAULT_IRQ: u8 = 11;
+const OUT_FRAMES: usize = 32;
+const BACKLOG_FRAMES: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode { Bridge, Nat }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Uplink {
+    /// Local bridging only
+    None,
+    /// Host virtio-net driver (`crate::virtio::net`)
+    Virtio,
+    /// UEFI Simple Network Protocol handle selected with `snp use`
+    Snp,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    pub tx_drops: u64,
+    pub rx_drops: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Frame { len: u16, data: [u8; FRAME_MAX] }
+
+impl Frame { const EMPTY: Frame = Frame { len: 0, data: [0; FRAME_MAX] }; }
+
+/// Fixed FIFO of frames; pushing into a full ring fails.
+#[derive(Clone, Copy)]
+struct Ring<const N: usize> { frames: [Frame; N], src: [usize; N], head: usize, len: usize }
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self { Ring { frames: [Frame::EMPTY; N], src: [0; N], head: 0, len: 0 } }
+
+    fn push(&mut self, src: usize, data: &[u8]) -> bool {
+        if self.len == N || data.len() > FRAME_MAX { return false; }
+        let i = (self.head + self.len) % N;
+        self.frames[i].data[..data.len()].copy_from_slice(data);
+        self.frames[i].len = data.len() as u16;
+        self.src[i] = src;
+        self.len += 1;
+        true
+    }
+
+    /// Copy the oldest frame into `out` (at least FRAME_MAX bytes) and drop it; returns (src, len).
+    fn pop_into(&mut self, out: &mut [u8]) -> Option<(usize, usize)> {
+        if self.len == 0 { return None; }
+        let f = &self.frames[self.head];
+        let n = (f.len as usize).min(out.len());
+        out[..n].copy_from_slice(&f.data[..n]);
+        let src = self.src[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some((src, n))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Nic {
+    pub t: Transport,
+    pub mac: [u8; 6],
+    pub mode: Mode,
+    /// Static guest address, used by NAT mode
+    pub guest_ip: [u8; 4],
+    pub stats: Stats,
+    backlog: Ring<BACKLOG_FRAMES>,
+    /// TX frame waiting for QoS tokens (len 0 when none)
+    held: Frame,
+}
+
+static NICS: SpinLock<[Option<Nic>; MAX_NICS]> = SpinLock::new([None; MAX_NICS]);
+/// Guest frames waiting for `pump`, one ring per QoS class; `src` is the NIC index.
+static OUTQ: SpinLock<[Ring<OUT_FRAMES>; qos::CLASSES]> = SpinLock::new([Ring::new(); qos::CLASSES]);
+static UPLINK: SpinLock<Uplink> = SpinLock::new(Uplink::None);
+
+pub fn set_uplink(u: Uplink) { UPLINK.lock(|x| *x = u); }
+pub fn uplink() -> Uplink { UPLINK.lock(|x| *x) }
+
+#[inline(always)]
+fn is_group(mac: &[u8]) -> bool { (mac[0] & 1) != 0 }
+
+// ---- Guest-facing side (runs in the exit path) ----
+
+fn device_cfg_read(n: &Nic, off: u64, size: u8) -> u64 {
+    let mut cfg = [0u8; CONFIG_LEN as usize];
+    cfg[..6].copy_from_slice(&n.mac);
+    cfg[6..8].copy_from_slice(&1u16.to_le_bytes()); // VIRTIO_NET_S_LINK_UP
+    cfg[8..10].copy_from_slice(&1u16.to_le_bytes());
+    cfg[10..12].copy_from_slice(&MTU.to_le_bytes());
+    let mut v = 0u64;
+    for i in 0..size as usize {
+        let o = off as usize + i;
+        if o < cfg.len() { v |= (cfg[o] as u64) << (i * 8); }
+    }
+    v
+}
+
+/// Queue one guest frame for the uplink (or translate/loop back in NAT mode).
+fn transmit(idx: usize, n: &mut Nic, frame: &mut [u8]) {
+    let class = qos::class(n.t.vm_id);
+    let queued = match n.mode {
+        Mode::Bridge => OUTQ.lock(|q| q[class].push(idx, frame)),
+        Mode::Nat => match nat::outbound(frame, n.t.vm_id, idx, n.mac, n.guest_ip) {
+            nat::Action::Forward => OUTQ.lock(|q| q[class].push(idx, frame)),
+            nat::Action::Reply => n.backlog.push(idx, frame),
+            nat::Action::Drop => false,
+        },
+    };
+    if queued {
+        n.stats.tx_frames += 1;
+        n.stats.tx_bytes += frame.len() as u64;
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::VNET_TX_FRAMES).inc();
+    } else {
+        n.stats.tx_drops += 1;
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::VNET_DROPS).inc();
+    }
+}
+
+/// Drain the TX queue while the VM's rate allows; the first frame over it
+/// is kept in `held` and the rest stay in the guest's ring.
+fn process_tx(idx: usize, n: &mut Nic) {
+    let vm = n.t.vm_id;
+    let mut buf = [0u8; HDR_LEN + FRAME_MAX];
+    let mut done = 0u32;
+    if n.held.len != 0 {
+        let len = n.held.len as usize;
+        if !qos::admit(vm, len, true) {
+            // Still over the rate: the ring waits for the next poll.
+            deliver_backlog(n);
+            return;
+        }
+        buf[..len].copy_from_slice(&n.held.data[..len]);
+        n.held.len = 0;
+        transmit(idx, n, &mut buf[..len]);
+    }
+    loop {
+        let Some(chain) = n.t.queues[TXQ as usize].pop(vm) else { break };
+        let len = chain.read(vm, &mut buf);
+        let _ = n.t.queues[TXQ as usize].push_used(vm, chain.head, 0);
+        done += 1;
+        if len < HDR_LEN + 14 { n.stats.tx_drops += 1; continue; }
+        if !qos::admit(vm, len - HDR_LEN, false) {
+            n.held.data[..len - HDR_LEN].copy_from_slice(&buf[HDR_LEN..len]);
+            n.held.len = (len - HDR_LEN) as u16;
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::VNET_QOS_THROTTLED).inc();
+            break;
+        }
+        transmit(idx, n, &mut buf[HDR_LEN..len]);
+    }
+    if done != 0 { n.t.interrupt(); }
+    // NAT replies (ARP) go straight back to the guest.
+    deliver_backlog(n);
+}
+
+/// Move backlog frames into guest RX buffers while both are available.
+fn deliver_backlog(n: &mut Nic) {
+    if !n.t.driver_ok() { return; }
+    let vm = n.t.vm_id;
+    let mut delivered = 0u32;
+    let mut buf = [0u8; HDR_LEN + FRAME_MAX];
+    while n.backlog.len != 0 {
+        let chain = match n.t.queues[RXQ as usize].pop(vm) { Some(c) => c, None => break };
+        let len = match n.backlog.pop_into(&mut buf[HDR_LEN..]) { Some((_, l)) => l, None => break };
+        delivered += 1;
+        if chain.writable_len() < HDR_LEN + len {
+            // Buffer too small for this frame (no mergeable buffers): drop it.
+            let _ = n.t.queues[RXQ as usize].push_used(vm, chain.head, 0);
+            n.stats.rx_drops += 1;
+            continue;
+        }
+        buf[..HDR_LEN].fill(0);
+        buf[10..12].copy_from_slice(&1u16.to_le_bytes()); // num_buffers
+        let wrote = chain.write(vm, &buf[..HDR_LEN + len]);
+        let _ = n.t.queues[RXQ as usize].push_used(vm, chain.head, wrote as u32);
+        n.stats.rx_frames += 1;
+        n.stats.rx_bytes += len as u64;
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::VNET_RX_FRAMES).inc();
+    }
+    if delivered != 0 { n.t.interrupt(); }
+}
+
+fn mmio(_vm_id: u64, _vcpu: u32, ctx: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    let idx = ctx as usize;
+    NICS.lock(|t| {
+        let n = match t.get_mut(idx).and_then(|n| n.as_mut()) { Some(n) => n, None => return 0 };
+        if (super::DEVICE_OFF..super::NOTIFY_OFF).contains(&off) {
+            return if write.is_none() { device_cfg_read(n, off - super::DEVICE_OFF, size) } else { 0 };
+        }
+        let (v, kick) = n.t.mmio(off, size, write);
+        match kick {
+            Kick::Queue(TXQ) => process_tx(idx, n),
+            Kick::Queue(RXQ) | Kick::Ready => deliver_backlog(n),
+            Kick::Reset => { n.backlog = Ring::new(); n.held.len = 0; }
+            _ => {}
+        }
+        v
+    })
+}
+
+fn on_bar(vm_id: u64, ctx: u64, _bar: usize, old: u64, new: u64) {
+    if old != 0 { let _ = crate::hv::bus::unregister_mmio(vm_id, old); }
+    if new != 0 { let _ = crate::hv::bus::register_mmio(vm_id, new, super::BAR_SIZE, "virtio-net", ctx, mmio); }
+    let _ = NICS.lock(|t| t.get_mut(ctx as usize).and_then(|n| n.as_mut()).map(|n| n.t.bar = new));
+}
+
+/// Plug a NIC into `vm_id`'s PCI bus. Returns (NIC index, PCI device number).
+pub fn add(vm_id: u64, mac: [u8; 6], mode: Mode, guest_ip: [u8; 4]) -> Result<(usize, u8), &'static str> {
+    if is_group(&mac) { return Err("vnet: MAC must be unicast"); }
+    // Every NIC shares the host network, bridged or behind NAT
+    crate::hv::info_flow::check_both(crate::hv::info_flow::Object::Vm(vm_id), crate::hv::info_flow::Object::Network)?;
+    let idx = NICS.lock(|t| {
+        if t.iter().flatten().any(|n| n.mac == mac) { return Err("vnet: MAC already in use"); }
+        let i = t.iter().position(|n| n.is_none()).ok_or("vnet: too many NICs")?;
+        t[i] = Some(Nic {
+            t: Transport::new(vm_id, 2, QUEUE_MAX, F_MAC | F_STATUS | F_MTU),
+            mac, mode, guest_ip, stats: Stats::default(), backlog: Ring::new(), held: Frame::EMPTY,
+        });
+        Ok(i)
+    })?;
+    let cfg = super::pci_config(VIRTIO_ID_NET, 0x02, 0x00, CONFIG_LEN, DEFAULT_IRQ);
+    match crate::hv::vpci::add(vm_id, cfg, idx as u64, on_bar) {
+        Some(dev) => {
+            NICS.lock(|t| if let Some(n) = t[idx].as_mut() { n.t.dev = dev; });
+            if mode == Mode::Bridge { switch::attach(idx, vm_id, mac); }
+            Ok((idx, dev))
+        }
+        None => {
+            NICS.lock(|t| t[idx] = None);
+            Err("vnet: no free PCI slot")
+        }
+    }
+}
+
+/// Unplug NIC `idx` of `vm_id`, with its switch port.
+pub fn remove(vm_id: u64, idx: usize) -> Result<(), &'static str> {
+    let dev = NICS.lock(|t| t.get(idx).and_then(|n| n.as_ref()).filter(|n| n.t.vm_id == vm_id).map(|n| n.t.dev)).ok_or("vnet: no such NIC")?;
+    crate::hv::vpci::remove(vm_id, dev);
+    NICS.lock(|t| t[idx] = None);
+    switch::detach(idx);
+    Ok(())
+}
+
+/// Remove every NIC of `vm_id` (PCI functions are torn down with the bus).
+pub fn detach_vm(vm_id: u64) {
+    let mut gone = [false; MAX_NICS];
+    NICS.lock(|t| { for (i, n) in t.iter_mut().enumerate() { if matches!(n, Some(x) if x.t.vm_id == vm_id) { *n = None; gone[i] = true; } } });
+    for (i, _) in gone.iter().enumerate().filter(|g| *g.1) { switch::detach(i); }
+    switch::forget_vm(vm_id);
+    qos::forget_vm(vm_id);
+    nat::forget_vm(vm_id);
+}
+
+/// NIC state for reporting (the backlog itself is not copied out).
+#[derive(Clone, Copy, Debug)]
+pub struct NicInfo {
+    pub vm_id: u64,
+    pub dev: u8,
+    pub bar: u64,
+    pub mac: [u8; 6],
+    pub mode: Mode,
+    pub guest_ip: [u8; 4],
+    pub driver_ok: bool,
+    pub backlog: usize,
+    pub stats: Stats,
+}
+
+impl Nic {
+    fn info(&self) -> NicInfo {
+        NicInfo { vm_id: self.t.vm_id, dev: self.t.dev, bar: self.t.bar, mac: self.mac, mode: self.mode, guest_ip: self.guest_ip, driver_ok: self.t.driver_ok(), backlog: self.backlog.len, stats: self.stats }
+    }
+}
+
+pub fn get(idx: usize) -> Option<NicInfo> { NICS.lock(|t| t.get(idx).and_then(|n| n.as_ref()).map(|n| n.info())) }
+
+/// Iterate NICs as (index, info).
+pub fn for_each(mut f: impl FnMut(usize, &NicInfo)) {
+    let mut snap: [Option<NicInfo>; MAX_NICS] = [None; MAX_NICS];
+    NICS.lock(|t| { for (i, n) in t.iter().enumerate() { snap[i] = n.as_ref().map(|n| n.info()); } });
+    for (i, n) in snap.iter().enumerate() { if let Some(n) = n { f(i, n); } }
+}
+
+/// Parse `aa:bb:cc:dd:ee:ff`.
+pub fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut it = s.split(':');
+    for b in mac.iter_mut() { *b = u8::from_str_radix(it.next()?, 16).ok()?; }
+    if it.next().is_some() { return None; }
+    Some(mac)
+}
+
+/// Parse dotted-quad IPv4.
+pub fn parse_ipv4(s: &str) -> Option<[u8; 4]> {
+    let mut ip = [0u8; 4];
+    let mut it = s.split('.');
+    for b in ip.iter_mut() { *b = it.next()?.parse::<u8>().ok()?; }
+    if it.next().is_some() { return None; }
+    Some(ip)
+}
+
+// ---- Host side ----
+
+/// Queue an inbound frame on NIC `idx` and try to hand it to the guest.
+fn deliver_to(idx: usize, frame: &[u8]) {
+    NICS.lock(|t| {
+        let n = match t.get_mut(idx).and_then(|n| n.as_mut()) { Some(n) => n, None => return };
+        if !n.backlog.push(idx, frame) {
+            n.stats.rx_drops += 1;
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::VNET_DROPS).inc();
+        }
+        deliver_backlog(n);
+    });
+}
+
+/// Switch a frame received on `from`: deliver it to the NICs the switch
+/// picks and, if it goes upstream too, shape it for the uplink into `up`
+/// (FRAME_MAX + TAG_LEN bytes) and return its length.
+fn switch_frame(from: switch::Port, frame: &[u8], up: &mut [u8]) -> Option<usize> {
+    let d = switch::forward(from, frame)?;
+    let mut plain = [0u8; FRAME_MAX + switch::TAG_LEN];
+    let n = switch::untag(frame, &mut plain);
+    let mut out = [0u8; FRAME_MAX + switch::TAG_LEN];
+    for (i, _) in d.nics.iter().enumerate().filter(|t| *t.1) {
+        let len = switch::egress(switch::Port::Nic(i), d.vlan, &plain[..n], &mut out);
+        deliver_to(i, &out[..len]);
+    }
+    if !d.uplink || up.is_empty() { return None; }
+    Some(switch::egress(switch::Port::Uplink, d.vlan, &plain[..n], up))
+}
+
+/// Frame received from the uplink.
+pub fn inbound(frame: &[u8]) {
+    if frame.len() < 14 || frame.len() > FRAME_MAX + switch::TAG_LEN { return; }
+    if crate::ctl::http::on_frame(frame) { return; }
+    if u16::from_be_bytes([frame[12], frame[13]]) == crate::cluster::ETHERTYPE { crate::cluster::on_frame(frame); return; }
+    if crate::migrate::rdma::on_frame(frame) { return; }
+    let mut f = [0u8; FRAME_MAX + switch::TAG_LEN];
+    f[..frame.len()].copy_from_slice(frame);
+    let f = &mut f[..frame.len()];
+    if let Some((_vm, nic)) = nat::inbound(f) { deliver_to(nic, f); return; }
+    let _ = switch_frame(switch::Port::Uplink, f, &mut []);
+}
+
+#[cfg(feature = "virtio-net")]
+pub(crate) fn uplink_send(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>, u: Uplink, frame: &[u8]) -> bool {
+    match u {
+        Uplink::None => false,
+        Uplink::Virtio => crate::virtio::net::tx_send(system_table, frame) != 0,
+        Uplink::Snp => crate::migrate::snp_send_raw(system_table, frame),
+    }
+}
+
+#[cfg(not(feature = "virtio-net"))]
+pub(crate) fn uplink_send(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>, u: Uplink, frame: &[u8]) -> bool {
+    match u {
+        Uplink::Snp => crate::migrate::snp_send_raw(system_table, frame),
+        _ => false,
+    }
+}
+
+#[cfg(feature = "virtio-net")]
+pub(crate) fn uplink_recv(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>, u: Uplink, limit: usize) -> usize {
+    match u {
+        Uplink::None => 0,
+        Uplink::Virtio => crate::virtio::net::rx_frames(system_table, limit, inbound),
+        Uplink::Snp => crate::migrate::snp_recv_raw(system_table, limit, inbound),
+    }
+}
+
+#[cfg(not(feature = "virtio-net"))]
+pub(crate) fn uplink_recv(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>, u: Uplink, limit: usize) -> usize {
+    match u {
+        Uplink::Snp => crate::migrate::snp_recv_raw(system_table, limit, inbound),
+        _ => 0,
+    }
+}
+
+/// Move frames between guests and the uplink. Returns (sent, received).
+/// `limit` bounds received frames (0 = until the uplink is empty).
+pub fn pump(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>, limit: usize) -> (usize, usize) {
+    let u = uplink();
+    let mut sent = 0usize;
+    let mut buf = [0u8; FRAME_MAX];
+    let mut up = [0u8; FRAME_MAX + switch::TAG_LEN];
+    // NICs waiting on their rate send what the bucket now allows.
+    NICS.lock(|t| { for (i, n) in t.iter_mut().enumerate() { if let Some(n) = n.as_mut().filter(|n| n.held.len != 0) { process_tx(i, n); } } });
+    // Strict priority: the highest class with a frame goes next.
+    while let Some((src, len)) = OUTQ.lock(|q| q.iter_mut().find_map(|r| r.pop_into(&mut buf))) {
+        let mode = get(src).map(|n| n.mode);
+        // NAT frames are already addressed to the uplink router.
+        let frame = if mode == Some(Mode::Bridge) {
+            match switch_frame(switch::Port::Nic(src), &buf[..len], &mut up) { Some(n) => &up[..n], None => continue }
+        } else {
+            &buf[..len]
+        };
+        if uplink_send(system_table, u, frame) { sent += 1; }
+        else if u != Uplink::None || mode == Some(Mode::Nat) {
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::VNET_DROPS).inc();
+        }
+    }
+    let received = uplink_recv(system_table, u, limit);
+    // Retry backlogs in case guests posted RX buffers without notifying.
+    NICS.lock(|t| { for n in t.iter_mut().flatten() { deliver_backlog(n); } });
+    (sent, received)
+}
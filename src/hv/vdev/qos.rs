@@ -0,0 +1,100 @@
+#![allow(dead_code)]
+
+//! Per-VM network QoS: a transmit rate limit and a priority class.
+//!
+//! Every frame a VM's NICs transmit takes its length from the VM's token
+//! bucket, which refills at `rate` and holds at most `BURST_MS` of it (never
+//! less than two full frames). A NIC whose next frame does not fit keeps it
+//! and stops draining its TX queue until the bucket refills, so an
+//! over-rate guest is pushed back through its own ring instead of crowding
+//! the shared outbound queue. What it does send waits there in the queue
+//! of its class, and `net::pump` always serves the highest class first.
+
+use crate::util::spinlock::SpinLock;
+
+/// Priority classes, 0 served first
+pub const CLASSES: usize = 3;
+/// Refill the bucket can hold, in milliseconds of the rate
+pub const BURST_MS: u64 = 10;
+const MIN_BURST: u64 = 2 * super::net::FRAME_MAX as u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prio { High, Normal, Low }
+
+impl Prio {
+    pub fn class(self) -> usize { self as usize }
+    pub fn name(self) -> &'static str { match self { Prio::High => "high", Prio::Normal => "normal", Prio::Low => "low" } }
+    pub fn parse(s: &str) -> Option<Prio> {
+        match s { "high" => Some(Prio::High), "normal" => Some(Prio::Normal), "low" => Some(Prio::Low), _ => None }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Qos {
+    pub vm_id: u64,
+    /// Megabits per second; 0 = unlimited
+    pub rate_mbps: u32,
+    pub prio: Prio,
+    /// Bytes let through
+    pub sent: u64,
+    /// Times a NIC of the VM had to wait for tokens
+    pub throttled: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Entry { q: Qos, tokens: u64, last_ns: u64 }
+
+static TABLE: SpinLock<[Option<Entry>; crate::mm::guest::MAX_VMS]> = SpinLock::new([None; crate::mm::guest::MAX_VMS]);
+
+fn burst(rate_mbps: u32) -> u64 { (rate_mbps as u64 * 125_000 / 1000 * BURST_MS).max(MIN_BURST) }
+
+/// Set `vm_id`'s limit and class. A VM with no limit in the normal class
+/// drops out of the table.
+pub fn set(vm_id: u64, rate_mbps: u32, prio: Prio) -> Result<(), &'static str> {
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err("qos: no such vm"); }
+    TABLE.lock(|t| {
+        let at = t.iter().position(|e| matches!(e, Some(x) if x.q.vm_id == vm_id));
+        if rate_mbps == 0 && prio == Prio::Normal {
+            if let Some(i) = at { t[i] = None; }
+            return Ok(());
+        }
+        let i = match at { Some(i) => i, None => t.iter().position(|e| e.is_none()).ok_or("qos: table full")? };
+        let (sent, throttled) = t[i].map_or((0, 0), |e| (e.q.sent, e.q.throttled));
+        t[i] = Some(Entry { q: Qos { vm_id, rate_mbps, prio, sent, throttled }, tokens: burst(rate_mbps), last_ns: crate::time::clock::now_ns() });
+        Ok(())
+    })
+}
+
+pub fn get(vm_id: u64) -> Option<Qos> { TABLE.lock(|t| t.iter().flatten().find(|e| e.q.vm_id == vm_id).map(|e| e.q)) }
+
+/// Queue class for `vm_id`'s frames.
+pub fn class(vm_id: u64) -> usize { get(vm_id).map_or(Prio::Normal.class(), |q| q.prio.class()) }
+
+/// Take `bytes` from `vm_id`'s bucket. False means the frame has to wait;
+/// `retry` is set when asking again for a frame already waiting.
+pub fn admit(vm_id: u64, bytes: usize, retry: bool) -> bool {
+    let running = crate::time::clock::hz() != 0;
+    let now = crate::time::clock::now_ns();
+    TABLE.lock(|t| {
+        let Some(e) = t.iter_mut().flatten().find(|e| e.q.vm_id == vm_id) else { return true };
+        if e.q.rate_mbps != 0 && running {
+            let rate = e.q.rate_mbps as u64 * 125_000;
+            let dt = now.saturating_sub(e.last_ns);
+            let add = (dt as u128 * rate as u128 / 1_000_000_000) as u64;
+            if add != 0 { e.tokens = (e.tokens + add).min(burst(e.q.rate_mbps)); e.last_ns = now; }
+            if e.tokens < bytes as u64 { if !retry { e.q.throttled += 1; } return false; }
+            e.tokens -= bytes as u64;
+        }
+        e.q.sent += bytes as u64;
+        true
+    })
+}
+
+pub fn forget_vm(vm_id: u64) {
+    TABLE.lock(|t| for e in t.iter_mut() { if matches!(e, Some(x) if x.q.vm_id == vm_id) { *e = None; } });
+}
+
+pub fn for_each(mut f: impl FnMut(&Qos)) {
+    let snap = TABLE.lock(|t| *t);
+    for e in snap.iter().flatten() { f(&e.q); }
+}
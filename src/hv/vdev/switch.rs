@@ -0,0 +1,365 @@
+#![allow(dead_code)]
+
+//! Layer-2 soft switch behind bridge-mode virtio-net NICs.
+//!
+//! Each bridge-mode NIC is a port, and so is the `vm net` uplink. Frames are
+//! forwarded by a MAC table keyed on (MAC, VLAN): a NIC's own MAC is a static
+//! entry from the moment it is plugged in, every other source address is
+//! learned from the port it arrives on and ages out after `aging` seconds
+//! of silence. A learned address cannot take over a static one, so a guest
+//! cannot pull another NIC's traffic to itself by spoofing its MAC.
+//!
+//! VLANs are per port. An access port (`vlan` 1..4094) carries untagged
+//! frames of that VLAN and drops tagged ones; a trunk port (`vlan` 0, the
+//! uplink's default) carries each VLAN with its 802.1Q tag and untagged
+//! frames as VLAN 0. Giving a VM a VLAN (`set_vm_vlan`) makes every NIC it
+//! has, and every NIC it gets later, an access port of that VLAN.
+//!
+//! Broadcast and multicast go to every port of the VLAN, subject to each
+//! ingress port's per-second limit. Unicast to an unknown address goes only
+//! to ports with flooding on, which is the uplink alone by default, so guests
+//! do not see each other's traffic to the outside. `net` owns the frames:
+//! `forward` only says where one goes, and `egress` shapes it for a port.
+
+use crate::obs::metrics::{Counter, VSWITCH_DROPS, VSWITCH_FLOODS};
+use crate::util::spinlock::SpinLock;
+
+use super::net::MAX_NICS;
+
+pub const MAX_PORTS: usize = MAX_NICS + 1;
+pub const MAX_FDB: usize = 128;
+pub const DEFAULT_AGING_S: u32 = 300;
+pub const VLAN_MAX: u16 = 4094;
+/// Bytes an 802.1Q tag adds to a frame
+pub const TAG_LEN: usize = 4;
+const TPID: u16 = 0x8100;
+const UPLINK: usize = MAX_NICS;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port { Nic(usize), Uplink }
+
+impl Port {
+    fn index(self) -> usize { match self { Port::Nic(i) => i, Port::Uplink => UPLINK } }
+    fn of(i: usize) -> Port { if i == UPLINK { Port::Uplink } else { Port::Nic(i) } }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortCfg {
+    /// Access VLAN, or 0 for a trunk
+    pub vlan: u16,
+    /// Receive unicast to unknown addresses
+    pub flood: bool,
+    /// Broadcast and multicast frames accepted per second (0 = no limit)
+    pub bcast_per_s: u32,
+}
+
+const NIC_DEFAULT: PortCfg = PortCfg { vlan: 0, flood: false, bcast_per_s: 0 };
+const UPLINK_DEFAULT: PortCfg = PortCfg { vlan: 0, flood: true, bcast_per_s: 0 };
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PortStats {
+    pub rx: u64,
+    pub forwarded: u64,
+    pub flooded: u64,
+    /// Destination on the port the frame came from
+    pub filtered: u64,
+    /// Over the broadcast limit
+    pub storm_drops: u64,
+    /// Tagged frame on an access port
+    pub vlan_drops: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PortInfo { pub port: Port, pub vm_id: Option<u64>, pub cfg: PortCfg, pub stats: PortStats }
+
+#[derive(Clone, Copy)]
+struct PortState {
+    attached: bool,
+    vm_id: Option<u64>,
+    cfg: PortCfg,
+    stats: PortStats,
+    /// Broadcast budget: start of the current second and frames in it
+    window_ms: u64,
+    window_frames: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FdbEntry {
+    pub mac: [u8; 6],
+    pub vlan: u16,
+    pub port: Port,
+    pub is_static: bool,
+    /// Since the address was last seen (0 for static entries)
+    pub age_ms: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Entry { mac: [u8; 6], vlan: u16, port: usize, is_static: bool, seen_ms: u64 }
+
+struct Switch {
+    ports: [PortState; MAX_PORTS],
+    fdb: [Option<Entry>; MAX_FDB],
+    aging_s: u32,
+    /// VLAN given to each VM's NICs
+    vm_vlans: [Option<(u64, u16)>; crate::mm::guest::MAX_VMS],
+}
+
+const DETACHED: PortState = PortState { attached: false, vm_id: None, cfg: NIC_DEFAULT, stats: PortStats { rx: 0, forwarded: 0, flooded: 0, filtered: 0, storm_drops: 0, vlan_drops: 0 }, window_ms: 0, window_frames: 0 };
+
+static SWITCH: SpinLock<Switch> = SpinLock::new(Switch {
+    ports: {
+        let mut p = [DETACHED; MAX_PORTS];
+        p[UPLINK].attached = true;
+        p[UPLINK].cfg = UPLINK_DEFAULT;
+        p
+    },
+    fdb: [None; MAX_FDB],
+    aging_s: DEFAULT_AGING_S,
+    vm_vlans: [None; crate::mm::guest::MAX_VMS],
+});
+
+/// Where a frame goes, and the VLAN it travels in.
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub vlan: u16,
+    pub nics: [bool; MAX_NICS],
+    pub uplink: bool,
+}
+
+/// VLAN ID of an 802.1Q tagged frame.
+pub fn tag_of(frame: &[u8]) -> Option<u16> {
+    if frame.len() < 14 + TAG_LEN || u16::from_be_bytes([frame[12], frame[13]]) != TPID { return None; }
+    Some(u16::from_be_bytes([frame[14], frame[15]]) & 0x0FFF)
+}
+
+/// Copy `frame` into `out` without its 802.1Q tag, if it has one.
+pub fn untag(frame: &[u8], out: &mut [u8]) -> usize {
+    if tag_of(frame).is_none() {
+        out[..frame.len()].copy_from_slice(frame);
+        return frame.len();
+    }
+    out[..12].copy_from_slice(&frame[..12]);
+    let rest = &frame[12 + TAG_LEN..];
+    out[12..12 + rest.len()].copy_from_slice(rest);
+    12 + rest.len()
+}
+
+/// Shape untagged `frame` of `vlan` for `port` into `out` (room for
+/// `frame.len() + TAG_LEN`): trunks get the tag unless `vlan` is 0.
+pub fn egress(port: Port, vlan: u16, frame: &[u8], out: &mut [u8]) -> usize {
+    let trunk = SWITCH.lock(|s| s.ports[port.index()].cfg.vlan == 0);
+    if !trunk || vlan == 0 {
+        out[..frame.len()].copy_from_slice(frame);
+        return frame.len();
+    }
+    out[..12].copy_from_slice(&frame[..12]);
+    out[12..14].copy_from_slice(&TPID.to_be_bytes());
+    out[14..16].copy_from_slice(&(vlan & 0x0FFF).to_be_bytes());
+    out[16..16 + frame.len() - 12].copy_from_slice(&frame[12..]);
+    frame.len() + TAG_LEN
+}
+
+fn carries(p: &PortState, vlan: u16) -> bool { p.attached && (p.cfg.vlan == 0 || p.cfg.vlan == vlan) }
+
+/// Learn `mac` in `vlan` on port `port`, unless a static entry owns it.
+fn learn(s: &mut Switch, mac: [u8; 6], vlan: u16, port: usize, now: u64) {
+    if s.fdb.iter().flatten().any(|e| e.is_static && e.mac == mac) { return; }
+    if let Some(e) = s.fdb.iter_mut().flatten().find(|e| e.mac == mac && e.vlan == vlan) {
+        e.port = port;
+        e.seen_ms = now;
+        return;
+    }
+    let slot = match s.fdb.iter().position(|e| e.is_none()) {
+        Some(i) => i,
+        // Full: the dynamic entry seen longest ago makes room.
+        None => match s.fdb.iter().enumerate()
+            .filter_map(|(i, e)| e.filter(|e| !e.is_static).map(|e| (i, now.saturating_sub(e.seen_ms))))
+            .max_by_key(|&(_, age)| age) {
+            Some((i, _)) => i,
+            None => return,
+        },
+    };
+    s.fdb[slot] = Some(Entry { mac, vlan, port, is_static: false, seen_ms: now });
+}
+
+/// Port that `mac` in `vlan` sits behind, dropping it if it aged out.
+fn lookup(s: &mut Switch, mac: &[u8], vlan: u16, now: u64) -> Option<usize> {
+    let aging_ms = s.aging_s as u64 * 1000;
+    let slot = s.fdb.iter_mut().find(|e| matches!(e, Some(x) if x.mac == mac && (x.vlan == vlan || x.is_static)))?;
+    let e = (*slot)?;
+    if !e.is_static && now.saturating_sub(e.seen_ms) > aging_ms { *slot = None; return None; }
+    Some(e.port)
+}
+
+/// Decide where `frame`, received on `from`, goes. None drops it.
+pub fn forward(from: Port, frame: &[u8]) -> Option<Decision> {
+    if frame.len() < 14 { return None; }
+    let now = crate::time::clock::now_ms();
+    let i = from.index();
+    let group = frame[0] & 1 != 0;
+    let (d, flooded) = SWITCH.lock(|s| {
+        let p = &mut s.ports[i];
+        if !p.attached { return (None, false); }
+        p.stats.rx += 1;
+        let vlan = match (p.cfg.vlan, tag_of(frame)) {
+            (0, t) => t.unwrap_or(0),
+            (_, Some(_)) => { p.stats.vlan_drops += 1; return (None, false); }
+            (v, None) => v,
+        };
+        if group && p.cfg.bcast_per_s != 0 {
+            if now.saturating_sub(p.window_ms) >= 1000 { p.window_ms = now; p.window_frames = 0; }
+            if p.window_frames >= p.cfg.bcast_per_s { p.stats.storm_drops += 1; return (None, false); }
+            p.window_frames += 1;
+        }
+        let mut src = [0u8; 6];
+        src.copy_from_slice(&frame[6..12]);
+        if src[0] & 1 == 0 { learn(s, src, vlan, i, now); }
+        let mut d = Decision { vlan, nics: [false; MAX_NICS], uplink: false };
+        let known = if group { None } else { lookup(s, &frame[0..6], vlan, now) };
+        match known {
+            Some(to) if to == i => { s.ports[i].stats.filtered += 1; return (None, false); }
+            Some(to) => {
+                if !carries(&s.ports[to], vlan) { s.ports[i].stats.vlan_drops += 1; return (None, false); }
+                if to == UPLINK { d.uplink = true; } else { d.nics[to] = true; }
+                s.ports[i].stats.forwarded += 1;
+            }
+            None => {
+                for (k, q) in s.ports.iter().enumerate() {
+                    if k == i || !carries(q, vlan) || (!group && !q.cfg.flood) { continue; }
+                    if k == UPLINK { d.uplink = true; } else { d.nics[k] = true; }
+                }
+                if group { s.ports[i].stats.forwarded += 1; } else { s.ports[i].stats.flooded += 1; }
+                if !group { return (Some(d), true); }
+            }
+        }
+        (Some(d), false)
+    });
+    if flooded { Counter::new(&VSWITCH_FLOODS).inc(); }
+    if d.is_none() { Counter::new(&VSWITCH_DROPS).inc(); }
+    d
+}
+
+/// Make NIC `idx` of `vm_id` a port, with `mac` as a static entry.
+pub fn attach(idx: usize, vm_id: u64, mac: [u8; 6]) {
+    if idx >= MAX_NICS { return; }
+    SWITCH.lock(|s| {
+        let vlan = s.vm_vlans.iter().flatten().find(|v| v.0 == vm_id).map_or(0, |v| v.1);
+        s.ports[idx] = PortState { attached: true, vm_id: Some(vm_id), cfg: PortCfg { vlan, ..NIC_DEFAULT }, ..DETACHED };
+        for e in s.fdb.iter_mut() { if matches!(e, Some(x) if x.port == idx || x.mac == mac) { *e = None; } }
+        if let Some(slot) = s.fdb.iter_mut().find(|e| e.is_none()) {
+            *slot = Some(Entry { mac, vlan, port: idx, is_static: true, seen_ms: 0 });
+        }
+    });
+}
+
+/// Remove NIC `idx`'s port and every address behind it.
+pub fn detach(idx: usize) {
+    if idx >= MAX_NICS { return; }
+    SWITCH.lock(|s| {
+        s.ports[idx] = DETACHED;
+        for e in s.fdb.iter_mut() { if matches!(e, Some(x) if x.port == idx) { *e = None; } }
+    });
+}
+
+pub fn forget_vm(vm_id: u64) {
+    SWITCH.lock(|s| for v in s.vm_vlans.iter_mut() { if matches!(v, Some(x) if x.0 == vm_id) { *v = None; } });
+}
+
+fn check_vlan(vlan: u16) -> Result<(), &'static str> {
+    if vlan > VLAN_MAX { return Err("vswitch: VLAN must be 1..4094 (0 for a trunk)"); }
+    Ok(())
+}
+
+/// Reconfigure a port. Changing its VLAN forgets what was learned on it.
+pub fn set_port(port: Port, cfg: PortCfg) -> Result<(), &'static str> {
+    check_vlan(cfg.vlan)?;
+    let i = port.index();
+    if i > UPLINK { return Err("vswitch: no such port"); }
+    SWITCH.lock(|s| {
+        let p = &mut s.ports[i];
+        if !p.attached { return Err("vswitch: no such port (bridge-mode NICs only)"); }
+        let moved = p.cfg.vlan != cfg.vlan;
+        p.cfg = cfg;
+        if moved { retag(s, i, cfg.vlan); }
+        Ok(())
+    })
+}
+
+/// Drop the dynamic entries of port `i` and move its static one to `vlan`.
+fn retag(s: &mut Switch, i: usize, vlan: u16) {
+    for e in s.fdb.iter_mut() {
+        match e {
+            Some(x) if x.port == i && x.is_static => x.vlan = vlan,
+            Some(x) if x.port == i => *e = None,
+            _ => {}
+        }
+    }
+}
+
+pub fn port(port: Port) -> Option<PortInfo> {
+    SWITCH.lock(|s| {
+        let p = s.ports.get(port.index())?;
+        p.attached.then_some(PortInfo { port, vm_id: p.vm_id, cfg: p.cfg, stats: p.stats })
+    })
+}
+
+/// Put every NIC of `vm_id`, now and later, on access VLAN `vlan` (0
+/// makes them trunks again).
+pub fn set_vm_vlan(vm_id: u64, vlan: u16) -> Result<u32, &'static str> {
+    check_vlan(vlan)?;
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err("vswitch: no such vm"); }
+    SWITCH.lock(|s| {
+        match s.vm_vlans.iter().position(|v| matches!(v, Some(x) if x.0 == vm_id)) {
+            Some(k) if vlan == 0 => s.vm_vlans[k] = None,
+            Some(k) => s.vm_vlans[k] = Some((vm_id, vlan)),
+            None if vlan == 0 => {}
+            None => *s.vm_vlans.iter_mut().find(|v| v.is_none()).ok_or("vswitch: VLAN table full")? = Some((vm_id, vlan)),
+        }
+        let mut n = 0;
+        for i in 0..MAX_NICS {
+            if !s.ports[i].attached || s.ports[i].vm_id != Some(vm_id) { continue; }
+            if s.ports[i].cfg.vlan != vlan { s.ports[i].cfg.vlan = vlan; retag(s, i, vlan); }
+            n += 1;
+        }
+        Ok(n)
+    })
+}
+
+pub fn vm_vlan(vm_id: u64) -> u16 { SWITCH.lock(|s| s.vm_vlans.iter().flatten().find(|v| v.0 == vm_id).map_or(0, |v| v.1)) }
+
+pub fn aging() -> u32 { SWITCH.lock(|s| s.aging_s) }
+
+pub fn set_aging(secs: u32) -> Result<(), &'static str> {
+    if secs == 0 { return Err("vswitch: aging must be at least 1 second"); }
+    SWITCH.lock(|s| s.aging_s = secs);
+    Ok(())
+}
+
+/// Forget every learned address. Returns how many.
+pub fn flush() -> u32 {
+    SWITCH.lock(|s| {
+        let mut n = 0;
+        for e in s.fdb.iter_mut() { if matches!(e, Some(x) if !x.is_static) { *e = None; n += 1; } }
+        n
+    })
+}
+
+pub fn for_each_port(mut f: impl FnMut(&PortInfo)) {
+    let ports = SWITCH.lock(|s| s.ports);
+    for (i, p) in ports.iter().enumerate() {
+        if p.attached { f(&PortInfo { port: Port::of(i), vm_id: p.vm_id, cfg: p.cfg, stats: p.stats }); }
+    }
+}
+
+/// Call `f` for every live table entry; aged-out ones are dropped first.
+pub fn for_each_fdb(mut f: impl FnMut(&FdbEntry)) {
+    let now = crate::time::clock::now_ms();
+    let fdb = SWITCH.lock(|s| {
+        let aging_ms = s.aging_s as u64 * 1000;
+        for e in s.fdb.iter_mut() { if matches!(e, Some(x) if !x.is_static && now.saturating_sub(x.seen_ms) > aging_ms) { *e = None; } }
+        s.fdb
+    });
+    for e in fdb.iter().flatten() {
+        f(&FdbEntry { mac: e.mac, vlan: e.vlan, port: Port::of(e.port), is_static: e.is_static, age_ms: if e.is_static { 0 } else { now.saturating_sub(e.seen_ms) } });
+    }
+}
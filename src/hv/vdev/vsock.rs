@@ -0,0 +1,550 @@
+#![allow(dead_code)]
+
+//! virtio-vsock device model (virtio device type 19) carrying the host ↔
+//! guest-agent control channel.
+//!
+//! The host end is CID 2 and only speaks to `AGENT_PORT`: the host either
+//! connects to a guest agent listening there, or accepts the agent's own
+//! connection to the same port on the host. Every other request is reset.
+//! One stream connection exists per VM.
+//!
+//! On top of the stream, messages are framed as an 8-byte header (payload
+//! length u32, kind u8, reserved u8, tag u16, little-endian) followed by at
+//! most `MAX_MSG` payload bytes. The host answers `PING` itself and counts it
+//! as a heartbeat (`hv::heartbeat`); replies are kept in a small per-VM inbox
+//! until `take_reply` collects them by tag, and agent `EVENT` messages are
+//! logged by `pump`.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::obs::metrics::{Counter, VSOCK_DROPS, VSOCK_RX_PKTS, VSOCK_TX_PKTS};
+use crate::util::spinlock::SpinLock;
+use super::{Kick, Transport};
+
+pub const VIRTIO_ID_VSOCK: u16 = 19;
+pub const MAX_VSOCKS: usize = 8;
+pub const QUEUE_MAX: u16 = 64;
+pub const RXQ: u16 = 0;
+pub const TXQ: u16 = 1;
+pub const EVQ: u16 = 2;
+/// ISA line used for INTx until the guest reprograms it
+pub const DEFAULT_IRQ: u8 = 7;
+pub const F_STREAM: u64 = 1 << 0;
+
+pub const HOST_CID: u64 = 2;
+/// Port of the guest agent, and of the host end for agent-initiated connections
+pub const AGENT_PORT: u32 = 5252;
+
+const HDR_LEN: usize = 44;
+const TYPE_STREAM: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_RESPONSE: u16 = 2;
+const OP_RST: u16 = 3;
+const OP_SHUTDOWN: u16 = 4;
+const OP_RW: u16 = 5;
+const OP_CREDIT_UPDATE: u16 = 6;
+const OP_CREDIT_REQUEST: u16 = 7;
+
+/// Stream bytes buffered on the host side (our advertised `buf_alloc`)
+const RX_BUF: usize = 2048;
+/// Host packets waiting for guest receive buffers
+const OUTQ: usize = 8;
+const INBOX: usize = 4;
+
+pub const MSG_HDR: usize = 8;
+pub const MAX_MSG: usize = 256;
+pub const MSG_PING: u8 = 1;
+pub const MSG_PONG: u8 = 2;
+pub const MSG_EXEC: u8 = 3;
+pub const MSG_OUTPUT: u8 = 4;
+pub const MSG_EXIT: u8 = 5;
+pub const MSG_ERROR: u8 = 6;
+pub const MSG_EVENT: u8 = 7;
+/// Signal the process of an earlier `EXEC`: payload is that exec's tag
+/// (u16) and the signal number (u8). Its `EXIT` answers on the exec's tag.
+pub const MSG_KILL: u8 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnState { Closed, Connecting, Established }
+
+impl ConnState {
+    pub fn name(self) -> &'static str {
+        match self { ConnState::Closed => "closed", ConnState::Connecting => "connecting", ConnState::Established => "established" }
+    }
+}
+
+/// One framed agent message.
+#[derive(Clone, Copy, Debug)]
+pub struct Msg {
+    pub kind: u8,
+    pub tag: u16,
+    pub len: usize,
+    pub data: [u8; MAX_MSG],
+}
+
+impl Msg {
+    pub fn payload(&self) -> &[u8] { &self.data[..self.len] }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Packets from the guest
+    pub tx_pkts: u64,
+    /// Packets delivered to the guest
+    pub rx_pkts: u64,
+    pub msgs_in: u64,
+    pub msgs_out: u64,
+    pub resets: u64,
+    /// Packets or messages dropped (no buffer, too large, inbox full)
+    pub drops: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Pkt { hdr: [u8; HDR_LEN], data: [u8; MSG_HDR + MAX_MSG], len: usize }
+
+#[derive(Clone, Copy)]
+struct Conn {
+    state: ConnState,
+    /// Guest-side port of the connection
+    peer_port: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// Stream bytes sent to the guest
+    tx_cnt: u32,
+    /// Stream bytes consumed from the guest
+    fwd_cnt: u32,
+    /// `fwd_cnt` last advertised to the guest
+    fwd_sent: u32,
+    rx: [u8; RX_BUF],
+    rx_len: usize,
+}
+
+impl Conn {
+    const fn closed() -> Self {
+        Conn { state: ConnState::Closed, peer_port: 0, peer_buf_alloc: 0, peer_fwd_cnt: 0, tx_cnt: 0, fwd_cnt: 0, fwd_sent: 0, rx: [0; RX_BUF], rx_len: 0 }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Vsock {
+    t: Transport,
+    cid: u64,
+    conn: Conn,
+    outq: [Option<Pkt>; OUTQ],
+    inbox: [Option<Msg>; INBOX],
+    next_tag: u16,
+    stats: Stats,
+}
+
+static VSOCKS: SpinLock<[Option<Vsock>; MAX_VSOCKS]> = SpinLock::new([None; MAX_VSOCKS]);
+
+// ---- Packets ----
+
+fn le32(b: &[u8], o: usize) -> u32 { u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]]) }
+fn le64(b: &[u8], o: usize) -> u64 { (le32(b, o) as u64) | (le32(b, o + 4) as u64) << 32 }
+
+impl Vsock {
+    fn dropped(&mut self) {
+        self.stats.drops += 1;
+        Counter::new(&VSOCK_DROPS).inc();
+    }
+
+    /// Queue a packet from the host end to guest port `peer_port`.
+    fn queue(&mut self, op: u16, peer_port: u32, data: &[u8]) -> bool {
+        let slot = match self.outq.iter().position(|p| p.is_none()) { Some(s) => s, None => { self.dropped(); return false; } };
+        let mut p = Pkt { hdr: [0; HDR_LEN], data: [0; MSG_HDR + MAX_MSG], len: data.len().min(MSG_HDR + MAX_MSG) };
+        p.hdr[0..8].copy_from_slice(&HOST_CID.to_le_bytes());
+        p.hdr[8..16].copy_from_slice(&self.cid.to_le_bytes());
+        p.hdr[16..20].copy_from_slice(&AGENT_PORT.to_le_bytes());
+        p.hdr[20..24].copy_from_slice(&peer_port.to_le_bytes());
+        p.hdr[24..28].copy_from_slice(&(p.len as u32).to_le_bytes());
+        p.hdr[28..30].copy_from_slice(&TYPE_STREAM.to_le_bytes());
+        p.hdr[30..32].copy_from_slice(&op.to_le_bytes());
+        p.hdr[36..40].copy_from_slice(&(RX_BUF as u32).to_le_bytes());
+        p.hdr[40..44].copy_from_slice(&self.conn.fwd_cnt.to_le_bytes());
+        p.data[..p.len].copy_from_slice(&data[..p.len]);
+        if op == OP_RW { self.conn.tx_cnt = self.conn.tx_cnt.wrapping_add(p.len as u32); }
+        self.conn.fwd_sent = self.conn.fwd_cnt;
+        self.outq[slot] = Some(p);
+        true
+    }
+
+    /// Answer `peer_port` with a reset.
+    fn reset_peer(&mut self, peer_port: u32) { let _ = self.queue(OP_RST, peer_port, &[]); }
+
+    fn close(&mut self) {
+        if self.conn.state != ConnState::Closed { self.stats.resets += 1; }
+        self.conn = Conn::closed();
+    }
+
+    /// Frame and queue one message. Fails without credit or queue space.
+    fn send_msg(&mut self, kind: u8, tag: u16, payload: &[u8]) -> Result<(), &'static str> {
+        if self.conn.state != ConnState::Established { return Err("vsock: agent not connected"); }
+        if payload.len() > MAX_MSG { return Err("vsock: message too large"); }
+        let total = (MSG_HDR + payload.len()) as u32;
+        let in_flight = self.conn.tx_cnt.wrapping_sub(self.conn.peer_fwd_cnt);
+        if self.conn.peer_buf_alloc.saturating_sub(in_flight) < total { return Err("vsock: no credit from guest"); }
+        let mut m = [0u8; MSG_HDR + MAX_MSG];
+        m[0..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        m[4] = kind;
+        m[6..8].copy_from_slice(&tag.to_le_bytes());
+        m[MSG_HDR..MSG_HDR + payload.len()].copy_from_slice(payload);
+        let port = self.conn.peer_port;
+        if !self.queue(OP_RW, port, &m[..total as usize]) { return Err("vsock: transmit queue full"); }
+        self.stats.msgs_out += 1;
+        Ok(())
+    }
+
+    /// Consume complete messages from the stream buffer.
+    fn parse(&mut self) {
+        loop {
+            if self.conn.rx_len < MSG_HDR { break; }
+            let len = le32(&self.conn.rx, 0) as usize;
+            if len > MAX_MSG {
+                // The framing is lost; only a new connection can recover it.
+                self.dropped();
+                let port = self.conn.peer_port;
+                self.close();
+                self.reset_peer(port);
+                return;
+            }
+            if self.conn.rx_len < MSG_HDR + len { break; }
+            let mut m = Msg { kind: self.conn.rx[4], tag: u16::from_le_bytes([self.conn.rx[6], self.conn.rx[7]]), len, data: [0; MAX_MSG] };
+            m.data[..len].copy_from_slice(&self.conn.rx[MSG_HDR..MSG_HDR + len]);
+            self.conn.rx.copy_within(MSG_HDR + len..self.conn.rx_len, 0);
+            self.conn.rx_len -= MSG_HDR + len;
+            self.conn.fwd_cnt = self.conn.fwd_cnt.wrapping_add((MSG_HDR + len) as u32);
+            self.stats.msgs_in += 1;
+            if m.kind == MSG_PING {
+                crate::hv::heartbeat::beat(self.t.vm_id);
+                let _ = self.send_msg(MSG_PONG, m.tag, m.payload());
+                continue;
+            }
+            match self.inbox.iter().position(|x| x.is_none()) {
+                Some(s) => self.inbox[s] = Some(m),
+                None => {
+                    // Keep the newest: a full inbox means nobody is waiting for the oldest.
+                    self.inbox.copy_within(1.., 0);
+                    self.inbox[INBOX - 1] = Some(m);
+                    self.dropped();
+                }
+            }
+        }
+        // Let the guest keep streaming once half the window has been consumed.
+        if self.conn.state == ConnState::Established && self.conn.fwd_cnt.wrapping_sub(self.conn.fwd_sent) >= (RX_BUF / 2) as u32 {
+            let port = self.conn.peer_port;
+            let _ = self.queue(OP_CREDIT_UPDATE, port, &[]);
+        }
+    }
+
+    /// Handle one packet sent by the guest.
+    fn on_packet(&mut self, hdr: &[u8], data: &[u8]) {
+        let (src_cid, dst_cid) = (le64(hdr, 0), le64(hdr, 8));
+        let (src_port, dst_port) = (le32(hdr, 16), le32(hdr, 20));
+        let op = u16::from_le_bytes([hdr[30], hdr[31]]);
+        let ty = u16::from_le_bytes([hdr[28], hdr[29]]);
+        if src_cid != self.cid || dst_cid != HOST_CID { self.dropped(); return; }
+        if op == OP_RST { if self.conn.peer_port == src_port { self.close(); } return; }
+        if ty != TYPE_STREAM || dst_port != AGENT_PORT { self.reset_peer(src_port); return; }
+        if op == OP_REQUEST {
+            if self.conn.state == ConnState::Established { self.reset_peer(src_port); return; }
+            // A connect of our own still pending loses to the agent's.
+            self.conn = Conn::closed();
+            self.conn.peer_port = src_port;
+        } else if self.conn.state == ConnState::Closed || self.conn.peer_port != src_port {
+            self.reset_peer(src_port);
+            return;
+        }
+        self.conn.peer_buf_alloc = le32(hdr, 36);
+        self.conn.peer_fwd_cnt = le32(hdr, 40);
+        match op {
+            OP_REQUEST => { self.conn.state = ConnState::Established; let _ = self.queue(OP_RESPONSE, src_port, &[]); }
+            OP_RESPONSE => {
+                if self.conn.state == ConnState::Connecting { self.conn.state = ConnState::Established; } else { self.close(); self.reset_peer(src_port); }
+            }
+            OP_SHUTDOWN => { self.close(); self.reset_peer(src_port); }
+            OP_RW => {
+                let take = data.len().min(RX_BUF - self.conn.rx_len);
+                if take < data.len() { self.dropped(); }
+                self.conn.rx[self.conn.rx_len..self.conn.rx_len + take].copy_from_slice(&data[..take]);
+                self.conn.rx_len += take;
+                self.parse();
+            }
+            OP_CREDIT_REQUEST => { let _ = self.queue(OP_CREDIT_UPDATE, src_port, &[]); }
+            _ => {}
+        }
+    }
+}
+
+// ---- Guest-facing side (runs in the exit path) ----
+
+fn device_cfg_read(cid: u64, off: u64, size: u8) -> u64 {
+    let cfg = cid.to_le_bytes();
+    let mut v = 0u64;
+    for i in 0..size as usize {
+        let o = off as usize + i;
+        if o < cfg.len() { v |= (cfg[o] as u64) << (i * 8); }
+    }
+    v
+}
+
+fn process_tx(v: &mut Vsock) {
+    let vm = v.t.vm_id;
+    let mut buf = [0u8; HDR_LEN + RX_BUF];
+    let mut done = 0u32;
+    while let Some(chain) = v.t.queues[TXQ as usize].pop(vm) {
+        let n = chain.read(vm, &mut buf);
+        let _ = v.t.queues[TXQ as usize].push_used(vm, chain.head, 0);
+        done += 1;
+        v.stats.tx_pkts += 1;
+        Counter::new(&VSOCK_TX_PKTS).inc();
+        if n < HDR_LEN { v.dropped(); continue; }
+        let len = (le32(&buf, 24) as usize).min(n - HDR_LEN);
+        let (hdr, data) = buf[..HDR_LEN + len].split_at(HDR_LEN);
+        v.on_packet(hdr, data);
+    }
+    if done != 0 { v.t.interrupt(); }
+    deliver_rx(v);
+}
+
+/// Move queued host packets into guest receive buffers, oldest first.
+fn deliver_rx(v: &mut Vsock) {
+    if !v.t.driver_ok() { return; }
+    let vm = v.t.vm_id;
+    let mut delivered = 0u32;
+    while let Some(p) = v.outq[0] {
+        let chain = match v.t.queues[RXQ as usize].pop(vm) { Some(ch) => ch, None => break };
+        let mut buf = [0u8; HDR_LEN + MSG_HDR + MAX_MSG];
+        buf[..HDR_LEN].copy_from_slice(&p.hdr);
+        buf[HDR_LEN..HDR_LEN + p.len].copy_from_slice(&p.data[..p.len]);
+        let total = HDR_LEN + p.len;
+        let wrote = if chain.writable_len() >= total { chain.write(vm, &buf[..total]) } else { 0 };
+        if wrote < total { v.dropped(); } else { v.stats.rx_pkts += 1; Counter::new(&VSOCK_RX_PKTS).inc(); }
+        let _ = v.t.queues[RXQ as usize].push_used(vm, chain.head, wrote as u32);
+        v.outq.copy_within(1.., 0);
+        v.outq[OUTQ - 1] = None;
+        delivered += 1;
+    }
+    if delivered != 0 { v.t.interrupt(); }
+}
+
+fn mmio(_vm_id: u64, _vcpu: u32, ctx: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    VSOCKS.lock(|t| {
+        let v = match t.get_mut(ctx as usize).and_then(|v| v.as_mut()) { Some(v) => v, None => return 0 };
+        if (super::DEVICE_OFF..super::NOTIFY_OFF).contains(&off) {
+            return match write { None => device_cfg_read(v.cid, off - super::DEVICE_OFF, size), Some(_) => 0 };
+        }
+        let (val, kick) = v.t.mmio(off, size, write);
+        match kick {
+            Kick::Queue(TXQ) => process_tx(v),
+            Kick::Queue(RXQ) | Kick::Ready => deliver_rx(v),
+            Kick::Reset => { v.close(); v.outq = [None; OUTQ]; }
+            _ => {}
+        }
+        val
+    })
+}
+
+fn on_bar(vm_id: u64, ctx: u64, _bar: usize, old: u64, new: u64) {
+    if old != 0 { let _ = crate::hv::bus::unregister_mmio(vm_id, old); }
+    if new != 0 { let _ = crate::hv::bus::register_mmio(vm_id, new, super::BAR_SIZE, "virtio-vsock", ctx, mmio); }
+    let _ = VSOCKS.lock(|t| t.get_mut(ctx as usize).and_then(|v| v.as_mut()).map(|v| v.t.bar = new));
+}
+
+// ---- Management ----
+
+/// Plug a vsock device into `vm_id`'s PCI bus (one per VM). The guest CID
+/// defaults to `3 + vm_id`. Returns (device index, PCI device number, CID).
+pub fn add(vm_id: u64, cid: Option<u64>) -> Result<(usize, u8, u64), &'static str> {
+    let cid = cid.unwrap_or(3 + vm_id);
+    if cid <= HOST_CID || cid >= 0xFFFF_FFFF { return Err("vsock: invalid guest CID"); }
+    let idx = VSOCKS.lock(|t| {
+        if t.iter().flatten().any(|v| v.t.vm_id == vm_id) { return Err("vsock: VM already has a vsock device"); }
+        if t.iter().flatten().any(|v| v.cid == cid) { return Err("vsock: CID in use"); }
+        let i = t.iter().position(|v| v.is_none()).ok_or("vsock: too many devices")?;
+        t[i] = Some(Vsock {
+            t: Transport::new(vm_id, 3, QUEUE_MAX, F_STREAM),
+            cid, conn: Conn::closed(), outq: [None; OUTQ], inbox: [None; INBOX], next_tag: 1, stats: Stats::default(),
+        });
+        Ok(i)
+    })?;
+    let cfg = super::pci_config(VIRTIO_ID_VSOCK, 0x07, 0x80, 8, DEFAULT_IRQ);
+    match crate::hv::vpci::add(vm_id, cfg, idx as u64, on_bar) {
+        Some(dev) => {
+            VSOCKS.lock(|t| if let Some(v) = t[idx].as_mut() { v.t.dev = dev; });
+            Ok((idx, dev, cid))
+        }
+        None => {
+            VSOCKS.lock(|t| t[idx] = None);
+            Err("vsock: no free PCI slot")
+        }
+    }
+}
+
+/// Remove the vsock device of `vm_id` (the PCI function is torn down with the bus).
+pub fn detach_vm(vm_id: u64) {
+    VSOCKS.lock(|t| { for v in t.iter_mut() { if matches!(v, Some(x) if x.t.vm_id == vm_id) { *v = None; } } });
+}
+
+/// Device index of `vm_id`.
+pub fn find(vm_id: u64) -> Option<usize> {
+    VSOCKS.lock(|t| t.iter().position(|v| matches!(v, Some(v) if v.t.vm_id == vm_id)))
+}
+
+pub fn state(idx: usize) -> ConnState {
+    VSOCKS.lock(|t| t.get(idx).and_then(|v| v.as_ref()).map(|v| v.conn.state).unwrap_or(ConnState::Closed))
+}
+
+/// Connect to the guest agent unless a connection exists or is pending.
+pub fn connect(idx: usize) -> Result<ConnState, &'static str> {
+    VSOCKS.lock(|t| {
+        let v = t.get_mut(idx).and_then(|v| v.as_mut()).ok_or("vsock: no such device")?;
+        if !v.t.driver_ok() { return Err("vsock: guest driver not ready"); }
+        if v.conn.state == ConnState::Closed {
+            v.conn = Conn::closed();
+            v.conn.state = ConnState::Connecting;
+            v.conn.peer_port = AGENT_PORT;
+            let _ = v.queue(OP_REQUEST, AGENT_PORT, &[]);
+            deliver_rx(v);
+        }
+        Ok(v.conn.state)
+    })
+}
+
+/// Close the agent connection.
+pub fn disconnect(idx: usize) {
+    VSOCKS.lock(|t| if let Some(v) = t.get_mut(idx).and_then(|v| v.as_mut()) {
+        if v.conn.state != ConnState::Closed {
+            let port = v.conn.peer_port;
+            v.close();
+            v.reset_peer(port);
+            deliver_rx(v);
+        }
+    });
+}
+
+/// Send one message to the agent. Returns the tag its replies will carry.
+pub fn send(idx: usize, kind: u8, payload: &[u8]) -> Result<u16, &'static str> {
+    VSOCKS.lock(|t| {
+        let v = t.get_mut(idx).and_then(|v| v.as_mut()).ok_or("vsock: no such device")?;
+        let tag = v.next_tag;
+        v.send_msg(kind, tag, payload)?;
+        v.next_tag = v.next_tag.wrapping_add(1).max(1);
+        deliver_rx(v);
+        Ok(tag)
+    })
+}
+
+/// Take the oldest inbox message carrying `tag`.
+pub fn take_reply(idx: usize, tag: u16) -> Option<Msg> {
+    VSOCKS.lock(|t| {
+        let v = t.get_mut(idx).and_then(|v| v.as_mut())?;
+        let i = v.inbox.iter().position(|m| matches!(m, Some(m) if m.tag == tag))?;
+        let m = v.inbox[i];
+        v.inbox.copy_within(i + 1.., i);
+        v.inbox[INBOX - 1] = None;
+        m
+    })
+}
+
+/// Wait up to `timeout_ms` for the agent connection, connecting if needed.
+pub fn wait_connected(system_table: &mut SystemTable<Boot>, idx: usize, timeout_ms: u32) -> Result<(), &'static str> {
+    for _ in 0..=timeout_ms {
+        match connect(idx)? {
+            ConnState::Established => return Ok(()),
+            _ => { let _ = system_table.boot_services().stall(1000); }
+        }
+    }
+    Err("vsock: agent did not accept the connection")
+}
+
+/// Round trip of one `PING`, in microseconds (0 while the clock cannot run).
+pub fn ping(system_table: &mut SystemTable<Boot>, idx: usize, timeout_ms: u32) -> Result<u64, &'static str> {
+    wait_connected(system_table, idx, timeout_ms)?;
+    let t0 = crate::time::clock::now_ns();
+    let tag = send(idx, MSG_PING, &t0.to_le_bytes())?;
+    for _ in 0..=timeout_ms {
+        if let Some(m) = take_reply(idx, tag) {
+            if m.kind != MSG_PONG { return Err("vsock: unexpected reply to ping"); }
+            return Ok(crate::time::clock::elapsed_ns(t0) / 1_000);
+        }
+        let _ = system_table.boot_services().stall(1000);
+    }
+    Err("vsock: ping timed out")
+}
+
+/// Run `command` through the guest agent. Output chunks are passed to
+/// `output` as they arrive; returns the exit status.
+pub fn exec(system_table: &mut SystemTable<Boot>, idx: usize, command: &str, timeout_ms: u32, mut output: impl FnMut(&mut SystemTable<Boot>, &[u8])) -> Result<i32, &'static str> {
+    wait_connected(system_table, idx, timeout_ms)?;
+    let tag = send(idx, MSG_EXEC, command.as_bytes())?;
+    let mut idle = 0u32;
+    while idle <= timeout_ms {
+        match take_reply(idx, tag) {
+            Some(m) if m.kind == MSG_OUTPUT => { output(system_table, m.payload()); idle = 0; }
+            Some(m) if m.kind == MSG_EXIT => {
+                let p = m.payload();
+                return Ok(if p.len() >= 4 { i32::from_le_bytes([p[0], p[1], p[2], p[3]]) } else { 0 });
+            }
+            Some(m) if m.kind == MSG_ERROR => { output(system_table, m.payload()); return Err("vsock: agent reported an error"); }
+            Some(_) => return Err("vsock: unexpected reply to exec"),
+            None => {
+                if state(idx) == ConnState::Closed { return Err("vsock: agent closed the connection"); }
+                let _ = system_table.boot_services().stall(1000);
+                idle += 1;
+            }
+        }
+    }
+    Err("vsock: exec timed out")
+}
+
+/// Log agent events waiting in the inboxes. Returns how many were logged.
+pub fn pump(system_table: &mut SystemTable<Boot>) -> usize {
+    let mut logged = 0usize;
+    for idx in 0..MAX_VSOCKS {
+        while let Some((vm_id, m)) = VSOCKS.lock(|t| {
+            let v = t[idx].as_mut()?;
+            let i = v.inbox.iter().position(|m| matches!(m, Some(m) if m.kind == MSG_EVENT))?;
+            let m = v.inbox[i]?;
+            v.inbox.copy_within(i + 1.., i);
+            v.inbox[INBOX - 1] = None;
+            Some((v.t.vm_id, m))
+        }) {
+            let mut msg = [0u8; 32 + MAX_MSG]; let mut n = 0;
+            for &b in b"vm " { msg[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(vm_id, &mut msg[n..]);
+            for &b in b": " { msg[n] = b; n += 1; }
+            for &b in m.payload().iter().filter(|b| (0x20..0x7F).contains(*b)) { msg[n] = b; n += 1; }
+            crate::obs::log::info(system_table, "agent", core::str::from_utf8(&msg[..n]).unwrap_or("event"));
+            logged += 1;
+        }
+    }
+    logged
+}
+
+/// Device state for reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct VsockInfo {
+    pub vm_id: u64,
+    pub dev: u8,
+    pub cid: u64,
+    pub driver_ok: bool,
+    pub state: ConnState,
+    pub peer_port: u32,
+    pub stats: Stats,
+}
+
+/// Iterate devices as (index, info).
+pub fn for_each(mut f: impl FnMut(usize, &VsockInfo)) {
+    let mut snap: [Option<VsockInfo>; MAX_VSOCKS] = [None; MAX_VSOCKS];
+    VSOCKS.lock(|t| {
+        for (i, v) in t.iter().enumerate() {
+            snap[i] = v.as_ref().map(|v| VsockInfo {
+                vm_id: v.t.vm_id, dev: v.t.dev, cid: v.cid, driver_ok: v.t.driver_ok(), state: v.conn.state,
+                peer_port: v.conn.peer_port, stats: v.stats,
+            });
+        }
+    });
+    for (i, v) in snap.iter().enumerate() { if let Some(v) = v { f(i, v); } }
+}
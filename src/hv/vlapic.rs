@@ -0,0 +1,434 @@
+#![allow(dead_code)]
+
+//! Virtual local APIC.
+//!
+//! One `VLapic` per vCPU models the xAPIC register page (and the x2APIC MSR
+//! view of it): ID/LDR/DFR, TPR/PPR, SVR, the IRR/ISR/TMR vectors, the LVT
+//! entries and the APIC timer in one-shot, periodic and TSC-deadline modes.
+//! The timer is clocked from the host TSC at a fixed virtual bus rate.
+//! Injection into the guest is done by `hv::event`.
+
+use crate::util::spinlock::SpinLock;
+
+/// Default guest-physical base of the xAPIC page.
+pub const APIC_DEFAULT_BASE: u64 = 0xFEE0_0000;
+/// x2APIC MSR range.
+pub const X2APIC_MSR_BASE: u32 = 0x800;
+pub const X2APIC_MSR_END: u32 = 0x8FF;
+/// Virtual APIC bus frequency used for the LAPIC timer (100 MHz).
+pub const TIMER_BUS_HZ: u64 = 100_000_000;
+/// Capacity of the per-vCPU table.
+pub const MAX_VLAPICS: usize = 64;
+
+// Register offsets
+pub const REG_ID: u32 = 0x020;
+pub const REG_VERSION: u32 = 0x030;
+pub const REG_TPR: u32 = 0x080;
+pub const REG_APR: u32 = 0x090;
+pub const REG_PPR: u32 = 0x0A0;
+pub const REG_EOI: u32 = 0x0B0;
+pub const REG_LDR: u32 = 0x0D0;
+pub const REG_DFR: u32 = 0x0E0;
+pub const REG_SVR: u32 = 0x0F0;
+pub const REG_ISR: u32 = 0x100;
+pub const REG_TMR: u32 = 0x180;
+pub const REG_IRR: u32 = 0x200;
+pub const REG_ESR: u32 = 0x280;
+pub const REG_ICR_LO: u32 = 0x300;
+pub const REG_ICR_HI: u32 = 0x310;
+pub const REG_LVT_TIMER: u32 = 0x320;
+pub const REG_LVT_THERMAL: u32 = 0x330;
+pub const REG_LVT_PERF: u32 = 0x340;
+pub const REG_LVT_LINT0: u32 = 0x350;
+pub const REG_LVT_LINT1: u32 = 0x360;
+pub const REG_LVT_ERROR: u32 = 0x370;
+pub const REG_TIMER_ICR: u32 = 0x380;
+pub const REG_TIMER_CCR: u32 = 0x390;
+pub const REG_TIMER_DCR: u32 = 0x3E0;
+pub const REG_SELF_IPI: u32 = 0x3F0;
+
+/// Version 0x14, 6 LVT entries (max LVT index 5).
+const VERSION_VALUE: u32 = 0x0005_0014;
+const LVT_MASKED: u32 = 1 << 16;
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// LVT timer mode (bits 18:17).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode { OneShot, Periodic, TscDeadline }
+
+/// IPI produced by an ICR write, routed by the caller to the target vCPUs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ipi {
+    pub vector: u8,
+    /// Delivery mode (bits 10:8): 0 fixed, 4 NMI, 5 INIT, 6 SIPI
+    pub mode: u8,
+    /// Destination shorthand (bits 19:18): 0 none, 1 self, 2 all, 3 all-but-self
+    pub shorthand: u8,
+    pub logical: bool,
+    pub dest: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VLapic {
+    pub id: u32,
+    pub apic_base: u64,
+    pub x2apic: bool,
+    pub tpr: u32,
+    pub ldr: u32,
+    pub dfr: u32,
+    pub svr: u32,
+    pub isr: [u32; 8],
+    pub tmr: [u32; 8],
+    pub irr: [u32; 8],
+    pub esr: u32,
+    pub icr_lo: u32,
+    pub icr_hi: u32,
+    /// LVT timer, thermal, perf, LINT0, LINT1, error
+    pub lvt: [u32; 6],
+    pub timer_icr: u32,
+    pub timer_dcr: u32,
+    /// Host TSC at which the timer was last armed
+    timer_start_tsc: u64,
+    /// Host TSC at which the timer next fires (0 = disarmed)
+    timer_expiry_tsc: u64,
+    /// Guest IA32_TSC_DEADLINE (guest TSC domain)
+    pub tsc_deadline: u64,
+    /// Guest TSC = host TSC + tsc_offset
+    pub tsc_offset: u64,
+    /// Pending INIT / SIPI vector recorded for the owning vCPU
+    pub init_pending: bool,
+    pub sipi_vector: Option<u8>,
+    pub nmi_pending: bool,
+}
+
+impl VLapic {
+    pub fn new(id: u32) -> Self {
+        VLapic {
+            id,
+            apic_base: APIC_DEFAULT_BASE | (1 << 11) | if id == 0 { 1 << 8 } else { 0 },
+            x2apic: false,
+            tpr: 0,
+            ldr: 0,
+            dfr: 0xFFFF_FFFF,
+            svr: 0xFF,
+            isr: [0; 8],
+            tmr: [0; 8],
+            irr: [0; 8],
+            esr: 0,
+            icr_lo: 0,
+            icr_hi: 0,
+            lvt: [LVT_MASKED; 6],
+            timer_icr: 0,
+            timer_dcr: 0,
+            timer_start_tsc: 0,
+            timer_expiry_tsc: 0,
+            tsc_deadline: 0,
+            tsc_offset: 0,
+            init_pending: false,
+            sipi_vector: None,
+            nmi_pending: false,
+        }
+    }
+
+    /// Reset to the power-on state while keeping the APIC ID and base.
+    pub fn reset(&mut self) {
+        let (id, base, x2) = (self.id, self.apic_base, self.x2apic);
+        *self = VLapic::new(id);
+        self.apic_base = base;
+        self.x2apic = x2;
+    }
+
+    #[inline(always)]
+    pub fn enabled(&self) -> bool { (self.apic_base & (1 << 11)) != 0 && (self.svr & SVR_ENABLE) != 0 }
+
+    #[inline(always)]
+    fn highest(v: &[u32; 8]) -> Option<u8> {
+        for i in (0..8).rev() {
+            if v[i] != 0 { return Some((i as u32 * 32 + (31 - v[i].leading_zeros())) as u8); }
+        }
+        None
+    }
+
+    #[inline(always)]
+    fn set_bit(v: &mut [u32; 8], vec: u8) { v[(vec >> 5) as usize] |= 1 << (vec & 31); }
+    #[inline(always)]
+    fn clear_bit(v: &mut [u32; 8], vec: u8) { v[(vec >> 5) as usize] &= !(1 << (vec & 31)); }
+    #[inline(always)]
+    fn test_bit(v: &[u32; 8], vec: u8) -> bool { (v[(vec >> 5) as usize] & (1 << (vec & 31))) != 0 }
+
+    pub fn highest_irr(&self) -> Option<u8> { Self::highest(&self.irr) }
+    pub fn highest_isr(&self) -> Option<u8> { Self::highest(&self.isr) }
+
+    /// Processor priority: the higher of TPR and the in-service class.
+    pub fn ppr(&self) -> u32 {
+        let isrv = self.highest_isr().map(|v| v as u32).unwrap_or(0);
+        if (self.tpr & 0xF0) >= (isrv & 0xF0) { self.tpr & 0xFF } else { isrv & 0xF0 }
+    }
+
+    /// Latch a fixed interrupt into IRR. Vectors below 16 are illegal and set ESR.
+    pub fn accept(&mut self, vector: u8, level: bool) -> bool {
+        if vector < 16 { self.esr |= 1 << 6; return false; }
+        if !self.enabled() { return false; }
+        Self::set_bit(&mut self.irr, vector);
+        if level { Self::set_bit(&mut self.tmr, vector) } else { Self::clear_bit(&mut self.tmr, vector) }
+        true
+    }
+
+    /// Highest IRR vector whose priority class exceeds PPR.
+    pub fn deliverable(&self) -> Option<u8> {
+        if !self.enabled() { return None; }
+        let v = self.highest_irr()?;
+        if (v as u32 & 0xF0) > (self.ppr() & 0xF0) { Some(v) } else { None }
+    }
+
+    /// Interrupt acknowledge: move the deliverable vector from IRR to ISR.
+    pub fn ack(&mut self) -> Option<u8> {
+        let v = self.deliverable()?;
+        Self::clear_bit(&mut self.irr, v);
+        Self::set_bit(&mut self.isr, v);
+        Some(v)
+    }
+
+    /// Guest EOI: retire the highest in-service vector. Returns it and whether it was level-triggered.
+    pub fn eoi(&mut self) -> Option<(u8, bool)> {
+        let v = self.highest_isr()?;
+        Self::clear_bit(&mut self.isr, v);
+        let level = Self::test_bit(&self.tmr, v);
+        if level { Self::clear_bit(&mut self.tmr, v); }
+        Some((v, level))
+    }
+
+    pub fn timer_mode(&self) -> TimerMode {
+        match (self.lvt[0] >> 17) & 3 { 1 => TimerMode::Periodic, 2 => TimerMode::TscDeadline, _ => TimerMode::OneShot }
+    }
+
+    fn timer_divisor(&self) -> u64 {
+        let idx = (self.timer_dcr & 3) | ((self.timer_dcr >> 1) & 4);
+        if idx == 7 { 1 } else { 2u64 << idx }
+    }
+
+    /// Host TSC ticks per timer count at the current divide configuration.
+    fn tsc_per_count(&self) -> u64 {
+        let hz = crate::time::tsc_hz();
+        if hz == 0 { return 0; }
+        ((hz as u128) * (self.timer_divisor() as u128) / (TIMER_BUS_HZ as u128)).max(1) as u64
+    }
+
+    fn arm_count(&mut self, now_tsc: u64) {
+        let per = self.tsc_per_count();
+        if self.timer_icr == 0 || per == 0 { self.timer_expiry_tsc = 0; return; }
+        self.timer_start_tsc = now_tsc;
+        self.timer_expiry_tsc = now_tsc.wrapping_add(per.saturating_mul(self.timer_icr as u64));
+    }
+
+    fn timer_ccr(&self, now_tsc: u64) -> u32 {
+        if self.timer_expiry_tsc == 0 || self.timer_mode() == TimerMode::TscDeadline { return 0; }
+        let per = self.tsc_per_count();
+        if per == 0 || now_tsc >= self.timer_expiry_tsc { return 0; }
+        ((self.timer_expiry_tsc - now_tsc) / per) as u32
+    }
+
+    /// Program IA32_TSC_DEADLINE (guest TSC value); zero disarms.
+    pub fn write_tsc_deadline(&mut self, deadline: u64) {
+        self.tsc_deadline = deadline;
+        if self.timer_mode() != TimerMode::TscDeadline { return; }
+        self.timer_expiry_tsc = if deadline == 0 { 0 } else { deadline.wrapping_sub(self.tsc_offset).max(1) };
+    }
+
+    /// Advance the timer to `now_tsc`; latches the timer vector on expiry.
+    /// Returns true if an interrupt was latched.
+    pub fn tick(&mut self, now_tsc: u64) -> bool {
+        if self.timer_expiry_tsc == 0 || now_tsc < self.timer_expiry_tsc { return false; }
+        match self.timer_mode() {
+            TimerMode::Periodic => {
+                let per = self.tsc_per_count().saturating_mul(self.timer_icr as u64);
+                if per == 0 { self.timer_expiry_tsc = 0; } else {
+                    // Skip missed periods instead of bursting them into the guest.
+                    while self.timer_expiry_tsc <= now_tsc { self.timer_expiry_tsc = self.timer_expiry_tsc.wrapping_add(per); }
+                }
+            }
+            TimerMode::OneShot => self.timer_expiry_tsc = 0,
+            TimerMode::TscDeadline => { self.timer_expiry_tsc = 0; self.tsc_deadline = 0; }
+        }
+        let lvt = self.lvt[0];
+        if (lvt & LVT_MASKED) != 0 { return false; }
+        self.accept((lvt & 0xFF) as u8, false)
+    }
+
+    /// Next host TSC at which `tick` has work to do (0 = none).
+    pub fn next_timer_tsc(&self) -> u64 { self.timer_expiry_tsc }
+
+    /// Register read (xAPIC offset).
+    pub fn read(&self, offset: u32, now_tsc: u64) -> u32 {
+        match offset {
+            REG_ID => if self.x2apic { self.id } else { self.id << 24 },
+            REG_VERSION => VERSION_VALUE,
+            REG_TPR => self.tpr,
+            REG_APR => 0,
+            REG_PPR => self.ppr(),
+            REG_LDR => if self.x2apic { ((self.id >> 4) << 16) | (1 << (self.id & 0xF)) } else { self.ldr },
+            REG_DFR => self.dfr,
+            REG_SVR => self.svr,
+            0x100..=0x170 => self.isr[((offset - REG_ISR) >> 4) as usize],
+            0x180..=0x1F0 => self.tmr[((offset - REG_TMR) >> 4) as usize],
+            0x200..=0x270 => self.irr[((offset - REG_IRR) >> 4) as usize],
+            REG_ESR => self.esr,
+            REG_ICR_LO => self.icr_lo & !(1 << 12),
+            REG_ICR_HI => self.icr_hi,
+            REG_LVT_TIMER..=REG_LVT_ERROR => self.lvt[((offset - REG_LVT_TIMER) >> 4) as usize],
+            REG_TIMER_ICR => self.timer_icr,
+            REG_TIMER_CCR => self.timer_ccr(now_tsc),
+            REG_TIMER_DCR => self.timer_dcr,
+            _ => 0,
+        }
+    }
+
+    /// Register write (xAPIC offset). An ICR write returns the IPI to route.
+    pub fn write(&mut self, offset: u32, val: u32, now_tsc: u64) -> Option<Ipi> {
+        match offset {
+            REG_ID => { if !self.x2apic { self.id = val >> 24; } }
+            REG_TPR => self.tpr = val & 0xFF,
+            REG_EOI => { let _ = self.eoi(); }
+            REG_LDR => { if !self.x2apic { self.ldr = val & 0xFF00_0000; } }
+            REG_DFR => { if !self.x2apic { self.dfr = val | 0x0FFF_FFFF; } }
+            REG_SVR => {
+                self.svr = val & 0x1FFF;
+                // Software disable masks every LVT entry.
+                if (self.svr & SVR_ENABLE) == 0 { for l in self.lvt.iter_mut() { *l |= LVT_MASKED; } }
+            }
+            REG_ESR => self.esr = 0,
+            REG_ICR_HI => { if !self.x2apic { self.icr_hi = val & 0xFF00_0000; } }
+            REG_ICR_LO => {
+                self.icr_lo = val;
+                let dest = if self.x2apic { self.icr_hi } else { self.icr_hi >> 24 };
+                return Some(Ipi {
+                    vector: (val & 0xFF) as u8,
+                    mode: ((val >> 8) & 7) as u8,
+                    shorthand: ((val >> 18) & 3) as u8,
+                    logical: (val & (1 << 11)) != 0,
+                    dest,
+                });
+            }
+            REG_LVT_TIMER..=REG_LVT_ERROR => {
+                let idx = ((offset - REG_LVT_TIMER) >> 4) as usize;
+                let mut v = val;
+                if (self.svr & SVR_ENABLE) == 0 { v |= LVT_MASKED; }
+                let old_mode = self.timer_mode();
+                self.lvt[idx] = v;
+                if idx == 0 && old_mode != self.timer_mode() { self.timer_expiry_tsc = 0; self.tsc_deadline = 0; }
+            }
+            REG_TIMER_ICR => {
+                if self.timer_mode() == TimerMode::TscDeadline { return None; }
+                self.timer_icr = val;
+                self.arm_count(now_tsc);
+            }
+            REG_TIMER_DCR => self.timer_dcr = val & 0xB,
+            REG_SELF_IPI => { if self.x2apic { let _ = self.accept((val & 0xFF) as u8, false); } }
+            _ => {}
+        }
+        None
+    }
+
+    /// x2APIC MSR read (0x800..0x8FF). ICR is a single 64-bit register in x2APIC mode.
+    pub fn msr_read(&self, msr: u32, now_tsc: u64) -> u64 {
+        let off = (msr - X2APIC_MSR_BASE) << 4;
+        if off == REG_ICR_LO { return ((self.icr_hi as u64) << 32) | self.icr_lo as u64; }
+        self.read(off, now_tsc) as u64
+    }
+
+    /// x2APIC MSR write.
+    pub fn msr_write(&mut self, msr: u32, val: u64, now_tsc: u64) -> Option<Ipi> {
+        let off = (msr - X2APIC_MSR_BASE) << 4;
+        if off == REG_ICR_LO { self.icr_hi = (val >> 32) as u32; }
+        self.write(off, val as u32, now_tsc)
+    }
+
+    /// Guest write to IA32_APIC_BASE; toggles x2APIC mode (EXTD, bit 10).
+    pub fn write_apic_base(&mut self, val: u64) {
+        self.apic_base = val;
+        self.x2apic = (val & (1 << 11)) != 0 && (val & (1 << 10)) != 0;
+        if (val & (1 << 11)) == 0 { self.reset(); self.apic_base = val; }
+    }
+}
+
+// ---- Per-vCPU table ----
+
+#[derive(Clone, Copy)]
+struct Slot { vm_id: u64, vcpu_id: u32, lapic: VLapic }
+
+static VLAPICS: SpinLock<[Option<Slot>; MAX_VLAPICS]> = SpinLock::new([None; MAX_VLAPICS]);
+
+/// Create a virtual LAPIC for (vm, vcpu); the APIC ID equals the vCPU index.
+pub fn attach(vm_id: u64, vcpu_id: u32) -> bool {
+    VLAPICS.lock(|t| {
+        if t.iter().flatten().any(|s| s.vm_id == vm_id && s.vcpu_id == vcpu_id) { return true; }
+        match t.iter_mut().find(|s| s.is_none()) {
+            Some(slot) => { *slot = Some(Slot { vm_id, vcpu_id, lapic: VLapic::new(vcpu_id) }); true }
+            None => false,
+        }
+    })
+}
+
+/// Drop every LAPIC belonging to `vm_id`.
+pub fn detach_vm(vm_id: u64) {
+    VLAPICS.lock(|t| { for s in t.iter_mut() { if matches!(s, Some(x) if x.vm_id == vm_id) { *s = None; } } });
+}
+
+/// Run `f` on the LAPIC of (vm, vcpu).
+pub fn with<R>(vm_id: u64, vcpu_id: u32, f: impl FnOnce(&mut VLapic) -> R) -> Option<R> {
+    VLAPICS.lock(|t| t.iter_mut().flatten().find(|s| s.vm_id == vm_id && s.vcpu_id == vcpu_id).map(|s| f(&mut s.lapic)))
+}
+
+/// Snapshot of the LAPIC of (vm, vcpu).
+pub fn get(vm_id: u64, vcpu_id: u32) -> Option<VLapic> { with(vm_id, vcpu_id, |l| *l) }
+
+/// Iterate vCPU ids with a LAPIC in `vm_id`.
+pub fn for_each_vcpu(vm_id: u64, mut f: impl FnMut(u32)) {
+    let t = VLAPICS.lock(|t| *t);
+    for s in t.iter().flatten() { if s.vm_id == vm_id { f(s.vcpu_id); } }
+}
+
+/// Latch a fixed interrupt on one vCPU.
+pub fn raise(vm_id: u64, vcpu_id: u32, vector: u8, level: bool) -> bool {
+    with(vm_id, vcpu_id, |l| l.accept(vector, level)).unwrap_or(false)
+}
+
+fn ipi_targets(ipi: &Ipi, src: u32, id: u32, ldr: u32) -> bool {
+    match ipi.shorthand {
+        1 => id == src,
+        2 => true,
+        3 => id != src,
+        _ => {
+            if ipi.logical { (ldr >> 24) & ipi.dest != 0 } else { ipi.dest == 0xFF || ipi.dest == 0xFFFF_FFFF || ipi.dest == id }
+        }
+    }
+}
+
+/// Deliver an IPI from vCPU `src` to the matching vCPUs of the same VM.
+pub fn route_ipi(vm_id: u64, src: u32, ipi: Ipi) -> u32 {
+    VLAPICS.lock(|t| {
+        let mut hit = 0u32;
+        for s in t.iter_mut().flatten() {
+            if s.vm_id != vm_id || !ipi_targets(&ipi, src, s.lapic.id, s.lapic.ldr) { continue; }
+            match ipi.mode {
+                0 | 1 => { if s.lapic.accept(ipi.vector, false) { hit += 1; } }
+                4 => { s.lapic.nmi_pending = true; hit += 1; }
+                5 => { s.lapic.init_pending = true; hit += 1; }
+                6 => { s.lapic.sipi_vector = Some(ipi.vector); hit += 1; }
+                _ => {}
+            }
+        }
+        hit
+    })
+}
+
+/// Guest MMIO access to the APIC page. Writes to ICR are routed immediately.
+pub fn mmio_write(vm_id: u64, vcpu_id: u32, offset: u32, val: u32) {
+    let now = crate::time::rdtsc();
+    if let Some(Some(ipi)) = with(vm_id, vcpu_id, |l| l.write(offset, val, now)) { let _ = route_ipi(vm_id, vcpu_id, ipi); }
+}
+
+pub fn mmio_read(vm_id: u64, vcpu_id: u32, offset: u32) -> u32 {
+    let now = crate::time::rdtsc();
+    with(vm_id, vcpu_id, |l| l.read(offset, now)).unwrap_or(0)
+}
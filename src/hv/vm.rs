@@ -1,9 +1,12 @@
 #![allow(dead_code)]
 
-use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
 
+use crate::util::spinlock::SpinLock;
+
 /// Global incremental VM identifier allocator.
 static NEXT_VM_ID: AtomicU64 = AtomicU64::new(1);
 
@@ -14,6 +17,12 @@ pub struct VmId(pub u64);
 pub struct VmConfig {
     pub memory_bytes: u64,
     pub vcpu_count: u32,
+    /// RTC definition; None uses the one saved for this VM id, else UTC from host time
+    pub rtc: Option<crate::hv::vtime::rtc::RtcConfig>,
+    /// Host CPUs the vCPUs may run on, one bit per APIC ID (0..63); 0 = any
+    pub affinity: u64,
+    /// Memory-encryption technology; `try_create` assigns the key slot
+    pub confidential: crate::hv::confidential::Kind,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -30,6 +39,26 @@ pub struct Vm {
 impl Vm {
     pub fn create(system_table: &SystemTable<Boot>, config: VmConfig) -> Vm {
         let id = VmId(NEXT_VM_ID.fetch_add(1, Ordering::Relaxed));
+        Self::build(system_table, id, config)
+    }
+
+    /// Create a VM only if its vCPUs, memory, huge-page share and passthrough
+    /// devices fit the admission policy. The reservation is held until `destroy`.
+    pub fn try_create(system_table: &SystemTable<Boot>, config: VmConfig, hugepage_bytes: u64, devices: u32) -> Result<Vm, crate::hv::admission::AdmissionError> {
+        crate::hv::admission::init_capacity(system_table);
+        let id = VmId(NEXT_VM_ID.fetch_add(1, Ordering::Relaxed));
+        let req = crate::hv::admission::Request { vcpus: config.vcpu_count.max(1), memory_bytes: config.memory_bytes, hugepage_bytes, devices };
+        crate::hv::admission::reserve(id.0, req)?;
+        if config.confidential != crate::hv::confidential::Kind::None {
+            if let Err(e) = crate::hv::confidential::assign(id.0, config.confidential) {
+                crate::hv::admission::release(id.0);
+                return Err(e);
+            }
+        }
+        Ok(Self::build(system_table, id, config))
+    }
+
+    fn build(system_table: &SystemTable<Boot>, id: VmId, config: VmConfig) -> Vm {
         crate::obs::metrics::Counter::new(&crate::obs::metrics::VM_CREATED).inc();
         crate::obs::trace::emit(crate::obs::trace::Event::VmCreate(id.0));
         crate::diag::audit::record(crate::diag::audit::AuditKind::VmCreate(id.0));
@@ -51,50 +80,141 @@ impl Vm {
             }
             HvVendor::Unknown => core::ptr::null_mut(),
         } as u64;
+        for v in 0..config.vcpu_count.max(1) { let _ = crate::hv::vlapic::attach(id.0, v); }
+        let _ = crate::hv::vtime::attach(id.0);
+        let rtc = config.rtc.or_else(|| crate::hv::vtime::rtc::load(system_table, id.0)).unwrap_or_default();
+        let _ = crate::hv::vtime::configure_rtc(id.0, rtc, crate::hv::vtime::rtc::host_unix(system_table));
+        crate::hv::bus::attach_platform(id.0);
         Vm { id, config, vendor, pml4_phys: pml4 }
     }
 
-    pub fn start(&self, system_table: &mut SystemTable<Boot>) {
+    /// Mark the VM running after the vendor's enable path. Fails without
+    /// touching the VM if the CPU's virtualization extension is not
+    /// registered as available.
+    pub fn start(&self, system_table: &mut SystemTable<Boot>) -> Result<(), &'static str> {
+        use crate::hv::features;
+        let ext = match self.vendor { HvVendor::Intel => "vmx", HvVendor::Amd => "svm", HvVendor::Unknown => "" };
+        if features::find("vmx").is_none() && features::find("svm").is_none() {
+            return Err("vm: no virtualization feature registered");
+        }
+        if ext.is_empty() || !features::available(ext) { return Err("vm: virtualization extension not available"); }
         crate::obs::metrics::Counter::new(&crate::obs::metrics::VM_STARTED).inc();
         crate::obs::trace::emit(crate::obs::trace::Event::VmStart(self.id.0));
         crate::diag::audit::record(crate::diag::audit::AuditKind::VmStart(self.id.0));
         match self.vendor {
             HvVendor::Intel => {
-                if crate::arch::x86::vm::vmx::vmx_preflight_available() {
-                    let _ = crate::arch::x86::vm::vmx::vmx_smoke_test(system_table);
-                    let _ = crate::arch::x86::vm::vmx::vmx_ept_smoke_test(system_table);
-                }
+                let _ = crate::arch::x86::vm::vmx::vmx_smoke_test(system_table);
+                let _ = crate::arch::x86::vm::vmx::vmx_ept_smoke_test(system_table);
             }
             HvVendor::Amd => {
-                if crate::arch::x86::vm::svm::svm_preflight_available() {
-                    let _ = crate::arch::x86::vm::svm::svm_try_enable();
-                    let _ = crate::arch::x86::vm::svm::svm_prepare_npt(system_table, self.config.memory_bytes.max(1u64 << 30));
-                }
+                let _ = crate::arch::x86::vm::svm::svm_try_enable();
+                let _ = crate::arch::x86::vm::svm::svm_prepare_npt(system_table, self.config.memory_bytes.max(1u64 << 30));
             }
             HvVendor::Unknown => {}
         }
+        note(self.id.0, VmState::Running);
+        Ok(())
     }
 
-    pub fn stop(&self) { /* no-op for prototype */ }
+    pub fn stop(&self) { note(self.id.0, VmState::Stopped); }
 
     pub fn destroy(self) {
         crate::obs::trace::emit(crate::obs::trace::Event::VmStop(self.id.0));
         crate::obs::trace::emit(crate::obs::trace::Event::VmDestroy(self.id.0));
         crate::diag::audit::record(crate::diag::audit::AuditKind::VmStop(self.id.0));
         crate::diag::audit::record(crate::diag::audit::AuditKind::VmDestroy(self.id.0));
-        let _ = self;
+        crate::hv::run::request_stop(self.id.0);
+        crate::hv::sched::credit::forget(self.id.0);
+        crate::hv::admission::release(self.id.0);
+        crate::hv::confidential::release(self.id.0);
+        crate::hv::ksm::detach_vm(self.id.0);
+        crate::hv::microvm::forget_vm(self.id.0);
+        crate::hv::wasm::forget_vm(self.id.0);
+        // Guest RAM stays with the VM while a vCPU may still be in the guest.
+        if crate::hv::run::active(self.id.0) == 0 {
+            crate::hv::loader::unload(self.id.0);
+            crate::mm::guest::release_vm(self.id.0);
+        }
+        let _ = crate::hv::gdb::detach(self.id.0);
+        crate::hv::vlapic::detach_vm(self.id.0);
+        crate::hv::vtime::detach(self.id.0);
+        crate::hv::vdev::net::detach_vm(self.id.0);
+        crate::hv::cni::forget_vm(self.id.0);
+        crate::hv::cri::forget_vm(self.id.0);
+        crate::hv::vdev::blk::detach_vm(self.id.0);
+        crate::hv::vdev::console::detach_vm(self.id.0);
+        crate::hv::vdev::vsock::detach_vm(self.id.0);
+        crate::hv::vdev::balloon::detach_vm(self.id.0);
+        crate::hv::vdev::accel::detach_vm(self.id.0);
+        crate::hv::zero_copy::detach_vm(self.id.0);
+        crate::hv::info_flow::forget(crate::hv::info_flow::Object::Vm(self.id.0));
+        crate::hv::mmiotrace::detach_vm(self.id.0);
+        crate::hv::power::detach_vm(self.id.0);
+        crate::hv::carbon::detach_vm(self.id.0);
+        crate::hv::numa::detach_vm(self.id.0);
+        crate::hv::fpga::detach_vm(self.id.0);
+        crate::hv::sriov::detach_vm(self.id.0);
+        crate::hv::vpci::detach(self.id.0);
+        crate::hv::plugin::detach_vm(self.id.0);
+        crate::hv::bus::unregister_vm(self.id.0);
+        unregister_vm(self.id.0);
     }
 
     pub fn pause(&self) {
+        crate::hv::vtime::pause(self.id.0);
         crate::obs::trace::emit(crate::obs::trace::Event::VmStop(self.id.0));
+        note(self.id.0, VmState::Paused);
     }
 
     pub fn resume(&self) {
+        crate::hv::vtime::resume(self.id.0);
         crate::obs::trace::emit(crate::obs::trace::Event::VmStart(self.id.0));
+        note(self.id.0, VmState::Running);
     }
 }
 
-// ---- Minimal VM registry for control-plane operations ----
+// ---- VM registry ----
+
+/// Registered VMs at most
+pub const MAX_VMS: usize = 64;
+/// Longest VM name in bytes
+pub const NAME_MAX: usize = 32;
+
+/// Lifecycle of a registered VM. Allowed moves (`can_enter`):
+///
+/// ```text
+/// Created   -> Running | Migrating | Stopped
+/// Running   -> Paused | Migrating | Stopped
+/// Paused    -> Running | Migrating | Stopped
+/// Migrating -> Created | Running | Paused | Stopped
+/// Stopped   -> Running | Migrating
+/// ```
+///
+/// A migration returns the VM to the state it was in when it began, unless
+/// the VM was stopped meanwhile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmState { Created, Running, Paused, Migrating, Stopped }
+
+impl VmState {
+    pub fn code(self) -> u8 { match self { VmState::Created => 0, VmState::Running => 1, VmState::Paused => 2, VmState::Migrating => 3, VmState::Stopped => 4 } }
+    pub fn from_code(c: u8) -> Option<VmState> {
+        match c { 0 => Some(VmState::Created), 1 => Some(VmState::Running), 2 => Some(VmState::Paused), 3 => Some(VmState::Migrating), 4 => Some(VmState::Stopped), _ => None }
+    }
+    pub fn name(self) -> &'static str {
+        match self { VmState::Created => "created", VmState::Running => "running", VmState::Paused => "paused", VmState::Migrating => "migrating", VmState::Stopped => "stopped" }
+    }
+    pub fn can_enter(self, to: VmState) -> bool {
+        use VmState::*;
+        matches!((self, to),
+            (Created, Running | Migrating | Stopped)
+            | (Running, Paused | Migrating | Stopped)
+            | (Paused, Running | Migrating | Stopped)
+            | (Migrating, Created | Running | Paused | Stopped)
+            | (Stopped, Running | Migrating))
+    }
+    /// Nothing of the VM is in the guest, so it can be destroyed.
+    pub fn destroyable(self) -> bool { matches!(self, VmState::Created | VmState::Stopped) }
+}
 
 #[derive(Clone, Copy, Debug)]
 pub struct VmInfo {
@@ -102,39 +222,221 @@ pub struct VmInfo {
     pub vendor: HvVendor,
     pub pml4_phys: u64,
     pub memory_bytes: u64,
+    pub vcpus: u32,
+    /// `VmConfig::affinity`
+    pub affinity: u64,
+    pub state: VmState,
+    /// `clock::now_ms` at registration
+    pub created_ms: u64,
+    /// `clock::now_ms` of the last state change
+    pub since_ms: u64,
+    /// Milliseconds spent running or migrating before `since_ms`
+    pub ran_ms: u64,
+    /// Times the VM entered `Running` from `Created` or `Stopped`
+    pub starts: u32,
+    name: [u8; NAME_MAX],
+    name_len: u8,
+    /// State a migration returns to
+    resume: VmState,
+}
+
+impl VmInfo {
+    pub fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("?") }
+
+    /// Guest run time in milliseconds, the current stretch included.
+    pub fn run_ms(&self) -> u64 {
+        let live = matches!(self.state, VmState::Running | VmState::Migrating);
+        self.ran_ms + if live { crate::time::clock::now_ms().saturating_sub(self.since_ms) } else { 0 }
+    }
+}
+
+/// What a VM holds right now, gathered from the modules that own each resource.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Usage {
+    /// vCPUs in the guest or waiting to enter it
+    pub vcpus_live: u32,
+    /// VM exits across its vCPUs
+    pub exits: u64,
+    /// Guest RAM reserved in `mm::guest`
+    pub mem_reserved: u64,
+    /// Guest RAM backed by host pages
+    pub mem_backed: u64,
+    /// Admission reservation, if the VM was admitted
+    pub admitted: Option<crate::hv::admission::Request>,
+    pub run_ms: u64,
+}
+
+static REGISTRY: SpinLock<Vec<VmInfo>> = SpinLock::new(Vec::new());
+
+/// Names are `[A-Za-z0-9_.-]`, start with a letter and are unique.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= NAME_MAX
+        && name.as_bytes()[0].is_ascii_alphabetic()
+        && name.bytes().all(|c| c.is_ascii_alphanumeric() || matches!(c, b'_' | b'.' | b'-'))
 }
 
-const VM_REG_CAP: usize = 16;
-static VM_REG_LEN: AtomicUsize = AtomicUsize::new(0);
-static mut VM_REG: [VmInfo; VM_REG_CAP] = [VmInfo { id: 0, vendor: HvVendor::Unknown, pml4_phys: 0, memory_bytes: 0 }; VM_REG_CAP];
+/// Register a VM under `name`, or `vm<id>` when none is given.
+pub fn register(vm: &Vm, name: Option<&str>) -> Result<(), &'static str> {
+    let mut buf = [0u8; NAME_MAX];
+    let len = match name {
+        Some(n) => {
+            if !valid_name(n) { return Err("vm: invalid name"); }
+            buf[..n.len()].copy_from_slice(n.as_bytes());
+            n.len()
+        }
+        None => {
+            buf[..2].copy_from_slice(b"vm");
+            2 + crate::util::format::u64_dec(vm.id.0, &mut buf[2..])
+        }
+    };
+    let now = crate::time::clock::now_ms();
+    let info = VmInfo {
+        id: vm.id.0, vendor: vm.vendor, pml4_phys: vm.pml4_phys,
+        memory_bytes: vm.config.memory_bytes.max(1u64 << 30), vcpus: vm.config.vcpu_count.max(1), affinity: vm.config.affinity,
+        state: VmState::Created, created_ms: now, since_ms: now, ran_ms: 0, starts: 0,
+        name: buf, name_len: len as u8, resume: VmState::Created,
+    };
+    REGISTRY.lock(|r| {
+        if r.iter().any(|v| v.id == info.id) { return Err("vm: id already registered"); }
+        if r.iter().any(|v| v.name() == info.name()) { return Err("vm: name in use"); }
+        if r.len() >= MAX_VMS || r.try_reserve(1).is_err() { return Err("vm: registry full"); }
+        r.push(info);
+        Ok(())
+    })?;
+    crate::cluster::store::note_vm(info.id);
+    crate::hv::plugin::vm_event(info.id, VmState::Created.code() as u32);
+    Ok(())
+}
 
 /// Register a VM for later lookup by id. Returns true on success.
-pub fn register_vm(vm: &Vm) -> bool {
-    let idx = VM_REG_LEN.load(Ordering::Relaxed);
-    if idx >= VM_REG_CAP { return false; }
-    let info = VmInfo { id: vm.id.0, vendor: vm.vendor, pml4_phys: vm.pml4_phys, memory_bytes: vm.config.memory_bytes.max(1u64 << 30) };
-    unsafe { VM_REG[idx] = info; }
-    VM_REG_LEN.store(idx + 1, Ordering::Relaxed);
-    true
+pub fn register_vm(vm: &Vm) -> bool { register(vm, None).is_ok() }
+
+/// Forget a VM. Returns true if it was registered.
+pub fn unregister_vm(id: u64) -> bool {
+    let found = REGISTRY.lock(|r| match r.iter().position(|v| v.id == id) {
+        Some(i) => { r.remove(i); true }
+        None => false,
+    });
+    if found { crate::cluster::store::note_vm(id); }
+    found
 }
 
 /// Find a VM by id and return its snapshot info.
 pub fn find_vm(id: u64) -> Option<VmInfo> {
-    let len = VM_REG_LEN.load(Ordering::Relaxed);
-    for i in 0..len {
-        let info = unsafe { VM_REG[i] };
-        if info.id == id { return Some(info); }
-    }
-    None
+    REGISTRY.lock(|r| r.iter().find(|v| v.id == id).copied())
 }
 
-/// Iterate registered VMs.
-pub fn list_vms(mut f: impl FnMut(VmInfo)) {
-    let len = VM_REG_LEN.load(Ordering::Relaxed);
-    for i in 0..len {
-        let info = unsafe { VM_REG[i] };
-        if info.id != 0 { f(info); }
-    }
+pub fn find_vm_by_name(name: &str) -> Option<VmInfo> {
+    REGISTRY.lock(|r| r.iter().find(|v| v.name() == name).copied())
+}
+
+/// Look a VM up by an `id=<n>` or `name=<s>` argument.
+pub fn select(arg: &str) -> Option<VmInfo> {
+    if let Some(v) = arg.strip_prefix("id=") { return v.parse::<u64>().ok().and_then(find_vm); }
+    arg.strip_prefix("name=").and_then(find_vm_by_name)
+}
+
+fn update(id: u64, f: impl FnOnce(&mut VmInfo)) -> bool {
+    REGISTRY.lock(|r| match r.iter_mut().find(|v| v.id == id) {
+        Some(v) => { f(v); true }
+        None => false,
+    })
+}
+
+/// Replace the second-level page table root recorded for a VM.
+pub fn set_vm_pml4(id: u64, pml4_phys: u64) -> bool { update(id, |v| v.pml4_phys = pml4_phys) }
+
+/// Replace the CPU affinity mask recorded for a VM.
+pub fn set_vm_affinity(id: u64, mask: u64) -> bool { update(id, |v| v.affinity = mask) }
+
+pub fn rename(id: u64, name: &str) -> Result<(), &'static str> {
+    if !valid_name(name) { return Err("vm: invalid name"); }
+    REGISTRY.lock(|r| {
+        if r.iter().any(|v| v.id != id && v.name() == name) { return Err("vm: name in use"); }
+        let v = r.iter_mut().find(|v| v.id == id).ok_or("vm: no such vm")?;
+        v.name = [0; NAME_MAX];
+        v.name[..name.len()].copy_from_slice(name.as_bytes());
+        v.name_len = name.len() as u8;
+        Ok(())
+    })?;
+    crate::cluster::store::note_vm(id);
+    Ok(())
+}
+
+fn enter(v: &mut VmInfo, to: VmState) {
+    let now = crate::time::clock::now_ms();
+    if matches!(v.state, VmState::Running | VmState::Migrating) { v.ran_ms += now.saturating_sub(v.since_ms); }
+    if to == VmState::Running && matches!(v.state, VmState::Created | VmState::Stopped) { v.starts += 1; }
+    v.state = to;
+    v.since_ms = now;
 }
 
+/// Move a VM to `to`. Returns the state it left.
+pub fn transition(id: u64, to: VmState) -> Result<VmState, &'static str> {
+    let from = REGISTRY.lock(|r| {
+        let v = r.iter_mut().find(|v| v.id == id).ok_or("vm: no such vm")?;
+        let from = v.state;
+        if !from.can_enter(to) { return Err("vm: invalid state transition"); }
+        if to == VmState::Migrating { v.resume = from; }
+        enter(v, to);
+        Ok(from)
+    })?;
+    crate::diag::audit::record(crate::diag::audit::AuditKind::VmState { vm: id, from: from.code(), to: to.code() });
+    crate::hv::plugin::vm_event(id, to.code() as u32);
+    Ok(from)
+}
+
+/// Record what the vCPUs of a VM just did. During a migration a pause or
+/// resume only changes the state the migration returns to. Unregistered
+/// VMs and moves the state machine does not allow are ignored.
+pub fn note(id: u64, to: VmState) {
+    let migrating = REGISTRY.lock(|r| match r.iter_mut().find(|v| v.id == id) {
+        Some(v) if v.state == VmState::Migrating && matches!(to, VmState::Running | VmState::Paused) => { v.resume = to; true }
+        _ => false,
+    });
+    if !migrating { let _ = transition(id, to); }
+}
+
+/// Enter `Migrating`. Unregistered VMs are allowed and not tracked.
+pub fn begin_migration(id: u64) -> Result<(), &'static str> {
+    if find_vm(id).is_none() { return Ok(()); }
+    transition(id, VmState::Migrating).map(|_| ())
+}
+
+/// Leave `Migrating` for the state the VM had before it.
+pub fn end_migration(id: u64) {
+    let Some(v) = find_vm(id) else { return };
+    if v.state == VmState::Migrating { let _ = transition(id, v.resume); }
+}
+
+pub fn usage(id: u64) -> Option<Usage> {
+    let v = find_vm(id)?;
+    let mut u = Usage {
+        vcpus_live: crate::hv::run::active(id),
+        mem_reserved: crate::mm::guest::reservation(id),
+        mem_backed: crate::mm::guest::backed(id),
+        admitted: crate::hv::admission::reservation(id),
+        ..Default::default()
+    };
+    crate::hv::run::for_each(id, |_, r| u.exits += r.exits);
+    u.run_ms = v.run_ms();
+    Some(u)
+}
+
+/// Stop tracking a VM and tear it down. Only a created or stopped VM can go.
+pub fn destroy_vm(id: u64) -> Result<(), &'static str> {
+    let info = find_vm(id).ok_or("vm: no such vm")?;
+    if !info.state.destroyable() { return Err("vm: stop the vm first"); }
+    let vm = Vm { id: VmId(id), config: VmConfig { memory_bytes: info.memory_bytes, vcpu_count: info.vcpus, affinity: info.affinity, ..Default::default() }, vendor: info.vendor, pml4_phys: info.pml4_phys };
+    vm.destroy();
+    Ok(())
+}
+
+/// Iterate registered VMs. Entries are copied out one at a time, so `f` may
+/// call back into the registry.
+pub fn list_vms(mut f: impl FnMut(VmInfo)) {
+    let mut i = 0;
+    while let Some(v) = REGISTRY.lock(|r| r.get(i).copied()) { f(v); i += 1; }
+}
 
+pub fn vm_count() -> usize { REGISTRY.lock(|r| r.len()) }
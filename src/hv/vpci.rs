@@ -0,0 +1,307 @@
+#![allow(dead_code)]
+
+//! Guest-visible PCI bus: configuration mechanism #1 on ports 0xCF8-0xCFF.
+//!
+//! Each VM has a single bus 0 with a host bridge at device 0 and up to
+//! `MAX_FUNCS - 1` emulated single-function devices. Config space is a plain
+//! 256-byte image plus a per-byte write mask; BAR sizing falls out of the
+//! mask (writing all-ones reads back `!(size - 1)`). When memory decoding is
+//! enabled and a BAR base changes, the device's `BarFn` is told so it can
+//! move its `hv::bus` window.
+
+use crate::util::spinlock::SpinLock;
+
+pub const PORT_ADDR: u16 = 0xCF8;
+pub const PORT_DATA: u16 = 0xCFC;
+/// Devices per bus, including the host bridge at 0
+pub const MAX_FUNCS: usize = 8;
+pub const MAX_BUSES: usize = 16;
+/// Guest-physical window BARs are placed in initially (one 1 MiB slot per device).
+pub const MMIO_WINDOW_BASE: u64 = 0xE000_0000;
+pub const MMIO_SLOT_SIZE: u64 = 0x10_0000;
+
+pub const CFG_VENDOR: usize = 0x00;
+pub const CFG_DEVICE: usize = 0x02;
+pub const CFG_COMMAND: usize = 0x04;
+pub const CFG_STATUS: usize = 0x06;
+pub const CFG_REVISION: usize = 0x08;
+pub const CFG_PROG_IF: usize = 0x09;
+pub const CFG_SUBCLASS: usize = 0x0A;
+pub const CFG_CLASS: usize = 0x0B;
+pub const CFG_HEADER_TYPE: usize = 0x0E;
+pub const CFG_BAR0: usize = 0x10;
+pub const CFG_SUBSYS_VENDOR: usize = 0x2C;
+pub const CFG_SUBSYS_ID: usize = 0x2E;
+pub const CFG_CAP_PTR: usize = 0x34;
+pub const CFG_INT_LINE: usize = 0x3C;
+pub const CFG_INT_PIN: usize = 0x3D;
+
+pub const CMD_IO: u16 = 1 << 0;
+pub const CMD_MEM: u16 = 1 << 1;
+pub const CMD_MASTER: u16 = 1 << 2;
+pub const CMD_INTX_DISABLE: u16 = 1 << 10;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+pub const STATUS_CAP_LIST: u16 = 1 << 4;
+pub const STATUS_INTX: u16 = 1 << 3;
+
+/// Called when BAR `bar` of the device registered with `ctx` moves from `old`
+/// to `new` (0 = unmapped).
+pub type BarFn = fn(vm_id: u64, ctx: u64, bar: usize, old: u64, new: u64);
+
+/// Config space image under construction.
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub cfg: [u8; 256],
+    pub wmask: [u8; 256],
+    /// Size of each BAR (0 = not implemented); a 64-bit BAR uses the next slot too
+    pub bar_size: [u64; 6],
+    next_cap: usize,
+}
+
+impl Config {
+    pub fn new(vendor: u16, device: u16, class: u8, subclass: u8, revision: u8) -> Self {
+        let mut c = Config { cfg: [0; 256], wmask: [0; 256], bar_size: [0; 6], next_cap: 0x40 };
+        c.put16(CFG_VENDOR, vendor);
+        c.put16(CFG_DEVICE, device);
+        c.cfg[CFG_REVISION] = revision;
+        c.cfg[CFG_SUBCLASS] = subclass;
+        c.cfg[CFG_CLASS] = class;
+        c.wmask[CFG_COMMAND] = (CMD_IO | CMD_MEM | CMD_MASTER) as u8;
+        c.wmask[CFG_COMMAND + 1] = (CMD_INTX_DISABLE >> 8) as u8;
+        c.wmask[CFG_INT_LINE] = 0xFF;
+        c
+    }
+
+    pub fn put16(&mut self, off: usize, v: u16) { self.cfg[off..off + 2].copy_from_slice(&v.to_le_bytes()); }
+    pub fn put32(&mut self, off: usize, v: u32) { self.cfg[off..off + 4].copy_from_slice(&v.to_le_bytes()); }
+
+    /// Declare a 64-bit memory BAR in slots `bar` and `bar + 1`; `size` must be a power of two >= 16.
+    pub fn bar64(&mut self, bar: usize, size: u64) {
+        let off = CFG_BAR0 + bar * 4;
+        self.put32(off, 0b100); // memory, 64-bit, non-prefetchable
+        let mask = !(size - 1);
+        for i in 0..4 { self.wmask[off + i] = (mask >> (i * 8)) as u8 & if i == 0 { 0xF0 } else { 0xFF }; }
+        for i in 0..4 { self.wmask[off + 4 + i] = (mask >> (32 + i * 8)) as u8; }
+        self.bar_size[bar] = size;
+    }
+
+    /// Declare a 32-bit memory BAR in slot `bar`; `size` must be a power of two >= 16.
+    pub fn bar32(&mut self, bar: usize, size: u64) {
+        let off = CFG_BAR0 + bar * 4;
+        self.put32(off, 0); // memory, 32-bit, non-prefetchable
+        let mask = !(size - 1);
+        for i in 0..4 { self.wmask[off + i] = (mask >> (i * 8)) as u8 & if i == 0 { 0xF0 } else { 0xFF }; }
+        self.bar_size[bar] = size;
+    }
+
+    /// Use legacy INTx pin A routed to ISA `line`.
+    pub fn intx(&mut self, line: u8) {
+        self.cfg[CFG_INT_LINE] = line;
+        self.cfg[CFG_INT_PIN] = 1;
+    }
+
+    /// Append a capability whose body (after id/next) is `body`; returns its offset.
+    pub fn add_cap(&mut self, id: u8, body: &[u8]) -> Option<usize> {
+        let off = self.next_cap;
+        let len = (2 + body.len() + 3) & !3;
+        if off + len > 256 { return None; }
+        self.cfg[off] = id;
+        self.cfg[off + 1] = 0;
+        self.cfg[off + 2..off + 2 + body.len()].copy_from_slice(body);
+        // Link from the previous capability, or from the header.
+        let mut p = CFG_CAP_PTR;
+        while self.cfg[p] != 0 { p = self.cfg[p] as usize + 1; }
+        self.cfg[p] = off as u8;
+        let st = u16::from_le_bytes([self.cfg[CFG_STATUS], self.cfg[CFG_STATUS + 1]]) | STATUS_CAP_LIST;
+        self.put16(CFG_STATUS, st);
+        self.next_cap = off + len;
+        Some(off)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Func {
+    c: Config,
+    on_bar: Option<BarFn>,
+    ctx: u64,
+    /// Base last reported through `on_bar`
+    mapped: [u64; 6],
+}
+
+impl Func {
+    fn bar_base(&self, bar: usize) -> u64 {
+        let off = CFG_BAR0 + bar * 4;
+        let lo = u32::from_le_bytes([self.c.cfg[off], self.c.cfg[off + 1], self.c.cfg[off + 2], self.c.cfg[off + 3]]) as u64;
+        let hi = if (lo & 0b110) == 0b100 && bar < 5 {
+            u32::from_le_bytes([self.c.cfg[off + 4], self.c.cfg[off + 5], self.c.cfg[off + 6], self.c.cfg[off + 7]]) as u64
+        } else { 0 };
+        (hi << 32) | (lo & !0xF)
+    }
+
+    fn command(&self) -> u16 { u16::from_le_bytes([self.c.cfg[CFG_COMMAND], self.c.cfg[CFG_COMMAND + 1]]) }
+}
+
+#[derive(Clone, Copy)]
+struct VBus {
+    vm_id: u64,
+    cf8: u32,
+    funcs: [Option<Func>; MAX_FUNCS],
+}
+
+static BUSES: SpinLock<[Option<VBus>; MAX_BUSES]> = SpinLock::new([None; MAX_BUSES]);
+
+fn with_bus<R>(vm_id: u64, f: impl FnOnce(&mut VBus) -> R) -> Option<R> {
+    BUSES.lock(|t| t.iter_mut().flatten().find(|b| b.vm_id == vm_id).map(f))
+}
+
+fn pio(vm_id: u64, _vcpu: u32, port: u16, size: u8, write: Option<u32>) -> u32 {
+    match (port, write) {
+        (PORT_ADDR, Some(v)) if size == 4 => { let _ = with_bus(vm_id, |b| b.cf8 = v); 0 }
+        (PORT_ADDR, None) if size == 4 => with_bus(vm_id, |b| b.cf8).unwrap_or(0xFFFF_FFFF),
+        (p, w) if (PORT_DATA..PORT_DATA + 4).contains(&p) => {
+            let addr = match with_bus(vm_id, |b| b.cf8) { Some(a) => a, None => return 0xFFFF_FFFF };
+            if (addr & 0x8000_0000) == 0 { return 0xFFFF_FFFF; }
+            let bus = (addr >> 16) as u8;
+            let dev = ((addr >> 11) & 0x1F) as u8;
+            let func = ((addr >> 8) & 7) as u8;
+            let off = (addr & 0xFC) as usize + (p - PORT_DATA) as usize;
+            if bus != 0 || func != 0 { return if w.is_some() { 0 } else { 0xFFFF_FFFF }; }
+            match w {
+                Some(v) => { config_write(vm_id, dev, off, size, v); 0 }
+                None => config_read(vm_id, dev, off, size),
+            }
+        }
+        (_, Some(_)) => 0,
+        _ => 0xFFFF_FFFF,
+    }
+}
+
+/// Read `size` bytes of config space; absent devices read all-ones.
+pub fn config_read(vm_id: u64, dev: u8, off: usize, size: u8) -> u32 {
+    let mask = if size >= 4 { 0xFFFF_FFFF } else { (1u32 << (size * 8)) - 1 };
+    with_bus(vm_id, |b| {
+        let f = match b.funcs.get(dev as usize).and_then(|f| f.as_ref()) { Some(f) => f, None => return mask };
+        let mut v = 0u32;
+        for i in 0..size as usize { if off + i < 256 { v |= (f.c.cfg[off + i] as u32) << (i * 8); } }
+        v
+    }).unwrap_or(mask)
+}
+
+/// Write config space through the write mask, then notify BAR moves.
+pub fn config_write(vm_id: u64, dev: u8, off: usize, size: u8, v: u32) {
+    let moves = with_bus(vm_id, |b| {
+        let mut moves: [Option<(BarFn, u64, usize, u64, u64)>; 6] = [None; 6];
+        let f = match b.funcs.get_mut(dev as usize).and_then(|f| f.as_mut()) { Some(f) => f, None => return moves };
+        for i in 0..size as usize {
+            let o = off + i;
+            if o >= 256 { break; }
+            let wm = f.c.wmask[o];
+            f.c.cfg[o] = (f.c.cfg[o] & !wm) | ((v >> (i * 8)) as u8 & wm);
+        }
+        let touched = off < CFG_COMMAND + 2 || (off < CFG_BAR0 + 24 && off + size as usize > CFG_BAR0);
+        if !touched { return moves; }
+        let mem_on = (f.command() & CMD_MEM) != 0;
+        for bar in 0..6 {
+            if f.c.bar_size[bar] == 0 { continue; }
+            let new = if mem_on { f.bar_base(bar) } else { 0 };
+            if new != f.mapped[bar] {
+                if let Some(cb) = f.on_bar { moves[bar] = Some((cb, f.ctx, bar, f.mapped[bar], new)); }
+                f.mapped[bar] = new;
+            }
+        }
+        moves
+    }).unwrap_or([None; 6]);
+    // Callbacks run without the bus lock so they may touch hv::bus freely.
+    for (cb, ctx, bar, old, new) in moves.iter().flatten() { cb(vm_id, *ctx, *bar, *old, *new); }
+}
+
+/// Create bus 0 for `vm_id` with a host bridge and claim the config ports.
+pub fn attach(vm_id: u64) -> bool {
+    let mut hb = Config::new(0x1B36, 0x0008, 0x06, 0x00, 0);
+    hb.wmask[CFG_COMMAND] = 0;
+    let ok = BUSES.lock(|t| {
+        if t.iter().flatten().any(|b| b.vm_id == vm_id) { return true; }
+        match t.iter_mut().find(|b| b.is_none()) {
+            Some(slot) => {
+                let mut funcs = [None; MAX_FUNCS];
+                funcs[0] = Some(Func { c: hb, on_bar: None, ctx: 0, mapped: [0; 6] });
+                *slot = Some(VBus { vm_id, cf8: 0, funcs });
+                true
+            }
+            None => false,
+        }
+    });
+    ok && crate::hv::bus::register_pio(vm_id, PORT_ADDR, 8, "pci", pio)
+}
+
+/// Plug a device into the first free slot. BARs are pre-assigned in the
+/// device's MMIO slot with memory decoding enabled, and `on_bar` is invoked for
+/// each (with `ctx`) so the device can map itself. Returns the device number.
+pub fn add(vm_id: u64, mut c: Config, ctx: u64, on_bar: BarFn) -> Option<u8> {
+    let dev = with_bus(vm_id, |b| {
+        let dev = b.funcs.iter().position(|f| f.is_none())?;
+        let mut base = MMIO_WINDOW_BASE + dev as u64 * MMIO_SLOT_SIZE;
+        let mut mapped = [0u64; 6];
+        for bar in 0..6 {
+            let size = c.bar_size[bar];
+            if size == 0 { continue; }
+            base = (base + size - 1) & !(size - 1);
+            let off = CFG_BAR0 + bar * 4;
+            let lo = u32::from_le_bytes([c.cfg[off], c.cfg[off + 1], c.cfg[off + 2], c.cfg[off + 3]]);
+            c.put32(off, (lo & 0xF) | base as u32);
+            if (lo & 0b110) == 0b100 { c.put32(off + 4, (base >> 32) as u32); }
+            mapped[bar] = base;
+            base += size;
+        }
+        let cmd = u16::from_le_bytes([c.cfg[CFG_COMMAND], c.cfg[CFG_COMMAND + 1]]) | CMD_MEM | CMD_MASTER;
+        c.put16(CFG_COMMAND, cmd);
+        b.funcs[dev] = Some(Func { c, on_bar: Some(on_bar), ctx, mapped });
+        Some((dev as u8, mapped))
+    }).flatten();
+    let (dev, mapped) = dev?;
+    for (bar, base) in mapped.iter().enumerate() { if *base != 0 { on_bar(vm_id, ctx, bar, 0, *base); } }
+    Some(dev)
+}
+
+/// Unplug device `dev`; its BARs are reported as unmapped first.
+pub fn remove(vm_id: u64, dev: u8) {
+    let f = with_bus(vm_id, |b| b.funcs.get_mut(dev as usize).and_then(|f| f.take())).flatten();
+    if let Some(f) = f {
+        if let Some(cb) = f.on_bar {
+            for (bar, base) in f.mapped.iter().enumerate() { if *base != 0 { cb(vm_id, f.ctx, bar, *base, 0); } }
+        }
+    }
+}
+
+pub fn detach(vm_id: u64) {
+    BUSES.lock(|t| { for b in t.iter_mut() { if matches!(b, Some(x) if x.vm_id == vm_id) { *b = None; } } });
+}
+
+/// Set or clear the INTx status bit; returns false if the guest disabled INTx.
+pub fn set_intx(vm_id: u64, dev: u8, asserted: bool) -> bool {
+    with_bus(vm_id, |b| {
+        let f = match b.funcs.get_mut(dev as usize).and_then(|f| f.as_mut()) { Some(f) => f, None => return false };
+        let st = u16::from_le_bytes([f.c.cfg[CFG_STATUS], f.c.cfg[CFG_STATUS + 1]]);
+        let st = if asserted { st | STATUS_INTX } else { st & !STATUS_INTX };
+        f.c.put16(CFG_STATUS, st);
+        (f.command() & CMD_INTX_DISABLE) == 0
+    }).unwrap_or(false)
+}
+
+/// ISA line the guest programmed for device `dev`.
+pub fn int_line(vm_id: u64, dev: u8) -> Option<u8> {
+    with_bus(vm_id, |b| b.funcs.get(dev as usize).and_then(|f| f.as_ref()).map(|f| f.c.cfg[CFG_INT_LINE])).flatten()
+}
+
+/// Iterate devices as (dev, vendor, device id, class, subclass).
+pub fn for_each(vm_id: u64, mut f: impl FnMut(u8, u16, u16, u8, u8)) {
+    let funcs = with_bus(vm_id, |b| b.funcs).unwrap_or([None; MAX_FUNCS]);
+    for (i, fc) in funcs.iter().enumerate() {
+        if let Some(fc) = fc {
+            let c = &fc.c.cfg;
+            f(i as u8, u16::from_le_bytes([c[0], c[1]]), u16::from_le_bytes([c[2], c[3]]), c[CFG_CLASS], c[CFG_SUBCLASS]);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+#![allow(dead_code)]
+
+//! Virtual HPET: one 64-bit main counter and three comparators.
+//!
+//! The main counter runs at `HPET_HZ` and is derived from the host TSC while
+//! ENABLE_CNF is set. Comparators are evaluated lazily by `poll`; timer 0 is
+//! the only one with periodic capability, as on most chipsets.
+
+/// Default guest-physical MMIO base.
+pub const HPET_BASE: u64 = 0xFED0_0000;
+/// Main counter frequency (10 MHz, period 100 ns).
+pub const HPET_HZ: u64 = 10_000_000;
+const PERIOD_FS: u64 = 1_000_000_000_000_000 / HPET_HZ;
+pub const NUM_TIMERS: usize = 3;
+
+pub const REG_CAP_ID: u32 = 0x000;
+pub const REG_CONFIG: u32 = 0x010;
+pub const REG_ISR: u32 = 0x020;
+pub const REG_COUNTER: u32 = 0x0F0;
+pub const REG_TIMER0: u32 = 0x100;
+
+const CONF_ENABLE: u64 = 1 << 0;
+const CONF_LEGACY: u64 = 1 << 1;
+const TN_INT_LEVEL: u64 = 1 << 1;
+const TN_INT_ENB: u64 = 1 << 2;
+const TN_PERIODIC: u64 = 1 << 3;
+const TN_PER_CAP: u64 = 1 << 4;
+const TN_SIZE_CAP: u64 = 1 << 5;
+const TN_VAL_SET: u64 = 1 << 6;
+const TN_32BIT: u64 = 1 << 8;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HpetTimer {
+    pub config: u64,
+    pub comparator: u64,
+    /// Period for periodic mode (last value written with VAL_SET)
+    pub period: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Hpet {
+    pub config: u64,
+    pub isr: u64,
+    /// Counter value at `enable_tsc` (or the frozen value while disabled)
+    base: u64,
+    enable_tsc: u64,
+    pub timers: [HpetTimer; NUM_TIMERS],
+}
+
+impl Hpet {
+    pub const fn new() -> Self {
+        const T: HpetTimer = HpetTimer { config: 0, comparator: u64::MAX, period: 0 };
+        let mut timers = [T; NUM_TIMERS];
+        timers[0].config = TN_PER_CAP | TN_SIZE_CAP;
+        timers[1].config = TN_SIZE_CAP;
+        timers[2].config = TN_SIZE_CAP;
+        Hpet { config: 0, isr: 0, base: 0, enable_tsc: 0, timers }
+    }
+
+    pub fn enabled(&self) -> bool { (self.config & CONF_ENABLE) != 0 }
+
+    /// Main counter value at host time `now_tsc`.
+    pub fn counter(&self, now_tsc: u64, tsc_hz: u64) -> u64 {
+        if !self.enabled() || tsc_hz == 0 { return self.base; }
+        let dt = now_tsc.wrapping_sub(self.enable_tsc) as u128;
+        self.base.wrapping_add((dt * HPET_HZ as u128 / tsc_hz as u128) as u64)
+    }
+
+    fn cap_id() -> u64 {
+        // rev 1, NUM_TIM_CAP = timers-1, COUNT_SIZE_CAP, LEG_RT_CAP, vendor 0x8086
+        1 | (((NUM_TIMERS as u64) - 1) << 8) | (1 << 13) | (1 << 15) | (0x8086 << 16) | (PERIOD_FS << 32)
+    }
+
+    /// 64-bit register read (32-bit accesses are split by the caller).
+    pub fn read(&self, offset: u32, now_tsc: u64, tsc_hz: u64) -> u64 {
+        match offset {
+            REG_CAP_ID => Self::cap_id(),
+            REG_CONFIG => self.config,
+            REG_ISR => self.isr,
+            REG_COUNTER => self.counter(now_tsc, tsc_hz),
+            o if o >= REG_TIMER0 && o < REG_TIMER0 + 0x20 * NUM_TIMERS as u32 => {
+                let t = &self.timers[((o - REG_TIMER0) / 0x20) as usize];
+                match (o - REG_TIMER0) % 0x20 {
+                    0x00 => t.config,
+                    0x08 => t.comparator,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    /// 64-bit register write.
+    pub fn write(&mut self, offset: u32, val: u64, now_tsc: u64, tsc_hz: u64) {
+        match offset {
+            REG_CONFIG => {
+                let was = self.enabled();
+                let cur = self.counter(now_tsc, tsc_hz);
+                self.config = val & (CONF_ENABLE | CONF_LEGACY);
+                if !was && self.enabled() { self.base = cur; self.enable_tsc = now_tsc; }
+                if was && !self.enabled() { self.base = cur; }
+            }
+            // Write-1-to-clear level interrupt status
+            REG_ISR => self.isr &= !val,
+            REG_COUNTER => {
+                // Only writable while halted.
+                if !self.enabled() { self.base = val; }
+            }
+            o if o >= REG_TIMER0 && o < REG_TIMER0 + 0x20 * NUM_TIMERS as u32 => {
+                let i = ((o - REG_TIMER0) / 0x20) as usize;
+                let t = &mut self.timers[i];
+                match (o - REG_TIMER0) % 0x20 {
+                    0x00 => {
+                        let ro = t.config & (TN_PER_CAP | TN_SIZE_CAP);
+                        let mut v = val & (TN_INT_LEVEL | TN_INT_ENB | TN_PERIODIC | TN_VAL_SET | TN_32BIT | (0x1F << 9));
+                        if (ro & TN_PER_CAP) == 0 { v &= !TN_PERIODIC; }
+                        t.config = v | ro;
+                    }
+                    0x08 => {
+                        let periodic = (t.config & TN_PERIODIC) != 0;
+                        if periodic && (t.config & TN_VAL_SET) != 0 {
+                            // VAL_SET: this write sets the accumulator; the next one only the period.
+                            t.comparator = val;
+                            t.config &= !TN_VAL_SET;
+                        } else if periodic {
+                            t.period = val;
+                        } else {
+                            t.comparator = val;
+                        }
+                        if periodic && t.period == 0 { t.period = val; }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Evaluate comparators; returns a bitmask of timers that fired.
+    pub fn poll(&mut self, now_tsc: u64, tsc_hz: u64) -> u32 {
+        if !self.enabled() { return 0; }
+        let now = self.counter(now_tsc, tsc_hz);
+        let mut fired = 0u32;
+        for (i, t) in self.timers.iter_mut().enumerate() {
+            if (t.config & TN_INT_ENB) == 0 || t.comparator == u64::MAX || now < t.comparator { continue; }
+            fired |= 1 << i;
+            if (t.config & TN_INT_LEVEL) != 0 { self.isr |= 1 << i; }
+            if (t.config & TN_PERIODIC) != 0 && t.period != 0 {
+                while t.comparator <= now { t.comparator = t.comparator.wrapping_add(t.period); }
+            } else {
+                t.comparator = u64::MAX;
+            }
+        }
+        fired
+    }
+
+    /// Legacy IRQ for timer `i` (legacy replacement: timer0->IRQ0, timer1->IRQ8).
+    pub fn timer_irq(&self, i: usize) -> u8 {
+        if (self.config & CONF_LEGACY) != 0 {
+            match i { 0 => return 0, 1 => return 8, _ => {} }
+        }
+        ((self.timers[i].config >> 9) & 0x1F) as u8
+    }
+}
@@ -0,0 +1,255 @@
+#![allow(dead_code)]
+
+//! Guest timekeeping.
+//!
+//! Each VM gets a fixed TSC offset (guest TSC = host TSC + offset) so its TSC
+//! starts at zero on creation and stays monotonic across pause/resume, plus an
+//! emulated PIT channel 0, HPET and CMOS RTC whose counters are derived from
+//! the same calibrated host TSC. `poll` turns timer expiries into vLAPIC
+//! interrupts.
+
+pub mod pit;
+pub mod hpet;
+pub mod rtc;
+
+use crate::util::spinlock::SpinLock;
+
+/// Vector used for legacy ISA IRQ n until an I/O APIC model exists (PIC-style base).
+pub const LEGACY_IRQ_VECTOR_BASE: u8 = 0x20;
+pub const MAX_CLOCKS: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct GuestClock {
+    pub vm_id: u64,
+    /// Added to host TSC to obtain guest TSC (wrapping)
+    pub tsc_offset: u64,
+    /// Host TSC at creation
+    pub created_tsc: u64,
+    /// Host TSC when paused (0 = running)
+    pub paused_tsc: u64,
+    pub pit: pit::Pit,
+    pub hpet: hpet::Hpet,
+    pub rtc: rtc::Rtc,
+}
+
+static CLOCKS: SpinLock<[Option<GuestClock>; MAX_CLOCKS]> = SpinLock::new([None; MAX_CLOCKS]);
+
+/// Create the clock for `vm_id`; guest TSC starts at 0.
+pub fn attach(vm_id: u64) -> bool {
+    let now = crate::time::rdtsc();
+    CLOCKS.lock(|t| {
+        if t.iter().flatten().any(|c| c.vm_id == vm_id) { return true; }
+        match t.iter_mut().find(|c| c.is_none()) {
+            Some(slot) => {
+                *slot = Some(GuestClock { vm_id, tsc_offset: 0u64.wrapping_sub(now), created_tsc: now, paused_tsc: 0, pit: pit::Pit::new(), hpet: hpet::Hpet::new(), rtc: rtc::Rtc::new() });
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+pub fn detach(vm_id: u64) {
+    CLOCKS.lock(|t| { for c in t.iter_mut() { if matches!(c, Some(x) if x.vm_id == vm_id) { *c = None; } } });
+}
+
+/// Run `f` on the clock of `vm_id`.
+pub fn with<R>(vm_id: u64, f: impl FnOnce(&mut GuestClock) -> R) -> Option<R> {
+    CLOCKS.lock(|t| t.iter_mut().flatten().find(|c| c.vm_id == vm_id).map(f))
+}
+
+pub fn tsc_offset(vm_id: u64) -> Option<u64> { with(vm_id, |c| c.tsc_offset) }
+
+/// Guest TSC value now.
+pub fn guest_tsc(vm_id: u64) -> Option<u64> {
+    let now = crate::time::rdtsc();
+    with(vm_id, |c| if c.paused_tsc != 0 { c.paused_tsc.wrapping_add(c.tsc_offset) } else { now.wrapping_add(c.tsc_offset) })
+}
+
+/// Freeze guest time (pause).
+pub fn pause(vm_id: u64) {
+    let now = crate::time::rdtsc();
+    let _ = with(vm_id, |c| if c.paused_tsc == 0 { c.paused_tsc = now; });
+}
+
+/// Resume guest time; the paused interval is absorbed into the offset so the guest TSC does not jump.
+pub fn resume(vm_id: u64) {
+    let now = crate::time::rdtsc();
+    let _ = with(vm_id, |c| {
+        if c.paused_tsc != 0 {
+            c.tsc_offset = c.tsc_offset.wrapping_sub(now.wrapping_sub(c.paused_tsc));
+            c.paused_tsc = 0;
+        }
+    });
+}
+
+/// Program TSC offsetting into the current VMCS.
+pub fn program_vmx(vm_id: u64) -> Result<(), &'static str> {
+    use crate::arch::x86::vm::vmcs;
+    let off = tsc_offset(vm_id).ok_or("vtime: no clock")?;
+    vmcs::vmwrite(vmcs::VMCS_TSC_OFFSET, off)?;
+    let ctl = vmcs::vmread(vmcs::VMCS_PROCBASED_CTLS)? as u32;
+    vmcs::vmwrite(vmcs::VMCS_PROCBASED_CTLS, (ctl | vmcs::PROC_USE_TSC_OFFSETTING) as u64)
+}
+
+/// Program TSC offsetting into `vmcb`.
+///
+/// # Safety
+/// `vmcb` must point to a mapped 4 KiB VMCB that is not running on any CPU.
+pub unsafe fn program_svm(vmcb: *mut u8, vm_id: u64) -> Result<(), &'static str> {
+    let off = tsc_offset(vm_id).ok_or("vtime: no clock")?;
+    core::ptr::write_volatile(vmcb.add(crate::arch::x86::vm::svm::VMCB_TSC_OFFSET) as *mut u64, off);
+    Ok(())
+}
+
+/// Guest port I/O to the PIT. Returns None for ports not handled here.
+pub fn pit_io(vm_id: u64, port: u16, write: Option<u8>) -> Option<u8> {
+    if port != pit::PORT_CH0 && port != pit::PORT_CMD { return None; }
+    let now = crate::time::rdtsc();
+    let hz = crate::time::tsc_hz();
+    with(vm_id, |c| match write {
+        Some(v) => { c.pit.io_write(port, v, now, hz); 0 }
+        None => c.pit.io_read(port, now, hz),
+    })
+}
+
+/// Guest MMIO to the HPET page (offset within the page, 64-bit access).
+pub fn hpet_mmio(vm_id: u64, offset: u32, write: Option<u64>) -> u64 {
+    let now = crate::time::rdtsc();
+    let hz = crate::time::tsc_hz();
+    with(vm_id, |c| match write {
+        Some(v) => { c.hpet.write(offset, v, now, hz); 0 }
+        None => c.hpet.read(offset, now, hz),
+    }).unwrap_or(0)
+}
+
+/// Guest and host microseconds since creation, as seen by the RTC.
+fn rtc_clocks(c: &GuestClock, now: u64, hz: u64) -> (u64, u64) {
+    if hz == 0 { return (0, 0); }
+    let g = if c.paused_tsc != 0 { c.paused_tsc } else { now }.wrapping_add(c.tsc_offset);
+    let us = |t: u64| ((t as u128) * 1_000_000 / hz as u128) as u64;
+    (us(g), us(now.wrapping_sub(c.created_tsc)))
+}
+
+/// Apply an RTC definition; `host_unix` is host UTC now (see `rtc::host_unix`).
+pub fn configure_rtc(vm_id: u64, cfg: rtc::RtcConfig, host_unix: i64) -> bool {
+    let now = crate::time::rdtsc();
+    let hz = crate::time::tsc_hz();
+    with(vm_id, |c| {
+        // Rebase the host reference to creation time so elapsed guest time is not counted twice.
+        let (_, host_us) = rtc_clocks(c, now, hz);
+        c.rtc.configure(cfg, host_unix - (host_us / 1_000_000) as i64);
+    }).is_some()
+}
+
+/// Current RTC definition, including guest adjustments made since creation.
+pub fn rtc_config(vm_id: u64) -> Option<rtc::RtcConfig> { with(vm_id, |c| c.rtc.cfg) }
+
+/// Change the RTC definition of a running VM; the host reference taken at creation is kept.
+pub fn set_rtc_config(vm_id: u64, cfg: rtc::RtcConfig) -> bool { with(vm_id, |c| c.rtc.cfg = cfg).is_some() }
+
+/// Make the guest RTC read UTC time `unix` now (host mode ignores this, like guest writes).
+pub fn set_rtc_time(vm_id: u64, unix: i64) -> bool {
+    let now = crate::time::rdtsc();
+    let hz = crate::time::tsc_hz();
+    with(vm_id, |c| {
+        if c.rtc.cfg.mode == rtc::RtcMode::Host { return false; }
+        let (g, h) = rtc_clocks(c, now, hz);
+        c.rtc.set_unix(unix, g, h);
+        true
+    }).unwrap_or(false)
+}
+
+/// Wall time the guest's RTC reads now.
+pub fn rtc_now(vm_id: u64) -> Option<rtc::DateTime> {
+    let now = crate::time::rdtsc();
+    let hz = crate::time::tsc_hz();
+    with(vm_id, |c| {
+        let (g, h) = rtc_clocks(c, now, hz);
+        rtc::civil_from_unix(c.rtc.now_unix(g, h))
+    })
+}
+
+/// Guest port I/O to the CMOS RTC. Returns None for ports not handled here.
+pub fn rtc_io(vm_id: u64, port: u16, write: Option<u8>) -> Option<u8> {
+    if port != rtc::PORT_INDEX && port != rtc::PORT_DATA { return None; }
+    let now = crate::time::rdtsc();
+    let hz = crate::time::tsc_hz();
+    with(vm_id, |c| {
+        let (g, h) = rtc_clocks(c, now, hz);
+        match write {
+            Some(v) => { c.rtc.io_write(port, v, g, h); 0 }
+            None => c.rtc.io_read(port, g, h),
+        }
+    })
+}
+
+/// Evaluate PIT, HPET and RTC expiries and raise the resulting interrupts on vCPU 0.
+/// Returns the number of interrupts raised.
+pub fn poll(vm_id: u64) -> u32 {
+    let now = crate::time::rdtsc();
+    let hz = crate::time::tsc_hz();
+    let (pit_edges, hpet_fired, irqs, rtc_irq) = match with(vm_id, |c| {
+        if c.paused_tsc != 0 { return (0, 0, [0u8; hpet::NUM_TIMERS], false); }
+        let p = c.pit.poll(now, hz);
+        let h = c.hpet.poll(now, hz);
+        let mut irqs = [0u8; hpet::NUM_TIMERS];
+        for (i, q) in irqs.iter_mut().enumerate() { *q = c.hpet.timer_irq(i); }
+        let (g, hu) = rtc_clocks(c, now, hz);
+        (p, h, irqs, c.rtc.poll(g, hu))
+    }) { Some(v) => v, None => return 0 };
+    let mut raised = 0u32;
+    // Coalesce: a guest that fell behind sees one tick, not a burst.
+    if pit_edges != 0 && crate::hv::vlapic::raise(vm_id, 0, LEGACY_IRQ_VECTOR_BASE, false) { raised += 1; }
+    for (i, irq) in irqs.iter().enumerate() {
+        if (hpet_fired & (1 << i)) != 0 && crate::hv::vlapic::raise(vm_id, 0, LEGACY_IRQ_VECTOR_BASE + irq, false) { raised += 1; }
+    }
+    if rtc_irq && crate::hv::vlapic::raise(vm_id, 0, LEGACY_IRQ_VECTOR_BASE + rtc::RTC_IRQ, false) { raised += 1; }
+    raised
+}
+
+/// Guest/host clock comparison for `vm timeinfo`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeInfo {
+    pub tsc_hz: u64,
+    pub host_tsc: u64,
+    pub guest_tsc: u64,
+    pub tsc_offset: u64,
+    /// Host wall time since VM creation
+    pub host_elapsed_ns: u64,
+    /// Guest time since creation according to its TSC
+    pub guest_tsc_ns: u64,
+    /// Guest time according to the HPET main counter
+    pub hpet_ns: u64,
+    pub hpet_enabled: bool,
+    pub pit_count: u16,
+    pub pit_rate_mhz: u64,
+    pub paused: bool,
+}
+
+impl TimeInfo {
+    /// Guest TSC clock minus host clock (negative while the guest lags, e.g. after a pause).
+    pub fn tsc_skew_ns(&self) -> i64 { self.guest_tsc_ns as i64 - self.host_elapsed_ns as i64 }
+}
+
+pub fn timeinfo(vm_id: u64) -> Option<TimeInfo> {
+    let now = crate::time::rdtsc();
+    let hz = crate::time::tsc_hz();
+    let to_ns = |ticks: u64| if hz == 0 { 0 } else { ((ticks as u128) * 1_000_000_000 / hz as u128) as u64 };
+    with(vm_id, |c| {
+        let g = if c.paused_tsc != 0 { c.paused_tsc } else { now }.wrapping_add(c.tsc_offset);
+        TimeInfo {
+            tsc_hz: hz,
+            host_tsc: now,
+            guest_tsc: g,
+            tsc_offset: c.tsc_offset,
+            host_elapsed_ns: to_ns(now.wrapping_sub(c.created_tsc)),
+            guest_tsc_ns: to_ns(g),
+            hpet_ns: c.hpet.counter(now, hz).saturating_mul(1_000_000_000 / hpet::HPET_HZ),
+            hpet_enabled: c.hpet.enabled(),
+            pit_count: c.pit.count(now, hz),
+            pit_rate_mhz: c.pit.rate_mhz(),
+            paused: c.paused_tsc != 0,
+        }
+    })
+}
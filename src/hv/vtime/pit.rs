@@ -0,0 +1,124 @@
+#![allow(dead_code)]
+
+//! Emulated 8254 PIT, channel 0 only.
+//!
+//! The counter is not stepped; its value is derived from the host TSC each
+//! time the guest reads it or the caller polls for expiry. Modes 0 (interrupt
+//! on terminal count), 2 (rate generator) and 3 (square wave) are modelled;
+//! other modes behave like mode 0.
+
+/// PIT input clock.
+pub const PIT_HZ: u64 = 1_193_182;
+pub const PORT_CH0: u16 = 0x40;
+pub const PORT_CMD: u16 = 0x43;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Pit {
+    /// Operating mode (0..5)
+    pub mode: u8,
+    /// Access mode: 1 = LSB, 2 = MSB, 3 = LSB then MSB
+    pub access: u8,
+    /// Reload value (0 means 65536)
+    pub reload: u32,
+    /// Host TSC when the current count was loaded (0 = not counting)
+    start_tsc: u64,
+    /// Edges already reported to the caller since `start_tsc`
+    fired: u64,
+    write_lsb: Option<u8>,
+    read_msb_next: bool,
+    latched: Option<u16>,
+}
+
+impl Pit {
+    pub const fn new() -> Self {
+        Pit { mode: 0, access: 3, reload: 0x1_0000, start_tsc: 0, fired: 0, write_lsb: None, read_msb_next: false, latched: None }
+    }
+
+    #[inline(always)]
+    fn ticks_since(&self, now_tsc: u64, tsc_hz: u64) -> u64 {
+        if self.start_tsc == 0 || tsc_hz == 0 { return 0; }
+        ((now_tsc.wrapping_sub(self.start_tsc) as u128) * (PIT_HZ as u128) / (tsc_hz as u128)) as u64
+    }
+
+    /// Current counter value.
+    pub fn count(&self, now_tsc: u64, tsc_hz: u64) -> u16 {
+        if self.start_tsc == 0 { return self.reload as u16; }
+        let t = self.ticks_since(now_tsc, tsc_hz);
+        let r = self.reload as u64;
+        match self.mode {
+            2 => (r - (t % r)) as u16,
+            // Square wave decrements by two and reloads every half period.
+            3 => { let half = (r / 2).max(1); ((half - (t % half)) * 2) as u16 }
+            _ => if t >= r { 0 } else { (r - t) as u16 },
+        }
+    }
+
+    fn load(&mut self, v: u32, now_tsc: u64) {
+        self.reload = if v == 0 { 0x1_0000 } else { v };
+        self.start_tsc = now_tsc.max(1);
+        self.fired = 0;
+    }
+
+    /// Guest OUT to port 0x40 or 0x43.
+    pub fn io_write(&mut self, port: u16, val: u8, now_tsc: u64, tsc_hz: u64) {
+        match port {
+            PORT_CMD => {
+                // Only channel 0 is implemented; channel select must be 00.
+                if (val >> 6) != 0 { return; }
+                let access = (val >> 4) & 3;
+                if access == 0 {
+                    // Counter latch command
+                    self.latched = Some(self.count(now_tsc, tsc_hz));
+                    self.read_msb_next = false;
+                    return;
+                }
+                self.access = access;
+                self.mode = (val >> 1) & 7;
+                if self.mode > 5 { self.mode -= 4; }
+                self.write_lsb = None;
+                self.read_msb_next = false;
+                self.start_tsc = 0;
+            }
+            PORT_CH0 => match self.access {
+                1 => self.load(val as u32, now_tsc),
+                2 => self.load((val as u32) << 8, now_tsc),
+                _ => match self.write_lsb.take() {
+                    None => self.write_lsb = Some(val),
+                    Some(lsb) => self.load(((val as u32) << 8) | lsb as u32, now_tsc),
+                },
+            },
+            _ => {}
+        }
+    }
+
+    /// Guest IN from port 0x40.
+    pub fn io_read(&mut self, port: u16, now_tsc: u64, tsc_hz: u64) -> u8 {
+        if port != PORT_CH0 { return 0xFF; }
+        let v = match self.latched { Some(l) => l, None => self.count(now_tsc, tsc_hz) };
+        let out = match self.access {
+            1 => v as u8,
+            2 => (v >> 8) as u8,
+            _ => { let b = if self.read_msb_next { (v >> 8) as u8 } else { v as u8 }; self.read_msb_next = !self.read_msb_next; b }
+        };
+        // A latch is consumed once fully read.
+        if self.access != 3 || !self.read_msb_next { self.latched = None; }
+        out
+    }
+
+    /// Number of IRQ0 edges produced since the last call.
+    pub fn poll(&mut self, now_tsc: u64, tsc_hz: u64) -> u64 {
+        if self.start_tsc == 0 { return 0; }
+        let t = self.ticks_since(now_tsc, tsc_hz);
+        let r = self.reload as u64;
+        let total = match self.mode { 2 | 3 => t / r, _ => if t >= r { 1 } else { 0 } };
+        let new = total.saturating_sub(self.fired);
+        self.fired = total;
+        new
+    }
+
+    /// Programmed IRQ0 rate in mHz (0 when stopped or one-shot).
+    pub fn rate_mhz(&self) -> u64 {
+        if self.start_tsc == 0 || !matches!(self.mode, 2 | 3) { return 0; }
+        PIT_HZ * 1000 / self.reload as u64
+    }
+}
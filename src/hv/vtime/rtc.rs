@@ -0,0 +1,389 @@
+#![allow(dead_code)]
+
+//! Emulated MC146818 CMOS RTC (ports 0x70/0x71).
+//!
+//! The time registers are not stored; they are computed from a base Unix
+//! time plus elapsed guest time each time the guest reads them. A guest that
+//! sets the clock only moves `RtcConfig::offset_secs`, so the adjustment
+//! survives a save/restore of the VM definition. In `Host` mode the clock
+//! follows host wall time (captured from UEFI at creation and advanced by the
+//! host TSC) and guest writes to the time registers are discarded.
+
+pub const PORT_INDEX: u16 = 0x70;
+pub const PORT_DATA: u16 = 0x71;
+/// ISA IRQ line of the RTC
+pub const RTC_IRQ: u8 = 8;
+
+pub const REG_SEC: u8 = 0x00;
+pub const REG_SEC_ALARM: u8 = 0x01;
+pub const REG_MIN: u8 = 0x02;
+pub const REG_MIN_ALARM: u8 = 0x03;
+pub const REG_HOUR: u8 = 0x04;
+pub const REG_HOUR_ALARM: u8 = 0x05;
+pub const REG_DOW: u8 = 0x06;
+pub const REG_DAY: u8 = 0x07;
+pub const REG_MONTH: u8 = 0x08;
+pub const REG_YEAR: u8 = 0x09;
+pub const REG_A: u8 = 0x0A;
+pub const REG_B: u8 = 0x0B;
+pub const REG_C: u8 = 0x0C;
+pub const REG_D: u8 = 0x0D;
+pub const REG_CENTURY: u8 = 0x32;
+
+pub const A_UIP: u8 = 0x80;
+pub const B_SET: u8 = 0x80;
+pub const B_PIE: u8 = 0x40;
+pub const B_AIE: u8 = 0x20;
+pub const B_UIE: u8 = 0x10;
+pub const B_BINARY: u8 = 0x04;
+pub const B_24H: u8 = 0x02;
+pub const C_IRQF: u8 = 0x80;
+pub const C_PF: u8 = 0x40;
+pub const C_AF: u8 = 0x20;
+pub const C_UF: u8 = 0x10;
+pub const D_VRT: u8 = 0x80;
+
+/// UIP is raised for this long before each second boundary.
+const UIP_WINDOW_US: u64 = 244;
+
+/// Where the guest's wall clock comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RtcMode {
+    /// Runs on guest time: stops while the VM is paused, guest may set it
+    #[default]
+    Emulated,
+    /// Follows host UEFI time; guest writes are ignored
+    Host,
+}
+
+/// Per-VM RTC settings, part of the VM definition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct RtcConfig {
+    pub mode: RtcMode,
+    /// Unix time the guest clock starts at; 0 = host time at VM creation
+    pub base_unix: i64,
+    /// Seconds added to the clock (guest adjustments accumulate here)
+    pub offset_secs: i64,
+    /// Present local time instead of UTC (Windows guests expect this)
+    pub localtime: bool,
+    /// Local time offset from UTC in minutes, used when `localtime` is set
+    pub tz_minutes: i16,
+}
+
+impl RtcConfig {
+    /// Fixed-size little-endian encoding used for persistence.
+    pub const ENCODED_LEN: usize = 20;
+
+    pub fn encode(&self, out: &mut [u8; Self::ENCODED_LEN]) {
+        out[0] = match self.mode { RtcMode::Emulated => 0, RtcMode::Host => 1 };
+        out[1] = self.localtime as u8;
+        out[2..4].copy_from_slice(&self.tz_minutes.to_le_bytes());
+        out[4..12].copy_from_slice(&self.base_unix.to_le_bytes());
+        out[12..20].copy_from_slice(&self.offset_secs.to_le_bytes());
+    }
+
+    pub fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() < Self::ENCODED_LEN { return None; }
+        let mode = match b[0] { 0 => RtcMode::Emulated, 1 => RtcMode::Host, _ => return None };
+        let mut tz = [0u8; 2]; tz.copy_from_slice(&b[2..4]);
+        let mut base = [0u8; 8]; base.copy_from_slice(&b[4..12]);
+        let mut off = [0u8; 8]; off.copy_from_slice(&b[12..20]);
+        Some(RtcConfig { mode, localtime: b[1] != 0, tz_minutes: i16::from_le_bytes(tz), base_unix: i64::from_le_bytes(base), offset_secs: i64::from_le_bytes(off) })
+    }
+}
+
+/// Broken-down calendar time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 1 = Sunday .. 7 = Saturday (MC146818 convention)
+    pub dow: u8,
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+pub fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Calendar time for a Unix timestamp (clamped to years 0..=9999).
+pub fn civil_from_unix(t: i64) -> DateTime {
+    let days = t.div_euclid(86_400);
+    let secs = t.rem_euclid(86_400);
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    DateTime {
+        year: year.clamp(0, 9999) as u16,
+        month,
+        day,
+        hour: (secs / 3600) as u8,
+        minute: ((secs / 60) % 60) as u8,
+        second: (secs % 60) as u8,
+        dow: ((days + 4).rem_euclid(7) + 1) as u8,
+    }
+}
+
+pub fn unix_from_civil(dt: &DateTime) -> i64 {
+    days_from_civil(dt.year as i64, dt.month as u32, dt.day as u32) * 86_400
+        + dt.hour as i64 * 3600 + dt.minute as i64 * 60 + dt.second as i64
+}
+
+/// Current host UTC time from UEFI, as Unix seconds (0 if unavailable).
+pub fn host_unix(system_table: &uefi::table::SystemTable<uefi::prelude::Boot>) -> i64 {
+    let t = match system_table.runtime_services().get_time() { Ok(t) => t, Err(_) => return 0 };
+    let dt = DateTime { year: t.year(), month: t.month(), day: t.day(), hour: t.hour(), minute: t.minute(), second: t.second(), dow: 0 };
+    // UEFI reports local time with its offset from UTC (Localtime = UTC + TimeZone).
+    unix_from_civil(&dt) - t.time_zone().map(|m| m as i64 * 60).unwrap_or(0)
+}
+
+// ---- Persistence ----
+
+const VAR_NS: uefi::table::runtime::VariableVendor = uefi::table::runtime::VariableVendor::GLOBAL_VARIABLE;
+const MAX_SAVED: usize = 16;
+const RECORD_LEN: usize = 8 + RtcConfig::ENCODED_LEN;
+
+/// Store `cfg` as the RTC definition of `vm_id`, replacing any earlier record.
+pub fn save(system_table: &uefi::table::SystemTable<uefi::prelude::Boot>, vm_id: u64, cfg: &RtcConfig) -> Result<(), &'static str> {
+    let rs = system_table.runtime_services();
+    let mut buf = [0u8; MAX_SAVED * RECORD_LEN];
+    let mut n = match rs.get_variable(uefi::cstr16!("ZerovisorVmRtc"), &VAR_NS, &mut buf) {
+        Ok((data, _)) => data.len() - data.len() % RECORD_LEN,
+        Err(_) => 0,
+    };
+    let mut pos = n;
+    let mut off = 0;
+    while off < n {
+        if buf[off..off + 8] == vm_id.to_le_bytes() { pos = off; break; }
+        off += RECORD_LEN;
+    }
+    if pos == n {
+        if n + RECORD_LEN > buf.len() { return Err("rtc: no room for another VM definition"); }
+        n += RECORD_LEN;
+    }
+    let mut enc = [0u8; RtcConfig::ENCODED_LEN];
+    cfg.encode(&mut enc);
+    buf[pos..pos + 8].copy_from_slice(&vm_id.to_le_bytes());
+    buf[pos + 8..pos + RECORD_LEN].copy_from_slice(&enc);
+    let attrs = uefi::table::runtime::VariableAttributes::BOOTSERVICE_ACCESS | uefi::table::runtime::VariableAttributes::NON_VOLATILE;
+    rs.set_variable(uefi::cstr16!("ZerovisorVmRtc"), &VAR_NS, attrs, &buf[..n]).map_err(|_| "rtc: set_variable failed")
+}
+
+/// Saved RTC definition of `vm_id`, if any.
+pub fn load(system_table: &uefi::table::SystemTable<uefi::prelude::Boot>, vm_id: u64) -> Option<RtcConfig> {
+    let rs = system_table.runtime_services();
+    let mut buf = [0u8; MAX_SAVED * RECORD_LEN];
+    let (data, _) = rs.get_variable(uefi::cstr16!("ZerovisorVmRtc"), &VAR_NS, &mut buf).ok()?;
+    data.chunks_exact(RECORD_LEN).find(|r| r[..8] == vm_id.to_le_bytes()).and_then(|r| RtcConfig::decode(&r[8..]))
+}
+
+#[inline(always)]
+fn to_bcd(v: u8) -> u8 { ((v / 10) << 4) | (v % 10) }
+#[inline(always)]
+fn from_bcd(v: u8) -> u8 { (v >> 4) * 10 + (v & 0x0F) }
+
+#[derive(Clone, Copy)]
+pub struct Rtc {
+    pub cfg: RtcConfig,
+    /// Host UTC at VM creation, from UEFI
+    host_unix_at_create: i64,
+    cmos: [u8; 128],
+    index: u8,
+    /// Time latched while the guest holds B.SET
+    set_latch: Option<DateTime>,
+    /// Last whole second seen by `poll` (update-ended detection)
+    last_sec: i64,
+    /// Periodic ticks already delivered
+    periodic_fired: u64,
+}
+
+impl Rtc {
+    pub const fn new() -> Self {
+        let mut cmos = [0u8; 128];
+        cmos[REG_A as usize] = 0x26; // 32.768 kHz time base, 1024 Hz periodic rate
+        cmos[REG_B as usize] = B_24H;
+        cmos[REG_D as usize] = D_VRT;
+        Rtc { cfg: RtcConfig { mode: RtcMode::Emulated, base_unix: 0, offset_secs: 0, localtime: false, tz_minutes: 0 }, host_unix_at_create: 0, cmos, index: 0, set_latch: None, last_sec: 0, periodic_fired: 0 }
+    }
+
+    pub fn configure(&mut self, cfg: RtcConfig, host_unix: i64) {
+        self.cfg = cfg;
+        self.host_unix_at_create = host_unix;
+        self.last_sec = 0;
+        self.periodic_fired = 0;
+    }
+
+    fn zone_secs(&self) -> i64 { if self.cfg.localtime { self.cfg.tz_minutes as i64 * 60 } else { 0 } }
+
+    /// Clock value in microseconds, in the presented zone. `guest_us` is guest
+    /// time since creation, `host_us` host time since creation.
+    fn now_us(&self, guest_us: u64, host_us: u64) -> i64 {
+        let (base, elapsed) = match self.cfg.mode {
+            RtcMode::Emulated => (if self.cfg.base_unix != 0 { self.cfg.base_unix } else { self.host_unix_at_create }, guest_us),
+            RtcMode::Host => (self.host_unix_at_create, host_us),
+        };
+        (base + self.cfg.offset_secs + self.zone_secs()) * 1_000_000 + elapsed as i64
+    }
+
+    /// Presented wall time in whole seconds.
+    pub fn now_unix(&self, guest_us: u64, host_us: u64) -> i64 { self.now_us(guest_us, host_us).div_euclid(1_000_000) }
+
+    fn encode(&self, v: u8) -> u8 { if (self.cmos[REG_B as usize] & B_BINARY) != 0 { v } else { to_bcd(v) } }
+    fn decode(&self, v: u8) -> u8 { if (self.cmos[REG_B as usize] & B_BINARY) != 0 { v } else { from_bcd(v) } }
+
+    fn encode_hour(&self, h: u8) -> u8 {
+        if (self.cmos[REG_B as usize] & B_24H) != 0 { return self.encode(h); }
+        let h12 = match h % 12 { 0 => 12, x => x };
+        self.encode(h12) | if h >= 12 { 0x80 } else { 0 }
+    }
+
+    fn decode_hour(&self, v: u8) -> u8 {
+        if (self.cmos[REG_B as usize] & B_24H) != 0 { return self.decode(v); }
+        let h = self.decode(v & 0x7F) % 12;
+        if (v & 0x80) != 0 { h + 12 } else { h }
+    }
+
+    fn read_reg(&mut self, reg: u8, guest_us: u64, host_us: u64) -> u8 {
+        let now = self.now_us(guest_us, host_us);
+        let dt = self.set_latch.unwrap_or_else(|| civil_from_unix(now.div_euclid(1_000_000)));
+        match reg {
+            REG_SEC => self.encode(dt.second),
+            REG_MIN => self.encode(dt.minute),
+            REG_HOUR => self.encode_hour(dt.hour),
+            REG_DOW => self.encode(dt.dow),
+            REG_DAY => self.encode(dt.day),
+            REG_MONTH => self.encode(dt.month),
+            REG_YEAR => self.encode((dt.year % 100) as u8),
+            REG_CENTURY => self.encode((dt.year / 100) as u8),
+            REG_A => {
+                let frac = now.rem_euclid(1_000_000) as u64;
+                let uip = self.set_latch.is_none() && frac >= 1_000_000 - UIP_WINDOW_US;
+                (self.cmos[REG_A as usize] & 0x7F) | if uip { A_UIP } else { 0 }
+            }
+            REG_C => {
+                // Reading C acknowledges all pending flags.
+                let v = self.cmos[REG_C as usize];
+                self.cmos[REG_C as usize] = 0;
+                v
+            }
+            r => self.cmos[r as usize],
+        }
+    }
+
+    fn write_reg(&mut self, reg: u8, v: u8, guest_us: u64, host_us: u64) {
+        let host_mode = self.cfg.mode == RtcMode::Host;
+        match reg {
+            REG_SEC | REG_MIN | REG_HOUR | REG_DOW | REG_DAY | REG_MONTH | REG_YEAR | REG_CENTURY => {
+                if host_mode { return; }
+                let mut dt = self.set_latch.unwrap_or_else(|| civil_from_unix(self.now_unix(guest_us, host_us)));
+                match reg {
+                    REG_SEC => dt.second = self.decode(v).min(59),
+                    REG_MIN => dt.minute = self.decode(v).min(59),
+                    REG_HOUR => dt.hour = self.decode_hour(v).min(23),
+                    REG_DOW => dt.dow = self.decode(v),
+                    REG_DAY => dt.day = self.decode(v).clamp(1, 31),
+                    REG_MONTH => dt.month = self.decode(v).clamp(1, 12),
+                    REG_YEAR => dt.year = (dt.year / 100) * 100 + self.decode(v) as u16 % 100,
+                    _ => dt.year = (self.decode(v) as u16) * 100 + dt.year % 100,
+                }
+                if self.set_latch.is_some() { self.set_latch = Some(dt); } else { self.commit(&dt, guest_us, host_us); }
+            }
+            REG_B => {
+                let was_set = (self.cmos[REG_B as usize] & B_SET) != 0;
+                let set = (v & B_SET) != 0;
+                if set && !was_set && !host_mode { self.set_latch = Some(civil_from_unix(self.now_unix(guest_us, host_us))); }
+                self.cmos[REG_B as usize] = v;
+                if !set && was_set {
+                    if let Some(dt) = self.set_latch.take() { self.commit(&dt, guest_us, host_us); }
+                }
+            }
+            REG_A => {
+                self.cmos[REG_A as usize] = v & 0x7F;
+                self.periodic_fired = 0;
+            }
+            REG_C | REG_D => {}
+            r => self.cmos[r as usize] = v,
+        }
+    }
+
+    /// Move the clock so it reads `dt` now.
+    fn commit(&mut self, dt: &DateTime, guest_us: u64, host_us: u64) {
+        let delta = unix_from_civil(dt) - self.now_unix(guest_us, host_us);
+        self.cfg.offset_secs += delta;
+        self.last_sec += delta;
+    }
+
+    /// Set the clock so it reads the UTC time `unix` now (converted to the presented zone).
+    pub fn set_unix(&mut self, unix: i64, guest_us: u64, host_us: u64) {
+        let dt = civil_from_unix(unix + self.zone_secs());
+        self.commit(&dt, guest_us, host_us);
+    }
+
+    pub fn io_read(&mut self, port: u16, guest_us: u64, host_us: u64) -> u8 {
+        match port {
+            PORT_INDEX => self.index,
+            PORT_DATA => self.read_reg(self.index, guest_us, host_us),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn io_write(&mut self, port: u16, v: u8, guest_us: u64, host_us: u64) {
+        match port {
+            // Bit 7 of the index port is the NMI mask on PC hardware; not modelled.
+            PORT_INDEX => self.index = v & 0x7F,
+            PORT_DATA => self.write_reg(self.index, v, guest_us, host_us),
+            _ => {}
+        }
+    }
+
+    fn periodic_hz(&self) -> u64 {
+        match self.cmos[REG_A as usize] & 0x0F { 0 => 0, r => 32_768 >> (r - 1) }
+    }
+
+    fn alarm_matches(&self, dt: &DateTime) -> bool {
+        // Alarm bytes with the two top bits set are "don't care".
+        let m = |reg: u8, cur: u8, hour: bool| {
+            let a = self.cmos[reg as usize];
+            (a & 0xC0) == 0xC0 || (if hour { self.decode_hour(a) } else { self.decode(a) }) == cur
+        };
+        m(REG_SEC_ALARM, dt.second, false) && m(REG_MIN_ALARM, dt.minute, false) && m(REG_HOUR_ALARM, dt.hour, true)
+    }
+
+    /// Update flags for elapsed time; returns true if IRQ 8 should be raised.
+    pub fn poll(&mut self, guest_us: u64, host_us: u64) -> bool {
+        if self.set_latch.is_some() { return false; }
+        let b = self.cmos[REG_B as usize];
+        let mut flags = 0u8;
+        let sec = self.now_unix(guest_us, host_us);
+        if self.last_sec != 0 && sec != self.last_sec {
+            flags |= C_UF;
+            if self.alarm_matches(&civil_from_unix(sec)) { flags |= C_AF; }
+        }
+        self.last_sec = sec;
+        let hz = self.periodic_hz();
+        if hz != 0 {
+            let ticks = ((guest_us as u128) * (hz as u128) / 1_000_000) as u64;
+            if ticks > self.periodic_fired { flags |= C_PF; self.periodic_fired = ticks; }
+        }
+        if flags == 0 { return false; }
+        let enabled = flags & (b & (B_PIE | B_AIE | B_UIE));
+        self.cmos[REG_C as usize] |= flags | if enabled != 0 { C_IRQF } else { 0 };
+        enabled != 0
+    }
+}
@@ -0,0 +1,931 @@
+#![allow(dead_code)]
+
+//! WebAssembly decoder and interpreter.
+//!
+//! Covers the MVP binary format restricted to integers: i32/i64 values,
+//! structured control flow, direct and table calls, one linear memory and
+//! its bulk copy/fill. Floating point, SIMD, reference types beyond the
+//! function table, multi-value blocks and imports other than host functions
+//! are refused when the module is decoded.
+//!
+//! Decoding checks structure and indexes, not operand types. A mistyped
+//! module traps or computes garbage, but every stack, local, global, table
+//! and memory access is bounds-checked, so it cannot reach outside its own
+//! state. Execution is resumable: `Machine::run` stops when its fuel (one
+//! unit per instruction) is spent or a host call asks it to, and continues
+//! from the same instruction on the next call.
+
+use alloc::vec::Vec;
+
+/// Linear memory page size
+pub const PAGE: usize = 65536;
+/// Pages one memory can address
+pub const MAX_PAGES: u32 = 65536;
+const MAX_FUNCS: usize = 4096;
+const MAX_TYPES: usize = 1024;
+const MAX_PARAMS: usize = 16;
+const MAX_GLOBALS: usize = 256;
+const MAX_TABLE: u32 = 4096;
+const MAX_BR_TABLE: u32 = 4096;
+/// Locals of one function, parameters included
+const MAX_LOCALS: usize = 1024;
+/// Local slots across the call stack
+const MAX_LOCAL_SLOTS: usize = 16384;
+const MAX_VALUES: usize = 16384;
+const MAX_LABELS: usize = 4096;
+const MAX_FRAMES: usize = 512;
+
+const I32: u8 = 0x7F;
+const I64: u8 = 0x7E;
+const NULL_FUNC: u32 = u32::MAX;
+
+const TRUNCATED: &str = "wasm: truncated module";
+const STACK: &str = "wasm: operand stack underflow";
+const OOB: &str = "wasm: out of bounds memory access";
+const FLOAT: &str = "wasm: floating point is not supported";
+const UNSUPPORTED: &str = "wasm: unsupported instruction";
+
+/// Host functions a module may import.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Import {
+    /// `zv.write(ptr, len)`: append bytes to the module's output
+    Write,
+    /// `zv.exit(code)`
+    Exit,
+    /// `zv.clock_ms() -> i64`: milliseconds since boot
+    ClockMs,
+    /// `zv.yield()`: give up the rest of the slice
+    Yield,
+    /// `wasi_snapshot_preview1.fd_write(fd, iovs, iovs_len, nwritten) -> errno`
+    FdWrite,
+    /// `wasi_snapshot_preview1.proc_exit(code)`
+    ProcExit,
+}
+
+impl Import {
+    fn resolve(module: &[u8], name: &[u8]) -> Option<Import> {
+        match (module, name) {
+            (b"zv", b"write") => Some(Import::Write),
+            (b"zv", b"exit") => Some(Import::Exit),
+            (b"zv", b"clock_ms") => Some(Import::ClockMs),
+            (b"zv", b"yield") => Some(Import::Yield),
+            (b"wasi_snapshot_preview1", b"fd_write") => Some(Import::FdWrite),
+            (b"wasi_snapshot_preview1", b"proc_exit") => Some(Import::ProcExit),
+            _ => None,
+        }
+    }
+
+    /// Parameter and result types the import must be declared with.
+    fn signature(self) -> (&'static [u8], &'static [u8]) {
+        match self {
+            Import::Write => (&[I32, I32], &[]),
+            Import::Exit | Import::ProcExit => (&[I32], &[]),
+            Import::ClockMs => (&[], &[I64]),
+            Import::Yield => (&[], &[]),
+            Import::FdWrite => (&[I32, I32, I32, I32], &[I32]),
+        }
+    }
+}
+
+/// Why `Machine::run` returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// Fuel ran out; call `run` again to continue
+    Fuel,
+    /// The module yielded; call `run` again to continue
+    Yield,
+    /// The entry function returned
+    Done,
+    /// The module called exit
+    Exit(i32),
+    Trap(&'static str),
+}
+
+/// Host side of the imports.
+pub trait Host {
+    /// Run import `f` with `args` (as many as its signature has). `Err`
+    /// stops the machine after the call.
+    fn call(&mut self, f: Import, args: &[u64], mem: &mut Memory) -> Result<Option<u64>, Exit>;
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct FuncType { params: Vec<u8>, results: Vec<u8> }
+
+#[derive(Clone, Copy)]
+enum Body {
+    Host(Import),
+    /// Instructions at `start..end` of the module bytes
+    Code { start: usize, end: usize, locals: usize },
+}
+
+#[derive(Clone, Copy)]
+struct Func { ty: usize, body: Body }
+
+#[derive(Clone, Copy)]
+struct Global { mutable: bool, init: u64 }
+
+#[derive(Clone, Copy)]
+struct Data { offset: u32, start: usize, len: usize }
+
+/// Where a `block` or `if` at `pc` continues: after its `else` (0 = none)
+/// and at its `end`.
+#[derive(Clone, Copy)]
+struct Block { pc: usize, els: usize, end: usize }
+
+/// A decoded module. Keeps a copy of the binary; function bodies are
+/// executed from it in place.
+pub struct Module {
+    bytes: Vec<u8>,
+    types: Vec<FuncType>,
+    funcs: Vec<Func>,
+    imports: usize,
+    table: Vec<u32>,
+    memory: bool,
+    mem_min: u32,
+    mem_max: u32,
+    globals: Vec<Global>,
+    data: Vec<Data>,
+    entry: Option<usize>,
+    start: Option<usize>,
+    blocks: Vec<Block>,
+}
+
+struct Reader<'a> { b: &'a [u8], pos: usize }
+
+impl<'a> Reader<'a> {
+    fn at(b: &'a [u8], pos: usize) -> Self { Reader { b, pos } }
+
+    fn byte(&mut self) -> Result<u8, &'static str> {
+        let v = *self.b.get(self.pos).ok_or(TRUNCATED)?;
+        self.pos += 1;
+        Ok(v)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        let end = self.pos.checked_add(n).filter(|&e| e <= self.b.len()).ok_or(TRUNCATED)?;
+        let s = &self.b[self.pos..end];
+        self.pos = end;
+        Ok(s)
+    }
+
+    fn leb(&mut self, bits: u32, signed: bool) -> Result<u64, &'static str> {
+        let mut v = 0u64;
+        let mut shift = 0u32;
+        loop {
+            let b = self.byte()?;
+            if shift >= bits { return Err("wasm: integer too long"); }
+            v |= ((b & 0x7F) as u64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                if signed && shift < 64 && b & 0x40 != 0 { v |= !0u64 << shift; }
+                return Ok(v);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        let v = self.leb(32, false)?;
+        u32::try_from(v).map_err(|_| "wasm: integer too large")
+    }
+
+    fn i32(&mut self) -> Result<u64, &'static str> { Ok(self.leb(32, true)? as u32 as u64) }
+
+    fn i64(&mut self) -> Result<u64, &'static str> { self.leb(64, true) }
+
+    fn name(&mut self) -> Result<&'a [u8], &'static str> {
+        let n = self.u32()? as usize;
+        self.bytes(n)
+    }
+}
+
+fn valtype(b: u8) -> Result<u8, &'static str> {
+    match b {
+        I32 | I64 => Ok(b),
+        0x7D | 0x7C => Err(FLOAT),
+        _ => Err("wasm: unsupported value type"),
+    }
+}
+
+/// Values a block leaves behind.
+fn block_arity(r: &mut Reader) -> Result<u8, &'static str> {
+    match r.byte()? {
+        0x40 => Ok(0),
+        I32 | I64 => Ok(1),
+        0x7D | 0x7C => Err(FLOAT),
+        _ => Err("wasm: multi-value blocks are not supported"),
+    }
+}
+
+fn limits(r: &mut Reader, cap: u32) -> Result<(u32, u32), &'static str> {
+    let (min, max) = match r.byte()? {
+        0 => (r.u32()?, cap),
+        1 => (r.u32()?, r.u32()?),
+        _ => return Err("wasm: unsupported limits"),
+    };
+    if min > max || min > cap { return Err("wasm: limits out of range"); }
+    Ok((min, max.min(cap)))
+}
+
+impl Module {
+    /// Decode and check a module binary.
+    pub fn decode(bin: &[u8]) -> Result<Module, &'static str> {
+        let mut r = Reader::at(bin, 0);
+        if r.bytes(4)? != b"\0asm" { return Err("wasm: bad magic"); }
+        if r.bytes(4)? != [1, 0, 0, 0] { return Err("wasm: unsupported version"); }
+        let mut m = Module {
+            bytes: Vec::new(), types: Vec::new(), funcs: Vec::new(), imports: 0, table: Vec::new(),
+            memory: false, mem_min: 0, mem_max: 0, globals: Vec::new(), data: Vec::new(),
+            entry: None, start: None, blocks: Vec::new(),
+        };
+        let mut bodies = 0usize;
+        while r.pos < bin.len() {
+            let id = r.byte()?;
+            let len = r.u32()? as usize;
+            let sec = r.bytes(len)?;
+            let base = r.pos - len;
+            let mut s = Reader::at(sec, 0);
+            match id {
+                0 | 12 => continue,
+                1 => m.types_section(&mut s)?,
+                2 => m.import_section(&mut s)?,
+                3 => {
+                    for _ in 0..s.u32()? {
+                        let ty = s.u32()? as usize;
+                        if ty >= m.types.len() { return Err("wasm: type index out of range"); }
+                        if m.funcs.len() >= MAX_FUNCS { return Err("wasm: too many functions"); }
+                        m.funcs.push(Func { ty, body: Body::Code { start: 0, end: 0, locals: 0 } });
+                    }
+                }
+                4 => {
+                    if s.u32()? != 1 || s.byte()? != 0x70 { return Err("wasm: one funcref table supported"); }
+                    let (min, _) = limits(&mut s, MAX_TABLE)?;
+                    m.table = alloc::vec![NULL_FUNC; min as usize];
+                }
+                5 => {
+                    if s.u32()? != 1 { return Err("wasm: one memory supported"); }
+                    (m.mem_min, m.mem_max) = limits(&mut s, MAX_PAGES)?;
+                    m.memory = true;
+                }
+                6 => {
+                    for _ in 0..s.u32()? {
+                        let ty = valtype(s.byte()?)?;
+                        let mutable = match s.byte()? { 0 => false, 1 => true, _ => return Err("wasm: bad global mutability") };
+                        let v = m.const_expr(&mut s)?;
+                        if m.globals.len() >= MAX_GLOBALS { return Err("wasm: too many globals"); }
+                        m.globals.push(Global { mutable, init: if ty == I32 { v as u32 as u64 } else { v } });
+                    }
+                }
+                7 => {
+                    for _ in 0..s.u32()? {
+                        let name = s.name()?;
+                        let kind = s.byte()?;
+                        let idx = s.u32()? as usize;
+                        if kind == 0 && name == b"_start" { m.entry = Some(idx); }
+                    }
+                }
+                8 => m.start = Some(s.u32()? as usize),
+                9 => {
+                    for _ in 0..s.u32()? {
+                        if s.u32()? != 0 { return Err("wasm: unsupported element segment"); }
+                        let off = m.const_expr(&mut s)? as u32 as usize;
+                        let n = s.u32()? as usize;
+                        if off.checked_add(n).map_or(true, |e| e > m.table.len()) { return Err("wasm: element segment out of bounds"); }
+                        for k in 0..n {
+                            let f = s.u32()?;
+                            if f as usize >= m.funcs.len() { return Err("wasm: function index out of range"); }
+                            m.table[off + k] = f;
+                        }
+                    }
+                }
+                10 => {
+                    let n = s.u32()? as usize;
+                    if n != m.funcs.len() - m.imports { return Err("wasm: function and code counts differ"); }
+                    for k in 0..n {
+                        let size = s.u32()? as usize;
+                        let body_at = s.pos;
+                        s.bytes(size)?;
+                        m.code_entry(bin, base + body_at, size, m.imports + k)?;
+                    }
+                    bodies = n;
+                }
+                11 => {
+                    for _ in 0..s.u32()? {
+                        match s.u32()? {
+                            0 => {}
+                            2 if s.u32()? == 0 => {}
+                            _ => return Err("wasm: passive data segments are not supported"),
+                        }
+                        let offset = m.const_expr(&mut s)? as u32;
+                        let len = s.u32()? as usize;
+                        let start = base + s.pos;
+                        s.bytes(len)?;
+                        m.data.push(Data { offset, start, len });
+                    }
+                }
+                _ => return Err("wasm: unknown section"),
+            }
+            if s.pos != sec.len() { return Err("wasm: section size mismatch"); }
+        }
+        if bodies != m.funcs.len() - m.imports { return Err("wasm: functions without code"); }
+        if !m.data.is_empty() && !m.memory { return Err("wasm: data without a memory"); }
+        for f in [m.entry, m.start].into_iter().flatten() {
+            let func = m.funcs.get(f).ok_or("wasm: function index out of range")?;
+            if !matches!(func.body, Body::Code { .. }) { return Err("wasm: entry point is an import"); }
+            let ty = &m.types[func.ty];
+            if !ty.params.is_empty() || !ty.results.is_empty() { return Err("wasm: entry point must take and return nothing"); }
+        }
+        if m.entry.is_none() && m.start.is_none() { return Err("wasm: no _start export or start function"); }
+        m.bytes = Vec::from(bin);
+        Ok(m)
+    }
+
+    fn types_section(&mut self, s: &mut Reader) -> Result<(), &'static str> {
+        for _ in 0..s.u32()? {
+            if s.byte()? != 0x60 { return Err("wasm: bad function type"); }
+            let mut ty = FuncType { params: Vec::new(), results: Vec::new() };
+            for _ in 0..s.u32()? { ty.params.push(valtype(s.byte()?)?); }
+            for _ in 0..s.u32()? { ty.results.push(valtype(s.byte()?)?); }
+            if ty.params.len() > MAX_PARAMS { return Err("wasm: too many parameters"); }
+            if ty.results.len() > 1 { return Err("wasm: multi-value results are not supported"); }
+            if self.types.len() >= MAX_TYPES { return Err("wasm: too many types"); }
+            self.types.push(ty);
+        }
+        Ok(())
+    }
+
+    fn import_section(&mut self, s: &mut Reader) -> Result<(), &'static str> {
+        if !self.funcs.is_empty() { return Err("wasm: imports after functions"); }
+        for _ in 0..s.u32()? {
+            let module = s.name()?;
+            let name = s.name()?;
+            if s.byte()? != 0 { return Err("wasm: only function imports are supported"); }
+            let ty = s.u32()? as usize;
+            let f = Import::resolve(module, name).ok_or("wasm: unsupported import")?;
+            let (params, results) = f.signature();
+            let t = self.types.get(ty).ok_or("wasm: type index out of range")?;
+            if t.params != params || t.results != results { return Err("wasm: import signature mismatch"); }
+            self.funcs.push(Func { ty, body: Body::Host(f) });
+            self.imports += 1;
+        }
+        Ok(())
+    }
+
+    /// `i32.const`, `i64.const` or `global.get` of an earlier global.
+    fn const_expr(&self, s: &mut Reader) -> Result<u64, &'static str> {
+        let v = match s.byte()? {
+            0x41 => s.i32()?,
+            0x42 => s.i64()?,
+            0x23 => self.globals.get(s.u32()? as usize).ok_or("wasm: global index out of range")?.init,
+            0x43 | 0x44 => return Err(FLOAT),
+            _ => return Err("wasm: unsupported constant expression"),
+        };
+        if s.byte()? != 0x0B { return Err("wasm: unsupported constant expression"); }
+        Ok(v)
+    }
+
+    fn code_entry(&mut self, bin: &[u8], at: usize, size: usize, func: usize) -> Result<(), &'static str> {
+        let mut r = Reader::at(bin, at);
+        let end = at + size;
+        let params = self.types[self.funcs[func].ty].params.len();
+        let mut locals = 0usize;
+        for _ in 0..r.u32()? {
+            locals = locals.saturating_add(r.u32()? as usize);
+            valtype(r.byte()?)?;
+        }
+        if params + locals > MAX_LOCALS { return Err("wasm: too many locals"); }
+        let start = r.pos;
+        self.check_body(bin, start, end, params + locals)?;
+        self.funcs[func].body = Body::Code { start, end, locals };
+        Ok(())
+    }
+
+    /// Walk a function body once: check opcodes and indexes, and record
+    /// where each block and if continues.
+    fn check_body(&mut self, bin: &[u8], start: usize, end: usize, nlocals: usize) -> Result<(), &'static str> {
+        const LOOP: usize = usize::MAX;
+        let mut r = Reader::at(&bin[..end], start);
+        let mut ctl: Vec<usize> = Vec::new();
+        while r.pos < end {
+            let pc = r.pos;
+            let op = r.byte()?;
+            let mem = matches!(op, 0x28 | 0x29 | 0x2C..=0x37 | 0x3A..=0x40);
+            if mem && !self.memory { return Err("wasm: memory instruction without a memory"); }
+            match op {
+                0x02 | 0x04 => {
+                    block_arity(&mut r)?;
+                    self.blocks.push(Block { pc, els: 0, end: 0 });
+                    ctl.push(self.blocks.len() - 1);
+                }
+                0x03 => { block_arity(&mut r)?; ctl.push(LOOP); }
+                0x05 => match ctl.last() {
+                    Some(&i) if i != LOOP && bin[self.blocks[i].pc] == 0x04 && self.blocks[i].els == 0 => self.blocks[i].els = r.pos,
+                    _ => return Err("wasm: else without if"),
+                },
+                0x0B => match ctl.pop() {
+                    Some(LOOP) => {}
+                    Some(i) => self.blocks[i].end = pc,
+                    None => return if r.pos == end { Ok(()) } else { Err("wasm: code after function end") },
+                },
+                0x0C | 0x0D => { r.u32()?; }
+                0x0E => {
+                    let n = r.u32()?;
+                    if n > MAX_BR_TABLE { return Err("wasm: br_table too large"); }
+                    for _ in 0..=n { r.u32()?; }
+                }
+                0x10 => if r.u32()? as usize >= self.funcs.len() { return Err("wasm: function index out of range"); },
+                0x11 => {
+                    if r.u32()? as usize >= self.types.len() { return Err("wasm: type index out of range"); }
+                    if r.byte()? != 0 || self.table.is_empty() { return Err("wasm: call_indirect without a table"); }
+                }
+                0x1C => {
+                    if r.u32()? != 1 { return Err("wasm: bad select"); }
+                    valtype(r.byte()?)?;
+                }
+                0x20..=0x22 => if r.u32()? as usize >= nlocals { return Err("wasm: local index out of range"); },
+                0x23 => if r.u32()? as usize >= self.globals.len() { return Err("wasm: global index out of range"); },
+                0x24 => if !self.globals.get(r.u32()? as usize).is_some_and(|g| g.mutable) { return Err("wasm: global is immutable or out of range"); },
+                0x28 | 0x29 | 0x2C..=0x37 | 0x3A..=0x3E => { r.u32()?; r.u32()?; }
+                0x3F | 0x40 => if r.byte()? != 0 { return Err("wasm: bad memory index"); },
+                0x41 => { r.i32()?; }
+                0x42 => { r.i64()?; }
+                0x00 | 0x01 | 0x0F | 0x1A | 0x1B | 0x45..=0x5A | 0x67..=0x8A | 0xA7 | 0xAC | 0xAD | 0xC0..=0xC4 => {}
+                0x2A | 0x2B | 0x38 | 0x39 | 0x43 | 0x44 | 0x5B..=0x66 | 0x8B..=0xA6 | 0xA8..=0xAB | 0xAE..=0xBF => return Err(FLOAT),
+                0xFC => {
+                    let copy = match r.u32()? {
+                        10 => true,
+                        11 => false,
+                        0..=7 => return Err(FLOAT),
+                        _ => return Err(UNSUPPORTED),
+                    };
+                    if !self.memory { return Err("wasm: memory instruction without a memory"); }
+                    if r.byte()? != 0 || (copy && r.byte()? != 0) { return Err("wasm: bad memory index"); }
+                }
+                _ => return Err(UNSUPPORTED),
+            }
+            if ctl.len() > MAX_LABELS { return Err("wasm: blocks nested too deeply"); }
+        }
+        Err("wasm: function body not terminated")
+    }
+
+    fn block(&self, pc: usize) -> Result<Block, &'static str> {
+        self.blocks.binary_search_by_key(&pc, |b| b.pc).map(|i| self.blocks[i]).map_err(|_| "wasm: no such block")
+    }
+
+    /// Initial memory in pages.
+    pub fn mem_min(&self) -> u32 { self.mem_min }
+
+    /// Largest memory the module accepts, in pages (0 without a memory).
+    pub fn mem_max(&self) -> u32 { if self.memory { self.mem_max } else { 0 } }
+
+    pub fn functions(&self) -> usize { self.funcs.len() }
+
+    pub fn size(&self) -> usize { self.bytes.len() }
+}
+
+/// Linear memory over host frames. `limit` pages are backed; `pages` of
+/// them are visible to the module.
+pub struct Memory { base: u64, pub pages: u32, pub limit: u32 }
+
+impl Memory {
+    pub const fn empty() -> Memory { Memory { base: 0, pages: 0, limit: 0 } }
+
+    /// # Safety
+    /// `base` must be writable for `limit` pages and owned by this memory.
+    pub unsafe fn new(base: u64, limit: u32) -> Memory { Memory { base, pages: 0, limit } }
+
+    pub fn bytes(&self) -> u64 { self.pages as u64 * PAGE as u64 }
+
+    pub fn get(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        if addr.checked_add(len as u64)? > self.bytes() { return None; }
+        if len == 0 { return Some(&[]); }
+        Some(unsafe { core::slice::from_raw_parts((self.base + addr) as *const u8, len) })
+    }
+
+    pub fn get_mut(&mut self, addr: u64, len: usize) -> Option<&mut [u8]> {
+        if addr.checked_add(len as u64)? > self.bytes() { return None; }
+        if len == 0 { return Some(&mut []); }
+        Some(unsafe { core::slice::from_raw_parts_mut((self.base + addr) as *mut u8, len) })
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Option<u32> { self.get(addr, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])) }
+
+    pub fn write_u32(&mut self, addr: u64, v: u32) -> bool {
+        self.get_mut(addr, 4).map(|b| b.copy_from_slice(&v.to_le_bytes())).is_some()
+    }
+
+    fn load(&self, addr: u64, n: usize) -> Result<u64, &'static str> {
+        let s = self.get(addr, n).ok_or(OOB)?;
+        let mut v = [0u8; 8];
+        v[..n].copy_from_slice(s);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    fn store(&mut self, addr: u64, n: usize, v: u64) -> Result<(), &'static str> {
+        self.get_mut(addr, n).ok_or(OOB)?.copy_from_slice(&v.to_le_bytes()[..n]);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Frame {
+    pc: usize,
+    /// First local slot and count
+    locals: usize,
+    nlocals: usize,
+    /// Label and operand stack heights on entry
+    labels: usize,
+    stack: usize,
+    arity: u8,
+}
+
+#[derive(Clone, Copy)]
+struct Label {
+    /// Where a branch to the label continues
+    cont: usize,
+    height: usize,
+    arity: u8,
+    /// Branches re-enter a loop and keep its label
+    lp: bool,
+}
+
+/// Execution state of one instance. Values are kept as u64; an i32 is its
+/// low half.
+pub struct Machine {
+    values: Vec<u64>,
+    locals: Vec<u64>,
+    labels: Vec<Label>,
+    frames: Vec<Frame>,
+    globals: Vec<u64>,
+}
+
+impl Machine {
+    /// Reset `mem` to the module's initial contents and prepare to run the
+    /// start function, then `_start`.
+    pub fn instantiate(m: &Module, mem: &mut Memory) -> Result<Machine, &'static str> {
+        if m.memory && m.mem_min > mem.limit { return Err("wasm: memory limit below the module's minimum"); }
+        mem.pages = mem.limit;
+        if let Some(all) = mem.get_mut(0, mem.bytes() as usize) { all.fill(0); }
+        mem.pages = if m.memory { m.mem_min } else { 0 };
+        for d in &m.data {
+            let dst = mem.get_mut(d.offset as u64, d.len).ok_or("wasm: data segment out of bounds")?;
+            dst.copy_from_slice(&m.bytes[d.start..d.start + d.len]);
+        }
+        let mut vm = Machine {
+            values: Vec::new(), locals: Vec::new(), labels: Vec::new(), frames: Vec::new(),
+            globals: m.globals.iter().map(|g| g.init).collect(),
+        };
+        for f in [m.entry, m.start].into_iter().flatten() { vm.enter(m, f)?; }
+        Ok(vm)
+    }
+
+    /// Run until `fuel` instructions have executed or the module stops.
+    pub fn run(&mut self, m: &Module, mem: &mut Memory, host: &mut dyn Host, fuel: &mut u64) -> Exit {
+        while *fuel != 0 {
+            *fuel -= 1;
+            match self.step(m, mem, host) {
+                Ok(None) => {}
+                Ok(Some(e)) => return e,
+                Err(t) => return Exit::Trap(t),
+            }
+        }
+        Exit::Fuel
+    }
+
+    fn push(&mut self, v: u64) -> Result<(), &'static str> {
+        if self.values.len() >= MAX_VALUES { return Err("wasm: operand stack exhausted"); }
+        self.values.push(v);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u64, &'static str> {
+        let f = self.frames.last().ok_or(STACK)?;
+        if self.values.len() <= f.stack { return Err(STACK); }
+        self.values.pop().ok_or(STACK)
+    }
+
+    fn push_label(&mut self, l: Label) -> Result<(), &'static str> {
+        if self.labels.len() >= MAX_LABELS { return Err("wasm: label stack exhausted"); }
+        self.labels.push(l);
+        Ok(())
+    }
+
+    /// Move the top `n` values down to `height` and drop the rest above it.
+    fn unwind(&mut self, height: usize, n: usize) -> Result<(), &'static str> {
+        if self.values.len() < height + n { return Err(STACK); }
+        let top = self.values.len() - n;
+        self.values.copy_within(top.., height);
+        self.values.truncate(height + n);
+        Ok(())
+    }
+
+    /// Push a frame for function `f`, taking its arguments off the stack.
+    fn enter(&mut self, m: &Module, f: usize) -> Result<(), &'static str> {
+        let func = m.funcs.get(f).ok_or("wasm: function index out of range")?;
+        let Body::Code { start, locals, .. } = func.body else { return Err("wasm: not a code function") };
+        let ty = &m.types[func.ty];
+        let np = ty.params.len();
+        if self.frames.len() >= MAX_FRAMES { return Err("wasm: call stack exhausted"); }
+        let floor = self.frames.last().map_or(0, |fr| fr.stack);
+        if self.values.len() < floor + np { return Err(STACK); }
+        let base = self.values.len() - np;
+        let lbase = self.locals.len();
+        if lbase + np + locals > MAX_LOCAL_SLOTS { return Err("wasm: call stack exhausted"); }
+        self.locals.extend_from_slice(&self.values[base..]);
+        self.values.truncate(base);
+        self.locals.resize(lbase + np + locals, 0);
+        self.frames.push(Frame { pc: start, locals: lbase, nlocals: np + locals, labels: self.labels.len(), stack: base, arity: ty.results.len() as u8 });
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<Option<Exit>, &'static str> {
+        let f = self.frames.pop().ok_or(STACK)?;
+        self.unwind(f.stack, f.arity as usize)?;
+        self.locals.truncate(f.locals);
+        self.labels.truncate(f.labels);
+        Ok(if self.frames.is_empty() { Some(Exit::Done) } else { None })
+    }
+
+    /// Branch to the label `depth` out; `None` when that leaves the function.
+    fn branch(&mut self, fr: &Frame, depth: u32) -> Result<Option<usize>, &'static str> {
+        let depth = depth as usize;
+        let inner = self.labels.len() - fr.labels;
+        if depth >= inner { return if depth == inner { Ok(None) } else { Err("wasm: branch depth out of range") }; }
+        let i = self.labels.len() - 1 - depth;
+        let l = self.labels[i];
+        self.unwind(l.height, l.arity as usize)?;
+        self.labels.truncate(if l.lp { i + 1 } else { i });
+        Ok(Some(l.cont))
+    }
+
+    fn call(&mut self, m: &Module, f: usize, mem: &mut Memory, host: &mut dyn Host) -> Result<Option<Exit>, &'static str> {
+        let func = m.funcs.get(f).ok_or("wasm: function index out of range")?;
+        let Body::Host(imp) = func.body else { self.enter(m, f)?; return Ok(None) };
+        let np = m.types[func.ty].params.len();
+        let mut args = [0u64; 4];
+        for k in (0..np).rev() { args[k] = self.pop()?; }
+        match host.call(imp, &args[..np], mem) {
+            Ok(Some(v)) => { self.push(v)?; Ok(None) }
+            Ok(None) => Ok(None),
+            Err(e) => Ok(Some(e)),
+        }
+    }
+
+    fn step(&mut self, m: &Module, mem: &mut Memory, host: &mut dyn Host) -> Result<Option<Exit>, &'static str> {
+        let Some(fr) = self.frames.last().copied() else { return Ok(Some(Exit::Done)) };
+        let mut r = Reader::at(&m.bytes, fr.pc);
+        let op_pc = fr.pc;
+        let op = r.byte()?;
+        let mut next = None;
+        match op {
+            0x00 => return Err("wasm: unreachable executed"),
+            0x01 => {}
+            0x02 => {
+                let arity = block_arity(&mut r)?;
+                let b = m.block(op_pc)?;
+                self.push_label(Label { cont: b.end + 1, height: self.values.len(), arity, lp: false })?;
+            }
+            0x03 => {
+                block_arity(&mut r)?;
+                self.push_label(Label { cont: r.pos, height: self.values.len(), arity: 0, lp: true })?;
+            }
+            0x04 => {
+                let arity = block_arity(&mut r)?;
+                let b = m.block(op_pc)?;
+                let c = self.pop()? as u32;
+                let label = Label { cont: b.end + 1, height: self.values.len(), arity, lp: false };
+                if c != 0 { self.push_label(label)?; }
+                else if b.els != 0 { self.push_label(label)?; next = Some(b.els); }
+                else { next = Some(b.end + 1); }
+            }
+            0x05 => {
+                if self.labels.len() <= fr.labels { return Err("wasm: else without if"); }
+                next = self.labels.pop().map(|l| l.cont);
+            }
+            0x0B => {
+                if self.labels.len() <= fr.labels { return self.ret(); }
+                self.labels.pop();
+            }
+            0x0C => {
+                let d = r.u32()?;
+                match self.branch(&fr, d)? { Some(pc) => next = Some(pc), None => return self.ret() }
+            }
+            0x0D => {
+                let d = r.u32()?;
+                if self.pop()? as u32 != 0 {
+                    match self.branch(&fr, d)? { Some(pc) => next = Some(pc), None => return self.ret() }
+                }
+            }
+            0x0E => {
+                let n = r.u32()?;
+                let i = (self.pop()? as u32).min(n);
+                let mut d = 0;
+                for k in 0..=n { let t = r.u32()?; if k == i { d = t; } }
+                match self.branch(&fr, d)? { Some(pc) => next = Some(pc), None => return self.ret() }
+            }
+            0x0F => return self.ret(),
+            0x10 => {
+                let f = r.u32()? as usize;
+                self.frames.last_mut().ok_or(STACK)?.pc = r.pos;
+                return self.call(m, f, mem, host);
+            }
+            0x11 => {
+                let ty = r.u32()? as usize;
+                r.byte()?;
+                let i = self.pop()? as u32 as usize;
+                let f = *m.table.get(i).ok_or("wasm: undefined table element")?;
+                if f == NULL_FUNC { return Err("wasm: uninitialized table element"); }
+                let func = m.funcs.get(f as usize).ok_or("wasm: function index out of range")?;
+                if m.types.get(ty) != Some(&m.types[func.ty]) { return Err("wasm: indirect call type mismatch"); }
+                self.frames.last_mut().ok_or(STACK)?.pc = r.pos;
+                return self.call(m, f as usize, mem, host);
+            }
+            0x1A => { self.pop()?; }
+            0x1B | 0x1C => {
+                if op == 0x1C { r.u32()?; r.byte()?; }
+                let c = self.pop()? as u32;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(if c != 0 { a } else { b })?;
+            }
+            0x20..=0x22 => {
+                let i = r.u32()? as usize;
+                if i >= fr.nlocals { return Err("wasm: local index out of range"); }
+                let slot = fr.locals + i;
+                match op {
+                    0x20 => { let v = self.locals[slot]; self.push(v)?; }
+                    0x21 => self.locals[slot] = self.pop()?,
+                    _ => { let v = self.pop()?; self.locals[slot] = v; self.push(v)?; }
+                }
+            }
+            0x23 => {
+                let v = *self.globals.get(r.u32()? as usize).ok_or("wasm: global index out of range")?;
+                self.push(v)?;
+            }
+            0x24 => {
+                let i = r.u32()? as usize;
+                let v = self.pop()?;
+                *self.globals.get_mut(i).ok_or("wasm: global index out of range")? = v;
+            }
+            0x28..=0x35 => {
+                r.u32()?;
+                let off = r.u32()? as u64;
+                let a = (self.pop()? as u32) as u64 + off;
+                let v = match op {
+                    0x28 => mem.load(a, 4)?,
+                    0x29 => mem.load(a, 8)?,
+                    0x2C => mem.load(a, 1)? as u8 as i8 as i32 as u32 as u64,
+                    0x2D | 0x31 => mem.load(a, 1)?,
+                    0x2E => mem.load(a, 2)? as u16 as i16 as i32 as u32 as u64,
+                    0x2F | 0x33 => mem.load(a, 2)?,
+                    0x30 => mem.load(a, 1)? as u8 as i8 as i64 as u64,
+                    0x32 => mem.load(a, 2)? as u16 as i16 as i64 as u64,
+                    0x34 => mem.load(a, 4)? as u32 as i32 as i64 as u64,
+                    0x35 => mem.load(a, 4)?,
+                    _ => return Err(FLOAT),
+                };
+                self.push(v)?;
+            }
+            0x36..=0x3E => {
+                r.u32()?;
+                let off = r.u32()? as u64;
+                let v = self.pop()?;
+                let a = (self.pop()? as u32) as u64 + off;
+                let n = match op { 0x36 | 0x3E => 4, 0x37 => 8, 0x3A | 0x3C => 1, 0x3B | 0x3D => 2, _ => return Err(FLOAT) };
+                mem.store(a, n, v)?;
+            }
+            0x3F => { r.byte()?; self.push(mem.pages as u64)?; }
+            0x40 => {
+                r.byte()?;
+                let n = self.pop()? as u32;
+                let old = mem.pages;
+                match old.checked_add(n).filter(|&p| p <= mem.limit) {
+                    Some(p) => { mem.pages = p; self.push(old as u64)?; }
+                    None => self.push(u32::MAX as u64)?,
+                }
+            }
+            0x41 => { let v = r.i32()?; self.push(v)?; }
+            0x42 => { let v = r.i64()?; self.push(v)?; }
+            0x45 => { let a = self.pop()? as u32; self.push((a == 0) as u64)?; }
+            0x46..=0x4F => {
+                let b = self.pop()? as u32;
+                let a = self.pop()? as u32;
+                let (sa, sb) = (a as i32, b as i32);
+                let v = match op {
+                    0x46 => a == b, 0x47 => a != b, 0x48 => sa < sb, 0x49 => a < b, 0x4A => sa > sb,
+                    0x4B => a > b, 0x4C => sa <= sb, 0x4D => a <= b, 0x4E => sa >= sb, _ => a >= b,
+                };
+                self.push(v as u64)?;
+            }
+            0x50 => { let a = self.pop()?; self.push((a == 0) as u64)?; }
+            0x51..=0x5A => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let (sa, sb) = (a as i64, b as i64);
+                let v = match op {
+                    0x51 => a == b, 0x52 => a != b, 0x53 => sa < sb, 0x54 => a < b, 0x55 => sa > sb,
+                    0x56 => a > b, 0x57 => sa <= sb, 0x58 => a <= b, 0x59 => sa >= sb, _ => a >= b,
+                };
+                self.push(v as u64)?;
+            }
+            0x67..=0x69 => {
+                let a = self.pop()? as u32;
+                let v = match op { 0x67 => a.leading_zeros(), 0x68 => a.trailing_zeros(), _ => a.count_ones() };
+                self.push(v as u64)?;
+            }
+            0x6A..=0x78 => {
+                let b = self.pop()? as u32;
+                let a = self.pop()? as u32;
+                let v = i32_binop(op, a, b)?;
+                self.push(v as u64)?;
+            }
+            0x79..=0x7B => {
+                let a = self.pop()?;
+                let v = match op { 0x79 => a.leading_zeros(), 0x7A => a.trailing_zeros(), _ => a.count_ones() };
+                self.push(v as u64)?;
+            }
+            0x7C..=0x8A => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let v = i64_binop(op, a, b)?;
+                self.push(v)?;
+            }
+            0xA7 | 0xAC | 0xAD | 0xC0..=0xC4 => {
+                let a = self.pop()?;
+                let v = match op {
+                    0xA7 | 0xAD => a as u32 as u64,
+                    0xAC | 0xC4 => a as u32 as i32 as i64 as u64,
+                    0xC0 => a as u8 as i8 as i32 as u32 as u64,
+                    0xC1 => a as u16 as i16 as i32 as u32 as u64,
+                    0xC2 => a as u8 as i8 as i64 as u64,
+                    _ => a as u16 as i16 as i64 as u64,
+                };
+                self.push(v)?;
+            }
+            0xFC => {
+                let sub = r.u32()?;
+                r.byte()?;
+                if sub == 10 { r.byte()?; }
+                let n = self.pop()? as u32 as usize;
+                let src = self.pop()? as u32 as u64;
+                let dst = self.pop()? as u32 as u64;
+                match sub {
+                    10 => {
+                        let top = src.max(dst) as usize + n;
+                        if top as u64 > mem.bytes() { return Err(OOB); }
+                        mem.get_mut(0, top).ok_or(OOB)?.copy_within(src as usize..src as usize + n, dst as usize);
+                    }
+                    11 => mem.get_mut(dst, n).ok_or(OOB)?.fill(src as u8),
+                    _ => return Err(UNSUPPORTED),
+                }
+            }
+            _ => return Err(UNSUPPORTED),
+        }
+        self.frames.last_mut().ok_or(STACK)?.pc = next.unwrap_or(r.pos);
+        Ok(None)
+    }
+}
+
+fn i32_binop(op: u8, a: u32, b: u32) -> Result<u32, &'static str> {
+    let (sa, sb) = (a as i32, b as i32);
+    Ok(match op {
+        0x6A => a.wrapping_add(b),
+        0x6B => a.wrapping_sub(b),
+        0x6C => a.wrapping_mul(b),
+        0x6D | 0x6F if b == 0 => return Err("wasm: integer divide by zero"),
+        0x6E | 0x70 if b == 0 => return Err("wasm: integer divide by zero"),
+        0x6D => sa.checked_div(sb).ok_or("wasm: integer overflow")? as u32,
+        0x6E => a / b,
+        0x6F => sa.wrapping_rem(sb) as u32,
+        0x70 => a % b,
+        0x71 => a & b,
+        0x72 => a | b,
+        0x73 => a ^ b,
+        0x74 => a.wrapping_shl(b),
+        0x75 => sa.wrapping_shr(b) as u32,
+        0x76 => a.wrapping_shr(b),
+        0x77 => a.rotate_left(b % 32),
+        _ => a.rotate_right(b % 32),
+    })
+}
+
+fn i64_binop(op: u8, a: u64, b: u64) -> Result<u64, &'static str> {
+    let (sa, sb) = (a as i64, b as i64);
+    Ok(match op {
+        0x7C => a.wrapping_add(b),
+        0x7D => a.wrapping_sub(b),
+        0x7E => a.wrapping_mul(b),
+        0x7F..=0x82 if b == 0 => return Err("wasm: integer divide by zero"),
+        0x7F => sa.checked_div(sb).ok_or("wasm: integer overflow")? as u64,
+        0x80 => a / b,
+        0x81 => sa.wrapping_rem(sb) as u64,
+        0x82 => a % b,
+        0x83 => a & b,
+        0x84 => a | b,
+        0x85 => a ^ b,
+        0x86 => a.wrapping_shl(b as u32),
+        0x87 => sa.wrapping_shr(b as u32) as u64,
+        0x88 => a.wrapping_shr(b as u32),
+        0x89 => a.rotate_left((b % 64) as u32),
+        _ => a.rotate_right((b % 64) as u32),
+    })
+}
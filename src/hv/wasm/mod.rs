@@ -0,0 +1,323 @@
+#![allow(dead_code)]
+
+//! WebAssembly modules as lightweight VMs.
+//!
+//! A VM created through the usual API can be given a WebAssembly module
+//! from the ESP instead of a guest image. It then has no vCPUs and no
+//! stage-2 tables: `interp` executes the module on the host, inside its
+//! own linear memory. `run::start`, `stop`, `pause` and `unpause` hand such
+//! a VM to this module, so the lifecycle calls, the VM state machine,
+//! teardown and the per-VM scheduler metrics are the same for both kinds
+//! and functions can run next to full guests.
+//!
+//! - The module passes the image signature policy like a guest image.
+//! - Linear memory is guest RAM of the VM in `mm::guest`, sized to the
+//!   module's maximum capped at the requested MiB, and counts against the
+//!   overcommit limit. `memory.grow` beyond it fails.
+//! - Running modules execute as the `wasm` background task. Each step
+//!   gives every running module a slice of `SLICE_FUEL` instructions scaled
+//!   by its credit-scheduler weight, and a capped VM is skipped once it has
+//!   used its share of the current credit period.
+//! - Host imports: `zv.write`, `zv.exit`, `zv.clock_ms`, `zv.yield`, and
+//!   WASI `fd_write` (stdout and stderr) and `proc_exit`. Output is kept,
+//!   the last `OUT_MAX` bytes, for the management API.
+//!
+//! A module that returns from `_start` or exits stops the VM; a trap stops
+//! it too and is kept in its status. Starting it again re-instantiates the
+//! module from scratch.
+
+pub mod interp;
+
+use alloc::vec::Vec;
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::imgsig::Verdict;
+use crate::hv::sched::{background, credit};
+use crate::obs::metrics::{self, Counter};
+use crate::util::spinlock::SpinLock;
+use interp::{Exit, Import, Machine, Memory, Module};
+
+pub const TASK: &str = "wasm";
+pub const MAX_INSTANCES: usize = 16;
+/// Instructions per slice at the default weight
+pub const SLICE_FUEL: u64 = 100_000;
+pub const DEFAULT_MEMORY_MIB: u64 = 16;
+/// Output kept per module
+pub const OUT_MAX: usize = 4096;
+const WASI_EBADF: u64 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Loaded,
+    Running,
+    Paused,
+    /// Stopped by the host
+    Stopped,
+    /// Returned from `_start` (0) or exited with a code
+    Exited(i32),
+    Trapped(&'static str),
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Loaded => "loaded",
+            State::Running => "running",
+            State::Paused => "paused",
+            State::Stopped => "stopped",
+            State::Exited(_) => "exited",
+            State::Trapped(_) => "trapped",
+        }
+    }
+
+    fn live(self) -> bool { matches!(self, State::Running | State::Paused) }
+}
+
+/// What a module VM is doing.
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub vm_id: u64,
+    pub state: State,
+    pub signature: Verdict,
+    /// Size of the module binary
+    pub bytes: usize,
+    pub functions: usize,
+    /// Linear memory in use and backed, in 64 KiB pages
+    pub pages: u32,
+    pub limit: u32,
+    pub instructions: u64,
+    pub host_calls: u64,
+    pub run_us: u64,
+    /// Slices skipped for the VM's cap
+    pub parks: u64,
+}
+
+struct Instance {
+    status: Status,
+    module: Module,
+    machine: Option<Machine>,
+    mem: Memory,
+    /// Host base of the linear memory extent (0 = none)
+    base: u64,
+    out: Vec<u8>,
+    /// Cap accounting, in ns on the monotonic clock
+    period_start: u64,
+    period_used: u64,
+}
+
+static INSTANCES: SpinLock<Vec<Instance>> = SpinLock::new(Vec::new());
+
+/// Whether `vm_id` runs a module instead of a guest image.
+pub fn is_wasm(vm_id: u64) -> bool { INSTANCES.lock(|t| t.iter().any(|i| i.status.vm_id == vm_id)) }
+
+pub fn status(vm_id: u64) -> Option<Status> { INSTANCES.lock(|t| t.iter().find(|i| i.status.vm_id == vm_id).map(|i| i.status)) }
+
+/// Visit the status of every module VM.
+pub fn for_each(mut f: impl FnMut(&Status)) {
+    let all: Vec<Status> = INSTANCES.lock(|t| t.iter().map(|i| i.status).collect());
+    for s in &all { f(s); }
+}
+
+/// Copy the most recent output of `vm_id` into `buf`; returns the length.
+pub fn output(vm_id: u64, buf: &mut [u8]) -> usize {
+    INSTANCES.lock(|t| {
+        let Some(i) = t.iter().find(|i| i.status.vm_id == vm_id) else { return 0 };
+        let n = i.out.len().min(buf.len());
+        buf[..n].copy_from_slice(&i.out[i.out.len() - n..]);
+        n
+    })
+}
+
+/// Load the module at ESP `path` into VM `vm_id` with at most `memory_mib`
+/// of linear memory, replacing a module loaded before.
+pub fn load(system_table: &SystemTable<Boot>, vm_id: u64, path: &str, memory_mib: u64) -> Result<Status, &'static str> {
+    if crate::hv::vm::find_vm(vm_id).is_none() { return Err("wasm: no such vm"); }
+    if crate::hv::loader::find_image(vm_id).is_some() { return Err("wasm: vm has a guest image loaded"); }
+    if active(vm_id) != 0 { return Err("wasm: stop the vm first"); }
+    let (stage, stage_pages, file_len) = crate::hv::loader::read_esp_file(system_table, path)?;
+    let file = unsafe { core::slice::from_raw_parts(stage as *const u8, file_len) };
+    let decoded = crate::hv::imgsig::admit(system_table, vm_id, file).and_then(|(v, len)| Module::decode(&file[..len]).map(|m| (v, m)));
+    crate::mm::uefi::free_pages(system_table, stage, stage_pages);
+    let (signature, module) = decoded?;
+
+    let limit = module.mem_max().min((memory_mib.max(1) * 16).min(interp::MAX_PAGES as u64) as u32);
+    if module.mem_min() > limit { return Err("wasm: module needs more memory than allowed"); }
+    release(vm_id);
+    let bytes = limit as u64 * interp::PAGE as u64;
+    let base = if bytes == 0 { 0 } else {
+        let _ = crate::mm::guest::scan(system_table);
+        crate::mm::guest::reserve(vm_id, bytes)?;
+        crate::mm::guest::alloc(system_table, vm_id, bytes / 4096, 1)?
+    };
+    let status = Status {
+        vm_id, state: State::Loaded, signature, bytes: module.size(), functions: module.functions(),
+        pages: module.mem_min(), limit, instructions: 0, host_calls: 0, run_us: 0, parks: 0,
+    };
+    let inst = Instance { status, module, machine: None, mem: unsafe { Memory::new(base, limit) }, base, out: Vec::new(), period_start: 0, period_used: 0 };
+    let added = INSTANCES.lock(|t| {
+        if t.len() >= MAX_INSTANCES { return false; }
+        t.push(inst);
+        true
+    });
+    if !added {
+        if base != 0 { crate::mm::guest::free(vm_id, base); }
+        let _ = crate::mm::guest::reserve(vm_id, 0);
+        return Err("wasm: too many modules loaded");
+    }
+    Counter::new(&metrics::WASM_LOADS).inc();
+    Ok(status)
+}
+
+/// Drop the module of `vm_id` and give its linear memory back.
+fn release(vm_id: u64) {
+    let base = INSTANCES.lock(|t| {
+        let k = t.iter().position(|i| i.status.vm_id == vm_id)?;
+        Some(t.remove(k).base)
+    });
+    if let Some(b) = base.filter(|&b| b != 0) {
+        crate::mm::guest::free(vm_id, b);
+        let _ = crate::mm::guest::reserve(vm_id, 0);
+    }
+}
+
+/// Instantiate the module of `vm_id` and let the background task run it.
+/// Returns 1, the VM's execution contexts, to match `run::start`.
+pub fn start(vm_id: u64) -> Result<u32, &'static str> {
+    INSTANCES.lock(|t| {
+        let i = t.iter_mut().find(|i| i.status.vm_id == vm_id).ok_or("wasm: no module loaded")?;
+        if i.status.state.live() { return Err("wasm: vm already running"); }
+        i.machine = Some(Machine::instantiate(&i.module, &mut i.mem)?);
+        i.status.state = State::Running;
+        i.status.pages = i.mem.pages;
+        i.out.clear();
+        i.period_start = 0;
+        Ok(())
+    })?;
+    if background::register(TASK, background::TaskKind::Other, step).is_none() {
+        halt(vm_id);
+        return Err("wasm: no background task slot");
+    }
+    background::kick(TASK);
+    Counter::new(&metrics::WASM_STARTS).inc();
+    crate::obs::trace::emit(crate::obs::trace::Event::VmStart(vm_id));
+    crate::hv::vm::note(vm_id, crate::hv::vm::VmState::Running);
+    Ok(1)
+}
+
+/// Stop the module of `vm_id` where it is.
+pub fn halt(vm_id: u64) {
+    INSTANCES.lock(|t| {
+        for i in t.iter_mut().filter(|i| i.status.vm_id == vm_id && i.status.state.live()) {
+            i.status.state = State::Stopped;
+            i.machine = None;
+        }
+    });
+}
+
+/// 1 while the module of `vm_id` is running or paused, else 0.
+pub fn active(vm_id: u64) -> u32 { INSTANCES.lock(|t| t.iter().any(|i| i.status.vm_id == vm_id && i.status.state.live()) as u32) }
+
+/// Pause or resume the module of `vm_id`; false if it is not a live module VM.
+pub fn set_paused(vm_id: u64, on: bool) -> bool {
+    let done = INSTANCES.lock(|t| match t.iter_mut().find(|i| i.status.vm_id == vm_id && i.status.state.live()) {
+        Some(i) => { i.status.state = if on { State::Paused } else { State::Running }; true }
+        None => false,
+    });
+    if done && !on { background::kick(TASK); }
+    done
+}
+
+/// Forget the module of a destroyed VM. Its memory went with the VM's.
+pub fn forget_vm(vm_id: u64) { INSTANCES.lock(|t| t.retain(|i| i.status.vm_id != vm_id)); }
+
+struct Sys<'a> { out: &'a mut Vec<u8>, calls: &'a mut u64 }
+
+impl Sys<'_> {
+    fn emit(&mut self, b: &[u8]) {
+        let b = &b[b.len().saturating_sub(OUT_MAX)..];
+        let over = (self.out.len() + b.len()).saturating_sub(OUT_MAX);
+        self.out.drain(..over);
+        self.out.extend_from_slice(b);
+    }
+}
+
+impl interp::Host for Sys<'_> {
+    fn call(&mut self, f: Import, args: &[u64], mem: &mut Memory) -> Result<Option<u64>, Exit> {
+        const OOB: Exit = Exit::Trap("wasm: out of bounds memory access");
+        *self.calls += 1;
+        let a = |k: usize| args[k] as u32 as u64;
+        match f {
+            Import::Write => {
+                let b = mem.get(a(0), a(1) as usize).ok_or(OOB)?;
+                self.emit(b);
+                Ok(None)
+            }
+            Import::Exit | Import::ProcExit => Err(Exit::Exit(args[0] as u32 as i32)),
+            Import::ClockMs => Ok(Some(crate::time::clock::now_ms())),
+            Import::Yield => Err(Exit::Yield),
+            Import::FdWrite => {
+                if a(0) != 1 && a(0) != 2 { return Ok(Some(WASI_EBADF)); }
+                let mut total = 0u32;
+                for k in 0..a(2) {
+                    let iov = a(1) + k * 8;
+                    let (ptr, len) = (mem.read_u32(iov).ok_or(OOB)?, mem.read_u32(iov + 4).ok_or(OOB)?);
+                    let b = mem.get(ptr as u64, len as usize).ok_or(OOB)?;
+                    self.emit(b);
+                    total = total.wrapping_add(len);
+                }
+                if !mem.write_u32(a(3), total) { return Err(OOB); }
+                Ok(Some(0))
+            }
+        }
+    }
+}
+
+/// Background step: one slice for every running module. More is pending
+/// while any of them is still running.
+fn step() -> bool {
+    let period = credit::PERIOD_US * 1_000;
+    let mut stopped = [0u64; MAX_INSTANCES];
+    let mut n = 0;
+    let busy = INSTANCES.lock(|t| {
+        let mut busy = false;
+        for i in t.iter_mut() {
+            if i.status.state != State::Running { continue; }
+            let vm_id = i.status.vm_id;
+            let p = credit::params(vm_id);
+            let now = crate::time::clock::now_ns();
+            if now.saturating_sub(i.period_start) >= period { i.period_start = now; i.period_used = 0; }
+            if p.cap != 0 && i.period_used >= period * p.cap as u64 / 100 {
+                i.status.parks += 1;
+                metrics::vm_sched_account(vm_id, 0, true);
+                busy = true;
+                continue;
+            }
+            let Some(machine) = i.machine.as_mut() else { continue };
+            let given = (SLICE_FUEL * p.weight as u64 / credit::DEFAULT_WEIGHT as u64).max(1);
+            let mut fuel = given;
+            let mut sys = Sys { out: &mut i.out, calls: &mut i.status.host_calls };
+            let exit = machine.run(&i.module, &mut i.mem, &mut sys, &mut fuel);
+            let used = crate::time::clock::elapsed_ns(now);
+            let us = used / 1_000;
+            i.period_used += used;
+            i.status.run_us += us;
+            i.status.instructions += given - fuel;
+            i.status.pages = i.mem.pages;
+            Counter::new(&metrics::WASM_INSTRUCTIONS).add(given - fuel);
+            metrics::vm_sched_account(vm_id, us, false);
+            i.status.state = match exit {
+                Exit::Fuel | Exit::Yield => { busy = true; continue; }
+                Exit::Done => State::Exited(0),
+                Exit::Exit(code) => State::Exited(code),
+                Exit::Trap(why) => { Counter::new(&metrics::WASM_TRAPS).inc(); State::Trapped(why) }
+            };
+            i.machine = None;
+            stopped[n] = vm_id;
+            n += 1;
+        }
+        busy
+    });
+    for &vm_id in &stopped[..n] { crate::hv::vm::note(vm_id, crate::hv::vm::VmState::Stopped); }
+    busy
+}
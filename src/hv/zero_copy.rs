@@ -0,0 +1,370 @@
+#![allow(dead_code)]
+
+//! Zero-copy shared memory between VMs (ivshmem-style).
+//!
+//! The operator creates named segments of host memory and attaches each to
+//! any number of VMs. Every attachment is an ivshmem PCI function on the
+//! VM's bus: BAR 0 holds the registers (interrupt mask and status, the
+//! attachment's peer ID and the doorbell), BAR 2 is the segment itself,
+//! mapped straight into the guest's stage-2 table with 4KiB leaves,
+//! writable or read-only per attachment. Writing `(peer << 16) | vector`
+//! to the doorbell raises INTx on that peer.
+//!
+//! The BAR 2 window is also registered on the MMIO bus. That path only
+//! runs until the stage-2 mapping exists (the guest moved the BAR, or no
+//! image was loaded yet) and serves the same memory, honouring read-only.
+//! `pump` maps moved windows; the loader calls `rebind` for a new image.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::info_flow;
+use crate::mm::stage2;
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_SEGMENTS: usize = 8;
+pub const MAX_ATTACH: usize = 16;
+/// Peers per segment; the peer ID is the attachment's place in this range
+pub const MAX_PEERS: u16 = 8;
+pub const NAME_MAX: usize = 16;
+pub const MIN_SIZE: u64 = 4096;
+/// Largest segment that fits the device's 1MiB BAR slot next to BAR 0
+pub const MAX_SIZE: u64 = 512 << 10;
+
+pub const IVSHMEM_VENDOR: u16 = 0x1AF4;
+pub const IVSHMEM_DEVICE: u16 = 0x1110;
+/// ISA line used for INTx until the guest reprograms it
+pub const DEFAULT_IRQ: u8 = 10;
+const REGS_SIZE: u64 = 0x100;
+const REG_INTR_MASK: u64 = 0x00;
+const REG_INTR_STATUS: u64 = 0x04;
+const REG_IV_POSITION: u64 = 0x08;
+const REG_DOORBELL: u64 = 0x0C;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Perm { Ro, Rw }
+
+impl Perm {
+    pub fn name(self) -> &'static str { match self { Perm::Ro => "ro", Perm::Rw => "rw" } }
+}
+
+#[derive(Clone, Copy)]
+struct Segment { name: [u8; NAME_MAX], name_len: usize, base: u64, size: u64 }
+
+impl Segment {
+    fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("?") }
+}
+
+#[derive(Clone, Copy)]
+struct Attach {
+    seg: usize,
+    vm_id: u64,
+    peer: u16,
+    perm: Perm,
+    dev: u8,
+    regs: u64,
+    /// BAR 2 base the guest sees (0 = unmapped)
+    window: u64,
+    /// `window` is in the stage-2 table
+    mapped: bool,
+    intr_mask: u32,
+    intr_status: u32,
+    doorbells_in: u64,
+    doorbells_out: u64,
+}
+
+struct State {
+    segs: [Option<Segment>; MAX_SEGMENTS],
+    atts: [Option<Attach>; MAX_ATTACH],
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State { segs: [None; MAX_SEGMENTS], atts: [None; MAX_ATTACH] });
+
+fn find_seg(s: &State, name: &str) -> Option<usize> { s.segs.iter().position(|g| matches!(g, Some(g) if g.name() == name)) }
+
+/// Stage-2 root and format of `vm_id`, if it has a table of its own.
+fn table_of(vm_id: u64) -> Option<(u64, stage2::Format, u64)> {
+    let g = crate::hv::loader::find_image(vm_id).filter(|g| g.root_phys != 0)?;
+    Some((g.root_phys, crate::hv::loader::stage2_format(vm_id)?, g.ram_bytes))
+}
+
+/// Drop the stage-2 mapping of an attachment's window. Its 4KiB leaves
+/// need no table memory to clear.
+fn unmap_window(a: &mut Attach, size: u64) {
+    if !a.mapped { return; }
+    if let Some((root, _, _)) = table_of(a.vm_id) { let _ = stage2::unmap_with(root, a.window, size, &mut || None); }
+    a.mapped = false;
+}
+
+// ---- Guest-facing side (runs in the exit path) ----
+
+fn raise(vm_id: u64, dev: u8) {
+    if !crate::hv::vpci::set_intx(vm_id, dev, true) { return; }
+    let line = crate::hv::vpci::int_line(vm_id, dev).unwrap_or(0);
+    if line != 0 && line < 16 {
+        let _ = crate::hv::vlapic::raise(vm_id, 0, crate::hv::vtime::LEGACY_IRQ_VECTOR_BASE + line, false);
+    }
+}
+
+fn regs_mmio(_vm_id: u64, _vcpu: u32, ctx: u64, off: u64, _size: u8, write: Option<u64>) -> u64 {
+    let mut ring: Option<(u64, u8)> = None;
+    let v = STATE.lock(|s| {
+        let Some(a) = s.atts.get_mut(ctx as usize).and_then(|a| a.as_mut()) else { return 0 };
+        match (off, write) {
+            (REG_INTR_MASK, None) => a.intr_mask as u64,
+            (REG_INTR_MASK, Some(v)) => { a.intr_mask = v as u32; 0 }
+            (REG_INTR_STATUS, None) => {
+                // Reading the status acknowledges the interrupt.
+                let v = a.intr_status;
+                a.intr_status = 0;
+                let _ = crate::hv::vpci::set_intx(a.vm_id, a.dev, false);
+                v as u64
+            }
+            (REG_IV_POSITION, None) => a.peer as u64,
+            (REG_DOORBELL, Some(v)) => {
+                let (seg, peer) = (a.seg, (v >> 16) as u16);
+                a.doorbells_out += 1;
+                if let Some(t) = s.atts.iter_mut().flatten().find(|t| t.seg == seg && t.peer == peer) {
+                    t.doorbells_in += 1;
+                    t.intr_status |= 1;
+                    if t.intr_mask & 1 != 0 { ring = Some((t.vm_id, t.dev)); }
+                    crate::obs::metrics::Counter::new(&crate::obs::metrics::ZCOPY_DOORBELLS).inc();
+                }
+                0
+            }
+            _ => 0,
+        }
+    });
+    if let Some((vm, dev)) = ring { raise(vm, dev); }
+    v
+}
+
+/// Window accesses before the stage-2 mapping exists.
+fn window_mmio(_vm_id: u64, _vcpu: u32, ctx: u64, off: u64, size: u8, write: Option<u64>) -> u64 {
+    let Some((base, len, perm)) = STATE.lock(|s| {
+        let a = s.atts.get(ctx as usize).and_then(|a| a.as_ref())?;
+        s.segs[a.seg].map(|g| (g.base, g.size, a.perm))
+    }) else { return 0 };
+    let size = (size as u64).min(8);
+    if off + size > len { return 0; }
+    let p = (base + off) as *mut u8;
+    match write {
+        None => {
+            let mut b = [0u8; 8];
+            unsafe { core::ptr::copy_nonoverlapping(p, b.as_mut_ptr(), size as usize); }
+            u64::from_le_bytes(b)
+        }
+        Some(v) => {
+            if perm == Perm::Rw { unsafe { core::ptr::copy_nonoverlapping(v.to_le_bytes().as_ptr(), p, size as usize); } }
+            0
+        }
+    }
+}
+
+fn on_bar(vm_id: u64, ctx: u64, bar: usize, old: u64, new: u64) {
+    if old != 0 { let _ = crate::hv::bus::unregister_mmio(vm_id, old); }
+    let size = STATE.lock(|s| s.atts.get(ctx as usize).and_then(|a| a.as_ref()).and_then(|a| s.segs[a.seg]).map(|g| g.size)).unwrap_or(0);
+    if bar == 0 {
+        if new != 0 { let _ = crate::hv::bus::register_mmio(vm_id, new, REGS_SIZE, "ivshmem", ctx, regs_mmio); }
+        let _ = STATE.lock(|s| s.atts.get_mut(ctx as usize).and_then(|a| a.as_mut()).map(|a| a.regs = new));
+        return;
+    }
+    if new != 0 { let _ = crate::hv::bus::register_mmio(vm_id, new, size, "ivshmem-shm", ctx, window_mmio); }
+    STATE.lock(|s| {
+        if let Some(a) = s.atts.get_mut(ctx as usize).and_then(|a| a.as_mut()) {
+            unmap_window(a, size);
+            a.window = new;
+        }
+    });
+}
+
+// ---- Management ----
+
+/// Create a zeroed segment of `size` bytes (a power of two within
+/// `MIN_SIZE..=MAX_SIZE`).
+pub fn create(system_table: &SystemTable<Boot>, name: &str, size: u64) -> Result<(), &'static str> {
+    if name.is_empty() || name.len() > NAME_MAX { return Err("zcopy: name must be 1-16 bytes"); }
+    if !size.is_power_of_two() || !(MIN_SIZE..=MAX_SIZE).contains(&size) { return Err("zcopy: size must be a power of two from 4KiB to 512KiB"); }
+    if STATE.lock(|s| find_seg(s, name).is_some()) { return Err("zcopy: segment exists"); }
+    let pages = (size / 4096) as usize;
+    let p = crate::mm::uefi::alloc_pages(system_table, pages, uefi::table::boot::MemoryType::LOADER_DATA).ok_or("zcopy: out of memory")?;
+    unsafe { core::ptr::write_bytes(p, 0, size as usize); }
+    let mut seg = Segment { name: [0; NAME_MAX], name_len: name.len(), base: p as u64, size };
+    seg.name[..name.len()].copy_from_slice(name.as_bytes());
+    let ok = STATE.lock(|s| match s.segs.iter_mut().find(|g| g.is_none()) {
+        Some(slot) => { *slot = Some(seg); true }
+        None => false,
+    });
+    if !ok {
+        crate::mm::uefi::free_pages(system_table, p, pages);
+        return Err("zcopy: too many segments");
+    }
+    Ok(())
+}
+
+/// Free a segment no VM is attached to.
+pub fn destroy(system_table: &SystemTable<Boot>, name: &str) -> Result<(), &'static str> {
+    let seg = STATE.lock(|s| {
+        let i = find_seg(s, name).ok_or("zcopy: no such segment")?;
+        if s.atts.iter().flatten().any(|a| a.seg == i) { return Err("zcopy: segment still attached"); }
+        Ok(s.segs[i].take())
+    })?;
+    if let Some(g) = seg { crate::mm::uefi::free_pages(system_table, g.base as *mut u8, (g.size / 4096) as usize); }
+    if let Some(o) = info_flow::Object::segment(name) { info_flow::forget(o); }
+    Ok(())
+}
+
+/// Map the window of attachment `i` into its VM's stage-2 table if it is
+/// placed and not mapped yet.
+fn map_one(system_table: &SystemTable<Boot>, i: usize) -> Result<bool, &'static str> {
+    let Some((a, g)) = STATE.lock(|s| s.atts[i].and_then(|a| s.segs[a.seg].map(|g| (a, g)))) else { return Ok(false) };
+    if a.mapped || a.window == 0 { return Ok(false); }
+    let Some((root, fmt, ram)) = table_of(a.vm_id) else { return Ok(false) };
+    if a.window < ram { return Err("zcopy: BAR window overlaps guest RAM"); }
+    stage2::map_range(system_table, fmt, root, a.window, g.size, g.base, a.perm == Perm::Rw)?;
+    STATE.lock(|s| if let Some(x) = s.atts[i].as_mut() { if x.window == a.window { x.mapped = true; } });
+    Ok(true)
+}
+
+/// Attach segment `name` to `vm_id`. Returns (peer ID, PCI device number).
+pub fn attach(system_table: &SystemTable<Boot>, name: &str, vm_id: u64, perm: Perm) -> Result<(u16, u8), &'static str> {
+    check_flow(name, vm_id, perm)?;
+    let (idx, peer) = STATE.lock(|s| {
+        let seg = find_seg(s, name).ok_or("zcopy: no such segment")?;
+        if s.atts.iter().flatten().any(|a| a.seg == seg && a.vm_id == vm_id) { return Err("zcopy: already attached"); }
+        let peer = (0..MAX_PEERS).find(|p| !s.atts.iter().flatten().any(|a| a.seg == seg && a.peer == *p)).ok_or("zcopy: segment has no free peer ID")?;
+        let i = s.atts.iter().position(|a| a.is_none()).ok_or("zcopy: too many attachments")?;
+        s.atts[i] = Some(Attach {
+            seg, vm_id, peer, perm, dev: 0, regs: 0, window: 0, mapped: false,
+            intr_mask: 0, intr_status: 0, doorbells_in: 0, doorbells_out: 0,
+        });
+        Ok((i, peer))
+    })?;
+    let size = STATE.lock(|s| s.segs[s.atts[idx].map(|a| a.seg).unwrap_or(0)].map(|g| g.size).unwrap_or(MIN_SIZE));
+    let mut cfg = crate::hv::vpci::Config::new(IVSHMEM_VENDOR, IVSHMEM_DEVICE, 0x05, 0x00, 1);
+    cfg.put16(crate::hv::vpci::CFG_SUBSYS_VENDOR, IVSHMEM_VENDOR);
+    cfg.put16(crate::hv::vpci::CFG_SUBSYS_ID, 0x1100);
+    cfg.bar32(0, REGS_SIZE);
+    cfg.bar64(2, size);
+    cfg.intx(DEFAULT_IRQ);
+    let dev = match crate::hv::vpci::add(vm_id, cfg, idx as u64, on_bar) {
+        Some(d) => d,
+        None => { STATE.lock(|s| s.atts[idx] = None); return Err("zcopy: no free PCI slot"); }
+    };
+    STATE.lock(|s| if let Some(a) = s.atts[idx].as_mut() { a.dev = dev; });
+    if let Err(e) = map_one(system_table, idx) {
+        detach_one(idx);
+        return Err(e);
+    }
+    Ok((peer, dev))
+}
+
+/// A read-only attachment lets data flow from the segment into the VM; a
+/// writable one also the other way.
+fn check_flow(name: &str, vm_id: u64, perm: Perm) -> Result<(), &'static str> {
+    let seg = info_flow::Object::segment(name).ok_or("zcopy: no such segment")?;
+    let vm = info_flow::Object::Vm(vm_id);
+    match perm { Perm::Ro => info_flow::check(seg, vm), Perm::Rw => info_flow::check_both(seg, vm) }
+}
+
+fn detach_one(i: usize) {
+    let a = STATE.lock(|s| {
+        let size = s.atts[i].and_then(|a| s.segs[a.seg]).map(|g| g.size).unwrap_or(0);
+        let mut a = s.atts[i].take()?;
+        unmap_window(&mut a, size);
+        Some(a)
+    });
+    if let Some(a) = a { crate::hv::vpci::remove(a.vm_id, a.dev); }
+}
+
+/// Remove `vm_id`'s attachment to segment `name`.
+pub fn detach(name: &str, vm_id: u64) -> Result<(), &'static str> {
+    let i = STATE.lock(|s| {
+        let seg = find_seg(s, name).ok_or("zcopy: no such segment")?;
+        s.atts.iter().position(|a| matches!(a, Some(a) if a.seg == seg && a.vm_id == vm_id)).ok_or("zcopy: not attached")
+    })?;
+    detach_one(i);
+    Ok(())
+}
+
+/// Change the access `vm_id` has to segment `name`.
+pub fn set_perm(name: &str, vm_id: u64, perm: Perm) -> Result<(), &'static str> {
+    check_flow(name, vm_id, perm)?;
+    STATE.lock(|s| {
+        let seg = find_seg(s, name).ok_or("zcopy: no such segment")?;
+        let g = s.segs[seg].ok_or("zcopy: no such segment")?;
+        let a = s.atts.iter_mut().flatten().find(|a| a.seg == seg && a.vm_id == vm_id).ok_or("zcopy: not attached")?;
+        a.perm = perm;
+        if a.mapped {
+            let (root, fmt, _) = table_of(vm_id).ok_or("zcopy: vm has no stage-2 table")?;
+            let mut off = 0;
+            while off < g.size {
+                let _ = stage2::remap_page(fmt, root, a.window + off, g.base + off, perm == Perm::Rw, false);
+                off += 4096;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Map windows the guest moved since the last call. Returns windows mapped.
+pub fn pump(system_table: &mut SystemTable<Boot>) -> usize {
+    let st: &SystemTable<Boot> = system_table;
+    (0..MAX_ATTACH).filter(|&i| matches!(map_one(st, i), Ok(true))).count()
+}
+
+/// Map `vm_id`'s windows into the stage-2 table of a newly loaded image.
+pub fn rebind(system_table: &SystemTable<Boot>, vm_id: u64) {
+    STATE.lock(|s| { for a in s.atts.iter_mut().flatten().filter(|a| a.vm_id == vm_id) { a.mapped = false; } });
+    for i in 0..MAX_ATTACH {
+        if STATE.lock(|s| matches!(s.atts[i], Some(a) if a.vm_id == vm_id)) { let _ = map_one(system_table, i); }
+    }
+}
+
+/// Drop every attachment of `vm_id` (its PCI bus goes with the VM).
+pub fn detach_vm(vm_id: u64) {
+    STATE.lock(|s| { for a in s.atts.iter_mut() { if matches!(a, Some(x) if x.vm_id == vm_id) { *a = None; } } });
+}
+
+/// Segment state for reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct SegmentInfo { pub name: [u8; NAME_MAX], pub name_len: usize, pub base: u64, pub size: u64, pub attached: u32 }
+
+impl SegmentInfo {
+    pub fn name(&self) -> &str { core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("?") }
+}
+
+/// Attachment state for reporting.
+#[derive(Clone, Copy, Debug)]
+pub struct AttachInfo {
+    pub seg: usize,
+    pub vm_id: u64,
+    pub peer: u16,
+    pub perm: Perm,
+    pub dev: u8,
+    pub window: u64,
+    pub mapped: bool,
+    pub doorbells_in: u64,
+    pub doorbells_out: u64,
+}
+
+/// Iterate segments as (index, info).
+pub fn for_each(mut f: impl FnMut(usize, &SegmentInfo)) {
+    let (segs, atts) = STATE.lock(|s| (s.segs, s.atts));
+    for (i, seg) in segs.iter().enumerate() {
+        let Some(seg) = seg else { continue };
+        let attached = atts.iter().flatten().filter(|a| a.seg == i).count() as u32;
+        f(i, &SegmentInfo { name: seg.name, name_len: seg.name_len, base: seg.base, size: seg.size, attached });
+    }
+}
+
+/// Iterate the attachments of segment `seg`.
+pub fn for_each_attach(seg: usize, mut f: impl FnMut(&AttachInfo)) {
+    let atts = STATE.lock(|s| s.atts);
+    for a in atts.iter().flatten().filter(|a| a.seg == seg) {
+        f(&AttachInfo {
+            seg, vm_id: a.vm_id, peer: a.peer, perm: a.perm, dev: a.dev, window: a.window, mapped: a.mapped,
+            doorbells_in: a.doorbells_in, doorbells_out: a.doorbells_out,
+        });
+    }
+}
@@ -1,8 +1,11 @@
 //! Minimal i18n message resolver.
 //!
-//! This module provides a tiny message table for English/Japanese/Chinese with a
-//! stable set of keys used by the bootstrap. It avoids allocations and keeps
-//! string lifetimes static for UEFI text output.
+//! Messages live in per-language tables indexed like `KEYS`: English,
+//! Japanese and Chinese are built in, and one more catalog can be loaded from
+//! the ESP at boot (`load_catalog`) to add a locale such as German or Korean.
+//! A missing message falls back to the catalog's fallback language, then to
+//! English. It avoids allocations and keeps string lifetimes static for UEFI
+//! text output.
 
 /// Supported languages.
 #[allow(dead_code)]
@@ -11,12 +14,16 @@ pub enum Lang {
     En,
     Ja,
     Zh,
+    /// The catalog loaded from the ESP
+    Ext,
 }
 
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
 use uefi::table::runtime::VariableVendor;
 use uefi::{cstr16};
+use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode};
+use uefi::CStr16;
 use core::sync::atomic::{AtomicU8, Ordering};
 
 /// Try to parse UEFI `PlatformLang` (RFC 3066 like "en-US", "ja-JP", "zh-CN").
@@ -49,18 +56,20 @@ fn parse_platform_lang_ascii(bytes: &[u8]) -> Option<Lang> {
         if s.len() < pat.len() { return false; }
         eq_ci(&s[..pat.len()], pat)
     };
+    let ext = ext_tag();
+    if !ext.is_empty() && starts_with_ci(ext.as_bytes()) { return Some(Lang::Ext); }
     if starts_with_ci(b"en") { return Some(Lang::En); }
     if starts_with_ci(b"ja") { return Some(Lang::Ja); }
     if starts_with_ci(b"zh") { return Some(Lang::Zh); }
     None
 }
 
-// Optional runtime override (0: auto, 1: en, 2: ja, 3: zh)
+// Optional runtime override (0: auto, 1: en, 2: ja, 3: zh, 4: loaded catalog)
 static OVERRIDE_LANG: AtomicU8 = AtomicU8::new(0);
 
 #[inline(always)]
 pub fn set_lang_override(l: Option<Lang>) {
-    let v = match l { None => 0u8, Some(Lang::En) => 1, Some(Lang::Ja) => 2, Some(Lang::Zh) => 3 };
+    let v = match l { None => 0u8, Some(Lang::En) => 1, Some(Lang::Ja) => 2, Some(Lang::Zh) => 3, Some(Lang::Ext) => 4 };
     OVERRIDE_LANG.store(v, Ordering::Relaxed);
 }
 
@@ -70,10 +79,18 @@ fn read_lang_override() -> Option<Lang> {
         1 => Some(Lang::En),
         2 => Some(Lang::Ja),
         3 => Some(Lang::Zh),
+        4 if !ext_tag().is_empty() => Some(Lang::Ext),
         _ => None,
     }
 }
 
+/// The language chosen by hand, set this boot or saved by an earlier one;
+/// `None` means `PlatformLang` decides.
+pub fn current_override(system_table: &SystemTable<Boot>) -> Option<Lang> {
+    if OVERRIDE_LANG.load(Ordering::Relaxed) != 0 { return read_lang_override(); }
+    read_persisted_override(system_table)
+}
+
 /// Select the language based on UEFI `PlatformLang` variable when available,
 /// falling back to English to maximize compatibility.
 #[inline(always)]
@@ -119,10 +136,12 @@ pub fn save_lang_override(system_table: &SystemTable<Boot>) {
     let rs = system_table.runtime_services();
     let name = cstr16!("ZerovisorLang");
     let vendor = VariableVendor::GLOBAL_VARIABLE;
+    let mut ext = [0u8; TAG_MAX + 1];
     let bytes: &[u8] = match v {
         1 => b"en\0",
         2 => b"ja\0",
         3 => b"zh\0",
+        4 => { let t = ext_tag().as_bytes(); ext[..t.len()].copy_from_slice(t); &ext[..t.len() + 1] }
         _ => b"auto\0",
     };
     let _ = rs.set_variable(name, &vendor, uefi::table::runtime::VariableAttributes::BOOTSERVICE_ACCESS, bytes);
@@ -177,211 +196,455 @@ pub mod key {
     pub const MIG_CHAN_NEW_FAIL: &str = "migrate_chan_new_fail";
     pub const MIG_CHAN_CLEARED: &str = "migrate_chan_cleared";
     pub const MIG_NO_BUFFER: &str = "migrate_no_buffer";
-    pub const MIG_NET_MAC_PREFIX: &str = "migrate_net_mac_prefix";
-    pub const MIG_NET_MTU_PREFIX: &str = "migrate_net_mtu_prefix";
+    pub const MIG_NET_MAC: &str = "migrate_net_mac";
+    pub const MIG_NET_MTU: &str = "migrate_net_mtu";
     pub const MIG_NET_MAC_UPDATED: &str = "migrate_net_mac_updated";
     pub const MIG_NET_MTU_UPDATED: &str = "migrate_net_mtu_updated";
     pub const MIG_NET_USAGE: &str = "migrate_net_usage";
     pub const MIG_NET_MAC_USAGE: &str = "migrate_net_mac_usage";
     pub const MIG_NET_MTU_USAGE: &str = "migrate_net_mtu_usage";
-    pub const MIG_NET_ETHER_PREFIX: &str = "migrate_net_ether_prefix";
+    pub const MIG_NET_ETHER: &str = "migrate_net_ether";
     pub const MIG_NET_ETHER_UPDATED: &str = "migrate_net_ether_updated";
     pub const MIG_NET_ETHER_USAGE: &str = "migrate_net_ether_usage";
     pub const IOMMU_CFG_SAVED: &str = "iommu_cfg_saved";
     pub const IOMMU_CFG_LOADED: &str = "iommu_cfg_loaded";
 }
 
-/// Resolve a message key for a given language.
-#[inline(always)]
+type Table = [(&'static str, &'static str); KEY_COUNT];
+
+pub const KEY_COUNT: usize = 59;
+
+/// English; every fallback chain ends here.
+static EN: Table = [
+    (key::BANNER, "Zerovisor: UEFI bootstrap started\r\n"),
+    (key::ENV, "Environment: x86_64 UEFI application\r\n"),
+    (key::READY, "Status: Initialization complete\r\n"),
+    (key::FEAT_VMX, "Feature: Intel VMX\r\n"),
+    (key::FEAT_SVM, "Feature: AMD SVM\r\n"),
+    (key::FEAT_EPT, "Feature: Intel EPT (hint)\r\n"),
+    (key::FEAT_NPT, "Feature: AMD NPT\r\n"),
+    (key::FEAT_VTD, "Feature: Intel VT-d (ACPI DMAR)\r\n"),
+    (key::FEAT_AMDVI, "Feature: AMD-Vi (ACPI IVRS)\r\n"),
+    (key::HPET_PRESENT, "HPET: present, base=0x{0} freq={1} MHz\r\n"),
+    (key::HPET_NOT_FOUND, "HPET: not found\r\n"),
+    (key::SMP_EXPECTED, "SMP: expected CPUs={0}\r\n"),
+    (key::SMP_OBSERVED, "SMP: observed AP IDs={0}\r\n"),
+    (key::SMP_PM_OK, "SMP: AP PM-entry OK\r\n"),
+    (key::SMP_PM_NG, "SMP: AP PM-entry not observed\r\n"),
+    (key::SMP_LM_OK, "SMP: AP LM-entry OK\r\n"),
+    (key::SMP_LM_NG, "SMP: AP LM-entry not observed\r\n"),
+    (key::SMP_LM_COUNT, "SMP: AP LM-count={0}\r\n"),
+    (key::SMP_APIC_BYTE, "SMP: AP APIC-ID(byte)={0}\r\n"),
+    (key::SMP_AP_IDS, "SMP: AP IDs={0}\r\n"),
+    (key::SMP_READY, "SMP: AP READY={0}\r\n"),
+    (key::VIRTIO_SCAN, "VirtIO: scanning ECAM segments\r\n"),
+    (key::VIRTIO_NONE, "VirtIO: no devices found\r\n"),
+    (key::IOMMU_VTD_NONE, "VT-d: DMAR not found\r\n"),
+    (key::IOMMU_AMDV_NONE, "AMD-Vi: IVRS not found\r\n"),
+    (key::VIRTIO_BLK, "VirtIO-blk: capacity={0} MiB\r\n"),
+    (key::VIRTIO_BLK_NONE, "VirtIO-blk: not found\r\n"),
+    (key::VIRTIO_NET, "VirtIO-net: present\r\n"),
+    (key::VIRTIO_NET_NONE, "VirtIO-net: not found\r\n"),
+    (key::SEC_WP_ON, "Security: CR0.WP=ON\r\n"),
+    (key::SEC_WP_OFF, "Security: CR0.WP=OFF\r\n"),
+    (key::SEC_SMEP_ON, "Security: CR4.SMEP=ON\r\n"),
+    (key::SEC_SMEP_OFF, "Security: CR4.SMEP=OFF\r\n"),
+    (key::SEC_SMAP_ON, "Security: CR4.SMAP=ON\r\n"),
+    (key::SEC_SMAP_OFF, "Security: CR4.SMAP=OFF\r\n"),
+    (key::SEC_NXE_ON, "Security: EFER.NXE=ON\r\n"),
+    (key::SEC_NXE_OFF, "Security: EFER.NXE=OFF\r\n"),
+    (key::SEC_SUMMARY_OK, "Security: protections OK (WP/SMEP/SMAP/NXE)\r\n"),
+    (key::SEC_SUMMARY_NG, "Security: protections NOT fully enabled\r\n"),
+    (key::MIG_TRACK_START_OK, "migrate: tracking started\r\n"),
+    (key::MIG_TRACK_START_FAIL, "migrate: start failed\r\n"),
+    (key::MIG_TRACK_STOP_OK, "migrate: tracking stopped\r\n"),
+    (key::MIG_TRACK_STOP_FAIL, "migrate: stop failed\r\n"),
+    (key::MIG_CHAN_NEW_OK, "migrate: chan new ok\r\n"),
+    (key::MIG_CHAN_NEW_FAIL, "migrate: chan new failed\r\n"),
+    (key::MIG_CHAN_CLEARED, "migrate: chan cleared\r\n"),
+    (key::MIG_NO_BUFFER, "migrate: no buffer\r\n"),
+    (key::MIG_NET_MAC, "net: mac={0}\r\n"),
+    (key::MIG_NET_MTU, "net: mtu={0}\r\n"),
+    (key::MIG_NET_MAC_UPDATED, "net: mac updated\r\n"),
+    (key::MIG_NET_MTU_UPDATED, "net: mtu updated\r\n"),
+    (key::MIG_NET_USAGE, "usage: migrate net [mac|mtu] ...\r\n"),
+    (key::MIG_NET_MAC_USAGE, "usage: migrate net mac [get|set xx:xx:xx:xx:xx:xx]\r\n"),
+    (key::MIG_NET_MTU_USAGE, "usage: migrate net mtu [get|set <n>]\r\n"),
+    (key::MIG_NET_ETHER, "net: ether=0x{0}\r\n"),
+    (key::MIG_NET_ETHER_UPDATED, "net: ether updated\r\n"),
+    (key::MIG_NET_ETHER_USAGE, "usage: migrate net ether [get|set <hex>]\r\n"),
+    (key::IOMMU_CFG_SAVED, "iommu: cfg saved\r\n"),
+    (key::IOMMU_CFG_LOADED, "iommu: cfg loaded\r\n"),
+];
+
+/// Japanese
+static JA: Table = [
+    (key::BANNER, "Zerovisor: UEFIブート開始\r\n"),
+    (key::ENV, "環境: x86_64 UEFI アプリケーション\r\n"),
+    (key::READY, "状態: 初期化完了\r\n"),
+    (key::FEAT_VMX, "機能: Intel VMX\r\n"),
+    (key::FEAT_SVM, "機能: AMD SVM\r\n"),
+    (key::FEAT_EPT, "機能: Intel EPT（示唆）\r\n"),
+    (key::FEAT_NPT, "機能: AMD NPT\r\n"),
+    (key::FEAT_VTD, "機能: Intel VT-d（ACPI DMAR）\r\n"),
+    (key::FEAT_AMDVI, "機能: AMD-Vi（ACPI IVRS）\r\n"),
+    (key::HPET_PRESENT, "HPET: 検出 base=0x{0} 周波数={1} MHz\r\n"),
+    (key::HPET_NOT_FOUND, "HPET: 見つかりません\r\n"),
+    (key::SMP_EXPECTED, "SMP: 期待CPU数={0}\r\n"),
+    (key::SMP_OBSERVED, "SMP: 観測AP ID数={0}\r\n"),
+    (key::SMP_PM_OK, "SMP: AP 保護モード到達 OK\r\n"),
+    (key::SMP_PM_NG, "SMP: AP 保護モード未到達\r\n"),
+    (key::SMP_LM_OK, "SMP: AP 長モード到達 OK\r\n"),
+    (key::SMP_LM_NG, "SMP: AP 長モード未到達\r\n"),
+    (key::SMP_LM_COUNT, "SMP: AP 長モード回数={0}\r\n"),
+    (key::SMP_APIC_BYTE, "SMP: AP APIC-ID(下位1B)={0}\r\n"),
+    (key::SMP_AP_IDS, "SMP: AP ID配列={0}\r\n"),
+    (key::SMP_READY, "SMP: AP READY={0}\r\n"),
+    (key::VIRTIO_SCAN, "VirtIO: ECAMセグメントを走査中\r\n"),
+    (key::VIRTIO_NONE, "VirtIO: デバイスが見つかりません\r\n"),
+    (key::IOMMU_VTD_NONE, "VT-d: DMARが見つかりません\r\n"),
+    (key::IOMMU_AMDV_NONE, "AMD-Vi: IVRSが見つかりません\r\n"),
+    (key::VIRTIO_BLK, "VirtIO-blk: 容量={0} MiB\r\n"),
+    (key::VIRTIO_BLK_NONE, "VirtIO-blk: 見つかりません\r\n"),
+    (key::VIRTIO_NET, "VirtIO-net: 検出\r\n"),
+    (key::VIRTIO_NET_NONE, "VirtIO-net: 見つかりません\r\n"),
+    (key::SEC_WP_ON, "セキュリティ: CR0.WP=有効\r\n"),
+    (key::SEC_WP_OFF, "セキュリティ: CR0.WP=無効\r\n"),
+    (key::SEC_SMEP_ON, "セキュリティ: CR4.SMEP=有効\r\n"),
+    (key::SEC_SMEP_OFF, "セキュリティ: CR4.SMEP=無効\r\n"),
+    (key::SEC_SMAP_ON, "セキュリティ: CR4.SMAP=有効\r\n"),
+    (key::SEC_SMAP_OFF, "セキュリティ: CR4.SMAP=無効\r\n"),
+    (key::SEC_NXE_ON, "セキュリティ: EFER.NXE=有効\r\n"),
+    (key::SEC_NXE_OFF, "セキュリティ: EFER.NXE=無効\r\n"),
+    (key::SEC_SUMMARY_OK, "セキュリティ: 保護は有効（WP/SMEP/SMAP/NXE）\r\n"),
+    (key::SEC_SUMMARY_NG, "セキュリティ: 保護が十分ではありません\r\n"),
+    (key::MIG_TRACK_START_OK, "migrate: 追跡を開始しました\r\n"),
+    (key::MIG_TRACK_START_FAIL, "migrate: 開始に失敗しました\r\n"),
+    (key::MIG_TRACK_STOP_OK, "migrate: 追跡を停止しました\r\n"),
+    (key::MIG_TRACK_STOP_FAIL, "migrate: 停止に失敗しました\r\n"),
+    (key::MIG_CHAN_NEW_OK, "migrate: チャネル作成に成功\r\n"),
+    (key::MIG_CHAN_NEW_FAIL, "migrate: チャネル作成に失敗\r\n"),
+    (key::MIG_CHAN_CLEARED, "migrate: チャネルをクリアしました\r\n"),
+    (key::MIG_NO_BUFFER, "migrate: バッファがありません\r\n"),
+    (key::MIG_NET_MAC, "net: MAC={0}\r\n"),
+    (key::MIG_NET_MTU, "net: MTU={0}\r\n"),
+    (key::MIG_NET_MAC_UPDATED, "net: MACを更新しました\r\n"),
+    (key::MIG_NET_MTU_UPDATED, "net: MTUを更新しました\r\n"),
+    (key::MIG_NET_USAGE, "usage: migrate net [mac|mtu] ...\r\n"),
+    (key::MIG_NET_MAC_USAGE, "usage: migrate net mac [get|set xx:xx:xx:xx:xx:xx]\r\n"),
+    (key::MIG_NET_MTU_USAGE, "usage: migrate net mtu [get|set <n>]\r\n"),
+    (key::MIG_NET_ETHER, "net: EtherType=0x{0}\r\n"),
+    (key::MIG_NET_ETHER_UPDATED, "net: EtherTypeを更新しました\r\n"),
+    (key::MIG_NET_ETHER_USAGE, "usage: migrate net ether [get|set <hex>]\r\n"),
+    (key::IOMMU_CFG_SAVED, "iommu: 設定を保存しました\r\n"),
+    (key::IOMMU_CFG_LOADED, "iommu: 設定を読み込みました\r\n"),
+];
+
+/// Simplified Chinese
+static ZH: Table = [
+    (key::BANNER, "Zerovisor: UEFI 引导已开始\r\n"),
+    (key::ENV, "环境: x86_64 UEFI 应用程序\r\n"),
+    (key::READY, "状态: 初始化完成\r\n"),
+    (key::FEAT_VMX, "功能: Intel VMX\r\n"),
+    (key::FEAT_SVM, "功能: AMD SVM\r\n"),
+    (key::FEAT_EPT, "功能: Intel EPT（提示）\r\n"),
+    (key::FEAT_NPT, "功能: AMD NPT\r\n"),
+    (key::FEAT_VTD, "功能: Intel VT-d（ACPI DMAR）\r\n"),
+    (key::FEAT_AMDVI, "功能: AMD-Vi（ACPI IVRS）\r\n"),
+    (key::HPET_PRESENT, "HPET: 已检测 base=0x{0} 频率={1} MHz\r\n"),
+    (key::HPET_NOT_FOUND, "HPET: 未找到\r\n"),
+    (key::SMP_EXPECTED, "SMP: 预期CPU数={0}\r\n"),
+    (key::SMP_OBSERVED, "SMP: 已观测AP ID数={0}\r\n"),
+    (key::SMP_PM_OK, "SMP: AP 保护模式就绪\r\n"),
+    (key::SMP_PM_NG, "SMP: AP 保护模式未就绪\r\n"),
+    (key::SMP_LM_OK, "SMP: AP 长模式就绪\r\n"),
+    (key::SMP_LM_NG, "SMP: AP 长模式未就绪\r\n"),
+    (key::SMP_LM_COUNT, "SMP: AP 长模式计数={0}\r\n"),
+    (key::SMP_APIC_BYTE, "SMP: AP APIC-ID(低1字节)={0}\r\n"),
+    (key::SMP_AP_IDS, "SMP: AP ID列表={0}\r\n"),
+    (key::SMP_READY, "SMP: AP READY={0}\r\n"),
+    (key::VIRTIO_SCAN, "VirtIO: 正在扫描ECAM段\r\n"),
+    (key::VIRTIO_NONE, "VirtIO: 未找到设备\r\n"),
+    (key::IOMMU_VTD_NONE, "VT-d: 未找到DMAR\r\n"),
+    (key::IOMMU_AMDV_NONE, "AMD-Vi: 未找到IVRS\r\n"),
+    (key::VIRTIO_BLK, "VirtIO-blk: 容量={0} MiB\r\n"),
+    (key::VIRTIO_BLK_NONE, "VirtIO-blk: 未找到\r\n"),
+    (key::VIRTIO_NET, "VirtIO-net: 已检测\r\n"),
+    (key::VIRTIO_NET_NONE, "VirtIO-net: 未找到\r\n"),
+    (key::SEC_WP_ON, "安全: CR0.WP=启用\r\n"),
+    (key::SEC_WP_OFF, "安全: CR0.WP=未启用\r\n"),
+    (key::SEC_SMEP_ON, "安全: CR4.SMEP=启用\r\n"),
+    (key::SEC_SMEP_OFF, "安全: CR4.SMEP=未启用\r\n"),
+    (key::SEC_SMAP_ON, "安全: CR4.SMAP=启用\r\n"),
+    (key::SEC_SMAP_OFF, "安全: CR4.SMAP=未启用\r\n"),
+    (key::SEC_NXE_ON, "安全: EFER.NXE=启用\r\n"),
+    (key::SEC_NXE_OFF, "安全: EFER.NXE=未启用\r\n"),
+    (key::SEC_SUMMARY_OK, "安全: 保护正常（WP/SMEP/SMAP/NXE）\r\n"),
+    (key::SEC_SUMMARY_NG, "安全: 保护未完全启用\r\n"),
+    (key::MIG_TRACK_START_OK, "migrate: 已开始跟踪\r\n"),
+    (key::MIG_TRACK_START_FAIL, "migrate: 启动失败\r\n"),
+    (key::MIG_TRACK_STOP_OK, "migrate: 已停止跟踪\r\n"),
+    (key::MIG_TRACK_STOP_FAIL, "migrate: 停止失败\r\n"),
+    (key::MIG_CHAN_NEW_OK, "migrate: 通道创建成功\r\n"),
+    (key::MIG_CHAN_NEW_FAIL, "migrate: 通道创建失败\r\n"),
+    (key::MIG_CHAN_CLEARED, "migrate: 通道已清空\r\n"),
+    (key::MIG_NO_BUFFER, "migrate: 无缓冲区\r\n"),
+    (key::MIG_NET_MAC, "net: MAC={0}\r\n"),
+    (key::MIG_NET_MTU, "net: MTU={0}\r\n"),
+    (key::MIG_NET_MAC_UPDATED, "net: 已更新MAC\r\n"),
+    (key::MIG_NET_MTU_UPDATED, "net: 已更新MTU\r\n"),
+    (key::MIG_NET_USAGE, "usage: migrate net [mac|mtu] ...\r\n"),
+    (key::MIG_NET_MAC_USAGE, "usage: migrate net mac [get|set xx:xx:xx:xx:xx:xx]\r\n"),
+    (key::MIG_NET_MTU_USAGE, "usage: migrate net mtu [get|set <n>]\r\n"),
+    (key::MIG_NET_ETHER, "net: EtherType=0x{0}\r\n"),
+    (key::MIG_NET_ETHER_UPDATED, "net: 已更新EtherType\r\n"),
+    (key::MIG_NET_ETHER_USAGE, "usage: migrate net ether [get|set <hex>]\r\n"),
+    (key::IOMMU_CFG_SAVED, "iommu: 已保存配置\r\n"),
+    (key::IOMMU_CFG_LOADED, "iommu: 已加载配置\r\n"),
+];
+
+/// Position of `key` in the tables.
+fn index(key: &str) -> Option<usize> {
+    EN.iter().position(|&(k, _)| k == key)
+}
+
+/// Resolve a message key for a given language, following the fallback chain
+/// until some catalog has the message.
 pub fn t(lang: Lang, key: &str) -> &'static str {
+    let Some(i) = index(key) else { return "\r\n" };
+    let mut l = lang;
+    loop {
+        if let Some(s) = lookup(l, i) { return s; }
+        match fallback(l) {
+            Some(next) => l = next,
+            None => return "\r\n",
+        }
+    }
+}
+
+/// Value spliced into a message by `t_fmt`.
+#[derive(Clone, Copy, Debug)]
+pub enum Arg<'a> {
+    /// Unsigned decimal
+    Dec(u64),
+    /// Uppercase hex without a prefix; the message carries any `0x`
+    Hex(u64),
+    Str(&'a str),
+}
+
+pub const MSG_MAX: usize = 192;
+
+/// A message formatted by `t_fmt`, truncated to `MSG_MAX` bytes.
+pub struct Msg {
+    buf: [u8; MSG_MAX],
+    len: usize,
+}
+
+impl Msg {
+    pub fn as_str(&self) -> &str {
+        match core::str::from_utf8(&self.buf[..self.len]) {
+            Ok(s) => s,
+            // Truncation may split a UTF-8 sequence; keep the valid prefix.
+            Err(e) => core::str::from_utf8(&self.buf[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let take = bytes.len().min(MSG_MAX - self.len);
+        self.buf[self.len..self.len + take].copy_from_slice(&bytes[..take]);
+        self.len += take;
+    }
+}
+
+/// Resolve `key` like `t` and replace `{0}`..`{9}` with `args`, so each
+/// language can put the numbers where its grammar wants them. `{{` is a
+/// literal brace; a placeholder without an argument is kept as written.
+pub fn t_fmt(lang: Lang, key: &str, args: &[Arg]) -> Msg {
+    let tmpl = t(lang, key).as_bytes();
+    let mut m = Msg { buf: [0; MSG_MAX], len: 0 };
+    let mut i = 0;
+    while i < tmpl.len() {
+        if tmpl[i] == b'{' && tmpl.get(i + 1) == Some(&b'{') {
+            m.push(b"{");
+            i += 2;
+            continue;
+        }
+        if tmpl[i] == b'{' && tmpl.get(i + 2) == Some(&b'}') && tmpl[i + 1].is_ascii_digit() {
+            if let Some(arg) = args.get((tmpl[i + 1] - b'0') as usize) {
+                let mut num = [0u8; 20];
+                match *arg {
+                    Arg::Dec(v) => { let n = crate::util::format::u64_dec(v, &mut num); m.push(&num[..n]); }
+                    Arg::Hex(v) => { let n = crate::util::format::u64_hex(v, &mut num); m.push(&num[..n]); }
+                    Arg::Str(s) => m.push(s.as_bytes()),
+                }
+                i += 3;
+                continue;
+            }
+        }
+        m.push(&tmpl[i..i + 1]);
+        i += 1;
+    }
+    m
+}
+
+fn lookup(lang: Lang, i: usize) -> Option<&'static str> {
+    let s = match lang {
+        Lang::En => EN[i].1,
+        Lang::Ja => JA[i].1,
+        Lang::Zh => ZH[i].1,
+        Lang::Ext => ext_value(i),
+    };
+    if s.is_empty() { None } else { Some(s) }
+}
+
+fn fallback(lang: Lang) -> Option<Lang> {
     match lang {
-        Lang::En => match key {
-            key::BANNER => "Zerovisor: UEFI bootstrap started\r\n",
-            key::ENV => "Environment: x86_64 UEFI application\r\n",
-            key::READY => "Status: Initialization complete\r\n",
-            key::FEAT_VMX => "Feature: Intel VMX\r\n",
-            key::FEAT_SVM => "Feature: AMD SVM\r\n",
-            key::FEAT_EPT => "Feature: Intel EPT (hint)\r\n",
-            key::FEAT_NPT => "Feature: AMD NPT\r\n",
-            key::FEAT_VTD => "Feature: Intel VT-d (ACPI DMAR)\r\n",
-            key::FEAT_AMDVI => "Feature: AMD-Vi (ACPI IVRS)\r\n",
-            key::HPET_PRESENT => "HPET: present, base=0x",
-            key::HPET_NOT_FOUND => "HPET: not found\r\n",
-            key::SMP_EXPECTED => "SMP: expected CPUs=",
-            key::SMP_OBSERVED => "SMP: observed AP IDs=",
-            key::SMP_PM_OK => "SMP: AP PM-entry OK\r\n",
-            key::SMP_PM_NG => "SMP: AP PM-entry not observed\r\n",
-            key::SMP_LM_OK => "SMP: AP LM-entry OK\r\n",
-            key::SMP_LM_NG => "SMP: AP LM-entry not observed\r\n",
-            key::SMP_LM_COUNT => "SMP: AP LM-count=",
-            key::SMP_APIC_BYTE => "SMP: AP APIC-ID(byte)=",
-            key::SMP_AP_IDS => "SMP: AP IDs=",
-            key::SMP_READY => "SMP: AP READY=",
-            key::VIRTIO_SCAN => "VirtIO: scanning ECAM segments\r\n",
-            key::VIRTIO_NONE => "VirtIO: no devices found\r\n",
-            key::VIRTIO_BLK => "VirtIO-blk: capacity=",
-            key::VIRTIO_BLK_NONE => "VirtIO-blk: not found\r\n",
-            key::VIRTIO_NET => "VirtIO-net: present\r\n",
-            key::VIRTIO_NET_NONE => "VirtIO-net: not found\r\n",
-            key::IOMMU_VTD_NONE => "VT-d: DMAR not found\r\n",
-            key::IOMMU_AMDV_NONE => "AMD-Vi: IVRS not found\r\n",
-            key::SEC_WP_ON => "Security: CR0.WP=ON\r\n",
-            key::SEC_WP_OFF => "Security: CR0.WP=OFF\r\n",
-            key::SEC_SMEP_ON => "Security: CR4.SMEP=ON\r\n",
-            key::SEC_SMEP_OFF => "Security: CR4.SMEP=OFF\r\n",
-            key::SEC_SMAP_ON => "Security: CR4.SMAP=ON\r\n",
-            key::SEC_SMAP_OFF => "Security: CR4.SMAP=OFF\r\n",
-            key::SEC_NXE_ON => "Security: EFER.NXE=ON\r\n",
-            key::SEC_NXE_OFF => "Security: EFER.NXE=OFF\r\n",
-            key::SEC_SUMMARY_OK => "Security: protections OK (WP/SMEP/SMAP/NXE)\r\n",
-            key::SEC_SUMMARY_NG => "Security: protections NOT fully enabled\r\n",
-            key::MIG_TRACK_START_OK => "migrate: tracking started\r\n",
-            key::MIG_TRACK_START_FAIL => "migrate: start failed\r\n",
-            key::MIG_TRACK_STOP_OK => "migrate: tracking stopped\r\n",
-            key::MIG_TRACK_STOP_FAIL => "migrate: stop failed\r\n",
-            key::MIG_CHAN_NEW_OK => "migrate: chan new ok\r\n",
-            key::MIG_CHAN_NEW_FAIL => "migrate: chan new failed\r\n",
-            key::MIG_CHAN_CLEARED => "migrate: chan cleared\r\n",
-            key::MIG_NO_BUFFER => "migrate: no buffer\r\n",
-            key::MIG_NET_MAC_PREFIX => "net: mac=",
-            key::MIG_NET_MTU_PREFIX => "net: mtu=",
-            key::MIG_NET_MAC_UPDATED => "net: mac updated\r\n",
-            key::MIG_NET_MTU_UPDATED => "net: mtu updated\r\n",
-            key::MIG_NET_USAGE => "usage: migrate net [mac|mtu] ...\r\n",
-            key::MIG_NET_MAC_USAGE => "usage: migrate net mac [get|set xx:xx:xx:xx:xx:xx]\r\n",
-            key::MIG_NET_MTU_USAGE => "usage: migrate net mtu [get|set <n>]\r\n",
-            key::MIG_NET_ETHER_PREFIX => "net: ether=0x",
-            key::MIG_NET_ETHER_UPDATED => "net: ether updated\r\n",
-            key::MIG_NET_ETHER_USAGE => "usage: migrate net ether [get|set <hex>]\r\n",
-            key::IOMMU_CFG_SAVED => "iommu: cfg saved\r\n",
-            key::IOMMU_CFG_LOADED => "iommu: cfg loaded\r\n",
-            _ => "\r\n",
-        },
-        Lang::Ja => match key {
-            key::BANNER => "Zerovisor: UEFIブート開始\r\n",
-            key::ENV => "環境: x86_64 UEFI アプリケーション\r\n",
-            key::READY => "状態: 初期化完了\r\n",
-            key::FEAT_VMX => "機能: Intel VMX\r\n",
-            key::FEAT_SVM => "機能: AMD SVM\r\n",
-            key::FEAT_EPT => "機能: Intel EPT（示唆）\r\n",
-            key::FEAT_NPT => "機能: AMD NPT\r\n",
-            key::FEAT_VTD => "機能: Intel VT-d（ACPI DMAR）\r\n",
-            key::FEAT_AMDVI => "機能: AMD-Vi（ACPI IVRS）\r\n",
-            key::HPET_PRESENT => "HPET: 検出 base=0x",
-            key::HPET_NOT_FOUND => "HPET: 見つかりません\r\n",
-            key::SMP_EXPECTED => "SMP: 期待CPU数=",
-            key::SMP_OBSERVED => "SMP: 観測AP ID数=",
-            key::SMP_PM_OK => "SMP: AP 保護モード到達 OK\r\n",
-            key::SMP_PM_NG => "SMP: AP 保護モード未到達\r\n",
-            key::SMP_LM_OK => "SMP: AP 長モード到達 OK\r\n",
-            key::SMP_LM_NG => "SMP: AP 長モード未到達\r\n",
-            key::SMP_LM_COUNT => "SMP: AP 長モード回数=",
-            key::SMP_APIC_BYTE => "SMP: AP APIC-ID(下位1B)=",
-            key::SMP_AP_IDS => "SMP: AP ID配列=",
-            key::SMP_READY => "SMP: AP READY=",
-            key::VIRTIO_SCAN => "VirtIO: ECAMセグメントを走査中\r\n",
-            key::VIRTIO_NONE => "VirtIO: デバイスが見つかりません\r\n",
-            key::VIRTIO_BLK => "VirtIO-blk: 容量=",
-            key::VIRTIO_BLK_NONE => "VirtIO-blk: 見つかりません\r\n",
-            key::VIRTIO_NET => "VirtIO-net: 検出\r\n",
-            key::VIRTIO_NET_NONE => "VirtIO-net: 見つかりません\r\n",
-            key::IOMMU_VTD_NONE => "VT-d: DMARが見つかりません\r\n",
-            key::IOMMU_AMDV_NONE => "AMD-Vi: IVRSが見つかりません\r\n",
-            key::SEC_WP_ON => "セキュリティ: CR0.WP=有効\r\n",
-            key::SEC_WP_OFF => "セキュリティ: CR0.WP=無効\r\n",
-            key::SEC_SMEP_ON => "セキュリティ: CR4.SMEP=有効\r\n",
-            key::SEC_SMEP_OFF => "セキュリティ: CR4.SMEP=無効\r\n",
-            key::SEC_SMAP_ON => "セキュリティ: CR4.SMAP=有効\r\n",
-            key::SEC_SMAP_OFF => "セキュリティ: CR4.SMAP=無効\r\n",
-            key::SEC_NXE_ON => "セキュリティ: EFER.NXE=有効\r\n",
-            key::SEC_NXE_OFF => "セキュリティ: EFER.NXE=無効\r\n",
-            key::SEC_SUMMARY_OK => "セキュリティ: 保護は有効（WP/SMEP/SMAP/NXE）\r\n",
-            key::SEC_SUMMARY_NG => "セキュリティ: 保護が十分ではありません\r\n",
-            key::MIG_TRACK_START_OK => "migrate: 追跡を開始しました\r\n",
-            key::MIG_TRACK_START_FAIL => "migrate: 開始に失敗しました\r\n",
-            key::MIG_TRACK_STOP_OK => "migrate: 追跡を停止しました\r\n",
-            key::MIG_TRACK_STOP_FAIL => "migrate: 停止に失敗しました\r\n",
-            key::MIG_CHAN_NEW_OK => "migrate: チャネル作成に成功\r\n",
-            key::MIG_CHAN_NEW_FAIL => "migrate: チャネル作成に失敗\r\n",
-            key::MIG_CHAN_CLEARED => "migrate: チャネルをクリアしました\r\n",
-            key::MIG_NO_BUFFER => "migrate: バッファがありません\r\n",
-            key::MIG_NET_MAC_PREFIX => "net: MAC=",
-            key::MIG_NET_MTU_PREFIX => "net: MTU=",
-            key::MIG_NET_MAC_UPDATED => "net: MACを更新しました\r\n",
-            key::MIG_NET_MTU_UPDATED => "net: MTUを更新しました\r\n",
-            key::MIG_NET_USAGE => "usage: migrate net [mac|mtu] ...\r\n",
-            key::MIG_NET_MAC_USAGE => "usage: migrate net mac [get|set xx:xx:xx:xx:xx:xx]\r\n",
-            key::MIG_NET_MTU_USAGE => "usage: migrate net mtu [get|set <n>]\r\n",
-            key::MIG_NET_ETHER_PREFIX => "net: EtherType=0x",
-            key::MIG_NET_ETHER_UPDATED => "net: EtherTypeを更新しました\r\n",
-            key::MIG_NET_ETHER_USAGE => "usage: migrate net ether [get|set <hex>]\r\n",
-            key::IOMMU_CFG_SAVED => "iommu: 設定を保存しました\r\n",
-            key::IOMMU_CFG_LOADED => "iommu: 設定を読み込みました\r\n",
-            _ => "\r\n",
-        },
-        Lang::Zh => match key {
-            key::BANNER => "Zerovisor: UEFI 引导已开始\r\n",
-            key::ENV => "环境: x86_64 UEFI 应用程序\r\n",
-            key::READY => "状态: 初始化完成\r\n",
-            key::FEAT_VMX => "功能: Intel VMX\r\n",
-            key::FEAT_SVM => "功能: AMD SVM\r\n",
-            key::FEAT_EPT => "功能: Intel EPT（提示）\r\n",
-            key::FEAT_NPT => "功能: AMD NPT\r\n",
-            key::FEAT_VTD => "功能: Intel VT-d（ACPI DMAR）\r\n",
-            key::FEAT_AMDVI => "功能: AMD-Vi（ACPI IVRS）\r\n",
-            key::HPET_PRESENT => "HPET: 已检测 base=0x",
-            key::HPET_NOT_FOUND => "HPET: 未找到\r\n",
-            key::SMP_EXPECTED => "SMP: 预期CPU数=",
-            key::SMP_OBSERVED => "SMP: 已观测AP ID数=",
-            key::SMP_PM_OK => "SMP: AP 保护模式就绪\r\n",
-            key::SMP_PM_NG => "SMP: AP 保护模式未就绪\r\n",
-            key::SMP_LM_OK => "SMP: AP 长模式就绪\r\n",
-            key::SMP_LM_NG => "SMP: AP 长模式未就绪\r\n",
-            key::SMP_LM_COUNT => "SMP: AP 长模式计数=",
-            key::SMP_APIC_BYTE => "SMP: AP APIC-ID(低1字节)=",
-            key::SMP_AP_IDS => "SMP: AP ID列表=",
-            key::SMP_READY => "SMP: AP READY=",
-            key::VIRTIO_SCAN => "VirtIO: 正在扫描ECAM段\r\n",
-            key::VIRTIO_NONE => "VirtIO: 未找到设备\r\n",
-            key::VIRTIO_BLK => "VirtIO-blk: 容量=",
-            key::VIRTIO_BLK_NONE => "VirtIO-blk: 未找到\r\n",
-            key::VIRTIO_NET => "VirtIO-net: 已检测\r\n",
-            key::VIRTIO_NET_NONE => "VirtIO-net: 未找到\r\n",
-            key::IOMMU_VTD_NONE => "VT-d: 未找到DMAR\r\n",
-            key::IOMMU_AMDV_NONE => "AMD-Vi: 未找到IVRS\r\n",
-            key::SEC_WP_ON => "安全: CR0.WP=启用\r\n",
-            key::SEC_WP_OFF => "安全: CR0.WP=未启用\r\n",
-            key::SEC_SMEP_ON => "安全: CR4.SMEP=启用\r\n",
-            key::SEC_SMEP_OFF => "安全: CR4.SMEP=未启用\r\n",
-            key::SEC_SMAP_ON => "安全: CR4.SMAP=启用\r\n",
-            key::SEC_SMAP_OFF => "安全: CR4.SMAP=未启用\r\n",
-            key::SEC_NXE_ON => "安全: EFER.NXE=启用\r\n",
-            key::SEC_NXE_OFF => "安全: EFER.NXE=未启用\r\n",
-            key::SEC_SUMMARY_OK => "安全: 保护正常（WP/SMEP/SMAP/NXE）\r\n",
-            key::SEC_SUMMARY_NG => "安全: 保护未完全启用\r\n",
-            key::MIG_TRACK_START_OK => "migrate: 已开始跟踪\r\n",
-            key::MIG_TRACK_START_FAIL => "migrate: 启动失败\r\n",
-            key::MIG_TRACK_STOP_OK => "migrate: 已停止跟踪\r\n",
-            key::MIG_TRACK_STOP_FAIL => "migrate: 停止失败\r\n",
-            key::MIG_CHAN_NEW_OK => "migrate: 通道创建成功\r\n",
-            key::MIG_CHAN_NEW_FAIL => "migrate: 通道创建失败\r\n",
-            key::MIG_CHAN_CLEARED => "migrate: 通道已清空\r\n",
-            key::MIG_NO_BUFFER => "migrate: 无缓冲区\r\n",
-            key::MIG_NET_MAC_PREFIX => "net: MAC=",
-            key::MIG_NET_MTU_PREFIX => "net: MTU=",
-            key::MIG_NET_MAC_UPDATED => "net: 已更新MAC\r\n",
-            key::MIG_NET_MTU_UPDATED => "net: 已更新MTU\r\n",
-            key::MIG_NET_USAGE => "usage: migrate net [mac|mtu] ...\r\n",
-            key::MIG_NET_MAC_USAGE => "usage: migrate net mac [get|set xx:xx:xx:xx:xx:xx]\r\n",
-            key::MIG_NET_MTU_USAGE => "usage: migrate net mtu [get|set <n>]\r\n",
-            key::MIG_NET_ETHER_PREFIX => "net: EtherType=0x",
-            key::MIG_NET_ETHER_UPDATED => "net: 已更新EtherType\r\n",
-            key::MIG_NET_ETHER_USAGE => "usage: migrate net ether [get|set <hex>]\r\n",
-            key::IOMMU_CFG_SAVED => "iommu: 已保存配置\r\n",
-            key::IOMMU_CFG_LOADED => "iommu: 已加载配置\r\n",
-            _ => "\r\n",
-        },
+        Lang::En => None,
+        Lang::Ja | Lang::Zh => Some(Lang::En),
+        Lang::Ext => Some(ext_fallback()),
+    }
+}
+
+// ---- Catalog loaded from the ESP ----
+//
+// A UTF-8 text file, one `key = message` per line. `#` starts a comment line,
+// `@lang <tag>` names the locale (matched as a prefix of `PlatformLang` and
+// accepted by `lang <tag>`), and `@fallback en|ja|zh` picks the built-in
+// catalog used for missing keys (English by default). In messages `\n` is a
+// line break (CR LF) and `\\` a backslash; leading and trailing blanks are
+// dropped. Messages resolved through `t_fmt` keep their `{0}` placeholders. Unknown keys are counted and skipped so a newer catalog still
+// loads on an older build.
+//
+// The catalog is written once and never changed afterwards, which is what
+// lets `t` hand out `&'static str` slices of it.
+
+/// Default catalog path on the boot ESP
+pub const CATALOG_PATH: &str = "\\EFI\\BOOT\\zerovisor.lang";
+pub const CATALOG_MAX: usize = 16 * 1024;
+const TAG_MAX: usize = 15;
+
+struct Ext {
+    tag: [u8; TAG_MAX],
+    tag_len: usize,
+    fallback: Lang,
+    text: [u8; CATALOG_MAX],
+    /// (offset, length) of each key's message in `text`; length 0 if absent
+    values: [(u16, u16); KEY_COUNT],
+}
+
+static mut EXT: Ext = Ext { tag: [0; TAG_MAX], tag_len: 0, fallback: Lang::En, text: [0; CATALOG_MAX], values: [(0, 0); KEY_COUNT] };
+// 0: none, 1: loading, 2: ready
+static EXT_STATE: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct CatalogSummary {
+    pub messages: usize,
+    pub unknown: usize,
+}
+
+fn ext() -> Option<&'static Ext> {
+    if EXT_STATE.load(Ordering::Acquire) != 2 { return None; }
+    Some(unsafe { &*core::ptr::addr_of!(EXT) })
+}
+
+/// Tag of the loaded catalog, empty if none.
+pub fn ext_tag() -> &'static str {
+    ext().map(|e| core::str::from_utf8(&e.tag[..e.tag_len]).unwrap_or("")).unwrap_or("")
+}
+
+fn ext_fallback() -> Lang { ext().map(|e| e.fallback).unwrap_or(Lang::En) }
+
+fn ext_value(i: usize) -> &'static str {
+    let Some(e) = ext() else { return "" };
+    let (off, len) = e.values[i];
+    core::str::from_utf8(&e.text[off as usize..off as usize + len as usize]).unwrap_or("")
+}
+
+/// Short name of a language for display.
+pub fn lang_tag(lang: Lang) -> &'static str {
+    match lang { Lang::En => "en", Lang::Ja => "ja", Lang::Zh => "zh", Lang::Ext => ext_tag() }
+}
+
+/// Parse a catalog into `e`.
+fn parse_catalog(src: &[u8], e: &mut Ext) -> Result<CatalogSummary, &'static str> {
+    let src = core::str::from_utf8(src).map_err(|_| "i18n: catalog is not UTF-8")?;
+    let src = src.strip_prefix('\u{feff}').unwrap_or(src);
+    let mut used = 0usize;
+    let mut sum = CatalogSummary { messages: 0, unknown: 0 };
+    for line in src.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        if let Some(tag) = line.strip_prefix("@lang") {
+            let tag = tag.trim();
+            if tag.is_empty() || tag.len() > TAG_MAX || !tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') { return Err("i18n: bad @lang tag"); }
+            if ["en", "ja", "zh", "auto"].iter().any(|b| tag.eq_ignore_ascii_case(b)) { return Err("i18n: @lang names a built-in language"); }
+            for (i, b) in tag.bytes().enumerate() { e.tag[i] = b.to_ascii_lowercase(); }
+            e.tag_len = tag.len();
+            continue;
+        }
+        if let Some(fb) = line.strip_prefix("@fallback") {
+            e.fallback = match fb.trim() { "en" => Lang::En, "ja" => Lang::Ja, "zh" => Lang::Zh, _ => return Err("i18n: @fallback must be en, ja or zh") };
+            continue;
+        }
+        let (k, v) = line.split_once('=').ok_or("i18n: expected key = message")?;
+        let Some(i) = index(k.trim()) else { sum.unknown += 1; continue };
+        let start = used;
+        let mut chars = v.trim().chars();
+        while let Some(c) = chars.next() {
+            let mut tmp = [0u8; 4];
+            let piece: &[u8] = if c == '\\' {
+                match chars.next() {
+                    Some('n') => b"\r\n",
+                    Some('\\') => b"\\",
+                    _ => return Err("i18n: bad escape in catalog"),
+                }
+            } else {
+                c.encode_utf8(&mut tmp).as_bytes()
+            };
+            if used + piece.len() > e.text.len() { return Err("i18n: catalog too large"); }
+            e.text[used..used + piece.len()].copy_from_slice(piece);
+            used += piece.len();
+        }
+        if e.values[i].1 == 0 && used > start { sum.messages += 1; }
+        e.values[i] = (start as u16, (used - start) as u16);
     }
+    if e.tag_len == 0 { return Err("i18n: catalog has no @lang line"); }
+    Ok(sum)
 }
 
+/// Load the catalog at `path` on the boot ESP. Only one catalog can be
+/// loaded per boot; call it before anything is printed through `t`.
+pub fn load_catalog(system_table: &SystemTable<Boot>, path: &str) -> Result<CatalogSummary, &'static str> {
+    if EXT_STATE.compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire).is_err() { return Err("i18n: a catalog is already loaded"); }
+    let res = read_catalog(system_table, path);
+    EXT_STATE.store(if res.is_ok() { 2 } else { 0 }, Ordering::Release);
+    res
+}
 
+fn read_catalog(system_table: &SystemTable<Boot>, path: &str) -> Result<CatalogSummary, &'static str> {
+    let mut name_buf = [0u16; 128];
+    let name = CStr16::from_str_with_buf(path, &mut name_buf).map_err(|_| "i18n: invalid path")?;
+    let bs = system_table.boot_services();
+    let mut fs = bs.get_image_file_system(bs.image_handle()).map_err(|_| "i18n: ESP not accessible")?;
+    let mut root = fs.open_volume().map_err(|_| "i18n: open volume failed")?;
+    let h = root.open(name, FileMode::Read, FileAttribute::empty()).map_err(|_| "i18n: catalog not found")?;
+    let mut f = h.into_regular_file().ok_or("i18n: catalog is not a file")?;
+    let mut info_buf = [0u8; 512];
+    let size = f.get_info::<FileInfo>(&mut info_buf).map_err(|_| "i18n: file info failed")?.file_size() as usize;
+    if size > CATALOG_MAX { return Err("i18n: catalog too large"); }
+    let mut raw = [0u8; CATALOG_MAX];
+    let mut got = 0usize;
+    while got < size {
+        match f.read(&mut raw[got..size]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => got += n,
+        }
+    }
+    if got != size { return Err("i18n: short read"); }
+    // State 1 keeps readers off EXT while it is filled.
+    let e = unsafe { &mut *core::ptr::addr_of_mut!(EXT) };
+    e.tag_len = 0;
+    e.fallback = Lang::En;
+    e.values = [(0, 0); KEY_COUNT];
+    parse_catalog(&raw[..size], e)
+}
@@ -1,53 +1,515 @@
 #![allow(dead_code)]
 
-//! AMD-Vi (IOMMU) minimal discovery and early initialization.
+//! AMD-Vi (IOMMU) discovery, device table and v1 page-table programming.
+//!
+//! Mirrors the VT-d path: `iommu::state` is the source of truth, and
+//! `apply_assignments`/`apply_mappings` turn it into hardware structures.
+//! Each unit gets a full 64K-entry device table (2 MiB) and a 4 KiB command
+//! buffer; each domain gets a 4-level v1 page table (48-bit IOVA) shared by
+//! every unit. Devices without an assignment keep V=0 in their device table
+//! entry, which lets their DMA through untranslated as before.
 
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
 use core::fmt::Write as _;
 use crate::util::spinlock::SpinLock;
 
-// AMD IOMMU register offsets (subset per common references)
-const REG_MMIO_BASE: usize = 0x0; // placeholder per-unit base mapped via IVRS device table
-const REG_STATUS: usize = 0x18; // Status (R)
-const REG_CONTROL: usize = 0x18; // Control (W)
+// MMIO register offsets
+const REG_DEV_TABLE: usize = 0x0000; // Device Table Base (base | size-in-pages - 1)
+const REG_CMD_BASE: usize = 0x0008; // Command Buffer Base (base | ComLen << 56)
+const REG_EVT_BASE: usize = 0x0010; // Event Log Base (base | EventLen << 56)
+const REG_CONTROL: usize = 0x0018; // Control (64-bit)
+const REG_EXT_FEATURE: usize = 0x0030; // Extended Feature Register
+const REG_CMD_HEAD: usize = 0x2000;
+const REG_CMD_TAIL: usize = 0x2008;
+const REG_EVT_HEAD: usize = 0x2010;
+const REG_EVT_TAIL: usize = 0x2018;
+const REG_STATUS: usize = 0x2020;
 
 // Control bits (subset)
-const CTRL_TE: u32 = 1 << 0; // Translation Enable
+const CTRL_IOMMU_EN: u64 = 1 << 0;
+const CTRL_EVTLOG_EN: u64 = 1 << 2;
+const CTRL_CMDBUF_EN: u64 = 1 << 12;
+// Status bits (subset)
+const STS_EVT_OVERFLOW: u64 = 1 << 0;
+const STS_CMDBUF_RUN: u64 = 1 << 4;
+
+// Device table: one 256-bit entry per 16-bit device id
+const DEV_TABLE_PAGES: usize = 512;
+const DTE_V: u64 = 1 << 0;
+const DTE_TV: u64 = 1 << 1;
+const DTE_MODE_4LVL: u64 = 4 << 9;
+const DTE_IR: u64 = 1 << 61;
+const DTE_IW: u64 = 1 << 62;
+const DTE_SA: u64 = 1 << 34; // qword 1: suppress all I/O page fault events
+
+// Command buffer: 256 entries of 16 bytes
+const CMD_BUF_BYTES: u32 = 4096;
+const CMD_COMLEN: u64 = 8;
+const CMD_COMPLETION_WAIT: u32 = 0x01;
+const CMD_INV_DEVTAB_ENTRY: u32 = 0x02;
+const CMD_INV_IOMMU_PAGES: u32 = 0x03;
+const CMD_SPINS: u32 = 2_000_000;
+
+// Event log: 256 entries of 16 bytes
+const EVT_LOG_BYTES: u32 = 4096;
+const EVT_LEN: u64 = 8;
+
+// v1 page-table entries
+const PTE_PR: u64 = 1 << 0;
+const PTE_NL_SHIFT: u64 = 9;
+const PTE_IR: u64 = 1 << 61;
+const PTE_IW: u64 = 1 << 62;
+const PTE_ADDR: u64 = 0x000F_FFFF_FFFF_F000;
+const SZ_2M: u64 = 2 * 1024 * 1024;
 
 #[derive(Clone, Copy)]
-struct AmdViUnit { seg: u16, reg_base: u64 }
+struct AmdViUnit { seg: u16, reg_base: u64, dev_table: u64, cmd_buf: u64, cmd_tail: u32, sem: u64, evt_log: u64 }
 
 static AMDVI_UNITS: SpinLock<[Option<AmdViUnit>; 8]> = SpinLock::new([None; 8]);
 
+/// Per-domain v1 page-table root, keyed by domain id (0 = free slot).
+static DOMAIN_ROOTS: SpinLock<[(u16, u64); crate::iommu::state::MAX_DOMAINS]> = SpinLock::new([(0, 0); crate::iommu::state::MAX_DOMAINS]);
+
+/// Device table entries we made valid, so a later apply can retire them.
+static PROGRAMMED: SpinLock<[Option<(u16, u16)>; crate::iommu::state::MAX_ASSIGNMENTS]> = SpinLock::new([None; crate::iommu::state::MAX_ASSIGNMENTS]);
+
 fn register_unit(seg: u16, reg_base: u64) {
     AMDVI_UNITS.lock(|arr| {
-        for slot in arr.iter_mut() { if slot.is_none() { *slot = Some(AmdViUnit { seg, reg_base }); break; } }
+        // Firmware commonly describes one unit with both a type 10h and 11h IVHD.
+        if arr.iter().flatten().any(|u| u.reg_base == reg_base) { return; }
+        for slot in arr.iter_mut() { if slot.is_none() { *slot = Some(AmdViUnit { seg, reg_base, dev_table: 0, cmd_buf: 0, cmd_tail: 0, sem: 0, evt_log: 0 }); break; } }
     });
 }
 
 fn for_each_unit(mut f: impl FnMut(AmdViUnit)) { AMDVI_UNITS.lock(|arr| { for o in arr.iter() { if let Some(u) = *o { f(u); } } }) }
 
+/// Snapshot of the unit table, so callers can submit commands without holding the lock.
+fn units() -> [Option<AmdViUnit>; 8] { AMDVI_UNITS.lock(|arr| *arr) }
+
+pub fn has_units() -> bool { AMDVI_UNITS.lock(|arr| arr.iter().any(|u| u.is_some())) }
+
+#[inline(always)]
+fn reg_read64(base: u64, off: usize) -> u64 { unsafe { core::ptr::read_volatile((base as usize + off) as *const u64) } }
+#[inline(always)]
+fn reg_write64(base: u64, off: usize, v: u64) { unsafe { core::ptr::write_volatile((base as usize + off) as *mut u64, v) } }
+
+fn alloc_zeroed_pages(system_table: &SystemTable<Boot>, pages: usize) -> Option<u64> {
+    let p = crate::mm::uefi::alloc_pages(system_table, pages, uefi::table::boot::MemoryType::LOADER_DATA)?;
+    unsafe { core::ptr::write_bytes(p, 0, pages * 4096); }
+    Some(p as u64)
+}
+
+#[inline(always)]
+fn devid(bus: u8, dev: u8, func: u8) -> u16 { ((bus as u16) << 8) | ((dev as u16 & 0x1F) << 3) | (func as u16 & 0x7) }
+
 /// Early minimal init: discover IVRS and remember units (no TE enable here).
 pub fn minimal_init(system_table: &mut SystemTable<Boot>) {
     if let Some(ivrs) = crate::firmware::acpi::find_ivrs(system_table) {
-        crate::firmware::acpi::ivrs_for_each_ivhd_from(|seg, base| { register_unit(seg, base); }, ivrs);
-        let stdout = system_table.stdout();
-        let _ = stdout.write_str("AMD-Vi: units registered from IVRS\r\n");
+        let mut count = 0u32;
+        crate::firmware::acpi::ivrs_for_each_ivhd_from(|seg, base| { register_unit(seg, base); count += 1; }, ivrs);
+        crate::log!(Info, "amdvi", "{} units registered from IVRS", count);
+    }
+}
+
+// --- Command buffer ---
+
+fn submit(base: u64, cmd: [u32; 4]) -> bool {
+    AMDVI_UNITS.lock(|arr| {
+        let u = match arr.iter_mut().flatten().find(|u| u.reg_base == base) { Some(u) => u, None => return false };
+        if u.cmd_buf == 0 { return false; }
+        let next = (u.cmd_tail + 16) % CMD_BUF_BYTES;
+        let mut spins = 0u32;
+        while (reg_read64(u.reg_base, REG_CMD_HEAD) as u32 & 0x7FFF0) == next {
+            spins += 1;
+            if spins >= CMD_SPINS { return false; }
+            core::hint::spin_loop();
+        }
+        let slot = (u.cmd_buf + u.cmd_tail as u64) as *mut u32;
+        for (i, w) in cmd.iter().enumerate() { unsafe { core::ptr::write_volatile(slot.add(i), *w); } }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        u.cmd_tail = next;
+        reg_write64(u.reg_base, REG_CMD_TAIL, next as u64);
+        true
+    })
+}
+
+/// COMPLETION_WAIT with a memory store; returns once every earlier command retired.
+fn wait(u: &AmdViUnit) -> bool {
+    if u.sem == 0 { return false; }
+    let sem = u.sem as *mut u64;
+    unsafe { core::ptr::write_volatile(sem, 0); }
+    let cmd = [(u.sem as u32 & 0xFFFF_FFF8) | 1, ((u.sem >> 32) as u32 & 0xF_FFFF) | (CMD_COMPLETION_WAIT << 28), 1, 0];
+    if !submit(u.reg_base, cmd) { return false; }
+    let mut spins = 0u32;
+    while unsafe { core::ptr::read_volatile(sem) } != 1 {
+        spins += 1;
+        if spins >= CMD_SPINS { return false; }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn inv_devtab_entry(u: &AmdViUnit, id: u16) -> bool {
+    submit(u.reg_base, [id as u32, CMD_INV_DEVTAB_ENTRY << 28, 0, 0])
+}
+
+/// INVALIDATE_IOMMU_PAGES for the whole address space of `dom` (S=1, PDE=1).
+fn inv_domain_pages(u: &AmdViUnit, dom: u16) -> bool {
+    submit(u.reg_base, [0, dom as u32 | (CMD_INV_IOMMU_PAGES << 28), 0xFFFF_F000 | 0b11, 0x7FFF_FFFF])
+}
+
+/// Flush cached translations for `dom` on every running unit.
+pub fn invalidate_domain(dom: u16) -> bool {
+    let t0 = crate::time::clock::now_ns();
+    let mut ok = true;
+    for u in units().iter().flatten() {
+        if u.cmd_buf == 0 { continue; }
+        ok &= inv_domain_pages(u, dom) && wait(u);
+    }
+    crate::obs::metrics::IOMMU_INVAL_NS.observe_since(t0);
+    ok
+}
+
+// --- Page tables ---
+
+fn ensure_root(system_table: &SystemTable<Boot>, dom: u16) -> Option<u64> {
+    if let Some(r) = domain_root(dom) { return Some(r); }
+    let page = alloc_zeroed_pages(system_table, 1)?;
+    DOMAIN_ROOTS.lock(|arr| {
+        if let Some(e) = arr.iter().find(|e| e.0 == dom) { return Some(e.1); }
+        let slot = arr.iter_mut().find(|e| e.0 == 0)?;
+        *slot = (dom, page);
+        Some(page)
+    })
+}
+
+/// Free a v1 table and everything below it.
+unsafe fn free_tree(system_table: &SystemTable<Boot>, table: u64, level: u32) {
+    if level > 1 {
+        for i in 0..512 {
+            let e = core::ptr::read_volatile((table as *const u64).add(i));
+            if e & PTE_PR != 0 && (e >> PTE_NL_SHIFT) & 7 != 0 { free_tree(system_table, e & PTE_ADDR, level - 1); }
+        }
+    }
+    crate::mm::uefi::free_pages(system_table, table as *mut u8, 1);
+}
+
+/// Release the tables of domains that `iommu::state` no longer knows.
+/// Callers retire device table entries first so no unit still walks them.
+fn reap_roots(system_table: &SystemTable<Boot>) {
+    let dead = DOMAIN_ROOTS.lock(|arr| {
+        let mut out = [0u64; crate::iommu::state::MAX_DOMAINS];
+        for (i, e) in arr.iter_mut().enumerate() {
+            if e.0 != 0 && !crate::iommu::state::domain_exists(e.0) { out[i] = e.1; *e = (0, 0); }
+        }
+        out
+    });
+    for &root in dead.iter() { if root != 0 { unsafe { free_tree(system_table, root, 4); } } }
+}
+
+fn domain_root(dom: u16) -> Option<u64> { DOMAIN_ROOTS.lock(|arr| arr.iter().find(|e| e.0 == dom && e.1 != 0).map(|e| e.1)) }
+
+#[inline(always)]
+fn pt_index(iova: u64, level: u32) -> usize { ((iova >> (12 + 9 * (level - 1))) & 0x1FF) as usize }
+
+/// Follow (or create) the table below `table[idx]`, whose entries sit at `next_level`.
+/// Returns None if the slot already holds a leaf.
+unsafe fn next_table(system_table: &SystemTable<Boot>, table: u64, idx: usize, next_level: u64) -> Option<u64> {
+    let e = (table as *mut u64).add(idx);
+    let v = core::ptr::read_volatile(e);
+    if v & PTE_PR == 0 {
+        let page = alloc_zeroed_pages(system_table, 1)?;
+        core::ptr::write_volatile(e, page | (next_level << PTE_NL_SHIFT) | PTE_IR | PTE_IW | PTE_PR);
+        return Some(page);
+    }
+    if (v >> PTE_NL_SHIFT) & 7 == 0 { return None; }
+    Some(v & PTE_ADDR)
+}
+
+/// Replace a 2 MiB leaf with an equivalent table of 4 KiB leaves.
+unsafe fn split_2m(system_table: &SystemTable<Boot>, pde: *mut u64) -> Option<u64> {
+    let v = core::ptr::read_volatile(pde);
+    let table = alloc_zeroed_pages(system_table, 1)?;
+    let perms = v & (PTE_IR | PTE_IW);
+    for i in 0..512u64 {
+        core::ptr::write_volatile((table as *mut u64).add(i as usize), ((v & PTE_ADDR) + i * 4096) | perms | PTE_PR);
+    }
+    core::ptr::write_volatile(pde, table | (1 << PTE_NL_SHIFT) | PTE_IR | PTE_IW | PTE_PR);
+    Some(table)
+}
+
+/// Map `[iova, iova+len)` to `pa`, using 2 MiB leaves where both sides are aligned.
+/// Returns the number of bytes that could not be mapped.
+fn map_range(system_table: &SystemTable<Boot>, root: u64, iova: u64, pa: u64, len: u64, r: bool, w: bool) -> u64 {
+    let mut perms = PTE_PR;
+    if r { perms |= PTE_IR; }
+    if w { perms |= PTE_IW; }
+    let mut off = 0u64; let mut skipped = 0u64;
+    while off < len {
+        let va = iova.wrapping_add(off) & !0xFFF;
+        let phys = pa.wrapping_add(off) & !0xFFF;
+        let big = (va | phys) & (SZ_2M - 1) == 0 && len - off >= SZ_2M;
+        let step = if big { SZ_2M } else { 4096 };
+        unsafe {
+            let l3 = next_table(system_table, root, pt_index(va, 4), 3);
+            let l2 = l3.and_then(|t| next_table(system_table, t, pt_index(va, 3), 2));
+            match l2 {
+                Some(l2) if big => {
+                    // A table already here is simply dropped from the walk.
+                    core::ptr::write_volatile((l2 as *mut u64).add(pt_index(va, 2)), phys | perms);
+                }
+                Some(l2) => match next_table(system_table, l2, pt_index(va, 2), 1) {
+                    Some(l1) => core::ptr::write_volatile((l1 as *mut u64).add(pt_index(va, 1)), phys | perms),
+                    None => skipped += step,
+                },
+                None => skipped += step,
+            }
+        }
+        off += step;
+    }
+    skipped
+}
+
+fn unmap_range_v1(system_table: &SystemTable<Boot>, root: u64, iova: u64, len: u64) {
+    let mut off = 0u64;
+    while off < len {
+        let va = iova.wrapping_add(off) & !0xFFF;
+        let mut step = 4096u64;
+        unsafe {
+            let e4 = core::ptr::read_volatile((root as *const u64).add(pt_index(va, 4)));
+            if e4 & PTE_PR == 0 { off += 4096; continue; }
+            let e3 = core::ptr::read_volatile(((e4 & PTE_ADDR) as *const u64).add(pt_index(va, 3)));
+            if e3 & PTE_PR == 0 { off += 4096; continue; }
+            let pde = ((e3 & PTE_ADDR) as *mut u64).add(pt_index(va, 2));
+            let e2 = core::ptr::read_volatile(pde);
+            if e2 & PTE_PR == 0 { off += 4096; continue; }
+            if (e2 >> PTE_NL_SHIFT) & 7 == 0 {
+                if va & (SZ_2M - 1) == 0 && len - off >= SZ_2M {
+                    core::ptr::write_volatile(pde, 0);
+                    step = SZ_2M;
+                } else if let Some(t) = split_2m(system_table, pde) {
+                    core::ptr::write_volatile((t as *mut u64).add(pt_index(va, 1)), 0);
+                }
+            } else {
+                core::ptr::write_volatile(((e2 & PTE_ADDR) as *mut u64).add(pt_index(va, 1)), 0);
+            }
+        }
+        off += step;
+    }
+}
+
+/// Walk the v1 tables of `dom`: (physical address, readable, writable).
+pub fn translate(dom: u16, iova: u64) -> Option<(u64, bool, bool)> {
+    let mut table = domain_root(dom)?;
+    let mut level = 4u32;
+    loop {
+        let e = unsafe { core::ptr::read_volatile((table as *const u64).add(pt_index(iova, level))) };
+        if e & PTE_PR == 0 { return None; }
+        let nl = ((e >> PTE_NL_SHIFT) & 7) as u32;
+        if nl == 0 || level == 1 {
+            let page = 1u64 << (12 + 9 * (level - 1));
+            return Some(((e & PTE_ADDR & !(page - 1)) | (iova & (page - 1)), e & PTE_IR != 0, e & PTE_IW != 0));
+        }
+        table = e & PTE_ADDR;
+        level = nl;
+    }
+}
+
+// --- Device table ---
+
+fn write_dte(u: &AmdViUnit, id: u16, lo: u64, q1: u64) {
+    let e = (u.dev_table + id as u64 * 32) as *mut u64;
+    unsafe {
+        // Clear V first so the unit never sees a half-written entry as valid.
+        core::ptr::write_volatile(e, 0);
+        core::ptr::write_volatile(e.add(1), q1);
+        core::ptr::write_volatile(e.add(2), 0);
+        core::ptr::write_volatile(e.add(3), 0);
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        core::ptr::write_volatile(e, lo);
+    }
+}
+
+/// Program device table entries from `iommu::state` assignments and retire
+/// entries whose assignment is gone.
+pub fn apply_assignments(system_table: &mut SystemTable<Boot>) {
+    if !has_units() { return; }
+    let all = units();
+    let mut written = 0u32; let mut retired = 0u32;
+    crate::iommu::state::list_assignments(|seg, bus, dev, func, dom| {
+        let root = match ensure_root(system_table, dom) { Some(r) => r, None => return };
+        let id = devid(bus, dev, func);
+        let lo = DTE_V | DTE_TV | DTE_MODE_4LVL | (root & PTE_ADDR) | DTE_IR | DTE_IW;
+        for u in all.iter().flatten() {
+            if u.seg != seg || u.dev_table == 0 { continue; }
+            write_dte(u, id, lo, dom as u64);
+            let _ = inv_devtab_entry(u, id);
+        }
+        PROGRAMMED.lock(|p| {
+            if p.iter().flatten().any(|&(s, d)| s == seg && d == id) { return; }
+            if let Some(slot) = p.iter_mut().find(|e| e.is_none()) { *slot = Some((seg, id)); }
+        });
+        written += 1;
+    });
+    let stale = PROGRAMMED.lock(|p| {
+        let mut out = [None; crate::iommu::state::MAX_ASSIGNMENTS];
+        for (i, e) in p.iter_mut().enumerate() {
+            if let Some((seg, id)) = *e {
+                let (bus, dev, func) = ((id >> 8) as u8, ((id >> 3) & 0x1F) as u8, (id & 7) as u8);
+                if crate::iommu::state::find_domain_for_bdf(seg, bus, dev, func).is_none() { out[i] = Some((seg, id)); *e = None; }
+            }
+        }
+        out
+    });
+    for &(seg, id) in stale.iter().flatten() {
+        // A quarantined device stays blocked instead of falling back to pass-through.
+        let (bus, dev, func) = ((id >> 8) as u8, ((id >> 3) & 0x1F) as u8, (id & 7) as u8);
+        let (lo, q1) = if crate::iommu::fault::is_quarantined(seg, bus, dev, func) { (DTE_V | DTE_TV, DTE_SA) } else { (0, 0) };
+        for u in all.iter().flatten() {
+            if u.seg != seg || u.dev_table == 0 { continue; }
+            write_dte(u, id, lo, q1);
+            let _ = inv_devtab_entry(u, id);
+        }
+        retired += 1;
+    }
+    for u in all.iter().flatten() { if u.cmd_buf != 0 { let _ = wait(u); } }
+    reap_roots(system_table);
+    let mut buf = [0u8; 96]; let mut n = 0;
+    for &b in b"AMD-Vi: device table entries=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(written, &mut buf[n..]);
+    for &b in b" retired=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(retired, &mut buf[n..]);
+    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+    let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+}
+
+/// Build v1 page tables for every mapping in `iommu::state` and flush the
+/// affected domains. A no-op until `minimal_init` has found a unit.
+pub fn apply_mappings(system_table: &mut SystemTable<Boot>) {
+    if !has_units() { return; }
+    let mut doms = [0u16; crate::iommu::state::MAX_DOMAINS]; let mut nd = 0usize;
+    let mut skipped = 0u64;
+    crate::iommu::state::list_mappings(|dom, iova, pa, len, r, w, _x| {
+        let root = match ensure_root(system_table, dom) { Some(r) => r, None => { skipped += len; return; } };
+        skipped += map_range(system_table, root, iova, pa, len, r, w);
+        if !doms[..nd].contains(&dom) && nd < doms.len() { doms[nd] = dom; nd += 1; }
+    });
+    for &d in &doms[..nd] { let _ = invalidate_domain(d); }
+    if skipped != 0 {
+        crate::log!(Warn, "amdvi", "mappings applied, skipped={:#x}", skipped);
+    } else {
+        crate::log!(Info, "amdvi", "mappings applied");
     }
 }
 
+pub fn unmap_range(system_table: &mut SystemTable<Boot>, dom: u16, iova: u64, len: u64) {
+    if let Some(root) = domain_root(dom) {
+        unmap_range_v1(system_table, root, iova, len);
+        let _ = invalidate_domain(dom);
+        crate::log!(Info, "amdvi", "dom={} unmapped {:#x}+{:#x} from v1 tables", dom, iova, len);
+    }
+}
+
+/// Block all DMA from `seg:bus:dev.func` (V=1, TV=1, Mode=0, no IR/IW) and
+/// suppress its page-fault events. Returns false if no unit has tables yet.
+pub(crate) fn block_device(seg: u16, bus: u8, dev: u8, func: u8) -> bool {
+    let id = devid(bus, dev, func);
+    let mut done = false;
+    for u in units().iter().flatten() {
+        if u.seg != seg || u.dev_table == 0 { continue; }
+        write_dte(u, id, DTE_V | DTE_TV, DTE_SA);
+        done |= inv_devtab_entry(u, id) && wait(u);
+    }
+    PROGRAMMED.lock(|p| { for e in p.iter_mut() { if *e == Some((seg, id)) { *e = None; } } });
+    done
+}
+
+/// Undo `block_device`: the entry goes back to V=0 (untranslated).
+pub(crate) fn unblock_device(seg: u16, bus: u8, dev: u8, func: u8) {
+    let id = devid(bus, dev, func);
+    for u in units().iter().flatten() {
+        if u.seg != seg || u.dev_table == 0 { continue; }
+        write_dte(u, id, 0, 0);
+        let _ = inv_devtab_entry(u, id) && wait(u);
+    }
+}
+
+/// Consume pending event log entries on every unit, handing each to
+/// `f(seg, device_id, event_code, is_write, address)`; returns entries consumed.
+pub(crate) fn drain_events(mut f: impl FnMut(u16, u16, u8, bool, u64)) -> usize {
+    let mut count = 0usize;
+    for u in units().iter().flatten() {
+        if u.evt_log == 0 { continue; }
+        let mut head = reg_read64(u.reg_base, REG_EVT_HEAD) as u32 & 0x7FFF0;
+        let tail = reg_read64(u.reg_base, REG_EVT_TAIL) as u32 & 0x7FFF0;
+        while head != tail {
+            let e = (u.evt_log + head as u64) as *const u32;
+            let w = unsafe { [core::ptr::read_volatile(e), core::ptr::read_volatile(e.add(1)), core::ptr::read_volatile(e.add(2)), core::ptr::read_volatile(e.add(3))] };
+            let code = (w[1] >> 28) as u8;
+            // IO_PAGE_FAULT flags live in dword 1 bits 27:16; RW is flag bit 5.
+            let write = code == 0x2 && (w[1] >> 21) & 1 != 0;
+            f(u.seg, w[0] as u16, code, write, (w[2] as u64) | ((w[3] as u64) << 32));
+            head = (head + 16) % EVT_LOG_BYTES;
+            count += 1;
+        }
+        reg_write64(u.reg_base, REG_EVT_HEAD, head as u64);
+        let sts = reg_read64(u.reg_base, REG_STATUS);
+        if sts & STS_EVT_OVERFLOW != 0 {
+            // Logging stops on overflow: clear it and restart from an empty log.
+            let ctrl = reg_read64(u.reg_base, REG_CONTROL);
+            reg_write64(u.reg_base, REG_CONTROL, ctrl & !CTRL_EVTLOG_EN);
+            reg_write64(u.reg_base, REG_STATUS, STS_EVT_OVERFLOW);
+            reg_write64(u.reg_base, REG_EVT_HEAD, 0);
+            reg_write64(u.reg_base, REG_EVT_TAIL, 0);
+            reg_write64(u.reg_base, REG_CONTROL, ctrl | CTRL_EVTLOG_EN);
+        }
+    }
+    count
+}
+
+// --- Enable / disable ---
+
+/// Give a unit our device table and command buffer; translation stays as it was.
+fn setup_unit(system_table: &SystemTable<Boot>, u: AmdViUnit) -> Option<AmdViUnit> {
+    if u.dev_table != 0 { return Some(u); }
+    let dev_table = alloc_zeroed_pages(system_table, DEV_TABLE_PAGES)?;
+    // Command buffer, completion-wait semaphore, event log
+    let cmd_page = alloc_zeroed_pages(system_table, 3)?;
+    let ctrl = reg_read64(u.reg_base, REG_CONTROL);
+    reg_write64(u.reg_base, REG_CONTROL, ctrl & !(CTRL_IOMMU_EN | CTRL_CMDBUF_EN));
+    reg_write64(u.reg_base, REG_DEV_TABLE, dev_table | (DEV_TABLE_PAGES as u64 - 1));
+    reg_write64(u.reg_base, REG_CMD_BASE, cmd_page | (CMD_COMLEN << 56));
+    reg_write64(u.reg_base, REG_CMD_HEAD, 0);
+    reg_write64(u.reg_base, REG_CMD_TAIL, 0);
+    reg_write64(u.reg_base, REG_EVT_BASE, (cmd_page + 8192) | (EVT_LEN << 56));
+    reg_write64(u.reg_base, REG_EVT_HEAD, 0);
+    reg_write64(u.reg_base, REG_EVT_TAIL, 0);
+    reg_write64(u.reg_base, REG_CONTROL, (ctrl & !CTRL_IOMMU_EN) | CTRL_CMDBUF_EN | CTRL_EVTLOG_EN);
+    let mut tries = 0u32;
+    while reg_read64(u.reg_base, REG_STATUS) & STS_CMDBUF_RUN == 0 && tries < 5000 { tries += 1; let _ = system_table.boot_services().stall(10); }
+    let nu = AmdViUnit { dev_table, cmd_buf: cmd_page, cmd_tail: 0, sem: cmd_page + 4096, evt_log: cmd_page + 8192, ..u };
+    AMDVI_UNITS.lock(|arr| { for s in arr.iter_mut().flatten() { if s.reg_base == u.reg_base { *s = nu; } } });
+    Some(nu)
+}
+
 pub fn enable_translation_all(system_table: &mut SystemTable<Boot>) {
+    for u in units().iter().flatten() {
+        if setup_unit(system_table, *u).is_none() {
+            crate::log!(Error, "amdvi", "table allocation failed for unit at {:#x}", u.reg_base);
+            return;
+        }
+    }
+    apply_assignments(system_table);
+    apply_mappings(system_table);
     for_each_unit(|u| {
-            let ctrl = (u.reg_base as usize + REG_CONTROL) as *mut u32;
-            let stat = (u.reg_base as usize + REG_STATUS) as *const u32;
-            let cur = unsafe { core::ptr::read_volatile(ctrl) };
-            unsafe { core::ptr::write_volatile(ctrl, cur | CTRL_TE); }
+            let cur = reg_read64(u.reg_base, REG_CONTROL);
+            reg_write64(u.reg_base, REG_CONTROL, cur | CTRL_IOMMU_EN | CTRL_CMDBUF_EN | CTRL_EVTLOG_EN);
             let mut ok = false; let mut tries = 0u32;
-            while tries < 5000 { if (unsafe { core::ptr::read_volatile(stat) } & CTRL_TE) != 0 { ok = true; break; } tries += 1; let _ = system_table.boot_services().stall(100); }
+            while tries < 5000 { if (reg_read64(u.reg_base, REG_CONTROL) & CTRL_IOMMU_EN) != 0 { ok = true; break; } tries += 1; let _ = system_table.boot_services().stall(100); }
             let mut buf = [0u8; 96]; let mut n = 0;
             for &b in b"AMD-Vi: enable seg=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
             for &b in b" result=" { buf[n] = b; n += 1; }
             let s: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
             for &b in s { buf[n] = b; n += 1; }
@@ -58,15 +520,14 @@ pub fn enable_translation_all(system_table: &mut SystemTable<Boot>) {
 
 pub fn disable_translation_all(system_table: &mut SystemTable<Boot>) {
     for_each_unit(|u| {
-            let ctrl = (u.reg_base as usize + REG_CONTROL) as *mut u32;
-            let stat = (u.reg_base as usize + REG_STATUS) as *const u32;
-            let cur = unsafe { core::ptr::read_volatile(ctrl) };
-            unsafe { core::ptr::write_volatile(ctrl, cur & !CTRL_TE); }
+            // The command buffer stays up so tables can still be invalidated.
+            let cur = reg_read64(u.reg_base, REG_CONTROL);
+            reg_write64(u.reg_base, REG_CONTROL, cur & !CTRL_IOMMU_EN);
             let mut ok = false; let mut tries = 0u32;
-            while tries < 5000 { if (unsafe { core::ptr::read_volatile(stat) } & CTRL_TE) == 0 { ok = true; break; } tries += 1; let _ = system_table.boot_services().stall(100); }
+            while tries < 5000 { if (reg_read64(u.reg_base, REG_CONTROL) & CTRL_IOMMU_EN) == 0 { ok = true; break; } tries += 1; let _ = system_table.boot_services().stall(100); }
             let mut buf = [0u8; 96]; let mut n = 0;
             for &b in b"AMD-Vi: disable seg=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
             for &b in b" result=" { buf[n] = b; n += 1; }
             let s: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
             for &b in s { buf[n] = b; n += 1; }
@@ -77,11 +538,21 @@ pub fn disable_translation_all(system_table: &mut SystemTable<Boot>) {
 
 pub fn report_units(system_table: &mut SystemTable<Boot>) {
     for_each_unit(|u| {
-        let mut buf = [0u8; 96]; let mut n = 0;
+        let mut buf = [0u8; 192]; let mut n = 0;
         for &b in b"AMD-Vi: seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
         for &b in b" reg=0x" { buf[n] = b; n += 1; }
         n += crate::util::format::u64_hex(u.reg_base, &mut buf[n..]);
+        for &b in b" ctrl=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(reg_read64(u.reg_base, REG_CONTROL), &mut buf[n..]);
+        for &b in b" status=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(reg_read64(u.reg_base, REG_STATUS), &mut buf[n..]);
+        for &b in b" efr=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(reg_read64(u.reg_base, REG_EXT_FEATURE), &mut buf[n..]);
+        if u.dev_table != 0 {
+            for &b in b" devtab=0x" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(u.dev_table, &mut buf[n..]);
+        }
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     });
@@ -92,6 +563,7 @@ pub fn probe_and_report(system_table: &mut SystemTable<Boot>) {
     let lang = crate::i18n::detect_lang(system_table);
     // Resolve header before borrowing stdout to avoid aliasing borrows
     let ivrs = crate::firmware::acpi::find_ivrs(system_table);
+    crate::hv::features::register_flag("iommu", "amd-vi", ivrs.is_some(), "no IVRS table");
     let stdout = system_table.stdout();
     if let Some(hdr) = ivrs {
         crate::firmware::acpi::ivrs_summary(|s| { let _ = stdout.write_str(s); }, hdr);
@@ -101,4 +573,3 @@ pub fn probe_and_report(system_table: &mut SystemTable<Boot>) {
     }
 }
 
-
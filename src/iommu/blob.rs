@@ -0,0 +1,303 @@
+#![allow(dead_code)]
+
+//! Binary import/export of IOMMU domain state.
+//!
+//! A blob carries every domain, device assignment and mapping so a whole
+//! layout can be restored in one step from UEFI variables or an ESP file.
+//!
+//! Layout (little endian, version 2):
+//! - header, 16 bytes: magic "ZIOM", version u16, flags u16, payload length
+//!   u32, CRC32 of the payload u32
+//! - payload: domain, assignment and mapping counts (u32 each, plus a u32
+//!   pad), then the records:
+//!   - domain, 2 bytes: id
+//!   - assignment, 8 bytes: seg u16, bus, dev, func, pad, domain id u16
+//!   - mapping, 32 bytes: domain id u16, perm u8 (bit 0 r, 1 w, 2 x), pad
+//!     u8 + u32, iova u64, pa u64, len u64
+//!
+//! Version 1 differs only in its counts (u16 each plus a u16 pad) and is
+//! still accepted by `import`.
+//!
+//! Domain ids in the blob only link records together; importing creates
+//! fresh domains and remaps them.
+//!
+//! A full blob is far larger than firmware allows for one variable, so
+//! `save_var` splits it into `VAR_CHUNK`-byte pieces: `ZerovisorIommuState`
+//! holds the first (and with it the header), `ZerovisorIommuState1`,
+//! `ZerovisorIommuState2`, ... the rest. The first piece is written last, so
+//! an interrupted save fails the CRC instead of loading a mix.
+
+use alloc::vec::Vec;
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use uefi::table::runtime::{RuntimeServices, VariableAttributes, VariableVendor};
+use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode, RegularFile};
+use uefi::CStr16;
+
+use super::state::{MAX_ASSIGNMENTS, MAX_DOMAINS, MAX_MAPPINGS};
+
+const MAGIC: [u8; 4] = *b"ZIOM";
+pub const VERSION: u16 = 2;
+const HDR_LEN: usize = 16;
+const COUNTS_LEN: usize = 16;
+const COUNTS_LEN_V1: usize = 8;
+const DOM_LEN: usize = 2;
+const ASSIGN_LEN: usize = 8;
+const MAP_LEN: usize = 32;
+
+const PERM_R: u8 = 1 << 0;
+const PERM_W: u8 = 1 << 1;
+const PERM_X: u8 = 1 << 2;
+
+/// Largest possible blob: every table full.
+pub const MAX_LEN: usize = HDR_LEN + COUNTS_LEN + MAX_DOMAINS * DOM_LEN + MAX_ASSIGNMENTS * ASSIGN_LEN + MAX_MAPPINGS * MAP_LEN;
+/// Largest version 1 blob; it was always saved as a single variable.
+const MAX_LEN_V1: usize = HDR_LEN + COUNTS_LEN_V1 + 16 * DOM_LEN + 128 * ASSIGN_LEN + 256 * MAP_LEN;
+
+/// Bytes per variable when saving
+pub const VAR_CHUNK: usize = 4096;
+const VAR_CHUNKS: usize = MAX_LEN.div_ceil(VAR_CHUNK);
+const VAR_NAME: &str = "ZerovisorIommuState";
+const VAR_NS: VariableVendor = VariableVendor::GLOBAL_VARIABLE;
+const PATH_MAX: usize = 128;
+
+/// Record counts of an exported or imported blob.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Summary { pub domains: usize, pub assignments: usize, pub mappings: usize, pub bytes: usize }
+
+#[derive(Clone, Copy, Default)]
+struct Assign { seg: u16, bus: u8, dev: u8, func: u8, dom: u16 }
+
+#[derive(Clone, Copy, Default)]
+struct Map { dom: u16, perm: u8, iova: u64, pa: u64, len: u64 }
+
+fn rd16(b: &[u8], o: usize) -> u16 { u16::from_le_bytes([b[o], b[o + 1]]) }
+fn rd32(b: &[u8], o: usize) -> u32 { u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]]) }
+fn rd64(b: &[u8], o: usize) -> u64 { let mut v = [0u8; 8]; v.copy_from_slice(&b[o..o + 8]); u64::from_le_bytes(v) }
+
+fn buffer(len: usize) -> Result<Vec<u8>, &'static str> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(len).map_err(|_| "iommu: out of memory")?;
+    v.resize(len, 0);
+    Ok(v)
+}
+
+/// Encode the current state; `Summary::bytes` is the blob length.
+pub fn export() -> Result<(Vec<u8>, Summary), &'static str> {
+    // Records added while the tables are walked are left out.
+    let (nd, na, nm) = (super::state::domain_count(), super::state::assignment_count(), super::state::mapping_count());
+    let mut out = buffer(HDR_LEN + COUNTS_LEN + nd * DOM_LEN + na * ASSIGN_LEN + nm * MAP_LEN)?;
+    let mut s = Summary::default();
+    let mut n = HDR_LEN + COUNTS_LEN;
+    super::state::list_domains(|id| {
+        if s.domains == nd { return; }
+        out[n..n + 2].copy_from_slice(&id.to_le_bytes());
+        n += DOM_LEN; s.domains += 1;
+    });
+    super::state::list_assignments(|seg, bus, dev, func, dom| {
+        if s.assignments == na { return; }
+        out[n..n + 2].copy_from_slice(&seg.to_le_bytes());
+        out[n + 2] = bus; out[n + 3] = dev; out[n + 4] = func; out[n + 5] = 0;
+        out[n + 6..n + 8].copy_from_slice(&dom.to_le_bytes());
+        n += ASSIGN_LEN; s.assignments += 1;
+    });
+    super::state::list_mappings(|dom, iova, pa, len, r, w, x| {
+        if s.mappings == nm { return; }
+        out[n..n + 2].copy_from_slice(&dom.to_le_bytes());
+        out[n + 2] = if r { PERM_R } else { 0 } | if w { PERM_W } else { 0 } | if x { PERM_X } else { 0 };
+        for b in &mut out[n + 3..n + 8] { *b = 0; }
+        out[n + 8..n + 16].copy_from_slice(&iova.to_le_bytes());
+        out[n + 16..n + 24].copy_from_slice(&pa.to_le_bytes());
+        out[n + 24..n + 32].copy_from_slice(&len.to_le_bytes());
+        n += MAP_LEN; s.mappings += 1;
+    });
+    // Records removed during the walk leave the tail unused.
+    out.truncate(n);
+    let c = HDR_LEN;
+    out[c..c + 4].copy_from_slice(&(s.domains as u32).to_le_bytes());
+    out[c + 4..c + 8].copy_from_slice(&(s.assignments as u32).to_le_bytes());
+    out[c + 8..c + 12].copy_from_slice(&(s.mappings as u32).to_le_bytes());
+    out[c + 12..c + 16].copy_from_slice(&0u32.to_le_bytes());
+    let payload = (n - HDR_LEN) as u32;
+    let crc = crate::util::crc32::crc32(&out[HDR_LEN..n]);
+    out[0..4].copy_from_slice(&MAGIC);
+    out[4..6].copy_from_slice(&VERSION.to_le_bytes());
+    out[6..8].copy_from_slice(&0u16.to_le_bytes());
+    out[8..12].copy_from_slice(&payload.to_le_bytes());
+    out[12..16].copy_from_slice(&crc.to_le_bytes());
+    s.bytes = n;
+    Ok((out, s))
+}
+
+/// Validate a blob and replace the current state with it. Nothing is changed
+/// unless the whole blob parses and every record refers to a listed domain.
+pub fn import(system_table: &mut SystemTable<Boot>, data: &[u8]) -> Result<Summary, &'static str> {
+    if data.len() < HDR_LEN { return Err("iommu: blob truncated"); }
+    if data[0..4] != MAGIC { return Err("iommu: not an IOMMU state blob"); }
+    let counts_len = match rd16(data, 4) { 1 => COUNTS_LEN_V1, VERSION => COUNTS_LEN, _ => return Err("iommu: unsupported blob version") };
+    let payload = rd32(data, 8) as usize;
+    if payload < counts_len || HDR_LEN + payload > data.len() { return Err("iommu: blob length mismatch"); }
+    let body = &data[HDR_LEN..HDR_LEN + payload];
+    if crate::util::crc32::crc32(body) != rd32(data, 12) { return Err("iommu: blob checksum mismatch"); }
+    let (nd, na, nm) = if counts_len == COUNTS_LEN_V1 {
+        (rd16(body, 0) as usize, rd16(body, 2) as usize, rd16(body, 4) as usize)
+    } else {
+        (rd32(body, 0) as usize, rd32(body, 4) as usize, rd32(body, 8) as usize)
+    };
+    if nd > MAX_DOMAINS || na > MAX_ASSIGNMENTS || nm > MAX_MAPPINGS { return Err("iommu: blob exceeds table capacity"); }
+    if counts_len + nd * DOM_LEN + na * ASSIGN_LEN + nm * MAP_LEN != payload { return Err("iommu: blob record counts do not match length"); }
+
+    let mut doms: Vec<u16> = Vec::new();
+    let mut assigns: Vec<Assign> = Vec::new();
+    let mut maps: Vec<Map> = Vec::new();
+    let mut remap: Vec<(u16, u16)> = Vec::new();
+    let reserved = doms.try_reserve_exact(nd).is_ok() && assigns.try_reserve_exact(na).is_ok()
+        && maps.try_reserve_exact(nm).is_ok() && remap.try_reserve_exact(nd).is_ok();
+    if !reserved { return Err("iommu: out of memory"); }
+    let mut o = counts_len;
+    for _ in 0..nd { doms.push(rd16(body, o)); o += DOM_LEN; }
+    for _ in 0..na {
+        let a = Assign { seg: rd16(body, o), bus: body[o + 2], dev: body[o + 3], func: body[o + 4], dom: rd16(body, o + 6) };
+        if a.dev > 31 || a.func > 7 { return Err("iommu: blob has an invalid device address"); }
+        if !doms.contains(&a.dom) { return Err("iommu: blob assignment names an unknown domain"); }
+        if assigns.iter().any(|b| (b.seg, b.bus, b.dev, b.func) == (a.seg, a.bus, a.dev, a.func)) { return Err("iommu: blob assigns a device twice"); }
+        assigns.push(a);
+        o += ASSIGN_LEN;
+    }
+    for _ in 0..nm {
+        let m = Map { dom: rd16(body, o), perm: body[o + 2], iova: rd64(body, o + 8), pa: rd64(body, o + 16), len: rd64(body, o + 24) };
+        if m.len == 0 { return Err("iommu: blob has an empty mapping"); }
+        if !doms.contains(&m.dom) { return Err("iommu: blob mapping names an unknown domain"); }
+        maps.push(m);
+        o += MAP_LEN;
+    }
+
+    // Only now drop the current layout.
+    while let Some(id) = first_domain() { let _ = super::state::destroy_domain(id); }
+
+    for &old in &doms {
+        remap.push((old, super::state::create_domain().ok_or("iommu: domain table full")?));
+    }
+    let new_id = |old: u16| remap.iter().find(|r| r.0 == old).map(|r| r.1).unwrap_or(0);
+    for a in &assigns {
+        if !super::state::assign_device(a.seg, a.bus, a.dev, a.func, new_id(a.dom)) { return Err("iommu: assignment table full"); }
+    }
+    for m in &maps {
+        let ok = super::state::add_mapping(new_id(m.dom), m.iova, m.pa, m.len, m.perm & PERM_R != 0, m.perm & PERM_W != 0, m.perm & PERM_X != 0);
+        if !ok { return Err("iommu: mapping table full"); }
+    }
+    super::vtd::apply_and_refresh(system_table);
+    super::vtd::apply_mappings(system_table);
+    super::amdv::apply_assignments(system_table);
+    super::amdv::apply_mappings(system_table);
+    Ok(Summary { domains: nd, assignments: na, mappings: nm, bytes: HDR_LEN + payload })
+}
+
+fn first_domain() -> Option<u16> {
+    let mut first = None;
+    super::state::list_domains(|id| { if first.is_none() { first = Some(id); } });
+    first
+}
+
+// ---- Storage ----
+
+/// Run `f` with the name of variable `i`: `VAR_NAME` itself for the first,
+/// `VAR_NAME` followed by `i` after that.
+fn with_var_name<R>(i: usize, f: impl FnOnce(&CStr16) -> Result<R, &'static str>) -> Result<R, &'static str> {
+    let mut ascii = [0u8; VAR_NAME.len() + 4];
+    ascii[..VAR_NAME.len()].copy_from_slice(VAR_NAME.as_bytes());
+    let mut n = VAR_NAME.len();
+    if i != 0 { n += crate::util::format::u64_dec(i as u64, &mut ascii[n..]); }
+    let mut wide = [0u16; VAR_NAME.len() + 5];
+    let s = core::str::from_utf8(&ascii[..n]).map_err(|_| "iommu: bad variable name")?;
+    f(CStr16::from_str_with_buf(s, &mut wide).map_err(|_| "iommu: bad variable name")?)
+}
+
+pub fn save_var(system_table: &SystemTable<Boot>) -> Result<Summary, &'static str> {
+    let (buf, s) = export()?;
+    let rs = system_table.runtime_services();
+    let attrs = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::NON_VOLATILE;
+    let count = buf.len().div_ceil(VAR_CHUNK);
+    for (i, chunk) in buf.chunks(VAR_CHUNK).enumerate().rev() {
+        with_var_name(i, |name| rs.set_variable(name, &VAR_NS, attrs, chunk).map_err(|_| "iommu: set_variable failed"))?;
+    }
+    // Drop the tail of an earlier, longer save.
+    for i in count..VAR_CHUNKS {
+        if with_var_name(i, |name| rs.delete_variable(name, &VAR_NS).map_err(|_| "iommu: no such variable")).is_err() { break; }
+    }
+    Ok(s)
+}
+
+/// Reassemble the saved blob from its variables without importing it.
+pub fn read_var(rs: &RuntimeServices) -> Result<Vec<u8>, &'static str> {
+    let mut buf = buffer(MAX_LEN_V1.max(VAR_CHUNK))?;
+    let first = rs.get_variable(uefi::cstr16!("ZerovisorIommuState"), &VAR_NS, &mut buf).map_err(|_| "iommu: no saved state")?.0.len();
+    let total = if first >= HDR_LEN { HDR_LEN + rd32(&buf, 8) as usize } else { first };
+    if total > MAX_LEN { return Err("iommu: blob too large"); }
+    if total > buf.len() {
+        let grow = total - buf.len();
+        buf.try_reserve_exact(grow).map_err(|_| "iommu: out of memory")?;
+        buf.resize(total, 0);
+    }
+    let mut got = first;
+    let mut i = 1;
+    while got < total {
+        let len = with_var_name(i, |name| rs.get_variable(name, &VAR_NS, &mut buf[got..]).map(|(d, _)| d.len()).map_err(|_| "iommu: saved state is incomplete"))?;
+        if len == 0 { return Err("iommu: saved state is incomplete"); }
+        got += len;
+        i += 1;
+    }
+    buf.truncate(got);
+    Ok(buf)
+}
+
+pub fn load_var(system_table: &mut SystemTable<Boot>) -> Result<Summary, &'static str> {
+    let buf = read_var(system_table.runtime_services())?;
+    import(system_table, &buf)
+}
+
+/// Open `path` on the boot ESP, optionally recreating it empty.
+fn open_esp_file(system_table: &SystemTable<Boot>, path: &str, create: bool) -> Result<RegularFile, &'static str> {
+    let mut pbuf = [0u8; PATH_MAX];
+    if path.is_empty() || path.len() > pbuf.len() { return Err("iommu: invalid path"); }
+    for (i, &c) in path.as_bytes().iter().enumerate() { pbuf[i] = if c == b'/' { b'\\' } else { c }; }
+    let p = core::str::from_utf8(&pbuf[..path.len()]).map_err(|_| "iommu: invalid path")?;
+    let mut name_buf = [0u16; PATH_MAX + 2];
+    let name = CStr16::from_str_with_buf(p, &mut name_buf).map_err(|_| "iommu: invalid path")?;
+    let bs = system_table.boot_services();
+    let mut fs = bs.get_image_file_system(bs.image_handle()).map_err(|_| "iommu: ESP not accessible")?;
+    let mut root = fs.open_volume().map_err(|_| "iommu: open volume failed")?;
+    if create {
+        // Truncate by deleting: a shorter blob must not keep the old tail.
+        if let Some(old) = root.open(name, FileMode::ReadWrite, FileAttribute::empty()).ok().and_then(|h| h.into_regular_file()) { let _ = old.delete(); }
+        let h = root.open(name, FileMode::CreateReadWrite, FileAttribute::empty()).map_err(|_| "iommu: cannot create file")?;
+        return h.into_regular_file().ok_or("iommu: not a regular file");
+    }
+    let h = root.open(name, FileMode::Read, FileAttribute::empty()).map_err(|_| "iommu: file not found")?;
+    h.into_regular_file().ok_or("iommu: not a regular file")
+}
+
+pub fn save_file(system_table: &SystemTable<Boot>, path: &str) -> Result<Summary, &'static str> {
+    let (buf, s) = export()?;
+    let mut f = open_esp_file(system_table, path, true)?;
+    f.write(&buf).map_err(|_| "iommu: write failed")?;
+    f.flush().map_err(|_| "iommu: flush failed")?;
+    Ok(s)
+}
+
+pub fn load_file(system_table: &mut SystemTable<Boot>, path: &str) -> Result<Summary, &'static str> {
+    let mut f = open_esp_file(system_table, path, false)?;
+    let mut info_buf = [0u8; 512];
+    let size = f.get_info::<FileInfo>(&mut info_buf).map_err(|_| "iommu: file info failed")?.file_size() as usize;
+    if size > MAX_LEN { return Err("iommu: blob too large"); }
+    let mut buf = buffer(size)?;
+    let mut got = 0usize;
+    while got < size {
+        match f.read(&mut buf[got..size]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => got += n,
+        }
+    }
+    if got != size { return Err("iommu: short read"); }
+    import(system_table, &buf[..size])
+}
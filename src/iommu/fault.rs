@@ -0,0 +1,222 @@
+#![allow(dead_code)]
+
+//! DMA remapping fault collection and per-device quarantine.
+//!
+//! `poll` drains the VT-d primary fault recording registers and the AMD-Vi
+//! event logs. Each fault is written to the audit trail (and persisted with
+//! it) and kept in a short in-RAM list for `iommu faults`. Faults are also
+//! counted per source device: an assigned device that reaches the threshold
+//! is unassigned from its domain and blocked at the IOMMU until released.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::fmt::Write as _;
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::time::clock::now_ms;
+use crate::util::spinlock::SpinLock;
+
+pub const RECENT_MAX: usize = 64;
+pub const TRACKED_MAX: usize = 64;
+const DEFAULT_THRESHOLD: u32 = 8;
+
+#[derive(Clone, Copy)]
+pub struct Fault {
+    pub seg: u16, pub bus: u8, pub dev: u8, pub func: u8,
+    /// Decoded as an AMD-Vi event code rather than a VT-d fault reason
+    pub amd: bool,
+    pub reason: u8,
+    pub write: bool,
+    pub addr: u64,
+    pub t_ms: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct DeviceFaults {
+    pub seg: u16, pub bus: u8, pub dev: u8, pub func: u8,
+    pub faults: u32,
+    pub quarantined: bool,
+    /// Domain the device was pulled from (0 if it had none)
+    pub dom: u16,
+}
+
+static RECENT: SpinLock<([Option<Fault>; RECENT_MAX], usize)> = SpinLock::new(([None; RECENT_MAX], 0));
+static DEVICES: SpinLock<[Option<DeviceFaults>; TRACKED_MAX]> = SpinLock::new([None; TRACKED_MAX]);
+/// Faults before an assigned device is quarantined; 0 disables the policy
+static THRESHOLD: AtomicU32 = AtomicU32::new(DEFAULT_THRESHOLD);
+
+pub fn threshold() -> u32 { THRESHOLD.load(Ordering::Relaxed) }
+pub fn set_threshold(n: u32) { THRESHOLD.store(n, Ordering::Relaxed); }
+
+fn same(d: &DeviceFaults, seg: u16, bus: u8, dev: u8, func: u8) -> bool { d.seg == seg && d.bus == bus && d.dev == dev && d.func == func }
+
+/// Record one fault; true when it pushes an assigned device over the threshold.
+fn note(f: Fault) -> bool {
+    crate::obs::metrics::IOMMU_FAULTS.fetch_add(1, Ordering::Relaxed);
+    crate::diag::audit::record(crate::diag::audit::AuditKind::IommuFault { seg: f.seg, bus: f.bus, dev: f.dev, func: f.func, amd: f.amd, reason: f.reason, write: f.write, addr: f.addr });
+    crate::obs::trace::emit(crate::obs::trace::Event::IommuFault { seg: f.seg, bus: f.bus, dev: f.dev, func: f.func, amd: f.amd, reason: f.reason, write: f.write, addr: f.addr });
+    RECENT.lock(|(ring, next)| { ring[*next % RECENT_MAX] = Some(f); *next += 1; });
+    let count = DEVICES.lock(|arr| {
+        if let Some(d) = arr.iter_mut().flatten().find(|d| same(d, f.seg, f.bus, f.dev, f.func)) {
+            if d.quarantined { return None; }
+            d.faults = d.faults.saturating_add(1);
+            return Some(d.faults);
+        }
+        // Table full: the device is still audited, just not counted.
+        let slot = arr.iter_mut().find(|d| d.is_none())?;
+        *slot = Some(DeviceFaults { seg: f.seg, bus: f.bus, dev: f.dev, func: f.func, faults: 1, quarantined: false, dom: 0 });
+        Some(1)
+    });
+    let limit = threshold();
+    match count {
+        Some(c) => limit != 0 && c == limit && crate::iommu::state::find_domain_for_bdf(f.seg, f.bus, f.dev, f.func).is_some(),
+        None => false,
+    }
+}
+
+/// Drain hardware fault sources and apply the quarantine policy. Returns the
+/// number of faults collected.
+pub fn poll(system_table: &mut SystemTable<Boot>) -> usize {
+    let mut over: [Option<(u16, u8, u8, u8)>; 8] = [None; 8];
+    let mut n_over = 0usize;
+    let t_ms = now_ms();
+    let mut take = |seg: u16, sid: u16, amd: bool, reason: u8, write: bool, addr: u64| {
+        let (bus, dev, func) = ((sid >> 8) as u8, ((sid >> 3) & 0x1F) as u8, (sid & 7) as u8);
+        if note(Fault { seg, bus, dev, func, amd, reason, write, addr, t_ms }) && n_over < over.len() {
+            over[n_over] = Some((seg, bus, dev, func)); n_over += 1;
+        }
+    };
+    let mut total = crate::iommu::vtd::drain_faults(|seg, sid, reason, write, addr| take(seg, sid, false, reason, write, addr));
+    total += crate::iommu::amdv::drain_events(|seg, sid, code, write, addr| take(seg, sid, true, code, write, addr));
+    for &(seg, bus, dev, func) in over.iter().flatten() {
+        if let Ok(dom) = quarantine(system_table, seg, bus, dev, func) {
+            let mut bdf = [0u8; 16];
+            let n = fmt_bdf(seg, bus, dev, func, &mut bdf);
+            crate::log!(Warn, "iommu", "quarantined {} from dom={} after faults={}", core::str::from_utf8(&bdf[..n]).unwrap_or("?"), dom, threshold());
+        }
+    }
+    total
+}
+
+/// Unassign `seg:bus:dev.func` from its domain (if any) and block its DMA on
+/// both VT-d and AMD-Vi. Returns the domain it was taken from (0 if none).
+pub fn quarantine(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8) -> Result<u16, &'static str> {
+    if is_quarantined(seg, bus, dev, func) { return Err("iommu: device already quarantined"); }
+    let dom = crate::iommu::state::find_domain_for_bdf(seg, bus, dev, func).unwrap_or(0);
+    let tracked = DEVICES.lock(|arr| {
+        if let Some(d) = arr.iter_mut().flatten().find(|d| same(d, seg, bus, dev, func)) { d.quarantined = true; d.dom = dom; return true; }
+        match arr.iter_mut().find(|d| d.is_none()) {
+            Some(slot) => { *slot = Some(DeviceFaults { seg, bus, dev, func, faults: 0, quarantined: true, dom }); true }
+            None => false,
+        }
+    });
+    if !tracked { return Err("iommu: fault table full"); }
+    if dom != 0 { let _ = crate::iommu::state::unassign_device(seg, bus, dev, func); }
+    let _ = crate::iommu::vtd::block_bdf(system_table, seg, bus, dev, func);
+    let _ = crate::iommu::amdv::block_device(seg, bus, dev, func);
+    crate::obs::metrics::IOMMU_QUARANTINED.fetch_add(1, Ordering::Relaxed);
+    crate::diag::audit::record(crate::diag::audit::AuditKind::IommuQuarantine { seg, bus, dev, func, dom });
+    Ok(dom)
+}
+
+/// Lift a quarantine. The device is not reassigned: on VT-d it stays without
+/// a context entry like any unassigned device, on AMD-Vi it returns to
+/// untranslated DMA. Use `dom ... assign` to give it a domain again.
+pub fn release(seg: u16, bus: u8, dev: u8, func: u8) -> Result<(), &'static str> {
+    let found = DEVICES.lock(|arr| {
+        for slot in arr.iter_mut() {
+            if matches!(slot, Some(d) if same(d, seg, bus, dev, func) && d.quarantined) { *slot = None; return true; }
+        }
+        false
+    });
+    if !found { return Err("iommu: device is not quarantined"); }
+    crate::iommu::amdv::unblock_device(seg, bus, dev, func);
+    Ok(())
+}
+
+pub fn is_quarantined(seg: u16, bus: u8, dev: u8, func: u8) -> bool {
+    DEVICES.lock(|arr| arr.iter().flatten().any(|d| same(d, seg, bus, dev, func) && d.quarantined))
+}
+
+/// Recent faults, oldest first.
+pub fn for_each_recent(mut f: impl FnMut(&Fault)) {
+    let (ring, next) = RECENT.lock(|r| *r);
+    let start = next.saturating_sub(RECENT_MAX);
+    for i in start..next { if let Some(x) = ring[i % RECENT_MAX].as_ref() { f(x); } }
+}
+
+pub fn for_each_device(mut f: impl FnMut(&DeviceFaults)) {
+    let arr = DEVICES.lock(|a| *a);
+    for d in arr.iter().flatten() { f(d); }
+}
+
+/// Forget recent faults and per-device counts; quarantines stay in force.
+pub fn clear() {
+    RECENT.lock(|r| *r = ([None; RECENT_MAX], 0));
+    DEVICES.lock(|arr| {
+        for slot in arr.iter_mut() {
+            match slot {
+                Some(d) if d.quarantined => d.faults = 0,
+                _ => *slot = None,
+            }
+        }
+    });
+}
+
+fn fmt_bdf(seg: u16, bus: u8, dev: u8, func: u8, out: &mut [u8]) -> usize {
+    let hex = b"0123456789abcdef";
+    let mut n = 0;
+    for sh in [12u32, 8, 4, 0] { out[n] = hex[((seg >> sh) & 0xF) as usize]; n += 1; }
+    out[n] = b':'; n += 1;
+    out[n] = hex[(bus >> 4) as usize]; out[n + 1] = hex[(bus & 0xF) as usize]; n += 2;
+    out[n] = b':'; n += 1;
+    out[n] = hex[(dev >> 4) as usize]; out[n + 1] = hex[(dev & 0xF) as usize]; n += 2;
+    out[n] = b'.'; n += 1;
+    out[n] = hex[(func & 7) as usize]; n += 1;
+    n
+}
+
+/// `iommu faults`: recent faults, then per-device counts and quarantines.
+pub fn report(system_table: &mut SystemTable<Boot>) {
+    let stdout = system_table.stdout();
+    let mut any = false;
+    for_each_recent(|f| {
+        any = true;
+        let mut buf = [0u8; 160]; let mut n = 0;
+        for &b in b"fault: t=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(f.t_ms, &mut buf[n..]);
+        for &b in b"ms bdf=" { buf[n] = b; n += 1; }
+        n += fmt_bdf(f.seg, f.bus, f.dev, f.func, &mut buf[n..]);
+        for &b in if f.amd { &b" src=amdvi type="[..] } else { &b" src=vtd type="[..] } { buf[n] = b; n += 1; }
+        for &b in crate::diag::audit::iommu_fault_name(f.amd, f.reason).as_bytes() { buf[n] = b; n += 1; }
+        for &b in b"(0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(f.reason as u64, &mut buf[n..]);
+        for &b in if f.write { &b") write addr=0x"[..] } else { &b") read addr=0x"[..] } { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(f.addr, &mut buf[n..]);
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    });
+    if !any { let _ = stdout.write_str("fault: none recorded\r\n"); }
+    for_each_device(|d| {
+        let mut buf = [0u8; 96]; let mut n = 0;
+        for &b in b"device: bdf=" { buf[n] = b; n += 1; }
+        n += fmt_bdf(d.seg, d.bus, d.dev, d.func, &mut buf[n..]);
+        for &b in b" faults=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(d.faults as u64, &mut buf[n..]);
+        if d.quarantined {
+            for &b in b" QUARANTINED dom=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(d.dom as u64, &mut buf[n..]);
+        }
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    });
+    let mut buf = [0u8; 64]; let mut n = 0;
+    for &b in b"policy: threshold=" { buf[n] = b; n += 1; }
+    match threshold() {
+        0 => { for &b in b"off" { buf[n] = b; n += 1; } }
+        t => { n += crate::util::format::u64_dec(t as u64, &mut buf[n..]); }
+    }
+    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+}
@@ -0,0 +1,364 @@
+#![allow(dead_code)]
+
+//! PCI capability walker and MSI / MSI-X parsing.
+//!
+//! Walks the standard capability list and the PCIe extended list of a
+//! function's ECAM config space, and decodes the MSI and MSI-X capabilities
+//! into plain structs: vector counts, where the MSI-X table and PBA live,
+//! mask state, and the messages currently programmed. Messages are decoded
+//! in both compatibility and remappable format so the report shows where
+//! each vector lands today, before anything virtualizes or remaps it.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use core::fmt::Write as _;
+
+use super::{mmio_read16, mmio_read32, mmio_read8};
+
+pub const CAP_ID_PM: u8 = 0x01;
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+pub const CAP_ID_PCIE: u8 = 0x10;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+const STATUS_CAP_LIST: u16 = 1 << 4;
+
+// MSI message control
+const MSI_CTL_EN: u16 = 1 << 0;
+const MSI_CTL_64: u16 = 1 << 7;
+const MSI_CTL_PVM: u16 = 1 << 8;
+// MSI-X message control
+const MSIX_CTL_FMASK: u16 = 1 << 14;
+const MSIX_CTL_EN: u16 = 1 << 15;
+// MSI-X vector control
+const MSIX_VCTL_MASK: u32 = 1 << 0;
+
+// Message address fields
+const ADDR_IF_REMAP: u32 = 1 << 4;
+const ADDR_RH: u32 = 1 << 3;
+const ADDR_DM: u32 = 1 << 2;
+// Remappable format reuses bit 3 as subhandle-valid
+const ADDR_SHV: u32 = 1 << 3;
+
+/// Call `f(id, offset)` for each entry of the standard capability list.
+pub fn for_each_cap(cfg: usize, mut f: impl FnMut(u8, usize)) {
+    if mmio_read16(cfg + 0x06) & STATUS_CAP_LIST == 0 { return; }
+    let mut p = (mmio_read8(cfg + 0x34) & 0xFC) as usize;
+    // Bounded: a malformed list can loop.
+    for _ in 0..48 {
+        if p < 0x40 { return; }
+        f(mmio_read8(cfg + p), p);
+        p = (mmio_read8(cfg + p + 1) & 0xFC) as usize;
+    }
+}
+
+pub fn find_cap(cfg: usize, id: u8) -> Option<usize> {
+    let mut out = None;
+    for_each_cap(cfg, |c, off| if c == id && out.is_none() { out = Some(off); });
+    out
+}
+
+/// Call `f(id, version, offset)` for each PCIe extended capability.
+pub fn for_each_ext_cap(cfg: usize, mut f: impl FnMut(u16, u8, usize)) {
+    let mut off = 0x100usize;
+    for _ in 0..64 {
+        let h = mmio_read32(cfg + off);
+        if h == 0 || h == 0xFFFF_FFFF { return; }
+        f((h & 0xFFFF) as u16, ((h >> 16) & 0xF) as u8, off);
+        off = ((h >> 20) & 0xFFC) as usize;
+        if off < 0x100 { return; }
+    }
+}
+
+pub fn find_ext_cap(cfg: usize, id: u16) -> Option<usize> {
+    let mut out = None;
+    for_each_ext_cap(cfg, |c, _, off| if c == id && out.is_none() { out = Some(off); });
+    out
+}
+
+/// Host address of BAR `bir` (type-0 header), following 64-bit BARs.
+pub fn bar_addr(cfg: usize, bir: usize) -> u64 {
+    if bir > 5 { return 0; }
+    let lo = mmio_read32(cfg + 0x10 + bir * 4);
+    if lo & 1 != 0 { return 0; }
+    let hi = if (lo & 0b110) == 0b100 && bir < 5 { mmio_read32(cfg + 0x14 + bir * 4) } else { 0 };
+    (hi as u64) << 32 | (lo & !0xF) as u64
+}
+
+pub fn cap_name(id: u8) -> &'static str {
+    match id {
+        CAP_ID_PM => "pm",
+        0x03 => "vpd",
+        CAP_ID_MSI => "msi",
+        CAP_ID_VENDOR => "vendor",
+        0x0D => "bridge-subsys",
+        CAP_ID_PCIE => "pcie",
+        CAP_ID_MSIX => "msix",
+        0x12 => "sata",
+        0x13 => "af",
+        0x14 => "ea",
+        _ => "?",
+    }
+}
+
+pub fn ext_cap_name(id: u16) -> &'static str {
+    match id {
+        0x0001 => "aer",
+        0x0002 | 0x0009 => "vc",
+        0x0003 => "dsn",
+        0x000B => "vendor",
+        0x000D => "acs",
+        0x000E => "ari",
+        0x000F => "ats",
+        0x0010 => "sriov",
+        0x0013 => "pri",
+        0x0015 => "rebar",
+        0x0017 => "tph",
+        0x0018 => "ltr",
+        0x0019 => "secondary-pcie",
+        0x001B => "pasid",
+        0x001E => "l1ss",
+        0x0023 => "dvsec",
+        0x0025 => "dlf",
+        0x0026 => "phy16",
+        0x002A => "pl32",
+        _ => "?",
+    }
+}
+
+/// MSI capability state.
+#[derive(Clone, Copy, Debug)]
+pub struct Msi {
+    pub off: usize,
+    pub enabled: bool,
+    /// Vectors the function can request / has been granted (powers of two)
+    pub vectors_cap: u8,
+    pub vectors_en: u8,
+    pub is64: bool,
+    pub per_vector_mask: bool,
+    pub mask: u32,
+    pub pending: u32,
+    pub addr: u64,
+    pub data: u16,
+}
+
+pub fn msi(cfg: usize) -> Option<Msi> {
+    let off = find_cap(cfg, CAP_ID_MSI)?;
+    let ctl = mmio_read16(cfg + off + 2);
+    let is64 = ctl & MSI_CTL_64 != 0;
+    let pvm = ctl & MSI_CTL_PVM != 0;
+    let lo = mmio_read32(cfg + off + 4) as u64;
+    let hi = if is64 { mmio_read32(cfg + off + 8) as u64 } else { 0 };
+    let data_off = if is64 { 0x0C } else { 0x08 };
+    // Mask and pending follow the (padded) data word when per-vector masking exists.
+    let (mask, pending) = if pvm { (mmio_read32(cfg + off + data_off + 4), mmio_read32(cfg + off + data_off + 8)) } else { (0, 0) };
+    Some(Msi {
+        off,
+        enabled: ctl & MSI_CTL_EN != 0,
+        vectors_cap: 1 << ((ctl >> 1) & 0x7).min(5),
+        vectors_en: 1 << ((ctl >> 4) & 0x7).min(5),
+        is64,
+        per_vector_mask: pvm,
+        mask,
+        pending,
+        addr: hi << 32 | lo,
+        data: mmio_read16(cfg + off + data_off),
+    })
+}
+
+/// MSI-X capability state; table and PBA addresses are resolved through the BARs.
+#[derive(Clone, Copy, Debug)]
+pub struct MsiX {
+    pub off: usize,
+    pub enabled: bool,
+    pub function_mask: bool,
+    pub table_size: u16,
+    pub table_bir: u8,
+    pub table_off: u32,
+    pub pba_bir: u8,
+    pub pba_off: u32,
+    /// Zero when the BAR is unassigned or I/O
+    pub table_addr: u64,
+    pub pba_addr: u64,
+}
+
+pub fn msix(cfg: usize) -> Option<MsiX> {
+    let off = find_cap(cfg, CAP_ID_MSIX)?;
+    let ctl = mmio_read16(cfg + off + 2);
+    let tbl = mmio_read32(cfg + off + 4);
+    let pba = mmio_read32(cfg + off + 8);
+    let resolve = |bir: u32, o: u32| { let b = bar_addr(cfg, bir as usize); if b == 0 { 0 } else { b + o as u64 } };
+    Some(MsiX {
+        off,
+        enabled: ctl & MSIX_CTL_EN != 0,
+        function_mask: ctl & MSIX_CTL_FMASK != 0,
+        table_size: (ctl & 0x7FF) + 1,
+        table_bir: (tbl & 7) as u8,
+        table_off: tbl & !7,
+        pba_bir: (pba & 7) as u8,
+        pba_off: pba & !7,
+        table_addr: resolve(tbl & 7, tbl & !7),
+        pba_addr: resolve(pba & 7, pba & !7),
+    })
+}
+
+/// One MSI-X table entry.
+#[derive(Clone, Copy, Debug)]
+pub struct MsixEntry { pub addr: u64, pub data: u32, pub masked: bool, pub pending: bool }
+
+/// Read entry `i`; None when the table is not reachable or `i` is out of range.
+/// Memory decoding must be on for the table BAR to answer.
+pub fn msix_entry(x: &MsiX, i: u16) -> Option<MsixEntry> {
+    if x.table_addr == 0 || i >= x.table_size { return None; }
+    let e = x.table_addr as usize + i as usize * 16;
+    let lo = mmio_read32(e) as u64;
+    let hi = mmio_read32(e + 4) as u64;
+    let pending = x.pba_addr != 0 && mmio_read32(x.pba_addr as usize + (i as usize / 32) * 4) & (1 << (i % 32)) != 0;
+    Some(MsixEntry { addr: hi << 32 | lo, data: mmio_read32(e + 8), masked: mmio_read32(e + 12) & MSIX_VCTL_MASK != 0, pending })
+}
+
+/// Append a decoded message to `out`: where it is delivered, or which
+/// remapping table entry it names.
+fn fmt_message(out: &mut [u8], addr: u64, data: u32) -> usize {
+    let mut n = 0;
+    let a = addr as u32;
+    if addr == 0 {
+        for &b in b" unprogrammed" { out[n] = b; n += 1; }
+        return n;
+    }
+    if a & 0xFFF0_0000 != 0xFEE0_0000 {
+        for &b in b" addr=0x" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(addr, &mut out[n..]);
+        for &b in b" (not an interrupt address)" { out[n] = b; n += 1; }
+        return n;
+    }
+    if a & ADDR_IF_REMAP != 0 {
+        let handle = ((a >> 5) & 0x7FFF) | ((a >> 2) & 1) << 15;
+        // With SHV (bit 3 in this format) the data word adds a subhandle.
+        let index = if a & ADDR_SHV != 0 { handle + (data & 0xFFFF) } else { handle };
+        for &b in b" remapped irte=" { out[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(index as u64, &mut out[n..]);
+        return n;
+    }
+    for &b in b" vec=" { out[n] = b; n += 1; }
+    n += crate::util::format::u64_dec((data & 0xFF) as u64, &mut out[n..]);
+    for &b in b" dest=" { out[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(((a >> 12) & 0xFF) as u64, &mut out[n..]);
+    for &b in if a & ADDR_DM != 0 { &b" logical"[..] } else { &b" physical"[..] } { out[n] = b; n += 1; }
+    if a & ADDR_RH != 0 { for &b in b" rh" { out[n] = b; n += 1; } }
+    for &b in b" dlm=" { out[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(((data >> 8) & 7) as u64, &mut out[n..]);
+    for &b in if data & (1 << 15) != 0 { &b" level"[..] } else { &b" edge"[..] } { out[n] = b; n += 1; }
+    n
+}
+
+fn emit(system_table: &mut SystemTable<Boot>, buf: &mut [u8], mut n: usize) {
+    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+    let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+}
+
+/// Print every standard and extended capability of a function.
+pub fn report_caps(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8) -> Result<(), &'static str> {
+    let cfg = super::ecam_cfg_base(system_table, seg, bus, dev, func).ok_or("pci: no ECAM window for device")?;
+    if mmio_read16(cfg) == 0xFFFF { return Err("pci: no device at that address"); }
+    let mut caps = [(0u8, 0usize); 48];
+    let mut nc = 0;
+    for_each_cap(cfg, |id, off| { caps[nc] = (id, off); nc += 1; });
+    let mut ext = [(0u16, 0u8, 0usize); 64];
+    let mut ne = 0;
+    for_each_ext_cap(cfg, |id, ver, off| { ext[ne] = (id, ver, off); ne += 1; });
+    for &(id, off) in &caps[..nc] {
+        let mut buf = [0u8; 96]; let mut n = 0;
+        for &b in b"cap 0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(off as u64, &mut buf[n..]);
+        for &b in b" id=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(id as u64, &mut buf[n..]);
+        buf[n] = b' '; n += 1;
+        for &b in cap_name(id).as_bytes() { buf[n] = b; n += 1; }
+        emit(system_table, &mut buf, n);
+    }
+    for &(id, ver, off) in &ext[..ne] {
+        let mut buf = [0u8; 96]; let mut n = 0;
+        for &b in b"ext 0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(off as u64, &mut buf[n..]);
+        for &b in b" id=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(id as u64, &mut buf[n..]);
+        for &b in b" v" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(ver as u64, &mut buf[n..]);
+        buf[n] = b' '; n += 1;
+        for &b in ext_cap_name(id).as_bytes() { buf[n] = b; n += 1; }
+        emit(system_table, &mut buf, n);
+    }
+    if nc == 0 && ne == 0 { let _ = system_table.stdout().write_str("pci: no capabilities\r\n"); }
+    Ok(())
+}
+
+/// Print the MSI and MSI-X state of a function and where each vector is
+/// routed. Unprogrammed masked MSI-X entries are skipped unless `all`.
+pub fn report_msix(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, all: bool) -> Result<(), &'static str> {
+    let cfg = super::ecam_cfg_base(system_table, seg, bus, dev, func).ok_or("pci: no ECAM window for device")?;
+    if mmio_read16(cfg) == 0xFFFF { return Err("pci: no device at that address"); }
+    let sid = (bus as u16) << 8 | (dev as u16) << 3 | func as u16;
+    let m = msi(cfg);
+    let x = msix(cfg);
+    if m.is_none() && x.is_none() { let _ = system_table.stdout().write_str("pci: no MSI or MSI-X capability\r\n"); return Ok(()); }
+    if let Some(m) = m {
+        let mut buf = [0u8; 192]; let mut n = 0;
+        for &b in b"msi: cap=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(m.off as u64, &mut buf[n..]);
+        for &b in if m.enabled { &b" enabled"[..] } else { &b" disabled"[..] } { buf[n] = b; n += 1; }
+        for &b in b" vectors=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(m.vectors_en as u64, &mut buf[n..]);
+        buf[n] = b'/'; n += 1;
+        n += crate::util::format::u64_dec(m.vectors_cap as u64, &mut buf[n..]);
+        for &b in if m.is64 { &b" 64bit"[..] } else { &b" 32bit"[..] } { buf[n] = b; n += 1; }
+        if m.per_vector_mask {
+            for &b in b" mask=0x" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(m.mask as u64, &mut buf[n..]);
+            for &b in b" pending=0x" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(m.pending as u64, &mut buf[n..]);
+        }
+        n += fmt_message(&mut buf[n..], m.addr, m.data as u32);
+        if super::vtd_ir::route_of(seg, sid, super::vtd_ir::Source::Msi).is_some() { for &b in b" [ir]" { buf[n] = b; n += 1; } }
+        emit(system_table, &mut buf, n);
+    }
+    if let Some(x) = x {
+        let mut buf = [0u8; 192]; let mut n = 0;
+        for &b in b"msix: cap=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(x.off as u64, &mut buf[n..]);
+        for &b in if x.enabled { &b" enabled"[..] } else { &b" disabled"[..] } { buf[n] = b; n += 1; }
+        if x.function_mask { for &b in b" fmask" { buf[n] = b; n += 1; } }
+        for &b in b" size=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(x.table_size as u64, &mut buf[n..]);
+        for &b in b" table=bar" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(x.table_bir as u64, &mut buf[n..]);
+        for &b in b"+0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(x.table_off as u64, &mut buf[n..]);
+        for &b in b" pba=bar" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(x.pba_bir as u64, &mut buf[n..]);
+        for &b in b"+0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(x.pba_off as u64, &mut buf[n..]);
+        for &b in b" @0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(x.table_addr, &mut buf[n..]);
+        emit(system_table, &mut buf, n);
+        if x.table_addr == 0 { let _ = system_table.stdout().write_str("msix: table BAR unassigned\r\n"); return Ok(()); }
+        if mmio_read16(cfg + 0x04) & 0x2 == 0 { let _ = system_table.stdout().write_str("msix: memory decoding off, table not readable\r\n"); return Ok(()); }
+        let mut shown = 0u32;
+        for i in 0..x.table_size {
+            let e = match msix_entry(&x, i) { Some(e) => e, None => break };
+            if !all && e.masked && e.addr == 0 { continue; }
+            shown += 1;
+            let mut buf = [0u8; 160]; let mut n = 0;
+            for &b in b"  [" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(i as u64, &mut buf[n..]);
+            buf[n] = b']'; n += 1;
+            for &b in if e.masked { &b" masked"[..] } else { &b" live"[..] } { buf[n] = b; n += 1; }
+            if e.pending { for &b in b" pending" { buf[n] = b; n += 1; } }
+            n += fmt_message(&mut buf[n..], e.addr, e.data);
+            if super::vtd_ir::route_of(seg, sid, super::vtd_ir::Source::Msix(i)).is_some() { for &b in b" [ir]" { buf[n] = b; n += 1; } }
+            emit(system_table, &mut buf, n);
+        }
+        if shown == 0 { let _ = system_table.stdout().write_str("  (all entries masked and unprogrammed)\r\n"); }
+    }
+    Ok(())
+}
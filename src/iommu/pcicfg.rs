@@ -0,0 +1,213 @@
+#![allow(dead_code)]
+
+//! Controlled PCI config-space writes.
+//!
+//! The rest of the PCI code only reads config space. The few writes the
+//! hypervisor does need, such as flipping command register bits or sizing
+//! BARs, go through here instead of raw ECAM pokes. A function must be on the
+//! allowlist before anything is written. Only the command register (a fixed
+//! set of bits) and the BARs can be touched, and host bridges are never
+//! written. Every attempt, refused or not, lands in the audit trail with the
+//! register offset and the old and new values.
+//!
+//! Clearing bus-master enable is how a device gets quiesced before its IOMMU
+//! domain changes. The write waits for the PCIe Transactions Pending bit to
+//! drop, and fails if it doesn't, so callers know whether DMA is really idle.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use core::fmt::Write as _;
+
+use crate::util::spinlock::SpinLock;
+use super::{mmio_read16, mmio_read32, mmio_read8, mmio_write16, mmio_write32};
+
+pub const CMD_IO: u16 = 1 << 0;
+pub const CMD_MEM: u16 = 1 << 1;
+pub const CMD_BME: u16 = 1 << 2;
+pub const CMD_PARITY: u16 = 1 << 6;
+pub const CMD_SERR: u16 = 1 << 8;
+pub const CMD_INTX_DISABLE: u16 = 1 << 10;
+/// Command bits `update_command` may change
+pub const CMD_WRITABLE: u16 = CMD_IO | CMD_MEM | CMD_BME | CMD_PARITY | CMD_SERR | CMD_INTX_DISABLE;
+
+const REG_COMMAND: usize = 0x04;
+const REG_HEADER_TYPE: usize = 0x0E;
+const REG_BAR0: usize = 0x10;
+// PCIe capability: device status, Transactions Pending
+const PCIE_DEVSTA: usize = 0x0A;
+const PCIE_DEVSTA_TP: u16 = 1 << 5;
+
+pub const MAX_ALLOW: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Entry { seg: u16, bus: u8, dev: u8, func: u8 }
+
+static ALLOW: SpinLock<[Option<Entry>; MAX_ALLOW]> = SpinLock::new([None; MAX_ALLOW]);
+
+/// Permit config writes to a function.
+pub fn allow(seg: u16, bus: u8, dev: u8, func: u8) -> Result<(), &'static str> {
+    let e = Entry { seg, bus, dev, func };
+    ALLOW.lock(|t| {
+        if t.iter().any(|s| *s == Some(e)) { return Ok(()); }
+        let slot = t.iter_mut().find(|s| s.is_none()).ok_or("pcicfg: allowlist full")?;
+        *slot = Some(e);
+        Ok(())
+    })
+}
+
+/// Revoke write permission; false if the function was not listed.
+pub fn disallow(seg: u16, bus: u8, dev: u8, func: u8) -> bool {
+    let e = Entry { seg, bus, dev, func };
+    ALLOW.lock(|t| match t.iter_mut().find(|s| **s == Some(e)) { Some(s) => { *s = None; true } None => false })
+}
+
+pub fn is_allowed(seg: u16, bus: u8, dev: u8, func: u8) -> bool {
+    let e = Entry { seg, bus, dev, func };
+    ALLOW.lock(|t| t.iter().any(|s| *s == Some(e)))
+}
+
+pub fn for_each_allowed(mut f: impl FnMut(u16, u8, u8, u8)) {
+    let t = ALLOW.lock(|t| *t);
+    for e in t.iter().flatten() { f(e.seg, e.bus, e.dev, e.func); }
+}
+
+fn audit(seg: u16, bus: u8, dev: u8, func: u8, off: usize, old: u32, new: u32, denied: bool) {
+    crate::diag::audit::record(crate::diag::audit::AuditKind::PciConfigWrite { seg, bus, dev, func, off: off as u16, old, new, denied });
+}
+
+/// Resolve a function's config base and apply the interlocks. A refusal is
+/// audited with the value the caller wanted to write.
+fn target(system_table: &SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, off: usize, new: u32) -> Result<usize, &'static str> {
+    let cfg = super::ecam_cfg_base(system_table, seg, bus, dev, func).ok_or("pcicfg: no ECAM window for device")?;
+    if mmio_read16(cfg) == 0xFFFF { return Err("pcicfg: no device at that address"); }
+    let old = if off == REG_COMMAND { mmio_read16(cfg + off) as u32 } else { mmio_read32(cfg + off) };
+    if !is_allowed(seg, bus, dev, func) {
+        audit(seg, bus, dev, func, off, old, new, true);
+        return Err("pcicfg: device not on allowlist");
+    }
+    if mmio_read8(cfg + 0x0B) == 0x06 && mmio_read8(cfg + 0x0A) == 0x00 {
+        audit(seg, bus, dev, func, off, old, new, true);
+        return Err("pcicfg: refusing to write a host bridge");
+    }
+    Ok(cfg)
+}
+
+/// Set and clear command register bits; returns the value read back.
+pub fn update_command(system_table: &SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, set: u16, clear: u16) -> Result<u16, &'static str> {
+    if (set | clear) & !CMD_WRITABLE != 0 { return Err("pcicfg: command bit not writable"); }
+    let cfg = super::ecam_cfg_base(system_table, seg, bus, dev, func).ok_or("pcicfg: no ECAM window for device")?;
+    let old = mmio_read16(cfg + REG_COMMAND);
+    let new = (old & !clear) | set;
+    let cfg = target(system_table, seg, bus, dev, func, REG_COMMAND, new as u32)?;
+    if new != old { mmio_write16(cfg + REG_COMMAND, new); }
+    let now = mmio_read16(cfg + REG_COMMAND);
+    audit(seg, bus, dev, func, REG_COMMAND, old as u32, now as u32, false);
+    Ok(now)
+}
+
+/// Enable or disable bus mastering. Disabling waits for outstanding
+/// non-posted requests to complete and fails if they don't within ~100ms.
+pub fn set_bus_master(system_table: &SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, on: bool) -> Result<u16, &'static str> {
+    let cmd = if on { update_command(system_table, seg, bus, dev, func, CMD_BME, 0)? } else { update_command(system_table, seg, bus, dev, func, 0, CMD_BME)? };
+    if on { return Ok(cmd); }
+    let cfg = super::ecam_cfg_base(system_table, seg, bus, dev, func).ok_or("pcicfg: no ECAM window for device")?;
+    let Some(pcie) = super::pcicap::find_cap(cfg, super::pcicap::CAP_ID_PCIE) else { return Ok(cmd) };
+    let mut tries = 0;
+    while mmio_read16(cfg + pcie + PCIE_DEVSTA) & PCIE_DEVSTA_TP != 0 {
+        if tries == 1000 { return Err("pcicfg: transactions still pending after bus-master disable"); }
+        tries += 1;
+        let _ = system_table.boot_services().stall(100);
+    }
+    Ok(cmd)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BarKind { Io, Mem32, Mem64 }
+
+/// One implemented BAR as found by `probe_bars`.
+#[derive(Clone, Copy, Debug)]
+pub struct Bar {
+    pub index: u8,
+    pub kind: BarKind,
+    pub prefetch: bool,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Size every BAR of a type 0 or type 1 header with the all-ones probe.
+///
+/// Memory and I/O decode are switched off for the duration so the transient
+/// all-ones address never decodes, then the original BARs and command
+/// register are restored. Each probed BAR register is audited. Devices
+/// assigned to an IOMMU domain are refused.
+pub fn probe_bars(system_table: &SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, mut f: impl FnMut(Bar)) -> Result<(), &'static str> {
+    let cfg = target(system_table, seg, bus, dev, func, REG_BAR0, u32::MAX)?;
+    // A guest may have these BARs mapped; moving them under it is not safe.
+    if super::state::find_domain_for_bdf(seg, bus, dev, func).is_some() { return Err("pcicfg: device is assigned to a domain"); }
+    let nbars = match mmio_read8(cfg + REG_HEADER_TYPE) & 0x7F { 0 => 6, 1 => 2, _ => return Err("pcicfg: header type has no BARs") };
+    let cmd = mmio_read16(cfg + REG_COMMAND);
+    mmio_write16(cfg + REG_COMMAND, cmd & !(CMD_IO | CMD_MEM));
+    let mut bars = [None; 6];
+    let mut i = 0;
+    while i < nbars {
+        let off = REG_BAR0 + i * 4;
+        let orig = mmio_read32(cfg + off);
+        mmio_write32(cfg + off, u32::MAX);
+        let lo = mmio_read32(cfg + off);
+        mmio_write32(cfg + off, orig);
+        audit(seg, bus, dev, func, off, orig, u32::MAX, false);
+        let index = i as u8;
+        i += 1;
+        if lo == 0 { continue; }
+        if orig & 1 != 0 {
+            let mut mask = lo & !3;
+            if mask >> 16 == 0 { mask |= 0xFFFF_0000; }
+            bars[index as usize] = Some(Bar { index, kind: BarKind::Io, prefetch: false, addr: (orig & !3) as u64, size: (!mask).wrapping_add(1) as u64 });
+            continue;
+        }
+        let prefetch = orig & 8 != 0;
+        if (orig >> 1) & 3 == 2 && i < nbars {
+            let off_hi = REG_BAR0 + i * 4;
+            let orig_hi = mmio_read32(cfg + off_hi);
+            mmio_write32(cfg + off_hi, u32::MAX);
+            let hi = mmio_read32(cfg + off_hi);
+            mmio_write32(cfg + off_hi, orig_hi);
+            audit(seg, bus, dev, func, off_hi, orig_hi, u32::MAX, false);
+            i += 1;
+            let mask = (hi as u64) << 32 | (lo & !0xF) as u64;
+            let addr = (orig_hi as u64) << 32 | (orig & !0xF) as u64;
+            bars[index as usize] = Some(Bar { index, kind: BarKind::Mem64, prefetch, addr, size: (!mask).wrapping_add(1) });
+        } else {
+            let mask = lo & !0xF;
+            bars[index as usize] = Some(Bar { index, kind: BarKind::Mem32, prefetch, addr: (orig & !0xF) as u64, size: (!mask).wrapping_add(1) as u64 });
+        }
+    }
+    mmio_write16(cfg + REG_COMMAND, cmd);
+    for b in bars.iter().flatten() { f(*b); }
+    Ok(())
+}
+
+/// Print the sized BARs of a function.
+pub fn report_bars(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8) -> Result<(), &'static str> {
+    let mut bars = [None; 6];
+    probe_bars(system_table, seg, bus, dev, func, |b| bars[b.index as usize] = Some(b))?;
+    let stdout = system_table.stdout();
+    let mut any = false;
+    for b in bars.iter().flatten() {
+        any = true;
+        let mut buf = [0u8; 96]; let mut n = 0;
+        for &c in b"bar" { buf[n] = c; n += 1; }
+        n += crate::util::format::u32_dec(b.index as u32, &mut buf[n..]);
+        let kind: &[u8] = match b.kind { BarKind::Io => b" io", BarKind::Mem32 => b" mem32", BarKind::Mem64 => b" mem64" };
+        for &c in kind { buf[n] = c; n += 1; }
+        if b.prefetch { for &c in b" pf" { buf[n] = c; n += 1; } }
+        for &c in b" addr=0x" { buf[n] = c; n += 1; }
+        n += crate::util::format::u64_hex(b.addr, &mut buf[n..]);
+        for &c in b" size=0x" { buf[n] = c; n += 1; }
+        n += crate::util::format::u64_hex(b.size, &mut buf[n..]);
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
+    if !any { let _ = stdout.write_str("pci: no implemented BARs\r\n"); }
+    Ok(())
+}
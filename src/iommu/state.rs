@@ -1,138 +1,197 @@
 #![allow(dead_code)]
 
-//! Minimal IOMMU domain state management for early bootstrap.
-//! This is a conservative, fixed-capacity registry to track domains and
-//! device assignments before full hardware programming is implemented.
+//! IOMMU domain state: domains, device assignments and DMA mappings.
+//!
+//! The tables are heap-backed and grow on demand up to the `MAX_*` limits,
+//! which bound memory use and the size of a persisted blob (`iommu::blob`).
+//! One lock covers all three, so destroying a domain and purging its
+//! assignments and mappings is a single step.
+//!
+//! `find_domain_for_bdf` is on the fault and remapping paths and does not
+//! take the lock: every change to the assignments publishes a sorted
+//! BDF -> domain index through `util::rcu`. If the index cannot be built
+//! (out of memory) it is withdrawn and lookups scan the table under the lock.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::util::rcu::Rcu;
+use crate::util::spinlock::SpinLock;
 
 #[derive(Clone, Copy, Debug, Default)]
-pub struct Domain { pub id: u16, pub used: bool }
+pub struct Domain { pub id: u16 }
 
 #[derive(Clone, Copy, Debug, Default)]
-pub struct DevAssign { pub used: bool, pub seg: u16, pub bus: u8, pub dev: u8, pub func: u8, pub domid: u16 }
+pub struct DevAssign { pub seg: u16, pub bus: u8, pub dev: u8, pub func: u8, pub domid: u16 }
 
-pub const MAX_DOMAINS: usize = 16;
-pub const MAX_ASSIGNMENTS: usize = 128;
-pub const MAX_MAPPINGS: usize = 256;
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Mapping { pub domid: u16, pub iova: u64, pub pa: u64, pub len: u64, pub perm_r: bool, pub perm_w: bool, pub perm_x: bool }
 
-use crate::util::spinlock::SpinLock;
+pub const MAX_DOMAINS: usize = 256;
+pub const MAX_ASSIGNMENTS: usize = 1024;
+pub const MAX_MAPPINGS: usize = 4096;
 
-static DOMAINS: SpinLock<[Domain; MAX_DOMAINS]> = SpinLock::new([Domain { id: 0, used: false }; MAX_DOMAINS]);
-static ASSIGNS: SpinLock<[DevAssign; MAX_ASSIGNMENTS]> = SpinLock::new([DevAssign { used: false, seg: 0, bus: 0, dev: 0, func: 0, domid: 0 }; MAX_ASSIGNMENTS]);
-static NEXT_DOMAIN_ID_LOCK: SpinLock<u16> = SpinLock::new(1);
+struct Tables { domains: Vec<Domain>, assigns: Vec<DevAssign>, mappings: Vec<Mapping>, next_id: u16 }
 
-#[derive(Clone, Copy, Debug, Default)]
-pub struct Mapping { pub used: bool, pub domid: u16, pub iova: u64, pub pa: u64, pub len: u64, pub perm_r: bool, pub perm_w: bool, pub perm_x: bool }
+static TABLES: SpinLock<Tables> = SpinLock::new(Tables { domains: Vec::new(), assigns: Vec::new(), mappings: Vec::new(), next_id: 1 });
 
-static MAPPINGS: SpinLock<[Mapping; MAX_MAPPINGS]> = SpinLock::new([Mapping { used: false, domid: 0, iova: 0, pa: 0, len: 0, perm_r: false, perm_w: false, perm_x: false }; MAX_MAPPINGS]);
+/// Assignments sorted by `bdf_key`; keys are unique.
+struct BdfIndex(Vec<(u64, u16)>);
+
+static BDF_INDEX: Rcu<BdfIndex> = Rcu::new();
+
+fn bdf_key(seg: u16, bus: u8, dev: u8, func: u8) -> u64 { (seg as u64) << 24 | (bus as u64) << 16 | (dev as u64) << 8 | func as u64 }
+
+impl DevAssign {
+    fn is(&self, seg: u16, bus: u8, dev: u8, func: u8) -> bool { self.seg == seg && self.bus == bus && self.dev == dev && self.func == func }
+}
+
+/// Publish a fresh index of `t.assigns`. Callers hold `TABLES`, which keeps
+/// publishes serialized.
+fn reindex(t: &Tables) {
+    let mut v = Vec::new();
+    if v.try_reserve_exact(t.assigns.len()).is_err() { BDF_INDEX.publish(None); return; }
+    v.extend(t.assigns.iter().map(|a| (bdf_key(a.seg, a.bus, a.dev, a.func), a.domid)));
+    v.sort_unstable_by_key(|e| e.0);
+    BDF_INDEX.publish(Some(Box::new(BdfIndex(v))));
+}
+
+/// Lowest free id at or after `next_id`, skipping 0.
+fn alloc_id(t: &mut Tables) -> u16 {
+    loop {
+        let id = t.next_id;
+        t.next_id = t.next_id.wrapping_add(1);
+        if id != 0 && !t.domains.iter().any(|d| d.id == id) { return id; }
+    }
+}
 
 pub fn create_domain() -> Option<u16> {
-    let id = NEXT_DOMAIN_ID_LOCK.lock(|n| { let id = *n; *n = n.wrapping_add(1); id });
-    let created = DOMAINS.lock(|arr| {
-        for i in 0..MAX_DOMAINS { if !arr[i].used { arr[i] = Domain { id, used: true }; return true; } }
-        false
-    });
-    if created {
-        crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_DOMAIN_CREATED).inc();
-        crate::diag::audit::record(crate::diag::audit::AuditKind::IommuDomainCreate(id));
+    let id = TABLES.lock(|t| {
+        if t.domains.len() >= MAX_DOMAINS || t.domains.try_reserve(1).is_err() { return None; }
+        let id = alloc_id(t);
+        t.domains.push(Domain { id });
         Some(id)
-    } else { None }
+    })?;
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_DOMAIN_CREATED).inc();
+    crate::diag::audit::record(crate::diag::audit::AuditKind::IommuDomainCreate(id));
+    Some(id)
 }
 
 pub fn domain_exists(id: u16) -> bool {
-    DOMAINS.lock(|arr| arr.iter().any(|d| d.used && d.id == id))
+    TABLES.lock(|t| t.domains.iter().any(|d| d.id == id))
 }
 
-pub fn list_domains(mut f: impl FnMut(u16)) {
-    DOMAINS.lock(|arr| { for d in arr.iter() { if d.used { f(d.id); } } })
+pub fn domain_count() -> usize { TABLES.lock(|t| t.domains.len()) }
+
+/// Entries are copied out one at a time and `f` runs unlocked, so it may
+/// call back into this module. A walk that races a change can skip or repeat
+/// an entry.
+fn walk<T: Copy>(table: fn(&Tables) -> &Vec<T>, mut f: impl FnMut(T)) {
+    let mut i = 0;
+    while let Some(e) = TABLES.lock(|t| table(t).get(i).copied()) { f(e); i += 1; }
 }
 
+pub fn list_domains(mut f: impl FnMut(u16)) { walk(|t| &t.domains, |d| f(d.id)) }
+
+/// Assign a device to an existing domain. A device is in at most one
+/// domain; assigning one that already is fails.
 pub fn assign_device(seg: u16, bus: u8, dev: u8, func: u8, domid: u16) -> bool {
-    if !domain_exists(domid) { return false; }
-    let added = ASSIGNS.lock(|arr| {
-        for i in 0..MAX_ASSIGNMENTS { if !arr[i].used { arr[i] = DevAssign { used: true, seg, bus, dev, func, domid }; return true; } }
-        false
+    let added = TABLES.lock(|t| {
+        if !t.domains.iter().any(|d| d.id == domid) { return false; }
+        if t.assigns.iter().any(|a| a.is(seg, bus, dev, func)) { return false; }
+        if t.assigns.len() >= MAX_ASSIGNMENTS || t.assigns.try_reserve(1).is_err() { return false; }
+        t.assigns.push(DevAssign { seg, bus, dev, func, domid });
+        reindex(t);
+        true
     });
     if added {
         crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_ASSIGN_ADDED).inc();
         crate::diag::audit::record(crate::diag::audit::AuditKind::IommuAssignAdded { seg, bus, dev, func, dom: domid });
-        true
-    } else { false }
+        crate::cluster::store::note_iommu(seg, bus, dev, func, Some(domid));
+    }
+    added
 }
 
-pub fn list_assignments(mut f: impl FnMut(u16, u8, u8, u8, u16)) { ASSIGNS.lock(|arr| { for a in arr.iter() { if a.used { f(a.seg, a.bus, a.dev, a.func, a.domid); } } }) }
+pub fn list_assignments(mut f: impl FnMut(u16, u8, u8, u8, u16)) { walk(|t| &t.assigns, |a| f(a.seg, a.bus, a.dev, a.func, a.domid)) }
 
-pub fn has_assignments() -> bool { ASSIGNS.lock(|arr| arr.iter().any(|a| a.used)) }
+pub fn has_assignments() -> bool { TABLES.lock(|t| !t.assigns.is_empty()) }
 
+pub fn assignment_count() -> usize { TABLES.lock(|t| t.assigns.len()) }
+
+/// Remove a domain together with its assignments and mappings.
 pub fn destroy_domain(id: u16) -> bool {
-    // Clear domain slot, purge mappings and assignments of this domain
-    let mut found = false;
-    DOMAINS.lock(|arr| {
-        for d in arr.iter_mut() { if d.used && d.id == id { d.used = false; found = true; break; } }
-    });
-    if !found { return false; }
-    ASSIGNS.lock(|arr| { for a in arr.iter_mut() { if a.used && a.domid == id { a.used = false; } } });
-    MAPPINGS.lock(|arr| { for m in arr.iter_mut() { if m.used && m.domid == id { m.used = false; } } });
-    true
+    if crate::cluster::store::running() {
+        walk(|t| &t.assigns, |a| if a.domid == id { crate::cluster::store::note_iommu(a.seg, a.bus, a.dev, a.func, None) });
+    }
+    TABLES.lock(|t| {
+        let Some(i) = t.domains.iter().position(|d| d.id == id) else { return false };
+        t.domains.swap_remove(i);
+        let before = t.assigns.len();
+        t.assigns.retain(|a| a.domid != id);
+        t.mappings.retain(|m| m.domid != id);
+        if t.assigns.len() != before { reindex(t); }
+        true
+    })
 }
 
 pub fn unassign_device(seg: u16, bus: u8, dev: u8, func: u8) -> bool {
-    let removed = ASSIGNS.lock(|arr| {
-        for a in arr.iter_mut() { if a.used && a.seg == seg && a.bus == bus && a.dev == dev && a.func == func { a.used = false; return true; } }
-        false
+    let removed = TABLES.lock(|t| {
+        let i = t.assigns.iter().position(|a| a.is(seg, bus, dev, func))?;
+        let domid = t.assigns.swap_remove(i).domid;
+        reindex(t);
+        Some(domid)
     });
-    if removed {
-        // Find the domain id that was previously set for this BDF to include in audit line
-        let domid = ASSIGNS.lock(|arr| {
-            for a in arr.iter() {
-                if !a.used && a.seg == seg && a.bus == bus && a.dev == dev && a.func == func { return a.domid; }
-            }
-            0
-        });
-        crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_ASSIGN_REMOVED).inc();
-        crate::diag::audit::record(crate::diag::audit::AuditKind::IommuAssignRemoved { seg, bus, dev, func, dom: domid });
-        true
-    } else { false }
+    let Some(domid) = removed else { return false };
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_ASSIGN_REMOVED).inc();
+    crate::diag::audit::record(crate::diag::audit::AuditKind::IommuAssignRemoved { seg, bus, dev, func, dom: domid });
+    crate::cluster::store::note_iommu(seg, bus, dev, func, None);
+    true
 }
 
 pub fn add_mapping(domid: u16, iova: u64, pa: u64, len: u64, r: bool, w: bool, x: bool) -> bool {
-    if !domain_exists(domid) || len == 0 { return false; }
-    let ok = MAPPINGS.lock(|arr| {
-        for m in arr.iter_mut() { if !m.used { *m = Mapping { used: true, domid, iova, pa, len, perm_r: r, perm_w: w, perm_x: x }; return true; } }
-        false
+    if len == 0 { return false; }
+    let ok = TABLES.lock(|t| {
+        if !t.domains.iter().any(|d| d.id == domid) { return false; }
+        if t.mappings.len() >= MAX_MAPPINGS || t.mappings.try_reserve(1).is_err() { return false; }
+        t.mappings.push(Mapping { domid, iova, pa, len, perm_r: r, perm_w: w, perm_x: x });
+        true
     });
-    if ok { crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_MAP_ADDED).inc(); true } else { false }
+    if ok { crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_MAP_ADDED).inc(); }
+    ok
 }
 
 pub fn remove_mapping(domid: u16, iova: u64, len: u64) -> bool {
-    let removed = MAPPINGS.lock(|arr| {
-        for m in arr.iter_mut() { if m.used && m.domid == domid && m.iova == iova && m.len == len { m.used = false; return true; } }
-        false
+    let removed = TABLES.lock(|t| {
+        let Some(i) = t.mappings.iter().position(|m| m.domid == domid && m.iova == iova && m.len == len) else { return false };
+        t.mappings.remove(i);
+        true
     });
-    if removed { crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_MAP_REMOVED).inc(); true } else { false }
+    if removed { crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_MAP_REMOVED).inc(); }
+    removed
 }
 
 pub fn list_mappings(mut f: impl FnMut(u16, u64, u64, u64, bool, bool, bool)) {
-    MAPPINGS.lock(|arr| { for m in arr.iter() { if m.used { f(m.domid, m.iova, m.pa, m.len, m.perm_r, m.perm_w, m.perm_x); } } })
+    walk(|t| &t.mappings, |m| f(m.domid, m.iova, m.pa, m.len, m.perm_r, m.perm_w, m.perm_x))
 }
 
+pub fn mapping_count() -> usize { TABLES.lock(|t| t.mappings.len()) }
+
 pub fn remove_mappings_for_domain(domid: u16) -> u32 {
-    let mut removed: u32 = 0;
-    MAPPINGS.lock(|arr| {
-        for m in arr.iter_mut() {
-            if m.used && m.domid == domid {
-                m.used = false;
-                removed = removed.saturating_add(1);
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_MAP_REMOVED).inc();
-            }
-        }
+    let removed = TABLES.lock(|t| {
+        let before = t.mappings.len();
+        t.mappings.retain(|m| m.domid != domid);
+        (before - t.mappings.len()) as u32
     });
+    for _ in 0..removed { crate::obs::metrics::Counter::new(&crate::obs::metrics::IOMMU_MAP_REMOVED).inc(); }
     removed
 }
 
+/// Domain of an assigned device. Lock-free while the index is published.
 pub fn find_domain_for_bdf(seg: u16, bus: u8, dev: u8, func: u8) -> Option<u16> {
-    let mut out: Option<u16> = None;
-    ASSIGNS.lock(|arr| { for a in arr.iter() { if a.used && a.seg == seg && a.bus == bus && a.dev == dev && a.func == func { out = Some(a.domid); break; } } });
-    out
+    let key = bdf_key(seg, bus, dev, func);
+    let hit = BDF_INDEX.read(|ix| ix.map(|ix| ix.0.binary_search_by_key(&key, |e| e.0).ok().map(|i| ix.0[i].1)));
+    match hit {
+        Some(found) => found,
+        None => TABLES.lock(|t| t.assigns.iter().find(|a| a.is(seg, bus, dev, func)).map(|a| a.domid)),
+    }
 }
-
-
@@ -24,6 +24,9 @@ const GCMD_TE: u32 = 1 << 31;   // Translation Enable
 // GSTS bits (subset)
 const GSTS_RTPS: u32 = 1 << 30; // Root Table Pointer Status
 const GSTS_TES: u32 = 1 << 31;  // Translation Enable Status
+// GCMD is write-only: commands are issued as GSTS with one-shot status bits
+// (SRTP/SFL/WBF/SIRTP) masked off, so enabling one feature keeps the others.
+pub(crate) const GSTS_PERSISTENT: u32 = 0x96FF_FFFF;
 
 #[repr(C, packed)]
 struct VtdRootEntry {
@@ -62,6 +65,17 @@ fn for_each_unit(mut f: impl FnMut(VtdUnit)) {
     VTD_UNITS.lock(|arr| { for slot in arr.iter() { if let Some(u) = slot.as_ref() { f(*u); } } });
 }
 
+/// (segment, register base) of every initialized unit.
+pub(crate) fn for_each_unit_reg(mut f: impl FnMut(u16, u64)) {
+    let snap = VTD_UNITS.lock(|arr| *arr);
+    for u in snap.iter().flatten() { f(u.seg, u.reg_base); }
+}
+
+/// Register base of the unit that owns `bdf`.
+pub(crate) fn unit_reg_for_bdf(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8) -> Option<u64> {
+    find_unit_for_bdf(system_table, seg, bus, dev, func).map(|u| u.reg_base)
+}
+
 fn get_unit_by_index(index: usize) -> Option<VtdUnit> {
     let mut out: Option<VtdUnit> = None;
     VTD_UNITS.lock(|arr| {
@@ -76,7 +90,7 @@ fn get_unit_by_index(index: usize) -> Option<VtdUnit> {
     out
 }
 
-fn ensure_domain_slptptr(system_table: &SystemTable<Boot>, domid: u16) -> Option<u64> {
+pub(crate) fn ensure_domain_slptptr(system_table: &SystemTable<Boot>, domid: u16) -> Option<u64> {
     // We only provision up to 16 domains in this early bootstrap path.
     let idx = (domid as usize) & 0xF;
     let mut ret: Option<u64> = None;
@@ -92,7 +106,7 @@ fn ensure_domain_slptptr(system_table: &SystemTable<Boot>, domid: u16) -> Option
     ret
 }
 
-fn get_domain_slptptr(domid: u16) -> Option<u64> {
+pub(crate) fn get_domain_slptptr(domid: u16) -> Option<u64> {
     let mut out = None;
     DOMAIN_SLPTPTR.lock(|arr| { out = arr[(domid as usize) & 0xF]; });
     out
@@ -204,7 +218,7 @@ pub fn apply_mappings(system_table: &mut SystemTable<Boot>) {
             }
         }
     });
-    let _ = system_table.stdout().write_str("iommu: second-level mappings applied\r\n");
+    crate::log!(Info, "iommu", "second-level mappings applied");
     // Emit trace for mapping activity per domain (summary only)
     crate::obs::trace::emit(crate::obs::trace::Event::IommuMapAdded(0));
     // If translation is enabled, refresh caches conservatively
@@ -214,7 +228,7 @@ pub fn apply_mappings(system_table: &mut SystemTable<Boot>) {
 pub fn unmap_range(system_table: &mut SystemTable<Boot>, dom: u16, iova: u64, len: u64) {
     if let Some(cr3) = get_domain_slptptr(dom) {
         unmap_range_4k(system_table, cr3, iova, len);
-        let _ = system_table.stdout().write_str("iommu: unmapped from second-level tables\r\n");
+        crate::log!(Info, "iommu", "dom={} unmapped {:#x}+{:#x} from second-level tables", dom, iova, len);
     }
     maybe_refresh_after_updates(system_table);
     crate::obs::trace::emit(crate::obs::trace::Event::IommuMapRemoved(dom));
@@ -283,9 +297,9 @@ pub fn translate_bdf_iova(system_table: &mut SystemTable<Boot>, seg: u16, bus: u
         let (pa, _) = walk_second_level(cr3, iova);
         let mut buf = [0u8; 96]; let mut n = 0;
         for &b in b"xlate: iova=0x" { buf[n] = b; n += 1; }
-        n += u64_to_hex(iova, &mut buf[n..]);
+        n += crate::util::format::u64_hex(iova, &mut buf[n..]);
         for &b in b" -> pa=" { buf[n] = b; n += 1; }
-        if let Some(pa) = pa { n += u64_to_hex(pa, &mut buf[n..]); } else { for &b in b"<none>" { buf[n] = b; n += 1; } }
+        if let Some(pa) = pa { n += crate::util::format::u64_hex(pa, &mut buf[n..]); } else { for &b in b"<none>" { buf[n] = b; n += 1; } }
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     } else {
@@ -295,9 +309,9 @@ pub fn translate_bdf_iova(system_table: &mut SystemTable<Boot>, seg: u16, bus: u
                 let (pa, _) = walk_second_level(cr3, iova);
                 let mut buf = [0u8; 96]; let mut n = 0;
                 for &b in b"xlate(dom): iova=0x" { buf[n] = b; n += 1; }
-                n += u64_to_hex(iova, &mut buf[n..]);
+                n += crate::util::format::u64_hex(iova, &mut buf[n..]);
                 for &b in b" -> pa=" { buf[n] = b; n += 1; }
-                if let Some(pa) = pa { n += u64_to_hex(pa, &mut buf[n..]); } else { for &b in b"<none>" { buf[n] = b; n += 1; } }
+                if let Some(pa) = pa { n += crate::util::format::u64_hex(pa, &mut buf[n..]); } else { for &b in b"<none>" { buf[n] = b; n += 1; } }
                 buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
                 let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                 return;
@@ -312,15 +326,15 @@ pub fn walk_bdf_iova(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, de
         let (pa, ents) = walk_second_level(cr3, iova);
         let mut buf = [0u8; 192]; let mut n = 0;
         for &b in b"walk: pml4e=" { buf[n] = b; n += 1; }
-        n += u64_to_hex(ents[0], &mut buf[n..]);
+        n += crate::util::format::u64_hex(ents[0], &mut buf[n..]);
         for &b in b" pdpte=" { buf[n] = b; n += 1; }
-        n += u64_to_hex(ents[1], &mut buf[n..]);
+        n += crate::util::format::u64_hex(ents[1], &mut buf[n..]);
         for &b in b" pde=" { buf[n] = b; n += 1; }
-        n += u64_to_hex(ents[2], &mut buf[n..]);
+        n += crate::util::format::u64_hex(ents[2], &mut buf[n..]);
         for &b in b" pte=" { buf[n] = b; n += 1; }
-        n += u64_to_hex(ents[3], &mut buf[n..]);
+        n += crate::util::format::u64_hex(ents[3], &mut buf[n..]);
         for &b in b" -> pa=" { buf[n] = b; n += 1; }
-        if let Some(pa) = pa { n += u64_to_hex(pa, &mut buf[n..]); } else { for &b in b"<none>" { buf[n] = b; n += 1; } }
+        if let Some(pa) = pa { n += crate::util::format::u64_hex(pa, &mut buf[n..]); } else { for &b in b"<none>" { buf[n] = b; n += 1; } }
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     } else {
@@ -333,11 +347,11 @@ pub fn list_units(system_table: &mut SystemTable<Boot>) {
     for_each_unit(|u| {
         let mut buf = [0u8; 128]; let mut n = 0;
         for &b in b"VT-d: unit seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
         for &b in b" reg=0x" { buf[n] = b; n += 1; }
-        n += u64_to_hex(u.reg_base, &mut buf[n..]);
+        n += crate::util::format::u64_hex(u.reg_base, &mut buf[n..]);
         for &b in b" root=0x" { buf[n] = b; n += 1; }
-        n += u64_to_hex(u.root_tbl as u64, &mut buf[n..]);
+        n += crate::util::format::u64_hex(u.root_tbl as u64, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let stdout = system_table.stdout();
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
@@ -356,17 +370,17 @@ pub fn dump_context(system_table: &mut SystemTable<Boot>, bus: u8, dev: u8, func
         let present = (re_lo & 1) != 0;
         let mut buf = [0u8; 192]; let mut n = 0;
         for &b in b"VT-d: dump seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
         for &b in b" bus=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
         for &b in b" dev=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
         for &b in b" fn=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
         for &b in b" re.present=" { buf[n] = b; n += 1; }
         buf[n] = if present { b'1' } else { b'0' }; n += 1;
         for &b in b" ctx=0x" { buf[n] = b; n += 1; }
-        n += u64_to_hex(ctx_ptr, &mut buf[n..]);
+        n += crate::util::format::u64_hex(ctx_ptr, &mut buf[n..]);
         // If present, peek context entry lower/upper
         if present && ctx_ptr != 0 {
             let ct = ctx_ptr as *const VtdContextEntry;
@@ -374,20 +388,20 @@ pub fn dump_context(system_table: &mut SystemTable<Boot>, bus: u8, dev: u8, func
             let ce_lo = core::ptr::read_volatile(core::ptr::addr_of!((*ce).lower));
             let ce_up = core::ptr::read_volatile(core::ptr::addr_of!((*ce).upper));
             for &b in b" ce.lo=0x" { buf[n] = b; n += 1; }
-            n += u64_to_hex(ce_lo, &mut buf[n..]);
+            n += crate::util::format::u64_hex(ce_lo, &mut buf[n..]);
             for &b in b" ce.hi=0x" { buf[n] = b; n += 1; }
-            n += u64_to_hex(ce_up, &mut buf[n..]);
+            n += crate::util::format::u64_hex(ce_up, &mut buf[n..]);
             // Decode fields (raw)
             for &b in b" present=" { buf[n] = b; n += 1; }
             buf[n] = if (ce_lo & CTX_PRESENT) != 0 { b'1' } else { b'0' }; n += 1;
             for &b in b" fpd=" { buf[n] = b; n += 1; }
             buf[n] = if (ce_lo & CTX_FPD) != 0 { b'1' } else { b'0' }; n += 1;
             for &b in b" tt=" { buf[n] = b; n += 1; }
-            let tt = ((ce_lo >> CTX_TT_SHIFT) & 0x3) as u32; n += crate::firmware::acpi::u32_to_dec(tt, &mut buf[n..]);
+            let tt = ((ce_lo >> CTX_TT_SHIFT) & 0x3) as u32; n += crate::util::format::u32_dec(tt, &mut buf[n..]);
             for &b in b" aw=" { buf[n] = b; n += 1; }
-            let aw = ((ce_up >> CTXU_AW_SHIFT) & 0x7) as u32; n += crate::firmware::acpi::u32_to_dec(aw, &mut buf[n..]);
+            let aw = ((ce_up >> CTXU_AW_SHIFT) & 0x7) as u32; n += crate::util::format::u32_dec(aw, &mut buf[n..]);
             for &b in b" did=" { buf[n] = b; n += 1; }
-            let did = ((ce_up >> CTXU_DID_SHIFT) & 0xFFFF) as u32; n += crate::firmware::acpi::u32_to_dec(did, &mut buf[n..]);
+            let did = ((ce_up >> CTXU_DID_SHIFT) & 0xFFFF) as u32; n += crate::util::format::u32_dec(did, &mut buf[n..]);
         }
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let stdout = system_table.stdout();
@@ -407,15 +421,15 @@ pub fn dump_root(system_table: &mut SystemTable<Boot>, bus: u8) {
         let ctx_ptr = re_lo & 0xFFFF_FFFF_FFFF_F000u64;
         let mut buf = [0u8; 192]; let mut n = 0;
         for &b in b"VT-d: root seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
         for &b in b" bus=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
         for &b in b" present=" { buf[n] = b; n += 1; }
         buf[n] = if present { b'1' } else { b'0' }; n += 1;
         for &b in b" ctx=0x" { buf[n] = b; n += 1; }
-        n += u64_to_hex(ctx_ptr, &mut buf[n..]);
+        n += crate::util::format::u64_hex(ctx_ptr, &mut buf[n..]);
         for &b in b" re.up=0x" { buf[n] = b; n += 1; }
-        n += u64_to_hex(re_up, &mut buf[n..]);
+        n += crate::util::format::u64_hex(re_up, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let stdout = system_table.stdout();
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
@@ -442,13 +456,13 @@ pub fn list_bus_contexts(system_table: &mut SystemTable<Boot>, bus: u8) {
                     found = found.saturating_add(1);
                     let mut buf = [0u8; 96]; let mut n = 0;
                     for &b in b"VT-d: ctx seg=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
                     for &b in b" bus=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
                     for &b in b" dev=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
                     for &b in b" fn=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
+                    n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
                     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
                     let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                 }
@@ -456,9 +470,9 @@ pub fn list_bus_contexts(system_table: &mut SystemTable<Boot>, bus: u8) {
         }
         let mut buf = [0u8; 64]; let mut n = 0;
         for &b in b"VT-d: total present on bus=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
         for &b in b" cnt=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(found, &mut buf[n..]);
+        n += crate::util::format::u32_dec(found, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     });
@@ -536,8 +550,8 @@ pub fn apply_assignments(system_table: &mut SystemTable<Boot>) {
             core::ptr::write_volatile(core::ptr::addr_of_mut!((*ce).upper), hi);
         }
     });
-    let stdout = system_table.stdout();
-    let _ = stdout.write_str("apply: context entries updated (in-memory, SLPTPTR provisioned)\r\n");
+    super::vtd_sm::apply_assignments(system_table);
+    crate::log!(Info, "iommu", "context entries updated (in-memory, SLPTPTR provisioned)");
 }
 
 /// Convenience: apply assignments and perform a conservative refresh of VT-d caches.
@@ -587,11 +601,12 @@ pub fn sync_contexts(system_table: &mut SystemTable<Boot>) {
         }
     });
     invalidate_all(system_table);
-    let _ = system_table.stdout().write_str("iommu: contexts synchronized from assignments\r\n");
+    crate::log!(Info, "iommu", "contexts synchronized from assignments");
 }
 
 /// Stub for global invalidates (context/iotlb). Currently prints a message only.
 pub fn invalidate_all(system_table: &mut SystemTable<Boot>) {
+    let t0 = crate::time::clock::now_ns();
     // Re-issue SRTP with current RTADDR per unit to conservatively force hardware to
     // re-sample the root pointer and refresh associated caches.
     for_each_unit(|u| unsafe {
@@ -600,13 +615,13 @@ pub fn invalidate_all(system_table: &mut SystemTable<Boot>) {
         let gsts = (u.reg_base as usize + REG_GSTS) as *const u32;
         let cur_rt = core::ptr::read_volatile(rtaddr);
         core::ptr::write_volatile(rtaddr, cur_rt);
-        let cur = core::ptr::read_volatile(gcmd);
+        let cur = core::ptr::read_volatile(gsts) & GSTS_PERSISTENT;
         core::ptr::write_volatile(gcmd, cur | GCMD_SRTP);
         let mut ok = false; let mut tries = 0u32;
         while tries < 5000 { if (core::ptr::read_volatile(gsts) & GSTS_RTPS) != 0 { ok = true; break; } tries += 1; let _ = system_table.boot_services().stall(100); }
         let mut buf = [0u8; 96]; let mut n = 0;
         for &b in b"VT-d: invalidate seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
         for &b in b" result=" { buf[n] = b; n += 1; }
         let s: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
         for &b in s { buf[n] = b; n += 1; }
@@ -616,6 +631,7 @@ pub fn invalidate_all(system_table: &mut SystemTable<Boot>) {
     });
     // Emit metrics and generic trace for all-units invalidate (segment not tracked per loop here)
     crate::obs::metrics::IOMMU_INV_ALL.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    crate::obs::metrics::IOMMU_INVAL_NS.observe_since(t0);
     crate::obs::trace::emit(crate::obs::trace::Event::IommuInvalidateAll(0));
 }
 
@@ -627,26 +643,26 @@ pub fn hard_invalidate_all(system_table: &mut SystemTable<Boot>) {
         // If TE is set, clear it
         let mut s = core::ptr::read_volatile(gsts);
         if (s & GSTS_TES) != 0 {
-            let cur = core::ptr::read_volatile(gcmd);
+            let cur = core::ptr::read_volatile(gsts) & GSTS_PERSISTENT;
             core::ptr::write_volatile(gcmd, cur & !GCMD_TE);
             let mut ok = false; let mut tries = 0u32;
             while tries < 5000 { s = core::ptr::read_volatile(gsts); if (s & GSTS_TES) == 0 { ok = true; break; } tries += 1; let _ = system_table.boot_services().stall(100); }
             let mut buf = [0u8; 96]; let mut n = 0;
             for &b in b"VT-d: hard-inv off seg=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
             for &b in b" result=" { buf[n] = b; n += 1; }
             let t: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
             for &b in t { buf[n] = b; n += 1; }
             buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1; let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
         }
         // Set TE
-        let cur = core::ptr::read_volatile(gcmd);
+        let cur = core::ptr::read_volatile(gsts) & GSTS_PERSISTENT;
         core::ptr::write_volatile(gcmd, cur | GCMD_TE);
         let mut ok = false; let mut tries = 0u32;
         while tries < 5000 { let s2 = core::ptr::read_volatile(gsts); if (s2 & GSTS_TES) != 0 { ok = true; break; } tries += 1; let _ = system_table.boot_services().stall(100); }
         let mut buf = [0u8; 96]; let mut n = 0;
         for &b in b"VT-d: hard-inv on seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
         for &b in b" result=" { buf[n] = b; n += 1; }
         let t: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
         for &b in t { buf[n] = b; n += 1; }
@@ -655,21 +671,23 @@ pub fn hard_invalidate_all(system_table: &mut SystemTable<Boot>) {
 }
 
 fn srtp_one_unit(system_table: &mut SystemTable<Boot>, seg: u16, reg_base: u64) {
+    // Scalable-mode units ignore register-based refreshes; flush their queue instead.
+    let _ = super::vtd_sm::flush_unit(system_table, reg_base);
     unsafe {
         let rtaddr = (reg_base as usize + REG_RTADDR) as *mut u64;
         let gcmd = (reg_base as usize + REG_GCMD) as *mut u32;
         let gsts = (reg_base as usize + REG_GSTS) as *const u32;
         let cur_rt = core::ptr::read_volatile(rtaddr);
         core::ptr::write_volatile(rtaddr, cur_rt);
-        let cur = core::ptr::read_volatile(gcmd);
+        let cur = core::ptr::read_volatile(gsts) & GSTS_PERSISTENT;
         core::ptr::write_volatile(gcmd, cur | GCMD_SRTP);
         let mut ok = false; let mut tries = 0u32;
         while tries < 5000 { if (core::ptr::read_volatile(gsts) & GSTS_RTPS) != 0 { ok = true; break; } tries += 1; let _ = system_table.boot_services().stall(100); }
         let mut buf = [0u8; 96]; let mut n = 0;
         for &b in b"VT-d: SRTP seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
         for &b in b" " { buf[n] = b; n += 1; }
-        n += u64_to_hex(reg_base, &mut buf[n..]);
+        n += crate::util::format::u64_hex(reg_base, &mut buf[n..]);
         for &b in b" result=" { buf[n] = b; n += 1; }
         let s: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
         for &b in s { buf[n] = b; n += 1; }
@@ -678,6 +696,7 @@ fn srtp_one_unit(system_table: &mut SystemTable<Boot>, seg: u16, reg_base: u64)
 }
 
 pub fn invalidate_domain(system_table: &mut SystemTable<Boot>, domid: u16) {
+    let t0 = crate::time::clock::now_ns();
     // Targeted SRTP to units that host any BDF assigned to this domain
     let mut regs: [u64; 8] = [0; 8];
     let mut segs: [u16; 8] = [0; 8];
@@ -694,14 +713,17 @@ pub fn invalidate_domain(system_table: &mut SystemTable<Boot>, domid: u16) {
     });
     for i in 0..cnt { srtp_one_unit(system_table, segs[i], regs[i]); }
     crate::obs::metrics::IOMMU_INV_DOMAIN.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    crate::obs::metrics::IOMMU_INVAL_NS.observe_since(t0);
     crate::obs::trace::emit(crate::obs::trace::Event::IommuInvalidateDomain(domid));
 }
 
 pub fn invalidate_bdf(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8) {
+    let t0 = crate::time::clock::now_ns();
     if let Some(u) = find_unit_for_bdf(system_table, seg, bus, dev, func) {
         srtp_one_unit(system_table, u.seg, u.reg_base);
     }
     crate::obs::metrics::IOMMU_INV_BDF.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    crate::obs::metrics::IOMMU_INVAL_NS.observe_since(t0);
     crate::obs::trace::emit(crate::obs::trace::Event::IommuInvalidateBdf(seg, bus, dev, func));
 }
 
@@ -711,20 +733,6 @@ fn alloc_zeroed_pages(system_table: &uefi::table::SystemTable<Boot>, pages: usiz
     Some(p)
 }
 
-fn u64_to_hex(v: u64, out: &mut [u8]) -> usize {
-    const HEX: &[u8; 16] = b"0123456789ABCDEF";
-    let mut started = false;
-    let mut n = 0usize;
-    for i in (0..16).rev() {
-        let nyb = ((v >> (i * 4)) & 0xF) as usize;
-        if nyb != 0 || started || i == 0 {
-            started = true;
-            if n < out.len() { out[n] = HEX[nyb]; n += 1; }
-        }
-    }
-    n
-}
-
 /// Perform a minimal, non-intrusive VT-d setup on all DRHD units found via ACPI DMAR:
 /// - Allocate an empty Root Table (4KiB, 256 entries) and program RTADDR
 /// - Issue SRTP and wait for RTPS per unit
@@ -744,9 +752,9 @@ pub fn minimal_init(system_table: &mut SystemTable<Boot>) {
                 let mut buf = [0u8; 128];
                 let mut n = 0;
                 for &b in b"VT-d: DRHD seg=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+                n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
                 for &b in b" reg=0x" { buf[n] = b; n += 1; }
-                n += u64_to_hex(reg_base, &mut buf[n..]);
+                n += crate::util::format::u64_hex(reg_base, &mut buf[n..]);
                 for &b in b" skip: TE=1\r\n" { buf[n] = b; n += 1; }
                 let stdout = system_table.stdout();
                 let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
@@ -768,7 +776,7 @@ pub fn minimal_init(system_table: &mut SystemTable<Boot>) {
             // Set SRTP in GCMD
             let gcmd = (reg_base as usize + REG_GCMD) as *mut u32;
             let gsts = (reg_base as usize + REG_GSTS) as *const u32;
-            let cur = core::ptr::read_volatile(gcmd);
+            let cur = core::ptr::read_volatile(gsts) & GSTS_PERSISTENT;
             core::ptr::write_volatile(gcmd, cur | GCMD_SRTP);
             // Poll RTPS
             let mut ok = false;
@@ -785,9 +793,9 @@ pub fn minimal_init(system_table: &mut SystemTable<Boot>) {
             let mut buf = [0u8; 128];
             let mut n = 0;
             for &b in b"VT-d: DRHD seg=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
             for &b in b" reg=0x" { buf[n] = b; n += 1; }
-            n += u64_to_hex(reg_base, &mut buf[n..]);
+            n += crate::util::format::u64_hex(reg_base, &mut buf[n..]);
             for &b in b" SRTP=" { buf[n] = b; n += 1; }
             let s: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
             for &b in s { buf[n] = b; n += 1; }
@@ -803,6 +811,7 @@ pub fn probe_and_report(system_table: &mut SystemTable<Boot>) {
     let lang = crate::i18n::detect_lang(system_table);
     // Resolve header before borrowing stdout to avoid aliasing borrows
     let dmar = crate::firmware::acpi::find_dmar(system_table);
+    crate::hv::features::register_flag("iommu", "vt-d", dmar.is_some(), "no DMAR table");
     let stdout = system_table.stdout();
     if let Some(hdr) = dmar {
         crate::firmware::acpi::dmar_summary(|s| { let _ = stdout.write_str(s); }, hdr);
@@ -829,17 +838,17 @@ pub fn report_details(system_table: &mut SystemTable<Boot>) {
             let mut buf = [0u8; 192];
             let mut n = 0;
             for &b in b"VT-d: DRHD seg=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
             for &b in b" ver=0x" { buf[n] = b; n += 1; }
-            n += u64_to_hex(ver, &mut buf[n..]);
+            n += crate::util::format::u64_hex(ver, &mut buf[n..]);
             for &b in b" cap=0x" { buf[n] = b; n += 1; }
-            n += u64_to_hex(cap, &mut buf[n..]);
+            n += crate::util::format::u64_hex(cap, &mut buf[n..]);
             for &b in b" ecap=0x" { buf[n] = b; n += 1; }
-            n += u64_to_hex(ecap, &mut buf[n..]);
+            n += crate::util::format::u64_hex(ecap, &mut buf[n..]);
             for &b in b" gsts=0x" { buf[n] = b; n += 1; }
-            n += u64_to_hex(gsts, &mut buf[n..]);
+            n += crate::util::format::u64_hex(gsts, &mut buf[n..]);
             for &b in b" rtaddr=0x" { buf[n] = b; n += 1; }
-            n += u64_to_hex(rtaddr, &mut buf[n..]);
+            n += crate::util::format::u64_hex(rtaddr, &mut buf[n..]);
             buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
             let stdout = system_table.stdout();
             let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
@@ -853,14 +862,63 @@ pub fn report_faults(system_table: &mut SystemTable<Boot>) {
         let fsts = core::ptr::read_volatile((u.reg_base as usize + REG_FSTS) as *const u32) as u64;
         let mut buf = [0u8; 96]; let mut n = 0;
         for &b in b"VT-d: FSTS seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
         for &b in b" fsts=0x" { buf[n] = b; n += 1; }
-        n += u64_to_hex(fsts, &mut buf[n..]);
+        n += crate::util::format::u64_hex(fsts, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     });
 }
 
+/// Drain primary fault recording registers on every unit, handing each record
+/// to `f(seg, source_id, reason, is_write, page_addr)`; returns records consumed.
+pub(crate) fn drain_faults(mut f: impl FnMut(u16, u16, u8, bool, u64)) -> usize {
+    let mut count = 0usize;
+    for_each_unit(|u| unsafe {
+        let base = u.reg_base as usize;
+        let fsts = core::ptr::read_volatile((base + REG_FSTS) as *const u32);
+        if fsts & 0x3 == 0 { return; }
+        let cap = core::ptr::read_volatile((base + REG_CAP) as *const u64);
+        let nfr = (((cap >> 40) & 0xFF) + 1) as usize;
+        let fro = (((cap >> 24) & 0x3FF) * 16) as usize;
+        let mut idx = ((fsts >> 8) & 0xFF) as usize % nfr;
+        for _ in 0..nfr {
+            let rec = base + fro + idx * 16;
+            let hi = core::ptr::read_volatile((rec + 8) as *const u64);
+            if hi >> 63 == 0 { break; }
+            let lo = core::ptr::read_volatile(rec as *const u64);
+            // T bit: 0 = write request, 1 = read request
+            f(u.seg, hi as u16, (hi >> 32) as u8, (hi >> 62) & 1 == 0, lo & !0xFFF);
+            // F is RW1C in the top dword of the record
+            core::ptr::write_volatile((rec + 12) as *mut u32, 0x8000_0000);
+            count += 1;
+            idx = (idx + 1) % nfr;
+        }
+        // Primary fault overflow is RW1C as well
+        if fsts & 0x1 != 0 { core::ptr::write_volatile((base + REG_FSTS) as *mut u32, 0x1); }
+    });
+    count
+}
+
+/// Make the context entry of `seg:bus:dev.func` not-present so its DMA is
+/// blocked once translation is on, then refresh the unit.
+pub(crate) fn block_bdf(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8) -> bool {
+    let u = match find_unit_for_bdf(system_table, seg, bus, dev, func) { Some(u) => u, None => return false };
+    unsafe {
+        let (ri, ci) = vtd_indices_from_bdf(bus, dev, func);
+        let re = (u.root_tbl as *const VtdRootEntry).add(ri);
+        let re_lo = core::ptr::read_volatile(core::ptr::addr_of!((*re).lower));
+        if (re_lo & CTX_PRESENT) != 0 {
+            let ce = ((re_lo & 0xFFFF_FFFF_FFFF_F000u64) as *mut VtdContextEntry).add(ci);
+            core::ptr::write_volatile(core::ptr::addr_of_mut!((*ce).lower), 0);
+            core::ptr::write_volatile(core::ptr::addr_of_mut!((*ce).upper), 0);
+        }
+    }
+    super::vtd_sm::block_device(system_table, seg, bus, dev, func);
+    invalidate_bdf(system_table, seg, bus, dev, func);
+    true
+}
+
 /// Clear Fault Status by write-1-to-clear semantics (write back read value).
 pub fn clear_faults(system_table: &mut SystemTable<Boot>) {
     for_each_unit(|u| unsafe {
@@ -869,7 +927,7 @@ pub fn clear_faults(system_table: &mut SystemTable<Boot>) {
         core::ptr::write_volatile(reg, val);
         let mut buf = [0u8; 64]; let mut n = 0;
         for &b in b"VT-d: FSTS cleared seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     });
@@ -880,7 +938,7 @@ pub fn set_te_for_unit(system_table: &mut SystemTable<Boot>, index: usize, enabl
         unsafe {
             let gcmd = (u.reg_base as usize + REG_GCMD) as *mut u32;
             let gsts = (u.reg_base as usize + REG_GSTS) as *const u32;
-            let cur = core::ptr::read_volatile(gcmd);
+            let cur = core::ptr::read_volatile(gsts) & GSTS_PERSISTENT;
             if enable { core::ptr::write_volatile(gcmd, cur | GCMD_TE); } else { core::ptr::write_volatile(gcmd, cur & !GCMD_TE); }
             let _want = if enable { GSTS_TES } else { 0 };
             let mut ok = false; let mut tries = 0u32;
@@ -892,7 +950,7 @@ pub fn set_te_for_unit(system_table: &mut SystemTable<Boot>, index: usize, enabl
             let mut buf = [0u8; 96]; let mut n = 0;
             let prefix: &[u8] = if enable { b"VT-d: TE on idx=" } else { b"VT-d: TE off idx=" };
             for &b in prefix { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(index as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(index as u32, &mut buf[n..]);
             for &b in b" result=" { buf[n] = b; n += 1; }
             let t: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
             for &b in t { buf[n] = b; n += 1; }
@@ -915,14 +973,14 @@ pub fn enable_translation_all(system_table: &mut SystemTable<Boot>) {
             if (core::ptr::read_volatile(gsts) & GSTS_TES) != 0 {
                 let mut buf = [0u8; 96]; let mut n = 0;
                 for &b in b"VT-d: DRHD seg=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+                n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
                 for &b in b" TE=1 (skip)\r\n" { buf[n] = b; n += 1; }
                 let stdout = system_table.stdout();
                 let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                 return;
             }
             // Set TE
-            let cur = core::ptr::read_volatile(gcmd);
+            let cur = core::ptr::read_volatile(gsts) & GSTS_PERSISTENT;
             core::ptr::write_volatile(gcmd, cur | GCMD_TE);
             // Poll TES
             let mut ok = false; let mut tries = 0u32;
@@ -932,7 +990,7 @@ pub fn enable_translation_all(system_table: &mut SystemTable<Boot>) {
             }
             let mut buf = [0u8; 96]; let mut n = 0;
             for &b in b"VT-d: enable seg=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
             for &b in b" result=" { buf[n] = b; n += 1; }
             let s: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
             for &b in s { buf[n] = b; n += 1; }
@@ -956,14 +1014,14 @@ pub fn disable_translation_all(system_table: &mut SystemTable<Boot>) {
             if (core::ptr::read_volatile(gsts) & GSTS_TES) == 0 {
                 let mut buf = [0u8; 96]; let mut n = 0;
                 for &b in b"VT-d: DRHD seg=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+                n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
                 for &b in b" TE=0 (skip)\r\n" { buf[n] = b; n += 1; }
                 let stdout = system_table.stdout();
                 let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                 return;
             }
             // Clear TE
-            let cur = core::ptr::read_volatile(gcmd);
+            let cur = core::ptr::read_volatile(gsts) & GSTS_PERSISTENT;
             core::ptr::write_volatile(gcmd, cur & !GCMD_TE);
             // Poll TES clear
             let mut ok = false; let mut tries = 0u32;
@@ -973,7 +1031,7 @@ pub fn disable_translation_all(system_table: &mut SystemTable<Boot>) {
             }
             let mut buf = [0u8; 96]; let mut n = 0;
             for &b in b"VT-d: disable seg=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
             for &b in b" result=" { buf[n] = b; n += 1; }
             let s: &[u8] = if ok { b"OK" } else { b"TIMEOUT" };
             for &b in s { buf[n] = b; n += 1; }
@@ -995,13 +1053,13 @@ pub fn dump_device_scopes(system_table: &mut SystemTable<Boot>) {
         // seg:bus:dev.func (hex)
         for &b in b"0000:" { buf[n] = b; n += 1; } // prefix; we will overwrite digits below
         // print seg as decimal to reuse helper, acceptable for now
-        n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
         for &b in b":" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
         for &b in b":" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
         for &b in b"." { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let stdout = system_table.stdout();
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
@@ -1024,19 +1082,19 @@ pub fn plan_assignments(system_table: &mut SystemTable<Boot>) {
         let (ri, ci) = vtd_indices_from_bdf(bus, dev, func);
         let mut buf = [0u8; 128]; let mut n = 0;
         for &b in b"  seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
         for &b in b" bus=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
         for &b in b" dev=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
         for &b in b" fn=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
         for &b in b" => root[" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(ri as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(ri as u32, &mut buf[n..]);
         for &b in b"], ctx[" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(ci as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(ci as u32, &mut buf[n..]);
         for &b in b"], dom=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(domid as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(domid as u32, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     });
@@ -1051,19 +1109,19 @@ pub fn plan_assignments_for_domain(system_table: &mut SystemTable<Boot>, domid_f
         let (ri, ci) = vtd_indices_from_bdf(bus, dev, func);
         let mut buf = [0u8; 128]; let mut n = 0;
         for &b in b"  seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
         for &b in b" bus=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
         for &b in b" dev=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
         for &b in b" fn=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
         for &b in b" => root[" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(ri as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(ri as u32, &mut buf[n..]);
         for &b in b"], ctx[" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(ci as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(ci as u32, &mut buf[n..]);
         for &b in b"], dom=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(domid as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(domid as u32, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     });
@@ -1080,7 +1138,7 @@ pub fn report_summary(system_table: &mut SystemTable<Boot>) {
         let te = (s & GSTS_TES) != 0;
         let mut buf = [0u8; 96]; let mut n = 0;
         for &b in b"VT-d: seg=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(u.seg as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
         for &b in b" TE=" { buf[n] = b; n += 1; }
         buf[n] = if te { b'1' } else { b'0' }; n += 1;
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
@@ -1091,7 +1149,7 @@ pub fn report_summary(system_table: &mut SystemTable<Boot>) {
         let stdout = system_table.stdout();
         let mut buf = [0u8; 128]; let mut n = 0;
         for &b in b"VT-d: units=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(unit_count, &mut buf[n..]);
+        n += crate::util::format::u32_dec(unit_count, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     }
@@ -1106,11 +1164,11 @@ pub fn report_stats(system_table: &mut SystemTable<Boot>) {
     let stdout = system_table.stdout();
     let mut buf = [0u8; 96]; let mut n = 0;
     for &b in b"VT-d: doms=" { buf[n] = b; n += 1; }
-    n += crate::firmware::acpi::u32_to_dec(doms, &mut buf[n..]);
+    n += crate::util::format::u32_dec(doms, &mut buf[n..]);
     for &b in b" assigns=" { buf[n] = b; n += 1; }
-    n += crate::firmware::acpi::u32_to_dec(assigns, &mut buf[n..]);
+    n += crate::util::format::u32_dec(assigns, &mut buf[n..]);
     for &b in b" maps=" { buf[n] = b; n += 1; }
-    n += crate::firmware::acpi::u32_to_dec(maps, &mut buf[n..]);
+    n += crate::util::format::u32_dec(maps, &mut buf[n..]);
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
 }
@@ -1143,13 +1201,13 @@ pub fn verify_state(system_table: &mut SystemTable<Boot>) {
                 issues = issues.saturating_add(1);
                 let mut buf = [0u8; 160]; let mut n = 0;
                 for &b in b"verify: mismatch seg=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
+                n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
                 for &b in b" bus=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+                n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
                 for &b in b" dev=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
+                n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
                 for &b in b" fn=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
+                n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
                 buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
                 let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
             }
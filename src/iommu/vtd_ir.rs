@@ -0,0 +1,547 @@
+#![allow(dead_code)]
+
+//! VT-d interrupt remapping.
+//!
+//! Each remapping-capable unit gets a 256-entry interrupt remapping table
+//! (IRT) and an invalidation queue; the interrupt entry cache can only be
+//! invalidated through queued invalidation. Routing a device reads the MSI or
+//! MSI-X messages it currently has programmed in compatibility format,
+//! builds an IRTE with the same vector, destination and delivery mode plus
+//! source-id verification, and rewrites the message in remappable format.
+//! The original messages are kept so unrouting (or disabling remapping)
+//! restores them. Compatibility-format interrupts from firmware-owned
+//! devices keep working unless remapping is enabled in strict mode.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use crate::util::spinlock::SpinLock;
+use core::fmt::Write as _;
+
+use super::vtd::GSTS_PERSISTENT;
+use super::{mmio_read16, mmio_read32, mmio_write16, mmio_write32};
+
+const REG_ECAP: usize = 0x010;
+const REG_GCMD: usize = 0x018;
+const REG_GSTS: usize = 0x01C;
+const REG_FSTS: usize = 0x034;
+const REG_IQH: usize = 0x080;
+const REG_IQT: usize = 0x088;
+const REG_IQA: usize = 0x090;
+const REG_IRTA: usize = 0x0B8;
+
+const ECAP_QI: u64 = 1 << 1;
+const ECAP_IR: u64 = 1 << 3;
+const ECAP_EIM: u64 = 1 << 4;
+
+const GCMD_CFI: u32 = 1 << 23;
+const GCMD_SIRTP: u32 = 1 << 24;
+const GCMD_IRE: u32 = 1 << 25;
+const GCMD_QIE: u32 = 1 << 26;
+const GSTS_CFIS: u32 = 1 << 23;
+const GSTS_IRTPS: u32 = 1 << 24;
+const GSTS_IRES: u32 = 1 << 25;
+const GSTS_QIES: u32 = 1 << 26;
+const FSTS_IQE: u32 = 1 << 4;
+
+const IRTA_EIME: u64 = 1 << 11;
+/// IRT size: 2^(IRTA_S + 1) entries, one 4 KiB page
+const IRTA_S: u64 = 7;
+pub const IRT_ENTRIES: usize = 256;
+/// 4 KiB queue of 128-bit descriptors
+const IQ_ENTRIES: usize = 256;
+const IQA_DW: u64 = 1 << 11;
+
+const DESC_IEC: u64 = 0x4;
+const DESC_IEC_INDEX: u64 = 1 << 4;
+const DESC_WAIT: u64 = 0x5;
+const DESC_WAIT_SW: u64 = 1 << 5;
+
+// IRTE (remapped format) low qword
+const IRTE_P: u64 = 1 << 0;
+const IRTE_DM: u64 = 1 << 2;
+const IRTE_RH: u64 = 1 << 3;
+const IRTE_TM: u64 = 1 << 4;
+const IRTE_DLM_SHIFT: u32 = 5;
+const IRTE_VECTOR_SHIFT: u32 = 16;
+const IRTE_DST_SHIFT: u32 = 32;
+// High qword: verify the full requester id
+const IRTE_SVT_SID: u64 = 1 << 18;
+
+// MSI address fields
+const MSI_ADDR_BASE: u32 = 0xFEE0_0000;
+const MSI_ADDR_IF_REMAP: u32 = 1 << 4;
+const MSI_ADDR_RH: u32 = 1 << 3;
+const MSI_ADDR_DM: u32 = 1 << 2;
+
+use super::pcicap::{CAP_ID_MSI, CAP_ID_MSIX};
+
+#[derive(Clone, Copy, Debug)]
+pub struct IrUnit {
+    pub seg: u16,
+    pub reg_base: u64,
+    irt: u64,
+    used: [u64; IRT_ENTRIES / 64],
+    pub eim: bool,
+    pub strict: bool,
+}
+
+impl IrUnit {
+    pub fn used_entries(&self) -> usize { self.used.iter().map(|w| w.count_ones() as usize).sum() }
+}
+
+/// Where a remapped message lives on the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source { Msi, Msix(u16) }
+
+#[derive(Clone, Copy, Debug)]
+pub struct Route {
+    pub seg: u16,
+    pub sid: u16,
+    pub source: Source,
+    /// IRT index on the owning unit
+    pub index: u16,
+    pub reg_base: u64,
+    pub vector: u8,
+    pub dest: u32,
+    /// Compatibility-format message restored on unroute
+    orig_addr: u32,
+    orig_data: u32,
+    /// Config space (MSI) or table entry (MSI-X) address
+    loc: usize,
+}
+
+pub const MAX_ROUTES: usize = 64;
+
+/// Invalidation queue of one unit, shared by interrupt remapping and
+/// scalable-mode translation.
+#[derive(Clone, Copy)]
+struct Queue {
+    reg_base: u64,
+    iq: u64,
+    entries: usize,
+    /// Next free descriptor slot
+    tail: usize,
+    /// Wait-descriptor status word
+    status: u64,
+}
+
+static IR_UNITS: SpinLock<[Option<IrUnit>; 8]> = SpinLock::new([None; 8]);
+static QUEUES: SpinLock<[Option<Queue>; 8]> = SpinLock::new([None; 8]);
+static ROUTES: SpinLock<[Option<Route>; MAX_ROUTES]> = SpinLock::new([None; MAX_ROUTES]);
+
+fn rd32(reg_base: u64, off: usize) -> u32 { unsafe { core::ptr::read_volatile((reg_base as usize + off) as *const u32) } }
+fn wr32(reg_base: u64, off: usize, v: u32) { unsafe { core::ptr::write_volatile((reg_base as usize + off) as *mut u32, v) } }
+fn rd64(reg_base: u64, off: usize) -> u64 { unsafe { core::ptr::read_volatile((reg_base as usize + off) as *const u64) } }
+fn wr64(reg_base: u64, off: usize, v: u64) { unsafe { core::ptr::write_volatile((reg_base as usize + off) as *mut u64, v) } }
+
+/// Set or clear a GCMD bit and wait for the matching GSTS bit.
+fn gcmd(system_table: &SystemTable<Boot>, reg_base: u64, cmd: u32, on: bool, sts: u32) -> bool {
+    let cur = rd32(reg_base, REG_GSTS) & GSTS_PERSISTENT;
+    wr32(reg_base, REG_GCMD, if on { cur | cmd } else { cur & !cmd });
+    for _ in 0..5000 {
+        if ((rd32(reg_base, REG_GSTS) & sts) != 0) == on { return true; }
+        let _ = system_table.boot_services().stall(100);
+    }
+    false
+}
+
+fn alloc_zeroed_page(system_table: &SystemTable<Boot>) -> Option<u64> {
+    let p = crate::mm::uefi::alloc_pages(system_table, 1, uefi::table::boot::MemoryType::LOADER_DATA)?;
+    unsafe { core::ptr::write_bytes(p, 0, 4096); }
+    Some(p as u64)
+}
+
+/// Queue descriptors followed by a wait descriptor, and poll for completion.
+fn qi_submit(system_table: &SystemTable<Boot>, q: &mut Queue, descs: &[(u64, u64)]) -> Result<(), &'static str> {
+    let st = q.status as *mut u32;
+    unsafe { core::ptr::write_volatile(st, 0); }
+    let wait = (DESC_WAIT | DESC_WAIT_SW | (1u64 << 32), q.status);
+    for &(lo, hi) in descs.iter().chain(core::iter::once(&wait)) {
+        let slot = (q.iq + (q.tail * 16) as u64) as *mut u64;
+        unsafe {
+            core::ptr::write_volatile(slot, lo);
+            core::ptr::write_volatile(slot.add(1), hi);
+        }
+        q.tail = (q.tail + 1) % q.entries;
+    }
+    wr64(q.reg_base, REG_IQT, (q.tail as u64) << 4);
+    for _ in 0..10_000 {
+        if unsafe { core::ptr::read_volatile(st) } == 1 { return Ok(()); }
+        if rd32(q.reg_base, REG_FSTS) & FSTS_IQE != 0 {
+            wr32(q.reg_base, REG_FSTS, FSTS_IQE);
+            return Err("qi: invalidation queue error");
+        }
+        let _ = system_table.boot_services().stall(10);
+    }
+    Err("qi: invalidation wait timed out")
+}
+
+/// Submit invalidation descriptors to the unit at `reg_base`, bringing up
+/// its queue on first use.
+pub(crate) fn queue_invalidate(system_table: &SystemTable<Boot>, reg_base: u64, descs: &[(u64, u64)]) -> Result<(), &'static str> {
+    let mut q = match QUEUES.lock(|t| t.iter().flatten().find(|q| q.reg_base == reg_base).copied()) {
+        Some(q) => q,
+        None => {
+            if rd64(reg_base, REG_ECAP) & ECAP_QI == 0 { return Err("qi: unit lacks queued invalidation"); }
+            let q = qi_enable(system_table, reg_base)?;
+            QUEUES.lock(|t| match t.iter_mut().find(|s| s.is_none()) {
+                Some(s) => { *s = Some(q); Ok(()) }
+                None => Err("qi: queue table full"),
+            })?;
+            q
+        }
+    };
+    let r = qi_submit(system_table, &mut q, descs);
+    QUEUES.lock(|t| if let Some(x) = t.iter_mut().flatten().find(|x| x.reg_base == reg_base) { x.tail = q.tail; });
+    r
+}
+
+fn iec_global(system_table: &SystemTable<Boot>, reg_base: u64) -> Result<(), &'static str> {
+    queue_invalidate(system_table, reg_base, &[(DESC_IEC, 0)])
+}
+
+fn iec_index(system_table: &SystemTable<Boot>, reg_base: u64, index: u16) -> Result<(), &'static str> {
+    queue_invalidate(system_table, reg_base, &[(DESC_IEC | DESC_IEC_INDEX | (index as u64) << 32, 0)])
+}
+
+/// Bring up queued invalidation on a unit, adopting a queue firmware already enabled.
+fn qi_enable(system_table: &SystemTable<Boot>, reg_base: u64) -> Result<Queue, &'static str> {
+    // Wait descriptors report completion into this word.
+    let status = alloc_zeroed_page(system_table).ok_or("qi: out of memory")?;
+    if rd32(reg_base, REG_GSTS) & GSTS_QIES != 0 {
+        let iqa = rd64(reg_base, REG_IQA);
+        if iqa & IQA_DW != 0 { return Err("qi: firmware queue uses 256-bit descriptors"); }
+        let entries = IQ_ENTRIES << (iqa & 7);
+        let tail = ((rd64(reg_base, REG_IQT) >> 4) & 0x7FFF) as usize % entries;
+        return Ok(Queue { reg_base, iq: iqa & !0xFFF, entries, tail, status });
+    }
+    let iq = alloc_zeroed_page(system_table).ok_or("qi: out of memory")?;
+    wr64(reg_base, REG_IQT, 0);
+    wr64(reg_base, REG_IQA, iq);
+    if !gcmd(system_table, reg_base, GCMD_QIE, true, GSTS_QIES) { return Err("qi: queued invalidation enable timed out"); }
+    Ok(Queue { reg_base, iq, entries: IQ_ENTRIES, tail: 0, status })
+}
+
+fn enable_unit(system_table: &SystemTable<Boot>, seg: u16, reg_base: u64, strict: bool) -> Result<IrUnit, &'static str> {
+    let ecap = rd64(reg_base, REG_ECAP);
+    if ecap & ECAP_IR == 0 { return Err("ir: unit lacks interrupt remapping"); }
+    if ecap & ECAP_QI == 0 { return Err("ir: unit lacks queued invalidation"); }
+    let eim = crate::arch::x86::lapic::is_x2apic_enabled();
+    if eim && ecap & ECAP_EIM == 0 { return Err("ir: host uses x2APIC but unit lacks EIM"); }
+    let irt = alloc_zeroed_page(system_table).ok_or("ir: out of memory")?;
+    let u = IrUnit { seg, reg_base, irt, used: [0; IRT_ENTRIES / 64], eim, strict };
+    wr64(reg_base, REG_IRTA, irt | IRTA_S | if eim { IRTA_EIME } else { 0 });
+    if !gcmd(system_table, reg_base, GCMD_SIRTP, true, GSTS_IRTPS) { return Err("ir: table pointer latch timed out"); }
+    iec_global(system_table, reg_base)?;
+    if !gcmd(system_table, reg_base, GCMD_CFI, !strict, GSTS_CFIS) { return Err("ir: compatibility-format setting timed out"); }
+    if !gcmd(system_table, reg_base, GCMD_IRE, true, GSTS_IRES) { return Err("ir: enable timed out"); }
+    Ok(u)
+}
+
+/// Mode for `iommu ir enable` when the command does not name one
+static STRICT_DEFAULT: AtomicBool = AtomicBool::new(false);
+
+pub fn default_strict() -> bool { STRICT_DEFAULT.load(Ordering::Relaxed) }
+pub fn set_default_strict(on: bool) { STRICT_DEFAULT.store(on, Ordering::Relaxed); }
+
+/// Enable remapping on every VT-d unit that supports it and route the MSIs of
+/// devices assigned to IOMMU domains. Returns the number of units enabled.
+pub fn enable(system_table: &mut SystemTable<Boot>, strict: bool) -> Result<usize, &'static str> {
+    let mut regs = [(0u16, 0u64); 8];
+    let mut cnt = 0;
+    super::vtd::for_each_unit_reg(|seg, reg| { if cnt < regs.len() { regs[cnt] = (seg, reg); cnt += 1; } });
+    if cnt == 0 { return Err("ir: no VT-d units initialized"); }
+    let mut enabled = 0;
+    let mut last_err = "ir: no unit supports interrupt remapping";
+    for &(seg, reg) in &regs[..cnt] {
+        if IR_UNITS.lock(|t| t.iter().flatten().any(|u| u.reg_base == reg)) { enabled += 1; continue; }
+        match enable_unit(system_table, seg, reg, strict) {
+            Ok(u) => {
+                IR_UNITS.lock(|t| if let Some(s) = t.iter_mut().find(|s| s.is_none()) { *s = Some(u); });
+                enabled += 1;
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    if enabled == 0 { return Err(last_err); }
+    let _ = route_assigned(system_table);
+    Ok(enabled)
+}
+
+/// Restore every routed device's original messages and turn remapping off.
+pub fn disable(system_table: &mut SystemTable<Boot>) {
+    loop {
+        let r = ROUTES.lock(|t| t.iter().flatten().next().copied());
+        match r { Some(r) => unroute(system_table, r.seg, r.sid), None => break }
+    }
+    let units = IR_UNITS.lock(|t| { let s = *t; *t = [None; 8]; s });
+    for u in units.iter().flatten() {
+        let _ = gcmd(system_table, u.reg_base, GCMD_IRE, false, GSTS_IRES);
+    }
+}
+
+pub fn is_enabled() -> bool { IR_UNITS.lock(|t| t.iter().any(|u| u.is_some())) }
+
+/// The remapping route of one message of a device, if it has been routed.
+pub fn route_of(seg: u16, sid: u16, source: Source) -> Option<Route> {
+    ROUTES.lock(|t| t.iter().flatten().find(|r| r.seg == seg && r.sid == sid && r.source == source).copied())
+}
+
+// ---- Entries ----
+
+fn alloc_index(u: &mut IrUnit) -> Option<u16> {
+    for (w, word) in u.used.iter_mut().enumerate() {
+        if *word != u64::MAX {
+            let b = (!*word).trailing_zeros() as usize;
+            *word |= 1 << b;
+            return Some((w * 64 + b) as u16);
+        }
+    }
+    None
+}
+
+fn write_irte(u: &IrUnit, index: u16, lo: u64, hi: u64) {
+    let e = (u.irt + index as u64 * 16) as *mut u64;
+    unsafe {
+        // Present bit lives in the low qword: publish high first, retire low first.
+        if lo & IRTE_P != 0 {
+            core::ptr::write_volatile(e.add(1), hi);
+            core::ptr::write_volatile(e, lo);
+        } else {
+            core::ptr::write_volatile(e, lo);
+            core::ptr::write_volatile(e.add(1), hi);
+        }
+    }
+}
+
+fn read_irte(u: &IrUnit, index: u16) -> (u64, u64) {
+    let e = (u.irt + index as u64 * 16) as *const u64;
+    unsafe { (core::ptr::read_volatile(e), core::ptr::read_volatile(e.add(1))) }
+}
+
+/// Build an IRTE from a compatibility-format message, allocate it on the unit
+/// at `reg_base`, and return (index, remappable address, data).
+fn program(system_table: &SystemTable<Boot>, reg_base: u64, sid: u16, addr: u32, data: u32) -> Result<(u16, u32, u32), &'static str> {
+    let vector = data & 0xFF;
+    let dlm = (data >> 8) & 0x7;
+    let dest = (addr >> 12) & 0xFF;
+    let mut lo = IRTE_P | (dlm as u64) << IRTE_DLM_SHIFT | (vector as u64) << IRTE_VECTOR_SHIFT;
+    if addr & MSI_ADDR_DM != 0 { lo |= IRTE_DM; }
+    if addr & MSI_ADDR_RH != 0 { lo |= IRTE_RH; }
+    if data & (1 << 15) != 0 { lo |= IRTE_TM; }
+    let hi = IRTE_SVT_SID | sid as u64;
+    let idx = IR_UNITS.lock(|t| {
+        let u = t.iter_mut().flatten().find(|u| u.reg_base == reg_base).ok_or("ir: remapping not enabled on device's unit")?;
+        let idx = alloc_index(u).ok_or("ir: remapping table full")?;
+        // xAPIC destinations sit in bits 47:40, x2APIC ids use the whole dword.
+        let d = if u.eim { dest as u64 } else { (dest as u64) << 8 };
+        write_irte(u, idx, lo | d << IRTE_DST_SHIFT, hi);
+        Ok::<_, &'static str>(idx)
+    })?;
+    iec_index(system_table, reg_base, idx)?;
+    let h = idx as u32;
+    let raddr = MSI_ADDR_BASE | (h & 0x7FFF) << 5 | MSI_ADDR_IF_REMAP | ((h >> 15) & 1) << 2;
+    Ok((idx, raddr, 0))
+}
+
+fn release(system_table: &SystemTable<Boot>, reg_base: u64, index: u16) {
+    let found = IR_UNITS.lock(|t| {
+        let u = match t.iter_mut().flatten().find(|u| u.reg_base == reg_base) { Some(u) => u, None => return false };
+        write_irte(u, index, 0, 0);
+        u.used[index as usize / 64] &= !(1 << (index % 64));
+        true
+    });
+    if found { let _ = iec_index(system_table, reg_base, index); }
+}
+
+// ---- Devices ----
+
+fn add_route(r: Route) -> Result<(), &'static str> {
+    ROUTES.lock(|t| match t.iter_mut().find(|s| s.is_none()) { Some(s) => { *s = Some(r); Ok(()) } None => Err("ir: too many routes") })
+}
+
+/// Remap the enabled MSI / unmasked MSI-X messages of a device. Returns how
+/// many messages were routed through the remapping table.
+pub fn route_device(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8) -> Result<usize, &'static str> {
+    let reg_base = super::vtd::unit_reg_for_bdf(system_table, seg, bus, dev, func).ok_or("ir: no VT-d unit for device")?;
+    let cfg = super::ecam_cfg_base(system_table, seg, bus, dev, func).ok_or("ir: no ECAM window for device")?;
+    let sid = (bus as u16) << 8 | (dev as u16) << 3 | func as u16;
+    if ROUTES.lock(|t| t.iter().flatten().any(|r| r.seg == seg && r.sid == sid)) { return Ok(0); }
+    let mut routed = 0;
+    if let Some(c) = super::pcicap::find_cap(cfg, CAP_ID_MSI) {
+        let ctl = mmio_read16(cfg + c + 2);
+        let wide = ctl & (1 << 7) != 0;
+        let data_off = if wide { 0x0C } else { 0x08 };
+        let addr = mmio_read32(cfg + c + 4);
+        // Multi-message MSI needs contiguous subhandles; left in compatibility format.
+        if ctl & 1 != 0 && (ctl >> 4) & 0x7 == 0 && addr & MSI_ADDR_IF_REMAP == 0 {
+            let data = mmio_read16(cfg + c + data_off) as u32;
+            let (idx, raddr, rdata) = program(system_table, reg_base, sid, addr, data)?;
+            mmio_write32(cfg + c + 4, raddr);
+            if wide { mmio_write32(cfg + c + 8, 0); }
+            mmio_write16(cfg + c + data_off, rdata as u16);
+            add_route(Route { seg, sid, source: Source::Msi, index: idx, reg_base, vector: data as u8, dest: (addr >> 12) & 0xFF, orig_addr: addr, orig_data: data, loc: cfg + c })?;
+            routed += 1;
+        }
+    }
+    if let Some(c) = super::pcicap::find_cap(cfg, CAP_ID_MSIX) {
+        let ctl = mmio_read16(cfg + c + 2);
+        if ctl & (1 << 15) != 0 {
+            let tbl = mmio_read32(cfg + c + 4);
+            let base = super::pcicap::bar_addr(cfg, (tbl & 7) as usize) + (tbl & !7) as u64;
+            for i in 0..=(ctl & 0x7FF) {
+                let e = base as usize + i as usize * 16;
+                let vctl = mmio_read32(e + 12);
+                let addr = mmio_read32(e);
+                if vctl & 1 != 0 || addr & MSI_ADDR_IF_REMAP != 0 || addr & 0xFFF0_0000 != MSI_ADDR_BASE { continue; }
+                let data = mmio_read32(e + 8);
+                let (idx, raddr, rdata) = program(system_table, reg_base, sid, addr, data)?;
+                // Mask while the address/data pair is inconsistent.
+                mmio_write32(e + 12, vctl | 1);
+                mmio_write32(e, raddr);
+                mmio_write32(e + 4, 0);
+                mmio_write32(e + 8, rdata);
+                mmio_write32(e + 12, vctl);
+                add_route(Route { seg, sid, source: Source::Msix(i), index: idx, reg_base, vector: data as u8, dest: (addr >> 12) & 0xFF, orig_addr: addr, orig_data: data, loc: e })?;
+                routed += 1;
+            }
+        }
+    }
+    Ok(routed)
+}
+
+/// Restore a device's original messages and free its remapping entries.
+pub fn unroute(system_table: &mut SystemTable<Boot>, seg: u16, sid: u16) {
+    loop {
+        let r = ROUTES.lock(|t| {
+            let i = t.iter().position(|r| matches!(r, Some(r) if r.seg == seg && r.sid == sid))?;
+            t[i].take()
+        });
+        let r = match r { Some(r) => r, None => break };
+        match r.source {
+            Source::Msi => {
+                let ctl = mmio_read16(r.loc + 2);
+                mmio_write32(r.loc + 4, r.orig_addr);
+                mmio_write16(r.loc + if ctl & (1 << 7) != 0 { 0x0C } else { 0x08 }, r.orig_data as u16);
+            }
+            Source::Msix(_) => {
+                let vctl = mmio_read32(r.loc + 12);
+                mmio_write32(r.loc + 12, vctl | 1);
+                mmio_write32(r.loc, r.orig_addr);
+                mmio_write32(r.loc + 8, r.orig_data);
+                mmio_write32(r.loc + 12, vctl);
+            }
+        }
+        release(system_table, r.reg_base, r.index);
+    }
+}
+
+/// Route every device currently assigned to an IOMMU domain.
+pub fn route_assigned(system_table: &mut SystemTable<Boot>) -> usize {
+    let mut bdfs = [(0u16, 0u8, 0u8, 0u8); 32];
+    let mut cnt = 0;
+    super::state::list_assignments(|seg, bus, dev, func, _dom| { if cnt < bdfs.len() { bdfs[cnt] = (seg, bus, dev, func); cnt += 1; } });
+    let mut total = 0;
+    for &(seg, bus, dev, func) in &bdfs[..cnt] {
+        if let Ok(n) = route_device(system_table, seg, bus, dev, func) { total += n; }
+    }
+    total
+}
+
+// ---- Reporting ----
+
+pub fn report_status(system_table: &mut SystemTable<Boot>) {
+    let mut any = false;
+    super::vtd::for_each_unit_reg(|seg, reg_base| {
+        any = true;
+        let ecap = rd64(reg_base, REG_ECAP);
+        let gsts = rd32(reg_base, REG_GSTS);
+        let unit = IR_UNITS.lock(|t| t.iter().flatten().find(|u| u.reg_base == reg_base).copied());
+        let routes = ROUTES.lock(|t| t.iter().flatten().filter(|r| r.reg_base == reg_base).count());
+        let mut buf = [0u8; 192]; let mut n = 0;
+        for &b in b"IR: seg=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
+        for &b in b" reg=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(reg_base, &mut buf[n..]);
+        let flag = |buf: &mut [u8], n: &mut usize, name: &[u8], on: bool| {
+            for &b in name { buf[*n] = b; *n += 1; }
+            buf[*n] = if on { b'1' } else { b'0' }; *n += 1;
+        };
+        flag(&mut buf, &mut n, b" cap_ir=", ecap & ECAP_IR != 0);
+        flag(&mut buf, &mut n, b" cap_eim=", ecap & ECAP_EIM != 0);
+        flag(&mut buf, &mut n, b" qi=", gsts & GSTS_QIES != 0);
+        flag(&mut buf, &mut n, b" ire=", gsts & GSTS_IRES != 0);
+        flag(&mut buf, &mut n, b" cfi=", gsts & GSTS_CFIS != 0);
+        for &b in b" irta=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(rd64(reg_base, REG_IRTA), &mut buf[n..]);
+        if let Some(u) = unit {
+            for &b in b" used=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(u.used_entries() as u64, &mut buf[n..]);
+            buf[n] = b'/'; n += 1;
+            n += crate::util::format::u64_dec(IRT_ENTRIES as u64, &mut buf[n..]);
+            for &b in b" routes=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(routes as u64, &mut buf[n..]);
+            if u.strict { for &b in b" strict" { buf[n] = b; n += 1; } }
+        }
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    });
+    if !any { let _ = system_table.stdout().write_str("IR: no VT-d units initialized\r\n"); }
+}
+
+/// Print the present entries of each enabled unit's remapping table.
+pub fn dump(system_table: &mut SystemTable<Boot>) {
+    let units = IR_UNITS.lock(|t| *t);
+    let stdout = system_table.stdout();
+    if units.iter().all(|u| u.is_none()) { let _ = stdout.write_str("IR: remapping not enabled\r\n"); return; }
+    for u in units.iter().flatten() {
+        for idx in 0..IRT_ENTRIES as u16 {
+            let (lo, hi) = read_irte(u, idx);
+            if lo & IRTE_P == 0 { continue; }
+            let dest = if u.eim { (lo >> IRTE_DST_SHIFT) as u32 } else { ((lo >> (IRTE_DST_SHIFT + 8)) & 0xFF) as u32 };
+            let sid = (hi & 0xFFFF) as u16;
+            let mut buf = [0u8; 160]; let mut n = 0;
+            for &b in b"IRTE seg=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u32_dec(u.seg as u32, &mut buf[n..]);
+            for &b in b" idx=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(idx as u64, &mut buf[n..]);
+            for &b in b" sid=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_hex((sid >> 8) as u64, &mut buf[n..]);
+            buf[n] = b':'; n += 1;
+            n += crate::util::format::u64_hex(((sid >> 3) & 0x1F) as u64, &mut buf[n..]);
+            buf[n] = b'.'; n += 1;
+            n += crate::util::format::u64_dec((sid & 7) as u64, &mut buf[n..]);
+            for &b in b" vec=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec((lo >> IRTE_VECTOR_SHIFT) & 0xFF, &mut buf[n..]);
+            for &b in b" dest=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(dest as u64, &mut buf[n..]);
+            for &b in if lo & IRTE_DM != 0 { &b" logical"[..] } else { &b" physical"[..] } { buf[n] = b; n += 1; }
+            for &b in if lo & IRTE_TM != 0 { &b" level"[..] } else { &b" edge"[..] } { buf[n] = b; n += 1; }
+            for &b in b" dlm=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec((lo >> IRTE_DLM_SHIFT) & 0x7, &mut buf[n..]);
+            for &b in b" svt=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec((hi >> 18) & 0x3, &mut buf[n..]);
+            buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+            let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+        }
+    }
+    let routes = ROUTES.lock(|t| *t);
+    for r in routes.iter().flatten() {
+        let mut buf = [0u8; 128]; let mut n = 0;
+        for &b in b"route: sid=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(r.sid as u64, &mut buf[n..]);
+        match r.source {
+            Source::Msi => { for &b in b" msi" { buf[n] = b; n += 1; } }
+            Source::Msix(i) => {
+                for &b in b" msix[" { buf[n] = b; n += 1; }
+                n += crate::util::format::u64_dec(i as u64, &mut buf[n..]);
+                buf[n] = b']'; n += 1;
+            }
+        }
+        for &b in b" -> idx=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(r.index as u64, &mut buf[n..]);
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
+}
@@ -0,0 +1,636 @@
+#![allow(dead_code)]
+
+//! VT-d scalable-mode translation and PASID binding.
+//!
+//! Units that report scalable mode (ECAP.SMTS) can be switched from the
+//! legacy root/context format to the scalable one, where every device owns a
+//! PASID directory and each PASID selects its own address space. PASID 0
+//! serves requests without a PASID (RID_PASID) and follows the device's
+//! `iommu::state` assignment, so existing domains keep working. Other PASIDs
+//! are bound explicitly, either to a domain's second-level table or to a
+//! first-level table: a caller-supplied one for shared virtual addressing,
+//! or a private one this module fills through `map`. Scalable mode only
+//! accepts queued invalidation, so every flush goes through the unit's
+//! invalidation queue.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use crate::util::spinlock::SpinLock;
+use core::fmt::Write as _;
+
+use super::vtd::GSTS_PERSISTENT;
+
+const REG_ECAP: usize = 0x010;
+const REG_GCMD: usize = 0x018;
+const REG_GSTS: usize = 0x01C;
+const REG_RTADDR: usize = 0x020;
+
+const ECAP_QI: u64 = 1 << 1;
+const ECAP_SRS: u64 = 1 << 31;
+const ECAP_PSS_SHIFT: u32 = 35;
+const ECAP_PASID: u64 = 1 << 40;
+const ECAP_SMTS: u64 = 1 << 43;
+const ECAP_SLTS: u64 = 1 << 46;
+const ECAP_FLTS: u64 = 1 << 47;
+
+const GCMD_SRTP: u32 = 1 << 30;
+const GSTS_RTPS: u32 = 1 << 30;
+const GSTS_TES: u32 = 1 << 31;
+
+/// Root table type: scalable mode
+const RTADDR_TTM_SM: u64 = 1 << 10;
+
+// Root entry: lower/upper halves point at context tables for devfn 0-127 / 128-255
+const RE_P: u64 = 1 << 0;
+// Scalable-mode context entry (256-bit), qword 0
+const SMCE_P: u64 = 1 << 0;
+const SMCE_PASIDE: u64 = 1 << 3;
+const SMCE_PDTS_SHIFT: u32 = 9;
+/// PASID directory of one page: 2^(PDTS + 7) = 512 entries
+const PDTS: u64 = 2;
+pub const PASID_MAX: u32 = 512 * 64;
+
+// PASID directory entry
+const PDE_P: u64 = 1 << 0;
+// PASID table entry (512-bit)
+const PE0_P: u64 = 1 << 0;
+const PE0_AW_48: u64 = 2 << 2;
+const PE0_PGTT_SHIFT: u32 = 6;
+const PGTT_FIRST: u64 = 1;
+const PGTT_SECOND: u64 = 2;
+
+// Page table entries (first and second level share P/RW/PS placement)
+const PTE_P: u64 = 1 << 0;
+const PTE_RW: u64 = 1 << 1;
+const PTE_US: u64 = 1 << 2;
+const PTE_PS: u64 = 1 << 7;
+const PTE_ADDR: u64 = 0x000F_FFFF_FFFF_F000;
+
+// Invalidation descriptors
+const DESC_CC: u64 = 0x1;
+const CC_GLOBAL: u64 = 1 << 4;
+const CC_DEVICE: u64 = 3 << 4;
+const DESC_IOTLB: u64 = 0x2;
+const IOTLB_GLOBAL: u64 = 1 << 4;
+const IOTLB_DOMAIN: u64 = 2 << 4;
+const IOTLB_DR: u64 = 1 << 7;
+const IOTLB_DW: u64 = 1 << 6;
+const DESC_PIOTLB: u64 = 0x6;
+const DESC_PC: u64 = 0x7;
+const PC_PASID: u64 = 1 << 4;
+const PC_GLOBAL: u64 = 3 << 4;
+
+/// Scalable-mode capabilities of one unit.
+#[derive(Clone, Copy, Debug)]
+pub struct Caps {
+    pub smts: bool,
+    pub pasid: bool,
+    /// Supported PASID width in bits
+    pub pasid_bits: u8,
+    pub flts: bool,
+    pub slts: bool,
+    /// Supervisor requests
+    pub srs: bool,
+    pub qi: bool,
+}
+
+/// Address space a PASID resolves to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Table {
+    /// The domain's second-level table, shared with its RID_PASID traffic
+    Second,
+    /// A first-level table; `owned` ones were allocated here and are filled by `map`
+    First { root: u64, owned: bool },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Binding {
+    pub seg: u16,
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+    pub pasid: u32,
+    /// Domain id tagging the PASID's IOTLB entries
+    pub dom: u16,
+    pub table: Table,
+}
+
+pub const MAX_BINDINGS: usize = 64;
+
+#[derive(Clone, Copy)]
+struct SmUnit {
+    seg: u16,
+    reg_base: u64,
+    root: u64,
+    /// RTADDR to restore when leaving scalable mode
+    legacy_rtaddr: u64,
+}
+
+static SM_UNITS: SpinLock<[Option<SmUnit>; 8]> = SpinLock::new([None; 8]);
+static BINDINGS: SpinLock<[Option<Binding>; MAX_BINDINGS]> = SpinLock::new([None; MAX_BINDINGS]);
+
+fn rd32(reg_base: u64, off: usize) -> u32 { unsafe { core::ptr::read_volatile((reg_base as usize + off) as *const u32) } }
+fn wr32(reg_base: u64, off: usize, v: u32) { unsafe { core::ptr::write_volatile((reg_base as usize + off) as *mut u32, v) } }
+fn rd64(reg_base: u64, off: usize) -> u64 { unsafe { core::ptr::read_volatile((reg_base as usize + off) as *const u64) } }
+fn wr64(reg_base: u64, off: usize, v: u64) { unsafe { core::ptr::write_volatile((reg_base as usize + off) as *mut u64, v) } }
+
+fn alloc_page(system_table: &SystemTable<Boot>) -> Option<u64> {
+    let p = crate::mm::uefi::alloc_pages(system_table, 1, uefi::table::boot::MemoryType::LOADER_DATA)?;
+    unsafe { core::ptr::write_bytes(p, 0, 4096); }
+    Some(p as u64)
+}
+
+pub fn caps(reg_base: u64) -> Caps {
+    let ecap = rd64(reg_base, REG_ECAP);
+    let pasid = ecap & ECAP_PASID != 0;
+    Caps {
+        smts: ecap & ECAP_SMTS != 0,
+        pasid,
+        pasid_bits: if pasid { ((ecap >> ECAP_PSS_SHIFT) & 0x1F) as u8 + 1 } else { 0 },
+        flts: ecap & ECAP_FLTS != 0,
+        slts: ecap & ECAP_SLTS != 0,
+        srs: ecap & ECAP_SRS != 0,
+        qi: ecap & ECAP_QI != 0,
+    }
+}
+
+pub fn is_scalable(reg_base: u64) -> bool { SM_UNITS.lock(|t| t.iter().flatten().any(|u| u.reg_base == reg_base)) }
+
+pub fn is_enabled() -> bool { SM_UNITS.lock(|t| t.iter().any(|u| u.is_some())) }
+
+fn same(b: &Binding, seg: u16, bus: u8, dev: u8, func: u8) -> bool { b.seg == seg && b.bus == bus && b.dev == dev && b.func == func }
+
+/// The scalable-mode unit owning a device, if its unit has been switched.
+fn unit_for(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8) -> Option<SmUnit> {
+    let reg = super::vtd::unit_reg_for_bdf(system_table, seg, bus, dev, func)?;
+    SM_UNITS.lock(|t| t.iter().flatten().find(|u| u.reg_base == reg).copied())
+}
+
+// ---- Tables ----
+
+/// Scalable-mode context entry of `bus:devfn` under `root`.
+unsafe fn context_entry(system_table: &SystemTable<Boot>, root: u64, bus: u8, devfn: u8, create: bool) -> Option<*mut u64> {
+    let re = (root as *mut u64).add(bus as usize * 2 + (devfn >> 7) as usize);
+    let mut v = core::ptr::read_volatile(re);
+    if v & RE_P == 0 {
+        if !create { return None; }
+        v = alloc_page(system_table)? | RE_P;
+        core::ptr::write_volatile(re, v);
+    }
+    Some(((v & PTE_ADDR) as *mut u64).add((devfn & 0x7F) as usize * 4))
+}
+
+/// PASID table entry for `pasid` behind a context entry. Creating one also
+/// makes the context entry present; a blocked entry keeps its directory so
+/// reviving it does not leak tables.
+unsafe fn pasid_entry(system_table: &SystemTable<Boot>, ce: *mut u64, pasid: u32, create: bool) -> Option<*mut u64> {
+    let q0 = core::ptr::read_volatile(ce);
+    let mut dir = q0 & PTE_ADDR;
+    if dir == 0 {
+        if !create { return None; }
+        dir = alloc_page(system_table)?;
+    }
+    if create && q0 & SMCE_P == 0 {
+        // RID_PASID stays 0: requests without a PASID use PASID 0's entry.
+        core::ptr::write_volatile(ce.add(1), 0);
+        core::ptr::write_volatile(ce, dir | PDTS << SMCE_PDTS_SHIFT | SMCE_PASIDE | SMCE_P);
+    }
+    let de = (dir as *mut u64).add((pasid >> 6) as usize);
+    let mut dv = core::ptr::read_volatile(de);
+    if dv & PDE_P == 0 {
+        if !create { return None; }
+        dv = alloc_page(system_table)? | PDE_P;
+        core::ptr::write_volatile(de, dv);
+    }
+    Some(((dv & PTE_ADDR) as *mut u64).add((pasid & 0x3F) as usize * 8))
+}
+
+/// Publish a PASID entry. The present bit lives in qword 0, so it is written last.
+unsafe fn write_pasid_entry(pe: *mut u64, dom: u16, pgtt: u64, slptptr: u64, flptr: u64) {
+    core::ptr::write_volatile(pe, 0);
+    core::ptr::write_volatile(pe.add(1), dom as u64);
+    core::ptr::write_volatile(pe.add(2), flptr & PTE_ADDR);
+    for i in 3..8 { core::ptr::write_volatile(pe.add(i), 0); }
+    core::ptr::write_volatile(pe, (slptptr & PTE_ADDR) | pgtt << PE0_PGTT_SHIFT | PE0_AW_48 | PE0_P);
+}
+
+unsafe fn clear_pasid_entry(pe: *mut u64) {
+    core::ptr::write_volatile(pe, 0);
+    for i in 1..8 { core::ptr::write_volatile(pe.add(i), 0); }
+}
+
+/// Program one binding (or RID_PASID when `pasid == 0`) into a unit's tables.
+fn program(system_table: &SystemTable<Boot>, u: &SmUnit, bus: u8, devfn: u8, pasid: u32, dom: u16, table: Table) -> Result<(), &'static str> {
+    let slpt = match table {
+        Table::Second => super::vtd::ensure_domain_slptptr(system_table, dom).ok_or("sm: no second-level table for domain")?,
+        Table::First { .. } => 0,
+    };
+    unsafe {
+        let ce = context_entry(system_table, u.root, bus, devfn, true).ok_or("sm: out of memory")?;
+        let pe = pasid_entry(system_table, ce, pasid, true).ok_or("sm: out of memory")?;
+        match table {
+            Table::Second => write_pasid_entry(pe, dom, PGTT_SECOND, slpt, 0),
+            Table::First { root, .. } => write_pasid_entry(pe, dom, PGTT_FIRST, 0, root),
+        }
+    }
+    Ok(())
+}
+
+fn retire(system_table: &SystemTable<Boot>, u: &SmUnit, bus: u8, devfn: u8, pasid: u32) {
+    unsafe {
+        if let Some(ce) = context_entry(system_table, u.root, bus, devfn, false) {
+            if let Some(pe) = pasid_entry(system_table, ce, pasid, false) { clear_pasid_entry(pe); }
+        }
+    }
+}
+
+/// Rebuild a device's context from `iommu::state` and its bindings. A
+/// quarantined device keeps its tables but loses the present bit.
+fn sync_device(system_table: &SystemTable<Boot>, u: &SmUnit, seg: u16, bus: u8, dev: u8, func: u8) {
+    let devfn = dev << 3 | func;
+    if crate::iommu::fault::is_quarantined(seg, bus, dev, func) {
+        unsafe {
+            if let Some(ce) = context_entry(system_table, u.root, bus, devfn, false) {
+                core::ptr::write_volatile(ce, core::ptr::read_volatile(ce) & !SMCE_P);
+            }
+        }
+        return;
+    }
+    match crate::iommu::state::find_domain_for_bdf(seg, bus, dev, func) {
+        Some(dom) => { let _ = program(system_table, u, bus, devfn, 0, dom, Table::Second); }
+        None => retire(system_table, u, bus, devfn, 0),
+    }
+    let binds = BINDINGS.lock(|t| *t);
+    for b in binds.iter().flatten().filter(|b| same(b, seg, bus, dev, func)) {
+        let _ = program(system_table, u, bus, devfn, b.pasid, b.dom, b.table);
+    }
+}
+
+fn flush_global(system_table: &SystemTable<Boot>, reg_base: u64) -> Result<(), &'static str> {
+    super::vtd_ir::queue_invalidate(system_table, reg_base, &[
+        (DESC_CC | CC_GLOBAL, 0),
+        (DESC_PC | PC_GLOBAL, 0),
+        (DESC_IOTLB | IOTLB_GLOBAL | IOTLB_DR | IOTLB_DW, 0),
+    ])
+}
+
+fn flush_pasid(system_table: &SystemTable<Boot>, reg_base: u64, dom: u16, pasid: u32) -> Result<(), &'static str> {
+    let tag = (dom as u64) << 16 | (pasid as u64) << 32;
+    super::vtd_ir::queue_invalidate(system_table, reg_base, &[
+        (DESC_PC | PC_PASID | tag, 0),
+        (DESC_PIOTLB | tag, 0),
+        (DESC_IOTLB | IOTLB_DOMAIN | IOTLB_DR | IOTLB_DW | (dom as u64) << 16, 0),
+    ])
+}
+
+/// Flush every cache of a scalable-mode unit; legacy units are left to SRTP.
+pub(crate) fn flush_unit(system_table: &SystemTable<Boot>, reg_base: u64) -> Result<(), &'static str> {
+    if !is_scalable(reg_base) { return Ok(()); }
+    flush_global(system_table, reg_base)
+}
+
+fn set_root(system_table: &SystemTable<Boot>, reg_base: u64, rtaddr: u64) -> bool {
+    wr64(reg_base, REG_RTADDR, rtaddr);
+    let cur = rd32(reg_base, REG_GSTS) & GSTS_PERSISTENT;
+    wr32(reg_base, REG_GCMD, cur | GCMD_SRTP);
+    for _ in 0..5000 {
+        if rd32(reg_base, REG_GSTS) & GSTS_RTPS != 0 { return true; }
+        let _ = system_table.boot_services().stall(100);
+    }
+    false
+}
+
+// ---- Mode switch ----
+
+/// Switch every unit that supports it to scalable mode. Translation must be
+/// off: the root table format cannot change under live DMA. Returns the
+/// number of units now in scalable mode.
+pub fn enable(system_table: &mut SystemTable<Boot>) -> Result<usize, &'static str> {
+    let mut regs = [(0u16, 0u64); 8];
+    let mut cnt = 0;
+    super::vtd::for_each_unit_reg(|seg, reg| { if cnt < regs.len() { regs[cnt] = (seg, reg); cnt += 1; } });
+    if cnt == 0 { return Err("sm: no VT-d units initialized"); }
+    let mut enabled = 0;
+    let mut last_err = "sm: no unit supports scalable mode";
+    for &(seg, reg) in &regs[..cnt] {
+        if is_scalable(reg) { enabled += 1; continue; }
+        let c = caps(reg);
+        if !c.smts || !c.slts { continue; }
+        if !c.qi { last_err = "sm: unit lacks queued invalidation"; continue; }
+        if rd32(reg, REG_GSTS) & GSTS_TES != 0 { last_err = "sm: translation enabled; disable it first"; continue; }
+        let root = match alloc_page(system_table) { Some(p) => p, None => { last_err = "sm: out of memory"; continue; } };
+        let u = SmUnit { seg, reg_base: reg, root, legacy_rtaddr: rd64(reg, REG_RTADDR) };
+        let mut devs = [(0u16, 0u8, 0u8, 0u8); crate::iommu::state::MAX_ASSIGNMENTS];
+        let mut nd = 0;
+        crate::iommu::state::list_assignments(|s, b, d, f, _| { if nd < devs.len() { devs[nd] = (s, b, d, f); nd += 1; } });
+        for x in BINDINGS.lock(|t| *t).iter().flatten() {
+            if nd < devs.len() { devs[nd] = (x.seg, x.bus, x.dev, x.func); nd += 1; }
+        }
+        for &(s, b, d, f) in &devs[..nd] {
+            if super::vtd::unit_reg_for_bdf(system_table, s, b, d, f) == Some(reg) { sync_device(system_table, &u, s, b, d, f); }
+        }
+        if !set_root(system_table, reg, root | RTADDR_TTM_SM) { last_err = "sm: root table latch timed out"; continue; }
+        SM_UNITS.lock(|t| if let Some(s) = t.iter_mut().find(|s| s.is_none()) { *s = Some(u); });
+        if let Err(e) = flush_global(system_table, reg) { last_err = e; continue; }
+        enabled += 1;
+    }
+    if enabled == 0 { return Err(last_err); }
+    Ok(enabled)
+}
+
+/// Return every unit to the legacy root table. Bindings are kept and
+/// reprogrammed by the next `enable`.
+pub fn disable(system_table: &mut SystemTable<Boot>) -> Result<(), &'static str> {
+    let units = SM_UNITS.lock(|t| *t);
+    for u in units.iter().flatten() {
+        if rd32(u.reg_base, REG_GSTS) & GSTS_TES != 0 { return Err("sm: translation enabled; disable it first"); }
+    }
+    for u in units.iter().flatten() {
+        if !set_root(system_table, u.reg_base, u.legacy_rtaddr) { return Err("sm: root table latch timed out"); }
+        SM_UNITS.lock(|t| for s in t.iter_mut() { if matches!(s, Some(x) if x.reg_base == u.reg_base) { *s = None; } });
+        super::vtd_ir::queue_invalidate(system_table, u.reg_base, &[(DESC_CC | CC_GLOBAL, 0), (DESC_IOTLB | IOTLB_GLOBAL | IOTLB_DR | IOTLB_DW, 0)])?;
+    }
+    Ok(())
+}
+
+/// Mirror `iommu::state` assignments into RID_PASID entries of scalable-mode units.
+pub fn apply_assignments(system_table: &mut SystemTable<Boot>) {
+    let units = SM_UNITS.lock(|t| *t);
+    if units.iter().all(|u| u.is_none()) { return; }
+    let mut devs = [(0u16, 0u8, 0u8, 0u8); crate::iommu::state::MAX_ASSIGNMENTS];
+    let mut nd = 0;
+    crate::iommu::state::list_assignments(|s, b, d, f, _| { if nd < devs.len() { devs[nd] = (s, b, d, f); nd += 1; } });
+    for &(s, b, d, f) in &devs[..nd] {
+        if let Some(u) = unit_for(system_table, s, b, d, f) { sync_device(system_table, &u, s, b, d, f); }
+    }
+    // Devices that lost their assignment still hold a PASID 0 entry; walk
+    // every present context and drop the stale ones.
+    for u in units.iter().flatten() {
+        for bus in 0..=255u8 {
+            for devfn in 0..=255u8 {
+                let live = unsafe { context_entry(system_table, u.root, bus, devfn, false) }
+                    .map(|ce| unsafe { core::ptr::read_volatile(ce) } & PTE_ADDR != 0)
+                    .unwrap_or(false);
+                if !live { continue; }
+                let (dev, func) = (devfn >> 3, devfn & 7);
+                if crate::iommu::state::find_domain_for_bdf(u.seg, bus, dev, func).is_none() { retire(system_table, u, bus, devfn, 0); }
+            }
+        }
+        let _ = flush_global(system_table, u.reg_base);
+    }
+}
+
+/// Clear the present bit of a device's scalable-mode context so none of its
+/// PASIDs can reach memory. Returns false when its unit is not in scalable mode.
+pub(crate) fn block_device(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8) -> bool {
+    let u = match unit_for(system_table, seg, bus, dev, func) { Some(u) => u, None => return false };
+    let devfn = dev << 3 | func;
+    unsafe {
+        if let Some(ce) = context_entry(system_table, u.root, bus, devfn, false) {
+            core::ptr::write_volatile(ce, core::ptr::read_volatile(ce) & !SMCE_P);
+        }
+    }
+    let sid = (bus as u64) << 8 | devfn as u64;
+    let _ = super::vtd_ir::queue_invalidate(system_table, u.reg_base, &[
+        (DESC_CC | CC_DEVICE | sid << 32, 0),
+        (DESC_PC | PC_GLOBAL, 0),
+        (DESC_IOTLB | IOTLB_GLOBAL | IOTLB_DR | IOTLB_DW, 0),
+    ]);
+    true
+}
+
+// ---- Bindings ----
+
+fn check_pasid(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, pasid: u32) -> Result<Caps, &'static str> {
+    if pasid == 0 { return Err("sm: PASID 0 is reserved for requests without a PASID"); }
+    if pasid >= PASID_MAX { return Err("sm: PASID out of range"); }
+    let reg = super::vtd::unit_reg_for_bdf(system_table, seg, bus, dev, func).ok_or("sm: no VT-d unit for device")?;
+    let c = caps(reg);
+    if !c.smts || !c.pasid { return Err("sm: unit lacks PASID support"); }
+    if c.pasid_bits < 32 && pasid >> c.pasid_bits != 0 { return Err("sm: PASID wider than the unit supports"); }
+    Ok(c)
+}
+
+/// Record a binding, replacing any earlier one for the same PASID, and
+/// program it if the device's unit is already in scalable mode.
+fn install(system_table: &mut SystemTable<Boot>, b: Binding) -> Result<(), &'static str> {
+    let old = BINDINGS.lock(|t| {
+        if let Some(slot) = t.iter_mut().find(|s| matches!(s, Some(x) if same(x, b.seg, b.bus, b.dev, b.func) && x.pasid == b.pasid)) {
+            return Ok(slot.replace(b));
+        }
+        match t.iter_mut().find(|s| s.is_none()) {
+            Some(slot) => { *slot = Some(b); Ok(None) }
+            None => Err("sm: binding table full"),
+        }
+    })?;
+    if let Some(u) = unit_for(system_table, b.seg, b.bus, b.dev, b.func) {
+        program(system_table, &u, b.bus, b.dev << 3 | b.func, b.pasid, b.dom, b.table)?;
+        flush_pasid(system_table, u.reg_base, b.dom, b.pasid)?;
+        if let Some(o) = old { if o.dom != b.dom { flush_pasid(system_table, u.reg_base, o.dom, o.pasid)?; } }
+    }
+    if let Some(Binding { table: t @ Table::First { root, owned: true }, .. }) = old {
+        if t != b.table { unsafe { free_tree(system_table, root, 4); } }
+    }
+    Ok(())
+}
+
+/// Bind `pasid` of a device to domain `dom`'s second-level table.
+pub fn bind(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, pasid: u32, dom: u16) -> Result<(), &'static str> {
+    let c = check_pasid(system_table, seg, bus, dev, func, pasid)?;
+    if !c.slts { return Err("sm: unit lacks second-level translation"); }
+    if !crate::iommu::state::domain_exists(dom) { return Err("sm: no such domain"); }
+    install(system_table, Binding { seg, bus, dev, func, pasid, dom, table: Table::Second })
+}
+
+/// Bind `pasid` of a device to a first-level table. With `root` the device
+/// shares that address space (a process or guest page table, 4-level, user
+/// mappings only); without one a private table is allocated for `map`.
+/// `dom` only tags IOTLB entries.
+pub fn bind_first_level(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, pasid: u32, dom: u16, root: Option<u64>) -> Result<(), &'static str> {
+    let c = check_pasid(system_table, seg, bus, dev, func, pasid)?;
+    if !c.flts { return Err("sm: unit lacks first-level translation"); }
+    let table = match root {
+        Some(r) => Table::First { root: r & PTE_ADDR, owned: false },
+        None => Table::First { root: alloc_page(system_table).ok_or("sm: out of memory")?, owned: true },
+    };
+    install(system_table, Binding { seg, bus, dev, func, pasid, dom, table })
+}
+
+pub fn unbind(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, pasid: u32) -> Result<(), &'static str> {
+    let b = BINDINGS.lock(|t| {
+        t.iter_mut().find(|s| matches!(s, Some(x) if same(x, seg, bus, dev, func) && x.pasid == pasid)).and_then(|s| s.take())
+    }).ok_or("sm: PASID not bound")?;
+    if let Some(u) = unit_for(system_table, seg, bus, dev, func) {
+        retire(system_table, &u, bus, dev << 3 | func, pasid);
+        flush_pasid(system_table, u.reg_base, b.dom, pasid)?;
+    }
+    if let Table::First { root, owned: true } = b.table { unsafe { free_tree(system_table, root, 4); } }
+    Ok(())
+}
+
+pub fn binding(seg: u16, bus: u8, dev: u8, func: u8, pasid: u32) -> Option<Binding> {
+    BINDINGS.lock(|t| t.iter().flatten().find(|b| same(b, seg, bus, dev, func) && b.pasid == pasid).copied())
+}
+
+pub fn for_each_binding(mut f: impl FnMut(&Binding)) {
+    let snap = BINDINGS.lock(|t| *t);
+    for b in snap.iter().flatten() { f(b); }
+}
+
+// ---- Mappings ----
+
+unsafe fn free_tree(system_table: &SystemTable<Boot>, table: u64, level: u32) {
+    if level > 1 {
+        for i in 0..512 {
+            let e = core::ptr::read_volatile((table as *const u64).add(i));
+            if e & PTE_P != 0 && e & PTE_PS == 0 { free_tree(system_table, e & PTE_ADDR, level - 1); }
+        }
+    }
+    crate::mm::uefi::free_pages(system_table, table as *mut u8, 1);
+}
+
+/// Map 4 KiB user pages into a private first-level table.
+fn fl_map(system_table: &SystemTable<Boot>, root: u64, iova: u64, pa: u64, len: u64, w: bool) -> Result<(), &'static str> {
+    let mut off = 0u64;
+    while off < len {
+        let va = iova.wrapping_add(off);
+        let mut table = root;
+        for level in (2..=4u32).rev() {
+            let e = unsafe { (table as *mut u64).add(((va >> (12 + 9 * (level - 1))) & 0x1FF) as usize) };
+            let v = unsafe { core::ptr::read_volatile(e) };
+            table = if v & PTE_P == 0 {
+                let p = alloc_page(system_table).ok_or("sm: out of memory")?;
+                unsafe { core::ptr::write_volatile(e, p | PTE_P | PTE_RW | PTE_US); }
+                p
+            } else {
+                v & PTE_ADDR
+            };
+        }
+        let flags = PTE_P | PTE_US | if w { PTE_RW } else { 0 };
+        unsafe { core::ptr::write_volatile((table as *mut u64).add(((va >> 12) & 0x1FF) as usize), (pa.wrapping_add(off) & PTE_ADDR) | flags); }
+        off = off.wrapping_add(4096);
+    }
+    Ok(())
+}
+
+fn fl_unmap(root: u64, iova: u64, len: u64) {
+    let mut off = 0u64;
+    while off < len {
+        let va = iova.wrapping_add(off);
+        let mut table = root;
+        let mut present = true;
+        for level in (2..=4u32).rev() {
+            let v = unsafe { core::ptr::read_volatile((table as *const u64).add(((va >> (12 + 9 * (level - 1))) & 0x1FF) as usize)) };
+            if v & PTE_P == 0 { present = false; break; }
+            table = v & PTE_ADDR;
+        }
+        if present { unsafe { core::ptr::write_volatile((table as *mut u64).add(((va >> 12) & 0x1FF) as usize), 0); } }
+        off = off.wrapping_add(4096);
+    }
+}
+
+/// Map `len` bytes at `iova` in the address space of a bound PASID. Second-level
+/// bindings map into their domain, exactly like `dom map`; shared first-level
+/// tables belong to their owner and are refused.
+pub fn map(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, pasid: u32, iova: u64, pa: u64, len: u64, w: bool) -> Result<(), &'static str> {
+    if (iova | pa | len) & 0xFFF != 0 || len == 0 { return Err("sm: iova, pa and len must be 4 KiB aligned"); }
+    let b = binding(seg, bus, dev, func, pasid).ok_or("sm: PASID not bound")?;
+    match b.table {
+        Table::Second => {
+            if !crate::iommu::state::add_mapping(b.dom, iova, pa, len, true, w, false) { return Err("sm: mapping table full"); }
+            super::vtd::apply_mappings(system_table);
+        }
+        Table::First { root, owned: true } => fl_map(system_table, root, iova, pa, len, w)?,
+        Table::First { owned: false, .. } => return Err("sm: shared address space is managed by its owner"),
+    }
+    if let Some(u) = unit_for(system_table, seg, bus, dev, func) { flush_pasid(system_table, u.reg_base, b.dom, pasid)?; }
+    Ok(())
+}
+
+pub fn unmap(system_table: &mut SystemTable<Boot>, seg: u16, bus: u8, dev: u8, func: u8, pasid: u32, iova: u64, len: u64) -> Result<(), &'static str> {
+    let b = binding(seg, bus, dev, func, pasid).ok_or("sm: PASID not bound")?;
+    match b.table {
+        Table::Second => {
+            if !crate::iommu::state::remove_mapping(b.dom, iova, len) { return Err("sm: no such mapping"); }
+            super::vtd::unmap_range(system_table, b.dom, iova, len);
+        }
+        Table::First { root, owned: true } => fl_unmap(root, iova, len),
+        Table::First { owned: false, .. } => return Err("sm: shared address space is managed by its owner"),
+    }
+    if let Some(u) = unit_for(system_table, seg, bus, dev, func) { flush_pasid(system_table, u.reg_base, b.dom, pasid)?; }
+    Ok(())
+}
+
+/// Software walk of a bound PASID's table: (pa, writable).
+pub fn translate(seg: u16, bus: u8, dev: u8, func: u8, pasid: u32, iova: u64) -> Option<(u64, bool)> {
+    let b = binding(seg, bus, dev, func, pasid)?;
+    let mut table = match b.table {
+        Table::Second => super::vtd::get_domain_slptptr(b.dom)?,
+        Table::First { root, .. } => root,
+    };
+    for level in (1..=4u32).rev() {
+        let shift = 12 + 9 * (level - 1);
+        let e = unsafe { core::ptr::read_volatile((table as *const u64).add(((iova >> shift) & 0x1FF) as usize)) };
+        if e & PTE_P == 0 { return None; }
+        if level == 1 || (level <= 3 && e & PTE_PS != 0) {
+            let mask = (1u64 << shift) - 1;
+            return Some(((e & PTE_ADDR & !mask) | (iova & mask), e & PTE_RW != 0));
+        }
+        table = e & PTE_ADDR;
+    }
+    None
+}
+
+// ---- Reporting ----
+
+pub fn report(system_table: &mut SystemTable<Boot>) {
+    let mut any = false;
+    super::vtd::for_each_unit_reg(|seg, reg_base| {
+        any = true;
+        let c = caps(reg_base);
+        let mut buf = [0u8; 160]; let mut n = 0;
+        for &b in b"SM: seg=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(seg as u32, &mut buf[n..]);
+        for &b in b" reg=0x" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_hex(reg_base, &mut buf[n..]);
+        let flag = |buf: &mut [u8], n: &mut usize, name: &[u8], on: bool| {
+            for &b in name { buf[*n] = b; *n += 1; }
+            buf[*n] = if on { b'1' } else { b'0' }; *n += 1;
+        };
+        flag(&mut buf, &mut n, b" smts=", c.smts);
+        flag(&mut buf, &mut n, b" pasid=", c.pasid);
+        for &b in b" pss=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(c.pasid_bits as u64, &mut buf[n..]);
+        flag(&mut buf, &mut n, b" flts=", c.flts);
+        flag(&mut buf, &mut n, b" slts=", c.slts);
+        flag(&mut buf, &mut n, b" srs=", c.srs);
+        flag(&mut buf, &mut n, b" scalable=", is_scalable(reg_base));
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    });
+    if !any { let _ = system_table.stdout().write_str("SM: no VT-d units initialized\r\n"); }
+    for_each_binding(|b| {
+        let mut buf = [0u8; 128]; let mut n = 0;
+        for &c in b"pasid: " { buf[n] = c; n += 1; }
+        n += crate::util::format::u64_hex(b.seg as u64, &mut buf[n..]);
+        buf[n] = b':'; n += 1;
+        n += crate::util::format::u64_hex(b.bus as u64, &mut buf[n..]);
+        buf[n] = b':'; n += 1;
+        n += crate::util::format::u64_hex(b.dev as u64, &mut buf[n..]);
+        buf[n] = b'.'; n += 1;
+        n += crate::util::format::u64_dec(b.func as u64, &mut buf[n..]);
+        for &c in b" pasid=" { buf[n] = c; n += 1; }
+        n += crate::util::format::u64_dec(b.pasid as u64, &mut buf[n..]);
+        for &c in b" dom=" { buf[n] = c; n += 1; }
+        n += crate::util::format::u64_dec(b.dom as u64, &mut buf[n..]);
+        match b.table {
+            Table::Second => { for &c in b" second-level" { buf[n] = c; n += 1; } }
+            Table::First { root, owned } => {
+                for &c in if owned { &b" first-level private root=0x"[..] } else { &b" first-level shared root=0x"[..] } { buf[n] = c; n += 1; }
+                n += crate::util::format::u64_hex(root, &mut buf[n..]);
+            }
+        }
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    });
+}
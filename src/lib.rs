@@ -1,5 +1,7 @@
 #![no_std]
 
+extern crate alloc;
+
 pub mod arch;
 pub mod firmware;
 pub mod i18n;
@@ -13,5 +15,10 @@ pub mod hv;
 pub mod obs;
 pub mod diag;
 pub mod migrate;
+pub mod tpm;
+pub mod nvme;
+pub mod cluster;
 
-
+/// UEFI pool while Boot Services are up, then a reserved arena; see `mm::heap`.
+#[global_allocator]
+static HEAP: mm::heap::Heap = mm::heap::Heap;
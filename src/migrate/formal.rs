@@ -0,0 +1,284 @@
+#![allow(dead_code)]
+
+//! Verification harness for the dirty bitmap and the ZMIG frame codec.
+//!
+//! The properties are plain functions over caller-supplied inputs, so the
+//! same checks run in two ways:
+//!
+//! - Under Kani (`cargo kani --features formal_verification`) the harnesses
+//!   in `proofs` feed them symbolic inputs, which proves them for every
+//!   input up to the sizes the harnesses fix. Loops are unwound that far.
+//! - On the target, `run` feeds them pseudo-random inputs at full size
+//!   (4 KiB pages, runs longer than 255 bytes), which is what
+//!   `migrate formal` does.
+//!
+//! What is checked:
+//! - `DirtyBitmap`: after `set_bit` over any indices, `count_set` equals the
+//!   number of distinct in-range indices, `for_each_set` yields exactly those
+//!   in ascending order, and out-of-range indices touch nothing outside the
+//!   bitmap.
+//! - `FrameHeader`: for versions 1 and 2, `parse` of the `wire_len` bytes
+//!   `to_bytes` gives back every field the version carries, `to_bytes`
+//!   matches the packed in-memory layout, and input without the magic, of
+//!   another version, or shorter than its version's header is refused.
+//! - TLV areas: `tlv_put` output is accepted and found again by `tlv_find`,
+//!   and an unknown critical type makes the area unacceptable.
+//! - RLE page payloads: encoding never needs more than two bytes per input
+//!   byte, and decoding the pairs restores the input exactly, consuming all
+//!   of them.
+
+use super::*;
+
+/// Bitmap bytes `run` checks (4096 pages)
+const BITMAP_BYTES: usize = 512;
+/// Indices set per bitmap case
+const BITMAP_INDICES: usize = 64;
+
+/// Outcome of `run`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Report {
+    pub bitmap_cases: u64,
+    pub header_cases: u64,
+    pub tlv_cases: u64,
+    pub rle_cases: u64,
+    /// First property that failed, if any
+    pub failed: Option<&'static str>,
+    /// Iteration it failed in
+    pub failed_at: u64,
+}
+
+// ---- Properties ----
+
+/// Set `indices` in a bitmap over `buf[..bytes]` and check it against a
+/// direct count. `buf` must be longer than `bytes`; the tail is a guard.
+pub fn bitmap_consistent(buf: &mut [u8], bytes: usize, indices: &[u64]) -> Result<(), &'static str> {
+    if bytes >= buf.len() { return Err("formal: bitmap needs a guard byte"); }
+    // The guard is all ones while clearing and all zeros while setting bits
+    buf[bytes..].fill(0xFF);
+    let mut bm = DirtyBitmap { base: buf.as_mut_ptr() as usize, bytes, pages: 0 };
+    bm.clear_all();
+    if buf[bytes..].iter().any(|&b| b != 0xFF) { return Err("formal: clear_all wrote past the bitmap"); }
+    buf[bytes..].fill(0);
+    let mut bm = DirtyBitmap { base: buf.as_mut_ptr() as usize, bytes, pages: 0 };
+    for &i in indices { bm.set_bit(i); }
+    if buf[bytes..].iter().any(|&b| b != 0) { return Err("formal: set_bit wrote past the bitmap"); }
+    let bm = DirtyBitmap { base: buf.as_mut_ptr() as usize, bytes, pages: 0 };
+    let limit = bytes as u64 * 8;
+    // Distinct in-range indices, counted without the bitmap
+    let mut expected = 0u64;
+    for (k, &i) in indices.iter().enumerate() {
+        if i < limit && !indices[..k].contains(&i) { expected += 1; }
+    }
+    if bm.count_set() != expected { return Err("formal: count_set differs from the indices set"); }
+    let mut seen = 0u64;
+    let mut prev: Option<u64> = None;
+    let mut bad = None;
+    bm.for_each_set(|i| {
+        seen += 1;
+        if prev.is_some_and(|p| p >= i) { bad = Some("formal: for_each_set out of order"); }
+        if i >= limit || !indices.contains(&i) { bad = Some("formal: for_each_set yielded an index never set"); }
+        prev = Some(i);
+    });
+    if let Some(e) = bad { return Err(e); }
+    if seen != expected { return Err("formal: for_each_set and count_set disagree"); }
+    Ok(())
+}
+
+/// Round-trip one header through its wire form. Versions other than 1 and
+/// 2 must be refused.
+#[allow(clippy::too_many_arguments)]
+pub fn header_roundtrip(ver: u8, typ: u8, flags: u16, seq: u32, page_index: u64, payload_len: u32, crc32: u32, ext_len: u16) -> Result<(), &'static str> {
+    let h = FrameHeader { magic: MAGIC, ver, typ, flags, seq, page_index, payload_len, crc32, ext_len, _rsvd: 0 };
+    let b = h.to_bytes();
+    let raw = unsafe { core::slice::from_raw_parts((&h as *const FrameHeader) as *const u8, FrameHeader::LEN) };
+    if b[..] != raw[..] { return Err("formal: header bytes differ from the packed layout"); }
+    let len = h.wire_len();
+    let parsed = FrameHeader::parse(&b[..len]);
+    if ver != VER1 && ver != VER2 {
+        return if parsed.is_some() { Err("formal: unknown version accepted") } else { Ok(()) };
+    }
+    let Some(p) = parsed else { return Err("formal: encoded header refused") };
+    let ext_len = if ver == VER1 { 0 } else { ext_len };
+    if (p.ver, p.typ, { p.flags }, { p.seq }, { p.page_index }, { p.payload_len }, { p.crc32 }, { p.ext_len }) != (ver, typ, flags, seq, page_index, payload_len, crc32, ext_len) {
+        return Err("formal: header fields changed in the round trip");
+    }
+    if p.wire_len() != len { return Err("formal: parsed header has another length"); }
+    if FrameHeader::parse(&b[..len - 1]).is_some() { return Err("formal: short header accepted"); }
+    Ok(())
+}
+
+/// Put a TLV of type `t` with `value` and check it reads back, and that a
+/// receiver knowing no types accepts it exactly when it is not critical.
+pub fn tlv_roundtrip(t: u8, value: &[u8], buf: &mut [u8]) -> Result<(), &'static str> {
+    let Some(end) = tlv_put(buf, 0, t, value) else {
+        return if value.len() > 255 || 2 + value.len() > buf.len() { Ok(()) } else { Err("formal: tlv did not fit") };
+    };
+    if end != 2 + value.len() { return Err("formal: tlv length wrong"); }
+    if tlv_find(&buf[..end], t) != Some(value) { return Err("formal: tlv not found again"); }
+    if tlv_acceptable(&buf[..end], |_| false) != (t & TLV_CRITICAL == 0) { return Err("formal: tlv criticality ignored"); }
+    if end > 2 && tlv_acceptable(&buf[..end - 1], |_| true) { return Err("formal: truncated tlv accepted"); }
+    Ok(())
+}
+
+/// A header whose first four bytes are `magic` parses only if they are
+/// `MAGIC` and the version after them is known.
+pub fn header_magic(magic: [u8; 4], rest: &[u8]) -> Result<(), &'static str> {
+    let mut b = [0u8; FrameHeader::LEN];
+    b[..4].copy_from_slice(&magic);
+    let n = rest.len().min(FrameHeader::LEN - 4);
+    b[4..4 + n].copy_from_slice(&rest[..n]);
+    if FrameHeader::parse(&b).is_some() != (magic == MAGIC && matches!(b[4], VER1 | VER2)) { return Err("formal: magic check wrong"); }
+    Ok(())
+}
+
+/// Encode `src`, decode into `dst` (same length) and compare. `enc` must
+/// hold `2 * src.len()` bytes.
+pub fn rle_roundtrip(src: &[u8], enc: &mut [u8], dst: &mut [u8]) -> Result<(), &'static str> {
+    if enc.len() < 2 * src.len() || dst.len() != src.len() { return Err("formal: rle buffers too small"); }
+    let Some(n) = (unsafe { rle_encode(src.as_ptr(), src.len(), &mut enc[..2 * src.len()]) }) else {
+        return Err("formal: rle needed more than two bytes per byte");
+    };
+    if n % 2 != 0 { return Err("formal: rle output is not whole pairs"); }
+    if enc[..n].chunks(2).any(|p| p[1] == 0) { return Err("formal: rle emitted an empty run"); }
+    let mut at = 0usize;
+    let ok = rle_decode(|| {
+        if at + 2 > n { return None; }
+        let p = [enc[at], enc[at + 1]];
+        at += 2;
+        Some(p)
+    }, dst);
+    if !ok { return Err("formal: rle output did not decode"); }
+    if at != n { return Err("formal: rle decode left pairs over"); }
+    if dst != src { return Err("formal: rle round trip changed the data"); }
+    Ok(())
+}
+
+// ---- Target-side runner ----
+
+#[inline(always)]
+fn xorshift(mut x: u64) -> u64 {
+    x ^= x << 13; x ^= x >> 7; x ^= x << 17; x
+}
+
+/// Fill `page` with runs of random bytes and random lengths, biased
+/// towards long runs so the 255-byte run limit is crossed.
+fn fill_runs(page: &mut [u8], mut x: u64) -> u64 {
+    let mut i = 0;
+    while i < page.len() {
+        x = xorshift(x);
+        let len = match x % 4 { 0 => 1, 1 => (x >> 8) as usize % 16 + 1, 2 => (x >> 8) as usize % 300 + 1, _ => (x >> 8) as usize % 1200 + 1 };
+        let v = if (x >> 40) % 3 == 0 { 0 } else { (x >> 32) as u8 };
+        let end = (i + len).min(page.len());
+        page[i..end].fill(v);
+        i = end;
+    }
+    x
+}
+
+/// Check every property `iterations` times with inputs derived from `seed`.
+/// Stops at the first failure.
+pub fn run(iterations: u64, seed: u64) -> Report {
+    let mut r = Report::default();
+    let mut x = seed | 1;
+    let mut bits = [0u8; BITMAP_BYTES + 8];
+    let mut src = [0u8; 4096];
+    let mut enc = [0u8; 8192];
+    let mut dst = [0u8; 4096];
+    for it in 0..iterations {
+        // Bitmap: indices mostly in range, some just past it, a few anywhere
+        let mut idx = [0u64; BITMAP_INDICES];
+        let bytes = 1 + (xorshift(x) as usize % BITMAP_BYTES);
+        for k in 0..idx.len() {
+            x = xorshift(x);
+            idx[k] = match x % 8 { 0 => x >> 3, 1 => bytes as u64 * 8 + (x >> 3) % 16, 2 => idx[k / 2], _ => (x >> 3) % (bytes as u64 * 8) };
+        }
+        if let Err(e) = bitmap_consistent(&mut bits, bytes, &idx) { r.failed = Some(e); r.failed_at = it; break; }
+        r.bitmap_cases += 1;
+
+        // Headers: random fields, mostly a known version, then a random magic
+        let f = [xorshift(x), xorshift(x ^ 0x9E37_79B9_7F4A_7C15), xorshift(x.rotate_left(17))];
+        x = f[2];
+        let ver = match f[0] % 4 { 0 => VER1, 1 | 2 => VER2, _ => (f[0] >> 56) as u8 };
+        let res = header_roundtrip(ver, (f[0] >> 8) as u8, (f[0] >> 16) as u16, (f[0] >> 32) as u32, f[1], f[2] as u32, (f[2] >> 32) as u32, (f[1] >> 48) as u16)
+            .and_then(|_| header_magic(if f[1] & 1 == 0 { MAGIC } else { (f[1] as u32).to_le_bytes() }, &f[2].to_le_bytes()));
+        if let Err(e) = res { r.failed = Some(e); r.failed_at = it; break; }
+        r.header_cases += 1;
+
+        // TLVs: random type and value, occasionally too big for the buffer
+        x = xorshift(x);
+        let vlen = (x >> 8) as usize % 40;
+        let mut tb = [0u8; 34];
+        if let Err(e) = tlv_roundtrip(x as u8, &src[..vlen], &mut tb) { r.failed = Some(e); r.failed_at = it; break; }
+        r.tlv_cases += 1;
+
+        // RLE: a full page, and a prefix of random length
+        x = fill_runs(&mut src, x);
+        let cut = (x >> 16) as usize % src.len() + 1;
+        let res = rle_roundtrip(&src, &mut enc, &mut dst).and_then(|_| rle_roundtrip(&src[..cut], &mut enc, &mut dst[..cut]));
+        if let Err(e) = res { r.failed = Some(e); r.failed_at = it; break; }
+        r.rle_cases += 2;
+    }
+    r
+}
+
+// ---- Kani harnesses ----
+
+#[cfg(kani)]
+mod proofs {
+    use super::*;
+
+    #[kani::proof]
+    #[kani::unwind(9)]
+    fn bitmap_set_count_iterate() {
+        let mut buf = [0u8; 3];
+        let bytes: usize = kani::any();
+        kani::assume(bytes >= 1 && bytes <= 2);
+        let idx: [u64; 3] = kani::any();
+        assert!(bitmap_consistent(&mut buf, bytes, &idx).is_ok());
+    }
+
+    #[kani::proof]
+    fn frame_header_roundtrip() {
+        assert!(header_roundtrip(kani::any(), kani::any(), kani::any(), kani::any(), kani::any(), kani::any(), kani::any(), kani::any()).is_ok());
+    }
+
+    #[kani::proof]
+    #[kani::unwind(8)]
+    fn tlv_put_find() {
+        let value: [u8; 4] = kani::any();
+        let len: usize = kani::any();
+        kani::assume(len <= value.len());
+        let mut buf = [0u8; 6];
+        assert!(tlv_roundtrip(kani::any(), &value[..len], &mut buf).is_ok());
+    }
+
+    #[kani::proof]
+    fn frame_header_magic() {
+        let rest: [u8; 8] = kani::any();
+        assert!(header_magic(kani::any(), &rest).is_ok());
+    }
+
+    #[kani::proof]
+    #[kani::unwind(10)]
+    fn rle_payload_roundtrip() {
+        let src: [u8; 8] = kani::any();
+        let len: usize = kani::any();
+        kani::assume(len <= src.len());
+        let mut enc = [0u8; 16];
+        let mut dst = [0u8; 8];
+        assert!(rle_roundtrip(&src[..len], &mut enc, &mut dst[..len]).is_ok());
+    }
+
+    /// Any pair stream either fills the page exactly or is refused; it never
+    /// writes past the destination.
+    #[kani::proof]
+    #[kani::unwind(10)]
+    fn rle_decode_bounded() {
+        let pairs: [[u8; 2]; 4] = kani::any();
+        let mut dst = [0u8; 8];
+        let mut k = 0usize;
+        let mut sum = 0usize;
+        let ok = rle_decode(|| { let p = pairs.get(k).copied(); k += 1; if let Some(p) = p { sum += p[1] as usize; } p }, &mut dst);
+        if ok { assert!(sum == dst.len()); }
+    }
+}
@@ -19,7 +19,10 @@
 //!   flags will not be toggled by hardware yet. The tracker and scanner are
 //!   designed to be correct and ready for future long-running guests.
 //!
-//! All code paths are `no_std` and safe for early-boot usage.
+//! All code paths are `no_std` and safe for early-boot usage. Control-plane
+//! state (tracker, channel buffer, sequence counter, transmit log, network
+//! settings) lives in one `MigrationState` behind a spinlock; the functions
+//! below are its accessors.
 
 use core::ptr::read_volatile;
 use core::ptr::write_volatile;
@@ -27,9 +30,18 @@ use core::fmt::Write as _; // enable write_str on UEFI text output
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
 use uefi::table::boot::MemoryType;
-use core::mem::size_of;
 use uefi::table::runtime::VariableVendor;
 
+use crate::util::spinlock::SpinLock;
+use wire::*;
+
+pub mod rdma;
+pub mod ring;
+pub mod selftest;
+pub mod wire;
+#[cfg(any(kani, feature = "formal_verification"))]
+pub mod formal;
+
 /// Kind of nested translation used by the VM.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TrackerKind { IntelEpt, AmdNpt, Unknown }
@@ -45,7 +57,7 @@ pub struct DirtyTracker {
 
 /// Compact bitset stored in UEFI-allocated pages.
 pub struct DirtyBitmap {
-    base: *mut u8,
+    base: usize,
     bytes: usize,
     pages: usize,
 }
@@ -57,19 +69,19 @@ impl DirtyBitmap {
         let pages = (bytes + 4095) / 4096;
         let ptr = crate::mm::uefi::alloc_pages(system_table, pages, uefi::table::boot::MemoryType::LOADER_DATA)?;
         unsafe { core::ptr::write_bytes(ptr, 0, pages * 4096); }
-        Some(Self { base: ptr, bytes, pages })
+        Some(Self { base: ptr as usize, bytes, pages })
     }
 
     /// Free underlying storage.
     pub fn free(self, system_table: &SystemTable<Boot>) {
         unsafe {
-            crate::mm::uefi::free_pages(system_table, self.base, self.pages);
+            crate::mm::uefi::free_pages(system_table, self.base as *mut u8, self.pages);
         }
     }
 
     #[inline(always)]
     pub fn clear_all(&mut self) {
-        unsafe { core::ptr::write_bytes(self.base, 0, self.bytes); }
+        unsafe { core::ptr::write_bytes(self.base as *mut u8, 0, self.bytes); }
     }
 
     #[inline(always)]
@@ -79,7 +91,7 @@ impl DirtyBitmap {
         let bit = i & 7;
         if byte < self.bytes {
             unsafe {
-                let p = self.base.add(byte);
+                let p = (self.base as *mut u8).add(byte);
                 let v = read_volatile(p);
                 write_volatile(p, v | (1u8 << bit));
             }
@@ -91,7 +103,7 @@ impl DirtyBitmap {
         let mut total: u64 = 0;
         let mut i = 0;
         while i < self.bytes {
-            let v = unsafe { read_volatile(self.base.add(i)) } as u64;
+            let v = unsafe { read_volatile((self.base as *const u8).add(i)) } as u64;
             total += v.count_ones() as u64;
             i += 1;
         }
@@ -103,7 +115,7 @@ impl DirtyBitmap {
         let mut byte_index = 0usize;
         let mut base_bit: u64 = 0;
         while byte_index < self.bytes {
-            let v = unsafe { read_volatile(self.base.add(byte_index)) };
+            let v = unsafe { read_volatile((self.base as *const u8).add(byte_index)) };
             if v != 0 {
                 let mut mask = v;
                 let mut bit = 0u8;
@@ -125,19 +137,195 @@ struct TrackerState {
     bitmap: DirtyBitmap,
 }
 
-static mut G_TRACKER: Option<TrackerState> = None;
-static mut G_SEQ: u32 = 1;
-static mut G_CHUNK: usize = 1500; // default MTU-like chunk size for writers
-static mut SESSION_START_TSC: u64 = 0;
 // Transmit log for resend operations
 #[derive(Clone, Copy)]
 struct TxEntry { kind: u8, seq: u32, page_index: u64 }
 const TX_LOG_CAP: usize = 1024;
-static mut TX_LOG: [TxEntry; TX_LOG_CAP] = [TxEntry { kind: 0, seq: 0, page_index: 0 }; TX_LOG_CAP];
-static mut TX_WIDX: usize = 0;
-#[inline(always)]
-unsafe fn tx_log_append(kind: u8, seq: u32, page_index: u64) {
-    let i = TX_WIDX % TX_LOG_CAP; TX_LOG[i] = TxEntry { kind, seq, page_index }; TX_WIDX = TX_WIDX.wrapping_add(1);
+const TX_EMPTY: TxEntry = TxEntry { kind: 0, seq: 0, page_index: 0 };
+
+#[cfg(feature = "snp")]
+const SNP_MAX: usize = 16;
+
+/// Watermark callback: the crossing, then the ring's fill level and size.
+pub type ChanWatcher = fn(ring::Mark, usize, usize);
+const CHAN_WATCHERS: usize = 4;
+/// Timeout retransmits of one window before giving up on the peer
+const RETX_TRIES_MAX: u8 = 5;
+
+/// Everything the control plane keeps between commands.
+///
+/// Critical sections are short and never nest: writers call back into this
+/// module (the buffer sink writes the channel, every frame takes a sequence
+/// number), so paths that walk the dirty bitmap or the channel buffer check
+/// the tracker out (`with_tracker`) or take a cursor (`chan_cursor`) first
+/// and run with the lock released.
+struct MigrationState {
+    tracker: Option<TrackerState>,
+    /// The tracker is checked out by `with_tracker`
+    tracker_busy: bool,
+    /// Channel ring in UEFI pages. Once installed the pages are never
+    /// freed, so a `ChanCursor` taken from it stays valid after the lock is
+    /// released.
+    buf: Option<ring::Ring>,
+    /// Applied to each new channel ring
+    chan_overflow: ring::Overflow,
+    chan_high_pct: u8,
+    chan_low_pct: u8,
+    /// Called on channel watermark crossings, outside the lock
+    chan_watchers: [Option<ChanWatcher>; CHAN_WATCHERS],
+    /// Sequence number of the next frame
+    seq: u32,
+    /// Chunk size for writers (MTU-like)
+    chunk: usize,
+    /// `clock::now_ns` at `session_start` (0 = none)
+    session_start_ns: u64,
+    tx_log: [TxEntry; TX_LOG_CAP],
+    tx_widx: usize,
+    dest_mac: [u8; 6],
+    /// Network MTU hint (payload chunking uses `chunk` by default)
+    mtu: usize,
+    /// Experimental EtherType for migration frames
+    ether_type: u16,
+    /// Where a ctrl NAK resends to
+    ctrl_resend_sink: ExportSink,
+    ctrl_auto_ack: bool,
+    ctrl_auto_nak: bool,
+    /// Resend unacknowledged frames after this long; 0 waits for a NAK
+    retx_ms: u64,
+    retx_timer: Option<crate::time::timer::TimerId>,
+    /// The retransmit timer expired; `retransmit_pump` resends
+    retx_due: bool,
+    /// Oldest frame not yet covered by an ACK
+    unacked: Option<u32>,
+    retx_tries: u8,
+    default_sink: ExportSink,
+    /// Frame version we send; 2 only after a hello from a peer that speaks it
+    wire_ver: u8,
+    /// Last hello received
+    peer: Option<Hello>,
+    /// Guest NUMA layout of the tracked VM, for page node hints
+    numa: Option<crate::hv::numa::Layout>,
+    #[cfg(feature = "snp")]
+    snp_handles: [usize; SNP_MAX],
+    #[cfg(feature = "snp")]
+    snp_len: usize,
+    #[cfg(feature = "snp")]
+    snp_sel: Option<usize>,
+}
+
+impl MigrationState {
+    const fn new() -> Self {
+        MigrationState {
+            tracker: None,
+            tracker_busy: false,
+            buf: None,
+            chan_overflow: ring::Overflow::DropOldest,
+            chan_high_pct: 75,
+            chan_low_pct: 25,
+            chan_watchers: [None; CHAN_WATCHERS],
+            seq: 1,
+            chunk: 1500,
+            session_start_ns: 0,
+            tx_log: [TX_EMPTY; TX_LOG_CAP],
+            tx_widx: 0,
+            dest_mac: [0; 6],
+            mtu: 1500,
+            ether_type: 0x88B5,
+            ctrl_resend_sink: ExportSink::Buffer,
+            ctrl_auto_ack: false,
+            ctrl_auto_nak: false,
+            retx_ms: 0,
+            retx_timer: None,
+            retx_due: false,
+            unacked: None,
+            retx_tries: 0,
+            default_sink: ExportSink::Buffer,
+            wire_ver: VER1,
+            peer: None,
+            numa: None,
+            #[cfg(feature = "snp")]
+            snp_handles: [0; SNP_MAX],
+            #[cfg(feature = "snp")]
+            snp_len: 0,
+            #[cfg(feature = "snp")]
+            snp_sel: None,
+        }
+    }
+}
+
+static STATE: SpinLock<MigrationState> = SpinLock::new(MigrationState::new());
+
+fn tx_log_append(kind: u8, seq: u32, page_index: u64) {
+    let arm = STATE.lock(|s| {
+        let i = s.tx_widx % TX_LOG_CAP;
+        s.tx_log[i] = TxEntry { kind, seq, page_index };
+        s.tx_widx = s.tx_widx.wrapping_add(1);
+        if s.retx_ms == 0 || s.unacked.is_some() { return false; }
+        s.unacked = Some(seq);
+        true
+    });
+    if arm { retx_arm(); }
+}
+
+/// (Re)start the retransmit timer. Called with the state unlocked, since
+/// the timer wheel has a lock of its own.
+fn retx_arm() {
+    let (old, ms) = STATE.lock(|s| (s.retx_timer.take(), s.retx_ms));
+    if let Some(id) = old { crate::time::timer::cancel(id); }
+    if ms == 0 { return; }
+    match crate::time::timer::add("mig-retx", ms, 0, retx_expired, 0) {
+        Ok(id) => STATE.lock(|s| s.retx_timer = Some(id)),
+        Err(e) => crate::log!(Warn, "migrate", "retransmit timer not armed: {}", e),
+    }
+}
+
+fn retx_expired(_: u64) {
+    STATE.lock(|s| { s.retx_timer = None; s.retx_due = s.unacked.is_some(); });
+}
+
+/// An ACK covers `seq` and every frame before it. Moves the window on and
+/// restarts the timer if frames after it are still outstanding.
+fn on_ack(seq: u32) {
+    let (old, rearm) = STATE.lock(|s| {
+        let Some(from) = s.unacked else { return (None, false) };
+        // Wrapping compare: an ACK from before the window is stale
+        if seq.wrapping_sub(from) >= 1 << 31 { return (None, false); }
+        let next = seq.wrapping_add(1);
+        s.unacked = if next.wrapping_sub(from) >= s.seq.wrapping_sub(from) { None } else { Some(next) };
+        s.retx_tries = 0;
+        s.retx_due = false;
+        (s.retx_timer.take(), s.unacked.is_some())
+    });
+    if let Some(id) = old { crate::time::timer::cancel(id); }
+    if rearm { retx_arm(); }
+}
+
+/// Absolute indexes of the oldest and one past the newest logged frame.
+fn tx_window() -> (usize, usize) { STATE.lock(|s| (s.tx_widx.saturating_sub(TX_LOG_CAP), s.tx_widx)) }
+
+fn tx_entry(idx: usize) -> TxEntry { STATE.lock(|s| s.tx_log[idx % TX_LOG_CAP]) }
+
+/// Take the sequence number for a new frame.
+fn next_seq() -> u32 {
+    STATE.lock(|s| { let seq = s.seq; s.seq = s.seq.wrapping_add(1); seq })
+}
+
+fn peek_seq() -> u32 { STATE.lock(|s| s.seq) }
+
+/// Run `f` on the active tracker with the lock released. The tracker is
+/// taken out for the duration, so `start_tracking` and `stop_tracking` fail
+/// rather than free the bitmap under `f`. None if there is no tracker or it
+/// is already checked out.
+fn with_tracker<R>(f: impl FnOnce(&mut TrackerState) -> R) -> Option<R> {
+    let mut state = STATE.lock(|s| {
+        if s.tracker_busy { return None; }
+        let t = s.tracker.take();
+        s.tracker_busy = t.is_some();
+        t
+    })?;
+    let r = f(&mut state);
+    STATE.lock(|s| { s.tracker = Some(state); s.tracker_busy = false; });
+    Some(r)
 }
 
 /// Create a tracker for the given VM with identity map already built.
@@ -157,15 +345,38 @@ pub fn start_tracking(system_table: &SystemTable<Boot>, vm: &crate::hv::vm::Vm)
     let tracker = match create_tracker_for_vm(vm) { Some(t) => t, None => return false };
     let pages = (tracker.memory_limit + 4095) / 4096; // 4KiB pages in scope
     let bitmap = match DirtyBitmap::allocate(system_table, pages) { Some(b) => b, None => return false };
-    unsafe { G_TRACKER = Some(TrackerState { tracker, bitmap }); }
+    // A registered VM must be in a state a migration can start from.
+    let again = crate::hv::vm::find_vm(vm.id.0).is_some_and(|v| v.state == crate::hv::vm::VmState::Migrating);
+    if !again && crate::hv::vm::begin_migration(vm.id.0).is_err() { bitmap.free(system_table); return false; }
+    let installed = STATE.lock(|s| {
+        if s.tracker_busy { return Err(bitmap); }
+        Ok(s.tracker.replace(TrackerState { tracker, bitmap }))
+    });
+    match installed {
+        Ok(prev) => if let Some(prev) = prev {
+            if prev.tracker.vm_id != vm.id.0 { crate::hv::vm::end_migration(prev.tracker.vm_id); }
+            prev.bitmap.free(system_table);
+        },
+        Err(bitmap) => {
+            bitmap.free(system_table);
+            if !again { crate::hv::vm::end_migration(vm.id.0); }
+            return false;
+        }
+    }
+    let numa = crate::hv::numa::layout_of(vm.id.0);
+    STATE.lock(|s| s.numa = numa);
     crate::diag::audit::record(crate::diag::audit::AuditKind::MigrateStart(vm.id.0));
     crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_SESSIONS).inc();
     true
 }
 
 pub fn start_tracking_by_id(system_table: &SystemTable<Boot>, id: u64) -> bool {
+    if !crate::hv::confidential::allows(id, crate::hv::confidential::Feature::Migrate) { return false; }
+    // The destination must be cleared for everything the VM holds
+    let dest = crate::hv::info_flow::Object::Dest(net_get_dest_mac());
+    if crate::hv::info_flow::check(crate::hv::info_flow::Object::Vm(id), dest).is_err() { return false; }
     if let Some(info) = crate::hv::vm::find_vm(id) {
-        let vm = crate::hv::vm::Vm { id: crate::hv::vm::VmId(info.id), config: crate::hv::vm::VmConfig { memory_bytes: info.memory_bytes, vcpu_count: 1 }, vendor: info.vendor, pml4_phys: info.pml4_phys };
+        let vm = crate::hv::vm::Vm { id: crate::hv::vm::VmId(info.id), config: crate::hv::vm::VmConfig { memory_bytes: info.memory_bytes, vcpu_count: 1, ..Default::default() }, vendor: info.vendor, pml4_phys: info.pml4_phys };
         return start_tracking(system_table, &vm);
     }
     false
@@ -173,20 +384,27 @@ pub fn start_tracking_by_id(system_table: &SystemTable<Boot>, id: u64) -> bool {
 
 /// Stop tracking and free resources if any.
 pub fn stop_tracking(system_table: &SystemTable<Boot>) -> bool {
-    let st = unsafe { G_TRACKER.take() };
+    let st = STATE.lock(|s| if s.tracker_busy { None } else { s.numa = None; s.tracker.take() });
     if let Some(state) = st {
         state.bitmap.free(system_table);
+        crate::hv::vm::end_migration(state.tracker.vm_id);
         crate::diag::audit::record(crate::diag::audit::AuditKind::MigrateStop(state.tracker.vm_id));
         return true;
     }
     false
 }
 
+/// VM being tracked and its dirty-page count, while a migration is in progress.
+pub fn tracking() -> Option<(u64, u64)> {
+    with_tracker(|st| (st.tracker.vm_id, st.bitmap.count_set()))
+}
+
 /// Perform one scan round. Returns number of dirty pages observed in this round.
 pub fn scan_round(clear_ad: bool) -> u64 {
-    let st = unsafe { G_TRACKER.as_mut() };
-    if st.is_none() { return 0; }
-    let state = st.unwrap();
+    with_tracker(|state| scan(state, clear_ad)).unwrap_or(0)
+}
+
+fn scan(state: &mut TrackerState, clear_ad: bool) -> u64 {
     let dirty = match state.tracker.kind {
         TrackerKind::IntelEpt => scan_ept(state.tracker.root_phys, state.tracker.memory_limit, &mut state.bitmap, clear_ad),
         TrackerKind::AmdNpt => scan_npt(state.tracker.root_phys, state.tracker.memory_limit, &mut state.bitmap, clear_ad),
@@ -203,17 +421,16 @@ pub fn scan_round(clear_ad: bool) -> u64 {
 pub fn dump_stats(system_table: &mut SystemTable<Boot>) {
     let stdout = system_table.stdout();
     let mut buf = [0u8; 128];
-    if let Some(st) = unsafe { G_TRACKER.as_ref() } {
+    if let Some((vm_id, total)) = with_tracker(|st| (st.tracker.vm_id, st.bitmap.count_set())) {
         let mut n = 0;
         for &b in b"migrate: vm_id=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(st.tracker.vm_id as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(vm_id as u32, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
         // Dirty pages total (bitmap popcount)
-        let total = st.bitmap.count_set();
         let mut n2 = 0;
         for &b in b"migrate: dirty_pages_total=" { buf[n2] = b; n2 += 1; }
-        n2 += crate::firmware::acpi::u32_to_dec(total as u32, &mut buf[n2..]);
+        n2 += crate::util::format::u32_dec(total as u32, &mut buf[n2..]);
         buf[n2] = b'\r'; n2 += 1; buf[n2] = b'\n'; n2 += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n2]).unwrap_or("\r\n"));
     } else {
@@ -223,7 +440,7 @@ pub fn dump_stats(system_table: &mut SystemTable<Boot>) {
 
 /// Data sink for migration export operations.
 #[derive(Clone, Copy, Debug)]
-pub enum ExportSink { Console, Null, Buffer, Snp, Virtio }
+pub enum ExportSink { Console, Null, Buffer, Snp, Virtio, Rdma }
 /// Abstract writer for migration. Future implementations can add network or storage sinks.
 pub trait MigrWriter {
     /// Write bytes; returns number written.
@@ -275,58 +492,79 @@ impl MigrWriter for NullWriter {
     fn write(&mut self, _buf: &[u8]) -> usize { 0 }
 }
 
-struct Buffer {
-    ptr: *mut u8,
-    cap: usize,
-    wpos: usize,
-    len: usize,
-}
-
-static mut G_BUF: Option<Buffer> = None;
-static mut G_DEST_MAC: [u8; 6] = [0; 6];
-static mut G_MTU: usize = 1500; // network MTU hint (payload chunking uses G_CHUNK by default)
-static mut G_ETHER_TYPE: u16 = 0x88B5; // experimental EtherType for migration frames
-static mut G_CTRL_RESEND_SINK: ExportSink = ExportSink::Buffer; // default resend target for ctrl NAK
-static mut G_CTRL_AUTO_ACK: bool = false;
-static mut G_CTRL_AUTO_NAK: bool = false;
-static mut G_DEFAULT_SINK: ExportSink = ExportSink::Buffer;
-#[cfg(feature = "snp")]
-const SNP_MAX: usize = 16;
-#[cfg(feature = "snp")]
-static mut G_SNP_HANDLES: [uefi::Handle; SNP_MAX] = [core::ptr::null_mut(); SNP_MAX];
-#[cfg(feature = "snp")]
-static mut G_SNP_LEN: usize = 0;
-#[cfg(feature = "snp")]
-static mut G_SNP_SEL_IDX: Option<usize> = None;
-
 #[inline(always)]
-pub fn net_get_dest_mac() -> [u8; 6] { unsafe { G_DEST_MAC } }
+pub fn net_get_dest_mac() -> [u8; 6] { STATE.lock(|s| s.dest_mac) }
+#[inline(always)]
+pub fn net_set_dest_mac(mac: [u8; 6]) { STATE.lock(|s| s.dest_mac = mac) }
 #[inline(always)]
-pub fn net_set_dest_mac(mac: [u8; 6]) { unsafe { G_DEST_MAC = mac; } }
+pub fn net_get_mtu() -> usize { STATE.lock(|s| if s.mtu == 0 { 1500 } else { s.mtu }) }
 #[inline(always)]
-pub fn net_get_mtu() -> usize { unsafe { if G_MTU == 0 { 1500 } else { G_MTU } } }
+pub fn net_set_mtu(mtu: usize) { STATE.lock(|s| s.mtu = if mtu < 576 { 576 } else { mtu }) }
 #[inline(always)]
-pub fn net_set_mtu(mtu: usize) { unsafe { G_MTU = if mtu < 576 { 576 } else { mtu }; } }
+pub fn net_get_ethertype() -> u16 { STATE.lock(|s| s.ether_type) }
 #[inline(always)]
-pub fn net_get_ethertype() -> u16 { unsafe { G_ETHER_TYPE } }
+pub fn net_set_ethertype(et: u16) { STATE.lock(|s| s.ether_type = et) }
 #[inline(always)]
-pub fn net_set_ethertype(et: u16) { unsafe { G_ETHER_TYPE = et; } }
+pub fn ctrl_get_resend_sink() -> ExportSink { STATE.lock(|s| s.ctrl_resend_sink) }
 #[inline(always)]
-pub fn ctrl_get_resend_sink() -> ExportSink { unsafe { G_CTRL_RESEND_SINK } }
+pub fn ctrl_set_resend_sink(sink: ExportSink) { STATE.lock(|s| s.ctrl_resend_sink = sink) }
 #[inline(always)]
-pub fn ctrl_set_resend_sink(s: ExportSink) { unsafe { G_CTRL_RESEND_SINK = s; } }
+pub fn ctrl_get_auto_ack() -> bool { STATE.lock(|s| s.ctrl_auto_ack) }
 #[inline(always)]
-pub fn ctrl_get_auto_ack() -> bool { unsafe { G_CTRL_AUTO_ACK } }
+pub fn ctrl_set_auto_ack(v: bool) { STATE.lock(|s| s.ctrl_auto_ack = v) }
 #[inline(always)]
-pub fn ctrl_set_auto_ack(v: bool) { unsafe { G_CTRL_AUTO_ACK = v; } }
+pub fn ctrl_get_auto_nak() -> bool { STATE.lock(|s| s.ctrl_auto_nak) }
 #[inline(always)]
-pub fn ctrl_get_auto_nak() -> bool { unsafe { G_CTRL_AUTO_NAK } }
+pub fn ctrl_set_auto_nak(v: bool) { STATE.lock(|s| s.ctrl_auto_nak = v) }
 #[inline(always)]
-pub fn ctrl_set_auto_nak(v: bool) { unsafe { G_CTRL_AUTO_NAK = v; } }
+pub fn ctrl_get_retransmit_ms() -> u64 { STATE.lock(|s| s.retx_ms) }
+
+/// Resend unacknowledged frames `ms` after they went out, or with 0 only on
+/// a NAK. Frames already sent are not covered; the window starts with the
+/// next one.
+pub fn ctrl_set_retransmit_ms(ms: u64) {
+    let old = STATE.lock(|s| {
+        s.retx_ms = ms;
+        s.unacked = None;
+        s.retx_due = false;
+        s.retx_tries = 0;
+        s.retx_timer.take()
+    });
+    if let Some(id) = old { crate::time::timer::cancel(id); }
+}
+
+/// Resend the unacknowledged window to the resend sink once the retransmit
+/// timer has expired. The resent frames get new sequence numbers and become
+/// the window; after `RETX_TRIES_MAX` tries without an ACK the window is
+/// dropped. Polled from the CLI loop, since sending needs the system table.
+pub fn retransmit_pump(system_table: &mut SystemTable<Boot>) {
+    let Some((from, end, tries)) = STATE.lock(|s| {
+        if !s.retx_due { return None; }
+        s.retx_due = false;
+        let from = s.unacked?;
+        s.retx_tries += 1;
+        Some((from, s.seq, s.retx_tries))
+    }) else { return };
+    if tries > RETX_TRIES_MAX {
+        STATE.lock(|s| { s.unacked = None; s.retx_tries = 0; });
+        crate::log!(Warn, "migrate", "no ACK from seq {} after {} retransmits; giving up", from, RETX_TRIES_MAX);
+        return;
+    }
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_TIMEOUT_RETRANSMITS).inc();
+    crate::log!(Info, "migrate", "ACK timeout: resending from seq {} (try {})", from, tries);
+    let (frames, _) = resend_from(system_table, from, end.wrapping_sub(from) as usize, false, ctrl_get_resend_sink());
+    let rearm = STATE.lock(|s| {
+        if s.unacked != Some(from) { return false; }
+        s.unacked = if frames == 0 { None } else { Some(end) };
+        s.unacked.is_some()
+    });
+    if rearm { retx_arm(); }
+}
+
 #[inline(always)]
-pub fn get_default_sink() -> ExportSink { unsafe { G_DEFAULT_SINK } }
+pub fn get_default_sink() -> ExportSink { STATE.lock(|s| s.default_sink) }
 #[inline(always)]
-pub fn set_default_sink(s: ExportSink) { unsafe { G_DEFAULT_SINK = s; } }
+pub fn set_default_sink(sink: ExportSink) { STATE.lock(|s| s.default_sink = sink) }
 
 #[inline(always)]
 fn sink_to_u8(s: ExportSink) -> u8 {
@@ -336,6 +574,7 @@ fn sink_to_u8(s: ExportSink) -> u8 {
         ExportSink::Buffer => 2,
         ExportSink::Snp => 3,
         ExportSink::Virtio => 4,
+        ExportSink::Rdma => 5,
     }
 }
 #[inline(always)]
@@ -346,10 +585,21 @@ fn u8_to_sink(v: u8) -> ExportSink {
         2 => ExportSink::Buffer,
         3 => ExportSink::Snp,
         4 => ExportSink::Virtio,
+        5 => ExportSink::Rdma,
         _ => ExportSink::Buffer,
     }
 }
 
+/// Record the migration transports this build carries with `hv::features`.
+pub fn register_features() {
+    use crate::hv::features::{self, register, register_flag, Status};
+    register_flag("migrate", "snp", cfg!(feature = "snp"), "not built (feature snp)");
+    register_flag("migrate", "virtio-net", cfg!(feature = "virtio-net"), "not built (feature virtio-net)");
+    // RDMA writes are built in software and go out over either link
+    if features::available("snp") || features::available("virtio-net") { register("migrate", "rdma", Status::Available, "software RoCEv2"); }
+    else { register("migrate", "rdma", Status::Disabled, "needs snp or virtio-net"); }
+}
+
 // ---- SNP discovery/control (feature-gated) ----
 #[cfg(feature = "snp")]
 pub fn snp_discover(system_table: &mut SystemTable<Boot>) {
@@ -358,20 +608,20 @@ pub fn snp_discover(system_table: &mut SystemTable<Boot>) {
     match bs.locate_handle_buffer(SearchType::ByProtocol(&uefi::proto::network::snp::SimpleNetwork::GUID)) {
         Ok(handles) => {
             let count = handles.len();
-            // Copy handles into our static store to avoid lifetime issues
-            let mut copied = 0usize;
-            unsafe {
-                while copied < count && copied < SNP_MAX { G_SNP_HANDLES[copied] = handles[copied]; copied += 1; }
-                G_SNP_LEN = copied;
-            }
+            // Copy handles into the state to avoid lifetime issues
+            let copied = count.min(SNP_MAX);
+            STATE.lock(|s| {
+                for i in 0..copied { s.snp_handles[i] = handles[i] as usize; }
+                s.snp_len = copied;
+            });
             let stdout = system_table.stdout();
             let mut buf = [0u8; 64]; let mut n = 0; for &b in b"snp: handles=" { buf[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(copied as u32, &mut buf[n..]); buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+            n += crate::util::format::u32_dec(copied as u32, &mut buf[n..]); buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
             let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
             for i in 0..copied {
-                let h = unsafe { G_SNP_HANDLES[i] };
+                let h = STATE.lock(|s| s.snp_handles[i]);
                 let mut line = [0u8; 64]; let mut m = 0; for &b in b"  idx=" { line[m] = b; m += 1; }
-                m += crate::firmware::acpi::u32_to_dec(i as u32, &mut line[m..]);
+                m += crate::util::format::u32_dec(i as u32, &mut line[m..]);
                 for &b in b" handle=0x" { line[m] = b; m += 1; }
                 m += crate::util::format::u64_hex(h as u64, &mut line[m..]);
                 line[m] = b'\r'; m += 1; line[m] = b'\n'; m += 1;
@@ -385,10 +635,16 @@ pub fn snp_discover(system_table: &mut SystemTable<Boot>) {
 #[cfg(not(feature = "snp"))]
 pub fn snp_discover(system_table: &mut SystemTable<Boot>) { let _ = system_table.stdout().write_str("snp: feature disabled\r\n"); }
 
+/// Handle picked with `snp_use`.
+#[cfg(feature = "snp")]
+fn snp_selected() -> Option<uefi::Handle> {
+    STATE.lock(|s| s.snp_sel.map(|i| s.snp_handles[i] as uefi::Handle))
+}
+
 #[cfg(feature = "snp")]
 pub fn snp_use(system_table: &mut SystemTable<Boot>, idx: usize) {
-    let len = unsafe { G_SNP_LEN };
-    if idx < len { unsafe { G_SNP_SEL_IDX = Some(idx); } let _ = system_table.stdout().write_str("snp: selected\r\n"); return; }
+    let selected = STATE.lock(|s| { if idx >= s.snp_len { return false; } s.snp_sel = Some(idx); true });
+    if selected { let _ = system_table.stdout().write_str("snp: selected\r\n"); return; }
     let _ = system_table.stdout().write_str("snp: invalid index\r\n");
 }
 
@@ -398,8 +654,7 @@ pub fn snp_use(system_table: &mut SystemTable<Boot>, _idx: usize) { let _ = syst
 #[cfg(feature = "snp")]
 pub fn snp_info(system_table: &mut SystemTable<Boot>) {
     let stdout = system_table.stdout();
-    if let Some(sel) = unsafe { G_SNP_SEL_IDX } {
-        let h = unsafe { G_SNP_HANDLES[sel] };
+    if let Some(h) = snp_selected() {
         // Try open protocol and print current station address
         let bs = system_table.boot_services();
         if let Ok(mut snp) = unsafe { bs.open_protocol_exclusive::<uefi::proto::network::snp::SimpleNetwork>(h) } {
@@ -408,7 +663,7 @@ pub fn snp_info(system_table: &mut SystemTable<Boot>) {
             let mut out = [0u8; 96]; let mut n = 0; for &b in b"snp: mac=" { out[n] = b; n += 1; }
             for i in 0..6 { n += crate::util::format::u64_hex(mac.addr[i] as u64, &mut out[n..]); if i != 5 { out[n] = b':'; n += 1; } }
             for &b in b" mtu=" { out[n] = b; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(mode.max_packet_size as u32, &mut out[n..]);
+            n += crate::util::format::u32_dec(mode.max_packet_size as u32, &mut out[n..]);
             out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1; let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
             return;
         }
@@ -422,72 +677,70 @@ pub fn snp_info(system_table: &mut SystemTable<Boot>) { let _ = system_table.std
 #[cfg(feature = "snp")]
 pub fn snp_pump(system_table: &mut SystemTable<Boot>, limit: usize) {
     let stdout = system_table.stdout();
-    let sel = unsafe { G_SNP_SEL_IDX };
-    if sel.is_none() { let _ = stdout.write_str("snp: not selected\r\n"); return; }
-    let h = unsafe { G_SNP_HANDLES[sel.unwrap()] };
+    let Some(h) = snp_selected() else { let _ = stdout.write_str("snp: not selected\r\n"); return; };
     let bs = system_table.boot_services();
     let mut opened = match unsafe { bs.open_protocol_exclusive::<uefi::proto::network::snp::SimpleNetwork>(h) } {
         Ok(p) => p,
-        Err(_) => { let _ = stdout.write_str("snp: open fail\r\n"); return; }
+        Err(_) => { crate::log!(Error, "snp", "open failed"); return; }
     };
     // Ensure started and initialized
     if opened.state() == uefi::proto::network::snp::State::Stopped {
-        if opened.start().is_err() { let _ = stdout.write_str("snp: start fail\r\n"); return; }
+        if opened.start().is_err() { crate::log!(Error, "snp", "start failed"); return; }
     }
     if opened.state() == uefi::proto::network::snp::State::Started {
-        if opened.initialize(0, 0).is_err() { let _ = stdout.write_str("snp: init fail\r\n"); return; }
+        if opened.initialize(0, 0).is_err() { crate::log!(Error, "snp", "initialize failed"); return; }
     }
     let mut pumped = 0usize;
     crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_CALLS).inc();
     let mut pkt = [0u8; 2048];
-    // Expected sequence tracking using global last seq
-    let mut expected_seq = crate::obs::metrics::MIG_LAST_SEQ.load(core::sync::atomic::Ordering::Relaxed) as u32;
-    let hdr_len = core::mem::size_of::<FrameHeader>();
     while limit == 0 || pumped < limit {
+        if chan_backpressure() { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_BACKPRESSURE).inc(); break; }
         let res = unsafe { opened.receive(None, &mut pkt) };
         let data = match res { Ok((_h, d)) => d, Err(_) => { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_EMPTY).inc(); break } };
-        let mut pos = 0usize;
-        while pos + hdr_len <= data.len() {
-            if &data[pos..pos+4] != &MAGIC { pos += 1; continue; }
-            if pos + hdr_len > data.len() { break; }
-            let ver = data[pos+4]; let _typ = data[pos+5];
-            if ver != 1 { pos += 1; continue; }
-            let payload_len = le_u32(&data[pos+20..pos+24]) as usize;
-            let crc_hdr = le_u32(&data[pos+24..pos+28]);
-            if pos + hdr_len + payload_len > data.len() { break; }
-            let payload = &data[pos+hdr_len .. pos+hdr_len+payload_len];
-            let crc_calc = crate::util::crc32::crc32(payload);
-            let seq = le_u32(&data[pos+8..pos+12]);
-            let good = crc_calc == crc_hdr;
-            if good {
-                // Write header+payload into channel buffer
-                let _ = chan_write(&data[pos .. pos+hdr_len]);
-                let _ = chan_write(payload);
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_OK).inc();
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_BYTES).add((hdr_len + payload_len) as u64);
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_FRAMES).inc();
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_BYTES).add((hdr_len + payload_len) as u64);
-                // Ordering diagnostics
-                if expected_seq != 0 {
-                    let next = expected_seq.wrapping_add(1);
-                    if seq == next { /* in order */ }
-                    else if seq < next { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_DUP_FRAMES).inc(); }
-                    else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_MISSING_FRAMES).inc(); }
-                }
-                expected_seq = seq;
-                crate::obs::metrics::MIG_LAST_SEQ.store(seq as u64, core::sync::atomic::Ordering::Relaxed);
-                pumped += 1;
-            } else {
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_BAD).inc();
-            }
-            pos += hdr_len + payload_len;
-        }
+        pumped += ingest_packet(data);
     }
 }
 
 #[cfg(not(feature = "snp"))]
 pub fn snp_pump(system_table: &mut SystemTable<Boot>, _limit: usize) { let _ = system_table.stdout().write_str("snp: feature disabled\r\n"); }
 
+/// Open the selected SNP handle and bring it to the initialized state.
+#[cfg(feature = "snp")]
+fn snp_open(system_table: &SystemTable<Boot>) -> Option<uefi::table::boot::ScopedProtocol<'_, uefi::proto::network::snp::SimpleNetwork>> {
+    let h = snp_selected()?;
+    let snp = unsafe { system_table.boot_services().open_protocol_exclusive::<uefi::proto::network::snp::SimpleNetwork>(h) }.ok()?;
+    if snp.state() == uefi::proto::network::snp::State::Stopped && snp.start().is_err() { return None; }
+    if snp.state() == uefi::proto::network::snp::State::Started && snp.initialize(0, 0).is_err() { return None; }
+    Some(snp)
+}
+
+/// Transmit one complete Ethernet frame (header included) on the selected SNP handle.
+#[cfg(feature = "snp")]
+pub fn snp_send_raw(system_table: &mut SystemTable<Boot>, frame: &[u8]) -> bool {
+    match snp_open(system_table) { Some(snp) => snp.transmit(0, frame, None, None, None).is_ok(), None => false }
+}
+
+/// Receive up to `limit` raw Ethernet frames (0 = until empty) and hand each to `f`.
+#[cfg(feature = "snp")]
+pub fn snp_recv_raw(system_table: &mut SystemTable<Boot>, limit: usize, mut f: impl FnMut(&[u8])) -> usize {
+    let snp = match snp_open(system_table) { Some(s) => s, None => return 0 };
+    let mut pkt = [0u8; 2048];
+    let mut n = 0usize;
+    while limit == 0 || n < limit {
+        match snp.receive(&mut pkt, None, None, None, None) {
+            Ok(len) => { f(&pkt[..len.min(pkt.len())]); n += 1; }
+            Err(_) => break,
+        }
+    }
+    n
+}
+
+#[cfg(not(feature = "snp"))]
+pub fn snp_send_raw(_system_table: &mut SystemTable<Boot>, _frame: &[u8]) -> bool { false }
+
+#[cfg(not(feature = "snp"))]
+pub fn snp_recv_raw(_system_table: &mut SystemTable<Boot>, _limit: usize, _f: impl FnMut(&[u8])) -> usize { 0 }
+
 #[cfg(feature = "snp")]
 pub fn snp_poll(system_table: &mut SystemTable<Boot>, cycles: usize, sleep_us: usize, do_ctrl: bool, do_verify: bool) {
     snp_poll_ex(system_table, cycles, sleep_us, do_ctrl, do_verify, 0);
@@ -535,33 +788,48 @@ pub fn virtio_poll_ex(system_table: &mut SystemTable<Boot>, mut cycles: usize, s
 pub fn snp_poll(system_table: &mut SystemTable<Boot>, _cycles: usize, _sleep_us: usize, _do_ctrl: bool, _do_verify: bool) { let _ = system_table.stdout().write_str("snp: feature disabled\r\n"); }
 
 fn chan_write(buf: &[u8]) -> usize {
-    unsafe {
-        if let Some(b) = G_BUF.as_mut() {
-            let mut written = 0usize;
-            let mut src_off = 0usize;
-            while src_off < buf.len() {
-                if b.cap == 0 { break; }
-                let space = b.cap - (if b.len < b.cap { b.len } else { b.cap });
-                let to_write = core::cmp::min(buf.len() - src_off, if space == 0 { b.cap } else { space });
-                // Overwrite oldest when full
-                if b.len + to_write > b.cap { b.len = b.cap; }
-                else { b.len += to_write; }
-                let end = core::cmp::min(b.cap - b.wpos, to_write);
-                core::ptr::copy_nonoverlapping(buf.as_ptr().add(src_off), b.ptr.add(b.wpos), end);
-                b.wpos = (b.wpos + end) % b.cap;
-                let rem = to_write - end;
-                if rem > 0 {
-                    core::ptr::copy_nonoverlapping(buf.as_ptr().add(src_off + end), b.ptr, rem);
-                    b.wpos = rem;
-                }
-                written += to_write;
-                src_off += to_write;
-            }
-            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CB_WRITTEN_BYTES).add(written as u64);
-            return written;
+    let Some(w) = STATE.lock(|s| s.buf.as_mut().map(|r| r.write(buf))) else { return 0 };
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CB_WRITTEN_BYTES).add(w.stored as u64);
+    if w.lost > 0 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CB_LOST_BYTES).add(w.lost as u64); }
+    if let Some(m) = w.mark { chan_notify(m); }
+    w.stored
+}
+
+/// Count a watermark crossing and run the watchers, with the lock released
+/// so they can pump, verify or consume.
+fn chan_notify(mark: ring::Mark) {
+    let (watchers, len, cap) = STATE.lock(|s| (s.chan_watchers, s.buf.as_ref().map_or(0, |r| r.len()), s.buf.as_ref().map_or(0, |r| r.cap())));
+    match mark {
+        ring::Mark::High => {
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CB_HIGH_MARKS).inc();
+            crate::log!(Warn, "migrate", "chan: high watermark, {} of {} bytes", len, cap);
         }
+        ring::Mark::Low => crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CB_LOW_MARKS).inc(),
     }
-    0
+    for f in watchers.iter().flatten() { f(mark, len, cap); }
+}
+
+/// Register `f` for channel watermark crossings. False if it is already
+/// registered or all slots are taken.
+pub fn chan_watch(f: ChanWatcher) -> bool {
+    STATE.lock(|s| {
+        if s.chan_watchers.iter().flatten().any(|&g| g as usize == f as usize) { return false; }
+        let Some(slot) = s.chan_watchers.iter_mut().find(|w| w.is_none()) else { return false };
+        *slot = Some(f);
+        true
+    })
+}
+
+pub fn chan_unwatch(f: ChanWatcher) {
+    STATE.lock(|s| for w in s.chan_watchers.iter_mut() { if w.is_some_and(|g| g as usize == f as usize) { *w = None; } })
+}
+
+/// The channel refuses data on overflow, filled past its high watermark,
+/// and has not drained to the low one since. Receive pumps stop while this
+/// holds, leaving frames queued at the NIC instead of losing them. A ring
+/// that drops its oldest bytes never pushes back.
+pub fn chan_backpressure() -> bool {
+    STATE.lock(|s| s.buf.as_ref().is_some_and(|r| r.overflow() == ring::Overflow::Reject && r.above_high()))
 }
 
 pub struct BufferWriter;
@@ -584,8 +852,7 @@ impl<'a> SnpWriter<'a> {
     pub fn new(system_table: &'a mut SystemTable<Boot>) -> Self { SnpWriter { system_table, snp: None } }
     fn ensure_open(&'a mut self) -> Option<&'a mut uefi::proto::network::snp::SimpleNetwork> {
         if self.snp.is_none() {
-            let sel = unsafe { G_SNP_SEL_IDX }?;
-            let h = unsafe { G_SNP_HANDLES[sel] };
+            let h = snp_selected()?;
             let bs = self.system_table.boot_services();
             match unsafe { bs.open_protocol_exclusive::<uefi::proto::network::snp::SimpleNetwork>(h) } {
                 Ok(s) => { self.snp = Some(s); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_NET_OPEN_OK).inc(); }
@@ -654,66 +921,81 @@ pub fn chan_new(system_table: &SystemTable<Boot>, pages: usize) -> bool {
     let bytes = pages.saturating_mul(4096);
     if bytes == 0 { return false; }
     if let Some(p) = crate::mm::uefi::alloc_pages(system_table, pages, MemoryType::LOADER_DATA) {
-        unsafe {
-            core::ptr::write_bytes(p, 0, bytes);
-            G_BUF = Some(Buffer { ptr: p, cap: bytes, wpos: 0, len: 0 });
-        }
+        unsafe { core::ptr::write_bytes(p, 0, bytes); }
+        let ring = unsafe { ring::Ring::new(p, bytes) };
+        STATE.lock(|s| { s.buf = Some(ring); chan_apply_config(s); });
         return true;
     }
     false
 }
 
+fn chan_apply_config(s: &mut MigrationState) {
+    let (o, hi, lo) = (s.chan_overflow, s.chan_high_pct as usize, s.chan_low_pct as usize);
+    if let Some(r) = s.buf.as_mut() {
+        r.set_overflow(o);
+        let cap = r.cap();
+        let _ = r.set_watermarks(cap * hi / 100, cap * lo / 100);
+    }
+}
+
+/// Set the overflow policy, now and for later rings.
+pub fn chan_set_overflow(o: ring::Overflow) { STATE.lock(|s| { s.chan_overflow = o; chan_apply_config(s); }) }
+
+/// Set the watermarks as percentages of the ring, now and for later rings.
+pub fn chan_set_watermarks(high_pct: u8, low_pct: u8) -> Result<(), &'static str> {
+    if low_pct >= high_pct || high_pct > 100 { return Err("migrate: watermarks need low < high <= 100"); }
+    STATE.lock(|s| { s.chan_high_pct = high_pct; s.chan_low_pct = low_pct; chan_apply_config(s); });
+    Ok(())
+}
+
+pub fn chan_ring_stats() -> Option<ring::Stats> { STATE.lock(|s| s.buf.as_ref().map(|r| r.stats())) }
+
+/// Install `buf` as the channel ring and return the previous one.
+fn chan_swap(buf: Option<ring::Ring>) -> Option<ring::Ring> { STATE.lock(|s| core::mem::replace(&mut s.buf, buf)) }
+
+/// Cursor over the unread bytes of the channel ring.
+fn chan_cursor() -> Option<ChanCursor> {
+    STATE.lock(|s| s.buf.as_ref().filter(|r| r.cap() > 0).map(|r| {
+        ChanCursor { ptr: r.base() as *const u8, cap: r.cap(), pos: r.read_pos(), remaining: r.len() }
+    }))
+}
+
 pub fn chan_clear() {
-    unsafe { if let Some(b) = G_BUF.as_mut() { b.wpos = 0; b.len = 0; } }
+    let mark = STATE.lock(|s| s.buf.as_mut().and_then(|r| r.clear()));
+    if let Some(m) = mark { chan_notify(m); }
 }
 
 pub fn chan_stats() -> (usize, usize) {
-    unsafe { if let Some(b) = G_BUF.as_ref() { return (b.len, b.cap); } }
-    (0, 0)
+    STATE.lock(|s| s.buf.as_ref().map_or((0, 0), |r| (r.len(), r.cap())))
 }
 
-pub fn chan_consume(mut bytes: usize) {
-    unsafe {
-        if let Some(b) = G_BUF.as_mut() {
-            if bytes > b.len { bytes = b.len; }
-            // Advance head by reducing length; start position is derived from wpos and len
-            b.len -= bytes;
-        }
-    }
+/// Advance the read cursor past `bytes` the consumer is done with.
+pub fn chan_consume(bytes: usize) -> usize {
+    let Some((n, mark)) = STATE.lock(|s| s.buf.as_mut().map(|r| r.consume(bytes))) else { return 0 };
+    if let Some(m) = mark { chan_notify(m); }
+    n
 }
 
 pub fn chan_dump(system_table: &mut SystemTable<Boot>, mut want: usize, hex: bool) {
     let stdout = system_table.stdout();
-    unsafe {
-        if let Some(b) = G_BUF.as_ref() {
-            if want == 0 || want > b.len { want = b.len; }
-            let start = if b.len < b.cap { (b.wpos + b.cap - b.len) % b.cap } else { (b.wpos + b.cap - b.len) % b.cap };
-            let mut remaining = want;
-            let mut pos = start;
-            let mut line: [u8; 96] = [0; 96];
-            while remaining > 0 {
-                let take = core::cmp::min(remaining, b.cap - pos);
-                let mut i = 0usize;
-                while i < take {
-                    if hex {
-                        let mut n = 0usize;
-                        let sub = core::cmp::min(16, take - i);
-                        let chunk_ptr = b.ptr.add(pos + i);
-                        for j in 0..sub { let v = core::ptr::read_volatile(chunk_ptr.add(j)) as u64; n += crate::util::format::u64_hex(v, &mut line[n..]); line[n] = b' '; n += 1; }
-                        line[n] = b'\r'; n += 1; line[n] = b'\n'; n += 1;
-                        let _ = stdout.write_str(core::str::from_utf8(&line[..n]).unwrap_or("\r\n"));
-                        i += sub;
-                    } else {
-                        let sub = core::cmp::min(64, take - i);
-                        let s = core::slice::from_raw_parts(b.ptr.add(pos + i), sub);
-                        let _ = stdout.write_str(core::str::from_utf8(s).unwrap_or(""));
-                        i += sub;
-                    }
-                }
-                pos = (pos + take) % b.cap; remaining -= take;
+    if let Some(mut cur) = chan_cursor() {
+        if want == 0 || want > cur.remaining { want = cur.remaining; }
+        let mut line: [u8; 96] = [0; 96];
+        let mut chunk = [0u8; 64];
+        while want > 0 {
+            let sub = core::cmp::min(if hex { 16 } else { 64 }, want);
+            if !cur.read_into(&mut chunk[..sub]) { break; }
+            if hex {
+                let mut n = 0usize;
+                for &v in &chunk[..sub] { n += crate::util::format::u64_hex(v as u64, &mut line[n..]); line[n] = b' '; n += 1; }
+                line[n] = b'\r'; n += 1; line[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&line[..n]).unwrap_or("\r\n"));
+            } else {
+                let _ = stdout.write_str(core::str::from_utf8(&chunk[..sub]).unwrap_or(""));
             }
-            return;
+            want -= sub;
         }
+        return;
     }
     let lang = crate::i18n::detect_lang(&*system_table);
     let stdout2 = system_table.stdout();
@@ -724,6 +1006,8 @@ pub fn chan_dump(system_table: &mut SystemTable<Boot>, mut want: usize, hex: boo
 /// For Console sink, prints hex lines; for Null sink, discards while counting bytes.
 pub fn export_range(system_table: &mut SystemTable<Boot>, start_pa: u64, len: u64, sink: ExportSink) -> u64 {
     if len == 0 { return 0; }
+    // RDMA places whole pages; it has no byte stream to walk.
+    if let ExportSink::Rdma = sink { return rdma::write_range(system_table, start_pa, len); }
     let mut remaining = len;
     let mut addr = start_pa;
     let stdout = system_table.stdout();
@@ -765,7 +1049,7 @@ pub fn export_range(system_table: &mut SystemTable<Boot>, start_pa: u64, len: u6
                     // Treat as null for raw export path; framed network path is via send_dirty_pages.
                     let mut i = 0usize; while i < chunk { let _ = read_volatile((addr as *const u8).add(i)); i += 1; }
                 }
-                ExportSink::Virtio => {
+                ExportSink::Virtio | ExportSink::Rdma => {
                     // For raw export_range, treat Virtio similarly to Null (raw bytes path is framed elsewhere).
                     let mut i = 0usize; while i < chunk { let _ = read_volatile((addr as *const u8).add(i)); i += 1; }
                 }
@@ -780,90 +1064,69 @@ pub fn export_range(system_table: &mut SystemTable<Boot>, start_pa: u64, len: u6
 
 /// Run a pre-copy loop: scan dirty, copy pages, repeat. Returns stats.
 pub fn precopy(system_table: &mut SystemTable<Boot>, max_rounds: u32, clear_each_round: bool, sink: ExportSink) -> (u32, u64, u64) {
-    let st = unsafe { G_TRACKER.as_mut() };
-    if st.is_none() { return (0, 0, 0); }
-    let state = st.unwrap();
-    let mut rounds_done = 0u32;
-    let mut pages_copied = 0u64;
-    let mut bytes_copied = 0u64;
-    while rounds_done < max_rounds {
-        state.bitmap.clear_all();
-        let dirty = scan_round(clear_each_round);
-        if dirty == 0 { rounds_done += 1; break; }
-        // Copy pages marked dirty in this round
-        state.bitmap.for_each_set(|page_idx| {
-            let pa = page_idx << 12;
-            // Simple zero-page elision
-            let mut all_zero = true;
-            unsafe {
-                let mut off = 0usize;
-                while off < 4096 {
-                    if read_volatile((pa as *const u64).add(off / 8)) != 0 { all_zero = false; break; }
-                    off += 8;
-                }
-            }
-            if all_zero {
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc();
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096);
-            } else {
-                // Optional content hash elision to skip duplicates (lightweight rolling hash)
-                let mut h: u64 = 1469598103934665603u64; // FNV offset basis
-                unsafe {
-                    let mut off = 0usize;
-                    while off < 4096 {
-                        let v = read_volatile((pa as *const u64).add(off / 8));
-                        h ^= v; h = h.wrapping_mul(1099511628211u64);
-                        off += 8;
+    with_tracker(|state| {
+        let mut rounds_done = 0u32;
+        let mut pages_copied = 0u64;
+        let mut bytes_copied = 0u64;
+        while rounds_done < max_rounds {
+            state.bitmap.clear_all();
+            let dirty = scan(state, clear_each_round);
+            if dirty == 0 { rounds_done += 1; break; }
+            // Copy pages marked dirty in this round, eliding zero pages and
+            // (for the prototype) pages whose content hash is zero
+            state.bitmap.for_each_set(|page_idx| {
+                let pa = page_idx << 12;
+                match page_skip_reason(pa) {
+                    Some(1) => {
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc();
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096);
+                    }
+                    Some(_) => {
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc();
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096);
+                    }
+                    None => {
+                        bytes_copied += export_range(system_table, pa, 4096, sink);
+                        pages_copied += 1;
                     }
                 }
-                // For prototype, treat zero hash only as special skip case
-                if h == 0 {
-                    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc();
-                    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096);
-                } else {
-                    bytes_copied += export_range(system_table, pa, 4096, sink);
-                    pages_copied += 1;
-                }
-            }
-        });
-        rounds_done += 1;
-        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PRECOPY_ROUNDS).inc();
-        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PRECOPY_PAGES).add(dirty);
-        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_BYTES_TX).add(dirty * 4096);
-        if dirty as u64 == 0 { break; }
-    }
-    (rounds_done, pages_copied, bytes_copied)
+            });
+            rounds_done += 1;
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PRECOPY_ROUNDS).inc();
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PRECOPY_PAGES).add(dirty);
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_BYTES_TX).add(dirty * 4096);
+        }
+        (rounds_done, pages_copied, bytes_copied)
+    }).unwrap_or((0, 0, 0))
 }
 
 /// Plan only: run scan rounds without copying, reporting tentative metrics.
 pub fn plan_dirty_runs(system_table: &mut SystemTable<Boot>) {
     let stdout = system_table.stdout();
-    let st = unsafe { G_TRACKER.as_mut() };
-    if st.is_none() { let _ = stdout.write_str("migrate: no active tracker\r\n"); return; }
-    let state = st.unwrap();
-    state.bitmap.clear_all();
-    let dirty = scan_round(false);
+    let Some(dirty) = with_tracker(|state| { state.bitmap.clear_all(); scan(state, false) }) else {
+        let _ = stdout.write_str("migrate: no active tracker\r\n");
+        return;
+    };
     let mut buf = [0u8; 64]; let mut n = 0;
     for &b in b"plan: dirty_pages=" { buf[n] = b; n += 1; }
-    n += crate::firmware::acpi::u32_to_dec(dirty as u32, &mut buf[n..]);
+    n += crate::util::format::u32_dec(dirty as u32, &mut buf[n..]);
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
 }
 
 /// Export dirty-set bytes for the current bitmap without framing, to selected sink.
 pub fn export_dirty_runs(system_table: &mut SystemTable<Boot>, sink: ExportSink) -> (u32, u64, u64) {
-    let st = unsafe { G_TRACKER.as_mut() };
-    if st.is_none() { return (0, 0, 0); }
-    let state = st.unwrap();
-    // Do one non-clearing scan then export
-    state.bitmap.clear_all();
-    let dirty = scan_round(false);
-    let mut pages = 0u64; let mut bytes = 0u64;
-    state.bitmap.for_each_set(|page_idx| {
-        let pa = page_idx << 12;
-        pages += 1; bytes += export_range(system_table, pa, 4096, sink);
-    });
-    (1, pages, bytes)
+    with_tracker(|state| {
+        // Do one non-clearing scan then export
+        state.bitmap.clear_all();
+        scan(state, false);
+        let mut pages = 0u64; let mut bytes = 0u64;
+        state.bitmap.for_each_set(|page_idx| {
+            let pa = page_idx << 12;
+            pages += 1; bytes += export_range(system_table, pa, 4096, sink);
+        });
+        (1, pages, bytes)
+    }).unwrap_or((0, 0, 0))
 }
 
 fn stall_for_rate(system_table: &mut SystemTable<Boot>, bytes: usize, rate_kbps: u32) {
@@ -875,135 +1138,113 @@ fn stall_for_rate(system_table: &mut SystemTable<Boot>, bytes: usize, rate_kbps:
 
 /// Throttled variant of precopy with approximate rate control in KB/s.
 pub fn precopy_throttled(system_table: &mut SystemTable<Boot>, max_rounds: u32, clear_each_round: bool, sink: ExportSink, rate_kbps: u32) -> (u32, u64, u64) {
-    let st = unsafe { G_TRACKER.as_mut() };
-    if st.is_none() { return (0, 0, 0); }
-    let state = st.unwrap();
-    let mut rounds_done = 0u32;
-    let mut pages_copied = 0u64;
-    let mut bytes_copied = 0u64;
-    while rounds_done < max_rounds {
-        state.bitmap.clear_all();
-        let dirty = scan_round(clear_each_round);
-        if dirty == 0 { rounds_done += 1; break; }
-        state.bitmap.for_each_set(|page_idx| {
-            let pa = page_idx << 12;
-            let mut all_zero = true;
-            unsafe {
-                let mut off = 0usize;
-                while off < 4096 {
-                    if read_volatile((pa as *const u64).add(off / 8)) != 0 { all_zero = false; break; }
-                    off += 8;
-                }
-            }
-            if all_zero {
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc();
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096);
-            } else {
-                let mut h: u64 = 1469598103934665603u64;
-                unsafe {
-                    let mut off = 0usize;
-                    while off < 4096 {
-                        let v = read_volatile((pa as *const u64).add(off / 8));
-                        h ^= v; h = h.wrapping_mul(1099511628211u64);
-                        off += 8;
+    with_tracker(|state| {
+        let mut rounds_done = 0u32;
+        let mut pages_copied = 0u64;
+        let mut bytes_copied = 0u64;
+        while rounds_done < max_rounds {
+            state.bitmap.clear_all();
+            let dirty = scan(state, clear_each_round);
+            if dirty == 0 { rounds_done += 1; break; }
+            state.bitmap.for_each_set(|page_idx| {
+                let pa = page_idx << 12;
+                match page_skip_reason(pa) {
+                    Some(1) => {
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc();
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096);
+                    }
+                    Some(_) => {
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc();
+                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096);
+                    }
+                    None => {
+                        let wrote = export_range(system_table, pa, 4096, sink) as usize;
+                        bytes_copied += wrote as u64;
+                        pages_copied += 1;
+                        stall_for_rate(system_table, wrote + core::mem::size_of::<FrameHeader>(), rate_kbps);
                     }
                 }
-                if h == 0 {
-                    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc();
-                    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096);
-                } else {
-                    let wrote = export_range(system_table, pa, 4096, sink) as usize;
-                    bytes_copied += wrote as u64;
-                    pages_copied += 1;
-                    stall_for_rate(system_table, wrote + core::mem::size_of::<FrameHeader>(), rate_kbps);
-                }
-            }
-        });
-        rounds_done += 1;
-        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PRECOPY_ROUNDS).inc();
-        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PRECOPY_PAGES).add(dirty);
-        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_BYTES_TX).add(dirty * 4096);
-        if dirty as u64 == 0 { break; }
-    }
-    (rounds_done, pages_copied, bytes_copied)
+            });
+            rounds_done += 1;
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PRECOPY_ROUNDS).inc();
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PRECOPY_PAGES).add(dirty);
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_BYTES_TX).add(dirty * 4096);
+        }
+        (rounds_done, pages_copied, bytes_copied)
+    }).unwrap_or((0, 0, 0))
 }
 
 pub fn txlog_dump(system_table: &mut SystemTable<Boot>, count: usize) {
     let stdout = system_table.stdout();
-    unsafe {
-        let total = if TX_WIDX > TX_LOG_CAP { TX_LOG_CAP } else { TX_WIDX };
-        let n = if count == 0 || count > total { total } else { count };
-        let start = TX_WIDX.saturating_sub(n);
-        for idx in start..TX_WIDX {
-            let e = TX_LOG[idx % TX_LOG_CAP];
-            let mut buf = [0u8; 96]; let mut i = 0;
-            for &b in b"txlog: kind=" { buf[i] = b; i += 1; }
-            let k: &[u8] = match e.kind { TYP_PAGE => b"page", TYP_MANIFEST => b"manifest", TYP_CTRL => b"ctrl", _ => b"?" };
-            for &b in k { buf[i] = b; i += 1; }
-            for &b in b" seq=" { buf[i] = b; i += 1; }
-            i += crate::firmware::acpi::u32_to_dec(e.seq, &mut buf[i..]);
-            if e.kind == TYP_PAGE { for &b in b" page=" { buf[i] = b; i += 1; } i += crate::firmware::acpi::u32_to_dec(e.page_index as u32, &mut buf[i..]); }
-            buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
-            let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
-        }
+    let (oldest, end) = tx_window();
+    let total = end - oldest;
+    let n = if count == 0 || count > total { total } else { count };
+    for idx in end - n..end {
+        let e = tx_entry(idx);
+        let mut buf = [0u8; 96]; let mut i = 0;
+        for &b in b"txlog: kind=" { buf[i] = b; i += 1; }
+        let k: &[u8] = match e.kind { TYP_PAGE => b"page", TYP_MANIFEST => b"manifest", TYP_CTRL => b"ctrl", _ => b"?" };
+        for &b in k { buf[i] = b; i += 1; }
+        for &b in b" seq=" { buf[i] = b; i += 1; }
+        i += crate::util::format::u32_dec(e.seq, &mut buf[i..]);
+        if e.kind == TYP_PAGE { for &b in b" page=" { buf[i] = b; i += 1; } i += crate::util::format::u32_dec(e.page_index as u32, &mut buf[i..]); }
+        buf[i] = b'\r'; i += 1; buf[i] = b'\n'; i += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..i]).unwrap_or("\r\n"));
     }
 }
 
 pub fn reset(system_table: &mut SystemTable<Boot>) {
-    unsafe {
-        G_SEQ = 1;
-        TX_WIDX = 0;
-        for i in 0..TX_LOG_CAP { TX_LOG[i] = TxEntry { kind: 0, seq: 0, page_index: 0 }; }
-    }
+    STATE.lock(|s| {
+        s.seq = 1;
+        s.tx_widx = 0;
+        s.tx_log = [TX_EMPTY; TX_LOG_CAP];
+    });
     chan_clear();
     let _ = system_table; // placeholder to keep signature uniform
 }
 
 pub fn session_start(system_table: &SystemTable<Boot>) {
-    let _ = crate::time::init_time(system_table);
-    unsafe { SESSION_START_TSC = crate::time::rdtsc(); }
+    let _ = crate::time::clock::init(system_table);
+    STATE.lock(|s| s.session_start_ns = crate::time::clock::now_ns());
 }
 
-fn elapsed_us_since(start_tsc: u64, system_table: &SystemTable<Boot>) -> u64 {
-    let hz = crate::time::tsc_hz();
-    if hz == 0 || start_tsc == 0 { return 0; }
-    let now = crate::time::rdtsc();
-    let dt = now.wrapping_sub(start_tsc);
-    (dt.saturating_mul(1_000_000)) / hz
+fn elapsed_us_since(start_ns: u64) -> u64 {
+    if crate::time::clock::hz() == 0 || start_ns == 0 { return 0; }
+    crate::time::clock::elapsed_ns(start_ns) / 1_000
 }
 
 pub fn session_elapsed(system_table: &mut SystemTable<Boot>) {
-    let us = unsafe { elapsed_us_since(SESSION_START_TSC, system_table) };
+    let us = elapsed_us_since(STATE.lock(|s| s.session_start_ns));
     let stdout = system_table.stdout();
     let mut buf = [0u8; 64]; let mut n = 0;
     for &b in b"migrate: elapsed_us=" { buf[n] = b; n += 1; }
-    n += crate::firmware::acpi::u32_to_dec(us as u32, &mut buf[n..]);
+    n += crate::util::format::u32_dec(us as u32, &mut buf[n..]);
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
 }
 
 pub fn session_bw(system_table: &mut SystemTable<Boot>) {
-    let us = unsafe { elapsed_us_since(SESSION_START_TSC, system_table) };
+    let us = elapsed_us_since(STATE.lock(|s| s.session_start_ns));
     let bytes = crate::obs::metrics::MIG_CB_WRITTEN_BYTES.load(core::sync::atomic::Ordering::Relaxed);
     let stdout = system_table.stdout();
     if us == 0 { let _ = stdout.write_str("migrate: bw unavailable\r\n"); return; }
     let kbps = (bytes.saturating_mul(1_000) / us) as u64; // KB/s approx (1KB=1000B)
     let mut buf = [0u8; 64]; let mut n = 0;
     for &b in b"migrate: kbps=" { buf[n] = b; n += 1; }
-    n += crate::firmware::acpi::u32_to_dec(kbps as u32, &mut buf[n..]);
+    n += crate::util::format::u32_dec(kbps as u32, &mut buf[n..]);
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
 }
 
 pub fn session_bw_net(system_table: &mut SystemTable<Boot>) {
-    let us = unsafe { elapsed_us_since(SESSION_START_TSC, system_table) };
+    let us = elapsed_us_since(STATE.lock(|s| s.session_start_ns));
     let bytes = crate::obs::metrics::MIG_NET_TX_BYTES.load(core::sync::atomic::Ordering::Relaxed);
     let stdout = system_table.stdout();
     if us == 0 { let _ = stdout.write_str("migrate: bw_net unavailable\r\n"); return; }
     let kbps = (bytes.saturating_mul(1_000) / us) as u64; // KB/s approx (1KB=1000B)
     let mut buf = [0u8; 64]; let mut n = 0;
     for &b in b"migrate: kbps_net=" { buf[n] = b; n += 1; }
-    n += crate::firmware::acpi::u32_to_dec(kbps as u32, &mut buf[n..]);
+    n += crate::util::format::u32_dec(kbps as u32, &mut buf[n..]);
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
     let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
 }
@@ -1042,7 +1283,7 @@ pub fn summary(system_table: &mut SystemTable<Boot>) {
     let mut print = |label: &str, val: u64| {
         let mut n = 0;
         for &b in label.as_bytes() { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(val as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(val as u32, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     };
@@ -1077,48 +1318,30 @@ pub fn summary(system_table: &mut SystemTable<Boot>) {
 
 // ---- Simple framing and compression ----
 
-#[repr(C, packed)]
-struct FrameHeader {
-    magic: [u8;4],   // 'Z','M','I','G'
-    ver: u8,         // 1
-    typ: u8,         // 1=page, 2=manifest
-    flags: u16,      // bit0=compressed
-    seq: u32,
-    page_index: u64,
-    payload_len: u32,
-    crc32: u32,
+fn rle_compress_page(pa: u64, out: &mut [u8]) -> Option<usize> {
+    unsafe { rle_encode(pa as *const u8, 4096, out) }
 }
 
-const MAGIC: [u8;4] = *b"ZMIG";
-const TYP_PAGE: u8 = 1;
-const TYP_MANIFEST: u8 = 2;
-const TYP_CTRL: u8 = 3;
-const CTRL_ACK: u8 = 1;
-const CTRL_NAK: u8 = 2;
-const FLAG_COMP: u16 = 1u16 << 0;
-
-fn rle_compress_page(pa: u64, out: &mut [u8]) -> Option<usize> {
-    // Very simple RLE: (value:1, run_len:1) pairs per byte, 4096 -> worst 8192, but we bound using out.len()
-    let mut w = 0usize;
-    let mut i = 0usize;
-    unsafe {
-        while i < 4096 {
-            let v = read_volatile((pa as *const u8).add(i));
-            let mut run = 1usize;
-            while i + run < 4096 && run < 255 {
-                let nv = read_volatile((pa as *const u8).add(i + run));
-                if nv != v { break; }
-                run += 1;
-            }
-            if w + 2 > out.len() { return None; }
-            out[w] = v; out[w+1] = run as u8; w += 2;
-            i += run;
-        }
-    }
-    Some(w)
+/// Frame version to send, whether the peer takes RLE payloads, and the
+/// guest NUMA node of `page_index` when the version can carry it.
+fn tx_params(page_index: u64) -> (u8, bool, Option<u8>) {
+    STATE.lock(|s| {
+        let rle = s.peer.map_or(true, |p| p.caps & CAP_RLE != 0);
+        let gpa = page_index << 12;
+        let node = if s.wire_ver < VER2 { None } else {
+            s.numa.as_ref().and_then(|l| l.ranges().iter().find(|r| gpa >= r.gpa && gpa - r.gpa < r.len).map(|r| r.node))
+        };
+        (s.wire_ver, rle, node)
+    })
 }
 
+fn wire_ver() -> u8 { STATE.lock(|s| s.wire_ver) }
+
+/// Frame one page and send it. Returns whether it went compressed and the
+/// frame's length on the wire.
 fn frame_and_send_page(writer: &mut impl MigrWriter, page_index: u64, pa: u64, compress: bool, chunked: bool) -> (bool, usize) {
+    let (ver, rle, node) = tx_params(page_index);
+    let compress = compress && rle;
     // Try compression if requested
     let mut flags: u16 = 0;
     let mut payload_len: usize = 4096;
@@ -1132,39 +1355,50 @@ fn frame_and_send_page(writer: &mut impl MigrWriter, page_index: u64, pa: u64, c
     } else {
         payload_ptr = pa as *const u8;
     }
-    // Build header
-    let mut hdr = FrameHeader { magic: MAGIC, ver: 1, typ: TYP_PAGE, flags, seq: 0, page_index, payload_len: payload_len as u32, crc32: 0 };
-    let seq = unsafe { let s = G_SEQ; G_SEQ = G_SEQ.wrapping_add(1); s };
+    // Build header and, for version 2, the metadata
+    let mut hdr = FrameHeader::new(ver, TYP_PAGE, flags, page_index, payload_len as u32);
+    let mut ext = [0u8; 8];
+    let ext_len = match node { Some(n) => tlv_put(&mut ext, 0, TLV_NUMA_NODE, &[n]).unwrap_or(0), None => 0 };
+    hdr.ext_len = ext_len as u16;
+    let seq = next_seq();
     hdr.seq = seq;
-    hdr.crc32 = crate::util::crc32::crc32_ptr(payload_ptr, payload_len);
-    // Send header then payload
-    let hdr_bytes: &[u8] = unsafe { core::slice::from_raw_parts((&hdr as *const FrameHeader) as *const u8, core::mem::size_of::<FrameHeader>()) };
+    // payload_ptr is either comp_buf_storage or the 4 KiB page at pa
+    hdr.crc32 = unsafe { crate::util::crc32::crc32_ptr_update(crate::util::crc32::crc32(&ext[..ext_len]), payload_ptr, payload_len) };
+    // Send header, metadata, then payload
+    let t0 = crate::time::clock::now_ns();
+    let hdr_bytes = &hdr.to_bytes()[..hdr.wire_len()];
     if chunked { write_chunked(writer, hdr_bytes); } else { let _ = writer.write(hdr_bytes); }
+    if ext_len > 0 {
+        if chunked { write_chunked(writer, &ext[..ext_len]); } else { let _ = writer.write(&ext[..ext_len]); }
+    }
     let payload_bytes: &[u8] = unsafe { core::slice::from_raw_parts(payload_ptr, payload_len) };
     if chunked { write_chunked(writer, payload_bytes); } else { let _ = writer.write(payload_bytes); }
+    crate::obs::metrics::MIG_FRAME_SEND_NS.observe_since(t0);
     crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_FRAMES).inc();
     if (flags & FLAG_COMP) != 0 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_COMPRESSED_PAGES).inc(); }
     else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RAW_PAGES).inc(); }
-    unsafe { tx_log_append(TYP_PAGE, seq, page_index); }
-    ((flags & FLAG_COMP) != 0, payload_len)
+    tx_log_append(TYP_PAGE, seq, page_index);
+    ((flags & FLAG_COMP) != 0, hdr.wire_len() + ext_len + payload_len)
 }
 
-fn frame_and_send_manifest(writer: &mut impl MigrWriter, pages: u64, bytes: u64, chunked: bool) {
+/// Send the round's manifest; returns its length on the wire.
+fn frame_and_send_manifest(writer: &mut impl MigrWriter, pages: u64, bytes: u64, chunked: bool) -> usize {
     let mut body = [0u8; 16];
     // pages (8) + bytes (8) little-endian
     body[0] = (pages & 0xFF) as u8; body[1] = ((pages >> 8) & 0xFF) as u8; body[2] = ((pages >> 16) & 0xFF) as u8; body[3] = ((pages >> 24) & 0xFF) as u8;
     body[4] = ((pages >> 32) & 0xFF) as u8; body[5] = ((pages >> 40) & 0xFF) as u8; body[6] = ((pages >> 48) & 0xFF) as u8; body[7] = ((pages >> 56) & 0xFF) as u8;
     body[8] = (bytes & 0xFF) as u8; body[9] = ((bytes >> 8) & 0xFF) as u8; body[10] = ((bytes >> 16) & 0xFF) as u8; body[11] = ((bytes >> 24) & 0xFF) as u8;
     body[12] = ((bytes >> 32) & 0xFF) as u8; body[13] = ((bytes >> 40) & 0xFF) as u8; body[14] = ((bytes >> 48) & 0xFF) as u8; body[15] = ((bytes >> 56) & 0xFF) as u8;
-    let mut hdr = FrameHeader { magic: MAGIC, ver: 1, typ: TYP_MANIFEST, flags: 0, seq: 0, page_index: 0, payload_len: 16, crc32: 0 };
-    let seq = unsafe { let s = G_SEQ; G_SEQ = G_SEQ.wrapping_add(1); s };
+    let mut hdr = FrameHeader::new(wire_ver(), TYP_MANIFEST, 0, 0, 16);
+    let seq = next_seq();
     hdr.seq = seq;
     hdr.crc32 = crate::util::crc32::crc32(&body);
-    let hdr_bytes: &[u8] = unsafe { core::slice::from_raw_parts((&hdr as *const FrameHeader) as *const u8, core::mem::size_of::<FrameHeader>()) };
+    let hdr_bytes = &hdr.to_bytes()[..hdr.wire_len()];
     if chunked { write_chunked(writer, hdr_bytes); } else { let _ = writer.write(hdr_bytes); }
     if chunked { write_chunked(writer, &body); } else { let _ = writer.write(&body); }
     crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_MANIFESTS).inc();
-    unsafe { tx_log_append(TYP_MANIFEST, seq, 0); }
+    tx_log_append(TYP_MANIFEST, seq, 0);
+    hdr.wire_len() + body.len()
 }
 
 #[inline(always)]
@@ -1192,74 +1426,12 @@ fn page_skip_reason(pa: u64) -> Option<u8> {
 }
 
 pub fn send_dirty_pages(system_table: &mut SystemTable<Boot>, compress: bool, sink: ExportSink) -> (u64, u64, u64) {
-    let st = unsafe { G_TRACKER.as_ref() };
-    if st.is_none() { return (0, 0, 0); }
-    let state = st.unwrap();
-    let mut frames = 0u64; let mut pages = 0u64; let mut bytes = 0u64;
-    // Choose writer
-    match sink {
-        ExportSink::Console => {
-            let mut w = ConsoleWriter { system_table };
-            state.bitmap.for_each_set(|page_idx| {
-                let pa = page_idx << 12;
-                if let Some(r) = page_skip_reason(pa) {
-                    if r == 1 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096); }
-                    else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096); }
-                    return;
-                }
-            let (_comp, plen) = frame_and_send_page(&mut w, page_idx, pa, compress, true);
-                frames += 1; pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-            });
-            // Trailer manifest
-            frame_and_send_manifest(&mut w, pages, bytes, true);
-        }
-        ExportSink::Buffer => {
-            let mut w = BufferWriter;
-            state.bitmap.for_each_set(|page_idx| {
-                let pa = page_idx << 12;
-                if let Some(r) = page_skip_reason(pa) {
-                    if r == 1 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096); }
-                    else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096); }
-                    return;
-                }
-                let (_comp, plen) = frame_and_send_page(&mut w, page_idx, pa, compress, true);
-                frames += 1; pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-            });
-            frame_and_send_manifest(&mut w, pages, bytes, true);
-        }
-        ExportSink::Null => {
-            let mut w = NullWriter;
-            state.bitmap.for_each_set(|page_idx| {
-                let pa = page_idx << 12;
-                if let Some(r) = page_skip_reason(pa) {
-                    if r == 1 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096); }
-                    else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096); }
-                    return;
-                }
-                let (_comp, plen) = frame_and_send_page(&mut w, page_idx, pa, compress, true);
-                frames += 1; pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-            });
-            frame_and_send_manifest(&mut w, pages, bytes, true);
-        }
-        ExportSink::Snp => {
-            let mut w = SnpWriter::new(system_table);
-            state.bitmap.for_each_set(|page_idx| {
-                let pa = page_idx << 12;
-                if let Some(r) = page_skip_reason(pa) {
-                    if r == 1 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096); }
-                    else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096); }
-                    return;
-                }
-                // Do not chunk at MIG frame level. Let SnpWriter segment into L2 frames internally.
-                let (_comp, plen) = frame_and_send_page(&mut w, page_idx, pa, compress, false);
-                frames += 1; pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-            });
-            frame_and_send_manifest(&mut w, pages, bytes, false);
-        }
-        ExportSink::Virtio => {
-            #[cfg(feature = "virtio-net")]
-            {
-                let mut w = VirtioNetWriter { system_table };
+    with_tracker(|state| {
+        let mut frames = 0u64; let mut pages = 0u64; let mut bytes = 0u64;
+        // Choose writer
+        match sink {
+            ExportSink::Console => {
+                let mut w = ConsoleWriter { system_table };
                 state.bitmap.for_each_set(|page_idx| {
                     let pa = page_idx << 12;
                     if let Some(r) = page_skip_reason(pa) {
@@ -1267,27 +1439,97 @@ pub fn send_dirty_pages(system_table: &mut SystemTable<Boot>, compress: bool, si
                         else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096); }
                         return;
                     }
-                    let (_comp, plen) = frame_and_send_page(&mut w, page_idx, pa, compress, false);
-                    frames += 1; pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
+                let (_comp, flen) = frame_and_send_page(&mut w, page_idx, pa, compress, true);
+                    frames += 1; pages += 1; bytes += flen as u64;
                 });
-                frame_and_send_manifest(&mut w, pages, bytes, false);
+                // Trailer manifest
+                frame_and_send_manifest(&mut w, pages, bytes, true);
             }
-            #[cfg(not(feature = "virtio-net"))]
-            {
+            ExportSink::Buffer => {
+                let mut w = BufferWriter;
+                state.bitmap.for_each_set(|page_idx| {
+                    let pa = page_idx << 12;
+                    if let Some(r) = page_skip_reason(pa) {
+                        if r == 1 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096); }
+                        else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096); }
+                        return;
+                    }
+                    let (_comp, flen) = frame_and_send_page(&mut w, page_idx, pa, compress, true);
+                    frames += 1; pages += 1; bytes += flen as u64;
+                });
+                frame_and_send_manifest(&mut w, pages, bytes, true);
+            }
+            ExportSink::Null => {
                 let mut w = NullWriter;
                 state.bitmap.for_each_set(|page_idx| {
                     let pa = page_idx << 12;
-                    let (_comp, plen) = frame_and_send_page(&mut w, page_idx, pa, compress, true);
-                    frames += 1; pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
+                    if let Some(r) = page_skip_reason(pa) {
+                        if r == 1 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096); }
+                        else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096); }
+                        return;
+                    }
+                    let (_comp, flen) = frame_and_send_page(&mut w, page_idx, pa, compress, true);
+                    frames += 1; pages += 1; bytes += flen as u64;
                 });
                 frame_and_send_manifest(&mut w, pages, bytes, true);
             }
+            ExportSink::Snp => {
+                let mut w = SnpWriter::new(system_table);
+                state.bitmap.for_each_set(|page_idx| {
+                    let pa = page_idx << 12;
+                    if let Some(r) = page_skip_reason(pa) {
+                        if r == 1 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096); }
+                        else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096); }
+                        return;
+                    }
+                    // Do not chunk at MIG frame level. Let SnpWriter segment into L2 frames internally.
+                    let (_comp, flen) = frame_and_send_page(&mut w, page_idx, pa, compress, false);
+                    frames += 1; pages += 1; bytes += flen as u64;
+                });
+                frame_and_send_manifest(&mut w, pages, bytes, false);
+            }
+            ExportSink::Virtio => {
+                #[cfg(feature = "virtio-net")]
+                {
+                    let mut w = VirtioNetWriter { system_table };
+                    state.bitmap.for_each_set(|page_idx| {
+                        let pa = page_idx << 12;
+                        if let Some(r) = page_skip_reason(pa) {
+                            if r == 1 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ZERO_BYTES_SAVED).add(4096); }
+                            else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_SKIPPED).inc(); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HASH_BYTES_SAVED).add(4096); }
+                            return;
+                        }
+                        let (_comp, flen) = frame_and_send_page(&mut w, page_idx, pa, compress, false);
+                        frames += 1; pages += 1; bytes += flen as u64;
+                    });
+                    frame_and_send_manifest(&mut w, pages, bytes, false);
+                }
+                #[cfg(not(feature = "virtio-net"))]
+                {
+                    let mut w = NullWriter;
+                    state.bitmap.for_each_set(|page_idx| {
+                        let pa = page_idx << 12;
+                        let (_comp, flen) = frame_and_send_page(&mut w, page_idx, pa, compress, true);
+                        frames += 1; pages += 1; bytes += flen as u64;
+                    });
+                    frame_and_send_manifest(&mut w, pages, bytes, true);
+                }
+            }
+            ExportSink::Rdma => {
+                // No zero skipping: the destination region is not known to be
+                // clear. One WRITE per page, then the round's count as immediate.
+                state.bitmap.for_each_set(|page_idx| {
+                    if rdma::send_page(system_table, page_idx).is_err() { return; }
+                    tx_log_append(TYP_PAGE, next_seq(), page_idx);
+                    frames += 1; pages += 1; bytes += 4096;
+                });
+                if rdma::flush(system_table, pages as u32, 1000).is_err() {
+                    crate::obs::log::warn(system_table, "migrate", "rdma round not fully acknowledged");
+                }
+            }
         }
-    }
-    bytes
-        .checked_add(0)
-        .unwrap_or(bytes);
-    (frames, pages, bytes)
+        (frames, pages, bytes)
+    }).unwrap_or((0, 0, 0))
 }
 
 pub fn resend_from(system_table: &mut SystemTable<Boot>, from_seq: u32, max_count: usize, compress: bool, sink: ExportSink) -> (u64, u64) {
@@ -1295,131 +1537,126 @@ pub fn resend_from(system_table: &mut SystemTable<Boot>, from_seq: u32, max_coun
     match sink {
         ExportSink::Console => {
             let mut w = ConsoleWriter { system_table };
-            unsafe {
-                let mut idx = if TX_WIDX > TX_LOG_CAP { TX_WIDX - TX_LOG_CAP } else { 0 };
-                let end = TX_WIDX;
-                while idx < end && (max_count == 0 || (frames as usize) < max_count) {
-                    let e = TX_LOG[idx % TX_LOG_CAP];
-                    idx += 1;
-                    if e.seq < from_seq { continue; }
-                    if e.kind == TYP_PAGE {
-                        let pa = e.page_index << 12;
-                        let (_comp, plen) = frame_and_send_page(&mut w, e.page_index, pa, compress, true);
-                        frames += 1; sent_pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-                    }
+            let (mut idx, end) = tx_window();
+            while idx < end && (max_count == 0 || (frames as usize) < max_count) {
+                let e = tx_entry(idx);
+                idx += 1;
+                if e.seq < from_seq { continue; }
+                if e.kind == TYP_PAGE {
+                    let pa = e.page_index << 12;
+                    let (_comp, flen) = frame_and_send_page(&mut w, e.page_index, pa, compress, true);
+                    frames += 1; sent_pages += 1; bytes += flen as u64;
                 }
-                // send a trailing manifest for the resend window
-                frame_and_send_manifest(&mut w, sent_pages, bytes, true);
             }
+            // send a trailing manifest for the resend window
+            frame_and_send_manifest(&mut w, sent_pages, bytes, true);
         }
         ExportSink::Buffer => {
             let mut w = BufferWriter;
-            unsafe {
-                let mut idx = if TX_WIDX > TX_LOG_CAP { TX_WIDX - TX_LOG_CAP } else { 0 };
-                let end = TX_WIDX;
-                while idx < end && (max_count == 0 || (frames as usize) < max_count) {
-                    let e = TX_LOG[idx % TX_LOG_CAP];
-                    idx += 1;
-                    if e.seq < from_seq { continue; }
-                    if e.kind == TYP_PAGE {
-                        let pa = e.page_index << 12;
-                        let (_comp, plen) = frame_and_send_page(&mut w, e.page_index, pa, compress, true);
-                        frames += 1; sent_pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-                    }
+            let (mut idx, end) = tx_window();
+            while idx < end && (max_count == 0 || (frames as usize) < max_count) {
+                let e = tx_entry(idx);
+                idx += 1;
+                if e.seq < from_seq { continue; }
+                if e.kind == TYP_PAGE {
+                    let pa = e.page_index << 12;
+                    let (_comp, flen) = frame_and_send_page(&mut w, e.page_index, pa, compress, true);
+                    frames += 1; sent_pages += 1; bytes += flen as u64;
                 }
-                frame_and_send_manifest(&mut w, sent_pages, bytes, true);
             }
+            frame_and_send_manifest(&mut w, sent_pages, bytes, true);
         }
         ExportSink::Null => {
             let mut w = NullWriter;
-            unsafe {
-                let mut idx = if TX_WIDX > TX_LOG_CAP { TX_WIDX - TX_LOG_CAP } else { 0 };
-                let end = TX_WIDX;
-                while idx < end && (max_count == 0 || (frames as usize) < max_count) {
-                    let e = TX_LOG[idx % TX_LOG_CAP];
-                    idx += 1;
-                    if e.seq < from_seq { continue; }
-                    if e.kind == TYP_PAGE {
-                        let pa = e.page_index << 12;
-                        let (_comp, plen) = frame_and_send_page(&mut w, e.page_index, pa, compress, true);
-                        frames += 1; sent_pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-                    }
+            let (mut idx, end) = tx_window();
+            while idx < end && (max_count == 0 || (frames as usize) < max_count) {
+                let e = tx_entry(idx);
+                idx += 1;
+                if e.seq < from_seq { continue; }
+                if e.kind == TYP_PAGE {
+                    let pa = e.page_index << 12;
+                    let (_comp, flen) = frame_and_send_page(&mut w, e.page_index, pa, compress, true);
+                    frames += 1; sent_pages += 1; bytes += flen as u64;
                 }
-                frame_and_send_manifest(&mut w, sent_pages, bytes, true);
             }
+            frame_and_send_manifest(&mut w, sent_pages, bytes, true);
         }
         ExportSink::Snp => {
             let mut w = SnpWriter::new(system_table);
-            unsafe {
-                let mut idx = if TX_WIDX > TX_LOG_CAP { TX_WIDX - TX_LOG_CAP } else { 0 };
-                let end = TX_WIDX;
-                while idx < end && (max_count == 0 || (frames as usize) < max_count) {
-                    let e = TX_LOG[idx % TX_LOG_CAP];
-                    idx += 1;
-                    if e.seq < from_seq { continue; }
-                    if e.kind == TYP_PAGE {
-                        let pa = e.page_index << 12;
-                        let (_comp, plen) = frame_and_send_page(&mut w, e.page_index, pa, compress, false);
-                        frames += 1; sent_pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-                    }
+            let (mut idx, end) = tx_window();
+            while idx < end && (max_count == 0 || (frames as usize) < max_count) {
+                let e = tx_entry(idx);
+                idx += 1;
+                if e.seq < from_seq { continue; }
+                if e.kind == TYP_PAGE {
+                    let pa = e.page_index << 12;
+                    let (_comp, flen) = frame_and_send_page(&mut w, e.page_index, pa, compress, false);
+                    frames += 1; sent_pages += 1; bytes += flen as u64;
                 }
-                frame_and_send_manifest(&mut w, sent_pages, bytes, false);
             }
+            frame_and_send_manifest(&mut w, sent_pages, bytes, false);
         }
         ExportSink::Virtio => {
             #[cfg(feature = "virtio-net")]
             {
                 let mut w = VirtioNetWriter { system_table };
-                unsafe {
-                    let mut idx = if TX_WIDX > TX_LOG_CAP { TX_WIDX - TX_LOG_CAP } else { 0 };
-                    let end = TX_WIDX;
-                    while idx < end && (max_count == 0 || (frames as usize) < max_count) {
-                        let e = TX_LOG[idx % TX_LOG_CAP];
-                        idx += 1;
-                        if e.seq < from_seq { continue; }
-                        if e.kind == TYP_PAGE {
-                            let pa = e.page_index << 12;
-                            let (_comp, plen) = frame_and_send_page(&mut w, e.page_index, pa, compress, false);
-                            frames += 1; sent_pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-                        }
+                let (mut idx, end) = tx_window();
+                while idx < end && (max_count == 0 || (frames as usize) < max_count) {
+                    let e = tx_entry(idx);
+                    idx += 1;
+                    if e.seq < from_seq { continue; }
+                    if e.kind == TYP_PAGE {
+                        let pa = e.page_index << 12;
+                        let (_comp, flen) = frame_and_send_page(&mut w, e.page_index, pa, compress, false);
+                        frames += 1; sent_pages += 1; bytes += flen as u64;
                     }
-                    frame_and_send_manifest(&mut w, sent_pages, bytes, false);
                 }
+                frame_and_send_manifest(&mut w, sent_pages, bytes, false);
             }
             #[cfg(not(feature = "virtio-net"))]
             {
                 let mut w = NullWriter;
-                unsafe {
-                    let mut idx = if TX_WIDX > TX_LOG_CAP { TX_WIDX - TX_LOG_CAP } else { 0 };
-                    let end = TX_WIDX;
-                    while idx < end && (max_count == 0 || (frames as usize) < max_count) {
-                        let e = TX_LOG[idx % TX_LOG_CAP];
-                        idx += 1;
-                        if e.seq < from_seq { continue; }
-                        if e.kind == TYP_PAGE {
-                            let pa = e.page_index << 12;
-                            let (_comp, plen) = frame_and_send_page(&mut w, e.page_index, pa, compress, true);
-                            frames += 1; sent_pages += 1; bytes += (core::mem::size_of::<FrameHeader>() + plen) as u64;
-                        }
+                let (mut idx, end) = tx_window();
+                while idx < end && (max_count == 0 || (frames as usize) < max_count) {
+                    let e = tx_entry(idx);
+                    idx += 1;
+                    if e.seq < from_seq { continue; }
+                    if e.kind == TYP_PAGE {
+                        let pa = e.page_index << 12;
+                        let (_comp, flen) = frame_and_send_page(&mut w, e.page_index, pa, compress, true);
+                        frames += 1; sent_pages += 1; bytes += flen as u64;
                     }
-                    frame_and_send_manifest(&mut w, sent_pages, bytes, true);
                 }
+                frame_and_send_manifest(&mut w, sent_pages, bytes, true);
+            }
+        }
+        ExportSink::Rdma => {
+            let (mut idx, end) = tx_window();
+            while idx < end && (max_count == 0 || (frames as usize) < max_count) {
+                let e = tx_entry(idx);
+                idx += 1;
+                if e.seq < from_seq || e.kind != TYP_PAGE { continue; }
+                if rdma::send_page(system_table, e.page_index).is_err() { break; }
+                frames += 1; sent_pages += 1; bytes += 4096;
             }
+            let _ = rdma::flush(system_table, sent_pages as u32, 1000);
         }
     }
     (frames, bytes)
 }
 
+fn frame_and_send_ctrl_body(writer: &mut impl MigrWriter, ver: u8, body: &[u8]) {
+    let mut hdr = FrameHeader::new(ver, TYP_CTRL, 0, 0, body.len() as u32);
+    hdr.seq = next_seq();
+    hdr.crc32 = crate::util::crc32::crc32(body);
+    write_chunked(writer, &hdr.to_bytes()[..hdr.wire_len()]);
+    write_chunked(writer, body);
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CTRL_FRAMES).inc();
+}
+
 fn frame_and_send_ctrl(writer: &mut impl MigrWriter, code: u8, seq_to_ref: u32) {
     let body = [code, (seq_to_ref & 0xFF) as u8, ((seq_to_ref >> 8) & 0xFF) as u8, ((seq_to_ref >> 16) & 0xFF) as u8, ((seq_to_ref >> 24) & 0xFF) as u8];
-    let mut hdr = FrameHeader { magic: MAGIC, ver: 1, typ: TYP_CTRL, flags: 0, seq: 0, page_index: 0, payload_len: body.len() as u32, crc32: 0 };
-    let seq = unsafe { let s = G_SEQ; G_SEQ = G_SEQ.wrapping_add(1); s };
-    hdr.seq = seq;
-    hdr.crc32 = crate::util::crc32::crc32(&body);
-    let hdr_bytes: &[u8] = unsafe { core::slice::from_raw_parts((&hdr as *const FrameHeader) as *const u8, core::mem::size_of::<FrameHeader>()) };
-    write_chunked(writer, hdr_bytes);
-    write_chunked(writer, &body);
-    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CTRL_FRAMES).inc();
+    frame_and_send_ctrl_body(writer, wire_ver(), &body);
     if code == CTRL_ACK { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_ACKS).inc(); }
     if code == CTRL_NAK { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_NAKS).inc(); }
 }
@@ -1436,58 +1673,131 @@ pub fn send_ctrl(system_table: &mut SystemTable<Boot>, ack: bool, seq_to_ref: u3
             #[cfg(not(feature = "virtio-net"))]
             { let mut w = NullWriter; frame_and_send_ctrl(&mut w, if ack { CTRL_ACK } else { CTRL_NAK }, seq_to_ref); }
         }
+        // The queue pair acknowledges by itself; control frames only count.
+        ExportSink::Rdma => { let mut w = NullWriter; frame_and_send_ctrl(&mut w, if ack { CTRL_ACK } else { CTRL_NAK }, seq_to_ref); }
+    }
+}
+
+/// Check the frames in one received packet (either version) and append
+/// those whose CRC matches to the channel, updating the receive and
+/// ordering counters. A bad frame is skipped one byte at a time, as a
+/// header may start inside it. Returns the frames appended.
+pub fn ingest_packet(data: &[u8]) -> usize {
+    let mut appended = 0usize;
+    let mut expected_seq = crate::obs::metrics::MIG_LAST_SEQ.load(core::sync::atomic::Ordering::Relaxed) as u32;
+    let mut pos = 0usize;
+    while let wire::Scan::Frame { at, hdr: h, frame, ext, payload } = wire::scan(data, pos) {
+        let crc_calc = crate::util::crc32::crc32_update(crate::util::crc32::crc32(ext), payload);
+        if crc_calc != h.crc32 {
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_BAD).inc();
+            pos = at + 1;
+            continue;
+        }
+        let _ = chan_write(frame);
+        let seq = h.seq;
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_OK).inc();
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_BYTES).add(frame.len() as u64);
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_FRAMES).inc();
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_BYTES).add(frame.len() as u64);
+        // Ordering diagnostics
+        if expected_seq != 0 {
+            let next = expected_seq.wrapping_add(1);
+            if seq == next { /* in order */ }
+            else if seq < next { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_DUP_FRAMES).inc(); }
+            else { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_MISSING_FRAMES).inc(); }
+        }
+        expected_seq = seq;
+        crate::obs::metrics::MIG_LAST_SEQ.store(seq as u64, core::sync::atomic::Ordering::Relaxed);
+        appended += 1;
+        pos = at + frame.len();
+    }
+    appended
+}
+
+// ---- Version negotiation ----
+
+/// Send a hello announcing this build's newest version and capabilities.
+/// Hellos always go out as version 1 frames so any peer can read them;
+/// `reply` marks one sent in answer to the peer's.
+pub fn send_hello(system_table: &mut SystemTable<Boot>, sink: ExportSink, reply: bool) {
+    let body = Hello { max_ver: MAX_VER, caps: LOCAL_CAPS, reply }.to_body();
+    match sink {
+        ExportSink::Console => { let mut w = ConsoleWriter { system_table }; frame_and_send_ctrl_body(&mut w, VER1, &body); }
+        ExportSink::Buffer => { let mut w = BufferWriter; frame_and_send_ctrl_body(&mut w, VER1, &body); }
+        ExportSink::Null => { let mut w = NullWriter; frame_and_send_ctrl_body(&mut w, VER1, &body); }
+        ExportSink::Snp => { let mut w = SnpWriter::new(system_table); frame_and_send_ctrl_body(&mut w, VER1, &body); }
+        ExportSink::Virtio => {
+            #[cfg(feature = "virtio-net")]
+            { let mut w = VirtioNetWriter { system_table }; frame_and_send_ctrl_body(&mut w, VER1, &body); }
+            #[cfg(not(feature = "virtio-net"))]
+            { let mut w = NullWriter; frame_and_send_ctrl_body(&mut w, VER1, &body); }
+        }
+        ExportSink::Rdma => { let mut w = NullWriter; frame_and_send_ctrl_body(&mut w, VER1, &body); }
     }
 }
 
+/// Record a peer's hello and switch to the version both speak. A hello
+/// that is not itself a reply is answered on the resend sink.
+fn on_hello(system_table: &mut SystemTable<Boot>, h: Hello) {
+    let ver = h.negotiate();
+    STATE.lock(|s| { s.peer = Some(h); s.wire_ver = ver; });
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_HELLOS).inc();
+    crate::log!(Info, "migrate", "hello: peer max_ver={} caps={:#x}, sending v{}", h.max_ver, h.caps, ver);
+    if !h.reply { send_hello(system_table, ctrl_get_resend_sink(), true); }
+}
+
+/// Version frames are sent with, and the last hello from the peer.
+pub fn wire_status() -> (u8, Option<Hello>) { STATE.lock(|s| (s.wire_ver, s.peer)) }
+
+/// Forget the peer and go back to version 1.
+pub fn wire_reset() { STATE.lock(|s| { s.wire_ver = VER1; s.peer = None; }) }
+
 pub fn chan_handle_ctrl(system_table: &mut SystemTable<Boot>, limit: usize) {
-    unsafe {
-        if let Some(b) = G_BUF.as_ref() {
-            let start = if b.len == 0 { 0 } else { (b.wpos + b.cap - b.len) % b.cap };
-            let mut cur = ChanCursor { ptr: b.ptr as *const u8, cap: b.cap, pos: start, remaining: b.len };
-            let mut handled = 0usize;
-            let mut hb = [0u8; 32];
-            while cur.remaining >= size_of::<FrameHeader>() && (limit == 0 || handled < limit) {
-                let mut hdr_bytes = [0u8; 32];
-                let mut tmp = cur;
-                if !tmp.read_into(&mut hdr_bytes) { break; }
-                if &hdr_bytes[0..4] != &MAGIC { let _ = cur.skip(1); continue; }
-                let typ = hdr_bytes[5];
-                let payload_len = le_u32(&hdr_bytes[20..24]) as usize;
-                let _ = cur.read_into(&mut hb[..size_of::<FrameHeader>()]);
-                if cur.remaining < payload_len { break; }
-                if typ == TYP_CTRL {
-                    let mut body = [0u8; 8];
-                    let take = if payload_len <= body.len() { payload_len } else { body.len() };
-                    if !cur.read_into(&mut body[..take]) { break; }
-                    if payload_len > take { let _ = cur.skip(payload_len - take); }
-                    let code = body[0];
-                    let seq = le_u32(&body[1..5]);
-                // Action on NAK: trigger resend from seq to configured sink
-                if code == CTRL_NAK {
-                    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RESEND_TRIGGERS).inc();
-                    let sink = ctrl_get_resend_sink();
-                    let (_f,_b) = resend_from(system_table, seq, 0, false, sink);
-                    if ctrl_get_auto_nak() { send_ctrl(system_table, false, seq, sink); }
-                }
-                    if code == CTRL_ACK {
-                    if ctrl_get_auto_ack() { let sink = ctrl_get_resend_sink(); send_ctrl(system_table, true, seq, sink); }
-                    }
+    if let Some(mut cur) = chan_cursor() {
+        let mut handled = 0usize;
+        let mut eb = [0u8; MAX_EXT];
+        while cur.remaining >= FrameHeader::V1_LEN && (limit == 0 || handled < limit) {
+            let Some(h) = cursor_header(&mut cur) else { continue; };
+            let (typ, payload_len) = (h.typ, h.payload_len as usize);
+            if cur.remaining < h.body_len() { break; }
+            let ext_ok = cursor_ext(&mut cur, &h, &mut eb).is_some();
+            if typ == TYP_CTRL && ext_ok {
+                let mut body = [0u8; 16];
+                let take = if payload_len <= body.len() { payload_len } else { body.len() };
+                if !cur.read_into(&mut body[..take]) { break; }
+                if payload_len > take { let _ = cur.skip(payload_len - take); }
+                if let Some(hello) = Hello::parse(&body[..take]) {
+                    on_hello(system_table, hello);
                     handled += 1;
-                    let mut out = [0u8; 64]; let mut n = 0;
-                    for &bch in b"ctrl: " { out[n] = bch; n += 1; }
-                    let s = if code == CTRL_ACK { b"ack" } else { b"nak" };
-                    for &bch in s { out[n] = bch; n += 1; }
-                    for &bch in b" seq=" { out[n] = bch; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(seq, &mut out[n..]);
-                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
-                    let stdout = system_table.stdout();
-                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
-                } else {
-                    let _ = cur.skip(payload_len);
+                    continue;
                 }
+                let Some((code, seq)) = ctrl_parse(&body[..take]) else { continue; };
+            // Action on NAK: trigger resend from seq to configured sink
+            if code == CTRL_NAK {
+                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RESEND_TRIGGERS).inc();
+                let sink = ctrl_get_resend_sink();
+                let (_f,_b) = resend_from(system_table, seq, 0, false, sink);
+                if ctrl_get_auto_nak() { send_ctrl(system_table, false, seq, sink); }
+            }
+                if code == CTRL_ACK {
+                on_ack(seq);
+                if ctrl_get_auto_ack() { let sink = ctrl_get_resend_sink(); send_ctrl(system_table, true, seq, sink); }
+                }
+                handled += 1;
+                let mut out = [0u8; 64]; let mut n = 0;
+                for &bch in b"ctrl: " { out[n] = bch; n += 1; }
+                let s = if code == CTRL_ACK { b"ack" } else { b"nak" };
+                for &bch in s { out[n] = bch; n += 1; }
+                for &bch in b" seq=" { out[n] = bch; n += 1; }
+                n += crate::util::format::u32_dec(seq, &mut out[n..]);
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let stdout = system_table.stdout();
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+            } else {
+                let _ = cur.skip(payload_len);
             }
-            return;
         }
+        return;
     }
     let lang = crate::i18n::detect_lang(&*system_table);
     let stdout = system_table.stdout();
@@ -1497,7 +1807,7 @@ pub fn chan_handle_ctrl(system_table: &mut SystemTable<Boot>, limit: usize) {
 #[inline(always)]
 fn write_chunked(writer: &mut impl MigrWriter, buf: &[u8]) -> usize {
     let mut written = 0usize;
-    let chunk = unsafe { if G_CHUNK == 0 { 1500 } else { G_CHUNK } };
+    let chunk = get_chunk_size();
     let mut off = 0usize;
     while off < buf.len() {
         let take = core::cmp::min(chunk, buf.len() - off);
@@ -1507,8 +1817,8 @@ fn write_chunked(writer: &mut impl MigrWriter, buf: &[u8]) -> usize {
     written
 }
 
-pub fn set_chunk_size(bytes: usize) { unsafe { G_CHUNK = if bytes == 0 { 1500 } else { bytes }; } }
-pub fn get_chunk_size() -> usize { unsafe { if G_CHUNK == 0 { 1500 } else { G_CHUNK } } }
+pub fn set_chunk_size(bytes: usize) { STATE.lock(|s| s.chunk = if bytes == 0 { 1500 } else { bytes }) }
+pub fn get_chunk_size() -> usize { STATE.lock(|s| if s.chunk == 0 { 1500 } else { s.chunk }) }
 
 // ---- Persist simple migration configuration in UEFI variables ----
 
@@ -1518,7 +1828,7 @@ pub fn cfg_save(system_table: &SystemTable<Boot>) {
     let rs = system_table.runtime_services();
     // Save chunk size and next seq
     let chunk = get_chunk_size() as u32;
-    let seq = unsafe { G_SEQ };
+    let seq = peek_seq();
     let mut buf = [0u8; 8];
     buf[0] = (chunk & 0xFF) as u8; buf[1] = ((chunk >> 8) & 0xFF) as u8; buf[2] = ((chunk >> 16) & 0xFF) as u8; buf[3] = ((chunk >> 24) & 0xFF) as u8;
     buf[4] = (seq & 0xFF) as u8; buf[5] = ((seq >> 8) & 0xFF) as u8; buf[6] = ((seq >> 16) & 0xFF) as u8; buf[7] = ((seq >> 24) & 0xFF) as u8;
@@ -1540,6 +1850,7 @@ pub fn cfg_save(system_table: &SystemTable<Boot>) {
     nbuf[15] = def_sink;
     let _ = rs.set_variable(uefi::cstr16!("ZerovisorMigNet"), &VAR_NS, uefi::table::runtime::VariableAttributes::BOOTSERVICE_ACCESS, &nbuf);
     crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CFG_SAVES).inc();
+    crate::cluster::store::note_migrate();
 }
 
 pub fn cfg_load(system_table: &SystemTable<Boot>) {
@@ -1550,7 +1861,7 @@ pub fn cfg_load(system_table: &SystemTable<Boot>) {
             let chunk = (data[0] as u32) | ((data[1] as u32) << 8) | ((data[2] as u32) << 16) | ((data[3] as u32) << 24);
             let seq = (data[4] as u32) | ((data[5] as u32) << 8) | ((data[6] as u32) << 16) | ((data[7] as u32) << 24);
             set_chunk_size(chunk as usize);
-            unsafe { G_SEQ = seq; }
+            STATE.lock(|s| s.seq = seq);
             crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CFG_LOADS).inc();
         }
     }
@@ -1576,6 +1887,9 @@ pub fn cfg_load(system_table: &SystemTable<Boot>) {
 
 // ---- Channel frame verification ----
 
+/// Read position in the channel buffer. Only `chan_cursor` builds one, from
+/// a live buffer whose pages are never freed, so reads through it are in
+/// bounds even after the state lock is released.
 #[derive(Clone, Copy)]
 struct ChanCursor {
     ptr: *const u8,
@@ -1585,31 +1899,31 @@ struct ChanCursor {
 }
 
 impl ChanCursor {
-    unsafe fn read_into(&mut self, dst: &mut [u8]) -> bool {
+    fn read_into(&mut self, dst: &mut [u8]) -> bool {
         if self.remaining < dst.len() { return false; }
         let first = core::cmp::min(dst.len(), self.cap - self.pos);
-        core::ptr::copy_nonoverlapping(self.ptr.add(self.pos), dst.as_mut_ptr(), first);
+        unsafe { core::ptr::copy_nonoverlapping(self.ptr.add(self.pos), dst.as_mut_ptr(), first); }
         self.pos = (self.pos + first) % self.cap; self.remaining -= first;
         if first < dst.len() {
             let rest = dst.len() - first;
-            core::ptr::copy_nonoverlapping(self.ptr.add(self.pos), dst.as_mut_ptr().add(first), rest);
+            unsafe { core::ptr::copy_nonoverlapping(self.ptr.add(self.pos), dst.as_mut_ptr().add(first), rest); }
             self.pos = (self.pos + rest) % self.cap; self.remaining -= rest;
         }
         true
     }
-    unsafe fn skip(&mut self, n: usize) -> bool {
+    fn skip(&mut self, n: usize) -> bool {
         if self.remaining < n { return false; }
         let adv = n % self.cap;
         self.pos = (self.pos + adv) % self.cap; self.remaining -= n; true
     }
-    unsafe fn checksum(&self, mut len: usize) -> u32 {
+    fn checksum(&self, len: usize) -> u32 {
         let mut c = 0xFFFF_FFFFu32;
         let mut pos = self.pos; let mut rem = self.remaining;
         let mut l = len;
         let mut tmp = [0u8; 64];
         while l > 0 && rem > 0 {
             let take = core::cmp::min(core::cmp::min(l, tmp.len()), self.cap - pos);
-            core::ptr::copy_nonoverlapping(self.ptr.add(pos), tmp.as_mut_ptr(), take);
+            unsafe { core::ptr::copy_nonoverlapping(self.ptr.add(pos), tmp.as_mut_ptr(), take); }
             c = crate::util::crc32::crc32_update(!c, &tmp[..take]);
             c = !c;
             pos = (pos + take) % self.cap; rem -= take; l -= take;
@@ -1618,8 +1932,32 @@ impl ChanCursor {
     }
 }
 
-fn le_u32(b: &[u8]) -> u32 { (b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24) }
-fn le_u64(b: &[u8]) -> u64 { (le_u32(&b[0..4]) as u64) | ((le_u32(&b[4..8]) as u64) << 32) }
+/// Header at the cursor, consumed; None if there is none, in which case
+/// the cursor has moved on by one byte to resynchronise.
+fn cursor_header(cur: &mut ChanCursor) -> Option<FrameHeader> {
+    let mut b = [0u8; FrameHeader::LEN];
+    let n = core::cmp::min(cur.remaining, b.len());
+    let mut tmp = *cur;
+    if !tmp.read_into(&mut b[..n]) { return None; }
+    let Some(h) = FrameHeader::parse(&b[..n]) else { let _ = cur.skip(1); return None; };
+    let _ = cur.skip(h.wire_len());
+    Some(h)
+}
+
+fn known_tlv(t: u8) -> bool { t == TLV_NUMA_NODE }
+
+/// Consume the TLV area of `h` into `buf`. None if it is longer than
+/// `MAX_EXT`, malformed, or holds a critical type we do not know; the frame
+/// must then be dropped.
+fn cursor_ext<'a>(cur: &mut ChanCursor, h: &FrameHeader, buf: &'a mut [u8; MAX_EXT]) -> Option<&'a [u8]> {
+    let len = h.ext_len as usize;
+    if len > MAX_EXT || !cur.read_into(&mut buf[..len]) || !tlv_acceptable(&buf[..len], known_tlv) {
+        if len > MAX_EXT { let _ = cur.skip(len); }
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_TLV_REJECTS).inc();
+        return None;
+    }
+    Some(&buf[..len])
+}
 
 pub fn chan_verify(system_table: &mut SystemTable<Boot>, limit: usize, quiet: bool) {
     chan_verify_ex(system_table, limit, quiet, false);
@@ -1627,159 +1965,142 @@ pub fn chan_verify(system_table: &mut SystemTable<Boot>, limit: usize, quiet: bo
 
 pub fn chan_verify_ex(system_table: &mut SystemTable<Boot>, limit: usize, quiet: bool, auto_ctrl: bool) {
     let stdout = system_table.stdout();
-    unsafe {
-        if let Some(b) = G_BUF.as_ref() {
-            let start = if b.len == 0 { 0 } else { (b.wpos + b.cap - b.len) % b.cap };
-            let mut cur = ChanCursor { ptr: b.ptr as *const u8, cap: b.cap, pos: start, remaining: b.len };
-            let mut frames = 0usize; let mut ok = 0usize; let mut bad = 0usize;
-            let mut expected_seq: u32 = 0;
-            let mut hb = [0u8; 32];
-            while cur.remaining >= size_of::<FrameHeader>() && (limit == 0 || frames < limit) {
-                // Peek header
-                let mut hdr_bytes = [0u8; 32];
-                let mut tmp = cur; // copy
-                if !tmp.read_into(&mut hdr_bytes) { break; }
-                if &hdr_bytes[0..4] != &MAGIC {
-                    // realign by one byte
-                    if !cur.skip(1) { break; }
-                    continue;
-                }
-                let ver = hdr_bytes[4]; let typ = hdr_bytes[5];
-                let flags = (hdr_bytes[6] as u16) | ((hdr_bytes[7] as u16) << 8);
-                let seq = le_u32(&hdr_bytes[8..12]);
-                let page_index = le_u64(&hdr_bytes[12..20]);
-                let payload_len = le_u32(&hdr_bytes[20..24]) as usize;
-                let crc = le_u32(&hdr_bytes[24..28]);
-                // Consume header
-                let _ = cur.read_into(&mut hb[..size_of::<FrameHeader>()]);
-                if cur.remaining < payload_len { break; }
-                let ccalc = cur.checksum(payload_len);
-                let _ = cur.skip(payload_len);
-                let good = ccalc == crc;
-                frames += 1; if good { ok += 1; } else { bad += 1; }
-                // Track simple ordering diagnostics
-                if expected_seq != 0 && seq == expected_seq { /* in order */ }
-                else if expected_seq != 0 && seq < expected_seq {
-                    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_DUP_FRAMES).inc();
-                    if auto_ctrl { send_ctrl(system_table, true, seq, ctrl_get_resend_sink()); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CTRL_AUTO_ACK_SENT).inc(); }
-                } else if expected_seq != 0 && seq > expected_seq {
-                    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_MISSING_FRAMES).inc();
-                    if auto_ctrl { send_ctrl(system_table, false, expected_seq, ctrl_get_resend_sink()); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CTRL_AUTO_NAK_SENT).inc(); }
-                }
-                expected_seq = seq.wrapping_add(1);
-                crate::obs::metrics::MIG_LAST_SEQ.store(seq as u64, core::sync::atomic::Ordering::Relaxed);
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_BYTES).add((size_of::<FrameHeader>() + payload_len) as u64);
-                if good { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_OK).inc(); }
-                else {
-                    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_BAD).inc();
-                    if auto_ctrl { send_ctrl(system_table, false, seq, ctrl_get_resend_sink()); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CTRL_AUTO_NAK_SENT).inc(); }
+    if let Some(mut cur) = chan_cursor() {
+        let mut frames = 0usize; let mut ok = 0usize; let mut bad = 0usize;
+        let mut expected_seq: u32 = 0;
+        let mut eb = [0u8; MAX_EXT];
+        while cur.remaining >= FrameHeader::V1_LEN && (limit == 0 || frames < limit) {
+            // Header, or realign by one byte
+            let Some(h) = cursor_header(&mut cur) else { continue; };
+            let (typ, seq, page_index, payload_len, crc) = (h.typ, h.seq, h.page_index, h.payload_len as usize, h.crc32);
+            if cur.remaining < h.body_len() { break; }
+            let ccalc = cur.checksum(h.body_len());
+            let ext = cursor_ext(&mut cur, &h, &mut eb);
+            let node = ext.and_then(|e| tlv_find(e, TLV_NUMA_NODE)).and_then(|v| v.first().copied());
+            let ext_ok = ext.is_some();
+            let _ = cur.skip(payload_len);
+            let good = ccalc == crc && ext_ok;
+            if h.ver >= VER2 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_V2_FRAMES).inc(); }
+            frames += 1; if good { ok += 1; } else { bad += 1; }
+            // Track simple ordering diagnostics
+            if expected_seq != 0 && seq == expected_seq { /* in order */ }
+            else if expected_seq != 0 && seq < expected_seq {
+                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_DUP_FRAMES).inc();
+                if auto_ctrl { send_ctrl(system_table, true, seq, ctrl_get_resend_sink()); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CTRL_AUTO_ACK_SENT).inc(); }
+            } else if expected_seq != 0 && seq > expected_seq {
+                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_MISSING_FRAMES).inc();
+                if auto_ctrl { send_ctrl(system_table, false, expected_seq, ctrl_get_resend_sink()); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CTRL_AUTO_NAK_SENT).inc(); }
+            }
+            expected_seq = seq.wrapping_add(1);
+            crate::obs::metrics::MIG_LAST_SEQ.store(seq as u64, core::sync::atomic::Ordering::Relaxed);
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_BYTES).add((h.wire_len() + h.body_len()) as u64);
+            if good { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_OK).inc(); }
+            else {
+                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_BAD).inc();
+                if auto_ctrl { send_ctrl(system_table, false, seq, ctrl_get_resend_sink()); crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_CTRL_AUTO_NAK_SENT).inc(); }
+            }
+            if !quiet {
+                let mut out = [0u8; 128]; let mut n = 0;
+                for &bch in b"verify: typ=" { out[n] = bch; n += 1; }
+        let t: &[u8] = if typ == TYP_MANIFEST { b"manifest" } else { b"page" };
+                for &bch in t { out[n] = bch; n += 1; }
+                for &bch in b" seq=" { out[n] = bch; n += 1; }
+                n += crate::util::format::u32_dec(seq, &mut out[n..]);
+                if typ != TYP_MANIFEST {
+                    for &bch in b" page=" { out[n] = bch; n += 1; }
+                    n += crate::util::format::u32_dec(page_index as u32, &mut out[n..]);
                 }
-                if !quiet {
-                    let mut out = [0u8; 128]; let mut n = 0;
-                    for &bch in b"verify: typ=" { out[n] = bch; n += 1; }
-            let t: &[u8] = if typ == TYP_MANIFEST { b"manifest" } else { b"page" };
-                    for &bch in t { out[n] = bch; n += 1; }
-                    for &bch in b" seq=" { out[n] = bch; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(seq, &mut out[n..]);
-                    if typ != TYP_MANIFEST {
-                        for &bch in b" page=" { out[n] = bch; n += 1; }
-                        n += crate::firmware::acpi::u32_to_dec(page_index as u32, &mut out[n..]);
-                    }
-                    for &bch in b" len=" { out[n] = bch; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(payload_len as u32, &mut out[n..]);
-                    for &bch in b" " { out[n] = bch; n += 1; }
-            let s: &[u8] = if good { b"ok" } else { b"bad" };
-                    for &bch in s { out[n] = bch; n += 1; }
-                    out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
-                    let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+                for &bch in b" len=" { out[n] = bch; n += 1; }
+                n += crate::util::format::u32_dec(payload_len as u32, &mut out[n..]);
+                if h.ver >= VER2 { for &bch in b" v2" { out[n] = bch; n += 1; } }
+                if let Some(nd) = node {
+                    for &bch in b" node=" { out[n] = bch; n += 1; }
+                    n += crate::util::format::u32_dec(nd as u32, &mut out[n..]);
                 }
+                for &bch in b" " { out[n] = bch; n += 1; }
+        let s: &[u8] = if good { b"ok" } else { b"bad" };
+                for &bch in s { out[n] = bch; n += 1; }
+                out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+                let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
             }
-            let mut out = [0u8; 96]; let mut n = 0;
-            for &bch in b"verify: frames=" { out[n] = bch; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(frames as u32, &mut out[n..]);
-            for &bch in b" ok=" { out[n] = bch; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(ok as u32, &mut out[n..]);
-            for &bch in b" bad=" { out[n] = bch; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(bad as u32, &mut out[n..]);
-            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
-            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
-            return;
         }
+        let mut out = [0u8; 96]; let mut n = 0;
+        for &bch in b"verify: frames=" { out[n] = bch; n += 1; }
+        n += crate::util::format::u32_dec(frames as u32, &mut out[n..]);
+        for &bch in b" ok=" { out[n] = bch; n += 1; }
+        n += crate::util::format::u32_dec(ok as u32, &mut out[n..]);
+        for &bch in b" bad=" { out[n] = bch; n += 1; }
+        n += crate::util::format::u32_dec(bad as u32, &mut out[n..]);
+        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+        return;
     }
     let _ = system_table.stdout().write_str("migrate: no buffer\r\n");
 }
 
 // ---- Replay (decompress and reconstruct) to a scratch buffer ----
 
+/// Decode one page payload at `cur` into the 4KiB page at `dst` (raw copy or
+/// RLE expansion per `flags`). Returns false if the payload is truncated or
+/// expands past the page.
+unsafe fn replay_payload(cur: &mut ChanCursor, flags: u16, payload_len: usize, dst: *mut u8) -> bool {
+    if (flags & FLAG_COMP) == 0 {
+        // Raw; copy up to 4KiB
+        let to_read = core::cmp::min(4096, payload_len);
+        let mut copied = 0usize;
+        while copied < to_read {
+            let take = core::cmp::min(to_read - copied, 64);
+            let mut buf = [0u8; 64];
+            if !cur.read_into(&mut buf[..take]) { return false; }
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), dst.add(copied), take);
+            copied += take;
+        }
+        if payload_len > to_read { let _ = cur.skip(payload_len - to_read); }
+        return true;
+    }
+    let page = core::slice::from_raw_parts_mut(dst, 4096);
+    rle_decode(|| {
+        let mut pair = [0u8; 2];
+        if cur.remaining < 2 || !cur.read_into(&mut pair) { return None; }
+        Some(pair)
+    }, page)
+}
+
 pub fn replay_to_buffer(system_table: &mut SystemTable<Boot>, max_pages: usize) {
     let stdout = system_table.stdout();
-    unsafe {
-        if let Some(b) = G_BUF.as_ref() {
-            // Allocate a scratch page for reconstructed data
-            // Avoid holding stdout across allocation calls
-            let scratch = crate::mm::uefi::alloc_pages(system_table, 1, MemoryType::LOADER_DATA);
-            if scratch.is_none() { let stdout2 = system_table.stdout(); let _ = stdout2.write_str("replay: alloc failed\r\n"); return; }
-            let scratch = scratch.unwrap();
-            let start = if b.len == 0 { 0 } else { (b.wpos + b.cap - b.len) % b.cap };
-            let mut cur = ChanCursor { ptr: b.ptr as *const u8, cap: b.cap, pos: start, remaining: b.len };
-            let mut pages_done = 0usize; let mut bytes_done = 0usize; let mut errors = 0usize;
-            let mut hdr = [0u8; 32];
-            while cur.remaining >= size_of::<FrameHeader>() && (max_pages == 0 || pages_done < max_pages) {
-                // Peek alignment
-                    let mut tmp = cur; if !tmp.read_into(&mut hdr) { break; }
-                    if &hdr[0..4] != &MAGIC { let _ = cur.skip(1); continue; }
-                let payload_len = le_u32(&hdr[20..24]) as usize;
-                let flags = (hdr[6] as u16) | ((hdr[7] as u16) << 8);
-                    let _ = cur.read_into(&mut hdr);
-                // Bounds
-                if cur.remaining < payload_len { break; }
-                // Reconstruct into scratch: either raw 4KiB or RLE expand
-                if (flags & FLAG_COMP) == 0 {
-                    // Raw; copy up to 4KiB
-                    let to_read = core::cmp::min(4096, payload_len);
-                    let mut copied = 0usize;
-                    while copied < to_read {
-                        let take = core::cmp::min(to_read - copied, 64);
-                        let mut buf = [0u8; 64];
-                        if !cur.read_into(&mut buf[..take]) { errors += 1; break; }
-                        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), scratch.add(copied), take); }
-                        copied += take;
-                    }
-                        if payload_len > to_read { let _ = cur.skip(payload_len - to_read); }
-                } else {
-                    // RLE decompress
-                    let mut wrote = 0usize;
-                    while wrote < 4096 {
-                        if cur.remaining < 2 { errors += 1; break; }
-                        let mut pair = [0u8; 2];
-                        if !cur.read_into(&mut pair) { errors += 1; break; }
-                        let v = pair[0]; let run = pair[1] as usize;
-                        if wrote + run > 4096 { errors += 1; break; }
-                        unsafe { core::ptr::write_bytes(scratch.add(wrote), v, run); }
-                        wrote += run;
-                    }
-                    // If extra payload due to over-provision, skip remainder
-                    if cur.remaining > 0 && wrote == 4096 {
-                        // No-op; we consumed exactly the page payload encoded
-                    }
-                }
-                pages_done += 1; bytes_done += 4096;
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_REPLAY_PAGES).inc();
-                crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_REPLAY_BYTES).add(4096);
-            }
-            crate::mm::uefi::free_pages(system_table, scratch, 1);
-            let mut out = [0u8; 96]; let mut n = 0;
-            for &bch in b"replay: pages=" { out[n] = bch; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(pages_done as u32, &mut out[n..]);
-            for &bch in b" bytes=" { out[n] = bch; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(bytes_done as u32, &mut out[n..]);
-            for &bch in b" errors=" { out[n] = bch; n += 1; }
-            n += crate::firmware::acpi::u32_to_dec(errors as u32, &mut out[n..]);
-            out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
-            let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
-            if errors > 0 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_REPLAY_ERRORS).add(errors as u64); }
-            return;
+    if let Some(mut cur) = chan_cursor() {
+        // Allocate a scratch page for reconstructed data
+        let scratch = crate::mm::uefi::alloc_pages(system_table, 1, MemoryType::LOADER_DATA);
+        if scratch.is_none() { crate::log!(Error, "migrate", "replay: scratch page allocation failed"); return; }
+        let scratch = scratch.unwrap();
+        let mut pages_done = 0usize; let mut bytes_done = 0usize; let mut errors = 0usize;
+        let mut eb = [0u8; MAX_EXT];
+        while cur.remaining >= FrameHeader::V1_LEN && (max_pages == 0 || pages_done < max_pages) {
+            // Header, or realign by one byte
+            let Some(h) = cursor_header(&mut cur) else { continue; };
+            let (payload_len, flags) = (h.payload_len as usize, h.flags);
+            // Bounds
+            if cur.remaining < h.body_len() { break; }
+            // Metadata only hints; a frame we must not interpret is skipped
+            if cursor_ext(&mut cur, &h, &mut eb).is_none() { let _ = cur.skip(payload_len); errors += 1; continue; }
+            // Reconstruct into scratch: either raw 4KiB or RLE expand
+            if !unsafe { replay_payload(&mut cur, flags, payload_len, scratch) } { errors += 1; }
+            pages_done += 1; bytes_done += 4096;
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_REPLAY_PAGES).inc();
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_REPLAY_BYTES).add(4096);
         }
+        crate::mm::uefi::free_pages(system_table, scratch, 1);
+        let mut out = [0u8; 96]; let mut n = 0;
+        for &bch in b"replay: pages=" { out[n] = bch; n += 1; }
+        n += crate::util::format::u32_dec(pages_done as u32, &mut out[n..]);
+        for &bch in b" bytes=" { out[n] = bch; n += 1; }
+        n += crate::util::format::u32_dec(bytes_done as u32, &mut out[n..]);
+        for &bch in b" errors=" { out[n] = bch; n += 1; }
+        n += crate::util::format::u32_dec(errors as u32, &mut out[n..]);
+        out[n] = b'\r'; n += 1; out[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+        if errors > 0 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_REPLAY_ERRORS).add(errors as u64); }
+        return;
     }
     let _ = system_table.stdout().write_str("replay: no buffer\r\n");
 }
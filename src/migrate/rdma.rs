@@ -0,0 +1,501 @@
+#![allow(dead_code)]
+
+//! RDMA transport for migration: RDMA WRITEs over RoCEv2.
+//!
+//! The destination registers the memory that will hold the guest's RAM with
+//! its RDMA NIC, brings up a reliable-connected queue pair, and hands over
+//! its QP number, the region's rkey and virtual address, and the starting
+//! PSN (`configure`; exchanging them is left to the operator or the
+//! control plane). Each dirty page then becomes one RDMA WRITE into that
+//! region at the page's guest-physical offset. The destination NIC places
+//! the data itself, so its CPU never touches the page stream; a round ends
+//! with a zero-length WRITE with immediate carrying the page count, which
+//! is the only completion the destination sees.
+//!
+//! This side is a software requester on the migration uplink: it builds the
+//! RoCEv2 packets (IPv4/UDP 4791, BTH, RETH, ICRC) and keeps the send queue
+//! of work requests until the responder acknowledges them. Acknowledgements
+//! arrive through `vdev::net::inbound` (`on_frame`) and retire work
+//! requests into the completion queue in order; `send_page` and `flush`
+//! drive the link and drain the completion queue, waiting when the send
+//! queue is full. A PSN sequence NAK or a timeout goes back to the oldest
+//! unacknowledged request and sends from there again, with the page's
+//! current contents. Source pages must lie in a registered region
+//! (`register`), which is how a round is confined to the VM's RAM.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::vdev::net::Uplink;
+use crate::obs::metrics::{Counter, MIG_RDMA_PAGES, MIG_RDMA_RETRANSMITS};
+use crate::util::spinlock::SpinLock;
+
+pub const ROCE_PORT: u16 = 4791;
+/// Work requests in flight before `send_page` waits
+pub const SQ_DEPTH: usize = 64;
+pub const CQ_DEPTH: usize = 128;
+pub const MAX_REGIONS: usize = 8;
+/// No acknowledgement for this long sends the window again
+pub const TIMEOUT_MS: u64 = 50;
+/// Attempts before a work request completes with an error
+pub const RETRY_MAX: u8 = 7;
+pub const DEFAULT_PMTU: u16 = 1024;
+const PAGE: u64 = 4096;
+const PSN_MASK: u32 = 0x00FF_FFFF;
+
+const ETH_HDR: usize = 14;
+const IP_HDR: usize = 20;
+const UDP_HDR: usize = 8;
+const BTH_LEN: usize = 12;
+const RETH_LEN: usize = 16;
+const IMM_LEN: usize = 4;
+const AETH_LEN: usize = 4;
+const ICRC_LEN: usize = 4;
+const HDRS: usize = ETH_HDR + IP_HDR + UDP_HDR + BTH_LEN;
+
+// RC opcodes
+const OP_WRITE_FIRST: u8 = 0x06;
+const OP_WRITE_MIDDLE: u8 = 0x07;
+const OP_WRITE_LAST: u8 = 0x08;
+const OP_WRITE_ONLY: u8 = 0x0A;
+const OP_WRITE_ONLY_IMM: u8 = 0x0B;
+const OP_ACK: u8 = 0x11;
+const BTH_ACKREQ: u8 = 0x80;
+
+// AETH syndrome
+const AETH_NAK: u8 = 0x60;
+const NAK_PSN_SEQ: u8 = 0;
+
+/// Queue pair parameters agreed with the destination.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub link: Uplink,
+    pub mac: [u8; 6],
+    pub peer_mac: [u8; 6],
+    pub ip: [u8; 4],
+    pub peer_ip: [u8; 4],
+    /// Our QP number (the destination sends its ACKs to it)
+    pub qpn: u32,
+    pub peer_qpn: u32,
+    pub rkey: u32,
+    /// Remote virtual address of guest-physical 0
+    pub va: u64,
+    /// Bytes the remote region covers
+    pub len: u64,
+    /// First PSN the responder expects
+    pub psn: u32,
+    /// Path MTU: 256, 512, 1024, 2048 or 4096
+    pub pmtu: u16,
+}
+
+/// How a work request ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WcStatus { Ok, RetryExceeded, RemoteError }
+
+#[derive(Clone, Copy, Debug)]
+pub struct Completion { pub wr_id: u64, pub status: WcStatus }
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub posted: u64,
+    pub completed: u64,
+    pub failed: u64,
+    pub packets: u64,
+    pub bytes: u64,
+    pub retransmits: u64,
+    pub naks: u64,
+    pub timeouts: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region { pub lkey: u32, pub base: u64, pub len: u64 }
+
+#[derive(Clone, Copy)]
+struct Wr {
+    wr_id: u64,
+    pa: u64,
+    len: u32,
+    /// Offset in the remote region
+    remote: u64,
+    imm: Option<u32>,
+    first_psn: u32,
+    last_psn: u32,
+    retries: u8,
+}
+
+struct Qp {
+    cfg: Option<Config>,
+    regions: [Option<Region>; MAX_REGIONS],
+    next_lkey: u32,
+    /// PSN of the next new packet
+    psn: u32,
+    sq: [Option<Wr>; SQ_DEPTH],
+    sq_head: usize,
+    sq_len: usize,
+    cq: [Option<Completion>; CQ_DEPTH],
+    cq_head: usize,
+    cq_len: usize,
+    /// Send again from this PSN at the next `progress`
+    rewind: Option<u32>,
+    /// `clock::now_ms` of the last acknowledgement or (re)transmission of the window
+    last_progress: u64,
+    /// A fatal NAK moved the QP to the error state
+    error: bool,
+    stats: Stats,
+}
+
+static QP: SpinLock<Qp> = SpinLock::new(Qp {
+    cfg: None, regions: [None; MAX_REGIONS], next_lkey: 1, psn: 0,
+    sq: [None; SQ_DEPTH], sq_head: 0, sq_len: 0,
+    cq: [None; CQ_DEPTH], cq_head: 0, cq_len: 0,
+    rewind: None, last_progress: 0, error: false, stats: Stats { posted: 0, completed: 0, failed: 0, packets: 0, bytes: 0, retransmits: 0, naks: 0, timeouts: 0 },
+});
+
+/// `a` at or before `b` in 24-bit PSN space.
+fn psn_le(a: u32, b: u32) -> bool { (b.wrapping_sub(a) & PSN_MASK) < (1 << 23) }
+fn psn_lt(a: u32, b: u32) -> bool { a != b && psn_le(a, b) }
+
+fn packets(len: u32, pmtu: u16) -> u32 { if len == 0 { 1 } else { (len + pmtu as u32 - 1) / pmtu as u32 } }
+
+/// Set up the queue pair, dropping anything still queued.
+pub fn configure(cfg: Config) -> Result<(), &'static str> {
+    if !matches!(cfg.pmtu, 256 | 512 | 1024 | 2048 | 4096) { return Err("rdma: pmtu must be 256, 512, 1024, 2048 or 4096"); }
+    if cfg.qpn > PSN_MASK || cfg.peer_qpn > PSN_MASK { return Err("rdma: QP numbers are 24 bits"); }
+    if cfg.link == Uplink::None { return Err("rdma: no uplink"); }
+    if cfg.len == 0 { return Err("rdma: remote region is empty"); }
+    QP.lock(|q| {
+        q.cfg = Some(Config { psn: cfg.psn & PSN_MASK, ..cfg });
+        q.psn = cfg.psn & PSN_MASK;
+        q.sq = [None; SQ_DEPTH]; q.sq_head = 0; q.sq_len = 0;
+        q.cq = [None; CQ_DEPTH]; q.cq_head = 0; q.cq_len = 0;
+        q.rewind = None; q.error = false; q.last_progress = 0;
+    });
+    Ok(())
+}
+
+pub fn config() -> Option<Config> { QP.lock(|q| q.cfg) }
+
+/// Tear the queue pair down; registrations stay.
+pub fn disconnect() { QP.lock(|q| { q.cfg = None; q.sq = [None; SQ_DEPTH]; q.sq_head = 0; q.sq_len = 0; q.rewind = None; }); }
+
+pub fn stats() -> Stats { QP.lock(|q| q.stats) }
+
+/// Requests in flight and whether the QP is in the error state.
+pub fn status() -> (usize, bool) { QP.lock(|q| (q.sq_len, q.error)) }
+
+/// Register `len` bytes at host-physical `base` as a source region. Returns its lkey.
+pub fn register(base: u64, len: u64) -> Result<u32, &'static str> {
+    if len == 0 || base % PAGE != 0 { return Err("rdma: region must be page aligned and non-empty"); }
+    QP.lock(|q| {
+        if q.regions.iter().flatten().any(|r| r.base < base.saturating_add(len) && base < r.base.saturating_add(r.len)) {
+            return Err("rdma: region overlaps a registered one");
+        }
+        let slot = q.regions.iter_mut().find(|r| r.is_none()).ok_or("rdma: too many regions")?;
+        let lkey = q.next_lkey;
+        *slot = Some(Region { lkey, base, len });
+        q.next_lkey = q.next_lkey.wrapping_add(1).max(1);
+        Ok(lkey)
+    })
+}
+
+pub fn deregister(lkey: u32) -> Result<(), &'static str> {
+    QP.lock(|q| match q.regions.iter_mut().find(|r| matches!(r, Some(x) if x.lkey == lkey)) {
+        Some(r) => { *r = None; Ok(()) }
+        None => Err("rdma: no such region"),
+    })
+}
+
+pub fn for_each_region(mut f: impl FnMut(&Region)) {
+    let regs = QP.lock(|q| q.regions);
+    for r in regs.iter().flatten() { f(r); }
+}
+
+/// Build and send the packets of `wr` from `from_psn` on.
+fn transmit(system_table: &mut SystemTable<Boot>, cfg: &Config, wr: &Wr, from_psn: u32) -> bool {
+    let pmtu = cfg.pmtu as u32;
+    let n = packets(wr.len, cfg.pmtu);
+    let mut sent = 0u64;
+    for k in 0..n {
+        let psn = (wr.first_psn + k) & PSN_MASK;
+        if !psn_le(from_psn, psn) { continue; }
+        let (first, last) = (k == 0, k + 1 == n);
+        let opcode = match (first, last, wr.imm.is_some()) {
+            (true, true, true) => OP_WRITE_ONLY_IMM,
+            (true, true, false) => OP_WRITE_ONLY,
+            (true, false, _) => OP_WRITE_FIRST,
+            (false, false, _) => OP_WRITE_MIDDLE,
+            (false, true, _) => OP_WRITE_LAST,
+        };
+        let off = k * pmtu;
+        let take = (wr.len - off.min(wr.len)).min(pmtu) as usize;
+        let mut f = [0u8; HDRS + RETH_LEN + IMM_LEN + 4096 + ICRC_LEN];
+        let mut at = HDRS;
+        if first {
+            f[at..at + 8].copy_from_slice(&(cfg.va + wr.remote).to_be_bytes());
+            f[at + 8..at + 12].copy_from_slice(&cfg.rkey.to_be_bytes());
+            f[at + 12..at + 16].copy_from_slice(&wr.len.to_be_bytes());
+            at += RETH_LEN;
+        }
+        if let Some(imm) = wr.imm.filter(|_| last) { f[at..at + 4].copy_from_slice(&imm.to_be_bytes()); at += IMM_LEN; }
+        if take != 0 {
+            let src = unsafe { core::slice::from_raw_parts((wr.pa + off as u64) as *const u8, take) };
+            f[at..at + take].copy_from_slice(src);
+            at += take;
+        }
+        let bth = [
+            opcode, 0, 0xFF, 0xFF, 0,
+            (cfg.peer_qpn >> 16) as u8, (cfg.peer_qpn >> 8) as u8, cfg.peer_qpn as u8,
+            if last { BTH_ACKREQ } else { 0 }, (psn >> 16) as u8, (psn >> 8) as u8, psn as u8,
+        ];
+        f[ETH_HDR + IP_HDR + UDP_HDR..HDRS].copy_from_slice(&bth);
+        let len = finish(&mut f, at, cfg);
+        if !crate::hv::vdev::net::uplink_send(system_table, cfg.link, &f[..len]) { return false; }
+        sent += take as u64;
+        QP.lock(|q| q.stats.packets += 1);
+    }
+    QP.lock(|q| q.stats.bytes += sent);
+    true
+}
+
+/// Fill in the Ethernet, IPv4 and UDP headers of a packet whose BTH and
+/// payload end at `end`, append the ICRC, and return the frame length.
+fn finish(f: &mut [u8], end: usize, cfg: &Config) -> usize {
+    let ip_len = (end + ICRC_LEN - ETH_HDR) as u16;
+    f[0..6].copy_from_slice(&cfg.peer_mac);
+    f[6..12].copy_from_slice(&cfg.mac);
+    f[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+    let ip = &mut f[ETH_HDR..ETH_HDR + IP_HDR];
+    ip[0] = 0x45; ip[1] = 0;
+    ip[2..4].copy_from_slice(&ip_len.to_be_bytes());
+    ip[4..6].copy_from_slice(&0u16.to_be_bytes());
+    ip[6..8].copy_from_slice(&0x4000u16.to_be_bytes());
+    ip[8] = 64; ip[9] = 17;
+    ip[10..12].copy_from_slice(&[0, 0]);
+    ip[12..16].copy_from_slice(&cfg.ip);
+    ip[16..20].copy_from_slice(&cfg.peer_ip);
+    let mut sum = 0u32;
+    for w in ip.chunks(2) { sum += u16::from_be_bytes([w[0], w[1]]) as u32; }
+    while sum >> 16 != 0 { sum = (sum & 0xFFFF) + (sum >> 16); }
+    ip[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    let udp = &mut f[ETH_HDR + IP_HDR..ETH_HDR + IP_HDR + UDP_HDR];
+    // Source port spreads flows over ECMP paths; one QP keeps one port.
+    udp[0..2].copy_from_slice(&(0xC000u16 | (cfg.qpn & 0x3FFF) as u16).to_be_bytes());
+    udp[2..4].copy_from_slice(&ROCE_PORT.to_be_bytes());
+    udp[4..6].copy_from_slice(&(ip_len - IP_HDR as u16).to_be_bytes());
+    udp[6..8].copy_from_slice(&[0, 0]);
+    let icrc = icrc(&f[ETH_HDR..end]);
+    f[end..end + ICRC_LEN].copy_from_slice(&icrc.to_le_bytes());
+    end + ICRC_LEN
+}
+
+/// Invariant CRC of a RoCEv2 packet (`p` starts at the IP header): CRC-32
+/// over a dummy LRH of ones and the packet with its variant fields (TOS,
+/// TTL, IP and UDP checksums, BTH reserved byte) set to ones.
+fn icrc(p: &[u8]) -> u32 {
+    const N: usize = IP_HDR + UDP_HDR + BTH_LEN;
+    let mut hdr = [0u8; N];
+    hdr.copy_from_slice(&p[..N]);
+    hdr[1] = 0xFF;
+    hdr[8] = 0xFF;
+    hdr[10] = 0xFF; hdr[11] = 0xFF;
+    hdr[IP_HDR + 6] = 0xFF; hdr[IP_HDR + 7] = 0xFF;
+    hdr[IP_HDR + UDP_HDR + 4] = 0xFF;
+    let c = crate::util::crc32::crc32_update(0, &[0xFF; 8]);
+    let c = crate::util::crc32::crc32_update(c, &hdr);
+    crate::util::crc32::crc32_update(c, &p[N..])
+}
+
+fn push_cq(q: &mut Qp, c: Completion) {
+    if q.cq_len == CQ_DEPTH { q.cq_head = (q.cq_head + 1) % CQ_DEPTH; q.cq_len -= 1; }
+    q.cq[(q.cq_head + q.cq_len) % CQ_DEPTH] = Some(c);
+    q.cq_len += 1;
+    match c.status { WcStatus::Ok => q.stats.completed += 1, _ => q.stats.failed += 1 }
+}
+
+/// Retire the oldest request into the completion queue.
+fn retire(q: &mut Qp, status: WcStatus) {
+    let Some(wr) = q.sq[q.sq_head].take() else { return };
+    q.sq_head = (q.sq_head + 1) % SQ_DEPTH;
+    q.sq_len -= 1;
+    push_cq(q, Completion { wr_id: wr.wr_id, status });
+}
+
+/// RoCEv2 packet from the uplink. True if it was ours.
+pub fn on_frame(frame: &[u8]) -> bool {
+    if frame.len() < HDRS + AETH_LEN + ICRC_LEN || frame[12..14] != [0x08, 0x00] || frame[ETH_HDR] != 0x45 || frame[ETH_HDR + 9] != 17 { return false; }
+    if u16::from_be_bytes([frame[ETH_HDR + IP_HDR + 2], frame[ETH_HDR + IP_HDR + 3]]) != ROCE_PORT { return false; }
+    let ip_len = u16::from_be_bytes([frame[ETH_HDR + 2], frame[ETH_HDR + 3]]) as usize;
+    let end = ETH_HDR + ip_len;
+    if end > frame.len() || end < HDRS + AETH_LEN + ICRC_LEN { return true; }
+    let bth = &frame[ETH_HDR + IP_HDR + UDP_HDR..HDRS];
+    let dqp = u32::from_be_bytes([0, bth[5], bth[6], bth[7]]);
+    let psn = u32::from_be_bytes([0, bth[9], bth[10], bth[11]]);
+    let mut crc = [0u8; 4];
+    crc.copy_from_slice(&frame[end - ICRC_LEN..end]);
+    if u32::from_le_bytes(crc) != icrc(&frame[ETH_HDR..end - ICRC_LEN]) { return true; }
+    if bth[0] != OP_ACK { return true; }
+    let syndrome = frame[HDRS];
+    QP.lock(|q| {
+        let Some(cfg) = q.cfg else { return };
+        if dqp != cfg.qpn || q.error { return; }
+        q.last_progress = crate::time::clock::now_ms();
+        if syndrome & 0xE0 == AETH_NAK {
+            q.stats.naks += 1;
+            if syndrome & 0x1F == NAK_PSN_SEQ {
+                // Everything before `psn` arrived; go back to it.
+                while q.sq_len != 0 && q.sq[q.sq_head].is_some_and(|w| psn_lt(w.last_psn, psn)) { retire(q, WcStatus::Ok); }
+                q.rewind = Some(psn);
+            } else {
+                // Remote access or operational error: the QP is done.
+                q.error = true;
+                while q.sq_len != 0 { retire(q, WcStatus::RemoteError); }
+            }
+            return;
+        }
+        // Coalesced ACK: retires every request up to `psn`.
+        while q.sq_len != 0 && q.sq[q.sq_head].is_some_and(|w| psn_le(w.last_psn, psn)) { retire(q, WcStatus::Ok); }
+    });
+    true
+}
+
+/// Receive acknowledgements and resend on NAK or timeout.
+pub fn progress(system_table: &mut SystemTable<Boot>) {
+    let Some(cfg) = config() else { return };
+    let _ = crate::hv::vdev::net::uplink_recv(system_table, cfg.link, 32);
+    let running = crate::time::clock::hz() != 0;
+    let now = crate::time::clock::now_ms();
+    let from = QP.lock(|q| {
+        if q.sq_len == 0 || q.error { q.rewind = None; return None; }
+        let oldest = q.sq[q.sq_head]?;
+        if let Some(p) = q.rewind.take() { return Some(p); }
+        if !running || now.saturating_sub(q.last_progress) < TIMEOUT_MS { return None; }
+        q.stats.timeouts += 1;
+        Some(oldest.first_psn)
+    });
+    let Some(from) = from else { return };
+    // Go-back-N from `from`; a request out of retries fails the QP.
+    let mut window: [Option<Wr>; SQ_DEPTH] = [None; SQ_DEPTH];
+    let exhausted = QP.lock(|q| {
+        for i in 0..q.sq_len {
+            let k = (q.sq_head + i) % SQ_DEPTH;
+            if let Some(w) = q.sq[k].as_mut() {
+                if !psn_le(from, w.last_psn) { continue; }
+                w.retries += 1;
+                if w.retries > RETRY_MAX { return true; }
+                window[i] = Some(*w);
+            }
+        }
+        q.last_progress = now;
+        false
+    });
+    if exhausted {
+        QP.lock(|q| { q.error = true; while q.sq_len != 0 { retire(q, WcStatus::RetryExceeded); } });
+        return;
+    }
+    for w in window.iter().flatten() {
+        let start = if psn_le(w.first_psn, from) { from } else { w.first_psn };
+        if !transmit(system_table, &cfg, w, start) { break; }
+        QP.lock(|q| q.stats.retransmits += 1);
+        Counter::new(&MIG_RDMA_RETRANSMITS).inc();
+    }
+}
+
+/// Queue an RDMA WRITE of `len` bytes at host-physical `pa` to offset
+/// `remote` of the destination region and send it.
+pub fn post_write(system_table: &mut SystemTable<Boot>, wr_id: u64, pa: u64, len: u32, remote: u64, imm: Option<u32>) -> Result<(), &'static str> {
+    let (cfg, wr) = QP.lock(|q| {
+        let cfg = q.cfg.ok_or("rdma: not connected")?;
+        if q.error { return Err("rdma: queue pair in error state"); }
+        if q.sq_len == SQ_DEPTH { return Err("rdma: send queue full"); }
+        if remote.saturating_add(len as u64) > cfg.len { return Err("rdma: write outside the remote region"); }
+        if len != 0 && !q.regions.iter().flatten().any(|r| pa >= r.base && pa + len as u64 <= r.base + r.len) {
+            return Err("rdma: source not in a registered region");
+        }
+        let n = packets(len, cfg.pmtu);
+        let wr = Wr { wr_id, pa, len, remote, imm, first_psn: q.psn, last_psn: (q.psn + n - 1) & PSN_MASK, retries: 0 };
+        q.psn = (q.psn + n) & PSN_MASK;
+        if q.sq_len == 0 { q.last_progress = crate::time::clock::now_ms(); }
+        q.sq[(q.sq_head + q.sq_len) % SQ_DEPTH] = Some(wr);
+        q.sq_len += 1;
+        q.stats.posted += 1;
+        Ok((cfg, wr))
+    })?;
+    // A lost packet is recovered by the timeout like any other.
+    let _ = transmit(system_table, &cfg, &wr, wr.first_psn);
+    Ok(())
+}
+
+/// Take up to `max` completions (0 = all). Returns how many.
+pub fn poll_cq(max: usize, mut f: impl FnMut(&Completion)) -> usize {
+    let mut n = 0;
+    while max == 0 || n < max {
+        let c = QP.lock(|q| {
+            if q.cq_len == 0 { return None; }
+            let c = q.cq[q.cq_head].take();
+            q.cq_head = (q.cq_head + 1) % CQ_DEPTH;
+            q.cq_len -= 1;
+            c
+        });
+        let Some(c) = c else { break };
+        f(&c);
+        n += 1;
+    }
+    n
+}
+
+/// Wait until the send queue has room, draining completions. False if
+/// the queue pair failed.
+fn wait_room(system_table: &mut SystemTable<Boot>) -> bool {
+    loop {
+        let (full, error) = QP.lock(|q| (q.sq_len == SQ_DEPTH, q.error));
+        if error { return false; }
+        if !full { return true; }
+        progress(system_table);
+        let _ = poll_cq(0, |_| {});
+    }
+}
+
+/// Write guest page `page_index` (identity mapped) to the same page of the
+/// destination region.
+pub fn send_page(system_table: &mut SystemTable<Boot>, page_index: u64) -> Result<(), &'static str> {
+    if !wait_room(system_table) { return Err("rdma: queue pair in error state"); }
+    post_write(system_table, page_index, page_index * PAGE, PAGE as u32, page_index * PAGE, None)?;
+    Counter::new(&MIG_RDMA_PAGES).inc();
+    Ok(())
+}
+
+/// Write `len` bytes from `start` page by page. Returns the bytes posted.
+pub fn write_range(system_table: &mut SystemTable<Boot>, start: u64, len: u64) -> u64 {
+    let mut done = 0u64;
+    let mut pa = start & !(PAGE - 1);
+    while pa < start.saturating_add(len) {
+        if send_page(system_table, pa / PAGE).is_err() { break; }
+        done += PAGE;
+        pa += PAGE;
+    }
+    done
+}
+
+/// End a round: tell the destination how many pages it got (WRITE with
+/// immediate) and wait up to `timeout_ms` for every request to be
+/// acknowledged. Returns the requests that completed with an error.
+pub fn flush(system_table: &mut SystemTable<Boot>, pages: u32, timeout_ms: u64) -> Result<u64, &'static str> {
+    if !wait_room(system_table) { return Err("rdma: queue pair in error state"); }
+    post_write(system_table, u64::MAX, 0, 0, 0, Some(pages))?;
+    let deadline = crate::time::clock::Deadline::after_ms(timeout_ms);
+    let mut failed = 0u64;
+    loop {
+        progress(system_table);
+        let _ = poll_cq(0, |c| if c.status != WcStatus::Ok { failed += 1; });
+        let (left, error) = QP.lock(|q| (q.sq_len, q.error));
+        if left == 0 { return if error { Err("rdma: queue pair in error state") } else { Ok(failed) }; }
+        if deadline.expired() { return Err("rdma: timed out waiting for acknowledgements"); }
+    }
+}
+
+/// Register the guest-physical span of the VM being tracked, which is
+/// where the page stream reads from.
+pub fn register_tracked() -> Result<u32, &'static str> {
+    let limit = super::with_tracker(|s| s.tracker.memory_limit).ok_or("rdma: no active tracker")?;
+    register(0, (limit + PAGE - 1) & !(PAGE - 1))
+}
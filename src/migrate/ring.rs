@@ -0,0 +1,289 @@
+#![allow(dead_code)]
+
+//! Byte ring behind the migration channel.
+//!
+//! The producer and consumer cursors are free-running byte counts: `head`
+//! is every byte ever stored, `tail` every byte ever consumed or dropped.
+//! The fill level is `head - tail` and a cursor's slot is `cursor % cap`,
+//! so there is no separate length to keep in step and no full/empty
+//! ambiguity when the two slots meet.
+//!
+//! When a write does not fit, `Overflow` decides what gives: the oldest
+//! bytes, or the new ones. Either way the loss is counted. Crossing the
+//! high watermark on the way up, and the low one on the way down, is
+//! reported once per crossing so the caller can start a pump or push back
+//! on its producers.
+//!
+//! Like `wire`, this depends only on `core`; the host unit tests at the
+//! bottom run from the `fuzz/` crate, which includes the file by path.
+
+/// What a write into a full ring gives up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Advance the consumer past the oldest bytes to make room
+    DropOldest,
+    /// Store what fits and refuse the rest
+    Reject,
+}
+
+/// A watermark crossing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mark {
+    /// The fill level reached the high watermark
+    High,
+    /// The fill level fell to the low watermark after a `High`
+    Low,
+}
+
+/// Outcome of `Ring::write`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Write {
+    /// Bytes of the caller's buffer accepted
+    pub stored: usize,
+    /// Bytes lost to overflow: old ones dropped, or new ones refused
+    pub lost: usize,
+    pub mark: Option<Mark>,
+}
+
+/// Counters and settings, for display.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+    pub len: usize,
+    pub cap: usize,
+    pub stored: u64,
+    pub consumed: u64,
+    pub lost: u64,
+    pub high: usize,
+    pub low: usize,
+    pub above: bool,
+    pub overflow: Overflow,
+}
+
+/// Ring over `cap` bytes at `base`. The memory is the caller's and must
+/// outlive the ring.
+pub struct Ring {
+    base: usize,
+    cap: usize,
+    head: u64,
+    tail: u64,
+    lost: u64,
+    overflow: Overflow,
+    high: usize,
+    low: usize,
+    /// A `High` was reported and no `Low` since
+    above: bool,
+}
+
+impl Ring {
+    /// Ring over `cap` bytes at `base`, dropping the oldest bytes on
+    /// overflow, with watermarks at 3/4 and 1/4 full.
+    ///
+    /// # Safety
+    /// `base..base + cap` must be writable and stay valid while the ring or
+    /// any copy of its `base` is in use.
+    pub unsafe fn new(base: *mut u8, cap: usize) -> Ring {
+        Ring { base: base as usize, cap, head: 0, tail: 0, lost: 0, overflow: Overflow::DropOldest, high: cap - cap / 4, low: cap / 4, above: false }
+    }
+
+    pub fn base(&self) -> *mut u8 { self.base as *mut u8 }
+    pub fn cap(&self) -> usize { self.cap }
+    pub fn len(&self) -> usize { (self.head - self.tail) as usize }
+    pub fn is_empty(&self) -> bool { self.head == self.tail }
+    pub fn free(&self) -> usize { self.cap - self.len() }
+    /// Slot of the oldest unconsumed byte.
+    pub fn read_pos(&self) -> usize { if self.cap == 0 { 0 } else { (self.tail % self.cap as u64) as usize } }
+    /// Between a `High` and the following `Low`.
+    pub fn above_high(&self) -> bool { self.above }
+
+    pub fn overflow(&self) -> Overflow { self.overflow }
+    pub fn set_overflow(&mut self, o: Overflow) { self.overflow = o; }
+
+    /// Set the watermarks in bytes. False, and nothing changed, unless
+    /// `low < high <= cap`.
+    pub fn set_watermarks(&mut self, high: usize, low: usize) -> bool {
+        if low >= high || high > self.cap { return false; }
+        self.high = high;
+        self.low = low;
+        true
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            len: self.len(), cap: self.cap, stored: self.head, consumed: self.tail, lost: self.lost,
+            high: self.high, low: self.low, above: self.above, overflow: self.overflow,
+        }
+    }
+
+    /// Store `src` per the overflow policy.
+    pub fn write(&mut self, src: &[u8]) -> Write {
+        if self.cap == 0 || src.is_empty() { return Write { stored: 0, lost: src.len(), mark: None }; }
+        let n = src.len();
+        let (src, take, lost) = match self.overflow {
+            Overflow::DropOldest => {
+                // Only the last `cap` bytes can survive this write
+                let skip = src.len().saturating_sub(self.cap);
+                let src = &src[skip..];
+                let drop = src.len().saturating_sub(self.free());
+                self.tail += drop as u64;
+                (src, src.len(), skip + drop)
+            }
+            Overflow::Reject => {
+                let take = src.len().min(self.free());
+                (src, take, src.len() - take)
+            }
+        };
+        let at = (self.head % self.cap as u64) as usize;
+        let first = take.min(self.cap - at);
+        unsafe {
+            core::ptr::copy_nonoverlapping(src.as_ptr(), self.base().add(at), first);
+            core::ptr::copy_nonoverlapping(src.as_ptr().add(first), self.base(), take - first);
+        }
+        self.head += take as u64;
+        self.lost += lost as u64;
+        let stored = if self.overflow == Overflow::DropOldest { n } else { take };
+        let mark = if !self.above && self.len() >= self.high { self.above = true; Some(Mark::High) } else { None };
+        Write { stored, lost, mark }
+    }
+
+    /// Copy `dst.len()` bytes starting `off` bytes past the consumer cursor,
+    /// without consuming them. False if they are not all there.
+    pub fn peek(&self, off: usize, dst: &mut [u8]) -> bool {
+        if off.saturating_add(dst.len()) > self.len() { return false; }
+        let at = ((self.tail + off as u64) % self.cap.max(1) as u64) as usize;
+        let first = dst.len().min(self.cap - at);
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.base().add(at), dst.as_mut_ptr(), first);
+            core::ptr::copy_nonoverlapping(self.base(), dst.as_mut_ptr().add(first), dst.len() - first);
+        }
+        true
+    }
+
+    /// Advance the consumer by up to `n` bytes; returns how far it moved
+    /// and a `Low` crossing if there was one.
+    pub fn consume(&mut self, n: usize) -> (usize, Option<Mark>) {
+        let n = n.min(self.len());
+        self.tail += n as u64;
+        (n, self.check_low())
+    }
+
+    /// Consume everything.
+    pub fn clear(&mut self) -> Option<Mark> {
+        self.tail = self.head;
+        self.check_low()
+    }
+
+    fn check_low(&mut self) -> Option<Mark> {
+        if self.above && self.len() <= self.low { self.above = false; return Some(Mark::Low); }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(mem: &mut [u8]) -> Ring { unsafe { Ring::new(mem.as_mut_ptr(), mem.len()) } }
+
+    fn contents(r: &Ring) -> ([u8; 32], usize) {
+        let mut out = [0u8; 32];
+        let n = r.len();
+        assert!(r.peek(0, &mut out[..n]));
+        (out, n)
+    }
+
+    #[test]
+    fn write_and_consume_wrap() {
+        let mut mem = [0u8; 8];
+        let mut r = ring(&mut mem);
+        // Default watermarks for 8 bytes are 6 and 2
+        assert_eq!(r.write(b"abcdef"), Write { stored: 6, lost: 0, mark: Some(Mark::High) });
+        assert_eq!(r.consume(4), (4, Some(Mark::Low)));
+        assert_eq!(r.write(b"ghij").stored, 4);
+        assert_eq!(r.len(), 6);
+        assert_eq!(r.read_pos(), 4);
+        let (c, n) = contents(&r);
+        assert_eq!(&c[..n], b"efghij");
+        assert_eq!(r.consume(100).0, 6);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn drop_oldest_counts_the_loss() {
+        let mut mem = [0u8; 8];
+        let mut r = ring(&mut mem);
+        r.write(b"abcdef");
+        let w = r.write(b"ghij");
+        assert_eq!((w.stored, w.lost), (4, 2));
+        let (c, n) = contents(&r);
+        assert_eq!(&c[..n], b"cdefghij");
+        // Larger than the ring: only the tail survives
+        let w = r.write(b"0123456789");
+        assert_eq!((w.stored, w.lost), (10, 10));
+        let (c, n) = contents(&r);
+        assert_eq!(&c[..n], b"23456789");
+        assert_eq!(r.stats().lost, 12);
+    }
+
+    #[test]
+    fn reject_keeps_old_data() {
+        let mut mem = [0u8; 8];
+        let mut r = ring(&mut mem);
+        r.set_overflow(Overflow::Reject);
+        r.write(b"abcdef");
+        let w = r.write(b"ghij");
+        assert_eq!((w.stored, w.lost), (2, 2));
+        let (c, n) = contents(&r);
+        assert_eq!(&c[..n], b"abcdefgh");
+        assert_eq!(r.write(b"x").stored, 0);
+        assert_eq!(r.stats().lost, 3);
+    }
+
+    #[test]
+    fn watermarks_fire_once_per_crossing() {
+        let mut mem = [0u8; 16];
+        let mut r = ring(&mut mem);
+        assert!(r.set_watermarks(12, 4));
+        assert!(!r.set_watermarks(4, 4));
+        assert!(!r.set_watermarks(17, 4));
+        assert_eq!(r.write(&[0; 11]).mark, None);
+        assert_eq!(r.write(&[0; 1]).mark, Some(Mark::High));
+        assert_eq!(r.write(&[0; 2]).mark, None);
+        assert_eq!(r.consume(8), (8, None));
+        assert_eq!(r.consume(2), (2, Some(Mark::Low)));
+        assert_eq!(r.consume(2), (2, None));
+        assert_eq!(r.write(&[0; 12]).mark, Some(Mark::High));
+        assert_eq!(r.clear(), Some(Mark::Low));
+        assert!(!r.above_high());
+    }
+
+    #[test]
+    fn cursors_survive_many_laps() {
+        let mut mem = [0u8; 7];
+        let mut r = ring(&mut mem);
+        let mut next = 0u8;
+        let mut expect = 0u8;
+        for round in 0..1000usize {
+            let mut chunk = [0u8; 5];
+            let n = 1 + round % 5;
+            for b in chunk[..n].iter_mut() { *b = next; next = next.wrapping_add(1); }
+            let w = r.write(&chunk[..n]);
+            // Bytes dropped from the front are skipped by the reader too
+            expect = expect.wrapping_add(w.lost as u8);
+            let mut out = [0u8; 7];
+            let m = r.len().min(3);
+            assert!(r.peek(0, &mut out[..m]));
+            for &b in &out[..m] { assert_eq!(b, expect); expect = expect.wrapping_add(1); }
+            r.consume(m);
+            assert_eq!(r.stats().stored - r.stats().consumed, r.len() as u64);
+        }
+    }
+
+    #[test]
+    fn empty_ring_refuses_everything() {
+        let mut r = ring(&mut []);
+        let w = r.write(b"abc");
+        assert_eq!((w.stored, w.lost), (0, 3));
+        assert!(!r.peek(0, &mut [0u8; 1]));
+        assert_eq!(r.consume(1), (0, None));
+    }
+}
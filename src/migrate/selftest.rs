@@ -0,0 +1,218 @@
+#![allow(dead_code)]
+
+//! Loopback self-test for the migration pipeline.
+//!
+//! `run` fills a scratch region with a deterministic pattern (zero pages,
+//! run-length friendly pages and pseudo-random pages), marks the written
+//! pages in a private dirty bitmap, frames them through the chosen sink into
+//! the channel buffer, verifies CRCs and sequence numbers, replays the frames
+//! into a second region and compares it with the source. A second round
+//! rewrites a subset of pages so the incremental path is covered as well.
+//!
+//! The bitmap is filled by the writer rather than by an EPT/NPT scan: the
+//! scratch region is host memory and no guest maps it. The test uses the
+//! global sequence counter and transmit log like a real transfer, so run it
+//! before starting a session rather than in the middle of one. The channel
+//! buffer is swapped for a private one and restored afterwards.
+
+use super::*;
+
+pub const DEFAULT_PAGES: usize = 64;
+pub const MAX_PAGES: usize = 4096;
+/// Every third page is rewritten in the second round.
+const ROUND2_STRIDE: u64 = 3;
+
+/// Outcome of one self-test run.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Report {
+    pub pages: usize,
+    /// Pages marked dirty across both rounds
+    pub dirtied: u64,
+    /// Page frames written to the sink (zero pages are skipped by the sender)
+    pub frames_sent: u64,
+    pub skipped: u64,
+    pub frames_ok: u64,
+    pub frames_bad: u64,
+    /// Frames whose sequence number did not follow the previous one
+    pub seq_errors: u64,
+    pub replayed: u64,
+    /// Pages that differ between source and replayed region
+    pub mismatches: u64,
+    /// Framed bytes (headers and payloads) written to the sink
+    pub bytes: u64,
+    pub elapsed_us: u64,
+    /// First stage that failed, if any
+    pub failed: Option<&'static str>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool { self.failed.is_none() }
+
+    /// Replayed page data per second, in KiB.
+    pub fn kib_per_sec(&self) -> u64 {
+        if self.elapsed_us == 0 { return 0; }
+        (self.replayed * 4096).saturating_mul(1_000_000) / self.elapsed_us / 1024
+    }
+}
+
+#[inline(always)]
+fn xorshift(mut x: u64) -> u64 {
+    x ^= x << 13; x ^= x >> 7; x ^= x << 17; x
+}
+
+/// Write the contents of page `index` for `round`. Round 0 covers all three
+/// kinds; later rounds never produce zero pages, because the sender skips
+/// them and the destination would keep the previous contents.
+unsafe fn fill_page(p: *mut u8, index: u64, round: u32) {
+    let kind = if round == 0 { index % 4 } else { 1 + index % 3 };
+    match kind {
+        0 => core::ptr::write_bytes(p, 0, 4096),
+        1 => {
+            // Eight 512-byte runs compress well under RLE.
+            for r in 0..8usize {
+                let v = ((index as u8) ^ (round as u8).wrapping_mul(31)).wrapping_add(r as u8) | 1;
+                core::ptr::write_bytes(p.add(r * 512), v, 512);
+            }
+        }
+        _ => {
+            let mut x = (index.wrapping_add(1)).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ ((round as u64 + 1) << 32);
+            for w in 0..512usize {
+                x = xorshift(x);
+                write_volatile((p as *mut u64).add(w), x);
+            }
+        }
+    }
+}
+
+/// Frame every dirty page of `base` (page index relative to `base`) plus a
+/// trailing manifest, as `send_dirty_pages` does for guest memory.
+fn send_round(w: &mut impl MigrWriter, base: *mut u8, bitmap: &DirtyBitmap, compress: bool, chunked: bool, r: &mut Report) {
+    let mut pages = 0u64; let mut bytes = 0u64;
+    bitmap.for_each_set(|i| {
+        let pa = base as u64 + (i << 12);
+        if page_skip_reason(pa).is_some() { r.skipped += 1; return; }
+        let (_comp, flen) = frame_and_send_page(w, i, pa, compress, chunked);
+        pages += 1; bytes += flen as u64;
+    });
+    let mlen = frame_and_send_manifest(w, pages, bytes, chunked);
+    r.frames_sent += pages;
+    r.bytes += bytes + mlen as u64;
+}
+
+/// Push one round through `sink`. For network sinks the frames only reach the
+/// channel buffer if the link loops them back (cable, switch port or peer
+/// echoing the migration EtherType); they are picked up by the regular pump.
+fn transfer(system_table: &mut SystemTable<Boot>, sink: ExportSink, base: *mut u8, bitmap: &DirtyBitmap, compress: bool, r: &mut Report) -> Result<(), &'static str> {
+    match sink {
+        ExportSink::Buffer => { send_round(&mut BufferWriter, base, bitmap, compress, true, r); Ok(()) }
+        ExportSink::Snp => {
+            if !crate::hv::features::available("snp") { return Err("snp feature disabled"); }
+            { let mut w = SnpWriter::new(system_table); send_round(&mut w, base, bitmap, compress, false, r); }
+            snp_poll_ex(system_table, 0, 1000, false, false, 8);
+            Ok(())
+        }
+        ExportSink::Virtio => {
+            #[cfg(feature = "virtio-net")]
+            {
+                { let mut w = VirtioNetWriter { system_table }; send_round(&mut w, base, bitmap, compress, false, r); }
+                virtio_poll_ex(system_table, 0, 1000, false, false, 8);
+                return Ok(());
+            }
+            #[cfg(not(feature = "virtio-net"))]
+            { let _ = (base, bitmap, compress, r); return Err("virtio-net feature disabled"); }
+        }
+        ExportSink::Console | ExportSink::Null => Err("sink cannot loop back"),
+        ExportSink::Rdma => Err("rdma writes land in remote memory, not the channel"),
+    }
+}
+
+/// Walk the channel buffer: check magic, CRC and sequence continuity from
+/// `first_seq`, then replay good page frames into `dst`.
+unsafe fn verify_and_replay(dst: *mut u8, pages: usize, first_seq: u32, r: &mut Report) {
+    let Some(mut cur) = chan_cursor() else { return };
+    let mut expected = first_seq;
+    let mut eb = [0u8; MAX_EXT];
+    while cur.remaining >= FrameHeader::V1_LEN {
+        let Some(h) = cursor_header(&mut cur) else { r.frames_bad += 1; continue; };
+        let (typ, flags, seq, page_index, payload_len, crc) = (h.typ, h.flags, h.seq, h.page_index, h.payload_len as usize, h.crc32);
+        if cur.remaining < h.body_len() { r.frames_bad += 1; break; }
+        if seq != expected { r.seq_errors += 1; }
+        expected = seq.wrapping_add(1);
+        let sum = cur.checksum(h.body_len());
+        let ext_ok = cursor_ext(&mut cur, &h, &mut eb).is_some();
+        if sum != crc || !ext_ok { r.frames_bad += 1; let _ = cur.skip(payload_len); continue; }
+        if typ != TYP_PAGE { let _ = cur.skip(payload_len); continue; }
+        r.frames_ok += 1;
+        if (page_index as usize) >= pages { r.frames_bad += 1; let _ = cur.skip(payload_len); continue; }
+        if replay_payload(&mut cur, flags, payload_len, dst.add((page_index as usize) << 12)) { r.replayed += 1; }
+        else { r.frames_bad += 1; }
+    }
+}
+
+/// Run the self-test over `pages` scratch pages through `sink`.
+pub fn run(system_table: &mut SystemTable<Boot>, pages: usize, sink: ExportSink, compress: bool) -> Report {
+    let pages = pages.clamp(1, MAX_PAGES);
+    let mut r = Report { pages, ..Default::default() };
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_SELFTEST_RUNS).inc();
+    // Source, destination and a channel buffer large enough for both rounds.
+    let round2 = (pages as u64).div_ceil(ROUND2_STRIDE) as usize;
+    // Largest page frame: version 2 header, node TLV, raw page
+    let chan_bytes = (pages + round2 + 2) * (FrameHeader::LEN + 3 + 4096);
+    let chan_pages = chan_bytes.div_ceil(4096);
+    let src = crate::mm::uefi::alloc_pages(system_table, pages, MemoryType::LOADER_DATA);
+    let dst = crate::mm::uefi::alloc_pages(system_table, pages, MemoryType::LOADER_DATA);
+    let chan = crate::mm::uefi::alloc_pages(system_table, chan_pages, MemoryType::LOADER_DATA);
+    let bitmap = DirtyBitmap::allocate(system_table, pages as u64);
+    let (src, dst, chan, mut bitmap) = match (src, dst, chan, bitmap) {
+        (Some(s), Some(d), Some(c), Some(b)) => (s, d, c, b),
+        (s, d, c, b) => {
+            if let Some(p) = s { crate::mm::uefi::free_pages(system_table, p, pages); }
+            if let Some(p) = d { crate::mm::uefi::free_pages(system_table, p, pages); }
+            if let Some(p) = c { crate::mm::uefi::free_pages(system_table, p, chan_pages); }
+            if let Some(b) = b { b.free(system_table); }
+            r.failed = Some("alloc");
+            crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_SELFTEST_FAILS).inc();
+            return r;
+        }
+    };
+    unsafe {
+        core::ptr::write_bytes(dst, 0, pages * 4096);
+        core::ptr::write_bytes(chan, 0, chan_pages * 4096);
+    }
+    let saved = chan_swap(Some(unsafe { ring::Ring::new(chan, chan_pages * 4096) }));
+    let first_seq = peek_seq();
+    let _ = crate::time::clock::init(system_table);
+    let t0 = crate::time::clock::now_ns();
+    let mut stage: Option<&'static str> = None;
+    for round in 0..2u32 {
+        bitmap.clear_all();
+        for i in 0..pages as u64 {
+            if round > 0 && i % ROUND2_STRIDE != 1 { continue; }
+            unsafe { fill_page(src.add((i as usize) << 12), i, round); }
+            bitmap.set_bit(i);
+            r.dirtied += 1;
+        }
+        if let Err(e) = transfer(system_table, sink, src, &bitmap, compress, &mut r) { stage = Some(e); break; }
+    }
+    if stage.is_none() {
+        unsafe { verify_and_replay(dst, pages, first_seq, &mut r); }
+        r.elapsed_us = elapsed_us_since(t0);
+        for i in 0..pages {
+            let a = unsafe { core::slice::from_raw_parts(src.add(i << 12), 4096) };
+            let b = unsafe { core::slice::from_raw_parts(dst.add(i << 12), 4096) };
+            if a != b { r.mismatches += 1; }
+        }
+        stage = if r.frames_ok == 0 && r.frames_sent > 0 { Some("pump") }
+            else if r.frames_bad > 0 || r.seq_errors > 0 || r.frames_ok < r.frames_sent { Some("verify") }
+            else if r.replayed < r.frames_sent || r.mismatches > 0 { Some("replay") }
+            else { None };
+    }
+    r.failed = stage;
+    chan_swap(saved);
+    bitmap.free(system_table);
+    crate::mm::uefi::free_pages(system_table, chan, chan_pages);
+    crate::mm::uefi::free_pages(system_table, dst, pages);
+    crate::mm::uefi::free_pages(system_table, src, pages);
+    if r.failed.is_some() { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_SELFTEST_FAILS).inc(); }
+    r
+}
@@ -0,0 +1,275 @@
+#![allow(dead_code)]
+
+//! ZMIG wire format: frame header, page payload RLE and control bodies.
+//!
+//! Everything here works on byte slices and depends only on `core`, so the
+//! exact code the receive paths run on untrusted frames also builds on the
+//! host, where the fuzz targets under `fuzz/` include this file directly.
+//! Keep it that way: no `crate::` paths, no allocation, no UEFI types.
+//!
+//! Two frame versions share the magic and the first 28 header bytes:
+//!
+//! - Version 1: header, then `payload_len` bytes of payload.
+//! - Version 2: the header grows by `ext_len` (u16) and a reserved u16, and
+//!   `ext_len` bytes of TLV metadata sit between the header and the payload.
+//!   Each TLV is a type byte, a length byte and the value. A receiver skips
+//!   types it does not know unless `TLV_CRITICAL` is set, in which case it
+//!   must drop the frame.
+//!
+//! In both, `crc32` covers everything after the header. Peers start on
+//! version 1 and move to version 2 only after a `CTRL_HELLO` exchange shows
+//! both support it. Receivers accept either version at any time, so a peer
+//! that never sends a hello keeps working.
+
+use core::mem::size_of;
+use core::ptr::read_volatile;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct FrameHeader {
+    pub magic: [u8;4],   // 'Z','M','I','G'
+    pub ver: u8,         // 1
+    pub typ: u8,         // 1=page, 2=manifest
+    pub flags: u16,      // bit0=compressed
+    pub seq: u32,
+    pub page_index: u64,
+    pub payload_len: u32,
+    pub crc32: u32,
+    /// Version 2: bytes of TLV metadata between header and payload
+    pub ext_len: u16,
+    pub _rsvd: u16,
+}
+
+pub const MAGIC: [u8;4] = *b"ZMIG";
+pub const TYP_PAGE: u8 = 1;
+pub const TYP_MANIFEST: u8 = 2;
+pub const TYP_CTRL: u8 = 3;
+pub const CTRL_ACK: u8 = 1;
+pub const CTRL_NAK: u8 = 2;
+/// Capability announcement; body is `Hello`
+pub const CTRL_HELLO: u8 = 3;
+pub const FLAG_COMP: u16 = 1u16 << 0;
+
+pub const VER1: u8 = 1;
+pub const VER2: u8 = 2;
+/// Newest version this build speaks
+pub const MAX_VER: u8 = VER2;
+
+/// Hello capability bits
+pub const CAP_RLE: u32 = 1 << 0;
+pub const CAP_TLV: u32 = 1 << 1;
+/// What this build offers in its hello
+pub const LOCAL_CAPS: u32 = CAP_RLE | CAP_TLV;
+
+/// A receiver that does not know this TLV type must drop the frame
+pub const TLV_CRITICAL: u8 = 0x80;
+/// Guest NUMA node of the page (u8)
+pub const TLV_NUMA_NODE: u8 = 0x01;
+/// Most TLV bytes a receiver looks at; frames with more are refused
+pub const MAX_EXT: usize = 256;
+
+pub fn le_u32(b: &[u8]) -> u32 { (b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24) }
+pub fn le_u64(b: &[u8]) -> u64 { (le_u32(&b[0..4]) as u64) | ((le_u32(&b[4..8]) as u64) << 32) }
+
+impl FrameHeader {
+    /// Largest header, version 2
+    pub const LEN: usize = size_of::<FrameHeader>();
+    /// Version 1 header, which stops before `ext_len`
+    pub const V1_LEN: usize = 28;
+
+    pub fn new(ver: u8, typ: u8, flags: u16, page_index: u64, payload_len: u32) -> FrameHeader {
+        FrameHeader { magic: MAGIC, ver, typ, flags, seq: 0, page_index, payload_len, crc32: 0, ext_len: 0, _rsvd: 0 }
+    }
+
+    /// Header bytes on the wire for this version.
+    pub fn wire_len(&self) -> usize { if self.ver >= VER2 { FrameHeader::LEN } else { FrameHeader::V1_LEN } }
+
+    /// Bytes after the header: TLVs and payload, all covered by `crc32`.
+    pub fn body_len(&self) -> usize { self.ext_len as usize + self.payload_len as usize }
+
+    /// Wire form: the fields in order, little-endian, unpadded. A version 1
+    /// header is the first `V1_LEN` bytes.
+    pub fn to_bytes(&self) -> [u8; FrameHeader::LEN] {
+        let mut b = [0u8; FrameHeader::LEN];
+        b[0..4].copy_from_slice(&self.magic);
+        b[4] = self.ver;
+        b[5] = self.typ;
+        b[6..8].copy_from_slice(&{ self.flags }.to_le_bytes());
+        b[8..12].copy_from_slice(&{ self.seq }.to_le_bytes());
+        b[12..20].copy_from_slice(&{ self.page_index }.to_le_bytes());
+        b[20..24].copy_from_slice(&{ self.payload_len }.to_le_bytes());
+        b[24..28].copy_from_slice(&{ self.crc32 }.to_le_bytes());
+        b[28..30].copy_from_slice(&{ self.ext_len }.to_le_bytes());
+        b[30..32].copy_from_slice(&{ self._rsvd }.to_le_bytes());
+        b
+    }
+
+    /// Header at the start of `b`; None if `b` lacks the magic, is of an
+    /// unknown version, or is shorter than that version's header.
+    pub fn parse(b: &[u8]) -> Option<FrameHeader> {
+        if b.len() < FrameHeader::V1_LEN || b[0..4] != MAGIC { return None; }
+        let mut h = FrameHeader {
+            magic: MAGIC, ver: b[4], typ: b[5], flags: (b[6] as u16) | ((b[7] as u16) << 8),
+            seq: le_u32(&b[8..12]), page_index: le_u64(&b[12..20]), payload_len: le_u32(&b[20..24]), crc32: le_u32(&b[24..28]),
+            ext_len: 0, _rsvd: 0,
+        };
+        match h.ver {
+            VER1 => {}
+            VER2 if b.len() >= FrameHeader::LEN => {
+                h.ext_len = (b[28] as u16) | ((b[29] as u16) << 8);
+                h._rsvd = (b[30] as u16) | ((b[31] as u16) << 8);
+            }
+            _ => return None,
+        }
+        Some(h)
+    }
+}
+
+/// Result of `scan`.
+pub enum Scan<'a> {
+    /// A frame whose header starts at `at`; `frame` is all of it. The CRC
+    /// and the TLVs are not checked here.
+    Frame { at: usize, hdr: FrameHeader, frame: &'a [u8], ext: &'a [u8], payload: &'a [u8] },
+    /// A header at `at` whose body runs past the end of `data`
+    Truncated { at: usize, hdr: FrameHeader },
+    /// No header at or after the start offset
+    End,
+}
+
+/// Find the next frame in `data` at or after `from`, skipping bytes that do
+/// not start a header. This is how a packet is split into frames: the
+/// caller continues from `at + frame.len()`.
+pub fn scan(data: &[u8], from: usize) -> Scan<'_> {
+    let mut pos = from;
+    while pos.saturating_add(FrameHeader::V1_LEN) <= data.len() {
+        let Some(h) = FrameHeader::parse(&data[pos..]) else { pos += 1; continue; };
+        let start = pos + h.wire_len();
+        if h.body_len() > data.len() - start { return Scan::Truncated { at: pos, hdr: h }; }
+        let split = start + h.ext_len as usize;
+        let end = start + h.body_len();
+        return Scan::Frame { at: pos, hdr: h, frame: &data[pos..end], ext: &data[start..split], payload: &data[split..end] };
+    }
+    Scan::End
+}
+
+/// Code and referenced sequence of a control body; None if it is shorter
+/// than the 5 bytes both need.
+pub fn ctrl_parse(body: &[u8]) -> Option<(u8, u32)> {
+    if body.len() < 5 { return None; }
+    Some((body[0], le_u32(&body[1..5])))
+}
+
+/// Body of a `CTRL_HELLO`: code, a zero sequence (so it parses as any
+/// control body), then the sender's newest version, capability bits, and
+/// whether this answers a hello.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub max_ver: u8,
+    pub caps: u32,
+    pub reply: bool,
+}
+
+impl Hello {
+    pub const LEN: usize = 11;
+
+    pub fn to_body(&self) -> [u8; Hello::LEN] {
+        let mut b = [0u8; Hello::LEN];
+        b[0] = CTRL_HELLO;
+        b[5] = self.max_ver;
+        b[6..10].copy_from_slice(&self.caps.to_le_bytes());
+        b[10] = self.reply as u8;
+        b
+    }
+
+    /// None unless `body` is a hello; longer bodies are accepted so later
+    /// versions can append fields.
+    pub fn parse(body: &[u8]) -> Option<Hello> {
+        if body.len() < Hello::LEN || body[0] != CTRL_HELLO { return None; }
+        Some(Hello { max_ver: body[5], caps: le_u32(&body[6..10]), reply: body[10] != 0 })
+    }
+
+    /// Version to send to a peer that said this: the newest both speak, and
+    /// never 2 without TLV support. At least 1.
+    pub fn negotiate(&self) -> u8 {
+        let v = self.max_ver.min(MAX_VER);
+        if v >= VER2 && self.caps & CAP_TLV == 0 { return VER1; }
+        v.max(VER1)
+    }
+}
+
+/// Call `f(type, value)` for each TLV in `ext`. False if a TLV runs past
+/// the end, which makes the whole area unusable.
+pub fn tlv_for_each<'a>(ext: &'a [u8], mut f: impl FnMut(u8, &'a [u8])) -> bool {
+    let mut off = 0usize;
+    while off < ext.len() {
+        if off + 2 > ext.len() { return false; }
+        let (t, len) = (ext[off], ext[off + 1] as usize);
+        if len > ext.len() - off - 2 { return false; }
+        f(t, &ext[off + 2..off + 2 + len]);
+        off += 2 + len;
+    }
+    true
+}
+
+/// Whether a receiver that understands the types `known` accepts `ext`:
+/// well formed, and every critical type is known.
+pub fn tlv_acceptable(ext: &[u8], known: impl Fn(u8) -> bool) -> bool {
+    let mut ok = true;
+    tlv_for_each(ext, |t, _| { if t & TLV_CRITICAL != 0 && !known(t) { ok = false; } }) && ok
+}
+
+/// First value of type `t`.
+pub fn tlv_find(ext: &[u8], t: u8) -> Option<&[u8]> {
+    let mut found = None;
+    tlv_for_each(ext, |ty, v| { if ty == t && found.is_none() { found = Some(v); } });
+    found
+}
+
+/// Append a TLV at `out[at..]`; returns the new end, or None if it does not
+/// fit or `value` is longer than 255 bytes.
+pub fn tlv_put(out: &mut [u8], at: usize, t: u8, value: &[u8]) -> Option<usize> {
+    if value.len() > 255 || at + 2 + value.len() > out.len() { return None; }
+    out[at] = t;
+    out[at + 1] = value.len() as u8;
+    out[at + 2..at + 2 + value.len()].copy_from_slice(value);
+    Some(at + 2 + value.len())
+}
+
+/// Run-length encode `len` bytes at `src` as (value, run) pairs with runs of
+/// at most 255, so the worst case is two bytes per input byte. None if `out`
+/// is too small. Reads are volatile: the source may be live guest memory.
+///
+/// # Safety
+/// `src..src + len` must be readable. It may change while being read; the
+/// encoding is then of some mix of old and new bytes, never out of bounds.
+pub unsafe fn rle_encode(src: *const u8, len: usize, out: &mut [u8]) -> Option<usize> {
+    let mut w = 0usize;
+    let mut i = 0usize;
+    while i < len {
+        let v = read_volatile(src.add(i));
+        let mut run = 1usize;
+        while i + run < len && run < 255 {
+            let nv = read_volatile(src.add(i + run));
+            if nv != v { break; }
+            run += 1;
+        }
+        if w + 2 > out.len() { return None; }
+        out[w] = v; out[w+1] = run as u8; w += 2;
+        i += run;
+    }
+    Some(w)
+}
+
+/// Expand (value, run) pairs taken from `next` until `dst` is full. False if
+/// the pairs run out first or a run would overshoot `dst`.
+pub fn rle_decode(mut next: impl FnMut() -> Option<[u8; 2]>, dst: &mut [u8]) -> bool {
+    let mut wrote = 0usize;
+    while wrote < dst.len() {
+        let Some([v, run]) = next() else { return false };
+        let run = run as usize;
+        if wrote + run > dst.len() { return false; }
+        dst[wrote..wrote + run].fill(v);
+        wrote += run;
+    }
+    true
+}
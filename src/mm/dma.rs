@@ -0,0 +1,69 @@
+#![allow(dead_code)]
+
+//! DMA page pool that outlives Boot Services.
+//!
+//! One physically contiguous region is reserved from UEFI while Boot
+//! Services are still up; drivers then take and return pages with a bitmap
+//! allocator that never calls into firmware. The region is LOADER_DATA, so it
+//! stays ours after ExitBootServices. Addresses are identity-mapped.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::util::spinlock::SpinLock;
+
+/// Largest pool (4 MiB)
+pub const MAX_PAGES: usize = 1024;
+
+struct Pool { base: u64, pages: usize, used: [u64; MAX_PAGES / 64] }
+
+static POOL: SpinLock<Pool> = SpinLock::new(Pool { base: 0, pages: 0, used: [0; MAX_PAGES / 64] });
+
+/// Reserve the pool. Idempotent; a later call never grows an existing pool.
+pub fn reserve(system_table: &SystemTable<Boot>, pages: usize) -> Result<(), &'static str> {
+    if POOL.lock(|p| p.pages != 0) { return Ok(()); }
+    let pages = pages.clamp(1, MAX_PAGES);
+    let mem = super::uefi::alloc_pages(system_table, pages, uefi::table::boot::MemoryType::LOADER_DATA).ok_or("dma: reservation failed")?;
+    unsafe { core::ptr::write_bytes(mem, 0, pages * 4096); }
+    POOL.lock(|p| { p.base = mem as u64; p.pages = pages; p.used = [0; MAX_PAGES / 64]; });
+    Ok(())
+}
+
+pub fn is_reserved() -> bool { POOL.lock(|p| p.pages != 0) }
+
+/// Take `count` contiguous zeroed pages; returns their physical address.
+pub fn alloc(count: usize) -> Option<u64> {
+    if count == 0 { return None; }
+    let addr = POOL.lock(|p| {
+        let mut run = 0;
+        for i in 0..p.pages {
+            if p.used[i / 64] & (1 << (i % 64)) != 0 { run = 0; continue; }
+            run += 1;
+            if run == count {
+                let first = i + 1 - count;
+                for j in first..=i { p.used[j / 64] |= 1 << (j % 64); }
+                return Some(p.base + (first * 4096) as u64);
+            }
+        }
+        None
+    })?;
+    unsafe { core::ptr::write_bytes(addr as *mut u8, 0, count * 4096); }
+    Some(addr)
+}
+
+/// Return pages obtained from `alloc`.
+pub fn free(addr: u64, count: usize) {
+    POOL.lock(|p| {
+        if addr < p.base { return; }
+        let first = ((addr - p.base) / 4096) as usize;
+        for j in first..(first + count).min(p.pages) { p.used[j / 64] &= !(1 << (j % 64)); }
+    });
+}
+
+/// (total, free) pages.
+pub fn usage() -> (usize, usize) {
+    POOL.lock(|p| {
+        let used: usize = p.used.iter().map(|w| w.count_ones() as usize).sum();
+        (p.pages, p.pages - used)
+    })
+}
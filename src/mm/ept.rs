@@ -59,6 +59,32 @@ pub fn build_identity_2m(system_table: &SystemTable<Boot>, limit_bytes: u64) ->
     Some(pml4)
 }
 
+/// Build an EPT mapping guest-physical `[0, guest_bytes)` onto host memory
+/// starting at `host_base` using 2MiB pages. `host_base` must be 2MiB aligned.
+/// Returns the host-physical (identity-assumed) address of the PML4 table.
+pub fn build_offset_2m(system_table: &SystemTable<Boot>, guest_bytes: u64, host_base: u64) -> Option<*mut u64> {
+    if guest_bytes == 0 || (host_base & 0x1F_FFFF) != 0 { return None; }
+    let pml4 = alloc_zeroed_page(system_table)?;
+    let pdpt = alloc_zeroed_page(system_table)?;
+    unsafe {
+        *pml4 = (pdpt as u64) | EPT_R | EPT_W | EPT_X;
+        let num_gb = ((guest_bytes + (1 << 30) - 1) >> 30) as usize;
+        for i in 0..num_gb {
+            let pd = alloc_zeroed_page(system_table)?;
+            *pdpt.add(i) = (pd as u64) | EPT_R | EPT_W | EPT_X;
+            let mut gpa: u64 = (i as u64) << 30;
+            for j in 0..512usize {
+                let hpa = host_base.wrapping_add(gpa);
+                *pd.add(j) = (hpa & 0xFFFF_FFFF_FFE0_0000)
+                    | EPT_R | EPT_W | EPT_X | EPT_MEMTYPE_WB | EPT_IGNORE_PAT | EPT_PAGE_SIZE;
+                gpa = gpa.wrapping_add(2 * 1024 * 1024);
+                if gpa >= guest_bytes { break; }
+            }
+        }
+    }
+    Some(pml4)
+}
+
 /// Build a minimal identity-mapped EPT up to `limit_bytes` using 1GiB pages.
 /// Returns the host-physical (identity-assumed) address of the PML4 table.
 pub fn build_identity_1g(system_table: &SystemTable<Boot>, limit_bytes: u64) -> Option<*mut u64> {
@@ -0,0 +1,491 @@
+#![allow(dead_code)]
+
+//! Guest RAM frame allocator.
+//!
+//! Guest RAM is handed out as per-VM extents: physically contiguous,
+//! identity-mapped runs of host frames taken from a pool. While Boot
+//! Services are up the pool grows on demand; an extent that fits none of
+//! the free ranges is claimed from the top of a CONVENTIONAL range of the
+//! UEFI memory map as LOADER_DATA, so it stays ours after ExitBootServices.
+//! Once firmware is gone, `adopt` takes the CONVENTIONAL ranges of the final
+//! map into the pool and allocation goes on without calling firmware. Freed
+//! extents return to the pool, never to firmware. `alloc_spread` picks
+//! where an extent goes by the SRAT nodes it should sit on, from the pool
+//! and firmware alike.
+//!
+//! Each VM also holds a reservation, the guest RAM it was promised. A VM's
+//! extents never exceed its reservation, and all reservations together may
+//! exceed host capacity (free memory plus the pool) only by the global
+//! overcommit percentage. Memory the firmware keeps is accounted by type.
+//! Pages a guest hands back through its balloon stay in its extents but
+//! are unmapped from it, so they count as lent and not against the limit.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use uefi::table::boot::MemoryType;
+
+use crate::firmware::acpi::numa::{NumaTopology, MAX_NODES};
+use crate::obs::metrics::{self, Counter};
+use crate::util::spinlock::SpinLock;
+
+pub const MAX_FREE: usize = 64;
+pub const MAX_EXTENTS: usize = 64;
+pub const MAX_VMS: usize = 16;
+pub const PAGE: u64 = 4096;
+/// Frames below 1 MiB stay with real-mode users and the AP trampoline.
+const LOW_LIMIT: u64 = 0x10_0000;
+
+/// Memory-map bytes by what holds them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MapSummary {
+    /// CONVENTIONAL memory not in the pool
+    pub usable: u64,
+    /// Loader code and data, including the pool's claims
+    pub loader: u64,
+    pub boot_services: u64,
+    pub runtime: u64,
+    /// ACPI reclaim and NVS
+    pub acpi: u64,
+    pub mmio: u64,
+    /// Reserved, unusable, PAL code and persistent memory
+    pub reserved: u64,
+    pub entries: u32,
+}
+
+impl MapSummary {
+    fn add(&mut self, ty: MemoryType, pages: u64) {
+        let bytes = pages.saturating_mul(PAGE);
+        let bucket = match ty {
+            MemoryType::CONVENTIONAL => &mut self.usable,
+            MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => &mut self.loader,
+            MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => &mut self.boot_services,
+            MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => &mut self.runtime,
+            MemoryType::ACPI_RECLAIM | MemoryType::ACPI_NON_VOLATILE => &mut self.acpi,
+            MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => &mut self.mmio,
+            _ => &mut self.reserved,
+        };
+        *bucket = bucket.saturating_add(bytes);
+        self.entries += 1;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Range { base: u64, pages: u64 }
+
+/// A run of frames backing guest RAM.
+#[derive(Clone, Copy, Debug)]
+pub struct Extent { pub vm_id: u64, pub base: u64, pub pages: u64 }
+
+#[derive(Clone, Copy)]
+struct Acct {
+    vm_id: u64,
+    reserved: u64,
+    /// Part of `reserved` the guest gave up through its balloon
+    lent: u64,
+}
+
+struct State {
+    map: MapSummary,
+    /// Firmware is gone; the pool can no longer grow by claims
+    adopted: bool,
+    overcommit_pct: u32,
+    /// Frames in the pool, free or backing an extent
+    pool_pages: u64,
+    free: [Option<Range>; MAX_FREE],
+    extents: [Option<Extent>; MAX_EXTENTS],
+    vms: [Option<Acct>; MAX_VMS],
+}
+
+static STATE: SpinLock<State> = SpinLock::new(State {
+    map: MapSummary { usable: 0, loader: 0, boot_services: 0, runtime: 0, acpi: 0, mmio: 0, reserved: 0, entries: 0 },
+    adopted: false,
+    overcommit_pct: 100,
+    pool_pages: 0,
+    free: [None; MAX_FREE],
+    extents: [None; MAX_EXTENTS],
+    vms: [None; MAX_VMS],
+});
+
+fn capacity(s: &State) -> u64 { s.map.usable.saturating_add(s.pool_pages * PAGE) }
+
+fn limit(s: &State) -> u64 { ((capacity(s) as u128) * (s.overcommit_pct as u128) / 100) as u64 }
+
+/// Reservation a VM holds against the overcommit limit.
+fn committed(a: &Acct) -> u64 { a.reserved.saturating_sub(a.lent) }
+
+fn backed_of(s: &State, vm_id: u64) -> u64 { s.extents.iter().flatten().filter(|e| e.vm_id == vm_id).map(|e| e.pages * PAGE).sum() }
+
+/// Refresh the memory-map accounting. Does nothing once firmware is gone.
+pub fn scan(system_table: &SystemTable<Boot>) -> bool {
+    if STATE.lock(|s| s.adopted) { return true; }
+    let mut m = MapSummary::default();
+    if !super::uefi::for_each_map_entry(system_table, |d| m.add(d.ty, d.page_count)) { return false; }
+    STATE.lock(|s| s.map = m);
+    true
+}
+
+/// Take over the final memory map after ExitBootServices: CONVENTIONAL
+/// ranges above 1 MiB join the pool. `entries` yields (type, base, pages).
+/// Returns the number of pages adopted.
+pub fn adopt(entries: impl Iterator<Item = (MemoryType, u64, u64)>) -> u64 {
+    STATE.lock(|s| {
+        let mut m = MapSummary::default();
+        let mut pages = 0;
+        for (ty, base, count) in entries {
+            m.add(ty, count);
+            if ty != MemoryType::CONVENTIONAL { continue; }
+            let start = base.max(LOW_LIMIT);
+            let end = base.saturating_add(count.saturating_mul(PAGE));
+            if end <= start { continue; }
+            let n = (end - start) / PAGE;
+            if give_back(s, start, n) { m.usable -= n * PAGE; pages += n; }
+        }
+        s.map = m;
+        s.pool_pages += pages;
+        s.adopted = true;
+        pages
+    })
+}
+
+/// Put a free range back into the pool, merged with its neighbours.
+/// Returns false if the free list is full and the range was dropped.
+fn give_back(s: &mut State, base: u64, pages: u64) -> bool {
+    let mut r = Range { base, pages };
+    while let Some(k) = s.free.iter().position(|x| matches!(x, Some(f) if f.base + f.pages * PAGE == r.base || r.base + r.pages * PAGE == f.base)) {
+        if let Some(f) = s.free[k].take() { r = Range { base: r.base.min(f.base), pages: r.pages + f.pages }; }
+    }
+    match s.free.iter_mut().find(|x| x.is_none()) {
+        Some(slot) => { *slot = Some(r); true }
+        None => false,
+    }
+}
+
+/// Cut `pages` frames at `start` out of free range `k`. False if they are
+/// not all inside it or the split needs a slot the free list lacks.
+fn cut(s: &mut State, k: usize, start: u64, pages: u64) -> bool {
+    let Some(f) = s.free[k] else { return false };
+    let end = f.base + f.pages * PAGE;
+    if start < f.base || start.saturating_add(pages * PAGE) > end { return false; }
+    let head = (start - f.base) / PAGE;
+    let tail = (end - start) / PAGE - pages;
+    // Splitting off both ends needs a second slot.
+    if head != 0 && tail != 0 && s.free.iter().all(|x| x.is_some()) { return false; }
+    s.free[k] = None;
+    if head != 0 { give_back(s, f.base, head); }
+    if tail != 0 { give_back(s, start + pages * PAGE, tail); }
+    true
+}
+
+/// First fit of `pages` frames aligned to `align` pages (a power of two).
+fn take(s: &mut State, pages: u64, align: u64) -> Option<u64> {
+    let align = align.max(1) * PAGE;
+    for k in 0..MAX_FREE {
+        let Some(f) = s.free[k] else { continue };
+        let start = (f.base + align - 1) & !(align - 1);
+        if cut(s, k, start, pages) { return Some(start); }
+    }
+    None
+}
+
+/// Claim `pages` frames from the top of a CONVENTIONAL range, so low memory
+/// stays available for firmware users.
+fn claim(system_table: &SystemTable<Boot>, pages: u64, align: u64) -> Option<u64> {
+    let bytes = pages * PAGE;
+    let align = align.max(1) * PAGE;
+    let mut best = 0u64;
+    super::uefi::for_each_map_entry(system_table, |d| {
+        if d.ty != MemoryType::CONVENTIONAL { return; }
+        let end = d.phys_start.saturating_add(d.page_count.saturating_mul(PAGE));
+        if end < bytes { return; }
+        let base = (end - bytes) & !(align - 1);
+        if base >= d.phys_start.max(LOW_LIMIT) && base > best { best = base; }
+    });
+    if best == 0 { return None; }
+    super::uefi::alloc_pages_at(system_table, best, pages as usize, MemoryType::LOADER_DATA).map(|p| p as u64)
+}
+
+/// Check that `vm_id` may back `pages` more frames.
+fn room(s: &State, vm_id: u64, pages: u64) -> Result<(), &'static str> {
+    let a = s.vms.iter().flatten().find(|a| a.vm_id == vm_id).ok_or("gmem: vm has no reservation")?;
+    if backed_of(s, vm_id) + pages * PAGE > a.reserved { return Err("gmem: extent exceeds the vm's reservation"); }
+    if s.extents.iter().all(|e| e.is_some()) { return Err("gmem: extent table full"); }
+    Ok(())
+}
+
+fn record(s: &mut State, vm_id: u64, base: u64, pages: u64) {
+    if let Some(slot) = s.extents.iter_mut().find(|e| e.is_none()) { *slot = Some(Extent { vm_id, base, pages }); }
+    Counter::new(&metrics::GMEM_EXTENTS).inc();
+}
+
+/// Set the guest RAM promised to `vm_id` (rounded up to pages). Fails if it
+/// is below what the VM already has backed or would pass the overcommit
+/// limit. Call `scan` first so host capacity is known.
+pub fn reserve(vm_id: u64, bytes: u64) -> Result<(), &'static str> {
+    let bytes = (bytes + PAGE - 1) & !(PAGE - 1);
+    STATE.lock(|s| {
+        if bytes < backed_of(s, vm_id) { return Err("gmem: reservation below backed memory"); }
+        let others: u64 = s.vms.iter().flatten().filter(|a| a.vm_id != vm_id).map(committed).sum();
+        let lent = s.vms.iter().flatten().find(|a| a.vm_id == vm_id).map(|a| a.lent).unwrap_or(0);
+        if others.saturating_add(bytes.saturating_sub(lent)) > limit(s) {
+            Counter::new(&metrics::GMEM_OVERCOMMIT_REJECTS).inc();
+            return Err("gmem: overcommit limit reached");
+        }
+        match s.vms.iter_mut().flatten().find(|a| a.vm_id == vm_id) {
+            Some(a) => a.reserved = bytes,
+            None => *s.vms.iter_mut().find(|a| a.is_none()).ok_or("gmem: too many vms")? = Some(Acct { vm_id, reserved: bytes, lent: 0 }),
+        }
+        Ok(())
+    })
+}
+
+/// Bytes reserved for `vm_id` (0 if none).
+pub fn reservation(vm_id: u64) -> u64 { STATE.lock(|s| s.vms.iter().flatten().find(|a| a.vm_id == vm_id).map(|a| a.reserved).unwrap_or(0)) }
+
+/// Record that `bytes` of `vm_id`'s RAM are unmapped and given back
+/// through its balloon (capped at the reservation). False if the VM has
+/// no reservation.
+pub fn set_lent(vm_id: u64, bytes: u64) -> bool {
+    STATE.lock(|s| match s.vms.iter_mut().flatten().find(|a| a.vm_id == vm_id) {
+        Some(a) => { a.lent = bytes.min(a.reserved); true }
+        None => false,
+    })
+}
+
+/// Bytes the reservations would have to shrink by for `vm_id` to hold
+/// `bytes` within the overcommit limit (0 if it fits already).
+pub fn shortfall(vm_id: u64, bytes: u64) -> u64 {
+    STATE.lock(|s| {
+        let others: u64 = s.vms.iter().flatten().filter(|a| a.vm_id != vm_id).map(committed).sum();
+        others.saturating_add(bytes).saturating_sub(limit(s))
+    })
+}
+
+/// Bytes of extents backing `vm_id`.
+pub fn backed(vm_id: u64) -> u64 { STATE.lock(|s| backed_of(s, vm_id)) }
+
+/// Extent from the pool, or None if no free range fits.
+fn grant(vm_id: u64, pages: u64, align: u64) -> Result<Option<u64>, &'static str> {
+    if pages == 0 { return Err("gmem: empty extent"); }
+    STATE.lock(|s| {
+        room(s, vm_id, pages)?;
+        let Some(base) = take(s, pages, align) else { return Ok(None) };
+        record(s, vm_id, base, pages);
+        Ok(Some(base))
+    })
+}
+
+/// Back `pages` frames of `vm_id`'s reservation with one extent aligned to
+/// `align` pages (a power of two), from the pool only. The frames are not
+/// zeroed. Usable after ExitBootServices.
+pub fn alloc_from_pool(vm_id: u64, pages: u64, align: u64) -> Result<u64, &'static str> {
+    let r = grant(vm_id, pages, align).and_then(|b| b.ok_or("gmem: out of guest memory"));
+    if r.is_err() { Counter::new(&metrics::GMEM_ALLOC_FAILS).inc(); }
+    r
+}
+
+/// As `alloc_from_pool`, claiming the frames from firmware when the pool
+/// has no fitting range.
+pub fn alloc(system_table: &SystemTable<Boot>, vm_id: u64, pages: u64, align: u64) -> Result<u64, &'static str> {
+    match grant(vm_id, pages, align) {
+        Ok(Some(base)) => return Ok(base),
+        Ok(None) if !STATE.lock(|s| s.adopted) => {}
+        Ok(None) => { Counter::new(&metrics::GMEM_ALLOC_FAILS).inc(); return Err("gmem: out of guest memory"); }
+        Err(e) => { Counter::new(&metrics::GMEM_ALLOC_FAILS).inc(); return Err(e); }
+    }
+    let Some(base) = claim(system_table, pages, align) else {
+        Counter::new(&metrics::GMEM_ALLOC_FAILS).inc();
+        return Err("gmem: out of guest memory");
+    };
+    Counter::new(&metrics::GMEM_FW_CLAIMS).inc();
+    let _ = scan(system_table);
+    STATE.lock(|s| {
+        s.pool_pages += pages;
+        // The reservation may have shrunk meanwhile; keep the frames pooled.
+        if let Err(e) = room(s, vm_id, pages) {
+            if !give_back(s, base, pages) { s.pool_pages -= pages; }
+            return Err(e);
+        }
+        record(s, vm_id, base, pages);
+        Ok(base)
+    })
+}
+
+/// Best place for an extent of `bytes` aligned to `align` bytes inside the
+/// free ranges `free` as (score, base, index into `free`): the score is how
+/// many of the bytes land where `want` asks for them (bytes per NUMA node).
+/// Candidates sit at the top and bottom of each node's share of a range and
+/// across node boundaries, split the way `want` is; ties go to the higher
+/// base.
+fn best_fit(topo: &NumaTopology, free: &[(u64, u64)], bytes: u64, align: u64, want: &[u64; MAX_NODES]) -> Option<(u64, u64, usize)> {
+    let score = |base: u64| -> u64 {
+        let mut on = [0u64; MAX_NODES];
+        for r in topo.mem_ranges().iter().filter(|r| !r.hotplug && (r.node as usize) < MAX_NODES) {
+            let lo = base.max(r.base);
+            let hi = base.saturating_add(bytes).min(r.base.saturating_add(r.len));
+            if hi > lo { on[r.node as usize] += hi - lo; }
+        }
+        on.iter().zip(want.iter()).map(|(&o, &w)| o.min(w)).sum()
+    };
+    let mut best: Option<(u64, u64, usize)> = None;
+    for (i, &(fs, fe)) in free.iter().enumerate() {
+        for r in topo.mem_ranges().iter().filter(|r| !r.hotplug && (r.node as usize) < MAX_NODES) {
+            let (rs, re) = (r.base, r.base.saturating_add(r.len));
+            let lo = fs.max(rs);
+            let hi = fe.min(re);
+            if hi <= lo { continue; }
+            let share = want[r.node as usize].min(bytes);
+            let up = |x: u64| x.saturating_add(align - 1) & !(align - 1);
+            // Inside the node, then with its share just below its end or just above its start.
+            for c in [hi.saturating_sub(bytes), up(lo), re.saturating_sub(share), rs.saturating_sub(bytes - share)] {
+                let base = c & !(align - 1);
+                if base < fs || base.saturating_add(bytes) > fe { continue; }
+                let sc = score(base);
+                if best.map_or(true, |(bs, bb, _)| sc > bs || (sc == bs && base > bb)) { best = Some((sc, base, i)); }
+            }
+        }
+    }
+    best
+}
+
+/// As `alloc`, placing the extent so that as much of it as possible sits on
+/// the NUMA nodes `want` names, `want[n]` bytes on node `n`. One extent can
+/// only cover several nodes where their ranges meet, so a split the memory
+/// map cannot give is approximated. Falls back to `alloc` without an SRAT
+/// or when no free range touches a wanted node.
+pub fn alloc_spread(system_table: &SystemTable<Boot>, vm_id: u64, pages: u64, align: u64, want: &[u64; MAX_NODES]) -> Result<u64, &'static str> {
+    let topo = crate::firmware::acpi::numa::topology(system_table);
+    if !topo.from_srat || pages == 0 { return alloc(system_table, vm_id, pages, align); }
+    let bytes = pages * PAGE;
+    let align_b = align.max(1) * PAGE;
+    // Pool ranges first, then what firmware still has free.
+    let mut free = [(0u64, 0u64); MAX_FREE + 128];
+    let (pooled, adopted) = STATE.lock(|s| {
+        let mut n = 0;
+        for f in s.free.iter().flatten() { free[n] = (f.base, f.base + f.pages * PAGE); n += 1; }
+        (n, s.adopted)
+    });
+    let mut n = pooled;
+    if !adopted {
+        super::uefi::for_each_map_entry(system_table, |d| {
+            if d.ty != MemoryType::CONVENTIONAL || n == free.len() { return; }
+            let start = d.phys_start.max(LOW_LIMIT);
+            let end = d.phys_start.saturating_add(d.page_count.saturating_mul(PAGE));
+            if end > start { free[n] = (start, end); n += 1; }
+        });
+    }
+    let Some((score, base, i)) = best_fit(&topo, &free[..n], bytes, align_b, want) else { return alloc(system_table, vm_id, pages, align) };
+    if score == 0 { return alloc(system_table, vm_id, pages, align); }
+    if i < pooled {
+        let placed = STATE.lock(|s| {
+            room(s, vm_id, pages)?;
+            let Some(k) = s.free.iter().position(|f| matches!(f, Some(f) if f.base <= base && base + bytes <= f.base + f.pages * PAGE)) else { return Ok(false) };
+            if !cut(s, k, base, pages) { return Ok(false); }
+            record(s, vm_id, base, pages);
+            Ok(true)
+        });
+        match placed {
+            Ok(true) => return Ok(base),
+            Ok(false) => return alloc(system_table, vm_id, pages, align),
+            Err(e) => { Counter::new(&metrics::GMEM_ALLOC_FAILS).inc(); return Err(e); }
+        }
+    }
+    if let Err(e) = STATE.lock(|s| room(s, vm_id, pages)) { Counter::new(&metrics::GMEM_ALLOC_FAILS).inc(); return Err(e); }
+    let Some(p) = super::uefi::alloc_pages_at(system_table, base, pages as usize, MemoryType::LOADER_DATA) else { return alloc(system_table, vm_id, pages, align) };
+    let base = p as u64;
+    Counter::new(&metrics::GMEM_FW_CLAIMS).inc();
+    let _ = scan(system_table);
+    STATE.lock(|s| {
+        s.pool_pages += pages;
+        if let Err(e) = room(s, vm_id, pages) {
+            if !give_back(s, base, pages) { s.pool_pages -= pages; }
+            return Err(e);
+        }
+        record(s, vm_id, base, pages);
+        Ok(base)
+    })
+}
+
+/// Return the extent of `vm_id` starting at `base` to the pool.
+pub fn free(vm_id: u64, base: u64) -> bool {
+    STATE.lock(|s| {
+        let Some(k) = s.extents.iter().position(|e| matches!(e, Some(x) if x.vm_id == vm_id && x.base == base)) else { return false };
+        let Some(e) = s.extents[k].take() else { return false };
+        // Frames the free list cannot hold stay allocated but leave the pool.
+        if !give_back(s, e.base, e.pages) { s.pool_pages -= e.pages; }
+        true
+    })
+}
+
+/// Free every extent of `vm_id` and drop its reservation.
+pub fn release_vm(vm_id: u64) {
+    STATE.lock(|s| {
+        for k in 0..MAX_EXTENTS {
+            let Some(e) = s.extents[k] else { continue };
+            if e.vm_id != vm_id { continue; }
+            s.extents[k] = None;
+            if !give_back(s, e.base, e.pages) { s.pool_pages -= e.pages; }
+        }
+        for a in s.vms.iter_mut() { if matches!(a, Some(x) if x.vm_id == vm_id) { *a = None; } }
+    });
+}
+
+pub fn set_overcommit_pct(pct: u32) { STATE.lock(|s| s.overcommit_pct = pct.max(1)); }
+
+/// Allocator totals, in bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub map: MapSummary,
+    pub adopted: bool,
+    pub pool: u64,
+    pub free: u64,
+    pub free_ranges: u32,
+    pub extents: u32,
+    /// Free memory plus the pool
+    pub capacity: u64,
+    pub reserved: u64,
+    /// Reserved bytes given back through balloons
+    pub lent: u64,
+    pub backed: u64,
+    pub overcommit_pct: u32,
+    /// Cap on the sum of reservations
+    pub limit: u64,
+}
+
+pub fn stats() -> Stats {
+    STATE.lock(|s| Stats {
+        map: s.map,
+        adopted: s.adopted,
+        pool: s.pool_pages * PAGE,
+        free: s.free.iter().flatten().map(|r| r.pages * PAGE).sum(),
+        free_ranges: s.free.iter().flatten().count() as u32,
+        extents: s.extents.iter().flatten().count() as u32,
+        capacity: capacity(s),
+        reserved: s.vms.iter().flatten().map(|a| a.reserved).sum(),
+        lent: s.vms.iter().flatten().map(|a| a.lent).sum(),
+        backed: s.extents.iter().flatten().map(|e| e.pages * PAGE).sum(),
+        overcommit_pct: s.overcommit_pct,
+        limit: limit(s),
+    })
+}
+
+/// Reservation and backing of one VM, in bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct VmMem { pub vm_id: u64, pub reserved: u64, pub lent: u64, pub backed: u64, pub extents: u32 }
+
+pub fn vms() -> [Option<VmMem>; MAX_VMS] {
+    STATE.lock(|s| {
+        let mut out = [None; MAX_VMS];
+        for (o, a) in out.iter_mut().zip(s.vms.iter()) {
+            let Some(a) = a else { continue };
+            let n = s.extents.iter().flatten().filter(|e| e.vm_id == a.vm_id).count() as u32;
+            *o = Some(VmMem { vm_id: a.vm_id, reserved: a.reserved, lent: a.lent, backed: backed_of(s, a.vm_id), extents: n });
+        }
+        out
+    })
+}
+
+/// Call `f` for every extent.
+pub fn for_each_extent(mut f: impl FnMut(&Extent)) {
+    let t = STATE.lock(|s| s.extents);
+    for e in t.iter().flatten() { f(e); }
+}
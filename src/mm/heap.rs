@@ -0,0 +1,230 @@
+#![allow(dead_code)]
+
+//! Heap behind `#[global_allocator]`, so `alloc::vec::Vec`, `String` and
+//! `Box` work in the hypervisor.
+//!
+//! There are two backends:
+//!
+//! - While Boot Services are up, allocations made on the BSP go to the UEFI
+//!   pool (`AllocatePool`, LOADER_DATA).
+//! - An arena of `ARENA_PAGES` LOADER_DATA pages, reserved by `install`,
+//!   serves everything else. Firmware cannot be called from APs, and after
+//!   `enter_runtime` the pool is gone. The arena is a first-fit free list
+//!   sorted by address, with neighbours merged on free.
+//!
+//! A pool block freed after `enter_runtime`, or freed on an AP, cannot go
+//! back to firmware. It is counted as leaked. Before `install`, every
+//! allocation fails.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::boot::{BootServices, MemoryType};
+use uefi::table::SystemTable;
+
+use crate::util::spinlock::SpinLock;
+
+/// Arena reserved at install (4 MiB)
+pub const ARENA_PAGES: usize = 1024;
+/// Every arena allocation is preceded by its block start and length.
+const HDR: usize = 16;
+const MIN_BLOCK: usize = 32;
+/// Alignment UEFI guarantees for pool memory
+const POOL_ALIGN: usize = 8;
+
+/// Free arena block; lives at the start of the block it describes.
+#[repr(C)]
+struct Free { len: usize, next: usize }
+
+struct Arena { base: usize, len: usize, head: usize, used: usize, peak: usize }
+
+static ARENA: SpinLock<Arena> = SpinLock::new(Arena { base: 0, len: 0, head: 0, used: 0, peak: 0 });
+static BOOT_SERVICES: AtomicPtr<BootServices> = AtomicPtr::new(core::ptr::null_mut());
+static RUNTIME: AtomicBool = AtomicBool::new(false);
+static POOL_LIVE: AtomicU64 = AtomicU64::new(0);
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+static FREES: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+static LEAKED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    /// Pool backend in use (Boot Services up, BSP)
+    pub pool: bool,
+    /// Bytes held in pool blocks
+    pub pool_live: u64,
+    pub arena_len: usize,
+    /// Arena bytes in use, headers and padding included
+    pub arena_used: usize,
+    pub arena_peak: usize,
+    pub allocs: u64,
+    pub frees: u64,
+    pub failed: u64,
+    /// Pool bytes that could not be returned to firmware
+    pub leaked: u64,
+}
+
+/// The `#[global_allocator]` type; see the module docs.
+pub struct Heap;
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = if pool_usable() { pool_alloc(layout) } else { ARENA.lock(|a| a.alloc(layout)) };
+        if p.is_null() { FAILED.fetch_add(1, Ordering::Relaxed); } else { ALLOCS.fetch_add(1, Ordering::Relaxed); }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        FREES.fetch_add(1, Ordering::Relaxed);
+        let addr = ptr as usize;
+        let in_arena = ARENA.lock(|a| {
+            if !a.contains(addr) { return false; }
+            a.free(addr);
+            true
+        });
+        if in_arena { return; }
+        if pool_usable() {
+            pool_free(ptr, layout);
+        } else {
+            LEAKED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+            POOL_LIVE.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Enable the heap: remember Boot Services for the pool and reserve the
+/// arena. Call once, early on the BSP. If the arena cannot be reserved, the
+/// pool still works and the error is returned.
+pub fn install(system_table: &SystemTable<Boot>) -> Result<(), &'static str> {
+    let bs = system_table.boot_services() as *const BootServices as *mut BootServices;
+    BOOT_SERVICES.store(bs, Ordering::Release);
+    if ARENA.lock(|a| a.len != 0) { return Ok(()); }
+    let mem = super::uefi::alloc_pages(system_table, ARENA_PAGES, MemoryType::LOADER_DATA).ok_or("heap: arena reservation failed")?;
+    ARENA.lock(|a| a.init(mem as usize, ARENA_PAGES * 4096));
+    Ok(())
+}
+
+/// Stop using the pool. Called just before ExitBootServices; from then on
+/// only the arena serves allocations.
+pub fn enter_runtime() {
+    RUNTIME.store(true, Ordering::Release);
+    BOOT_SERVICES.store(core::ptr::null_mut(), Ordering::Release);
+}
+
+pub fn stats() -> Stats {
+    let (arena_len, arena_used, arena_peak) = ARENA.lock(|a| (a.len, a.used, a.peak));
+    Stats {
+        pool: !RUNTIME.load(Ordering::Acquire) && !BOOT_SERVICES.load(Ordering::Acquire).is_null(),
+        pool_live: POOL_LIVE.load(Ordering::Relaxed),
+        arena_len,
+        arena_used,
+        arena_peak,
+        allocs: ALLOCS.load(Ordering::Relaxed),
+        frees: FREES.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+        leaked: LEAKED.load(Ordering::Relaxed),
+    }
+}
+
+/// Boot Services may only be called on the BSP (IA32_APIC_BASE.BSP).
+fn pool_usable() -> bool {
+    if RUNTIME.load(Ordering::Acquire) || BOOT_SERVICES.load(Ordering::Acquire).is_null() { return false; }
+    unsafe { crate::arch::x86::msr::rdmsr(0x1B) & (1 << 8) != 0 }
+}
+
+/// Pool memory is 8-byte aligned; larger alignments over-allocate and keep
+/// the pool pointer in the word before the returned one.
+unsafe fn pool_alloc(layout: Layout) -> *mut u8 {
+    let bs = &*BOOT_SERVICES.load(Ordering::Acquire);
+    let align = layout.align();
+    let size = if align > POOL_ALIGN { layout.size() + align } else { layout.size() };
+    let Ok(p) = bs.allocate_pool(MemoryType::LOADER_DATA, size.max(1)) else { return core::ptr::null_mut() };
+    POOL_LIVE.fetch_add(layout.size() as u64, Ordering::Relaxed);
+    let p = p.as_ptr();
+    if align <= POOL_ALIGN { return p; }
+    let user = (p as usize + align) & !(align - 1);
+    *((user - 8) as *mut usize) = p as usize;
+    user as *mut u8
+}
+
+unsafe fn pool_free(ptr: *mut u8, layout: Layout) {
+    let bs = &*BOOT_SERVICES.load(Ordering::Acquire);
+    let p = if layout.align() > POOL_ALIGN { *((ptr as usize - 8) as *const usize) as *mut u8 } else { ptr };
+    let _ = bs.free_pool(p);
+    POOL_LIVE.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+}
+
+impl Arena {
+    fn init(&mut self, base: usize, len: usize) {
+        self.base = base;
+        self.len = len;
+        self.used = 0;
+        self.peak = 0;
+        self.head = base;
+        unsafe { (base as *mut Free).write(Free { len, next: 0 }); }
+    }
+
+    fn contains(&self, addr: usize) -> bool { self.len != 0 && addr >= self.base && addr < self.base + self.len }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let align = layout.align().max(HDR);
+        let mut prev = 0usize;
+        let mut cur = self.head;
+        while cur != 0 {
+            let blk = unsafe { &*(cur as *const Free) };
+            let user = (cur + HDR + align - 1) & !(align - 1);
+            let mut need = (user + layout.size() - cur + HDR - 1) & !(HDR - 1);
+            if need <= blk.len {
+                let next = if blk.len - need >= MIN_BLOCK {
+                    let rest = cur + need;
+                    unsafe { (rest as *mut Free).write(Free { len: blk.len - need, next: blk.next }); }
+                    rest
+                } else {
+                    need = blk.len;
+                    blk.next
+                };
+                if prev == 0 { self.head = next; } else { unsafe { (*(prev as *mut Free)).next = next; } }
+                unsafe {
+                    *((user - 16) as *mut usize) = cur;
+                    *((user - 8) as *mut usize) = need;
+                }
+                self.used += need;
+                self.peak = self.peak.max(self.used);
+                return user as *mut u8;
+            }
+            prev = cur;
+            cur = blk.next;
+        }
+        core::ptr::null_mut()
+    }
+
+    fn free(&mut self, user: usize) {
+        let (start, len) = unsafe { (*((user - 16) as *const usize), *((user - 8) as *const usize)) };
+        self.used -= len;
+        // Find the neighbours in address order.
+        let mut prev = 0usize;
+        let mut next = self.head;
+        while next != 0 && next < start {
+            prev = next;
+            next = unsafe { (*(next as *const Free)).next };
+        }
+        let mut blk = Free { len, next };
+        if next != 0 && start + len == next {
+            let n = unsafe { &*(next as *const Free) };
+            blk.len += n.len;
+            blk.next = n.next;
+        }
+        if prev != 0 {
+            let p = unsafe { &mut *(prev as *mut Free) };
+            if prev + p.len == start {
+                p.len += blk.len;
+                p.next = blk.next;
+                return;
+            }
+            p.next = start;
+        } else {
+            self.head = start;
+        }
+        unsafe { (start as *mut Free).write(blk); }
+    }
+}
@@ -4,5 +4,9 @@ pub mod uefi;
 pub mod ept;
 pub mod npt;
 pub mod paging;
+pub mod dma;
+pub mod guest;
+pub mod stage2;
+pub mod heap;
 
 
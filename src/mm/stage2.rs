@@ -0,0 +1,379 @@
+#![allow(dead_code)]
+
+//! Stage-2 (EPT/NPT) mappings of guest RAM with large leaves.
+//!
+//! `build_offset` maps a guest-physical range onto host memory with the
+//! largest leaf each piece allows: 1GiB where the guest and host addresses
+//! are both 1GiB aligned, the page lies wholly inside the range and the CPU
+//! supports it, then 2MiB on the same terms, else 4KiB. Write-protecting a
+//! subrange for dirty tracking needs 4KiB leaves there, so `demote` splits
+//! the large leaves over a range and `promote` merges tables of uniform,
+//! contiguous leaves back into one large leaf. `unmap` and `fill_offset`
+//! punch 4KiB holes into the mapping and close them again. `remap_page`
+//! points one 4KiB leaf at another frame; leaves it marks shared keep
+//! their frame and stay read-only until remapped again.
+//!
+//! EPT and NPT share the x86 table layout (large leaves at levels 3 and 2
+//! carry bit 7); only the leaf permission, memory-type and accessed/dirty
+//! bits differ. Every change bumps `generation`; vCPUs compare it before
+//! entry and flush cached translations when it moved.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use super::ept::EptCaps;
+
+const ADDR: u64 = 0x000F_FFFF_FFFF_F000;
+const PS: u64 = 1 << 7;
+const WRITE: u64 = 1 << 1;
+/// Any of R/W/X (EPT) or P/W/U (NPT): the entry is in use
+const PRESENT: u64 = 0x7;
+/// Non-leaf entries grant everything; leaves decide
+const TABLE: u64 = 0x7;
+/// Software bit (ignored by EPT and NPT walks): the leaf maps a frame
+/// shared between guests and must not be made writable or merged
+const SHARED: u64 = 1 << 52;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format { Ept, Npt }
+
+impl Format {
+    fn leaf(self) -> u64 {
+        match self {
+            // RWX, write-back, ignore PAT
+            Format::Ept => 0x7 | (6 << 3) | (1 << 6),
+            // present, writable, user
+            Format::Npt => 0x7,
+        }
+    }
+
+    /// Accessed and dirty flags, which differ within one leaf and are merged on promotion.
+    fn ad(self) -> u64 {
+        match self {
+            Format::Ept => (1 << 8) | (1 << 9),
+            Format::Npt => (1 << 5) | (1 << 6),
+        }
+    }
+}
+
+/// Leaf sizes the CPU supports for `fmt`.
+pub fn caps(fmt: Format) -> EptCaps {
+    match fmt {
+        Format::Ept => {
+            if !crate::arch::x86::cpuid::has_vmx() { return EptCaps::default(); }
+            let c = unsafe { crate::arch::x86::msr::rdmsr(crate::arch::x86::msr::IA32_VMX_EPT_VPID_CAP) };
+            EptCaps { large_page_2m: (c & (1 << 16)) != 0, large_page_1g: (c & (1 << 17)) != 0 }
+        }
+        Format::Npt => EptCaps { large_page_2m: true, large_page_1g: crate::arch::x86::cpuid::has_page1gb() },
+    }
+}
+
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// Changes so far to any stage-2 table; starts at 1.
+pub fn generation() -> u64 { GENERATION.load(Ordering::SeqCst) }
+
+fn bump() { GENERATION.fetch_add(1, Ordering::SeqCst); }
+
+/// Bytes mapped by one entry at `level` (1 = 4KiB PTE .. 3 = 1GiB PDPTE).
+fn size(level: u32) -> u64 { 1u64 << (12 + 9 * (level - 1)) }
+
+fn index(gpa: u64, level: u32) -> usize { ((gpa >> (12 + 9 * (level - 1))) & 0x1FF) as usize }
+
+fn alloc_table(system_table: &SystemTable<Boot>) -> Option<*mut u64> {
+    let page = super::uefi::alloc_pages(system_table, 1, uefi::table::boot::MemoryType::LOADER_DATA)?;
+    unsafe { core::ptr::write_bytes(page, 0, 4096); }
+    Some(page as *mut u64)
+}
+
+/// The leaf mapping `gpa` and its level, or the level of the missing entry.
+unsafe fn lookup(root: u64, gpa: u64) -> Result<(*mut u64, u32), u32> {
+    let mut table = (root & ADDR) as *mut u64;
+    let mut level = 4;
+    loop {
+        let e = unsafe { table.add(index(gpa, level)) };
+        let v = unsafe { e.read_volatile() };
+        if v & PRESENT == 0 { return Err(level); }
+        if level == 1 || (level <= 3 && v & PS != 0) { return Ok((e, level)); }
+        table = (v & ADDR) as *mut u64;
+        level -= 1;
+    }
+}
+
+/// Install `leaf` for `gpa` at `level`, creating tables on the way down.
+unsafe fn map(system_table: &SystemTable<Boot>, root: *mut u64, gpa: u64, leaf: u64, level: u32) -> Option<()> {
+    let mut table = root;
+    let mut l = 4;
+    while l > level {
+        let e = unsafe { table.add(index(gpa, l)) };
+        if unsafe { *e } & PRESENT == 0 { unsafe { *e = alloc_table(system_table)? as u64 | TABLE; } }
+        table = (unsafe { *e } & ADDR) as *mut u64;
+        l -= 1;
+    }
+    unsafe { *table.add(index(gpa, level)) = leaf; }
+    Some(())
+}
+
+fn fits(gpa: u64, hpa: u64, end: u64, level: u32) -> bool {
+    let s = size(level);
+    gpa % s == 0 && hpa % s == 0 && gpa + s <= end
+}
+
+/// Map guest-physical `[0, guest_bytes)` onto host memory at `host_base`
+/// (4KiB aligned) with the largest leaves `caps` and alignment allow.
+/// Returns the root table.
+pub fn build_offset(system_table: &SystemTable<Boot>, fmt: Format, guest_bytes: u64, host_base: u64, caps: EptCaps) -> Option<*mut u64> {
+    if guest_bytes == 0 || (host_base & 0xFFF) != 0 { return None; }
+    let end = (guest_bytes + 0xFFF) & !0xFFF;
+    let root = alloc_table(system_table)?;
+    let mut gpa = 0;
+    while gpa < end {
+        let hpa = host_base + gpa;
+        let level = if caps.large_page_1g && fits(gpa, hpa, end, 3) { 3 } else if caps.large_page_2m && fits(gpa, hpa, end, 2) { 2 } else { 1 };
+        let leaf = hpa | fmt.leaf() | if level > 1 { PS } else { 0 };
+        unsafe { map(system_table, root, gpa, leaf, level)?; }
+        gpa += size(level);
+    }
+    Some(root)
+}
+
+/// Replace the large leaf at `e` with a full table `t` of leaves one level
+/// down. The table is filled before it is linked, so walks never see it
+/// half built.
+unsafe fn split(t: *mut u64, e: *mut u64, level: u32) {
+    let v = unsafe { *e };
+    let child = size(level - 1);
+    let base = v & ADDR & !(size(level) - 1);
+    let flags = v & !ADDR & !PS;
+    let ps = if level - 1 > 1 { PS } else { 0 };
+    for i in 0..512u64 { unsafe { *t.add(i as usize) = (base + i * child) | flags | ps; } }
+    unsafe { e.write_volatile(t as u64 | TABLE); }
+}
+
+/// Split every large leaf overlapping `[start, start + len)` down to 4KiB.
+/// Returns the number of leaves split.
+pub fn demote(system_table: &SystemTable<Boot>, root: u64, start: u64, len: u64) -> Result<u32, &'static str> {
+    demote_with(root, start, len, &mut || alloc_table(system_table).map(|t| t as u64))
+}
+
+/// As `demote`, taking zeroed table pages from `alloc`; usable where boot
+/// services are not.
+pub fn demote_with(root: u64, start: u64, len: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Result<u32, &'static str> {
+    let end = start.saturating_add(len);
+    let mut gpa = start & !0xFFF;
+    let mut n = 0;
+    while gpa < end {
+        match unsafe { lookup(root, gpa) } {
+            Err(level) => gpa = (gpa & !(size(level) - 1)) + size(level),
+            Ok((_, 1)) => gpa += 4096,
+            Ok((e, level)) => {
+                let t = alloc().ok_or("stage2: out of memory for page tables")?;
+                unsafe { split(t as *mut u64, e, level); }
+                n += 1;
+            }
+        }
+    }
+    if n != 0 { bump(); }
+    Ok(n)
+}
+
+/// Merge the table under the level-`level` entry for `gpa` into one leaf if
+/// its 512 leaves map contiguous, aligned host memory with equal flags.
+unsafe fn merge(system_table: &SystemTable<Boot>, fmt: Format, root: u64, gpa: u64, level: u32) -> bool {
+    let mut table = (root & ADDR) as *mut u64;
+    let mut l = 4;
+    while l > level {
+        let v = unsafe { *table.add(index(gpa, l)) };
+        if v & PRESENT == 0 || (l <= 3 && v & PS != 0) { return false; }
+        table = (v & ADDR) as *mut u64;
+        l -= 1;
+    }
+    let e = unsafe { table.add(index(gpa, level)) };
+    let v = unsafe { *e };
+    if v & PRESENT == 0 || v & PS != 0 { return false; }
+    let t = (v & ADDR) as *mut u64;
+    let first = unsafe { *t };
+    let child_leaf = level - 1 == 1 || first & PS != 0;
+    let base = first & ADDR;
+    let flags = first & !ADDR & !fmt.ad();
+    if !child_leaf || first & PRESENT == 0 || first & SHARED != 0 || base % size(level) != 0 { return false; }
+    let mut ad = 0;
+    for i in 0..512u64 {
+        let c = unsafe { *t.add(i as usize) };
+        if c & ADDR != base + i * size(level - 1) || c & !ADDR & !fmt.ad() != flags { return false; }
+        ad |= c & fmt.ad();
+    }
+    unsafe { e.write_volatile(base | flags | PS | ad); }
+    // Walks cached through the old table still translate the same way.
+    super::uefi::free_pages(system_table, t as *mut u8, 1);
+    true
+}
+
+/// Merge uniform tables inside `[start, start + len)` into 2MiB leaves, then
+/// 2MiB leaves into 1GiB ones, as far as `caps` allow. Returns the merges done.
+pub fn promote(system_table: &SystemTable<Boot>, fmt: Format, root: u64, start: u64, len: u64, caps: EptCaps) -> u32 {
+    let end = start.saturating_add(len);
+    let mut n = 0;
+    for (level, ok) in [(2, caps.large_page_2m), (3, caps.large_page_1g)] {
+        if !ok { continue; }
+        let s = size(level);
+        let mut gpa = (start + s - 1) & !(s - 1);
+        while gpa.saturating_add(s) <= end {
+            if unsafe { merge(system_table, fmt, root, gpa, level) } { n += 1; }
+            gpa += s;
+        }
+    }
+    if n != 0 { bump(); }
+    n
+}
+
+/// Write-protect `[start, start + len)` at 4KiB granularity, or make it
+/// writable again and promote what became uniform. Returns the leaves changed.
+pub fn set_writable(system_table: &SystemTable<Boot>, fmt: Format, root: u64, start: u64, len: u64, writable: bool, caps: EptCaps) -> Result<u32, &'static str> {
+    demote(system_table, root, start, len)?;
+    let end = start.saturating_add(len);
+    let mut gpa = start & !0xFFF;
+    let mut n = 0;
+    while gpa < end {
+        match unsafe { lookup(root, gpa) } {
+            Err(level) => gpa = (gpa & !(size(level) - 1)) + size(level),
+            Ok((e, _)) => {
+                let v = unsafe { *e };
+                let new = if v & SHARED != 0 { v } else if writable { v | WRITE } else { v & !WRITE };
+                if new != v { unsafe { e.write_volatile(new); } n += 1; }
+                gpa += 4096;
+            }
+        }
+    }
+    if n != 0 { bump(); }
+    if writable { promote(system_table, fmt, root, start, len, caps); }
+    Ok(n)
+}
+
+/// Unmap the 4KiB pages of `[start, start + len)`, splitting large leaves
+/// first. Returns the pages that were mapped.
+pub fn unmap(system_table: &SystemTable<Boot>, root: u64, start: u64, len: u64) -> Result<u32, &'static str> {
+    unmap_with(root, start, len, &mut || alloc_table(system_table).map(|t| t as u64))
+}
+
+/// As `unmap`, taking table pages from `alloc`. A range mapped with 4KiB
+/// leaves needs none.
+pub fn unmap_with(root: u64, start: u64, len: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Result<u32, &'static str> {
+    demote_with(root, start, len, alloc)?;
+    let end = start.saturating_add(len);
+    let mut gpa = start & !0xFFF;
+    let mut n = 0;
+    while gpa < end {
+        match unsafe { lookup(root, gpa) } {
+            Err(level) => gpa = (gpa & !(size(level) - 1)) + size(level),
+            Ok((e, _)) => { unsafe { e.write_volatile(0); } n += 1; gpa += 4096; }
+        }
+    }
+    if n != 0 { bump(); }
+    Ok(n)
+}
+
+/// Map `[gpa, gpa + len)` onto host memory at `hpa` with 4KiB leaves,
+/// read-only unless `writable`, so the range can later be unmapped or
+/// re-protected without allocating. Returns the pages mapped.
+pub fn map_range(system_table: &SystemTable<Boot>, fmt: Format, root: u64, gpa: u64, len: u64, hpa: u64, writable: bool) -> Result<u32, &'static str> {
+    let mut n = 0;
+    let mut off = 0;
+    while off < len {
+        let leaf = ((hpa + off) & ADDR) | if writable { fmt.leaf() } else { fmt.leaf() & !WRITE };
+        unsafe { map(system_table, (root & ADDR) as *mut u64, gpa + off, leaf, 1) }.ok_or("stage2: out of memory for page tables")?;
+        n += 1;
+        off += 4096;
+    }
+    if n != 0 { bump(); }
+    Ok(n)
+}
+
+/// Map every hole in `[start, start + len)` back onto host memory at
+/// `host_base + gpa` with 4KiB leaves, then promote what became uniform.
+/// Returns the pages mapped.
+pub fn fill_offset(system_table: &SystemTable<Boot>, fmt: Format, root: u64, start: u64, len: u64, host_base: u64, caps: EptCaps) -> Result<u32, &'static str> {
+    let end = start.saturating_add(len);
+    let mut gpa = start & !0xFFF;
+    let mut n = 0;
+    while gpa < end {
+        match unsafe { lookup(root, gpa) } {
+            Ok((_, level)) => gpa = (gpa & !(size(level) - 1)) + size(level),
+            Err(_) => {
+                unsafe { map(system_table, (root & ADDR) as *mut u64, gpa, (host_base + gpa) | fmt.leaf(), 1) }
+                    .ok_or("stage2: out of memory for page tables")?;
+                n += 1;
+                gpa += 4096;
+            }
+        }
+    }
+    if n != 0 {
+        bump();
+        promote(system_table, fmt, root, start, len, caps);
+    }
+    Ok(n)
+}
+
+/// The leaf mapping one guest page.
+#[derive(Clone, Copy, Debug)]
+pub struct PageLeaf {
+    /// Host-physical address of the 4KiB page within the leaf
+    pub hpa: u64,
+    /// 1 = 4KiB .. 3 = 1GiB
+    pub level: u32,
+    pub writable: bool,
+    pub shared: bool,
+}
+
+/// How guest page `gpa` is mapped, or None if it is not.
+pub fn page(root: u64, gpa: u64) -> Option<PageLeaf> {
+    let (e, level) = unsafe { lookup(root, gpa) }.ok()?;
+    let v = unsafe { e.read_volatile() };
+    let hpa = (v & ADDR & !(size(level) - 1)) + (gpa & (size(level) - 1) & !0xFFF);
+    Some(PageLeaf { hpa, level, writable: v & WRITE != 0, shared: v & SHARED != 0 })
+}
+
+/// Point the 4KiB leaf of `gpa` at `hpa`, read-only unless `writable`, and
+/// marked shared if `shared`. False if `gpa` is not mapped by a 4KiB leaf.
+pub fn remap_page(fmt: Format, root: u64, gpa: u64, hpa: u64, writable: bool, shared: bool) -> bool {
+    let Ok((e, 1)) = (unsafe { lookup(root, gpa) }) else { return false };
+    let mut v = (hpa & ADDR) | fmt.leaf();
+    if !writable { v &= !WRITE; }
+    if shared { v |= SHARED; }
+    unsafe { e.write_volatile(v); }
+    bump();
+    true
+}
+
+/// Leaves of a stage-2 table by size.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LeafStats { pub g1: u64, pub m2: u64, pub k4: u64 }
+
+impl LeafStats {
+    pub fn add(&mut self, o: &LeafStats) { self.g1 += o.g1; self.m2 += o.m2; self.k4 += o.k4; }
+
+    pub fn bytes(&self) -> u64 { (self.g1 << 30) + (self.m2 << 21) + (self.k4 << 12) }
+
+    /// Share of mapped bytes in 1GiB and 2MiB leaves, in percent.
+    pub fn large_pct(&self) -> u32 {
+        let total = self.bytes();
+        if total == 0 { 0 } else { (((self.g1 << 30) + (self.m2 << 21)) as u128 * 100 / total as u128) as u32 }
+    }
+}
+
+/// Count the leaves mapping guest-physical `[0, limit)`.
+pub fn leaf_stats(root: u64, limit: u64) -> LeafStats {
+    let mut s = LeafStats::default();
+    let mut gpa = 0;
+    while gpa < limit {
+        match unsafe { lookup(root, gpa) } {
+            Err(level) => gpa = (gpa & !(size(level) - 1)) + size(level),
+            Ok((_, level)) => {
+                match level { 3 => s.g1 += 1, 2 => s.m2 += 1, _ => s.k4 += 1 }
+                gpa = (gpa & !(size(level) - 1)) + size(level);
+            }
+        }
+    }
+    s
+}
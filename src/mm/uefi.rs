@@ -3,7 +3,7 @@
 //! Thin wrappers around UEFI Boot Services page allocation for identity-mapped
 //! early memory usage (e.g., VMXON region).
 
-use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::table::boot::{AllocateType, MemoryDescriptor, MemoryType};
 use uefi::table::SystemTable;
 use uefi::prelude::Boot;
 
@@ -33,3 +33,73 @@ pub fn alloc_pages_at(system_table: &SystemTable<Boot>, phys: u64, pages: usize,
 }
 
 
+
+/// Call `f` for each descriptor of the current UEFI memory map. Returns
+/// false if the map cannot be retrieved. `f` must not allocate from firmware.
+pub fn for_each_map_entry(system_table: &SystemTable<Boot>, mut f: impl FnMut(&MemoryDescriptor)) -> bool {
+    let bs = system_table.boot_services();
+    let sz = bs.memory_map_size();
+    // Leave headroom for descriptors added by our own buffer allocation.
+    let bytes = sz.map_size + 8 * sz.entry_size;
+    let pages = (bytes + 4095) / 4096;
+    let buf = match alloc_pages(system_table, pages, MemoryType::LOADER_DATA) { Some(p) => p, None => return false };
+    let slice = unsafe { core::slice::from_raw_parts_mut(buf, pages * 4096) };
+    let ok = match bs.memory_map(slice) {
+        Ok(map) => { for d in map.entries() { f(d); } true }
+        Err(_) => false,
+    };
+    free_pages(system_table, buf, pages);
+    ok
+}
+
+/// Total bytes of conventional (free) memory reported by the UEFI memory map.
+/// Returns 0 if the map cannot be retrieved.
+pub fn conventional_bytes(system_table: &SystemTable<Boot>) -> u64 {
+    let mut total = 0u64;
+    for_each_map_entry(system_table, |d| {
+        if d.ty == MemoryType::CONVENTIONAL { total = total.saturating_add(d.page_count.saturating_mul(4096)); }
+    });
+    total
+}
+
+/// Allocate pages from free memory on NUMA `node`, spilling to the nearest
+/// other nodes when it is full. Returns the allocation and the node it came
+/// from. Without SRAT there is only node 0 and this is plain `alloc_pages`.
+pub fn alloc_pages_on_node(system_table: &SystemTable<Boot>, node: u8, pages: usize, mem_type: MemoryType) -> Option<(*mut u8, u8)> {
+    let topo = crate::firmware::acpi::numa::topology(system_table);
+    if !topo.from_srat { return alloc_pages(system_table, pages, mem_type).map(|p| (p, 0)); }
+    // Snapshot free regions first; allocating while holding the map would change it.
+    let mut free = [(0u64, 0u64); 128];
+    let mut nfree = 0;
+    let bs = system_table.boot_services();
+    let sz = bs.memory_map_size();
+    let bytes = sz.map_size + 8 * sz.entry_size;
+    let mpages = (bytes + 4095) / 4096;
+    let buf = alloc_pages(system_table, mpages, MemoryType::LOADER_DATA)?;
+    let slice = unsafe { core::slice::from_raw_parts_mut(buf, mpages * 4096) };
+    if let Ok(map) = bs.memory_map(slice) {
+        for d in map.entries() {
+            if d.ty == MemoryType::CONVENTIONAL && nfree < free.len() {
+                free[nfree] = (d.phys_start, d.phys_start.saturating_add(d.page_count.saturating_mul(4096)));
+                nfree += 1;
+            }
+        }
+    }
+    free_pages(system_table, buf, mpages);
+    let want = (pages as u64).saturating_mul(4096);
+    let mut order = [0u8; crate::firmware::acpi::numa::MAX_NODES];
+    let n = topo.nearest(node, &mut order);
+    let first = [node];
+    for &nd in first.iter().chain(order[..n].iter()) {
+        for r in topo.mem_ranges().iter().filter(|r| r.node == nd && !r.hotplug) {
+            for &(fs, fe) in &free[..nfree] {
+                // Top of the overlap, so low memory stays available for firmware users.
+                let lo = fs.max(r.base);
+                let hi = fe.min(r.base.saturating_add(r.len)) & !0xFFF;
+                if hi <= lo || hi - lo < want { continue; }
+                if let Some(p) = alloc_pages_at(system_table, hi - want, pages, mem_type) { return Some((p, nd)); }
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,516 @@
+#![allow(dead_code)]
+
+//! NVMe host driver.
+//!
+//! Controllers are found by class code (01h/08h/02h) on the ECAM buses.
+//! `probe` takes one over: it resets the controller, puts an admin queue
+//! pair and one I/O queue pair in `mm::dma` and reads the active
+//! namespaces. Commands are polled one at a time under the driver's lock,
+//! and data moves through a bounce buffer in the pool, so the controller
+//! only ever DMAs into the pool and nothing after `probe` needs Boot
+//! Services.
+//!
+//! Namespaces are for the host: a `storage` pool can sit on one, and
+//! `vm blk add nvme=` hands one to a guest as a virtio-blk disk. The guest
+//! then never reaches the controller. To give a guest the controller
+//! itself, `assign` passes it through whole with `hv::sriov::attach_function`,
+//! into the VM's IOMMU domain; the driver has to let go of it first.
+//! Direct per-namespace passthrough would need the controller's own
+//! virtualization support and is not done.
+//!
+//! Firmware's NVMe driver is not told when a controller is taken. Its
+//! Block I/O handles on that controller stop working, so the controller
+//! the hypervisor was booted from must not be probed.
+
+pub mod queue;
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::hv::sriov::{self, Bdf};
+use crate::hv::vpci::{CFG_CLASS, CFG_COMMAND, CFG_DEVICE, CFG_PROG_IF, CFG_SUBCLASS, CFG_VENDOR, CMD_INTX_DISABLE, CMD_MASTER, CMD_MEM};
+use crate::iommu::{mmio_read16, mmio_read32, mmio_read8, mmio_write16, mmio_write32};
+use crate::obs::metrics::{Counter, NVME_COMMANDS, NVME_ERRORS};
+use crate::util::spinlock::SpinLock;
+use queue::{Queue, Status};
+
+pub const MAX_CTRLS: usize = 4;
+/// Namespaces tracked per controller
+pub const MAX_NS: usize = 16;
+/// Bounce buffer pages; also the largest transfer per command
+pub const BOUNCE_PAGES: usize = 8;
+/// Pool pages `probe` reserves when `mm::dma` is still empty
+pub const POOL_PAGES: usize = 64;
+
+// Controller registers
+const REG_CAP: usize = 0x00;
+const REG_VS: usize = 0x08;
+const REG_INTMS: usize = 0x0C;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1C;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+
+const CC_EN: u32 = 1 << 0;
+const CC_SHN_NORMAL: u32 = 1 << 14;
+const CC_SHN_MASK: u32 = 3 << 14;
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+const CSTS_SHST_DONE: u32 = 2 << 2;
+const CSTS_SHST_MASK: u32 = 3 << 2;
+
+// Admin opcodes
+const OP_DELETE_SQ: u8 = 0x00;
+const OP_CREATE_SQ: u8 = 0x01;
+const OP_DELETE_CQ: u8 = 0x04;
+const OP_CREATE_CQ: u8 = 0x05;
+const OP_IDENTIFY: u8 = 0x06;
+// NVM opcodes
+const OP_FLUSH: u8 = 0x00;
+const OP_WRITE: u8 = 0x01;
+const OP_READ: u8 = 0x02;
+
+const CNS_NAMESPACE: u32 = 0;
+const CNS_CONTROLLER: u32 = 1;
+const CNS_ACTIVE_LIST: u32 = 2;
+
+const IO_QID: u16 = 1;
+/// Shortest wait for a command, whatever CAP.TO says
+const MIN_TIMEOUT_MS: u64 = 2000;
+
+/// An NVMe controller on the PCI buses.
+#[derive(Clone, Copy, Debug)]
+pub struct Found {
+    pub bdf: Bdf,
+    pub vendor: u16,
+    pub device: u16,
+    /// Driver index while the host drives it
+    pub ctrl: Option<usize>,
+    /// VM it is passed through to
+    pub vm_id: Option<u64>,
+}
+
+/// A controller the driver owns.
+#[derive(Clone, Copy, Debug)]
+pub struct Info {
+    pub bdf: Bdf,
+    pub vendor: u16,
+    pub device: u16,
+    /// VS register: major << 16 | minor << 8 | tertiary
+    pub version: u32,
+    pub serial: [u8; 20],
+    pub model: [u8; 40],
+    pub firmware: [u8; 8],
+    /// Largest transfer per command, bytes
+    pub max_xfer: u32,
+    /// I/O queue entries
+    pub depth: u16,
+}
+
+impl Info {
+    pub fn serial(&self) -> &str { trim(&self.serial) }
+    pub fn model(&self) -> &str { trim(&self.model) }
+    pub fn firmware(&self) -> &str { trim(&self.firmware) }
+}
+
+/// An active namespace with a format the driver handles (no metadata,
+/// 512 to 4096 byte blocks).
+#[derive(Clone, Copy, Debug)]
+pub struct Namespace {
+    pub nsid: u32,
+    pub blocks: u64,
+    pub block_size: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+    /// Status of the last failed command
+    pub last_status: Status,
+}
+
+#[derive(Clone, Copy)]
+struct Ctrl {
+    info: Info,
+    cfg: usize,
+    regs: usize,
+    /// Doorbell stride, bytes
+    stride: usize,
+    timeout_ms: u64,
+    admin: Queue,
+    io: Queue,
+    bounce: u64,
+    /// PRP list for transfers over two pages; points into `bounce`
+    prp: u64,
+    ns: [Option<Namespace>; MAX_NS],
+    stats: Stats,
+    /// A command went unanswered; the queues are out of step
+    failed: bool,
+}
+
+static CTRLS: SpinLock<[Option<Ctrl>; MAX_CTRLS]> = SpinLock::new([None; MAX_CTRLS]);
+
+fn trim(b: &[u8]) -> &str {
+    let end = b.iter().rposition(|&c| c != b' ' && c != 0).map_or(0, |i| i + 1);
+    core::str::from_utf8(&b[..end]).unwrap_or("?")
+}
+
+fn read64(addr: usize) -> u64 { mmio_read32(addr) as u64 | (mmio_read32(addr + 4) as u64) << 32 }
+
+fn write64(addr: usize, v: u64) {
+    mmio_write32(addr, v as u32);
+    mmio_write32(addr + 4, (v >> 32) as u32);
+}
+
+/// Spin until `done` holds or `ms` pass; true if it held.
+fn wait(ms: u64, mut done: impl FnMut() -> bool) -> bool {
+    if crate::time::clock::hz() != 0 {
+        return crate::time::clock::spin_until(crate::time::clock::Deadline::after_ms(ms), done);
+    }
+    // No clock yet: bound the spin count instead
+    for _ in 0..ms * 100_000 {
+        if done() { return true; }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+fn command(opcode: u8, nsid: u32, prp1: u64, prp2: u64, cdw: [u32; 6]) -> [u32; 16] {
+    let mut c = [0u32; 16];
+    c[0] = opcode as u32;
+    c[1] = nsid;
+    c[6] = prp1 as u32;
+    c[7] = (prp1 >> 32) as u32;
+    c[8] = prp2 as u32;
+    c[9] = (prp2 >> 32) as u32;
+    c[10..16].copy_from_slice(&cdw);
+    c
+}
+
+fn is_nvme(cfg: usize) -> bool {
+    mmio_read8(cfg + CFG_CLASS) == 0x01 && mmio_read8(cfg + CFG_SUBCLASS) == 0x08 && mmio_read8(cfg + CFG_PROG_IF) == 0x02
+}
+
+/// Register base from BAR0/BAR1 (always 64-bit memory on NVMe).
+fn bar0(cfg: usize) -> Option<usize> {
+    let lo = mmio_read32(cfg + 0x10);
+    if lo & 1 != 0 { return None; }
+    let mut base = (lo & 0xFFFF_F000) as u64;
+    if (lo >> 1) & 3 == 2 { base |= (mmio_read32(cfg + 0x14) as u64) << 32; }
+    if base == 0 { None } else { Some(base as usize) }
+}
+
+/// Clear CC.EN and wait for the controller to report not ready.
+fn disable(regs: usize, timeout_ms: u64) -> Result<(), &'static str> {
+    let cc = mmio_read32(regs + REG_CC);
+    if cc & CC_EN != 0 { mmio_write32(regs + REG_CC, cc & !CC_EN); }
+    if wait(timeout_ms, || mmio_read32(regs + REG_CSTS) & CSTS_RDY == 0) { Ok(()) } else { Err("nvme: controller did not reset") }
+}
+
+impl Ctrl {
+    fn exec(&mut self, io: bool, cmd: [u32; 16]) -> Result<u32, &'static str> {
+        if self.failed { return Err("nvme: controller stopped answering (release and probe it again)"); }
+        Counter::new(&NVME_COMMANDS).inc();
+        let q = if io { &mut self.io } else { &mut self.admin };
+        match q.exec(cmd, self.timeout_ms) {
+            Ok(v) => Ok(v),
+            Err(s) => {
+                Counter::new(&NVME_ERRORS).inc();
+                self.stats.errors += 1;
+                match s {
+                    Some(s) => { self.stats.last_status = s; Err("nvme: command failed") }
+                    None => { self.failed = true; Err("nvme: command timed out") }
+                }
+            }
+        }
+    }
+
+    /// Identify into the first bounce page and copy it out.
+    fn identify(&mut self, cns: u32, nsid: u32) -> Result<[u8; 4096], &'static str> {
+        self.exec(false, command(OP_IDENTIFY, nsid, self.bounce, 0, [cns, 0, 0, 0, 0, 0]))?;
+        let mut out = [0u8; 4096];
+        unsafe { core::ptr::copy_nonoverlapping(self.bounce as *const u8, out.as_mut_ptr(), out.len()); }
+        Ok(out)
+    }
+
+    /// Identify, create the I/O queue pair and read the namespaces.
+    fn init(&mut self) -> Result<(), &'static str> {
+        let id = self.identify(CNS_CONTROLLER, 0)?;
+        self.info.serial.copy_from_slice(&id[4..24]);
+        self.info.model.copy_from_slice(&id[24..64]);
+        self.info.firmware.copy_from_slice(&id[64..72]);
+        let max = (BOUNCE_PAGES * 4096) as u32;
+        self.info.max_xfer = match id[77] { 0 => max, m if m < 16 => max.min(4096 << m), _ => max };
+        let nn = u32::from_le_bytes([id[516], id[517], id[518], id[519]]);
+
+        let depth = self.info.depth;
+        self.io = Queue::new(self.regs, self.stride, IO_QID, depth)?;
+        let size = (depth as u32 - 1) << 16 | IO_QID as u32;
+        self.exec(false, command(OP_CREATE_CQ, 0, self.io.cq, 0, [size, 1, 0, 0, 0, 0]))?;
+        self.exec(false, command(OP_CREATE_SQ, 0, self.io.sq, 0, [size, (IO_QID as u32) << 16 | 1, 0, 0, 0, 0]))?;
+
+        // The active list needs NVMe 1.1; older controllers get every id up to NN.
+        let mut ids = [0u32; MAX_NS];
+        if self.info.version >= 0x0001_0100 {
+            let list = self.identify(CNS_ACTIVE_LIST, 0)?;
+            for (i, w) in list.chunks_exact(4).take(MAX_NS).enumerate() { ids[i] = u32::from_le_bytes([w[0], w[1], w[2], w[3]]); }
+        } else {
+            for (i, n) in (1..=nn.min(MAX_NS as u32)).enumerate() { ids[i] = n; }
+        }
+        let mut k = 0;
+        for &nsid in ids.iter().take_while(|&&n| n != 0) {
+            let id = self.identify(CNS_NAMESPACE, nsid)?;
+            let blocks = u64::from_le_bytes(id[0..8].try_into().unwrap_or([0; 8]));
+            let f = 128 + (id[26] & 0xF) as usize * 4;
+            let lbaf = u32::from_le_bytes([id[f], id[f + 1], id[f + 2], id[f + 3]]);
+            let (ms, lbads) = (lbaf & 0xFFFF, (lbaf >> 16) & 0xFF);
+            if blocks == 0 || ms != 0 || !(9..=12).contains(&lbads) { continue; }
+            self.ns[k] = Some(Namespace { nsid, blocks, block_size: 1 << lbads });
+            k += 1;
+        }
+        Ok(())
+    }
+
+    fn namespace(&self, nsid: u32) -> Result<Namespace, &'static str> {
+        self.ns.iter().flatten().find(|n| n.nsid == nsid).copied().ok_or("nvme: no such namespace")
+    }
+
+    /// Read or write `bytes` (at most `max_xfer`) between the bounce buffer and `lba`.
+    fn transfer(&mut self, opcode: u8, nsid: u32, lba: u64, bytes: usize, block_size: u32) -> Result<(), &'static str> {
+        let pages = bytes.div_ceil(4096);
+        let prp2 = match pages { 1 => 0, 2 => self.bounce + 4096, _ => self.prp };
+        let nlb = (bytes / block_size as usize) as u32 - 1;
+        self.exec(true, command(opcode, nsid, self.bounce, prp2, [lba as u32, (lba >> 32) as u32, nlb, 0, 0, 0])).map(|_| ())
+    }
+
+    /// Orderly shutdown, then disable the controller and free its pages.
+    fn shutdown(&mut self) {
+        if !self.failed && self.io.size != 0 {
+            let _ = self.exec(false, command(OP_DELETE_SQ, 0, 0, 0, [IO_QID as u32, 0, 0, 0, 0, 0]));
+            let _ = self.exec(false, command(OP_DELETE_CQ, 0, 0, 0, [IO_QID as u32, 0, 0, 0, 0, 0]));
+        }
+        let regs = self.regs;
+        let cc = mmio_read32(regs + REG_CC);
+        mmio_write32(regs + REG_CC, (cc & !CC_SHN_MASK) | CC_SHN_NORMAL);
+        let _ = wait(self.timeout_ms, || mmio_read32(regs + REG_CSTS) & CSTS_SHST_MASK == CSTS_SHST_DONE);
+        let _ = disable(regs, self.timeout_ms);
+        let cmd = mmio_read16(self.cfg + CFG_COMMAND);
+        mmio_write16(self.cfg + CFG_COMMAND, cmd & !CMD_MASTER);
+        self.io.free();
+        self.admin.free();
+        if self.bounce != 0 { crate::mm::dma::free(self.bounce, BOUNCE_PAGES); }
+        if self.prp != 0 { crate::mm::dma::free(self.prp, 1); }
+        self.bounce = 0;
+        self.prp = 0;
+    }
+}
+
+/// Enumerate NVMe controllers on every ECAM segment.
+pub fn for_each_controller(system_table: &SystemTable<Boot>, mut f: impl FnMut(&Found)) {
+    let Some(mcfg) = crate::firmware::acpi::find_mcfg(system_table) else { return };
+    let owned = CTRLS.lock(|t| {
+        let mut o = [None; MAX_CTRLS];
+        for (i, c) in t.iter().enumerate() { o[i] = c.as_ref().map(|c| c.info.bdf); }
+        o
+    });
+    crate::firmware::acpi::mcfg_for_each_allocation_from(|a| {
+        let mut bus = a.start_bus;
+        loop {
+            for dev in 0u8..32 {
+                for func in 0u8..8 {
+                    let cfg = crate::iommu::ecam_fn_base(a.base_address, a.start_bus, bus, dev, func);
+                    let vendor = mmio_read16(cfg + CFG_VENDOR);
+                    if vendor == 0xFFFF || !is_nvme(cfg) { continue; }
+                    let bdf = Bdf { seg: a.pci_segment, bus, dev, func };
+                    let ctrl = owned.iter().position(|b| *b == Some(bdf));
+                    let mut vm_id = None;
+                    sriov::for_each_attached(|x| if x.vf == bdf { vm_id = Some(x.vm_id); });
+                    f(&Found { bdf, vendor, device: mmio_read16(cfg + CFG_DEVICE), ctrl, vm_id });
+                }
+            }
+            if bus >= a.end_bus { break; }
+            bus += 1;
+        }
+    }, mcfg);
+}
+
+/// Take over the controller at `bdf`. Returns its driver index.
+pub fn probe(system_table: &mut SystemTable<Boot>, bdf: Bdf) -> Result<usize, &'static str> {
+    if CTRLS.lock(|t| t.iter().flatten().any(|c| c.info.bdf == bdf)) { return Err("nvme: controller already probed"); }
+    if crate::iommu::state::find_domain_for_bdf(bdf.seg, bdf.bus, bdf.dev, bdf.func).is_some() {
+        return Err("nvme: controller is assigned to an IOMMU domain");
+    }
+    let cfg = crate::iommu::ecam_cfg_base(system_table, bdf.seg, bdf.bus, bdf.dev, bdf.func).ok_or("nvme: no ECAM window for device")?;
+    if mmio_read16(cfg + CFG_VENDOR) == 0xFFFF { return Err("nvme: no device at address"); }
+    if !is_nvme(cfg) { return Err("nvme: not an NVMe controller"); }
+    let regs = bar0(cfg).ok_or("nvme: BAR0 not assigned")?;
+    if CTRLS.lock(|t| t.iter().all(|c| c.is_some())) { return Err("nvme: too many controllers"); }
+    crate::mm::dma::reserve(system_table, POOL_PAGES)?;
+
+    // Memory decode and bus mastering; completions are polled, so INTx stays off.
+    let cmd = mmio_read16(cfg + CFG_COMMAND);
+    mmio_write16(cfg + CFG_COMMAND, cmd | CMD_MEM | CMD_MASTER | CMD_INTX_DISABLE);
+    let cap = read64(regs + REG_CAP);
+    if (cap >> 37) & 1 == 0 { return Err("nvme: controller lacks the NVM command set"); }
+    if (cap >> 48) & 0xF != 0 { return Err("nvme: controller does not take 4 KiB pages"); }
+    let timeout_ms = ((cap >> 24) & 0xFF).max(1) * 500;
+    let stride = 4usize << ((cap >> 32) & 0xF);
+    let depth = queue::DEPTH.min((cap & 0xFFFF) as u16 + 1);
+    disable(regs, timeout_ms)?;
+    mmio_write32(regs + REG_INTMS, !0);
+
+    let admin = Queue::new(regs, stride, 0, depth)?;
+    let mut c = Ctrl {
+        info: Info {
+            bdf, vendor: mmio_read16(cfg + CFG_VENDOR), device: mmio_read16(cfg + CFG_DEVICE), version: mmio_read32(regs + REG_VS),
+            serial: [0; 20], model: [0; 40], firmware: [0; 8], max_xfer: 4096, depth,
+        },
+        cfg, regs, stride, timeout_ms: timeout_ms.max(MIN_TIMEOUT_MS), admin, io: Queue::NONE,
+        bounce: 0, prp: 0, ns: [None; MAX_NS], stats: Stats::default(), failed: false,
+    };
+    match (crate::mm::dma::alloc(BOUNCE_PAGES), crate::mm::dma::alloc(1)) {
+        (Some(b), Some(p)) => { c.bounce = b; c.prp = p; }
+        (b, p) => {
+            if let Some(b) = b { crate::mm::dma::free(b, BOUNCE_PAGES); }
+            if let Some(p) = p { crate::mm::dma::free(p, 1); }
+            c.admin.free();
+            return Err("nvme: dma pool exhausted");
+        }
+    }
+    // The PRP list is fixed: bounce pages 1.. in order.
+    for i in 1..BOUNCE_PAGES { write64(c.prp as usize + (i - 1) * 8, c.bounce + (i * 4096) as u64); }
+
+    let aq = (depth as u32 - 1) << 16 | (depth as u32 - 1);
+    mmio_write32(regs + REG_AQA, aq);
+    write64(regs + REG_ASQ, c.admin.sq);
+    write64(regs + REG_ACQ, c.admin.cq);
+    mmio_write32(regs + REG_CC, CC_IOCQES | CC_IOSQES | CC_EN);
+    let ready = wait(timeout_ms, || mmio_read32(regs + REG_CSTS) & (CSTS_RDY | CSTS_CFS) != 0);
+    let result = if !ready || mmio_read32(regs + REG_CSTS) & CSTS_CFS != 0 { Err("nvme: controller did not become ready") } else { c.init() };
+    if let Err(e) = result {
+        c.shutdown();
+        return Err(e);
+    }
+    let idx = CTRLS.lock(|t| {
+        let i = t.iter().position(|s| s.is_none())?;
+        t[i] = Some(c);
+        Some(i)
+    });
+    match idx {
+        Some(i) => {
+            crate::obs::log::info(system_table, "nvme", "controller up");
+            Ok(i)
+        }
+        None => {
+            c.shutdown();
+            Err("nvme: too many controllers")
+        }
+    }
+}
+
+/// Shut controller `idx` down and give it up. Fails while a storage pool or
+/// a guest disk uses one of its namespaces.
+pub fn release(idx: usize) -> Result<(), &'static str> {
+    if matches!(crate::hv::storage::info(), Some(p) if matches!(p.device, crate::hv::storage::Device::Nvme { ctrl, .. } if ctrl == idx)) {
+        return Err("nvme: the storage pool is on this controller (storage pool close first)");
+    }
+    let mut used = false;
+    crate::hv::vdev::blk::for_each(|_, d| if matches!(d.backing, crate::hv::vdev::blk::Backing::Nvme { ctrl, .. } if ctrl == idx) { used = true; });
+    if used { return Err("nvme: a vm disk is on this controller"); }
+    let mut c = CTRLS.lock(|t| t.get_mut(idx).and_then(|c| c.take())).ok_or("nvme: no such controller")?;
+    c.shutdown();
+    Ok(())
+}
+
+pub fn info(idx: usize) -> Option<Info> { CTRLS.lock(|t| t.get(idx).and_then(|c| c.as_ref()).map(|c| c.info)) }
+
+/// Iterate owned controllers as (index, info, stats).
+pub fn for_each(mut f: impl FnMut(usize, &Info, &Stats)) {
+    let mut snap = [None; MAX_CTRLS];
+    CTRLS.lock(|t| for (i, c) in t.iter().enumerate() { snap[i] = c.as_ref().map(|c| (c.info, c.stats)); });
+    for (i, s) in snap.iter().enumerate() { if let Some((info, stats)) = s { f(i, info, stats); } }
+}
+
+pub fn for_each_namespace(idx: usize, mut f: impl FnMut(&Namespace)) {
+    let ns = CTRLS.lock(|t| t.get(idx).and_then(|c| c.as_ref()).map(|c| c.ns)).unwrap_or([None; MAX_NS]);
+    for n in ns.iter().flatten() { f(n); }
+}
+
+pub fn namespace(idx: usize, nsid: u32) -> Option<Namespace> {
+    CTRLS.lock(|t| t.get(idx).and_then(|c| c.as_ref()).and_then(|c| c.namespace(nsid).ok()))
+}
+
+/// Read `buf.len()` bytes (whole blocks) from namespace `nsid` at `lba`.
+pub fn read(idx: usize, nsid: u32, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+    CTRLS.lock(|t| {
+        let c = t.get_mut(idx).and_then(|c| c.as_mut()).ok_or("nvme: no such controller")?;
+        let ns = c.namespace(nsid)?;
+        let bs = ns.block_size as usize;
+        if buf.len() % bs != 0 { return Err("nvme: length is not whole blocks"); }
+        if lba.checked_add((buf.len() / bs) as u64).map_or(true, |e| e > ns.blocks) { return Err("nvme: past end of namespace"); }
+        let step = c.info.max_xfer as usize;
+        for (i, part) in buf.chunks_mut(step).enumerate() {
+            c.transfer(OP_READ, nsid, lba + (i * step / bs) as u64, part.len(), ns.block_size)?;
+            unsafe { core::ptr::copy_nonoverlapping(c.bounce as *const u8, part.as_mut_ptr(), part.len()); }
+        }
+        c.stats.reads += 1;
+        c.stats.bytes_read += buf.len() as u64;
+        Ok(())
+    })
+}
+
+/// Write `buf` (whole blocks) to namespace `nsid` at `lba`.
+pub fn write(idx: usize, nsid: u32, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+    CTRLS.lock(|t| {
+        let c = t.get_mut(idx).and_then(|c| c.as_mut()).ok_or("nvme: no such controller")?;
+        let ns = c.namespace(nsid)?;
+        let bs = ns.block_size as usize;
+        if buf.len() % bs != 0 { return Err("nvme: length is not whole blocks"); }
+        if lba.checked_add((buf.len() / bs) as u64).map_or(true, |e| e > ns.blocks) { return Err("nvme: past end of namespace"); }
+        let step = c.info.max_xfer as usize;
+        for (i, part) in buf.chunks(step).enumerate() {
+            unsafe { core::ptr::copy_nonoverlapping(part.as_ptr(), c.bounce as *mut u8, part.len()); }
+            c.transfer(OP_WRITE, nsid, lba + (i * step / bs) as u64, part.len(), ns.block_size)?;
+        }
+        c.stats.writes += 1;
+        c.stats.bytes_written += buf.len() as u64;
+        Ok(())
+    })
+}
+
+/// Commit the controller's volatile write cache for namespace `nsid`.
+pub fn flush(idx: usize, nsid: u32) -> Result<(), &'static str> {
+    CTRLS.lock(|t| {
+        let c = t.get_mut(idx).and_then(|c| c.as_mut()).ok_or("nvme: no such controller")?;
+        c.namespace(nsid)?;
+        c.exec(true, command(OP_FLUSH, nsid, 0, 0, [0; 6]))?;
+        c.stats.flushes += 1;
+        Ok(())
+    })
+}
+
+/// Pass the controller at `bdf` through to `vm_id` whole. It must not be
+/// driven by the host, and must be on the `pci allow` list so its BARs can
+/// be sized.
+pub fn assign(system_table: &mut SystemTable<Boot>, vm_id: u64, bdf: Bdf) -> Result<sriov::Attached, &'static str> {
+    if CTRLS.lock(|t| t.iter().flatten().any(|c| c.info.bdf == bdf)) { return Err("nvme: controller is driven by the host (nvme release first)"); }
+    let cfg = crate::iommu::ecam_cfg_base(system_table, bdf.seg, bdf.bus, bdf.dev, bdf.func).ok_or("nvme: no ECAM window for device")?;
+    if mmio_read16(cfg + CFG_VENDOR) == 0xFFFF || !is_nvme(cfg) { return Err("nvme: not an NVMe controller"); }
+    sriov::attach_function(system_table, vm_id, bdf)
+}
+
+/// Parse a namespace reference `<ctrl>n<nsid>`, as in `0n1`.
+pub fn parse_ns(s: &str) -> Option<(usize, u32)> {
+    let (c, n) = s.split_once('n')?;
+    let nsid = n.parse::<u32>().ok()?;
+    if nsid == 0 { return None; }
+    Some((c.parse().ok()?, nsid))
+}
@@ -0,0 +1,89 @@
+#![allow(dead_code)]
+
+//! Submission/completion queue pairs.
+//!
+//! Each queue is one page of the DMA pool. The driver waits for every
+//! command before submitting the next, so a queue never has more than one
+//! entry in flight: the command identifier is a counter, and the phase tag
+//! of the entry at the head tells whether the controller has posted it.
+
+use core::sync::atomic::{fence, Ordering};
+
+use crate::iommu::{mmio_read32, mmio_write32};
+
+/// Submission entry size (IOSQES = 6)
+pub const SQE: usize = 64;
+/// Completion entry size (IOCQES = 4)
+pub const CQE: usize = 16;
+/// Entries a one-page submission queue holds
+pub const DEPTH: u16 = (4096 / SQE) as u16;
+/// First doorbell register
+const DOORBELLS: usize = 0x1000;
+
+/// Completion status: status code type << 8 | status code.
+pub type Status = u16;
+
+#[derive(Clone, Copy)]
+pub struct Queue {
+    pub qid: u16,
+    /// Entries; 0 = not created
+    pub size: u16,
+    pub sq: u64,
+    pub cq: u64,
+    sq_db: usize,
+    cq_db: usize,
+    tail: u16,
+    head: u16,
+    phase: bool,
+    cid: u16,
+}
+
+impl Queue {
+    pub const NONE: Queue = Queue { qid: 0, size: 0, sq: 0, cq: 0, sq_db: 0, cq_db: 0, tail: 0, head: 0, phase: true, cid: 0 };
+
+    /// Take the pages for queue pair `qid` of the controller at `regs`;
+    /// `stride` is the doorbell stride in bytes.
+    pub fn new(regs: usize, stride: usize, qid: u16, size: u16) -> Result<Queue, &'static str> {
+        let sq = crate::mm::dma::alloc(1).ok_or("nvme: dma pool exhausted")?;
+        let Some(cq) = crate::mm::dma::alloc(1) else {
+            crate::mm::dma::free(sq, 1);
+            return Err("nvme: dma pool exhausted");
+        };
+        let db = regs + DOORBELLS + 2 * qid as usize * stride;
+        Ok(Queue { qid, size, sq, cq, sq_db: db, cq_db: db + stride, ..Queue::NONE })
+    }
+
+    pub fn free(&mut self) {
+        if self.size == 0 { return; }
+        crate::mm::dma::free(self.sq, 1);
+        crate::mm::dma::free(self.cq, 1);
+        *self = Queue::NONE;
+    }
+
+    /// Submit `cmd` and wait up to `timeout_ms` for its completion. Ok holds
+    /// DW0 of the completion; `Err(None)` means none arrived in time.
+    pub fn exec(&mut self, mut cmd: [u32; 16], timeout_ms: u64) -> Result<u32, Option<Status>> {
+        self.cid = self.cid.wrapping_add(1);
+        cmd[0] = (cmd[0] & 0xFFFF) | (self.cid as u32) << 16;
+        let slot = self.sq as usize + self.tail as usize * SQE;
+        for (i, w) in cmd.iter().enumerate() { mmio_write32(slot + i * 4, *w); }
+        self.tail = (self.tail + 1) % self.size;
+        fence(Ordering::SeqCst);
+        mmio_write32(self.sq_db, self.tail as u32);
+
+        let entry = self.cq as usize + self.head as usize * CQE;
+        let phase = self.phase;
+        if !super::wait(timeout_ms, || (mmio_read32(entry + 12) >> 16 & 1 != 0) == phase) { return Err(None); }
+        fence(Ordering::SeqCst);
+        let dw0 = mmio_read32(entry);
+        let dw3 = mmio_read32(entry + 12);
+        self.head = (self.head + 1) % self.size;
+        if self.head == 0 { self.phase = !self.phase; }
+        mmio_write32(self.cq_db, self.head as u32);
+        if dw3 & 0xFFFF != self.cid as u32 { return Err(None); }
+        match (dw3 >> 17) as u16 & 0x7FF {
+            0 => Ok(dw0),
+            s => Err(Some(s)),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+#![allow(dead_code)]
+
+//! Console output routing: UEFI text output, optionally mirrored to a 16550.
+//!
+//! `install` hooks `OutputString` of the system table's `ConOut`, so every
+//! string printed through `SystemTable::stdout()` (banner, CLI, logs, the
+//! panic report and firmware messages alike) is also sent to the serial
+//! port when one is enabled, UTF-16 converted to UTF-8. The CLI reads serial
+//! input with `read_byte` next to the UEFI keyboard. `write_bytes` goes to
+//! the serial port directly, for code that cannot use UEFI text output, such
+//! as the runtime phase after ExitBootServices (`detach_firmware`).
+//!
+//! The serial setting is saved in the `ZerovisorConsole` variable and applied
+//! by `install` at the next boot, so the banner reaches serial too.
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU32, AtomicUsize, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::arch::x86::uart;
+
+pub const DEFAULT_BAUD: u32 = 115_200;
+/// UTF-16 units of one `OutputString` call mirrored to serial
+const MAX_UNITS: usize = 4096;
+const VAR_NS: uefi::table::runtime::VariableVendor = uefi::table::runtime::VariableVendor::GLOBAL_VARIABLE;
+
+type OutputStringFn = unsafe extern "efiapi" fn(*mut RawOutput, *const u16) -> usize;
+
+/// Leading fields of EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL
+#[repr(C)]
+struct RawOutput {
+    reset: usize,
+    output_string: OutputStringFn,
+}
+
+/// Serial port base, 0 while serial is off
+static SERIAL_PORT: AtomicU16 = AtomicU16::new(0);
+static SERIAL_BAUD: AtomicU32 = AtomicU32::new(0);
+/// Firmware `OutputString` the hook forwards to
+static ORIG_OUTPUT: AtomicUsize = AtomicUsize::new(0);
+/// `ConOut`, for `print`
+static CONOUT: AtomicPtr<uefi::proto::console::text::Output> = AtomicPtr::new(core::ptr::null_mut());
+/// Cleared at ExitBootServices, when the firmware console goes away
+static FIRMWARE_OUT: AtomicBool = AtomicBool::new(true);
+
+unsafe extern "efiapi" fn output_string(this: *mut RawOutput, s: *const u16) -> usize {
+    let port = SERIAL_PORT.load(Ordering::Relaxed);
+    if port != 0 && !s.is_null() {
+        let mut len = 0;
+        while len < MAX_UNITS && *s.add(len) != 0 { len += 1; }
+        let units = core::slice::from_raw_parts(s, len);
+        let mut utf8 = [0u8; 4];
+        for c in char::decode_utf16(units.iter().copied()) {
+            uart::write(port, c.unwrap_or('?').encode_utf8(&mut utf8).as_bytes());
+        }
+    }
+    if !FIRMWARE_OUT.load(Ordering::Relaxed) { return 0; }
+    match ORIG_OUTPUT.load(Ordering::Relaxed) {
+        0 => 0,
+        f => core::mem::transmute::<usize, OutputStringFn>(f)(this, s),
+    }
+}
+
+/// Hook `ConOut` and apply the saved serial setting. Calling it again only
+/// reapplies the setting.
+pub fn install(system_table: &mut SystemTable<Boot>) {
+    if ORIG_OUTPUT.load(Ordering::Relaxed) == 0 {
+        let out = system_table.stdout() as *mut uefi::proto::console::text::Output;
+        CONOUT.store(out, Ordering::Relaxed);
+        let raw = out as *mut RawOutput;
+        // SAFETY: `Output` is the firmware's protocol instance, which starts
+        // with the `Reset` and `OutputString` members.
+        unsafe {
+            ORIG_OUTPUT.store((*raw).output_string as usize, Ordering::Relaxed);
+            (*raw).output_string = output_string;
+        }
+    }
+    if let Some((port, baud)) = load(system_table) { let _ = enable(port, baud); }
+}
+
+/// Mirror console output to the UART at `port` and accept input from it.
+pub fn enable(port: u16, baud: u32) -> Result<(), &'static str> {
+    uart::init(port, baud)?;
+    SERIAL_BAUD.store(baud, Ordering::Relaxed);
+    SERIAL_PORT.store(port, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Stop forwarding to firmware; serial, if on, is the only console left.
+pub fn detach_firmware() {
+    FIRMWARE_OUT.store(false, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    SERIAL_PORT.store(0, Ordering::Relaxed);
+}
+
+/// Serial port base and baud rate, if serial is on.
+pub fn serial() -> Option<(u16, u32)> {
+    match SERIAL_PORT.load(Ordering::Relaxed) {
+        0 => None,
+        port => Some((port, SERIAL_BAUD.load(Ordering::Relaxed))),
+    }
+}
+
+/// Print without a `SystemTable`: through `ConOut` (and so the serial mirror)
+/// while firmware is up, else to serial alone.
+pub fn print(s: &str) {
+    let out = CONOUT.load(Ordering::Relaxed);
+    if out.is_null() || !FIRMWARE_OUT.load(Ordering::Relaxed) { write_bytes(s.as_bytes()); return; }
+    // SAFETY: the firmware's ConOut instance, valid until ExitBootServices.
+    let _ = core::fmt::Write::write_str(unsafe { &mut *out }, s);
+}
+
+/// Write straight to the serial port, bypassing UEFI text output.
+pub fn write_bytes(data: &[u8]) {
+    let port = SERIAL_PORT.load(Ordering::Relaxed);
+    if port != 0 { uart::write(port, data); }
+}
+
+/// A byte typed on the serial console, if any.
+pub fn read_byte() -> Option<u8> {
+    match SERIAL_PORT.load(Ordering::Relaxed) {
+        0 => None,
+        port => uart::getc(port),
+    }
+}
+
+/// Echo an input byte back to the serial terminal; UEFI input does not echo
+/// there.
+pub fn echo(b: u8) {
+    match b {
+        0x08 | 0x7F => write_bytes(b"\x08 \x08"),
+        b'\r' | b'\n' => {}
+        _ => write_bytes(&[b]),
+    }
+}
+
+/// Save the current setting for the next boot.
+pub fn save(system_table: &SystemTable<Boot>) -> Result<(), &'static str> {
+    let mut buf = [0u8; 8];
+    if let Some((port, baud)) = serial() {
+        buf[0] = 1;
+        buf[2..4].copy_from_slice(&port.to_le_bytes());
+        buf[4..8].copy_from_slice(&baud.to_le_bytes());
+    }
+    system_table.runtime_services().set_variable(uefi::cstr16!("ZerovisorConsole"), &VAR_NS,
+        uefi::table::runtime::VariableAttributes::BOOTSERVICE_ACCESS | uefi::table::runtime::VariableAttributes::NON_VOLATILE, &buf)
+        .map_err(|_| "console: set_variable failed")
+}
+
+fn load(system_table: &SystemTable<Boot>) -> Option<(u16, u32)> {
+    let mut buf = [0u8; 8];
+    let (data, _) = system_table.runtime_services().get_variable(uefi::cstr16!("ZerovisorConsole"), &VAR_NS, &mut buf).ok()?;
+    if data.len() != 8 || data[0] != 1 { return None; }
+    Some((u16::from_le_bytes([data[2], data[3]]), u32::from_le_bytes([data[4], data[5], data[6], data[7]])))
+}
@@ -1,10 +1,20 @@
 #![allow(dead_code)]
 
+//! Host log: a ring of recent lines plus console output filtered by level.
+//!
+//! New code logs with `log!`, which needs no `SystemTable`; `write` and the
+//! `info`/`warn`/`error` helpers print through a borrowed `SystemTable`.
+//! Both keep every line in the ring, whatever `loglevel` says, so `logs` and
+//! the panic report can show it later.
+
 use core::fmt::Write as _;
 use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
 
+use crate::util::format::BufWriter;
+use crate::util::spinlock::SpinLock;
+
 #[derive(Clone, Copy, Debug)]
 pub enum Level { Info, Warn, Error }
 
@@ -19,6 +29,9 @@ struct LogEntry { level: Level, cat_len: u8, msg_len: u8, cat: [u8; CAT_MAX], ms
 static mut LOG_RING: [LogEntry; LOG_CAP] = [LogEntry { level: Level::Info, cat_len: 0, msg_len: 0, cat: [0; CAT_MAX], msg: [0; MSG_MAX] }; LOG_CAP];
 static LOG_WIDX: AtomicUsize = AtomicUsize::new(0);
 static LOG_MIN_LEVEL: AtomicU8 = AtomicU8::new(0); // 0=Info,1=Warn,2=Error
+/// Comma-separated categories printed on the console; empty prints all
+const CAT_FILTER_MAX: usize = 96;
+static CAT_FILTER: SpinLock<([u8; CAT_FILTER_MAX], usize)> = SpinLock::new(([0; CAT_FILTER_MAX], 0));
 
 fn record_to_ring(level: Level, category: &str, message: &str) {
     let i = LOG_WIDX.fetch_add(1, Ordering::Relaxed) % LOG_CAP;
@@ -36,51 +49,105 @@ fn record_to_ring(level: Level, category: &str, message: &str) {
     }
 }
 
-pub fn write(system_table: &mut SystemTable<Boot>, level: Level, category: &str, message: &str) {
-    // Record first to ring
-    record_to_ring(level, category, message);
-    // Then print to console
-    let _lang = crate::i18n::detect_lang(system_table);
-    // Respect minimal level for console output
-    let min = LOG_MIN_LEVEL.load(Ordering::Relaxed);
-    let lev_u8 = match level { Level::Info => 0, Level::Warn => 1, Level::Error => 2 };
-    if lev_u8 < min { return; }
-    let stdout = system_table.stdout();
-    let mut buf = [0u8; 224]; let mut n = 0;
+impl Level {
+    fn rank(self) -> u8 { match self { Level::Info => 0, Level::Warn => 1, Level::Error => 2 } }
+    fn tag(self) -> &'static [u8] { match self { Level::Info => b"INFO", Level::Warn => b"WARN", Level::Error => b"ERROR" } }
+}
+
+/// `LOG [LEVEL] {category} message\r\n`
+fn format_line(buf: &mut [u8; 224], level: Level, category: &[u8], message: &[u8]) -> usize {
+    let mut n = 0;
     for &b in b"LOG [" { buf[n] = b; n += 1; }
-    match level {
-        Level::Info => { for &b in b"INFO" { buf[n] = b; n += 1; } }
-        Level::Warn => { for &b in b"WARN" { buf[n] = b; n += 1; } }
-        Level::Error => { for &b in b"ERROR" { buf[n] = b; n += 1; } }
-    }
+    for &b in level.tag() { buf[n] = b; n += 1; }
     for &b in b"] {" { buf[n] = b; n += 1; }
-    for &b in category.as_bytes() { if n < buf.len() { buf[n] = b; n += 1; } }
+    for &b in category.iter().take(CAT_MAX) { buf[n] = b; n += 1; }
     for &b in b"} " { buf[n] = b; n += 1; }
-    for &b in message.as_bytes() { if n < buf.len() { buf[n] = b; n += 1; } }
+    for &b in message { if n < buf.len() - 2 { buf[n] = b; n += 1; } }
     buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
-    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    n
+}
+
+/// Limit console output to a comma-separated list of categories; "all" or
+/// an empty list removes the limit. The ring still records every category.
+pub fn set_category_filter(list: &str) -> Result<(), &'static str> {
+    let list = list.trim();
+    let list = if list.eq_ignore_ascii_case("all") { "" } else { list };
+    if list.len() > CAT_FILTER_MAX { return Err("loglevel: category list too long"); }
+    CAT_FILTER.lock(|f| {
+        f.0[..list.len()].copy_from_slice(list.as_bytes());
+        f.1 = list.len();
+    });
+    Ok(())
+}
+
+/// Current category filter, empty when every category is printed.
+pub fn category_filter(mut f: impl FnMut(&str)) {
+    let (buf, len) = CAT_FILTER.lock(|f| *f);
+    f(core::str::from_utf8(&buf[..len]).unwrap_or(""));
+}
+
+/// Whether a line passes `loglevel` and the category filter.
+fn printable(level: Level, category: &str) -> bool {
+    if level.rank() < LOG_MIN_LEVEL.load(Ordering::Relaxed) { return false; }
+    let (buf, len) = CAT_FILTER.lock(|f| *f);
+    if len == 0 { return true; }
+    let filter = core::str::from_utf8(&buf[..len]).unwrap_or("");
+    filter.split(',').any(|c| c.trim() == category)
+}
+
+fn entry_at(idx: usize) -> LogEntry { unsafe { core::ptr::read_volatile(core::ptr::addr_of!(LOG_RING[idx % LOG_CAP])) } }
+
+fn entry_line(buf: &mut [u8; 224], e: &LogEntry) -> usize {
+    let cl = e.cat_len.min(CAT_MAX as u8) as usize;
+    let ml = e.msg_len.min(MSG_MAX as u8) as usize;
+    format_line(buf, e.level, &e.cat[..cl], &e.msg[..ml])
+}
+
+pub fn write(system_table: &mut SystemTable<Boot>, level: Level, category: &str, message: &str) {
+    // Record first to ring
+    record_to_ring(level, category, message);
+    // Respect minimal level and category filter for console output
+    if !printable(level, category) { return; }
+    let mut buf = [0u8; 224];
+    let n = format_line(&mut buf, level, category.as_bytes(), message.as_bytes());
+    let _ = system_table.stdout().write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+}
+
+/// Backend of `log!`: format `args` without allocating, record the line and
+/// print it through `obs::console` if it passes `loglevel`. Needs no
+/// `SystemTable`, so it also works after ExitBootServices and on APs.
+pub fn emit(level: Level, category: &str, args: core::fmt::Arguments) {
+    let mut msg = [0u8; MSG_MAX];
+    let mut w = BufWriter::new(&mut msg);
+    let _ = w.write_fmt(args);
+    let text = w.as_str();
+    record_to_ring(level, category, text);
+    if !printable(level, category) { return; }
+    let mut buf = [0u8; 224];
+    let n = format_line(&mut buf, level, category.as_bytes(), text.as_bytes());
+    crate::obs::console::print(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+}
+
+/// Log a formatted message: `log!(Warn, "iommu", "fault at {:#x}", addr)`.
+///
+/// The level is `Info`, `Warn` or `Error`. The message is formatted into a
+/// fixed buffer (truncated at 160 bytes), kept in the log ring for `logs`,
+/// and printed on the console, including the serial mirror, unless
+/// `loglevel` filters its level or category out.
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $category:expr, $($arg:tt)+) => {
+        $crate::obs::log::emit($crate::obs::log::Level::$level, $category, format_args!($($arg)+))
+    };
 }
 
 pub fn dump(system_table: &mut SystemTable<Boot>) {
     let stdout = system_table.stdout();
     let cur = LOG_WIDX.load(Ordering::Relaxed);
     let start = cur.saturating_sub(LOG_CAP);
+    let mut buf = [0u8; 224];
     for idx in start..cur {
-        let e = unsafe { core::ptr::read_volatile(&LOG_RING[idx % LOG_CAP]) };
-        let mut buf = [0u8; 224]; let mut n = 0;
-        for &b in b"LOG [" { buf[n] = b; n += 1; }
-        match e.level {
-            Level::Info => { for &b in b"INFO" { buf[n] = b; n += 1; } }
-            Level::Warn => { for &b in b"WARN" { buf[n] = b; n += 1; } }
-            Level::Error => { for &b in b"ERROR" { buf[n] = b; n += 1; } }
-        }
-        for &b in b"] {" { buf[n] = b; n += 1; }
-        let cl = e.cat_len.min(CAT_MAX as u8) as usize;
-        let ml = e.msg_len.min(MSG_MAX as u8) as usize;
-        for i in 0..cl { buf[n] = e.cat[i]; n += 1; }
-        for &b in b"} " { buf[n] = b; n += 1; }
-        for i in 0..ml { if n < buf.len() { buf[n] = e.msg[i]; n += 1; } }
-        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let n = entry_line(&mut buf, &entry_at(idx));
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     }
 }
@@ -89,34 +156,16 @@ pub fn dump_filtered(system_table: &mut SystemTable<Boot>, min_level: u8, cat_pr
     let stdout = system_table.stdout();
     let cur = LOG_WIDX.load(Ordering::Relaxed);
     let start = cur.saturating_sub(LOG_CAP);
+    let mut buf = [0u8; 224];
     for idx in start..cur {
-        let e = unsafe { core::ptr::read_volatile(&LOG_RING[idx % LOG_CAP]) };
-        let lev_u8 = match e.level { Level::Info => 0, Level::Warn => 1, Level::Error => 2 };
-        if lev_u8 < min_level { continue; }
+        let e = entry_at(idx);
+        if e.level.rank() < min_level { continue; }
         let cl = e.cat_len.min(CAT_MAX as u8) as usize;
-        let ml = e.msg_len.min(MSG_MAX as u8) as usize;
         // Category prefix match (ASCII)
-        let mut match_prefix = true;
         let p = cat_prefix.as_bytes();
-        for i in 0..p.len() {
-            if i >= cl { match_prefix = false; break; }
-            if e.cat[i].to_ascii_lowercase() != p[i].to_ascii_lowercase() { match_prefix = false; break; }
-        }
-        if !match_prefix && !cat_prefix.is_empty() { continue; }
-        let mut buf = [0u8; 224]; let mut n = 0;
-        for &b in b"LOG [" { buf[n] = b; n += 1; }
-        match e.level {
-            Level::Info => { for &b in b"INFO" { buf[n] = b; n += 1; } }
-            Level::Warn => { for &b in b"WARN" { buf[n] = b; n += 1; } }
-            Level::Error => { for &b in b"ERROR" { buf[n] = b; n += 1; } }
-        }
-        for &b in b"] {" { buf[n] = b; n += 1; }
-        for i in 0..cl { buf[n] = e.cat[i]; n += 1; }
-        for &b in b"} " { buf[n] = b; n += 1; }
-        for i in 0..ml { if n < buf.len() { buf[n] = e.msg[i]; n += 1; } }
-        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
-        let s = match core::str::from_utf8(&buf[..n]) { Ok(v) => v, Err(_) => "\r\n" };
-        let _ = stdout.write_str(s);
+        if p.len() > cl || !e.cat[..p.len()].eq_ignore_ascii_case(p) { continue; }
+        let n = entry_line(&mut buf, &e);
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     }
 }
 
@@ -126,21 +175,7 @@ pub fn dump_with_writer(mut write_bytes: impl FnMut(&[u8])) {
     let start = cur.saturating_sub(LOG_CAP);
     let mut line = [0u8; 224];
     for idx in start..cur {
-        let e = unsafe { core::ptr::read_volatile(&LOG_RING[idx % LOG_CAP]) };
-        let mut n = 0;
-        for &b in b"LOG [" { line[n] = b; n += 1; }
-        match e.level {
-            Level::Info => { for &b in b"INFO" { line[n] = b; n += 1; } }
-            Level::Warn => { for &b in b"WARN" { line[n] = b; n += 1; } }
-            Level::Error => { for &b in b"ERROR" { line[n] = b; n += 1; } }
-        }
-        for &b in b"] {" { line[n] = b; n += 1; }
-        let cl = e.cat_len.min(CAT_MAX as u8) as usize;
-        let ml = e.msg_len.min(MSG_MAX as u8) as usize;
-        for i in 0..cl { line[n] = e.cat[i]; n += 1; }
-        for &b in b"} " { line[n] = b; n += 1; }
-        for i in 0..ml { if n < line.len() { line[n] = e.msg[i]; n += 1; } }
-        line[n] = b'\r'; n += 1; line[n] = b'\n'; n += 1;
+        let n = entry_line(&mut line, &entry_at(idx));
         write_bytes(&line[..n]);
     }
 }
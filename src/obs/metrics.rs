@@ -16,6 +16,85 @@ pub static VM_CREATED: AtomicU64 = AtomicU64::new(0);
 pub static VM_STARTED: AtomicU64 = AtomicU64::new(0);
 pub static VCPU_STARTED: AtomicU64 = AtomicU64::new(0);
 pub static VCPU_STOPPED: AtomicU64 = AtomicU64::new(0);
+pub static VM_IMAGE_LOADS: AtomicU64 = AtomicU64::new(0);
+pub static VM_ADMIT_OK: AtomicU64 = AtomicU64::new(0);
+pub static VM_ADMIT_REJECTS: AtomicU64 = AtomicU64::new(0);
+
+// Virtual interrupt delivery
+pub static VIRQ_INJECTED: AtomicU64 = AtomicU64::new(0);
+pub static VIRQ_ACCEL: AtomicU64 = AtomicU64::new(0);
+
+// Device access emulation
+pub static EMUL_MMIO: AtomicU64 = AtomicU64::new(0);
+pub static EMUL_PIO: AtomicU64 = AtomicU64::new(0);
+pub static EMUL_FAIL: AtomicU64 = AtomicU64::new(0);
+pub static VNET_TX_FRAMES: AtomicU64 = AtomicU64::new(0);
+pub static VNET_RX_FRAMES: AtomicU64 = AtomicU64::new(0);
+pub static VNET_DROPS: AtomicU64 = AtomicU64::new(0);
+pub static VSWITCH_FLOODS: AtomicU64 = AtomicU64::new(0);
+pub static VSWITCH_DROPS: AtomicU64 = AtomicU64::new(0);
+pub static VNET_QOS_THROTTLED: AtomicU64 = AtomicU64::new(0);
+pub static STORAGE_CHUNK_ALLOCS: AtomicU64 = AtomicU64::new(0);
+pub static STORAGE_COW_COPIES: AtomicU64 = AtomicU64::new(0);
+pub static STORAGE_SNAPSHOTS: AtomicU64 = AtomicU64::new(0);
+pub static NVME_COMMANDS: AtomicU64 = AtomicU64::new(0);
+pub static NVME_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static CSI_CREATES: AtomicU64 = AtomicU64::new(0);
+pub static CSI_PUBLISHES: AtomicU64 = AtomicU64::new(0);
+pub static CNI_ADDS: AtomicU64 = AtomicU64::new(0);
+pub static CRI_SANDBOXES: AtomicU64 = AtomicU64::new(0);
+pub static CRI_CONTAINER_STARTS: AtomicU64 = AtomicU64::new(0);
+pub static MICROVM_CLONES: AtomicU64 = AtomicU64::new(0);
+pub static MICROVM_COW_BREAKS: AtomicU64 = AtomicU64::new(0);
+pub static MICROVM_STARTS: AtomicU64 = AtomicU64::new(0);
+pub static MICROVM_POOL_HITS: AtomicU64 = AtomicU64::new(0);
+pub static WASM_LOADS: AtomicU64 = AtomicU64::new(0);
+pub static WASM_STARTS: AtomicU64 = AtomicU64::new(0);
+pub static WASM_INSTRUCTIONS: AtomicU64 = AtomicU64::new(0);
+pub static WASM_TRAPS: AtomicU64 = AtomicU64::new(0);
+pub static PLUGIN_LOADS: AtomicU64 = AtomicU64::new(0);
+pub static PLUGIN_REJECTS: AtomicU64 = AtomicU64::new(0);
+pub static PLUGIN_CALLS: AtomicU64 = AtomicU64::new(0);
+pub static VCON_TX_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static VCON_RX_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static VSOCK_TX_PKTS: AtomicU64 = AtomicU64::new(0);
+pub static VSOCK_RX_PKTS: AtomicU64 = AtomicU64::new(0);
+pub static VSOCK_DROPS: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_HB_TX: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_HB_RX: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_QUORUM_LOST: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_GOSSIP_TX: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_GOSSIP_RX: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_MEMBER_FAILED: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_HA_RESTARTED: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_HA_FAILED: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_LOG_COMMITS: AtomicU64 = AtomicU64::new(0);
+pub static CLUSTER_LOG_VIEW_CHANGES: AtomicU64 = AtomicU64::new(0);
+/// Configured host power cap in mW (gauge, 0 = uncapped)
+pub static POWER_CAP_MW: AtomicU64 = AtomicU64::new(0);
+/// Last sampled package draw in mW (gauge)
+pub static POWER_DRAW_MW: AtomicU64 = AtomicU64::new(0);
+pub static POWER_THROTTLE_EVENTS: AtomicU64 = AtomicU64::new(0);
+pub static POWER_THROTTLE_US: AtomicU64 = AtomicU64::new(0);
+/// Current grid carbon intensity in gCO2e/kWh (gauge, 0 = no signal)
+pub static CARBON_INTENSITY_G: AtomicU64 = AtomicU64::new(0);
+/// Mean AP utilization over the last DVFS tick, percent (gauge)
+pub static DVFS_UTIL_PCT: AtomicU64 = AtomicU64::new(0);
+pub static DVFS_TRANSITIONS: AtomicU64 = AtomicU64::new(0);
+/// Hottest package temperature at the last thermal tick, C (gauge)
+pub static THERMAL_MAX_C: AtomicU64 = AtomicU64::new(0);
+pub static THERMAL_THROTTLE_EVENTS: AtomicU64 = AtomicU64::new(0);
+/// GPU slices held by VMs at the last GPU tick (gauge)
+pub static GPU_SLICES_ATTACHED: AtomicU64 = AtomicU64::new(0);
+pub static GPU_SLICE_ATTACHES: AtomicU64 = AtomicU64::new(0);
+pub static CARBON_DEFERRED: AtomicU64 = AtomicU64::new(0);
+pub static CARBON_RELEASED: AtomicU64 = AtomicU64::new(0);
+pub static CARBON_FORCED: AtomicU64 = AtomicU64::new(0);
+pub static CARBON_AVOIDED_MG: AtomicU64 = AtomicU64::new(0);
+pub static VBLK_REQS: AtomicU64 = AtomicU64::new(0);
+pub static VBLK_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static MIG_SELFTEST_RUNS: AtomicU64 = AtomicU64::new(0);
+pub static MIG_SELFTEST_FAILS: AtomicU64 = AtomicU64::new(0);
 
 // IOMMU domain and mapping counters
 pub static IOMMU_DOMAIN_CREATED: AtomicU64 = AtomicU64::new(0);
@@ -29,6 +108,10 @@ pub static IOMMU_INV_ALL: AtomicU64 = AtomicU64::new(0);
 pub static IOMMU_INV_DOMAIN: AtomicU64 = AtomicU64::new(0);
 pub static IOMMU_INV_BDF: AtomicU64 = AtomicU64::new(0);
 
+// IOMMU fault handling
+pub static IOMMU_FAULTS: AtomicU64 = AtomicU64::new(0);
+pub static IOMMU_QUARANTINED: AtomicU64 = AtomicU64::new(0);
+
 // Migration counters
 pub static MIG_SESSIONS: AtomicU64 = AtomicU64::new(0);
 pub static MIG_SCAN_ROUNDS: AtomicU64 = AtomicU64::new(0);
@@ -48,7 +131,13 @@ pub static MIG_CTRL_FRAMES: AtomicU64 = AtomicU64::new(0);
 pub static MIG_ACKS: AtomicU64 = AtomicU64::new(0);
 pub static MIG_NAKS: AtomicU64 = AtomicU64::new(0);
 pub static MIG_RESEND_TRIGGERS: AtomicU64 = AtomicU64::new(0);
+pub static MIG_RDMA_PAGES: AtomicU64 = AtomicU64::new(0);
+pub static MIG_RDMA_RETRANSMITS: AtomicU64 = AtomicU64::new(0);
+pub static MIG_TIMEOUT_RETRANSMITS: AtomicU64 = AtomicU64::new(0);
 pub static MIG_CB_WRITTEN_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static MIG_CB_LOST_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static MIG_CB_HIGH_MARKS: AtomicU64 = AtomicU64::new(0);
+pub static MIG_CB_LOW_MARKS: AtomicU64 = AtomicU64::new(0);
 pub static MIG_CFG_SAVES: AtomicU64 = AtomicU64::new(0);
 pub static MIG_CFG_LOADS: AtomicU64 = AtomicU64::new(0);
 pub static MIG_NET_TX_BYTES: AtomicU64 = AtomicU64::new(0);
@@ -65,6 +154,7 @@ pub static MIG_PUMP_CALLS: AtomicU64 = AtomicU64::new(0);
 pub static MIG_PUMP_FRAMES: AtomicU64 = AtomicU64::new(0);
 pub static MIG_PUMP_BYTES: AtomicU64 = AtomicU64::new(0);
 pub static MIG_PUMP_EMPTY: AtomicU64 = AtomicU64::new(0);
+pub static MIG_PUMP_BACKPRESSURE: AtomicU64 = AtomicU64::new(0);
 pub static MIG_POLL_CYCLES: AtomicU64 = AtomicU64::new(0);
 pub static MIG_CTRL_AUTO_ACK_SENT: AtomicU64 = AtomicU64::new(0);
 pub static MIG_CTRL_AUTO_NAK_SENT: AtomicU64 = AtomicU64::new(0);
@@ -77,6 +167,391 @@ pub static MIG_REPLAY_ERRORS: AtomicU64 = AtomicU64::new(0);
 pub static MIG_DUP_FRAMES: AtomicU64 = AtomicU64::new(0);
 pub static MIG_MISSING_FRAMES: AtomicU64 = AtomicU64::new(0);
 pub static MIG_LAST_SEQ: AtomicU64 = AtomicU64::new(0);
+pub static MIG_HELLOS: AtomicU64 = AtomicU64::new(0);
+pub static MIG_RX_V2_FRAMES: AtomicU64 = AtomicU64::new(0);
+pub static MIG_TLV_REJECTS: AtomicU64 = AtomicU64::new(0);
+
+// TPM counters
+pub static TPM_INIT_OK: AtomicU64 = AtomicU64::new(0);
+pub static TPM_CMDS: AtomicU64 = AtomicU64::new(0);
+pub static TPM_XPORT_ERRS: AtomicU64 = AtomicU64::new(0);
+pub static TPM_RC_ERRS: AtomicU64 = AtomicU64::new(0);
+pub static TPM_PCR_EXTENDS: AtomicU64 = AtomicU64::new(0);
+pub static TPM_NV_READS: AtomicU64 = AtomicU64::new(0);
+pub static TPM_NV_WRITES: AtomicU64 = AtomicU64::new(0);
+pub static TPM_SEALS: AtomicU64 = AtomicU64::new(0);
+pub static TPM_UNSEALS: AtomicU64 = AtomicU64::new(0);
+pub static TPM_QUOTES: AtomicU64 = AtomicU64::new(0);
+pub static TPM_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+// Background housekeeping tasks
+pub static BG_RUNS: AtomicU64 = AtomicU64::new(0);
+pub static BG_THROTTLED: AtomicU64 = AtomicU64::new(0);
+pub static BG_RT_SKIPS: AtomicU64 = AtomicU64::new(0);
+
+// Credit scheduler
+pub static SCHED_SLICES: AtomicU64 = AtomicU64::new(0);
+pub static SCHED_SWITCHES: AtomicU64 = AtomicU64::new(0);
+pub static SCHED_PREEMPTS: AtomicU64 = AtomicU64::new(0);
+pub static SCHED_PERIODS: AtomicU64 = AtomicU64::new(0);
+pub static SCHED_CAP_PARKS: AtomicU64 = AtomicU64::new(0);
+pub static SCHED_RT_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+pub static SCHED_RT_MISSES: AtomicU64 = AtomicU64::new(0);
+pub static SCHED_RT_REJECTS: AtomicU64 = AtomicU64::new(0);
+
+// Guest RAM frame allocator
+pub static GMEM_EXTENTS: AtomicU64 = AtomicU64::new(0);
+pub static GMEM_FW_CLAIMS: AtomicU64 = AtomicU64::new(0);
+pub static GMEM_ALLOC_FAILS: AtomicU64 = AtomicU64::new(0);
+pub static GMEM_OVERCOMMIT_REJECTS: AtomicU64 = AtomicU64::new(0);
+
+// virtio-balloon, in 4KiB pages
+pub static BALLOON_INFLATED: AtomicU64 = AtomicU64::new(0);
+pub static BALLOON_DEFLATED: AtomicU64 = AtomicU64::new(0);
+
+// Accelerator job queues
+pub static ACCEL_JOBS: AtomicU64 = AtomicU64::new(0);
+pub static ACCEL_JOBS_REJECTED: AtomicU64 = AtomicU64::new(0);
+
+// Same-page merging
+pub static KSM_SCANNED: AtomicU64 = AtomicU64::new(0);
+pub static KSM_MERGES: AtomicU64 = AtomicU64::new(0);
+pub static KSM_COW_BREAKS: AtomicU64 = AtomicU64::new(0);
+
+// Inter-VM shared memory
+pub static ZCOPY_DOORBELLS: AtomicU64 = AtomicU64::new(0);
+pub static HTTP_REQUESTS: AtomicU64 = AtomicU64::new(0);
+pub static HTTP_RETRANSMITS: AtomicU64 = AtomicU64::new(0);
+
+/// Per-task CPU accounting for background housekeeping, keyed by slot.
+#[derive(Clone, Copy)]
+pub struct TaskAcct { pub name: &'static str, pub runs: u64, pub run_ns: u64 }
+
+pub const MAX_TASK_ACCT: usize = 16;
+static TASK_ACCT: crate::util::spinlock::SpinLock<[TaskAcct; MAX_TASK_ACCT]> =
+    crate::util::spinlock::SpinLock::new([TaskAcct { name: "", runs: 0, run_ns: 0 }; MAX_TASK_ACCT]);
+
+/// Charge one run of `ns` nanoseconds to task `slot`.
+pub fn task_account(slot: usize, name: &'static str, ns: u64) {
+    if slot >= MAX_TASK_ACCT { return; }
+    TASK_ACCT.lock(|t| { t[slot].name = name; t[slot].runs += 1; t[slot].run_ns = t[slot].run_ns.wrapping_add(ns); });
+}
+
+/// Snapshot of per-task accounting.
+pub fn task_accounts() -> [TaskAcct; MAX_TASK_ACCT] { TASK_ACCT.lock(|t| *t) }
+
+/// Per-VM time spent under a reduced power quota, keyed by VM id.
+#[derive(Clone, Copy)]
+pub struct VmThrottle { pub vm_id: u64, pub us: u64 }
+
+pub const MAX_VM_THROTTLE: usize = 16;
+static VM_THROTTLE: crate::util::spinlock::SpinLock<[Option<VmThrottle>; MAX_VM_THROTTLE]> =
+    crate::util::spinlock::SpinLock::new([None; MAX_VM_THROTTLE]);
+
+/// Charge `us` microseconds of throttled time to `vm_id`.
+pub fn vm_throttle_account(vm_id: u64, us: u64) {
+    POWER_THROTTLE_US.fetch_add(us, Ordering::Relaxed);
+    VM_THROTTLE.lock(|t| {
+        if let Some(e) = t.iter_mut().flatten().find(|e| e.vm_id == vm_id) { e.us = e.us.saturating_add(us); return; }
+        if let Some(slot) = t.iter_mut().find(|e| e.is_none()) { *slot = Some(VmThrottle { vm_id, us }); }
+    });
+}
+
+/// Drop the throttle entry of a destroyed VM.
+pub fn vm_throttle_forget(vm_id: u64) {
+    VM_THROTTLE.lock(|t| { for e in t.iter_mut() { if matches!(e, Some(x) if x.vm_id == vm_id) { *e = None; } } });
+}
+
+pub fn vm_throttles() -> [Option<VmThrottle>; MAX_VM_THROTTLE] { VM_THROTTLE.lock(|t| *t) }
+
+/// Per-VM guest CPU time and cap parks from the credit scheduler, keyed by VM id.
+#[derive(Clone, Copy)]
+pub struct VmSched { pub vm_id: u64, pub run_us: u64, pub parks: u64 }
+
+pub const MAX_VM_SCHED: usize = 16;
+static VM_SCHED: crate::util::spinlock::SpinLock<[Option<VmSched>; MAX_VM_SCHED]> =
+    crate::util::spinlock::SpinLock::new([None; MAX_VM_SCHED]);
+
+/// Charge `us` microseconds of guest time to `vm_id`; `parked` counts a cap park.
+pub fn vm_sched_account(vm_id: u64, us: u64, parked: bool) {
+    let parks = parked as u64;
+    VM_SCHED.lock(|t| {
+        if let Some(e) = t.iter_mut().flatten().find(|e| e.vm_id == vm_id) { e.run_us = e.run_us.saturating_add(us); e.parks += parks; return; }
+        if let Some(slot) = t.iter_mut().find(|e| e.is_none()) { *slot = Some(VmSched { vm_id, run_us: us, parks }); }
+    });
+}
+
+/// Drop the scheduling entry of a destroyed VM.
+pub fn vm_sched_forget(vm_id: u64) {
+    VM_SCHED.lock(|t| { for e in t.iter_mut() { if matches!(e, Some(x) if x.vm_id == vm_id) { *e = None; } } });
+}
+
+pub fn vm_sched() -> [Option<VmSched>; MAX_VM_SCHED] { VM_SCHED.lock(|t| *t) }
+
+/// Every scalar counter, in report order. Names are used verbatim by `dump` and exporters.
+pub static COUNTERS: [(&str, &AtomicU64); 170] = [
+    ("vm_created", &VM_CREATED),
+    ("vm_started", &VM_STARTED),
+    ("vcpu_started", &VCPU_STARTED),
+    ("vcpu_stopped", &VCPU_STOPPED),
+    ("vm_image_loads", &VM_IMAGE_LOADS),
+    ("vm_admit_ok", &VM_ADMIT_OK),
+    ("vm_admit_rejects", &VM_ADMIT_REJECTS),
+    ("virq_injected", &VIRQ_INJECTED),
+    ("virq_accel", &VIRQ_ACCEL),
+    ("emul_mmio", &EMUL_MMIO),
+    ("emul_pio", &EMUL_PIO),
+    ("emul_fail", &EMUL_FAIL),
+    ("vnet_tx_frames", &VNET_TX_FRAMES),
+    ("vnet_rx_frames", &VNET_RX_FRAMES),
+    ("vnet_drops", &VNET_DROPS),
+    ("vswitch_floods", &VSWITCH_FLOODS),
+    ("vswitch_drops", &VSWITCH_DROPS),
+    ("vnet_qos_throttled", &VNET_QOS_THROTTLED),
+    ("storage_chunk_allocs", &STORAGE_CHUNK_ALLOCS),
+    ("storage_cow_copies", &STORAGE_COW_COPIES),
+    ("storage_snapshots", &STORAGE_SNAPSHOTS),
+    ("nvme_commands", &NVME_COMMANDS),
+    ("nvme_errors", &NVME_ERRORS),
+    ("csi_creates", &CSI_CREATES),
+    ("csi_publishes", &CSI_PUBLISHES),
+    ("cni_adds", &CNI_ADDS),
+    ("cri_sandboxes", &CRI_SANDBOXES),
+    ("cri_container_starts", &CRI_CONTAINER_STARTS),
+    ("microvm_clones", &MICROVM_CLONES),
+    ("microvm_cow_breaks", &MICROVM_COW_BREAKS),
+    ("microvm_starts", &MICROVM_STARTS),
+    ("microvm_pool_hits", &MICROVM_POOL_HITS),
+    ("wasm_loads", &WASM_LOADS),
+    ("wasm_starts", &WASM_STARTS),
+    ("wasm_instructions", &WASM_INSTRUCTIONS),
+    ("wasm_traps", &WASM_TRAPS),
+    ("plugin_loads", &PLUGIN_LOADS),
+    ("plugin_rejects", &PLUGIN_REJECTS),
+    ("plugin_calls", &PLUGIN_CALLS),
+    ("vcon_tx_bytes", &VCON_TX_BYTES),
+    ("vcon_rx_bytes", &VCON_RX_BYTES),
+    ("vsock_tx_pkts", &VSOCK_TX_PKTS),
+    ("vsock_rx_pkts", &VSOCK_RX_PKTS),
+    ("vsock_drops", &VSOCK_DROPS),
+    ("cluster_hb_tx", &CLUSTER_HB_TX),
+    ("cluster_hb_rx", &CLUSTER_HB_RX),
+    ("cluster_quorum_lost", &CLUSTER_QUORUM_LOST),
+    ("cluster_gossip_tx", &CLUSTER_GOSSIP_TX),
+    ("cluster_gossip_rx", &CLUSTER_GOSSIP_RX),
+    ("cluster_member_failed", &CLUSTER_MEMBER_FAILED),
+    ("cluster_ha_restarted", &CLUSTER_HA_RESTARTED),
+    ("cluster_ha_failed", &CLUSTER_HA_FAILED),
+    ("cluster_log_commits", &CLUSTER_LOG_COMMITS),
+    ("cluster_log_view_changes", &CLUSTER_LOG_VIEW_CHANGES),
+    ("power_throttle_events", &POWER_THROTTLE_EVENTS),
+    ("power_throttle_us", &POWER_THROTTLE_US),
+    ("carbon_deferred", &CARBON_DEFERRED),
+    ("carbon_released", &CARBON_RELEASED),
+    ("carbon_forced", &CARBON_FORCED),
+    ("carbon_avoided_mg", &CARBON_AVOIDED_MG),
+    ("dvfs_transitions", &DVFS_TRANSITIONS),
+    ("thermal_throttle_events", &THERMAL_THROTTLE_EVENTS),
+    ("gpu_slice_attaches", &GPU_SLICE_ATTACHES),
+    ("vblk_reqs", &VBLK_REQS),
+    ("vblk_errors", &VBLK_ERRORS),
+    ("mig_selftest_runs", &MIG_SELFTEST_RUNS),
+    ("mig_selftest_fails", &MIG_SELFTEST_FAILS),
+    ("iommu_domain_created", &IOMMU_DOMAIN_CREATED),
+    ("iommu_assign_added", &IOMMU_ASSIGN_ADDED),
+    ("iommu_assign_removed", &IOMMU_ASSIGN_REMOVED),
+    ("iommu_map_added", &IOMMU_MAP_ADDED),
+    ("iommu_map_removed", &IOMMU_MAP_REMOVED),
+    ("iommu_inval_all", &IOMMU_INV_ALL),
+    ("iommu_inval_domain", &IOMMU_INV_DOMAIN),
+    ("iommu_inval_bdf", &IOMMU_INV_BDF),
+    ("iommu_faults", &IOMMU_FAULTS),
+    ("iommu_quarantined", &IOMMU_QUARANTINED),
+    ("mig_sessions", &MIG_SESSIONS),
+    ("mig_scan_rounds", &MIG_SCAN_ROUNDS),
+    ("mig_dirty_pages", &MIG_DIRTY_PAGES),
+    ("mig_precopy_rounds", &MIG_PRECOPY_ROUNDS),
+    ("mig_precopy_pages", &MIG_PRECOPY_PAGES),
+    ("mig_bytes_tx", &MIG_BYTES_TX),
+    ("mig_zero_skipped", &MIG_ZERO_SKIPPED),
+    ("mig_hash_skipped", &MIG_HASH_SKIPPED),
+    ("mig_zero_bytes_saved", &MIG_ZERO_BYTES_SAVED),
+    ("mig_hash_bytes_saved", &MIG_HASH_BYTES_SAVED),
+    ("mig_frames", &MIG_FRAMES),
+    ("mig_raw_pages", &MIG_RAW_PAGES),
+    ("mig_compressed_pages", &MIG_COMPRESSED_PAGES),
+    ("mig_manifests", &MIG_MANIFESTS),
+    ("mig_ctrl_frames", &MIG_CTRL_FRAMES),
+    ("mig_acks", &MIG_ACKS),
+    ("mig_naks", &MIG_NAKS),
+    ("mig_resend_triggers", &MIG_RESEND_TRIGGERS),
+    ("mig_rdma_pages", &MIG_RDMA_PAGES),
+    ("mig_rdma_retransmits", &MIG_RDMA_RETRANSMITS),
+    ("mig_timeout_retransmits", &MIG_TIMEOUT_RETRANSMITS),
+    ("mig_cb_written_bytes", &MIG_CB_WRITTEN_BYTES),
+    ("mig_cb_lost_bytes", &MIG_CB_LOST_BYTES),
+    ("mig_cb_high_marks", &MIG_CB_HIGH_MARKS),
+    ("mig_cb_low_marks", &MIG_CB_LOW_MARKS),
+    ("mig_cfg_saves", &MIG_CFG_SAVES),
+    ("mig_cfg_loads", &MIG_CFG_LOADS),
+    ("mig_net_tx_bytes", &MIG_NET_TX_BYTES),
+    ("mig_net_cfg_set", &MIG_NET_CFG_SET),
+    ("mig_net_tx_frames", &MIG_NET_TX_FRAMES),
+    ("mig_net_open_ok", &MIG_NET_OPEN_OK),
+    ("mig_net_open_fail", &MIG_NET_OPEN_FAIL),
+    ("mig_net_start_ok", &MIG_NET_START_OK),
+    ("mig_net_start_fail", &MIG_NET_START_FAIL),
+    ("mig_net_init_ok", &MIG_NET_INIT_OK),
+    ("mig_net_init_fail", &MIG_NET_INIT_FAIL),
+    ("mig_net_tx_errs", &MIG_NET_TX_ERRS),
+    ("mig_pump_calls", &MIG_PUMP_CALLS),
+    ("mig_pump_frames", &MIG_PUMP_FRAMES),
+    ("mig_pump_bytes", &MIG_PUMP_BYTES),
+    ("mig_pump_empty", &MIG_PUMP_EMPTY),
+    ("mig_pump_backpressure", &MIG_PUMP_BACKPRESSURE),
+    ("mig_poll_cycles", &MIG_POLL_CYCLES),
+    ("mig_ctrl_auto_ack", &MIG_CTRL_AUTO_ACK_SENT),
+    ("mig_ctrl_auto_nak", &MIG_CTRL_AUTO_NAK_SENT),
+    ("mig_rx_frames_ok", &MIG_RX_FRAMES_OK),
+    ("mig_rx_frames_bad", &MIG_RX_FRAMES_BAD),
+    ("mig_rx_bytes", &MIG_RX_BYTES),
+    ("mig_replay_pages", &MIG_REPLAY_PAGES),
+    ("mig_replay_bytes", &MIG_REPLAY_BYTES),
+    ("mig_replay_errors", &MIG_REPLAY_ERRORS),
+    ("mig_dup_frames", &MIG_DUP_FRAMES),
+    ("mig_missing_frames", &MIG_MISSING_FRAMES),
+    ("mig_last_seq", &MIG_LAST_SEQ),
+    ("mig_hellos", &MIG_HELLOS),
+    ("mig_rx_v2_frames", &MIG_RX_V2_FRAMES),
+    ("mig_tlv_rejects", &MIG_TLV_REJECTS),
+    ("tpm_init_ok", &TPM_INIT_OK),
+    ("tpm_cmds", &TPM_CMDS),
+    ("tpm_xport_errs", &TPM_XPORT_ERRS),
+    ("tpm_rc_errs", &TPM_RC_ERRS),
+    ("tpm_pcr_extends", &TPM_PCR_EXTENDS),
+    ("tpm_nv_reads", &TPM_NV_READS),
+    ("tpm_nv_writes", &TPM_NV_WRITES),
+    ("tpm_seals", &TPM_SEALS),
+    ("tpm_unseals", &TPM_UNSEALS),
+    ("tpm_quotes", &TPM_QUOTES),
+    ("tpm_events", &TPM_EVENTS),
+    ("bg_runs", &BG_RUNS),
+    ("bg_throttled", &BG_THROTTLED),
+    ("bg_rt_skips", &BG_RT_SKIPS),
+    ("sched_slices", &SCHED_SLICES),
+    ("sched_switches", &SCHED_SWITCHES),
+    ("sched_preempts", &SCHED_PREEMPTS),
+    ("sched_periods", &SCHED_PERIODS),
+    ("sched_cap_parks", &SCHED_CAP_PARKS),
+    ("sched_rt_exhausted", &SCHED_RT_EXHAUSTED),
+    ("sched_rt_misses", &SCHED_RT_MISSES),
+    ("sched_rt_rejects", &SCHED_RT_REJECTS),
+    ("gmem_extents", &GMEM_EXTENTS),
+    ("gmem_fw_claims", &GMEM_FW_CLAIMS),
+    ("gmem_alloc_fails", &GMEM_ALLOC_FAILS),
+    ("gmem_overcommit_rejects", &GMEM_OVERCOMMIT_REJECTS),
+    ("balloon_inflated_pages", &BALLOON_INFLATED),
+    ("balloon_deflated_pages", &BALLOON_DEFLATED),
+    ("accel_jobs", &ACCEL_JOBS),
+    ("accel_jobs_rejected", &ACCEL_JOBS_REJECTED),
+    ("ksm_pages_scanned", &KSM_SCANNED),
+    ("ksm_merges", &KSM_MERGES),
+    ("ksm_cow_breaks", &KSM_COW_BREAKS),
+    ("zcopy_doorbells", &ZCOPY_DOORBELLS),
+    ("http_requests", &HTTP_REQUESTS),
+    ("http_retransmits", &HTTP_RETRANSMITS),
+];
+
+/// Latency histogram with power-of-two buckets: bucket `i` counts values
+/// whose bit length is `i` (0, 1, 2..3, 4..7, ..), the last one everything
+/// longer. Lock-free and cheap enough for the VM-exit path.
+pub struct Histogram { buckets: [AtomicU64; HIST_BUCKETS], sum: AtomicU64 }
+
+pub const HIST_BUCKETS: usize = 32;
+
+impl Default for Histogram { fn default() -> Self { Self::new() } }
+
+impl Histogram {
+    pub const fn new() -> Self {
+        const Z: AtomicU64 = AtomicU64::new(0);
+        Histogram { buckets: [Z; HIST_BUCKETS], sum: Z }
+    }
+
+    pub fn observe(&self, v: u64) {
+        let i = ((64 - v.leading_zeros()) as usize).min(HIST_BUCKETS - 1);
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(v, Ordering::Relaxed);
+    }
+
+    /// Record the nanoseconds since `t0`, a `clock::now_ns` reading
+    /// (dropped while the clock cannot run).
+    pub fn observe_since(&self, t0: u64) {
+        if crate::time::clock::hz() != 0 { self.observe(crate::time::clock::elapsed_ns(t0)); }
+    }
+
+    pub fn bucket(&self, i: usize) -> u64 { self.buckets[i].load(Ordering::Relaxed) }
+    pub fn sum(&self) -> u64 { self.sum.load(Ordering::Relaxed) }
+    pub fn count(&self) -> u64 { self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum() }
+
+    /// Largest value bucket `i` holds; `u64::MAX` for the open last bucket.
+    pub fn upper(i: usize) -> u64 { if i + 1 >= HIST_BUCKETS { u64::MAX } else { (1u64 << i) - 1 } }
+
+    /// Upper bound of the bucket holding the `pct`-th percentile, 0 if empty.
+    pub fn percentile(&self, pct: u32) -> u64 {
+        let total = self.count();
+        if total == 0 { return 0; }
+        let rank = ((total * pct.min(100) as u64 + 99) / 100).max(1);
+        let mut acc = 0;
+        for i in 0..HIST_BUCKETS {
+            acc += self.bucket(i);
+            if acc >= rank { return Self::upper(i); }
+        }
+        u64::MAX
+    }
+
+    pub fn reset(&self) {
+        for b in self.buckets.iter() { b.store(0, Ordering::Relaxed); }
+        self.sum.store(0, Ordering::Relaxed);
+    }
+}
+
+/// VM exit handling, from the exit to the next entry attempt
+pub static EXIT_HANDLING_NS: Histogram = Histogram::new();
+/// One migration frame (header and payload) handed to the sink
+pub static MIG_FRAME_SEND_NS: Histogram = Histogram::new();
+/// IOMMU invalidation, domain or device scope or global
+pub static IOMMU_INVAL_NS: Histogram = Histogram::new();
+
+pub static HISTOGRAMS: [(&str, &Histogram); 3] = [
+    ("vm_exit_handling_ns", &EXIT_HANDLING_NS),
+    ("mig_frame_send_ns", &MIG_FRAME_SEND_NS),
+    ("iommu_inval_ns", &IOMMU_INVAL_NS),
+];
+
+/// `metrics hist`: count, mean and bucket-bound percentiles per histogram.
+pub fn dump_hist(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>) {
+    let stdout = system_table.stdout();
+    let mut buf = [0u8; 192];
+    for (name, h) in HISTOGRAMS.iter() {
+        let mut n = 0;
+        for &b in b"metrics: " { buf[n] = b; n += 1; }
+        for &b in name.as_bytes() { buf[n] = b; n += 1; }
+        let count = h.count();
+        for &b in b" count=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(count, &mut buf[n..]);
+        if count != 0 {
+            for &b in b" mean=" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_dec(h.sum() / count, &mut buf[n..]);
+            for (label, pct) in [(&b" p50<="[..], 50), (&b" p90<="[..], 90), (&b" p99<="[..], 99), (&b" max<="[..], 100)] {
+                for &b in label { buf[n] = b; n += 1; }
+                let v = h.percentile(pct);
+                if v == u64::MAX { for &b in b"inf" { buf[n] = b; n += 1; } } else { n += crate::util::format::u64_dec(v, &mut buf[n..]); }
+            }
+        }
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
+}
 
 // Simple fixed-bucket histogram for microsecond durations
 const VMX_SMOKE_BUCKET_EDGES_US: [u64; 8] = [1, 5, 10, 25, 50, 100, 250, 1000];
@@ -85,6 +560,9 @@ pub static VMX_SMOKE_HIST_US: [AtomicU64; 9] = [
     AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)
 ];
 
+/// Upper bucket edges of the VMX smoke-test histogram (the last bucket is open-ended).
+pub fn vmx_smoke_bucket_edges_us() -> &'static [u64] { &VMX_SMOKE_BUCKET_EDGES_US }
+
 pub fn observe_vmx_smoke_us(us: u64) {
     // Find bucket index
     let mut idx = VMX_SMOKE_BUCKET_EDGES_US.len();
@@ -100,69 +578,17 @@ pub fn dump(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>) {
     let mut print = |label: &str, val: u64| {
         let mut n = 0;
         for &b in label.as_bytes() { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec(val as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(val as u32, &mut buf[n..]);
         buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     };
-    print("metrics: vm_created=", VM_CREATED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: vm_started=", VM_STARTED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: vcpu_started=", VCPU_STARTED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: vcpu_stopped=", VCPU_STOPPED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: iommu_domain_created=", IOMMU_DOMAIN_CREATED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: iommu_assign_added=", IOMMU_ASSIGN_ADDED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: iommu_assign_removed=", IOMMU_ASSIGN_REMOVED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: iommu_map_added=", IOMMU_MAP_ADDED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: iommu_map_removed=", IOMMU_MAP_REMOVED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: iommu_inval_all=", IOMMU_INV_ALL.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: iommu_inval_domain=", IOMMU_INV_DOMAIN.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: iommu_inval_bdf=", IOMMU_INV_BDF.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_sessions=", MIG_SESSIONS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_scan_rounds=", MIG_SCAN_ROUNDS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_dirty_pages=", MIG_DIRTY_PAGES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_precopy_rounds=", MIG_PRECOPY_ROUNDS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_precopy_pages=", MIG_PRECOPY_PAGES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_bytes_tx=", MIG_BYTES_TX.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_zero_skipped=", MIG_ZERO_SKIPPED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_hash_skipped=", MIG_HASH_SKIPPED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_zero_bytes_saved=", MIG_ZERO_BYTES_SAVED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_hash_bytes_saved=", MIG_HASH_BYTES_SAVED.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_frames=", MIG_FRAMES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_raw_pages=", MIG_RAW_PAGES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_compressed_pages=", MIG_COMPRESSED_PAGES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_manifests=", MIG_MANIFESTS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_ctrl_frames=", MIG_CTRL_FRAMES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_acks=", MIG_ACKS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_naks=", MIG_NAKS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_resend_triggers=", MIG_RESEND_TRIGGERS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_cb_written_bytes=", MIG_CB_WRITTEN_BYTES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_cfg_saves=", MIG_CFG_SAVES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_cfg_loads=", MIG_CFG_LOADS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_tx_bytes=", MIG_NET_TX_BYTES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_cfg_set=", MIG_NET_CFG_SET.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_tx_frames=", MIG_NET_TX_FRAMES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_open_ok=", MIG_NET_OPEN_OK.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_open_fail=", MIG_NET_OPEN_FAIL.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_start_ok=", MIG_NET_START_OK.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_start_fail=", MIG_NET_START_FAIL.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_init_ok=", MIG_NET_INIT_OK.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_init_fail=", MIG_NET_INIT_FAIL.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_net_tx_errs=", MIG_NET_TX_ERRS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_pump_calls=", MIG_PUMP_CALLS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_pump_frames=", MIG_PUMP_FRAMES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_pump_bytes=", MIG_PUMP_BYTES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_pump_empty=", MIG_PUMP_EMPTY.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_poll_cycles=", MIG_POLL_CYCLES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_ctrl_auto_ack=", MIG_CTRL_AUTO_ACK_SENT.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_ctrl_auto_nak=", MIG_CTRL_AUTO_NAK_SENT.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_rx_frames_ok=", MIG_RX_FRAMES_OK.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_rx_frames_bad=", MIG_RX_FRAMES_BAD.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_rx_bytes=", MIG_RX_BYTES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_replay_pages=", MIG_REPLAY_PAGES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_replay_bytes=", MIG_REPLAY_BYTES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_replay_errors=", MIG_REPLAY_ERRORS.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_dup_frames=", MIG_DUP_FRAMES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_missing_frames=", MIG_MISSING_FRAMES.load(core::sync::atomic::Ordering::Relaxed));
-    print("metrics: mig_last_seq=", MIG_LAST_SEQ.load(core::sync::atomic::Ordering::Relaxed));
+    for (name, cell) in COUNTERS.iter() {
+        let mut label = [0u8; 48]; let mut k = 0;
+        for &b in b"metrics: " { label[k] = b; k += 1; }
+        for &b in name.as_bytes() { label[k] = b; k += 1; }
+        label[k] = b'='; k += 1;
+        print(core::str::from_utf8(&label[..k]).unwrap_or(""), cell.load(Ordering::Relaxed));
+    }
     // Dump histogram (compact)
     {
         let mut n = 0;
@@ -171,19 +597,61 @@ pub fn dump(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>) {
         for (i, edge) in VMX_SMOKE_BUCKET_EDGES_US.iter().enumerate() {
             if i > 0 { buf[n] = b','; n += 1; }
             buf[n] = b'['; n += 1; buf[n] = b'<'; n += 1; buf[n] = b'='; n += 1;
-            n += crate::firmware::acpi::u32_to_dec(*edge as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(*edge as u32, &mut buf[n..]);
             buf[n] = b':'; n += 1;
-            n += crate::firmware::acpi::u32_to_dec(VMX_SMOKE_HIST_US[i].load(Ordering::Relaxed) as u32, &mut buf[n..]);
+            n += crate::util::format::u32_dec(VMX_SMOKE_HIST_US[i].load(Ordering::Relaxed) as u32, &mut buf[n..]);
             buf[n] = b']'; n += 1;
         }
         // Last bucket '>'
         buf[n] = b','; n += 1; buf[n] = b'['; n += 1; buf[n] = b'>'; n += 1;
-        n += crate::firmware::acpi::u32_to_dec(*VMX_SMOKE_BUCKET_EDGES_US.last().unwrap() as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(*VMX_SMOKE_BUCKET_EDGES_US.last().unwrap() as u32, &mut buf[n..]);
         buf[n] = b':'; n += 1;
-        n += crate::firmware::acpi::u32_to_dec(VMX_SMOKE_HIST_US[VMX_SMOKE_BUCKET_EDGES_US.len()].load(Ordering::Relaxed) as u32, &mut buf[n..]);
+        n += crate::util::format::u32_dec(VMX_SMOKE_HIST_US[VMX_SMOKE_BUCKET_EDGES_US.len()].load(Ordering::Relaxed) as u32, &mut buf[n..]);
         buf[n] = b']'; n += 1; buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
         let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
     }
+    // Per-task background CPU time
+    for t in task_accounts().iter() {
+        if t.name.is_empty() { continue; }
+        let mut n = 0;
+        for &b in b"metrics: bg_task " { buf[n] = b; n += 1; }
+        for &b in t.name.as_bytes().iter().take(32) { buf[n] = b; n += 1; }
+        for &b in b" runs=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec(t.runs as u32, &mut buf[n..]);
+        for &b in b" cpu_us=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u32_dec((t.run_ns / 1_000) as u32, &mut buf[n..]);
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
+    // Power capping gauges and per-VM throttle time
+    for (label, cell) in [(&b"metrics: power_cap_mw="[..], &POWER_CAP_MW), (&b"metrics: power_draw_mw="[..], &POWER_DRAW_MW), (&b"metrics: carbon_intensity_g="[..], &CARBON_INTENSITY_G), (&b"metrics: dvfs_util_pct="[..], &DVFS_UTIL_PCT), (&b"metrics: thermal_max_c="[..], &THERMAL_MAX_C), (&b"metrics: gpu_slices_attached="[..], &GPU_SLICES_ATTACHED)] {
+        let mut n = 0;
+        for &b in label { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(cell.load(Ordering::Relaxed), &mut buf[n..]);
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
+    for e in vm_throttles().iter().flatten() {
+        let mut n = 0;
+        for &b in b"metrics: power_throttle vm=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(e.vm_id, &mut buf[n..]);
+        for &b in b" us=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(e.us, &mut buf[n..]);
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
+    // Per-VM guest CPU time under the credit scheduler
+    for e in vm_sched().iter().flatten() {
+        let mut n = 0;
+        for &b in b"metrics: sched vm=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(e.vm_id, &mut buf[n..]);
+        for &b in b" run_us=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(e.run_us, &mut buf[n..]);
+        for &b in b" parks=" { buf[n] = b; n += 1; }
+        n += crate::util::format::u64_dec(e.parks, &mut buf[n..]);
+        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    }
 }
 
 pub fn reset() {
@@ -191,6 +659,107 @@ pub fn reset() {
     VM_STARTED.store(0, Ordering::Relaxed);
     VCPU_STARTED.store(0, Ordering::Relaxed);
     VCPU_STOPPED.store(0, Ordering::Relaxed);
+    VM_IMAGE_LOADS.store(0, Ordering::Relaxed);
+    VM_ADMIT_OK.store(0, Ordering::Relaxed);
+    VM_ADMIT_REJECTS.store(0, Ordering::Relaxed);
+    VIRQ_INJECTED.store(0, Ordering::Relaxed);
+    VIRQ_ACCEL.store(0, Ordering::Relaxed);
+    EMUL_MMIO.store(0, Ordering::Relaxed);
+    EMUL_PIO.store(0, Ordering::Relaxed);
+    EMUL_FAIL.store(0, Ordering::Relaxed);
+    VNET_TX_FRAMES.store(0, Ordering::Relaxed);
+    VNET_RX_FRAMES.store(0, Ordering::Relaxed);
+    VNET_DROPS.store(0, Ordering::Relaxed);
+    VSWITCH_FLOODS.store(0, Ordering::Relaxed);
+    VSWITCH_DROPS.store(0, Ordering::Relaxed);
+    VNET_QOS_THROTTLED.store(0, Ordering::Relaxed);
+    STORAGE_CHUNK_ALLOCS.store(0, Ordering::Relaxed);
+    STORAGE_COW_COPIES.store(0, Ordering::Relaxed);
+    STORAGE_SNAPSHOTS.store(0, Ordering::Relaxed);
+    NVME_COMMANDS.store(0, Ordering::Relaxed);
+    NVME_ERRORS.store(0, Ordering::Relaxed);
+    CSI_CREATES.store(0, Ordering::Relaxed);
+    CSI_PUBLISHES.store(0, Ordering::Relaxed);
+    CNI_ADDS.store(0, Ordering::Relaxed);
+    CRI_SANDBOXES.store(0, Ordering::Relaxed);
+    CRI_CONTAINER_STARTS.store(0, Ordering::Relaxed);
+    MICROVM_CLONES.store(0, Ordering::Relaxed);
+    MICROVM_COW_BREAKS.store(0, Ordering::Relaxed);
+    MICROVM_STARTS.store(0, Ordering::Relaxed);
+    MICROVM_POOL_HITS.store(0, Ordering::Relaxed);
+    WASM_LOADS.store(0, Ordering::Relaxed);
+    WASM_STARTS.store(0, Ordering::Relaxed);
+    WASM_INSTRUCTIONS.store(0, Ordering::Relaxed);
+    WASM_TRAPS.store(0, Ordering::Relaxed);
+    PLUGIN_LOADS.store(0, Ordering::Relaxed);
+    PLUGIN_REJECTS.store(0, Ordering::Relaxed);
+    PLUGIN_CALLS.store(0, Ordering::Relaxed);
+    VCON_TX_BYTES.store(0, Ordering::Relaxed);
+    VCON_RX_BYTES.store(0, Ordering::Relaxed);
+    VSOCK_TX_PKTS.store(0, Ordering::Relaxed);
+    VSOCK_RX_PKTS.store(0, Ordering::Relaxed);
+    VSOCK_DROPS.store(0, Ordering::Relaxed);
+    CLUSTER_HB_TX.store(0, Ordering::Relaxed);
+    CLUSTER_HB_RX.store(0, Ordering::Relaxed);
+    CLUSTER_QUORUM_LOST.store(0, Ordering::Relaxed);
+    CLUSTER_GOSSIP_TX.store(0, Ordering::Relaxed);
+    CLUSTER_GOSSIP_RX.store(0, Ordering::Relaxed);
+    CLUSTER_MEMBER_FAILED.store(0, Ordering::Relaxed);
+    CLUSTER_HA_RESTARTED.store(0, Ordering::Relaxed);
+    CLUSTER_HA_FAILED.store(0, Ordering::Relaxed);
+    CLUSTER_LOG_COMMITS.store(0, Ordering::Relaxed);
+    CLUSTER_LOG_VIEW_CHANGES.store(0, Ordering::Relaxed);
+    POWER_THROTTLE_EVENTS.store(0, Ordering::Relaxed);
+    POWER_THROTTLE_US.store(0, Ordering::Relaxed);
+    CARBON_DEFERRED.store(0, Ordering::Relaxed);
+    CARBON_RELEASED.store(0, Ordering::Relaxed);
+    CARBON_FORCED.store(0, Ordering::Relaxed);
+    CARBON_AVOIDED_MG.store(0, Ordering::Relaxed);
+    DVFS_TRANSITIONS.store(0, Ordering::Relaxed);
+    THERMAL_THROTTLE_EVENTS.store(0, Ordering::Relaxed);
+    GPU_SLICE_ATTACHES.store(0, Ordering::Relaxed);
+    VBLK_REQS.store(0, Ordering::Relaxed);
+    VBLK_ERRORS.store(0, Ordering::Relaxed);
+    MIG_SELFTEST_RUNS.store(0, Ordering::Relaxed);
+    MIG_SELFTEST_FAILS.store(0, Ordering::Relaxed);
+    MIG_RDMA_PAGES.store(0, Ordering::Relaxed);
+    MIG_RDMA_RETRANSMITS.store(0, Ordering::Relaxed);
+    MIG_HELLOS.store(0, Ordering::Relaxed);
+    MIG_RX_V2_FRAMES.store(0, Ordering::Relaxed);
+    MIG_TLV_REJECTS.store(0, Ordering::Relaxed);
+    MIG_CB_LOST_BYTES.store(0, Ordering::Relaxed);
+    MIG_CB_HIGH_MARKS.store(0, Ordering::Relaxed);
+    MIG_CB_LOW_MARKS.store(0, Ordering::Relaxed);
+    MIG_PUMP_BACKPRESSURE.store(0, Ordering::Relaxed);
+    BG_RUNS.store(0, Ordering::Relaxed);
+    BG_THROTTLED.store(0, Ordering::Relaxed);
+    BG_RT_SKIPS.store(0, Ordering::Relaxed);
+    SCHED_SLICES.store(0, Ordering::Relaxed);
+    SCHED_SWITCHES.store(0, Ordering::Relaxed);
+    SCHED_PREEMPTS.store(0, Ordering::Relaxed);
+    SCHED_PERIODS.store(0, Ordering::Relaxed);
+    SCHED_CAP_PARKS.store(0, Ordering::Relaxed);
+    SCHED_RT_EXHAUSTED.store(0, Ordering::Relaxed);
+    SCHED_RT_MISSES.store(0, Ordering::Relaxed);
+    SCHED_RT_REJECTS.store(0, Ordering::Relaxed);
+    GMEM_EXTENTS.store(0, Ordering::Relaxed);
+    GMEM_FW_CLAIMS.store(0, Ordering::Relaxed);
+    GMEM_ALLOC_FAILS.store(0, Ordering::Relaxed);
+    GMEM_OVERCOMMIT_REJECTS.store(0, Ordering::Relaxed);
+    BALLOON_INFLATED.store(0, Ordering::Relaxed);
+    BALLOON_DEFLATED.store(0, Ordering::Relaxed);
+    ACCEL_JOBS.store(0, Ordering::Relaxed);
+    ACCEL_JOBS_REJECTED.store(0, Ordering::Relaxed);
+    KSM_SCANNED.store(0, Ordering::Relaxed);
+    KSM_MERGES.store(0, Ordering::Relaxed);
+    KSM_COW_BREAKS.store(0, Ordering::Relaxed);
+    ZCOPY_DOORBELLS.store(0, Ordering::Relaxed);
+    HTTP_REQUESTS.store(0, Ordering::Relaxed);
+    HTTP_RETRANSMITS.store(0, Ordering::Relaxed);
+    for (_, h) in HISTOGRAMS.iter() { h.reset(); }
+    TASK_ACCT.lock(|t| { for a in t.iter_mut() { a.runs = 0; a.run_ns = 0; } });
+    VM_THROTTLE.lock(|t| { for e in t.iter_mut().flatten() { e.us = 0; } });
+    VM_SCHED.lock(|t| { for e in t.iter_mut().flatten() { e.run_us = 0; e.parks = 0; } });
     IOMMU_DOMAIN_CREATED.store(0, Ordering::Relaxed);
     IOMMU_ASSIGN_ADDED.store(0, Ordering::Relaxed);
     IOMMU_ASSIGN_REMOVED.store(0, Ordering::Relaxed);
@@ -1,5 +1,8 @@
+pub mod console;
 pub mod log;
 pub mod metrics;
+pub mod page;
 pub mod trace;
 
 
+pub mod prom;
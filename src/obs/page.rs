@@ -0,0 +1,177 @@
+#![allow(dead_code)]
+
+//! Shared-memory metrics page for agents outside the hypervisor.
+//!
+//! `init` allocates one `EfiRuntimeServicesData` page and publishes its
+//! address as a UEFI configuration table under `METRICS_PAGE_GUID`, so a DXE
+//! driver, an OS loader or an in-guest agent with the page mapped can find it
+//! by walking `SystemTable->ConfigurationTable`. `tick` mirrors the counters
+//! into the page at most every `INTERVAL_MS`.
+//!
+//! Layout (little-endian, version 1):
+//!
+//! ```text
+//! 0x00  [u8; 8]  magic "ZVMETRIC"
+//! 0x08  u16      version
+//! 0x0A  u16      header length (64)
+//! 0x0C  u16      entry length (16)
+//! 0x0E  u16      entry count
+//! 0x10  u64      sequence; odd while an update is in progress
+//! 0x18  u64      TSC frequency in Hz
+//! 0x20  u64      TSC at the last update
+//! 0x28  u64      updates published
+//! 0x30  ..       reserved, zero
+//! 0x40  entries: u32 id, u16 kind, u16 reserved, u64 value
+//! ```
+//!
+//! An entry's id is the FNV-1a 32-bit hash of its metric name as printed by
+//! `metrics` (histograms add `_count` and `_sum`), so ids stay valid when
+//! counters are added or reordered. Readers copy the entries between two reads
+//! of the sequence and retry if it was odd or changed.
+
+use core::sync::atomic::{compiler_fence, AtomicU64, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::table::SystemTable;
+use uefi::Guid;
+
+use super::metrics;
+
+/// Configuration table entry pointing at the page
+pub const METRICS_PAGE_GUID: Guid = uefi::guid!("5a564d45-7452-4963-8e50-616765763031");
+pub const METRICS_PAGE_GUID_STR: &str = "5a564d45-7452-4963-8e50-616765763031";
+pub const MAGIC: [u8; 8] = *b"ZVMETRIC";
+pub const VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 64;
+pub const ENTRY_LEN: usize = 16;
+pub const PAGE_LEN: usize = 4096;
+pub const MAX_ENTRIES: usize = (PAGE_LEN - HEADER_LEN) / ENTRY_LEN;
+/// Minimum time between two mirrors from `tick`
+pub const INTERVAL_MS: u64 = 100;
+
+pub const KIND_COUNTER: u16 = 0;
+pub const KIND_GAUGE: u16 = 1;
+
+const OFF_SEQ: usize = 0x10;
+const OFF_TSC_HZ: usize = 0x18;
+const OFF_LAST_TSC: usize = 0x20;
+const OFF_UPDATES: usize = 0x28;
+
+static PAGE: AtomicU64 = AtomicU64::new(0);
+/// `clock::now_ms` at the last update
+static LAST_MS: AtomicU64 = AtomicU64::new(0);
+static UPDATES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Info {
+    pub addr: u64,
+    pub entries: u16,
+    pub updates: u64,
+}
+
+const FNV_BASIS: u32 = 0x811c_9dc5;
+
+const fn fnv(mut h: u32, bytes: &[u8]) -> u32 {
+    let mut i = 0;
+    while i < bytes.len() {
+        h ^= bytes[i] as u32;
+        h = h.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    h
+}
+
+/// Stable id of a metric name.
+pub const fn id(name: &[u8]) -> u32 { fnv(FNV_BASIS, name) }
+
+/// Id of a histogram's `<name>_count` or `<name>_sum` series.
+fn id_suffixed(name: &str, suffix: &str) -> u32 { fnv(id(name.as_bytes()), suffix.as_bytes()) }
+
+fn put<T>(base: u64, off: usize, v: T) {
+    unsafe { core::ptr::write_volatile((base + off as u64) as *mut T, v) };
+}
+
+/// Every entry in page order.
+fn for_each_entry(mut f: impl FnMut(u32, u16, u64)) {
+    for (name, cell) in metrics::COUNTERS.iter() {
+        f(id(name.as_bytes()), KIND_COUNTER, cell.load(Ordering::Relaxed));
+    }
+    for (name, h) in metrics::HISTOGRAMS.iter() {
+        f(id_suffixed(name, "_count"), KIND_COUNTER, h.count());
+        f(id_suffixed(name, "_sum"), KIND_COUNTER, h.sum());
+    }
+    f(id(b"power_cap_mw"), KIND_GAUGE, metrics::POWER_CAP_MW.load(Ordering::Relaxed));
+    f(id(b"power_draw_mw"), KIND_GAUGE, metrics::POWER_DRAW_MW.load(Ordering::Relaxed));
+    f(id(b"carbon_intensity_g"), KIND_GAUGE, metrics::CARBON_INTENSITY_G.load(Ordering::Relaxed));
+    f(id(b"dvfs_util_pct"), KIND_GAUGE, metrics::DVFS_UTIL_PCT.load(Ordering::Relaxed));
+    f(id(b"thermal_max_c"), KIND_GAUGE, metrics::THERMAL_MAX_C.load(Ordering::Relaxed));
+    f(id(b"gpu_slices_attached"), KIND_GAUGE, metrics::GPU_SLICES_ATTACHED.load(Ordering::Relaxed));
+}
+
+/// Allocate the page, write the header and the first snapshot, and install
+/// the configuration table entry. Calling it again is a no-op.
+pub fn init(system_table: &SystemTable<Boot>) -> Result<u64, &'static str> {
+    let cur = PAGE.load(Ordering::Acquire);
+    if cur != 0 { return Ok(cur); }
+    let bs = system_table.boot_services();
+    let addr = bs.allocate_pages(AllocateType::AnyPages, MemoryType::RUNTIME_SERVICES_DATA, 1)
+        .map_err(|_| "metrics page: alloc failed")?;
+    unsafe { core::ptr::write_bytes(addr as *mut u8, 0, PAGE_LEN) };
+    let mut count = 0usize;
+    for_each_entry(|_, _, _| count += 1);
+    let count = count.min(MAX_ENTRIES);
+    unsafe { core::ptr::copy_nonoverlapping(MAGIC.as_ptr(), addr as *mut u8, MAGIC.len()) };
+    put(addr, 0x08, VERSION);
+    put(addr, 0x0A, HEADER_LEN as u16);
+    put(addr, 0x0C, ENTRY_LEN as u16);
+    put(addr, 0x0E, count as u16);
+    put(addr, OFF_TSC_HZ, crate::time::tsc_hz());
+    PAGE.store(addr, Ordering::Release);
+    publish();
+    let installed = unsafe { bs.install_configuration_table(&METRICS_PAGE_GUID, addr as *const core::ffi::c_void) };
+    if installed.is_err() {
+        PAGE.store(0, Ordering::Release);
+        let _ = unsafe { bs.free_pages(addr, 1) };
+        return Err("metrics page: install config table failed");
+    }
+    Ok(addr)
+}
+
+/// Mirror every entry into the page now.
+pub fn publish() {
+    let base = PAGE.load(Ordering::Acquire);
+    if base == 0 { return; }
+    let seq = unsafe { core::ptr::read_volatile((base + OFF_SEQ as u64) as *const u64) };
+    put(base, OFF_SEQ, seq.wrapping_add(1) | 1);
+    compiler_fence(Ordering::SeqCst);
+    let mut i = 0usize;
+    for_each_entry(|id, kind, value| {
+        if i >= MAX_ENTRIES { return; }
+        let off = HEADER_LEN + i * ENTRY_LEN;
+        put(base, off, id);
+        put(base, off + 4, kind);
+        put(base, off + 8, value);
+        i += 1;
+    });
+    let now = crate::time::rdtsc();
+    put(base, OFF_LAST_TSC, now);
+    put(base, OFF_UPDATES, UPDATES.fetch_add(1, Ordering::Relaxed) + 1);
+    compiler_fence(Ordering::SeqCst);
+    put(base, OFF_SEQ, (seq | 1).wrapping_add(1));
+    LAST_MS.store(crate::time::clock::now_ms(), Ordering::Relaxed);
+}
+
+/// Mirror the counters if `INTERVAL_MS` has passed since the last update.
+pub fn tick() {
+    if PAGE.load(Ordering::Relaxed) == 0 { return; }
+    let since = crate::time::clock::now_ms().saturating_sub(LAST_MS.load(Ordering::Relaxed));
+    if crate::time::clock::hz() != 0 && since < INTERVAL_MS { return; }
+    publish();
+}
+
+pub fn info() -> Option<Info> {
+    let addr = PAGE.load(Ordering::Acquire);
+    if addr == 0 { return None; }
+    let entries = unsafe { core::ptr::read_volatile((addr + 0x0E) as *const u16) };
+    Some(Info { addr, entries, updates: UPDATES.load(Ordering::Relaxed) })
+}
@@ -0,0 +1,252 @@
+#![allow(dead_code)]
+
+//! Prometheus text exposition for host metrics, and a typed parser for it.
+//!
+//! `render` emits every counter in `metrics::COUNTERS`, the VMX smoke-test
+//! and latency histograms, the per-task background CPU gauges and the
+//! power-capping gauges and per-VM throttle time, one line at a time so no
+//! large buffer is needed. `parse` turns exposition text (ours or a peer's)
+//! back into `MetricFamily` values without allocation; the callback sees each
+//! family once all of its samples have been read.
+
+use core::sync::atomic::Ordering;
+use super::metrics;
+
+/// Prefix applied to every exported metric name.
+pub const PREFIX: &str = "zerovisor_";
+/// Samples kept per family while parsing; extra samples are counted but dropped.
+pub const MAX_SAMPLES: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricType { Counter, Gauge, Histogram, Summary, Untyped }
+
+impl MetricType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+            MetricType::Summary => "summary",
+            MetricType::Untyped => "untyped",
+        }
+    }
+
+    fn from_str(s: &str) -> Self {
+        match s {
+            "counter" => MetricType::Counter,
+            "gauge" => MetricType::Gauge,
+            "histogram" => MetricType::Histogram,
+            "summary" => MetricType::Summary,
+            _ => MetricType::Untyped,
+        }
+    }
+}
+
+/// One sample line: `name{labels} value [timestamp]`.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample<'a> {
+    /// Full sample name (may carry `_bucket`, `_sum`, `_count` suffixes)
+    pub name: &'a str,
+    /// Raw label set without braces, e.g. `task="ksm",le="10"`
+    pub labels: &'a str,
+    pub value: f64,
+    pub timestamp_ms: Option<i64>,
+}
+
+impl<'a> Sample<'a> {
+    /// Value of label `key`, if present (escape sequences are left as-is).
+    pub fn label(&self, key: &str) -> Option<&'a str> {
+        let mut rest = self.labels;
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ',' || c == ' ');
+            if rest.is_empty() { return None; }
+            let eq = rest.find('=')?;
+            let k = rest[..eq].trim();
+            let val = rest[eq + 1..].trim_start().strip_prefix('"')?;
+            let b = val.as_bytes();
+            let mut i = 0;
+            while i < b.len() && b[i] != b'"' { i += if b[i] == b'\\' { 2 } else { 1 }; }
+            if i >= b.len() { return None; }
+            if k == key { return Some(&val[..i]); }
+            rest = &val[i + 1..];
+        }
+    }
+}
+
+/// A metric family: TYPE/HELP metadata and its samples.
+#[derive(Clone, Copy, Debug)]
+pub struct MetricFamily<'a> {
+    pub name: &'a str,
+    pub help: &'a str,
+    pub kind: MetricType,
+    pub samples: [Option<Sample<'a>>; MAX_SAMPLES],
+    pub len: usize,
+    /// Samples that did not fit in `samples`
+    pub dropped: usize,
+}
+
+impl<'a> MetricFamily<'a> {
+    fn new(name: &'a str) -> Self {
+        MetricFamily { name, help: "", kind: MetricType::Untyped, samples: [None; MAX_SAMPLES], len: 0, dropped: 0 }
+    }
+
+    fn push(&mut self, s: Sample<'a>) {
+        if self.len < MAX_SAMPLES { self.samples[self.len] = Some(s); self.len += 1; } else { self.dropped += 1; }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Sample<'a>> { self.samples[..self.len].iter().flatten() }
+
+    /// Value of the first sample, the usual case for counters and gauges.
+    pub fn value(&self) -> Option<f64> { self.iter().next().map(|s| s.value) }
+}
+
+/// Family a sample belongs to: strip histogram/summary suffixes when the
+/// current family declares them.
+fn family_of<'a>(sample: &'a str, cur: &str) -> &'a str {
+    for suf in ["_bucket", "_sum", "_count"] {
+        if let Some(base) = sample.strip_suffix(suf) { if base == cur { return base; } }
+    }
+    sample
+}
+
+fn parse_sample(line: &str) -> Option<Sample<'_>> {
+    let (name, labels, rest) = match line.find('{') {
+        Some(b) => {
+            let e = line[b..].rfind('}')? + b;
+            (line[..b].trim(), &line[b + 1..e], &line[e + 1..])
+        }
+        None => {
+            let sp = line.find(|c: char| c == ' ' || c == '\t')?;
+            (&line[..sp], "", &line[sp..])
+        }
+    };
+    let mut it = rest.split_whitespace();
+    let v = it.next()?;
+    let value = match v {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        "NaN" => f64::NAN,
+        _ => v.parse::<f64>().ok()?,
+    };
+    let timestamp_ms = it.next().and_then(|t| t.parse::<i64>().ok());
+    Some(Sample { name, labels, value, timestamp_ms })
+}
+
+/// Parse exposition text, invoking `f` once per family. Returns the number of
+/// families seen, or the 1-based line number of the first malformed line.
+pub fn parse<'a>(text: &'a str, mut f: impl FnMut(&MetricFamily<'a>)) -> Result<usize, usize> {
+    let mut cur: Option<MetricFamily<'a>> = None;
+    let mut count = 0usize;
+    for (lineno, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() { continue; }
+        if let Some(meta) = line.strip_prefix('#') {
+            let meta = meta.trim_start();
+            let (kw, rest) = match meta.split_once(' ') { Some(p) => p, None => continue };
+            if kw != "TYPE" && kw != "HELP" { continue; }
+            let (name, arg) = match rest.trim().split_once(' ') { Some((n, a)) => (n, a.trim()), None => (rest.trim(), "") };
+            if cur.as_ref().map(|c| c.name != name).unwrap_or(true) {
+                if let Some(c) = cur.take() { f(&c); count += 1; }
+                cur = Some(MetricFamily::new(name));
+            }
+            if let Some(c) = cur.as_mut() {
+                if kw == "TYPE" { c.kind = MetricType::from_str(arg); } else { c.help = arg; }
+            }
+            continue;
+        }
+        let s = parse_sample(line).ok_or(lineno + 1)?;
+        let fam = family_of(s.name, cur.as_ref().map(|c| c.name).unwrap_or(""));
+        if cur.as_ref().map(|c| c.name != fam).unwrap_or(true) {
+            if let Some(c) = cur.take() { f(&c); count += 1; }
+            cur = Some(MetricFamily::new(fam));
+        }
+        if let Some(c) = cur.as_mut() { c.push(s); }
+    }
+    if let Some(c) = cur.take() { f(&c); count += 1; }
+    Ok(count)
+}
+
+// ---- Rendering ----
+
+/// One exposition line under construction; `emit` hands it to the writer.
+pub(crate) struct Line { buf: [u8; 160], n: usize }
+
+impl Line {
+    pub(crate) fn new() -> Self { Line { buf: [0; 160], n: 0 } }
+    pub(crate) fn s(&mut self, s: &str) -> &mut Self {
+        for &b in s.as_bytes() { if self.n < self.buf.len() { self.buf[self.n] = b; self.n += 1; } }
+        self
+    }
+    pub(crate) fn u(&mut self, v: u64) -> &mut Self { self.n += crate::util::format::u64_dec(v, &mut self.buf[self.n..]); self }
+    pub(crate) fn emit(&mut self, w: &mut impl FnMut(&str)) {
+        self.s("\n");
+        w(core::str::from_utf8(&self.buf[..self.n]).unwrap_or("\n"));
+        self.n = 0;
+    }
+}
+
+/// Write the current metrics as exposition text, one line per call of `w`
+/// (each line ends with `\n`).
+pub fn render(mut w: impl FnMut(&str)) {
+    let mut l = Line::new();
+    for (name, cell) in metrics::COUNTERS.iter() {
+        l.s("# TYPE ").s(PREFIX).s(name).s(" counter").emit(&mut w);
+        l.s(PREFIX).s(name).s(" ").u(cell.load(Ordering::Relaxed)).emit(&mut w);
+    }
+    // Histogram buckets are cumulative in the exposition format.
+    l.s("# TYPE ").s(PREFIX).s("vmx_smoke_us histogram").emit(&mut w);
+    let mut acc = 0u64;
+    for (i, edge) in metrics::vmx_smoke_bucket_edges_us().iter().enumerate() {
+        acc += metrics::VMX_SMOKE_HIST_US[i].load(Ordering::Relaxed);
+        l.s(PREFIX).s("vmx_smoke_us_bucket{le=\"").u(*edge).s("\"} ").u(acc).emit(&mut w);
+    }
+    acc += metrics::VMX_SMOKE_HIST_US[metrics::vmx_smoke_bucket_edges_us().len()].load(Ordering::Relaxed);
+    l.s(PREFIX).s("vmx_smoke_us_bucket{le=\"+Inf\"} ").u(acc).emit(&mut w);
+    l.s(PREFIX).s("vmx_smoke_us_count ").u(acc).emit(&mut w);
+    for (name, h) in metrics::HISTOGRAMS.iter() {
+        l.s("# TYPE ").s(PREFIX).s(name).s(" histogram").emit(&mut w);
+        // Buckets up to the highest one in use; the rest only repeat the count.
+        let top = (0..metrics::HIST_BUCKETS - 1).rev().find(|&i| h.bucket(i) != 0).unwrap_or(0);
+        let mut acc = 0u64;
+        for i in 0..=top {
+            acc += h.bucket(i);
+            l.s(PREFIX).s(name).s("_bucket{le=\"").u(metrics::Histogram::upper(i)).s("\"} ").u(acc).emit(&mut w);
+        }
+        let count = h.count();
+        l.s(PREFIX).s(name).s("_bucket{le=\"+Inf\"} ").u(count).emit(&mut w);
+        l.s(PREFIX).s(name).s("_sum ").u(h.sum()).emit(&mut w);
+        l.s(PREFIX).s(name).s("_count ").u(count).emit(&mut w);
+    }
+    // Background task CPU time
+    l.s("# TYPE ").s(PREFIX).s("bg_task_cpu_us counter").emit(&mut w);
+    for t in metrics::task_accounts().iter() {
+        if t.name.is_empty() { continue; }
+        l.s(PREFIX).s("bg_task_cpu_us{task=\"").s(t.name).s("\"} ").u(t.run_ns / 1_000).emit(&mut w);
+    }
+    // Power capping
+    l.s("# TYPE ").s(PREFIX).s("power_cap_mw gauge").emit(&mut w);
+    l.s(PREFIX).s("power_cap_mw ").u(metrics::POWER_CAP_MW.load(Ordering::Relaxed)).emit(&mut w);
+    l.s("# TYPE ").s(PREFIX).s("power_draw_mw gauge").emit(&mut w);
+    l.s(PREFIX).s("power_draw_mw ").u(metrics::POWER_DRAW_MW.load(Ordering::Relaxed)).emit(&mut w);
+    l.s("# TYPE ").s(PREFIX).s("carbon_intensity_g gauge").emit(&mut w);
+    l.s(PREFIX).s("carbon_intensity_g ").u(metrics::CARBON_INTENSITY_G.load(Ordering::Relaxed)).emit(&mut w);
+    l.s("# TYPE ").s(PREFIX).s("dvfs_util_pct gauge").emit(&mut w);
+    l.s(PREFIX).s("dvfs_util_pct ").u(metrics::DVFS_UTIL_PCT.load(Ordering::Relaxed)).emit(&mut w);
+    l.s("# TYPE ").s(PREFIX).s("thermal_max_c gauge").emit(&mut w);
+    l.s(PREFIX).s("thermal_max_c ").u(metrics::THERMAL_MAX_C.load(Ordering::Relaxed)).emit(&mut w);
+    l.s("# TYPE ").s(PREFIX).s("gpu_slices_attached gauge").emit(&mut w);
+    l.s(PREFIX).s("gpu_slices_attached ").u(metrics::GPU_SLICES_ATTACHED.load(Ordering::Relaxed)).emit(&mut w);
+    l.s("# TYPE ").s(PREFIX).s("vm_power_throttle_us counter").emit(&mut w);
+    for e in metrics::vm_throttles().iter().flatten() {
+        l.s(PREFIX).s("vm_power_throttle_us{vm=\"").u(e.vm_id).s("\"} ").u(e.us).emit(&mut w);
+    }
+    // Credit scheduler
+    l.s("# TYPE ").s(PREFIX).s("vm_sched_run_us counter").emit(&mut w);
+    for e in metrics::vm_sched().iter().flatten() {
+        l.s(PREFIX).s("vm_sched_run_us{vm=\"").u(e.vm_id).s("\"} ").u(e.run_us).emit(&mut w);
+    }
+    l.s("# TYPE ").s(PREFIX).s("vm_sched_cap_parks counter").emit(&mut w);
+    for e in metrics::vm_sched().iter().flatten() {
+        l.s(PREFIX).s("vm_sched_cap_parks{vm=\"").u(e.vm_id).s("\"} ").u(e.parks).emit(&mut w);
+    }
+}
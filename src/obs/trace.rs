@@ -1,6 +1,29 @@
 #![allow(dead_code)]
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+//! Hypervisor trace ring.
+//!
+//! `emit` stores each event as a fixed 48-byte record stamped with the TSC and
+//! the emitting CPU, in a ring of `TRACE_CAP` records that overwrites the
+//! oldest. Events belong to a category; categories outside the enable mask
+//! are dropped at `emit`, so the per-exit and per-slice events cost one load
+//! when they are off (the default).
+//!
+//! `trace` prints the tail of the ring as text. `export_with_writer` streams
+//! the raw ring for offline decoding: a 64-byte header followed by records
+//! oldest first, all little-endian.
+//!
+//! ```text
+//! header  0 magic "ZVTRACE\0"   8 version u16   10 record len u16
+//!        12 header len u32     16 TSC Hz u64   24 category mask u32
+//!        28 capacity u32       32 records u64  40 dropped u64
+//! record  0 tsc u64   8 seq u32   12 kind u16   14 cpu u16   16 args [u64; 4]
+//! ```
+//!
+//! `kind` is the `KIND_*` code of the event and `args` its payload in the
+//! order `encode` packs it. `seq` counts from 1; a record with `seq` 0 is a
+//! slot that was overwritten while the export was reading it.
+
+use core::sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering};
 use core::fmt::Write as _;
 
 #[derive(Clone, Copy, Debug)]
@@ -9,128 +32,392 @@ pub enum Event {
     VmStart(u64),
     VmStop(u64),
     VmDestroy(u64),
-        MigrateScanRound(u64, u64),
+    MigrateScanRound(u64, u64),
     IommuInvalidateAll(u16),
     IommuInvalidateDomain(u16),
     IommuInvalidateBdf(u16, u8, u8, u8),
     IommuMapAdded(u16),
     IommuMapRemoved(u16),
+    /// Guest MMIO access matched by an `hv::mmiotrace` filter
+    GuestMmio { vm: u32, vcpu: u16, size: u8, write: bool, gpa: u64, value: u64, rip: u64 },
+    /// VM exit taken by a vCPU, before it is handled
+    VmExit { vm: u32, vcpu: u16, reason: u16, rip: u64 },
+    /// DMA remapping fault drained by `iommu::fault::poll`
+    IommuFault { seg: u16, bus: u8, dev: u8, func: u8, amd: bool, reason: u8, write: bool, addr: u64 },
+    /// Scheduler gave run slot `slot` a slice of `slice` TSC cycles
+    SchedPick { slot: u16, vm: u32, vcpu: u16, slice: u64 },
+}
+
+pub const CAT_VM: u32 = 1 << 0;
+pub const CAT_EXIT: u32 = 1 << 1;
+pub const CAT_IOMMU: u32 = 1 << 2;
+pub const CAT_MIGRATE: u32 = 1 << 3;
+pub const CAT_SCHED: u32 = 1 << 4;
+pub const CAT_MMIO: u32 = 1 << 5;
+pub const CAT_ALL: u32 = (1 << 6) - 1;
+/// Names accepted by `trace mask`, in bit order
+pub const CATEGORIES: [(&str, u32); 6] = [
+    ("vm", CAT_VM), ("exit", CAT_EXIT), ("iommu", CAT_IOMMU),
+    ("migrate", CAT_MIGRATE), ("sched", CAT_SCHED), ("mmio", CAT_MMIO),
+];
+/// Exits and scheduler picks happen thousands of times a second; opt in.
+const DEFAULT_MASK: u32 = CAT_ALL & !(CAT_EXIT | CAT_SCHED);
+
+const KIND_VM_CREATE: u16 = 1;
+const KIND_VM_START: u16 = 2;
+const KIND_VM_STOP: u16 = 3;
+const KIND_VM_DESTROY: u16 = 4;
+const KIND_MIGRATE_SCAN: u16 = 5;
+const KIND_IOMMU_INVAL_ALL: u16 = 6;
+const KIND_IOMMU_INVAL_DOM: u16 = 7;
+const KIND_IOMMU_INVAL_BDF: u16 = 8;
+const KIND_IOMMU_MAP_ADD: u16 = 9;
+const KIND_IOMMU_MAP_DEL: u16 = 10;
+const KIND_GUEST_MMIO: u16 = 11;
+const KIND_VM_EXIT: u16 = 12;
+const KIND_IOMMU_FAULT: u16 = 13;
+const KIND_SCHED_PICK: u16 = 14;
+
+pub const TRACE_CAP: usize = 2048;
+pub const RECORD_LEN: usize = 48;
+pub const HEADER_LEN: usize = 64;
+const FORMAT_VERSION: u16 = 1;
+/// Records `trace` prints when no count is given
+pub const DUMP_DEFAULT: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Record {
+    tsc: u64,
+    /// Write index + 1, stored last; 0 while the slot is empty or being written
+    seq: u32,
+    kind: u16,
+    cpu: u16,
+    arg: [u64; 4],
 }
 
-const TRACE_CAP: usize = 64;
+const EMPTY: Record = Record { tsc: 0, seq: 0, kind: 0, cpu: 0, arg: [0; 4] };
+
 static TRACE_WIDX: AtomicUsize = AtomicUsize::new(0);
-static mut TRACE_BUF: [Event; TRACE_CAP] = [Event::VmCreate(0); TRACE_CAP];
+static TRACE_MASK: AtomicU32 = AtomicU32::new(DEFAULT_MASK);
+static mut TRACE_BUF: [Record; TRACE_CAP] = [EMPTY; TRACE_CAP];
+
+impl Event {
+    pub fn category(&self) -> u32 {
+        match self {
+            Event::VmCreate(_) | Event::VmStart(_) | Event::VmStop(_) | Event::VmDestroy(_) => CAT_VM,
+            Event::MigrateScanRound(..) => CAT_MIGRATE,
+            Event::IommuInvalidateAll(_) | Event::IommuInvalidateDomain(_) | Event::IommuInvalidateBdf(..)
+            | Event::IommuMapAdded(_) | Event::IommuMapRemoved(_) | Event::IommuFault { .. } => CAT_IOMMU,
+            Event::GuestMmio { .. } => CAT_MMIO,
+            Event::VmExit { .. } => CAT_EXIT,
+            Event::SchedPick { .. } => CAT_SCHED,
+        }
+    }
+
+    fn encode(&self) -> (u16, [u64; 4]) {
+        match *self {
+            Event::VmCreate(id) => (KIND_VM_CREATE, [id, 0, 0, 0]),
+            Event::VmStart(id) => (KIND_VM_START, [id, 0, 0, 0]),
+            Event::VmStop(id) => (KIND_VM_STOP, [id, 0, 0, 0]),
+            Event::VmDestroy(id) => (KIND_VM_DESTROY, [id, 0, 0, 0]),
+            Event::MigrateScanRound(id, pages) => (KIND_MIGRATE_SCAN, [id, pages, 0, 0]),
+            Event::IommuInvalidateAll(seg) => (KIND_IOMMU_INVAL_ALL, [seg as u64, 0, 0, 0]),
+            Event::IommuInvalidateDomain(dom) => (KIND_IOMMU_INVAL_DOM, [dom as u64, 0, 0, 0]),
+            Event::IommuInvalidateBdf(seg, bus, dev, func) => (KIND_IOMMU_INVAL_BDF, [bdf(seg, bus, dev, func), 0, 0, 0]),
+            Event::IommuMapAdded(dom) => (KIND_IOMMU_MAP_ADD, [dom as u64, 0, 0, 0]),
+            Event::IommuMapRemoved(dom) => (KIND_IOMMU_MAP_DEL, [dom as u64, 0, 0, 0]),
+            // vm | vcpu << 32 | size << 48 | write << 56
+            Event::GuestMmio { vm, vcpu, size, write, gpa, value, rip } =>
+                (KIND_GUEST_MMIO, [vm as u64 | (vcpu as u64) << 32 | (size as u64) << 48 | (write as u64) << 56, gpa, value, rip]),
+            // vm | vcpu << 32 | reason << 48
+            Event::VmExit { vm, vcpu, reason, rip } => (KIND_VM_EXIT, [vm as u64 | (vcpu as u64) << 32 | (reason as u64) << 48, rip, 0, 0]),
+            // bdf | reason << 32 | write << 40 | amd << 41
+            Event::IommuFault { seg, bus, dev, func, amd, reason, write, addr } =>
+                (KIND_IOMMU_FAULT, [bdf(seg, bus, dev, func) | (reason as u64) << 32 | (write as u64) << 40 | (amd as u64) << 41, addr, 0, 0]),
+            // slot | vcpu << 16 | vm << 32
+            Event::SchedPick { slot, vm, vcpu, slice } => (KIND_SCHED_PICK, [slot as u64 | (vcpu as u64) << 16 | (vm as u64) << 32, slice, 0, 0]),
+        }
+    }
+
+    fn decode(kind: u16, a: [u64; 4]) -> Option<Event> {
+        let (seg, bus, dev, func) = ((a[0] >> 16) as u16, (a[0] >> 8) as u8, ((a[0] >> 3) & 0x1F) as u8, (a[0] & 7) as u8);
+        Some(match kind {
+            KIND_VM_CREATE => Event::VmCreate(a[0]),
+            KIND_VM_START => Event::VmStart(a[0]),
+            KIND_VM_STOP => Event::VmStop(a[0]),
+            KIND_VM_DESTROY => Event::VmDestroy(a[0]),
+            KIND_MIGRATE_SCAN => Event::MigrateScanRound(a[0], a[1]),
+            KIND_IOMMU_INVAL_ALL => Event::IommuInvalidateAll(a[0] as u16),
+            KIND_IOMMU_INVAL_DOM => Event::IommuInvalidateDomain(a[0] as u16),
+            KIND_IOMMU_INVAL_BDF => Event::IommuInvalidateBdf(seg, bus, dev, func),
+            KIND_IOMMU_MAP_ADD => Event::IommuMapAdded(a[0] as u16),
+            KIND_IOMMU_MAP_DEL => Event::IommuMapRemoved(a[0] as u16),
+            KIND_GUEST_MMIO => Event::GuestMmio { vm: a[0] as u32, vcpu: (a[0] >> 32) as u16, size: (a[0] >> 48) as u8, write: (a[0] >> 56) & 1 != 0, gpa: a[1], value: a[2], rip: a[3] },
+            KIND_VM_EXIT => Event::VmExit { vm: a[0] as u32, vcpu: (a[0] >> 32) as u16, reason: (a[0] >> 48) as u16, rip: a[1] },
+            KIND_IOMMU_FAULT => Event::IommuFault { seg, bus, dev, func, reason: (a[0] >> 32) as u8, write: (a[0] >> 40) & 1 != 0, amd: (a[0] >> 41) & 1 != 0, addr: a[1] },
+            KIND_SCHED_PICK => Event::SchedPick { slot: a[0] as u16, vcpu: (a[0] >> 16) as u16, vm: (a[0] >> 32) as u32, slice: a[1] },
+            _ => return None,
+        })
+    }
+}
+
+/// seg << 16 | bus << 8 | dev << 3 | func
+fn bdf(seg: u16, bus: u8, dev: u8, func: u8) -> u64 { (seg as u64) << 16 | (bus as u64) << 8 | ((dev & 0x1F) as u64) << 3 | (func & 7) as u64 }
+
+pub fn mask() -> u32 { TRACE_MASK.load(Ordering::Relaxed) }
+pub fn set_mask(m: u32) { TRACE_MASK.store(m & CAT_ALL, Ordering::Relaxed); }
+/// True if events of `cat` are recorded; lets hot paths skip building them.
+#[inline(always)]
+pub fn enabled(cat: u32) -> bool { mask() & cat != 0 }
+
+/// Parse `all`, `none` or a comma-separated list of category names.
+pub fn parse_mask(s: &str) -> Option<u32> {
+    if s.eq_ignore_ascii_case("all") { return Some(CAT_ALL); }
+    if s.eq_ignore_ascii_case("none") { return Some(0); }
+    let mut m = 0;
+    for name in s.split(',') {
+        m |= CATEGORIES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))?.1;
+    }
+    Some(m)
+}
 
 pub fn emit(e: Event) {
-    let i = TRACE_WIDX.fetch_add(1, Ordering::Relaxed) % TRACE_CAP;
-    unsafe { core::ptr::write_volatile(&mut TRACE_BUF[i], e); }
+    if !enabled(e.category()) { return; }
+    let (kind, arg) = e.encode();
+    let tsc = crate::time::rdtsc();
+    let cpu = (crate::arch::x86::cpuid::cpuid(crate::arch::x86::cpuid::leaf::BASIC_FEATURES, 0).ebx >> 24) as u16;
+    let idx = TRACE_WIDX.fetch_add(1, Ordering::Relaxed);
+    unsafe {
+        let slot = core::ptr::addr_of_mut!(TRACE_BUF[idx % TRACE_CAP]);
+        core::ptr::write_volatile(core::ptr::addr_of_mut!((*slot).seq), 0);
+        fence(Ordering::Release);
+        core::ptr::write_volatile(slot, Record { tsc, seq: 0, kind, cpu, arg });
+        fence(Ordering::Release);
+        core::ptr::write_volatile(core::ptr::addr_of_mut!((*slot).seq), (idx as u32).wrapping_add(1));
+    }
 }
 
-pub fn dump(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>) {
-    let stdout = system_table.stdout();
-    let mut buf = [0u8; 96];
-    // Print last TRACE_CAP events
-    let cur = TRACE_WIDX.load(Ordering::Relaxed);
-    let start = cur.saturating_sub(TRACE_CAP);
-    for idx in start..cur {
-        let ev = unsafe { core::ptr::read_volatile(&TRACE_BUF[idx % TRACE_CAP]) };
-        let mut n = 0;
-        match ev {
-            Event::VmCreate(id) => {
-                for &b in b"trace: vm_create id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-            }
-            Event::VmStart(id) => {
-                for &b in b"trace: vm_start id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-            }
-            Event::VmStop(id) => {
-                for &b in b"trace: vm_stop id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-            }
-            Event::VmDestroy(id) => {
-                for &b in b"trace: vm_destroy id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-                }
-                Event::MigrateScanRound(id, pages) => {
-                    for &b in b"trace: migrate_scan id=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-                    for &b in b" pages=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(pages as u32, &mut buf[n..]);
-            }
-            Event::IommuInvalidateAll(seg) => {
-                for &b in b"trace: vtd_inval_all seg=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
-            }
-            Event::IommuInvalidateDomain(dom) => {
-                for &b in b"trace: vtd_inval_dom id=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
-            }
-            Event::IommuInvalidateBdf(seg, bus, dev, func) => {
-                for &b in b"trace: vtd_inval_bdf " { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]);
-                buf[n] = b':'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
-                buf[n] = b':'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
-                buf[n] = b'.'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
-            }
-            Event::IommuMapAdded(dom) => {
-                for &b in b"trace: vtd_map_add dom=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
-            }
-            Event::IommuMapRemoved(dom) => {
-                for &b in b"trace: vtd_map_del dom=" { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]);
-            }
+/// Record `idx` of the write order, unless it is not written yet or was
+/// overwritten while being read.
+fn read_slot(idx: usize) -> Option<Record> {
+    let want = (idx as u32).wrapping_add(1);
+    unsafe {
+        let slot = core::ptr::addr_of!(TRACE_BUF[idx % TRACE_CAP]);
+        if core::ptr::read_volatile(core::ptr::addr_of!((*slot).seq)) != want { return None; }
+        fence(Ordering::Acquire);
+        let rec = core::ptr::read_volatile(slot);
+        fence(Ordering::Acquire);
+        if core::ptr::read_volatile(core::ptr::addr_of!((*slot).seq)) != want || rec.seq != want { return None; }
+        Some(rec)
+    }
+}
+
+/// Visit the last `last` complete records, oldest first.
+fn for_each_record(last: usize, mut f: impl FnMut(&Record)) {
+    let cur = TRACE_WIDX.load(Ordering::Acquire);
+    for idx in cur.saturating_sub(last.min(TRACE_CAP))..cur {
+        if let Some(rec) = read_slot(idx) { f(&rec); }
+    }
+}
+
+/// `mmio vm=<id> vcpu=<n> <R|W><size> gpa=0x.. val=0x.. rip=0x..`
+fn mmio_line(buf: &mut [u8], vm: u32, vcpu: u16, size: u8, write: bool, gpa: u64, value: u64, rip: u64) -> usize {
+    let mut n = 0;
+    for &b in b"mmio vm=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(vm, &mut buf[n..]);
+    for &b in b" vcpu=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(vcpu as u32, &mut buf[n..]);
+    buf[n] = b' '; n += 1;
+    buf[n] = if write { b'W' } else { b'R' }; n += 1;
+    n += crate::util::format::u32_dec(size as u32, &mut buf[n..]);
+    for &b in b" gpa=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(gpa, &mut buf[n..]);
+    for &b in b" val=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(value, &mut buf[n..]);
+    for &b in b" rip=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(rip, &mut buf[n..]);
+    n
+}
+
+fn bdf_text(buf: &mut [u8], seg: u16, bus: u8, dev: u8, func: u8) -> usize {
+    let mut n = crate::util::format::u32_dec(seg as u32, buf);
+    buf[n] = b':'; n += 1;
+    n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
+    buf[n] = b':'; n += 1;
+    n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
+    buf[n] = b'.'; n += 1;
+    n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
+    n
+}
+
+/// `trace: [<us>us cpu<n>] <event> ..\r\n`
+fn record_line(buf: &mut [u8], rec: &Record) -> usize {
+    let mut n = 0;
+    for &b in b"trace: [" { buf[n] = b; n += 1; }
+    let hz = crate::time::tsc_hz();
+    let us = if hz == 0 { 0 } else { ((rec.tsc as u128 * 1_000_000) / hz as u128) as u64 };
+    n += crate::util::format::u64_dec(us, &mut buf[n..]);
+    for &b in b"us cpu" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(rec.cpu as u32, &mut buf[n..]);
+    for &b in b"] " { buf[n] = b; n += 1; }
+    let dec = |v: u64, out: &mut [u8]| crate::util::format::u64_dec(v, out);
+    match Event::decode(rec.kind, rec.arg) {
+        Some(Event::VmCreate(id)) => { for &b in b"vm_create id=" { buf[n] = b; n += 1; } n += dec(id, &mut buf[n..]); }
+        Some(Event::VmStart(id)) => { for &b in b"vm_start id=" { buf[n] = b; n += 1; } n += dec(id, &mut buf[n..]); }
+        Some(Event::VmStop(id)) => { for &b in b"vm_stop id=" { buf[n] = b; n += 1; } n += dec(id, &mut buf[n..]); }
+        Some(Event::VmDestroy(id)) => { for &b in b"vm_destroy id=" { buf[n] = b; n += 1; } n += dec(id, &mut buf[n..]); }
+        Some(Event::MigrateScanRound(id, pages)) => {
+            for &b in b"migrate_scan id=" { buf[n] = b; n += 1; }
+            n += dec(id, &mut buf[n..]);
+            for &b in b" pages=" { buf[n] = b; n += 1; }
+            n += dec(pages, &mut buf[n..]);
         }
-        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
-        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+        Some(Event::IommuInvalidateAll(seg)) => { for &b in b"vtd_inval_all seg=" { buf[n] = b; n += 1; } n += dec(seg as u64, &mut buf[n..]); }
+        Some(Event::IommuInvalidateDomain(dom)) => { for &b in b"vtd_inval_dom id=" { buf[n] = b; n += 1; } n += dec(dom as u64, &mut buf[n..]); }
+        Some(Event::IommuInvalidateBdf(seg, bus, dev, func)) => {
+            for &b in b"vtd_inval_bdf " { buf[n] = b; n += 1; }
+            n += bdf_text(&mut buf[n..], seg, bus, dev, func);
+        }
+        Some(Event::IommuMapAdded(dom)) => { for &b in b"vtd_map_add dom=" { buf[n] = b; n += 1; } n += dec(dom as u64, &mut buf[n..]); }
+        Some(Event::IommuMapRemoved(dom)) => { for &b in b"vtd_map_del dom=" { buf[n] = b; n += 1; } n += dec(dom as u64, &mut buf[n..]); }
+        Some(Event::GuestMmio { vm, vcpu, size, write, gpa, value, rip }) => { n += mmio_line(&mut buf[n..], vm, vcpu, size, write, gpa, value, rip); }
+        Some(Event::VmExit { vm, vcpu, reason, rip }) => {
+            for &b in b"exit vm=" { buf[n] = b; n += 1; }
+            n += dec(vm as u64, &mut buf[n..]);
+            for &b in b" vcpu=" { buf[n] = b; n += 1; }
+            n += dec(vcpu as u64, &mut buf[n..]);
+            for &b in b" reason=" { buf[n] = b; n += 1; }
+            n += dec(reason as u64, &mut buf[n..]);
+            for &b in b" rip=0x" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(rip, &mut buf[n..]);
+        }
+        Some(Event::IommuFault { seg, bus, dev, func, amd, reason, write, addr }) => {
+            for &b in if amd { &b"amdvi_fault "[..] } else { &b"vtd_fault "[..] } { buf[n] = b; n += 1; }
+            n += bdf_text(&mut buf[n..], seg, bus, dev, func);
+            for &b in b" reason=" { buf[n] = b; n += 1; }
+            n += dec(reason as u64, &mut buf[n..]);
+            buf[n] = b' '; n += 1;
+            buf[n] = if write { b'W' } else { b'R' }; n += 1;
+            for &b in b" addr=0x" { buf[n] = b; n += 1; }
+            n += crate::util::format::u64_hex(addr, &mut buf[n..]);
+        }
+        Some(Event::SchedPick { slot, vm, vcpu, slice }) => {
+            for &b in b"sched_pick slot=" { buf[n] = b; n += 1; }
+            n += dec(slot as u64, &mut buf[n..]);
+            for &b in b" vm=" { buf[n] = b; n += 1; }
+            n += dec(vm as u64, &mut buf[n..]);
+            for &b in b" vcpu=" { buf[n] = b; n += 1; }
+            n += dec(vcpu as u64, &mut buf[n..]);
+            for &b in b" slice=" { buf[n] = b; n += 1; }
+            n += dec(slice, &mut buf[n..]);
+        }
+        None => { for &b in b"unknown kind=" { buf[n] = b; n += 1; } n += dec(rec.kind as u64, &mut buf[n..]); }
     }
+    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+    n
 }
 
+/// Print the last `last` records.
+pub fn dump(system_table: &mut uefi::table::SystemTable<uefi::prelude::Boot>, last: usize) {
+    let stdout = system_table.stdout();
+    let mut buf = [0u8; 192];
+    for_each_record(last, |rec| {
+        let n = record_line(&mut buf, rec);
+        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+    });
+}
+
+/// Text of the last `DUMP_DEFAULT` records, one line per call.
 pub fn dump_with_writer(mut write_bytes: impl FnMut(&[u8])) {
-    let cur = TRACE_WIDX.load(Ordering::Relaxed);
-    let start = cur.saturating_sub(TRACE_CAP);
-    let mut buf = [0u8; 96];
-    for idx in start..cur {
-        let ev = unsafe { core::ptr::read_volatile(&TRACE_BUF[idx % TRACE_CAP]) };
-        let mut n = 0;
-        match ev {
-            Event::VmCreate(id) => { for &b in b"trace: vm_create id=" { buf[n] = b; n += 1; } n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]); }
-            Event::VmStart(id) => { for &b in b"trace: vm_start id=" { buf[n] = b; n += 1; } n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]); }
-            Event::VmStop(id) => { for &b in b"trace: vm_stop id=" { buf[n] = b; n += 1; } n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]); }
-            Event::VmDestroy(id) => { for &b in b"trace: vm_destroy id=" { buf[n] = b; n += 1; } n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]); }
-                Event::MigrateScanRound(id, pages) => {
-                    for &b in b"trace: migrate_scan id=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(id as u32, &mut buf[n..]);
-                    for &b in b" pages=" { buf[n] = b; n += 1; }
-                    n += crate::firmware::acpi::u32_to_dec(pages as u32, &mut buf[n..]);
-                }
-            Event::IommuInvalidateAll(seg) => { for &b in b"trace: vtd_inval_all seg=" { buf[n] = b; n += 1; } n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]); }
-            Event::IommuInvalidateDomain(dom) => { for &b in b"trace: vtd_inval_dom id=" { buf[n] = b; n += 1; } n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]); }
-            Event::IommuInvalidateBdf(seg, bus, dev, func) => {
-                for &b in b"trace: vtd_inval_bdf " { buf[n] = b; n += 1; }
-                n += crate::firmware::acpi::u32_to_dec(seg as u32, &mut buf[n..]); buf[n] = b':'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]); buf[n] = b':'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]); buf[n] = b'.'; n += 1;
-                n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
-            }
-            Event::IommuMapAdded(dom) => { for &b in b"trace: vtd_map_add dom=" { buf[n] = b; n += 1; } n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]); }
-            Event::IommuMapRemoved(dom) => { for &b in b"trace: vtd_map_del dom=" { buf[n] = b; n += 1; } n += crate::firmware::acpi::u32_to_dec(dom as u32, &mut buf[n..]); }
-        }
-        buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+    let mut buf = [0u8; 192];
+    for_each_record(DUMP_DEFAULT, |rec| {
+        let n = record_line(&mut buf, rec);
         write_bytes(&buf[..n]);
+    });
+}
+
+/// Records overwritten before anyone could read them.
+pub fn dropped() -> u64 { TRACE_WIDX.load(Ordering::Relaxed).saturating_sub(TRACE_CAP) as u64 }
+
+fn to_bytes(rec: &Record) -> [u8; RECORD_LEN] {
+    let mut r = [0u8; RECORD_LEN];
+    r[0..8].copy_from_slice(&rec.tsc.to_le_bytes());
+    r[8..12].copy_from_slice(&rec.seq.to_le_bytes());
+    r[12..14].copy_from_slice(&rec.kind.to_le_bytes());
+    r[14..16].copy_from_slice(&rec.cpu.to_le_bytes());
+    for (k, a) in rec.arg.iter().enumerate() { r[16 + 8 * k..24 + 8 * k].copy_from_slice(&a.to_le_bytes()); }
+    r
+}
+
+fn from_bytes(r: &[u8; RECORD_LEN]) -> Record {
+    let u64_at = |o: usize| { let mut b = [0u8; 8]; b.copy_from_slice(&r[o..o + 8]); u64::from_le_bytes(b) };
+    Record {
+        tsc: u64_at(0),
+        seq: u32::from_le_bytes([r[8], r[9], r[10], r[11]]),
+        kind: u16::from_le_bytes([r[12], r[13]]),
+        cpu: u16::from_le_bytes([r[14], r[15]]),
+        arg: [u64_at(16), u64_at(24), u64_at(32), u64_at(40)],
     }
 }
 
+/// The last `last` records in the export format, oldest first.
+pub fn last_raw(last: usize, mut f: impl FnMut(&[u8; RECORD_LEN])) {
+    for_each_record(last, |rec| f(&to_bytes(rec)));
+}
+
+/// Text line of a record in the export format, as `trace` prints it.
+pub fn raw_line(raw: &[u8; RECORD_LEN], buf: &mut [u8]) -> usize { record_line(buf, &from_bytes(raw)) }
+
+/// Stream the ring in the binary export format: the header, then each
+/// record. Returns the number of records written.
+pub fn export_with_writer(mut write_bytes: impl FnMut(&[u8]) -> Result<(), &'static str>) -> Result<u64, &'static str> {
+    let cur = TRACE_WIDX.load(Ordering::Acquire);
+    let mut h = [0u8; HEADER_LEN];
+    h[..8].copy_from_slice(b"ZVTRACE\0");
+    h[8..10].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    h[10..12].copy_from_slice(&(RECORD_LEN as u16).to_le_bytes());
+    h[12..16].copy_from_slice(&(HEADER_LEN as u32).to_le_bytes());
+    h[16..24].copy_from_slice(&crate::time::tsc_hz().to_le_bytes());
+    h[24..28].copy_from_slice(&mask().to_le_bytes());
+    h[28..32].copy_from_slice(&(TRACE_CAP as u32).to_le_bytes());
+    h[32..40].copy_from_slice(&(cur.min(TRACE_CAP) as u64).to_le_bytes());
+    h[40..48].copy_from_slice(&(cur.saturating_sub(TRACE_CAP) as u64).to_le_bytes());
+    write_bytes(&h)?;
+    let mut count = 0u64;
+    for idx in cur.saturating_sub(TRACE_CAP)..cur {
+        // Keep the record count of the header: a slot lost to a newer event
+        // goes out as an empty record.
+        write_bytes(&to_bytes(&read_slot(idx).unwrap_or(EMPTY)))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Write the binary export to `path` on the ESP, replacing any older file.
+pub fn export_file(system_table: &uefi::table::SystemTable<uefi::prelude::Boot>, path: &str) -> Result<(u64, u64), &'static str> {
+    use uefi::proto::media::file::{File, FileAttribute, FileMode};
+    let mut pbuf = [0u8; 128];
+    if path.is_empty() || path.len() > pbuf.len() { return Err("trace: invalid path"); }
+    for (i, &c) in path.as_bytes().iter().enumerate() { pbuf[i] = if c == b'/' { b'\\' } else { c }; }
+    let p = core::str::from_utf8(&pbuf[..path.len()]).map_err(|_| "trace: invalid path")?;
+    let mut name_buf = [0u16; 130];
+    let name = uefi::CStr16::from_str_with_buf(p, &mut name_buf).map_err(|_| "trace: invalid path")?;
+    let bs = system_table.boot_services();
+    let mut fs = bs.get_image_file_system(bs.image_handle()).map_err(|_| "trace: ESP not accessible")?;
+    let mut root = fs.open_volume().map_err(|_| "trace: open volume failed")?;
+    if let Some(old) = root.open(name, FileMode::ReadWrite, FileAttribute::empty()).ok().and_then(|h| h.into_regular_file()) { let _ = old.delete(); }
+    let h = root.open(name, FileMode::CreateReadWrite, FileAttribute::empty()).map_err(|_| "trace: cannot create file")?;
+    let mut f = h.into_regular_file().ok_or("trace: not a regular file")?;
+    let mut bytes = 0u64;
+    let records = export_with_writer(|b| { bytes += b.len() as u64; f.write(b).map_err(|_| "trace: write failed") })?;
+    f.flush().map_err(|_| "trace: flush failed")?;
+    Ok((records, bytes))
+}
+
 pub fn clear() {
     // Reset write index and wipe buffer best-effort
     TRACE_WIDX.store(0, Ordering::Relaxed);
     unsafe {
-        for i in 0..TRACE_CAP { core::ptr::write_volatile(&mut TRACE_BUF[i], Event::VmCreate(0)); }
+        for i in 0..TRACE_CAP { core::ptr::write_volatile(core::ptr::addr_of_mut!(TRACE_BUF[i]), EMPTY); }
     }
 }
-
-
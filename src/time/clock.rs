@@ -0,0 +1,203 @@
+#![allow(dead_code)]
+
+//! Monotonic clock.
+//!
+//! `init` picks one source for the life of the hypervisor, in order of
+//! preference: the TSC when it is invariant, the HPET main counter, the
+//! ACPI PM timer, and a TSC that is not invariant as a last resort.
+//! `now_ns` reads it as nanoseconds, never going backwards.
+//!
+//! The HPET counter may be 32 bits wide and the PM timer 24 or 32 bits.
+//! Narrow counters are extended to 64 bits in software. Each read folds the
+//! raw value into `EXT`, the latest extended value, and a raw value that is
+//! more than half the range ahead is taken as a stale read rather than a
+//! wrap. That only holds if something reads the clock once per half wrap.
+//! A 24-bit PM timer wraps every 4.7 s, so `start_timer` reads it from the
+//! timer wheel.
+//!
+//! The state is atomics only, with no lock, so APs may read the clock at
+//! any time, `init` included. Before `init` the clock is the TSC at the
+//! cached `tsc_hz`, counting from reset, and `init` carries that reading
+//! over to the new source. Without a calibrated TSC and before `init` it
+//! reads 0.
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+/// ACPI PM timer rate
+pub const PM_TIMER_HZ: u64 = 3_579_545;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source { None, Tsc, Hpet, PmTimer }
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self { Source::None => "none", Source::Tsc => "tsc", Source::Hpet => "hpet", Source::PmTimer => "pm-timer" }
+    }
+
+    fn from_u8(v: u8) -> Source {
+        match v { 1 => Source::Tsc, 2 => Source::Hpet, 3 => Source::PmTimer, _ => Source::None }
+    }
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(0);
+static HZ: AtomicU64 = AtomicU64::new(0);
+/// HPET MMIO base or PM timer port
+static ADDR: AtomicU64 = AtomicU64::new(0);
+/// Counter width; all ones for the TSC
+static MASK: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Latest extended counter value
+static EXT: AtomicU64 = AtomicU64::new(0);
+/// Extended counter value at `init`
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+/// `now_ns` at `init`
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+fn to_ns(ticks: u64, hz: u64) -> u64 {
+    if hz == 0 { return 0; }
+    (ticks as u128 * 1_000_000_000 / hz as u128) as u64
+}
+
+fn raw(src: Source, addr: u64) -> u64 {
+    match src {
+        Source::Hpet => super::hpet::read_hpet_main_counter(addr),
+        Source::PmTimer => unsafe { crate::arch::x86::port::inl(addr as u16) as u64 },
+        Source::Tsc | Source::None => super::rdtsc(),
+    }
+}
+
+/// Extended count of a narrow counter.
+fn extended(src: Source) -> u64 {
+    let mask = MASK.load(Ordering::Relaxed);
+    let cur = raw(src, ADDR.load(Ordering::Relaxed)) & mask;
+    let mut last = EXT.load(Ordering::Acquire);
+    loop {
+        let ahead = cur.wrapping_sub(last) & mask;
+        // Behind `last`: another CPU read the counter after we did
+        if ahead > mask / 2 { return last; }
+        match EXT.compare_exchange_weak(last, last + ahead, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return last + ahead,
+            Err(v) => last = v,
+        }
+    }
+}
+
+pub fn source() -> Source { Source::from_u8(SOURCE.load(Ordering::Acquire)) }
+
+/// Counter rate of the source, 0 if the clock cannot run yet.
+pub fn hz() -> u64 {
+    match source() {
+        Source::None => super::tsc_hz(),
+        _ => HZ.load(Ordering::Relaxed),
+    }
+}
+
+/// Nanoseconds on the monotonic clock.
+pub fn now_ns() -> u64 {
+    match source() {
+        Source::None => to_ns(super::rdtsc(), super::tsc_hz()),
+        Source::Tsc => to_ns(super::rdtsc(), HZ.load(Ordering::Relaxed)),
+        src => {
+            let t = extended(src).wrapping_sub(EPOCH.load(Ordering::Relaxed));
+            BASE_NS.load(Ordering::Relaxed).saturating_add(to_ns(t, HZ.load(Ordering::Relaxed)))
+        }
+    }
+}
+
+pub fn now_us() -> u64 { now_ns() / 1_000 }
+
+pub fn now_ms() -> u64 { now_ns() / 1_000_000 }
+
+/// Time on the clock: since reset while the TSC is the source, which is
+/// usually power-on.
+pub fn uptime() -> Duration { Duration::from_nanos(now_ns()) }
+
+/// Nanoseconds since an earlier `now_ns` reading.
+pub fn elapsed_ns(since_ns: u64) -> u64 { now_ns().saturating_sub(since_ns) }
+
+/// A point on the `now_ns` scale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    pub const NEVER: Deadline = Deadline(u64::MAX);
+
+    pub fn at_ns(ns: u64) -> Deadline { Deadline(ns) }
+
+    pub fn after_ns(ns: u64) -> Deadline { Deadline(now_ns().saturating_add(ns)) }
+
+    pub fn after_us(us: u64) -> Deadline { Deadline::after_ns(us.saturating_mul(1_000)) }
+
+    pub fn after_ms(ms: u64) -> Deadline { Deadline::after_ns(ms.saturating_mul(1_000_000)) }
+
+    pub fn expired(self) -> bool { now_ns() >= self.0 }
+
+    pub fn remaining_ns(self) -> u64 { self.0.saturating_sub(now_ns()) }
+
+    pub fn as_ns(self) -> u64 { self.0 }
+}
+
+/// Spin until `done` holds or `d` passes; true if it held. Checks `done`
+/// once more at the deadline, so a slow poll does not turn into a timeout.
+pub fn spin_until(d: Deadline, mut done: impl FnMut() -> bool) -> bool {
+    loop {
+        if done() { return true; }
+        if d.expired() { return done(); }
+        core::hint::spin_loop();
+    }
+}
+
+/// Busy-wait `us` microseconds. Returns at once while the clock cannot run.
+pub fn delay_us(us: u64) {
+    if hz() == 0 { return; }
+    let d = Deadline::after_us(us);
+    while !d.expired() { core::hint::spin_loop(); }
+}
+
+/// Choose the source. Calibrates the TSC first if nobody has. Returns the
+/// source in use; later calls return it unchanged.
+pub fn init(system_table: &SystemTable<Boot>) -> Source {
+    let cur = source();
+    if cur != Source::None { return cur; }
+    let tsc_hz = super::init_time(system_table);
+    if tsc_hz != 0 && crate::arch::x86::cpuid::has_invariant_tsc() {
+        HZ.store(tsc_hz, Ordering::Relaxed);
+        SOURCE.store(Source::Tsc as u8, Ordering::Release);
+        return Source::Tsc;
+    }
+    let hpet = super::hpet::locate_hpet(system_table).map(|h| {
+        super::hpet::enable_hpet_counter(h.base_phys);
+        let mask = if super::hpet::counter_is_64bit(h.base_phys) { u64::MAX } else { u32::MAX as u64 };
+        (Source::Hpet, h.base_phys, mask, super::hpet::hpet_hz_from_period(h.period_fs))
+    });
+    let pm = || {
+        let fadt = crate::firmware::acpi::find_fadt(system_table)?;
+        let (port, wide) = crate::firmware::acpi::fadt_pm_timer(fadt)?;
+        Some((Source::PmTimer, port as u64, if wide { u32::MAX as u64 } else { 0xFF_FFFF }, PM_TIMER_HZ))
+    };
+    let Some((src, addr, mask, hz)) = hpet.filter(|h| h.3 != 0).or_else(pm) else {
+        if tsc_hz == 0 { return Source::None; }
+        HZ.store(tsc_hz, Ordering::Relaxed);
+        SOURCE.store(Source::Tsc as u8, Ordering::Release);
+        return Source::Tsc;
+    };
+    let base_ns = now_ns();
+    let start = raw(src, addr) & mask;
+    ADDR.store(addr, Ordering::Relaxed);
+    MASK.store(mask, Ordering::Relaxed);
+    HZ.store(hz, Ordering::Relaxed);
+    EXT.store(start, Ordering::Relaxed);
+    EPOCH.store(start, Ordering::Relaxed);
+    BASE_NS.store(base_ns, Ordering::Relaxed);
+    SOURCE.store(src as u8, Ordering::Release);
+    src
+}
+
+/// Keep a narrow counter's extension current from the timer wheel. Nothing
+/// to do, and no timer armed, for a 64-bit source.
+pub fn start_timer() -> Result<(), &'static str> {
+    if source() == Source::Tsc || MASK.load(Ordering::Relaxed) == u64::MAX { return Ok(()); }
+    crate::time::timer::add("clock", 1000, 1000, |_| { let _ = now_ns(); }, 0).map(|_| ())
+}
@@ -11,7 +11,6 @@ use core::ptr::{read_volatile, write_volatile};
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
 use core::fmt::Write as _;
-use crate::util::format;
 
 /// ACPI Generic Address Structure (GAS)
 #[repr(C, packed)]
@@ -41,7 +40,27 @@ const GAS_SPACE_SYSTEM_MEMORY: u8 = 0x00;
 /// HPET register offsets.
 const HPET_GENERAL_CAP_ID: usize = 0x000; // 64-bit
 const HPET_GENERAL_CONFIG: usize = 0x010; // 64-bit
+const HPET_GENERAL_INT_STATUS: usize = 0x020; // 64-bit, write 1 to clear
 const HPET_MAIN_COUNTER: usize = 0x0F0; // 64-bit
+/// Timer N configuration and comparator, 0x20 apart per timer.
+const HPET_TN_CONFIG: usize = 0x100;
+const HPET_TN_COMPARATOR: usize = 0x108;
+const HPET_TN_STRIDE: usize = 0x20;
+
+/// General capabilities bits.
+const CAP_NUM_TIM_SHIFT: u32 = 8; // bits 12:8, number of timers minus one
+const CAP_COUNT_SIZE: u64 = 1 << 13; // 64-bit main counter
+/// General configuration: legacy replacement routes timers 0 and 1 to IRQ 0 and 8.
+const CFG_LEG_RT: u64 = 1 << 1;
+
+/// Timer N configuration bits.
+const TN_INT_TYPE_LEVEL: u64 = 1 << 1;
+const TN_INT_ENB: u64 = 1 << 2;
+const TN_TYPE_PERIODIC: u64 = 1 << 3;
+const TN_PER_INT_CAP: u64 = 1 << 4;
+const TN_VAL_SET: u64 = 1 << 6;
+const TN_32MODE: u64 = 1 << 8;
+const TN_FSB_EN: u64 = 1 << 14;
 
 /// HPET info discovered from ACPI.
 #[derive(Clone, Copy, Debug)]
@@ -94,6 +113,62 @@ pub fn restore_hpet_config(hpet_base_phys: u64, prev_cfg: u64) {
     unsafe { write64(base, HPET_GENERAL_CONFIG, prev_cfg); }
 }
 
+/// Whether the main counter is 64 bits wide; a 32-bit one wraps.
+pub fn counter_is_64bit(hpet_base_phys: u64) -> bool {
+    unsafe { read64(hpet_base_phys as *const u8, HPET_GENERAL_CAP_ID) & CAP_COUNT_SIZE != 0 }
+}
+
+/// Put a comparator nobody uses into periodic mode, firing every `period`
+/// counter ticks with its interrupt disabled. The timer still sets its bit in
+/// the general interrupt status register each period (see `take_status`).
+/// Timers firmware has enabled, and timers 0 and 1 under legacy replacement,
+/// are left alone. Starts the main counter. Returns the timer number.
+pub fn setup_periodic(hpet_base_phys: u64, period: u64) -> Option<u8> {
+    let base = hpet_base_phys as *mut u8;
+    if period == 0 { return None; }
+    unsafe {
+        let cap = read64(base as *const u8, HPET_GENERAL_CAP_ID);
+        let legacy = read64(base as *const u8, HPET_GENERAL_CONFIG) & CFG_LEG_RT != 0;
+        let count = ((cap >> CAP_NUM_TIM_SHIFT) & 0x1F) as usize + 1;
+        let n = (0..count).rev().find(|&n| {
+            let cfg = read64(base as *const u8, HPET_TN_CONFIG + n * HPET_TN_STRIDE);
+            cfg & TN_PER_INT_CAP != 0 && cfg & (TN_INT_ENB | TN_FSB_EN) == 0 && !(legacy && n < 2)
+        })?;
+        let off = n * HPET_TN_STRIDE;
+        let cfg = read64(base as *const u8, HPET_TN_CONFIG + off) & !(TN_32MODE | TN_INT_ENB);
+        enable_hpet_counter(hpet_base_phys);
+        // VAL_SET: the first comparator write sets the first expiry, the second the period
+        write64(base, HPET_TN_CONFIG + off, cfg | TN_TYPE_PERIODIC | TN_VAL_SET | TN_INT_TYPE_LEVEL);
+        let now = read64(base as *const u8, HPET_MAIN_COUNTER);
+        write64(base, HPET_TN_COMPARATOR + off, now.wrapping_add(period));
+        write64(base, HPET_TN_COMPARATOR + off, period);
+        take_status(hpet_base_phys, n as u8);
+        Some(n as u8)
+    }
+}
+
+/// Return a timer set up by `setup_periodic` to one-shot mode.
+pub fn stop_periodic(hpet_base_phys: u64, timer: u8) {
+    let base = hpet_base_phys as *mut u8;
+    let off = timer as usize * HPET_TN_STRIDE;
+    unsafe {
+        let cfg = read64(base as *const u8, HPET_TN_CONFIG + off);
+        write64(base, HPET_TN_CONFIG + off, cfg & !(TN_TYPE_PERIODIC | TN_VAL_SET));
+    }
+    take_status(hpet_base_phys, timer);
+}
+
+/// Whether `timer` has fired since the last call, clearing its status bit.
+pub fn take_status(hpet_base_phys: u64, timer: u8) -> bool {
+    let base = hpet_base_phys as *mut u8;
+    let bit = 1u64 << timer;
+    unsafe {
+        if read64(base as *const u8, HPET_GENERAL_INT_STATUS) & bit == 0 { return false; }
+        write64(base, HPET_GENERAL_INT_STATUS, bit);
+    }
+    true
+}
+
 /// Read HPET main counter value.
 #[inline(always)]
 pub fn read_hpet_main_counter(hpet_base_phys: u64) -> u64 {
@@ -152,17 +227,9 @@ pub fn report_hpet(system_table: &mut SystemTable<Boot>) {
     let lang = crate::i18n::detect_lang(system_table);
     if let Some(info) = locate_hpet(system_table) {
         let hz = hpet_hz_from_period(info.period_fs);
-        let stdout = system_table.stdout();
-        let mut buf = [0u8; 96];
-        let mut n = 0;
-        // Prefix localized: "HPET: present, base=0x"
-        let _ = stdout.write_str(crate::i18n::t(lang, crate::i18n::key::HPET_PRESENT));
-        // Append hex base and frequency
-        n += format::u64_hex(info.base_phys, &mut buf[n..]);
-        for &b in b" freq=" { buf[n] = b; n += 1; }
-        n += crate::firmware::acpi::u32_to_dec((hz / 1_000_000) as u32, &mut buf[n..]);
-        for &b in b" MHz\r\n" { buf[n] = b; n += 1; }
-        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+        let args = [crate::i18n::Arg::Hex(info.base_phys), crate::i18n::Arg::Dec(hz / 1_000_000)];
+        let msg = crate::i18n::t_fmt(lang, crate::i18n::key::HPET_PRESENT, &args);
+        let _ = system_table.stdout().write_str(msg.as_str());
     } else {
         let stdout = system_table.stdout();
         let _ = stdout.write_str(crate::i18n::t(lang, crate::i18n::key::HPET_NOT_FOUND));
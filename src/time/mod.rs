@@ -3,17 +3,21 @@
 //! Minimal time utilities for UEFI bootstrap.
 //!
 //! Provides TSC calibration using UEFI Boot Services Stall as a fallback and
-//! an optional HPET-based calibration when available, along with a TSC-based
-//! busy wait.
+//! an optional HPET-based calibration when available. `clock` turns the best
+//! counter into a monotonic clock and `timer` builds a periodic tick and
+//! timer wheel on top. Code that measures or waits uses `clock`; the raw TSC
+//! is for guest-visible time and the scheduler's slices.
 
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
 
+pub mod clock;
 pub mod hpet;
+pub mod timer;
 
 /// Reads the Time Stamp Counter.
 #[inline(always)]
-pub(crate) fn rdtsc() -> u64 {
+pub fn rdtsc() -> u64 {
     let hi: u32;
     let lo: u32;
     unsafe { core::arch::asm!("rdtsc", out("edx") hi, out("eax") lo, options(nomem, nostack, preserves_flags)) };
@@ -58,14 +62,4 @@ pub fn tsc_hz() -> u64 {
     unsafe { TSC_HZ }
 }
 
-/// Busy-wait for approximately the specified microseconds using TSC.
-pub fn busy_wait_tsc(system_table: &SystemTable<Boot>, usec: u64, tsc_hz: u64) {
-    if tsc_hz == 0 { let _ = system_table.boot_services().stall(usec as usize); return; }
-    let start = rdtsc();
-    let target = start.wrapping_add(usec.saturating_mul(tsc_hz) / 1_000_000);
-    while rdtsc().wrapping_sub(start) < target.wrapping_sub(start) {
-        core::hint::spin_loop();
-    }
-}
-
 
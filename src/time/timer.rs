@@ -0,0 +1,347 @@
+#![allow(dead_code)]
+
+//! Periodic tick and timer wheel.
+//!
+//! `init` picks the tick source: an HPET comparator that firmware left
+//! unused, put in periodic mode at `TICK_HZ`, or the TSC when there is no
+//! HPET. The comparator runs with its interrupt disabled. Firmware owns the
+//! interrupt controllers while it is up, and the runtime phase polls
+//! anyway, so `poll` reads the main counter and works out how many ticks
+//! have passed. A late poll catches up instead of losing ticks. The
+//! comparator's status bit only confirms that the tick is running.
+//!
+//! Timers live in a hierarchical wheel of `LEVELS` levels of `SLOTS` slots.
+//! A timer due within `SLOTS` ticks sits in level 0 and one due within
+//! `SLOTS^2` ticks in level 1, and so on. Each time a level wraps, the next
+//! slot of the level above is cascaded down. Arming and cancelling cost
+//! O(1), and a poll costs one slot per elapsed tick plus the cascades.
+//!
+//! Callbacks run from `poll` on the polling CPU with the wheel unlocked:
+//! the CLI prompt in the boot phase and the serial loop in the runtime
+//! phase. They get the `arg` they were armed with and must be short. A
+//! callback that needs firmware sets a flag for code that has the system
+//! table.
+
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::util::spinlock::SpinLock;
+
+/// Tick rate
+pub const TICK_HZ: u64 = 1000;
+pub const SLOTS: usize = 64;
+const SLOT_BITS: u32 = 6;
+pub const LEVELS: usize = 4;
+/// Ticks the wheel spans; later expiries wait in the top level
+pub const SPAN: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+/// Timers armed at once
+pub const MAX_TIMERS: usize = 32;
+const NIL: u8 = 0xFF;
+
+/// Timer callback; gets the `arg` it was armed with.
+pub type Callback = fn(u64);
+
+/// Handle of an armed timer. Stale once a one-shot timer has fired or the
+/// timer was cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    index: u8,
+    gen: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceKind { None, Hpet, Tsc }
+
+impl SourceKind {
+    pub fn name(self) -> &'static str {
+        match self { SourceKind::None => "none", SourceKind::Hpet => "hpet", SourceKind::Tsc => "tsc" }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Source {
+    kind: SourceKind,
+    /// HPET MMIO base
+    base: u64,
+    /// HPET comparator in use
+    timer: u8,
+    /// Counter units per tick
+    per_tick: u64,
+    /// Counter value at the last whole tick
+    last: u64,
+    /// Counter width; a 32-bit HPET counter must be polled once per wrap
+    mask: u64,
+}
+
+impl Source {
+    const NONE: Source = Source { kind: SourceKind::None, base: 0, timer: 0, per_tick: 0, last: 0, mask: u64::MAX };
+
+    fn counter(&self) -> u64 {
+        match self.kind {
+            SourceKind::Hpet => super::hpet::read_hpet_main_counter(self.base) & self.mask,
+            SourceKind::Tsc => super::rdtsc(),
+            SourceKind::None => 0,
+        }
+    }
+
+    /// Whole ticks since the last call.
+    fn elapsed(&mut self) -> u64 {
+        if self.kind == SourceKind::None { return 0; }
+        let t = (self.counter().wrapping_sub(self.last) & self.mask) / self.per_tick;
+        self.last = self.last.wrapping_add(t * self.per_tick) & self.mask;
+        t
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    name: &'static str,
+    cb: Callback,
+    arg: u64,
+    /// Tick it is due at
+    expires: u64,
+    /// Ticks between firings; 0 for one-shot
+    period: u64,
+    fired: u64,
+    level: u8,
+    slot: u8,
+    next: u8,
+}
+
+/// Counters, for `timer stats`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Ticks counted from the source
+    pub ticks: u64,
+    pub polls: u64,
+    pub fired: u64,
+    /// Periods a periodic timer skipped because polling fell behind
+    pub missed: u64,
+    /// Largest delay from a timer's due tick to its callback, in ticks
+    pub max_lag: u64,
+    pub cascades: u64,
+    /// Polls that stopped with ticks left over, after firing `MAX_TIMERS`
+    pub deferred: u64,
+    /// Comparator periods seen through the HPET status bit
+    pub edges: u64,
+}
+
+/// One armed timer, for display.
+#[derive(Clone, Copy, Debug)]
+pub struct TimerInfo {
+    pub name: &'static str,
+    pub due_in: u64,
+    pub period: u64,
+    pub fired: u64,
+}
+
+struct Wheel {
+    timers: [Option<Timer>; MAX_TIMERS],
+    /// Bumped each time a timer slot is freed, so old ids go stale
+    gens: [u16; MAX_TIMERS],
+    heads: [[u8; SLOTS]; LEVELS],
+    /// Tick the wheel has been advanced to; trails `stats.ticks` after a
+    /// deferred poll
+    now: u64,
+    src: Source,
+    stats: Stats,
+}
+
+static WHEEL: SpinLock<Wheel> = SpinLock::new(Wheel {
+    timers: [None; MAX_TIMERS],
+    gens: [0; MAX_TIMERS],
+    heads: [[NIL; SLOTS]; LEVELS],
+    now: 0,
+    src: Source::NONE,
+    stats: Stats { ticks: 0, polls: 0, fired: 0, missed: 0, max_lag: 0, cascades: 0, deferred: 0, edges: 0 },
+});
+
+/// Guards against a callback polling again.
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+fn ms_to_ticks(ms: u64) -> u64 { ms.saturating_mul(TICK_HZ).saturating_add(999) / 1000 }
+
+pub fn ticks_to_ms(ticks: u64) -> u64 { ticks.saturating_mul(1000) / TICK_HZ }
+
+impl Wheel {
+    fn link(&mut self, i: usize) {
+        let now = self.now;
+        let Some(t) = self.timers[i].as_mut() else { return };
+        let at = t.expires.max(now).min(now + SPAN - 1);
+        let delta = at - now;
+        let level = (0..LEVELS).find(|&l| delta < 1 << (SLOT_BITS * (l as u32 + 1))).unwrap_or(LEVELS - 1);
+        let slot = ((at >> (SLOT_BITS * level as u32)) as usize) & (SLOTS - 1);
+        t.level = level as u8;
+        t.slot = slot as u8;
+        t.next = self.heads[level][slot];
+        self.heads[level][slot] = i as u8;
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let Some(t) = self.timers[i] else { return };
+        let head = &mut self.heads[t.level as usize][t.slot as usize];
+        if *head == i as u8 { *head = t.next; return; }
+        let mut k = *head;
+        while k != NIL {
+            let Some(n) = self.timers[k as usize].as_mut() else { return };
+            if n.next == i as u8 { n.next = t.next; return; }
+            k = n.next;
+        }
+    }
+
+    fn take(&mut self, level: usize, slot: usize) -> u8 {
+        core::mem::replace(&mut self.heads[level][slot], NIL)
+    }
+
+    /// Move `now` one tick on: cascade the levels that wrapped, then expire
+    /// level 0. Fired callbacks are appended to `out`.
+    fn step(&mut self, out: &mut [Option<(Callback, u64)>; 2 * MAX_TIMERS], n: &mut usize) {
+        self.now += 1;
+        let now = self.now;
+        for level in (1..LEVELS).rev() {
+            if now & ((1 << (SLOT_BITS * level as u32)) - 1) != 0 { continue; }
+            let mut k = self.take(level, ((now >> (SLOT_BITS * level as u32)) as usize) & (SLOTS - 1));
+            while k != NIL {
+                let next = self.timers[k as usize].map_or(NIL, |t| t.next);
+                self.link(k as usize);
+                self.stats.cascades += 1;
+                k = next;
+            }
+        }
+        let mut k = self.take(0, (now as usize) & (SLOTS - 1));
+        while k != NIL {
+            let i = k as usize;
+            let Some(mut t) = self.timers[i] else { break };
+            k = t.next;
+            // Clamped to the top level and not due yet
+            if t.expires > now { self.link(i); continue; }
+            out[*n] = Some((t.cb, t.arg));
+            *n += 1;
+            t.fired += 1;
+            self.stats.fired += 1;
+            self.stats.max_lag = self.stats.max_lag.max(self.stats.ticks - t.expires);
+            if t.period == 0 {
+                self.timers[i] = None;
+                self.gens[i] = self.gens[i].wrapping_add(1);
+                continue;
+            }
+            let mut next = t.expires + t.period;
+            if next <= self.stats.ticks {
+                let skipped = (self.stats.ticks - next) / t.period + 1;
+                next += skipped * t.period;
+                self.stats.missed += skipped;
+            }
+            t.expires = next;
+            self.timers[i] = Some(t);
+            self.link(i);
+        }
+    }
+
+    fn armed(&self) -> usize { self.timers.iter().flatten().count() }
+}
+
+/// Start the tick: an unused HPET comparator if there is one, else the TSC.
+/// Brings up the clock first, which calibrates the TSC. Returns the source
+/// in use.
+pub fn init(system_table: &SystemTable<Boot>) -> SourceKind {
+    let cur = WHEEL.lock(|w| w.src.kind);
+    if cur != SourceKind::None { return cur; }
+    let _ = super::clock::init(system_table);
+    let hpet = super::hpet::locate_hpet(system_table).and_then(|info| {
+        let per_tick = super::hpet::hpet_hz_from_period(info.period_fs) / TICK_HZ;
+        let timer = super::hpet::setup_periodic(info.base_phys, per_tick)?;
+        let mask = if super::hpet::counter_is_64bit(info.base_phys) { u64::MAX } else { u32::MAX as u64 };
+        let last = super::hpet::read_hpet_main_counter(info.base_phys) & mask;
+        Some(Source { kind: SourceKind::Hpet, base: info.base_phys, timer, per_tick, last, mask })
+    });
+    let per_tick = super::tsc_hz() / TICK_HZ;
+    let src = match hpet {
+        Some(s) => s,
+        None if per_tick != 0 => Source { kind: SourceKind::Tsc, per_tick, last: super::rdtsc(), ..Source::NONE },
+        None => Source::NONE,
+    };
+    WHEEL.lock(|w| w.src = src);
+    src.kind
+}
+
+/// Arm `cb` to run `after_ms` from now, then every `period_ms` unless that
+/// is 0.
+pub fn add(name: &'static str, after_ms: u64, period_ms: u64, cb: Callback, arg: u64) -> Result<TimerId, &'static str> {
+    WHEEL.lock(|w| {
+        let i = w.timers.iter().position(|t| t.is_none()).ok_or("timer: all timers in use")?;
+        let expires = w.stats.ticks.max(w.now) + ms_to_ticks(after_ms).max(1);
+        let period = if period_ms == 0 { 0 } else { ms_to_ticks(period_ms).max(1) };
+        w.timers[i] = Some(Timer { name, cb, arg, expires, period, fired: 0, level: 0, slot: 0, next: NIL });
+        w.link(i);
+        Ok(TimerId { index: i as u8, gen: w.gens[i] })
+    })
+}
+
+/// Disarm a timer. False if it already fired (one-shot) or was cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    WHEEL.lock(|w| {
+        let i = id.index as usize;
+        if i >= MAX_TIMERS || w.gens[i] != id.gen || w.timers[i].is_none() { return false; }
+        w.unlink(i);
+        w.timers[i] = None;
+        w.gens[i] = w.gens[i].wrapping_add(1);
+        true
+    })
+}
+
+/// Count the ticks that passed and run what fell due. Returns the number of
+/// callbacks run.
+pub fn poll() -> usize {
+    if POLLING.swap(true, Ordering::Acquire) { return 0; }
+    let mut out = [None; 2 * MAX_TIMERS];
+    let mut n = 0usize;
+    WHEEL.lock(|w| {
+        w.stats.polls += 1;
+        w.stats.ticks += w.src.elapsed();
+        if w.src.kind == SourceKind::Hpet && super::hpet::take_status(w.src.base, w.src.timer) { w.stats.edges += 1; }
+        if w.armed() == 0 { w.now = w.stats.ticks; return; }
+        // A step fires each timer at most once, so stopping at MAX_TIMERS
+        // keeps `out` from overflowing
+        while w.now < w.stats.ticks && n < MAX_TIMERS { w.step(&mut out, &mut n); }
+        if w.now < w.stats.ticks { w.stats.deferred += 1; }
+    });
+    for &(cb, arg) in out[..n].iter().flatten() { cb(arg); }
+    POLLING.store(false, Ordering::Release);
+    n
+}
+
+pub fn source() -> SourceKind { WHEEL.lock(|w| w.src.kind) }
+
+pub fn stats() -> Stats { WHEEL.lock(|w| w.stats) }
+
+/// Armed timers, in slot order.
+pub fn for_each(mut f: impl FnMut(TimerInfo)) {
+    let (timers, now) = WHEEL.lock(|w| (w.timers, w.stats.ticks));
+    for t in timers.iter().flatten() {
+        f(TimerInfo { name: t.name, due_in: t.expires.saturating_sub(now), period: t.period, fired: t.fired });
+    }
+}
+
+/// `timer stats` text, one line at a time, each ending in CRLF.
+pub fn render(mut out: impl FnMut(&str)) {
+    let (src, st, armed) = WHEEL.lock(|w| (w.src, w.stats, w.armed()));
+    let mut buf = [0u8; 256];
+    let mut line = crate::util::format::BufWriter::new(&mut buf);
+    let _ = write!(line, "timer: source={}", src.kind.name());
+    if src.kind == SourceKind::Hpet { let _ = write!(line, " comparator={}", src.timer); }
+    let _ = write!(line, " tick={}Hz ticks={} polls={} fired={} missed={} max_lag={}ms cascades={} deferred={}",
+        TICK_HZ, st.ticks, st.polls, st.fired, st.missed, ticks_to_ms(st.max_lag), st.cascades, st.deferred);
+    if src.kind == SourceKind::Hpet { let _ = write!(line, " edges={}", st.edges); }
+    let _ = write!(line, " armed={}/{}\r\n", armed, MAX_TIMERS);
+    out(line.as_str());
+    for_each(|t| {
+        let mut buf = [0u8; 128];
+        let mut line = crate::util::format::BufWriter::new(&mut buf);
+        let _ = write!(line, "  {:<16}", t.name);
+        if t.period == 0 { let _ = line.write_str(" once     "); } else { let _ = write!(line, " every {}ms", ticks_to_ms(t.period)); }
+        let _ = write!(line, " next in {}ms fired={}\r\n", ticks_to_ms(t.due_in), t.fired);
+        out(line.as_str());
+    });
+}
@@ -0,0 +1,303 @@
+#![allow(dead_code)]
+
+//! Boot measurements and quotes.
+//!
+//! At boot the hypervisor measures itself into PCR 8 and its configuration
+//! into PCR 9, logging every extend in `eventlog`. PCR 8 gets two digests:
+//! the `zerovisor.efi` file exactly as it sits on the boot volume (what
+//! `sha256sum` on the build output prints), and the read-only sections of
+//! the loaded PE image with every base relocation rewritten to its RVA, so
+//! it does not depend on where firmware placed the image. Configuration is
+//! the load options plus each persisted settings variable, bound to its
+//! name. A separator closes both PCRs.
+//!
+//! The result is kept as a `BootMeasurement` for later consumers (quotes,
+//! reports) to pick up with `boot_measurement`.
+//!
+//! A quote signs the selected PCRs and a caller nonce; together with the
+//! event log it lets a remote verifier check what this host booted.
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use uefi::table::runtime::VariableVendor;
+use uefi::proto::device_path::DevicePathNodeEnum;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::file::{File, FileAttribute, FileMode};
+use uefi::CStr16;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::eventlog::{self, EV_IPL, EV_PLATFORM_CONFIG_FLAGS, EV_SEPARATOR};
+use crate::util::sha256::{Sha256, DIGEST_LEN};
+use crate::util::spinlock::SpinLock;
+
+/// PCR holding the hypervisor image
+pub const PCR_IMAGE: u32 = 8;
+/// PCR holding the hypervisor configuration
+pub const PCR_CONFIG: u32 = 9;
+/// PCRs a default quote covers
+pub const DEFAULT_QUOTE_MASK: u32 = (1 << PCR_IMAGE) | (1 << PCR_CONFIG);
+/// Longest image path kept in a `BootMeasurement`
+pub const PATH_MAX: usize = 96;
+
+/// Settings variables that change how the hypervisor behaves.
+const CONFIG_VARS: [(&str, &CStr16); 7] = [
+    ("ZerovisorLang", uefi::cstr16!("ZerovisorLang")),
+    ("ZerovisorMigCfg", uefi::cstr16!("ZerovisorMigCfg")),
+    ("ZerovisorMigNet", uefi::cstr16!("ZerovisorMigNet")),
+    ("ZerovisorIommuState", uefi::cstr16!("ZerovisorIommuState")),
+    ("ZerovisorCluster", uefi::cstr16!("ZerovisorCluster")),
+    ("ZerovisorAuditCfg", uefi::cstr16!("ZerovisorAuditCfg")),
+    ("ZerovisorVmRtc", uefi::cstr16!("ZerovisorVmRtc")),
+];
+/// Largest settings variable; IOMMU state spans several and is read by `iommu::blob`.
+const VAR_MAX: usize = 4096;
+
+const SCN_CNT_CODE: u32 = 0x20;
+const SCN_CNT_INITIALIZED_DATA: u32 = 0x40;
+const SCN_MEM_WRITE: u32 = 0x8000_0000;
+const REL_BASED_DIR64: u16 = 10;
+const PAGE: usize = 4096;
+
+static MEASURED: AtomicBool = AtomicBool::new(false);
+static BOOT: SpinLock<Option<BootMeasurement>> = SpinLock::new(None);
+
+fn rd16(p: *const u8, off: usize) -> u16 { unsafe { core::ptr::read_unaligned(p.add(off) as *const u16) } }
+fn rd32(p: *const u8, off: usize) -> u32 { unsafe { core::ptr::read_unaligned(p.add(off) as *const u32) } }
+fn rd64(p: *const u8, off: usize) -> u64 { unsafe { core::ptr::read_unaligned(p.add(off) as *const u64) } }
+
+/// Walk the DIR64 relocations of the page at `page_rva` and the page
+/// before it, calling `f` with each target RVA.
+fn relocs_near(base: *const u8, reloc: (usize, usize), page_rva: usize, mut f: impl FnMut(usize)) {
+    let (mut p, end) = (reloc.0, reloc.0 + reloc.1);
+    while p + 8 <= end {
+        let (blk, size) = (rd32(base, p) as usize, rd32(base, p + 4) as usize);
+        if size < 8 { break; }
+        if blk == page_rva || blk + PAGE == page_rva {
+            for i in 0..(size - 8) / 2 {
+                let e = rd16(base, p + 8 + i * 2);
+                if e >> 12 == REL_BASED_DIR64 { f(blk + (e & 0xFFF) as usize); }
+            }
+        }
+        p += size;
+    }
+}
+
+/// Load-address independent digest of the read-only sections of the PE
+/// image at `base`.
+fn image_digest(base: *const u8, size: usize) -> Option<[u8; DIGEST_LEN]> {
+    if size < 0x40 || rd16(base, 0) != 0x5A4D { return None; }
+    let pe = rd32(base, 0x3C) as usize;
+    if pe + 24 > size || rd32(base, pe) != 0x0000_4550 { return None; }
+    let sections = rd16(base, pe + 6) as usize;
+    let opt = pe + 24;
+    let opt_len = rd16(base, pe + 20) as usize;
+    if rd16(base, opt) != 0x20B { return None; } // PE32+
+    let reloc = if rd32(base, opt + 108) > 5 { (rd32(base, opt + 152) as usize, rd32(base, opt + 156) as usize) } else { (0, 0) };
+    let reloc = if reloc.0 + reloc.1 <= size { reloc } else { (0, 0) };
+    let mut h = Sha256::new();
+    let mut buf = [0u8; PAGE];
+    for s in 0..sections {
+        let sh = opt + opt_len + s * 40;
+        if sh + 40 > size { return None; }
+        let chars = rd32(base, sh + 36);
+        if chars & SCN_MEM_WRITE != 0 || chars & (SCN_CNT_CODE | SCN_CNT_INITIALIZED_DATA) == 0 { continue; }
+        let rva = rd32(base, sh + 12) as usize;
+        let len = core::cmp::min(rd32(base, sh + 8) as usize, rd32(base, sh + 16) as usize);
+        if rva + len > size { return None; }
+        h.update(unsafe { core::slice::from_raw_parts(base.add(sh), 8) }); // name
+        h.update(&(rva as u32).to_le_bytes());
+        let mut off = 0;
+        while off < len {
+            let at = rva + off;
+            let page_rva = at & !(PAGE - 1);
+            let n = core::cmp::min(PAGE - (at - page_rva), len - off);
+            unsafe { core::ptr::copy_nonoverlapping(base.add(at), buf.as_mut_ptr(), n); }
+            // Replace each relocated pointer (possibly straddling the chunk) by its RVA.
+            relocs_near(base, reloc, page_rva, |r| {
+                if r + 8 <= at || r >= at + n || r + 8 > size { return; }
+                let v = rd64(base, r).wrapping_sub(base as u64).to_le_bytes();
+                for (k, b) in v.iter().enumerate() {
+                    let x = r + k;
+                    if x >= at && x < at + n { buf[x - at] = *b; }
+                }
+            });
+            h.update(&buf[..n]);
+            off += n;
+        }
+    }
+    Some(h.finish())
+}
+
+/// Path of the file this image was loaded from, taken from the file path
+/// nodes of its device path. Returns 0 if there is none or it is not ASCII.
+fn image_path(li: &LoadedImage, out: &mut [u8; PATH_MAX]) -> usize {
+    let Some(dp) = li.file_path() else { return 0 };
+    let mut n = 0;
+    for node in dp.node_iter() {
+        let Ok(DevicePathNodeEnum::MediaFilePath(f)) = node.as_enum() else { continue };
+        let name = f.path_name();
+        if n > 0 && out[n - 1] != b'\\' {
+            if n >= PATH_MAX { return 0; }
+            out[n] = b'\\'; n += 1;
+        }
+        for i in 0..name.len() {
+            let c = name.get(i).unwrap_or(0);
+            if c == 0 { break; }
+            if n >= PATH_MAX || !(0x20..0x7F).contains(&c) { return 0; }
+            out[n] = if c == b'/' as u16 { b'\\' } else { c as u8 }; n += 1;
+        }
+    }
+    n
+}
+
+/// SHA-256 of the whole file at `path` on the volume this image came from.
+fn file_digest(system_table: &SystemTable<Boot>, path: &str) -> Option<[u8; DIGEST_LEN]> {
+    let mut name_buf = [0u16; PATH_MAX + 1];
+    let name = CStr16::from_str_with_buf(path, &mut name_buf).ok()?;
+    let bs = system_table.boot_services();
+    let mut fs = bs.get_image_file_system(bs.image_handle()).ok()?;
+    let mut root = fs.open_volume().ok()?;
+    let mut file = root.open(name, FileMode::Read, FileAttribute::empty()).ok()?.into_regular_file()?;
+    let mut h = Sha256::new();
+    let mut buf = [0u8; PAGE];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => h.update(&buf[..n]),
+            Err(_) => return None,
+        }
+    }
+    Some(h.finish())
+}
+
+/// Summary of `measure_boot`.
+#[derive(Clone, Copy, Debug)]
+pub struct BootMeasurement {
+    pub events: u32,
+    /// Every event also reached the TPM
+    pub extended: bool,
+    /// Digest of the `zerovisor.efi` file, if it could be read back
+    pub file: Option<[u8; DIGEST_LEN]>,
+    path: [u8; PATH_MAX],
+    path_len: usize,
+    /// Relocation-normalized digest of the image in memory
+    pub image: [u8; DIGEST_LEN],
+}
+
+impl BootMeasurement {
+    /// Where the image was loaded from, e.g. `\EFI\BOOT\BOOTX64.EFI`
+    pub fn path(&self) -> &str { core::str::from_utf8(&self.path[..self.path_len]).unwrap_or("") }
+}
+
+/// The measurement taken at boot, once `measure_boot` has run.
+pub fn boot_measurement() -> Option<BootMeasurement> { BOOT.lock(|b| *b) }
+
+/// Measure the hypervisor file, image and configuration. Runs once; later
+/// calls return `None`.
+pub fn measure_boot(system_table: &SystemTable<Boot>) -> Option<BootMeasurement> {
+    if MEASURED.swap(true, Ordering::AcqRel) { return None; }
+    let _ = super::init(system_table);
+    let bs = system_table.boot_services();
+    let mut image = [0u8; DIGEST_LEN];
+    let mut path = [0u8; PATH_MAX]; let mut path_len = 0;
+    let mut opts = [0u8; 512]; let mut opts_len = 0;
+    // Scoped: the file system lookup below opens LoadedImage on the same handle.
+    if let Ok(li) = unsafe { bs.open_protocol_exclusive::<LoadedImage>(bs.image_handle()) } {
+        let (base, size) = li.info();
+        image = image_digest(base as *const u8, size as usize).unwrap_or([0; DIGEST_LEN]);
+        path_len = image_path(&li, &mut path);
+        if let Some(o) = li.load_options_as_bytes() {
+            opts_len = o.len().min(opts.len());
+            opts[..opts_len].copy_from_slice(&o[..opts_len]);
+        }
+    }
+    let file = match core::str::from_utf8(&path[..path_len]) {
+        Ok(p) if !p.is_empty() => file_digest(system_table, p),
+        _ => None,
+    };
+    let mut m = BootMeasurement { events: 0, extended: true, file, path, path_len, image };
+    let (mut events, mut extended) = (0u32, true);
+    let mut note = |r: Result<bool, &'static str>| match r {
+        Ok(x) => { events += 1; extended &= x; }
+        Err(_) => extended = false,
+    };
+    if let Some(d) = file { note(eventlog::record(PCR_IMAGE, EV_IPL, &d, &path[..path_len])); }
+    note(eventlog::record(PCR_IMAGE, EV_IPL, &image, b"zerovisor image"));
+    note(eventlog::measure(PCR_CONFIG, EV_IPL, &opts[..opts_len], b"load options").map(|r| r.1));
+    let rs = system_table.runtime_services();
+    let mut buf = [0u8; VAR_MAX];
+    for (name, var) in CONFIG_VARS {
+        let iommu;
+        let data: &[u8] = if name == "ZerovisorIommuState" {
+            iommu = crate::iommu::blob::read_var(rs).unwrap_or_default();
+            &iommu
+        } else {
+            match rs.get_variable(var, &VariableVendor::GLOBAL_VARIABLE, &mut buf) { Ok((d, _)) => d, Err(_) => &[] }
+        };
+        let mut h = Sha256::new();
+        h.update(name.as_bytes()); h.update(&[0]); h.update(data);
+        note(eventlog::record(PCR_CONFIG, EV_PLATFORM_CONFIG_FLAGS, &h.finish(), name.as_bytes()));
+    }
+    for pcr in [PCR_IMAGE, PCR_CONFIG] { note(eventlog::measure(pcr, EV_SEPARATOR, &[0; 4], &[0; 4]).map(|r| r.1)); }
+    m.events = events;
+    m.extended = extended;
+    BOOT.lock(|b| *b = Some(m));
+    Some(m)
+}
+
+/// Measure at boot and print a one-line result.
+pub fn measure_boot_and_report(system_table: &mut SystemTable<Boot>) {
+    let Some(m) = measure_boot(system_table) else { return };
+    let mut out = [0u8; 160 + PATH_MAX]; let mut n = 0;
+    for &b in b"tpm: measured boot events=" { out[n] = b; n += 1; }
+    n += crate::util::format::u64_dec(m.events as u64, &mut out[n..]);
+    for &b in b" extended=" { out[n] = b; n += 1; }
+    for &b in if m.extended { b"yes".as_slice() } else { b"no".as_slice() } { out[n] = b; n += 1; }
+    if let Some(f) = m.file {
+        for &b in b" file=" { out[n] = b; n += 1; }
+        n += hex(&f[..8], &mut out[n..]);
+        for &b in b".." { out[n] = b; n += 1; }
+    }
+    for &b in b" image=" { out[n] = b; n += 1; }
+    n += hex(&m.image[..8], &mut out[n..]);
+    for &b in b".." { out[n] = b; n += 1; }
+    if m.path_len != 0 {
+        for &b in b" (" { out[n] = b; n += 1; }
+        for &b in &m.path[..m.path_len] { out[n] = b; n += 1; }
+        out[n] = b')'; n += 1;
+    }
+    for &b in b"\r\n" { out[n] = b; n += 1; }
+    let _ = system_table.stdout().write_str(core::str::from_utf8(&out[..n]).unwrap_or("\r\n"));
+}
+
+/// Lower-case hex of `d` into `out`; returns bytes written.
+pub fn hex(d: &[u8], out: &mut [u8]) -> usize {
+    const H: &[u8; 16] = b"0123456789abcdef";
+    let mut n = 0;
+    for &b in d {
+        if n + 2 > out.len() { break; }
+        out[n] = H[(b >> 4) as usize]; out[n + 1] = H[(b & 15) as usize]; n += 2;
+    }
+    n
+}
+
+/// Replay the log for every PCR in `mask` and compare with the TPM.
+/// Returns the mask of PCRs that do not match (or could not be read).
+pub fn verify_log(mask: u32) -> u32 {
+    let mut bad = 0;
+    for pcr in 0..24 {
+        if mask & (1 << pcr) == 0 { continue; }
+        match super::cmd::pcr_read(pcr) {
+            Ok(v) if v == eventlog::replay(pcr) => {}
+            _ => bad |= 1 << pcr,
+        }
+    }
+    bad
+}
+
+/// Quote `pcr_mask` (default: the hypervisor PCRs) over `nonce`.
+pub fn quote(system_table: &SystemTable<Boot>, nonce: &[u8], pcr_mask: u32) -> Result<super::cmd::Quote, &'static str> {
+    super::init(system_table)?;
+    super::cmd::quote(nonce, if pcr_mask == 0 { DEFAULT_QUOTE_MASK } else { pcr_mask })
+}
@@ -0,0 +1,558 @@
+#![allow(dead_code)]
+
+//! TPM 2.0 command marshaling.
+//!
+//! Only the handful of commands needed by the hypervisor are implemented:
+//! startup, PCR extend/read (SHA-256 bank), NV index define/undefine/read/
+//! write under owner authorization, and sealing of small secrets to a
+//! transient ECC storage primary, optionally bound to a PCR policy.
+//! Quotes are signed by a transient ECDSA P-256 attestation key derived from
+//! the endorsement seed, so the same key comes back on every boot.
+//! All authorizations use the empty-password session (TPM_RS_PW) except
+//! PCR-bound unseal, which replays TPM2_PolicyPCR in a policy session.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use super::transport::HDR_LEN;
+
+// Structure tags
+const ST_NO_SESSIONS: u16 = 0x8001;
+const ST_SESSIONS: u16 = 0x8002;
+
+// Command codes
+const CC_NV_UNDEFINE_SPACE: u32 = 0x0122;
+const CC_NV_DEFINE_SPACE: u32 = 0x012A;
+const CC_CREATE_PRIMARY: u32 = 0x0131;
+const CC_NV_WRITE: u32 = 0x0137;
+const CC_STARTUP: u32 = 0x0144;
+const CC_NV_READ: u32 = 0x014E;
+const CC_CREATE: u32 = 0x0153;
+const CC_LOAD: u32 = 0x0157;
+const CC_UNSEAL: u32 = 0x015E;
+const CC_FLUSH_CONTEXT: u32 = 0x0165;
+const CC_START_AUTH_SESSION: u32 = 0x0176;
+const CC_PCR_READ: u32 = 0x017E;
+const CC_POLICY_PCR: u32 = 0x017F;
+const CC_PCR_EXTEND: u32 = 0x0182;
+const CC_POLICY_GET_DIGEST: u32 = 0x0189;
+const CC_QUOTE: u32 = 0x0158;
+
+// Handles
+const RH_OWNER: u32 = 0x4000_0001;
+const RH_NULL: u32 = 0x4000_0007;
+const RS_PW: u32 = 0x4000_0009;
+const RH_ENDORSEMENT: u32 = 0x4000_000B;
+
+// Algorithms
+const ALG_AES: u16 = 0x0006;
+const ALG_KEYEDHASH: u16 = 0x0008;
+const ALG_SHA256: u16 = 0x000B;
+const ALG_NULL: u16 = 0x0010;
+const ALG_ECDSA: u16 = 0x0018;
+const ALG_ECC: u16 = 0x0023;
+const ALG_CFB: u16 = 0x0043;
+const ECC_NIST_P256: u16 = 0x0003;
+
+// Session types
+const SE_POLICY: u8 = 0x01;
+const SE_TRIAL: u8 = 0x03;
+
+// Object attributes
+const OA_FIXED_TPM: u32 = 1 << 1;
+const OA_FIXED_PARENT: u32 = 1 << 4;
+const OA_SENSITIVE_DATA_ORIGIN: u32 = 1 << 5;
+const OA_USER_WITH_AUTH: u32 = 1 << 6;
+const OA_NO_DA: u32 = 1 << 10;
+const OA_RESTRICTED: u32 = 1 << 16;
+const OA_DECRYPT: u32 = 1 << 17;
+const OA_SIGN: u32 = 1 << 18;
+
+/// NV attributes: owner/auth read+write, no dictionary-attack lockout.
+pub const NV_ATTR_DEFAULT: u32 = (1 << 1) | (1 << 2) | (1 << 17) | (1 << 18) | (1 << 25);
+
+/// TPM_RC_INITIALIZE: TPM2_Startup already issued by firmware.
+const RC_INITIALIZE: u32 = 0x0100;
+
+/// SHA-256 digest length.
+pub const DIGEST_LEN: usize = 32;
+/// Largest NV chunk transferred per command (well below TPM_PT_NV_BUFFER_MAX).
+const NV_CHUNK: usize = 256;
+/// Maximum secret size accepted by `seal` (TPM2B_SENSITIVE_DATA limit).
+pub const SEAL_MAX: usize = 128;
+/// Largest caller nonce (qualifyingData) accepted by `quote`.
+pub const NONCE_MAX: usize = 32;
+/// Room for a marshaled TPMS_ATTEST of a single-bank quote.
+pub const ATTEST_MAX: usize = 256;
+/// P-256 coordinate / signature component size.
+pub const ECC_LEN: usize = 32;
+
+const BUF_LEN: usize = 1024;
+
+/// Response code of the most recent failed command (0 when none).
+static LAST_RC: AtomicU32 = AtomicU32::new(0);
+
+/// Return the last non-success TPM response code.
+pub fn last_rc() -> u32 { LAST_RC.load(Ordering::Relaxed) }
+
+// ---- marshaling helpers ----
+
+struct Enc<'a> { b: &'a mut [u8], n: usize }
+
+impl<'a> Enc<'a> {
+    fn new(b: &'a mut [u8], tag: u16, cc: u32) -> Self {
+        let mut e = Enc { b, n: 0 };
+        e.u16(tag); e.u32(0); e.u32(cc);
+        e
+    }
+    fn u8(&mut self, v: u8) { if self.n < self.b.len() { self.b[self.n] = v; } self.n += 1; }
+    fn u16(&mut self, v: u16) { self.u8((v >> 8) as u8); self.u8(v as u8); }
+    fn u32(&mut self, v: u32) { self.u16((v >> 16) as u16); self.u16(v as u16); }
+    fn bytes(&mut self, d: &[u8]) { for &x in d { self.u8(x); } }
+    fn tpm2b(&mut self, d: &[u8]) { self.u16(d.len() as u16); self.bytes(d); }
+    /// Empty-password authorization area for one handle.
+    fn pw_auth(&mut self) { self.session_auth(RS_PW); }
+    fn session_auth(&mut self, handle: u32) {
+        self.u32(9); self.u32(handle); self.u16(0); self.u8(0); self.u16(0);
+    }
+    /// TPML_PCR_SELECTION with a single SHA-256 bank.
+    fn pcr_sel(&mut self, mask: u32) {
+        self.u32(1); self.u16(ALG_SHA256); self.u8(3);
+        self.u8(mask as u8); self.u8((mask >> 8) as u8); self.u8((mask >> 16) as u8);
+    }
+    /// Reserve a u16 size slot and return its offset for `patch16`.
+    fn size16_slot(&mut self) -> usize { let at = self.n; self.u16(0); at }
+    fn patch16(&mut self, at: usize) {
+        let len = (self.n - at - 2) as u16;
+        if at + 1 < self.b.len() { self.b[at] = (len >> 8) as u8; self.b[at + 1] = len as u8; }
+    }
+    fn finish(self) -> Result<usize, &'static str> {
+        if self.n > self.b.len() { return Err("tpm: command too large"); }
+        let n = self.n as u32;
+        self.b[2] = (n >> 24) as u8; self.b[3] = (n >> 16) as u8; self.b[4] = (n >> 8) as u8; self.b[5] = n as u8;
+        Ok(self.n)
+    }
+}
+
+struct Dec<'a> { b: &'a [u8], p: usize }
+
+impl<'a> Dec<'a> {
+    fn new(b: &'a [u8], p: usize) -> Self { Dec { b, p } }
+    fn u8(&mut self) -> Option<u8> { let v = *self.b.get(self.p)?; self.p += 1; Some(v) }
+    fn u16(&mut self) -> Option<u16> { Some(((self.u8()? as u16) << 8) | self.u8()? as u16) }
+    fn u32(&mut self) -> Option<u32> { Some(((self.u16()? as u32) << 16) | self.u16()? as u32) }
+    fn skip(&mut self, n: usize) -> Option<()> { if self.p + n > self.b.len() { return None; } self.p += n; Some(()) }
+    fn tpm2b(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        if self.p + len > self.b.len() { return None; }
+        let s = &self.b[self.p..self.p + len];
+        self.p += len;
+        Some(s)
+    }
+    /// TPM2B including its size prefix (as needed for re-submission).
+    fn tpm2b_raw(&mut self) -> Option<&'a [u8]> {
+        let start = self.p;
+        self.tpm2b()?;
+        Some(&self.b[start..self.p])
+    }
+}
+
+/// Submit a command and check the response code. Returns the response length.
+fn exec(cmd: &[u8], rsp: &mut [u8]) -> Result<usize, &'static str> {
+    let n = super::submit(cmd, rsp)?;
+    let rc = ((rsp[6] as u32) << 24) | ((rsp[7] as u32) << 16) | ((rsp[8] as u32) << 8) | (rsp[9] as u32);
+    if rc != 0 {
+        LAST_RC.store(rc, Ordering::Relaxed);
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_RC_ERRS).inc();
+        return Err("tpm: command failed (see rc)");
+    }
+    Ok(n)
+}
+
+fn flush(handle: u32) {
+    let mut c = [0u8; 16]; let mut r = [0u8; 32];
+    let mut e = Enc::new(&mut c, ST_NO_SESSIONS, CC_FLUSH_CONTEXT);
+    e.u32(handle);
+    if let Ok(n) = e.finish() { let _ = exec(&c[..n], &mut r); }
+}
+
+// ---- commands ----
+
+/// TPM2_Startup(CLEAR). TPM_RC_INITIALIZE is treated as success.
+pub fn startup() -> Result<(), &'static str> {
+    let mut c = [0u8; 16]; let mut r = [0u8; 32];
+    let mut e = Enc::new(&mut c, ST_NO_SESSIONS, CC_STARTUP);
+    e.u16(0); // TPM_SU_CLEAR
+    let n = e.finish()?;
+    match exec(&c[..n], &mut r) {
+        Ok(_) => Ok(()),
+        Err(_) if last_rc() == RC_INITIALIZE => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// TPM2_PCR_Extend with a single SHA-256 digest.
+pub fn pcr_extend(pcr: u32, digest: &[u8; DIGEST_LEN]) -> Result<(), &'static str> {
+    if pcr >= 24 { return Err("tpm: pcr index out of range"); }
+    let mut c = [0u8; 128]; let mut r = [0u8; 64];
+    let mut e = Enc::new(&mut c, ST_SESSIONS, CC_PCR_EXTEND);
+    e.u32(pcr);
+    e.pw_auth();
+    e.u32(1); e.u16(ALG_SHA256); e.bytes(digest);
+    let n = e.finish()?;
+    exec(&c[..n], &mut r)?;
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_PCR_EXTENDS).inc();
+    Ok(())
+}
+
+/// TPM2_PCR_Read of one SHA-256 PCR.
+pub fn pcr_read(pcr: u32) -> Result<[u8; DIGEST_LEN], &'static str> {
+    if pcr >= 24 { return Err("tpm: pcr index out of range"); }
+    let mut c = [0u8; 32]; let mut r = [0u8; 128];
+    let mut e = Enc::new(&mut c, ST_NO_SESSIONS, CC_PCR_READ);
+    e.pcr_sel(1u32 << pcr);
+    let n = e.finish()?;
+    let rn = exec(&c[..n], &mut r)?;
+    let mut d = Dec::new(&r[..rn], HDR_LEN);
+    let bad = "tpm: malformed PCR_Read response";
+    d.u32().ok_or(bad)?; // pcrUpdateCounter
+    let sels = d.u32().ok_or(bad)?;
+    for _ in 0..sels { d.u16().ok_or(bad)?; let sz = d.u8().ok_or(bad)? as usize; d.skip(sz).ok_or(bad)?; }
+    if d.u32().ok_or(bad)? == 0 { return Err("tpm: pcr bank not allocated"); }
+    let dig = d.tpm2b().ok_or(bad)?;
+    if dig.len() != DIGEST_LEN { return Err(bad); }
+    let mut out = [0u8; DIGEST_LEN];
+    out.copy_from_slice(dig);
+    Ok(out)
+}
+
+/// TPM2_NV_DefineSpace under owner hierarchy with an empty index auth value.
+pub fn nv_define(index: u32, size: u16, attrs: u32) -> Result<(), &'static str> {
+    let mut c = [0u8; 96]; let mut r = [0u8; 64];
+    let mut e = Enc::new(&mut c, ST_SESSIONS, CC_NV_DEFINE_SPACE);
+    e.u32(RH_OWNER);
+    e.pw_auth();
+    e.u16(0); // auth
+    let at = e.size16_slot();
+    e.u32(index); e.u16(ALG_SHA256); e.u32(attrs); e.u16(0); e.u16(size);
+    e.patch16(at);
+    let n = e.finish()?;
+    exec(&c[..n], &mut r)?;
+    Ok(())
+}
+
+/// TPM2_NV_UndefineSpace under owner hierarchy.
+pub fn nv_undefine(index: u32) -> Result<(), &'static str> {
+    let mut c = [0u8; 48]; let mut r = [0u8; 64];
+    let mut e = Enc::new(&mut c, ST_SESSIONS, CC_NV_UNDEFINE_SPACE);
+    e.u32(RH_OWNER); e.u32(index);
+    e.pw_auth();
+    let n = e.finish()?;
+    exec(&c[..n], &mut r)?;
+    Ok(())
+}
+
+/// TPM2_NV_Write, split into chunks as needed.
+pub fn nv_write(index: u32, offset: u16, data: &[u8]) -> Result<(), &'static str> {
+    let mut c = [0u8; BUF_LEN]; let mut r = [0u8; 64];
+    let mut done = 0usize;
+    while done < data.len() {
+        let take = core::cmp::min(NV_CHUNK, data.len() - done);
+        let mut e = Enc::new(&mut c, ST_SESSIONS, CC_NV_WRITE);
+        e.u32(RH_OWNER); e.u32(index);
+        e.pw_auth();
+        e.tpm2b(&data[done..done + take]);
+        e.u16(offset.wrapping_add(done as u16));
+        let n = e.finish()?;
+        exec(&c[..n], &mut r)?;
+        done += take;
+    }
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_NV_WRITES).inc();
+    Ok(())
+}
+
+/// TPM2_NV_Read into `out`, split into chunks as needed. Returns bytes read.
+pub fn nv_read(index: u32, offset: u16, out: &mut [u8]) -> Result<usize, &'static str> {
+    let mut c = [0u8; 64]; let mut r = [0u8; BUF_LEN];
+    let mut done = 0usize;
+    while done < out.len() {
+        let want = core::cmp::min(NV_CHUNK, out.len() - done);
+        let mut e = Enc::new(&mut c, ST_SESSIONS, CC_NV_READ);
+        e.u32(RH_OWNER); e.u32(index);
+        e.pw_auth();
+        e.u16(want as u16); e.u16(offset.wrapping_add(done as u16));
+        let n = e.finish()?;
+        let rn = exec(&c[..n], &mut r)?;
+        let mut d = Dec::new(&r[..rn], HDR_LEN);
+        d.u32().ok_or("tpm: malformed NV_Read response")?; // parameterSize
+        let got = d.tpm2b().ok_or("tpm: malformed NV_Read response")?;
+        if got.is_empty() { break; }
+        let k = core::cmp::min(got.len(), out.len() - done);
+        out[done..done + k].copy_from_slice(&got[..k]);
+        done += k;
+    }
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_NV_READS).inc();
+    Ok(done)
+}
+
+// ---- sealing ----
+
+/// Create the transient ECC P-256 storage primary under the owner hierarchy.
+fn create_storage_primary() -> Result<u32, &'static str> {
+    let mut c = [0u8; 256]; let mut r = [0u8; BUF_LEN];
+    let mut e = Enc::new(&mut c, ST_SESSIONS, CC_CREATE_PRIMARY);
+    e.u32(RH_OWNER);
+    e.pw_auth();
+    e.u16(4); e.u16(0); e.u16(0); // inSensitive: empty userAuth and data
+    let at = e.size16_slot();
+    e.u16(ALG_ECC); e.u16(ALG_SHA256);
+    e.u32(OA_FIXED_TPM | OA_FIXED_PARENT | OA_SENSITIVE_DATA_ORIGIN | OA_USER_WITH_AUTH | OA_NO_DA | OA_RESTRICTED | OA_DECRYPT);
+    e.u16(0); // authPolicy
+    e.u16(ALG_AES); e.u16(128); e.u16(ALG_CFB); // symmetric
+    e.u16(ALG_NULL); e.u16(ECC_NIST_P256); e.u16(ALG_NULL); // scheme, curve, kdf
+    e.u16(0); e.u16(0); // unique.x, unique.y
+    e.patch16(at);
+    e.u16(0); // outsideInfo
+    e.u32(0); // creationPCR
+    let n = e.finish()?;
+    let rn = exec(&c[..n], &mut r)?;
+    Dec::new(&r[..rn], HDR_LEN).u32().ok_or("tpm: malformed CreatePrimary response")
+}
+
+/// Start an unbound, unsalted policy (or trial) session.
+fn start_policy_session(kind: u8) -> Result<u32, &'static str> {
+    let mut c = [0u8; 64]; let mut r = [0u8; 96];
+    let mut e = Enc::new(&mut c, ST_NO_SESSIONS, CC_START_AUTH_SESSION);
+    e.u32(RH_NULL); e.u32(RH_NULL);
+    // nonceCaller: uniqueness only, derived from the TSC
+    let t = crate::time::rdtsc();
+    e.u16(16);
+    for i in 0..16u32 { e.u8((t.rotate_left(i * 5) ^ (i as u64 * 0x9E)) as u8); }
+    e.u16(0); // encryptedSalt
+    e.u8(kind);
+    e.u16(ALG_NULL); // symmetric
+    e.u16(ALG_SHA256);
+    let n = e.finish()?;
+    let rn = exec(&c[..n], &mut r)?;
+    Dec::new(&r[..rn], HDR_LEN).u32().ok_or("tpm: malformed StartAuthSession response")
+}
+
+fn policy_pcr(session: u32, mask: u32) -> Result<(), &'static str> {
+    let mut c = [0u8; 48]; let mut r = [0u8; 32];
+    let mut e = Enc::new(&mut c, ST_NO_SESSIONS, CC_POLICY_PCR);
+    e.u32(session);
+    e.u16(0); // pcrDigest: use current PCR values
+    e.pcr_sel(mask);
+    let n = e.finish()?;
+    exec(&c[..n], &mut r)?;
+    Ok(())
+}
+
+/// Compute the TPM2_PolicyPCR digest for `mask` via a trial session.
+fn pcr_policy_digest(mask: u32, out: &mut [u8; DIGEST_LEN]) -> Result<(), &'static str> {
+    let s = start_policy_session(SE_TRIAL)?;
+    let res = (|| {
+        policy_pcr(s, mask)?;
+        let mut c = [0u8; 16]; let mut r = [0u8; 64];
+        let mut e = Enc::new(&mut c, ST_NO_SESSIONS, CC_POLICY_GET_DIGEST);
+        e.u32(s);
+        let n = e.finish()?;
+        let rn = exec(&c[..n], &mut r)?;
+        let d = Dec::new(&r[..rn], HDR_LEN).tpm2b().ok_or("tpm: malformed PolicyGetDigest response")?;
+        if d.len() != DIGEST_LEN { return Err("tpm: unexpected policy digest size"); }
+        out.copy_from_slice(d);
+        Ok(())
+    })();
+    flush(s);
+    res
+}
+
+/// Seal `secret` to the storage primary. When `pcr_mask` is non-zero the
+/// object can only be unsealed while the selected SHA-256 PCRs hold their
+/// current values. The opaque blob written to `blob` is
+/// `mask(u32 BE) || TPM2B_PRIVATE || TPM2B_PUBLIC`; returns its length.
+pub fn seal(secret: &[u8], pcr_mask: u32, blob: &mut [u8]) -> Result<usize, &'static str> {
+    if secret.is_empty() || secret.len() > SEAL_MAX { return Err("tpm: secret size invalid"); }
+    if pcr_mask >> 24 != 0 { return Err("tpm: pcr mask out of range"); }
+    let mut policy = [0u8; DIGEST_LEN];
+    if pcr_mask != 0 { pcr_policy_digest(pcr_mask, &mut policy)?; }
+    let parent = create_storage_primary()?;
+    let res = (|| {
+        let mut c = [0u8; BUF_LEN]; let mut r = [0u8; BUF_LEN];
+        let mut e = Enc::new(&mut c, ST_SESSIONS, CC_CREATE);
+        e.u32(parent);
+        e.pw_auth();
+        let at = e.size16_slot();
+        e.u16(0); e.tpm2b(secret);
+        e.patch16(at);
+        let at = e.size16_slot();
+        e.u16(ALG_KEYEDHASH); e.u16(ALG_SHA256);
+        let mut oa = OA_FIXED_TPM | OA_FIXED_PARENT | OA_NO_DA;
+        if pcr_mask == 0 { oa |= OA_USER_WITH_AUTH; }
+        e.u32(oa);
+        if pcr_mask != 0 { e.tpm2b(&policy); } else { e.u16(0); }
+        e.u16(ALG_NULL); // keyedHash scheme
+        e.u16(0); // unique
+        e.patch16(at);
+        e.u16(0); e.u32(0); // outsideInfo, creationPCR
+        let n = e.finish()?;
+        let rn = exec(&c[..n], &mut r)?;
+        let mut d = Dec::new(&r[..rn], HDR_LEN);
+        let bad = "tpm: malformed Create response";
+        d.u32().ok_or(bad)?; // parameterSize
+        let private = d.tpm2b_raw().ok_or(bad)?;
+        let public = d.tpm2b_raw().ok_or(bad)?;
+        let total = 4 + private.len() + public.len();
+        if total > blob.len() { return Err("tpm: blob buffer too small"); }
+        blob[0] = (pcr_mask >> 24) as u8; blob[1] = (pcr_mask >> 16) as u8; blob[2] = (pcr_mask >> 8) as u8; blob[3] = pcr_mask as u8;
+        blob[4..4 + private.len()].copy_from_slice(private);
+        blob[4 + private.len()..total].copy_from_slice(public);
+        Ok(total)
+    })();
+    flush(parent);
+    if res.is_ok() { crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_SEALS).inc(); }
+    res
+}
+
+/// Unseal a blob produced by `seal` into `out`. Returns the secret length.
+pub fn unseal(blob: &[u8], out: &mut [u8]) -> Result<usize, &'static str> {
+    if blob.len() < 8 { return Err("tpm: blob too short"); }
+    let mask = ((blob[0] as u32) << 24) | ((blob[1] as u32) << 16) | ((blob[2] as u32) << 8) | (blob[3] as u32);
+    let mut d = Dec::new(blob, 4);
+    let private = d.tpm2b_raw().ok_or("tpm: blob malformed")?;
+    let public = d.tpm2b_raw().ok_or("tpm: blob malformed")?;
+    let parent = create_storage_primary()?;
+    let item = (|| {
+        let mut c = [0u8; BUF_LEN]; let mut r = [0u8; 128];
+        let mut e = Enc::new(&mut c, ST_SESSIONS, CC_LOAD);
+        e.u32(parent);
+        e.pw_auth();
+        e.bytes(private); e.bytes(public);
+        let n = e.finish()?;
+        let rn = exec(&c[..n], &mut r)?;
+        Dec::new(&r[..rn], HDR_LEN).u32().ok_or("tpm: malformed Load response")
+    })();
+    let item = match item { Ok(h) => h, Err(e) => { flush(parent); return Err(e); } };
+    let res = (|| {
+        let auth = if mask != 0 {
+            let s = start_policy_session(SE_POLICY)?;
+            if let Err(e) = policy_pcr(s, mask) { flush(s); return Err(e); }
+            s
+        } else { RS_PW };
+        let mut c = [0u8; 48]; let mut r = [0u8; 256];
+        let mut e = Enc::new(&mut c, ST_SESSIONS, CC_UNSEAL);
+        e.u32(item);
+        // continueSession is clear, so the policy session is consumed here
+        e.session_auth(auth);
+        let n = e.finish()?;
+        let rn = exec(&c[..n], &mut r);
+        let rn = match rn { Ok(v) => v, Err(e) => { if auth != RS_PW { flush(auth); } return Err(e); } };
+        let mut d = Dec::new(&r[..rn], HDR_LEN);
+        d.u32().ok_or("tpm: malformed Unseal response")?;
+        let s = d.tpm2b().ok_or("tpm: malformed Unseal response")?;
+        if s.len() > out.len() { return Err("tpm: output buffer too small"); }
+        out[..s.len()].copy_from_slice(s);
+        Ok(s.len())
+    })();
+    flush(item);
+    flush(parent);
+    if res.is_ok() { crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_UNSEALS).inc(); }
+    res
+}
+
+// ---- quotes ----
+
+/// TPM2_Quote output plus the public part of the key that signed it.
+#[derive(Clone, Copy)]
+pub struct Quote {
+    /// Marshaled TPMS_ATTEST (the signed structure)
+    pub attest: [u8; ATTEST_MAX],
+    pub attest_len: usize,
+    /// ECDSA signature over SHA-256(attest), big-endian, left-padded
+    pub sig_r: [u8; ECC_LEN],
+    pub sig_s: [u8; ECC_LEN],
+    /// Attestation key public point
+    pub ak_x: [u8; ECC_LEN],
+    pub ak_y: [u8; ECC_LEN],
+}
+
+fn pad_into(dst: &mut [u8; ECC_LEN], src: &[u8]) -> Option<()> {
+    if src.len() > ECC_LEN { return None; }
+    dst[ECC_LEN - src.len()..].copy_from_slice(src);
+    Some(())
+}
+
+/// Create the transient ECDSA P-256 attestation key (restricted signing)
+/// under the endorsement hierarchy. Returns the handle and public point.
+fn create_attestation_key() -> Result<(u32, [u8; ECC_LEN], [u8; ECC_LEN]), &'static str> {
+    let mut c = [0u8; 256]; let mut r = [0u8; BUF_LEN];
+    let mut e = Enc::new(&mut c, ST_SESSIONS, CC_CREATE_PRIMARY);
+    e.u32(RH_ENDORSEMENT);
+    e.pw_auth();
+    e.u16(4); e.u16(0); e.u16(0); // inSensitive: empty userAuth and data
+    let at = e.size16_slot();
+    e.u16(ALG_ECC); e.u16(ALG_SHA256);
+    e.u32(OA_FIXED_TPM | OA_FIXED_PARENT | OA_SENSITIVE_DATA_ORIGIN | OA_USER_WITH_AUTH | OA_NO_DA | OA_RESTRICTED | OA_SIGN);
+    e.u16(0); // authPolicy
+    e.u16(ALG_NULL); // symmetric
+    e.u16(ALG_ECDSA); e.u16(ALG_SHA256); // scheme
+    e.u16(ECC_NIST_P256); e.u16(ALG_NULL); // curve, kdf
+    e.u16(0); e.u16(0); // unique.x, unique.y
+    e.patch16(at);
+    e.u16(0); // outsideInfo
+    e.u32(0); // creationPCR
+    let n = e.finish()?;
+    let rn = exec(&c[..n], &mut r)?;
+    let bad = "tpm: malformed CreatePrimary response";
+    let mut d = Dec::new(&r[..rn], HDR_LEN);
+    let handle = d.u32().ok_or(bad)?;
+    let parse = |d: &mut Dec| -> Option<([u8; ECC_LEN], [u8; ECC_LEN])> {
+        d.u32()?; // parameterSize
+        d.u16()?; // TPM2B_PUBLIC size
+        d.u16()?; d.u16()?; d.u32()?; // type, nameAlg, objectAttributes
+        d.tpm2b()?; // authPolicy
+        d.u16()?; // symmetric (NULL)
+        d.u16()?; d.u16()?; // scheme, hashAlg
+        d.u16()?; d.u16()?; // curve, kdf (NULL)
+        let (mut x, mut y) = ([0u8; ECC_LEN], [0u8; ECC_LEN]);
+        pad_into(&mut x, d.tpm2b()?)?;
+        pad_into(&mut y, d.tpm2b()?)?;
+        Some((x, y))
+    };
+    match parse(&mut d) {
+        Some((x, y)) => Ok((handle, x, y)),
+        None => { flush(handle); Err(bad) }
+    }
+}
+
+/// TPM2_Quote over the SHA-256 PCRs in `pcr_mask`, binding `nonce` as
+/// qualifyingData.
+pub fn quote(nonce: &[u8], pcr_mask: u32) -> Result<Quote, &'static str> {
+    if nonce.len() > NONCE_MAX { return Err("tpm: nonce too long"); }
+    if pcr_mask == 0 || pcr_mask >> 24 != 0 { return Err("tpm: pcr mask out of range"); }
+    let (ak, ak_x, ak_y) = create_attestation_key()?;
+    let res = (|| {
+        let mut c = [0u8; 128]; let mut r = [0u8; BUF_LEN];
+        let mut e = Enc::new(&mut c, ST_SESSIONS, CC_QUOTE);
+        e.u32(ak);
+        e.pw_auth();
+        e.tpm2b(nonce);
+        e.u16(ALG_NULL); // inScheme: the key's own
+        e.pcr_sel(pcr_mask);
+        let n = e.finish()?;
+        let rn = exec(&c[..n], &mut r)?;
+        let bad = "tpm: malformed Quote response";
+        let mut d = Dec::new(&r[..rn], HDR_LEN);
+        d.u32().ok_or(bad)?; // parameterSize
+        let attest = d.tpm2b().ok_or(bad)?;
+        if attest.len() > ATTEST_MAX { return Err("tpm: attest structure too large"); }
+        if d.u16().ok_or(bad)? != ALG_ECDSA { return Err("tpm: unexpected signature scheme"); }
+        d.u16().ok_or(bad)?; // hash
+        let mut q = Quote { attest: [0; ATTEST_MAX], attest_len: attest.len(), sig_r: [0; ECC_LEN], sig_s: [0; ECC_LEN], ak_x, ak_y };
+        q.attest[..attest.len()].copy_from_slice(attest);
+        pad_into(&mut q.sig_r, d.tpm2b().ok_or(bad)?).ok_or(bad)?;
+        pad_into(&mut q.sig_s, d.tpm2b().ok_or(bad)?).ok_or(bad)?;
+        Ok(q)
+    })();
+    flush(ak);
+    if res.is_ok() { crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_QUOTES).inc(); }
+    res
+}
@@ -0,0 +1,152 @@
+#![allow(dead_code)]
+
+//! TCG event log of the measurements the hypervisor makes.
+//!
+//! The log uses the crypto-agile format of the TCG PC Client Platform
+//! Firmware Profile: a legacy `TCG_PCR_EVENT` header carrying the
+//! "Spec ID Event03" structure, followed by `TCG_PCR_EVENT2` records with a
+//! single SHA-256 digest each. A verifier replays the records to recompute
+//! the PCRs and checks them against a quote.
+//!
+//! Records are kept even when no TPM is present (the extend is then counted
+//! as missed), so the log always shows what would have been measured.
+
+use crate::util::sha256::{sha256, DIGEST_LEN};
+use crate::util::spinlock::SpinLock;
+
+// Event types (TCG PC Client PFP, section 10.4.1)
+pub const EV_NO_ACTION: u32 = 0x0000_0003;
+pub const EV_SEPARATOR: u32 = 0x0000_0004;
+pub const EV_EVENT_TAG: u32 = 0x0000_0006;
+pub const EV_PLATFORM_CONFIG_FLAGS: u32 = 0x0000_000A;
+pub const EV_IPL: u32 = 0x0000_000D;
+
+const ALG_SHA256: u16 = 0x000B;
+/// Log capacity in bytes
+pub const LOG_MAX: usize = 16 * 1024;
+/// Longest event data kept per record
+pub const EVENT_DATA_MAX: usize = 256;
+/// Size of the Spec ID header record
+const SPEC_ID_LEN: usize = 4 + 4 + 20 + 4 + 33;
+/// Fixed part of a TCG_PCR_EVENT2 with one SHA-256 digest
+const EVENT2_HDR: usize = 4 + 4 + 4 + 2 + DIGEST_LEN + 4;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub events: u32,
+    /// Records that did not fit the log
+    pub dropped: u32,
+    /// Records whose PCR extend failed or had no TPM to go to
+    pub unextended: u32,
+}
+
+struct Log { buf: [u8; LOG_MAX], len: usize, stats: Stats }
+
+static LOG: SpinLock<Log> = SpinLock::new(Log { buf: [0; LOG_MAX], len: 0, stats: Stats { events: 0, dropped: 0, unextended: 0 } });
+
+fn put(l: &mut Log, b: &[u8]) { l.buf[l.len..l.len + b.len()].copy_from_slice(b); l.len += b.len(); }
+
+/// Write the Spec ID header the first time anything is logged.
+fn header(l: &mut Log) {
+    if l.len != 0 { return; }
+    put(l, &0u32.to_le_bytes()); // pcrIndex
+    put(l, &EV_NO_ACTION.to_le_bytes());
+    put(l, &[0u8; 20]); // SHA-1 digest field, unused
+    put(l, &33u32.to_le_bytes()); // eventSize
+    put(l, b"Spec ID Event03\0");
+    put(l, &0u32.to_le_bytes()); // platformClass: client
+    put(l, &[0, 2, 0, 2]); // specVersionMinor, Major, specErrata, uintnSize (u64)
+    put(l, &1u32.to_le_bytes()); // numberOfAlgorithms
+    put(l, &ALG_SHA256.to_le_bytes());
+    put(l, &(DIGEST_LEN as u16).to_le_bytes());
+    put(l, &[0]); // vendorInfoSize
+}
+
+/// Address and length of the log as it stands. The buffer is static, so the
+/// address stays valid after Boot Services are gone.
+pub fn region() -> (u64, usize) { LOG.lock(|l| (l.buf.as_ptr() as u64, l.len)) }
+
+/// Append a record for an already computed digest and extend `pcr` with it.
+/// Returns whether the PCR was extended.
+pub fn record(pcr: u32, event_type: u32, digest: &[u8; DIGEST_LEN], event: &[u8]) -> Result<bool, &'static str> {
+    if pcr >= 24 { return Err("tpm: pcr index out of range"); }
+    let event = &event[..event.len().min(EVENT_DATA_MAX)];
+    let stored = LOG.lock(|l| {
+        header(l);
+        if l.len + EVENT2_HDR + event.len() > LOG_MAX { l.stats.dropped += 1; return false; }
+        put(l, &pcr.to_le_bytes());
+        put(l, &event_type.to_le_bytes());
+        put(l, &1u32.to_le_bytes());
+        put(l, &ALG_SHA256.to_le_bytes());
+        put(l, digest);
+        put(l, &(event.len() as u32).to_le_bytes());
+        put(l, event);
+        l.stats.events += 1;
+        true
+    });
+    // A measurement that cannot be logged must not reach the PCR either,
+    // or the log could never be replayed to match it again.
+    if !stored { return Err("tpm: event log full"); }
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_EVENTS).inc();
+    let extended = match super::info() {
+        Some(i) if i.started => super::cmd::pcr_extend(pcr, digest).is_ok(),
+        _ => false,
+    };
+    if !extended { LOG.lock(|l| l.stats.unextended += 1); }
+    Ok(extended)
+}
+
+/// Hash `data`, log it with `event` as the description and extend `pcr`.
+/// Returns the digest and whether the PCR was extended.
+pub fn measure(pcr: u32, event_type: u32, data: &[u8], event: &[u8]) -> Result<([u8; DIGEST_LEN], bool), &'static str> {
+    let d = sha256(data);
+    record(pcr, event_type, &d, event).map(|x| (d, x))
+}
+
+/// One parsed TCG_PCR_EVENT2 record.
+pub struct Event<'a> {
+    pub pcr: u32,
+    pub event_type: u32,
+    pub digest: [u8; DIGEST_LEN],
+    pub data: &'a [u8],
+}
+
+fn rd32(b: &[u8], at: usize) -> u32 { u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]) }
+
+/// Iterate the records after the Spec ID header.
+pub fn for_each(mut f: impl FnMut(&Event)) {
+    LOG.lock(|l| {
+        let b = &l.buf[..l.len];
+        let mut p = if l.len >= SPEC_ID_LEN { SPEC_ID_LEN } else { l.len };
+        while p + EVENT2_HDR <= b.len() {
+            let mut digest = [0u8; DIGEST_LEN];
+            digest.copy_from_slice(&b[p + 14..p + 14 + DIGEST_LEN]);
+            let size = rd32(b, p + 14 + DIGEST_LEN) as usize;
+            let data = &b[p + EVENT2_HDR..p + EVENT2_HDR + size];
+            f(&Event { pcr: rd32(b, p), event_type: rd32(b, p + 4), digest, data });
+            p += EVENT2_HDR + size;
+        }
+    });
+}
+
+/// Recompute `pcr` from the log: PCR' = SHA-256(PCR || digest), from zero.
+pub fn replay(pcr: u32) -> [u8; DIGEST_LEN] {
+    let mut v = [0u8; DIGEST_LEN];
+    for_each(|e| {
+        if e.pcr != pcr { return; }
+        let mut s = crate::util::sha256::Sha256::new();
+        s.update(&v);
+        s.update(&e.digest);
+        v = s.finish();
+    });
+    v
+}
+
+/// Hand the raw log (header included) to `f`, `chunk` bytes at a time.
+pub fn raw(chunk: usize, mut f: impl FnMut(usize, &[u8])) {
+    LOG.lock(|l| { for (i, c) in l.buf[..l.len].chunks(chunk.max(1)).enumerate() { f(i * chunk.max(1), c); } });
+}
+
+pub fn len() -> usize { LOG.lock(|l| l.len) }
+
+pub fn stats() -> Stats { LOG.lock(|l| l.stats) }
@@ -0,0 +1,179 @@
+#![allow(dead_code)]
+
+//! TPM 2.0 support.
+//!
+//! Discovery uses the ACPI TPM2 table (falling back to probing the standard
+//! PC Client MMIO window at 0xFED40000). The register interface (FIFO or CRB)
+//! is taken from `INTF_ID` and commands are submitted synchronously at
+//! locality 0. Command marshaling for PCR, NV storage, sealing and quotes
+//! lives in `cmd`; `eventlog` and `attest` build measured boot on top.
+
+pub mod transport;
+pub mod cmd;
+pub mod eventlog;
+pub mod attest;
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+use uefi::cstr16;
+use core::fmt::Write as _;
+use crate::util::spinlock::SpinLock;
+
+/// Standard PC Client TPM MMIO base (locality 0).
+pub const TPM_DEFAULT_BASE: u64 = 0xFED4_0000;
+
+/// ACPI TPM2 start methods we understand.
+const START_METHOD_ACPI: u32 = 2;
+const START_METHOD_TIS: u32 = 6;
+const START_METHOD_CRB: u32 = 7;
+const START_METHOD_CRB_ACPI: u32 = 8;
+
+/// Register interface exposed by the TPM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interface { None, Tis, Crb }
+
+/// Discovered TPM instance.
+#[derive(Clone, Copy, Debug)]
+pub struct TpmInfo {
+    pub iface: Interface,
+    /// Locality 0 register base (physical, identity-mapped)
+    pub base: u64,
+    /// ACPI start method (0 when the table is absent)
+    pub start_method: u32,
+    pub did_vid: u32,
+    /// TPM2_Startup has been issued (or was already done by firmware)
+    pub started: bool,
+}
+
+/// Minimal ACPI TPM2 table (subset).
+#[repr(C, packed)]
+struct Tpm2Table {
+    header: crate::firmware::acpi::SdtHeader,
+    platform_class: u16,
+    _reserved: u16,
+    control_area: u64,
+    start_method: u32,
+}
+
+static STATE: SpinLock<TpmInfo> = SpinLock::new(TpmInfo { iface: Interface::None, base: 0, start_method: 0, did_vid: 0, started: false });
+
+fn iface_from_intf_id(id: u32) -> Interface {
+    if id == 0xFFFF_FFFF { return Interface::None; }
+    match id & 0xF {
+        0x0 | 0xF => Interface::Tis,
+        0x1 => Interface::Crb,
+        _ => Interface::None,
+    }
+}
+
+/// Locate the TPM without issuing any commands.
+pub fn probe(system_table: &SystemTable<Boot>) -> Option<TpmInfo> {
+    let mut base = TPM_DEFAULT_BASE;
+    let mut start_method = 0u32;
+    if let Some(hdr) = crate::firmware::acpi::find_table(system_table, *b"TPM2") {
+        let t = hdr as *const crate::firmware::acpi::SdtHeader as *const Tpm2Table;
+        let t = unsafe { &*t };
+        start_method = t.start_method;
+        let ctrl = t.control_area;
+        // For CRB the control area sits at +0x40 of the locality page.
+        if (start_method == START_METHOD_CRB || start_method == START_METHOD_CRB_ACPI) && ctrl != 0 {
+            base = ctrl & !0xFFFu64;
+        }
+        if start_method == START_METHOD_ACPI { return None; }
+    }
+    let iface = iface_from_intf_id(transport::read_intf_id(base as usize));
+    if iface == Interface::None { return None; }
+    let did_vid = transport::read_did_vid(base as usize);
+    Some(TpmInfo { iface, base, start_method, did_vid, started: false })
+}
+
+/// Probe and issue TPM2_Startup(CLEAR). Already-initialized TPMs are accepted.
+pub fn init(system_table: &SystemTable<Boot>) -> Result<TpmInfo, &'static str> {
+    let cur = STATE.lock(|s| *s);
+    if cur.started { return Ok(cur); }
+    let Some(mut info) = probe(system_table) else {
+        crate::hv::features::register("tpm", "tpm2", crate::hv::features::Status::Absent, "no TPM 2.0 device");
+        return Err("tpm: not present");
+    };
+    STATE.lock(|s| *s = info);
+    if let Err(e) = cmd::startup() {
+        crate::hv::features::register("tpm", "tpm2", crate::hv::features::Status::Disabled, "TPM2_Startup failed");
+        return Err(e);
+    }
+    crate::hv::features::register("tpm", "tpm2", crate::hv::features::Status::Available, "");
+    info.started = true;
+    STATE.lock(|s| s.started = true);
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_INIT_OK).inc();
+    Ok(info)
+}
+
+/// Current TPM state, if a device has been probed.
+pub fn info() -> Option<TpmInfo> {
+    let s = STATE.lock(|s| *s);
+    if s.iface == Interface::None { None } else { Some(s) }
+}
+
+/// Submit a marshaled command and receive the raw response.
+pub fn submit(command: &[u8], response: &mut [u8]) -> Result<usize, &'static str> {
+    let s = STATE.lock(|s| *s);
+    crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_CMDS).inc();
+    let r = match s.iface {
+        Interface::Tis => transport::tis_transmit(s.base as usize, command, response),
+        Interface::Crb => transport::crb_transmit(s.base as usize, command, response),
+        Interface::None => Err("tpm: not initialized"),
+    };
+    if r.is_err() { crate::obs::metrics::Counter::new(&crate::obs::metrics::TPM_XPORT_ERRS).inc(); }
+    r
+}
+
+/// Print TPM presence and interface details.
+pub fn report(system_table: &mut SystemTable<Boot>) {
+    let found = match info() { Some(i) => Some(i), None => probe(system_table) };
+    let stdout = system_table.stdout();
+    let i = match found {
+        Some(i) => i,
+        None => { let _ = stdout.write_str("tpm: not present\r\n"); return; }
+    };
+    let mut buf = [0u8; 128]; let mut n = 0;
+    for &b in b"tpm: iface=" { buf[n] = b; n += 1; }
+    let s: &[u8] = match i.iface { Interface::Tis => b"tis", Interface::Crb => b"crb", Interface::None => b"none" };
+    for &b in s { buf[n] = b; n += 1; }
+    for &b in b" base=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex(i.base, &mut buf[n..]);
+    for &b in b" vid=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex((i.did_vid & 0xFFFF) as u64, &mut buf[n..]);
+    for &b in b" did=0x" { buf[n] = b; n += 1; }
+    n += crate::util::format::u64_hex((i.did_vid >> 16) as u64, &mut buf[n..]);
+    for &b in b" start=" { buf[n] = b; n += 1; }
+    n += crate::util::format::u32_dec(i.start_method, &mut buf[n..]);
+    for &b in b" started=" { buf[n] = b; n += 1; }
+    let st: &[u8] = if i.started { b"yes" } else { b"no" };
+    for &b in st { buf[n] = b; n += 1; }
+    buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
+    let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+}
+
+// ---- Sealed blob persistence (UEFI variable) ----
+
+const SEALED_VAR_MAX: usize = 512;
+
+/// Seal `secret` (optionally to `pcr_mask`) and keep the blob in a boot-services variable.
+pub fn seal_store(system_table: &SystemTable<Boot>, secret: &[u8], pcr_mask: u32) -> Result<(), &'static str> {
+    let mut blob = [0u8; SEALED_VAR_MAX];
+    let len = cmd::seal(secret, pcr_mask, &mut blob)?;
+    let rs = system_table.runtime_services();
+    rs.set_variable(cstr16!("ZerovisorTpmSealed"), &VariableVendor::GLOBAL_VARIABLE, VariableAttributes::BOOTSERVICE_ACCESS, &blob[..len])
+        .map_err(|_| "tpm: blob save failed")
+}
+
+/// Load the stored blob and unseal it into `out`. Returns the secret length.
+pub fn unseal_load(system_table: &SystemTable<Boot>, out: &mut [u8]) -> Result<usize, &'static str> {
+    let mut blob = [0u8; SEALED_VAR_MAX];
+    let rs = system_table.runtime_services();
+    let len = match rs.get_variable(cstr16!("ZerovisorTpmSealed"), &VariableVendor::GLOBAL_VARIABLE, &mut blob) {
+        Ok((d, _)) => d.len(),
+        Err(_) => return Err("tpm: no sealed blob"),
+    };
+    cmd::unseal(&blob[..len], out)
+}
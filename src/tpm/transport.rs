@@ -0,0 +1,205 @@
+#![allow(dead_code)]
+
+//! TPM 2.0 MMIO transports at locality 0.
+//!
+//! Two register interfaces are supported, both driven purely by polling:
+//! - PC Client FIFO (TIS 1.3 / PTP FIFO): command bytes are streamed through
+//!   the data FIFO honoring `burstCount`.
+//! - Command Response Buffer (CRB): the command is copied into the buffer
+//!   advertised by the control area and started via `CTRL_START`.
+//!
+//! Callers pass fully marshaled big-endian command bytes and receive the raw
+//! response (header included). Response codes are interpreted by `tpm::cmd`.
+
+use core::ptr::{read_volatile, write_volatile};
+
+// ---- TIS (FIFO) registers, locality 0 ----
+const TIS_ACCESS: usize = 0x00;
+const TIS_STS: usize = 0x18;
+const TIS_DATA_FIFO: usize = 0x24;
+const TIS_INTF_ID: usize = 0x30;
+const TIS_DID_VID: usize = 0xF00;
+
+const ACCESS_VALID: u8 = 1 << 7;
+const ACCESS_ACTIVE_LOCALITY: u8 = 1 << 5;
+const ACCESS_REQUEST_USE: u8 = 1 << 1;
+
+const STS_VALID: u32 = 1 << 7;
+const STS_COMMAND_READY: u32 = 1 << 6;
+const STS_GO: u32 = 1 << 5;
+const STS_DATA_AVAIL: u32 = 1 << 4;
+const STS_EXPECT: u32 = 1 << 3;
+
+// ---- CRB registers, locality 0 ----
+const CRB_LOC_CTRL: usize = 0x08;
+const CRB_LOC_STS: usize = 0x0C;
+const CRB_INTF_ID: usize = 0x30;
+const CRB_CTRL_REQ: usize = 0x40;
+const CRB_CTRL_STS: usize = 0x44;
+const CRB_CTRL_CANCEL: usize = 0x48;
+const CRB_CTRL_START: usize = 0x4C;
+const CRB_CTRL_CMD_SIZE: usize = 0x58;
+const CRB_CTRL_CMD_LADDR: usize = 0x5C;
+const CRB_CTRL_CMD_HADDR: usize = 0x60;
+const CRB_CTRL_RSP_SIZE: usize = 0x64;
+const CRB_CTRL_RSP_ADDR: usize = 0x68;
+
+const CRB_LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+const CRB_LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+const CRB_LOC_STS_GRANTED: u32 = 1 << 0;
+const CRB_CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CRB_CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+const CRB_CTRL_STS_ERROR: u32 = 1 << 0;
+
+/// Short register handshakes (locality, commandReady): TIS timeout B.
+const TIMEOUT_SHORT_US: u64 = 2_000_000;
+/// Command execution budget; primary key creation can take seconds.
+const TIMEOUT_CMD_US: u64 = 10_000_000;
+
+/// TPM command/response header length (tag, size, code).
+pub const HDR_LEN: usize = 10;
+
+#[inline(always)]
+fn rd8(addr: usize) -> u8 { unsafe { read_volatile(addr as *const u8) } }
+#[inline(always)]
+fn rd32(addr: usize) -> u32 { unsafe { read_volatile(addr as *const u32) } }
+#[inline(always)]
+fn wr8(addr: usize, v: u8) { unsafe { write_volatile(addr as *mut u8, v) } }
+#[inline(always)]
+fn wr32(addr: usize, v: u32) { unsafe { write_volatile(addr as *mut u32, v) } }
+
+/// Poll `done` until it returns true or `timeout_us` elapses.
+/// Falls back to a bounded spin count while the clock cannot run yet.
+fn poll(timeout_us: u64, mut done: impl FnMut() -> bool) -> bool {
+    use crate::time::clock;
+    if clock::hz() != 0 { return clock::spin_until(clock::Deadline::after_us(timeout_us), done); }
+    let mut spins: u64 = 0;
+    loop {
+        if done() { return true; }
+        spins += 1;
+        if spins >= timeout_us.saturating_mul(100) { return false; }
+        core::hint::spin_loop();
+    }
+}
+
+/// Big-endian size field from a response header, if present.
+#[inline(always)]
+fn hdr_size(hdr: &[u8]) -> usize {
+    ((hdr[2] as usize) << 24) | ((hdr[3] as usize) << 16) | ((hdr[4] as usize) << 8) | (hdr[5] as usize)
+}
+
+/// Read the interface identifier register (low 32 bits; same offset for TIS and CRB).
+pub fn read_intf_id(base: usize) -> u32 { rd32(base + TIS_INTF_ID) }
+
+/// Read TIS DID/VID (vendor in low 16 bits). CRB exposes the same at 0xF00 on most parts.
+pub fn read_did_vid(base: usize) -> u32 { rd32(base + TIS_DID_VID) }
+
+// ---- TIS ----
+
+fn tis_request_locality(base: usize) -> bool {
+    let acc = rd8(base + TIS_ACCESS);
+    if (acc & (ACCESS_VALID | ACCESS_ACTIVE_LOCALITY)) == (ACCESS_VALID | ACCESS_ACTIVE_LOCALITY) { return true; }
+    wr8(base + TIS_ACCESS, ACCESS_REQUEST_USE);
+    poll(TIMEOUT_SHORT_US, || {
+        let a = rd8(base + TIS_ACCESS);
+        (a & (ACCESS_VALID | ACCESS_ACTIVE_LOCALITY)) == (ACCESS_VALID | ACCESS_ACTIVE_LOCALITY)
+    })
+}
+
+#[inline(always)]
+fn tis_burst(base: usize) -> usize { ((rd32(base + TIS_STS) >> 8) & 0xFFFF) as usize }
+
+fn tis_read_fifo(base: usize, out: &mut [u8]) -> bool {
+    let mut i = 0usize;
+    while i < out.len() {
+        let mut burst = tis_burst(base);
+        if burst == 0 {
+            if !poll(TIMEOUT_SHORT_US, || tis_burst(base) != 0) { return false; }
+            burst = tis_burst(base);
+        }
+        while burst > 0 && i < out.len() { out[i] = rd8(base + TIS_DATA_FIFO); i += 1; burst -= 1; }
+    }
+    true
+}
+
+/// Execute one command over the FIFO interface.
+pub fn tis_transmit(base: usize, cmd: &[u8], rsp: &mut [u8]) -> Result<usize, &'static str> {
+    if cmd.len() < HDR_LEN || rsp.len() < HDR_LEN { return Err("tpm: buffer too small"); }
+    if !tis_request_locality(base) { return Err("tpm: locality 0 not granted"); }
+    wr32(base + TIS_STS, STS_COMMAND_READY);
+    if !poll(TIMEOUT_SHORT_US, || (rd32(base + TIS_STS) & STS_COMMAND_READY) != 0) {
+        return Err("tpm: commandReady timeout");
+    }
+    // Stream command bytes; the TPM keeps Expect set until the last byte.
+    let mut i = 0usize;
+    while i < cmd.len() {
+        let mut burst = tis_burst(base);
+        if burst == 0 {
+            if !poll(TIMEOUT_SHORT_US, || tis_burst(base) != 0) { return Err("tpm: burstCount timeout"); }
+            burst = tis_burst(base);
+        }
+        while burst > 0 && i < cmd.len() { wr8(base + TIS_DATA_FIFO, cmd[i]); i += 1; burst -= 1; }
+    }
+    if !poll(TIMEOUT_SHORT_US, || (rd32(base + TIS_STS) & STS_VALID) != 0) { return Err("tpm: stsValid timeout"); }
+    if (rd32(base + TIS_STS) & STS_EXPECT) != 0 { return Err("tpm: device expects more data"); }
+    wr32(base + TIS_STS, STS_GO);
+    if !poll(TIMEOUT_CMD_US, || {
+        let s = rd32(base + TIS_STS);
+        (s & (STS_VALID | STS_DATA_AVAIL)) == (STS_VALID | STS_DATA_AVAIL)
+    }) {
+        wr32(base + TIS_STS, STS_COMMAND_READY);
+        return Err("tpm: command timeout");
+    }
+    if !tis_read_fifo(base, &mut rsp[..HDR_LEN]) { return Err("tpm: response read timeout"); }
+    let total = hdr_size(rsp);
+    if total < HDR_LEN || total > rsp.len() {
+        wr32(base + TIS_STS, STS_COMMAND_READY);
+        return Err("tpm: response size invalid");
+    }
+    if !tis_read_fifo(base, &mut rsp[HDR_LEN..total]) { return Err("tpm: response read timeout"); }
+    // Return the interface to idle for the next command.
+    wr32(base + TIS_STS, STS_COMMAND_READY);
+    Ok(total)
+}
+
+// ---- CRB ----
+
+fn crb_request_locality(base: usize) -> bool {
+    if (rd32(base + CRB_LOC_STS) & CRB_LOC_STS_GRANTED) != 0 { return true; }
+    wr32(base + CRB_LOC_CTRL, CRB_LOC_CTRL_REQUEST_ACCESS);
+    poll(TIMEOUT_SHORT_US, || (rd32(base + CRB_LOC_STS) & CRB_LOC_STS_GRANTED) != 0)
+}
+
+/// Execute one command over the CRB interface.
+pub fn crb_transmit(base: usize, cmd: &[u8], rsp: &mut [u8]) -> Result<usize, &'static str> {
+    if cmd.len() < HDR_LEN || rsp.len() < HDR_LEN { return Err("tpm: buffer too small"); }
+    if !crb_request_locality(base) { return Err("tpm: locality 0 not granted"); }
+    wr32(base + CRB_CTRL_REQ, CRB_CTRL_REQ_CMD_READY);
+    if !poll(TIMEOUT_SHORT_US, || (rd32(base + CRB_CTRL_REQ) & CRB_CTRL_REQ_CMD_READY) == 0) {
+        return Err("tpm: cmdReady timeout");
+    }
+    let cmd_size = rd32(base + CRB_CTRL_CMD_SIZE) as usize;
+    let cmd_addr = ((rd32(base + CRB_CTRL_CMD_HADDR) as u64) << 32) | (rd32(base + CRB_CTRL_CMD_LADDR) as u64);
+    let rsp_size = rd32(base + CRB_CTRL_RSP_SIZE) as usize;
+    let rsp_addr = unsafe { read_volatile((base + CRB_CTRL_RSP_ADDR) as *const u64) };
+    if cmd_addr == 0 || rsp_addr == 0 { return Err("tpm: crb buffers not advertised"); }
+    if cmd.len() > cmd_size { return Err("tpm: command exceeds crb buffer"); }
+    // Identity mapping assumption: buffer addresses are directly accessible.
+    for (i, &b) in cmd.iter().enumerate() { wr8(cmd_addr as usize + i, b); }
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    wr32(base + CRB_CTRL_START, 1);
+    if !poll(TIMEOUT_CMD_US, || rd32(base + CRB_CTRL_START) == 0) {
+        wr32(base + CRB_CTRL_CANCEL, 1);
+        return Err("tpm: command timeout");
+    }
+    if (rd32(base + CRB_CTRL_STS) & CRB_CTRL_STS_ERROR) != 0 { return Err("tpm: crb fatal error"); }
+    for i in 0..HDR_LEN { rsp[i] = rd8(rsp_addr as usize + i); }
+    let total = hdr_size(rsp);
+    if total < HDR_LEN || total > rsp.len() || total > rsp_size { return Err("tpm: response size invalid"); }
+    for i in HDR_LEN..total { rsp[i] = rd8(rsp_addr as usize + i); }
+    wr32(base + CRB_CTRL_REQ, CRB_CTRL_REQ_GO_IDLE);
+    Ok(total)
+}
+
+/// Release locality 0 on CRB so firmware or an OS driver can claim it later.
+pub fn crb_relinquish(base: usize) { wr32(base + CRB_LOC_CTRL, CRB_LOC_CTRL_RELINQUISH); }
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 
-/// CRC32 (IEEE 802.3, polynomial 0xEDB88320) implementation with a static table.
-/// Provides slice-based and pointer-based helpers for no_std environments.
+//! CRC32 (IEEE 802.3, polynomial 0xEDB88320) implementation with a static table.
+//! Provides slice-based and pointer-based helpers for no_std environments.
 
 const T: [u32; 256] = [
     0x00000000,0x77073096,0xEE0E612C,0x990951BA,0x076DC419,0x706AF48F,0xE963A535,0x9E6495A3,
@@ -53,16 +53,23 @@ pub fn crc32_update(mut c: u32, data: &[u8]) -> u32 {
 }
 
 /// Compute CRC32 over a volatile pointer region.
-pub fn crc32_ptr(ptr: *const u8, len: usize) -> u32 {
-    let mut c: u32 = 0xFFFF_FFFF;
+///
+/// # Safety
+/// `ptr..ptr + len` must be readable.
+pub unsafe fn crc32_ptr(ptr: *const u8, len: usize) -> u32 { crc32_ptr_update(0, ptr, len) }
+
+/// Continue a CRC32 over a volatile pointer region.
+///
+/// # Safety
+/// `ptr..ptr + len` must be readable.
+pub unsafe fn crc32_ptr_update(c: u32, ptr: *const u8, len: usize) -> u32 {
+    let mut c = !c;
     let mut i = 0usize;
-    unsafe {
-        while i < len {
-            let b = core::ptr::read_volatile(ptr.add(i));
-            let idx = ((c ^ b as u32) & 0xFF) as usize;
-            c = (c >> 8) ^ T[idx];
-            i += 1;
-        }
+    while i < len {
+        let b = core::ptr::read_volatile(ptr.add(i));
+        let idx = ((c ^ b as u32) & 0xFF) as usize;
+        c = (c >> 8) ^ T[idx];
+        i += 1;
     }
     !c
 }
@@ -0,0 +1,237 @@
+#![allow(dead_code)]
+
+//! Ed25519 signature verification (RFC 8032), no_std and verify-only.
+//!
+//! Field elements are sixteen signed 16-bit limbs in `i64`, the layout of
+//! TweetNaCl, which this follows closely: it is small and easy to audit, and
+//! verification runs once per guest image, so speed does not matter. Nothing
+//! here handles secrets, so no effort is made to be constant-time.
+
+use crate::util::sha512::Sha512;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+type Gf = [i64; 16];
+
+const GF0: Gf = [0; 16];
+const GF1: Gf = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// Curve constant d = -121665/121666
+const D: Gf = [0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070, 0xe898, 0x7779, 0x4079, 0x8cc7, 0xfe73, 0x2b6f, 0x6cee, 0x5203];
+const D2: Gf = [0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0, 0xd130, 0xeef3, 0x80f2, 0x198e, 0xfce7, 0x56df, 0xd9dc, 0x2406];
+/// Base point coordinates
+const X: Gf = [0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c, 0xdc5c, 0xfdd6, 0xe231, 0xc0a4, 0x53fe, 0xcd6e, 0x36d3, 0x2169];
+const Y: Gf = [0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666];
+/// sqrt(-1)
+const I: Gf = [0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43, 0xd7a7, 0x3dfb, 0x0099, 0x2b4d, 0xdf0b, 0x4fc1, 0x2480, 0x2b83];
+/// Group order, little-endian bytes
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+fn car(o: &mut Gf) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 { o[i + 1] += c - 1; } else { o[0] += 38 * (c - 1); }
+        o[i] -= c << 16;
+    }
+}
+
+fn sel(p: &mut Gf, q: &mut Gf, b: i64) {
+    let c = !(b - 1);
+    for i in 0..16 {
+        let t = c & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+fn pack25519(n: &Gf) -> [u8; 32] {
+    let mut t = *n;
+    car(&mut t); car(&mut t); car(&mut t);
+    let mut m = GF0;
+    for _ in 0..2 {
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        sel(&mut t, &mut m, 1 - b);
+    }
+    let mut o = [0u8; 32];
+    for i in 0..16 { o[2 * i] = t[i] as u8; o[2 * i + 1] = (t[i] >> 8) as u8; }
+    o
+}
+
+fn neq(a: &Gf, b: &Gf) -> bool { pack25519(a) != pack25519(b) }
+
+fn parity(a: &Gf) -> u8 { pack25519(a)[0] & 1 }
+
+fn unpack25519(n: &[u8; 32]) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 { o[i] = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8); }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn add(a: &Gf, b: &Gf) -> Gf { let mut o = GF0; for i in 0..16 { o[i] = a[i] + b[i]; } o }
+
+fn sub(a: &Gf, b: &Gf) -> Gf { let mut o = GF0; for i in 0..16 { o[i] = a[i] - b[i]; } o }
+
+fn mul(a: &Gf, b: &Gf) -> Gf {
+    let mut t = [0i64; 31];
+    for i in 0..16 { for j in 0..16 { t[i + j] += a[i] * b[j]; } }
+    for i in 0..15 { t[i] += 38 * t[i + 16]; }
+    let mut o = GF0;
+    o.copy_from_slice(&t[..16]);
+    car(&mut o); car(&mut o);
+    o
+}
+
+fn sq(a: &Gf) -> Gf { mul(a, a) }
+
+/// a^(2^252 - 3), the square-root helper
+fn pow2523(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..=250).rev() {
+        c = sq(&c);
+        if a != 1 { c = mul(&c, i); }
+    }
+    c
+}
+
+fn inv(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..=253).rev() {
+        c = sq(&c);
+        if a != 2 && a != 4 { c = mul(&c, i); }
+    }
+    c
+}
+
+/// Extended coordinates (X, Y, Z, T)
+type Point = [Gf; 4];
+
+fn padd(p: &mut Point, q: &Point) {
+    let a = mul(&sub(&p[1], &p[0]), &sub(&q[1], &q[0]));
+    let b = mul(&add(&p[0], &p[1]), &add(&q[0], &q[1]));
+    let c = mul(&mul(&p[3], &q[3]), &D2);
+    let d = mul(&p[2], &q[2]);
+    let d = add(&d, &d);
+    let (e, f, g, h) = (sub(&b, &a), sub(&d, &c), add(&d, &c), add(&b, &a));
+    p[0] = mul(&e, &f);
+    p[1] = mul(&h, &g);
+    p[2] = mul(&g, &f);
+    p[3] = mul(&e, &h);
+}
+
+fn cswap(p: &mut Point, q: &mut Point, b: u8) {
+    for i in 0..4 { sel(&mut p[i], &mut q[i], b as i64); }
+}
+
+fn pack(p: &Point) -> [u8; 32] {
+    let zi = inv(&p[2]);
+    let mut r = pack25519(&mul(&p[1], &zi));
+    r[31] ^= parity(&mul(&p[0], &zi)) << 7;
+    r
+}
+
+fn scalarmult(q: &Point, s: &[u8; 32]) -> Point {
+    let mut p: Point = [GF0, GF1, GF1, GF0];
+    let mut q = *q;
+    for i in (0..256).rev() {
+        let b = (s[i / 8] >> (i & 7)) & 1;
+        cswap(&mut p, &mut q, b);
+        let pc = p;
+        padd(&mut q, &pc);
+        padd(&mut p, &pc);
+        cswap(&mut p, &mut q, b);
+    }
+    p
+}
+
+fn scalarbase(s: &[u8; 32]) -> Point { scalarmult(&[X, Y, GF1, mul(&X, &Y)], s) }
+
+/// Decode a public key and negate it. `None` if it is not a curve point.
+fn unpackneg(p: &[u8; 32]) -> Option<Point> {
+    let y = unpack25519(p);
+    let z = GF1;
+    let num = sq(&y);
+    let den = mul(&num, &D);
+    let num = sub(&num, &z);
+    let den = add(&z, &den);
+    let den2 = sq(&den);
+    let den4 = sq(&den2);
+    let den6 = mul(&den4, &den2);
+    let mut t = mul(&mul(&den6, &num), &den);
+    t = pow2523(&t);
+    t = mul(&mul(&mul(&t, &num), &den), &den);
+    let mut x = mul(&t, &den);
+    if neq(&mul(&sq(&x), &den), &num) { x = mul(&x, &I); }
+    if neq(&mul(&sq(&x), &den), &num) { return None; }
+    if parity(&x) == p[31] >> 7 { x = sub(&GF0, &x); }
+    Some([x, y, z, mul(&x, &y)])
+}
+
+/// Reduce a 512-bit little-endian number modulo L.
+fn reduce(h: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for i in 0..64 { x[i] = h[i] as i64; }
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 { x[j] -= carry * L[j]; }
+    let mut r = [0u8; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = (x[i] & 255) as u8;
+    }
+    r
+}
+
+/// Scalars at or above L are rejected (RFC 8032 5.1.7), so a signature has
+/// one encoding.
+fn scalar_canonical(s: &[u8]) -> bool {
+    for i in (0..32).rev() {
+        let l = L[i] as u8;
+        if s[i] != l { return s[i] < l; }
+    }
+    false
+}
+
+/// Check `sig` over `msg` against `public_key`.
+pub fn verify(public_key: &[u8; PUBLIC_KEY_LEN], msg: &[u8], sig: &[u8; SIGNATURE_LEN]) -> bool {
+    if !scalar_canonical(&sig[32..]) { return false; }
+    let Some(neg_a) = unpackneg(public_key) else { return false };
+    let mut h = Sha512::new();
+    h.update(&sig[..32]);
+    h.update(public_key);
+    h.update(msg);
+    let k = reduce(&h.finish());
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&sig[32..]);
+    // [S]B - [k]A must equal R
+    let mut p = scalarmult(&neg_a, &k);
+    padd(&mut p, &scalarbase(&s));
+    pack(&p)[..] == sig[..32]
+}
@@ -1,8 +1,15 @@
 #![allow(dead_code)]
 
+//! Number formatting into caller-provided byte buffers.
+//!
+//! Every function writes at most `out.len()` bytes and returns how many it
+//! wrote, so a line can be built up in one fixed buffer with `n += f(v,
+//! &mut out[n..])`. `BufWriter` does the same for `write!`.
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
 /// Write u64 hex into a buffer without allocation, returns bytes written.
 pub fn u64_hex(v: u64, out: &mut [u8]) -> usize {
-    const HEX: &[u8; 16] = b"0123456789ABCDEF";
     let mut started = false; let mut n = 0;
     for i in (0..16).rev() {
         let nyb = ((v >> (i * 4)) & 0xF) as usize;
@@ -11,5 +18,88 @@ pub fn u64_hex(v: u64, out: &mut [u8]) -> usize {
     n
 }
 
+/// Uppercase hex padded with zeros to `width` digits (wider values are not
+/// cut), e.g. `hex_pad(0xA, 4, ..)` writes `000A`.
+pub fn hex_pad(v: u64, width: usize, out: &mut [u8]) -> usize {
+    let digits = (16 - (v.leading_zeros() as usize) / 4).max(1);
+    let mut n = 0;
+    for _ in digits..width.min(16) { if n < out.len() { out[n] = b'0'; n += 1; } }
+    n + u64_hex(v, &mut out[n..])
+}
 
+/// Write u64 decimal into a buffer without allocation, returns bytes written.
+pub fn u64_dec(mut v: u64, out: &mut [u8]) -> usize {
+    let mut tmp = [0u8; 20]; let mut i = 0;
+    loop { tmp[i] = b'0' + (v % 10) as u8; i += 1; v /= 10; if v == 0 { break; } }
+    let mut n = 0;
+    while i > 0 && n < out.len() { i -= 1; out[n] = tmp[i]; n += 1; }
+    n
+}
+
+pub fn u32_dec(v: u32, out: &mut [u8]) -> usize { u64_dec(v as u64, out) }
 
+/// Signed decimal with a leading `-` for negative values.
+pub fn i64_dec(v: i64, out: &mut [u8]) -> usize {
+    if v >= 0 { return u64_dec(v as u64, out); }
+    if out.is_empty() { return 0; }
+    out[0] = b'-';
+    1 + u64_dec(v.unsigned_abs(), &mut out[1..])
+}
+
+/// A byte count in the largest binary unit it reaches, with one decimal
+/// when it is not whole: `512B`, `4KiB`, `1.5MiB`, `16GiB`. The decimal is
+/// truncated, not rounded.
+pub fn size(bytes: u64, out: &mut [u8]) -> usize {
+    const UNITS: [&[u8]; 5] = [b"B", b"KiB", b"MiB", b"GiB", b"TiB"];
+    let mut u = 0;
+    while u + 1 < UNITS.len() && bytes >> (10 * (u + 1)) != 0 { u += 1; }
+    let whole = bytes >> (10 * u);
+    let mut n = u64_dec(whole, out);
+    if u > 0 {
+        let tenth = ((bytes - (whole << (10 * u))) * 10) >> (10 * u);
+        if tenth != 0 && n + 2 <= out.len() { out[n] = b'.'; out[n + 1] = b'0' + tenth as u8; n += 2; }
+    }
+    for &b in UNITS[u] { if n < out.len() { out[n] = b; n += 1; } }
+    n
+}
+
+/// `core::fmt::Write` over a fixed buffer. Output past the end is dropped
+/// and `truncated` is set instead of failing, so `write!` into it never
+/// errors and the start of a long line survives.
+pub struct BufWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    truncated: bool,
+}
+
+impl<'a> BufWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self { BufWriter { buf, len: 0, truncated: false } }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    pub fn truncated(&self) -> bool { self.truncated }
+
+    pub fn bytes(&self) -> &[u8] { &self.buf[..self.len] }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] { &mut self.buf[..self.len] }
+
+    /// The text written so far; a UTF-8 sequence cut by truncation is left out.
+    pub fn as_str(&self) -> &str {
+        match core::str::from_utf8(&self.buf[..self.len]) {
+            Ok(s) => s,
+            Err(e) => core::str::from_utf8(&self.buf[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl core::fmt::Write for BufWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let take = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        if take < s.len() { self.truncated = true; }
+        Ok(())
+    }
+}
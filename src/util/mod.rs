@@ -1,5 +1,9 @@
 pub mod format;
 pub mod crc32;
+pub mod sha256;
+pub mod sha512;
+pub mod ed25519;
+pub mod rcu;
 
 pub mod spinlock {
     #![allow(dead_code)]
@@ -0,0 +1,60 @@
+#![allow(dead_code)]
+
+//! Read-mostly values that readers see without taking a lock.
+//!
+//! A writer builds a complete new value and `publish`es it by pointer swap.
+//! `read` never spins or blocks: it pins the current epoch, loads the
+//! pointer and runs its closure on whatever value was current. After the
+//! swap, `publish` advances the epoch and waits for readers still pinned to
+//! the previous one, then frees the old value.
+//!
+//! Writers must be serialized by the caller, usually by publishing while
+//! holding the lock that guards the authoritative data. `publish` must not
+//! be called from inside `read` on the same `Rcu`; it would wait for itself.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+pub struct Rcu<T> {
+    ptr: AtomicPtr<T>,
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    _owns: PhantomData<Box<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T> Rcu<T> {
+    /// An `Rcu` with no value published.
+    pub const fn new() -> Self {
+        Self { ptr: AtomicPtr::new(core::ptr::null_mut()), epoch: AtomicUsize::new(0), readers: [AtomicUsize::new(0), AtomicUsize::new(0)], _owns: PhantomData }
+    }
+
+    /// Run `f` on the current value, or on `None` if nothing is published.
+    pub fn read<R>(&self, f: impl FnOnce(Option<&T>) -> R) -> R {
+        // The epoch is re-read after pinning so a reader is always counted
+        // under the parity the next writer waits on.
+        let slot = loop {
+            let e = self.epoch.load(Ordering::SeqCst);
+            let slot = &self.readers[e & 1];
+            slot.fetch_add(1, Ordering::SeqCst);
+            if self.epoch.load(Ordering::SeqCst) == e { break slot; }
+            slot.fetch_sub(1, Ordering::SeqCst);
+        };
+        let p = self.ptr.load(Ordering::SeqCst);
+        let r = f(unsafe { p.as_ref() });
+        slot.fetch_sub(1, Ordering::Release);
+        r
+    }
+
+    /// Replace the value and free the old one once no reader can hold it.
+    pub fn publish(&self, v: Option<Box<T>>) {
+        let new = v.map_or(core::ptr::null_mut(), Box::into_raw);
+        let old = self.ptr.swap(new, Ordering::SeqCst);
+        let e = self.epoch.fetch_add(1, Ordering::SeqCst);
+        while self.readers[e & 1].load(Ordering::SeqCst) != 0 { core::hint::spin_loop(); }
+        if !old.is_null() { drop(unsafe { Box::from_raw(old) }); }
+    }
+}
@@ -0,0 +1,88 @@
+#![allow(dead_code)]
+
+/// SHA-256 (FIPS 180-4) for no_std: streaming state plus a one-shot helper.
+/// Used for TPM measurements, where the digest must match the TPM's bank.
+
+pub const DIGEST_LEN: usize = 32;
+
+const K: [u32; 64] = [
+    0x428a2f98,0x71374491,0xb5c0fbcf,0xe9b5dba5,0x3956c25b,0x59f111f1,0x923f82a4,0xab1c5ed5,
+    0xd807aa98,0x12835b01,0x243185be,0x550c7dc3,0x72be5d74,0x80deb1fe,0x9bdc06a7,0xc19bf174,
+    0xe49b69c1,0xefbe4786,0x0fc19dc6,0x240ca1cc,0x2de92c6f,0x4a7484aa,0x5cb0a9dc,0x76f988da,
+    0x983e5152,0xa831c66d,0xb00327c8,0xbf597fc7,0xc6e00bf3,0xd5a79147,0x06ca6351,0x14292967,
+    0x27b70a85,0x2e1b2138,0x4d2c6dfc,0x53380d13,0x650a7354,0x766a0abb,0x81c2c92e,0x92722c85,
+    0xa2bfe8a1,0xa81a664b,0xc24b8b70,0xc76c51a3,0xd192e819,0xd6990624,0xf40e3585,0x106aa070,
+    0x19a4c116,0x1e376c08,0x2748774c,0x34b0bcb5,0x391c0cb3,0x4ed8aa4a,0x5b9cca4f,0x682e6ff3,
+    0x748f82ee,0x78a5636f,0x84c87814,0x8cc70208,0x90befffa,0xa4506ceb,0xbef9a3f7,0xc67178f2,
+];
+
+const H0: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+
+#[derive(Clone, Copy)]
+pub struct Sha256 { h: [u32; 8], buf: [u8; 64], len: usize, total: u64 }
+
+impl Default for Sha256 { fn default() -> Self { Self::new() } }
+
+impl Sha256 {
+    pub const fn new() -> Self { Sha256 { h: H0, buf: [0; 64], len: 0, total: 0 } }
+
+    fn block(&mut self, b: &[u8]) {
+        let mut w = [0u32; 64];
+        for i in 0..16 { w[i] = u32::from_be_bytes([b[i * 4], b[i * 4 + 1], b[i * 4 + 2], b[i * 4 + 3]]); }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b_, mut c, mut d, mut e, mut f, mut g, mut h] = self.h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b_) ^ (a & c) ^ (b_ & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g; g = f; f = e; e = d.wrapping_add(t1);
+            d = c; c = b_; b_ = a; a = t1.wrapping_add(t2);
+        }
+        for (x, v) in self.h.iter_mut().zip([a, b_, c, d, e, f, g, h]) { *x = x.wrapping_add(v); }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+        if self.len != 0 {
+            let take = core::cmp::min(64 - self.len, data.len());
+            self.buf[self.len..self.len + take].copy_from_slice(&data[..take]);
+            self.len += take;
+            data = &data[take..];
+            if self.len < 64 { return; }
+            let b = self.buf;
+            self.block(&b);
+            self.len = 0;
+        }
+        while data.len() >= 64 { self.block(&data[..64]); data = &data[64..]; }
+        self.buf[..data.len()].copy_from_slice(data);
+        self.len = data.len();
+    }
+
+    pub fn finish(mut self) -> [u8; DIGEST_LEN] {
+        let bits = self.total.wrapping_mul(8);
+        let mut pad = [0u8; 72];
+        pad[0] = 0x80;
+        let padlen = if self.len < 56 { 56 - self.len } else { 120 - self.len };
+        pad[padlen..padlen + 8].copy_from_slice(&bits.to_be_bytes());
+        let total = self.total;
+        self.update(&pad[..padlen + 8]);
+        self.total = total;
+        let mut out = [0u8; DIGEST_LEN];
+        for (i, v) in self.h.iter().enumerate() { out[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes()); }
+        out
+    }
+}
+
+/// One-shot digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut s = Sha256::new();
+    s.update(data);
+    s.finish()
+}
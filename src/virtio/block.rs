@@ -76,12 +76,8 @@ pub fn report_first(system_table: &mut SystemTable<Boot>) {
                         let capacity_sectors = (cap_hi << 32) | cap_lo;
                         let capacity_mb = (capacity_sectors.saturating_mul(512) / 1_048_576) as u32;
                         // Print
-                        let mut buf = [0u8; 96];
-                        let mut n = 0;
-                        for &b in crate::i18n::t(lang, crate::i18n::key::VIRTIO_BLK).as_bytes() { buf[n] = b; n += 1; }
-                        n += crate::firmware::acpi::u32_to_dec(capacity_mb, &mut buf[n..]);
-                        for &b in b" MiB\r\n" { buf[n] = b; n += 1; }
-                        let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
+                        let msg = crate::i18n::t_fmt(lang, crate::i18n::key::VIRTIO_BLK, &[crate::i18n::Arg::Dec(capacity_mb as u64)]);
+                        let _ = stdout.write_str(msg.as_str());
                         reported = true; break;
                     }
                     if reported { break; }
@@ -2,7 +2,6 @@
 
 use uefi::prelude::Boot;
 use uefi::table::SystemTable;
-use core::fmt::Write as _;
 
 use super::{mmio_read8, mmio_read16, mmio_read32, mmio_write8, ecam_fn_base};
 
@@ -25,7 +24,6 @@ const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
 pub fn init_and_write_hello(system_table: &mut SystemTable<Boot>) {
     // Find MCFG and scan for a virtio device with console-like class hints (not strictly needed)
     if let Some(mcfg_hdr) = crate::firmware::acpi::find_mcfg(system_table) {
-        let stdout = system_table.stdout();
         let mut initialized = false;
         crate::firmware::acpi::mcfg_for_each_allocation_from(|a| {
             if initialized { return; }
@@ -85,7 +83,7 @@ pub fn init_and_write_hello(system_table: &mut SystemTable<Boot>) {
                         // Features negotiation would go here; skip and set DRIVER_OK for demo
                         let st = mmio_read8(device_status);
                         mmio_write8(device_status, st | VIRTIO_STATUS_DRIVER_OK);
-                        let _ = stdout.write_str("virtio-console: minimal init (status set)\r\n");
+                        crate::log!(Info, "virtio", "console: minimal init (status set)");
                         initialized = true;
                         break;
                     }
@@ -96,7 +94,7 @@ pub fn init_and_write_hello(system_table: &mut SystemTable<Boot>) {
             }
         }, mcfg_hdr);
         if !initialized {
-            let _ = stdout.write_str("virtio-console: device not found\r\n");
+            crate::log!(Warn, "virtio", "console: device not found");
         }
     }
 }
@@ -14,6 +14,7 @@ use core::fmt::Write as _;
 mod console;
 mod block;
 pub mod net;
+pub mod nic;
 
 /// Read a 32-bit little-endian value from an MMIO address safely.
 #[inline(always)]
@@ -101,21 +102,21 @@ pub fn scan_and_report(system_table: &mut SystemTable<Boot>) {
                             let mut buf = [0u8; 128];
                             let mut n = 0;
                             for &b in b"VirtIO: seg=" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(a.pci_segment as u32, &mut buf[n..]);
+                            n += crate::util::format::u32_dec(a.pci_segment as u32, &mut buf[n..]);
                             for &b in b" bus=" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(bus as u32, &mut buf[n..]);
+                            n += crate::util::format::u32_dec(bus as u32, &mut buf[n..]);
                             for &b in b" dev=" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(dev as u32, &mut buf[n..]);
+                            n += crate::util::format::u32_dec(dev as u32, &mut buf[n..]);
                             for &b in b" fn=" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(func as u32, &mut buf[n..]);
+                            n += crate::util::format::u32_dec(func as u32, &mut buf[n..]);
                             for &b in b" vid=0x" { buf[n] = b; n += 1; }
                             n += crate::util::format::u64_hex(vid as u64, &mut buf[n..]);
                             for &b in b" did=0x" { buf[n] = b; n += 1; }
                             n += crate::util::format::u64_hex(did as u64, &mut buf[n..]);
                             for &b in b" class=" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(cls as u32, &mut buf[n..]);
+                            n += crate::util::format::u32_dec(cls as u32, &mut buf[n..]);
                             for &b in b"/" { buf[n] = b; n += 1; }
-                            n += crate::firmware::acpi::u32_to_dec(scls as u32, &mut buf[n..]);
+                            n += crate::util::format::u32_dec(scls as u32, &mut buf[n..]);
                             buf[n] = b'\r'; n += 1; buf[n] = b'\n'; n += 1;
                             let _ = stdout.write_str(core::str::from_utf8(&buf[..n]).unwrap_or("\r\n"));
                             found = found.saturating_add(1);
@@ -144,9 +145,9 @@ pub fn scan_and_report(system_table: &mut SystemTable<Boot>) {
                                     let mut lbuf = [0u8; 128];
                                     let mut m = 0;
                                     for &b in b"  cap: type=" { lbuf[m] = b; m += 1; }
-                                    m += crate::firmware::acpi::u32_to_dec(cfg_type as u32, &mut lbuf[m..]);
+                                    m += crate::util::format::u32_dec(cfg_type as u32, &mut lbuf[m..]);
                                     for &b in b" bar=" { lbuf[m] = b; m += 1; }
-                                    m += crate::firmware::acpi::u32_to_dec(bar as u32, &mut lbuf[m..]);
+                                    m += crate::util::format::u32_dec(bar as u32, &mut lbuf[m..]);
                                     for &b in b" off=0x" { lbuf[m] = b; m += 1; }
                                     m += crate::util::format::u64_hex(off as u64, &mut lbuf[m..]);
                                     for &b in b" len=0x" { lbuf[m] = b; m += 1; }
@@ -203,7 +204,7 @@ pub fn scan_and_report(system_table: &mut SystemTable<Boot>) {
                                         mmio_write8(common_base + device_status, st | VIRTIO_STATUS_ACKNOWLEDGE);
                                         let st2 = mmio_read8(common_base + device_status);
                                         mmio_write8(common_base + device_status, st2 | VIRTIO_STATUS_DRIVER);
-                                        let _ = stdout.write_str("  handshake: ACK|DRIVER set\r\n");
+                                        crate::log!(Info, "virtio", "handshake: ACK|DRIVER set");
                                     }
                                 }
                             }
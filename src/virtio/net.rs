@@ -151,7 +151,7 @@ unsafe fn mmio_write64(addr: usize, val: u64) { core::ptr::write_volatile(addr a
 const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
 const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
 
-fn find_first_virtio_net(system_table: &mut SystemTable<Boot>) -> Option<(usize, u32, usize, usize)> {
+pub(super) fn find_first_virtio_net(system_table: &mut SystemTable<Boot>) -> Option<(usize, u32, usize, usize)> {
     // returns (common_base, notify_mul, notify_base, cfg)
     if let Some(mcfg_hdr) = crate::firmware::acpi::find_mcfg(system_table) {
         let mut found: Option<(usize, u32, usize, usize)> = None;
@@ -342,14 +342,24 @@ pub fn init_rx(system_table: &mut SystemTable<Boot>) -> bool {
     }
 }
 
+/// Feed CRC-valid ZMIG frames found in one received payload to the migration channel.
+fn ingest_mig(payload: &[u8]) {
+    if crate::migrate::ingest_packet(payload) == 0 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_EMPTY).inc(); }
+}
+
 pub fn rx_pump(system_table: &mut SystemTable<Boot>, limit: usize) {
+    if crate::migrate::chan_backpressure() { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_BACKPRESSURE).inc(); return; }
+    if super::nic::is_up() {
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_CALLS).inc();
+        super::nic::recv(limit, ingest_mig);
+        return;
+    }
     unsafe {
         if !RX.inited { if !init_rx(system_table) { return; } }
         crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_CALLS).inc();
         let used_idx_ptr = (RX.q_used as usize + 2) as *const u16;
         let mut processed = 0usize;
         let hdr_len = 10usize;
-        let hdr_mig = *b"ZMIG";
         loop {
             let used_idx = core::ptr::read_volatile(used_idx_ptr);
             if RX.used_last == used_idx { break; }
@@ -361,35 +371,7 @@ pub fn rx_pump(system_table: &mut SystemTable<Boot>, limit: usize) {
             let buf_ptr = RX.slab.add((ue.id as usize) * (2048 + 64));
             if len > hdr_len {
                 let payload = core::slice::from_raw_parts(buf_ptr.add(hdr_len), len - hdr_len);
-                // search for MIG magic and CRC-validate like SNP pump
-                let mut pos = 0usize;
-                let mut wrote_any = false;
-                while pos + 28 <= payload.len() { // header size
-                    if &payload[pos..pos+4] != &hdr_mig { pos += 1; continue; }
-                    let payload_len = {
-                        let b = &payload[pos+20..pos+24]; (b[0] as usize) | ((b[1] as usize) << 8) | ((b[2] as usize) << 16) | ((b[3] as usize) << 24)
-                    };
-                    if pos + 28 + payload_len > payload.len() { break; }
-                    let crc_hdr = {
-                        let b = &payload[pos+24..pos+28]; (b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24)
-                    };
-                    let body = &payload[pos+28 .. pos+28+payload_len];
-                    let crc_calc = crate::util::crc32::crc32(body);
-                    if crc_calc == crc_hdr {
-                        let _ = crate::migrate::chan_write_bytes(&payload[pos .. pos+28]);
-                        let _ = crate::migrate::chan_write_bytes(body);
-                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_OK).inc();
-                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_FRAMES).inc();
-                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_BYTES).add((28 + payload_len) as u64);
-                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_BYTES).add((28 + payload_len) as u64);
-                        wrote_any = true;
-                        pos += 28 + payload_len;
-                    } else {
-                        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_RX_FRAMES_BAD).inc();
-                        pos += 1;
-                    }
-                }
-                if !wrote_any { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_PUMP_EMPTY).inc(); }
+                ingest_mig(payload);
             }
             RX.used_last = RX.used_last.wrapping_add(1);
             processed += 1;
@@ -404,6 +386,38 @@ pub fn rx_pump(system_table: &mut SystemTable<Boot>, limit: usize) {
     }
 }
 
+/// Hand up to `limit` received frames (0 = all pending) to `f` with the
+/// virtio header stripped, recycling each buffer. Unlike `rx_pump` this does
+/// not look for migration frames; the RX queue is shared, so use one or the other.
+pub fn rx_frames(system_table: &mut SystemTable<Boot>, limit: usize, mut f: impl FnMut(&[u8])) -> usize {
+    if super::nic::is_up() { return super::nic::recv(limit, f); }
+    unsafe {
+        if !RX.inited { if !init_rx(system_table) { return 0; } }
+        let used_idx_ptr = (RX.q_used as usize + 2) as *const u16;
+        let avail_idx_ptr = (RX.q_avail_hdr as usize + 2) as *mut u16;
+        let hdr_len = 10usize;
+        let mut processed = 0usize;
+        while limit == 0 || processed < limit {
+            let used_idx = core::ptr::read_volatile(used_idx_ptr);
+            if RX.used_last == used_idx { break; }
+            let slot = (RX.used_last as usize) % (RX.queue_size as usize);
+            let ue_ptr = (RX.q_used as usize + 4 + slot * core::mem::size_of::<VirtqUsedElem>()) as *const VirtqUsedElem;
+            let ue = core::ptr::read_volatile(ue_ptr);
+            let len = (ue.len as usize).min(2048 + 64);
+            let buf_ptr = RX.slab.add((ue.id as usize) * (2048 + 64));
+            if len > hdr_len { f(core::slice::from_raw_parts(buf_ptr.add(hdr_len), len - hdr_len)); }
+            RX.used_last = RX.used_last.wrapping_add(1);
+            processed += 1;
+            let avail_idx = core::ptr::read_volatile(avail_idx_ptr);
+            let a_slot = (avail_idx as usize) % (RX.queue_size as usize);
+            core::ptr::write_volatile(RX.q_avail.add(a_slot), ue.id as u16);
+            fence();
+            core::ptr::write_volatile(avail_idx_ptr, avail_idx.wrapping_add(1));
+        }
+        processed
+    }
+}
+
 #[inline(always)]
 fn fence() { core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst) }
 
@@ -425,6 +439,13 @@ unsafe fn reclaim_used() {
 }
 
 pub fn tx_send(system_table: &mut SystemTable<Boot>, data: &[u8]) -> usize {
+    if super::nic::is_up() {
+        let sent = super::nic::send(data);
+        if sent == 0 { crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_NET_TX_ERRS).inc(); return 0; }
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_NET_TX_FRAMES).inc();
+        crate::obs::metrics::Counter::new(&crate::obs::metrics::MIG_NET_TX_BYTES).add((super::nic::HDR_LEN + sent) as u64);
+        return super::nic::HDR_LEN + sent;
+    }
     unsafe {
         if !TX.inited { if !init_tx(system_table) { return 0; } }
         if TX.desc_data.is_null() || TX.q_desc.is_null() { return 0; }
@@ -464,8 +485,15 @@ pub fn tx_send(system_table: &mut SystemTable<Boot>, data: &[u8]) -> usize {
 }
 
 
+/// Drop the queue state after another driver reset the device; the next
+/// `init` starts over.
+pub(super) fn forget_queues() {
+    unsafe { TX.inited = false; RX.inited = false; }
+}
+
 /// Initialize both TX and RX queues for virtio-net.
 pub fn init(system_table: &mut SystemTable<Boot>) -> bool {
+    if super::nic::is_up() { return true; }
     let tx_ok = init_tx(system_table);
     let rx_ok = init_rx(system_table);
     tx_ok && rx_ok
@@ -0,0 +1,429 @@
+#![allow(dead_code)]
+
+//! Self-contained virtio-net (modern, VIRTIO 1.x) driver.
+//!
+//! Unlike `virtio::net`, which borrows pages from Boot Services on first use,
+//! this driver takes its rings and packet buffers from `mm::dma` and touches
+//! only the device's PCI config space and BARs afterwards. Once `probe` has run
+//! (Boot Services up) or `probe_at` has run against an already reserved pool,
+//! `send`/`recv`/`poll` work without a `SystemTable`, so the management link
+//! and migration stream survive ExitBootServices.
+//!
+//! Completion is polled by default. `enable_msix` routes both queues to one
+//! MSI-X vector; whoever owns that IDT vector calls `on_interrupt`, and the
+//! next `poll` drains the rings.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use uefi::prelude::Boot;
+use uefi::table::SystemTable;
+
+use crate::util::spinlock::SpinLock;
+use super::{mmio_read8, mmio_read16, mmio_read32, mmio_write8, mmio_write16, mmio_write32, mmio_write64};
+
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+const F_VERSION_1: u64 = 1 << 32;
+
+const S_ACKNOWLEDGE: u8 = 1;
+const S_DRIVER: u8 = 2;
+const S_DRIVER_OK: u8 = 4;
+const S_FEATURES_OK: u8 = 8;
+const S_FAILED: u8 = 0x80;
+
+// Common configuration layout (virtio 1.x, 4.1.4.3)
+const C_DFSELECT: usize = 0x00;
+const C_DF: usize = 0x04;
+const C_GFSELECT: usize = 0x08;
+const C_GF: usize = 0x0C;
+const C_MSIX_CONFIG: usize = 0x10;
+const C_STATUS: usize = 0x14;
+const C_Q_SELECT: usize = 0x16;
+const C_Q_SIZE: usize = 0x18;
+const C_Q_MSIX: usize = 0x1A;
+const C_Q_ENABLE: usize = 0x1C;
+const C_Q_NOTIFY_OFF: usize = 0x1E;
+const C_Q_DESC: usize = 0x20;
+const C_Q_DRIVER: usize = 0x28;
+const C_Q_DEVICE: usize = 0x30;
+
+const NO_VECTOR: u16 = 0xFFFF;
+const DESC_F_WRITE: u16 = 2;
+
+/// virtio_net_hdr with num_buffers; always 12 bytes once VERSION_1 is negotiated.
+pub const HDR_LEN: usize = 12;
+/// Per-descriptor buffer; holds the header plus a 1514-byte frame.
+const BUF_SIZE: usize = 2048;
+/// Ring depth we ask for; devices may offer more.
+const QUEUE_MAX: u16 = 64;
+/// Pool pages `probe` reserves when `mm::dma` is still empty.
+pub const DEFAULT_POOL_PAGES: usize = 128;
+
+const RX_Q: u16 = 0;
+const TX_Q: u16 = 1;
+
+#[derive(Clone, Copy)]
+struct Queue {
+    index: u16,
+    size: u16,
+    /// One page: descriptors at 0, avail ring at 1024, used ring at 2048.
+    ring: u64,
+    bufs: u64,
+    notify: usize,
+    last_used: u16,
+}
+
+impl Queue {
+    const EMPTY: Queue = Queue { index: 0, size: 0, ring: 0, bufs: 0, notify: 0, last_used: 0 };
+    fn desc(&self, i: usize) -> usize { self.ring as usize + i * 16 }
+    fn avail(&self) -> usize { self.ring as usize + 1024 }
+    fn used(&self) -> usize { self.ring as usize + 2048 }
+    fn buf(&self, i: usize) -> u64 { self.bufs + (i * BUF_SIZE) as u64 }
+    fn avail_idx(&self) -> u16 { mmio_read16(self.avail() + 2) }
+    fn used_idx(&self) -> u16 { mmio_read16(self.used() + 2) }
+    fn pages(&self) -> usize { (self.size as usize * BUF_SIZE + 4095) / 4096 }
+}
+
+#[derive(Clone, Copy)]
+pub struct Info {
+    pub cfg: usize,
+    pub mac: [u8; 6],
+    pub features: u64,
+    pub rx_size: u16,
+    pub tx_size: u16,
+    /// (vector, destination APIC id) when MSI-X is on
+    pub msix: Option<(u8, u32)>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Stats { pub rx_frames: u64, pub rx_bytes: u64, pub tx_frames: u64, pub tx_bytes: u64, pub tx_full: u64 }
+
+struct Nic {
+    info: Info,
+    common: usize,
+    device: usize,
+    rx: Queue,
+    tx: Queue,
+    stats: Stats,
+}
+
+static NIC: SpinLock<Option<Nic>> = SpinLock::new(None);
+static IRQ_PENDING: AtomicBool = AtomicBool::new(false);
+static IRQS: AtomicU64 = AtomicU64::new(0);
+
+#[inline(always)]
+fn fence() { core::sync::atomic::fence(Ordering::SeqCst) }
+
+/// Resolve a memory BAR of the function at `cfg`; I/O BARs yield None.
+fn bar_base(cfg: usize, bar: u8) -> Option<u64> {
+    if bar >= 6 { return None; }
+    let off = 0x10 + bar as usize * 4;
+    let lo = mmio_read32(cfg + off);
+    if lo & 1 != 0 { return None; }
+    let mut base = (lo & 0xFFFF_FFF0) as u64;
+    if (lo >> 1) & 3 == 2 && bar < 5 { base |= (mmio_read32(cfg + off + 4) as u64) << 32; }
+    if base == 0 { None } else { Some(base) }
+}
+
+/// Walk the capability list for the virtio structures we need:
+/// (common, notify, notify_mul, device).
+fn find_caps(cfg: usize) -> Option<(usize, usize, u32, usize)> {
+    let (mut common, mut notify, mut mul, mut device) = (0usize, 0usize, 0u32, 0usize);
+    let mut p = mmio_read8(cfg + 0x34) as usize & !3;
+    let mut guard = 0;
+    while p >= 0x40 && p < 0x100 && guard < 48 {
+        if mmio_read8(cfg + p) == 0x09 && mmio_read8(cfg + p + 2) >= 16 {
+            let kind = mmio_read8(cfg + p + 3);
+            let addr = bar_base(cfg, mmio_read8(cfg + p + 4)).map(|b| b as usize + mmio_read32(cfg + p + 8) as usize);
+            match (kind, addr) {
+                (1, Some(a)) => common = a,
+                (2, Some(a)) => { notify = a; mul = mmio_read32(cfg + p + 16); }
+                (4, Some(a)) => device = a,
+                _ => {}
+            }
+        }
+        p = mmio_read8(cfg + p + 1) as usize & !3;
+        guard += 1;
+    }
+    if common == 0 || notify == 0 { return None; }
+    Some((common, notify, mul, device))
+}
+
+fn setup_queue(common: usize, notify: usize, mul: u32, index: u16) -> Result<Queue, &'static str> {
+    mmio_write16(common + C_Q_SELECT, index);
+    let max = mmio_read16(common + C_Q_SIZE);
+    if max == 0 { return Err("nic: queue not available"); }
+    let size = max.min(QUEUE_MAX);
+    let mut q = Queue { index, size, ..Queue::EMPTY };
+    q.ring = crate::mm::dma::alloc(1).ok_or("nic: dma pool exhausted")?;
+    q.bufs = match crate::mm::dma::alloc(q.pages()) {
+        Some(b) => b,
+        None => { crate::mm::dma::free(q.ring, 1); return Err("nic: dma pool exhausted"); }
+    };
+    mmio_write16(common + C_Q_SIZE, size);
+    mmio_write16(common + C_Q_MSIX, NO_VECTOR);
+    mmio_write64(common + C_Q_DESC, q.ring);
+    mmio_write64(common + C_Q_DRIVER, q.avail() as u64);
+    mmio_write64(common + C_Q_DEVICE, q.used() as u64);
+    q.notify = notify + mmio_read16(common + C_Q_NOTIFY_OFF) as usize * mul as usize;
+    Ok(q)
+}
+
+fn release(q: &Queue) {
+    if q.ring != 0 { crate::mm::dma::free(q.ring, 1); }
+    if q.bufs != 0 { crate::mm::dma::free(q.bufs, q.pages()); }
+}
+
+fn reset(common: usize) {
+    mmio_write8(common + C_STATUS, 0);
+    let mut spins = 0u32;
+    while mmio_read8(common + C_STATUS) != 0 && spins < 1_000_000 { core::hint::spin_loop(); spins += 1; }
+}
+
+/// Find the first virtio-net function, reserve the DMA pool if needed and
+/// bring the device up. Requires Boot Services only for discovery.
+pub fn probe(system_table: &mut SystemTable<Boot>) -> Result<Info, &'static str> {
+    if let Some(i) = info() { return Ok(i); }
+    crate::mm::dma::reserve(system_table, DEFAULT_POOL_PAGES)?;
+    let (_, _, _, cfg) = super::net::find_first_virtio_net(system_table).ok_or("nic: no virtio-net device")?;
+    probe_at(cfg)
+}
+
+/// Bring up the virtio-net function whose ECAM config space is at `cfg`.
+/// Firmware-free: the DMA pool must already be reserved.
+pub fn probe_at(cfg: usize) -> Result<Info, &'static str> {
+    if NIC.lock(|n| n.is_some()) { return Err("nic: already up"); }
+    if !crate::mm::dma::is_reserved() { return Err("nic: dma pool not reserved"); }
+    if mmio_read16(cfg) != 0x1AF4 { return Err("nic: not a virtio function"); }
+    let (common, notify, mul, device) = find_caps(cfg).ok_or("nic: modern capabilities missing")?;
+    // Bus mastering and memory decoding; legacy INTx off
+    let cmd = mmio_read16(cfg + 0x04);
+    mmio_write16(cfg + 0x04, cmd | 0x0406);
+
+    reset(common);
+    super::net::forget_queues();
+    mmio_write8(common + C_STATUS, S_ACKNOWLEDGE);
+    mmio_write8(common + C_STATUS, S_ACKNOWLEDGE | S_DRIVER);
+    mmio_write32(common + C_DFSELECT, 0);
+    let lo = mmio_read32(common + C_DF) as u64;
+    mmio_write32(common + C_DFSELECT, 1);
+    let hi = mmio_read32(common + C_DF) as u64;
+    let offered = lo | (hi << 32);
+    if offered & F_VERSION_1 == 0 { mmio_write8(common + C_STATUS, S_FAILED); return Err("nic: device is legacy-only"); }
+    let features = offered & (F_VERSION_1 | F_MAC | F_STATUS);
+    mmio_write32(common + C_GFSELECT, 0);
+    mmio_write32(common + C_GF, features as u32);
+    mmio_write32(common + C_GFSELECT, 1);
+    mmio_write32(common + C_GF, (features >> 32) as u32);
+    mmio_write8(common + C_STATUS, S_ACKNOWLEDGE | S_DRIVER | S_FEATURES_OK);
+    if mmio_read8(common + C_STATUS) & S_FEATURES_OK == 0 { mmio_write8(common + C_STATUS, S_FAILED); return Err("nic: features rejected"); }
+    mmio_write16(common + C_MSIX_CONFIG, NO_VECTOR);
+
+    let rx = match setup_queue(common, notify, mul, RX_Q) {
+        Ok(q) => q,
+        Err(e) => { mmio_write8(common + C_STATUS, S_FAILED); return Err(e); }
+    };
+    let tx = match setup_queue(common, notify, mul, TX_Q) {
+        Ok(q) => q,
+        Err(e) => { release(&rx); mmio_write8(common + C_STATUS, S_FAILED); return Err(e); }
+    };
+    // Every RX descriptor owns one buffer and is posted up front.
+    for i in 0..rx.size as usize {
+        let d = rx.desc(i);
+        mmio_write64(d, rx.buf(i));
+        mmio_write32(d + 8, BUF_SIZE as u32);
+        mmio_write16(d + 12, DESC_F_WRITE);
+        mmio_write16(d + 14, 0);
+        mmio_write16(rx.avail() + 4 + i * 2, i as u16);
+    }
+    fence();
+    mmio_write16(rx.avail() + 2, rx.size);
+    for q in [RX_Q, TX_Q] {
+        mmio_write16(common + C_Q_SELECT, q);
+        mmio_write16(common + C_Q_ENABLE, 1);
+    }
+    mmio_write8(common + C_STATUS, S_ACKNOWLEDGE | S_DRIVER | S_FEATURES_OK | S_DRIVER_OK);
+    mmio_write16(rx.notify, RX_Q);
+
+    let mut mac = [0u8; 6];
+    if features & F_MAC != 0 && device != 0 { for (i, m) in mac.iter_mut().enumerate() { *m = mmio_read8(device + i); } }
+    let info = Info { cfg, mac, features, rx_size: rx.size, tx_size: tx.size, msix: None };
+    NIC.lock(|n| *n = Some(Nic { info, common, device, rx, tx, stats: Stats::default() }));
+    Ok(info)
+}
+
+pub fn is_up() -> bool { NIC.lock(|n| n.is_some()) }
+
+pub fn info() -> Option<Info> { NIC.lock(|n| n.as_ref().map(|n| n.info)) }
+
+pub fn stats() -> Stats { NIC.lock(|n| n.as_ref().map(|n| n.stats).unwrap_or_default()) }
+
+pub fn irq_count() -> u64 { IRQS.load(Ordering::Relaxed) }
+
+/// Link state from the device config; reported up when STATUS was not offered.
+pub fn link_up() -> bool {
+    NIC.lock(|n| match n {
+        Some(n) if n.info.features & F_STATUS != 0 && n.device != 0 => mmio_read16(n.device + 6) & 1 != 0,
+        Some(_) => true,
+        None => false,
+    })
+}
+
+/// Queue one Ethernet frame; returns the frame length, or 0 when the
+/// driver is down, the frame is oversized or the TX ring is full.
+pub fn send(frame: &[u8]) -> usize {
+    if frame.is_empty() || HDR_LEN + frame.len() > BUF_SIZE { return 0; }
+    NIC.lock(|n| {
+        let n = match n { Some(n) => n, None => return 0 };
+        let q = &mut n.tx;
+        // Completed TX entries need no bookkeeping beyond the index.
+        q.last_used = q.used_idx();
+        let avail = q.avail_idx();
+        if avail.wrapping_sub(q.last_used) >= q.size { n.stats.tx_full += 1; return 0; }
+        let slot = avail as usize % q.size as usize;
+        let buf = q.buf(slot);
+        unsafe {
+            core::ptr::write_bytes(buf as *mut u8, 0, HDR_LEN);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), (buf as usize + HDR_LEN) as *mut u8, frame.len());
+        }
+        let d = q.desc(slot);
+        mmio_write64(d, buf);
+        mmio_write32(d + 8, (HDR_LEN + frame.len()) as u32);
+        mmio_write16(d + 12, 0);
+        mmio_write16(d + 14, 0);
+        mmio_write16(q.avail() + 4 + slot * 2, slot as u16);
+        fence();
+        mmio_write16(q.avail() + 2, avail.wrapping_add(1));
+        fence();
+        mmio_write16(q.notify, q.index);
+        n.stats.tx_frames += 1;
+        n.stats.tx_bytes += frame.len() as u64;
+        frame.len()
+    })
+}
+
+/// Hand up to `limit` received frames (0 = all pending) to `f` without the
+/// virtio header. The lock is not held across `f`, so it may call `send`.
+pub fn recv(limit: usize, mut f: impl FnMut(&[u8])) -> usize {
+    let mut done = 0usize;
+    while limit == 0 || done < limit {
+        let got = NIC.lock(|n| {
+            let n = n.as_mut()?;
+            let q = &mut n.rx;
+            if q.last_used == q.used_idx() { return None; }
+            let slot = q.last_used as usize % q.size as usize;
+            let elem = q.used() + 4 + slot * 8;
+            let id = mmio_read32(elem) as usize % q.size as usize;
+            let len = (mmio_read32(elem + 4) as usize).min(BUF_SIZE);
+            q.last_used = q.last_used.wrapping_add(1);
+            n.stats.rx_frames += 1;
+            n.stats.rx_bytes += len.saturating_sub(HDR_LEN) as u64;
+            Some((id, q.buf(id), len))
+        });
+        let (id, buf, len) = match got { Some(g) => g, None => break };
+        if len > HDR_LEN { f(unsafe { core::slice::from_raw_parts((buf as usize + HDR_LEN) as *const u8, len - HDR_LEN) }); }
+        NIC.lock(|n| {
+            if let Some(n) = n.as_mut() {
+                let q = &n.rx;
+                let avail = q.avail_idx();
+                mmio_write16(q.avail() + 4 + (avail as usize % q.size as usize) * 2, id as u16);
+                fence();
+                mmio_write16(q.avail() + 2, avail.wrapping_add(1));
+                fence();
+                mmio_write16(q.notify, q.index);
+            }
+        });
+        done += 1;
+    }
+    done
+}
+
+/// Drain the RX ring if an interrupt arrived (or always, when polled).
+pub fn poll(limit: usize, f: impl FnMut(&[u8])) -> usize {
+    let msix = info().map(|i| i.msix.is_some()).unwrap_or(false);
+    if msix && !IRQ_PENDING.swap(false, Ordering::AcqRel) { return 0; }
+    recv(limit, f)
+}
+
+/// Entry point for the MSI-X vector programmed by `enable_msix`. Safe to call
+/// from interrupt context: it only touches atomics.
+pub fn on_interrupt() {
+    IRQS.fetch_add(1, Ordering::Relaxed);
+    IRQ_PENDING.store(true, Ordering::Release);
+}
+
+/// Locate the MSI-X capability: (cap offset, table address, table size).
+fn msix_table(cfg: usize) -> Option<(usize, usize, u16)> {
+    let mut p = mmio_read8(cfg + 0x34) as usize & !3;
+    let mut guard = 0;
+    while p >= 0x40 && p < 0x100 && guard < 48 {
+        if mmio_read8(cfg + p) == 0x11 {
+            let size = (mmio_read16(cfg + p + 2) & 0x7FF) + 1;
+            let tbl = mmio_read32(cfg + p + 4);
+            let base = bar_base(cfg, (tbl & 7) as u8)?;
+            return Some((p, base as usize + (tbl & !7) as usize, size));
+        }
+        p = mmio_read8(cfg + p + 1) as usize & !3;
+        guard += 1;
+    }
+    None
+}
+
+/// Route both queues to MSI-X table entry 0, delivering `vector` to the
+/// local APIC `dest` (physical destination mode, fixed delivery).
+pub fn enable_msix(vector: u8, dest: u32) -> Result<(), &'static str> {
+    if vector < 0x20 { return Err("nic: vector must be >= 0x20"); }
+    NIC.lock(|n| {
+        let n = n.as_mut().ok_or("nic: not up")?;
+        let (cap, table, _) = msix_table(n.info.cfg).ok_or("nic: no MSI-X capability")?;
+        let ctrl = n.info.cfg + cap + 2;
+        // Function mask while the entry is rewritten
+        mmio_write16(ctrl, mmio_read16(ctrl) | 0x4000);
+        mmio_write32(table, 0xFEE0_0000 | ((dest & 0xFF) << 12));
+        mmio_write32(table + 4, 0);
+        mmio_write32(table + 8, vector as u32);
+        mmio_write32(table + 12, 0);
+        mmio_write16(ctrl, (mmio_read16(ctrl) | 0x8000) & !0x4000);
+        for q in [RX_Q, TX_Q] {
+            mmio_write16(n.common + C_Q_SELECT, q);
+            mmio_write16(n.common + C_Q_MSIX, 0);
+            if mmio_read16(n.common + C_Q_MSIX) != 0 {
+                mmio_write16(ctrl, mmio_read16(ctrl) & !0x8000);
+                return Err("nic: device refused MSI-X vector");
+            }
+        }
+        n.info.msix = Some((vector, dest));
+        Ok(())
+    })
+}
+
+/// Return to polled completion.
+pub fn disable_msix() {
+    NIC.lock(|n| {
+        if let Some(n) = n.as_mut() {
+            for q in [RX_Q, TX_Q] {
+                mmio_write16(n.common + C_Q_SELECT, q);
+                mmio_write16(n.common + C_Q_MSIX, NO_VECTOR);
+            }
+            if let Some((cap, table, _)) = msix_table(n.info.cfg) {
+                mmio_write32(table + 12, 1);
+                let ctrl = n.info.cfg + cap + 2;
+                mmio_write16(ctrl, mmio_read16(ctrl) & !0x8000);
+            }
+            n.info.msix = None;
+        }
+    });
+    IRQ_PENDING.store(false, Ordering::Release);
+}
+
+/// Reset the device and give the rings back to the pool.
+pub fn shutdown() {
+    disable_msix();
+    NIC.lock(|n| {
+        if let Some(nic) = n.take() {
+            reset(nic.common);
+            release(&nic.rx);
+            release(&nic.tx);
+        }
+    });
+}